@@ -1,8 +1,12 @@
 use criterion::*;
 use matrix_sdk::{config::StoreConfig, Client, RoomInfo, RoomState, Session, StateChanges};
 use matrix_sdk_base::{store::MemoryStore, StateStore as _};
-use matrix_sdk_sqlite::SqliteStateStore;
-use ruma::{device_id, user_id, RoomId};
+use matrix_sdk_crypto::{
+    store::{Changes, CryptoStore as _},
+    EncryptionSettings, ReadOnlyAccount,
+};
+use matrix_sdk_sqlite::{SqliteCryptoStore, SqliteStateStore};
+use ruma::{device_id, room_id, user_id, RoomId};
 use tokio::runtime::Builder;
 
 fn criterion() -> Criterion {
@@ -110,9 +114,79 @@ pub fn restore_session(c: &mut Criterion) {
     group.finish()
 }
 
+/// Number of inbound group sessions in the crypto store benchmark.
+const NUM_INBOUND_GROUP_SESSIONS: usize = 5000;
+
+pub fn inbound_group_session_lookup(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread().build().expect("Can't create runtime");
+
+    // Create the room keys of a room with a lot of history.
+    let room_id = room_id!("!room:example.com");
+    let account = ReadOnlyAccount::new(user_id!("@somebody:example.com"), device_id!("DEVICE_ID"));
+    let inbound_group_sessions: Vec<_> = runtime.block_on(async {
+        let mut sessions = Vec::with_capacity(NUM_INBOUND_GROUP_SESSIONS);
+        for _ in 0..NUM_INBOUND_GROUP_SESSIONS {
+            let (_, inbound) = account
+                .create_group_session_pair(room_id, EncryptionSettings::default())
+                .await
+                .expect("Can't create a group session pair");
+            sessions.push(inbound);
+        }
+        sessions
+    });
+    let session_ids: Vec<_> =
+        inbound_group_sessions.iter().map(|s| s.session_id().to_owned()).collect();
+
+    // Start the benchmark.
+
+    let mut group = c.benchmark_group("Crypto store lookups");
+    group.throughput(Throughput::Elements(session_ids.len() as u64));
+
+    const NAME: &str = "get every inbound group session of a room";
+
+    for encryption_password in [None, Some("hunter2")] {
+        let encrypted_suffix = if encryption_password.is_some() { "encrypted" } else { "clear" };
+
+        let sqlite_dir = tempfile::tempdir().unwrap();
+        let sqlite_store = runtime
+            .block_on(SqliteCryptoStore::open(sqlite_dir.path(), encryption_password))
+            .unwrap();
+        let changes = Changes {
+            inbound_group_sessions: inbound_group_sessions.clone(),
+            ..Default::default()
+        };
+        runtime
+            .block_on(sqlite_store.save_changes(changes))
+            .expect("initial filling of sqlite failed");
+
+        group.bench_with_input(
+            BenchmarkId::new(format!("sqlite store {encrypted_suffix}"), NAME),
+            &sqlite_store,
+            |b, store| {
+                b.to_async(&runtime).iter(|| async {
+                    for session_id in &session_ids {
+                        store
+                            .get_inbound_group_session(room_id, session_id)
+                            .await
+                            .expect("couldn't load the session")
+                            .expect("the session is missing");
+                    }
+                })
+            },
+        );
+
+        {
+            let _guard = runtime.enter();
+            drop(sqlite_store);
+        }
+    }
+
+    group.finish()
+}
+
 criterion_group! {
     name = benches;
     config = criterion();
-    targets = restore_session
+    targets = restore_session, inbound_group_session_lookup
 }
 criterion_main!(benches);
@@ -641,6 +641,8 @@ impl From<EncryptionSettings> for RustEncryptionSettings {
             rotation_period_msgs: v.rotation_period_msgs,
             history_visibility: v.history_visibility.into(),
             only_allow_trusted_devices: v.only_allow_trusted_devices,
+            sender_authentication: Default::default(),
+            trust_requirement: Default::default(),
         }
     }
 }
@@ -834,6 +836,7 @@ impl From<RoomSettings> for RustRoomSettings {
         Self {
             algorithm: value.algorithm.into(),
             only_allow_trusted_devices: value.only_allow_trusted_devices,
+            trust_requirement: Default::default(),
         }
     }
 }
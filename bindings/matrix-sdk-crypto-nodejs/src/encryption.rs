@@ -104,6 +104,8 @@ impl From<&EncryptionSettings> for matrix_sdk_crypto::olm::EncryptionSettings {
             rotation_period_msgs: value.rotation_period_messages.get_u64().1,
             history_visibility: value.history_visibility.into(),
             only_allow_trusted_devices: value.only_allow_trusted_devices,
+            sender_authentication: Default::default(),
+            trust_requirement: Default::default(),
         }
     }
 }
@@ -227,6 +227,9 @@ impl TimelineItem {
             Item::Virtual(VItem::ReadMarker) => Some(VirtualTimelineItem::ReadMarker),
             Item::Virtual(VItem::LoadingIndicator) => Some(VirtualTimelineItem::LoadingIndicator),
             Item::Virtual(VItem::TimelineStart) => Some(VirtualTimelineItem::TimelineStart),
+            Item::Virtual(VItem::SecurityNotice { notice, .. }) => {
+                Some(VirtualTimelineItem::SecurityNotice { notice: notice.clone().into() })
+            }
             Item::Event(_) => None,
         }
     }
@@ -313,7 +316,7 @@ impl EventTimelineItem {
         self.0
             .reactions()
             .iter()
-            .map(|(k, v)| Reaction { key: k.to_owned(), count: v.len().try_into().unwrap() })
+            .map(|(k, v)| Reaction { key: k.to_owned(), count: v.count() })
             .collect()
     }
 
@@ -337,11 +340,15 @@ impl EventTimelineItem {
 #[derive(uniffi::Record)]
 pub struct Receipt {
     pub timestamp: Option<u64>,
+    pub is_private: bool,
 }
 
-impl From<ruma::events::receipt::Receipt> for Receipt {
-    fn from(value: ruma::events::receipt::Receipt) -> Self {
-        Receipt { timestamp: value.ts.map(|ts| ts.0.into()) }
+impl From<matrix_sdk_ui::timeline::ReadReceipt> for Receipt {
+    fn from(value: matrix_sdk_ui::timeline::ReadReceipt) -> Self {
+        Receipt {
+            timestamp: value.ts.map(|ts| ts.0.into()),
+            is_private: value.receipt_type == ruma::events::receipt::ReceiptType::ReadPrivate,
+        }
     }
 }
 
@@ -1079,6 +1086,38 @@ pub enum VirtualTimelineItem {
     /// There might be earlier events the user is not allowed to see due to
     /// history visibility.
     TimelineStart,
+
+    /// A security-relevant change in the room.
+    SecurityNotice { notice: SecurityNotice },
+}
+
+/// A security-relevant change in a room, shown inline in the timeline.
+#[derive(uniffi::Enum)]
+pub enum SecurityNotice {
+    /// End-to-end encryption was enabled in the room.
+    EncryptionEnabled,
+    /// A member added a device that isn't verified, while all the members of
+    /// the room were verified.
+    UnverifiedDevice { user_id: String, device_id: String },
+    /// The cross-signing identity of a member of the room changed.
+    IdentityChanged { user_id: String },
+}
+
+impl From<matrix_sdk_ui::timeline::SecurityNotice> for SecurityNotice {
+    fn from(notice: matrix_sdk_ui::timeline::SecurityNotice) -> Self {
+        use matrix_sdk_ui::timeline::SecurityNotice as Notice;
+
+        match notice {
+            Notice::EncryptionEnabled => Self::EncryptionEnabled,
+            Notice::UnverifiedDevice { user_id, device_id } => Self::UnverifiedDevice {
+                user_id: user_id.to_string(),
+                device_id: device_id.to_string(),
+            },
+            Notice::IdentityChanged { user_id } => {
+                Self::IdentityChanged { user_id: user_id.to_string() }
+            }
+        }
+    }
 }
 
 #[extension_trait]
@@ -16,24 +16,21 @@
 #[cfg(feature = "e2e-encryption")]
 use std::ops::Deref;
 use std::{
-    collections::{BTreeMap, BTreeSet},
-    fmt, iter,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt,
+    future::Future,
+    iter,
     sync::Arc,
 };
 
 use eyeball::{shared::Observable as SharedObservable, Subscriber};
-use matrix_sdk_common::instant::Instant;
+use matrix_sdk_common::{executor::spawn, instant::Instant, SendOutsideWasm};
+#[cfg(feature = "decryption-audit")]
+use matrix_sdk_crypto::DecryptionAuditSink;
 #[cfg(feature = "e2e-encryption")]
 use matrix_sdk_crypto::{
-    store::DynCryptoStore, EncryptionSettings, OlmError, OlmMachine, ToDeviceRequest,
-};
-#[cfg(feature = "e2e-encryption")]
-use ruma::events::{
-    room::{
-        history_visibility::HistoryVisibility, message::MessageType,
-        redaction::SyncRoomRedactionEvent,
-    },
-    AnySyncMessageLikeEvent, SyncMessageLikeEvent,
+    store::{CryptoStateExport, DynCryptoStore},
+    EncryptionSettings, OlmError, OlmMachine, ToDeviceRequest, UtdTelemetryHook,
 };
 use ruma::{
     api::client::{self as api, push::get_notifications::v3::Notification},
@@ -41,34 +38,71 @@ use ruma::{
         push_rules::{PushRulesEvent, PushRulesEventContent},
         room::{
             member::{MembershipState, SyncRoomMemberEvent},
-            power_levels::{RoomPowerLevelsEvent, RoomPowerLevelsEventContent},
+            power_levels::{RoomPowerLevels, RoomPowerLevelsEvent, RoomPowerLevelsEventContent},
         },
-        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStateEvent, AnyStrippedStateEvent,
         AnySyncEphemeralRoomEvent, AnySyncStateEvent, AnySyncTimelineEvent,
         GlobalAccountDataEventType, StateEventType,
     },
     push::{Action, PushConditionRoomCtx, Ruleset},
     serde::Raw,
-    MilliSecondsSinceUnixEpoch, OwnedUserId, RoomId, UInt, UserId,
+    MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
+};
+#[cfg(feature = "e2e-encryption")]
+use ruma::{
+    events::{
+        room::{
+            history_visibility::HistoryVisibility, message::MessageType,
+            redaction::SyncRoomRedactionEvent,
+        },
+        AnySyncMessageLikeEvent, SyncMessageLikeEvent,
+    },
+    DeviceId,
 };
-use tokio::sync::RwLock;
 #[cfg(feature = "e2e-encryption")]
 use tokio::sync::RwLockReadGuard;
-use tracing::{debug, info, instrument, trace, warn};
+use tokio::sync::{broadcast, RwLock};
+use tracing::{debug, info, instrument, trace, warn, Instrument, Span};
 
+#[cfg(feature = "experimental-encrypted-state-events")]
+use ruma::OwnedEventId;
+
+#[cfg(feature = "e2e-encryption")]
+use crate::RoomMemberships;
+#[cfg(feature = "experimental-encrypted-state-events")]
+use crate::{deserialized_responses::RawAnySyncOrStrippedState, encrypted_state};
 use crate::{
     deserialized_responses::{AmbiguityChanges, MembersResponse, SyncTimelineEvent},
-    error::Result,
+    error::{Error, Result},
     rooms::{Room, RoomInfo, RoomState},
     store::{
-        ambiguity_map::AmbiguityCache, DynStateStore, Result as StoreResult, StateChanges,
-        StateStoreDataKey, StateStoreDataValue, StateStoreExt, Store, StoreConfig,
+        ambiguity_map::AmbiguityCache, DynStateStore, IntoStateStore, MemoryStore,
+        Result as StoreResult, StateChanges, StateStoreDataKey, StateStoreDataValue, StateStoreExt,
+        Store, StoreConfig,
+    },
+    sync::{
+        JoinedRoom, LeftRoom, Rooms, SyncProcessingPolicy, SyncProgress, SyncResponse,
+        SyncResponseProcessor, Timeline,
     },
-    sync::{JoinedRoom, LeftRoom, Rooms, SyncResponse, Timeline},
     RoomStateFilter, Session, SessionMeta, SessionTokens,
 };
-#[cfg(feature = "e2e-encryption")]
-use crate::{error::Error, RoomMemberships};
+
+/// The maximum number of rooms of a sync response that are processed at the
+/// same time.
+const MAX_CONCURRENT_ROOM_PROCESSING: usize = 16;
+
+/// A room of a sync response that was processed on its own, waiting to be
+/// merged with the other rooms of the response.
+pub(crate) struct ProcessedRoom<T> {
+    /// The ID of the room.
+    pub room_id: OwnedRoomId,
+    /// The update to the room, as returned in the `SyncResponse`.
+    pub update: T,
+    /// The changes to the store for this room.
+    pub changes: StateChanges,
+    /// The display name ambiguities for this room.
+    pub ambiguity_cache: AmbiguityCache,
+}
 
 /// A no IO Client implementation.
 ///
@@ -89,7 +123,24 @@ pub struct BaseClient {
     /// [`BaseClient::set_session_meta`]
     #[cfg(feature = "e2e-encryption")]
     olm_machine: Arc<RwLock<Option<OlmMachine>>>,
+    /// The sink notified of the decryptions of the `OlmMachine`, kept here so
+    /// it is also set on a machine created after a login.
+    #[cfg(feature = "decryption-audit")]
+    decryption_audit_sink: Arc<std::sync::RwLock<Option<Arc<dyn DecryptionAuditSink>>>>,
+    /// The hook notified of the UTDs of the `OlmMachine`, kept here so it is
+    /// also set on a machine created after a login.
+    #[cfg(feature = "e2e-encryption")]
+    utd_telemetry_hook: Arc<std::sync::RwLock<Option<Arc<dyn UtdTelemetryHook>>>>,
     pub(crate) ignore_user_list_changes_tx: Arc<SharedObservable<()>>,
+    /// The progress of the processing of the rooms of the last sync response.
+    pub(crate) sync_progress: Arc<SharedObservable<SyncProgress>>,
+    /// The processors called with the changes of the sync responses.
+    sync_response_processors: Arc<std::sync::RwLock<Vec<Arc<dyn SyncResponseProcessor>>>>,
+    /// The policy deciding which data of the sync responses is saved.
+    sync_processing_policy: Arc<std::sync::RwLock<SyncProcessingPolicy>>,
+    /// The encrypted state events that couldn't be decrypted yet.
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    pending_encrypted_state: encrypted_state::PendingEncryptedState,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -121,7 +172,16 @@ impl BaseClient {
             crypto_store: config.crypto_store,
             #[cfg(feature = "e2e-encryption")]
             olm_machine: Default::default(),
+            #[cfg(feature = "decryption-audit")]
+            decryption_audit_sink: Default::default(),
+            #[cfg(feature = "e2e-encryption")]
+            utd_telemetry_hook: Default::default(),
             ignore_user_list_changes_tx: Default::default(),
+            sync_progress: Default::default(),
+            sync_response_processors: Default::default(),
+            sync_processing_policy: Default::default(),
+            #[cfg(feature = "experimental-encrypted-state-events")]
+            pending_encrypted_state: Default::default(),
         }
     }
 
@@ -236,17 +296,70 @@ impl BaseClient {
 
         #[cfg(feature = "e2e-encryption")]
         {
-            let olm_machine = OlmMachine::with_store(
-                &session_meta.user_id,
-                &session_meta.device_id,
-                self.crypto_store.clone(),
-            )
+            let olm_machine =
+                self.create_olm_machine(&session_meta.user_id, &session_meta.device_id).await?;
+            *self.olm_machine.write().await = Some(olm_machine);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "e2e-encryption")]
+    async fn create_olm_machine(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<OlmMachine> {
+        let olm_machine = OlmMachine::with_store(user_id, device_id, self.crypto_store.clone())
             .await
             .map_err(OlmError::from)?;
 
-            *self.olm_machine.write().await = Some(olm_machine);
+        #[cfg(feature = "decryption-audit")]
+        olm_machine.set_decryption_audit_sink(self.decryption_audit_sink.read().unwrap().clone());
+        olm_machine.set_utd_telemetry_hook(self.utd_telemetry_hook.read().unwrap().clone());
+
+        Ok(olm_machine)
+    }
+
+    /// Recreate the `OlmMachine` from the crypto store.
+    ///
+    /// This must be called when another process modified the crypto store,
+    /// since the state of the current `OlmMachine` is outdated, see
+    /// [`OlmMachine::maintain_crypto_store_generation()`]. The caches of the
+    /// crypto store are cleared too.
+    ///
+    /// Does nothing if the `OlmMachine` wasn't initialized yet.
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn regenerate_olm(&self) -> Result<()> {
+        let mut olm_machine = self.olm_machine.write().await;
+        let Some(previous) = olm_machine.as_ref() else {
+            return Ok(());
+        };
+        let (user_id, device_id) = (previous.user_id().to_owned(), previous.device_id().to_owned());
+
+        self.crypto_store.clear_caches().await;
+        *olm_machine = Some(self.create_olm_machine(&user_id, &device_id).await?);
+
+        Ok(())
+    }
+
+    /// Save the crypto state exported from another client into the crypto
+    /// store.
+    ///
+    /// This must be called before the session is restored with
+    /// [`BaseClient::set_session_meta()`], which will then restore the
+    /// `OlmMachine` from the imported state.
+    ///
+    /// Returns [`Error::BadCryptoStoreState`] if the `OlmMachine` was already
+    /// initialized.
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn import_crypto_state(&self, state: CryptoStateExport) -> Result<()> {
+        if self.olm_machine.read().await.is_some() {
+            return Err(Error::BadCryptoStoreState);
         }
 
+        state.import_into(&*self.crypto_store).await?;
+
         Ok(())
     }
 
@@ -301,6 +414,120 @@ impl BaseClient {
         Ok(Some(event))
     }
 
+    /// Decrypt an encrypted state event, if it is one.
+    ///
+    /// Returns `None` if the event is not an encrypted state event, if it
+    /// couldn't be decrypted, or if the decrypted event is not allowed. The
+    /// events that couldn't be decrypted are retried in the next syncs, see
+    /// [`BaseClient::retry_encrypted_state()`].
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    async fn decrypt_sync_state_event(
+        &self,
+        event: &Raw<AnySyncTimelineEvent>,
+        room_id: &RoomId,
+        changes: &StateChanges,
+    ) -> Option<(SyncTimelineEvent, AnySyncStateEvent)> {
+        if !encrypted_state::is_encrypted_state_event(event) {
+            return None;
+        }
+
+        let olm = self.olm_machine().await;
+        let decrypted = match olm.as_ref()?.decrypt_room_event(event.cast_ref(), room_id).await {
+            Ok(decrypted) => decrypted,
+            Err(error) => {
+                debug!("Failed to decrypt a state event, retrying in the next sync: {error}");
+                self.pending_encrypted_state.insert(room_id, event);
+                return None;
+            }
+        };
+
+        // This event replaces the pending one for the same piece of state, if
+        // any.
+        if let Ok(Some(packed)) = event.get_field::<String>("state_key") {
+            self.pending_encrypted_state.remove(room_id, &packed);
+        }
+
+        let raw_event = match encrypted_state::decrypted_state_event(event, &decrypted.event) {
+            Ok(raw_event) => raw_event,
+            Err(error) => {
+                warn!("Discarding an invalid encrypted state event: {error}");
+                return None;
+            }
+        };
+
+        let state_event = match raw_event.deserialize_as::<AnySyncStateEvent>() {
+            Ok(state_event) => state_event,
+            Err(error) => {
+                warn!("Failed to deserialize a decrypted state event: {error}");
+                return None;
+            }
+        };
+
+        let power_levels = match self.room_power_levels(room_id, changes).await {
+            Ok(power_levels) => power_levels,
+            Err(error) => {
+                warn!("Failed to get the power levels to check a decrypted state event: {error}");
+                return None;
+            }
+        };
+        if let Err(error) = encrypted_state::check_power_level(&state_event, power_levels.as_ref())
+        {
+            warn!("Discarding an invalid encrypted state event: {error}");
+            return None;
+        }
+
+        Some((
+            SyncTimelineEvent {
+                event: raw_event,
+                encryption_info: decrypted.encryption_info,
+                push_actions: Vec::new(),
+            },
+            state_event,
+        ))
+    }
+
+    /// Decrypt the encrypted state events of the given room that couldn't be
+    /// decrypted before, for example because their room key arrived later.
+    ///
+    /// Only the latest event of each piece of state is retried.
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    async fn retry_encrypted_state(
+        &self,
+        room_info: &mut RoomInfo,
+        changes: &mut StateChanges,
+    ) -> StoreResult<()> {
+        let room_id = room_info.room_id.clone();
+
+        for (packed, event_id) in self.pending_encrypted_state.get(&room_id) {
+            let raw_event = match self
+                .store
+                .get_state_event(&room_id, encrypted_state::ENCRYPTED_EVENT_TYPE.into(), &packed)
+                .await?
+            {
+                Some(RawAnySyncOrStrippedState::Sync(raw_event))
+                    if raw_event.get_field::<OwnedEventId>("event_id").ok().flatten().as_ref()
+                        == Some(&event_id) =>
+                {
+                    raw_event
+                }
+                // The event was replaced by a more recent one.
+                _ => {
+                    self.pending_encrypted_state.remove(&room_id, &packed);
+                    continue;
+                }
+            };
+
+            if let Some((decrypted, state_event)) =
+                self.decrypt_sync_state_event(raw_event.cast_ref(), &room_id, changes).await
+            {
+                room_info.handle_state_event(&state_event);
+                changes.add_state_event(&room_id, state_event, decrypted.event.cast());
+            }
+        }
+
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     #[instrument(skip_all, fields(room_id = ?room_info.room_id))]
     pub(crate) async fn handle_timeline(
@@ -323,6 +550,23 @@ impl BaseClient {
 
             match event.event.deserialize() {
                 Ok(e) => {
+                    // Process encrypted state events like the state events they
+                    // contain.
+                    #[cfg(feature = "experimental-encrypted-state-events")]
+                    let e = match Box::pin(self.decrypt_sync_state_event(
+                        &event.event,
+                        room.room_id(),
+                        changes,
+                    ))
+                    .await
+                    {
+                        Some((decrypted, state_event)) => {
+                            event = decrypted;
+                            AnySyncTimelineEvent::State(state_event)
+                        }
+                        None => e,
+                    };
+
                     #[allow(clippy::single_match)]
                     match &e {
                         AnySyncTimelineEvent::State(s) => {
@@ -412,6 +656,15 @@ impl BaseClient {
                         _ => (),
                     }
 
+                    // This is the remote echo of an event sent by this client, the other
+                    // processes using the same store don't need its ID anymore to match it
+                    // with its local echo.
+                    if let Some(txn_id) = e.transaction_id() {
+                        if e.sender() == room.own_user_id() {
+                            self.store.remove_kv_data(StateStoreDataKey::SentEvent(txn_id)).await?;
+                        }
+                    }
+
                     if let Some(context) = &mut push_context {
                         self.update_push_room_context(
                             context,
@@ -501,7 +754,18 @@ impl BaseClient {
         let mut user_ids = BTreeSet::new();
         let mut profiles = BTreeMap::new();
 
+        #[cfg(feature = "experimental-encrypted-state-events")]
+        let mut encrypted_events = Vec::new();
+
         for (raw_event, event) in iter::zip(raw_events, events) {
+            // The encrypted state events are decrypted once the other state
+            // events are known, to check the power levels of their senders.
+            #[cfg(feature = "experimental-encrypted-state-events")]
+            if encrypted_state::is_encrypted_state_event(raw_event.cast_ref()) {
+                encrypted_events.push((raw_event, event));
+                continue;
+            }
+
             room_info.handle_state_event(event);
 
             if let AnySyncStateEvent::RoomMember(member) = &event {
@@ -532,6 +796,21 @@ impl BaseClient {
         changes.profiles.insert((*room_info.room_id).to_owned(), profiles);
         changes.state.insert((*room_info.room_id).to_owned(), state_events);
 
+        // Encrypted state events can't contain members, so only the state of the
+        // room is updated.
+        #[cfg(feature = "experimental-encrypted-state-events")]
+        for (raw_event, event) in encrypted_events {
+            let room_id = room_info.room_id.clone();
+            match self.decrypt_sync_state_event(raw_event.cast_ref(), &room_id, changes).await {
+                Some((decrypted, state_event)) => {
+                    room_info.handle_state_event(&state_event);
+                    changes.add_state_event(&room_id, state_event, decrypted.event.cast());
+                }
+                // Keep the encrypted event, so it can be decrypted later.
+                None => changes.add_state_event(&room_id, event.clone(), raw_event.clone()),
+            }
+        }
+
         Ok(user_ids)
     }
 
@@ -633,9 +912,8 @@ impl BaseClient {
             room_info.mark_state_partially_synced();
             room_info.mark_members_missing(); // the own member event changed
             let mut changes = StateChanges::default();
-            changes.add_room(room_info.clone());
-            self.store.save_changes(&changes).await?; // Update the store
-            room.update_summary(room_info); // Update the cached room handle
+            changes.add_room(room_info);
+            self.save_changes(&changes).await?;
         }
 
         Ok(room)
@@ -654,9 +932,8 @@ impl BaseClient {
             room_info.mark_state_partially_synced();
             room_info.mark_members_missing(); // the own member event changed
             let mut changes = StateChanges::default();
-            changes.add_room(room_info.clone());
-            self.store.save_changes(&changes).await?; // Update the store
-            room.update_summary(room_info); // Update the cached room handle
+            changes.add_room(room_info);
+            self.save_changes(&changes).await?;
         }
 
         Ok(room)
@@ -704,142 +981,38 @@ impl BaseClient {
 
         self.handle_account_data(&response.account_data.events, &mut changes).await;
 
-        let push_rules = self.get_push_rules(&changes).await?;
-
-        let mut new_rooms = Rooms::default();
-
-        for (room_id, new_info) in response.rooms.join {
-            let room = self.store.get_or_create_room(&room_id, RoomState::Joined).await;
-            let mut room_info = room.clone_info();
-            room_info.mark_as_joined();
-
-            room_info.update_summary(&new_info.summary);
-            room_info.set_prev_batch(new_info.timeline.prev_batch.as_deref());
-            room_info.mark_state_fully_synced();
-
-            let deserialized_events = Self::deserialize_events(&new_info.state.events);
-
-            let mut user_ids = self
-                .handle_state(
-                    &new_info.state.events,
-                    &deserialized_events,
-                    &mut room_info,
-                    &mut changes,
-                    &mut ambiguity_cache,
-                )
-                .await?;
-
-            for raw in &new_info.ephemeral.events {
-                match raw.deserialize() {
-                    Ok(AnySyncEphemeralRoomEvent::Receipt(event)) => {
-                        changes.add_receipts(&room_id, event.content);
-                    }
-                    Ok(_) => {}
-                    Err(e) => {
-                        let event_id: Option<String> = raw.get_field("event_id").ok().flatten();
-                        #[rustfmt::skip]
-                        info!(
-                            ?room_id, event_id,
-                            "Failed to deserialize ephemeral room event: {e}"
-                        );
-                    }
-                }
-            }
-
-            if new_info.timeline.limited {
-                room_info.mark_members_missing();
-            }
-
-            let timeline = self
-                .handle_timeline(
-                    &room,
-                    new_info.timeline.limited,
-                    new_info.timeline.events,
-                    new_info.timeline.prev_batch,
-                    &push_rules,
-                    &mut user_ids,
-                    &mut room_info,
-                    &mut changes,
-                    &mut ambiguity_cache,
-                )
-                .await?;
+        let push_rules = Arc::new(self.get_push_rules(&changes).await?);
 
-            self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
-                .await;
-
-            #[cfg(feature = "e2e-encryption")]
-            if room_info.is_encrypted() {
-                if let Some(o) = self.olm_machine().await.as_ref() {
-                    if !room.is_encrypted() {
-                        // The room turned on encryption in this sync, we need
-                        // to also get all the existing users and mark them for
-                        // tracking.
-                        let user_ids =
-                            self.store.get_user_ids(&room_id, RoomMemberships::ACTIVE).await?;
-                        o.update_tracked_users(user_ids.iter().map(Deref::deref)).await?
-                    }
+        let total_rooms =
+            response.rooms.join.len() + response.rooms.leave.len() + response.rooms.invite.len();
+        self.sync_progress.set(SyncProgress { processed_rooms: 0, total_rooms });
 
-                    o.update_tracked_users(user_ids.iter().map(Deref::deref)).await?;
-                }
-            }
+        let mut new_rooms = Rooms::default();
 
-            let notification_count = new_info.unread_notifications.into();
-            room_info.update_notification_count(notification_count);
-
-            new_rooms.join.insert(
-                room_id,
-                JoinedRoom::new(
-                    timeline,
-                    new_info.state.events,
-                    new_info.account_data.events,
-                    new_info.ephemeral.events,
-                    notification_count,
-                ),
-            );
+        let joined_rooms = self
+            .process_rooms_concurrently(response.rooms.join, |client, room_id, new_info| {
+                let push_rules = push_rules.clone();
+                async move { client.process_joined_room(room_id, new_info, &push_rules).await }
+            })
+            .await?;
 
-            changes.add_room(room_info);
+        for processed_room in joined_rooms {
+            changes.merge(processed_room.changes);
+            ambiguity_cache.merge(processed_room.ambiguity_cache);
+            new_rooms.join.insert(processed_room.room_id, processed_room.update);
         }
 
-        for (room_id, new_info) in response.rooms.leave {
-            let room = self.store.get_or_create_room(&room_id, RoomState::Left).await;
-            let mut room_info = room.clone_info();
-            room_info.mark_as_left();
-            room_info.mark_state_partially_synced();
-
-            let deserialized_events = Self::deserialize_events(&new_info.state.events);
-
-            let mut user_ids = self
-                .handle_state(
-                    &new_info.state.events,
-                    &deserialized_events,
-                    &mut room_info,
-                    &mut changes,
-                    &mut ambiguity_cache,
-                )
-                .await?;
-
-            let timeline = self
-                .handle_timeline(
-                    &room,
-                    new_info.timeline.limited,
-                    new_info.timeline.events,
-                    new_info.timeline.prev_batch,
-                    &push_rules,
-                    &mut user_ids,
-                    &mut room_info,
-                    &mut changes,
-                    &mut ambiguity_cache,
-                )
-                .await?;
-
-            self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
-                .await;
+        let left_rooms = self
+            .process_rooms_concurrently(response.rooms.leave, |client, room_id, new_info| {
+                let push_rules = push_rules.clone();
+                async move { client.process_left_room(room_id, new_info, &push_rules).await }
+            })
+            .await?;
 
-            changes.add_room(room_info);
-            new_rooms.leave.insert(
-                room_id,
-                LeftRoom::new(timeline, new_info.state.events, new_info.account_data.events),
-            );
+        for processed_room in left_rooms {
+            changes.merge(processed_room.changes);
+            ambiguity_cache.merge(processed_room.ambiguity_cache);
+            new_rooms.leave.insert(processed_room.room_id, processed_room.update);
         }
 
         for (room_id, new_info) in response.rooms.invite {
@@ -853,6 +1026,7 @@ impl BaseClient {
             changes.add_room(room_info);
 
             new_rooms.invite.insert(room_id, new_info);
+            self.sync_progress.update(|progress| progress.processed_rooms += 1);
         }
 
         // TODO remove this, we're processing account data events here again
@@ -873,7 +1047,10 @@ impl BaseClient {
 
         changes.ambiguity_maps = ambiguity_cache.cache;
 
+        self.apply_sync_processing_policy(&mut changes);
+
         let sync_lock = self.sync_lock().write().await;
+        self.run_sync_response_processors(&mut changes).await?;
         self.store.save_changes(&changes).await?;
         *self.store.sync_token.write().await = Some(response.next_batch.clone());
         self.apply_changes(&changes).await;
@@ -899,6 +1076,214 @@ impl BaseClient {
         Ok(response)
     }
 
+    /// Process the given rooms of a sync response on a pool of tasks, with at
+    /// most [`MAX_CONCURRENT_ROOM_PROCESSING`] rooms being processed at the
+    /// same time.
+    ///
+    /// The processed rooms are returned in the same order as the given rooms,
+    /// so they can be merged deterministically.
+    pub(crate) async fn process_rooms_concurrently<R, T, F, Fut>(
+        &self,
+        rooms: impl IntoIterator<Item = (OwnedRoomId, R)>,
+        process: F,
+    ) -> Result<Vec<ProcessedRoom<T>>>
+    where
+        F: Fn(BaseClient, OwnedRoomId, R) -> Fut,
+        Fut: Future<Output = Result<ProcessedRoom<T>>> + SendOutsideWasm + 'static,
+        T: SendOutsideWasm + 'static,
+    {
+        let mut rooms = rooms.into_iter();
+        let mut tasks = VecDeque::with_capacity(MAX_CONCURRENT_ROOM_PROCESSING);
+        let mut processed_rooms = Vec::new();
+
+        loop {
+            let available_slots = MAX_CONCURRENT_ROOM_PROCESSING - tasks.len();
+            tasks.extend(rooms.by_ref().take(available_slots).map(|(room_id, room)| {
+                spawn(process(self.clone(), room_id, room).instrument(Span::current()))
+            }));
+
+            let Some(task) = tasks.pop_front() else {
+                break;
+            };
+
+            match task.await.map_err(Error::from).and_then(|result| result) {
+                Ok(processed_room) => {
+                    processed_rooms.push(processed_room);
+                    self.sync_progress.update(|progress| progress.processed_rooms += 1);
+                }
+                Err(error) => {
+                    // Wait for the rooms that are already being processed, so they don't
+                    // keep running in the background once the error is returned.
+                    for task in tasks {
+                        _ = task.await;
+                    }
+
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(processed_rooms)
+    }
+
+    #[instrument(skip_all, fields(?room_id))]
+    async fn process_joined_room(
+        &self,
+        room_id: OwnedRoomId,
+        new_info: api::sync::sync_events::v3::JoinedRoom,
+        push_rules: &Ruleset,
+    ) -> Result<ProcessedRoom<JoinedRoom>> {
+        let mut changes = StateChanges::default();
+        let mut ambiguity_cache = AmbiguityCache::new(self.store.inner.clone());
+
+        let room = self.store.get_or_create_room(&room_id, RoomState::Joined).await;
+        let mut room_info = room.clone_info();
+        room_info.mark_as_joined();
+
+        room_info.update_summary(&new_info.summary);
+        room_info.set_prev_batch(new_info.timeline.prev_batch.as_deref());
+        room_info.mark_state_fully_synced();
+
+        // Before the new state, so it isn't replaced by older events.
+        #[cfg(feature = "experimental-encrypted-state-events")]
+        self.retry_encrypted_state(&mut room_info, &mut changes).await?;
+
+        let deserialized_events = Self::deserialize_events(&new_info.state.events);
+
+        let mut user_ids = self
+            .handle_state(
+                &new_info.state.events,
+                &deserialized_events,
+                &mut room_info,
+                &mut changes,
+                &mut ambiguity_cache,
+            )
+            .await?;
+
+        for raw in &new_info.ephemeral.events {
+            match raw.deserialize() {
+                Ok(AnySyncEphemeralRoomEvent::Receipt(event)) => {
+                    changes.add_receipts(&room_id, event.content);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let event_id: Option<String> = raw.get_field("event_id").ok().flatten();
+                    #[rustfmt::skip]
+                    info!(
+                        ?room_id, event_id,
+                        "Failed to deserialize ephemeral room event: {e}"
+                    );
+                }
+            }
+        }
+
+        if new_info.timeline.limited {
+            room_info.mark_members_missing();
+        }
+
+        let timeline = self
+            .handle_timeline(
+                &room,
+                new_info.timeline.limited,
+                new_info.timeline.events,
+                new_info.timeline.prev_batch,
+                push_rules,
+                &mut user_ids,
+                &mut room_info,
+                &mut changes,
+                &mut ambiguity_cache,
+            )
+            .await?;
+
+        self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes).await;
+
+        #[cfg(feature = "e2e-encryption")]
+        if room_info.is_encrypted() {
+            if let Some(o) = self.olm_machine().await.as_ref() {
+                if !room.is_encrypted() {
+                    // The room turned on encryption in this sync, we need
+                    // to also get all the existing users and mark them for
+                    // tracking.
+                    let user_ids =
+                        self.store.get_user_ids(&room_id, RoomMemberships::ACTIVE).await?;
+                    o.update_tracked_users(user_ids.iter().map(Deref::deref)).await?
+                }
+
+                o.update_tracked_users(user_ids.iter().map(Deref::deref)).await?;
+            }
+        }
+
+        let notification_count = new_info.unread_notifications.into();
+        room_info.update_notification_count(notification_count);
+
+        changes.add_room(room_info);
+
+        let joined_room = JoinedRoom::new(
+            timeline,
+            new_info.state.events,
+            new_info.account_data.events,
+            new_info.ephemeral.events,
+            notification_count,
+        );
+
+        Ok(ProcessedRoom { room_id, update: joined_room, changes, ambiguity_cache })
+    }
+
+    #[instrument(skip_all, fields(?room_id))]
+    async fn process_left_room(
+        &self,
+        room_id: OwnedRoomId,
+        new_info: api::sync::sync_events::v3::LeftRoom,
+        push_rules: &Ruleset,
+    ) -> Result<ProcessedRoom<LeftRoom>> {
+        let mut changes = StateChanges::default();
+        let mut ambiguity_cache = AmbiguityCache::new(self.store.inner.clone());
+
+        let room = self.store.get_or_create_room(&room_id, RoomState::Left).await;
+        let mut room_info = room.clone_info();
+        room_info.mark_as_left();
+        room_info.mark_state_partially_synced();
+
+        // Before the new state, so it isn't replaced by older events.
+        #[cfg(feature = "experimental-encrypted-state-events")]
+        self.retry_encrypted_state(&mut room_info, &mut changes).await?;
+
+        let deserialized_events = Self::deserialize_events(&new_info.state.events);
+
+        let mut user_ids = self
+            .handle_state(
+                &new_info.state.events,
+                &deserialized_events,
+                &mut room_info,
+                &mut changes,
+                &mut ambiguity_cache,
+            )
+            .await?;
+
+        let timeline = self
+            .handle_timeline(
+                &room,
+                new_info.timeline.limited,
+                new_info.timeline.events,
+                new_info.timeline.prev_batch,
+                push_rules,
+                &mut user_ids,
+                &mut room_info,
+                &mut changes,
+                &mut ambiguity_cache,
+            )
+            .await?;
+
+        self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes).await;
+
+        changes.add_room(room_info);
+
+        let left_room =
+            LeftRoom::new(timeline, new_info.state.events, new_info.account_data.events);
+
+        Ok(ProcessedRoom { room_id, update: left_room, changes, ambiguity_cache })
+    }
+
     pub(crate) async fn apply_changes(&self, changes: &StateChanges) {
         if changes.account_data.contains_key(&GlobalAccountDataEventType::IgnoredUserList) {
             self.ignore_user_list_changes_tx.set(());
@@ -908,6 +1293,58 @@ impl BaseClient {
                 room.update_summary(room_info.clone())
             }
         }
+
+        self.update_display_names(changes).await;
+        self.store.broadcast_state_changes(changes);
+    }
+
+    /// Save the given changes in the state store, and apply them to the
+    /// cached rooms.
+    ///
+    /// The changes are then sent to the receivers of
+    /// [`BaseClient::subscribe_to_state_changes()`].
+    pub async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
+        self.store.save_changes(changes).await?;
+        self.apply_changes(changes).await;
+        Ok(())
+    }
+
+    /// Mark the display names of the rooms as outdated if they depend on the
+    /// member events of the given changes, and calculate again the ones that
+    /// are observed.
+    async fn update_display_names(&self, changes: &StateChanges) {
+        fn member_ids<T>(
+            events: &BTreeMap<StateEventType, BTreeMap<String, T>>,
+        ) -> impl Iterator<Item = &UserId> {
+            events
+                .get(&StateEventType::RoomMember)
+                .into_iter()
+                .flat_map(|events| events.keys())
+                .filter_map(|state_key| <&UserId>::try_from(state_key.as_str()).ok())
+        }
+
+        for (room_id, events) in &changes.state {
+            if let Some(room) = self.store.get_room(room_id) {
+                room.members_changed(member_ids(events));
+            }
+        }
+        for (room_id, events) in &changes.stripped_state {
+            if let Some(room) = self.store.get_room(room_id) {
+                room.members_changed(member_ids(events));
+            }
+        }
+
+        let room_ids: BTreeSet<_> = changes
+            .room_infos
+            .keys()
+            .chain(changes.state.keys())
+            .chain(changes.stripped_state.keys())
+            .collect();
+        for room_id in room_ids {
+            if let Some(room) = self.store.get_room(room_id) {
+                room.refresh_observed_display_name().await;
+            }
+        }
     }
 
     /// Receive a get member events response and convert it to a deserialized
@@ -996,6 +1433,7 @@ impl BaseClient {
 
             changes.ambiguity_maps = ambiguity_cache.cache;
             changes.add_room(room_info);
+            self.apply_sync_processing_policy(&mut changes);
 
             self.store.save_changes(&changes).await?;
             self.apply_changes(&changes).await;
@@ -1007,24 +1445,93 @@ impl BaseClient {
         })
     }
 
-    /// Receive a successful filter upload response, the filter id will be
-    /// stored under the given name in the store.
-    ///
-    /// The filter id can later be retrieved with the [`get_filter`] method.
+    /// Receive the current state of a room, to peek into it without being a
+    /// member.
     ///
+    /// The state events are only kept in memory, in a store dedicated to the
+    /// returned room, so the members of the room can be looked up without
+    /// touching the store of the client. The returned room is not added to the
+    /// rooms of the client, it is only valid as long as it is kept around, and
+    /// is considered as left.
     ///
     /// # Arguments
     ///
-    /// * `filter_name` - The name that should be used to persist the filter id
-    ///   in
-    /// the store.
+    /// * `room_id` - The ID of the room the state belongs to.
     ///
-    /// * `response` - The successful filter upload response containing the
-    /// filter id.
+    /// * `state` - The state events of the room, as returned by the
+    ///   `/rooms/{roomId}/state` endpoint.
     ///
-    /// [`get_filter`]: #method.get_filter
-    pub async fn receive_filter_upload(
-        &self,
+    /// # Errors
+    ///
+    /// Returns [`Error::AuthenticationRequired`] if the client isn't logged in.
+    #[instrument(skip_all, fields(?room_id))]
+    pub async fn receive_peeked_state(
+        &self,
+        room_id: &RoomId,
+        state: &[Raw<AnyStateEvent>],
+    ) -> Result<Room> {
+        let Some(own_user_id) = self.session_meta().map(|meta| meta.user_id.clone()) else {
+            return Err(Error::AuthenticationRequired);
+        };
+
+        let mut room_info = match self.store.get_room(room_id) {
+            Some(room) => room.clone_info(),
+            None => RoomInfo::new(room_id, RoomState::Left),
+        };
+
+        let peek_store = MemoryStore::new().into_state_store();
+        let room = Room::restore(&own_user_id, peek_store.clone(), room_info.clone());
+
+        let (raw_events, events): (Vec<Raw<AnySyncStateEvent>>, Vec<_>) = state
+            .iter()
+            .filter_map(|raw_event| {
+                let raw_event = raw_event.clone().cast::<AnySyncStateEvent>();
+                match raw_event.deserialize() {
+                    Ok(event) => Some((raw_event, event)),
+                    Err(e) => {
+                        warn!("Couldn't deserialize state event: {e}");
+                        None
+                    }
+                }
+            })
+            .unzip();
+
+        let mut changes = StateChanges::default();
+        let mut ambiguity_cache = AmbiguityCache::new(peek_store.clone());
+
+        self.handle_state(&raw_events, &events, &mut room_info, &mut changes, &mut ambiguity_cache)
+            .await?;
+
+        // We received the full state, including all the members.
+        room_info.mark_state_fully_synced();
+        room_info.mark_members_synced();
+        changes.ambiguity_maps = ambiguity_cache.cache;
+        changes.add_room(room_info.clone());
+
+        peek_store.save_changes(&changes).await?;
+        room.update_summary(room_info);
+
+        Ok(room)
+    }
+
+    /// Receive a successful filter upload response, the filter id will be
+    /// stored under the given name in the store.
+    ///
+    /// The filter id can later be retrieved with the [`get_filter`] method.
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `filter_name` - The name that should be used to persist the filter id
+    ///   in
+    /// the store.
+    ///
+    /// * `response` - The successful filter upload response containing the
+    /// filter id.
+    ///
+    /// [`get_filter`]: #method.get_filter
+    pub async fn receive_filter_upload(
+        &self,
         filter_name: &str,
         response: &api::filter::create_filter::v3::Response,
     ) -> Result<()> {
@@ -1079,7 +1586,24 @@ impl BaseClient {
                 let members = self.store.get_user_ids(room_id, filter).await?;
 
                 let settings = settings.ok_or(Error::EncryptionNotEnabled)?;
-                let settings = EncryptionSettings::new(settings, history_visibility, false);
+
+                // The settings of the room can only make the global ones
+                // stricter.
+                let store = o.store();
+                let room_settings = store.get_room_settings(room_id).await?.unwrap_or_default();
+                let only_allow_trusted_devices = room_settings.only_allow_trusted_devices
+                    || store.get_only_allow_trusted_devices().await?;
+                let trust_requirement =
+                    room_settings.trust_requirement.max(store.get_trust_requirement().await?);
+
+                let settings = EncryptionSettings {
+                    trust_requirement,
+                    ..EncryptionSettings::new(
+                        settings,
+                        history_visibility,
+                        only_allow_trusted_devices,
+                    )
+                };
 
                 Ok(o.share_room_key(room_id, members.iter().map(Deref::deref), settings).await?)
             }
@@ -1102,6 +1626,32 @@ impl BaseClient {
         self.olm_machine.read().await
     }
 
+    /// Set the sink that is notified of every successful decryption of a room
+    /// event, or remove it with `None`.
+    ///
+    /// The sink is kept for the `OlmMachine` created when the client logs in.
+    #[cfg(feature = "decryption-audit")]
+    pub async fn set_decryption_audit_sink(&self, sink: Option<Arc<dyn DecryptionAuditSink>>) {
+        *self.decryption_audit_sink.write().unwrap() = sink.clone();
+
+        if let Some(olm_machine) = self.olm_machine.read().await.as_ref() {
+            olm_machine.set_decryption_audit_sink(sink);
+        }
+    }
+
+    /// Set the hook that is notified of the room events that couldn't be
+    /// decrypted, or remove it with `None`.
+    ///
+    /// The hook is kept for the `OlmMachine` created when the client logs in.
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn set_utd_telemetry_hook(&self, hook: Option<Arc<dyn UtdTelemetryHook>>) {
+        *self.utd_telemetry_hook.write().unwrap() = hook.clone();
+
+        if let Some(olm_machine) = self.olm_machine.read().await.as_ref() {
+            olm_machine.set_utd_telemetry_hook(hook);
+        }
+    }
+
     /// Get the push rules.
     ///
     /// Gets the push rules from `changes` if they have been updated, otherwise
@@ -1128,6 +1678,32 @@ impl BaseClient {
         }
     }
 
+    /// Get the power levels of the given room, from `changes` or from the
+    /// store.
+    ///
+    /// Returns `None` if the room has no power levels.
+    async fn room_power_levels(
+        &self,
+        room_id: &RoomId,
+        changes: &StateChanges,
+    ) -> StoreResult<Option<RoomPowerLevels>> {
+        if let Some(event) = changes
+            .state
+            .get(room_id)
+            .and_then(|types| types.get(&StateEventType::RoomPowerLevels)?.get(""))
+            .and_then(|e| e.deserialize_as::<RoomPowerLevelsEvent>().ok())
+        {
+            return Ok(Some(event.power_levels()));
+        }
+
+        Ok(self
+            .store
+            .get_state_event_static::<RoomPowerLevelsEventContent>(room_id)
+            .await?
+            .and_then(|e| e.deserialize().ok())
+            .map(|event| event.power_levels()))
+    }
+
     /// Get the push context for the given room.
     ///
     /// Tries to get the data from `changes` or the up to date `room_info`.
@@ -1164,22 +1740,9 @@ impl BaseClient {
             return Ok(None);
         };
 
-        let room_power_levels = if let Some(event) = changes
-            .state
-            .get(room_id)
-            .and_then(|types| types.get(&StateEventType::RoomPowerLevels)?.get(""))
-            .and_then(|e| e.deserialize_as::<RoomPowerLevelsEvent>().ok())
-        {
-            event.power_levels()
-        } else if let Some(event) = self
-            .store
-            .get_state_event_static::<RoomPowerLevelsEventContent>(room_id)
-            .await?
-            .and_then(|e| e.deserialize().ok())
-        {
-            event.power_levels()
-        } else {
-            return Ok(None);
+        let room_power_levels = match self.room_power_levels(room_id, changes).await? {
+            Some(room_power_levels) => room_power_levels,
+            None => return Ok(None),
         };
 
         Ok(Some(PushConditionRoomCtx {
@@ -1241,6 +1804,75 @@ impl BaseClient {
         self.ignore_user_list_changes_tx.subscribe()
     }
 
+    /// Returns a subscriber that publishes the progress of the processing of
+    /// the rooms of the sync responses.
+    ///
+    /// The rooms of a sync response are processed concurrently, this allows to
+    /// show how far the processing of a large response, like the one of an
+    /// initial sync, is.
+    pub fn subscribe_to_sync_progress(&self) -> Subscriber<SyncProgress> {
+        self.sync_progress.subscribe()
+    }
+
+    /// Add a processor that is called with the changes of every sync response,
+    /// before they are saved in the state store.
+    ///
+    /// The processors are called in the order they were added, after the
+    /// SDK's own processing. See [`SyncResponseProcessor`] for more details.
+    pub fn add_sync_response_processor(&self, processor: Arc<dyn SyncResponseProcessor>) {
+        self.sync_response_processors.write().unwrap().push(processor);
+    }
+
+    /// Set the policy deciding which data of the sync responses, and of the
+    /// responses of the members requests, is saved in the state store.
+    ///
+    /// See [`SyncProcessingPolicy`] for more details.
+    pub fn set_sync_processing_policy(&self, policy: SyncProcessingPolicy) {
+        *self.sync_processing_policy.write().unwrap() = policy;
+    }
+
+    /// Remove the data that must not be saved, according to the sync
+    /// processing policy, from the given changes.
+    pub(crate) fn apply_sync_processing_policy(&self, changes: &mut StateChanges) {
+        let policy = self.sync_processing_policy.read().unwrap();
+        let own_user_id = self.session_meta().map(|meta| &*meta.user_id);
+
+        policy.apply(changes, own_user_id, |room_id| {
+            self.store.get_room(room_id).map(|room| room.clone_info())
+        });
+    }
+
+    /// Call the sync response processors with the given changes.
+    pub(crate) async fn run_sync_response_processors(
+        &self,
+        changes: &mut StateChanges,
+    ) -> Result<()> {
+        let processors = self.sync_response_processors.read().unwrap().clone();
+
+        for processor in processors {
+            processor.process(self.store(), changes).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns a receiver of the changes to the state of the rooms and of the
+    /// account, after they are persisted in the state store and applied to
+    /// the rooms.
+    ///
+    /// This allows to react incrementally to the changes, like new account
+    /// data or room state events, instead of querying the store again after
+    /// every sync response.
+    ///
+    /// If the receiver lags behind, the oldest changes are dropped and it
+    /// receives a [`RecvError::Lagged`] error, after which the store should be
+    /// queried again to get the current state.
+    ///
+    /// [`RecvError::Lagged`]: broadcast::error::RecvError::Lagged
+    pub fn subscribe_to_state_changes(&self) -> broadcast::Receiver<Arc<StateChanges>> {
+        self.store.subscribe_to_state_changes()
+    }
+
     pub(crate) fn deserialize_events(
         raw_events: &[Raw<AnySyncStateEvent>],
     ) -> Vec<AnySyncStateEvent> {
@@ -1265,18 +1897,503 @@ impl Default for BaseClient {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+    use async_trait::async_trait;
     use matrix_sdk_test::{
-        async_test, response_from_file, EventBuilder, InvitedRoomBuilder, LeftRoomBuilder,
-        StrippedStateTestEvent, TimelineTestEvent,
+        async_test, response_from_file, test_json, EphemeralTestEvent, EventBuilder,
+        GlobalAccountDataTestEvent, InvitedRoomBuilder, JoinedRoomBuilder, LeftRoomBuilder,
+        PresenceTestEvent, StateTestEvent, StrippedStateTestEvent, TimelineTestEvent,
     };
     use ruma::{
         api::{client as api, IncomingResponse},
-        room_id, user_id,
+        event_id,
+        events::{
+            receipt::{ReceiptThread, ReceiptType},
+            GlobalAccountDataEventType,
+        },
+        room_id,
+        serde::Raw,
+        user_id, RoomId, TransactionId,
     };
     use serde_json::json;
 
     use super::BaseClient;
-    use crate::{DisplayName, RoomState, SessionMeta};
+    use crate::{
+        error::Error,
+        store::{DynStateStore, Result as StoreResult, StateStoreExt},
+        sync::{SyncProcessingPolicy, SyncProgress, SyncResponseProcessor},
+        DisplayName, RoomState, SessionMeta, StateChanges, StateStoreDataKey, StateStoreDataValue,
+    };
+
+    #[async_test]
+    async fn many_rooms_are_processed_concurrently() {
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id!("@alice:example.org").to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        let progress = client.subscribe_to_sync_progress();
+
+        let mut ev_builder = EventBuilder::new();
+        let room_ids: Vec<_> =
+            (0..50).map(|i| RoomId::parse(format!("!room{i}:example.org")).unwrap()).collect();
+        for room_id in &room_ids {
+            ev_builder.add_joined_room(
+                JoinedRoomBuilder::new(room_id.clone()).add_state_event(StateTestEvent::Member),
+            );
+        }
+        ev_builder.add_left_room(LeftRoomBuilder::new(room_id!("!left:example.org")));
+
+        let response =
+            client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+
+        assert_eq!(response.rooms.join.len(), room_ids.len());
+        assert_eq!(response.rooms.leave.len(), 1);
+        assert_eq!(progress.get(), SyncProgress { processed_rooms: 51, total_rooms: 51 });
+
+        for room_id in &room_ids {
+            assert_eq!(client.get_room(room_id).unwrap().state(), RoomState::Joined);
+            let member = client
+                .store()
+                .get_member_event(room_id, user_id!("@example:localhost"))
+                .await
+                .unwrap();
+            assert!(member.is_some(), "the member event of {room_id} wasn't saved");
+        }
+    }
+
+    #[async_test]
+    async fn state_changes_are_broadcast() {
+        let room_id = room_id!("!test:example.org");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id!("@alice:example.org").to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        let mut state_changes = client.subscribe_to_state_changes();
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder
+            .add_joined_room(
+                JoinedRoomBuilder::new(room_id).add_state_event(StateTestEvent::Member),
+            )
+            .add_global_account_data_event(GlobalAccountDataTestEvent::Direct);
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+
+        let changes = state_changes.try_recv().unwrap();
+        assert!(changes.state.get(room_id).is_some());
+        assert!(changes.room_infos.get(room_id).is_some());
+        assert!(changes.account_data.contains_key(&GlobalAccountDataEventType::Direct));
+    }
+
+    #[async_test]
+    async fn state_changes_are_broadcast_outside_of_sync() {
+        let room_id = room_id!("!test:example.org");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id!("@alice:example.org").to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        let mut state_changes = client.subscribe_to_state_changes();
+
+        let room = client.room_joined(room_id).await.unwrap();
+        let changes = state_changes.try_recv().unwrap();
+        assert_eq!(changes.room_infos[room_id].state(), RoomState::Joined);
+        assert_eq!(room.state(), RoomState::Joined);
+
+        client.room_left(room_id).await.unwrap();
+        let changes = state_changes.try_recv().unwrap();
+        assert_eq!(changes.room_infos[room_id].state(), RoomState::Left);
+        assert_eq!(room.state(), RoomState::Left);
+    }
+
+    #[async_test]
+    async fn sent_event_ids_are_removed_with_the_remote_echo() {
+        let room_id = room_id!("!test:example.org");
+        let user_id = user_id!("@alice:example.org");
+        let txn_id = TransactionId::new();
+        let event_id = event_id!("$sent");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id.to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        client
+            .store()
+            .set_kv_data(
+                StateStoreDataKey::SentEvent(&txn_id),
+                StateStoreDataValue::SentEvent(event_id.to_owned()),
+            )
+            .await
+            .unwrap();
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_timeline_event(
+            TimelineTestEvent::Custom(json!({
+                "content": { "body": "hello", "msgtype": "m.text" },
+                "event_id": event_id,
+                "origin_server_ts": 152037280,
+                "sender": user_id,
+                "type": "m.room.message",
+                "unsigned": { "transaction_id": txn_id },
+            })),
+        ));
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+
+        assert_matches!(
+            client.store().get_kv_data(StateStoreDataKey::SentEvent(&txn_id)).await,
+            Ok(None)
+        );
+    }
+
+    #[async_test]
+    async fn sync_processing_policy_skips_data() {
+        let big_room_id = room_id!("!big:example.org");
+        let small_room_id = room_id!("!small:example.org");
+        let user_id = user_id!("@example:localhost");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id!("@alice:example.org").to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        client.set_sync_processing_policy(
+            SyncProcessingPolicy::new()
+                .skip_presence()
+                .skip_receipts_above(2)
+                .skip_members_above(2),
+        );
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder
+            .add_joined_room(
+                JoinedRoomBuilder::new(big_room_id)
+                    .set_room_summary(json!({ "m.joined_member_count": 3 }))
+                    .add_state_event(StateTestEvent::Member)
+                    .add_ephemeral_event(EphemeralTestEvent::ReadReceipt),
+            )
+            .add_joined_room(
+                JoinedRoomBuilder::new(small_room_id)
+                    .add_state_event(StateTestEvent::Member)
+                    .add_ephemeral_event(EphemeralTestEvent::ReadReceipt),
+            )
+            .add_presence_event(PresenceTestEvent::Presence);
+        let response =
+            client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+
+        // The skipped data is still in the response.
+        assert_eq!(response.presence.len(), 1);
+        assert!(client.store().get_presence_event(user_id).await.unwrap().is_none());
+
+        for (room_id, is_saved) in [(big_room_id, false), (small_room_id, true)] {
+            let receipt = client
+                .store()
+                .get_user_room_receipt_event(
+                    room_id,
+                    ReceiptType::Read,
+                    ReceiptThread::Unthreaded,
+                    user_id,
+                )
+                .await
+                .unwrap();
+            assert_eq!(receipt.is_some(), is_saved);
+
+            let member = client.store().get_member_event(room_id, user_id).await.unwrap();
+            assert_eq!(member.is_some(), is_saved);
+        }
+    }
+
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    #[async_test]
+    async fn encrypted_state_events_are_checked_and_retried() {
+        use std::iter;
+
+        use matrix_sdk_crypto::{EncryptionSettings, OlmMachine};
+        use ruma::device_id;
+        use serde_json::Value as JsonValue;
+
+        use crate::encrypted_state::pack_state_key;
+
+        /// Encrypt a state event with the current room key of the given machine.
+        async fn encrypted_state_event(
+            olm: &OlmMachine,
+            event_id: &str,
+            event_type: &str,
+            state_key: &str,
+            content: JsonValue,
+        ) -> JsonValue {
+            let room_id = room_id!("!encrypted_state:example.org");
+            let content = olm.encrypt_room_event_raw(room_id, content, event_type).await.unwrap();
+            json!({
+                "type": "m.room.encrypted",
+                "state_key": pack_state_key(event_type, state_key),
+                "event_id": event_id,
+                "sender": "@alice:example.org",
+                "origin_server_ts": 0,
+                "content": content,
+            })
+        }
+
+        let room_id = room_id!("!encrypted_state:example.org");
+        let alice = user_id!("@alice:example.org");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta { user_id: alice.to_owned(), device_id: "FOOBAR".into() })
+            .await
+            .unwrap();
+        let olm = client.olm_machine().await;
+        let olm = olm.as_ref().unwrap();
+        olm.share_room_key(room_id, iter::empty(), EncryptionSettings::default()).await.unwrap();
+
+        // Another device of Alice, whose room key isn't known yet.
+        let other_olm = OlmMachine::new(alice, device_id!("OTHERDEVICE")).await;
+        other_olm
+            .share_room_key(room_id, iter::empty(), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let power_levels = |alice_level: i64| {
+            StateTestEvent::Custom(json!({
+                "type": "m.room.power_levels",
+                "state_key": "",
+                "event_id": format!("$power_levels_{alice_level}"),
+                "sender": "@alice:example.org",
+                "origin_server_ts": 0,
+                "content": { "users": { "@alice:example.org": alice_level } },
+            }))
+        };
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder.add_joined_room(
+            JoinedRoomBuilder::new(room_id)
+                .add_state_event(StateTestEvent::Encryption)
+                .add_state_event(power_levels(100))
+                .add_state_event(StateTestEvent::Custom(
+                    encrypted_state_event(
+                        olm,
+                        "$topic",
+                        "m.room.topic",
+                        "",
+                        json!({ "topic": "Secret plans" }),
+                    )
+                    .await,
+                ))
+                .add_state_event(StateTestEvent::Custom(
+                    encrypted_state_event(
+                        &other_olm,
+                        "$name",
+                        "m.room.name",
+                        "",
+                        json!({ "name": "Secret room" }),
+                    )
+                    .await,
+                ))
+                // Alice tries to replace the state of Bob.
+                .add_timeline_event(TimelineTestEvent::Custom(
+                    encrypted_state_event(
+                        olm,
+                        "$status",
+                        "org.example.status",
+                        "@bob:example.org",
+                        json!({ "status": "away" }),
+                    )
+                    .await,
+                )),
+        );
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+
+        let room = client.get_room(room_id).unwrap();
+        assert_eq!(room.topic().as_deref(), Some("Secret plans"));
+        assert_eq!(room.name(), None);
+        let status = client
+            .store()
+            .get_state_event(room_id, "org.example.status".into(), "@bob:example.org")
+            .await
+            .unwrap();
+        assert!(status.is_none());
+
+        // The name is decrypted in the next sync, once its room key arrived.
+        let keys = other_olm.export_room_keys(|_| true).await.unwrap();
+        olm.import_room_keys(keys, false, |_, _| {}).await.unwrap();
+
+        ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id));
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+        assert_eq!(room.name().as_deref(), Some("Secret room"));
+
+        // Alice can't change the topic anymore.
+        ev_builder.add_joined_room(
+            JoinedRoomBuilder::new(room_id).add_state_event(power_levels(0)).add_state_event(
+                StateTestEvent::Custom(
+                    encrypted_state_event(
+                        olm,
+                        "$new_topic",
+                        "m.room.topic",
+                        "",
+                        json!({ "topic": "Public plans" }),
+                    )
+                    .await,
+                ),
+            ),
+        );
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+        assert_eq!(room.topic().as_deref(), Some("Secret plans"));
+    }
+
+    #[async_test]
+    async fn sync_processing_policy_keeps_members_of_encrypted_rooms() {
+        let room_id = room_id!("!big:example.org");
+        let user_id = user_id!("@example:localhost");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id!("@alice:example.org").to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        client.set_sync_processing_policy(SyncProcessingPolicy::new().skip_members_above(2));
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder.add_joined_room(
+            JoinedRoomBuilder::new(room_id)
+                .set_room_summary(json!({ "m.joined_member_count": 3 }))
+                .add_state_event(StateTestEvent::Encryption)
+                .add_state_event(StateTestEvent::Member),
+        );
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+
+        // The members are needed to share the room keys.
+        assert!(client.get_room(room_id).unwrap().is_encrypted());
+        let member = client.store().get_member_event(room_id, user_id).await.unwrap();
+        assert!(member.is_some());
+    }
+
+    #[async_test]
+    async fn sync_processing_policy_refetches_members_when_encryption_is_enabled() {
+        let room_id = room_id!("!big:example.org");
+        let user_id = user_id!("@example:localhost");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id!("@alice:example.org").to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        client.set_sync_processing_policy(SyncProcessingPolicy::new().skip_members_above(2));
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder.add_joined_room(
+            JoinedRoomBuilder::new(room_id)
+                .set_room_summary(json!({ "m.joined_member_count": 3 }))
+                .add_state_event(StateTestEvent::Member),
+        );
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+
+        // The display name of the skipped member isn't saved either.
+        assert!(client.store().get_member_event(room_id, user_id).await.unwrap().is_none());
+        let users = client.store().get_users_with_display_name(room_id, "example").await.unwrap();
+        assert!(users.is_empty());
+
+        let mut member = test_json::sync_events::MEMBER.clone();
+        member["room_id"] = json!(room_id);
+        let response =
+            api::membership::get_member_events::v3::Response::new(vec![Raw::new(&member)
+                .unwrap()
+                .cast()]);
+        client.receive_members(room_id, &response).await.unwrap();
+
+        let room = client.get_room(room_id).unwrap();
+        assert!(room.are_members_synced());
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder.add_joined_room(
+            JoinedRoomBuilder::new(room_id).add_state_event(StateTestEvent::Encryption),
+        );
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+
+        // The members must be fetched again to share the room keys with them.
+        assert!(room.is_encrypted());
+        assert!(!room.are_members_synced());
+    }
+
+    #[async_test]
+    async fn sync_response_processors_save_custom_values() {
+        /// Counts the sync responses with state events, in a custom value.
+        #[derive(Debug)]
+        struct StateCounter;
+
+        #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+        #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+        impl SyncResponseProcessor for StateCounter {
+            async fn process(
+                &self,
+                store: &DynStateStore,
+                changes: &mut StateChanges,
+            ) -> StoreResult<()> {
+                if changes.state.is_empty() {
+                    return Ok(());
+                }
+
+                let count = store.get_custom_value(b"state_count").await?.map_or(0, |v| v[0]);
+                changes.set_custom_value(b"state_count", vec![count + 1]);
+                Ok(())
+            }
+        }
+
+        let room_id = room_id!("!test:example.org");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id!("@alice:example.org").to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        client.add_sync_response_processor(Arc::new(StateCounter));
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder.add_joined_room(
+            JoinedRoomBuilder::new(room_id).add_state_event(StateTestEvent::Member),
+        );
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+        assert_eq!(client.store().get_custom_value(b"state_count").await.unwrap(), Some(vec![1]));
+
+        // A response without state doesn't change the value.
+        ev_builder.add_global_account_data_event(GlobalAccountDataTestEvent::Direct);
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+        assert_eq!(client.store().get_custom_value(b"state_count").await.unwrap(), Some(vec![1]));
+
+        ev_builder.add_joined_room(
+            JoinedRoomBuilder::new(room_id).add_state_event(StateTestEvent::Topic),
+        );
+        client.receive_sync_response(ev_builder.build_sync_response()).await.unwrap();
+        assert_eq!(client.store().get_custom_value(b"state_count").await.unwrap(), Some(vec![2]));
+    }
 
     #[async_test]
     async fn invite_after_leaving() {
@@ -1331,6 +2448,69 @@ mod tests {
         assert_eq!(client.get_room(room_id).unwrap().state(), RoomState::Invited);
     }
 
+    #[async_test]
+    async fn peeked_room_is_not_added_to_rooms() {
+        let user_id = user_id!("@alice:example.org");
+        let room_id = room_id!("!peeked:example.org");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id.to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+
+        let state = [
+            Raw::new(&json!({
+                "content": { "name": "Public archive" },
+                "event_id": "$name:example.org",
+                "origin_server_ts": 1432135524678u64,
+                "room_id": room_id,
+                "sender": "@example:example.org",
+                "state_key": "",
+                "type": "m.room.name",
+            }))
+            .unwrap()
+            .cast(),
+            Raw::new(&json!({
+                "content": { "displayname": "Example", "membership": "join" },
+                "event_id": "$member:example.org",
+                "origin_server_ts": 1432135524678u64,
+                "room_id": room_id,
+                "sender": "@example:example.org",
+                "state_key": "@example:example.org",
+                "type": "m.room.member",
+            }))
+            .unwrap()
+            .cast(),
+        ];
+
+        let room = client.receive_peeked_state(room_id, &state).await.unwrap();
+        assert_eq!(room.state(), RoomState::Left);
+        assert_eq!(room.name().as_deref(), Some("Public archive"));
+        assert!(client.get_room(room_id).is_none());
+
+        let member = room.get_member(user_id!("@example:example.org")).await.unwrap().unwrap();
+        assert_eq!(member.display_name(), Some("Example"));
+
+        // The peeked state isn't persisted in the store of the client.
+        assert!(client
+            .store()
+            .get_member_event(room_id, user_id!("@example:example.org"))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[async_test]
+    async fn peeking_requires_a_session() {
+        let client = BaseClient::new();
+        let result = client.receive_peeked_state(room_id!("!peeked:example.org"), &[]).await;
+        assert_matches!(result, Err(Error::AuthenticationRequired));
+    }
+
     #[async_test]
     async fn invite_displayname_integration_test() {
         let user_id = user_id!("@alice:example.org");
@@ -0,0 +1,411 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for the experimental encrypted state events ([MSC3414]).
+//!
+//! An encrypted state event is sent as an `m.room.encrypted` state event, with
+//! a state key made of the type and the state key of the plaintext event,
+//! separated by a colon. This way, the homeserver still resolves the state
+//! correctly without knowing the type of the event.
+//!
+//! The state events that the homeserver needs to authorize events, like the
+//! membership or the power levels, can't be encrypted.
+//!
+//! [MSC3414]: https://github.com/matrix-org/matrix-spec-proposals/pull/3414
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use ruma::{
+    events::{
+        room::power_levels::RoomPowerLevels, AnySyncStateEvent, AnySyncTimelineEvent,
+        AnyTimelineEvent, StateEventType,
+    },
+    serde::Raw,
+    OwnedEventId, OwnedRoomId, OwnedUserId, RoomId,
+};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use thiserror::Error;
+
+/// The field of the content of the `m.room.encryption` event that tells
+/// whether the participants of the room agreed to encrypt state events.
+pub const ENCRYPT_STATE_EVENTS_FIELD: &str = "io.element.msc3414.encrypt_state_events";
+
+/// The type of the encrypted events.
+pub(crate) const ENCRYPTED_EVENT_TYPE: &str = "m.room.encrypted";
+
+/// An error that occurred when processing a decrypted state event.
+#[derive(Debug, Error)]
+pub enum EncryptedStateError {
+    /// The state key of the encrypted event is not made of a type and a state
+    /// key.
+    #[error("the state key of the encrypted event is invalid: {0}")]
+    InvalidStateKey(String),
+
+    /// The type of the decrypted event doesn't match the state key of the
+    /// encrypted event, so the sender may try to replace another piece of
+    /// state.
+    #[error("the decrypted event has type {found}, but the state key is for {expected}")]
+    MismatchedType {
+        /// The type in the state key of the encrypted event.
+        expected: String,
+        /// The type of the decrypted event.
+        found: String,
+    },
+
+    /// The decrypted event has a type that must not be encrypted.
+    #[error("state events of type {0} can't be encrypted")]
+    NotEncryptable(String),
+
+    /// The state key of the decrypted event is the ID of another user than the
+    /// sender, so the sender may try to replace the state of that user.
+    #[error("{sender} can't send a state event with the state key {state_key}")]
+    ForeignStateKey {
+        /// The sender of the encrypted event.
+        sender: String,
+        /// The state key of the decrypted event.
+        state_key: String,
+    },
+
+    /// The sender of the decrypted event isn't allowed to send state events of
+    /// its type.
+    #[error("{sender} isn't allowed to send state events of type {event_type}")]
+    Unauthorized {
+        /// The sender of the encrypted event.
+        sender: OwnedUserId,
+        /// The type of the decrypted event.
+        event_type: StateEventType,
+    },
+
+    /// The decrypted event is not a valid JSON object.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Whether state events of the given type can be encrypted.
+///
+/// The state events used by the homeserver to authorize events or to resolve
+/// the state, and the ones needed to decrypt the events, must stay in
+/// plaintext. The types containing a colon can't be represented in the state
+/// key of the encrypted event.
+pub fn can_be_encrypted(event_type: &StateEventType) -> bool {
+    !matches!(
+        event_type,
+        StateEventType::RoomCreate
+            | StateEventType::RoomMember
+            | StateEventType::RoomPowerLevels
+            | StateEventType::RoomJoinRules
+            | StateEventType::RoomThirdPartyInvite
+            | StateEventType::RoomEncryption
+            | StateEventType::RoomHistoryVisibility
+            | StateEventType::RoomServerAcl
+            | StateEventType::RoomTombstone
+    ) && {
+        let event_type = event_type.to_string();
+        event_type != ENCRYPTED_EVENT_TYPE && !event_type.contains(':')
+    }
+}
+
+/// Get the state key of the encrypted event for the given type and state key
+/// of the plaintext event.
+pub fn pack_state_key(event_type: &str, state_key: &str) -> String {
+    format!("{event_type}:{state_key}")
+}
+
+/// Get the type and the state key of the plaintext event from the state key of
+/// the encrypted event.
+pub fn unpack_state_key(packed: &str) -> Option<(&str, &str)> {
+    packed.split_once(':').filter(|(event_type, _)| !event_type.is_empty())
+}
+
+/// Whether the given event is an encrypted state event.
+pub fn is_encrypted_state_event(event: &Raw<AnySyncTimelineEvent>) -> bool {
+    event.get_field::<String>("type").ok().flatten().as_deref() == Some(ENCRYPTED_EVENT_TYPE)
+        && event.get_field::<String>("state_key").ok().flatten().is_some()
+}
+
+/// Get the decrypted state event from the decrypted payload of an encrypted
+/// state event.
+///
+/// The decrypted payload doesn't have a state key, so it is taken from the
+/// encrypted event, after checking that it matches the type of the payload.
+///
+/// The homeserver can't enforce that the state keys starting with `@` belong to
+/// the sender, since it only sees the packed state key, so it is checked here.
+pub fn decrypted_state_event(
+    encrypted: &Raw<AnySyncTimelineEvent>,
+    decrypted: &Raw<AnyTimelineEvent>,
+) -> Result<Raw<AnySyncTimelineEvent>, EncryptedStateError> {
+    let packed: String = encrypted
+        .get_field("state_key")?
+        .ok_or_else(|| EncryptedStateError::InvalidStateKey(String::new()))?;
+    let (expected_type, state_key) = unpack_state_key(&packed)
+        .ok_or_else(|| EncryptedStateError::InvalidStateKey(packed.clone()))?;
+
+    let mut event: JsonMap<String, JsonValue> = decrypted.deserialize_as()?;
+    let event_type = event.get("type").and_then(JsonValue::as_str).unwrap_or_default().to_owned();
+
+    if event_type != expected_type {
+        return Err(EncryptedStateError::MismatchedType {
+            expected: expected_type.to_owned(),
+            found: event_type,
+        });
+    }
+    if !can_be_encrypted(&event_type.as_str().into()) {
+        return Err(EncryptedStateError::NotEncryptable(event_type));
+    }
+
+    let sender: String = encrypted.get_field("sender")?.unwrap_or_default();
+    if state_key.starts_with('@') && state_key != sender {
+        return Err(EncryptedStateError::ForeignStateKey {
+            sender,
+            state_key: state_key.to_owned(),
+        });
+    }
+
+    event.insert("state_key".to_owned(), state_key.into());
+
+    Ok(Raw::new(&event)?.cast())
+}
+
+/// Check that the sender of the given decrypted state event is allowed to send
+/// state events of its type.
+///
+/// The homeserver only checks the power level of the sender for the
+/// `m.room.encrypted` type. Without power levels, every member can send state
+/// events.
+pub fn check_power_level(
+    event: &AnySyncStateEvent,
+    power_levels: Option<&RoomPowerLevels>,
+) -> Result<(), EncryptedStateError> {
+    match power_levels {
+        Some(power_levels)
+            if !power_levels.user_can_send_state(event.sender(), event.event_type()) =>
+        {
+            Err(EncryptedStateError::Unauthorized {
+                sender: event.sender().to_owned(),
+                event_type: event.event_type(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The encrypted state events that couldn't be decrypted yet, by room and by
+/// packed state key.
+///
+/// Only the latest event of each piece of state is kept, so an older event is
+/// never decrypted over a more recent one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PendingEncryptedState {
+    events: Arc<Mutex<BTreeMap<OwnedRoomId, BTreeMap<String, OwnedEventId>>>>,
+}
+
+impl PendingEncryptedState {
+    /// Remember the given encrypted state event, to decrypt it later.
+    pub(crate) fn insert(&self, room_id: &RoomId, event: &Raw<AnySyncTimelineEvent>) {
+        let packed = event.get_field::<String>("state_key").ok().flatten();
+        let event_id = event.get_field::<OwnedEventId>("event_id").ok().flatten();
+
+        if let Some((packed, event_id)) = packed.zip(event_id) {
+            let mut events = self.events.lock().unwrap();
+            events.entry(room_id.to_owned()).or_default().insert(packed, event_id);
+        }
+    }
+
+    /// Forget the pending event with the given packed state key, because a
+    /// more recent event replaced it or it was decrypted.
+    pub(crate) fn remove(&self, room_id: &RoomId, packed: &str) {
+        let mut events = self.events.lock().unwrap();
+        if let Some(room_events) = events.get_mut(room_id) {
+            room_events.remove(packed);
+            if room_events.is_empty() {
+                events.remove(room_id);
+            }
+        }
+    }
+
+    /// Get the pending events of the given room, by packed state key.
+    pub(crate) fn get(&self, room_id: &RoomId) -> BTreeMap<String, OwnedEventId> {
+        self.events.lock().unwrap().get(room_id).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use ruma::{
+        events::{
+            room::power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+            AnySyncStateEvent, StateEventType,
+        },
+        int,
+        serde::Raw,
+        user_id,
+    };
+    use serde_json::json;
+
+    use super::{
+        can_be_encrypted, check_power_level, decrypted_state_event, is_encrypted_state_event,
+        pack_state_key, unpack_state_key, EncryptedStateError,
+    };
+
+    #[test]
+    fn state_key_packing() {
+        let packed = pack_state_key("m.room.name", "");
+        assert_eq!(packed, "m.room.name:");
+        assert_eq!(unpack_state_key(&packed), Some(("m.room.name", "")));
+
+        let packed = pack_state_key("org.example.status", "@alice:example.org");
+        assert_eq!(unpack_state_key(&packed), Some(("org.example.status", "@alice:example.org")));
+
+        assert_eq!(unpack_state_key("m.room.name"), None);
+        assert_eq!(unpack_state_key(":foo"), None);
+    }
+
+    #[test]
+    fn encryptable_types() {
+        assert!(can_be_encrypted(&StateEventType::RoomName));
+        assert!(can_be_encrypted(&StateEventType::RoomTopic));
+        assert!(can_be_encrypted(&"org.example.status".into()));
+
+        assert!(!can_be_encrypted(&StateEventType::RoomMember));
+        assert!(!can_be_encrypted(&StateEventType::RoomPowerLevels));
+        assert!(!can_be_encrypted(&StateEventType::RoomEncryption));
+        assert!(!can_be_encrypted(&"m.room.encrypted".into()));
+        assert!(!can_be_encrypted(&"org.example:status".into()));
+    }
+
+    #[test]
+    fn decrypted_state_event_gets_state_key() {
+        let encrypted = Raw::new(&json!({
+            "type": "m.room.encrypted",
+            "state_key": "m.room.topic:",
+            "event_id": "$topic",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "content": {},
+        }))
+        .unwrap()
+        .cast();
+        assert!(is_encrypted_state_event(&encrypted));
+
+        let decrypted = Raw::new(&json!({
+            "type": "m.room.topic",
+            "room_id": "!room:example.org",
+            "event_id": "$topic",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "content": { "topic": "Secret plans" },
+        }))
+        .unwrap()
+        .cast();
+
+        let event = decrypted_state_event(&encrypted, &decrypted).unwrap();
+        assert_eq!(event.get_field::<String>("state_key").unwrap().as_deref(), Some(""));
+        assert!(!is_encrypted_state_event(&event));
+    }
+
+    #[test]
+    fn decrypted_state_event_with_mismatched_type() {
+        let encrypted = Raw::new(&json!({
+            "type": "m.room.encrypted",
+            "state_key": "m.room.topic:",
+            "content": {},
+        }))
+        .unwrap()
+        .cast();
+
+        // The sender tries to change the power levels with an encrypted topic.
+        let decrypted = Raw::new(&json!({
+            "type": "m.room.power_levels",
+            "room_id": "!room:example.org",
+            "content": {},
+        }))
+        .unwrap()
+        .cast();
+
+        assert_matches!(
+            decrypted_state_event(&encrypted, &decrypted),
+            Err(EncryptedStateError::MismatchedType { .. })
+        );
+    }
+
+    #[test]
+    fn decrypted_state_event_with_foreign_state_key() {
+        let encrypted = Raw::new(&json!({
+            "type": "m.room.encrypted",
+            "state_key": "org.example.call.member:@bob:example.org",
+            "sender": "@alice:example.org",
+            "content": {},
+        }))
+        .unwrap()
+        .cast();
+
+        // Alice tries to replace the state of Bob.
+        let decrypted = Raw::new(&json!({
+            "type": "org.example.call.member",
+            "room_id": "!room:example.org",
+            "content": {},
+        }))
+        .unwrap()
+        .cast();
+
+        assert_matches!(
+            decrypted_state_event(&encrypted, &decrypted),
+            Err(EncryptedStateError::ForeignStateKey { .. })
+        );
+
+        // Her own state is accepted.
+        let encrypted = Raw::new(&json!({
+            "type": "m.room.encrypted",
+            "state_key": "org.example.call.member:@alice:example.org",
+            "sender": "@alice:example.org",
+            "content": {},
+        }))
+        .unwrap()
+        .cast();
+        decrypted_state_event(&encrypted, &decrypted).unwrap();
+    }
+
+    #[test]
+    fn power_level_of_decrypted_state_event() {
+        let event: AnySyncStateEvent = serde_json::from_value(json!({
+            "type": "m.room.topic",
+            "state_key": "",
+            "event_id": "$topic",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "content": { "topic": "Secret plans" },
+        }))
+        .unwrap();
+
+        let mut content = RoomPowerLevelsEventContent::new();
+        content.users.insert(user_id!("@bob:example.org").to_owned(), int!(100));
+        let power_levels = RoomPowerLevels::from(content);
+
+        // The default state level is 50, and Alice has the default user level.
+        assert_matches!(
+            check_power_level(&event, Some(&power_levels)),
+            Err(EncryptedStateError::Unauthorized { .. })
+        );
+        check_power_level(&event, None).unwrap();
+
+        let mut content = RoomPowerLevelsEventContent::new();
+        content.users.insert(user_id!("@alice:example.org").to_owned(), int!(50));
+        check_power_level(&event, Some(&RoomPowerLevels::from(content))).unwrap();
+    }
+}
@@ -15,6 +15,7 @@
 
 //! Error conditions.
 
+use matrix_sdk_common::executor::JoinError;
 #[cfg(feature = "e2e-encryption")]
 use matrix_sdk_crypto::{CryptoStoreError, MegolmError, OlmError};
 use thiserror::Error;
@@ -56,4 +57,13 @@ pub enum Error {
     #[cfg(feature = "e2e-encryption")]
     #[error(transparent)]
     MegolmError(#[from] MegolmError),
+
+    /// The operation requires the client to be logged in.
+    #[error("The client must be logged in")]
+    AuthenticationRequired,
+
+    /// A task processing a room of a sync response panicked or was
+    /// cancelled.
+    #[error("A task processing a room of a sync response failed: {0}")]
+    RoomProcessingTask(#[from] JoinError),
 }
@@ -27,6 +27,8 @@ pub use crate::{
 mod client;
 pub mod debug;
 pub mod deserialized_responses;
+#[cfg(feature = "experimental-encrypted-state-events")]
+pub mod encrypted_state;
 mod error;
 pub mod media;
 mod rooms;
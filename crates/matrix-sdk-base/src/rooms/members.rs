@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::{
+    borrow::Cow,
     collections::{BTreeMap, BTreeSet},
     sync::Arc,
 };
@@ -110,6 +111,19 @@ impl RoomMember {
         }
     }
 
+    /// Get the name of the member, disambiguated with their user ID if another
+    /// member of the room uses the same name.
+    ///
+    /// This returns for example `Alice (@alice:example.org)` if the name is
+    /// [ambiguous](Self::name_ambiguous), like other Matrix clients do.
+    pub fn disambiguated_name(&self) -> Cow<'_, str> {
+        if self.name_ambiguous() {
+            Cow::Owned(format!("{} ({})", self.name(), self.user_id()))
+        } else {
+            Cow::Borrowed(self.name())
+        }
+    }
+
     /// Get the avatar url of the member, if there is one.
     pub fn avatar_url(&self) -> Option<&MxcUri> {
         if let Some(p) = self.profile.as_ref() {
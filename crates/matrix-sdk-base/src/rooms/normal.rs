@@ -14,10 +14,14 @@
 
 use std::{
     collections::{BTreeMap, HashSet},
-    sync::{Arc, RwLock as SyncRwLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock as SyncRwLock,
+    },
 };
 
 use bitflags::bitflags;
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
 use futures_util::stream::{self, StreamExt};
 use ruma::{
     api::client::sync::sync_events::v3::RoomSummary as RumaSummary,
@@ -65,11 +69,16 @@ pub struct Room {
     own_user_id: OwnedUserId,
     inner: Arc<SyncRwLock<RoomInfo>>,
     store: Arc<DynStateStore>,
+    /// The last computed display name of the room.
+    display_name: SharedObservable<Option<DisplayName>>,
+    /// Whether an event that the display name depends on changed since it was
+    /// computed.
+    display_name_outdated: Arc<AtomicBool>,
 }
 
 /// The room summary containing member counts and members that should be used to
 /// calculate the room display name.
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RoomSummary {
     /// The heroes of the room, members that should be used for the room display
     /// name.
@@ -128,6 +137,8 @@ impl Room {
             room_id: room_info.room_id.clone(),
             store,
             inner: Arc::new(SyncRwLock::new(room_info)),
+            display_name: Default::default(),
+            display_name_outdated: Arc::new(AtomicBool::new(true)),
         }
     }
 
@@ -163,7 +174,7 @@ impl Room {
     ///
     /// Returns true if no members are missing, false otherwise.
     pub fn are_members_synced(&self) -> bool {
-        self.inner.read().unwrap().members_synced
+        self.inner.read().unwrap().are_members_synced()
     }
 
     /// Mark this Room as still missing member information.
@@ -337,15 +348,75 @@ impl Room {
         self.inner.read().unwrap().topic().map(ToOwned::to_owned)
     }
 
-    /// Return the cached display name of the room if it was provided via sync,
-    /// or otherwise calculate it, taking into account its name, aliases and
+    /// Return the cached display name of the room if it is up-to-date, or
+    /// otherwise calculate it, taking into account its name, aliases and
     /// members.
     ///
-    /// The display name is calculated according to [this algorithm][spec].
+    /// The display name is calculated according to [this algorithm][spec]. The
+    /// cache is invalidated when the name, the canonical alias, the summary or
+    /// the member events used to calculate it change.
     ///
     /// [spec]: <https://matrix.org/docs/spec/client_server/latest#calculating-the-display-name-for-a-room>
     pub async fn display_name(&self) -> StoreResult<DisplayName> {
-        self.calculate_name().await
+        // Reset the flag before calculating the name, so a change received
+        // during the calculation marks the new name as outdated.
+        if !self.display_name_outdated.swap(false, Ordering::SeqCst) {
+            if let Some(display_name) = self.display_name.get() {
+                return Ok(display_name);
+            }
+        }
+
+        match self.calculate_name().await {
+            Ok(display_name) => {
+                self.display_name.set_if_not_eq(Some(display_name.clone()));
+                Ok(display_name)
+            }
+            Err(error) => {
+                self.display_name_outdated.store(true, Ordering::SeqCst);
+                Err(error)
+            }
+        }
+    }
+
+    /// Subscribe to the display name of the room.
+    ///
+    /// The value is `None` until the display name is calculated for the first
+    /// time with [`Room::display_name()`]. After that, it is calculated again
+    /// after every sync that changes it, as long as there is a subscriber.
+    pub fn subscribe_to_display_name(&self) -> Subscriber<Option<DisplayName>> {
+        self.display_name.subscribe()
+    }
+
+    /// Mark the display name as outdated if it depends on the member events of
+    /// the given users.
+    pub(crate) fn members_changed<'a>(&self, user_ids: impl IntoIterator<Item = &'a UserId>) {
+        let inner = self.inner.read().unwrap();
+        if inner.name().is_some() || inner.canonical_alias().is_some() {
+            return;
+        }
+
+        // Without heroes, the display name is calculated from the first
+        // members of the room, which can be any member.
+        let heroes = &inner.summary.heroes;
+        if heroes.is_empty()
+            || user_ids.into_iter().any(|u| heroes.iter().any(|h| h.as_str() == u.as_str()))
+        {
+            self.display_name_outdated.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Calculate the display name again if it is outdated and someone is
+    /// subscribed to it.
+    pub(crate) async fn refresh_observed_display_name(&self) {
+        if self.display_name.subscriber_count() == 0
+            || !self.display_name_outdated.load(Ordering::SeqCst)
+        {
+            return;
+        }
+
+        if let Err(error) = self.display_name().await {
+            warn!(room_id = ?self.room_id, ?error, "Failed to calculate the display name");
+        }
     }
 
     /// Get the list of users ids that are considered to be joined members of
@@ -509,6 +580,9 @@ impl Room {
     /// Update the summary with given RoomInfo
     pub fn update_summary(&self, summary: RoomInfo) {
         let mut inner = self.inner.write().unwrap();
+        if inner.display_name_inputs_changed(&summary) {
+            self.display_name_outdated.store(true, Ordering::SeqCst);
+        }
         *inner = summary;
     }
 
@@ -867,6 +941,11 @@ impl RoomInfo {
         &self.room_id
     }
 
+    /// Whether all the members of this room are synced.
+    pub fn are_members_synced(&self) -> bool {
+        self.members_synced
+    }
+
     /// Get the room version of this room.
     pub fn room_version(&self) -> Option<&RoomVersionId> {
         Some(&self.base_info.create.as_ref()?.as_original()?.content.room_version)
@@ -916,6 +995,15 @@ impl RoomInfo {
     fn topic(&self) -> Option<&str> {
         Some(&self.base_info.topic.as_ref()?.as_original()?.content.topic)
     }
+
+    /// Whether the display name of the room might be different with the given
+    /// info.
+    fn display_name_inputs_changed(&self, other: &RoomInfo) -> bool {
+        self.name() != other.name()
+            || self.canonical_alias() != other.canonical_alias()
+            || self.room_state != other.room_state
+            || self.summary != other.summary
+    }
 }
 
 bitflags! {
@@ -1111,8 +1199,10 @@ mod test {
         let (_, room) = make_room(RoomState::Joined);
         room.inner.write().unwrap().base_info.canonical_alias = Some(make_canonical_alias_event());
         assert_eq!(room.display_name().await.unwrap(), DisplayName::Aliased("test".to_owned()));
-        room.inner.write().unwrap().base_info.name = Some(make_name_event());
-        // Display name wasn't cached when we asked for it above, and name overrides
+        let mut room_info = room.clone_info();
+        room_info.base_info.name = Some(make_name_event());
+        room.update_summary(room_info);
+        // The name invalidates the cached display name, and overrides the alias
         assert_eq!(room.display_name().await.unwrap(), DisplayName::Named("Test Room".to_owned()));
     }
 
@@ -1134,8 +1224,10 @@ mod test {
         let (_, room) = make_room(RoomState::Invited);
         room.inner.write().unwrap().base_info.canonical_alias = Some(make_canonical_alias_event());
         assert_eq!(room.display_name().await.unwrap(), DisplayName::Aliased("test".to_owned()));
-        room.inner.write().unwrap().base_info.name = Some(make_name_event());
-        // Display name wasn't cached when we asked for it above, and name overrides
+        let mut room_info = room.clone_info();
+        room_info.base_info.name = Some(make_name_event());
+        room.update_summary(room_info);
+        // The name invalidates the cached display name, and overrides the alias
         assert_eq!(room.display_name().await.unwrap(), DisplayName::Named("Test Room".to_owned()));
     }
 
@@ -1258,6 +1350,67 @@ mod test {
         );
     }
 
+    #[async_test]
+    async fn test_display_name_is_cached_and_observable() {
+        let (store, room) = make_room(RoomState::Joined);
+        let room_id = room_id!("!test:localhost");
+        let matthew = user_id!("@matthew:example.org");
+        let me = user_id!("@me:example.org");
+        let mut changes = StateChanges::new("".to_owned());
+        let summary = assign!(RumaSummary::new(), {
+            joined_member_count: Some(2u32.into()),
+            heroes: vec![me.to_string(), matthew.to_string()],
+        });
+
+        let members = changes
+            .state
+            .entry(room_id.to_owned())
+            .or_default()
+            .entry(StateEventType::RoomMember)
+            .or_default();
+        members.insert(matthew.into(), make_member_event(matthew, "Matthew").cast());
+        members.insert(me.into(), make_member_event(me, "Me").cast());
+        store.save_changes(&changes).await.unwrap();
+
+        let mut room_info = room.clone_info();
+        room_info.update_summary(&summary);
+        room.update_summary(room_info);
+
+        let display_name = room.subscribe_to_display_name();
+        assert_eq!(display_name.get(), None);
+        assert_eq!(
+            room.display_name().await.unwrap(),
+            DisplayName::Calculated("Matthew".to_owned())
+        );
+        assert_eq!(display_name.get(), Some(DisplayName::Calculated("Matthew".to_owned())));
+
+        // The display name doesn't change until the cache is invalidated.
+        let mut changes = StateChanges::new("".to_owned());
+        changes
+            .state
+            .entry(room_id.to_owned())
+            .or_default()
+            .entry(StateEventType::RoomMember)
+            .or_default()
+            .insert(matthew.into(), make_member_event(matthew, "Matt").cast());
+        store.save_changes(&changes).await.unwrap();
+        assert_eq!(
+            room.display_name().await.unwrap(),
+            DisplayName::Calculated("Matthew".to_owned())
+        );
+
+        // A change of a member that isn't a hero doesn't invalidate the cache.
+        room.members_changed([user_id!("@alice:example.org")]);
+        assert_eq!(
+            room.display_name().await.unwrap(),
+            DisplayName::Calculated("Matthew".to_owned())
+        );
+
+        room.members_changed([matthew]);
+        room.refresh_observed_display_name().await;
+        assert_eq!(display_name.get(), Some(DisplayName::Calculated("Matt".to_owned())));
+    }
+
     #[async_test]
     async fn test_display_name_dm_alone() {
         let (store, room) = make_room(RoomState::Joined);
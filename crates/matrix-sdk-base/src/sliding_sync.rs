@@ -14,14 +14,17 @@
 
 #[cfg(feature = "e2e-encryption")]
 use std::ops::Deref;
+use std::sync::Arc;
 
 use ruma::{
     api::client::sync::sync_events::{
         v3::{self, InvitedRoom, RoomSummary},
-        v4::{self, AccountData},
+        v4,
     },
-    events::AnySyncStateEvent,
-    RoomId,
+    events::{AnyRoomAccountDataEvent, AnySyncStateEvent},
+    push::Ruleset,
+    serde::Raw,
+    OwnedRoomId, RoomId,
 };
 use tracing::{debug, info, instrument};
 
@@ -29,11 +32,12 @@ use super::BaseClient;
 #[cfg(feature = "e2e-encryption")]
 use crate::RoomMemberships;
 use crate::{
+    client::ProcessedRoom,
     deserialized_responses::AmbiguityChanges,
     error::Result,
     rooms::RoomState,
     store::{ambiguity_map::AmbiguityCache, StateChanges, Store},
-    sync::{JoinedRoom, Rooms, SyncResponse},
+    sync::{JoinedRoom, Rooms, SyncProgress, SyncResponse},
     Room, RoomInfo,
 };
 
@@ -102,25 +106,42 @@ impl BaseClient {
             self.handle_account_data(&account_data.global, &mut changes).await;
         }
 
+        let push_rules = Arc::new(self.get_push_rules(&changes).await?);
+
+        self.sync_progress.set(SyncProgress { processed_rooms: 0, total_rooms: rooms.len() });
+
         let mut new_rooms = Rooms::default();
 
-        for (room_id, room_data) in rooms {
-            let (room_to_store, joined_room, invited_room) = self
-                .process_sliding_sync_room(
-                    room_id,
-                    room_data,
-                    &store,
-                    &mut changes,
-                    &mut ambiguity_cache,
-                    account_data,
-                )
-                .await?;
-            changes.add_room(room_to_store);
+        let rooms = rooms.iter().map(|(room_id, room_data)| {
+            let room_account_data = account_data.rooms.get(room_id).cloned();
+            (room_id.clone(), (room_data.clone(), room_account_data))
+        });
+        let processed_rooms = self
+            .process_rooms_concurrently(rooms, |client, room_id, (room_data, room_account_data)| {
+                let push_rules = push_rules.clone();
+                async move {
+                    client
+                        .process_sliding_sync_room(
+                            room_id,
+                            room_data,
+                            room_account_data,
+                            &push_rules,
+                        )
+                        .await
+                }
+            })
+            .await?;
+
+        for processed_room in processed_rooms {
+            changes.merge(processed_room.changes);
+            ambiguity_cache.merge(processed_room.ambiguity_cache);
+
+            let (joined_room, invited_room) = processed_room.update;
             if let Some(joined_room) = joined_room {
-                new_rooms.join.insert(room_id.clone(), joined_room);
+                new_rooms.join.insert(processed_room.room_id.clone(), joined_room);
             }
             if let Some(invited_room) = invited_room {
-                new_rooms.invite.insert(room_id.clone(), invited_room);
+                new_rooms.invite.insert(processed_room.room_id, invited_room);
             }
         }
 
@@ -160,11 +181,16 @@ impl BaseClient {
         //     .collect();
 
         changes.ambiguity_maps = ambiguity_cache.cache;
+        self.apply_sync_processing_policy(&mut changes);
+
+        let sync_lock = self.sync_lock().write().await;
+        self.run_sync_response_processors(&mut changes).await?;
 
         debug!("ready to submit changes to store");
 
         store.save_changes(&changes).await?;
         self.apply_changes(&changes).await;
+        drop(sync_lock);
         debug!("applied changes");
 
         let device_one_time_keys_count =
@@ -183,25 +209,28 @@ impl BaseClient {
         })
     }
 
+    #[instrument(skip_all, fields(?room_id))]
     async fn process_sliding_sync_room(
         &self,
-        room_id: &RoomId,
-        room_data: &v4::SlidingSyncRoom,
-        store: &Store,
-        changes: &mut StateChanges,
-        ambiguity_cache: &mut AmbiguityCache,
-        account_data: &AccountData,
-    ) -> Result<(RoomInfo, Option<JoinedRoom>, Option<InvitedRoom>)> {
+        room_id: OwnedRoomId,
+        room_data: v4::SlidingSyncRoom,
+        room_account_data: Option<Vec<Raw<AnyRoomAccountDataEvent>>>,
+        push_rules: &Ruleset,
+    ) -> Result<ProcessedRoom<(Option<JoinedRoom>, Option<InvitedRoom>)>> {
+        let store = &self.store;
+        let mut changes = StateChanges::default();
+        let mut ambiguity_cache = AmbiguityCache::new(store.inner.clone());
+
         let required_state = Self::deserialize_events(&room_data.required_state);
 
         // Find or create the room in the store
         let (room, mut room_info, invited_room) = self
             .process_sliding_sync_room_membership(
-                room_data,
+                &room_data,
                 &required_state,
                 store,
-                room_id,
-                changes,
+                &room_id,
+                &mut changes,
             )
             .await;
 
@@ -212,36 +241,31 @@ impl BaseClient {
                 &room_data.required_state,
                 &required_state,
                 &mut room_info,
-                changes,
-                ambiguity_cache,
+                &mut changes,
+                &mut ambiguity_cache,
             )
             .await?
         } else {
             Default::default()
         };
 
-        let room_account_data = if let Some(events) = account_data.rooms.get(room_id) {
-            self.handle_room_account_data(room_id, events, changes).await;
-            Some(events.to_vec())
-        } else {
-            None
-        };
-
-        process_room_properties(room_data, &mut room_info);
+        if let Some(events) = &room_account_data {
+            self.handle_room_account_data(&room_id, events, &mut changes).await;
+        }
 
-        let push_rules = self.get_push_rules(changes).await?;
+        process_room_properties(&room_data, &mut room_info);
 
         let timeline = self
             .handle_timeline(
                 &room,
                 room_data.limited,
-                room_data.timeline.clone(),
-                room_data.prev_batch.clone(),
-                &push_rules,
+                room_data.timeline,
+                room_data.prev_batch,
+                push_rules,
                 &mut user_ids,
                 &mut room_info,
-                changes,
-                ambiguity_cache,
+                &mut changes,
+                &mut ambiguity_cache,
             )
             .await?;
 
@@ -252,7 +276,7 @@ impl BaseClient {
                     // The room turned on encryption in this sync, we need
                     // to also get all the existing users and mark them for
                     // tracking.
-                    let user_ids = store.get_user_ids(room_id, RoomMemberships::ACTIVE).await?;
+                    let user_ids = store.get_user_ids(&room_id, RoomMemberships::ACTIVE).await?;
                     o.update_tracked_users(user_ids.iter().map(Deref::deref)).await?
                 }
 
@@ -262,7 +286,7 @@ impl BaseClient {
             }
         }
 
-        let notification_count = room_data.unread_notifications.clone().into();
+        let notification_count = room_data.unread_notifications.into();
         room_info.update_notification_count(notification_count);
 
         // If this room was not an invite, we treat it as joined
@@ -270,7 +294,7 @@ impl BaseClient {
         let joined_room = if invited_room.is_none() {
             Some(JoinedRoom::new(
                 timeline,
-                room_data.required_state.clone(),
+                room_data.required_state,
                 room_account_data.unwrap_or_default(),
                 Vec::new(),
                 notification_count,
@@ -279,7 +303,9 @@ impl BaseClient {
             None
         };
 
-        Ok((room_info, joined_room, invited_room))
+        changes.add_room(room_info);
+
+        Ok(ProcessedRoom { room_id, update: (joined_room, invited_room), changes, ambiguity_cache })
     }
 
     /// Look through the sliding sync data for this room, find/create it in the
@@ -79,6 +79,18 @@ impl AmbiguityCache {
         Self { store, cache: BTreeMap::new(), changes: BTreeMap::new() }
     }
 
+    /// Merge the cache and the changes of `other`, built for other rooms, into
+    /// this cache.
+    pub fn merge(&mut self, other: AmbiguityCache) {
+        for (room_id, cache) in other.cache {
+            self.cache.entry(room_id).or_default().extend(cache);
+        }
+
+        for (room_id, changes) in other.changes {
+            self.changes.entry(room_id).or_default().extend(changes);
+        }
+    }
+
     pub async fn handle_event(
         &mut self,
         changes: &StateChanges,
@@ -26,7 +26,7 @@ use ruma::{
     },
     mxc_uri, room_id,
     serde::Raw,
-    uint, user_id, EventId, OwnedEventId, OwnedUserId, RoomId, UserId,
+    uint, user_id, EventId, OwnedEventId, OwnedUserId, RoomId, TransactionId, UserId,
 };
 use serde_json::{json, value::Value as JsonValue};
 
@@ -40,8 +40,8 @@ use crate::{
 
 /// `StateStore` integration tests.
 ///
-/// This trait is not meant to be used directly, but will be used with the [``]
-/// macro.
+/// This trait is not meant to be used directly, but will be used with the
+/// [`statestore_integration_tests!`] macro.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
 #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 pub trait StateStoreIntegrationTests {
@@ -51,6 +51,8 @@ pub trait StateStoreIntegrationTests {
     async fn test_media_content(&self);
     /// Test room topic redaction.
     async fn test_topic_redaction(&self) -> Result<()>;
+    /// Test room member redaction.
+    async fn test_member_redaction(&self) -> Result<()>;
     /// Test populating the store.
     async fn test_populate_store(&self) -> Result<()>;
     /// Test room member saving.
@@ -59,6 +61,10 @@ pub trait StateStoreIntegrationTests {
     async fn test_filter_saving(&self);
     /// Test sync token saving.
     async fn test_sync_token_saving(&self);
+    /// Test the saving of the IDs of sent events.
+    async fn test_sent_event_saving(&self);
+    /// Test room member saving when the members are lazy-loaded.
+    async fn test_lazy_loaded_members(&self) -> Result<()>;
     /// Test stripped room member saving.
     async fn test_stripped_member_saving(&self);
     /// Test room power levels saving.
@@ -303,6 +309,59 @@ impl StateStoreIntegrationTests for DynStateStore {
         Ok(())
     }
 
+    async fn test_member_redaction(&self) -> Result<()> {
+        let room_id = room_id!("!test_member_redaction:localhost");
+        let user_id = user_id();
+        let member_event_id = event_id!("$member_event");
+
+        let mut changes = StateChanges::default();
+        let member_event: Raw<AnySyncStateEvent> = serde_json::from_value(json!({
+            "type": "m.room.member",
+            "content": {
+                "membership": "join",
+                "displayname": "Example",
+            },
+            "event_id": member_event_id,
+            "origin_server_ts": 198,
+            "sender": user_id,
+            "state_key": user_id,
+        }))
+        .unwrap();
+        changes.add_state_event(room_id, member_event.deserialize().unwrap(), member_event);
+        changes.add_room(RoomInfo::new(room_id, RoomState::Joined));
+        self.save_changes(&changes).await?;
+
+        let member_event = self.get_member_event(room_id, user_id).await?.unwrap();
+        assert_eq!(member_event.deserialize().unwrap().display_name(), "Example");
+
+        let mut changes = StateChanges::default();
+        let redaction: Raw<_> = serde_json::from_value(json!({
+            "type": "m.room.redaction",
+            "content": {},
+            "redacts": member_event_id,
+            "event_id": "$member_redaction",
+            "origin_server_ts": 199,
+            "sender": user_id,
+        }))
+        .unwrap();
+        changes.add_redaction(room_id, member_event_id, redaction);
+        self.save_changes(&changes).await?;
+
+        // The membership survives the redaction, but the rest of the content is
+        // gone.
+        let member_event =
+            self.get_member_event(room_id, user_id).await?.unwrap().deserialize().unwrap();
+        assert_matches!(&member_event, MemberEvent::Sync(SyncStateEvent::Redacted(_)));
+        assert_eq!(*member_event.membership(), MembershipState::Join);
+        assert_eq!(member_event.display_name(), user_id.localpart());
+        assert_eq!(
+            self.get_user_ids(room_id, RoomMemberships::JOIN).await?,
+            vec![user_id.to_owned()]
+        );
+
+        Ok(())
+    }
+
     async fn test_populate_store(&self) -> Result<()> {
         let room_id = room_id();
         let user_id = user_id();
@@ -494,6 +553,28 @@ impl StateStoreIntegrationTests for DynStateStore {
         assert_matches!(self.get_kv_data(StateStoreDataKey::Filter(filter_name)).await, Ok(None));
     }
 
+    async fn test_sent_event_saving(&self) {
+        let txn_id = TransactionId::new();
+        let event_id = event_id!("$sent");
+
+        assert_matches!(self.get_kv_data(StateStoreDataKey::SentEvent(&txn_id)).await, Ok(None));
+
+        self.set_kv_data(
+            StateStoreDataKey::SentEvent(&txn_id),
+            StateStoreDataValue::SentEvent(event_id.to_owned()),
+        )
+        .await
+        .unwrap();
+        let stored_event_id = assert_matches!(
+            self.get_kv_data(StateStoreDataKey::SentEvent(&txn_id)).await,
+            Ok(Some(StateStoreDataValue::SentEvent(e))) => e
+        );
+        assert_eq!(stored_event_id, event_id);
+
+        self.remove_kv_data(StateStoreDataKey::SentEvent(&txn_id)).await.unwrap();
+        assert_matches!(self.get_kv_data(StateStoreDataKey::SentEvent(&txn_id)).await, Ok(None));
+    }
+
     async fn test_sync_token_saving(&self) {
         let sync_token_1 = "t392-516_47314_0_7_1";
         let sync_token_2 = "t392-516_47314_0_7_2";
@@ -525,6 +606,79 @@ impl StateStoreIntegrationTests for DynStateStore {
         assert_matches!(self.get_kv_data(StateStoreDataKey::SyncToken).await, Ok(None));
     }
 
+    async fn test_lazy_loaded_members(&self) -> Result<()> {
+        let room_id = room_id!("!test_lazy_loaded_members:localhost");
+        let user_id = user_id();
+        let invited_user_id = invited_user_id();
+        let second_user_id = user_id!("@second:localhost");
+
+        // With lazy-loading, a sync only contains our own member event.
+        let mut changes = StateChanges::default();
+        let member_event = membership_event().cast::<AnySyncStateEvent>();
+        changes.add_state_event(room_id, member_event.deserialize().unwrap(), member_event);
+        changes.add_room(RoomInfo::new(room_id, RoomState::Joined));
+        self.save_changes(&changes).await?;
+
+        let room_info = self.get_room_infos().await?.into_iter().find(|r| r.room_id() == room_id);
+        assert!(!room_info.unwrap().are_members_synced());
+        assert_eq!(self.get_user_ids(room_id, RoomMemberships::empty()).await?.len(), 1);
+
+        // Fetching the members returns our own member event again, along with
+        // the others.
+        let mut changes = StateChanges::default();
+        let members = [
+            membership_event(),
+            custom_membership_event(second_user_id, event_id!("$second_member_event")),
+            custom_membership_event_with_state(
+                invited_user_id,
+                event_id!("$invited_member_event"),
+                MembershipState::Invite,
+            ),
+        ];
+        for member_event in members.map(Raw::cast::<AnySyncStateEvent>) {
+            changes.add_state_event(room_id, member_event.deserialize().unwrap(), member_event);
+        }
+        let mut room_info = RoomInfo::new(room_id, RoomState::Joined);
+        room_info.mark_members_synced();
+        changes.add_room(room_info);
+        self.save_changes(&changes).await?;
+
+        let room_info = self.get_room_infos().await?.into_iter().find(|r| r.room_id() == room_id);
+        assert!(room_info.unwrap().are_members_synced());
+        assert_eq!(self.get_user_ids(room_id, RoomMemberships::empty()).await?.len(), 3);
+        assert_eq!(self.get_user_ids(room_id, RoomMemberships::JOIN).await?.len(), 2);
+        assert_eq!(
+            self.get_user_ids(room_id, RoomMemberships::INVITE).await?,
+            vec![invited_user_id.to_owned()]
+        );
+
+        // A membership change replaces the previous member event.
+        let mut changes = StateChanges::default();
+        let leave_event = custom_membership_event_with_state(
+            second_user_id,
+            event_id!("$second_leave_event"),
+            MembershipState::Leave,
+        )
+        .cast::<AnySyncStateEvent>();
+        changes.add_state_event(room_id, leave_event.deserialize().unwrap(), leave_event);
+        self.save_changes(&changes).await?;
+
+        assert_eq!(self.get_user_ids(room_id, RoomMemberships::empty()).await?.len(), 3);
+        assert_eq!(
+            self.get_user_ids(room_id, RoomMemberships::JOIN).await?,
+            vec![user_id.to_owned()]
+        );
+        assert_eq!(
+            self.get_user_ids(room_id, RoomMemberships::LEAVE).await?,
+            vec![second_user_id.to_owned()]
+        );
+        let member_event =
+            self.get_member_event(room_id, second_user_id).await?.unwrap().deserialize().unwrap();
+        assert_eq!(*member_event.membership(), MembershipState::Leave);
+
+        Ok(())
+    }
+
     async fn test_stripped_member_saving(&self) {
         let room_id = room_id!("!test_stripped_member_saving:localhost");
         let user_id = user_id();
@@ -1130,6 +1284,15 @@ impl StateStoreIntegrationTests for DynStateStore {
 /// You need to provide a `async fn get_store() -> StoreResult<impl StateStore>`
 /// providing a fresh store on the same level you invoke the macro.
 ///
+/// Store implementations living outside of this repository can use it too, to
+/// check that they behave like the stores of the SDK, including in edge cases
+/// like redactions or lazy-loaded members. The macro is only available with the
+/// `testing` feature of `matrix-sdk-base`, and the generated tests need
+/// `matrix-sdk-test` as a dev-dependency.
+///
+/// Invoking it as `statestore_integration_tests!(with_media_tests)` also runs
+/// the tests of the media cache.
+///
 /// ## Usage Example:
 /// ```no_run
 /// # use matrix_sdk_base::store::{
@@ -1181,6 +1344,12 @@ macro_rules! statestore_integration_tests {
             store.test_topic_redaction().await
         }
 
+        #[async_test]
+        async fn test_member_redaction() -> StoreResult<()> {
+            let store = get_store().await?.into_state_store();
+            store.test_member_redaction().await
+        }
+
         #[async_test]
         async fn test_populate_store() -> StoreResult<()> {
             let store = get_store().await?.into_state_store();
@@ -1205,6 +1374,18 @@ macro_rules! statestore_integration_tests {
             store.test_sync_token_saving().await
         }
 
+        #[async_test]
+        async fn test_sent_event_saving() {
+            let store = get_store().await.unwrap().into_state_store();
+            store.test_sent_event_saving().await
+        }
+
+        #[async_test]
+        async fn test_lazy_loaded_members() -> StoreResult<()> {
+            let store = get_store().await?.into_state_store();
+            store.test_lazy_loaded_members().await
+        }
+
         #[async_test]
         async fn test_stripped_member_saving() {
             let store = get_store().await.unwrap().into_state_store();
@@ -1316,9 +1497,17 @@ fn membership_event() -> Raw<SyncRoomMemberEvent> {
 }
 
 fn custom_membership_event(user_id: &UserId, event_id: &EventId) -> Raw<SyncRoomMemberEvent> {
+    custom_membership_event_with_state(user_id, event_id, MembershipState::Join)
+}
+
+fn custom_membership_event_with_state(
+    user_id: &UserId,
+    event_id: &EventId,
+    membership: MembershipState,
+) -> Raw<SyncRoomMemberEvent> {
     let ev_json = json!({
         "type": "m.room.member",
-        "content": RoomMemberEventContent::new(MembershipState::Join),
+        "content": RoomMemberEventContent::new(membership),
         "event_id": event_id,
         "origin_server_ts": 198,
         "sender": user_id,
@@ -31,8 +31,8 @@ use ruma::{
         AnySyncStateEvent, GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType,
     },
     serde::Raw,
-    CanonicalJsonObject, EventId, MxcUri, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId,
-    RoomVersionId, UserId,
+    CanonicalJsonObject, EventId, MxcUri, OwnedEventId, OwnedRoomId, OwnedTransactionId,
+    OwnedUserId, RoomId, RoomVersionId, UserId,
 };
 use tracing::{debug, warn};
 
@@ -49,6 +49,7 @@ use crate::{
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
     user_avatar_url: Arc<DashMap<String, String>>,
+    sent_events: Arc<DashMap<OwnedTransactionId, OwnedEventId>>,
     sync_token: Arc<RwLock<Option<String>>>,
     filters: Arc<DashMap<String, String>>,
     account_data: Arc<DashMap<GlobalAccountDataEventType, Raw<AnyGlobalAccountDataEvent>>>,
@@ -92,6 +93,7 @@ impl MemoryStore {
     pub fn new() -> Self {
         Self {
             user_avatar_url: Default::default(),
+            sent_events: Default::default(),
             sync_token: Default::default(),
             filters: Default::default(),
             account_data: Default::default(),
@@ -127,6 +129,10 @@ impl MemoryStore {
                 .user_avatar_url
                 .get(user_id.as_str())
                 .map(|u| StateStoreDataValue::UserAvatarUrl(u.value().clone()))),
+            StateStoreDataKey::SentEvent(txn_id) => Ok(self
+                .sent_events
+                .get(txn_id)
+                .map(|e| StateStoreDataValue::SentEvent(e.value().clone()))),
         }
     }
 
@@ -152,6 +158,12 @@ impl MemoryStore {
                     value.into_user_avatar_url().expect("Session data not a user avatar url"),
                 );
             }
+            StateStoreDataKey::SentEvent(txn_id) => {
+                self.sent_events.insert(
+                    txn_id.to_owned(),
+                    value.into_sent_event().expect("Session data not a sent event"),
+                );
+            }
         }
 
         Ok(())
@@ -166,6 +178,9 @@ impl MemoryStore {
             StateStoreDataKey::UserAvatarUrl(user_id) => {
                 self.filters.remove(user_id.as_str());
             }
+            StateStoreDataKey::SentEvent(txn_id) => {
+                self.sent_events.remove(txn_id);
+            }
         }
 
         Ok(())
@@ -250,6 +265,13 @@ impl MemoryStore {
             self.presence.insert(sender.clone(), event.clone());
         }
 
+        for (key, value) in &changes.custom {
+            match value {
+                Some(value) => self.custom.insert(key.clone(), value.clone()),
+                None => self.custom.remove(key).map(|entry| entry.1),
+            };
+        }
+
         for (room, event_types) in &changes.stripped_state {
             for (event_type, events) in event_types {
                 for (state_key, raw_event) in events {
@@ -0,0 +1,327 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use matrix_sdk_common::{
+    instant::Instant,
+    metrics::{self, STORE_OPERATION_DURATION},
+};
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::{Receipt, ReceiptThread, ReceiptType},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, GlobalAccountDataEventType,
+        RoomAccountDataEventType, StateEventType,
+    },
+    serde::Raw,
+    EventId, MxcUri, OwnedEventId, OwnedUserId, RoomId, UserId,
+};
+
+use super::{
+    DynStateStore, StateChanges, StateStore, StateStoreDataKey, StateStoreDataValue, StoreError,
+};
+use crate::{
+    deserialized_responses::RawAnySyncOrStrippedState, media::MediaRequest, MinimalRoomMemberEvent,
+    RoomInfo, RoomMemberships,
+};
+
+/// A `StateStore` that records the duration of the operations of the inner
+/// store, see [`STORE_OPERATION_DURATION`].
+#[derive(Debug)]
+pub(crate) struct MetricsStateStore(pub(crate) Arc<DynStateStore>);
+
+async fn timed<T>(
+    operation: &'static str,
+    future: impl Future<Output = Result<T, StoreError>>,
+) -> Result<T, StoreError> {
+    let start = Instant::now();
+    let result = future.await;
+
+    metrics::record_duration(
+        STORE_OPERATION_DURATION,
+        start,
+        &[("operation", operation), ("outcome", metrics::outcome_label(&result))],
+    );
+
+    result
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl StateStore for MetricsStateStore {
+    type Error = StoreError;
+
+    async fn get_kv_data(
+        &self,
+        key: StateStoreDataKey<'_>,
+    ) -> Result<Option<StateStoreDataValue>, Self::Error> {
+        timed("get_kv_data", self.0.get_kv_data(key)).await
+    }
+
+    async fn set_kv_data(
+        &self,
+        key: StateStoreDataKey<'_>,
+        value: StateStoreDataValue,
+    ) -> Result<(), Self::Error> {
+        timed("set_kv_data", self.0.set_kv_data(key, value)).await
+    }
+
+    async fn remove_kv_data(&self, key: StateStoreDataKey<'_>) -> Result<(), Self::Error> {
+        timed("remove_kv_data", self.0.remove_kv_data(key)).await
+    }
+
+    async fn save_changes(&self, changes: &StateChanges) -> Result<(), Self::Error> {
+        timed("save_changes", self.0.save_changes(changes)).await
+    }
+
+    async fn get_presence_event(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<Raw<PresenceEvent>>, Self::Error> {
+        timed("get_presence_event", self.0.get_presence_event(user_id)).await
+    }
+
+    async fn get_presence_events(
+        &self,
+        user_ids: &[OwnedUserId],
+    ) -> Result<Vec<Raw<PresenceEvent>>, Self::Error> {
+        timed("get_presence_events", self.0.get_presence_events(user_ids)).await
+    }
+
+    async fn get_state_event(
+        &self,
+        room_id: &RoomId,
+        event_type: StateEventType,
+        state_key: &str,
+    ) -> Result<Option<RawAnySyncOrStrippedState>, Self::Error> {
+        timed("get_state_event", self.0.get_state_event(room_id, event_type, state_key)).await
+    }
+
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: StateEventType,
+    ) -> Result<Vec<RawAnySyncOrStrippedState>, Self::Error> {
+        timed("get_state_events", self.0.get_state_events(room_id, event_type)).await
+    }
+
+    async fn get_state_events_for_keys(
+        &self,
+        room_id: &RoomId,
+        event_type: StateEventType,
+        state_keys: &[&str],
+    ) -> Result<Vec<RawAnySyncOrStrippedState>, Self::Error> {
+        timed(
+            "get_state_events_for_keys",
+            self.0.get_state_events_for_keys(room_id, event_type, state_keys),
+        )
+        .await
+    }
+
+    async fn get_profile(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<MinimalRoomMemberEvent>, Self::Error> {
+        timed("get_profile", self.0.get_profile(room_id, user_id)).await
+    }
+
+    async fn get_profiles<'a>(
+        &self,
+        room_id: &RoomId,
+        user_ids: &'a [OwnedUserId],
+    ) -> Result<BTreeMap<&'a UserId, MinimalRoomMemberEvent>, Self::Error> {
+        timed("get_profiles", self.0.get_profiles(room_id, user_ids)).await
+    }
+
+    async fn get_user_ids(
+        &self,
+        room_id: &RoomId,
+        memberships: RoomMemberships,
+    ) -> Result<Vec<OwnedUserId>, Self::Error> {
+        timed("get_user_ids", self.0.get_user_ids(room_id, memberships)).await
+    }
+
+    async fn get_invited_user_ids(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<OwnedUserId>, Self::Error> {
+        timed("get_invited_user_ids", self.0.get_invited_user_ids(room_id)).await
+    }
+
+    async fn get_joined_user_ids(&self, room_id: &RoomId) -> Result<Vec<OwnedUserId>, Self::Error> {
+        timed("get_joined_user_ids", self.0.get_joined_user_ids(room_id)).await
+    }
+
+    async fn get_room_infos(&self) -> Result<Vec<RoomInfo>, Self::Error> {
+        timed("get_room_infos", self.0.get_room_infos()).await
+    }
+
+    #[allow(deprecated)]
+    async fn get_stripped_room_infos(&self) -> Result<Vec<RoomInfo>, Self::Error> {
+        timed("get_stripped_room_infos", self.0.get_stripped_room_infos()).await
+    }
+
+    async fn get_users_with_display_name(
+        &self,
+        room_id: &RoomId,
+        display_name: &str,
+    ) -> Result<BTreeSet<OwnedUserId>, Self::Error> {
+        timed(
+            "get_users_with_display_name",
+            self.0.get_users_with_display_name(room_id, display_name),
+        )
+        .await
+    }
+
+    async fn get_users_with_display_names<'a>(
+        &self,
+        room_id: &RoomId,
+        display_names: &'a [String],
+    ) -> Result<BTreeMap<&'a str, BTreeSet<OwnedUserId>>, Self::Error> {
+        timed(
+            "get_users_with_display_names",
+            self.0.get_users_with_display_names(room_id, display_names),
+        )
+        .await
+    }
+
+    async fn get_account_data_event(
+        &self,
+        event_type: GlobalAccountDataEventType,
+    ) -> Result<Option<Raw<AnyGlobalAccountDataEvent>>, Self::Error> {
+        timed("get_account_data_event", self.0.get_account_data_event(event_type)).await
+    }
+
+    async fn get_room_account_data_event(
+        &self,
+        room_id: &RoomId,
+        event_type: RoomAccountDataEventType,
+    ) -> Result<Option<Raw<AnyRoomAccountDataEvent>>, Self::Error> {
+        timed(
+            "get_room_account_data_event",
+            self.0.get_room_account_data_event(room_id, event_type),
+        )
+        .await
+    }
+
+    async fn get_user_room_receipt_event(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        thread: ReceiptThread,
+        user_id: &UserId,
+    ) -> Result<Option<(OwnedEventId, Receipt)>, Self::Error> {
+        timed(
+            "get_user_room_receipt_event",
+            self.0.get_user_room_receipt_event(room_id, receipt_type, thread, user_id),
+        )
+        .await
+    }
+
+    async fn get_event_room_receipt_events(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        thread: ReceiptThread,
+        event_id: &EventId,
+    ) -> Result<Vec<(OwnedUserId, Receipt)>, Self::Error> {
+        timed(
+            "get_event_room_receipt_events",
+            self.0.get_event_room_receipt_events(room_id, receipt_type, thread, event_id),
+        )
+        .await
+    }
+
+    async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        timed("get_custom_value", self.0.get_custom_value(key)).await
+    }
+
+    async fn set_custom_value(
+        &self,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        timed("set_custom_value", self.0.set_custom_value(key, value)).await
+    }
+
+    async fn remove_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        timed("remove_custom_value", self.0.remove_custom_value(key)).await
+    }
+
+    async fn add_media_content(
+        &self,
+        request: &MediaRequest,
+        content: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        timed("add_media_content", self.0.add_media_content(request, content)).await
+    }
+
+    async fn get_media_content(
+        &self,
+        request: &MediaRequest,
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        timed("get_media_content", self.0.get_media_content(request)).await
+    }
+
+    async fn remove_media_content(&self, request: &MediaRequest) -> Result<(), Self::Error> {
+        timed("remove_media_content", self.0.remove_media_content(request)).await
+    }
+
+    async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<(), Self::Error> {
+        timed("remove_media_content_for_uri", self.0.remove_media_content_for_uri(uri)).await
+    }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<(), Self::Error> {
+        timed("remove_room", self.0.remove_room(room_id)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk_common::metrics::STORE_OPERATION_DURATION;
+    use matrix_sdk_test::{
+        async_test,
+        metrics::{install_recorder, take_recorded_histograms},
+    };
+    use ruma::user_id;
+
+    use crate::{BaseClient, StateChanges};
+
+    #[async_test]
+    async fn store_operations_are_recorded() {
+        let client = BaseClient::new();
+        install_recorder();
+
+        client.store().save_changes(&StateChanges::default()).await.unwrap();
+        client.store().get_presence_event(user_id!("@alice:localhost")).await.unwrap();
+
+        let recorded = take_recorded_histograms();
+        assert_eq!(recorded.len(), 2, "{recorded:?}");
+        assert!(recorded[0].matches(
+            STORE_OPERATION_DURATION,
+            &[("operation", "save_changes"), ("outcome", "success")]
+        ));
+        assert!(recorded[1].matches(
+            STORE_OPERATION_DURATION,
+            &[("operation", "get_presence_event"), ("outcome", "success")]
+        ));
+    }
+}
@@ -54,7 +54,7 @@ use ruma::{
     serde::Raw,
     EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 
 /// BoxStream of owned Types
 pub type BoxStream<T> = Pin<Box<dyn futures_util::Stream<Item = T> + Send>>;
@@ -66,6 +66,8 @@ use crate::{
 
 pub(crate) mod ambiguity_map;
 mod memory_store;
+#[cfg(feature = "metrics")]
+mod metrics_store;
 
 #[cfg(any(test, feature = "testing"))]
 pub use self::integration_tests::StateStoreIntegrationTests;
@@ -152,11 +154,19 @@ pub(crate) struct Store {
     /// might acquire read access, such that access to different rooms can be
     /// parallelized.
     sync_lock: Arc<RwLock<()>>,
+    /// The sender of the changes broadcast with
+    /// [`Store::broadcast_state_changes`].
+    state_changes_sender: broadcast::Sender<Arc<StateChanges>>,
 }
 
 impl Store {
     /// Create a new store, wrapping the given `StateStore`
     pub fn new(inner: Arc<DynStateStore>) -> Self {
+        let (state_changes_sender, _) = broadcast::channel(32);
+
+        #[cfg(feature = "metrics")]
+        let inner: Arc<DynStateStore> = Arc::new(metrics_store::MetricsStateStore(inner));
+
         Self {
             inner,
             session_meta: Default::default(),
@@ -164,6 +174,7 @@ impl Store {
             sync_token: Default::default(),
             rooms: Default::default(),
             sync_lock: Default::default(),
+            state_changes_sender,
         }
     }
 
@@ -247,6 +258,25 @@ impl Store {
             .or_insert_with(|| Room::new(user_id, self.inner.clone(), room_id, room_type))
             .clone()
     }
+
+    /// Notify the subscribers of [`Store::subscribe_to_state_changes`] of the
+    /// given changes.
+    ///
+    /// This must be called once the changes are persisted and applied to the
+    /// rooms.
+    pub(crate) fn broadcast_state_changes(&self, changes: &StateChanges) {
+        // Don't clone the changes if nobody is listening.
+        if self.state_changes_sender.receiver_count() > 0 {
+            // Ignore the error, it only means that all the receivers were
+            // dropped in the meantime.
+            _ = self.state_changes_sender.send(Arc::new(changes.clone()));
+        }
+    }
+
+    /// Subscribe to the changes saved in the store.
+    pub fn subscribe_to_state_changes(&self) -> broadcast::Receiver<Arc<StateChanges>> {
+        self.state_changes_sender.subscribe()
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -312,6 +342,9 @@ pub struct StateChanges {
     pub ambiguity_maps: BTreeMap<OwnedRoomId, BTreeMap<String, BTreeSet<OwnedUserId>>>,
     /// A map of `RoomId` to a vector of `Notification`s
     pub notifications: BTreeMap<OwnedRoomId, Vec<Notification>>,
+    /// A map of keys to custom values, saved like with
+    /// [`StateStore::set_custom_value()`]. A `None` value removes the key.
+    pub custom: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
 }
 
 impl StateChanges {
@@ -409,6 +442,82 @@ impl StateChanges {
     pub fn add_receipts(&mut self, room_id: &RoomId, event: ReceiptEventContent) {
         self.receipts.insert(room_id.to_owned(), event);
     }
+
+    /// Update the `StateChanges` struct with a custom value to save.
+    pub fn set_custom_value(&mut self, key: &[u8], value: Vec<u8>) {
+        self.custom.insert(key.to_owned(), Some(value));
+    }
+
+    /// Update the `StateChanges` struct with a custom value to remove.
+    pub fn remove_custom_value(&mut self, key: &[u8]) {
+        self.custom.insert(key.to_owned(), None);
+    }
+
+    /// Merge the given `StateChanges` into this one.
+    ///
+    /// The changes in `other` are considered more recent, and replace the
+    /// changes in `self` that they conflict with.
+    pub(crate) fn merge(&mut self, other: StateChanges) {
+        let StateChanges {
+            sync_token,
+            account_data,
+            presence,
+            profiles,
+            state,
+            room_account_data,
+            room_infos,
+            receipts,
+            redactions,
+            stripped_state,
+            ambiguity_maps,
+            notifications,
+            custom,
+        } = other;
+
+        if sync_token.is_some() {
+            self.sync_token = sync_token;
+        }
+
+        self.account_data.extend(account_data);
+        self.presence.extend(presence);
+
+        for (room_id, profiles) in profiles {
+            self.profiles.entry(room_id).or_default().extend(profiles);
+        }
+
+        for (room_id, events) in state {
+            let room_state = self.state.entry(room_id).or_default();
+            for (event_type, events) in events {
+                room_state.entry(event_type).or_default().extend(events);
+            }
+        }
+
+        for (room_id, events) in room_account_data {
+            self.room_account_data.entry(room_id).or_default().extend(events);
+        }
+
+        self.room_infos.extend(room_infos);
+        self.receipts.extend(receipts);
+
+        for (room_id, redactions) in redactions {
+            self.redactions.entry(room_id).or_default().extend(redactions);
+        }
+
+        for (room_id, events) in stripped_state {
+            let room_state = self.stripped_state.entry(room_id).or_default();
+            for (event_type, events) in events {
+                room_state.entry(event_type).or_default().extend(events);
+            }
+        }
+
+        self.ambiguity_maps.extend(ambiguity_maps);
+
+        for (room_id, notifications) in notifications {
+            self.notifications.entry(room_id).or_default().extend(notifications);
+        }
+
+        self.custom.extend(custom);
+    }
 }
 
 /// Configuration for the state store and, when `encryption` is enabled, for the
@@ -456,6 +565,10 @@ impl StoreConfig {
     }
 
     /// Set a custom implementation of a `StateStore`.
+    ///
+    /// Custom implementations can be checked against the expectations of the
+    /// SDK with the `statestore_integration_tests!` macro, available with the
+    /// `testing` feature.
     pub fn state_store(mut self, store: impl IntoStateStore) -> Self {
         self.state_store = store.into_state_store();
         self
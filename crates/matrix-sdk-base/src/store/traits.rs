@@ -31,7 +31,7 @@ use ruma::{
         RoomAccountDataEventType, StateEventType, StaticEventContent, StaticStateEventContent,
     },
     serde::Raw,
-    EventId, MxcUri, OwnedEventId, OwnedUserId, RoomId, UserId,
+    EventId, MxcUri, OwnedEventId, OwnedUserId, RoomId, TransactionId, UserId,
 };
 
 use super::{StateChanges, StoreError};
@@ -739,7 +739,7 @@ pub type DynStateStore = dyn StateStore<Error = StoreError>;
 /// A type that can be type-erased into `Arc<dyn StateStore>`.
 ///
 /// This trait is not meant to be implemented directly outside
-/// `matrix-sdk-crypto`, but it is automatically implemented for everything that
+/// `matrix-sdk-base`, but it is automatically implemented for everything that
 /// implements `StateStore`.
 pub trait IntoStateStore {
     #[doc(hidden)]
@@ -781,6 +781,9 @@ pub enum StateStoreDataValue {
 
     /// The user avatar url
     UserAvatarUrl(String),
+
+    /// The ID of an event sent by this client.
+    SentEvent(OwnedEventId),
 }
 
 impl StateStoreDataValue {
@@ -807,6 +810,14 @@ impl StateStoreDataValue {
             _ => None,
         }
     }
+
+    /// Get this value if it is the ID of a sent event.
+    pub fn into_sent_event(self) -> Option<OwnedEventId> {
+        match self {
+            Self::SentEvent(event_id) => Some(event_id),
+            _ => None,
+        }
+    }
 }
 
 /// A key for key-value data.
@@ -820,6 +831,12 @@ pub enum StateStoreDataKey<'a> {
 
     /// Avatar URL
     UserAvatarUrl(&'a UserId),
+
+    /// The event sent by this client with the given transaction ID.
+    ///
+    /// It allows to match the remote echoes of the events sent by another
+    /// process using the same store, like a notification extension.
+    SentEvent(&'a TransactionId),
 }
 
 impl StateStoreDataKey<'_> {
@@ -830,4 +847,6 @@ impl StateStoreDataKey<'_> {
     /// Key prefix to use for the [`UserAvatarUrl`][Self::UserAvatarUrl]
     /// variant.
     pub const USER_AVATAR_URL: &str = "user_avatar_url";
+    /// Key prefix to use for the [`SentEvent`][Self::SentEvent] variant.
+    pub const SENT_EVENT: &str = "sent_event";
 }
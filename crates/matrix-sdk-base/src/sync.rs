@@ -16,7 +16,8 @@
 
 use std::{collections::BTreeMap, fmt};
 
-use matrix_sdk_common::deserialized_responses::SyncTimelineEvent;
+use async_trait::async_trait;
+use matrix_sdk_common::{deserialized_responses::SyncTimelineEvent, AsyncTraitDeps};
 use ruma::{
     api::client::{
         push::get_notifications::v3::Notification,
@@ -26,18 +27,21 @@ use ruma::{
     },
     events::{
         presence::PresenceEvent, AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent,
-        AnySyncEphemeralRoomEvent, AnySyncStateEvent, AnyToDeviceEvent,
+        AnySyncEphemeralRoomEvent, AnySyncStateEvent, AnyToDeviceEvent, StateEventType,
     },
     serde::Raw,
-    DeviceKeyAlgorithm, OwnedRoomId,
+    DeviceKeyAlgorithm, OwnedRoomId, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
+use tracing::trace;
 
 use crate::{
     debug::{
         DebugInvitedRoom, DebugListOfRawEvents, DebugListOfRawEventsNoId, DebugNotificationMap,
     },
     deserialized_responses::AmbiguityChanges,
+    store::{DynStateStore, Result as StoreResult, StateChanges},
+    RoomInfo,
 };
 
 /// Internal representation of a `/sync` response.
@@ -148,7 +152,7 @@ impl JoinedRoom {
 }
 
 /// Counts of unread notifications for a room.
-#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct UnreadNotificationsCount {
     /// The number of unread notifications for this room with the highlight flag
     /// set.
@@ -222,6 +226,190 @@ impl Timeline {
     }
 }
 
+/// Progress of the processing of the rooms of a sync response.
+///
+/// See [`BaseClient::subscribe_to_sync_progress()`].
+///
+/// [`BaseClient::subscribe_to_sync_progress()`]: crate::BaseClient::subscribe_to_sync_progress
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncProgress {
+    /// How many rooms of the response were already processed.
+    pub processed_rooms: usize,
+    /// How many rooms there are in the response.
+    pub total_rooms: usize,
+}
+
+/// A processor of the sync responses, to maintain custom data along with the
+/// state of the SDK.
+///
+/// The processors are called after the SDK processed a sync response, with
+/// the [`StateChanges`] that are about to be saved in the state store. The
+/// changes they make, like [custom values](StateChanges::set_custom_value),
+/// are saved in the same transaction as the rest of the response, so the
+/// custom data can't get out of sync with the state of the SDK.
+///
+/// See [`BaseClient::add_sync_response_processor()`].
+///
+/// [`BaseClient::add_sync_response_processor()`]: crate::BaseClient::add_sync_response_processor
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait SyncResponseProcessor: AsyncTraitDeps {
+    /// Process the changes of a sync response.
+    ///
+    /// Returning an error aborts the processing of the sync response, and
+    /// nothing is saved in the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The state store, with the state before the response.
+    ///
+    /// * `changes` - The changes to the state from the response.
+    async fn process(&self, store: &DynStateStore, changes: &mut StateChanges) -> StoreResult<()>;
+}
+
+/// A policy deciding which data of the sync responses is saved in the state
+/// store.
+///
+/// By default, everything is saved. Bridges and bots that are in huge rooms
+/// can skip the data they don't need, to reduce the writes to the store. The
+/// skipped data is still part of the [`SyncResponse`], so the event handlers
+/// receive it, but it can't be loaded from the store afterwards.
+///
+/// Typing notifications are never saved in the store.
+///
+/// See [`BaseClient::set_sync_processing_policy()`].
+///
+/// [`BaseClient::set_sync_processing_policy()`]: crate::BaseClient::set_sync_processing_policy
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncProcessingPolicy {
+    skip_presence: bool,
+    receipts_max_joined_members: Option<u64>,
+    members_max_active_members: Option<u64>,
+}
+
+impl SyncProcessingPolicy {
+    /// Create a new policy saving everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Don't save the presence of the other users.
+    pub fn skip_presence(mut self) -> Self {
+        self.skip_presence = true;
+        self
+    }
+
+    /// Don't save the receipts of the rooms with more joined members than the
+    /// given number.
+    ///
+    /// Use `0` to skip the receipts of all the rooms.
+    pub fn skip_receipts_above(mut self, joined_members: u64) -> Self {
+        self.receipts_max_joined_members = Some(joined_members);
+        self
+    }
+
+    /// Don't save the member events of the rooms with more joined and invited
+    /// members than the given number.
+    ///
+    /// The member event of the own user is always saved. Since the other
+    /// member events are missing from the store, the members of these rooms
+    /// and their display names are not available offline.
+    ///
+    /// The member events of encrypted rooms are always saved, because they
+    /// are needed to share the room keys with all the members. When
+    /// encryption is enabled in a room whose member events were skipped, the
+    /// members of the room are marked as missing, so they are fetched again.
+    pub fn skip_members_above(mut self, active_members: u64) -> Self {
+        self.members_max_active_members = Some(active_members);
+        self
+    }
+
+    /// Remove the data that must not be saved from the given changes.
+    ///
+    /// `get_room_info` returns the information of the room with the given ID,
+    /// to know the number of members of the room.
+    pub(crate) fn apply(
+        &self,
+        changes: &mut StateChanges,
+        own_user_id: Option<&UserId>,
+        get_room_info: impl Fn(&RoomId) -> Option<RoomInfo>,
+    ) {
+        if self.skip_presence {
+            changes.presence.clear();
+        }
+
+        let room_info = |room_id: &RoomId, changes: &StateChanges| {
+            changes.room_infos.get(room_id).cloned().or_else(|| get_room_info(room_id))
+        };
+
+        if let Some(max) = self.receipts_max_joined_members {
+            let skipped: Vec<_> = changes
+                .receipts
+                .keys()
+                .filter(|room_id| {
+                    room_info(room_id, changes)
+                        .is_some_and(|info| info.joined_members_count() > max)
+                })
+                .cloned()
+                .collect();
+
+            for room_id in skipped {
+                trace!(?room_id, "Skipping the receipts of a big room");
+                changes.receipts.remove(&room_id);
+            }
+        }
+
+        if let Some(max) = self.members_max_active_members {
+            // The member events of a room that was skipped before encryption
+            // was enabled are missing from the store, so they must be fetched
+            // again to share the room keys with all the members.
+            for (room_id, info) in &mut changes.room_infos {
+                let was_encrypted =
+                    get_room_info(room_id).map_or(true, |stored| stored.is_encrypted());
+                if info.is_encrypted() && !was_encrypted && info.active_members_count() > max {
+                    trace!(
+                        ?room_id,
+                        "Encryption was enabled in a big room, the members are missing"
+                    );
+                    info.mark_members_missing();
+                }
+            }
+
+            let skipped: Vec<_> = changes
+                .state
+                .keys()
+                .filter(|room_id| {
+                    room_info(room_id, changes).is_some_and(|info| {
+                        !info.is_encrypted() && info.active_members_count() > max
+                    })
+                })
+                .cloned()
+                .collect();
+
+            for room_id in skipped {
+                trace!(?room_id, "Skipping the member events of a big room");
+
+                let is_own_user = |user_id: &str| own_user_id.is_some_and(|own| own == user_id);
+                if let Some(members) = changes
+                    .state
+                    .get_mut(&room_id)
+                    .and_then(|state| state.get_mut(&StateEventType::RoomMember))
+                {
+                    members.retain(|state_key, _| is_own_user(state_key));
+                }
+                if let Some(profiles) = changes.profiles.get_mut(&room_id) {
+                    profiles.retain(|user_id, _| is_own_user(user_id.as_str()));
+                }
+                if let Some(ambiguity_map) = changes.ambiguity_maps.get_mut(&room_id) {
+                    for user_ids in ambiguity_map.values_mut() {
+                        user_ids.retain(|user_id| is_own_user(user_id.as_str()));
+                    }
+                }
+            }
+        }
+    }
+}
+
 struct DebugInvitedRooms<'a>(&'a BTreeMap<OwnedRoomId, InvitedRoom>);
 
 #[cfg(not(tarpaulin_include))]
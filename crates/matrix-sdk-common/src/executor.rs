@@ -27,7 +27,7 @@ use std::{
 #[cfg(target_arch = "wasm32")]
 use futures_util::{future::RemoteHandle, FutureExt};
 #[cfg(not(target_arch = "wasm32"))]
-pub use tokio::task::{spawn, JoinError, JoinHandle};
+pub use tokio::task::{spawn, spawn_blocking, JoinError, JoinHandle};
 
 #[cfg(target_arch = "wasm32")]
 pub fn spawn<F, T>(future: F) -> JoinHandle<T>
@@ -40,6 +40,19 @@ where
     JoinHandle { handle }
 }
 
+/// Run the given blocking closure.
+///
+/// There are no threads to offload it to under WASM, so it runs as a task on
+/// the current thread.
+#[cfg(target_arch = "wasm32")]
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + 'static,
+    T: 'static,
+{
+    spawn(async move { f() })
+}
+
 #[cfg(target_arch = "wasm32")]
 #[derive(Debug)]
 pub struct JoinHandle<T> {
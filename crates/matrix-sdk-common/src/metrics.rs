@@ -0,0 +1,87 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics about the performance of the SDK.
+//!
+//! When the `metrics` feature is enabled, the SDK records histograms with the
+//! [`metrics`] facade. They can be exported by installing a recorder, e.g.
+//! the Prometheus exporter of the `metrics-exporter-prometheus` crate. Without
+//! the feature, nothing is recorded.
+//!
+//! All the durations are recorded in seconds.
+//!
+//! [`metrics`]: https://docs.rs/metrics
+
+use crate::instant::Instant;
+
+/// The duration of a `/sync` request, from sending the request to the end of
+/// the processing of the response.
+///
+/// Labels: `kind`, either `v3` or `sliding`.
+pub const SYNC_DURATION: &str = "matrix_sdk_sync_duration_seconds";
+
+/// The duration of the processing of a `/sync` response.
+///
+/// Labels: `kind`, either `v3` or `sliding`.
+pub const SYNC_PROCESSING_DURATION: &str = "matrix_sdk_sync_processing_duration_seconds";
+
+/// The duration of an HTTP request to the homeserver, including the retries.
+///
+/// For the media downloads whose response is streamed, this is the duration
+/// until the headers of the response are received.
+///
+/// Labels: `endpoint`, the path of the ruma request type, e.g.
+/// `message::send_message_event::v3`, and `outcome`, either `success` or
+/// `error`.
+pub const HTTP_REQUEST_DURATION: &str = "matrix_sdk_http_request_duration_seconds";
+
+/// The duration of an operation of the state store.
+///
+/// Labels: `operation`, the name of the `StateStore` method, e.g.
+/// `save_changes`, and `outcome`, either `success` or `error`.
+pub const STORE_OPERATION_DURATION: &str = "matrix_sdk_store_operation_duration_seconds";
+
+/// The duration of the update of a timeline with new events.
+///
+/// Labels: `source`, either `sync`, `back_pagination` or
+/// `forward_pagination`.
+pub const TIMELINE_UPDATE_DURATION: &str = "matrix_sdk_timeline_update_duration_seconds";
+
+/// Record the time elapsed since `start` in the histogram with the given name.
+///
+/// This is a no-op if the `metrics` feature is disabled.
+pub fn record_duration(
+    name: &'static str,
+    start: Instant,
+    labels: &[(&'static str, &'static str)],
+) {
+    #[cfg(feature = "metrics")]
+    {
+        let labels: Vec<_> =
+            labels.iter().map(|&(key, value)| ::metrics::Label::new(key, value)).collect();
+        ::metrics::histogram!(name, start.elapsed(), labels);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (name, start, labels);
+}
+
+/// The label of the outcome of an operation.
+pub fn outcome_label<T, E>(result: &Result<T, E>) -> &'static str {
+    if result.is_ok() {
+        "success"
+    } else {
+        "error"
+    }
+}
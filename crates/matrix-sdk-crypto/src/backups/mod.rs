@@ -39,12 +39,14 @@ use crate::{
     olm::{Account, InboundGroupSession, SignedJsonObject},
     store::{BackupKeys, Changes, RecoveryKey, RoomKeyCounts, Store},
     types::{MegolmV1AuthData, RoomKeyBackupInfo, Signatures},
-    CryptoStoreError, Device, KeysBackupRequest, OutgoingRequest,
+    CryptoStoreError, Device, KeysBackupRequest, OutgoingRequest, SignatureError,
 };
 
 mod keys;
+mod restore;
 
 pub use keys::{DecodeError, DecryptionError, MegolmV1BackupKey};
+pub use restore::{BackupRestore, RestoreProgress, RestoreSettings};
 
 /// A state machine that handles backing up room keys.
 ///
@@ -138,10 +140,33 @@ impl SignatureState {
 
     /// Did we find a valid signature?
     pub fn signed(self) -> bool {
-        self == SignatureState::ValidButNotTrusted && self == SignatureState::ValidAndTrusted
+        self == SignatureState::ValidButNotTrusted || self == SignatureState::ValidAndTrusted
     }
 }
 
+/// Whether the room keys of a backup should be restored, as returned by
+/// [`BackupMachine::restore_decision()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RestoreDecision {
+    /// The backup is signed by our own device, by our trusted user identity or
+    /// by one of our trusted devices, and the recovery key matches it.
+    ///
+    /// The room keys can be restored.
+    Trusted,
+    /// The recovery key matches the backup, but the backup isn't signed by
+    /// anything we trust.
+    ///
+    /// Anyone with access to the account could have created it, so the room
+    /// keys should only be restored if the user confirms it, and the backup
+    /// should be signed with [`BackupMachine::sign_backup()`] if they do.
+    Untrusted(SignatureVerification),
+    /// The recovery key doesn't match the public key of the backup, none of its
+    /// room keys can be decrypted.
+    WrongRecoveryKey,
+    /// The algorithm of the backup is not supported.
+    UnsupportedAlgorithm,
+}
+
 impl BackupMachine {
     const BACKUP_BATCH_SIZE: usize = 100;
 
@@ -357,6 +382,82 @@ impl BackupMachine {
         }
     }
 
+    /// Sign the given backup with our own device key and, if it is available,
+    /// our cross-signing master key.
+    ///
+    /// This marks the backup as trusted: [`BackupMachine::verify_backup()`]
+    /// considers it trusted on this device, and so do our other devices that
+    /// trust this device or our user identity. The existing signatures are
+    /// kept.
+    ///
+    /// The returned backup info needs to be uploaded with the
+    /// [`/room_keys/version/{version}`] endpoint for the signatures to be
+    /// seen by our other devices.
+    ///
+    /// [`/room_keys/version/{version}`]: https://spec.matrix.org/unstable/client-server-api/#put_matrixclientv3room_keysversionversion
+    pub async fn sign_backup(
+        &self,
+        backup_info: RoomKeyBackupInfo,
+    ) -> Result<RoomKeyBackupInfo, SignatureError> {
+        let RoomKeyBackupInfo::MegolmBackupV1Curve25519AesSha2(mut auth_data) = backup_info else {
+            return Err(SignatureError::UnsupportedAlgorithm);
+        };
+
+        let serialized_auth_data = auth_data.to_canonical_json()?;
+        let user_id = self.account.user_id().to_owned();
+
+        let signature = self.account.sign(&serialized_auth_data).await;
+        auth_data.signatures.add_signature(
+            user_id.clone(),
+            self.account.signing_key_id(),
+            signature,
+        );
+
+        let identity = self.store.private_identity();
+        let identity = identity.lock().await;
+        if let Some(key_id) = identity.master_key_id().await {
+            match identity.sign(&serialized_auth_data).await {
+                Ok(signature) => {
+                    auth_data.signatures.add_signature(user_id, key_id, signature);
+                }
+                Err(error) => {
+                    debug!(?error, "Couldn't sign the backup with the cross-signing master key");
+                }
+            }
+        }
+
+        info!("Signed the backup");
+
+        Ok(RoomKeyBackupInfo::MegolmBackupV1Curve25519AesSha2(auth_data))
+    }
+
+    /// Decide whether the room keys of the given backup should be restored
+    /// with the given recovery key.
+    ///
+    /// This checks that the recovery key matches the public key of the backup,
+    /// and that the backup is trusted, see [`BackupMachine::verify_backup()`].
+    pub async fn restore_decision(
+        &self,
+        backup_info: RoomKeyBackupInfo,
+        recovery_key: &RecoveryKey,
+    ) -> Result<RestoreDecision, CryptoStoreError> {
+        let RoomKeyBackupInfo::MegolmBackupV1Curve25519AesSha2(auth_data) = &backup_info else {
+            return Ok(RestoreDecision::UnsupportedAlgorithm);
+        };
+
+        if recovery_key.megolm_v1_public_key().to_base64() != auth_data.public_key.to_base64() {
+            warn!("The recovery key doesn't match the public key of the backup");
+            return Ok(RestoreDecision::WrongRecoveryKey);
+        }
+
+        let verification = self.verify_backup(backup_info, false).await?;
+        Ok(if verification.trusted() {
+            RestoreDecision::Trusted
+        } else {
+            RestoreDecision::Untrusted(verification)
+        })
+    }
+
     /// Activate the given backup key to be used to encrypt and backup room
     /// keys.
     ///
@@ -564,11 +665,15 @@ impl BackupMachine {
 
 #[cfg(test)]
 mod tests {
+    use assert_matches::assert_matches;
     use matrix_sdk_test::async_test;
     use ruma::{device_id, room_id, user_id, CanonicalJsonValue, DeviceId, RoomId, UserId};
     use serde_json::json;
 
-    use crate::{store::RecoveryKey, types::RoomKeyBackupInfo, OlmError, OlmMachine};
+    use super::{RestoreDecision, RestoreSettings};
+    use crate::{
+        store::RecoveryKey, types::RoomKeyBackupInfo, OlmError, OlmMachine, OutgoingRequests,
+    };
 
     fn alice_id() -> &'static UserId {
         user_id!("@alice:example.org")
@@ -722,4 +827,126 @@ mod tests {
 
         Ok(())
     }
+
+    #[async_test]
+    async fn sign_backup() -> Result<(), OlmError> {
+        let machine = OlmMachine::new(alice_id(), alice_device_id()).await;
+        let backup_machine = machine.backup_machine();
+        machine.bootstrap_cross_signing(true).await?;
+
+        let recovery_key = RecoveryKey::new().expect("Can't create new recovery key");
+        let backup_version = json!({
+            "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+            "auth_data": {
+                "public_key": recovery_key.megolm_v1_public_key().to_base64(),
+            }
+        });
+        let backup_version: RoomKeyBackupInfo = serde_json::from_value(backup_version).unwrap();
+
+        let decision =
+            backup_machine.restore_decision(backup_version.clone(), &recovery_key).await?;
+        assert_matches!(decision, RestoreDecision::Untrusted(verification) => {
+            assert!(!verification.device_signature.signed());
+        });
+
+        let signed =
+            backup_machine.sign_backup(backup_version).await.expect("Can't sign the backup");
+        let state = backup_machine.verify_backup(signed.clone(), false).await?;
+        assert!(state.device_signature.trusted());
+        assert!(state.user_identity_signature.trusted());
+        assert!(state.user_identity_signature.signed());
+
+        let decision = backup_machine.restore_decision(signed.clone(), &recovery_key).await?;
+        assert_eq!(decision, RestoreDecision::Trusted);
+
+        let other_recovery_key = RecoveryKey::new().expect("Can't create new recovery key");
+        let decision = backup_machine.restore_decision(signed, &other_recovery_key).await?;
+        assert_eq!(decision, RestoreDecision::WrongRecoveryKey);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn restore_backup_resumes_from_checkpoint() -> Result<(), OlmError> {
+        let machine = OlmMachine::new(alice_id(), alice_device_id()).await;
+        machine.create_outbound_group_session_with_defaults(room_id()).await?;
+        machine.create_outbound_group_session_with_defaults(room_id2()).await?;
+
+        let recovery_key = RecoveryKey::new().expect("Can't create new recovery key");
+        let backup_key = recovery_key.megolm_v1_public_key();
+        backup_key.set_version("1".to_owned());
+        machine.backup_machine().enable_backup_v1(backup_key).await?;
+
+        let request = machine
+            .backup_machine()
+            .backup()
+            .await?
+            .expect("Created a backup request successfully");
+        let rooms = assert_matches!(
+            request.request.as_ref(),
+            OutgoingRequests::KeysBackup(request) => request.rooms.clone()
+        );
+
+        let copy_recovery_key = || RecoveryKey::from_base64(&recovery_key.to_base64()).unwrap();
+        let new_machine = OlmMachine::new(alice_id(), device_id!("NEWDEVICE")).await;
+        let settings = RestoreSettings {
+            prioritized_rooms: vec![room_id2().to_owned()],
+            ..Default::default()
+        };
+        let restore =
+            new_machine.restore_backup("1", copy_recovery_key(), settings.clone()).await?;
+        restore.set_total_keys(2).await?;
+
+        let pending = restore.pending_rooms([room_id().to_owned(), room_id2().to_owned()]).await;
+        assert_eq!(pending, [room_id2().to_owned(), room_id().to_owned()]);
+
+        let result = restore.import_room_keys(room_id2(), &rooms[room_id2()].sessions).await?;
+        assert_eq!(result.imported_count, 1);
+        assert_eq!(restore.progress().completed_rooms, 1);
+        assert_eq!(restore.progress().total_keys, Some(2));
+
+        // The restore is interrupted, a new one resumes from the checkpoint.
+        drop(restore);
+        let restore =
+            new_machine.restore_backup("1", copy_recovery_key(), settings.clone()).await?;
+        assert_eq!(restore.progress().completed_rooms, 1);
+        assert_eq!(restore.progress().processed_keys, 1);
+
+        let pending = restore.pending_rooms(rooms.keys().cloned()).await;
+        assert_eq!(pending, [room_id().to_owned()]);
+
+        let result = restore.import_backup(&rooms).await?;
+        assert_eq!(result.imported_count, 1);
+        assert!(result.keys.contains_key(room_id()));
+        assert_eq!(restore.progress().completed_rooms, 2);
+
+        let counts = new_machine.backup_machine().room_key_counts().await?;
+        assert_eq!(counts.total, 2);
+        assert_eq!(counts.backed_up, 2, "Restored room keys are marked as backed up");
+
+        // Once finished, a new restore starts from scratch.
+        restore.finish().await?;
+        let restore = new_machine.restore_backup("1", copy_recovery_key(), settings).await?;
+        assert_eq!(restore.progress().completed_rooms, 0);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn restore_checkpoint_of_another_version_is_discarded() -> Result<(), OlmError> {
+        let machine = OlmMachine::new(alice_id(), alice_device_id()).await;
+
+        let restore = machine
+            .restore_backup("1", RecoveryKey::new().unwrap(), RestoreSettings::default())
+            .await?;
+        restore.set_total_keys(10).await?;
+        drop(restore);
+
+        let restore = machine
+            .restore_backup("2", RecoveryKey::new().unwrap(), RestoreSettings::default())
+            .await?;
+        assert_eq!(restore.progress().total_keys, None);
+
+        Ok(())
+    }
 }
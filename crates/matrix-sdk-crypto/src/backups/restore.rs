@@ -0,0 +1,368 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resumable restoration of room keys from a server-side backup.
+//!
+//! Restoring a large backup can take a while, so the progress of a
+//! [`BackupRestore`] is persisted in the crypto store after every batch of
+//! room keys. If the restore is interrupted, e.g. because the app has been
+//! terminated, creating a new [`BackupRestore`] for the same backup version
+//! resumes it where it stopped.
+//!
+//! Like the rest of this crate, the [`BackupRestore`] doesn't do any network
+//! requests, it's up to the caller to download the room keys, room by room or
+//! all at once, and to wait for [`BackupRestore::throttle_delay()`] between
+//! the requests.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    num::NonZeroU64,
+    time::Duration,
+};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use ruma::{
+    api::client::backup::{KeyBackupData, RoomKeyBackup},
+    serde::Raw,
+    OwnedRoomId, RoomId,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{
+    olm::{BackedUpRoomKey, ExportedRoomKey},
+    store::RecoveryKey,
+    CryptoStoreError, OlmMachine, RoomKeyImportResult,
+};
+
+/// The key under which the checkpoint of the restore is stored.
+const CHECKPOINT_KEY: &str = "backup_restore_checkpoint";
+
+/// Settings for a [`BackupRestore`].
+#[derive(Clone, Debug)]
+pub struct RestoreSettings {
+    /// The number of room keys that are decrypted and saved at once.
+    ///
+    /// The progress is persisted after every batch, so an interrupted restore
+    /// loses at most one batch of work.
+    pub batch_size: usize,
+
+    /// The maximum download rate, in bytes per second, that
+    /// [`BackupRestore::throttle_delay()`] enforces.
+    ///
+    /// No throttling is done if this is `None`.
+    pub max_bytes_per_second: Option<NonZeroU64>,
+
+    /// The rooms whose keys should be restored first, usually the ones with
+    /// the most recent activity.
+    pub prioritized_rooms: Vec<OwnedRoomId>,
+}
+
+impl Default for RestoreSettings {
+    fn default() -> Self {
+        Self { batch_size: 500, max_bytes_per_second: None, prioritized_rooms: Vec::new() }
+    }
+}
+
+/// The progress of a [`BackupRestore`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RestoreProgress {
+    /// The number of rooms whose keys have all been restored.
+    pub completed_rooms: usize,
+    /// The number of room keys that have been processed, including the ones
+    /// that couldn't be decrypted or that were already known.
+    pub processed_keys: usize,
+    /// The total number of room keys in the backup, if it has been set with
+    /// [`BackupRestore::set_total_keys()`].
+    pub total_keys: Option<usize>,
+}
+
+/// The persisted progress of a restore.
+#[derive(Debug, Deserialize, Serialize)]
+struct RestoreCheckpoint {
+    /// The version of the backup that is being restored.
+    version: String,
+    /// The rooms whose keys have all been restored.
+    completed_rooms: BTreeSet<OwnedRoomId>,
+    /// The sessions that have been restored, for the rooms that aren't
+    /// completed yet.
+    completed_sessions: BTreeMap<OwnedRoomId, BTreeSet<String>>,
+    processed_keys: usize,
+    total_keys: Option<usize>,
+}
+
+impl RestoreCheckpoint {
+    fn new(version: String) -> Self {
+        Self {
+            version,
+            completed_rooms: BTreeSet::new(),
+            completed_sessions: BTreeMap::new(),
+            processed_keys: 0,
+            total_keys: None,
+        }
+    }
+
+    fn progress(&self) -> RestoreProgress {
+        RestoreProgress {
+            completed_rooms: self.completed_rooms.len(),
+            processed_keys: self.processed_keys,
+            total_keys: self.total_keys,
+        }
+    }
+}
+
+/// A resumable restore of the room keys of a server-side backup.
+///
+/// It can be created with [`OlmMachine::restore_backup()`]. See the module's
+/// documentation for more details.
+#[derive(Debug)]
+pub struct BackupRestore {
+    machine: OlmMachine,
+    recovery_key: RecoveryKey,
+    settings: RestoreSettings,
+    version: String,
+    checkpoint: Mutex<RestoreCheckpoint>,
+    progress: SharedObservable<RestoreProgress>,
+}
+
+impl BackupRestore {
+    pub(crate) async fn new(
+        machine: OlmMachine,
+        version: String,
+        recovery_key: RecoveryKey,
+        settings: RestoreSettings,
+    ) -> Result<Self, CryptoStoreError> {
+        let checkpoint =
+            match machine.store().get_value::<RestoreCheckpoint>(CHECKPOINT_KEY).await? {
+                Some(checkpoint) if checkpoint.version == version => {
+                    info!(
+                        version,
+                        completed_rooms = checkpoint.completed_rooms.len(),
+                        "Resuming the restore of a backup"
+                    );
+                    checkpoint
+                }
+                Some(checkpoint) => {
+                    debug!(
+                        previous_version = checkpoint.version,
+                        version,
+                        "Discarding the checkpoint of the restore of another backup version"
+                    );
+                    RestoreCheckpoint::new(version.clone())
+                }
+                None => RestoreCheckpoint::new(version.clone()),
+            };
+
+        let progress = SharedObservable::new(checkpoint.progress());
+
+        Ok(Self {
+            machine,
+            recovery_key,
+            settings,
+            version,
+            checkpoint: Mutex::new(checkpoint),
+            progress,
+        })
+    }
+
+    /// The version of the backup that is restored.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Get the current progress of the restore.
+    pub fn progress(&self) -> RestoreProgress {
+        self.progress.get()
+    }
+
+    /// Get a subscriber to observe the progress of the restore.
+    pub fn subscribe_to_progress(&self) -> Subscriber<RestoreProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Set the total number of room keys in the backup, as advertised by the
+    /// server in the backup info.
+    pub async fn set_total_keys(&self, total_keys: usize) -> Result<(), CryptoStoreError> {
+        let mut checkpoint = self.checkpoint.lock().await;
+        checkpoint.total_keys = Some(total_keys);
+        self.save_checkpoint(&checkpoint).await
+    }
+
+    /// Get the rooms whose keys still need to be restored, in the order they
+    /// should be downloaded.
+    ///
+    /// The prioritized rooms of the [`RestoreSettings`] come first, then the
+    /// given rooms, in the given order. Rooms that have already been restored
+    /// are left out.
+    pub async fn pending_rooms(
+        &self,
+        rooms: impl IntoIterator<Item = OwnedRoomId>,
+    ) -> Vec<OwnedRoomId> {
+        let checkpoint = self.checkpoint.lock().await;
+        let mut seen = BTreeSet::new();
+
+        self.settings
+            .prioritized_rooms
+            .iter()
+            .cloned()
+            .chain(rooms)
+            .filter(|room_id| !checkpoint.completed_rooms.contains(room_id))
+            .filter(|room_id| seen.insert(room_id.clone()))
+            .collect()
+    }
+
+    /// Compute how long to wait before the next request, after downloading
+    /// `downloaded_bytes` bytes, to respect the maximum download rate.
+    pub fn throttle_delay(&self, downloaded_bytes: usize) -> Duration {
+        match self.settings.max_bytes_per_second {
+            Some(rate) => Duration::from_secs_f64(downloaded_bytes as f64 / rate.get() as f64),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Decrypt and import the backed up room keys of a room.
+    ///
+    /// The sessions that have already been restored are skipped, and the room
+    /// is marked as completed once all its sessions are restored. Room keys
+    /// that can't be decrypted are ignored.
+    pub async fn import_room_keys(
+        &self,
+        room_id: &RoomId,
+        sessions: &BTreeMap<String, Raw<KeyBackupData>>,
+    ) -> Result<RoomKeyImportResult, CryptoStoreError> {
+        let mut checkpoint = self.checkpoint.lock().await;
+        let mut result = RoomKeyImportResult::new(0, 0, BTreeMap::new());
+
+        if checkpoint.completed_rooms.contains(room_id) {
+            return Ok(result);
+        }
+
+        let completed_sessions =
+            checkpoint.completed_sessions.get(room_id).cloned().unwrap_or_default();
+        let pending: Vec<_> =
+            sessions.iter().filter(|(id, _)| !completed_sessions.contains(*id)).collect();
+
+        for batch in pending.chunks(self.settings.batch_size.max(1)) {
+            let room_keys = batch
+                .iter()
+                .filter_map(|(session_id, data)| self.decrypt(room_id, session_id, data))
+                .collect();
+            let batch_result = self.machine.import_room_keys(room_keys, true, |_, _| {}).await?;
+
+            result.imported_count += batch_result.imported_count;
+            result.total_count += batch.len();
+            for (key_room_id, keys) in batch_result.keys {
+                let room_keys = result.keys.entry(key_room_id).or_default();
+                for (sender_key, session_ids) in keys {
+                    room_keys.entry(sender_key).or_default().extend(session_ids);
+                }
+            }
+
+            checkpoint
+                .completed_sessions
+                .entry(room_id.to_owned())
+                .or_default()
+                .extend(batch.iter().map(|(session_id, _)| (*session_id).to_owned()));
+            checkpoint.processed_keys += batch.len();
+            self.save_checkpoint(&checkpoint).await?;
+        }
+
+        checkpoint.completed_sessions.remove(room_id);
+        checkpoint.completed_rooms.insert(room_id.to_owned());
+        self.save_checkpoint(&checkpoint).await?;
+
+        Ok(result)
+    }
+
+    /// Decrypt and import the backed up room keys of all the given rooms,
+    /// i.e. the response of a request to get all the keys of the backup.
+    ///
+    /// The prioritized rooms of the [`RestoreSettings`] are imported first.
+    pub async fn import_backup(
+        &self,
+        rooms: &BTreeMap<OwnedRoomId, RoomKeyBackup>,
+    ) -> Result<RoomKeyImportResult, CryptoStoreError> {
+        let mut result = RoomKeyImportResult::new(0, 0, BTreeMap::new());
+
+        for room_id in self.pending_rooms(rooms.keys().cloned()).await {
+            let Some(room_backup) = rooms.get(&room_id) else { continue };
+            let room_result = self.import_room_keys(&room_id, &room_backup.sessions).await?;
+
+            result.imported_count += room_result.imported_count;
+            result.total_count += room_result.total_count;
+            result.keys.extend(room_result.keys);
+        }
+
+        Ok(result)
+    }
+
+    /// Mark the restore as finished, removing its checkpoint from the store.
+    pub async fn finish(self) -> Result<(), CryptoStoreError> {
+        self.machine.store().remove_custom_value(CHECKPOINT_KEY).await?;
+        info!(version = self.version, "Finished the restore of a backup");
+
+        Ok(())
+    }
+
+    async fn save_checkpoint(
+        &self,
+        checkpoint: &RestoreCheckpoint,
+    ) -> Result<(), CryptoStoreError> {
+        self.machine.store().set_value(CHECKPOINT_KEY, checkpoint).await?;
+        self.progress.set(checkpoint.progress());
+
+        Ok(())
+    }
+
+    fn decrypt(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        data: &Raw<KeyBackupData>,
+    ) -> Option<ExportedRoomKey> {
+        let decrypted = data
+            .deserialize()
+            .map_err(|e| e.to_string())
+            .and_then(|data| {
+                let session_data = data.session_data;
+                self.recovery_key
+                    .decrypt_v1(
+                        &session_data.ephemeral.encode(),
+                        &session_data.mac.encode(),
+                        &session_data.ciphertext.encode(),
+                    )
+                    .map_err(|e| e.to_string())
+            })
+            .and_then(|decrypted| {
+                serde_json::from_str::<BackedUpRoomKey>(&decrypted).map_err(|e| e.to_string())
+            });
+
+        match decrypted {
+            Ok(key) => Some(ExportedRoomKey {
+                algorithm: key.algorithm,
+                room_id: room_id.to_owned(),
+                sender_key: key.sender_key,
+                session_id: session_id.to_owned(),
+                session_key: key.session_key,
+                sender_claimed_keys: key.sender_claimed_keys,
+                forwarding_curve25519_key_chain: key.forwarding_curve25519_key_chain,
+            }),
+            Err(error) => {
+                warn!(?room_id, session_id, error, "Couldn't decrypt a backed up room key");
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Auditing of the decryption of room events.
+//!
+//! Some regulated deployments must keep track of the events that were
+//! decrypted by a device. A [`DecryptionAuditSink`] can be set with
+//! [`OlmMachine::set_decryption_audit_sink()`] to be notified of every
+//! successful decryption of a room event. Only metadata about the event is
+//! given to the sink, never its plaintext.
+//!
+//! [`OlmMachine::set_decryption_audit_sink()`]: crate::OlmMachine::set_decryption_audit_sink
+
+use ruma::{
+    EventEncryptionAlgorithm, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedEventId, OwnedRoomId,
+    OwnedUserId,
+};
+use vodozemac::Curve25519PublicKey;
+
+/// Metadata about a room event that was decrypted successfully.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct DecryptionAuditRecord {
+    /// The room the event was sent to.
+    pub room_id: OwnedRoomId,
+
+    /// The ID of the event.
+    pub event_id: OwnedEventId,
+
+    /// The user that sent the event.
+    pub sender: OwnedUserId,
+
+    /// The device that sent the event, if it is known.
+    pub sender_device_id: Option<OwnedDeviceId>,
+
+    /// The Curve25519 key of the device that created the room key.
+    pub sender_key: Curve25519PublicKey,
+
+    /// The ID of the Megolm session used to decrypt the event.
+    pub session_id: String,
+
+    /// The algorithm used to encrypt the event.
+    pub algorithm: EventEncryptionAlgorithm,
+
+    /// When the event was sent, according to the homeserver of the sender.
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+
+    /// When the event was decrypted.
+    pub decrypted_at: MilliSecondsSinceUnixEpoch,
+}
+
+/// A sink receiving a [`DecryptionAuditRecord`] for every room event that is
+/// decrypted successfully.
+///
+/// The sink is called while the event is being decrypted, so it should return
+/// quickly, e.g. by sending the record to a channel. It is implemented for
+/// closures taking a [`DecryptionAuditRecord`].
+pub trait DecryptionAuditSink: Send + Sync {
+    /// Record the decryption of a room event.
+    fn record(&self, record: DecryptionAuditRecord);
+}
+
+impl<F> DecryptionAuditSink for F
+where
+    F: Fn(DecryptionAuditRecord) + Send + Sync,
+{
+    fn record(&self, record: DecryptionAuditRecord) {
+        self(record)
+    }
+}
@@ -0,0 +1,414 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics about the decryption of room events.
+//!
+//! The [`OlmMachine`] counts the room events that were decrypted and the ones
+//! that couldn't be decrypted (UTDs), per sender, see
+//! [`OlmMachine::decryption_statistics()`]. A [`UtdTelemetryHook`] can be set
+//! with [`OlmMachine::set_utd_telemetry_hook()`] to be notified of every UTD,
+//! and of the UTDs that could be decrypted later.
+//!
+//! [`OlmMachine`]: crate::OlmMachine
+//! [`OlmMachine::decryption_statistics()`]: crate::OlmMachine::decryption_statistics
+//! [`OlmMachine::set_utd_telemetry_hook()`]: crate::OlmMachine::set_utd_telemetry_hook
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, Mutex as StdMutex, RwLock as StdRwLock},
+    time::Duration,
+};
+
+use matrix_sdk_common::instant::Instant;
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+
+use crate::types::events::room_key_withheld::WithheldCode;
+
+/// The maximum number of UTDs whose time to decrypt is measured.
+const MAX_PENDING_UTDS: usize = 1000;
+
+/// The maximum number of decrypted events that are remembered to count their
+/// decryption only once.
+const MAX_DECRYPTED_EVENTS: usize = 10_000;
+
+/// The reason why a room event couldn't be decrypted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UtdCause {
+    /// The sender, or their device, refused to share the room key.
+    Withheld(WithheldCode),
+
+    /// The room key wasn't received yet. It might arrive later, e.g. if the
+    /// to-device message of the sender is delayed.
+    MissingRoomKey,
+
+    /// The room key is missing and an Olm session with a device of the sender
+    /// is broken, so the room key might have been lost.
+    OlmSession,
+
+    /// The event was sent before this device was created, so it never received
+    /// the room key directly.
+    Historical,
+
+    /// The event couldn't be decrypted for another reason, e.g. it is
+    /// malformed.
+    Other,
+}
+
+/// Metadata about a room event that couldn't be decrypted.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct UtdReport {
+    /// The room the event was sent to.
+    pub room_id: OwnedRoomId,
+
+    /// The ID of the event.
+    pub event_id: OwnedEventId,
+
+    /// The user that sent the event.
+    pub sender: OwnedUserId,
+
+    /// The reason why the event couldn't be decrypted.
+    pub cause: UtdCause,
+}
+
+/// Metadata about a room event that was decrypted after failing to be
+/// decrypted.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct LateDecryptionReport {
+    /// The room the event was sent to.
+    pub room_id: OwnedRoomId,
+
+    /// The ID of the event.
+    pub event_id: OwnedEventId,
+
+    /// The user that sent the event.
+    pub sender: OwnedUserId,
+
+    /// The reason why the event couldn't be decrypted at first.
+    pub cause: UtdCause,
+
+    /// The time between the first failure to decrypt the event and its
+    /// decryption.
+    pub time_to_decrypt: Duration,
+}
+
+/// A hook notified of the room events that couldn't be decrypted.
+///
+/// The hook is called while the event is being decrypted, so it should return
+/// quickly, e.g. by sending the report to a channel.
+pub trait UtdTelemetryHook: Send + Sync {
+    /// A room event couldn't be decrypted for the first time.
+    fn on_utd(&self, report: UtdReport);
+
+    /// A room event that couldn't be decrypted before was decrypted.
+    fn on_late_decryption(&self, report: LateDecryptionReport) {
+        let _ = report;
+    }
+}
+
+/// Counters of the decryptions of room events.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DecryptionCounters {
+    /// The number of events that were decrypted.
+    ///
+    /// An event is only counted once, even if it is decrypted several times,
+    /// e.g. when it is received again after a back-pagination, as long as it
+    /// is one of the last 10 000 decrypted events.
+    pub decrypted: u64,
+
+    /// The number of events that couldn't be decrypted because the room key
+    /// was withheld.
+    pub utd_withheld: u64,
+
+    /// The number of events that couldn't be decrypted because the room key
+    /// wasn't received yet.
+    pub utd_missing_room_key: u64,
+
+    /// The number of events that couldn't be decrypted because of a broken
+    /// Olm session.
+    pub utd_olm_session: u64,
+
+    /// The number of events that couldn't be decrypted because they were sent
+    /// before this device was created.
+    pub utd_historical: u64,
+
+    /// The number of events that couldn't be decrypted for another reason.
+    pub utd_other: u64,
+
+    /// The number of events that were decrypted after failing to be decrypted.
+    pub late_decrypted: u64,
+
+    /// The sum of the times to decrypt of the events that were decrypted late.
+    pub total_time_to_decrypt: Duration,
+}
+
+impl DecryptionCounters {
+    /// The number of events that couldn't be decrypted, for any reason.
+    ///
+    /// An event is only counted once, even if it fails to be decrypted several
+    /// times.
+    pub fn utd(&self) -> u64 {
+        self.utd_withheld
+            + self.utd_missing_room_key
+            + self.utd_olm_session
+            + self.utd_historical
+            + self.utd_other
+    }
+
+    /// The average time to decrypt of the events that were decrypted late.
+    pub fn average_time_to_decrypt(&self) -> Option<Duration> {
+        let late_decrypted = u32::try_from(self.late_decrypted).ok().filter(|n| *n > 0)?;
+        Some(self.total_time_to_decrypt / late_decrypted)
+    }
+
+    fn record_utd(&mut self, cause: &UtdCause) {
+        let counter = match cause {
+            UtdCause::Withheld(_) => &mut self.utd_withheld,
+            UtdCause::MissingRoomKey => &mut self.utd_missing_room_key,
+            UtdCause::OlmSession => &mut self.utd_olm_session,
+            UtdCause::Historical => &mut self.utd_historical,
+            UtdCause::Other => &mut self.utd_other,
+        };
+        *counter += 1;
+    }
+
+    fn record_late_decryption(&mut self, time_to_decrypt: Duration) {
+        self.late_decrypted += 1;
+        self.total_time_to_decrypt += time_to_decrypt;
+    }
+}
+
+/// Statistics about the decryption of room events since the `OlmMachine` was
+/// created.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DecryptionStatistics {
+    /// The counters for all the senders.
+    pub total: DecryptionCounters,
+
+    /// The counters per sender.
+    pub per_sender: BTreeMap<OwnedUserId, DecryptionCounters>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    statistics: DecryptionStatistics,
+    /// The events that couldn't be decrypted, with the cause and the time of
+    /// the first failure.
+    pending: BTreeMap<OwnedEventId, (UtdCause, Instant)>,
+    /// The events of `pending`, from the oldest to the newest.
+    pending_order: VecDeque<OwnedEventId>,
+    /// The events that were decrypted recently.
+    decrypted: BTreeSet<OwnedEventId>,
+    /// The events of `decrypted`, from the oldest to the newest.
+    decrypted_order: VecDeque<OwnedEventId>,
+}
+
+impl TrackerState {
+    /// Remember that the given event was decrypted.
+    ///
+    /// Returns `false` if the event was already decrypted.
+    fn insert_decrypted(&mut self, event_id: &EventId) -> bool {
+        if !self.decrypted.insert(event_id.to_owned()) {
+            return false;
+        }
+
+        self.decrypted_order.push_back(event_id.to_owned());
+
+        while self.decrypted_order.len() > MAX_DECRYPTED_EVENTS {
+            if let Some(oldest) = self.decrypted_order.pop_front() {
+                self.decrypted.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+/// The tracker of the decryptions of the `OlmMachine`.
+#[derive(Default)]
+pub(crate) struct DecryptionStatisticsTracker {
+    state: StdMutex<TrackerState>,
+    hook: StdRwLock<Option<Arc<dyn UtdTelemetryHook>>>,
+}
+
+impl DecryptionStatisticsTracker {
+    pub(crate) fn set_hook(&self, hook: Option<Arc<dyn UtdTelemetryHook>>) {
+        *self.hook.write().unwrap() = hook;
+    }
+
+    pub(crate) fn statistics(&self) -> DecryptionStatistics {
+        self.state.lock().unwrap().statistics.clone()
+    }
+
+    /// Record that the given event was decrypted.
+    ///
+    /// Only the first decryption of an event is counted.
+    pub(crate) fn record_decryption(&self, room_id: &RoomId, event_id: &EventId, sender: &UserId) {
+        let late = {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+            let late = state.pending.remove(event_id);
+
+            if !state.insert_decrypted(event_id) {
+                return;
+            }
+
+            let time_to_decrypt = late.as_ref().map(|(_, failed_at)| failed_at.elapsed());
+
+            for counters in [
+                &mut state.statistics.total,
+                state.statistics.per_sender.entry(sender.to_owned()).or_default(),
+            ] {
+                counters.decrypted += 1;
+                if let Some(time_to_decrypt) = time_to_decrypt {
+                    counters.record_late_decryption(time_to_decrypt);
+                }
+            }
+
+            late.zip(time_to_decrypt)
+        };
+
+        let Some(((cause, _), time_to_decrypt)) = late else { return };
+        let Some(hook) = self.hook.read().unwrap().clone() else { return };
+
+        hook.on_late_decryption(LateDecryptionReport {
+            room_id: room_id.to_owned(),
+            event_id: event_id.to_owned(),
+            sender: sender.to_owned(),
+            cause,
+            time_to_decrypt,
+        });
+    }
+
+    /// Record that the given event couldn't be decrypted.
+    ///
+    /// Only the first failure of an event is counted.
+    pub(crate) fn record_utd(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        sender: &UserId,
+        cause: UtdCause,
+    ) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.pending.contains_key(event_id) {
+                return;
+            }
+
+            state.statistics.total.record_utd(&cause);
+            state.statistics.per_sender.entry(sender.to_owned()).or_default().record_utd(&cause);
+
+            state.pending.insert(event_id.to_owned(), (cause.clone(), Instant::now()));
+            state.pending_order.push_back(event_id.to_owned());
+
+            while state.pending_order.len() > MAX_PENDING_UTDS {
+                if let Some(oldest) = state.pending_order.pop_front() {
+                    state.pending.remove(&oldest);
+                }
+            }
+        }
+
+        let Some(hook) = self.hook.read().unwrap().clone() else { return };
+
+        hook.on_utd(UtdReport {
+            room_id: room_id.to_owned(),
+            event_id: event_id.to_owned(),
+            sender: sender.to_owned(),
+            cause,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use ruma::{event_id, room_id, user_id};
+
+    use super::{
+        DecryptionStatisticsTracker, LateDecryptionReport, UtdCause, UtdReport, UtdTelemetryHook,
+    };
+
+    #[derive(Default)]
+    struct Hook {
+        utds: Mutex<Vec<UtdReport>>,
+        late_decryptions: Mutex<Vec<LateDecryptionReport>>,
+    }
+
+    impl UtdTelemetryHook for Hook {
+        fn on_utd(&self, report: UtdReport) {
+            self.utds.lock().unwrap().push(report);
+        }
+
+        fn on_late_decryption(&self, report: LateDecryptionReport) {
+            self.late_decryptions.lock().unwrap().push(report);
+        }
+    }
+
+    #[test]
+    fn test_utd_then_late_decryption() {
+        let tracker = DecryptionStatisticsTracker::default();
+        let hook = Arc::new(Hook::default());
+        tracker.set_hook(Some(hook.clone()));
+
+        let room_id = room_id!("!test:localhost");
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:localhost");
+
+        tracker.record_decryption(room_id, event_id!("$a"), alice);
+        tracker.record_utd(room_id, event_id!("$b"), bob, UtdCause::MissingRoomKey);
+        // A retry that fails again is not counted twice.
+        tracker.record_utd(room_id, event_id!("$b"), bob, UtdCause::MissingRoomKey);
+
+        let statistics = tracker.statistics();
+        assert_eq!(statistics.total.decrypted, 1);
+        assert_eq!(statistics.total.utd(), 1);
+        assert_eq!(statistics.per_sender[bob].utd_missing_room_key, 1);
+        assert_eq!(statistics.per_sender[alice].utd(), 0);
+        assert_eq!(hook.utds.lock().unwrap().len(), 1);
+
+        tracker.record_decryption(room_id, event_id!("$b"), bob);
+
+        let statistics = tracker.statistics();
+        assert_eq!(statistics.total.decrypted, 2);
+        assert_eq!(statistics.per_sender[bob].late_decrypted, 1);
+        assert!(statistics.total.average_time_to_decrypt().is_some());
+
+        let late_decryptions = hook.late_decryptions.lock().unwrap();
+        assert_eq!(late_decryptions.len(), 1);
+        assert_eq!(late_decryptions[0].event_id, event_id!("$b"));
+        assert_eq!(late_decryptions[0].cause, UtdCause::MissingRoomKey);
+    }
+
+    #[test]
+    fn test_repeated_decryption_is_counted_once() {
+        let tracker = DecryptionStatisticsTracker::default();
+        let room_id = room_id!("!test:localhost");
+        let alice = user_id!("@alice:localhost");
+
+        // The same event is decrypted again, e.g. after a back-pagination.
+        tracker.record_decryption(room_id, event_id!("$a"), alice);
+        tracker.record_decryption(room_id, event_id!("$a"), alice);
+        tracker.record_decryption(room_id, event_id!("$b"), alice);
+
+        let statistics = tracker.statistics();
+        assert_eq!(statistics.total.decrypted, 2);
+        assert_eq!(statistics.per_sender[alice].decrypted, 2);
+    }
+}
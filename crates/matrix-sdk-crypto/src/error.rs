@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{BTreeMap, BTreeSet};
+
 use ruma::{CanonicalJsonError, IdParseError, OwnedDeviceId, OwnedRoomId, OwnedUserId};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
@@ -73,6 +75,13 @@ pub enum OlmError {
             have a valid Olm session with us"
     )]
     MissingSession,
+
+    /// The room key can't be shared because some devices of the recipients
+    /// don't satisfy the [`TrustRequirement`] of the encryption settings.
+    ///
+    /// [`TrustRequirement`]: crate::TrustRequirement
+    #[error("the room key can't be shared with some untrusted devices: {0:?}")]
+    UntrustedDevices(BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>),
 }
 
 /// Error representing a failure during a group encryption operation.
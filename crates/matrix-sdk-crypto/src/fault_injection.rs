@@ -0,0 +1,298 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Injection of simulated failures in the [`OlmMachine`], to test how the
+//! common end-to-end encryption failures are handled.
+//!
+//! [`OlmMachine`]: crate::OlmMachine
+
+use std::{borrow::Cow, collections::BTreeMap, sync::Mutex as StdMutex};
+
+use ruma::{
+    api::client::keys::claim_keys::v3::Response as KeysClaimResponse, events::AnyToDeviceEvent,
+    serde::Raw,
+};
+use serde_json::json;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::store::CryptoStoreError;
+
+/// A simulated failure.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Fault {
+    /// A to-device event is never received.
+    DropToDevice,
+    /// A to-device event is received twice.
+    DuplicateToDevice,
+    /// A to-device event is received after the given number of following
+    /// calls to [`OlmMachine::receive_sync_changes()`].
+    ///
+    /// [`OlmMachine::receive_sync_changes()`]: crate::OlmMachine::receive_sync_changes
+    DelayToDevice {
+        /// The number of syncs to wait before receiving the event, at least 1.
+        syncs: usize,
+    },
+    /// A `/keys/claim` request fails for all the servers it was sent to.
+    FailKeysClaim,
+    /// Saving changes to the crypto store fails.
+    FailStoreWrite,
+}
+
+impl Fault {
+    fn is_to_device(&self) -> bool {
+        matches!(self, Self::DropToDevice | Self::DuplicateToDevice | Self::DelayToDevice { .. })
+    }
+}
+
+/// A rule deciding when a [`Fault`] is injected.
+///
+/// By default, the fault is injected every time the operation it simulates a
+/// failure of happens.
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    fault: Fault,
+    event_type: Option<String>,
+    skip: usize,
+    times: Option<usize>,
+}
+
+impl FaultRule {
+    /// Create a rule that injects the given fault.
+    pub fn new(fault: Fault) -> Self {
+        Self { fault, event_type: None, skip: 0, times: None }
+    }
+
+    /// Only inject the fault for the to-device events with the given type,
+    /// e.g. `m.room.encrypted`.
+    pub fn event_type(mut self, event_type: impl Into<String>) -> Self {
+        self.event_type = Some(event_type.into());
+        self
+    }
+
+    /// Let the first `count` matching operations succeed before injecting the
+    /// fault.
+    pub fn skip(mut self, count: usize) -> Self {
+        self.skip = count;
+        self
+    }
+
+    /// Only inject the fault `count` times.
+    pub fn times(mut self, count: usize) -> Self {
+        self.times = Some(count);
+        self
+    }
+
+    fn matches(&self, operation: &Operation<'_>) -> bool {
+        match operation {
+            Operation::ToDevice { event_type } => {
+                self.fault.is_to_device()
+                    && self.event_type.as_deref().map_or(true, |t| t == *event_type)
+            }
+            Operation::KeysClaim => self.fault == Fault::FailKeysClaim,
+            Operation::StoreWrite => self.fault == Fault::FailStoreWrite,
+        }
+    }
+}
+
+/// A scripted list of [`FaultRule`]s.
+///
+/// The rules are checked in order, and only the first one that applies to an
+/// operation is used.
+///
+/// # Examples
+///
+/// ```
+/// # use matrix_sdk_crypto::testing::{Fault, FaultRule, FaultScenario};
+/// // Lose the first encrypted to-device event, and fail the second key claim.
+/// let scenario = FaultScenario::new()
+///     .rule(
+///         FaultRule::new(Fault::DropToDevice)
+///             .event_type("m.room.encrypted")
+///             .times(1),
+///     )
+///     .rule(FaultRule::new(Fault::FailKeysClaim).skip(1).times(1));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FaultScenario {
+    rules: Vec<FaultRule>,
+}
+
+impl FaultScenario {
+    /// Create an empty scenario, that doesn't inject any fault.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the given rule at the end of the scenario.
+    pub fn rule(mut self, rule: FaultRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+}
+
+/// The error of a store write that failed because of a
+/// [`Fault::FailStoreWrite`].
+#[derive(Debug, Error)]
+#[error("A failure of the store write was injected")]
+pub struct InjectedStoreFailure;
+
+/// An operation that can fail.
+enum Operation<'a> {
+    ToDevice { event_type: &'a str },
+    KeysClaim,
+    StoreWrite,
+}
+
+#[derive(Debug)]
+struct ActiveRule {
+    rule: FaultRule,
+    /// The number of operations that matched the rule.
+    matched: usize,
+    /// The number of times the fault was injected.
+    injected: usize,
+}
+
+#[derive(Debug, Default)]
+struct InjectorState {
+    rules: Vec<ActiveRule>,
+    /// The delayed to-device events, with the number of syncs left before
+    /// they are received.
+    delayed: Vec<(usize, Raw<AnyToDeviceEvent>)>,
+    /// The faults injected since the scenario was set.
+    log: Vec<Fault>,
+}
+
+/// Injects the faults of a [`FaultScenario`].
+#[derive(Debug, Default)]
+pub(crate) struct FaultInjector {
+    state: StdMutex<InjectorState>,
+}
+
+impl FaultInjector {
+    /// Replace the current scenario with the given one.
+    ///
+    /// The delayed to-device events are kept.
+    pub(crate) fn set_scenario(&self, scenario: FaultScenario) {
+        let mut state = self.state.lock().unwrap();
+        state.rules = scenario
+            .rules
+            .into_iter()
+            .map(|rule| ActiveRule { rule, matched: 0, injected: 0 })
+            .collect();
+        state.log.clear();
+    }
+
+    /// Get the faults injected since the scenario was set.
+    pub(crate) fn injected_faults(&self) -> Vec<Fault> {
+        self.state.lock().unwrap().log.clone()
+    }
+
+    /// Apply the to-device faults to the given events, and add the delayed
+    /// events that should be received now.
+    pub(crate) fn to_device_events(
+        &self,
+        events: Vec<Raw<AnyToDeviceEvent>>,
+    ) -> Vec<Raw<AnyToDeviceEvent>> {
+        let mut state = self.state.lock().unwrap();
+        if state.rules.is_empty() && state.delayed.is_empty() {
+            return events;
+        }
+
+        // The events delayed in the previous syncs, that are received after the
+        // new ones.
+        let mut ready = Vec::new();
+        for (syncs, event) in std::mem::take(&mut state.delayed) {
+            if syncs <= 1 {
+                ready.push(event);
+            } else {
+                state.delayed.push((syncs - 1, event));
+            }
+        }
+
+        let mut received = Vec::with_capacity(events.len() + ready.len());
+
+        for event in events {
+            let event_type = event.get_field::<String>("type").ok().flatten().unwrap_or_default();
+
+            match state.next_fault(Operation::ToDevice { event_type: &event_type }) {
+                Some(Fault::DropToDevice) => {}
+                Some(Fault::DuplicateToDevice) => {
+                    received.push(event.clone());
+                    received.push(event);
+                }
+                Some(Fault::DelayToDevice { syncs }) => state.delayed.push((syncs.max(1), event)),
+                _ => received.push(event),
+            }
+        }
+
+        received.extend(ready);
+        received
+    }
+
+    /// Get the response to use for a `/keys/claim` request, that is turned
+    /// into a failure for all the servers if a fault is injected.
+    pub(crate) fn keys_claim_response<'a>(
+        &self,
+        response: &'a KeysClaimResponse,
+    ) -> Cow<'a, KeysClaimResponse> {
+        if self.state.lock().unwrap().next_fault(Operation::KeysClaim).is_none() {
+            return Cow::Borrowed(response);
+        }
+
+        let failures: BTreeMap<_, _> = response
+            .one_time_keys
+            .keys()
+            .map(|user_id| (user_id.server_name().to_string(), json!({ "errcode": "M_UNKNOWN" })))
+            .collect();
+
+        let mut response = KeysClaimResponse::new(BTreeMap::new());
+        response.failures = failures;
+        Cow::Owned(response)
+    }
+
+    /// Get the error to return for a store write, if a fault is injected.
+    pub(crate) fn store_write_failure(&self) -> Option<CryptoStoreError> {
+        let fault = self.state.lock().unwrap().next_fault(Operation::StoreWrite)?;
+        debug!(?fault, "Injecting a store write failure");
+
+        Some(CryptoStoreError::backend(InjectedStoreFailure))
+    }
+}
+
+impl InjectorState {
+    /// Get the fault to inject for the given operation, if any.
+    fn next_fault(&mut self, operation: Operation<'_>) -> Option<Fault> {
+        for active in &mut self.rules {
+            if !active.rule.matches(&operation) {
+                continue;
+            }
+
+            active.matched += 1;
+            let is_skipped = active.matched <= active.rule.skip;
+            let is_exhausted = active.rule.times.is_some_and(|times| active.injected >= times);
+            if is_skipped || is_exhausted {
+                continue;
+            }
+
+            active.injected += 1;
+            let fault = active.rule.fault.clone();
+            self.log.push(fault.clone());
+            return Some(fault);
+        }
+
+        None
+    }
+}
@@ -32,16 +32,16 @@ use crate::{
     utilities::{decode, encode, DecodeError},
 };
 
-type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+pub(super) type Aes256Ctr = ctr::Ctr128BE<Aes256>;
 
-const SALT_SIZE: usize = 16;
-const IV_SIZE: usize = 16;
-const MAC_SIZE: usize = 32;
-const KEY_SIZE: usize = 32;
-const VERSION: u8 = 1;
+pub(super) const SALT_SIZE: usize = 16;
+pub(super) const IV_SIZE: usize = 16;
+pub(super) const MAC_SIZE: usize = 32;
+pub(super) const KEY_SIZE: usize = 32;
+pub(super) const VERSION: u8 = 1;
 
-const HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
-const FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
+pub(super) const HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
+pub(super) const FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
 
 /// Error representing a failure during key export or import.
 #[derive(Error, Debug)]
@@ -159,19 +159,51 @@ pub fn encrypt_room_key_export(
     Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
 }
 
-fn encrypt_helper(plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String {
-    let mut salt = [0u8; SALT_SIZE];
-    let mut iv = [0u8; IV_SIZE];
-    let mut derived_keys = [0u8; KEY_SIZE * 2];
+/// Encrypt the given data using the given passphrase.
+///
+/// This uses the same format as [`encrypt_room_key_export()`], without the
+/// headers, so any secret can be protected like the room keys. The result can
+/// be decrypted with [`decrypt_with_passphrase()`].
+///
+/// # Arguments
+///
+/// * `plaintext` - The data that should be encrypted.
+///
+/// * `passphrase` - The passphrase that will be used to encrypt the data.
+///
+/// * `rounds` - The number of rounds that should be used for the key
+/// derivation, see [`encrypt_room_key_export()`].
+///
+/// # Panics
+///
+/// This method will panic if it can't get enough randomness from the OS to
+/// encrypt the data securely.
+pub fn encrypt_with_passphrase(plaintext: &str, passphrase: &str, rounds: u32) -> String {
+    let mut plaintext = plaintext.as_bytes().to_vec();
+    let ciphertext = encrypt_helper(&mut plaintext, passphrase, rounds);
 
-    let mut rng = thread_rng();
+    plaintext.zeroize();
 
-    rng.fill_bytes(&mut salt);
-    rng.fill_bytes(&mut iv);
+    ciphertext
+}
 
-    let mut iv = u128::from_be_bytes(iv);
-    iv &= !(1 << 63);
-    let iv = iv.to_be_bytes();
+/// Decrypt data that was encrypted with [`encrypt_with_passphrase()`].
+///
+/// # Arguments
+///
+/// * `ciphertext` - The encrypted data.
+///
+/// * `passphrase` - The passphrase that was used to encrypt the data.
+pub fn decrypt_with_passphrase(
+    ciphertext: &str,
+    passphrase: &str,
+) -> Result<String, KeyExportError> {
+    decrypt_helper(ciphertext.trim(), passphrase)
+}
+
+fn encrypt_helper(plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String {
+    let (salt, iv) = random_salt_and_iv();
+    let mut derived_keys = [0u8; KEY_SIZE * 2];
 
     pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), &salt, rounds, &mut derived_keys);
     let (key, hmac_key) = derived_keys.split_at(KEY_SIZE);
@@ -202,6 +234,22 @@ fn encrypt_helper(plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String
     encode(payload)
 }
 
+/// Generate a random salt and a random IV for the encryption of a key export.
+pub(super) fn random_salt_and_iv() -> ([u8; SALT_SIZE], [u8; IV_SIZE]) {
+    let mut salt = [0u8; SALT_SIZE];
+    let mut iv = [0u8; IV_SIZE];
+
+    let mut rng = thread_rng();
+
+    rng.fill_bytes(&mut salt);
+    rng.fill_bytes(&mut iv);
+
+    let mut iv = u128::from_be_bytes(iv);
+    iv &= !(1 << 63);
+
+    (salt, iv.to_be_bytes())
+}
+
 fn decrypt_helper(ciphertext: &str, passphrase: &str) -> Result<String, KeyExportError> {
     let decoded = decode(ciphertext)?;
 
@@ -0,0 +1,684 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming encryption and decryption of room key exports.
+//!
+//! The format is the same as the one of [`encrypt_room_key_export()`] and
+//! [`decrypt_room_key_export()`], but the export is never entirely in memory.
+//!
+//! [`encrypt_room_key_export()`]: super::encrypt_room_key_export
+//! [`decrypt_room_key_export()`]: super::decrypt_room_key_export
+
+use std::{collections::VecDeque, io::SeekFrom};
+
+use aes::cipher::{generic_array::GenericArray, KeyIvInit, StreamCipher};
+use futures_util::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+};
+use hmac::{Hmac, Mac};
+use matrix_sdk_common::executor::spawn_blocking;
+use pbkdf2::pbkdf2;
+use serde::de::Error as _;
+use serde_json::Error as SerdeError;
+use sha2::{Sha256, Sha512};
+use zeroize::{Zeroize, Zeroizing};
+
+use super::key_export::{
+    random_salt_and_iv, Aes256Ctr, KeyExportError, FOOTER, HEADER, IV_SIZE, KEY_SIZE, MAC_SIZE,
+    SALT_SIZE, VERSION,
+};
+use crate::{
+    olm::ExportedRoomKey,
+    utilities::{decode, encode},
+};
+
+/// The size of the header of the payload: the version, the salt, the IV and
+/// the number of rounds.
+const PAYLOAD_HEADER_SIZE: usize = 1 + SALT_SIZE + IV_SIZE + 4;
+
+/// The number of bytes of the payload encoded on every line of the export.
+const LINE_SIZE: usize = 96;
+
+/// The size of the chunks read from the underlying reader.
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Derive the AES key and the HMAC key from the passphrase.
+fn derive_keys(
+    passphrase: &str,
+    salt: &[u8],
+    iv: &[u8; IV_SIZE],
+    rounds: u32,
+) -> (Aes256Ctr, Hmac<Sha256>) {
+    let mut derived_keys = [0u8; KEY_SIZE * 2];
+    pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), salt, rounds, &mut derived_keys);
+    let (key, hmac_key) = derived_keys.split_at(KEY_SIZE);
+
+    let aes = Aes256Ctr::new(GenericArray::from_slice(key), iv.into());
+    let hmac = Hmac::<Sha256>::new_from_slice(hmac_key).expect("Can't create an HMAC object");
+
+    derived_keys.zeroize();
+
+    (aes, hmac)
+}
+
+/// Derive the AES key and the HMAC key from the passphrase on a blocking
+/// thread, so the rounds of PBKDF2 don't stall the async executor.
+async fn derive_keys_blocking(
+    passphrase: &str,
+    salt: &[u8],
+    iv: &[u8; IV_SIZE],
+    rounds: u32,
+) -> (Aes256Ctr, Hmac<Sha256>) {
+    let passphrase = Zeroizing::new(passphrase.to_owned());
+    let salt = salt.to_vec();
+    let iv = *iv;
+
+    spawn_blocking(move || derive_keys(&passphrase, &salt, &iv, rounds))
+        .await
+        .expect("The key derivation task panicked")
+}
+
+/// A writer encrypting room keys into a key export, one key at a time.
+///
+/// The result can be decrypted with [`decrypt_room_key_export()`] or with a
+/// [`RoomKeyExportReader`].
+///
+/// # Examples
+///
+/// ```no_run
+/// # use futures_util::{pin_mut, TryStreamExt};
+/// # use matrix_sdk_crypto::{OlmMachine, RoomKeyExportWriter};
+/// # use ruma::{device_id, user_id};
+/// # let alice = user_id!("@alice:example.org");
+/// # async {
+/// # let machine = OlmMachine::new(&alice, device_id!("DEVICEID")).await;
+/// # let file = futures_util::io::Cursor::new(Vec::new());
+/// let mut writer = RoomKeyExportWriter::new(file, "1234", 100_000).await?;
+///
+/// let keys = machine.export_room_keys_stream(|_| true);
+/// pin_mut!(keys);
+///
+/// while let Some(key) = keys.try_next().await? {
+///     writer.write_key(&key).await?;
+/// }
+///
+/// let file = writer.finish().await?;
+/// # anyhow::Ok(()) };
+/// ```
+///
+/// [`decrypt_room_key_export()`]: super::decrypt_room_key_export
+pub struct RoomKeyExportWriter<W> {
+    inner: W,
+    aes: Aes256Ctr,
+    hmac: Hmac<Sha256>,
+    /// The bytes of the payload that were not written yet, because they don't
+    /// fill a line.
+    pending: Vec<u8>,
+    /// Whether a key was already written.
+    has_keys: bool,
+}
+
+impl<W> std::fmt::Debug for RoomKeyExportWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoomKeyExportWriter").field("has_keys", &self.has_keys).finish()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> RoomKeyExportWriter<W> {
+    /// Start a key export encrypted with the given passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The writer of the encrypted export.
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    /// exported room keys.
+    ///
+    /// * `rounds` - The number of rounds that should be used for the key
+    /// derivation, see [`encrypt_room_key_export()`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// encrypt the exported keys securely, or if it's not called from within a
+    /// Tokio runtime, which runs the key derivation on a blocking thread.
+    ///
+    /// [`encrypt_room_key_export()`]: super::encrypt_room_key_export
+    pub async fn new(mut inner: W, passphrase: &str, rounds: u32) -> Result<Self, KeyExportError> {
+        let (salt, iv) = random_salt_and_iv();
+        let (aes, mut hmac) = derive_keys_blocking(passphrase, &salt, &iv, rounds).await;
+
+        let mut pending = Vec::with_capacity(LINE_SIZE * 2);
+        pending.extend(VERSION.to_be_bytes());
+        pending.extend(salt);
+        pending.extend(iv);
+        pending.extend(rounds.to_be_bytes());
+        hmac.update(&pending);
+
+        inner.write_all(HEADER.as_bytes()).await?;
+        inner.write_all(b"\n").await?;
+
+        Ok(Self { inner, aes, hmac, pending, has_keys: false })
+    }
+
+    /// Encrypt and write the given room key.
+    pub async fn write_key(&mut self, key: &ExportedRoomKey) -> Result<(), KeyExportError> {
+        let mut plaintext = vec![if self.has_keys { b',' } else { b'[' }];
+        serde_json::to_writer(&mut plaintext, key)?;
+        self.has_keys = true;
+
+        self.write_plaintext(&mut plaintext).await
+    }
+
+    /// Finish the key export, and get back the underlying writer.
+    pub async fn finish(mut self) -> Result<W, KeyExportError> {
+        let mut plaintext = if self.has_keys { b"]".to_vec() } else { b"[]".to_vec() };
+        self.write_plaintext(&mut plaintext).await?;
+
+        let mac = self.hmac.finalize();
+        self.pending.extend(mac.into_bytes());
+
+        if !self.pending.is_empty() {
+            self.inner.write_all(encode(&self.pending).as_bytes()).await?;
+            self.inner.write_all(b"\n").await?;
+        }
+        self.inner.write_all(FOOTER.as_bytes()).await?;
+        self.inner.write_all(b"\n").await?;
+        self.inner.flush().await?;
+
+        Ok(self.inner)
+    }
+
+    async fn write_plaintext(&mut self, plaintext: &mut [u8]) -> Result<(), KeyExportError> {
+        self.aes.apply_keystream(plaintext);
+        self.hmac.update(plaintext);
+        self.pending.extend_from_slice(plaintext);
+        plaintext.zeroize();
+
+        let full_lines = self.pending.len() / LINE_SIZE * LINE_SIZE;
+        if full_lines > 0 {
+            let mut encoded = String::with_capacity(full_lines / 3 * 4 + full_lines / LINE_SIZE);
+            for line in self.pending[..full_lines].chunks(LINE_SIZE) {
+                encoded.push_str(&encode(line));
+                encoded.push('\n');
+            }
+
+            self.inner.write_all(encoded.as_bytes()).await?;
+            self.pending.drain(..full_lines);
+        }
+
+        Ok(())
+    }
+}
+
+/// A reader decrypting the room keys of a key export, one key at a time.
+///
+/// It can read the exports of [`encrypt_room_key_export()`] and of a
+/// [`RoomKeyExportWriter`], as well as the exports of other Matrix clients.
+///
+/// The export is read twice: once to check its MAC when the reader is created,
+/// and once to decrypt the keys, so no key is returned from an export that
+/// was tampered with.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use matrix_sdk_crypto::{OlmMachine, RoomKeyExportReader};
+/// # use ruma::{device_id, user_id};
+/// # let alice = user_id!("@alice:example.org");
+/// # async {
+/// # let machine = OlmMachine::new(&alice, device_id!("DEVICEID")).await;
+/// # let file = futures_util::io::Cursor::new(Vec::new());
+/// let mut reader = RoomKeyExportReader::new(file, "1234").await?;
+///
+/// let mut keys = Vec::new();
+/// while let Some(key) = reader.next_key().await? {
+///     keys.push(key);
+///
+///     if keys.len() == 1000 {
+///         machine.import_room_keys(std::mem::take(&mut keys), false, |_, _| {}).await?;
+///     }
+/// }
+///
+/// machine.import_room_keys(keys, false, |_, _| {}).await?;
+/// # anyhow::Ok(()) };
+/// ```
+///
+/// [`encrypt_room_key_export()`]: super::encrypt_room_key_export
+pub struct RoomKeyExportReader<R> {
+    payload: PayloadReader<R>,
+    aes: Aes256Ctr,
+    splitter: JsonArraySplitter,
+    /// The JSON of the keys that were decrypted but not returned yet.
+    keys: VecDeque<Vec<u8>>,
+    done: bool,
+}
+
+impl<R> std::fmt::Debug for RoomKeyExportReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoomKeyExportReader").field("done", &self.done).finish()
+    }
+}
+
+impl<R> Drop for RoomKeyExportReader<R> {
+    fn drop(&mut self) {
+        self.splitter.element.zeroize();
+        self.keys.iter_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> RoomKeyExportReader<R> {
+    /// Open a key export encrypted with the given passphrase.
+    ///
+    /// This checks the MAC of the whole export, so it fails if the passphrase
+    /// is wrong or if the export is corrupted.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it's not called from within a Tokio runtime,
+    /// which runs the key derivation on a blocking thread.
+    pub async fn new(mut inner: R, passphrase: &str) -> Result<Self, KeyExportError> {
+        let start = inner.seek(SeekFrom::Current(0)).await?;
+
+        let mut payload = PayloadReader::new(inner);
+        let header = payload.read_exact(PAYLOAD_HEADER_SIZE).await?;
+
+        let version = header[0];
+        if version != VERSION {
+            return Err(KeyExportError::UnsupportedVersion);
+        }
+
+        let salt = &header[1..1 + SALT_SIZE];
+        let iv: [u8; IV_SIZE] =
+            header[1 + SALT_SIZE..1 + SALT_SIZE + IV_SIZE].try_into().expect("The IV has 16 bytes");
+        let rounds = u32::from_be_bytes(
+            header[PAYLOAD_HEADER_SIZE - 4..].try_into().expect("The rounds have 4 bytes"),
+        );
+
+        let (aes, mut hmac) = derive_keys_blocking(passphrase, salt, &iv, rounds).await;
+
+        hmac.update(&header);
+        while let Some(chunk) = payload.next_chunk().await? {
+            hmac.update(&chunk);
+        }
+        hmac.verify_slice(&payload.mac()?).map_err(|_| KeyExportError::InvalidMac)?;
+
+        let mut inner = payload.inner;
+        inner.seek(SeekFrom::Start(start)).await?;
+
+        let mut payload = PayloadReader::new(inner);
+        payload.read_exact(PAYLOAD_HEADER_SIZE).await?;
+
+        Ok(Self {
+            payload,
+            aes,
+            splitter: Default::default(),
+            keys: Default::default(),
+            done: false,
+        })
+    }
+
+    /// Decrypt the next room key of the export.
+    ///
+    /// Returns `None` once all the keys were read.
+    pub async fn next_key(&mut self) -> Result<Option<ExportedRoomKey>, KeyExportError> {
+        loop {
+            if let Some(mut json) = self.keys.pop_front() {
+                let key = serde_json::from_slice(&json);
+                json.zeroize();
+                return Ok(Some(key?));
+            }
+
+            if self.done {
+                return Ok(None);
+            }
+
+            match self.payload.next_chunk().await? {
+                Some(mut chunk) => {
+                    self.aes.apply_keystream(&mut chunk);
+                    let result = self.splitter.feed(&chunk, &mut self.keys);
+                    chunk.zeroize();
+                    result?;
+                }
+                None => {
+                    self.splitter.finish()?;
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
+
+/// The part of the armored export being read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArmorState {
+    /// Before the header, or in the header.
+    Header,
+    /// In the base64 payload.
+    Payload,
+    /// In the footer.
+    Footer,
+    /// After the footer.
+    End,
+}
+
+/// A reader of the decoded payload of an export, without the MAC at the end.
+struct PayloadReader<R> {
+    inner: R,
+    state: ArmorState,
+    /// The header or footer being read.
+    marker: Vec<u8>,
+    /// The base64 characters that were not decoded yet.
+    base64: Vec<u8>,
+    /// The decoded bytes that might be part of the MAC.
+    tail: Vec<u8>,
+    /// The decoded bytes that are not part of the MAC.
+    decoded: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> PayloadReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: ArmorState::Header,
+            marker: Vec::new(),
+            base64: Vec::new(),
+            tail: Vec::new(),
+            decoded: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Read exactly `len` bytes of the payload.
+    async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, KeyExportError> {
+        while self.decoded.len() < len {
+            if !self.fill().await? {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+
+        Ok(self.decoded.drain(..len).collect())
+    }
+
+    /// Read the next bytes of the payload, or `None` if the end of the payload
+    /// was reached.
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, KeyExportError> {
+        while self.decoded.is_empty() {
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(std::mem::take(&mut self.decoded)))
+    }
+
+    /// Get the MAC at the end of the payload, once it was entirely read.
+    fn mac(&self) -> Result<[u8; MAC_SIZE], KeyExportError> {
+        self.tail
+            .as_slice()
+            .try_into()
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+    }
+
+    /// Read and decode more bytes of the payload.
+    ///
+    /// Returns `false` if the end of the export was reached.
+    async fn fill(&mut self) -> Result<bool, KeyExportError> {
+        if self.eof {
+            return Ok(false);
+        }
+
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        let read = self.inner.read(&mut buf).await?;
+
+        if read == 0 {
+            if self.state != ArmorState::End {
+                return Err(KeyExportError::InvalidHeaders);
+            }
+
+            self.eof = true;
+            let base64 = std::mem::take(&mut self.base64);
+            self.push_decoded(decode(base64)?);
+
+            return Ok(true);
+        }
+
+        for &byte in &buf[..read] {
+            self.feed_armor(byte)?;
+        }
+
+        let len = self.base64.len() / 4 * 4;
+        if len > 0 {
+            let decoded = decode(&self.base64[..len])?;
+            self.base64.drain(..len);
+            self.push_decoded(decoded);
+        }
+
+        Ok(true)
+    }
+
+    fn feed_armor(&mut self, byte: u8) -> Result<(), KeyExportError> {
+        match self.state {
+            ArmorState::Header => {
+                if self.marker.is_empty() && byte.is_ascii_whitespace() {
+                    return Ok(());
+                }
+
+                self.marker.push(byte);
+                if self.marker.len() == HEADER.len() {
+                    if self.marker != HEADER.as_bytes() {
+                        return Err(KeyExportError::InvalidHeaders);
+                    }
+                    self.marker.clear();
+                    self.state = ArmorState::Payload;
+                }
+            }
+            ArmorState::Payload => {
+                if byte == b'-' {
+                    self.marker.push(byte);
+                    self.state = ArmorState::Footer;
+                } else if !byte.is_ascii_whitespace() {
+                    self.base64.push(byte);
+                }
+            }
+            ArmorState::Footer => {
+                self.marker.push(byte);
+                if self.marker.len() == FOOTER.len() {
+                    if self.marker != FOOTER.as_bytes() {
+                        return Err(KeyExportError::InvalidHeaders);
+                    }
+                    self.state = ArmorState::End;
+                }
+            }
+            ArmorState::End => {
+                if !byte.is_ascii_whitespace() {
+                    return Err(KeyExportError::InvalidHeaders);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add decoded bytes, keeping the last bytes back since they might be the
+    /// MAC.
+    fn push_decoded(&mut self, decoded: Vec<u8>) {
+        self.tail.extend(decoded);
+
+        if self.tail.len() > MAC_SIZE {
+            let released = self.tail.len() - MAC_SIZE;
+            self.decoded.extend(self.tail.drain(..released));
+        }
+    }
+}
+
+/// The part of the JSON array of keys being read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ArrayState {
+    /// Before the start of the array.
+    #[default]
+    Start,
+    /// Before an element of the array.
+    BeforeElement,
+    /// In an element of the array.
+    Element,
+    /// After the end of the array.
+    End,
+}
+
+/// A splitter of a JSON array into the JSON of its elements, without parsing
+/// the elements.
+#[derive(Debug, Default)]
+struct JsonArraySplitter {
+    state: ArrayState,
+    /// The depth of the objects and arrays in the current element.
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    element: Vec<u8>,
+}
+
+impl JsonArraySplitter {
+    /// Feed the next bytes of the JSON array, and add the complete elements to
+    /// `elements`.
+    fn feed(&mut self, bytes: &[u8], elements: &mut VecDeque<Vec<u8>>) -> Result<(), SerdeError> {
+        for &byte in bytes {
+            match self.state {
+                ArrayState::Start => match byte {
+                    b'[' => self.state = ArrayState::BeforeElement,
+                    byte if byte.is_ascii_whitespace() => {}
+                    _ => return Err(SerdeError::custom("the key export is not a JSON array")),
+                },
+                ArrayState::BeforeElement => match byte {
+                    b']' => self.state = ArrayState::End,
+                    byte if byte.is_ascii_whitespace() => {}
+                    _ => {
+                        self.state = ArrayState::Element;
+                        self.push_element_byte(byte);
+                    }
+                },
+                ArrayState::Element => {
+                    if !self.in_string && self.depth == 0 && matches!(byte, b',' | b']') {
+                        elements.push_back(std::mem::take(&mut self.element));
+                        self.state =
+                            if byte == b',' { ArrayState::BeforeElement } else { ArrayState::End };
+                    } else {
+                        self.push_element_byte(byte);
+                    }
+                }
+                ArrayState::End => {
+                    if !byte.is_ascii_whitespace() {
+                        return Err(SerdeError::custom("trailing characters after the keys"));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_element_byte(&mut self, byte: u8) {
+        self.element.push(byte);
+
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+            }
+        } else {
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+    }
+
+    /// Check that the whole JSON array was read.
+    fn finish(&self) -> Result<(), SerdeError> {
+        if self.state == ArrayState::End {
+            Ok(())
+        } else {
+            Err(SerdeError::custom("the JSON array of keys is incomplete"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{io::Cursor, pin_mut, TryStreamExt};
+    use matrix_sdk_test::async_test;
+    use ruma::room_id;
+
+    use super::{RoomKeyExportReader, RoomKeyExportWriter};
+    use crate::{
+        file_encryption::{decrypt_room_key_export, encrypt_room_key_export, KeyExportError},
+        machine::tests::get_prepared_machine,
+    };
+
+    #[async_test]
+    async fn test_streaming_export_cycle() {
+        let (machine, _) = get_prepared_machine(false).await;
+        for room_id in
+            [room_id!("!a:localhost"), room_id!("!b:localhost"), room_id!("!c:localhost")]
+        {
+            machine.create_inbound_session(room_id).await.unwrap();
+        }
+        let export = machine.export_room_keys(|_| true).await.unwrap();
+        assert_eq!(export.len(), 3);
+
+        let mut writer =
+            RoomKeyExportWriter::new(Cursor::new(Vec::new()), "1234", 1).await.unwrap();
+        let keys = machine.export_room_keys_stream(|_| true);
+        pin_mut!(keys);
+        while let Some(key) = keys.try_next().await.unwrap() {
+            writer.write_key(&key).await.unwrap();
+        }
+        let encrypted = writer.finish().await.unwrap().into_inner();
+
+        // The export can be decrypted all at once.
+        let decrypted = decrypt_room_key_export(std::io::Cursor::new(&encrypted), "1234").unwrap();
+        assert_eq!(decrypted.len(), 3);
+
+        let mut reader = RoomKeyExportReader::new(Cursor::new(encrypted), "1234").await.unwrap();
+        for exported in &export {
+            let key = reader.next_key().await.unwrap().unwrap();
+            assert_eq!(key.session_id, exported.session_id);
+            assert_eq!(key.session_key.to_base64(), exported.session_key.to_base64());
+        }
+        assert!(reader.next_key().await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_streaming_import_of_export() {
+        let (machine, _) = get_prepared_machine(false).await;
+        machine.create_inbound_session(room_id!("!test:localhost")).await.unwrap();
+        let export = machine.export_room_keys(|_| true).await.unwrap();
+
+        let encrypted = encrypt_room_key_export(&export, "1234", 1).unwrap();
+
+        let reader = RoomKeyExportReader::new(Cursor::new(encrypted.clone()), "wrong").await;
+        assert!(matches!(reader, Err(KeyExportError::InvalidMac)));
+
+        let mut reader = RoomKeyExportReader::new(Cursor::new(encrypted), "1234").await.unwrap();
+        let key = reader.next_key().await.unwrap().unwrap();
+        assert_eq!(key.session_id, export[0].session_id);
+        assert!(reader.next_key().await.unwrap().is_none());
+    }
+}
@@ -1,7 +1,12 @@
 mod attachments;
 mod key_export;
+mod key_export_stream;
 
 pub use attachments::{
     AttachmentDecryptor, AttachmentEncryptor, DecryptorError, MediaEncryptionInfo,
 };
-pub use key_export::{decrypt_room_key_export, encrypt_room_key_export, KeyExportError};
+pub use key_export::{
+    decrypt_room_key_export, decrypt_with_passphrase, encrypt_room_key_export,
+    encrypt_with_passphrase, KeyExportError,
+};
+pub use key_export_stream::{RoomKeyExportReader, RoomKeyExportWriter};
@@ -0,0 +1,173 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Controls over the answers to incoming room key requests, and the audit log
+//! of those answers.
+
+#[cfg(feature = "automatic-room-key-forwarding")]
+use std::{fmt, sync::Arc};
+
+#[cfg(feature = "automatic-room-key-forwarding")]
+use ruma::RoomId;
+use ruma::{MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedRoomId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{Result as StoreResult, Store};
+#[cfg(feature = "automatic-room-key-forwarding")]
+use crate::Device;
+
+/// The key of the audit log in the custom values of the crypto store.
+const LOG_STORE_KEY: &str = "room_key_forwarding_log";
+
+/// The maximum number of records kept in the audit log, the oldest records
+/// are removed first.
+#[cfg(feature = "automatic-room-key-forwarding")]
+const MAX_LOG_RECORDS: usize = 1000;
+
+/// A policy deciding which incoming room key requests are answered.
+///
+/// The policy is only applied on top of the built-in rules: a room key is
+/// never forwarded to a device that isn't allowed to receive it by those
+/// rules, whatever the policy says.
+#[cfg(feature = "automatic-room-key-forwarding")]
+#[derive(Clone, Default)]
+pub enum RoomKeyForwardingPolicy {
+    /// Only apply the built-in rules.
+    #[default]
+    Default,
+
+    /// Only forward room keys to devices that we have verified.
+    VerifiedDevicesOnly,
+
+    /// Let the given callback decide if a request, that passed the built-in
+    /// rules, should be answered.
+    Custom(Arc<dyn Fn(&RoomKeyForwardingRequest<'_>) -> bool + Send + Sync>),
+}
+
+#[cfg(feature = "automatic-room-key-forwarding")]
+impl RoomKeyForwardingPolicy {
+    pub(crate) fn allows(&self, request: &RoomKeyForwardingRequest<'_>) -> bool {
+        match self {
+            Self::Default => true,
+            Self::VerifiedDevicesOnly => request.device.is_verified(),
+            Self::Custom(callback) => callback(request),
+        }
+    }
+}
+
+#[cfg(feature = "automatic-room-key-forwarding")]
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for RoomKeyForwardingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => f.write_str("Default"),
+            Self::VerifiedDevicesOnly => f.write_str("VerifiedDevicesOnly"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// An incoming room key request, as given to a
+/// [`RoomKeyForwardingPolicy::Custom`] callback.
+#[cfg(feature = "automatic-room-key-forwarding")]
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct RoomKeyForwardingRequest<'a> {
+    /// The device requesting the room key.
+    pub device: &'a Device,
+
+    /// The room the requested room key is used in.
+    pub room_id: &'a RoomId,
+
+    /// The ID of the requested Megolm session.
+    pub session_id: &'a str,
+
+    /// The first message index that would be shared, `None` if the session
+    /// would be shared from the earliest known index.
+    pub message_index: Option<u32>,
+}
+
+/// The answer given to an incoming room key request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum RoomKeyForwardingOutcome {
+    /// The room key was forwarded to the requesting device.
+    Forwarded {
+        /// The first message index that was shared, `None` if the session was
+        /// shared from the earliest known index.
+        message_index: Option<u32>,
+    },
+
+    /// The request was declined.
+    Declined {
+        /// A human readable description of why the request was declined.
+        reason: String,
+    },
+}
+
+/// A record of the audit log of the room key forwarding, see
+/// [`OlmMachine::room_key_forwarding_log()`].
+///
+/// [`OlmMachine::room_key_forwarding_log()`]: crate::OlmMachine::room_key_forwarding_log
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomKeyForwardingRecord {
+    /// The user that requested the room key.
+    pub user_id: OwnedUserId,
+
+    /// The device that requested the room key.
+    pub device_id: OwnedDeviceId,
+
+    /// The room the requested room key is used in.
+    pub room_id: OwnedRoomId,
+
+    /// The ID of the requested Megolm session.
+    pub session_id: String,
+
+    /// The answer that was given to the request.
+    #[serde(flatten)]
+    pub outcome: RoomKeyForwardingOutcome,
+
+    /// When the request was answered.
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+}
+
+/// Load the audit log from the store, from the oldest to the newest record.
+pub(crate) async fn load_log(store: &Store) -> StoreResult<Vec<RoomKeyForwardingRecord>> {
+    Ok(store.get_value(LOG_STORE_KEY).await?.unwrap_or_default())
+}
+
+/// Append a record to the audit log in the store.
+///
+/// The whole log is rewritten, the calls to this function and to
+/// [`clear_log()`] must be serialized by the caller so no record is lost.
+#[cfg(feature = "automatic-room-key-forwarding")]
+pub(crate) async fn append_to_log(
+    store: &Store,
+    record: RoomKeyForwardingRecord,
+) -> StoreResult<()> {
+    let mut log = load_log(store).await?;
+
+    log.push(record);
+    if log.len() > MAX_LOG_RECORDS {
+        log.drain(..log.len() - MAX_LOG_RECORDS);
+    }
+
+    store.set_value(LOG_STORE_KEY, &log).await
+}
+
+/// Remove all the records of the audit log from the store.
+pub(crate) async fn clear_log(store: &Store) -> StoreResult<()> {
+    store.remove_custom_value(LOG_STORE_KEY).await?;
+    Ok(())
+}
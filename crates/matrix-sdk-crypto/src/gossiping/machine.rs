@@ -20,6 +20,8 @@
 // If we don't trust the device store an object that remembers the request and
 // let the users introspect that object.
 
+#[cfg(feature = "automatic-room-key-forwarding")]
+use std::sync::RwLock as StdRwLock;
 use std::{
     collections::BTreeMap,
     sync::{atomic::AtomicBool, Arc},
@@ -35,9 +37,15 @@ use ruma::{
     DeviceId, DeviceKeyAlgorithm, OwnedDeviceId, OwnedTransactionId, OwnedUserId, RoomId,
     TransactionId, UserId,
 };
+use tokio::sync::Mutex;
 use tracing::{debug, info, trace, warn};
 use vodozemac::{megolm::SessionOrdering, Curve25519PublicKey};
 
+#[cfg(feature = "automatic-room-key-forwarding")]
+use super::{
+    forwarding, RoomKeyForwardingOutcome, RoomKeyForwardingPolicy, RoomKeyForwardingRecord,
+    RoomKeyForwardingRequest,
+};
 use super::{GossipRequest, RequestEvent, RequestInfo, SecretInfo, WaitQueue};
 use crate::{
     error::{EventError, OlmError, OlmResult},
@@ -73,6 +81,11 @@ pub(crate) struct GossipMachineInner {
     wait_queue: WaitQueue,
     users_for_key_claim: Arc<DashMap<OwnedUserId, DashSet<OwnedDeviceId>>>,
     room_key_forwarding_enabled: AtomicBool,
+    #[cfg(feature = "automatic-room-key-forwarding")]
+    room_key_forwarding_policy: StdRwLock<RoomKeyForwardingPolicy>,
+    /// Serializes the updates of the room key forwarding audit log, which is
+    /// rewritten as a whole for every record.
+    room_key_forwarding_log_lock: Mutex<()>,
 }
 
 impl GossipMachine {
@@ -98,6 +111,9 @@ impl GossipMachine {
                 wait_queue: WaitQueue::new(),
                 users_for_key_claim,
                 room_key_forwarding_enabled,
+                #[cfg(feature = "automatic-room-key-forwarding")]
+                room_key_forwarding_policy: Default::default(),
+                room_key_forwarding_log_lock: Mutex::new(()),
             }),
         }
     }
@@ -111,6 +127,17 @@ impl GossipMachine {
         self.inner.room_key_forwarding_enabled.load(Ordering::SeqCst)
     }
 
+    #[cfg(feature = "automatic-room-key-forwarding")]
+    pub fn set_room_key_forwarding_policy(&self, policy: RoomKeyForwardingPolicy) {
+        *self.inner.room_key_forwarding_policy.write().unwrap() = policy;
+    }
+
+    /// Remove all the records from the room key forwarding audit log.
+    pub async fn clear_room_key_forwarding_log(&self) -> Result<(), CryptoStoreError> {
+        let _guard = self.inner.room_key_forwarding_log_lock.lock().await;
+        super::forwarding::clear_log(&self.inner.store).await
+    }
+
     /// Load stored outgoing requests that were not yet sent out.
     async fn load_outgoing_requests(&self) -> Result<Vec<OutgoingRequest>, CryptoStoreError> {
         Ok(self
@@ -396,9 +423,41 @@ impl GossipMachine {
             return Ok(None);
         };
 
-        match self.should_share_key(&device, session).await {
+        let decision = self.should_share_key(&device, session).await.and_then(|message_index| {
+            let request = RoomKeyForwardingRequest {
+                device: &device,
+                room_id: session.room_id(),
+                session_id: session.session_id(),
+                message_index,
+            };
+
+            if self.inner.room_key_forwarding_policy.read().unwrap().allows(&request) {
+                Ok(message_index)
+            } else {
+                Err(KeyForwardDecision::DeclinedByPolicy)
+            }
+        });
+
+        let outcome = match decision {
             Ok(message_index) => {
-                self.try_to_forward_room_key(event, device, session, message_index).await
+                let (user_id, device_id) =
+                    (device.user_id().to_owned(), device.device_id().to_owned());
+                let used_session =
+                    self.try_to_forward_room_key(event, device, session, message_index).await?;
+
+                // The request might have been put in the wait queue, it will be recorded
+                // once it's retried.
+                if used_session.is_some() {
+                    self.record_key_forwarding(
+                        user_id,
+                        device_id,
+                        session,
+                        RoomKeyForwardingOutcome::Forwarded { message_index },
+                    )
+                    .await?;
+                }
+
+                return Ok(used_session);
             }
             Err(e) => {
                 if let KeyForwardDecision::ChangedSenderKey = e {
@@ -413,9 +472,41 @@ impl GossipMachine {
                     );
                 }
 
-                Ok(None)
+                RoomKeyForwardingOutcome::Declined { reason: e.to_string() }
             }
-        }
+        };
+
+        self.record_key_forwarding(
+            device.user_id().to_owned(),
+            device.device_id().to_owned(),
+            session,
+            outcome,
+        )
+        .await?;
+
+        Ok(None)
+    }
+
+    /// Add the answer to a room key request to the audit log.
+    #[cfg(feature = "automatic-room-key-forwarding")]
+    async fn record_key_forwarding(
+        &self,
+        user_id: OwnedUserId,
+        device_id: OwnedDeviceId,
+        session: &InboundGroupSession,
+        outcome: RoomKeyForwardingOutcome,
+    ) -> OlmResult<()> {
+        let record = RoomKeyForwardingRecord {
+            user_id,
+            device_id,
+            room_id: session.room_id().to_owned(),
+            session_id: session.session_id().to_owned(),
+            outcome,
+            timestamp: ruma::MilliSecondsSinceUnixEpoch::now(),
+        };
+
+        let _guard = self.inner.room_key_forwarding_log_lock.lock().await;
+        Ok(forwarding::append_to_log(&self.inner.store, record).await?)
     }
 
     #[cfg(feature = "automatic-room-key-forwarding")]
@@ -1052,14 +1143,17 @@ mod tests {
     use super::GossipMachine;
     #[cfg(feature = "automatic-room-key-forwarding")]
     use crate::{
-        gossiping::KeyForwardDecision,
+        gossiping::{
+            load_room_key_forwarding_log, KeyForwardDecision, RoomKeyForwardingOutcome,
+            RoomKeyForwardingPolicy,
+        },
         olm::OutboundGroupSession,
         store::Changes,
         types::{
             events::{
                 forwarded_room_key::ForwardedRoomKeyContent, olm_v1::AnyDecryptedOlmEvent,
-                olm_v1::DecryptedOlmV1Event, room::encrypted::EncryptedToDeviceEvent, EventType,
-                ToDeviceEvent,
+                olm_v1::DecryptedOlmV1Event, room::encrypted::EncryptedToDeviceEvent,
+                room_key_request::RoomKeyRequestEvent, EventType, ToDeviceEvent,
             },
             EventEncryptionAlgorithm,
         },
@@ -1669,6 +1763,81 @@ mod tests {
         assert!(session.is_none(), "We should not receive a room key from another user");
     }
 
+    #[async_test]
+    #[cfg(feature = "automatic-room-key-forwarding")]
+    async fn room_key_forwarding_policy_and_log() {
+        let (alice_machine, _, group_session, bob_machine) =
+            machines_for_key_share(alice_id(), true, EventEncryptionAlgorithm::MegolmV1AesSha2)
+                .await;
+
+        let requests = alice_machine.outgoing_to_device_requests().await.unwrap();
+        let request = &requests[0];
+        let event: RoomKeyRequestEvent = request_to_event(alice_id(), alice_id(), request);
+
+        // Alice's device isn't verified, so the policy declines the request.
+        bob_machine.set_room_key_forwarding_policy(RoomKeyForwardingPolicy::VerifiedDevicesOnly);
+        bob_machine.receive_incoming_key_request(&event);
+        bob_machine.collect_incoming_key_requests().await.unwrap();
+        assert!(bob_machine.inner.outgoing_requests.is_empty());
+
+        // A custom policy can let the request through.
+        bob_machine.set_room_key_forwarding_policy(RoomKeyForwardingPolicy::Custom(Arc::new(
+            |request| request.device.user_id() == alice_id(),
+        )));
+        bob_machine.receive_incoming_key_request(&event);
+        bob_machine.collect_incoming_key_requests().await.unwrap();
+        assert!(!bob_machine.inner.outgoing_requests.is_empty());
+
+        // Both answers are in the audit log.
+        let log = load_room_key_forwarding_log(&bob_machine.inner.store).await.unwrap();
+        assert_eq!(log.len(), 2);
+        assert!(log.iter().all(|r| r.user_id == alice_id()
+            && r.device_id == alice_device_id()
+            && r.room_id == room_id()
+            && r.session_id == group_session.session_id()));
+        assert_matches!(
+            &log[0].outcome,
+            RoomKeyForwardingOutcome::Declined { reason } if reason.contains("policy")
+        );
+        assert_matches!(log[1].outcome, RoomKeyForwardingOutcome::Forwarded { .. });
+    }
+
+    #[async_test]
+    #[cfg(feature = "automatic-room-key-forwarding")]
+    async fn room_key_forwarding_log_concurrent_records() {
+        let (_, _, group_session, bob_machine) =
+            machines_for_key_share(alice_id(), true, EventEncryptionAlgorithm::MegolmV1AesSha2)
+                .await;
+
+        let session = bob_machine
+            .inner
+            .store
+            .get_inbound_group_session(room_id(), group_session.session_id())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Record many answers at the same time, none of them must be lost.
+        let records = (0..20).map(|i| {
+            bob_machine.record_key_forwarding(
+                alice_id().to_owned(),
+                alice_device_id().to_owned(),
+                &session,
+                RoomKeyForwardingOutcome::Forwarded { message_index: Some(i) },
+            )
+        });
+        for result in futures_util::future::join_all(records).await {
+            result.unwrap();
+        }
+
+        let log = load_room_key_forwarding_log(&bob_machine.inner.store).await.unwrap();
+        assert_eq!(log.len(), 20);
+
+        bob_machine.clear_room_key_forwarding_log().await.unwrap();
+        let log = load_room_key_forwarding_log(&bob_machine.inner.store).await.unwrap();
+        assert!(log.is_empty());
+    }
+
     #[async_test]
     #[cfg(feature = "automatic-room-key-forwarding")]
     async fn key_share_cycle_megolm_v1() {
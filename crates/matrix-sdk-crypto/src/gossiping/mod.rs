@@ -12,11 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod forwarding;
 mod machine;
 
 use std::sync::Arc;
 
 use dashmap::{DashMap, DashSet};
+pub(crate) use forwarding::load_log as load_room_key_forwarding_log;
+pub use forwarding::{RoomKeyForwardingOutcome, RoomKeyForwardingRecord};
+#[cfg(feature = "automatic-room-key-forwarding")]
+pub use forwarding::{RoomKeyForwardingPolicy, RoomKeyForwardingRequest};
 pub(crate) use machine::GossipMachine;
 use ruma::{
     events::{
@@ -60,6 +65,10 @@ pub enum KeyForwardDecision {
     /// accidentally or maliciously changed their curve25519 sender key.
     #[error("the device has changed their curve25519 sender key")]
     ChangedSenderKey,
+    /// The built-in rules allowed the request, but it was declined by the
+    /// configured [`RoomKeyForwardingPolicy`].
+    #[error("the request was declined by the room key forwarding policy")]
+    DeclinedByPolicy,
 }
 
 /// A struct describing an outgoing key request.
@@ -43,7 +43,8 @@ use crate::{
     error::{EventError, OlmError, OlmResult, SignatureError},
     identities::{ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities},
     olm::{
-        InboundGroupSession, OutboundGroupSession, Session, ShareInfo, SignedJsonObject, VerifyJson,
+        InboundGroupSession, OutboundGroupSession, SenderAuthenticationMode, Session, ShareInfo,
+        SignedJsonObject, VerifyJson,
     },
     store::{Changes, DeviceChanges, DynCryptoStore, Result as StoreResult},
     types::{
@@ -433,6 +434,21 @@ impl Device {
     /// # Arguments
     ///
     /// * `content` - The content of the event that should be encrypted.
+    pub(crate) async fn encrypt(
+        &self,
+        event_type: &str,
+        content: Value,
+    ) -> OlmResult<(Session, Raw<ToDeviceEncryptedEventContent>)> {
+        self.encrypt_with_sender_authentication(
+            event_type,
+            content,
+            SenderAuthenticationMode::Standard,
+        )
+        .await
+    }
+
+    /// Encrypt the given content for this `Device`, authenticating us with the
+    /// given [`SenderAuthenticationMode`].
     #[instrument(
         skip_all,
         fields(
@@ -444,10 +460,11 @@ impl Device {
             message_id,
         ))
     ]
-    pub(crate) async fn encrypt(
+    async fn encrypt_with_sender_authentication(
         &self,
         event_type: &str,
         content: Value,
+        sender_authentication: SenderAuthenticationMode,
     ) -> OlmResult<(Session, Raw<ToDeviceEncryptedEventContent>)> {
         #[cfg(feature = "message-ids")]
         let message_id = {
@@ -464,13 +481,20 @@ impl Device {
         let message_id = None;
 
         self.inner
-            .encrypt(self.verification_machine.store.inner(), event_type, content, message_id)
+            .encrypt(
+                self.verification_machine.store.inner(),
+                event_type,
+                content,
+                message_id,
+                sender_authentication,
+            )
             .await
     }
 
     pub(crate) async fn maybe_encrypt_room_key(
         &self,
         session: OutboundGroupSession,
+        sender_authentication: SenderAuthenticationMode,
     ) -> OlmResult<MaybeEncryptedRoomKey> {
         let content = session.as_content().await;
         let message_index = session.message_index().await;
@@ -478,7 +502,10 @@ impl Device {
         let content =
             serde_json::to_value(content).expect("We can always serialize our own room key");
 
-        match self.encrypt(event_type, content).await {
+        match self
+            .encrypt_with_sender_authentication(event_type, content, sender_authentication)
+            .await
+        {
             Ok((session, encrypted)) => Ok(MaybeEncryptedRoomKey::Encrypted {
                 share_info: ShareInfo::new_shared(session.sender_key().to_owned(), message_index),
                 used_session: session,
@@ -798,11 +825,20 @@ impl ReadOnlyDevice {
         event_type: &str,
         content: Value,
         message_id: Option<String>,
+        sender_authentication: SenderAuthenticationMode,
     ) -> OlmResult<(Session, Raw<ToDeviceEncryptedEventContent>)> {
         let session = self.get_most_recent_session(store).await?;
 
         if let Some(mut session) = session {
-            let message = session.encrypt(self, event_type, content, message_id).await?;
+            let message = session
+                .encrypt_with_sender_authentication(
+                    self,
+                    event_type,
+                    content,
+                    message_id,
+                    sender_authentication,
+                )
+                .await?;
 
             trace!("Successfully encrypted an event");
 
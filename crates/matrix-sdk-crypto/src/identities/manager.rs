@@ -15,12 +15,13 @@
 use std::{
     collections::{BTreeMap, BTreeSet, HashSet},
     ops::Deref,
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
 };
 
 use futures_util::future::join_all;
 use itertools::Itertools;
-use matrix_sdk_common::executor::spawn;
+use matrix_sdk_common::{executor::spawn, instant::Instant};
 use ruma::{
     api::client::keys::get_keys::v3::Response as KeysQueryResponse, serde::Raw, OwnedDeviceId,
     OwnedServerName, OwnedTransactionId, OwnedUserId, ServerName, TransactionId, UserId,
@@ -32,6 +33,7 @@ use crate::{
     error::OlmResult,
     identities::{
         ReadOnlyDevice, ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities, ReadOnlyUserIdentity,
+        UserIdentities,
     },
     olm::PrivateCrossSigningIdentity,
     requests::KeysQueryRequest,
@@ -62,35 +64,53 @@ pub(crate) struct IdentityManager {
     failures: FailuresCache<OwnedServerName>,
     store: Store,
 
-    /// Details of the current "in-flight" key query request, if any
-    keys_query_request_details: Arc<Mutex<Option<KeysQueryRequestDetails>>>,
+    /// Details of the "in-flight" key query requests, keyed by request ID.
+    keys_query_requests_in_flight:
+        Arc<Mutex<BTreeMap<OwnedTransactionId, KeysQueryRequestDetails>>>,
+
+    /// Users whose keys should be queried before the other outdated users,
+    /// for example because they are members of the room the user is looking
+    /// at.
+    prioritized_users: Arc<StdMutex<BTreeSet<OwnedUserId>>>,
 }
 
 /// Details of an in-flight key query request
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 struct KeysQueryRequestDetails {
     /// The sequence number, to be passed to
     /// `Store.mark_tracked_users_as_up_to_date`.
     sequence_number: SequenceNumber,
 
-    /// A single batch of queries returned by the Store is broken up into one or
-    /// more actual KeysQueryRequests, each with their own request id. We
-    /// record the outstanding request ids here.
-    request_ids: HashSet<OwnedTransactionId>,
+    /// The users whose keys are queried by this request.
+    users: BTreeSet<OwnedUserId>,
+
+    /// When the request was created, used to give up on requests for which we
+    /// never get a response.
+    created_at: Instant,
 }
 
 impl IdentityManager {
+    /// The maximum number of users included in a single `/keys/query`
+    /// request.
     const MAX_KEY_QUERY_USERS: usize = 250;
 
-    pub fn new(user_id: OwnedUserId, device_id: OwnedDeviceId, store: Store) -> Self {
-        let keys_query_request_details = Mutex::new(None);
+    /// The maximum number of `/keys/query` requests that can be in flight at
+    /// the same time. The remaining outdated users are queried once some of
+    /// those requests get a response.
+    const MAX_KEY_QUERY_REQUESTS_IN_FLIGHT: usize = 4;
 
+    /// How long we wait for the response of a `/keys/query` request before
+    /// we consider it lost, and query its users again.
+    const KEY_QUERY_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn new(user_id: OwnedUserId, device_id: OwnedDeviceId, store: Store) -> Self {
         IdentityManager {
             user_id,
             device_id,
             store,
             failures: Default::default(),
-            keys_query_request_details: keys_query_request_details.into(),
+            keys_query_requests_in_flight: Default::default(),
+            prioritized_users: Default::default(),
         }
     }
 
@@ -152,17 +172,12 @@ impl IdentityManager {
         // if this request is one of those we expected to be in flight, pass the
         // sequence number back to the store so that it can mark devices up to
         // date
-        let sequence_number = {
-            let mut request_details = self.keys_query_request_details.lock().await;
-
-            request_details.as_mut().and_then(|details| {
-                if details.request_ids.remove(request_id) {
-                    Some(details.sequence_number)
-                } else {
-                    None
-                }
-            })
-        };
+        let sequence_number = self
+            .keys_query_requests_in_flight
+            .lock()
+            .await
+            .remove(request_id)
+            .map(|details| details.sequence_number);
 
         if let Some(sequence_number) = sequence_number {
             self.store
@@ -555,9 +570,20 @@ impl IdentityManager {
         if master_key.user_id() != user_id || self_signing.user_id() != user_id {
             warn!(?user_id, "User ID mismatch in one of the cross signing keys",);
         } else if let Some(i) = self.store.get_user_identity(user_id).await? {
+            let master_key_changed = *i.master_key() != master_key;
+            let was_verified = master_key_changed
+                && self.store.get_identity(user_id).await?.is_some_and(|i| match i {
+                    UserIdentities::Own(i) => i.is_verified(),
+                    UserIdentities::Other(i) => i.is_verified(),
+                });
+
             match self.handle_changed_identity(response, master_key, self_signing, i).await {
                 Ok(c) => {
                     trace!(identity = ?c.public, "Updated a user identity");
+                    if master_key_changed {
+                        info!(was_verified, "The master key of a user identity changed");
+                        changes.master_key_changed.insert(user_id.to_owned(), was_verified);
+                    }
                     changes.changed.push(c.public);
                     *changed_identity = c.private;
                 }
@@ -619,6 +645,14 @@ impl IdentityManager {
 
     /// Get a list of key query requests needed.
     ///
+    /// Outdated users are batched into requests of at most
+    /// [`Self::MAX_KEY_QUERY_USERS`] users, prioritized users come first.
+    /// Users that are already part of an in-flight request aren't queried
+    /// again, so concurrent callers waiting for the keys of the same user
+    /// share a single request. At most
+    /// [`Self::MAX_KEY_QUERY_REQUESTS_IN_FLIGHT`] requests are in flight at the
+    /// same time.
+    ///
     /// # Returns
     ///
     /// A map of a request ID to the `/keys/query` request.
@@ -631,9 +665,6 @@ impl IdentityManager {
     pub async fn users_for_key_query(
         &self,
     ) -> StoreResult<BTreeMap<OwnedTransactionId, KeysQueryRequest>> {
-        // Forget about any previous key queries in flight.
-        *self.keys_query_request_details.lock().await = None;
-
         let (users, sequence_number) = self.store.users_for_key_query().await?;
 
         // We always want to track our own user, but in case we aren't in an encrypted
@@ -649,47 +680,107 @@ impl IdentityManager {
                 (users, sequence_number)
             };
 
-        if users.is_empty() {
-            Ok(BTreeMap::new())
-        } else {
-            // Let's remove users that are part of the `FailuresCache`. The cache, which is
-            // a TTL cache, remembers users for which a previous `/key/query` request has
-            // failed. We don't retry a `/keys/query` for such users for a
-            // certain amount of time.
-            let users = users.into_iter().filter(|u| !self.failures.contains(u.server_name()));
-
-            // We don't want to create a single `/keys/query` request with an infinite
-            // amount of users. Some servers will likely bail out after a
-            // certain amount of users and the responses will be large. In the
-            // case of a transmission error, we'll have to retransmit the large
-            // response.
-            //
-            // Convert the set of users into multiple /keys/query requests.
-            let requests: BTreeMap<_, _> = users
-                .chunks(Self::MAX_KEY_QUERY_USERS)
-                .into_iter()
-                .map(|user_chunk| {
-                    let request_id = TransactionId::new();
-                    let request = KeysQueryRequest::new(user_chunk);
-
-                    debug!(?request_id, users = ?request.device_keys.keys(), "Created a /keys/query request");
-
-                    (request_id, request)
-                })
-                .collect();
-
-            // Collect the request IDs, these will be used later in the
-            // `receive_keys_query_response()` method to figure out if the user can be
-            // marked as up-to-date/non-dirty.
-            let request_ids = requests.keys().cloned().collect();
-            let request_details = KeysQueryRequestDetails { sequence_number, request_ids };
-
-            *self.keys_query_request_details.lock().await = Some(request_details);
-
-            Ok(requests)
+        let mut in_flight = self.keys_query_requests_in_flight.lock().await;
+
+        // Forget about the requests for which we didn't get a response in time, their
+        // users will be queried again.
+        in_flight.retain(|request_id, details| {
+            let expired = details.created_at.elapsed() >= Self::KEY_QUERY_REQUEST_TIMEOUT;
+
+            if expired {
+                warn!(?request_id, "A /keys/query request timed out, querying its users again");
+            }
+
+            !expired
+        });
+
+        if users.is_empty() || in_flight.len() >= Self::MAX_KEY_QUERY_REQUESTS_IN_FLIGHT {
+            return Ok(BTreeMap::new());
+        }
+
+        // Let's remove users that are part of the `FailuresCache`. The cache, which is
+        // a TTL cache, remembers users for which a previous `/key/query` request has
+        // failed. We don't retry a `/keys/query` for such users for a
+        // certain amount of time.
+        //
+        // We also remove the users that are already part of an in-flight request,
+        // their keys will be updated once the response of that request is received.
+        let users = users.into_iter().filter(|u| {
+            !self.failures.contains(u.server_name())
+                && !in_flight.values().any(|details| details.users.contains(u))
+        });
+
+        // Put the prioritized users first, so they end up in the first requests. The
+        // order of the other users doesn't matter, but sorting them makes the requests
+        // deterministic.
+        let users: Vec<_> = {
+            let mut prioritized_users = self.prioritized_users.lock().unwrap();
+            let users =
+                users.sorted_by_key(|u| (!prioritized_users.contains(u), u.clone())).collect();
+            prioritized_users.clear();
+
+            users
+        };
+
+        // We don't want to create a single `/keys/query` request with an infinite
+        // amount of users. Some servers will likely bail out after a
+        // certain amount of users and the responses will be large. In the
+        // case of a transmission error, we'll have to retransmit the large
+        // response.
+        //
+        // Convert the list of users into multiple /keys/query requests, and keep the
+        // remaining users for when some of the in-flight requests have completed.
+        let available_slots = Self::MAX_KEY_QUERY_REQUESTS_IN_FLIGHT - in_flight.len();
+        let requests: BTreeMap<_, _> = users
+            .chunks(Self::MAX_KEY_QUERY_USERS)
+            .take(available_slots)
+            .map(|user_chunk| {
+                let request_id = TransactionId::new();
+                let request = KeysQueryRequest::new(user_chunk.iter().cloned());
+
+                debug!(?request_id, users = ?request.device_keys.keys(), "Created a /keys/query request");
+
+                // Record the request, this will be used later in the
+                // `receive_keys_query_response()` method to figure out if the users can be
+                // marked as up-to-date/non-dirty.
+                in_flight.insert(
+                    request_id.clone(),
+                    KeysQueryRequestDetails {
+                        sequence_number,
+                        users: user_chunk.iter().cloned().collect(),
+                        created_at: Instant::now(),
+                    },
+                );
+
+                (request_id, request)
+            })
+            .collect();
+
+        Ok(requests)
+    }
+
+    /// Forget about the in-flight `/keys/query` request with the given ID,
+    /// because sending it failed.
+    ///
+    /// Its users are included again in the next requests returned by
+    /// [`IdentityManager::users_for_key_query()`], instead of waiting for
+    /// [`Self::KEY_QUERY_REQUEST_TIMEOUT`].
+    pub async fn mark_keys_query_as_failed(&self, request_id: &TransactionId) {
+        if self.keys_query_requests_in_flight.lock().await.remove(request_id).is_some() {
+            debug!(?request_id, "A /keys/query request failed, its users will be queried again");
         }
     }
 
+    /// Mark the given users as prioritized for the next `/keys/query`
+    /// requests.
+    ///
+    /// See the docs for [`OlmMachine::prioritize_key_queries()`].
+    ///
+    /// [`OlmMachine::prioritize_key_queries()`]: crate::OlmMachine::prioritize_key_queries
+    pub fn prioritize_key_queries<'a>(&self, users: impl IntoIterator<Item = &'a UserId>) {
+        self.prioritized_users.lock().unwrap().extend(users.into_iter().map(ToOwned::to_owned));
+    }
+
     /// Receive the list of users that contained changed devices from the
     /// `/sync` response.
     ///
@@ -957,16 +1048,22 @@ pub(crate) mod testing {
 
 #[cfg(test)]
 pub(crate) mod tests {
-    use std::ops::Deref;
+    use std::{collections::BTreeMap, ops::Deref};
 
     use matrix_sdk_test::{async_test, response_from_file};
     use ruma::{
         api::{client::keys::get_keys::v3::Response as KeysQueryResponse, IncomingResponse},
-        device_id, user_id, TransactionId,
+        assign, device_id,
+        serde::Raw,
+        user_id, OwnedUserId, TransactionId, UserId,
     };
     use serde_json::json;
 
-    use super::testing::{device_id, key_query, manager, other_key_query, other_user_id, user_id};
+    use super::{
+        testing::{device_id, key_query, manager, other_key_query, other_user_id, user_id},
+        IdentityManager,
+    };
+    use crate::olm::PrivateCrossSigningIdentity;
 
     fn key_query_with_failures() -> KeysQueryResponse {
         let response = json!({
@@ -1038,6 +1135,38 @@ pub(crate) mod tests {
         identity.is_device_signed(&device).unwrap();
     }
 
+    #[async_test]
+    async fn test_manager_master_key_change() {
+        let manager = manager().await;
+        let other_user = other_user_id();
+
+        let (_, identities) = manager
+            .receive_keys_query_response(&TransactionId::new(), &other_key_query())
+            .await
+            .unwrap();
+        assert_eq!(identities.new.len(), 1);
+        assert!(identities.master_key_changed.is_empty());
+
+        // The user resets their cross-signing keys.
+        let identity = PrivateCrossSigningIdentity::new(other_user.to_owned()).await;
+        let keys = identity.as_upload_request().await;
+        let response = assign!(KeysQueryResponse::new(), {
+            master_keys: BTreeMap::from([(
+                other_user.to_owned(),
+                Raw::new(&keys.master_key.unwrap()).unwrap().cast(),
+            )]),
+            self_signing_keys: BTreeMap::from([(
+                other_user.to_owned(),
+                Raw::new(&keys.self_signing_key.unwrap()).unwrap().cast(),
+            )]),
+        });
+
+        let (_, identities) =
+            manager.receive_keys_query_response(&TransactionId::new(), &response).await.unwrap();
+        assert_eq!(identities.changed.len(), 1);
+        assert_eq!(identities.master_key_changed.get(other_user), Some(&false));
+    }
+
     #[async_test]
     async fn test_manager_own_key_query_response() {
         let manager = manager().await;
@@ -1159,4 +1288,78 @@ pub(crate) mod tests {
             .iter()
             .any(|(_, r)| r.device_keys.contains_key(alice)));
     }
+
+    fn many_users(count: usize) -> Vec<OwnedUserId> {
+        (0..count).map(|i| UserId::parse(format!("@user{i:04}:example.org")).unwrap()).collect()
+    }
+
+    #[async_test]
+    async fn in_flight_users_are_not_queried_again() {
+        let manager = manager().await;
+        let alice = other_user_id();
+        manager.update_tracked_users([alice]).await.unwrap();
+
+        let (reqid, req) = manager.users_for_key_query().await.unwrap().pop_first().unwrap();
+        assert!(req.device_keys.contains_key(alice));
+
+        // alice's keys are already being queried, so there is no need to query them
+        // again, even if her devices change in the meantime.
+        manager.receive_device_changes([alice].into_iter()).await.unwrap();
+        assert!(manager.users_for_key_query().await.unwrap().is_empty());
+
+        // Once the response is received, the new change needs a new query.
+        manager.receive_keys_query_response(&reqid, &other_key_query()).await.unwrap();
+        let (_, req) = manager.users_for_key_query().await.unwrap().pop_first().unwrap();
+        assert!(req.device_keys.contains_key(alice));
+    }
+
+    #[async_test]
+    async fn users_of_failed_key_queries_are_queried_again() {
+        let manager = manager().await;
+        let alice = other_user_id();
+        manager.update_tracked_users([alice]).await.unwrap();
+
+        let (reqid, req) = manager.users_for_key_query().await.unwrap().pop_first().unwrap();
+        assert!(req.device_keys.contains_key(alice));
+        assert!(manager.users_for_key_query().await.unwrap().is_empty());
+
+        // The request couldn't be sent, alice's keys are queried again right away.
+        manager.mark_keys_query_as_failed(&reqid).await;
+        let (new_reqid, req) = manager.users_for_key_query().await.unwrap().pop_first().unwrap();
+        assert_ne!(new_reqid, reqid);
+        assert!(req.device_keys.contains_key(alice));
+    }
+
+    #[async_test]
+    async fn key_queries_are_batched_and_limited() {
+        let manager = manager().await;
+        let users = many_users(IdentityManager::MAX_KEY_QUERY_USERS * 10);
+        manager.update_tracked_users(users.iter().map(Deref::deref)).await.unwrap();
+
+        let requests = manager.users_for_key_query().await.unwrap();
+        assert_eq!(requests.len(), IdentityManager::MAX_KEY_QUERY_REQUESTS_IN_FLIGHT);
+        assert!(requests
+            .values()
+            .all(|r| r.device_keys.len() == IdentityManager::MAX_KEY_QUERY_USERS));
+
+        // No more requests until some of the in-flight ones complete.
+        assert!(manager.users_for_key_query().await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn prioritized_users_are_queried_first() {
+        let manager = manager().await;
+        let users = many_users(IdentityManager::MAX_KEY_QUERY_USERS + 50);
+        let last_user = users.last().unwrap();
+        manager.update_tracked_users(users.iter().map(Deref::deref)).await.unwrap();
+
+        manager.prioritize_key_queries([last_user.deref()]);
+
+        let requests = manager.users_for_key_query().await.unwrap();
+        let first_request = requests
+            .values()
+            .find(|r| r.device_keys.len() == IdentityManager::MAX_KEY_QUERY_USERS)
+            .unwrap();
+        assert!(first_request.device_keys.contains_key(last_user));
+    }
 }
@@ -114,7 +114,10 @@ impl OwnUserIdentity {
         self.mark_as_verified();
 
         let changes = Changes {
-            identities: IdentityChanges { changed: vec![self.inner.clone().into()], new: vec![] },
+            identities: IdentityChanges {
+                changed: vec![self.inner.clone().into()],
+                ..Default::default()
+            },
             ..Default::default()
         };
 
@@ -18,7 +18,12 @@
 
 #[cfg(feature = "backups_v1")]
 pub mod backups;
+#[cfg(feature = "decryption-audit")]
+mod decryption_audit;
+mod decryption_statistics;
 mod error;
+#[cfg(any(test, feature = "testing"))]
+mod fault_injection;
 mod file_encryption;
 mod gossiping;
 mod identities;
@@ -34,15 +39,18 @@ mod verification;
 #[cfg(feature = "testing")]
 /// Testing facilities and helpers for crypto tests
 pub mod testing {
-    pub use crate::identities::{
-        device::testing::get_device,
-        user::testing::{get_other_identity, get_own_identity},
+    pub use crate::{
+        fault_injection::{Fault, FaultRule, FaultScenario, InjectedStoreFailure},
+        identities::{
+            device::testing::get_device,
+            user::testing::{get_other_identity, get_own_identity},
+        },
     };
 }
 
 use std::collections::{BTreeMap, BTreeSet};
 
-use ruma::OwnedRoomId;
+use ruma::{OwnedDeviceId, OwnedRoomId};
 
 /// Return type for the room key importing.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -68,12 +76,32 @@ impl RoomKeyImportResult {
     }
 }
 
+/// Return type for the encryption of a to-device event, see
+/// [`OlmMachine::encrypt_to_device()`].
+#[derive(Debug, Clone)]
+pub struct ToDeviceEncryptionResult {
+    /// The requests that send the encrypted event to the devices.
+    pub requests: Vec<ToDeviceRequest>,
+    /// The devices that won't receive the event, because they are unknown,
+    /// blacklisted or because we don't have an Olm session with them.
+    pub failed_devices: Vec<OwnedDeviceId>,
+}
+
+#[cfg(feature = "decryption-audit")]
+pub use decryption_audit::{DecryptionAuditRecord, DecryptionAuditSink};
+pub use decryption_statistics::{
+    DecryptionCounters, DecryptionStatistics, LateDecryptionReport, UtdCause, UtdReport,
+    UtdTelemetryHook,
+};
 pub use error::{EventError, MegolmError, OlmError, SessionCreationError, SignatureError};
 pub use file_encryption::{
-    decrypt_room_key_export, encrypt_room_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError, KeyExportError, MediaEncryptionInfo,
+    decrypt_room_key_export, decrypt_with_passphrase, encrypt_room_key_export,
+    encrypt_with_passphrase, AttachmentDecryptor, AttachmentEncryptor, DecryptorError,
+    KeyExportError, MediaEncryptionInfo, RoomKeyExportReader, RoomKeyExportWriter,
 };
-pub use gossiping::GossipRequest;
+pub use gossiping::{GossipRequest, RoomKeyForwardingOutcome, RoomKeyForwardingRecord};
+#[cfg(feature = "automatic-room-key-forwarding")]
+pub use gossiping::{RoomKeyForwardingPolicy, RoomKeyForwardingRequest};
 pub use identities::{
     Device, LocalTrust, OwnUserIdentity, ReadOnlyDevice, ReadOnlyOwnUserIdentity,
     ReadOnlyUserIdentities, ReadOnlyUserIdentity, UserDevices, UserIdentities, UserIdentity,
@@ -81,13 +109,20 @@ pub use identities::{
 pub use machine::OlmMachine;
 #[cfg(feature = "qrcode")]
 pub use matrix_sdk_qrcode;
-pub use olm::{CrossSigningStatus, EncryptionSettings, ReadOnlyAccount};
+pub use olm::{
+    CrossSigningStatus, EncryptionSettings, ReadOnlyAccount, SenderAuthenticationDowngrade,
+    SenderAuthenticationMode, SenderAuthenticationPolicy, TrustRequirement,
+};
 pub use requests::{
     IncomingResponse, KeysBackupRequest, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
     OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest, UploadSigningKeysRequest,
 };
+pub use session_manager::{
+    EncryptionPreflightReport, PreflightWarning, UserEncryptionPreflight, MANY_DEVICES_THRESHOLD,
+};
 pub use store::{
-    CrossSigningKeyExport, CryptoStoreError, SecretImportError, SecretInfo, TrackedUser,
+    CrossSigningKeyExport, CryptoStoreError, DecryptedToDeviceEvent, SecretImportError, SecretInfo,
+    TrackedUser,
 };
 pub use verification::{
     format_emojis, AcceptSettings, AcceptedProtocols, CancelInfo, Emoji, EmojiShortAuthString, Sas,
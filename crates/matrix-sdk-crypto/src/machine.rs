@@ -14,11 +14,14 @@
 
 use std::{
     collections::{BTreeMap, BTreeSet, HashSet},
+    iter,
     sync::Arc,
     time::Duration,
 };
 
 use dashmap::DashMap;
+use futures_core::Stream;
+use futures_util::{stream, TryStreamExt};
 use matrix_sdk_common::deserialized_responses::{
     AlgorithmInfo, DeviceLinkProblem, EncryptionInfo, TimelineEvent, VerificationLevel,
     VerificationState,
@@ -35,11 +38,13 @@ use ruma::{
     },
     assign,
     events::{
-        secret::request::SecretName, AnyMessageLikeEvent, AnyToDeviceEvent, MessageLikeEventContent,
+        secret::request::SecretName, AnyMessageLikeEvent, AnyToDeviceEvent,
+        AnyToDeviceEventContent, MessageLikeEventContent, ToDeviceEventType,
     },
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, OwnedDeviceId, OwnedDeviceKeyId, OwnedTransactionId, OwnedUserId,
-    RoomId, TransactionId, UInt, UserId,
+    to_device::DeviceIdOrAllDevices,
+    DeviceId, DeviceKeyAlgorithm, OwnedDeviceId, OwnedDeviceKeyId, OwnedRoomId, OwnedTransactionId,
+    OwnedUserId, RoomId, TransactionId, UInt, UserId,
 };
 use serde_json::{value::to_raw_value, Value};
 use tokio::sync::Mutex;
@@ -53,22 +58,35 @@ use vodozemac::{
     Curve25519PublicKey, Ed25519Signature,
 };
 
+#[cfg(any(test, feature = "testing"))]
+use crate::fault_injection::{Fault, FaultScenario};
+#[cfg(feature = "automatic-room-key-forwarding")]
+use crate::gossiping::RoomKeyForwardingPolicy;
 #[cfg(feature = "backups_v1")]
-use crate::backups::BackupMachine;
 use crate::{
+    backups::{BackupMachine, BackupRestore, RestoreSettings},
+    store::RecoveryKey,
+};
+use crate::{
+    decryption_statistics::{
+        DecryptionStatistics, DecryptionStatisticsTracker, UtdCause, UtdTelemetryHook,
+    },
     error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult},
-    gossiping::GossipMachine,
+    gossiping::{self, GossipMachine, RoomKeyForwardingRecord},
     identities::{user::UserIdentities, Device, IdentityManager, UserDevices},
     olm::{
         Account, CrossSigningStatus, EncryptionSettings, ExportedRoomKey, IdentityKeys,
         InboundGroupSession, OlmDecryptionInfo, PrivateCrossSigningIdentity, ReadOnlyAccount,
-        SessionType,
+        SessionType, ShareState,
     },
     requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
-    session_manager::{GroupSessionManager, SessionManager},
+    session_manager::{
+        encryption_preflight, EncryptionPreflightReport, GroupSessionManager, SessionManager,
+    },
     store::{
-        Changes, DeviceChanges, DynCryptoStore, IdentityChanges, IntoCryptoStore, MemoryStore,
-        Result as StoreResult, SecretImportError, Store,
+        Changes, CryptoStateExport, CryptoStoreError, DecryptedToDeviceEvent, DeviceChanges,
+        DynCryptoStore, IdentityChanges, IntoCryptoStore, MemoryStore, Result as StoreResult,
+        SecretImportError, Store,
     },
     types::{
         events::{
@@ -78,17 +96,20 @@ use crate::{
                 RoomEventEncryptionScheme, SupportedEventEncryptionSchemes,
             },
             room_key::{MegolmV1AesSha2Content, RoomKeyContent},
+            room_key_bundle::{RoomKeyBundle, RoomKeyBundleContent},
             room_key_withheld::{
                 MegolmV1AesSha2WithheldContent, RoomKeyWithheldContent, RoomKeyWithheldEvent,
             },
-            ToDeviceEvents,
+            EventType, ToDeviceEvents,
         },
         Signatures,
     },
     verification::{Verification, VerificationMachine, VerificationRequest},
     CrossSigningKeyExport, CryptoStoreError, LocalTrust, ReadOnlyDevice, RoomKeyImportResult,
-    SignatureError, ToDeviceRequest,
+    SignatureError, ToDeviceEncryptionResult, ToDeviceRequest,
 };
+#[cfg(feature = "decryption-audit")]
+use crate::{DecryptionAuditRecord, DecryptionAuditSink};
 
 /// State machine implementation of the Olm/Megolm encryption protocol used for
 /// Matrix end to end encryption.
@@ -129,6 +150,11 @@ pub struct OlmMachineInner {
     /// A state machine that handles creating room key backups.
     #[cfg(feature = "backups_v1")]
     backup_machine: BackupMachine,
+    /// The sink notified of every successful decryption of a room event.
+    #[cfg(feature = "decryption-audit")]
+    decryption_audit_sink: std::sync::RwLock<Option<Arc<dyn DecryptionAuditSink>>>,
+    /// The statistics about the decryption of room events.
+    decryption_statistics: DecryptionStatisticsTracker,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -212,6 +238,9 @@ impl OlmMachine {
             identity_manager,
             #[cfg(feature = "backups_v1")]
             backup_machine,
+            #[cfg(feature = "decryption-audit")]
+            decryption_audit_sink: Default::default(),
+            decryption_statistics: Default::default(),
         });
 
         Self { inner }
@@ -301,7 +330,10 @@ impl OlmMachine {
             }
         };
 
-        Ok(OlmMachine::new_helper(user_id, device_id, store, account, identity))
+        let machine = OlmMachine::new_helper(user_id, device_id, store, account, identity);
+        machine.inner.verification_machine.cancel_interrupted_verifications().await?;
+
+        Ok(machine)
     }
 
     /// Get the crypto store associated with this `OlmMachine` instance.
@@ -352,6 +384,70 @@ impl OlmMachine {
         self.inner.key_request_machine.is_room_key_forwarding_enabled()
     }
 
+    /// Set the policy deciding which incoming room key requests are answered.
+    ///
+    /// The policy is applied on top of the built-in rules, it can only decline
+    /// requests that would otherwise be answered.
+    #[cfg(feature = "automatic-room-key-forwarding")]
+    pub fn set_room_key_forwarding_policy(&self, policy: RoomKeyForwardingPolicy) {
+        self.inner.key_request_machine.set_room_key_forwarding_policy(policy)
+    }
+
+    /// Set the sink that is notified of every successful decryption of a room
+    /// event, or remove it with `None`.
+    ///
+    /// The sink only receives metadata about the events, see
+    /// [`DecryptionAuditRecord`].
+    #[cfg(feature = "decryption-audit")]
+    pub fn set_decryption_audit_sink(&self, sink: Option<Arc<dyn DecryptionAuditSink>>) {
+        *self.inner.decryption_audit_sink.write().unwrap() = sink;
+    }
+
+    /// Set the hook that is notified of the room events that couldn't be
+    /// decrypted, or remove it with `None`.
+    ///
+    /// The hook is also notified when one of these events is decrypted later,
+    /// with the time it took, see [`UtdTelemetryHook`].
+    pub fn set_utd_telemetry_hook(&self, hook: Option<Arc<dyn UtdTelemetryHook>>) {
+        self.inner.decryption_statistics.set_hook(hook);
+    }
+
+    /// Get the statistics about the decryption of room events since this
+    /// machine was created.
+    pub fn decryption_statistics(&self) -> DecryptionStatistics {
+        self.inner.decryption_statistics.statistics()
+    }
+
+    /// Inject the simulated failures of the given scenario, to test how they
+    /// are handled.
+    ///
+    /// This replaces the previous scenario.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn inject_faults(&self, scenario: FaultScenario) {
+        self.store().fault_injector().set_scenario(scenario);
+    }
+
+    /// Get the simulated failures that were injected since the current
+    /// scenario was set with [`OlmMachine::inject_faults()`].
+    #[cfg(any(test, feature = "testing"))]
+    pub fn injected_faults(&self) -> Vec<Fault> {
+        self.store().fault_injector().injected_faults()
+    }
+
+    /// Get the audit log of the answers given to incoming room key requests,
+    /// from the oldest to the newest record.
+    ///
+    /// The log is persisted in the crypto store, only the most recent records
+    /// are kept.
+    pub async fn room_key_forwarding_log(&self) -> StoreResult<Vec<RoomKeyForwardingRecord>> {
+        gossiping::load_room_key_forwarding_log(self.store()).await
+    }
+
+    /// Remove all the records from the room key forwarding audit log.
+    pub async fn clear_room_key_forwarding_log(&self) -> StoreResult<()> {
+        self.inner.key_request_machine.clear_room_key_forwarding_log().await
+    }
+
     /// Get the outgoing requests that need to be sent out.
     ///
     /// This returns a list of [`OutgoingRequest`]. Those requests need to be
@@ -409,6 +505,10 @@ impl OlmMachine {
                 self.receive_keys_query_response(request_id, response).await?;
             }
             IncomingResponse::KeysClaim(response) => {
+                #[cfg(any(test, feature = "testing"))]
+                let response = self.store().fault_injector().keys_claim_response(response);
+                #[cfg(any(test, feature = "testing"))]
+                let response = &*response;
                 self.receive_keys_claim_response(response).await?;
             }
             IncomingResponse::ToDevice(_) => {
@@ -422,6 +522,7 @@ impl OlmMachine {
             }
             IncomingResponse::RoomMessage(_) => {
                 self.inner.verification_machine.mark_request_as_sent(request_id);
+                self.inner.verification_machine.save_pending_verifications().await?;
             }
             IncomingResponse::KeysBackup(_) => {
                 #[cfg(feature = "backups_v1")]
@@ -797,6 +898,42 @@ impl OlmMachine {
         self.inner.group_session_manager.invalidate_group_session(room_id).await
     }
 
+    /// Get our own other devices that can't decrypt the last message that was
+    /// encrypted for the given room.
+    ///
+    /// This can be checked after sending a message in an encrypted room, to
+    /// warn the user that they won't be able to read it on their other
+    /// devices, e.g. because a device appeared after the room key was shared
+    /// and only received it for the next messages. The blacklisted and deleted
+    /// devices are ignored.
+    ///
+    /// Returns an empty list if no message was encrypted with the current room
+    /// key of the room.
+    pub async fn own_devices_without_room_key(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<Vec<OwnedDeviceId>> {
+        let Some(session) = self.inner.group_session_manager.get_outbound_group_session(room_id)
+        else {
+            return Ok(Vec::new());
+        };
+        let Some(last_message_index) = session.message_index().await.checked_sub(1) else {
+            return Ok(Vec::new());
+        };
+
+        let devices = self.store().get_user_devices_filtered(self.user_id()).await?;
+
+        Ok(devices
+            .devices()
+            .filter(|device| !device.is_blacklisted() && !device.is_deleted())
+            .filter(|device| match session.is_shared_with(device) {
+                ShareState::Shared(message_index) => message_index > last_message_index,
+                ShareState::NotShared | ShareState::SharedButChangedSenderKey => true,
+            })
+            .map(|device| device.device_id().to_owned())
+            .collect())
+    }
+
     /// Get to-device requests to share a room key with users in a room.
     ///
     /// # Arguments
@@ -817,6 +954,39 @@ impl OlmMachine {
         self.inner.group_session_manager.share_room_key(room_id, users, encryption_settings).await
     }
 
+    /// Check what would happen if a room key was shared with the given users,
+    /// without sharing it.
+    ///
+    /// This can be used before creating an encrypted room or enabling
+    /// encryption in a room, to warn about users that wouldn't be able to read
+    /// the messages, e.g. because they have no device supporting encryption.
+    ///
+    /// Only the devices and identities that are in the store are considered,
+    /// so the users should be tracked and their keys queried first, see
+    /// [`OlmMachine::update_tracked_users()`].
+    ///
+    /// # Arguments
+    ///
+    /// `users` - The list of users that would receive the room key.
+    ///
+    /// `encryption_settings` - The encryption settings of the room.
+    ///
+    /// `timeout` - The amount of time to wait for a pending key query of the
+    /// users to finish, if any.
+    pub async fn encryption_preflight(
+        &self,
+        users: impl Iterator<Item = &UserId>,
+        encryption_settings: impl Into<EncryptionSettings>,
+        timeout: Option<Duration>,
+    ) -> OlmResult<EncryptionPreflightReport> {
+        let users: Vec<_> = users.collect();
+        for user_id in &users {
+            self.wait_if_user_pending(user_id, timeout).await;
+        }
+
+        encryption_preflight(self.store(), users.into_iter(), &encryption_settings.into()).await
+    }
+
     /// Receive an unencrypted verification event.
     ///
     /// This method can be used to pass verification events that are happening
@@ -890,8 +1060,25 @@ impl OlmMachine {
             AnyDecryptedOlmEvent::Dummy(_) => {
                 debug!("Received an `m.dummy` event");
             }
-            AnyDecryptedOlmEvent::Custom(_) => {
-                warn!("Received an unexpected encrypted to-device event");
+            AnyDecryptedOlmEvent::Custom(e) => {
+                // Room key bundles need to be downloaded before they can be
+                // imported, so this is left to the consumers of the event,
+                // like for custom events.
+                debug!("Received a custom encrypted to-device event");
+
+                let sender_device_id = self
+                    .store()
+                    .get_device_from_curve_key(&e.sender, decrypted.result.sender_key)
+                    .await?
+                    .map(|device| device.device_id().to_owned());
+
+                self.store().notify_decrypted_to_device_event(DecryptedToDeviceEvent {
+                    sender: e.sender.clone(),
+                    sender_key: decrypted.result.sender_key,
+                    sender_device_id,
+                    event_type: e.event_type.clone(),
+                    raw_event: decrypted.result.raw_event.clone(),
+                });
             }
         }
 
@@ -907,6 +1094,7 @@ impl OlmMachine {
     /// Mark an outgoing to-device requests as sent.
     async fn mark_to_device_request_as_sent(&self, request_id: &TransactionId) -> StoreResult<()> {
         self.inner.verification_machine.mark_request_as_sent(request_id);
+        self.inner.verification_machine.save_pending_verifications().await?;
         self.inner.key_request_machine.mark_outgoing_request_as_sent(request_id).await?;
         self.inner.group_session_manager.mark_request_as_sent(request_id).await?;
         self.inner.session_manager.mark_outgoing_request_as_sent(request_id);
@@ -992,7 +1180,7 @@ impl OlmMachine {
         &self,
         changes: &mut Changes,
         mut raw_event: Raw<AnyToDeviceEvent>,
-    ) -> OlmResult<Raw<AnyToDeviceEvent>> {
+    ) -> OlmResult<Option<Raw<AnyToDeviceEvent>>> {
         Self::record_message_id(&raw_event);
 
         let event: ToDeviceEvents = match raw_event.deserialize_as() {
@@ -1001,7 +1189,7 @@ impl OlmMachine {
                 // Skip invalid events.
                 warn!("Received an invalid to-device event: {e}");
 
-                return Ok(raw_event);
+                return Ok(Some(raw_event));
             }
         };
 
@@ -1026,7 +1214,7 @@ impl OlmMachine {
                             }
                         }
 
-                        return Ok(raw_event);
+                        return Ok(Some(raw_event));
                     }
                 };
 
@@ -1064,10 +1252,17 @@ impl OlmMachine {
                 }
             }
 
+            // Room key bundles must only be accepted over Olm, otherwise
+            // anyone could claim to be the sender.
+            e if e.event_type().to_string() == RoomKeyBundleContent::EVENT_TYPE => {
+                warn!("Dropping an unencrypted room key bundle");
+
+                return Ok(None);
+            }
             e => self.handle_to_device_event(changes, &e).await,
         }
 
-        Ok(raw_event)
+        Ok(Some(raw_event))
     }
 
     /// Handle a to-device and one-time key counts from a sync response.
@@ -1116,14 +1311,23 @@ impl OlmMachine {
             error!(error = ?e, "Error marking a tracked user as changed");
         }
 
+        #[cfg(any(test, feature = "testing"))]
+        let to_device_events = self.store().fault_injector().to_device_events(to_device_events);
+
         for raw_event in to_device_events {
-            let raw_event = Box::pin(self.receive_to_device_event(&mut changes, raw_event)).await?;
-            events.push(raw_event);
+            if let Some(raw_event) =
+                Box::pin(self.receive_to_device_event(&mut changes, raw_event)).await?
+            {
+                events.push(raw_event);
+            }
         }
 
         let changed_sessions =
             self.inner.key_request_machine.collect_incoming_key_requests().await?;
 
+        // The sessions used to encrypt the responses to the key requests must
+        // not be deferred by a store coalescing the writes of the sessions.
+        changes.sessions_used_to_encrypt = !changed_sessions.is_empty();
         changes.sessions.extend(changed_sessions);
 
         self.store().save_changes(changes).await?;
@@ -1249,6 +1453,33 @@ impl OlmMachine {
         })
     }
 
+    /// Notify the decryption audit sink, if any, that the given event was
+    /// decrypted.
+    #[cfg(feature = "decryption-audit")]
+    fn audit_decryption(
+        &self,
+        room_id: &RoomId,
+        event: &EncryptedEvent,
+        session: &InboundGroupSession,
+        encryption_info: &EncryptionInfo,
+    ) {
+        let Some(sink) = self.inner.decryption_audit_sink.read().unwrap().clone() else {
+            return;
+        };
+
+        sink.record(DecryptionAuditRecord {
+            room_id: room_id.to_owned(),
+            event_id: event.event_id.clone(),
+            sender: event.sender.clone(),
+            sender_device_id: encryption_info.sender_device.clone(),
+            sender_key: session.sender_key(),
+            session_id: session.session_id().to_owned(),
+            algorithm: session.algorithm().to_owned(),
+            origin_server_ts: event.origin_server_ts,
+            decrypted_at: ruma::MilliSecondsSinceUnixEpoch::now(),
+        });
+    }
+
     async fn decrypt_megolm_events(
         &self,
         room_id: &RoomId,
@@ -1269,6 +1500,10 @@ impl OlmMachine {
             match result {
                 Ok((decrypted_event, _)) => {
                     let encryption_info = self.get_encryption_info(&session, &event.sender).await?;
+
+                    #[cfg(feature = "decryption-audit")]
+                    self.audit_decryption(room_id, event, &session, &encryption_info);
+
                     Ok(TimelineEvent {
                         encryption_info: Some(encryption_info),
                         event: decrypted_event,
@@ -1309,6 +1544,26 @@ impl OlmMachine {
         }
     }
 
+    /// Classify the reason why the given event couldn't be decrypted.
+    fn utd_cause(&self, event: &EncryptedEvent, error: &MegolmError) -> UtdCause {
+        match error {
+            MegolmError::MissingRoomKey(Some(withheld_code)) => {
+                UtdCause::Withheld(withheld_code.clone())
+            }
+            MegolmError::MissingRoomKey(None)
+            | MegolmError::Decryption(DecryptionError::UnknownMessageIndex(_, _)) => {
+                if event.origin_server_ts < self.account().creation_local_time() {
+                    UtdCause::Historical
+                } else if self.inner.session_manager.has_wedged_devices(&event.sender) {
+                    UtdCause::OlmSession
+                } else {
+                    UtdCause::MissingRoomKey
+                }
+            }
+            _ => UtdCause::Other,
+        }
+    }
+
     /// Decrypt an event from a room timeline.
     ///
     /// # Arguments
@@ -1345,6 +1600,22 @@ impl OlmMachine {
         tracing::Span::current().record("session_id", content.session_id());
         let result = self.decrypt_megolm_events(room_id, &event, &content).await;
 
+        match &result {
+            Ok(_) => self.inner.decryption_statistics.record_decryption(
+                room_id,
+                &event.event_id,
+                &event.sender,
+            ),
+            // The event might be decryptable, the store just failed.
+            Err(MegolmError::Store(_)) => {}
+            Err(e) => self.inner.decryption_statistics.record_utd(
+                room_id,
+                &event.event_id,
+                &event.sender,
+                self.utd_cause(&event, e),
+            ),
+        }
+
         if let Err(e) = &result {
             #[cfg(feature = "automatic-room-key-forwarding")]
             match e {
@@ -1390,6 +1661,39 @@ impl OlmMachine {
         self.inner.identity_manager.update_tracked_users(users).await
     }
 
+    /// Query the keys of the given users before the keys of the other
+    /// outdated users.
+    ///
+    /// This is useful in large accounts, where many users might need a key
+    /// query at the same time: the users of the room that is currently being
+    /// displayed, or that a message is being sent to, should be queried
+    /// first.
+    ///
+    /// The priority only applies to the next batch of requests returned by
+    /// [`OlmMachine::outgoing_requests()`]. Users that don't need a key query
+    /// are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The users whose keys should be queried first.
+    pub fn prioritize_key_queries<'a>(&self, users: impl IntoIterator<Item = &'a UserId>) {
+        self.inner.identity_manager.prioritize_key_queries(users)
+    }
+
+    /// Mark the `/keys/query` request with the given ID as failed.
+    ///
+    /// This should be called when sending a request returned by
+    /// [`OlmMachine::outgoing_requests()`] failed, so the keys of its users
+    /// are queried again by the next requests. Otherwise they are only
+    /// queried again once the request times out.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - The ID of the request that failed.
+    pub async fn mark_keys_query_as_failed(&self, request_id: &TransactionId) {
+        self.inner.identity_manager.mark_keys_query_as_failed(request_id).await
+    }
+
     async fn wait_if_user_pending(&self, user_id: &UserId, timeout: Option<Duration>) {
         if let Some(timeout) = timeout {
             self.store().wait_if_user_key_query_pending(timeout, user_id).await;
@@ -1597,6 +1901,10 @@ impl OlmMachine {
 
     /// Export the keys that match the given predicate.
     ///
+    /// All the exported keys are kept in memory, see
+    /// [`OlmMachine::export_room_keys_stream()`] to export a large number of
+    /// keys.
+    ///
     /// # Arguments
     ///
     /// * `predicate` - A closure that will be called for every known
@@ -1624,24 +1932,241 @@ impl OlmMachine {
     /// ```
     pub async fn export_room_keys(
         &self,
-        mut predicate: impl FnMut(&InboundGroupSession) -> bool,
+        predicate: impl FnMut(&InboundGroupSession) -> bool,
     ) -> StoreResult<Vec<ExportedRoomKey>> {
-        let mut exported = Vec::new();
+        self.export_room_keys_stream(predicate).try_collect().await
+    }
 
-        let sessions: Vec<InboundGroupSession> = self
-            .store()
-            .get_inbound_group_sessions()
-            .await?
-            .into_iter()
-            .filter(|s| predicate(s))
+    /// Export the keys that match the given predicate, as a stream.
+    ///
+    /// The room keys are loaded from the store in batches, so only a batch of
+    /// them is in memory at once. The stream can be fed to a
+    /// [`RoomKeyExportWriter`] to write an encrypted export.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - A closure that will be called for every known
+    /// `InboundGroupSession`, which represents a room key. If the closure
+    /// returns `true` the `InboundGroupSession` will be included in the export,
+    /// if the closure returns `false` it will not be included.
+    ///
+    /// [`RoomKeyExportWriter`]: crate::RoomKeyExportWriter
+    pub fn export_room_keys_stream<'a>(
+        &'a self,
+        predicate: impl FnMut(&InboundGroupSession) -> bool + 'a,
+    ) -> impl Stream<Item = StoreResult<ExportedRoomKey>> + 'a {
+        /// The number of sessions loaded from the store at once.
+        const BATCH_SIZE: usize = 1000;
+
+        let store = self.store();
+
+        stream::try_unfold(
+            (predicate, None::<(OwnedRoomId, String)>),
+            move |(mut predicate, after)| async move {
+                let sessions = store
+                    .get_inbound_group_sessions_batch(
+                        after.as_ref().map(|(room_id, session_id)| (&**room_id, &**session_id)),
+                        BATCH_SIZE,
+                    )
+                    .await?;
+
+                let after = match sessions.last() {
+                    Some(last) => (last.room_id().to_owned(), last.session_id().to_owned()),
+                    None => return Ok(None),
+                };
+
+                let mut exported = Vec::new();
+                for session in sessions.into_iter().filter(|s| predicate(s)) {
+                    exported.push(session.export().await);
+                }
+
+                let exported = stream::iter(exported.into_iter().map(Ok));
+
+                Ok::<_, CryptoStoreError>(Some((exported, (predicate, Some(after)))))
+            },
+        )
+        .try_flatten()
+    }
+
+    /// Build a bundle of all the room keys we have for the given room.
+    ///
+    /// The bundle can be serialized, encrypted with an
+    /// [`AttachmentEncryptor`] and uploaded as a file. The decryption info of
+    /// the file can then be sent to the devices of a user with
+    /// [`OlmMachine::share_room_key_bundle_data()`], allowing them to read the
+    /// history of the room without the room keys being sent one by one.
+    ///
+    /// [`AttachmentEncryptor`]: crate::AttachmentEncryptor
+    pub async fn build_room_key_bundle(&self, room_id: &RoomId) -> StoreResult<RoomKeyBundle> {
+        let room_keys = self.export_room_keys(|s| s.room_id() == room_id).await?;
+
+        Ok(RoomKeyBundle { room_id: room_id.to_owned(), room_keys })
+    }
+
+    /// Encrypt the given room key bundle data for all the devices of a user
+    /// and create the to-device requests that send it to them.
+    ///
+    /// Blacklisted devices and devices we don't have an Olm session with are
+    /// skipped, so [`OlmMachine::get_missing_sessions()`] should be used first
+    /// to establish the missing Olm sessions.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should receive the room key bundle.
+    ///
+    /// * `content` - The content pointing to the uploaded room key bundle.
+    pub async fn share_room_key_bundle_data(
+        &self,
+        user_id: &UserId,
+        content: &RoomKeyBundleContent,
+    ) -> OlmResult<Vec<ToDeviceRequest>> {
+        let result = self
+            .encrypt_to_device(user_id, None, content.event_type(), serde_json::to_value(content)?)
+            .await?;
+
+        Ok(result.requests)
+    }
+
+    /// Encrypt a to-device event for the devices of a user and create the
+    /// to-device requests that send it to them.
+    ///
+    /// Blacklisted devices, unknown devices and devices we don't have an Olm
+    /// session with are skipped and returned in
+    /// [`ToDeviceEncryptionResult::failed_devices`], so
+    /// [`OlmMachine::get_missing_sessions()`] should be used first to
+    /// establish the missing Olm sessions. Our own device is always skipped.
+    ///
+    /// The receiving side gets the decrypted event in
+    /// [`Store::decrypted_to_device_events_stream()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should receive the event.
+    ///
+    /// * `device_ids` - The devices that should receive the event, or `None`
+    /// to send it to all the devices of the user.
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `content` - The content of the event.
+    ///
+    /// [`Store::decrypted_to_device_events_stream()`]: crate::store::Store::decrypted_to_device_events_stream
+    pub async fn encrypt_to_device(
+        &self,
+        user_id: &UserId,
+        device_ids: Option<&[OwnedDeviceId]>,
+        event_type: &str,
+        content: Value,
+    ) -> OlmResult<ToDeviceEncryptionResult> {
+        let user_devices = self.store().get_user_devices_filtered(user_id).await?;
+        let mut failed_devices = Vec::new();
+
+        let devices: Vec<Device> = match device_ids {
+            Some(device_ids) => device_ids
+                .iter()
+                .filter_map(|device_id| {
+                    let device = user_devices.get(device_id);
+                    if device.is_none()
+                        && !(user_id == self.user_id() && device_id == self.device_id())
+                    {
+                        failed_devices.push(device_id.clone());
+                    }
+                    device
+                })
+                .collect(),
+            None => user_devices.devices().collect(),
+        };
+
+        let mut messages: Vec<(DeviceIdOrAllDevices, Raw<AnyToDeviceEventContent>)> = Vec::new();
+        let mut changed_sessions = Vec::new();
+
+        for device in devices {
+            if device.is_blacklisted() {
+                failed_devices.push(device.device_id().to_owned());
+                continue;
+            }
+
+            match device.encrypt(event_type, content.clone()).await {
+                Ok((used_session, message)) => {
+                    changed_sessions.push(used_session);
+                    messages.push((
+                        DeviceIdOrAllDevices::DeviceId(device.device_id().to_owned()),
+                        message.cast(),
+                    ));
+                }
+                Err(
+                    OlmError::MissingSession | OlmError::EventError(EventError::MissingSenderKey),
+                ) => {
+                    debug!(
+                        device_id = ?device.device_id(),
+                        "Not sending a to-device event to a device without an Olm session"
+                    );
+                    failed_devices.push(device.device_id().to_owned());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !changed_sessions.is_empty() {
+            self.store().save_sessions(&changed_sessions).await?;
+        }
+
+        let requests = messages
+            .chunks(GroupSessionManager::MAX_TO_DEVICE_MESSAGES)
+            .map(|chunk| ToDeviceRequest {
+                event_type: ToDeviceEventType::RoomEncrypted,
+                txn_id: TransactionId::new(),
+                messages: iter::once((user_id.to_owned(), chunk.iter().cloned().collect()))
+                    .collect(),
+            })
             .collect();
 
-        for session in sessions {
-            let export = session.export().await;
-            exported.push(export);
+        Ok(ToDeviceEncryptionResult { requests, failed_devices })
+    }
+
+    /// Import the room keys of a room key bundle we received.
+    ///
+    /// Only the room keys for the given room are imported, to prevent the
+    /// sender of the bundle from injecting room keys for other rooms.
+    ///
+    /// # Arguments
+    ///
+    /// * `bundle` - The decrypted room key bundle.
+    ///
+    /// * `room_id` - The room that the [`RoomKeyBundleContent`] we received
+    /// was about.
+    pub async fn import_room_key_bundle(
+        &self,
+        bundle: RoomKeyBundle,
+        room_id: &RoomId,
+    ) -> StoreResult<RoomKeyImportResult> {
+        if bundle.room_id != room_id {
+            warn!(
+                expected_room_id = ?room_id,
+                bundle_room_id = ?bundle.room_id,
+                "The room key bundle is for a different room than announced",
+            );
         }
 
-        Ok(exported)
+        let room_keys = bundle
+            .room_keys
+            .into_iter()
+            .filter(|key| {
+                let matches = key.room_id == room_id;
+
+                if !matches {
+                    warn!(
+                        session_id = key.session_id,
+                        room_id = ?key.room_id,
+                        "Ignoring a room key for another room in a room key bundle",
+                    );
+                }
+
+                matches
+            })
+            .collect();
+
+        self.import_room_keys(room_keys, false, |_, _| {}).await
     }
 
     /// Get the status of the private cross signing keys.
@@ -1684,6 +2209,26 @@ impl OlmMachine {
         self.store().import_cross_signing_keys(export).await
     }
 
+    /// Export the state needed to restore this device on another machine.
+    ///
+    /// The export can be saved into the store of the other machine with
+    /// [`CryptoStateExport::import_into()`], before an `OlmMachine` is created
+    /// with this store.
+    ///
+    /// **Warning**: The export contains the private keys of the device in the
+    /// clear, it should be encrypted before it leaves the memory, for example
+    /// with [`encrypt_with_passphrase()`].
+    ///
+    /// [`encrypt_with_passphrase()`]: crate::encrypt_with_passphrase
+    pub async fn export_state(&self) -> StoreResult<CryptoStateExport> {
+        let account = self.account().pickle().await;
+        let private_identity = self.inner.user_identity.lock().await.pickle().await;
+        let room_keys = self.export_room_keys(|_| true).await?;
+        let backup_keys = self.store().load_backup_keys().await?;
+
+        Ok(CryptoStateExport::new(account, private_identity, room_keys, backup_keys))
+    }
+
     async fn sign_with_master_key(
         &self,
         message: &str,
@@ -1728,6 +2273,129 @@ impl OlmMachine {
     pub fn backup_machine(&self) -> &BackupMachine {
         &self.inner.backup_machine
     }
+
+    /// Start or resume the restore of the room keys of a server-side backup.
+    ///
+    /// If a restore of the same backup version was interrupted, the returned
+    /// [`BackupRestore`] resumes from its last checkpoint, otherwise it starts
+    /// from scratch.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The version of the backup to restore.
+    ///
+    /// * `recovery_key` - The recovery key to decrypt the backed up room keys.
+    ///
+    /// * `settings` - The settings of the restore.
+    #[cfg(feature = "backups_v1")]
+    pub async fn restore_backup(
+        &self,
+        version: &str,
+        recovery_key: RecoveryKey,
+        settings: RestoreSettings,
+    ) -> StoreResult<BackupRestore> {
+        BackupRestore::new(self.clone(), version.to_owned(), recovery_key, settings).await
+    }
+
+    /// The key of the generation counter in the crypto store.
+    const CURRENT_GENERATION_STORE_KEY: &'static str = "generation-counter";
+
+    /// Initialize the generation counter of the crypto store, used to detect
+    /// that another process sharing the store modified it.
+    ///
+    /// This must be called once while holding the cross-process lock of the
+    /// store, see [`Store::create_store_lock()`]. The generation known by
+    /// this process is saved in `generation`, which must outlive the
+    /// `OlmMachine` since the machine is recreated when the store is
+    /// modified by another process.
+    pub async fn initialize_crypto_store_generation(
+        &self,
+        generation: &Mutex<Option<u64>>,
+    ) -> StoreResult<()> {
+        // Hold the lock for the whole function, to avoid concurrent
+        // initializations.
+        let mut generation_guard = generation.lock().await;
+
+        let new_generation =
+            match self.store().get_custom_value(Self::CURRENT_GENERATION_STORE_KEY).await? {
+                // Another process initialized the counter, increment it so
+                // this process is seen as a different writer.
+                Some(value) => decode_generation(value)?.wrapping_add(1),
+                None => 0,
+            };
+
+        debug!(generation = new_generation, "Initializing the crypto store generation");
+        self.store()
+            .set_custom_value(
+                Self::CURRENT_GENERATION_STORE_KEY,
+                new_generation.to_le_bytes().to_vec(),
+            )
+            .await?;
+
+        *generation_guard = Some(new_generation);
+
+        Ok(())
+    }
+
+    /// Check whether another process modified the crypto store since this
+    /// process last held the cross-process lock of the store.
+    ///
+    /// This must be called every time the lock is taken, before using the
+    /// `OlmMachine`. If it returns `true`, the caches in memory are outdated
+    /// and the `OlmMachine` must be recreated, after clearing the caches of
+    /// the store with [`CryptoStore::clear_caches()`].
+    ///
+    /// When the generation changed, it is incremented in the store and in
+    /// `generation`, so the next change of process is detected too.
+    ///
+    /// [`CryptoStore::clear_caches()`]: crate::store::CryptoStore::clear_caches
+    pub async fn maintain_crypto_store_generation(
+        &self,
+        generation: &Mutex<Option<u64>>,
+    ) -> StoreResult<bool> {
+        let mut generation_guard = generation.lock().await;
+
+        // The counter was written either by this process when it initialized
+        // it, or by another process that held the lock at that time.
+        let actual_generation =
+            self.store().get_custom_value(Self::CURRENT_GENERATION_STORE_KEY).await?.ok_or_else(
+                || CryptoStoreError::InvalidLockGeneration("missing counter".to_owned()),
+            )?;
+        let actual_generation = decode_generation(actual_generation)?;
+
+        let new_generation = match *generation_guard {
+            Some(expected) if expected == actual_generation => return Ok(false),
+            Some(expected) => expected.max(actual_generation).wrapping_add(1),
+            // Another process held the lock when this one initialized the
+            // counter.
+            None => actual_generation.wrapping_add(1),
+        };
+
+        debug!(
+            expected = ?*generation_guard,
+            actual = actual_generation,
+            new = new_generation,
+            "The crypto store was modified by another process"
+        );
+
+        self.store()
+            .set_custom_value(
+                Self::CURRENT_GENERATION_STORE_KEY,
+                new_generation.to_le_bytes().to_vec(),
+            )
+            .await?;
+        *generation_guard = Some(new_generation);
+
+        Ok(true)
+    }
+}
+
+/// Decode the generation counter of the crypto store.
+fn decode_generation(value: Vec<u8>) -> StoreResult<u64> {
+    let bytes = value
+        .try_into()
+        .map_err(|_| CryptoStoreError::InvalidLockGeneration("invalid format".to_owned()))?;
+    Ok(u64::from_le_bytes(bytes))
 }
 
 #[cfg(any(feature = "testing", test))]
@@ -1778,7 +2446,7 @@ pub(crate) mod tests {
         room_id,
         serde::Raw,
         uint, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch,
-        OwnedDeviceKeyId, SecondsSinceUnixEpoch, TransactionId, UserId,
+        OwnedDeviceKeyId, RoomId, SecondsSinceUnixEpoch, TransactionId, UserId,
     };
     use serde_json::json;
     use vodozemac::{
@@ -1789,11 +2457,19 @@ pub(crate) mod tests {
     use super::testing::response_from_file;
     use crate::{
         error::EventError,
+        fault_injection::{Fault, FaultRule, FaultScenario},
         machine::OlmMachine,
         olm::{InboundGroupSession, OutboundGroupSession, VerifyJson},
+        store::{
+            coalescing::CoalescingCryptoStore, CryptoStateExport, CryptoStore, IntoCryptoStore,
+            MemoryStore,
+        },
         types::{
             events::{
-                room::encrypted::{EncryptedToDeviceEvent, ToDeviceEncryptedEventContent},
+                room::encrypted::{
+                    EncryptedEvent, EncryptedToDeviceEvent, ToDeviceEncryptedEventContent,
+                },
+                room_key_bundle::{RoomKeyBundleContent, RoomKeyBundleEvent},
                 room_key_withheld::{RoomKeyWithheldContent, WithheldCode},
                 ToDeviceEvent,
             },
@@ -1801,8 +2477,9 @@ pub(crate) mod tests {
         },
         utilities::json_convert,
         verification::tests::{outgoing_request_to_event, request_to_event},
-        EncryptionSettings, LocalTrust, MegolmError, OlmError, ReadOnlyDevice, ToDeviceRequest,
-        UserIdentities,
+        CryptoStoreError, EncryptionSettings, LocalTrust, MegolmError, OlmError, ReadOnlyDevice,
+        SenderAuthenticationDowngrade, SenderAuthenticationMode, SenderAuthenticationPolicy,
+        ToDeviceRequest, UserIdentities, UtdCause, UtdReport, UtdTelemetryHook,
     };
 
     /// These keys need to be periodically uploaded to the server.
@@ -1855,15 +2532,23 @@ pub(crate) mod tests {
 
     pub(crate) async fn get_prepared_machine(use_fallback_key: bool) -> (OlmMachine, OneTimeKeys) {
         let machine = OlmMachine::new(user_id(), bob_device_id()).await;
+        let keys = upload_initial_keys(&machine, use_fallback_key).await;
+
+        (machine, keys)
+    }
+
+    async fn upload_initial_keys(machine: &OlmMachine, use_fallback_key: bool) -> OneTimeKeys {
         machine.account().generate_fallback_key_helper().await;
         machine.account().update_uploaded_key_count(0);
         let request = machine.keys_for_upload().await.expect("Can't prepare initial key upload");
         let response = keys_upload_response();
         machine.receive_keys_upload_response(&response).await.unwrap();
 
-        let keys = if use_fallback_key { request.fallback_keys } else { request.one_time_keys };
-
-        (machine, keys)
+        if use_fallback_key {
+            request.fallback_keys
+        } else {
+            request.one_time_keys
+        }
     }
 
     async fn get_machine_after_query() -> (OlmMachine, OneTimeKeys) {
@@ -1997,25 +2682,112 @@ pub(crate) mod tests {
     }
 
     #[async_test]
-    async fn test_invalid_signature() {
-        let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+    async fn test_export_and_import_state() {
+        let machine = OlmMachine::new(alice_id(), alice_device_id()).await;
+        let room_id = room_id!("!test:example.org");
+        machine.create_outbound_group_session_with_defaults(room_id).await.unwrap();
 
-        let device_keys = machine.account().device_keys().await;
+        let export = machine.export_state().await.unwrap();
+        let export: CryptoStateExport =
+            serde_json::from_str(&serde_json::to_string(&export).unwrap()).unwrap();
+        assert_eq!(export.room_keys.len(), 1);
 
-        let key = Ed25519PublicKey::from_slice(&[0u8; 32]).unwrap();
+        let store = MemoryStore::new().into_crypto_store();
+        export.import_into(&store).await.unwrap();
 
-        let ret = key.verify_json(
-            machine.user_id(),
-            &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, machine.device_id()),
-            &device_keys,
+        let restored =
+            OlmMachine::with_store(alice_id(), alice_device_id(), store.clone()).await.unwrap();
+        assert_eq!(restored.identity_keys(), machine.identity_keys());
+        assert_eq!(restored.export_room_keys(|_| true).await.unwrap().len(), 1);
+
+        let own_device =
+            restored.get_device(alice_id(), alice_device_id(), None).await.unwrap().unwrap();
+        assert!(own_device.is_locally_trusted());
+
+        // The state of another device can't be imported into this store.
+        let other_machine = OlmMachine::new(alice_id(), device_id!("OTHERALICE")).await;
+        let other_export = other_machine.export_state().await.unwrap();
+        assert_matches!(
+            other_export.import_into(&store).await,
+            Err(CryptoStoreError::MismatchedAccount { .. })
         );
-        ret.unwrap_err();
     }
 
     #[async_test]
-    async fn one_time_key_signing() {
-        let machine = OlmMachine::new(user_id(), alice_device_id()).await;
-        machine.account().update_uploaded_key_count(49);
+    async fn test_crypto_store_generation() {
+        let store = MemoryStore::new().into_crypto_store();
+        let first =
+            OlmMachine::with_store(alice_id(), alice_device_id(), store.clone()).await.unwrap();
+        let second =
+            OlmMachine::with_store(alice_id(), alice_device_id(), store.clone()).await.unwrap();
+        let first_generation = tokio::sync::Mutex::new(None);
+        let second_generation = tokio::sync::Mutex::new(None);
+
+        first.initialize_crypto_store_generation(&first_generation).await.unwrap();
+        assert_eq!(*first_generation.lock().await, Some(0));
+        assert!(!first.maintain_crypto_store_generation(&first_generation).await.unwrap());
+
+        // Another process takes the lock, the first one must reload its state
+        // the next time it takes the lock.
+        second.initialize_crypto_store_generation(&second_generation).await.unwrap();
+        assert_eq!(*second_generation.lock().await, Some(1));
+        assert!(first.maintain_crypto_store_generation(&first_generation).await.unwrap());
+        assert!(!first.maintain_crypto_store_generation(&first_generation).await.unwrap());
+
+        // And so does the second one.
+        assert!(second.maintain_crypto_store_generation(&second_generation).await.unwrap());
+        assert!(!second.maintain_crypto_store_generation(&second_generation).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_own_devices_without_room_key() {
+        let machine = OlmMachine::new(alice_id(), alice_device_id()).await;
+        let room_id = room_id!("!test:example.org");
+
+        machine
+            .share_room_key(room_id, iter::empty(), EncryptionSettings::default())
+            .await
+            .unwrap();
+        // No message was encrypted yet.
+        assert!(machine.own_devices_without_room_key(room_id).await.unwrap().is_empty());
+
+        // Our other device appears after the room key was shared.
+        let other_machine = OlmMachine::new(alice_id(), device_id!("OTHERALICE")).await;
+        let other_device = ReadOnlyDevice::from_machine(&other_machine).await;
+        machine.store().save_devices(&[other_device]).await.unwrap();
+
+        let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+        machine
+            .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            machine.own_devices_without_room_key(room_id).await.unwrap(),
+            [device_id!("OTHERALICE").to_owned()]
+        );
+    }
+
+    #[async_test]
+    async fn test_invalid_signature() {
+        let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+
+        let device_keys = machine.account().device_keys().await;
+
+        let key = Ed25519PublicKey::from_slice(&[0u8; 32]).unwrap();
+
+        let ret = key.verify_json(
+            machine.user_id(),
+            &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, machine.device_id()),
+            &device_keys,
+        );
+        ret.unwrap_err();
+    }
+
+    #[async_test]
+    async fn one_time_key_signing() {
+        let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+        machine.account().update_uploaded_key_count(49);
 
         let mut one_time_keys = machine.account().signed_one_time_keys().await;
         let ed25519_key = machine.account().identity_keys().ed25519;
@@ -2316,6 +3088,267 @@ pub(crate) mod tests {
         assert!(session.unwrap().is_some());
     }
 
+    #[async_test]
+    async fn test_coalesced_sync_changes_are_written_together() {
+        let backend = Arc::new(MemoryStore::new());
+        let store =
+            Arc::new(CoalescingCryptoStore::new(backend.clone(), Duration::from_secs(3600)));
+        let bob = OlmMachine::with_store(user_id(), bob_device_id(), store.clone()).await.unwrap();
+        let one_time_keys = upload_initial_keys(&bob, false).await;
+
+        let alice = OlmMachine::new(alice_id(), alice_device_id()).await;
+        let alice_device = ReadOnlyDevice::from_machine(&alice).await;
+        let bob_device = ReadOnlyDevice::from_machine(&bob).await;
+        alice.store().save_devices(&[bob_device]).await.unwrap();
+        bob.store().save_devices(&[alice_device]).await.unwrap();
+
+        let (device_key_id, one_time_key) = one_time_keys.into_iter().next().unwrap();
+        let one_time_keys = BTreeMap::from([(
+            bob.user_id().to_owned(),
+            BTreeMap::from([(
+                bob.device_id().to_owned(),
+                BTreeMap::from([(device_key_id, one_time_key)]),
+            )]),
+        )]);
+        alice
+            .receive_keys_claim_response(&claim_keys::v3::Response::new(one_time_keys))
+            .await
+            .unwrap();
+
+        let room_id = room_id!("!test:example.org");
+        let to_device_requests = alice
+            .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+            .await
+            .unwrap();
+        let event = ToDeviceEvent::new(
+            alice.user_id().to_owned(),
+            to_device_requests_to_content(to_device_requests),
+        );
+        let event = json_convert(&event).unwrap();
+        let session_id = alice
+            .inner
+            .group_session_manager
+            .get_outbound_group_session(room_id)
+            .unwrap()
+            .session_id()
+            .to_owned();
+
+        let key_counts = BTreeMap::from([(DeviceKeyAlgorithm::SignedCurve25519, uint!(42))]);
+        bob.receive_sync_changes(vec![event], &Default::default(), &key_counts, None)
+            .await
+            .unwrap();
+
+        // The changes of the sync, including the account, are deferred but
+        // visible through the coalescing store.
+        assert!(backend.get_inbound_group_session(room_id, &session_id).await.unwrap().is_none());
+        assert!(store.get_inbound_group_session(room_id, &session_id).await.unwrap().is_some());
+        assert_eq!(store.load_account().await.unwrap().unwrap().uploaded_key_count(), 42);
+
+        // The account and the room key are written together.
+        store.flush().await.unwrap();
+
+        assert!(backend.get_inbound_group_session(room_id, &session_id).await.unwrap().is_some());
+    }
+
+    /// Decrypt the Olm payload that carries the room key shared by Alice with
+    /// Bob's account.
+    async fn room_key_payload(
+        alice: &OlmMachine,
+        bob: &OlmMachine,
+        requests: Vec<Arc<ToDeviceRequest>>,
+    ) -> serde_json::Value {
+        let ToDeviceEncryptedEventContent::OlmV1Curve25519AesSha2(content) =
+            to_device_requests_to_content(requests)
+        else {
+            panic!("The room key should be encrypted with Olm");
+        };
+        let vodozemac::olm::OlmMessage::PreKey(message) = content.ciphertext else {
+            panic!("The first message of the Olm session should be a pre-key message");
+        };
+
+        let result = bob
+            .account()
+            .create_inbound_session(alice.identity_keys().curve25519, &message)
+            .await
+            .unwrap();
+        serde_json::from_str(&result.plaintext).unwrap()
+    }
+
+    #[async_test]
+    async fn test_room_key_sharing_with_sender_authentication() {
+        let room_id = room_id!("!test:example.org");
+        let settings = EncryptionSettings {
+            sender_authentication: SenderAuthenticationPolicy {
+                mode: SenderAuthenticationMode::UnboundSenderKey,
+                downgrade: SenderAuthenticationDowngrade::ToStandard,
+            },
+            ..Default::default()
+        };
+
+        // Bob doesn't support the requested mode, the room key is sent in the
+        // standard mode.
+        let (alice, bob) = get_machine_pair_with_session(false).await;
+        let requests = alice
+            .share_room_key(room_id, iter::once(bob.user_id()), settings.clone())
+            .await
+            .unwrap();
+
+        let payload = room_key_payload(&alice, &bob, requests).await;
+        assert_eq!(payload["type"], "m.room_key");
+        assert_eq!(payload["keys"]["ed25519"], alice.identity_keys().ed25519.to_base64());
+
+        // Bob supports the requested mode, Alice's Ed25519 key is left out.
+        let (alice, bob) = get_machine_pair_with_session(false).await;
+        let mut bob_keys = ReadOnlyDevice::from_machine(&bob).await.as_device_keys().clone();
+        bob_keys.algorithms.push(EventEncryptionAlgorithm::from(
+            SenderAuthenticationMode::UnboundSenderKey.capability().unwrap(),
+        ));
+        alice
+            .store()
+            .save_devices(&[ReadOnlyDevice::new(bob_keys, LocalTrust::Unset)])
+            .await
+            .unwrap();
+
+        let requests =
+            alice.share_room_key(room_id, iter::once(bob.user_id()), settings).await.unwrap();
+
+        let payload = room_key_payload(&alice, &bob, requests).await;
+        assert_eq!(payload["type"], "m.room_key");
+        assert!(payload.get("keys").is_none());
+    }
+
+    #[async_test]
+    async fn test_room_key_bundle() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        // Create a room key without sharing it with Bob.
+        alice.share_room_key(room_id, iter::empty(), EncryptionSettings::default()).await.unwrap();
+        let alice_session =
+            alice.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+
+        let bundle = alice.build_room_key_bundle(room_id).await.unwrap();
+        assert_eq!(bundle.room_keys.len(), 1);
+        assert_eq!(bundle.room_keys[0].session_id, alice_session.session_id());
+
+        let file = serde_json::from_value(json!({
+            "url": "mxc://example.org/FHyPlCeYUSFFxlgbQYZmoEoe",
+            "key": {
+                "kty": "oct",
+                "key_ops": ["encrypt", "decrypt"],
+                "alg": "A256CTR",
+                "k": "aWF6-32KGYaC3A_FEUCk1Bt0JA37zP0wrStgmdCaW-0",
+                "ext": true
+            },
+            "iv": "w+sE15fzSc0AAAAAAAAAAA",
+            "hashes": {
+                "sha256": "fdSLu/YkRx3Wyh3KQabP3rd6+SFiKg5lsJZQHtkSAYA"
+            },
+            "v": "v2"
+        }))
+        .unwrap();
+        let content = RoomKeyBundleContent::new(room_id.to_owned(), file);
+
+        let requests = alice.share_room_key_bundle_data(bob.user_id(), &content).await.unwrap();
+        assert_eq!(requests.len(), 1);
+        let event = ToDeviceEvent::new(
+            alice.user_id().to_owned(),
+            to_device_requests_to_content(requests.into_iter().map(Arc::new).collect()),
+        );
+        let event = json_convert(&event).unwrap();
+
+        let decrypted = bob
+            .receive_sync_changes(vec![event], &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+        let event: RoomKeyBundleEvent = decrypted[0].deserialize_as().unwrap();
+        assert_eq!(&event.sender, alice.user_id());
+        assert_eq!(event.content.room_id, room_id);
+
+        // The bundle would be uploaded and downloaded as JSON.
+        let bundle = serde_json::from_slice(&serde_json::to_vec(&bundle).unwrap()).unwrap();
+        let result = bob.import_room_key_bundle(bundle, room_id).await.unwrap();
+        assert_eq!(result.imported_count, 1);
+
+        let session = bob
+            .store()
+            .get_inbound_group_session(room_id, alice_session.session_id())
+            .await
+            .unwrap();
+        assert!(session.is_some());
+    }
+
+    #[async_test]
+    async fn test_encrypt_to_device() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let mut stream = Box::pin(bob.store().decrypted_to_device_events_stream());
+
+        let result = alice
+            .encrypt_to_device(
+                bob.user_id(),
+                Some(&[bob.device_id().to_owned(), device_id!("UNKNOWN").to_owned()]),
+                "org.example.custom",
+                json!({ "foo": "bar" }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.failed_devices, vec![device_id!("UNKNOWN").to_owned()]);
+        assert_eq!(result.requests.len(), 1);
+
+        let event = ToDeviceEvent::new(
+            alice.user_id().to_owned(),
+            to_device_requests_to_content(result.requests.into_iter().map(Arc::new).collect()),
+        );
+        let event = json_convert(&event).unwrap();
+        bob.receive_sync_changes(vec![event], &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+
+        let event = stream.next().now_or_never().flatten().unwrap();
+        assert_eq!(event.sender, alice.user_id());
+        assert_eq!(event.sender_device_id.as_deref(), Some(alice.device_id()));
+        assert_eq!(event.event_type, "org.example.custom");
+        assert_eq!(
+            event.raw_event.get_field::<serde_json::Value>("content").unwrap(),
+            Some(json!({ "foo": "bar" }))
+        );
+    }
+
+    #[async_test]
+    async fn test_unencrypted_room_key_bundle_is_dropped() {
+        let (_, bob) = get_machine_pair_with_setup_sessions().await;
+
+        let event = json_convert(&json!({
+            "sender": alice_id(),
+            "type": "io.element.msc4268.room_key_bundle",
+            "content": {
+                "room_id": "!test:example.org",
+                "file": {
+                    "url": "mxc://example.org/FHyPlCeYUSFFxlgbQYZmoEoe",
+                    "key": {
+                        "kty": "oct",
+                        "key_ops": ["encrypt", "decrypt"],
+                        "alg": "A256CTR",
+                        "k": "aWF6-32KGYaC3A_FEUCk1Bt0JA37zP0wrStgmdCaW-0",
+                        "ext": true
+                    },
+                    "iv": "w+sE15fzSc0AAAAAAAAAAA",
+                    "hashes": {
+                        "sha256": "fdSLu/YkRx3Wyh3KQabP3rd6+SFiKg5lsJZQHtkSAYA"
+                    },
+                    "v": "v2"
+                },
+            },
+        }))
+        .unwrap();
+
+        let events = bob
+            .receive_sync_changes(vec![event], &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+        assert!(events.is_empty());
+    }
+
     #[async_test]
     async fn test_megolm_encryption() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
@@ -2387,6 +3420,67 @@ pub(crate) mod tests {
         }
     }
 
+    #[cfg(feature = "decryption-audit")]
+    #[async_test]
+    async fn test_decryption_audit_sink() {
+        use std::sync::Mutex;
+
+        use crate::DecryptionAuditRecord;
+
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        let to_device_requests = alice
+            .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+            .await
+            .unwrap();
+        let event = ToDeviceEvent::new(
+            alice.user_id().to_owned(),
+            to_device_requests_to_content(to_device_requests),
+        );
+        let group_session =
+            bob.decrypt_to_device_event(&event).await.unwrap().inbound_group_session.unwrap();
+        bob.store().save_inbound_group_sessions(&[group_session.clone()]).await.unwrap();
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink_records = records.clone();
+        bob.set_decryption_audit_sink(Some(Arc::new(move |record: DecryptionAuditRecord| {
+            sink_records.lock().unwrap().push(record)
+        })));
+
+        let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+        let encrypted_content = alice
+            .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content))
+            .await
+            .unwrap();
+        let event = json_convert(&json!({
+            "event_id": "$xxxxx:example.org",
+            "origin_server_ts": 1_000,
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+            "content": encrypted_content,
+        }))
+        .unwrap();
+
+        bob.decrypt_room_event(&event, room_id).await.unwrap();
+
+        {
+            let records = records.lock().unwrap();
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].room_id, room_id);
+            assert_eq!(records[0].event_id, "$xxxxx:example.org");
+            assert_eq!(records[0].sender, alice.user_id());
+            assert_eq!(records[0].sender_device_id.as_deref(), Some(alice.device_id()));
+            assert_eq!(records[0].session_id, group_session.session_id());
+            assert_eq!(records[0].origin_server_ts, MilliSecondsSinceUnixEpoch(uint!(1_000)));
+        }
+
+        // Nothing is recorded once the sink is removed.
+        bob.set_decryption_audit_sink(None);
+        bob.decrypt_room_event(&event, room_id).await.unwrap();
+        assert_eq!(records.lock().unwrap().len(), 1);
+    }
+
     #[async_test]
     async fn test_withheld_unverified() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
@@ -2449,6 +3543,119 @@ pub(crate) mod tests {
         assert_matches!(err, MegolmError::MissingRoomKey(Some(WithheldCode::Unverified)));
     }
 
+    /// A UTD telemetry hook collecting the causes of the UTDs.
+    #[derive(Default)]
+    struct UtdCauses(std::sync::Mutex<Vec<UtdCause>>);
+
+    impl UtdTelemetryHook for UtdCauses {
+        fn on_utd(&self, report: UtdReport) {
+            self.0.lock().unwrap().push(report.cause);
+        }
+    }
+
+    async fn encrypted_room_event(
+        alice: &OlmMachine,
+        room_id: &RoomId,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+    ) -> Raw<EncryptedEvent> {
+        let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+        let content = alice
+            .encrypt_room_event(room_id, AnyMessageLikeEventContent::RoomMessage(content))
+            .await
+            .unwrap();
+
+        json_convert(&json!({
+            "event_id": "$xxxxx:example.org",
+            "origin_server_ts": origin_server_ts,
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    #[async_test]
+    async fn test_utd_cause_withheld() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+        let causes = Arc::new(UtdCauses::default());
+        bob.set_utd_telemetry_hook(Some(causes.clone()));
+
+        let encryption_settings =
+            EncryptionSettings { only_allow_trusted_devices: true, ..Default::default() };
+        let to_device_requests = alice
+            .share_room_key(room_id, iter::once(bob.user_id()), encryption_settings)
+            .await
+            .unwrap();
+
+        let withheld_content = to_device_requests[0]
+            .messages
+            .values()
+            .next()
+            .unwrap()
+            .values()
+            .next()
+            .unwrap()
+            .deserialize_as::<RoomKeyWithheldContent>()
+            .unwrap();
+        let event = json_convert(&ToDeviceEvent::new(alice.user_id().to_owned(), withheld_content))
+            .unwrap();
+        bob.receive_sync_changes(vec![event], &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+
+        let event = encrypted_room_event(&alice, room_id, MilliSecondsSinceUnixEpoch::now()).await;
+        assert_matches!(
+            bob.decrypt_room_event(&event, room_id).await,
+            Err(MegolmError::MissingRoomKey(Some(WithheldCode::Unverified)))
+        );
+
+        assert_eq!(*causes.0.lock().unwrap(), [UtdCause::Withheld(WithheldCode::Unverified)]);
+        assert_eq!(bob.decryption_statistics().total.utd_withheld, 1);
+    }
+
+    #[async_test]
+    async fn test_utd_cause_historical() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+        let causes = Arc::new(UtdCauses::default());
+        bob.set_utd_telemetry_hook(Some(causes.clone()));
+
+        // The room key isn't shared with Bob, and the event was sent before his
+        // device was created.
+        alice.share_room_key(room_id, iter::empty(), EncryptionSettings::default()).await.unwrap();
+        let event =
+            encrypted_room_event(&alice, room_id, MilliSecondsSinceUnixEpoch(uint!(1000))).await;
+        assert_matches!(
+            bob.decrypt_room_event(&event, room_id).await,
+            Err(MegolmError::MissingRoomKey(None))
+        );
+
+        assert_eq!(*causes.0.lock().unwrap(), [UtdCause::Historical]);
+        assert_eq!(bob.decryption_statistics().total.utd_historical, 1);
+    }
+
+    #[async_test]
+    async fn test_utd_cause_missing_room_key() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+        let causes = Arc::new(UtdCauses::default());
+        bob.set_utd_telemetry_hook(Some(causes.clone()));
+
+        // The room key isn't shared with Bob, and the event was sent after his
+        // device was created.
+        alice.share_room_key(room_id, iter::empty(), EncryptionSettings::default()).await.unwrap();
+        let event = encrypted_room_event(&alice, room_id, MilliSecondsSinceUnixEpoch::now()).await;
+        assert_matches!(
+            bob.decrypt_room_event(&event, room_id).await,
+            Err(MegolmError::MissingRoomKey(None))
+        );
+
+        assert_eq!(*causes.0.lock().unwrap(), [UtdCause::MissingRoomKey]);
+        assert_eq!(bob.decryption_statistics().total.utd_missing_room_key, 1);
+        assert_eq!(bob.decryption_statistics().total.utd(), 1);
+    }
+
     #[async_test]
     async fn test_decryption_verification_state() {
         macro_rules! assert_shield {
@@ -3252,4 +4459,90 @@ pub(crate) mod tests {
             Err(MegolmError::MismatchedIdentityKeys { .. })
         );
     }
+
+    fn custom_to_device_event(body: &str) -> Raw<AnyToDeviceEvent> {
+        json_convert(&json!({
+            "sender": alice_id(),
+            "type": "org.example.test",
+            "content": { "body": body },
+        }))
+        .unwrap()
+    }
+
+    fn custom_to_device_bodies(events: &[Raw<AnyToDeviceEvent>]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| event.get_field::<serde_json::Value>("content").unwrap().unwrap())
+            .map(|content| content["body"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[async_test]
+    async fn injected_to_device_faults() {
+        let machine = OlmMachine::new(user_id(), bob_device_id()).await;
+        machine.inject_faults(
+            FaultScenario::new()
+                .rule(FaultRule::new(Fault::DropToDevice).times(1))
+                .rule(FaultRule::new(Fault::DuplicateToDevice).times(1))
+                .rule(FaultRule::new(Fault::DelayToDevice { syncs: 1 }).times(1)),
+        );
+
+        let events = ["dropped", "duplicated", "delayed", "received"].map(custom_to_device_event);
+        let received = machine
+            .receive_sync_changes(events.into(), &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(custom_to_device_bodies(&received), ["duplicated", "duplicated", "received"]);
+
+        // The delayed event is received with the events of the next sync.
+        let events = vec![custom_to_device_event("next")];
+        let received = machine
+            .receive_sync_changes(events, &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(custom_to_device_bodies(&received), ["next", "delayed"]);
+
+        assert_eq!(
+            machine.injected_faults(),
+            [Fault::DropToDevice, Fault::DuplicateToDevice, Fault::DelayToDevice { syncs: 1 }]
+        );
+    }
+
+    #[async_test]
+    async fn injected_keys_claim_failure() {
+        let (alice, bob, one_time_keys) = get_machine_pair(false).await;
+        alice.inject_faults(FaultScenario::new().rule(FaultRule::new(Fault::FailKeysClaim)));
+
+        let (device_key_id, one_time_key) = one_time_keys.into_iter().next().unwrap();
+        let keys = BTreeMap::from([(device_key_id, one_time_key)]);
+        let bob_keys = BTreeMap::from([(bob.device_id().to_owned(), keys)]);
+        let response =
+            claim_keys::v3::Response::new(BTreeMap::from([(bob.user_id().to_owned(), bob_keys)]));
+
+        alice.mark_request_as_sent(&TransactionId::new(), &response).await.unwrap();
+
+        let sessions =
+            alice.store().get_sessions(&bob.identity_keys().curve25519.to_base64()).await.unwrap();
+        assert!(sessions.is_none());
+        assert_eq!(alice.injected_faults(), [Fault::FailKeysClaim]);
+    }
+
+    #[async_test]
+    async fn injected_store_write_failure() {
+        let machine = OlmMachine::new(user_id(), bob_device_id()).await;
+        machine.inject_faults(
+            FaultScenario::new().rule(FaultRule::new(Fault::FailStoreWrite).times(1)),
+        );
+
+        let result = machine
+            .receive_sync_changes(vec![], &Default::default(), &Default::default(), None)
+            .await;
+        assert_matches!(result, Err(OlmError::Store(_)));
+
+        // The fault is only injected once.
+        machine
+            .receive_sync_changes(vec![], &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+    }
 }
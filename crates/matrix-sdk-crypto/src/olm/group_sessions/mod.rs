@@ -17,11 +17,17 @@ use serde::{Deserialize, Serialize};
 
 mod inbound;
 mod outbound;
+mod sender_authentication;
 
 pub use inbound::{InboundGroupSession, PickledInboundGroupSession};
 pub(crate) use outbound::ShareState;
 pub use outbound::{
     EncryptionSettings, GroupSession, OutboundGroupSession, PickledOutboundGroupSession, ShareInfo,
+    TrustRequirement,
+};
+pub use sender_authentication::{
+    NegotiatedSenderAuthentication, SenderAuthenticationDowngrade, SenderAuthenticationMode,
+    SenderAuthenticationPolicy,
 };
 use thiserror::Error;
 pub use vodozemac::megolm::{ExportedSessionKey, SessionKey};
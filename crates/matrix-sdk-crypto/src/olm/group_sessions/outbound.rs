@@ -41,7 +41,7 @@ pub use vodozemac::{
     PickleError,
 };
 
-use super::SessionCreationError;
+use super::{SenderAuthenticationPolicy, SessionCreationError};
 #[cfg(feature = "experimental-algorithms")]
 use crate::types::events::room::encrypted::MegolmV2AesSha2Content;
 use crate::{
@@ -55,7 +55,7 @@ use crate::{
         },
         EventEncryptionAlgorithm,
     },
-    Device, ToDeviceRequest,
+    Device, LocalTrust, ToDeviceRequest,
 };
 
 const ROTATION_PERIOD: Duration = Duration::from_millis(604800000);
@@ -68,6 +68,55 @@ pub(crate) enum ShareState {
     Shared(u32),
 }
 
+/// The trust that is required from the devices of the recipients of a room
+/// key.
+///
+/// Contrary to [`EncryptionSettings::only_allow_trusted_devices`], which
+/// silently excludes the untrusted devices from the conversation, sharing the
+/// room key fails with [`OlmError::UntrustedDevices`] if some devices don't
+/// satisfy the requirement, so the user can decide what to do with them, e.g.
+/// verify them, blacklist them or ignore their trust state with
+/// [`Device::set_local_trust()`].
+///
+/// The devices whose local trust state is [`LocalTrust::Ignored`] always
+/// satisfy the requirement.
+///
+/// The variants are ordered from the least to the most strict.
+///
+/// [`OlmError::UntrustedDevices`]: crate::OlmError::UntrustedDevices
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustRequirement {
+    /// Share the room key with every device that isn't blacklisted.
+    #[default]
+    Untrusted,
+
+    /// Only share the room key with devices that are cross-signed by their
+    /// owner, or that we verified.
+    CrossSigned,
+
+    /// Only share the room key with devices that we verified, either
+    /// manually or with cross-signing.
+    Verified,
+}
+
+impl TrustRequirement {
+    /// Check whether the given device satisfies this requirement.
+    pub fn is_satisfied_by(&self, device: &Device) -> bool {
+        if device.local_trust_state() == LocalTrust::Ignored {
+            return true;
+        }
+
+        match self {
+            Self::Untrusted => true,
+            Self::CrossSigned => device.is_cross_signed_by_owner() || device.is_verified(),
+            Self::Verified => device.is_verified(),
+        }
+    }
+}
+
 /// Settings for an encrypted room.
 ///
 /// This determines the algorithm and rotation periods of a group session.
@@ -85,6 +134,12 @@ pub struct EncryptionSettings {
     /// excluded from the conversation.
     #[serde(default)]
     pub only_allow_trusted_devices: bool,
+    /// How the sender of the messages is authenticated to the recipients.
+    #[serde(default)]
+    pub sender_authentication: SenderAuthenticationPolicy,
+    /// The trust that is required from the devices of the recipients.
+    #[serde(default)]
+    pub trust_requirement: TrustRequirement,
 }
 
 impl Default for EncryptionSettings {
@@ -95,6 +150,8 @@ impl Default for EncryptionSettings {
             rotation_period_msgs: ROTATION_MESSAGES,
             history_visibility: HistoryVisibility::Shared,
             only_allow_trusted_devices: false,
+            sender_authentication: Default::default(),
+            trust_requirement: Default::default(),
         }
     }
 }
@@ -119,6 +176,8 @@ impl EncryptionSettings {
             rotation_period_msgs,
             history_visibility,
             only_allow_trusted_devices,
+            sender_authentication: Default::default(),
+            trust_requirement: Default::default(),
         }
     }
 }
@@ -0,0 +1,208 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration of how the sender of an encrypted room event is
+//! authenticated to its recipients.
+//!
+//! The standard Megolm setup binds every room key to the Ed25519 key of the
+//! device that created it, which gives recipients a cryptographic proof of who
+//! sent a message, and prevents the sender from later denying it. The
+//! experimental modes defined here trade some of that proof for deniability.
+//!
+//! This is only scaffolding for experiments: a non-standard mode is only used
+//! if every recipient device advertises support for it, and otherwise either
+//! falls back to [`SenderAuthenticationMode::Standard`] or refuses to share the
+//! room key with the devices that don't support it, depending on the
+//! [`SenderAuthenticationPolicy`].
+
+use ruma::{OwnedDeviceId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+
+use crate::{identities::ReadOnlyDevice, types::EventEncryptionAlgorithm};
+
+/// How the sender of an encrypted room event is authenticated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum SenderAuthenticationMode {
+    /// Room keys are bound to the Ed25519 key of the sending device, as
+    /// described in the Matrix specification.
+    #[default]
+    Standard,
+
+    /// Experimental: the Ed25519 key of the sending device is omitted from the
+    /// Olm payload that carries the room key, so the room key can't be proven
+    /// to originate from that device.
+    UnboundSenderKey,
+
+    /// Experimental: every Megolm session is authenticated with a dedicated
+    /// key, that is only vouched for by the sending device over the pairwise
+    /// Olm channel.
+    PerSessionSenderKey,
+}
+
+impl SenderAuthenticationMode {
+    /// The identifier a device needs to advertise in its list of supported
+    /// algorithms to be able to receive room keys in this mode.
+    ///
+    /// Returns `None` for [`SenderAuthenticationMode::Standard`], which is
+    /// supported by every device.
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            Self::Standard => None,
+            Self::UnboundSenderKey => Some("org.matrix.experimental.sender_auth.unbound_key"),
+            Self::PerSessionSenderKey => Some("org.matrix.experimental.sender_auth.session_key"),
+        }
+    }
+
+    /// Does the given device advertise support for this mode.
+    pub fn is_supported_by(&self, device: &ReadOnlyDevice) -> bool {
+        match self.capability() {
+            None => true,
+            Some(capability) => {
+                device.algorithms().contains(&EventEncryptionAlgorithm::from(capability))
+            }
+        }
+    }
+}
+
+/// What to do when some recipient devices don't support the requested
+/// [`SenderAuthenticationMode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SenderAuthenticationDowngrade {
+    /// Use [`SenderAuthenticationMode::Standard`] for the whole session, so
+    /// every device can read the messages.
+    #[default]
+    ToStandard,
+
+    /// Keep the requested mode, and don't share the room key with the devices
+    /// that don't support it.
+    Refuse,
+}
+
+/// The sender authentication settings of a room.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SenderAuthenticationPolicy {
+    /// The requested sender authentication mode.
+    #[serde(default)]
+    pub mode: SenderAuthenticationMode,
+
+    /// What to do if some recipient devices don't support `mode`.
+    #[serde(default)]
+    pub downgrade: SenderAuthenticationDowngrade,
+}
+
+/// The result of negotiating the [`SenderAuthenticationMode`] of a group
+/// session with its recipients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NegotiatedSenderAuthentication {
+    /// The mode that the session should use.
+    pub mode: SenderAuthenticationMode,
+
+    /// The devices that don't support the requested mode and must not receive
+    /// the room key, as per [`SenderAuthenticationDowngrade::Refuse`].
+    pub refused_devices: Vec<(OwnedUserId, OwnedDeviceId)>,
+}
+
+impl SenderAuthenticationPolicy {
+    /// Compute the mode to use for a group session shared with the given
+    /// devices.
+    ///
+    /// The requested mode is only used if every device supports it. Otherwise
+    /// the [`downgrade`](Self::downgrade) rule applies: either the whole
+    /// session falls back to [`SenderAuthenticationMode::Standard`], or the
+    /// devices that don't support the requested mode are refused.
+    pub fn negotiate<'a>(
+        &self,
+        devices: impl IntoIterator<Item = &'a ReadOnlyDevice>,
+    ) -> NegotiatedSenderAuthentication {
+        let unsupported: Vec<_> = devices
+            .into_iter()
+            .filter(|d| !self.mode.is_supported_by(d))
+            .map(|d| (d.user_id().to_owned(), d.device_id().to_owned()))
+            .collect();
+
+        if unsupported.is_empty() {
+            return NegotiatedSenderAuthentication { mode: self.mode, refused_devices: Vec::new() };
+        }
+
+        match self.downgrade {
+            SenderAuthenticationDowngrade::ToStandard => NegotiatedSenderAuthentication {
+                mode: SenderAuthenticationMode::Standard,
+                refused_devices: Vec::new(),
+            },
+            SenderAuthenticationDowngrade::Refuse => {
+                NegotiatedSenderAuthentication { mode: self.mode, refused_devices: unsupported }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{device_id, user_id};
+
+    use super::{
+        SenderAuthenticationDowngrade, SenderAuthenticationMode, SenderAuthenticationPolicy,
+    };
+    use crate::{
+        identities::{LocalTrust, ReadOnlyDevice},
+        olm::ReadOnlyAccount,
+        types::EventEncryptionAlgorithm,
+    };
+
+    fn device(device_id: &str, capability: Option<&str>) -> ReadOnlyDevice {
+        let account = ReadOnlyAccount::new(user_id!("@alice:localhost"), device_id.into());
+        let mut device_keys = account.unsigned_device_keys();
+        if let Some(capability) = capability {
+            device_keys.algorithms.push(EventEncryptionAlgorithm::from(capability));
+        }
+        ReadOnlyDevice::new(device_keys, LocalTrust::Unset)
+    }
+
+    #[test]
+    fn standard_mode_is_always_supported() {
+        let policy = SenderAuthenticationPolicy::default();
+        let negotiated = policy.negotiate([&device("A", None)]);
+
+        assert_eq!(negotiated.mode, SenderAuthenticationMode::Standard);
+        assert!(negotiated.refused_devices.is_empty());
+    }
+
+    #[test]
+    fn downgrade_rules() {
+        let mode = SenderAuthenticationMode::PerSessionSenderKey;
+        let supported = device("A", mode.capability());
+        let unsupported = device("B", None);
+
+        let policy = SenderAuthenticationPolicy {
+            mode,
+            downgrade: SenderAuthenticationDowngrade::ToStandard,
+        };
+        assert_eq!(policy.negotiate([&supported]).mode, mode);
+        let negotiated = policy.negotiate([&supported, &unsupported]);
+        assert_eq!(negotiated.mode, SenderAuthenticationMode::Standard);
+        assert!(negotiated.refused_devices.is_empty());
+
+        let policy =
+            SenderAuthenticationPolicy { mode, downgrade: SenderAuthenticationDowngrade::Refuse };
+        let negotiated = policy.negotiate([&supported, &unsupported]);
+        assert_eq!(negotiated.mode, mode);
+        assert_eq!(
+            negotiated.refused_devices,
+            [(user_id!("@alice:localhost").to_owned(), device_id!("B").to_owned())]
+        );
+    }
+}
@@ -28,8 +28,10 @@ pub use account::{OlmMessageHash, PickledAccount, ReadOnlyAccount};
 pub(crate) use group_sessions::ShareState;
 pub use group_sessions::{
     BackedUpRoomKey, EncryptionSettings, ExportedRoomKey, InboundGroupSession,
-    OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession,
-    SessionCreationError, SessionExportError, SessionKey, ShareInfo,
+    NegotiatedSenderAuthentication, OutboundGroupSession, PickledInboundGroupSession,
+    PickledOutboundGroupSession, SenderAuthenticationDowngrade, SenderAuthenticationMode,
+    SenderAuthenticationPolicy, SessionCreationError, SessionExportError, SessionKey, ShareInfo,
+    TrustRequirement,
 };
 pub use session::{PickledSession, Session};
 pub use signing::{CrossSigningStatus, PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
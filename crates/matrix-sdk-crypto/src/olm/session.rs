@@ -24,7 +24,7 @@ use vodozemac::{
     Curve25519PublicKey,
 };
 
-use super::IdentityKeys;
+use super::{IdentityKeys, SenderAuthenticationMode};
 #[cfg(feature = "experimental-algorithms")]
 use crate::types::events::room::encrypted::OlmV2Curve25519AesSha2Content;
 use crate::{
@@ -152,17 +152,39 @@ impl Session {
         event_type: &str,
         content: Value,
         message_id: Option<String>,
+    ) -> OlmResult<Raw<ToDeviceEncryptedEventContent>> {
+        self.encrypt_with_sender_authentication(
+            recipient_device,
+            event_type,
+            content,
+            message_id,
+            SenderAuthenticationMode::Standard,
+        )
+        .await
+    }
+
+    /// Encrypt the given event content as an m.room.encrypted event
+    /// content, authenticating us to the recipient with the given
+    /// [`SenderAuthenticationMode`].
+    ///
+    /// Only the standard mode binds the payload to our Ed25519 key, the
+    /// experimental modes leave it out so the recipient can't prove that the
+    /// content was sent by our device.
+    pub(crate) async fn encrypt_with_sender_authentication(
+        &mut self,
+        recipient_device: &ReadOnlyDevice,
+        event_type: &str,
+        content: Value,
+        message_id: Option<String>,
+        sender_authentication: SenderAuthenticationMode,
     ) -> OlmResult<Raw<ToDeviceEncryptedEventContent>> {
         let plaintext = {
             let recipient_signing_key =
                 recipient_device.ed25519_key().ok_or(EventError::MissingSigningKey)?;
 
-            let payload = json!({
+            let mut payload = json!({
                 "sender": &self.user_id,
                 "sender_device": &self.device_id,
-                "keys": {
-                    "ed25519": self.our_identity_keys.ed25519.to_base64(),
-                },
                 "recipient": recipient_device.user_id(),
                 "recipient_keys": {
                     "ed25519": recipient_signing_key.to_base64(),
@@ -171,6 +193,12 @@ impl Session {
                 "content": content,
             });
 
+            if sender_authentication == SenderAuthenticationMode::Standard {
+                payload["keys"] = json!({
+                    "ed25519": self.our_identity_keys.ed25519.to_base64(),
+                });
+            }
+
             serde_json::to_string(&payload)?
         };
 
@@ -33,12 +33,36 @@ use tracing::{debug, error, info, instrument, trace};
 use crate::{
     error::{EventError, MegolmResult, OlmResult},
     identities::device::MaybeEncryptedRoomKey,
-    olm::{Account, InboundGroupSession, OutboundGroupSession, Session, ShareInfo, ShareState},
+    olm::{
+        Account, InboundGroupSession, OutboundGroupSession, SenderAuthenticationDowngrade,
+        SenderAuthenticationMode, Session, ShareInfo, ShareState,
+    },
     store::{Changes, Result as StoreResult, Store},
     types::events::{room::encrypted::RoomEncryptedEventContent, room_key_withheld::WithheldCode},
     Device, EncryptionSettings, OlmError, ToDeviceRequest,
 };
 
+/// Get the reason why the room key shouldn't be shared with the given device,
+/// according to the encryption settings, if any.
+pub(crate) fn withheld_code(
+    device: &Device,
+    settings: &EncryptionSettings,
+) -> Option<WithheldCode> {
+    let sender_authentication = &settings.sender_authentication;
+
+    if device.is_blacklisted() {
+        Some(WithheldCode::Blacklisted)
+    } else if settings.only_allow_trusted_devices && !device.is_verified() {
+        Some(WithheldCode::Unverified)
+    } else if sender_authentication.downgrade == SenderAuthenticationDowngrade::Refuse
+        && !sender_authentication.mode.is_supported_by(device)
+    {
+        Some(WithheldCode::Unauthorised)
+    } else {
+        None
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct GroupSessionCache {
     store: Store,
@@ -127,6 +151,8 @@ pub struct CollectRecipientsResult {
     /// The map of user|device that won't receive the key with the withheld
     /// code.
     pub withheld_devices: Vec<(Device, WithheldCode)>,
+    /// The sender authentication mode negotiated with the recipient devices.
+    pub sender_authentication: SenderAuthenticationMode,
 }
 
 #[derive(Debug, Clone)]
@@ -141,7 +167,7 @@ pub struct GroupSessionManager {
 }
 
 impl GroupSessionManager {
-    const MAX_TO_DEVICE_MESSAGES: usize = 250;
+    pub(crate) const MAX_TO_DEVICE_MESSAGES: usize = 250;
 
     pub(crate) fn new(account: Account, store: Store) -> Self {
         Self { account, store: store.clone(), sessions: GroupSessionCache::new(store) }
@@ -261,6 +287,7 @@ impl GroupSessionManager {
     async fn encrypt_session_for(
         group_session: OutboundGroupSession,
         devices: Vec<Device>,
+        sender_authentication: SenderAuthenticationMode,
     ) -> OlmResult<(
         OwnedTransactionId,
         ToDeviceRequest,
@@ -280,7 +307,8 @@ impl GroupSessionManager {
         let mut withheld_devices = Vec::new();
 
         let encrypt = |device: Device, session: OutboundGroupSession| async move {
-            let encryption_result = device.maybe_encrypt_room_key(session).await?;
+            let encryption_result =
+                device.maybe_encrypt_room_key(session, sender_authentication).await?;
 
             Ok::<_, OlmError>(DeviceResult { device, maybe_encrypted_room_key: encryption_result })
         };
@@ -341,6 +369,8 @@ impl GroupSessionManager {
         let users: BTreeSet<&UserId> = users.collect();
         let mut devices: BTreeMap<OwnedUserId, Vec<Device>> = Default::default();
         let mut withheld_devices: Vec<(Device, WithheldCode)> = Default::default();
+        let mut untrusted_devices: BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>> =
+            Default::default();
 
         trace!(
             ?users,
@@ -363,6 +393,9 @@ impl GroupSessionManager {
         let visibility_changed =
             outbound.settings().history_visibility != settings.history_visibility;
         let algorithm_changed = outbound.settings().algorithm != settings.algorithm;
+        let sender_authentication_changed =
+            outbound.settings().sender_authentication != settings.sender_authentication;
+        let sender_authentication = &settings.sender_authentication;
 
         // To protect the room history we need to rotate the session if either:
         //
@@ -370,9 +403,11 @@ impl GroupSessionManager {
         // 2. Any of the users' devices got deleted or blacklisted.
         // 3. The history visibility changed.
         // 4. The encryption algorithm changed.
+        // 5. The sender authentication policy changed.
         //
         // This is calculated in the following code and stored in this variable.
-        let mut should_rotate = user_left || visibility_changed || algorithm_changed;
+        let mut should_rotate =
+            user_left || visibility_changed || algorithm_changed || sender_authentication_changed;
 
         for user_id in users {
             let user_devices = self.store.get_user_devices_filtered(user_id).await?;
@@ -382,14 +417,9 @@ impl GroupSessionManager {
             // room key and a bucket of devices that should receive
             // a withheld code.
             let (recipients, withheld_recipients): (Vec<Device>, Vec<(Device, WithheldCode)>) =
-                user_devices.devices().partition_map(|d| {
-                    if d.is_blacklisted() {
-                        Either::Right((d, WithheldCode::Blacklisted))
-                    } else if settings.only_allow_trusted_devices && !d.is_verified() {
-                        Either::Right((d, WithheldCode::Unverified))
-                    } else {
-                        Either::Left(d)
-                    }
+                user_devices.devices().partition_map(|d| match withheld_code(&d, settings) {
+                    Some(code) => Either::Right((d, code)),
+                    None => Either::Left(d),
                 });
 
             // If we haven't already concluded that the session should be
@@ -421,27 +451,61 @@ impl GroupSessionManager {
                 };
             }
 
+            let untrusted: BTreeSet<OwnedDeviceId> = recipients
+                .iter()
+                .filter(|d| !settings.trust_requirement.is_satisfied_by(d))
+                .map(|d| d.device_id().to_owned())
+                .collect();
+            if !untrusted.is_empty() {
+                untrusted_devices.insert(user_id.to_owned(), untrusted);
+            }
+
             devices.entry(user_id.to_owned()).or_default().extend(recipients);
             withheld_devices.extend(withheld_recipients);
         }
 
+        // The user needs to decide what to do with the devices that don't
+        // satisfy the trust requirement before the room key can be shared.
+        if !untrusted_devices.is_empty() {
+            debug!(
+                ?untrusted_devices,
+                trust_requirement = ?settings.trust_requirement,
+                room_id = outbound.room_id().as_str(),
+                "Some devices don't satisfy the trust requirement, not sharing the room key"
+            );
+            return Err(OlmError::UntrustedDevices(untrusted_devices));
+        }
+
+        // Devices that don't support the requested mode were already withheld
+        // if the policy refuses to downgrade, so this only decides if the
+        // session needs to fall back to the standard mode.
+        let sender_authentication =
+            sender_authentication.negotiate(devices.values().flatten().map(|d| &d.inner)).mode;
+
         trace!(
             should_rotate = should_rotate,
+            ?sender_authentication,
             session_id = outbound.session_id(),
             room_id = outbound.room_id().as_str(),
             "Done calculating group session recipients"
         );
 
-        Ok(CollectRecipientsResult { should_rotate, devices, withheld_devices })
+        Ok(CollectRecipientsResult {
+            should_rotate,
+            devices,
+            withheld_devices,
+            sender_authentication,
+        })
     }
 
     pub async fn encrypt_request(
         chunk: Vec<Device>,
         outbound: OutboundGroupSession,
         being_shared: Arc<DashMap<OwnedTransactionId, OutboundGroupSession>>,
+        sender_authentication: SenderAuthenticationMode,
     ) -> OlmResult<(Vec<Session>, Vec<(Device, WithheldCode)>)> {
         let (id, request, share_infos, used_sessions, no_olm) =
-            Self::encrypt_session_for(outbound.clone(), chunk).await?;
+            Self::encrypt_session_for(outbound.clone(), chunk, sender_authentication).await?;
 
         if !request.messages.is_empty() {
             trace!(
@@ -495,6 +559,7 @@ impl GroupSessionManager {
         &self,
         recipient_devices: Vec<Device>,
         group_session: &OutboundGroupSession,
+        sender_authentication: SenderAuthenticationMode,
         changes: &mut Changes,
     ) -> OlmResult<Vec<(Device, WithheldCode)>> {
         // If we have some recipients, log them here.
@@ -530,6 +595,7 @@ impl GroupSessionManager {
                     chunk.to_vec(),
                     group_session.clone(),
                     self.sessions.sessions_being_shared.clone(),
+                    sender_authentication,
                 ))
             })
             .collect();
@@ -769,8 +835,21 @@ impl GroupSessionManager {
         // Collect the recipient devices and check if either the settings
         // or the recipient list changed in a way that requires the
         // session to be rotated.
-        let CollectRecipientsResult { should_rotate, devices, mut withheld_devices } =
-            self.collect_session_recipients(users, &encryption_settings, &outbound).await?;
+        let CollectRecipientsResult {
+            should_rotate,
+            devices,
+            mut withheld_devices,
+            sender_authentication,
+        } = self.collect_session_recipients(users, &encryption_settings, &outbound).await?;
+
+        if sender_authentication != encryption_settings.sender_authentication.mode {
+            info!(
+                requested = ?encryption_settings.sender_authentication.mode,
+                negotiated = ?sender_authentication,
+                "Not all recipient devices support the requested sender authentication mode, \
+                 falling back"
+            );
+        }
 
         let outbound = self
             .maybe_rotate_group_session(
@@ -797,8 +876,9 @@ impl GroupSessionManager {
         // for the m.room_key_withheld events since we might have more of those
         // coming from the `collect_session_recipients()` method. Instead they get
         // returned by the method.
-        let unable_to_encrypt_devices =
-            self.encrypt_for_devices(devices, &outbound, &mut changes).await?;
+        let unable_to_encrypt_devices = self
+            .encrypt_for_devices(devices, &outbound, sender_authentication, &mut changes)
+            .await?;
 
         // Merge the withheld recipients.
         withheld_devices.extend(unable_to_encrypt_devices);
@@ -849,6 +929,7 @@ impl GroupSessionManager {
 mod tests {
     use std::{collections::BTreeSet, ops::Deref, sync::Arc};
 
+    use assert_matches::assert_matches;
     use matrix_sdk_test::{async_test, response_from_file};
     use ruma::{
         api::{
@@ -874,7 +955,9 @@ mod tests {
             },
             EventEncryptionAlgorithm,
         },
-        EncryptionSettings, LocalTrust, OlmMachine, ToDeviceRequest,
+        EncryptionSettings, LocalTrust, OlmError, OlmMachine, PreflightWarning,
+        SenderAuthenticationDowngrade, SenderAuthenticationMode, SenderAuthenticationPolicy,
+        ToDeviceRequest, TrustRequirement,
     };
 
     fn alice_id() -> &'static UserId {
@@ -1180,6 +1263,86 @@ mod tests {
         assert!(should_rotate);
     }
 
+    #[async_test]
+    async fn changing_sender_authentication_rotates_session() {
+        let machine = machine_with_shared_room_key().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let outbound =
+            machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+
+        let settings = EncryptionSettings {
+            sender_authentication: SenderAuthenticationPolicy {
+                mode: SenderAuthenticationMode::UnboundSenderKey,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let CollectRecipientsResult { should_rotate, sender_authentication, .. } = machine
+            .inner
+            .group_session_manager
+            .collect_session_recipients(users, &settings, &outbound)
+            .await
+            .unwrap();
+
+        // The policy changed, even if the negotiated mode is still the standard
+        // one.
+        assert!(should_rotate);
+        assert_eq!(sender_authentication, SenderAuthenticationMode::Standard);
+    }
+
+    #[async_test]
+    async fn sender_authentication_negotiation() {
+        let machine = machine_with_shared_room_key().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let outbound =
+            machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+
+        // None of the devices advertise support for the experimental mode, so
+        // the session falls back to the standard mode by default.
+        let mut settings = EncryptionSettings {
+            sender_authentication: SenderAuthenticationPolicy {
+                mode: SenderAuthenticationMode::PerSessionSenderKey,
+                downgrade: SenderAuthenticationDowngrade::ToStandard,
+            },
+            ..Default::default()
+        };
+
+        let CollectRecipientsResult { devices, withheld_devices, sender_authentication, .. } =
+            machine
+                .inner
+                .group_session_manager
+                .collect_session_recipients(users.clone(), &settings, &outbound)
+                .await
+                .unwrap();
+
+        assert_eq!(sender_authentication, SenderAuthenticationMode::Standard);
+        assert!(devices.values().any(|d| !d.is_empty()));
+        assert!(withheld_devices.is_empty());
+
+        // Or refuses to share the room key with them.
+        settings.sender_authentication.downgrade = SenderAuthenticationDowngrade::Refuse;
+
+        let CollectRecipientsResult { devices, withheld_devices, sender_authentication, .. } =
+            machine
+                .inner
+                .group_session_manager
+                .collect_session_recipients(users, &settings, &outbound)
+                .await
+                .unwrap();
+
+        assert_eq!(sender_authentication, SenderAuthenticationMode::PerSessionSenderKey);
+        assert!(devices.values().all(|d| d.is_empty()));
+        assert!(!withheld_devices.is_empty());
+        assert!(withheld_devices.iter().all(|(_, code)| *code == WithheldCode::Unauthorised));
+    }
+
     #[async_test]
     async fn key_recipient_collecting() {
         // The user id comes from the fact that the keys_query.json file uses
@@ -1266,6 +1429,65 @@ mod tests {
         assert_eq!(149, withheld.len());
     }
 
+    #[async_test]
+    async fn trust_requirement() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let settings = EncryptionSettings {
+            trust_requirement: TrustRequirement::Verified,
+            ..Default::default()
+        };
+
+        let user_id = user_id!("@example:localhost");
+        let verified_device_id = device_id!("MWFXPINOAO");
+        let blacklisted_device_id = device_id!("MWVTUXDNNM");
+        machine
+            .get_device(user_id, verified_device_id, None)
+            .await
+            .unwrap()
+            .unwrap()
+            .set_local_trust(LocalTrust::Verified)
+            .await
+            .unwrap();
+        machine
+            .get_device(user_id, blacklisted_device_id, None)
+            .await
+            .unwrap()
+            .unwrap()
+            .set_local_trust(LocalTrust::BlackListed)
+            .await
+            .unwrap();
+
+        // The verified and blacklisted devices are not reported.
+        let untrusted_devices = assert_matches!(
+            machine.share_room_key(room_id, users.clone(), settings.clone()).await,
+            Err(OlmError::UntrustedDevices(devices)) => devices
+        );
+        assert_eq!(untrusted_devices.len(), 1);
+        let device_ids = &untrusted_devices[user_id];
+        assert_eq!(device_ids.len(), 148);
+        assert!(!device_ids.contains(verified_device_id));
+        assert!(!device_ids.contains(blacklisted_device_id));
+
+        // The room key can be shared once the untrusted devices are ignored.
+        for device_id in device_ids {
+            machine
+                .get_device(user_id, device_id, None)
+                .await
+                .unwrap()
+                .unwrap()
+                .set_local_trust(LocalTrust::Ignored)
+                .await
+                .unwrap();
+        }
+
+        let requests = machine.share_room_key(room_id, users, settings).await.unwrap();
+        assert!(requests.iter().any(|r| r.event_type == "m.room.encrypted".into()));
+    }
+
     #[async_test]
     async fn test_sharing_withheld_only_trusted() {
         let machine = machine().await;
@@ -1385,4 +1607,66 @@ mod tests {
 
         assert!(device.was_withheld_code_sent());
     }
+
+    #[async_test]
+    async fn encryption_preflight() {
+        let machine = machine().await;
+        let keys_claim = keys_claim_response();
+        let users: Vec<_> = keys_claim.one_time_keys.keys().map(Deref::deref).collect();
+
+        let report = machine
+            .encryption_preflight(users.iter().copied(), EncryptionSettings::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(report.users.len(), users.len());
+        assert!(report.recipient_devices > 0);
+        assert_eq!(report.withheld_devices, 0);
+        assert_eq!(report.to_device_requests, 1);
+
+        let settings =
+            EncryptionSettings { only_allow_trusted_devices: true, ..Default::default() };
+        let report =
+            machine.encryption_preflight(users.iter().copied(), settings, None).await.unwrap();
+        assert_eq!(report.recipient_devices, 0);
+        assert!(report.withheld_devices > 0);
+        assert!(report
+            .warnings()
+            .any(|(_, warning)| *warning == PreflightWarning::AllDevicesWithheld));
+
+        let settings = EncryptionSettings {
+            trust_requirement: TrustRequirement::Verified,
+            ..Default::default()
+        };
+        let report =
+            machine.encryption_preflight(users.iter().copied(), settings, None).await.unwrap();
+        assert!(report.recipient_devices > 0);
+        assert!(report
+            .warnings()
+            .any(|(_, warning)| matches!(warning, PreflightWarning::UntrustedDevices { .. })));
+    }
+
+    #[async_test]
+    async fn encryption_preflight_unknown_user() {
+        let machine = machine().await;
+        let carol = user_id!("@carol:localhost");
+
+        let report = machine
+            .encryption_preflight([carol].into_iter(), EncryptionSettings::default(), None)
+            .await
+            .unwrap();
+
+        let carol_report = &report.users[carol];
+        assert_eq!(carol_report.devices, 0);
+        assert!(!carol_report.has_identity);
+        assert_eq!(
+            carol_report.warnings,
+            [
+                PreflightWarning::DeviceListUnknown,
+                PreflightWarning::NoEncryptionCapableDevices,
+                PreflightWarning::Unverifiable,
+            ]
+        );
+        assert_eq!(report.recipient_devices, 0);
+        assert_eq!(report.to_device_requests, 0);
+    }
 }
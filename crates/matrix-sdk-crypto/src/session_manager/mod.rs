@@ -13,7 +13,12 @@
 // limitations under the License.
 
 mod group_sessions;
+mod preflight;
 mod sessions;
 
 pub(crate) use group_sessions::{GroupSessionCache, GroupSessionManager};
+pub(crate) use preflight::encryption_preflight;
+pub use preflight::{
+    EncryptionPreflightReport, PreflightWarning, UserEncryptionPreflight, MANY_DEVICES_THRESHOLD,
+};
 pub(crate) use sessions::SessionManager;
@@ -0,0 +1,198 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ruma::{OwnedDeviceId, OwnedUserId, UserId};
+
+use super::group_sessions::{withheld_code, GroupSessionManager};
+use crate::{error::OlmResult, store::Store, EncryptionSettings};
+
+/// The number of devices above which a user is reported with
+/// [`PreflightWarning::ManyDevices`].
+pub const MANY_DEVICES_THRESHOLD: usize = 50;
+
+/// A report on what would happen if a room key was shared with a list of
+/// users, returned by [`OlmMachine::encryption_preflight()`].
+///
+/// This allows clients to warn about problems, e.g. that a user has no device
+/// that can receive encrypted messages, before creating an encrypted room or
+/// enabling encryption in a room.
+///
+/// [`OlmMachine::encryption_preflight()`]: crate::OlmMachine::encryption_preflight
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct EncryptionPreflightReport {
+    /// The report for each user.
+    pub users: BTreeMap<OwnedUserId, UserEncryptionPreflight>,
+    /// The number of devices that would receive the room key.
+    pub recipient_devices: usize,
+    /// The number of devices that would be told that the room key is withheld
+    /// from them, because of the encryption settings.
+    pub withheld_devices: usize,
+    /// The number of recipient devices without an Olm session, for which a
+    /// one-time key would need to be claimed first.
+    pub missing_sessions: usize,
+    /// The number of to-device requests needed to share the room key.
+    pub to_device_requests: usize,
+}
+
+impl EncryptionPreflightReport {
+    /// Whether there is any warning for any user.
+    pub fn has_warnings(&self) -> bool {
+        self.users.values().any(|user| !user.warnings.is_empty())
+    }
+
+    /// The warnings for all the users.
+    pub fn warnings(&self) -> impl Iterator<Item = (&UserId, &PreflightWarning)> {
+        self.users
+            .iter()
+            .flat_map(|(user_id, user)| user.warnings.iter().map(move |w| (&**user_id, w)))
+    }
+}
+
+/// The part of an [`EncryptionPreflightReport`] about a single user.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct UserEncryptionPreflight {
+    /// The number of known devices of the user.
+    ///
+    /// For our own user, the current device is not counted.
+    pub devices: usize,
+    /// The number of devices of the user that would receive the room key.
+    pub recipient_devices: usize,
+    /// The number of devices of the user that we have verified.
+    pub verified_devices: usize,
+    /// Whether the user has a cross-signing identity, which is necessary to
+    /// verify them.
+    pub has_identity: bool,
+    /// The problems found for this user.
+    pub warnings: Vec<PreflightWarning>,
+}
+
+/// A problem found by an [`EncryptionPreflightReport`] for a user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PreflightWarning {
+    /// The devices of the user are not tracked, so they might be unknown or
+    /// outdated.
+    DeviceListUnknown,
+    /// The user doesn't have any device that supports end-to-end encryption,
+    /// so they won't be able to read the messages.
+    NoEncryptionCapableDevices,
+    /// None of the devices of the user would receive the room key because of
+    /// the encryption settings, e.g. because they are not verified.
+    AllDevicesWithheld,
+    /// The user doesn't have a cross-signing identity, so they can't be
+    /// verified.
+    Unverifiable,
+    /// The user has a lot of devices, which makes sharing room keys slower.
+    ManyDevices {
+        /// The number of devices of the user.
+        count: usize,
+    },
+    /// Some devices of the user don't satisfy the
+    /// [`TrustRequirement`](crate::TrustRequirement) of the encryption
+    /// settings, so sharing the room key would fail until they are verified,
+    /// blacklisted or ignored.
+    UntrustedDevices {
+        /// The IDs of the untrusted devices.
+        device_ids: BTreeSet<OwnedDeviceId>,
+    },
+}
+
+/// Build the [`EncryptionPreflightReport`] for the given users, with the
+/// devices and identities that are in the store.
+pub(crate) async fn encryption_preflight(
+    store: &Store,
+    users: impl Iterator<Item = &UserId>,
+    settings: &EncryptionSettings,
+) -> OlmResult<EncryptionPreflightReport> {
+    let tracked_users = store.tracked_users().await?;
+    let mut report = EncryptionPreflightReport::default();
+
+    for user_id in users.collect::<BTreeSet<_>>() {
+        let is_own_user = user_id == store.user_id();
+        let mut user = UserEncryptionPreflight::default();
+        let mut capable_devices = 0;
+        let mut untrusted_device_ids = BTreeSet::new();
+
+        let devices = store.get_user_devices_filtered(user_id).await?;
+        for device in devices.devices() {
+            if device.is_deleted() {
+                continue;
+            }
+
+            user.devices += 1;
+            if device.is_verified() {
+                user.verified_devices += 1;
+            }
+
+            // Room keys are sent over Olm, so the device needs an Olm key.
+            if !device.supports_olm() || device.curve25519_key().is_none() {
+                continue;
+            }
+            capable_devices += 1;
+
+            if withheld_code(&device, settings).is_some() {
+                report.withheld_devices += 1;
+                continue;
+            }
+            user.recipient_devices += 1;
+
+            if !settings.trust_requirement.is_satisfied_by(&device) {
+                untrusted_device_ids.insert(device.device_id().to_owned());
+            }
+
+            let has_session = match device.get_sessions().await? {
+                Some(sessions) => !sessions.lock().await.is_empty(),
+                None => false,
+            };
+            if !has_session {
+                report.missing_sessions += 1;
+            }
+        }
+
+        user.has_identity = store.get_identity(user_id).await?.is_some();
+
+        if !tracked_users.contains(user_id) {
+            user.warnings.push(PreflightWarning::DeviceListUnknown);
+        }
+        // Our own user doesn't need other devices to read the messages.
+        if capable_devices == 0 && !is_own_user {
+            user.warnings.push(PreflightWarning::NoEncryptionCapableDevices);
+        } else if capable_devices > 0 && user.recipient_devices == 0 {
+            user.warnings.push(PreflightWarning::AllDevicesWithheld);
+        }
+        if !user.has_identity {
+            user.warnings.push(PreflightWarning::Unverifiable);
+        }
+        if user.devices > MANY_DEVICES_THRESHOLD {
+            user.warnings.push(PreflightWarning::ManyDevices { count: user.devices });
+        }
+        if !untrusted_device_ids.is_empty() {
+            user.warnings
+                .push(PreflightWarning::UntrustedDevices { device_ids: untrusted_device_ids });
+        }
+
+        report.recipient_devices += user.recipient_devices;
+        report.users.insert(user_id.to_owned(), user);
+    }
+
+    let chunk_size = GroupSessionManager::MAX_TO_DEVICE_MESSAGES;
+    report.to_device_requests = (report.recipient_devices + chunk_size - 1) / chunk_size
+        + (report.withheld_devices + chunk_size - 1) / chunk_size;
+
+    Ok(report)
+}
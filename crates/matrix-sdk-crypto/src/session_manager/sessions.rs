@@ -133,6 +133,11 @@ impl SessionManager {
         self.wedged_devices.get(device.user_id()).is_some_and(|d| d.contains(device.device_id()))
     }
 
+    /// Check if an Olm session with a device of the given user is wedged.
+    pub fn has_wedged_devices(&self, user_id: &UserId) -> bool {
+        self.wedged_devices.get(user_id).is_some_and(|devices| !devices.is_empty())
+    }
+
     /// Check if the session was created to unwedge a Device.
     ///
     /// If the device was wedged this will queue up a dummy to-device message.
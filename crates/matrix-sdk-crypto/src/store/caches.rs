@@ -71,6 +71,11 @@ impl SessionStore {
     pub fn set_for_sender(&self, sender_key: &str, sessions: Vec<Session>) {
         self.entries.insert(sender_key.to_owned(), Arc::new(Mutex::new(sessions)));
     }
+
+    /// Remove all the sessions from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -105,6 +110,24 @@ impl GroupSessionStore {
             .collect()
     }
 
+    /// Get at most `limit` group sessions, ordered by their room ID and session
+    /// ID, starting after the given room ID and session ID.
+    pub fn get_batch(
+        &self,
+        after: Option<(&RoomId, &str)>,
+        limit: usize,
+    ) -> Vec<InboundGroupSession> {
+        let mut sessions: Vec<_> = self
+            .get_all()
+            .into_iter()
+            .filter(|s| after.map_or(true, |after| (s.room_id(), s.session_id()) > after))
+            .collect();
+        sessions.sort_by(|a, b| (a.room_id(), a.session_id()).cmp(&(b.room_id(), b.session_id())));
+        sessions.truncate(limit);
+
+        sessions
+    }
+
     /// Get the number of `InboundGroupSession`s we have.
     pub fn count(&self) -> usize {
         self.entries.iter().map(|d| d.value().len()).sum()
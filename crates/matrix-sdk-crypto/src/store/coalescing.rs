@@ -0,0 +1,722 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A crypto store wrapper that coalesces the writes of sessions.
+//!
+//! Every decrypted message updates the Olm or Megolm session that was used to
+//! decrypt it, and the session is saved again right away. Clients that decrypt
+//! a lot of messages, like bots, end up rewriting the same sessions over and
+//! over.
+//!
+//! The [`CoalescingCryptoStore`] keeps the changes made by decryption in
+//! memory, along with the account, and writes the latest version of every
+//! dirty session at once: periodically, when too many sessions are dirty, or
+//! along with the next other change, so the account, its sessions and the room
+//! keys are still written atomically.
+//!
+//! The sessions used to encrypt are always written right away: if an older
+//! version of such a session was restored after a crash, the next messages
+//! would be encrypted with message keys that were already used.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+#[cfg(not(target_arch = "wasm32"))]
+use matrix_sdk_common::executor::{spawn, JoinHandle};
+use ruma::{DeviceId, OwnedDeviceId, OwnedRoomId, RoomId, TransactionId, UserId};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use super::{
+    BackupKeys, Changes, CryptoStore, DynCryptoStore, IntoCryptoStore, Result, RoomKeyCounts,
+    RoomSettings,
+};
+use crate::{
+    olm::{
+        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
+        Session,
+    },
+    types::events::room_key_withheld::RoomKeyWithheldEvent,
+    GossipRequest, ReadOnlyAccount, ReadOnlyDevice, ReadOnlyUserIdentities, SecretInfo,
+    TrackedUser,
+};
+
+/// The number of dirty sessions after which they are written without waiting
+/// for the next periodic flush.
+const MAX_PENDING_SESSIONS: usize = 1000;
+
+/// A [`CryptoStore`] that coalesces the writes of sessions to another store.
+///
+/// The changes made by the decryption of to-device messages, that only
+/// contain the account, Olm sessions, inbound group sessions and the hashes of
+/// the decrypted messages, are kept in memory, and only the latest version of
+/// each session is written to the wrapped store. Reads see the pending changes.
+///
+/// The changes without message hashes, or with sessions that were used to
+/// encrypt, are written right away with the pending changes.
+///
+/// The pending changes are written:
+///
+/// * every `flush_interval`, except on WebAssembly,
+/// * when more than a thousand sessions are pending,
+/// * along with any other change,
+/// * when [`flush()`](Self::flush) or [`shutdown()`](Self::shutdown) is called.
+///
+/// The pending changes are dropped without being written when
+/// [`CryptoStore::clear_caches()`] is called, because the wrapped store was
+/// modified by another process.
+///
+/// The pending changes are lost if the process stops before they are written,
+/// so [`shutdown()`](Self::shutdown) must be awaited before stopping the
+/// process. This means that a to-device message could be decrypted again after
+/// a crash, the wrapped store being as it was a few seconds earlier.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use std::{sync::Arc, time::Duration};
+/// # use matrix_sdk_crypto::{store::{coalescing::CoalescingCryptoStore, MemoryStore}, OlmMachine};
+/// # use ruma::{device_id, user_id};
+/// # futures_executor::block_on(async {
+/// let store = Arc::new(CoalescingCryptoStore::new(MemoryStore::new(), Duration::from_secs(5)));
+/// let machine = OlmMachine::with_store(
+///     user_id!("@bot:example.org"),
+///     device_id!("BOTDEVICE"),
+///     store.clone(),
+/// )
+/// .await?;
+///
+/// // Decrypt a lot of messages…
+///
+/// // Write the pending sessions before exiting.
+/// store.shutdown().await?;
+/// # anyhow::Ok(()) });
+/// ```
+pub struct CoalescingCryptoStore {
+    inner: Arc<CoalescingInner>,
+    #[cfg(not(target_arch = "wasm32"))]
+    flusher: JoinHandle<()>,
+}
+
+impl CoalescingCryptoStore {
+    /// Wrap the given store, writing the pending sessions every
+    /// `flush_interval`.
+    ///
+    /// This must be called from a Tokio runtime, that runs the periodic
+    /// writes. On WebAssembly, the sessions aren't written periodically, only
+    /// with other changes or when the store is flushed.
+    pub fn new(store: impl IntoCryptoStore, flush_interval: Duration) -> Self {
+        let inner = Arc::new(CoalescingInner {
+            store: store.into_crypto_store(),
+            pending: Default::default(),
+            write_lock: Mutex::new(()),
+        });
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let flusher = {
+            let inner = Arc::downgrade(&inner);
+
+            spawn(async move {
+                loop {
+                    tokio::time::sleep(flush_interval).await;
+
+                    let Some(inner) = inner.upgrade() else {
+                        break;
+                    };
+                    if let Err(error) = inner.flush().await {
+                        warn!(?error, "Couldn't write the pending sessions");
+                    }
+                }
+            })
+        };
+        #[cfg(target_arch = "wasm32")]
+        let _ = flush_interval;
+
+        Self {
+            inner,
+            #[cfg(not(target_arch = "wasm32"))]
+            flusher,
+        }
+    }
+
+    /// Write the pending sessions to the wrapped store.
+    pub async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    /// Stop the periodic writes and write the pending sessions.
+    ///
+    /// This must be awaited before stopping the process, otherwise the
+    /// sessions that were updated since the last write are lost.
+    pub async fn shutdown(&self) -> Result<()> {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.flusher.abort();
+
+        self.inner.flush().await
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for CoalescingCryptoStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CoalescingCryptoStore")
+            .field("store", &self.inner.store)
+            .field("pending_sessions", &self.inner.pending.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for CoalescingCryptoStore {
+    fn drop(&mut self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.flusher.abort();
+
+        let pending = self.inner.pending.lock().unwrap().len();
+        if pending > 0 {
+            warn!(pending, "The coalescing crypto store was dropped without writing its sessions");
+        }
+    }
+}
+
+struct CoalescingInner {
+    store: Arc<DynCryptoStore>,
+    pending: StdMutex<PendingChanges>,
+    /// Serializes the writes to the wrapped store, so the pending changes are
+    /// only cleared once they were written, and reads don't miss them.
+    write_lock: Mutex<()>,
+}
+
+impl CoalescingInner {
+    async fn flush(&self) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+
+        let changes = self.pending.lock().unwrap().to_changes(Changes::default());
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        debug!(
+            sessions = changes.sessions.len(),
+            inbound_group_sessions = changes.inbound_group_sessions.len(),
+            "Writing the pending sessions"
+        );
+        self.store.save_changes(changes).await?;
+        self.pending.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+
+        let changes = {
+            let mut pending = self.pending.lock().unwrap();
+
+            if is_decryption_delta(&changes) {
+                pending.extend(changes);
+                if pending.len() < MAX_PENDING_SESSIONS {
+                    return Ok(());
+                }
+            }
+
+            // The pending sessions come first, so the new changes win when
+            // they are written.
+            pending.to_changes(changes)
+        };
+
+        self.store.save_changes(changes).await?;
+        self.pending.lock().unwrap().clear();
+
+        Ok(())
+    }
+}
+
+/// The changes to sessions that weren't written yet.
+#[derive(Default)]
+struct PendingChanges {
+    /// The latest version of the account.
+    account: Option<ReadOnlyAccount>,
+    /// The Olm sessions, by sender key and session ID.
+    sessions: BTreeMap<(String, String), Session>,
+    /// The inbound group sessions, by room ID and session ID.
+    inbound_group_sessions: BTreeMap<(OwnedRoomId, String), InboundGroupSession>,
+    /// The message hashes, by sender key and hash.
+    message_hashes: BTreeMap<(String, String), OlmMessageHash>,
+}
+
+impl PendingChanges {
+    fn len(&self) -> usize {
+        self.sessions.len() + self.inbound_group_sessions.len()
+    }
+
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn extend(&mut self, changes: Changes) {
+        if let Some(account) = changes.account {
+            self.account = Some(account);
+        }
+
+        for session in changes.sessions {
+            let key = (session.sender_key.to_base64(), session.session_id().to_owned());
+            self.sessions.insert(key, session);
+        }
+
+        for session in changes.inbound_group_sessions {
+            let key = (session.room_id().to_owned(), session.session_id().to_owned());
+            self.inbound_group_sessions.insert(key, session);
+        }
+
+        for hash in changes.message_hashes {
+            self.message_hashes.insert((hash.sender_key.clone(), hash.hash.clone()), hash);
+        }
+    }
+
+    /// Add the pending changes before the ones of `changes`.
+    fn to_changes(&self, mut changes: Changes) -> Changes {
+        if changes.account.is_none() {
+            changes.account = self.account.clone();
+        }
+        changes.sessions = self.sessions.values().cloned().chain(changes.sessions).collect();
+        changes.inbound_group_sessions = self
+            .inbound_group_sessions
+            .values()
+            .cloned()
+            .chain(changes.inbound_group_sessions)
+            .collect();
+        changes.message_hashes =
+            self.message_hashes.values().cloned().chain(changes.message_hashes).collect();
+
+        changes
+    }
+
+    fn sessions_for_sender(&self, sender_key: &str) -> Vec<Session> {
+        self.sessions
+            .iter()
+            .filter(|((key, _), _)| key == sender_key)
+            .map(|(_, session)| session.clone())
+            .collect()
+    }
+}
+
+/// Whether the given changes were only made by the decryption of to-device
+/// messages: they contain message hashes, and otherwise only sessions and the
+/// account, that is saved after every sync.
+///
+/// The sessions saved without message hashes might have been used to encrypt,
+/// so they must not be deferred.
+fn is_decryption_delta(changes: &Changes) -> bool {
+    let Changes {
+        account: _,
+        private_identity,
+        backup_version,
+        recovery_key,
+        sessions: _,
+        message_hashes,
+        inbound_group_sessions: _,
+        outbound_group_sessions,
+        key_requests,
+        identities,
+        devices,
+        withheld_session_info,
+        room_settings,
+        sessions_used_to_encrypt,
+    } = changes;
+
+    !message_hashes.is_empty()
+        && !sessions_used_to_encrypt
+        && private_identity.is_none()
+        && backup_version.is_none()
+        && recovery_key.is_none()
+        && outbound_group_sessions.is_empty()
+        && key_requests.is_empty()
+        && identities.is_empty()
+        && devices.is_empty()
+        && withheld_session_info.is_empty()
+        && room_settings.is_empty()
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl CryptoStore for CoalescingCryptoStore {
+    type Error = super::CryptoStoreError;
+
+    async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
+        let pending = self.inner.pending.lock().unwrap().account.clone();
+
+        match pending {
+            Some(account) => Ok(Some(account)),
+            None => self.inner.store.load_account().await,
+        }
+    }
+
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        self.inner.save_changes(Changes { account: Some(account), ..Default::default() }).await
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        self.inner.store.load_identity().await
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        self.inner.save_changes(changes).await
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
+        let pending = self.inner.pending.lock().unwrap().sessions_for_sender(sender_key);
+        let sessions = self.inner.store.get_sessions(sender_key).await?;
+
+        if pending.is_empty() {
+            return Ok(sessions);
+        }
+
+        // Return a new list rather than updating the one of the wrapped store,
+        // the sessions are added to it when they are written.
+        let mut merged = match &sessions {
+            Some(sessions) => sessions.lock().await.clone(),
+            None => Vec::new(),
+        };
+        for session in pending {
+            if !merged.contains(&session) {
+                merged.push(session);
+            }
+        }
+
+        Ok(Some(Arc::new(Mutex::new(merged))))
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        let key = (room_id.to_owned(), session_id.to_owned());
+        let pending = self.inner.pending.lock().unwrap().inbound_group_sessions.get(&key).cloned();
+
+        match pending {
+            Some(session) => Ok(Some(session)),
+            None => self.inner.store.get_inbound_group_session(room_id, session_id).await,
+        }
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        self.inner.flush().await?;
+        self.inner.store.get_inbound_group_sessions().await
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(&RoomId, &str)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner.flush().await?;
+        self.inner.store.get_inbound_group_sessions_batch(after, limit).await
+    }
+
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
+        self.inner.flush().await?;
+        self.inner.store.inbound_group_session_counts().await
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inner.flush().await?;
+        self.inner.store.inbound_group_sessions_for_backup(limit).await
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        self.inner.flush().await?;
+        self.inner.store.reset_backup_state().await
+    }
+
+    async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        self.inner.store.load_backup_keys().await
+    }
+
+    async fn get_outbound_group_session(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        self.inner.store.get_outbound_group_session(room_id).await
+    }
+
+    async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>> {
+        self.inner.store.load_tracked_users().await
+    }
+
+    async fn save_tracked_users(&self, users: &[(&UserId, bool)]) -> Result<()> {
+        self.inner.store.save_tracked_users(users).await
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        self.inner.store.get_device(user_id, device_id).await
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<OwnedDeviceId, ReadOnlyDevice>> {
+        self.inner.store.get_user_devices(user_id).await
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<ReadOnlyUserIdentities>> {
+        self.inner.store.get_user_identity(user_id).await
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+        let key = (message_hash.sender_key.clone(), message_hash.hash.clone());
+        if self.inner.pending.lock().unwrap().message_hashes.contains_key(&key) {
+            return Ok(true);
+        }
+
+        self.inner.store.is_message_known(message_hash).await
+    }
+
+    async fn get_outgoing_secret_requests(
+        &self,
+        request_id: &TransactionId,
+    ) -> Result<Option<GossipRequest>> {
+        self.inner.store.get_outgoing_secret_requests(request_id).await
+    }
+
+    async fn get_secret_request_by_info(
+        &self,
+        secret_info: &SecretInfo,
+    ) -> Result<Option<GossipRequest>> {
+        self.inner.store.get_secret_request_by_info(secret_info).await
+    }
+
+    async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>> {
+        self.inner.store.get_unsent_secret_requests().await
+    }
+
+    async fn delete_outgoing_secret_requests(&self, request_id: &TransactionId) -> Result<()> {
+        self.inner.store.delete_outgoing_secret_requests(request_id).await
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>> {
+        self.inner.store.get_withheld_info(room_id, session_id).await
+    }
+
+    async fn get_room_settings(&self, room_id: &RoomId) -> Result<Option<RoomSettings>> {
+        self.inner.store.get_room_settings(room_id).await
+    }
+
+    async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.store.get_custom_value(key).await
+    }
+
+    async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.inner.store.set_custom_value(key, value).await
+    }
+
+    async fn insert_custom_value_if_missing(&self, key: &str, new: Vec<u8>) -> Result<bool> {
+        self.inner.store.insert_custom_value_if_missing(key, new).await
+    }
+
+    async fn remove_custom_value(&self, key: &str) -> Result<bool> {
+        self.inner.store.remove_custom_value(key).await
+    }
+
+    async fn clear_caches(&self) {
+        {
+            let _guard = self.inner.write_lock.lock().await;
+            let mut pending = self.inner.pending.lock().unwrap();
+
+            // The wrapped store was modified by another process, so the pending
+            // sessions might be older than the ones it contains now.
+            if pending.len() > 0 {
+                warn!(pending = pending.len(), "Dropping the pending sessions");
+            }
+            pending.clear();
+        }
+
+        self.inner.store.clear_caches().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use matrix_sdk_test::async_test;
+    use ruma::room_id;
+    use vodozemac::{Curve25519PublicKey, Ed25519PublicKey};
+
+    use super::CoalescingCryptoStore;
+    use crate::{
+        olm::{tests::get_account_and_session, InboundGroupSession, OlmMessageHash},
+        store::{Changes, CryptoStore, MemoryStore},
+    };
+
+    #[async_test]
+    async fn test_sessions_are_coalesced() {
+        let (_, session) = get_account_and_session().await;
+        let backend = Arc::new(MemoryStore::new());
+        let store = CoalescingCryptoStore::new(backend.clone(), Duration::from_secs(3600));
+
+        let sender_key = session.sender_key.to_base64();
+        let hash = OlmMessageHash { sender_key: sender_key.clone(), hash: "hash".to_owned() };
+        let changes = Changes {
+            sessions: vec![session.clone()],
+            message_hashes: vec![hash.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        // The session is only visible through the coalescing store.
+        assert!(backend.get_sessions(&sender_key).await.unwrap().is_none());
+        assert!(!backend.is_message_known(&hash).await.unwrap());
+        let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
+        assert_eq!(sessions.lock().await.as_slice(), [session.clone()]);
+        assert!(store.is_message_known(&hash).await.unwrap());
+
+        store.shutdown().await.unwrap();
+
+        let sessions = backend.get_sessions(&sender_key).await.unwrap().unwrap();
+        assert_eq!(sessions.lock().await.as_slice(), [session]);
+        assert!(backend.is_message_known(&hash).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_pending_sessions_are_written_with_other_changes() {
+        let (account, _) = get_account_and_session().await;
+        let room_id = room_id!("!test:localhost");
+
+        let (outbound, _) = account.create_group_session_pair_with_defaults(room_id).await;
+        let inbound = InboundGroupSession::new(
+            Curve25519PublicKey::from_base64("Nn0L2hkcCMFKqynTjyGsJbth7QrVmX3lbrksMkrGOAw")
+                .unwrap(),
+            Ed25519PublicKey::from_base64("ee3Ek+J2LkkPmjGPGLhMxiKnhiX//xcqaVL4RP6EypE").unwrap(),
+            room_id,
+            &outbound.session_key().await,
+            outbound.settings().algorithm.to_owned(),
+            None,
+        )
+        .unwrap();
+
+        let backend = Arc::new(MemoryStore::new());
+        let store = CoalescingCryptoStore::new(backend.clone(), Duration::from_secs(3600));
+
+        // The room key was received in an encrypted to-device message.
+        let hash = OlmMessageHash { sender_key: "sender_key".to_owned(), hash: "hash".to_owned() };
+        let changes = Changes {
+            inbound_group_sessions: vec![inbound.clone()],
+            message_hashes: vec![hash],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let session_id = outbound.session_id();
+        assert!(backend.get_inbound_group_session(room_id, session_id).await.unwrap().is_none());
+        let loaded = store.get_inbound_group_session(room_id, session_id).await.unwrap().unwrap();
+        assert_eq!(loaded, inbound);
+
+        store.save_account(account).await.unwrap();
+
+        let loaded = backend.get_inbound_group_session(room_id, session_id).await.unwrap().unwrap();
+        assert_eq!(loaded, inbound);
+    }
+
+    #[async_test]
+    async fn test_sessions_used_to_encrypt_are_written_right_away() {
+        let (_, session) = get_account_and_session().await;
+        let backend = Arc::new(MemoryStore::new());
+        let store = CoalescingCryptoStore::new(backend.clone(), Duration::from_secs(3600));
+
+        let sender_key = session.sender_key.to_base64();
+        let hash = OlmMessageHash { sender_key: sender_key.clone(), hash: "hash".to_owned() };
+        let changes = Changes {
+            sessions: vec![session.clone()],
+            message_hashes: vec![hash.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        assert!(backend.get_sessions(&sender_key).await.unwrap().is_none());
+
+        // Saving the session without a message hash, as done after encrypting,
+        // writes it along with the pending changes.
+        let changes = Changes { sessions: vec![session.clone()], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        let sessions = backend.get_sessions(&sender_key).await.unwrap().unwrap();
+        assert_eq!(sessions.lock().await.as_slice(), [session]);
+        assert!(backend.is_message_known(&hash).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_sessions_used_to_encrypt_after_decryption_are_written_right_away() {
+        let (account, session) = get_account_and_session().await;
+        let backend = Arc::new(MemoryStore::new());
+        let store = CoalescingCryptoStore::new(backend.clone(), Duration::from_secs(3600));
+
+        // A sync decrypted a message and answered a key request with the same
+        // session.
+        let sender_key = session.sender_key.to_base64();
+        let hash = OlmMessageHash { sender_key: sender_key.clone(), hash: "hash".to_owned() };
+        let changes = Changes {
+            account: Some(account),
+            sessions: vec![session.clone()],
+            message_hashes: vec![hash.clone()],
+            sessions_used_to_encrypt: true,
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let sessions = backend.get_sessions(&sender_key).await.unwrap().unwrap();
+        assert_eq!(sessions.lock().await.as_slice(), [session]);
+        assert!(backend.is_message_known(&hash).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_clear_caches_drops_pending_sessions() {
+        let (_, session) = get_account_and_session().await;
+        let backend = Arc::new(MemoryStore::new());
+        let store = CoalescingCryptoStore::new(backend.clone(), Duration::from_secs(3600));
+
+        let sender_key = session.sender_key.to_base64();
+        let hash = OlmMessageHash { sender_key: sender_key.clone(), hash: "hash".to_owned() };
+        let changes = Changes {
+            sessions: vec![session],
+            message_hashes: vec![hash.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        // Another process modified the wrapped store.
+        store.clear_caches().await;
+
+        assert!(store.get_sessions(&sender_key).await.unwrap().is_none());
+        assert!(!store.is_message_known(&hash).await.unwrap());
+
+        store.shutdown().await.unwrap();
+        assert!(backend.get_sessions(&sender_key).await.unwrap().is_none());
+    }
+}
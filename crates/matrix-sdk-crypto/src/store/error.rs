@@ -83,6 +83,11 @@ pub enum CryptoStoreError {
     /// An error due to locking.
     #[error(transparent)]
     Lock(#[from] LockStoreError),
+
+    /// The generation counter of the store, used to detect that another
+    /// process modified it, is invalid.
+    #[error("invalid crypto store generation counter: {0}")]
+    InvalidLockGeneration(String),
 }
 
 impl CryptoStoreError {
@@ -38,7 +38,7 @@ macro_rules! cryptostore_integration_tests {
                     },
                     EventEncryptionAlgorithm,
                 },
-                ReadOnlyDevice, SecretInfo, ToDeviceRequest, TrackedUser,
+                ReadOnlyDevice, SecretInfo, ToDeviceRequest, TrackedUser, TrustRequirement,
             };
 
             use super::get_store;
@@ -353,6 +353,46 @@ macro_rules! cryptostore_integration_tests {
                 assert_eq!(store.inbound_group_session_counts().await.unwrap().total, 1);
             }
 
+            #[async_test]
+            async fn load_inbound_group_sessions_in_batches() {
+                let (account, store) =
+                    get_loaded_store("load_inbound_group_sessions_in_batches").await;
+
+                let mut sessions = Vec::new();
+                for room_id in
+                    [room_id!("!a:localhost"), room_id!("!b:localhost"), room_id!("!c:localhost")]
+                {
+                    let (_, session) =
+                        account.create_group_session_pair_with_defaults(room_id).await;
+                    sessions.push(session);
+                }
+
+                let changes =
+                    Changes { inbound_group_sessions: sessions.clone(), ..Default::default() };
+                store.save_changes(changes).await.expect("Can't save group sessions");
+
+                let first = store.get_inbound_group_sessions_batch(None, 2).await.unwrap();
+                assert_eq!(first.len(), 2);
+
+                let last = first.last().unwrap();
+                let after = Some((last.room_id(), last.session_id()));
+                let second = store.get_inbound_group_sessions_batch(after, 2).await.unwrap();
+                assert_eq!(second.len(), 1);
+
+                let last = second.last().unwrap();
+                let after = Some((last.room_id(), last.session_id()));
+                assert!(store.get_inbound_group_sessions_batch(after, 2).await.unwrap().is_empty());
+
+                // Every session was returned exactly once.
+                let mut loaded: Vec<_> =
+                    first.iter().chain(&second).map(|s| s.session_id().to_owned()).collect();
+                let mut expected: Vec<_> =
+                    sessions.iter().map(|s| s.session_id().to_owned()).collect();
+                loaded.sort();
+                expected.sort();
+                assert_eq!(loaded, expected);
+            }
+
             #[async_test]
             async fn test_tracked_users() {
                 let dir = "test_tracked_users";
@@ -727,12 +767,14 @@ macro_rules! cryptostore_integration_tests {
                 let settings_1 = RoomSettings {
                     algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
                     only_allow_trusted_devices: true,
+                    trust_requirement: TrustRequirement::Verified,
                 };
 
                 let room_2 = room_id!("!test_2:localhost");
                 let settings_2 = RoomSettings {
                     algorithm: EventEncryptionAlgorithm::OlmV1Curve25519AesSha2,
                     only_allow_trusted_devices: false,
+                    trust_requirement: TrustRequirement::Untrusted,
                 };
 
                 let room_3 = room_id!("!test_3:localhost");
@@ -15,7 +15,7 @@
 use std::{collections::HashMap, convert::Infallible, sync::Arc};
 
 use async_trait::async_trait;
-use dashmap::{DashMap, DashSet};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use ruma::{
     DeviceId, OwnedDeviceId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId, TransactionId,
     UserId,
@@ -56,6 +56,7 @@ pub struct MemoryStore {
     outgoing_key_requests: Arc<DashMap<OwnedTransactionId, GossipRequest>>,
     key_requests_by_info: Arc<DashMap<String, OwnedTransactionId>>,
     direct_withheld_info: Arc<DashMap<OwnedRoomId, DashMap<String, RoomKeyWithheldEvent>>>,
+    custom_values: Arc<DashMap<String, Vec<u8>>>,
 }
 
 impl Default for MemoryStore {
@@ -69,6 +70,7 @@ impl Default for MemoryStore {
             outgoing_key_requests: Default::default(),
             key_requests_by_info: Default::default(),
             direct_withheld_info: Default::default(),
+            custom_values: Default::default(),
         }
     }
 }
@@ -178,6 +180,14 @@ impl CryptoStore for MemoryStore {
         Ok(self.inbound_group_sessions.get_all())
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(&RoomId, &str)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        Ok(self.inbound_group_sessions.get_batch(after, limit))
+    }
+
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
         let backed_up =
             self.get_inbound_group_sessions().await?.into_iter().filter(|s| s.backed_up()).count();
@@ -302,24 +312,27 @@ impl CryptoStore for MemoryStore {
         Ok(None)
     }
 
-    async fn get_custom_value(&self, _key: &str) -> Result<Option<Vec<u8>>> {
-        warn!("Method not implemented");
-        Ok(None)
+    async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.custom_values.get(key).map(|value| value.clone()))
     }
 
-    async fn set_custom_value(&self, _key: &str, _value: Vec<u8>) -> Result<()> {
-        warn!("Method not implemented");
+    async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.custom_values.insert(key.to_owned(), value);
         Ok(())
     }
 
-    async fn insert_custom_value_if_missing(&self, _key: &str, _new: Vec<u8>) -> Result<bool> {
-        warn!("Method insert_custom_value_if_missing not implemented");
-        Ok(false)
+    async fn insert_custom_value_if_missing(&self, key: &str, new: Vec<u8>) -> Result<bool> {
+        match self.custom_values.entry(key.to_owned()) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(new);
+                Ok(true)
+            }
+        }
     }
 
-    async fn remove_custom_value(&self, _key: &str) -> Result<bool> {
-        warn!("Method remove_custom_value not implemented");
-        Ok(false)
+    async fn remove_custom_value(&self, key: &str) -> Result<bool> {
+        Ok(self.custom_values.remove(key).is_some())
     }
 }
 
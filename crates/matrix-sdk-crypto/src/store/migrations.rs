@@ -0,0 +1,416 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A shared framework to migrate the schema of [`CryptoStore`]
+//! implementations.
+//!
+//! A store declares the list of its schema versions as [`Migration`]s, and
+//! implements [`MigrationBackend`] to load its current version and to apply a
+//! single migration. The [`Migrator`] then takes care of figuring out which
+//! migrations are pending, running them in order and reporting progress.
+//!
+//! Every migration is applied once, atomically, by the backend, together with
+//! the update of the stored schema version, so a failing migration leaves the
+//! store at the last version that was successfully reached, instead of in a
+//! half-migrated state. Checking beforehand that the migrations would succeed
+//! is left to [`Migrator::dry_run()`].
+//!
+//! [`CryptoStore`]: super::CryptoStore
+
+use std::{fmt, sync::Arc};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tracing::{debug, info};
+
+/// A single step in the evolution of a store's schema.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Migration {
+    /// The schema version of the store once this migration is applied.
+    pub version: u8,
+    /// A short, human-readable description of what the migration does.
+    pub description: &'static str,
+}
+
+impl Migration {
+    /// Create a new `Migration` to the given schema version.
+    pub const fn new(version: u8, description: &'static str) -> Self {
+        Self { version, description }
+    }
+}
+
+/// The progress of a migration run, reported to the listener set with
+/// [`Migrator::with_progress_listener()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrationProgress {
+    /// Migrations are about to be applied.
+    Started {
+        /// The schema version of the store before the migrations.
+        from_version: u8,
+        /// The schema version the store is migrated to.
+        to_version: u8,
+        /// The number of migrations that are going to be applied.
+        total: usize,
+    },
+    /// A migration was applied.
+    Applied {
+        /// The migration that was applied.
+        migration: Migration,
+        /// The number of migrations applied so far, including this one.
+        done: usize,
+        /// The number of migrations that are going to be applied.
+        total: usize,
+    },
+    /// All the migrations were applied.
+    Finished {
+        /// The schema version of the store after the migrations.
+        version: u8,
+    },
+}
+
+/// The storage-specific part of a migration.
+#[async_trait]
+pub trait MigrationBackend {
+    /// The error type of the store.
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Load the current schema version of the store.
+    ///
+    /// Returns `0` if the store was never initialized.
+    async fn load_version(&self) -> Result<u8, Self::Error>;
+
+    /// Apply the given migration and persist its version as the new schema
+    /// version of the store.
+    ///
+    /// This must be atomic: if an error is returned, no changes must have
+    /// been persisted.
+    async fn apply(&self, migration: &Migration) -> Result<(), Self::Error>;
+
+    /// Apply the given migrations, in order, without persisting any changes.
+    ///
+    /// This is used to check that the data in the store can be migrated
+    /// successfully before doing so.
+    async fn dry_run(&self, migrations: &[Migration]) -> Result<(), Self::Error>;
+}
+
+/// Errors that can happen when migrating a store.
+#[derive(Debug, Error)]
+pub enum MigrationError<E: std::error::Error + 'static> {
+    /// The declared migrations are not sorted by strictly increasing
+    /// versions.
+    #[error("migrations must have strictly increasing versions, found {0} after {1}")]
+    UnorderedMigrations(u8, u8),
+
+    /// The store has a newer schema version than the most recent known
+    /// migration, it was probably created by a newer version of the SDK.
+    #[error("the store has version {found}, but the latest supported version is {latest}")]
+    UnsupportedVersion {
+        /// The schema version of the store.
+        found: u8,
+        /// The most recent known schema version.
+        latest: u8,
+    },
+
+    /// Loading the schema version failed.
+    #[error("failed to load the schema version of the store")]
+    LoadVersion(#[source] E),
+
+    /// A migration failed. The store is left at the version before this
+    /// migration.
+    #[error("migration to version {version} failed")]
+    Migration {
+        /// The version of the failed migration.
+        version: u8,
+        /// The error returned by the backend.
+        #[source]
+        source: E,
+    },
+
+    /// The dry-run of the pending migrations failed, no changes were made.
+    #[error("dry-run of the pending migrations failed")]
+    DryRun(#[source] E),
+}
+
+type ProgressListener = Arc<dyn Fn(MigrationProgress) + Send + Sync>;
+
+/// Runs the pending [`Migration`]s of a store, in order.
+#[derive(Clone)]
+pub struct Migrator {
+    migrations: &'static [Migration],
+    progress_listener: Option<ProgressListener>,
+}
+
+impl Migrator {
+    /// Create a new `Migrator` for the given migrations.
+    ///
+    /// The migrations must be sorted by strictly increasing versions, this is
+    /// checked before any migration is applied.
+    pub fn new(migrations: &'static [Migration]) -> Self {
+        Self { migrations, progress_listener: None }
+    }
+
+    /// Set a callback that is called as the migrations are applied.
+    pub fn with_progress_listener(
+        mut self,
+        listener: impl Fn(MigrationProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress_listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// The most recent schema version, i.e. the version of the last
+    /// migration.
+    pub fn latest_version(&self) -> u8 {
+        self.migrations.last().map_or(0, |m| m.version)
+    }
+
+    /// Get the migrations that still need to be applied to the store.
+    pub async fn pending<B: MigrationBackend>(
+        &self,
+        backend: &B,
+    ) -> Result<&'static [Migration], MigrationError<B::Error>> {
+        let version = backend.load_version().await.map_err(MigrationError::LoadVersion)?;
+        self.pending_for_version(version)
+    }
+
+    fn pending_for_version<E: std::error::Error>(
+        &self,
+        version: u8,
+    ) -> Result<&'static [Migration], MigrationError<E>> {
+        for pair in self.migrations.windows(2) {
+            if pair[1].version <= pair[0].version {
+                return Err(MigrationError::UnorderedMigrations(pair[1].version, pair[0].version));
+            }
+        }
+
+        let latest = self.latest_version();
+        if version > latest {
+            return Err(MigrationError::UnsupportedVersion { found: version, latest });
+        }
+
+        let first_pending = self.migrations.partition_point(|m| m.version <= version);
+        Ok(&self.migrations[first_pending..])
+    }
+
+    /// Check that the pending migrations can be applied to the store, without
+    /// persisting any changes.
+    ///
+    /// Returns the migrations that would be applied.
+    pub async fn dry_run<B: MigrationBackend>(
+        &self,
+        backend: &B,
+    ) -> Result<&'static [Migration], MigrationError<B::Error>> {
+        let pending = self.pending(backend).await?;
+
+        if !pending.is_empty() {
+            debug!(count = pending.len(), "Dry-running pending migrations");
+            backend.dry_run(pending).await.map_err(MigrationError::DryRun)?;
+        }
+
+        Ok(pending)
+    }
+
+    /// Apply all the pending migrations to the store.
+    ///
+    /// Each migration is applied once, in its own transaction. If one of them
+    /// fails, the store stays at the version of the previous one. Use
+    /// [`Migrator::dry_run()`] beforehand to check that they would all
+    /// succeed.
+    ///
+    /// Returns the migrations that were applied.
+    pub async fn run<B: MigrationBackend>(
+        &self,
+        backend: &B,
+    ) -> Result<&'static [Migration], MigrationError<B::Error>> {
+        let from_version = backend.load_version().await.map_err(MigrationError::LoadVersion)?;
+        let pending = self.pending_for_version(from_version)?;
+
+        if pending.is_empty() {
+            return Ok(pending);
+        }
+
+        let to_version = self.latest_version();
+        let total = pending.len();
+        info!(from_version, to_version, "Migrating store");
+        self.report(MigrationProgress::Started { from_version, to_version, total });
+
+        for (idx, migration) in pending.iter().enumerate() {
+            debug!(version = migration.version, description = migration.description, "Migrating");
+
+            backend.apply(migration).await.map_err(|source| MigrationError::Migration {
+                version: migration.version,
+                source,
+            })?;
+
+            self.report(MigrationProgress::Applied { migration: *migration, done: idx + 1, total });
+        }
+
+        self.report(MigrationProgress::Finished { version: to_version });
+
+        Ok(pending)
+    }
+
+    fn report(&self, progress: MigrationProgress) {
+        if let Some(listener) = &self.progress_listener {
+            listener(progress);
+        }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for Migrator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migrator").field("migrations", &self.migrations).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use assert_matches::assert_matches;
+    use async_trait::async_trait;
+    use matrix_sdk_test::async_test;
+
+    use super::{Migration, MigrationBackend, MigrationError, MigrationProgress, Migrator};
+
+    static MIGRATIONS: &[Migration] = &[
+        Migration::new(1, "init"),
+        Migration::new(2, "add table"),
+        Migration::new(3, "drop column"),
+    ];
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("broken data")]
+    struct BrokenData;
+
+    #[derive(Default)]
+    struct FakeStore {
+        version: Mutex<u8>,
+        broken_version: Option<u8>,
+        /// Whether the broken version is only noticed when it is applied.
+        broken_on_apply_only: bool,
+        /// The versions of the migrations that were applied, in order.
+        applied: Mutex<Vec<u8>>,
+    }
+
+    #[async_trait]
+    impl MigrationBackend for FakeStore {
+        type Error = BrokenData;
+
+        async fn load_version(&self) -> Result<u8, Self::Error> {
+            Ok(*self.version.lock().unwrap())
+        }
+
+        async fn apply(&self, migration: &Migration) -> Result<(), Self::Error> {
+            if self.broken_version == Some(migration.version) {
+                return Err(BrokenData);
+            }
+            self.applied.lock().unwrap().push(migration.version);
+            *self.version.lock().unwrap() = migration.version;
+            Ok(())
+        }
+
+        async fn dry_run(&self, migrations: &[Migration]) -> Result<(), Self::Error> {
+            if !self.broken_on_apply_only
+                && migrations.iter().any(|m| self.broken_version == Some(m.version))
+            {
+                return Err(BrokenData);
+            }
+            Ok(())
+        }
+    }
+
+    #[async_test]
+    async fn unordered_migrations() {
+        static UNORDERED: &[Migration] = &[Migration::new(2, "two"), Migration::new(1, "one")];
+        let store = FakeStore::default();
+
+        assert_matches!(
+            Migrator::new(UNORDERED).run(&store).await,
+            Err(MigrationError::UnorderedMigrations(1, 2))
+        );
+        assert_eq!(store.load_version().await.unwrap(), 0);
+    }
+
+    #[async_test]
+    async fn run_pending_migrations() {
+        let store = FakeStore { version: Mutex::new(1), ..Default::default() };
+        let progress = Arc::new(Mutex::new(Vec::new()));
+
+        let migrator = Migrator::new(MIGRATIONS).with_progress_listener({
+            let progress = progress.clone();
+            move |p| progress.lock().unwrap().push(p)
+        });
+
+        assert_eq!(migrator.dry_run(&store).await.unwrap(), &MIGRATIONS[1..]);
+        assert_eq!(store.load_version().await.unwrap(), 1);
+
+        assert_eq!(migrator.run(&store).await.unwrap(), &MIGRATIONS[1..]);
+        assert_eq!(store.load_version().await.unwrap(), 3);
+        assert_eq!(*store.applied.lock().unwrap(), [2, 3]);
+
+        assert_eq!(
+            *progress.lock().unwrap(),
+            [
+                MigrationProgress::Started { from_version: 1, to_version: 3, total: 2 },
+                MigrationProgress::Applied { migration: MIGRATIONS[1], done: 1, total: 2 },
+                MigrationProgress::Applied { migration: MIGRATIONS[2], done: 2, total: 2 },
+                MigrationProgress::Finished { version: 3 },
+            ]
+        );
+
+        assert!(migrator.run(&store).await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn failed_dry_run_changes_nothing() {
+        let store =
+            FakeStore { version: Mutex::new(0), broken_version: Some(3), ..Default::default() };
+        let migrator = Migrator::new(MIGRATIONS);
+
+        assert_matches!(migrator.dry_run(&store).await, Err(MigrationError::DryRun(_)));
+        assert_eq!(store.load_version().await.unwrap(), 0);
+        assert!(store.applied.lock().unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn failed_migration_keeps_previous_version() {
+        let store = FakeStore {
+            version: Mutex::new(0),
+            broken_version: Some(3),
+            broken_on_apply_only: true,
+            ..Default::default()
+        };
+        let migrator = Migrator::new(MIGRATIONS);
+
+        assert_matches!(
+            migrator.run(&store).await,
+            Err(MigrationError::Migration { version: 3, .. })
+        );
+        assert_eq!(store.load_version().await.unwrap(), 2);
+        assert_eq!(*store.applied.lock().unwrap(), [1, 2]);
+    }
+
+    #[async_test]
+    async fn newer_store_version() {
+        let store = FakeStore { version: Mutex::new(4), ..Default::default() };
+        let migrator = Migrator::new(MIGRATIONS);
+
+        assert_matches!(
+            migrator.run(&store).await,
+            Err(MigrationError::UnsupportedVersion { found: 4, latest: 3 })
+        );
+    }
+}
@@ -53,7 +53,9 @@ use dashmap::DashSet;
 use futures_core::Stream;
 use futures_util::stream::StreamExt;
 use ruma::{
-    events::secret::request::SecretName, DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, UserId,
+    events::{secret::request::SecretName, AnyToDeviceEvent},
+    serde::Raw,
+    DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
@@ -63,6 +65,8 @@ use tracing::{info, warn};
 use vodozemac::{megolm::SessionOrdering, Curve25519PublicKey};
 use zeroize::Zeroize;
 
+#[cfg(any(test, feature = "testing"))]
+use crate::fault_injection::FaultInjector;
 use crate::{
     identities::{
         user::{OwnUserIdentity, UserIdentities, UserIdentity},
@@ -70,7 +74,7 @@ use crate::{
     },
     olm::{
         InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, Session,
+        ReadOnlyAccount, Session, TrustRequirement,
     },
     types::{events::room_key_withheld::RoomKeyWithheldEvent, EventEncryptionAlgorithm},
     utilities::encode,
@@ -79,9 +83,12 @@ use crate::{
 };
 
 pub mod caches;
+pub mod coalescing;
 mod error;
 pub mod locks;
 mod memorystore;
+pub mod migrations;
+mod state_export;
 mod traits;
 
 #[cfg(any(test, feature = "testing"))]
@@ -91,8 +98,10 @@ pub mod integration_tests;
 
 use caches::{SequenceNumber, UsersForKeyQuery};
 pub use error::{CryptoStoreError, Result};
+use locks::CryptoStoreLock;
 use matrix_sdk_common::timeout::timeout;
 pub use memorystore::MemoryStore;
+pub use state_export::CryptoStateExport;
 pub use traits::{CryptoStore, DynCryptoStore, IntoCryptoStore};
 
 pub use crate::gossiping::{GossipRequest, SecretInfo};
@@ -132,6 +141,22 @@ struct StoreInner {
     /// The sender side of a broadcast stream that is notified whenever we get
     /// an update to an inbound group session.
     room_keys_received_sender: broadcast::Sender<Vec<RoomKeyInfo>>,
+
+    /// The sender side of a broadcast stream that is notified whenever we
+    /// decrypt a to-device event that isn't handled by the crypto crate.
+    decrypted_to_device_sender: broadcast::Sender<DecryptedToDeviceEvent>,
+
+    /// The sender side of a broadcast stream that is notified whenever user
+    /// identities are received or updated.
+    identities_sender: broadcast::Sender<IdentityChanges>,
+
+    /// The sender side of a broadcast stream that is notified whenever devices
+    /// are received, updated or deleted.
+    devices_sender: broadcast::Sender<DeviceChanges>,
+
+    /// The simulated failures injected in the tests.
+    #[cfg(any(test, feature = "testing"))]
+    fault_injector: FaultInjector,
 }
 
 #[derive(Default, Debug)]
@@ -151,6 +176,10 @@ pub struct Changes {
     /// Stores when a `m.room_key.withheld` is received
     pub withheld_session_info: BTreeMap<OwnedRoomId, BTreeMap<String, RoomKeyWithheldEvent>>,
     pub room_settings: HashMap<OwnedRoomId, RoomSettings>,
+    /// Whether some of the `sessions` were used to encrypt, so they must be
+    /// written right away even by a store that defers the writes of the
+    /// sessions updated by decryption.
+    pub sessions_used_to_encrypt: bool,
 }
 
 /// A user for which we are tracking the list of devices.
@@ -185,6 +214,11 @@ impl Changes {
 pub struct IdentityChanges {
     pub new: Vec<ReadOnlyUserIdentities>,
     pub changed: Vec<ReadOnlyUserIdentities>,
+    /// The users whose master key changed, with whether their identity was
+    /// verified before the change.
+    ///
+    /// Their identities are in `changed`.
+    pub master_key_changed: BTreeMap<OwnedUserId, bool>,
 }
 
 impl IdentityChanges {
@@ -329,6 +363,10 @@ pub struct RoomSettings {
     /// Should untrusted devices receive the room key, or should they be
     /// excluded from the conversation.
     pub only_allow_trusted_devices: bool,
+    /// The trust that is required from the devices of the recipients of the
+    /// room keys.
+    #[serde(default)]
+    pub trust_requirement: TrustRequirement,
 }
 
 impl Default for RoomSettings {
@@ -336,6 +374,7 @@ impl Default for RoomSettings {
         Self {
             algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
             only_allow_trusted_devices: false,
+            trust_requirement: Default::default(),
         }
     }
 }
@@ -370,6 +409,22 @@ impl From<&InboundGroupSession> for RoomKeyInfo {
     }
 }
 
+/// A to-device event that was received encrypted over Olm and that isn't
+/// handled by the crypto crate, e.g. an event of a custom protocol.
+#[derive(Clone, Debug)]
+pub struct DecryptedToDeviceEvent {
+    /// The user that sent the event.
+    pub sender: OwnedUserId,
+    /// The Curve25519 key of the device that sent the event.
+    pub sender_key: Curve25519PublicKey,
+    /// The ID of the device that sent the event, if the device is known.
+    pub sender_device_id: Option<OwnedDeviceId>,
+    /// The type of the event.
+    pub event_type: String,
+    /// The decrypted event.
+    pub raw_event: Raw<AnyToDeviceEvent>,
+}
+
 impl Store {
     /// Create a new Store
     pub(crate) fn new(
@@ -379,6 +434,9 @@ impl Store {
         verification_machine: VerificationMachine,
     ) -> Self {
         let (room_keys_received_sender, _) = broadcast::channel(10);
+        let (decrypted_to_device_sender, _) = broadcast::channel(100);
+        let (identities_sender, _) = broadcast::channel(10);
+        let (devices_sender, _) = broadcast::channel(10);
         let inner = Arc::new(StoreInner {
             user_id,
             identity,
@@ -390,6 +448,11 @@ impl Store {
             tracked_users_loaded: AtomicBool::new(false),
             tracked_user_loading_lock: Mutex::new(()),
             room_keys_received_sender,
+            decrypted_to_device_sender,
+            identities_sender,
+            devices_sender,
+            #[cfg(any(test, feature = "testing"))]
+            fault_injector: Default::default(),
         });
         Self { inner }
     }
@@ -434,6 +497,9 @@ impl Store {
     // Practically, it shouldn't matter whether the first if block is run at
     // function call time or when first polling the returned future.
     pub(crate) fn save_changes(&self, changes: Changes) -> impl Future<Output = Result<()>> + '_ {
+        #[cfg(any(test, feature = "testing"))]
+        let injected_failure = self.inner.fault_injector.store_write_failure();
+
         // if we have any listeners on the room_keys_received stream, broadcast any
         // updates to them
         if self.inner.room_keys_received_sender.receiver_count() > 0
@@ -445,7 +511,31 @@ impl Store {
             let _ = self.inner.room_keys_received_sender.send(updates);
         }
 
-        self.inner.store.save_changes(changes)
+        // Same as above, for the identities and the devices.
+        if self.inner.identities_sender.receiver_count() > 0 && !changes.identities.is_empty() {
+            let _ = self.inner.identities_sender.send(changes.identities.clone());
+        }
+
+        if self.inner.devices_sender.receiver_count() > 0 && !changes.devices.is_empty() {
+            let _ = self.inner.devices_sender.send(changes.devices.clone());
+        }
+
+        let future = self.inner.store.save_changes(changes);
+
+        async move {
+            #[cfg(any(test, feature = "testing"))]
+            if let Some(error) = injected_failure {
+                return Err(error);
+            }
+
+            future.await
+        }
+    }
+
+    /// The injector of the simulated failures.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn fault_injector(&self) -> &FaultInjector {
+        &self.inner.fault_injector
     }
 
     /// Compare the given `InboundGroupSession` with an existing session we have
@@ -943,6 +1033,31 @@ impl Store {
         self.set_value("only_allow_trusted_devices", &block_untrusted_devices).await
     }
 
+    /// Save the encryption settings of the given room.
+    pub async fn set_room_settings(&self, room_id: &RoomId, settings: RoomSettings) -> Result<()> {
+        let changes = Changes {
+            room_settings: HashMap::from([(room_id.to_owned(), settings)]),
+            ..Default::default()
+        };
+        self.save_changes(changes).await
+    }
+
+    /// Get the global [`TrustRequirement`] for the devices of the recipients
+    /// of the room keys.
+    pub async fn get_trust_requirement(&self) -> Result<TrustRequirement> {
+        let value = self.get_value("trust_requirement").await?.unwrap_or_default();
+        Ok(value)
+    }
+
+    /// Set the global [`TrustRequirement`] for the devices of the recipients
+    /// of the room keys.
+    ///
+    /// The strictest of this one and the one in the [`RoomSettings`] is used
+    /// when sharing a room key.
+    pub async fn set_trust_requirement(&self, trust_requirement: TrustRequirement) -> Result<()> {
+        self.set_value("trust_requirement", &trust_requirement).await
+    }
+
     /// Get custom stored value associated with a key
     pub async fn get_value<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
         let Some(value) = self.get_custom_value(key).await? else {
@@ -995,6 +1110,91 @@ impl Store {
             }
         })
     }
+
+    /// Notify the listeners of [`Store::decrypted_to_device_events_stream()`]
+    /// of a decrypted to-device event.
+    pub(crate) fn notify_decrypted_to_device_event(&self, event: DecryptedToDeviceEvent) {
+        // Ignore the result, it can only fail if there are no listeners.
+        let _ = self.inner.decrypted_to_device_sender.send(event);
+    }
+
+    /// Receive the to-device events that were received encrypted and that
+    /// aren't handled by the crypto crate as a [`Stream`].
+    ///
+    /// This allows to build custom protocols on top of encrypted to-device
+    /// events, since only the events in this stream are guaranteed to have
+    /// been sent by the device with the given Curve25519 key.
+    ///
+    /// If the reader of the stream lags too far behind, a warning will be
+    /// logged and items will be dropped.
+    pub fn decrypted_to_device_events_stream(&self) -> impl Stream<Item = DecryptedToDeviceEvent> {
+        let stream = BroadcastStream::new(self.inner.decrypted_to_device_sender.subscribe());
+
+        stream.filter_map(|result| async move {
+            match result {
+                Ok(event) => Some(event),
+                Err(BroadcastStreamRecvError::Lagged(lag)) => {
+                    warn!("decrypted_to_device_events_stream missed {lag} events");
+                    None
+                }
+            }
+        })
+    }
+
+    /// Receive notifications of user identities being received or updated as
+    /// a [`Stream`].
+    ///
+    /// Each time identities are saved to the store, for example after a
+    /// `/keys/query` response, the changes are sent to the stream. An
+    /// identity can be marked as changed even if its keys stayed the same.
+    ///
+    /// If the reader of the stream lags too far behind, a warning will be
+    /// logged and items will be dropped.
+    pub fn identities_stream(&self) -> impl Stream<Item = IdentityChanges> {
+        let stream = BroadcastStream::new(self.inner.identities_sender.subscribe());
+
+        stream.filter_map(|result| async move {
+            match result {
+                Ok(changes) => Some(changes),
+                Err(BroadcastStreamRecvError::Lagged(lag)) => {
+                    warn!("identities_stream missed {lag} updates");
+                    None
+                }
+            }
+        })
+    }
+
+    /// Receive notifications of devices being received, updated or deleted as
+    /// a [`Stream`].
+    ///
+    /// If the reader of the stream lags too far behind, a warning will be
+    /// logged and items will be dropped.
+    pub fn devices_stream(&self) -> impl Stream<Item = DeviceChanges> {
+        let stream = BroadcastStream::new(self.inner.devices_sender.subscribe());
+
+        stream.filter_map(|result| async move {
+            match result {
+                Ok(changes) => Some(changes),
+                Err(BroadcastStreamRecvError::Lagged(lag)) => {
+                    warn!("devices_stream missed {lag} updates");
+                    None
+                }
+            }
+        })
+    }
+
+    /// Create a lock on this store, shared with the other processes using
+    /// the same database.
+    ///
+    /// # Arguments
+    ///
+    /// * `lock_key` - The key of the lock in the store.
+    ///
+    /// * `lock_holder` - The value identifying this process as the holder of
+    ///   the lock.
+    pub fn create_store_lock(&self, lock_key: String, lock_holder: String) -> CryptoStoreLock {
+        CryptoStoreLock::new(self.inner.store.clone(), lock_key, lock_holder, None)
+    }
 }
 
 impl Deref for Store {
@@ -0,0 +1,133 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::{BackupKeys, Changes, CryptoStoreError, DeviceChanges, DynCryptoStore, RecoveryKey};
+use crate::{
+    olm::{
+        ExportedRoomKey, InboundGroupSession, PickledAccount, PickledCrossSigningIdentity,
+        PrivateCrossSigningIdentity, ReadOnlyAccount,
+    },
+    LocalTrust, ReadOnlyDevice,
+};
+
+/// The state of an [`OlmMachine`] that is needed to restore it on another
+/// machine, with the same device.
+///
+/// It contains the Olm account, the private cross-signing keys, the room keys
+/// and the backup keys. The Olm sessions aren't part of it, they are
+/// established again when they are needed.
+///
+/// The export contains private keys in the clear, so it should only be
+/// serialized to be encrypted, for example with
+/// [`encrypt_with_passphrase()`].
+///
+/// [`OlmMachine`]: crate::OlmMachine
+/// [`encrypt_with_passphrase()`]: crate::encrypt_with_passphrase
+#[derive(Serialize, Deserialize)]
+#[allow(missing_debug_implementations)]
+pub struct CryptoStateExport {
+    /// The pickled Olm account of the device.
+    pub account: PickledAccount,
+
+    /// The pickled private cross-signing identity of the user.
+    pub private_identity: PickledCrossSigningIdentity,
+
+    /// The room keys known by the device.
+    pub room_keys: Vec<ExportedRoomKey>,
+
+    /// The key used to decrypt the server-side backup of the room keys, if
+    /// any.
+    pub recovery_key: Option<RecoveryKey>,
+
+    /// The version of the server-side backup of the room keys, if any.
+    pub backup_version: Option<String>,
+}
+
+impl CryptoStateExport {
+    pub(crate) fn new(
+        account: PickledAccount,
+        private_identity: PickledCrossSigningIdentity,
+        room_keys: Vec<ExportedRoomKey>,
+        backup_keys: BackupKeys,
+    ) -> Self {
+        Self {
+            account,
+            private_identity,
+            room_keys,
+            recovery_key: backup_keys.recovery_key,
+            backup_version: backup_keys.backup_version,
+        }
+    }
+
+    /// Save this state into the given store.
+    ///
+    /// This must be done before an [`OlmMachine`] is created with the store,
+    /// which will then restore the device of the export.
+    ///
+    /// Returns an error if the store already contains an account for another
+    /// device.
+    ///
+    /// [`OlmMachine`]: crate::OlmMachine
+    pub async fn import_into(self, store: &DynCryptoStore) -> Result<(), CryptoStoreError> {
+        let account = ReadOnlyAccount::from_pickle(self.account)?;
+
+        if let Some(existing) = store.load_account().await? {
+            if existing.user_id() != account.user_id()
+                || existing.device_id() != account.device_id()
+            {
+                return Err(CryptoStoreError::MismatchedAccount {
+                    expected: (existing.user_id().to_owned(), existing.device_id().to_owned()),
+                    got: (account.user_id().to_owned(), account.device_id().to_owned()),
+                });
+            }
+        }
+
+        let private_identity = PrivateCrossSigningIdentity::from_pickle(self.private_identity)
+            .await
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        let inbound_group_sessions = self
+            .room_keys
+            .iter()
+            .map(InboundGroupSession::from_export)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // This is our own device, created from our own Olm account, so it can
+        // be marked as verified like when the account is created.
+        let device = ReadOnlyDevice::from_account(&account).await;
+        device.set_trust_state(LocalTrust::Verified);
+
+        debug!(
+            user_id = ?account.user_id(),
+            device_id = ?account.device_id(),
+            room_key_count = inbound_group_sessions.len(),
+            "Importing an exported crypto state"
+        );
+
+        let changes = Changes {
+            account: Some(account),
+            private_identity: Some(private_identity),
+            inbound_group_sessions,
+            recovery_key: self.recovery_key,
+            backup_version: self.backup_version,
+            devices: DeviceChanges { new: vec![device], ..Default::default() },
+            ..Default::default()
+        };
+
+        store.save_changes(changes).await
+    }
+}
@@ -96,6 +96,24 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>, Self::Error>;
 
+    /// Get a batch of the inbound group sessions we have stored.
+    ///
+    /// The sessions are returned in an order defined by the store, so all of
+    /// them can be loaded without keeping them in memory at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - The room ID and session ID of the last session of the
+    /// previous batch, or `None` to get the first batch.
+    ///
+    /// * `limit` - The maximum number of sessions to return. An empty batch is
+    /// returned once all the sessions were returned.
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(&RoomId, &str)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error>;
+
     /// Get the number inbound group sessions we have and how many of them are
     /// backed up.
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts, Self::Error>;
@@ -243,6 +261,13 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Returns a boolean indicating whether the value was actually present in
     /// the store.
     async fn remove_custom_value(&self, key: &str) -> Result<bool, Self::Error>;
+
+    /// Clear the values that the store caches in memory, so they are loaded
+    /// again from the database.
+    ///
+    /// This is needed when another process modified the database. The default
+    /// implementation does nothing, for the stores that don't cache anything.
+    async fn clear_caches(&self) {}
 }
 
 #[repr(transparent)]
@@ -291,6 +316,14 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.get_inbound_group_sessions().await.map_err(Into::into)
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(&RoomId, &str)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.0.get_inbound_group_sessions_batch(after, limit).await.map_err(Into::into)
+    }
+
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
         self.0.inbound_group_session_counts().await.map_err(Into::into)
     }
@@ -401,6 +434,10 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
     async fn remove_custom_value(&self, key: &str) -> Result<bool, Self::Error> {
         self.0.remove_custom_value(key).await.map_err(Into::into)
     }
+
+    async fn clear_caches(&self) {
+        self.0.clear_caches().await
+    }
 }
 
 /// A type-erased [`CryptoStore`].
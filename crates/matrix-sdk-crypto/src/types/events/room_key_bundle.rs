@@ -0,0 +1,130 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for the `io.element.msc4268.room_key_bundle` to-device events, as
+//! described in [MSC4268].
+//!
+//! A room key bundle is a set of room keys for a single room, encrypted and
+//! uploaded as a file. The key to decrypt the file is then sent over Olm to
+//! the devices of a user that was invited to the room, so they can read the
+//! history of the room.
+//!
+//! [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
+
+use std::collections::BTreeMap;
+
+use ruma::{events::room::EncryptedFile, OwnedRoomId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{EventType, ToDeviceEvent};
+use crate::olm::ExportedRoomKey;
+
+/// The `io.element.msc4268.room_key_bundle` to-device event.
+pub type RoomKeyBundleEvent = ToDeviceEvent<RoomKeyBundleContent>;
+
+/// The content of an `io.element.msc4268.room_key_bundle` event.
+///
+/// This event must only be accepted if it was sent encrypted over Olm.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomKeyBundleContent {
+    /// The room that the room keys of the bundle are for.
+    pub room_id: OwnedRoomId,
+
+    /// The encrypted file containing the serialized [`RoomKeyBundle`].
+    pub file: EncryptedFile,
+
+    /// Any other, custom and non-specced fields of the content.
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+impl RoomKeyBundleContent {
+    /// Create a new `io.element.msc4268.room_key_bundle` event content.
+    pub fn new(room_id: OwnedRoomId, file: EncryptedFile) -> Self {
+        Self { room_id, file, other: Default::default() }
+    }
+}
+
+impl EventType for RoomKeyBundleContent {
+    const EVENT_TYPE: &'static str = "io.element.msc4268.room_key_bundle";
+}
+
+/// The decrypted content of the file uploaded for a
+/// [`RoomKeyBundleContent`].
+#[derive(Deserialize, Serialize)]
+pub struct RoomKeyBundle {
+    /// The room that the room keys are for.
+    pub room_id: OwnedRoomId,
+
+    /// The room keys of the bundle.
+    pub room_keys: Vec<ExportedRoomKey>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl std::fmt::Debug for RoomKeyBundle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoomKeyBundle")
+            .field("room_id", &self.room_id)
+            .field("room_keys", &self.room_keys.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use serde_json::{json, Value};
+
+    use super::RoomKeyBundleEvent;
+
+    pub fn json() -> Value {
+        json!({
+            "sender": "@alice:example.org",
+            "content": {
+                "room_id": "!Cuyf34gef24t:localhost",
+                "file": {
+                    "url": "mxc://example.org/FHyPlCeYUSFFxlgbQYZmoEoe",
+                    "key": {
+                        "kty": "oct",
+                        "key_ops": ["encrypt", "decrypt"],
+                        "alg": "A256CTR",
+                        "k": "aWF6-32KGYaC3A_FEUCk1Bt0JA37zP0wrStgmdCaW-0",
+                        "ext": true
+                    },
+                    "iv": "w+sE15fzSc0AAAAAAAAAAA",
+                    "hashes": {
+                        "sha256": "fdSLu/YkRx3Wyh3KQabP3rd6+SFiKg5lsJZQHtkSAYA"
+                    },
+                    "v": "v2"
+                },
+                "m.custom": "something custom",
+            },
+            "type": "io.element.msc4268.room_key_bundle",
+            "m.custom.top": "something custom in the top",
+        })
+    }
+
+    #[test]
+    fn deserialization() -> Result<(), serde_json::Error> {
+        let json = json();
+        let event: RoomKeyBundleEvent = serde_json::from_value(json.clone())?;
+
+        assert_eq!(event.content.room_id, "!Cuyf34gef24t:localhost");
+
+        let serialized = serde_json::to_value(event)?;
+        assert_eq!(json, serialized);
+
+        Ok(())
+    }
+}
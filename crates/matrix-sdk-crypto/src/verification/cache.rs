@@ -133,6 +133,13 @@ impl VerificationCache {
         self.verification.get(sender)?.get(flow_id).map(|v| v.clone())
     }
 
+    pub fn verifications(&self) -> Vec<Verification> {
+        self.verification
+            .iter()
+            .flat_map(|v| v.value().iter().map(|v| v.value().clone()).collect::<Vec<_>>())
+            .collect()
+    }
+
     pub fn outgoing_requests(&self) -> Vec<OutgoingRequest> {
         self.outgoing_requests.iter().map(|r| (*r).clone()).collect()
     }
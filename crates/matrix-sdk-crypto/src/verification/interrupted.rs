@@ -0,0 +1,115 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Persistence of the verification flows that are in progress.
+//!
+//! The verification objects only live in memory, the ephemeral keys of a SAS
+//! verification are never persisted, so a flow can't be resumed if the process
+//! gets killed. We instead keep a small record of every flow in progress in the
+//! store, and cancel the flows that were interrupted on the next launch, so the
+//! other side isn't left waiting for a response that will never come.
+
+use std::time::Duration;
+
+use ruma::{
+    events::key::verification::cancel::CancelCode, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+    OwnedUserId,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{event_enums::OutgoingContent, Cancelled, FlowId, VerificationStore};
+use crate::CryptoStoreError;
+
+/// The key of the pending verifications in the custom values of the crypto
+/// store.
+const STORE_KEY: &str = "pending_verifications";
+
+/// How long an interrupted verification flow is worth cancelling.
+///
+/// The other side gives up on the flow after 10 minutes without a response, so
+/// there is no point in cancelling older flows.
+const PENDING_VERIFICATION_EXPIRY: Duration = Duration::from_secs(600);
+
+/// How far a verification flow in progress went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum PendingStage {
+    /// The verification was requested, but not accepted yet.
+    Requested,
+    /// The verification request was accepted.
+    Ready,
+    /// A concrete verification flow, like a SAS verification, was started.
+    Started,
+}
+
+/// A record of a verification flow in progress.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct PendingVerification {
+    /// The ID of the flow.
+    pub flow_id: FlowId,
+    /// The user on the other side of the flow.
+    pub other_user_id: OwnedUserId,
+    /// The device on the other side of the flow, `None` if it isn't known
+    /// yet.
+    pub other_device_id: Option<OwnedDeviceId>,
+    /// How far the flow went.
+    pub stage: PendingStage,
+    /// When the flow reached its current stage.
+    pub since: MilliSecondsSinceUnixEpoch,
+}
+
+impl PendingVerification {
+    /// Has the other side given up on this flow already?
+    pub fn is_expired(&self) -> bool {
+        let since = Duration::from_millis(self.since.get().into());
+        let now = Duration::from_millis(MilliSecondsSinceUnixEpoch::now().get().into());
+
+        now.saturating_sub(since) > PENDING_VERIFICATION_EXPIRY
+    }
+
+    /// The content cancelling this flow.
+    pub fn cancel_content(&self) -> OutgoingContent {
+        Cancelled::new(true, CancelCode::User).as_content(&self.flow_id)
+    }
+}
+
+impl VerificationStore {
+    /// Load the verification flows that were in progress the last time they
+    /// were saved.
+    pub(super) async fn load_pending_verifications(
+        &self,
+    ) -> Result<Vec<PendingVerification>, CryptoStoreError> {
+        let Some(value) = self.inner.get_custom_value(STORE_KEY).await? else {
+            return Ok(Vec::new());
+        };
+
+        rmp_serde::from_slice(&value).map_err(|e| CryptoStoreError::Backend(e.into()))
+    }
+
+    /// Replace the saved verification flows in progress with the given ones.
+    pub(super) async fn save_pending_verifications(
+        &self,
+        pending: &[PendingVerification],
+    ) -> Result<(), CryptoStoreError> {
+        if pending.is_empty() {
+            self.inner.remove_custom_value(STORE_KEY).await?;
+        } else {
+            let value = rmp_serde::to_vec_named(pending)
+                .map_err(|e| CryptoStoreError::Backend(e.into()))?;
+            self.inner.set_custom_value(STORE_KEY, value).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -24,6 +24,7 @@ use ruma::{
         ToDeviceEvent,
     },
     serde::Raw,
+    to_device::DeviceIdOrAllDevices,
     uint, DeviceId, EventId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId,
     SecondsSinceUnixEpoch, TransactionId, UInt, UserId,
 };
@@ -33,9 +34,10 @@ use tracing::{debug, info, instrument, trace, warn};
 use super::{
     cache::{RequestInfo, VerificationCache},
     event_enums::{AnyEvent, AnyVerificationContent, OutgoingContent},
+    interrupted::{PendingStage, PendingVerification},
     requests::VerificationRequest,
     sas::Sas,
-    FlowId, Verification, VerificationResult, VerificationStore,
+    FlowId, Verification, VerificationRequestState, VerificationResult, VerificationStore,
 };
 use crate::{
     olm::PrivateCrossSigningIdentity,
@@ -50,6 +52,9 @@ pub struct VerificationMachine {
     pub(crate) store: VerificationStore,
     verifications: VerificationCache,
     requests: Arc<DashMap<OwnedUserId, DashMap<String, VerificationRequest>>>,
+    /// The verification flows in progress, as they were last saved in the
+    /// store.
+    pending_verifications: Arc<Mutex<Vec<PendingVerification>>>,
 }
 
 impl VerificationMachine {
@@ -62,6 +67,7 @@ impl VerificationMachine {
             store: VerificationStore { account, private_identity: identity, inner: store },
             verifications: VerificationCache::new(),
             requests: Default::default(),
+            pending_verifications: Default::default(),
         }
     }
 
@@ -311,13 +317,165 @@ impl VerificationMachine {
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    /// Collect the verification flows that are currently in progress.
+    ///
+    /// The timestamp of the flows that didn't change stage since the
+    /// `previous` snapshot is kept.
+    fn collect_pending_verifications(
+        &self,
+        previous: &[PendingVerification],
+    ) -> Vec<PendingVerification> {
+        let mut pending = Vec::new();
+
+        let mut push = |flow_id: &FlowId,
+                        other_user_id: &UserId,
+                        other_device_id: Option<OwnedDeviceId>,
+                        stage: PendingStage| {
+            if pending.iter().any(|p: &PendingVerification| &p.flow_id == flow_id) {
+                return;
+            }
+
+            let since = previous
+                .iter()
+                .find(|p| &p.flow_id == flow_id && p.stage == stage)
+                .map(|p| p.since)
+                .unwrap_or_else(MilliSecondsSinceUnixEpoch::now);
+
+            pending.push(PendingVerification {
+                flow_id: flow_id.to_owned(),
+                other_user_id: other_user_id.to_owned(),
+                other_device_id,
+                stage,
+                since,
+            });
+        };
+
+        for user_requests in self.requests.iter() {
+            for request in user_requests.value().iter() {
+                if request.is_done() || request.is_cancelled() || request.is_passive() {
+                    continue;
+                }
+
+                let (other_device_id, stage) = match request.state() {
+                    VerificationRequestState::Created { .. } => (None, PendingStage::Requested),
+                    VerificationRequestState::Requested { other_device_id, .. } => {
+                        (Some(other_device_id), PendingStage::Requested)
+                    }
+                    VerificationRequestState::Ready { other_device_id, .. } => {
+                        (Some(other_device_id), PendingStage::Ready)
+                    }
+                    VerificationRequestState::Transitioned { verification } => {
+                        (Some(verification.other_device_id().to_owned()), PendingStage::Started)
+                    }
+                    VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => {
+                        continue
+                    }
+                };
+
+                push(request.flow_id(), request.other_user(), other_device_id, stage);
+            }
+        }
+
+        // Verifications can be started without a request, e.g. with
+        // `start_sas()`.
+        for verification in self.verifications.verifications() {
+            if verification.is_done() || verification.is_cancelled() {
+                continue;
+            }
+
+            push(
+                verification.full_flow_id(),
+                verification.other_user(),
+                Some(verification.other_device_id().to_owned()),
+                PendingStage::Started,
+            );
+        }
+
+        pending
+    }
+
+    /// Save the verification flows that are currently in progress in the
+    /// store, so they can be cancelled if the process gets interrupted.
+    pub async fn save_pending_verifications(&self) -> Result<(), CryptoStoreError> {
+        let mut saved = self.pending_verifications.lock().await;
+        let pending = self.collect_pending_verifications(&saved);
+
+        if pending != *saved {
+            self.store.save_pending_verifications(&pending).await?;
+            *saved = pending;
+        }
+
+        Ok(())
+    }
+
+    /// Cancel the verification flows that were in progress when the process
+    /// was interrupted.
+    ///
+    /// The verification objects only live in memory so these flows can't be
+    /// resumed, the cancellations are queued up so the other side doesn't
+    /// wait for a response that will never come. Flows that the other side
+    /// has already given up on are dropped silently.
+    pub async fn cancel_interrupted_verifications(&self) -> Result<(), CryptoStoreError> {
+        let mut saved = self.pending_verifications.lock().await;
+        let interrupted = self.store.load_pending_verifications().await?;
+
+        for pending in interrupted {
+            if pending.is_expired() {
+                debug!(
+                    flow_id = pending.flow_id.as_str(),
+                    "Dropping an expired interrupted verification"
+                );
+                continue;
+            }
+
+            info!(
+                flow_id = pending.flow_id.as_str(),
+                other_user_id = pending.other_user_id.as_str(),
+                stage = ?pending.stage,
+                "Cancelling a verification that was interrupted"
+            );
+
+            let request = match pending.cancel_content() {
+                OutgoingContent::Room(room_id, content) => {
+                    RoomMessageRequest { room_id, txn_id: TransactionId::new(), content }.into()
+                }
+                OutgoingContent::ToDevice(content) => {
+                    let recipient = pending
+                        .other_device_id
+                        .map(DeviceIdOrAllDevices::DeviceId)
+                        .unwrap_or(DeviceIdOrAllDevices::AllDevices);
+
+                    ToDeviceRequest::with_id(
+                        &pending.other_user_id,
+                        recipient,
+                        &content,
+                        TransactionId::new(),
+                    )
+                    .into()
+                }
+            };
+
+            self.verifications.add_verification_request(request);
+        }
+
+        self.store.save_pending_verifications(&[]).await?;
+        saved.clear();
+
+        Ok(())
+    }
+
+    /// Receive a verification event and save the verification flows that are
+    /// in progress afterwards.
     pub async fn receive_any_event(
         &self,
         event: impl Into<AnyEvent<'_>>,
     ) -> Result<(), CryptoStoreError> {
-        let event = event.into();
+        self.handle_any_event(event.into()).await?;
+        self.save_pending_verifications().await
+    }
 
+    #[instrument(skip_all)]
+    async fn handle_any_event(&self, event: AnyEvent<'_>) -> Result<(), CryptoStoreError> {
         let Ok(flow_id) = FlowId::try_from(&event) else {
             // This isn't a verification event, return early.
             return Ok(());
@@ -527,8 +685,9 @@ impl VerificationMachine {
 mod tests {
     use std::sync::Arc;
 
+    use assert_matches::assert_matches;
     use matrix_sdk_test::async_test;
-    use ruma::TransactionId;
+    use ruma::{events::AnyToDeviceEventContent, TransactionId};
     use tokio::sync::Mutex;
 
     use super::{Sas, VerificationMachine};
@@ -552,6 +711,7 @@ mod tests {
             store,
             verifications: VerificationCache::new(),
             requests: Default::default(),
+            pending_verifications: Default::default(),
         };
 
         (machine, bob_store)
@@ -637,6 +797,37 @@ mod tests {
         assert!(bob.is_done());
     }
 
+    #[async_test]
+    async fn interrupted_verification_is_cancelled() {
+        let (machine, bob) = setup_verification_machine().await;
+
+        // Simulate a restart, only the store survives.
+        let restart = || VerificationMachine {
+            store: machine.store.clone(),
+            verifications: VerificationCache::new(),
+            requests: Default::default(),
+            pending_verifications: Default::default(),
+        };
+
+        let restarted = restart();
+        restarted.cancel_interrupted_verifications().await.unwrap();
+
+        let requests = restarted.outgoing_messages();
+        assert_eq!(requests.len(), 1);
+        let content = OutgoingContent::try_from(requests[0].clone()).unwrap();
+        assert_matches!(
+            content,
+            OutgoingContent::ToDevice(AnyToDeviceEventContent::KeyVerificationCancel(c)) => {
+                assert_eq!(c.transaction_id.as_str(), bob.flow_id().as_str());
+            }
+        );
+
+        // The flow is only cancelled once.
+        let restarted = restart();
+        restarted.cancel_interrupted_verifications().await.unwrap();
+        assert!(restarted.outgoing_messages().is_empty());
+    }
+
     #[cfg(not(target_os = "macos"))]
     #[allow(unknown_lints, clippy::unchecked_duration_subtraction)]
     #[async_test]
@@ -14,6 +14,7 @@
 
 mod cache;
 mod event_enums;
+mod interrupted;
 mod machine;
 #[cfg(feature = "qrcode")]
 mod qrcode;
@@ -45,6 +46,7 @@ use ruma::{
     UserId,
 };
 pub use sas::{AcceptSettings, AcceptedProtocols, EmojiShortAuthString, Sas, SasState};
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use tracing::{error, info, trace, warn};
 
@@ -236,6 +238,14 @@ impl Verification {
         }
     }
 
+    fn full_flow_id(&self) -> &FlowId {
+        match self {
+            Verification::SasV1(s) => s.flow_id(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.flow_id(),
+        }
+    }
+
     /// Has the verification been cancelled.
     pub fn is_cancelled(&self) -> bool {
         match self {
@@ -263,6 +273,14 @@ impl Verification {
         }
     }
 
+    fn other_device_id(&self) -> &DeviceId {
+        match self {
+            Verification::SasV1(s) => s.other_device_id(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.other_device_id(),
+        }
+    }
+
     /// Is this a verification verifying a device that belongs to us.
     pub fn is_self_verification(&self) -> bool {
         match self {
@@ -417,7 +435,7 @@ impl Cancelled {
 /// A key verification can be requested and started by a to-device
 /// request or a room event. `FlowId` helps to represent both
 /// usecases.
-#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Serialize, Deserialize)]
 pub enum FlowId {
     /// The flow ID comes from a to-device request.
     ToDevice(OwnedTransactionId),
@@ -865,7 +883,7 @@ mod test {
         let alice_changes = Changes {
             identities: IdentityChanges {
                 new: vec![alice_readonly_identity.into(), bob_public_identity.into()],
-                changed: vec![],
+                ..Default::default()
             },
             ..Default::default()
         };
@@ -875,7 +893,7 @@ mod test {
         let bob_changes = Changes {
             identities: IdentityChanges {
                 new: vec![bob_readonly_identity.into(), alice_public_identity.into()],
-                changed: vec![],
+                ..Default::default()
             },
             ..Default::default()
         };
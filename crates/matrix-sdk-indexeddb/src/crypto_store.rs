@@ -793,6 +793,40 @@ impl_crypto_store! {
             .collect())
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(&RoomId, &str)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let range = match after {
+            Some(after) => IdbKeyRange::lower_bound_with_open(
+                &self.encode_key(keys::INBOUND_GROUP_SESSIONS, after),
+                true,
+            ),
+            None => IdbKeyRange::lower_bound(&JsValue::from_str("")),
+        }
+        .map_err(|e| IndexeddbCryptoStoreError::DomException {
+            code: 0,
+            name: "IdbKeyRangeMakeError".to_owned(),
+            message: format!("{e:?}"),
+        })?;
+        let limit = u32::try_from(limit).unwrap_or(u32::MAX);
+
+        Ok(self
+            .inner
+            .transaction_on_one_with_mode(
+                keys::INBOUND_GROUP_SESSIONS,
+                IdbTransactionMode::Readonly,
+            )?
+            .object_store(keys::INBOUND_GROUP_SESSIONS)?
+            .get_all_with_key_and_limit(&range, limit)?
+            .await?
+            .iter()
+            .filter_map(|i| self.deserialize_value(i).ok())
+            .filter_map(|p| InboundGroupSession::from_pickle(p).ok())
+            .collect())
+    }
+
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
         let all = self.get_inbound_group_sessions().await?;
         let backed_up = all.iter().filter(|s| s.backed_up()).count();
@@ -1090,6 +1124,10 @@ impl_crypto_store! {
             Ok(false)
         }
     }
+
+    async fn clear_caches(&self) {
+        self.session_cache.clear();
+    }
 }
 
 impl Drop for IndexeddbCryptoStore {
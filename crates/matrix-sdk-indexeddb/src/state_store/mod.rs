@@ -383,6 +383,9 @@ impl IndexeddbStateStore {
             StateStoreDataKey::UserAvatarUrl(user_id) => {
                 self.encode_key(keys::KV, (StateStoreDataKey::USER_AVATAR_URL, user_id))
             }
+            StateStoreDataKey::SentEvent(txn_id) => {
+                self.encode_key(keys::KV, (StateStoreDataKey::SENT_EVENT, txn_id))
+            }
         }
     }
 }
@@ -425,17 +428,25 @@ impl_state_store!({
             .transaction_on_one_with_mode(keys::KV, IdbTransactionMode::Readonly)?
             .object_store(keys::KV)?
             .get(&encoded_key)?
-            .await?
-            .map(|f| self.deserialize_event::<String>(&f))
-            .transpose()?;
+            .await?;
+        let Some(value) = value else { return Ok(None) };
 
         let value = match key {
-            StateStoreDataKey::SyncToken => value.map(StateStoreDataValue::SyncToken),
-            StateStoreDataKey::Filter(_) => value.map(StateStoreDataValue::Filter),
-            StateStoreDataKey::UserAvatarUrl(_) => value.map(StateStoreDataValue::UserAvatarUrl),
+            StateStoreDataKey::SyncToken => {
+                StateStoreDataValue::SyncToken(self.deserialize_event(&value)?)
+            }
+            StateStoreDataKey::Filter(_) => {
+                StateStoreDataValue::Filter(self.deserialize_event(&value)?)
+            }
+            StateStoreDataKey::UserAvatarUrl(_) => {
+                StateStoreDataValue::UserAvatarUrl(self.deserialize_event(&value)?)
+            }
+            StateStoreDataKey::SentEvent(_) => {
+                StateStoreDataValue::SentEvent(self.deserialize_event(&value)?)
+            }
         };
 
-        Ok(value)
+        Ok(Some(value))
     }
 
     async fn set_kv_data(
@@ -453,6 +464,9 @@ impl_state_store!({
             StateStoreDataKey::UserAvatarUrl(_) => {
                 value.into_user_avatar_url().expect("Session data not an user avatar url")
             }
+            StateStoreDataKey::SentEvent(_) => {
+                value.into_sent_event().expect("Session data not a sent event").to_string()
+            }
         };
 
         let tx =
@@ -490,6 +504,7 @@ impl_state_store!({
             (!changes.profiles.is_empty(), keys::PROFILES),
             (!changes.room_account_data.is_empty(), keys::ROOM_ACCOUNT_DATA),
             (!changes.receipts.is_empty(), keys::ROOM_EVENT_RECEIPTS),
+            (!changes.custom.is_empty(), keys::CUSTOM),
         ]
         .iter()
         .filter_map(|(id, key)| if *id { Some(*key) } else { None })
@@ -558,6 +573,17 @@ impl_state_store!({
             }
         }
 
+        if !changes.custom.is_empty() {
+            let store = tx.object_store(keys::CUSTOM)?;
+            for (key, value) in &changes.custom {
+                let key = JsValue::from_str(core::str::from_utf8(key).map_err(StoreError::Codec)?);
+                match value {
+                    Some(value) => store.put_key_val(&key, &self.serialize_event(value)?)?,
+                    None => store.delete(&key)?,
+                };
+            }
+        }
+
         if !changes.room_account_data.is_empty() {
             let store = tx.object_store(keys::ROOM_ACCOUNT_DATA)?;
             for (room, events) in &changes.room_account_data {
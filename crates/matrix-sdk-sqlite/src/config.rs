@@ -0,0 +1,242 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{path::Path, time::Duration};
+
+use deadpool_sqlite::{
+    CreatePoolError, Hook, HookError, HookErrorCause, Pool as SqlitePool, PoolConfig, Runtime,
+};
+use tokio::fs;
+
+use crate::OpenStoreError;
+
+/// The journal mode of a SQLite database.
+///
+/// See the [SQLite documentation](https://www.sqlite.org/pragma.html#pragma_journal_mode)
+/// for the details of each mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum JournalMode {
+    /// Use a write-ahead log, readers don't block writers and vice versa.
+    #[default]
+    Wal,
+    /// Delete the rollback journal at the end of each transaction.
+    Delete,
+    /// Truncate the rollback journal at the end of each transaction, which is
+    /// faster than deleting it on many filesystems.
+    Truncate,
+    /// Keep the rollback journal in memory, the database might get corrupted
+    /// if the process crashes in the middle of a transaction.
+    Memory,
+}
+
+impl JournalMode {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Self::Wal => "wal",
+            Self::Delete => "delete",
+            Self::Truncate => "truncate",
+            Self::Memory => "memory",
+        }
+    }
+}
+
+/// How often SQLite waits for the data to be written to the disk.
+///
+/// See the [SQLite documentation](https://www.sqlite.org/pragma.html#pragma_synchronous)
+/// for the details of each level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Synchronous {
+    /// Never wait, the database might get corrupted if the device loses power.
+    Off,
+    /// Wait at the most critical moments, in WAL mode the last transactions
+    /// might be rolled back if the device loses power.
+    Normal,
+    /// Wait at every transaction.
+    #[default]
+    Full,
+    /// Like [`Synchronous::Full`], and also wait after deleting the rollback
+    /// journal.
+    Extra,
+}
+
+impl Synchronous {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Normal => "normal",
+            Self::Full => "full",
+            Self::Extra => "extra",
+        }
+    }
+}
+
+/// The settings of the SQLite databases of a store.
+///
+/// The defaults are suited for most workloads, they can be tuned to trade
+/// durability for speed on slow flash storage, or to allow more concurrent
+/// connections on servers.
+///
+/// # Example
+///
+/// ```no_run
+/// # async {
+/// use std::time::Duration;
+///
+/// use matrix_sdk_sqlite::{SqliteStateStore, SqliteStoreConfig, Synchronous};
+///
+/// let config = SqliteStoreConfig::new()
+///     .synchronous(Synchronous::Normal)
+///     .busy_timeout(Duration::from_secs(10));
+/// let store =
+///     SqliteStateStore::open_with_config("/tmp/store", None, config).await?;
+/// # Ok::<_, matrix_sdk_sqlite::OpenStoreError>(()) };
+/// ```
+#[derive(Clone, Debug)]
+pub struct SqliteStoreConfig {
+    pool_size: Option<usize>,
+    journal_mode: JournalMode,
+    synchronous: Synchronous,
+    busy_timeout: Duration,
+    cache_size: Option<u32>,
+}
+
+impl SqliteStoreConfig {
+    /// Create a new `SqliteStoreConfig` with the default settings.
+    pub fn new() -> Self {
+        Self {
+            pool_size: None,
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            busy_timeout: Duration::from_secs(5),
+            cache_size: None,
+        }
+    }
+
+    /// Set the maximum number of connections to the database.
+    ///
+    /// Defaults to four times the number of CPUs.
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = Some(pool_size);
+        self
+    }
+
+    /// Set the journal mode of the database.
+    ///
+    /// Defaults to [`JournalMode::Wal`].
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> Self {
+        self.journal_mode = journal_mode;
+        self
+    }
+
+    /// Set how often SQLite waits for the data to be written to the disk.
+    ///
+    /// Defaults to [`Synchronous::Full`].
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// Set how long to wait for a lock held by another connection, before
+    /// failing with a "database is locked" error.
+    ///
+    /// Defaults to 5 seconds.
+    pub fn busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// Set the maximum size of the page cache of each connection, in KiB.
+    ///
+    /// Defaults to the default of SQLite, 2000 KiB.
+    pub fn cache_size(mut self, kib: u32) -> Self {
+        self.cache_size = Some(kib);
+        self
+    }
+
+    pub(crate) fn journal_mode_pragma(&self) -> String {
+        format!("PRAGMA journal_mode = {};", self.journal_mode.as_sql())
+    }
+
+    /// The pragmas applied to every new connection.
+    fn pragmas(&self) -> String {
+        let mut pragmas = format!(
+            "{} PRAGMA synchronous = {}; PRAGMA busy_timeout = {};",
+            self.journal_mode_pragma(),
+            self.synchronous.as_sql(),
+            self.busy_timeout.as_millis(),
+        );
+
+        if let Some(kib) = self.cache_size {
+            // A negative value is a size in KiB, a positive one a number of pages.
+            pragmas.push_str(&format!(" PRAGMA cache_size = -{kib};"));
+        }
+
+        pragmas
+    }
+
+    /// Create a pool of connections to the database in the given file,
+    /// creating its parent directory if necessary.
+    pub(crate) async fn create_pool(&self, path: &Path) -> Result<SqlitePool, OpenStoreError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await.map_err(OpenStoreError::CreateDir)?;
+        }
+
+        let mut cfg = deadpool_sqlite::Config::new(path);
+        cfg.pool = self.pool_size.map(PoolConfig::new);
+
+        let pragmas = self.pragmas();
+        let post_create = Hook::async_fn(move |conn, _| {
+            let pragmas = pragmas.clone();
+            Box::pin(async move {
+                conn.interact(move |conn| conn.execute_batch(&pragmas))
+                    .await
+                    .map_err(|e| HookError::Abort(HookErrorCause::Message(e.to_string())))?
+                    .map_err(|e| HookError::Abort(HookErrorCause::Backend(e)))
+            })
+        });
+
+        Ok(cfg
+            .builder(Runtime::Tokio1)
+            .map_err(CreatePoolError::Config)?
+            .post_create(post_create)
+            .build()
+            .map_err(CreatePoolError::Build)?)
+    }
+}
+
+impl Default for SqliteStoreConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The size of the files of a SQLite database, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DatabaseSize {
+    /// The size of the main database file.
+    pub database: u64,
+    /// The size of the write-ahead log, `0` if the database isn't in
+    /// [`JournalMode::Wal`].
+    pub wal: u64,
+}
+
+impl DatabaseSize {
+    /// The total size of the files of the database.
+    pub fn total(&self) -> u64 {
+        self.database + self.wal
+    }
+}
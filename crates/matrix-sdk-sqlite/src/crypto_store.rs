@@ -18,6 +18,7 @@ use std::{
     fmt,
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use async_trait::async_trait;
@@ -27,27 +28,35 @@ use matrix_sdk_crypto::{
         IdentityKeys, InboundGroupSession, OutboundGroupSession, PickledInboundGroupSession,
         PrivateCrossSigningIdentity, Session,
     },
-    store::{caches::SessionStore, BackupKeys, Changes, CryptoStore, RoomKeyCounts, RoomSettings},
+    store::{
+        caches::SessionStore,
+        migrations::{Migration, MigrationBackend, MigrationProgress, Migrator},
+        BackupKeys, Changes, CryptoStore, CryptoStoreError, RoomKeyCounts, RoomSettings,
+    },
     types::events::room_key_withheld::RoomKeyWithheldEvent,
     GossipRequest, ReadOnlyAccount, ReadOnlyDevice, ReadOnlyUserIdentities, SecretInfo,
     TrackedUser,
 };
 use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId};
-use rusqlite::OptionalExtension;
+use rusqlite::{backup::Backup, OpenFlags, OptionalExtension};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::{fs, sync::Mutex};
+use tokio::sync::Mutex;
 use tracing::{debug, instrument, warn};
 
 use crate::{
     error::{Error, Result},
     get_or_create_store_cipher,
     utils::{
-        load_db_version, Key, SqliteConnectionExt as _, SqliteObjectExt, SqliteObjectStoreExt as _,
+        blob_ref, load_db_size, load_db_version, Key, SqliteConnectionExt as _, SqliteObjectExt,
+        SqliteObjectStoreExt as _,
     },
-    OpenStoreError,
+    DatabaseSize, OpenStoreError, SqliteStoreConfig,
 };
 
+/// The name of the database file, in the directory of the store.
+pub(crate) const DATABASE_NAME: &str = "matrix-sdk-crypto.sqlite3";
+
 #[derive(Clone, Debug)]
 pub struct AccountInfo {
     user_id: OwnedUserId,
@@ -85,12 +94,21 @@ impl SqliteCryptoStore {
         path: impl AsRef<Path>,
         passphrase: Option<&str>,
     ) -> Result<Self, OpenStoreError> {
-        let path = path.as_ref();
-        fs::create_dir_all(path).await.map_err(OpenStoreError::CreateDir)?;
-        let cfg = deadpool_sqlite::Config::new(path.join("matrix-sdk-crypto.sqlite3"));
-        let pool = cfg.create_pool(Runtime::Tokio1)?;
+        Self::open_with_config(path, passphrase, SqliteStoreConfig::default()).await
+    }
 
-        Self::open_with_pool(pool, passphrase).await
+    /// Open the sqlite-based crypto store at the given path using the given
+    /// passphrase to encrypt private data, with the given settings for the
+    /// database.
+    pub async fn open_with_config(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        config: SqliteStoreConfig,
+    ) -> Result<Self, OpenStoreError> {
+        let pool = Self::create_pool(path.as_ref(), &config).await?;
+        let migrator = Migrator::new(MIGRATIONS);
+
+        Self::open_with_pool_and_migrator(pool, passphrase, migrator, &config).await
     }
 
     /// Create a sqlite-based crypto store using the given sqlite database pool.
@@ -98,10 +116,78 @@ impl SqliteCryptoStore {
     pub async fn open_with_pool(
         pool: SqlitePool,
         passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        let migrator = Migrator::new(MIGRATIONS);
+
+        Self::open_with_pool_and_migrator(pool, passphrase, migrator, &SqliteStoreConfig::default())
+            .await
+    }
+
+    /// Open the sqlite-based crypto store at the given path using the given
+    /// passphrase to encrypt private data, calling `progress_listener` while
+    /// the database is migrated to the latest schema version.
+    ///
+    /// Migrating a large store can take a while, this allows to give some
+    /// feedback to the user in the meantime.
+    pub async fn open_with_migration_progress(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        progress_listener: impl Fn(MigrationProgress) + Send + Sync + 'static,
+    ) -> Result<Self, OpenStoreError> {
+        let config = SqliteStoreConfig::default();
+        let pool = Self::create_pool(path.as_ref(), &config).await?;
+        let migrator = Migrator::new(MIGRATIONS).with_progress_listener(progress_listener);
+
+        Self::open_with_pool_and_migrator(pool, passphrase, migrator, &config).await
+    }
+
+    /// Check that the sqlite-based crypto store at the given path can be
+    /// migrated to the latest schema version, without modifying it.
+    ///
+    /// The database is opened read-only, and the migrations are tried on an
+    /// in-memory copy of it. This fails if there is no store at the given
+    /// path.
+    ///
+    /// Returns the migrations that would be applied when opening the store.
+    pub async fn dry_run_migrations(
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<Migration>, OpenStoreError> {
+        let path = path.as_ref().join(DATABASE_NAME);
+        let config = SqliteStoreConfig::default();
+
+        let pool = deadpool_sqlite::Config::new(":memory:").create_pool(Runtime::Tokio1)?;
+        let conn = pool.get().await?;
+        conn.interact(move |conn| {
+            let source = rusqlite::Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            Backup::new(&source, conn)?.run_to_completion(-1, Duration::ZERO, None)
+        })
+        .await
+        .unwrap()
+        .map_err(OpenStoreError::OpenDatabase)?;
+
+        let backend = SqliteMigrationBackend { conn: &conn, config: &config };
+        let pending = Migrator::new(MIGRATIONS).dry_run(&backend).await?;
+        Ok(pending.to_vec())
+    }
+
+    async fn create_pool(
+        path: &Path,
+        config: &SqliteStoreConfig,
+    ) -> Result<SqlitePool, OpenStoreError> {
+        config.create_pool(&path.join(DATABASE_NAME)).await
+    }
+
+    async fn open_with_pool_and_migrator(
+        pool: SqlitePool,
+        passphrase: Option<&str>,
+        migrator: Migrator,
+        config: &SqliteStoreConfig,
     ) -> Result<Self, OpenStoreError> {
         let conn = pool.get().await?;
-        let version = load_db_version(&conn).await?;
-        run_migrations(&conn, version).await?;
+        run_migrations(&conn, &migrator, config).await?;
         let store_cipher = match passphrase {
             Some(p) => Some(Arc::new(get_or_create_store_cipher(p, &conn).await?)),
             None => None,
@@ -191,71 +277,117 @@ impl SqliteCryptoStore {
     async fn acquire(&self) -> Result<deadpool_sqlite::Object> {
         Ok(self.pool.get().await?)
     }
-}
 
-const DATABASE_VERSION: u8 = 6;
-
-/// Run migrations for the given version of the database.
-async fn run_migrations(conn: &SqliteConn, version: u8) -> Result<()> {
-    if version == 0 {
-        debug!("Creating database");
-    } else if version < DATABASE_VERSION {
-        debug!(version, new_version = DATABASE_VERSION, "Upgrading database");
-    } else {
-        return Ok(());
+    /// Rebuild the database file, to give the space left by deleted data back
+    /// to the filesystem.
+    ///
+    /// This can take a while on large databases, and needs up to twice the
+    /// size of the database in free disk space.
+    pub async fn vacuum(&self) -> Result<(), CryptoStoreError> {
+        self.acquire().await?.execute_batch("VACUUM").await.map_err(Error::from)?;
+        Ok(())
     }
 
-    if version < 1 {
-        // First turn on WAL mode, this can't be done in the transaction, it fails with
-        // the error message: "cannot change into wal mode from within a transaction".
-        conn.execute_batch("PRAGMA journal_mode = wal;").await?;
-        conn.with_transaction(|txn| {
-            txn.execute_batch(include_str!("../migrations/crypto_store/001_init.sql"))
-        })
-        .await?;
+    /// Let SQLite update the statistics used by its query planner.
+    ///
+    /// This is cheap and can be called periodically, e.g. when the app goes to
+    /// the background.
+    pub async fn optimize(&self) -> Result<(), CryptoStoreError> {
+        self.acquire().await?.execute_batch("PRAGMA optimize").await.map_err(Error::from)?;
+        Ok(())
     }
 
-    if version < 2 {
-        conn.with_transaction(|txn| {
-            txn.execute_batch(include_str!("../migrations/crypto_store/002_reset_olm_hash.sql"))
-        })
-        .await?;
+    /// Get the size of the files of the database.
+    pub async fn database_size(&self) -> Result<DatabaseSize, CryptoStoreError> {
+        Ok(load_db_size(&self.acquire().await?).await?)
     }
+}
 
-    if version < 3 {
-        conn.with_transaction(|txn| {
-            txn.execute_batch(include_str!("../migrations/crypto_store/003_room_settings.sql"))
-        })
-        .await?;
-    }
+/// The migrations of the crypto store's schema, the last one being the current
+/// version of the database.
+static MIGRATIONS: &[Migration] = &[
+    Migration::new(1, "init"),
+    Migration::new(2, "reset olm hash"),
+    Migration::new(3, "room settings"),
+    Migration::new(4, "drop outbound group sessions"),
+    Migration::new(5, "withheld code"),
+    Migration::new(6, "drop outbound group sessions"),
+];
+
+/// Get the SQL script of the migration to the given version.
+fn migration_sql(version: u8) -> Result<&'static str> {
+    Ok(match version {
+        1 => include_str!("../migrations/crypto_store/001_init.sql"),
+        2 => include_str!("../migrations/crypto_store/002_reset_olm_hash.sql"),
+        3 => include_str!("../migrations/crypto_store/003_room_settings.sql"),
+        4 => include_str!("../migrations/crypto_store/004_drop_outbound_group_sessions.sql"),
+        5 => include_str!("../migrations/crypto_store/005_withheld_code.sql"),
+        6 => include_str!("../migrations/crypto_store/006_drop_outbound_group_sessions.sql"),
+        _ => return Err(Error::UnsupportedVersion(version)),
+    })
+}
 
-    if version < 4 {
-        conn.with_transaction(|txn| {
-            txn.execute_batch(include_str!(
-                "../migrations/crypto_store/004_drop_outbound_group_sessions.sql"
-            ))
-        })
-        .await?;
-    }
+/// The sqlite implementation of the crypto store migrations.
+struct SqliteMigrationBackend<'a> {
+    conn: &'a SqliteConn,
+    config: &'a SqliteStoreConfig,
+}
 
-    if version < 5 {
-        conn.with_transaction(|txn| {
-            txn.execute_batch(include_str!("../migrations/crypto_store/005_withheld_code.sql"))
+#[async_trait]
+impl MigrationBackend for SqliteMigrationBackend<'_> {
+    type Error = Error;
+
+    async fn load_version(&self) -> Result<u8> {
+        load_db_version(self.conn).await.map_err(|e| match e {
+            OpenStoreError::LoadVersion(e) => Error::Sqlite(e),
+            _ => Error::InvalidVersion,
         })
-        .await?;
     }
 
-    if version < 6 {
-        conn.with_transaction(|txn| {
-            txn.execute_batch(include_str!(
-                "../migrations/crypto_store/006_drop_outbound_group_sessions.sql"
-            ))
-        })
-        .await?;
+    async fn apply(&self, migration: &Migration) -> Result<()> {
+        let version = migration.version;
+
+        if version == 1 {
+            // First set the journal mode, this can't be done in the transaction, it fails
+            // with the error message: "cannot change into wal mode from within a
+            // transaction".
+            self.conn.execute_batch(self.config.journal_mode_pragma()).await?;
+        }
+
+        // The new version is stored in the same transaction as the migration, so the
+        // database is never left in a half-migrated state.
+        self.conn
+            .with_transaction(move |txn| {
+                txn.execute_batch(migration_sql(version)?)?;
+                txn.set_kv("version", &[version])?;
+                Ok::<_, Error>(())
+            })
+            .await?;
+
+        Ok(())
     }
 
-    conn.set_kv("version", vec![DATABASE_VERSION]).await?;
+    async fn dry_run(&self, migrations: &[Migration]) -> Result<()> {
+        let versions: Vec<_> = migrations.iter().map(|m| m.version).collect();
+
+        self.conn
+            .with_rolled_back_transaction(move |txn| {
+                for version in versions {
+                    txn.execute_batch(migration_sql(version)?)?;
+                }
+                Ok::<_, Error>(())
+            })
+            .await
+    }
+}
 
+/// Run the pending migrations on the database.
+async fn run_migrations(
+    conn: &SqliteConn,
+    migrator: &Migrator,
+    config: &SqliteStoreConfig,
+) -> Result<(), OpenStoreError> {
+    migrator.run(&SqliteMigrationBackend { conn, config }).await?;
     Ok(())
 }
 
@@ -419,26 +551,43 @@ impl SqliteConnectionExt for rusqlite::Connection {
 
 #[async_trait]
 trait SqliteObjectCryptoStoreExt: SqliteObjectExt {
-    async fn get_sessions_for_sender_key(&self, sender_key: Key) -> Result<Vec<Vec<u8>>> {
-        Ok(self
-            .prepare("SELECT data FROM session WHERE sender_key = ?", |mut stmt| {
-                stmt.query((sender_key,))?.mapped(|row| row.get(0)).collect()
-            })
-            .await?)
-    }
-
-    async fn get_inbound_group_session(
-        &self,
-        session_id: Key,
-    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
-        Ok(self
-            .query_row(
-                "SELECT room_id, data FROM inbound_group_session WHERE session_id = ?",
-                (session_id,),
-                |row| Ok((row.get(0)?, row.get(1)?)),
-            )
-            .await
-            .optional()?)
+    /// Get the sessions of the given sender key, converted with `f`.
+    ///
+    /// These lookups are on the hot path of the decryption of to-device
+    /// messages, so `f` is called with the data borrowed from the row, on the
+    /// connection's thread, to avoid copying the pickles.
+    async fn get_sessions_for_sender_key<T, F>(&self, sender_key: Key, f: F) -> Result<Vec<T>>
+    where
+        T: Send + 'static,
+        F: Fn(&[u8]) -> Result<T> + Send + 'static,
+    {
+        self.prepare_cached("SELECT data FROM session WHERE sender_key = ?", move |mut stmt| {
+            stmt.query((sender_key,))?.mapped(|row| Ok(f(blob_ref(row, 0)?))).collect()
+        })
+        .await?
+        .into_iter()
+        .collect()
+    }
+
+    /// Get the inbound group session with the given session ID, converted with
+    /// `f` from its room ID and data.
+    ///
+    /// These lookups are on the hot path of the decryption of room messages, so
+    /// `f` is called with the data borrowed from the row, on the connection's
+    /// thread, to avoid copying the pickle.
+    async fn get_inbound_group_session<T, F>(&self, session_id: Key, f: F) -> Result<Option<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&[u8], &[u8]) -> Result<T> + Send + 'static,
+    {
+        self.query_row_cached(
+            "SELECT room_id, data FROM inbound_group_session WHERE session_id = ?",
+            (session_id,),
+            |row| Ok(f(blob_ref(row, 0)?, blob_ref(row, 1)?)),
+        )
+        .await
+        .optional()?
+        .transpose()
     }
 
     async fn get_inbound_group_sessions(&self) -> Result<Vec<(Vec<u8>, bool)>> {
@@ -449,6 +598,41 @@ trait SqliteObjectCryptoStoreExt: SqliteObjectExt {
             .await?)
     }
 
+    /// Get at most `limit` inbound group sessions, ordered by their encoded
+    /// session ID, starting after the given one.
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<Key>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, bool)>> {
+        let sessions = match after {
+            Some(after) => {
+                self.prepare(
+                    "SELECT data, backed_up FROM inbound_group_session \
+                     WHERE session_id > ? ORDER BY session_id LIMIT ?",
+                    move |mut stmt| {
+                        stmt.query((after, limit))?
+                            .mapped(|row| Ok((row.get(0)?, row.get(1)?)))
+                            .collect()
+                    },
+                )
+                .await?
+            }
+            None => {
+                self.prepare(
+                    "SELECT data, backed_up FROM inbound_group_session \
+                     ORDER BY session_id LIMIT ?",
+                    move |mut stmt| {
+                        stmt.query((limit,))?.mapped(|row| Ok((row.get(0)?, row.get(1)?))).collect()
+                    },
+                )
+                .await?
+            }
+        };
+
+        Ok(sessions)
+    }
+
     async fn get_inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
         let total = self
             .query_row("SELECT count(*) FROM inbound_group_session", (), |row| row.get(0))
@@ -807,22 +991,23 @@ impl CryptoStore for SqliteCryptoStore {
         let account_info = self.get_account_info().ok_or(Error::AccountUnset)?;
 
         if self.session_cache.get(sender_key).is_none() {
+            let this = self.clone();
             let sessions = self
                 .acquire()
                 .await?
-                .get_sessions_for_sender_key(self.encode_key("session", sender_key.as_bytes()))
-                .await?
-                .into_iter()
-                .map(|bytes| {
-                    let pickle = self.deserialize_value(&bytes)?;
-                    Ok(Session::from_pickle(
-                        account_info.user_id.clone(),
-                        account_info.device_id.clone(),
-                        account_info.identity_keys.clone(),
-                        pickle,
-                    ))
-                })
-                .collect::<Result<_>>()?;
+                .get_sessions_for_sender_key(
+                    self.encode_key("session", sender_key.as_bytes()),
+                    move |data| {
+                        let pickle = this.deserialize_value(data)?;
+                        Ok(Session::from_pickle(
+                            account_info.user_id.clone(),
+                            account_info.device_id.clone(),
+                            account_info.identity_keys.clone(),
+                            pickle,
+                        ))
+                    },
+                )
+                .await?;
 
             self.session_cache.set_for_sender(sender_key, sessions);
         }
@@ -837,21 +1022,30 @@ impl CryptoStore for SqliteCryptoStore {
         session_id: &str,
     ) -> Result<Option<InboundGroupSession>> {
         let session_id = self.encode_key("inbound_group_session", session_id);
-        let Some((room_id_from_db, value)) =
-            self.acquire().await?.get_inbound_group_session(session_id).await?
-        else {
-            return Ok(None);
-        };
-
         let room_id = self.encode_key("inbound_group_session", room_id.as_bytes());
-        if *room_id != room_id_from_db {
-            warn!("expected room_id for session_id doesn't match what's in the DB");
-            return Ok(None);
-        }
 
-        let pickle = self.deserialize_value(&value)?;
+        let this = self.clone();
+        let session = self
+            .acquire()
+            .await?
+            .get_inbound_group_session(session_id, move |room_id_from_db, value| {
+                if *room_id != *room_id_from_db {
+                    return Ok(None);
+                }
+
+                let pickle = this.deserialize_value(value)?;
+                Ok(Some(InboundGroupSession::from_pickle(pickle)?))
+            })
+            .await?;
 
-        Ok(Some(InboundGroupSession::from_pickle(pickle)?))
+        match session {
+            Some(Some(session)) => Ok(Some(session)),
+            Some(None) => {
+                warn!("expected room_id for session_id doesn't match what's in the DB");
+                Ok(None)
+            }
+            None => Ok(None),
+        }
     }
 
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
@@ -867,6 +1061,26 @@ impl CryptoStore for SqliteCryptoStore {
             .collect()
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<(&RoomId, &str)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let after =
+            after.map(|(_, session_id)| self.encode_key("inbound_group_session", session_id));
+
+        self.acquire()
+            .await?
+            .get_inbound_group_sessions_batch(after, limit)
+            .await?
+            .into_iter()
+            .map(|(value, backed_up)| {
+                let pickle = self.deserialize_pickled_inbound_group_session(&value, backed_up)?;
+                Ok(InboundGroupSession::from_pickle(pickle)?)
+            })
+            .collect()
+    }
+
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
         Ok(self.acquire().await?.get_inbound_group_session_counts().await?)
     }
@@ -1145,6 +1359,10 @@ impl CryptoStore for SqliteCryptoStore {
 
         Ok(num_touched == 1)
     }
+
+    async fn clear_caches(&self) {
+        self.session_cache.clear();
+    }
 }
 
 #[cfg(test)]
@@ -1189,3 +1407,61 @@ mod encrypted_tests {
 
     cryptostore_integration_tests!();
 }
+
+#[cfg(test)]
+mod migration_tests {
+    use std::sync::{Arc, Mutex};
+
+    use assert_matches::assert_matches;
+    use matrix_sdk_crypto::store::migrations::MigrationProgress;
+    use matrix_sdk_test::async_test;
+    use tempfile::tempdir;
+
+    use super::{migration_sql, Error, SqliteCryptoStore, DATABASE_NAME, MIGRATIONS};
+
+    #[async_test]
+    async fn dry_run_then_migrate() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join(DATABASE_NAME);
+
+        // There is no store to check.
+        SqliteCryptoStore::dry_run_migrations(dir.path()).await.unwrap_err();
+        assert!(!db_path.exists());
+
+        // Create a store at the first schema version.
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        conn.execute_batch(migration_sql(1).unwrap()).unwrap();
+        conn.execute("INSERT INTO kv VALUES ('version', ?1)", (vec![1u8],)).unwrap();
+        drop(conn);
+
+        let pending = SqliteCryptoStore::dry_run_migrations(dir.path()).await.unwrap();
+        assert_eq!(pending, &MIGRATIONS[1..]);
+
+        // The dry-run didn't change anything.
+        let pending = SqliteCryptoStore::dry_run_migrations(dir.path()).await.unwrap();
+        assert_eq!(pending, &MIGRATIONS[1..]);
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        SqliteCryptoStore::open_with_migration_progress(dir.path(), None, {
+            let progress = progress.clone();
+            move |p| progress.lock().unwrap().push(p)
+        })
+        .await
+        .unwrap();
+
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.len(), MIGRATIONS.len() + 1);
+        assert_eq!(
+            progress.last(),
+            Some(&MigrationProgress::Finished { version: MIGRATIONS.last().unwrap().version })
+        );
+
+        let pending = SqliteCryptoStore::dry_run_migrations(dir.path()).await.unwrap();
+        assert!(pending.is_empty());
+    }
+    #[test]
+    fn unknown_migration() {
+        let version = MIGRATIONS.last().unwrap().version + 1;
+        assert_matches!(migration_sql(version), Err(Error::UnsupportedVersion(v)) if v == version);
+    }
+}
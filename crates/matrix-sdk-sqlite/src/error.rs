@@ -12,11 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::path::PathBuf;
+
 use deadpool_sqlite::{CreatePoolError, PoolError};
 #[cfg(feature = "state-store")]
 use matrix_sdk_base::store::StoreError as StateStoreError;
 #[cfg(feature = "crypto-store")]
-use matrix_sdk_crypto::CryptoStoreError;
+use matrix_sdk_crypto::{store::migrations::MigrationError, CryptoStoreError};
 use thiserror::Error;
 use tokio::io;
 
@@ -32,6 +34,10 @@ pub enum OpenStoreError {
     #[error(transparent)]
     CreatePool(#[from] CreatePoolError),
 
+    /// Failed to open the database.
+    #[error("Failed to open the database")]
+    OpenDatabase(#[source] rusqlite::Error),
+
     /// Failed to load the database's version.
     #[error("Failed to load database version")]
     LoadVersion(#[source] rusqlite::Error),
@@ -48,6 +54,11 @@ pub enum OpenStoreError {
     #[error("Failed to run migrations")]
     Migration(#[from] Error),
 
+    /// Failed to migrate the crypto store.
+    #[cfg(feature = "crypto-store")]
+    #[error("Failed to migrate the crypto store")]
+    CryptoStoreMigration(#[from] MigrationError<Error>),
+
     /// Failed to get a DB connection from the pool.
     #[error(transparent)]
     Pool(#[from] PoolError),
@@ -63,6 +74,10 @@ pub enum OpenStoreError {
     /// Failed to save the store cipher to the DB.
     #[error("Failed to save the store cipher to the DB")]
     SaveCipher(#[source] rusqlite::Error),
+
+    /// The passphrase of the DB can't be rotated because it is not encrypted.
+    #[error("The database at {0} is not encrypted with a passphrase")]
+    MissingCipher(PathBuf),
 }
 
 #[derive(Debug, Error)]
@@ -78,6 +93,8 @@ pub enum Error {
     #[error(transparent)]
     Json(#[from] serde_json::Error),
     #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
     Encryption(matrix_sdk_store_encryption::Error),
     #[error("can't save/load sessions or group sessions in the store before an account is stored")]
     AccountUnset,
@@ -87,6 +104,10 @@ pub enum Error {
     Unpickle,
     #[error("Redaction failed: {0}")]
     Redaction(#[source] ruma::canonical_json::RedactionError),
+    #[error("The database version is missing or invalid")]
+    InvalidVersion,
+    #[error("There is no migration to the database version {0}")]
+    UnsupportedVersion(u8),
 }
 
 macro_rules! impl_from {
@@ -22,41 +22,58 @@ use deadpool_sqlite::Object as SqliteConn;
 use matrix_sdk_base::store::StoreConfig;
 use matrix_sdk_store_encryption::StoreCipher;
 
+mod config;
 #[cfg(feature = "crypto-store")]
 mod crypto_store;
 mod error;
+mod passphrase;
 #[cfg(feature = "state-store")]
 mod state_store;
 mod utils;
 
 #[cfg(feature = "crypto-store")]
 pub use self::crypto_store::SqliteCryptoStore;
-pub use self::error::OpenStoreError;
 #[cfg(feature = "state-store")]
 pub use self::state_store::SqliteStateStore;
 use self::utils::SqliteObjectStoreExt;
+pub use self::{
+    config::{DatabaseSize, JournalMode, SqliteStoreConfig, Synchronous},
+    error::OpenStoreError,
+    passphrase::{
+        rotate_passphrase, rotate_passphrase_with_config, PassphraseRotationProgress,
+        PassphraseRotationStep,
+    },
+};
 
 async fn get_or_create_store_cipher(
     passphrase: &str,
     conn: &SqliteConn,
 ) -> Result<StoreCipher, OpenStoreError> {
-    let encrypted_cipher = conn.get_kv("cipher").await.map_err(OpenStoreError::LoadCipher)?;
-
-    let cipher = if let Some(encrypted) = encrypted_cipher {
-        StoreCipher::import(passphrase, &encrypted)?
+    let cipher = if let Some(cipher) = passphrase::load_store_cipher(passphrase, conn).await? {
+        cipher
     } else {
         let cipher = StoreCipher::new()?;
-        #[cfg(not(test))]
-        let export = cipher.export(passphrase);
-        #[cfg(test)]
-        let export = cipher._insecure_export_fast_for_testing(passphrase);
-        conn.set_kv("cipher", export?).await.map_err(OpenStoreError::SaveCipher)?;
+        let export = export_store_cipher(&cipher, passphrase)?;
+        conn.set_kv(passphrase::CIPHER_KEY, export).await.map_err(OpenStoreError::SaveCipher)?;
         cipher
     };
 
     Ok(cipher)
 }
 
+/// Encrypt the store cipher with the given passphrase, to save it in the
+/// database.
+fn export_store_cipher(
+    cipher: &StoreCipher,
+    passphrase: &str,
+) -> Result<Vec<u8>, matrix_sdk_store_encryption::Error> {
+    #[cfg(not(test))]
+    let export = cipher.export(passphrase);
+    #[cfg(test)]
+    let export = cipher._insecure_export_fast_for_testing(passphrase);
+    export
+}
+
 #[cfg(test)]
 #[ctor::ctor]
 fn init_logging() {
@@ -75,12 +92,25 @@ pub async fn make_store_config(
     path: &Path,
     passphrase: Option<&str>,
 ) -> Result<StoreConfig, OpenStoreError> {
-    let state_store = SqliteStateStore::open(path, passphrase).await?;
+    make_store_config_with(path, passphrase, SqliteStoreConfig::default()).await
+}
+
+/// Like [`make_store_config`], with the given settings for the SQLite
+/// databases.
+#[cfg(feature = "state-store")]
+pub async fn make_store_config_with(
+    path: &Path,
+    passphrase: Option<&str>,
+    sqlite_config: SqliteStoreConfig,
+) -> Result<StoreConfig, OpenStoreError> {
+    let state_store =
+        SqliteStateStore::open_with_config(path, passphrase, sqlite_config.clone()).await?;
     let config = StoreConfig::new().state_store(state_store);
 
     #[cfg(feature = "crypto-store")]
     {
-        let crypto_store = SqliteCryptoStore::open(path, passphrase).await?;
+        let crypto_store =
+            SqliteCryptoStore::open_with_config(path, passphrase, sqlite_config).await?;
         Ok(config.crypto_store(crypto_store))
     }
 
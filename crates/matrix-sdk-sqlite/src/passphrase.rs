@@ -0,0 +1,348 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rotation of the passphrase of the stores.
+//!
+//! The data of a store is encrypted with the keys of a [`StoreCipher`], that
+//! is itself encrypted with the passphrase and saved in the database. Rotating
+//! the passphrase only encrypts the store cipher again, so it doesn't depend on
+//! the size of the store.
+//!
+//! The state store and the crypto store are separate databases, that can't be
+//! updated in a single transaction. To make sure that they can always be
+//! opened with the old or the new passphrase if the rotation is interrupted,
+//! it happens in three steps, each applied to all the databases before the next
+//! one starts:
+//!
+//! 1. the store cipher encrypted with the new passphrase is saved next to the
+//!    current one,
+//! 2. in a single transaction, it replaces the current one, which is kept as
+//!    the previous one,
+//! 3. the previous one is removed.
+//!
+//! When a store is opened, all the saved store ciphers are tried, so whatever
+//! the step at which the rotation was interrupted, both passphrases work until
+//! the rotation is run again to completion.
+//!
+//! The data itself is not encrypted again: it stays encrypted with the keys of
+//! the same store cipher. A copy of the database taken before the rotation can
+//! still be decrypted with the old passphrase, and it reveals the keys that
+//! decrypt the current data.
+
+use std::path::Path;
+
+use deadpool_sqlite::Object as SqliteConn;
+use matrix_sdk_store_encryption::StoreCipher;
+use tokio::fs;
+use tracing::{debug, info};
+
+use crate::{
+    export_store_cipher,
+    utils::{SqliteConnectionExt, SqliteObjectExt, SqliteObjectStoreExt},
+    OpenStoreError, SqliteStoreConfig,
+};
+
+/// The key of the store cipher in use.
+pub(crate) const CIPHER_KEY: &str = "cipher";
+/// The key of the store cipher encrypted with the new passphrase, while a
+/// rotation is in progress.
+const NEXT_CIPHER_KEY: &str = "cipher_next";
+/// The key of the store cipher encrypted with the old passphrase, while a
+/// rotation is in progress.
+const PREVIOUS_CIPHER_KEY: &str = "cipher_previous";
+
+/// A step of a passphrase rotation.
+///
+/// See the [`rotate_passphrase`] documentation for the details.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PassphraseRotationStep {
+    /// The store cipher is encrypted with the new passphrase, and saved next
+    /// to the current one.
+    Prepare,
+    /// The store cipher encrypted with the new passphrase replaces the current
+    /// one.
+    Commit,
+    /// The store cipher encrypted with the old passphrase is removed.
+    CleanUp,
+}
+
+/// The progress of a passphrase rotation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PassphraseRotationProgress {
+    /// The step that was just applied to a database.
+    pub step: PassphraseRotationStep,
+    /// The number of steps applied so far, including this one.
+    pub done: usize,
+    /// The number of steps of the whole rotation.
+    pub total: usize,
+}
+
+/// Encrypt the stores in the given directory with a new passphrase.
+///
+/// Only the databases that exist are updated, so this works with a directory
+/// containing a state store, a crypto store, or both of them. All of them must
+/// be encrypted with `old_passphrase`, otherwise none of them are modified.
+///
+/// The stores can be open while the passphrase is rotated. If the rotation is
+/// interrupted, for example because the app crashed, the stores can still be
+/// opened with both passphrases, and the rotation should be run again.
+///
+/// `progress_listener` is called after every step applied to a database.
+///
+/// Only the store cipher is encrypted with the new passphrase, the data is not
+/// encrypted again. See the [module documentation](self) for the details.
+pub async fn rotate_passphrase(
+    path: impl AsRef<Path>,
+    old_passphrase: &str,
+    new_passphrase: &str,
+    progress_listener: impl Fn(PassphraseRotationProgress),
+) -> Result<(), OpenStoreError> {
+    rotate_passphrase_with_config(
+        path,
+        old_passphrase,
+        new_passphrase,
+        SqliteStoreConfig::default(),
+        progress_listener,
+    )
+    .await
+}
+
+/// Like [`rotate_passphrase`], with the settings that the SQLite databases
+/// are opened with.
+///
+/// The settings must be the same as the ones of the stores, otherwise settings
+/// that are saved in the databases, like the journal mode, are changed.
+pub async fn rotate_passphrase_with_config(
+    path: impl AsRef<Path>,
+    old_passphrase: &str,
+    new_passphrase: &str,
+    config: SqliteStoreConfig,
+    progress_listener: impl Fn(PassphraseRotationProgress),
+) -> Result<(), OpenStoreError> {
+    let mut conns = Vec::new();
+    for name in DATABASE_NAMES {
+        let path = path.as_ref().join(name);
+        if fs::metadata(&path).await.is_ok() {
+            let pool = config.create_pool(&path).await?;
+            conns.push((path, pool.get().await?));
+        }
+    }
+
+    info!(databases = conns.len(), "Rotating the passphrase of the stores");
+
+    // Check that all the databases can be decrypted before modifying any of
+    // them.
+    let mut ciphers = Vec::with_capacity(conns.len());
+    for (path, conn) in &conns {
+        let Some(cipher) = load_store_cipher(old_passphrase, conn).await? else {
+            return Err(OpenStoreError::MissingCipher(path.clone()));
+        };
+        ciphers.push(cipher);
+    }
+
+    let total = conns.len() * 3;
+    let mut done = 0;
+    let mut report = |step| {
+        done += 1;
+        progress_listener(PassphraseRotationProgress { step, done, total });
+    };
+
+    for ((path, conn), cipher) in conns.iter().zip(&ciphers) {
+        let export = export_store_cipher(cipher, new_passphrase)?;
+        conn.set_kv(NEXT_CIPHER_KEY, export).await.map_err(OpenStoreError::SaveCipher)?;
+        debug!(?path, "Prepared the passphrase rotation");
+        report(PassphraseRotationStep::Prepare);
+    }
+
+    for (path, conn) in &conns {
+        conn.with_transaction(|txn| {
+            let get = |key| {
+                txn.query_row("SELECT value FROM kv WHERE key = ?", (key,), |row| {
+                    row.get::<_, Vec<u8>>(0)
+                })
+            };
+            let current = get(CIPHER_KEY)?;
+            let next = get(NEXT_CIPHER_KEY)?;
+
+            txn.set_kv(PREVIOUS_CIPHER_KEY, &current)?;
+            txn.set_kv(CIPHER_KEY, &next)?;
+            txn.execute("DELETE FROM kv WHERE key = ?", (NEXT_CIPHER_KEY,))?;
+
+            Ok::<_, rusqlite::Error>(())
+        })
+        .await
+        .map_err(OpenStoreError::SaveCipher)?;
+        debug!(?path, "Committed the passphrase rotation");
+        report(PassphraseRotationStep::Commit);
+    }
+
+    for (path, conn) in &conns {
+        conn.execute("DELETE FROM kv WHERE key = ?", (PREVIOUS_CIPHER_KEY,))
+            .await
+            .map_err(OpenStoreError::SaveCipher)?;
+        debug!(?path, "Cleaned up the passphrase rotation");
+        report(PassphraseRotationStep::CleanUp);
+    }
+
+    info!("The passphrase of the stores was rotated");
+
+    Ok(())
+}
+
+/// The file names of the databases whose passphrase is rotated.
+const DATABASE_NAMES: &[&str] = &[
+    #[cfg(feature = "state-store")]
+    crate::state_store::DATABASE_NAME,
+    #[cfg(feature = "crypto-store")]
+    crate::crypto_store::DATABASE_NAME,
+];
+
+/// Load the store cipher of the database, trying all the exports that can be
+/// saved while a passphrase rotation is in progress.
+///
+/// Returns `None` if the database has no store cipher.
+pub(crate) async fn load_store_cipher(
+    passphrase: &str,
+    conn: &SqliteConn,
+) -> Result<Option<StoreCipher>, OpenStoreError> {
+    let encrypted = conn.get_kv(CIPHER_KEY).await.map_err(OpenStoreError::LoadCipher)?;
+    let Some(encrypted) = encrypted else {
+        return Ok(None);
+    };
+
+    let error = match StoreCipher::import(passphrase, &encrypted) {
+        Ok(cipher) => return Ok(Some(cipher)),
+        Err(error) => error,
+    };
+
+    // A rotation might have been interrupted, the cipher might only be
+    // encrypted with this passphrase under another key.
+    for key in [NEXT_CIPHER_KEY, PREVIOUS_CIPHER_KEY] {
+        let encrypted = conn.get_kv(key).await.map_err(OpenStoreError::LoadCipher)?;
+
+        if let Some(Ok(cipher)) = encrypted.map(|e| StoreCipher::import(passphrase, &e)) {
+            debug!(key, "Loaded the store cipher of an interrupted passphrase rotation");
+            return Ok(Some(cipher));
+        }
+    }
+
+    Err(error.into())
+}
+
+#[cfg(all(test, feature = "state-store", feature = "crypto-store"))]
+mod tests {
+    use std::sync::Mutex;
+
+    use matrix_sdk_base::StateStore;
+    use matrix_sdk_test::async_test;
+    use tempfile::tempdir;
+
+    use super::{
+        load_store_cipher, rotate_passphrase, rotate_passphrase_with_config,
+        PassphraseRotationProgress, PassphraseRotationStep, NEXT_CIPHER_KEY,
+    };
+    use crate::{
+        export_store_cipher, state_store::DATABASE_NAME, utils::SqliteObjectStoreExt, JournalMode,
+        OpenStoreError, SqliteCryptoStore, SqliteStateStore, SqliteStoreConfig,
+    };
+
+    #[async_test]
+    async fn rotate_passphrase_of_existing_stores() {
+        let dir = tempdir().unwrap();
+
+        let state_store = SqliteStateStore::open(dir.path(), Some("old")).await.unwrap();
+        state_store.set_custom_value(b"key", b"value".to_vec()).await.unwrap();
+        drop(state_store);
+        drop(SqliteCryptoStore::open(dir.path(), Some("old")).await.unwrap());
+
+        let progress = Mutex::new(Vec::new());
+        rotate_passphrase(dir.path(), "old", "new", |p| progress.lock().unwrap().push(p))
+            .await
+            .unwrap();
+
+        let progress = progress.into_inner().unwrap();
+        assert_eq!(progress.len(), 6);
+        assert_eq!(
+            progress[5],
+            PassphraseRotationProgress { step: PassphraseRotationStep::CleanUp, done: 6, total: 6 }
+        );
+
+        let state_store = SqliteStateStore::open(dir.path(), Some("new")).await.unwrap();
+        let value = state_store.get_custom_value(b"key").await.unwrap();
+        assert_eq!(value.as_deref(), Some(&b"value"[..]));
+        drop(state_store);
+        SqliteCryptoStore::open(dir.path(), Some("new")).await.unwrap();
+
+        assert!(SqliteStateStore::open(dir.path(), Some("old")).await.is_err());
+    }
+
+    #[async_test]
+    async fn rotation_keeps_the_settings_of_the_stores() {
+        let dir = tempdir().unwrap();
+        let config = SqliteStoreConfig::new().journal_mode(JournalMode::Delete);
+        drop(
+            SqliteStateStore::open_with_config(dir.path(), Some("old"), config.clone())
+                .await
+                .unwrap(),
+        );
+
+        rotate_passphrase_with_config(dir.path(), "old", "new", config.clone(), |_| {})
+            .await
+            .unwrap();
+
+        let conn = rusqlite::Connection::open(dir.path().join(DATABASE_NAME)).unwrap();
+        let journal_mode: String =
+            conn.query_row("PRAGMA journal_mode", (), |row| row.get(0)).unwrap();
+        assert_eq!(journal_mode, "delete");
+
+        SqliteStateStore::open_with_config(dir.path(), Some("new"), config).await.unwrap();
+    }
+
+    #[async_test]
+    async fn wrong_passphrase_modifies_nothing() {
+        let dir = tempdir().unwrap();
+        drop(SqliteStateStore::open(dir.path(), Some("old")).await.unwrap());
+
+        let result = rotate_passphrase(dir.path(), "wrong", "new", |_| {}).await;
+        assert!(matches!(result, Err(OpenStoreError::InitCipher(_))));
+
+        SqliteStateStore::open(dir.path(), Some("old")).await.unwrap();
+    }
+
+    #[async_test]
+    async fn interrupted_rotation_accepts_both_passphrases() {
+        let dir = tempdir().unwrap();
+        drop(SqliteStateStore::open(dir.path(), Some("old")).await.unwrap());
+
+        // Simulate a rotation interrupted after the first step.
+        let config = SqliteStoreConfig::default();
+        let pool = config.create_pool(&dir.path().join(DATABASE_NAME)).await.unwrap();
+        let conn = pool.get().await.unwrap();
+        let cipher = load_store_cipher("old", &conn).await.unwrap().unwrap();
+        let export = export_store_cipher(&cipher, "new").unwrap();
+        conn.set_kv(NEXT_CIPHER_KEY, export).await.unwrap();
+
+        SqliteStateStore::open(dir.path(), Some("old")).await.unwrap();
+        SqliteStateStore::open(dir.path(), Some("new")).await.unwrap();
+        assert!(matches!(
+            SqliteStateStore::open(dir.path(), Some("other")).await,
+            Err(OpenStoreError::InitCipher(_))
+        ));
+
+        // Running the rotation again completes it.
+        rotate_passphrase(dir.path(), "old", "new", |_| {}).await.unwrap();
+        SqliteStateStore::open(dir.path(), Some("new")).await.unwrap();
+        assert!(SqliteStateStore::open(dir.path(), Some("old")).await.is_err());
+    }
+}
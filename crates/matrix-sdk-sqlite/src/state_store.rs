@@ -7,12 +7,12 @@ use std::{
 };
 
 use async_trait::async_trait;
-use deadpool_sqlite::{Object as SqliteConn, Pool as SqlitePool, Runtime};
+use deadpool_sqlite::{Object as SqliteConn, Pool as SqlitePool};
 use matrix_sdk_base::{
     deserialized_responses::RawAnySyncOrStrippedState,
     media::{MediaRequest, UniqueKey},
     MinimalRoomMemberEvent, RoomInfo, RoomMemberships, RoomState, StateChanges, StateStore,
-    StateStoreDataKey, StateStoreDataValue,
+    StateStoreDataKey, StateStoreDataValue, StoreError,
 };
 use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{
@@ -29,14 +29,13 @@ use ruma::{
 };
 use rusqlite::{OptionalExtension, Transaction};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::fs;
 use tracing::{debug, warn};
 
 use crate::{
     error::{Error, Result},
     get_or_create_store_cipher,
-    utils::{load_db_version, Key, SqliteObjectExt},
-    OpenStoreError, SqliteObjectStoreExt,
+    utils::{load_db_size, load_db_version, Key, SqliteObjectExt},
+    DatabaseSize, OpenStoreError, SqliteObjectStoreExt, SqliteStoreConfig,
 };
 
 mod keys {
@@ -55,6 +54,9 @@ mod keys {
 
 const DATABASE_VERSION: u8 = 2;
 
+/// The name of the database file, in the directory of the store.
+pub(crate) const DATABASE_NAME: &str = "matrix-sdk-state.sqlite3";
+
 /// A sqlite based cryptostore.
 #[derive(Clone)]
 pub struct SqliteStateStore {
@@ -80,9 +82,20 @@ impl SqliteStateStore {
         path: impl AsRef<Path>,
         passphrase: Option<&str>,
     ) -> Result<Self, OpenStoreError> {
-        let pool = create_pool(path.as_ref()).await?;
+        Self::open_with_config(path, passphrase, SqliteStoreConfig::default()).await
+    }
+
+    /// Open the sqlite-based state store at the given path using the given
+    /// passphrase to encrypt private data, with the given settings for the
+    /// database.
+    pub async fn open_with_config(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        config: SqliteStoreConfig,
+    ) -> Result<Self, OpenStoreError> {
+        let pool = config.create_pool(&path.as_ref().join(DATABASE_NAME)).await?;
 
-        Self::open_with_pool(pool, passphrase).await
+        Self::open_with_pool_and_config(pool, passphrase, &config).await
     }
 
     /// Create a sqlite-based state store using the given sqlite database pool.
@@ -90,12 +103,20 @@ impl SqliteStateStore {
     pub async fn open_with_pool(
         pool: SqlitePool,
         passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        Self::open_with_pool_and_config(pool, passphrase, &SqliteStoreConfig::default()).await
+    }
+
+    async fn open_with_pool_and_config(
+        pool: SqlitePool,
+        passphrase: Option<&str>,
+        config: &SqliteStoreConfig,
     ) -> Result<Self, OpenStoreError> {
         let conn = pool.get().await?;
         let mut version = load_db_version(&conn).await?;
 
         if version == 0 {
-            init(&conn).await?;
+            init(&conn, config).await?;
             version = 1;
         }
 
@@ -220,6 +241,9 @@ impl SqliteStateStore {
             StateStoreDataKey::UserAvatarUrl(u) => {
                 Cow::Owned(format!("{}:{u}", StateStoreDataKey::USER_AVATAR_URL))
             }
+            StateStoreDataKey::SentEvent(txn_id) => {
+                Cow::Owned(format!("{}:{txn_id}", StateStoreDataKey::SENT_EVENT))
+            }
         };
 
         self.encode_key(keys::KV_BLOB, &*key_s)
@@ -239,6 +263,30 @@ impl SqliteStateStore {
         Ok(self.pool.get().await?)
     }
 
+    /// Rebuild the database file, to give the space left by deleted data back
+    /// to the filesystem.
+    ///
+    /// This can take a while on large databases, and needs up to twice the
+    /// size of the database in free disk space.
+    pub async fn vacuum(&self) -> Result<(), StoreError> {
+        self.acquire().await?.execute_batch("VACUUM").await.map_err(Error::from)?;
+        Ok(())
+    }
+
+    /// Let SQLite update the statistics used by its query planner.
+    ///
+    /// This is cheap and can be called periodically, e.g. when the app goes to
+    /// the background.
+    pub async fn optimize(&self) -> Result<(), StoreError> {
+        self.acquire().await?.execute_batch("PRAGMA optimize").await.map_err(Error::from)?;
+        Ok(())
+    }
+
+    /// Get the size of the files of the database.
+    pub async fn database_size(&self) -> Result<DatabaseSize, StoreError> {
+        Ok(load_db_size(&self.acquire().await?).await?)
+    }
+
     fn remove_maybe_stripped_room_data(
         &self,
         txn: &Transaction<'_>,
@@ -253,17 +301,12 @@ impl SqliteStateStore {
     }
 }
 
-async fn create_pool(path: &Path) -> Result<SqlitePool, OpenStoreError> {
-    fs::create_dir_all(path).await.map_err(OpenStoreError::CreateDir)?;
-    let cfg = deadpool_sqlite::Config::new(path.join("matrix-sdk-state.sqlite3"));
-    Ok(cfg.create_pool(Runtime::Tokio1)?)
-}
-
 /// Initialize the database.
-async fn init(conn: &SqliteConn) -> Result<()> {
-    // First turn on WAL mode, this can't be done in the transaction, it fails with
-    // the error message: "cannot change into wal mode from within a transaction".
-    conn.execute_batch("PRAGMA journal_mode = wal;").await?;
+async fn init(conn: &SqliteConn, config: &SqliteStoreConfig) -> Result<()> {
+    // First set the journal mode, this can't be done in the transaction, it fails
+    // with the error message: "cannot change into wal mode from within a
+    // transaction".
+    conn.execute_batch(config.journal_mode_pragma()).await?;
     conn.with_transaction(|txn| {
         txn.execute_batch(include_str!("../migrations/state_store/001_init.sql"))
     })
@@ -276,6 +319,7 @@ async fn init(conn: &SqliteConn) -> Result<()> {
 
 trait SqliteConnectionStateStoreExt {
     fn set_kv_blob(&self, key: &[u8], value: &[u8]) -> rusqlite::Result<()>;
+    fn delete_kv_blob(&self, key: &[u8]) -> rusqlite::Result<()>;
 
     fn set_global_account_data(&self, event_type: &[u8], data: &[u8]) -> rusqlite::Result<()>;
 
@@ -346,6 +390,11 @@ impl SqliteConnectionStateStoreExt for rusqlite::Connection {
         Ok(())
     }
 
+    fn delete_kv_blob(&self, key: &[u8]) -> rusqlite::Result<()> {
+        self.execute("DELETE FROM kv_blob WHERE key = ?", (key,))?;
+        Ok(())
+    }
+
     fn set_global_account_data(&self, event_type: &[u8], data: &[u8]) -> rusqlite::Result<()> {
         self.prepare_cached(
             "INSERT OR REPLACE INTO global_account_data (event_type, data)
@@ -802,12 +851,18 @@ impl StateStore for SqliteStateStore {
             .get_kv_blob(self.encode_state_store_data_key(key))
             .await?
             .map(|data| {
-                let string = self.deserialize_value(&data)?;
                 Ok(match key {
-                    StateStoreDataKey::SyncToken => StateStoreDataValue::SyncToken(string),
-                    StateStoreDataKey::Filter(_) => StateStoreDataValue::Filter(string),
+                    StateStoreDataKey::SyncToken => {
+                        StateStoreDataValue::SyncToken(self.deserialize_value(&data)?)
+                    }
+                    StateStoreDataKey::Filter(_) => {
+                        StateStoreDataValue::Filter(self.deserialize_value(&data)?)
+                    }
                     StateStoreDataKey::UserAvatarUrl(_) => {
-                        StateStoreDataValue::UserAvatarUrl(string)
+                        StateStoreDataValue::UserAvatarUrl(self.deserialize_value(&data)?)
+                    }
+                    StateStoreDataKey::SentEvent(_) => {
+                        StateStoreDataValue::SentEvent(self.deserialize_value(&data)?)
                     }
                 })
             })
@@ -827,6 +882,9 @@ impl StateStore for SqliteStateStore {
             StateStoreDataKey::UserAvatarUrl(_) => {
                 value.into_user_avatar_url().expect("Session data not an user avatar url")
             }
+            StateStoreDataKey::SentEvent(_) => {
+                value.into_sent_event().expect("Session data not a sent event").to_string()
+            }
         };
 
         self.acquire()
@@ -858,6 +916,7 @@ impl StateStore for SqliteStateStore {
                     stripped_state,
                     ambiguity_maps,
                     notifications: _,
+                    custom,
                 } = changes;
 
                 if let Some(sync_token) = sync_token {
@@ -1113,6 +1172,14 @@ impl StateStore for SqliteStateStore {
                     }
                 }
 
+                for (key, value) in custom {
+                    let key = this.encode_custom_key(&key);
+                    match value {
+                        Some(value) => txn.set_kv_blob(&key, &value)?,
+                        None => txn.delete_kv_blob(&key)?,
+                    }
+                }
+
                 Ok::<_, Error>(())
             })
             .await?;
@@ -1595,11 +1662,12 @@ mod migration_tests {
     use ruma::RoomId;
     use tempfile::{tempdir, TempDir};
 
-    use super::{create_pool, init, keys, SqliteStateStore};
+    use super::{init, keys, SqliteStateStore, DATABASE_NAME};
     use crate::{
         error::{Error, Result},
         get_or_create_store_cipher,
         utils::SqliteObjectExt,
+        SqliteStoreConfig,
     };
 
     static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
@@ -1612,10 +1680,11 @@ mod migration_tests {
     }
 
     async fn create_fake_db(path: &Path, version: u8) -> Result<SqliteStateStore> {
-        let pool = create_pool(path).await.unwrap();
+        let config = SqliteStoreConfig::default();
+        let pool = config.create_pool(&path.join(DATABASE_NAME)).await.unwrap();
         let conn = pool.get().await?;
 
-        init(&conn).await?;
+        init(&conn, &config).await?;
 
         let store_cipher = Some(Arc::new(get_or_create_store_cipher(SECRET, &conn).await.unwrap()));
         let this = SqliteStateStore { store_cipher, path: None, pool };
@@ -1666,3 +1735,54 @@ mod migration_tests {
         assert_eq!(stripped_rooms.len(), 2);
     }
 }
+
+#[cfg(test)]
+mod config_tests {
+    use std::time::Duration;
+
+    use matrix_sdk_base::{StateStore, StateStoreDataKey, StateStoreDataValue};
+    use matrix_sdk_test::async_test;
+    use tempfile::tempdir;
+
+    use super::SqliteStateStore;
+    use crate::{utils::SqliteObjectExt, JournalMode, SqliteStoreConfig, Synchronous};
+
+    #[async_test]
+    async fn test_open_with_config() {
+        let dir = tempdir().unwrap();
+        let config = SqliteStoreConfig::new()
+            .pool_size(2)
+            .journal_mode(JournalMode::Truncate)
+            .synchronous(Synchronous::Normal)
+            .busy_timeout(Duration::from_millis(1500))
+            .cache_size(4096);
+        let store = SqliteStateStore::open_with_config(dir.path(), None, config).await.unwrap();
+
+        let conn = store.pool.get().await.unwrap();
+        let journal_mode: String =
+            conn.query_row("PRAGMA journal_mode", (), |row| row.get(0)).await.unwrap();
+        assert_eq!(journal_mode, "truncate");
+        let pragma =
+            |name: &str| conn.query_row(format!("PRAGMA {name}"), (), |row| row.get::<_, i64>(0));
+        // `NORMAL` is 1.
+        assert_eq!(pragma("synchronous").await.unwrap(), 1);
+        assert_eq!(pragma("busy_timeout").await.unwrap(), 1500);
+        assert_eq!(pragma("cache_size").await.unwrap(), -4096);
+        drop(conn);
+
+        store
+            .set_kv_data(
+                StateStoreDataKey::SyncToken,
+                StateStoreDataValue::SyncToken("s1234".to_owned()),
+            )
+            .await
+            .unwrap();
+
+        let size = store.database_size().await.unwrap();
+        assert!(size.database > 0);
+        assert_eq!(size.wal, 0);
+
+        store.vacuum().await.unwrap();
+        store.optimize().await.unwrap();
+    }
+}
@@ -12,12 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{borrow::Borrow, ops::Deref};
+use std::{borrow::Borrow, io, ops::Deref};
 
 use async_trait::async_trait;
-use rusqlite::{OptionalExtension, Params, Row, Statement, Transaction};
+use rusqlite::{
+    types::Type, CachedStatement, OptionalExtension, Params, Row, Statement, Transaction,
+};
+use tokio::fs;
 
-use crate::OpenStoreError;
+use crate::{error::Error, DatabaseSize, OpenStoreError};
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum Key {
@@ -69,6 +72,16 @@ pub(crate) trait SqliteObjectExt {
         T: Send + 'static,
         F: FnOnce(Statement<'_>) -> rusqlite::Result<T> + Send + 'static;
 
+    /// Like [`SqliteObjectExt::prepare`], but the statement is cached by the
+    /// connection, so it is only compiled the first time.
+    ///
+    /// It should only be used for static SQL that is run often, since the
+    /// number of cached statements is limited.
+    async fn prepare_cached<T, F>(&self, sql: &'static str, f: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(CachedStatement<'_>) -> rusqlite::Result<T> + Send + 'static;
+
     async fn query_row<T, P, F>(
         &self,
         sql: impl AsRef<str> + Send + 'static,
@@ -80,11 +93,32 @@ pub(crate) trait SqliteObjectExt {
         P: Params + Send + 'static,
         F: FnOnce(&Row<'_>) -> rusqlite::Result<T> + Send + 'static;
 
+    /// Like [`SqliteObjectExt::query_row`], but the statement is cached by
+    /// the connection, so it is only compiled the first time.
+    async fn query_row_cached<T, P, F>(
+        &self,
+        sql: &'static str,
+        params: P,
+        f: F,
+    ) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        P: Params + Send + 'static,
+        F: FnOnce(&Row<'_>) -> rusqlite::Result<T> + Send + 'static;
+
     async fn with_transaction<T, E, F>(&self, f: F) -> Result<T, E>
     where
         T: Send + 'static,
         E: From<rusqlite::Error> + Send + 'static,
         F: FnOnce(&Transaction<'_>) -> Result<T, E> + Send + 'static;
+
+    /// Run the given closure in a transaction that is always rolled back,
+    /// even if the closure succeeds.
+    async fn with_rolled_back_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<rusqlite::Error> + Send + 'static,
+        F: FnOnce(&Transaction<'_>) -> Result<T, E> + Send + 'static;
 }
 
 #[async_trait]
@@ -116,6 +150,14 @@ impl SqliteObjectExt for deadpool_sqlite::Object {
         self.interact(move |conn| f(conn.prepare(sql.as_ref())?)).await.unwrap()
     }
 
+    async fn prepare_cached<T, F>(&self, sql: &'static str, f: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(CachedStatement<'_>) -> rusqlite::Result<T> + Send + 'static,
+    {
+        self.interact(move |conn| f(conn.prepare_cached(sql)?)).await.unwrap()
+    }
+
     async fn query_row<T, P, F>(
         &self,
         sql: impl AsRef<str> + Send + 'static,
@@ -130,6 +172,20 @@ impl SqliteObjectExt for deadpool_sqlite::Object {
         self.interact(move |conn| conn.query_row(sql.as_ref(), params, f)).await.unwrap()
     }
 
+    async fn query_row_cached<T, P, F>(
+        &self,
+        sql: &'static str,
+        params: P,
+        f: F,
+    ) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        P: Params + Send + 'static,
+        F: FnOnce(&Row<'_>) -> rusqlite::Result<T> + Send + 'static,
+    {
+        self.interact(move |conn| conn.prepare_cached(sql)?.query_row(params, f)).await.unwrap()
+    }
+
     async fn with_transaction<T, E, F>(&self, f: F) -> Result<T, E>
     where
         T: Send + 'static,
@@ -145,6 +201,29 @@ impl SqliteObjectExt for deadpool_sqlite::Object {
         .await
         .unwrap()
     }
+
+    async fn with_rolled_back_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<rusqlite::Error> + Send + 'static,
+        F: FnOnce(&Transaction<'_>) -> Result<T, E> + Send + 'static,
+    {
+        self.interact(move |conn| {
+            let txn = conn.transaction()?;
+            let result = f(&txn)?;
+            txn.rollback()?;
+            Ok(result)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// Get the blob in the given column of the row, without copying it.
+pub(crate) fn blob_ref<'a>(row: &'a Row<'_>, idx: usize) -> rusqlite::Result<&'a [u8]> {
+    row.get_ref(idx)?
+        .as_blob()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Blob, Box::new(e)))
 }
 
 pub(crate) trait SqliteConnectionExt {
@@ -205,3 +284,30 @@ pub(crate) async fn load_db_version(conn: &deadpool_sqlite::Object) -> Result<u8
         Ok(0)
     }
 }
+
+/// Get the size of the files of the database with the given connection.
+pub(crate) async fn load_db_size(conn: &deadpool_sqlite::Object) -> Result<DatabaseSize, Error> {
+    let path: String = conn
+        .query_row("SELECT file FROM pragma_database_list WHERE name = 'main'", (), |row| {
+            row.get(0)
+        })
+        .await?;
+
+    // The path is empty for in-memory databases.
+    if path.is_empty() {
+        return Ok(DatabaseSize::default());
+    }
+
+    let database = file_size(&path).await?;
+    let wal = file_size(&format!("{path}-wal")).await?;
+
+    Ok(DatabaseSize { database, wal })
+}
+
+async fn file_size(path: &str) -> io::Result<u64> {
+    match fs::metadata(path).await {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
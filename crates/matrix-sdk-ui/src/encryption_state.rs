@@ -0,0 +1,369 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `EncryptionStateObserver` API.
+//!
+//! Apps usually nudge the user with a banner until the encryption of their
+//! account is fully set up: "Verify this session", "Set up recovery" or "Key
+//! backup is off". The [`EncryptionStateObserver`] computes which one to show
+//! from:
+//!
+//! * the cross-signing identity of the user, and whether this session is signed
+//!   by it,
+//! * the presence of a default secret storage key in the account data, and
+//!   whether this session has all the private cross-signing keys,
+//! * the presence of a key backup on the homeserver.
+//!
+//! The [`EncryptionState`] is updated in the background when the identity or
+//! the devices of the user change, or when the account data related to the
+//! secret storage is received. The key backup isn't part of the sync, so its
+//! state is only requested from the homeserver when the observer is created,
+//! when the backup key in the secret storage changes, and when
+//! [`EncryptionStateObserver::refresh`] is called, which should be done after
+//! the app enabled or disabled the key backup.
+
+use std::sync::Arc;
+
+use eyeball::{shared::Observable, Subscriber};
+use futures_util::{future, pin_mut, stream, StreamExt};
+use matrix_sdk::{
+    encryption::identities::DeviceUpdate,
+    executor::{spawn, JoinHandle},
+    ruma::{
+        api::client::{backup::get_latest_backup_info, error::ErrorKind},
+        events::GlobalAccountDataEventType,
+        UserId,
+    },
+    Client,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{trace, warn};
+
+/// The account data event type of the ID of the default secret storage key.
+const SECRET_STORAGE_DEFAULT_KEY: &str = "m.secret_storage.default_key";
+
+/// The account data event type of the backup key stored in the secret
+/// storage.
+const MEGOLM_BACKUP_SECRET: &str = "m.megolm_backup.v1";
+
+/// Whether recovery, i.e. the secret storage of the encryption keys, is set up
+/// for the account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryState {
+    /// The state couldn't be loaded yet.
+    Unknown,
+    /// The account has no secret storage.
+    Disabled,
+    /// The account has a secret storage, but this session doesn't have all
+    /// the private cross-signing keys, they must be recovered from the secret
+    /// storage or received from another session.
+    Incomplete,
+    /// The account has a secret storage, and this session has all the private
+    /// cross-signing keys.
+    Enabled,
+}
+
+/// Whether the room keys are backed up on the homeserver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyBackupState {
+    /// The state couldn't be loaded yet, or the request to the homeserver
+    /// failed.
+    Unknown,
+    /// There is no key backup on the homeserver.
+    Disabled,
+    /// There is a key backup on the homeserver.
+    Enabled,
+}
+
+/// The banner an app should show to finish setting up the encryption.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionBanner {
+    /// The account has a cross-signing identity, but this session isn't
+    /// verified with it.
+    VerifySession,
+    /// The account has no secret storage, the encryption keys would be lost
+    /// with the sessions of the user.
+    SetUpRecovery,
+    /// The room keys aren't backed up on the homeserver.
+    KeyBackupOff,
+}
+
+/// The state of the encryption of the account, see the module's
+/// documentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncryptionState {
+    /// Whether the account has a cross-signing identity.
+    pub has_cross_signing_identity: bool,
+    /// Whether this session is signed by the cross-signing identity of the
+    /// account.
+    pub is_session_verified: bool,
+    /// The state of the recovery.
+    pub recovery: RecoveryState,
+    /// The state of the key backup.
+    pub key_backup: KeyBackupState,
+}
+
+impl EncryptionState {
+    /// The state before anything is loaded.
+    const UNKNOWN: Self = Self {
+        has_cross_signing_identity: false,
+        is_session_verified: false,
+        recovery: RecoveryState::Unknown,
+        key_backup: KeyBackupState::Unknown,
+    };
+
+    /// The most important banner to show, if any.
+    ///
+    /// Verifying the session comes first, because the other actions are
+    /// only possible from a verified session. Nothing is suggested for the
+    /// states that couldn't be loaded.
+    pub fn banner(&self) -> Option<EncryptionBanner> {
+        if self.has_cross_signing_identity && !self.is_session_verified {
+            Some(EncryptionBanner::VerifySession)
+        } else if self.recovery == RecoveryState::Disabled {
+            Some(EncryptionBanner::SetUpRecovery)
+        } else if self.key_backup == KeyBackupState::Disabled {
+            Some(EncryptionBanner::KeyBackupOff)
+        } else {
+            None
+        }
+    }
+}
+
+/// Observer of the [`EncryptionState`] of the account.
+///
+/// See the module's documentation for more details. The background task
+/// updating the state is stopped when the observer is dropped.
+#[derive(Debug)]
+pub struct EncryptionStateObserver {
+    inner: Arc<Inner>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    task: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    client: Client,
+    state: Observable<EncryptionState>,
+}
+
+impl EncryptionStateObserver {
+    /// Create a new `EncryptionStateObserver` for the given client, and load
+    /// the current state.
+    ///
+    /// The client must be logged in.
+    pub async fn new(client: Client) -> Self {
+        let inner = Arc::new(Inner { client, state: Observable::new(EncryptionState::UNKNOWN) });
+        inner.refresh(true).await;
+
+        let task = spawn(Arc::clone(&inner).run());
+
+        Self { inner, task }
+    }
+
+    /// Get the current state.
+    pub fn get(&self) -> EncryptionState {
+        self.inner.state.get()
+    }
+
+    /// Get a subscriber to the state.
+    ///
+    /// The banner to show is given by [`EncryptionState::banner`].
+    pub fn subscribe(&self) -> Subscriber<EncryptionState> {
+        self.inner.state.subscribe()
+    }
+
+    /// Load the state again, including the state of the key backup.
+    pub async fn refresh(&self) {
+        self.inner.refresh(true).await;
+    }
+}
+
+impl Drop for EncryptionStateObserver {
+    fn drop(&mut self) {
+        // The task is aborted when its handle is dropped on WASM.
+        #[cfg(not(target_arch = "wasm32"))]
+        self.task.abort();
+    }
+}
+
+impl Inner {
+    async fn run(self: Arc<Self>) {
+        let Some(own_user_id) = self.client.user_id().map(ToOwned::to_owned) else {
+            warn!("Can't observe the encryption state of a client that isn't logged in");
+            return;
+        };
+
+        let encryption = self.client.encryption();
+        let (identities, devices) =
+            match (encryption.user_identity_updates().await, encryption.devices_stream().await) {
+                (Ok(identities), Ok(devices)) => (identities, devices),
+                (Err(error), _) | (_, Err(error)) => {
+                    warn!(?error, "Couldn't observe the encryption state");
+                    return;
+                }
+            };
+
+        // The items of the stream are whether the key backup might have changed.
+        let identities = identities
+            .filter(|user_ids| future::ready(user_ids.contains(&own_user_id)))
+            .map(|_| false);
+        let devices = devices
+            .filter(|update| future::ready(is_own_device(update, &own_user_id)))
+            .map(|_| false);
+        let account_data = account_data_updates(&self.client);
+
+        let updates = stream::select(stream::select(identities, devices), account_data);
+        pin_mut!(updates);
+
+        while let Some(reload_key_backup) = updates.next().await {
+            trace!("The encryption state might have changed");
+            self.refresh(reload_key_backup).await;
+        }
+    }
+
+    /// Load the state again.
+    ///
+    /// The state of the key backup is requested from the homeserver only if
+    /// `reload_key_backup` is set or if it isn't known yet, the previous one
+    /// is kept otherwise.
+    async fn refresh(&self, reload_key_backup: bool) {
+        let key_backup = match self.state.get().key_backup {
+            KeyBackupState::Unknown => load_key_backup(&self.client).await,
+            _ if reload_key_backup => load_key_backup(&self.client).await,
+            key_backup => key_backup,
+        };
+
+        let state = load(&self.client, key_backup).await;
+        self.state.set_if_not_eq(state);
+    }
+}
+
+fn is_own_device(update: &DeviceUpdate, own_user_id: &UserId) -> bool {
+    let user_id = match update {
+        DeviceUpdate::New(device) | DeviceUpdate::Updated(device) => device.user_id(),
+        DeviceUpdate::Deleted { user_id, .. } => &**user_id,
+    };
+    user_id == own_user_id
+}
+
+/// A stream that yields when account data related to the secret storage is
+/// received, with whether the backup key changed.
+fn account_data_updates(client: &Client) -> impl futures_core::Stream<Item = bool> {
+    let state_changes = client.subscribe_to_state_changes();
+    let default_key = GlobalAccountDataEventType::from(SECRET_STORAGE_DEFAULT_KEY);
+    let backup_secret = GlobalAccountDataEventType::from(MEGOLM_BACKUP_SECRET);
+
+    stream::unfold(state_changes, move |mut state_changes| {
+        let (default_key, backup_secret) = (default_key.clone(), backup_secret.clone());
+        async move {
+            loop {
+                match state_changes.recv().await {
+                    Ok(changes) => {
+                        let backup_changed = changes.account_data.contains_key(&backup_secret);
+                        if backup_changed || changes.account_data.contains_key(&default_key) {
+                            return Some((backup_changed, state_changes));
+                        }
+                    }
+                    // Some changes were missed, assume they were relevant.
+                    Err(RecvError::Lagged(_)) => return Some((true, state_changes)),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
+
+async fn load(client: &Client, key_backup: KeyBackupState) -> EncryptionState {
+    let (Some(user_id), Some(device_id)) = (client.user_id(), client.device_id()) else {
+        return EncryptionState::UNKNOWN;
+    };
+    let encryption = client.encryption();
+
+    let has_cross_signing_identity =
+        matches!(encryption.get_user_identity(user_id).await, Ok(Some(_)));
+    let is_session_verified = match encryption.get_device(user_id, device_id).await {
+        Ok(Some(device)) => device.is_cross_signed_by_owner(),
+        _ => false,
+    };
+
+    let recovery = match client
+        .account()
+        .account_data_raw(GlobalAccountDataEventType::from(SECRET_STORAGE_DEFAULT_KEY))
+        .await
+    {
+        Ok(None) => RecoveryState::Disabled,
+        Ok(Some(_)) => match encryption.cross_signing_status().await {
+            Some(status)
+                if status.has_master && status.has_self_signing && status.has_user_signing =>
+            {
+                RecoveryState::Enabled
+            }
+            _ => RecoveryState::Incomplete,
+        },
+        Err(error) => {
+            warn!(?error, "Couldn't load the secret storage default key");
+            RecoveryState::Unknown
+        }
+    };
+
+    EncryptionState { has_cross_signing_identity, is_session_verified, recovery, key_backup }
+}
+
+async fn load_key_backup(client: &Client) -> KeyBackupState {
+    match client.send(get_latest_backup_info::v3::Request::new(), None).await {
+        Ok(_) => KeyBackupState::Enabled,
+        Err(error) if error.client_api_error_kind() == Some(&ErrorKind::NotFound) => {
+            KeyBackupState::Disabled
+        }
+        Err(error) => {
+            warn!(?error, "Couldn't load the key backup info");
+            KeyBackupState::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EncryptionBanner, EncryptionState, KeyBackupState, RecoveryState};
+
+    #[test]
+    fn test_banner() {
+        let done = EncryptionState {
+            has_cross_signing_identity: true,
+            is_session_verified: true,
+            recovery: RecoveryState::Enabled,
+            key_backup: KeyBackupState::Enabled,
+        };
+        assert_eq!(done.banner(), None);
+
+        let unverified = EncryptionState { is_session_verified: false, ..done };
+        assert_eq!(unverified.banner(), Some(EncryptionBanner::VerifySession));
+
+        // A new account without cross-signing needs to set up recovery first.
+        let new_account = EncryptionState {
+            has_cross_signing_identity: false,
+            is_session_verified: false,
+            recovery: RecoveryState::Disabled,
+            key_backup: KeyBackupState::Disabled,
+        };
+        assert_eq!(new_account.banner(), Some(EncryptionBanner::SetUpRecovery));
+
+        let no_backup = EncryptionState { key_backup: KeyBackupState::Disabled, ..done };
+        assert_eq!(no_backup.banner(), Some(EncryptionBanner::KeyBackupOff));
+
+        // Nothing is suggested when the state couldn't be loaded.
+        assert_eq!(EncryptionState::UNKNOWN.banner(), None);
+    }
+}
@@ -12,14 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "e2e-encryption")]
+pub mod encryption_state;
 mod events;
 
 #[cfg(feature = "experimental-notification")]
 pub mod notifications;
 #[cfg(feature = "experimental-room-list")]
 pub mod room_list;
+#[cfg(feature = "experimental-sync-service")]
+pub mod sync_service;
 pub mod timeline;
 
+#[cfg(feature = "e2e-encryption")]
+pub use self::encryption_state::EncryptionStateObserver;
 #[cfg(feature = "experimental-room-list")]
 pub use self::room_list::RoomList;
+#[cfg(feature = "experimental-sync-service")]
+pub use self::sync_service::SyncService;
 pub use self::timeline::Timeline;
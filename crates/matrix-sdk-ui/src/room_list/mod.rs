@@ -72,8 +72,8 @@ use futures_util::{pin_mut, Stream, StreamExt};
 use imbl::Vector;
 pub use matrix_sdk::RoomListEntry;
 use matrix_sdk::{
-    sliding_sync::Ranges, Client, Error as SlidingSyncError, SlidingSync, SlidingSyncList,
-    SlidingSyncListLoadingState, SlidingSyncMode,
+    sliding_sync::Ranges, Client, Error as SlidingSyncError, SessionVerdict, SlidingSync,
+    SlidingSyncList, SlidingSyncListLoadingState, SlidingSyncMode,
 };
 pub use room::*;
 use ruma::{
@@ -88,6 +88,7 @@ use thiserror::Error;
 /// The [`RoomList`] type. See the module's documentation to learn more.
 #[derive(Debug)]
 pub struct RoomList {
+    client: Client,
     sliding_sync: Arc<SlidingSync>,
     state: Observable<State>,
 }
@@ -98,15 +99,32 @@ impl RoomList {
     /// A [`matrix_sdk::SlidingSync`] client will be created, with a cached list
     /// already pre-configured.
     pub async fn new(client: Client) -> Result<Self, Error> {
-        let sliding_sync = client
+        Self::new_with_encryption(client, true).await
+    }
+
+    /// Create a new `RoomList`, enabling or not the e2ee and to-device
+    /// extensions.
+    ///
+    /// The extensions must be disabled when the encryption is handled by
+    /// another sliding sync instance, like the one of a
+    /// [`SyncService`](crate::SyncService).
+    pub async fn new_with_encryption(client: Client, with_encryption: bool) -> Result<Self, Error> {
+        let mut builder = client
             .sliding_sync("room-list")
             .map_err(Error::SlidingSync)?
             .enable_caching()
             .map_err(Error::SlidingSync)?
-            .with_common_extensions()
-            // TODO different strategy when the encryption sync is in main by default
-            .with_e2ee_extension(assign!(E2EEConfig::default(), { enabled: Some(true) }))
-            .with_to_device_extension(assign!(ToDeviceConfig::default(), { enabled: Some(true) }))
+            .with_common_extensions();
+
+        if with_encryption {
+            builder = builder
+                .with_e2ee_extension(assign!(E2EEConfig::default(), { enabled: Some(true) }))
+                .with_to_device_extension(
+                    assign!(ToDeviceConfig::default(), { enabled: Some(true) }),
+                );
+        }
+
+        let sliding_sync = builder
             // TODO revert to `add_cached_list` when reloading rooms from the cache is blazingly
             // fast
             .add_list(
@@ -134,7 +152,7 @@ impl RoomList {
             .map(Arc::new)
             .map_err(Error::SlidingSync)?;
 
-        Ok(Self { sliding_sync, state: Observable::new(State::Init) })
+        Ok(Self { client, sliding_sync, state: Observable::new(State::Init) })
     }
 
     /// Start to sync the room list.
@@ -198,6 +216,14 @@ impl RoomList {
         self.state.subscribe()
     }
 
+    /// Get a subscriber to the [`SessionVerdict`] of the client.
+    ///
+    /// When the session needs a new login, the sync stops with an error, and
+    /// it will keep failing until the user logs in again.
+    pub fn session_verdict(&self) -> Subscriber<SessionVerdict> {
+        self.client.subscribe_to_session_verdict()
+    }
+
     /// Get all previous room list entries, in addition to a [`Stream`] to room
     /// list entry's updates.
     pub async fn entries(
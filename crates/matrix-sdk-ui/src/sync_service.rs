@@ -0,0 +1,377 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `SyncService` API.
+//!
+//! The [`SyncService`] is a high-level helper that runs all the sync loops an
+//! app needs, so that apps don't have to coordinate them by hand:
+//!
+//! * the sliding sync of a [`RoomList`], that keeps the rooms up to date,
+//! * a separate sliding sync dedicated to the encryption, with no lists but the
+//!   e2ee and to-device extensions enabled, like the one of a
+//!   [`NotificationSync`]. Keeping it apart allows to receive the encryption
+//!   keys while the room list is not synced, e.g. when the app is in the
+//!   background.
+//!
+//! The sync loops are run in the background, they are controlled with
+//! [`SyncService::start`], [`SyncService::pause`], [`SyncService::stop`] and
+//! [`SyncService::enter_background`]. The [`SyncService::state`] reflects what
+//! is running, and whether a sync loop failed. When a sync loop fails, all of
+//! them are stopped, so there is never a loop running on its own by mistake.
+//!
+//! If the loops stopped because the homeserver doesn't accept the session
+//! anymore, [`SyncService::session_verdict`] tells whether the user needs to
+//! log in again. It is the same signal as the one of the [`RoomList`] and the
+//! timelines, so apps only need to listen to one of them.
+
+use std::sync::{Arc, Mutex};
+
+use eyeball::{shared::Observable, Subscriber};
+use futures_util::{future, pin_mut, StreamExt};
+use matrix_sdk::{
+    executor::{spawn, JoinHandle},
+    Client, SessionVerdict,
+};
+use thiserror::Error;
+use tracing::{error, trace, warn};
+
+use crate::{
+    notifications::{self, NotificationSync},
+    room_list::{self, RoomList},
+};
+
+/// The ID of the sliding sync instance dedicated to the encryption.
+const ENCRYPTION_SYNC_ID: &str = "encryption";
+
+/// The state of a [`SyncService`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// The sync loops have never been started, or have been stopped.
+    Idle,
+    /// All the sync loops are running.
+    Running,
+    /// The app is in the background, only the encryption sync loop is running.
+    Background,
+    /// All the sync loops are paused, they can be resumed with
+    /// [`SyncService::start`].
+    Paused,
+    /// A sync loop failed, and all of them have been stopped. They can be
+    /// restarted with [`SyncService::start`].
+    Error,
+}
+
+/// High-level helper running the sync loops of an app.
+///
+/// See the module's documentation for more details.
+pub struct SyncService {
+    client: Client,
+    room_list: Arc<RoomList>,
+    encryption_sync: Option<Arc<NotificationSync>>,
+    state: Observable<State>,
+    /// The task running the sync loops, if any.
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SyncService {
+    /// Create a new builder for a `SyncService`.
+    pub fn builder(client: Client) -> SyncServiceBuilder {
+        SyncServiceBuilder::new(client)
+    }
+
+    /// The [`RoomList`] whose sliding sync is run by this service.
+    pub fn room_list(&self) -> Arc<RoomList> {
+        self.room_list.clone()
+    }
+
+    /// Get a subscriber to the state.
+    pub fn state(&self) -> Subscriber<State> {
+        self.state.subscribe()
+    }
+
+    /// Get a subscriber to the [`SessionVerdict`] of the client.
+    ///
+    /// When it says that the user needs to log in again, the sync loops stop
+    /// and the state becomes [`State::Error`]. Restarting them is pointless
+    /// until the session is restored.
+    pub fn session_verdict(&self) -> Subscriber<SessionVerdict> {
+        self.client.subscribe_to_session_verdict()
+    }
+
+    /// Start all the sync loops, or resume them.
+    ///
+    /// This does nothing if they are already running. If the app was in the
+    /// background, the room list sync loop is started again.
+    pub fn start(&self) {
+        let mut task = self.task.lock().unwrap();
+        if self.state.get() == State::Running {
+            trace!("The sync loops are already running");
+            return;
+        }
+
+        Self::abort(&mut task);
+        self.state.set(State::Running);
+        *task = Some(spawn(run_sync_loops(
+            self.client.clone(),
+            Some(self.room_list.clone()),
+            self.encryption_sync.clone(),
+            self.state.clone(),
+        )));
+    }
+
+    /// Keep only the encryption sync loop running, for when the app goes to
+    /// the background.
+    ///
+    /// This allows to still receive the encryption keys, to be able to
+    /// decrypt the notifications. If there is no encryption sync loop, this is
+    /// like [`SyncService::pause`], with a different state.
+    ///
+    /// Use [`SyncService::start`] when the app comes back to the foreground.
+    pub fn enter_background(&self) {
+        let mut task = self.task.lock().unwrap();
+        if self.state.get() == State::Background {
+            return;
+        }
+
+        Self::abort(&mut task);
+        self.state.set(State::Background);
+        *task = self.encryption_sync.clone().map(|encryption_sync| {
+            spawn(run_sync_loops(
+                self.client.clone(),
+                None,
+                Some(encryption_sync),
+                self.state.clone(),
+            ))
+        });
+    }
+
+    /// Pause all the sync loops.
+    ///
+    /// They can be resumed with [`SyncService::start`], from where they were.
+    pub fn pause(&self) {
+        let mut task = self.task.lock().unwrap();
+        Self::abort(&mut task);
+        self.state.set(State::Paused);
+    }
+
+    /// Stop all the sync loops.
+    pub fn stop(&self) {
+        let mut task = self.task.lock().unwrap();
+        Self::abort(&mut task);
+        self.state.set(State::Idle);
+    }
+
+    fn abort(task: &mut Option<JoinHandle<()>>) {
+        if let Some(task) = task.take() {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for SyncService {
+    fn drop(&mut self) {
+        Self::abort(self.task.get_mut().unwrap());
+    }
+}
+
+/// Run the given sync loops until one of them fails.
+async fn run_sync_loops(
+    client: Client,
+    room_list: Option<Arc<RoomList>>,
+    encryption_sync: Option<Arc<NotificationSync>>,
+    state: Observable<State>,
+) {
+    let room_list_loop = async move {
+        match room_list {
+            Some(room_list) => room_list_sync_loop(&room_list).await,
+            None => future::pending().await,
+        }
+    };
+    let encryption_loop = async move {
+        match encryption_sync {
+            Some(encryption_sync) => encryption_sync_loop(&encryption_sync).await,
+            None => future::pending().await,
+        }
+    };
+    pin_mut!(room_list_loop, encryption_loop);
+
+    future::select(room_list_loop, encryption_loop).await;
+
+    // Dropping the other loop stops it.
+    let verdict = client.session_verdict();
+    if verdict.needs_login() {
+        warn!(?verdict, "The sync loops stopped because the session is not valid anymore");
+    }
+
+    state.set(State::Error);
+}
+
+/// Run the sync loop of the room list until it fails.
+async fn room_list_sync_loop(room_list: &RoomList) {
+    let sync = room_list.sync();
+    pin_mut!(sync);
+
+    while let Some(result) = sync.next().await {
+        if let Err(error) = result {
+            error!("Error in the room list sync loop: {error:#}");
+            return;
+        }
+    }
+
+    warn!("The room list sync loop stopped");
+}
+
+/// Run the encryption sync loop until it fails.
+async fn encryption_sync_loop(encryption_sync: &NotificationSync) {
+    let sync = encryption_sync.sync();
+    pin_mut!(sync);
+
+    while let Some(result) = sync.next().await {
+        match result {
+            Ok(()) => {}
+            Err(error @ notifications::Error::UnexpectedNonEmptyListsOrRooms) => {
+                warn!("{error}");
+            }
+            Err(error) => {
+                error!("Error in the encryption sync loop: {error:#}");
+                return;
+            }
+        }
+    }
+
+    warn!("The encryption sync loop stopped");
+}
+
+/// Builder for a [`SyncService`].
+#[derive(Debug)]
+pub struct SyncServiceBuilder {
+    client: Client,
+    with_encryption_sync: bool,
+}
+
+impl SyncServiceBuilder {
+    fn new(client: Client) -> Self {
+        Self { client, with_encryption_sync: true }
+    }
+
+    /// Whether to run a separate sliding sync dedicated to the encryption.
+    ///
+    /// If it is disabled, the encryption is handled by the sliding sync of the
+    /// room list, and nothing is synced when the app is in the background.
+    ///
+    /// Defaults to `true`.
+    pub fn with_encryption_sync(mut self, enabled: bool) -> Self {
+        self.with_encryption_sync = enabled;
+        self
+    }
+
+    /// Create the [`SyncService`], without starting it.
+    pub async fn build(self) -> Result<SyncService, Error> {
+        let Self { client, with_encryption_sync } = self;
+
+        let room_list =
+            Arc::new(RoomList::new_with_encryption(client.clone(), !with_encryption_sync).await?);
+        let encryption_sync = if with_encryption_sync {
+            Some(Arc::new(NotificationSync::new(ENCRYPTION_SYNC_ID, client.clone()).await?))
+        } else {
+            None
+        };
+
+        Ok(SyncService {
+            client,
+            room_list,
+            encryption_sync,
+            state: Observable::new(State::Idle),
+            task: Mutex::new(None),
+        })
+    }
+}
+
+/// [`SyncService`]'s errors.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The room list couldn't be created.
+    #[error(transparent)]
+    RoomList(#[from] room_list::Error),
+
+    /// The encryption sync couldn't be created.
+    #[error(transparent)]
+    EncryptionSync(#[from] notifications::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::{config::RequestConfig, Session};
+    use matrix_sdk_test::async_test;
+    use ruma::{api::MatrixVersion, device_id, user_id};
+    use wiremock::MockServer;
+
+    use super::*;
+
+    async fn new_client() -> (Client, MockServer) {
+        let session = Session {
+            access_token: "1234".to_owned(),
+            refresh_token: None,
+            user_id: user_id!("@example:localhost").to_owned(),
+            device_id: device_id!("DEVICEID").to_owned(),
+        };
+
+        let server = MockServer::start().await;
+        let client = Client::builder()
+            .homeserver_url(server.uri())
+            .server_versions([MatrixVersion::V1_0])
+            .request_config(RequestConfig::new().disable_retry())
+            .build()
+            .await
+            .unwrap();
+        client.restore_session(session).await.unwrap();
+
+        (client, server)
+    }
+
+    #[async_test]
+    async fn test_lifecycle() -> Result<(), Error> {
+        let (client, _server) = new_client().await;
+        let sync_service = SyncService::builder(client).build().await?;
+        let state = sync_service.state();
+        assert_eq!(state.get(), State::Idle);
+
+        // The spawned task doesn't get a chance to run between these calls.
+        sync_service.start();
+        assert_eq!(state.get(), State::Running);
+
+        sync_service.enter_background();
+        assert_eq!(state.get(), State::Background);
+
+        sync_service.pause();
+        assert_eq!(state.get(), State::Paused);
+
+        sync_service.stop();
+        assert_eq!(state.get(), State::Idle);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_failing_sync_loop_stops_the_service() -> Result<(), Error> {
+        // The server doesn't answer the sliding sync requests.
+        let (client, _server) = new_client().await;
+        let sync_service = SyncService::builder(client).with_encryption_sync(false).build().await?;
+        sync_service.start();
+
+        let mut state = sync_service.state();
+        assert_eq!(state.next().await, Some(State::Error));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use eyeball_im::VectorDiff;
+use futures_core::Stream;
+use imbl::Vector;
+use pin_project_lite::pin_project;
+
+/// The number of updates in a batch above which the batch is replaced by a
+/// single [`VectorDiff::Reset`].
+pub(super) const BATCH_RESET_THRESHOLD: usize = 25;
+
+pin_project! {
+    /// A stream grouping all the updates of the items that are ready when it is
+    /// polled.
+    ///
+    /// Consecutive updates are coalesced when possible, and a batch with more
+    /// than [`BATCH_RESET_THRESHOLD`] updates is replaced by a
+    /// [`VectorDiff::Reset`] with the current items.
+    pub(super) struct BatchedStream<S, T: Clone> {
+        #[pin]
+        inner: S,
+        // A copy of the items with all the received updates applied, used for
+        // the resets.
+        items: Vector<T>,
+    }
+}
+
+impl<S, T: Clone> BatchedStream<S, T> {
+    pub(super) fn new(items: Vector<T>, inner: S) -> Self {
+        Self { inner, items }
+    }
+}
+
+impl<S, T> Stream for BatchedStream<S, T>
+where
+    S: Stream<Item = VectorDiff<T>>,
+    T: Clone,
+{
+    type Item = Vec<VectorDiff<T>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let mut batch = Vec::new();
+
+        loop {
+            match this.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(diff)) => {
+                    apply_diff(this.items, diff.clone());
+                    push_coalesced(&mut batch, diff);
+                }
+                // The end of the stream is reported at the next poll if there
+                // is a batch to return first.
+                Poll::Ready(None) if batch.is_empty() => return Poll::Ready(None),
+                Poll::Pending if batch.is_empty() => return Poll::Pending,
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        if batch.len() > BATCH_RESET_THRESHOLD {
+            batch = vec![VectorDiff::Reset { values: this.items.clone() }];
+        }
+
+        Poll::Ready(Some(batch))
+    }
+}
+
+/// Add an update to a batch, merging it with the last one when possible.
+fn push_coalesced<T: Clone>(batch: &mut Vec<VectorDiff<T>>, diff: VectorDiff<T>) {
+    match (batch.last_mut(), diff) {
+        (Some(VectorDiff::Append { values }), VectorDiff::PushBack { value }) => {
+            values.push_back(value);
+        }
+        (Some(VectorDiff::Append { values }), VectorDiff::Append { values: new_values }) => {
+            values.append(new_values);
+        }
+        (Some(last @ VectorDiff::PushBack { .. }), VectorDiff::PushBack { value }) => {
+            if let VectorDiff::PushBack { value: first } = last {
+                *last = VectorDiff::Append { values: Vector::from_iter([first.clone(), value]) };
+            }
+        }
+        (
+            Some(VectorDiff::Set { index: last_index, value: last_value }),
+            VectorDiff::Set { index, value },
+        ) if *last_index == index => {
+            *last_value = value;
+        }
+        // Everything that happened before is overridden.
+        (_, diff @ (VectorDiff::Reset { .. } | VectorDiff::Clear)) => {
+            batch.clear();
+            batch.push(diff);
+        }
+        (_, diff) => batch.push(diff),
+    }
+}
+
+/// Apply an update to a list of items.
+fn apply_diff<T: Clone>(items: &mut Vector<T>, diff: VectorDiff<T>) {
+    match diff {
+        VectorDiff::Append { values } => items.append(values),
+        VectorDiff::Clear => items.clear(),
+        VectorDiff::PushFront { value } => items.push_front(value),
+        VectorDiff::PushBack { value } => items.push_back(value),
+        VectorDiff::PopFront => {
+            items.pop_front();
+        }
+        VectorDiff::PopBack => {
+            items.pop_back();
+        }
+        VectorDiff::Insert { index, value } => items.insert(index, value),
+        VectorDiff::Set { index, value } => {
+            items.set(index, value);
+        }
+        VectorDiff::Remove { index } => {
+            items.remove(index);
+        }
+        VectorDiff::Reset { values } => *items = values,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use eyeball_im::{ObservableVector, VectorDiff};
+    use futures_util::StreamExt;
+    use imbl::vector;
+    use matrix_sdk_test::async_test;
+    use stream_assert::assert_pending;
+
+    use super::{BatchedStream, BATCH_RESET_THRESHOLD};
+
+    #[async_test]
+    async fn updates_are_batched_and_coalesced() {
+        let mut items = ObservableVector::new();
+        let mut stream = BatchedStream::new(items.clone(), items.subscribe());
+
+        items.push_back(1);
+        items.push_back(2);
+        items.set(0, 3);
+        items.set(0, 4);
+        items.push_front(0);
+
+        let batch = stream.next().await.unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_matches!(&batch[0], VectorDiff::Append { values } => {
+            assert_eq!(*values, vector![1, 2]);
+        });
+        assert_matches!(&batch[1], VectorDiff::Set { index: 0, value: 4 });
+        assert_matches!(&batch[2], VectorDiff::PushFront { value: 0 });
+        assert_pending!(stream);
+    }
+
+    #[async_test]
+    async fn large_batch_is_replaced_by_reset() {
+        // Big enough for the subscriber not to lag behind.
+        let mut items = ObservableVector::with_capacity(BATCH_RESET_THRESHOLD * 4);
+        items.push_back(0);
+        let mut stream = BatchedStream::new(items.clone(), items.subscribe());
+
+        for i in 1..=BATCH_RESET_THRESHOLD * 2 {
+            items.insert(0, i);
+        }
+
+        let batch = stream.next().await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_matches!(&batch[0], VectorDiff::Reset { values } => {
+            assert_eq!(*values, *items);
+        });
+        assert_pending!(stream);
+    }
+}
@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use async_std::sync::Mutex;
 use imbl::Vector;
@@ -121,6 +124,8 @@ impl TimelineBuilder {
             }
         }
 
+        inner.load_item_metadata().await;
+
         if has_events {
             inner.add_initial_events(events).await;
         }
@@ -133,11 +138,13 @@ impl TimelineBuilder {
         let client = room.client();
 
         let start_token = Arc::new(Mutex::new(prev_token));
+        let is_live = Arc::new(AtomicBool::new(true));
 
         let mut room_update_rx = room.subscribe_to_updates();
         let room_update_join_handle = spawn({
             let inner = inner.clone();
             let start_token = start_token.clone();
+            let is_live = is_live.clone();
             async move {
                 loop {
                     let update = match room_update_rx.recv().await {
@@ -150,6 +157,12 @@ impl TimelineBuilder {
                         }
                     };
 
+                    if !is_live.load(Ordering::SeqCst) {
+                        // The timeline is focused on a point in the past, live
+                        // events would create a gap in it.
+                        continue;
+                    }
+
                     let update_start_token = |prev_batch: &Option<_>| {
                         // Only update start_token if it's not currently locked.
                         // If it is locked, pagination is currently in progress.
@@ -189,6 +202,16 @@ impl TimelineBuilder {
             room.room_id().to_owned(),
         ));
 
+        #[cfg(feature = "e2e-encryption")]
+        let security_notices_join_handle =
+            match super::security_notices::security_notices_task(inner.clone()).await {
+                Ok(task) => Some(spawn(task)),
+                Err(e) => {
+                    warn!("Failed to observe the security notices: {e}");
+                    None
+                }
+            };
+
         let handles = vec![
             #[cfg(feature = "e2e-encryption")]
             room_key_handle,
@@ -200,11 +223,15 @@ impl TimelineBuilder {
             inner,
             start_token,
             start_token_condvar: Default::default(),
-            _end_token: Mutex::new(None),
+            end_token: Mutex::new(None),
+            is_live,
+            viewport_generation: Default::default(),
             drop_handle: Arc::new(TimelineDropHandle {
                 client,
                 event_handler_handles: handles,
                 room_update_join_handle,
+                #[cfg(feature = "e2e-encryption")]
+                security_notices_join_handle,
             }),
         };
 
@@ -0,0 +1,168 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, sync::Arc};
+
+use chrono::{DateTime, Local, TimeZone, Timelike};
+use eyeball_im::ObservableVector;
+use ruma::MilliSecondsSinceUnixEpoch;
+use tracing::trace;
+
+use super::TimelineItem;
+
+/// A function deciding whether a divider is needed between two events, given
+/// the timestamps of the previous event and of the next one.
+pub type DividerFn =
+    dyn Fn(MilliSecondsSinceUnixEpoch, MilliSecondsSinceUnixEpoch) -> bool + Send + Sync;
+
+/// When [`VirtualTimelineItem::DayDivider`]s are inserted between the events
+/// of a [`Timeline`].
+///
+/// Unless the policy is [`DividerPolicy::None`], the first event of the
+/// timeline is always preceded by a divider.
+///
+/// [`VirtualTimelineItem::DayDivider`]: super::VirtualTimelineItem::DayDivider
+/// [`Timeline`]: super::Timeline
+#[derive(Clone, Default)]
+pub enum DividerPolicy {
+    /// No dividers are inserted.
+    None,
+
+    /// A divider is inserted between events of different days, in local time.
+    #[default]
+    Daily,
+
+    /// A divider is inserted between events of different hours, in local
+    /// time.
+    Hourly,
+
+    /// A divider is inserted between two events when the function returns
+    /// `true` for their timestamps.
+    Custom(Arc<DividerFn>),
+}
+
+impl DividerPolicy {
+    /// Whether a divider is needed before an event with the `next` timestamp,
+    /// that follows an event with the `previous` timestamp, or that is the
+    /// first event if `previous` is `None`.
+    pub(super) fn needs_divider(
+        &self,
+        previous: Option<MilliSecondsSinceUnixEpoch>,
+        next: MilliSecondsSinceUnixEpoch,
+    ) -> bool {
+        let Some(previous) = previous else {
+            return !matches!(self, Self::None);
+        };
+
+        match self {
+            Self::None => false,
+            Self::Daily => {
+                let (previous, next) = (local_datetime(previous), local_datetime(next));
+                previous.date_naive() != next.date_naive()
+            }
+            Self::Hourly => {
+                let (previous, next) = (local_datetime(previous), local_datetime(next));
+                previous.date_naive() != next.date_naive() || previous.hour() != next.hour()
+            }
+            Self::Custom(f) => f(previous, next),
+        }
+    }
+
+    /// Get the divider to insert before an event with the `next` timestamp,
+    /// if one is needed.
+    pub(super) fn divider(
+        &self,
+        previous: Option<MilliSecondsSinceUnixEpoch>,
+        next: MilliSecondsSinceUnixEpoch,
+    ) -> Option<TimelineItem> {
+        self.needs_divider(previous, next).then(|| TimelineItem::day_divider(next))
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for DividerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::Daily => f.write_str("Daily"),
+            Self::Hourly => f.write_str("Hourly"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Converts a timestamp since Unix Epoch to a date and time in local time.
+fn local_datetime(ts: MilliSecondsSinceUnixEpoch) -> DateTime<Local> {
+    Local
+        .timestamp_millis_opt(ts.0.into())
+        // Only returns `None` if date is after Dec 31, 262143 BCE.
+        .single()
+        // Fallback to the current date to avoid issues with malicious
+        // homeservers.
+        .unwrap_or_else(Local::now)
+}
+
+/// Insert and remove dividers so the items follow the given policy.
+///
+/// The dividers that are still needed are kept, so only the dividers that
+/// changed are reported to the subscribers of the timeline.
+pub(super) fn adjust_dividers(
+    items: &mut ObservableVector<Arc<TimelineItem>>,
+    policy: &DividerPolicy,
+) {
+    let mut previous_ts = None;
+    // The divider since the previous event, if any.
+    let mut divider_idx = None;
+    let mut idx = 0;
+
+    while idx < items.len() {
+        if items[idx].is_day_divider() {
+            if divider_idx.is_some() {
+                trace!(idx, "Removing duplicate divider");
+                items.remove(idx);
+            } else {
+                divider_idx = Some(idx);
+                idx += 1;
+            }
+            continue;
+        }
+
+        let Some(ts) = items[idx].as_event().map(|event| event.timestamp()) else {
+            idx += 1;
+            continue;
+        };
+
+        match (policy.needs_divider(previous_ts, ts), divider_idx.take()) {
+            (true, None) => {
+                trace!(idx, "Adding divider");
+                items.insert(idx, Arc::new(TimelineItem::day_divider(ts)));
+                idx += 1;
+            }
+            (false, Some(divider_idx)) => {
+                trace!(idx = divider_idx, "Removing divider");
+                items.remove(divider_idx);
+                idx -= 1;
+            }
+            _ => {}
+        }
+
+        previous_ts = Some(ts);
+        idx += 1;
+    }
+
+    if let Some(divider_idx) = divider_idx {
+        trace!(idx = divider_idx, "Removing divider without events");
+        items.remove(divider_idx);
+    }
+}
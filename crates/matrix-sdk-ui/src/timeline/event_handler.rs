@@ -14,7 +14,6 @@
 
 use std::{collections::HashMap, sync::Arc};
 
-use chrono::{Datelike, Local, TimeZone};
 use eyeball_im::{ObservableVector, Vector};
 use indexmap::{map::Entry, IndexMap, IndexSet};
 use matrix_sdk::deserialized_responses::EncryptionInfo;
@@ -39,19 +38,23 @@ use ruma::{
     serde::Raw,
     EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedTransactionId, OwnedUserId,
 };
+use serde::Deserialize;
 use tracing::{debug, error, field::debug, info, instrument, trace, warn};
 
 use super::{
+    divider::DividerPolicy,
     event_item::{
         AnyOtherFullStateEventContent, BundledReactions, EventSendState, EventTimelineItemKind,
-        LocalEventTimelineItem, MemberProfileChange, OtherState, Profile, RemoteEventOrigin,
-        RemoteEventTimelineItem, RoomMembershipChange, Sticker,
+        ItemMetadata, LocalEventTimelineItem, MemberProfileChange, OtherState, Profile,
+        RemoteEventOrigin, RemoteEventTimelineItem, RoomMembershipChange, Sticker,
     },
     find_read_marker,
+    language::{detect_language, LanguageDetector},
     read_receipts::maybe_add_implicit_read_receipt,
-    rfind_event_by_id, rfind_event_item, EventTimelineItem, MembershipChange, Message,
-    ReactionGroup, TimelineDetails, TimelineInnerState, TimelineItem, TimelineItemContent,
-    VirtualTimelineItem, DEFAULT_SANITIZER_MODE,
+    rfind_event_by_id, rfind_event_item, EventOrdering, EventTimelineItem, MembershipChange,
+    Message, ReactionGroup, ReadReceipt, SecurityNotice, SecurityNoticeSettings, TimelineDetails,
+    TimelineInnerState, TimelineItem, TimelineItemContent, VirtualTimelineItem,
+    DEFAULT_SANITIZER_MODE,
 };
 use crate::events::SyncTimelineEventWithoutContent;
 
@@ -62,7 +65,7 @@ pub(super) enum Flow {
     Remote {
         event_id: OwnedEventId,
         txn_id: Option<OwnedTransactionId>,
-        raw_event: Raw<AnySyncTimelineEvent>,
+        raw_event: Arc<Raw<AnySyncTimelineEvent>>,
         position: TimelineItemPosition,
     },
 }
@@ -73,7 +76,7 @@ pub(super) struct TimelineEventMetadata {
     pub(super) timestamp: MilliSecondsSinceUnixEpoch,
     pub(super) is_own_event: bool,
     pub(super) encryption_info: Option<EncryptionInfo>,
-    pub(super) read_receipts: IndexMap<OwnedUserId, Receipt>,
+    pub(super) read_receipts: IndexMap<OwnedUserId, ReadReceipt>,
     pub(super) is_highlighted: bool,
 }
 
@@ -133,6 +136,22 @@ impl TimelineEventKind {
             },
         }
     }
+
+    /// Create a `TimelineEventKind` for an encrypted state event that couldn't
+    /// be decrypted, so it is shown like a message that couldn't be
+    /// decrypted, and its decryption can be retried.
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    pub(super) fn undecrypted_state(raw: &Raw<AnySyncTimelineEvent>) -> Option<Self> {
+        if !matrix_sdk_base::encrypted_state::is_encrypted_state_event(raw) {
+            return None;
+        }
+
+        let content = raw.get_field::<RoomEncryptedEventContent>("content").ok().flatten()?;
+        Some(Self::Message {
+            content: AnyMessageLikeEventContent::RoomEncrypted(content),
+            relations: Default::default(),
+        })
+    }
 }
 
 impl From<AnySyncTimelineEvent> for TimelineEventKind {
@@ -178,8 +197,8 @@ impl From<AnySyncTimelineEvent> for TimelineEventKind {
 pub(super) enum TimelineItemPosition {
     Start,
     End {
-        /// Whether this event is coming from a local cache.
-        from_cache: bool,
+        /// Where this event is coming from.
+        origin: RemoteEventOrigin,
     },
     #[cfg(feature = "e2e-encryption")]
     Update(usize),
@@ -207,11 +226,17 @@ pub(super) struct TimelineEventHandler<'a> {
         (OwnedUserId, Annotation),
     >,
     pending_reactions: &'a mut HashMap<OwnedEventId, IndexSet<OwnedEventId>>,
+    pending_edits: &'a mut HashMap<OwnedEventId, PendingEdit>,
     fully_read_event: &'a mut Option<OwnedEventId>,
     event_should_update_fully_read_marker: &'a mut bool,
     track_read_receipts: bool,
     users_read_receipts:
         &'a mut HashMap<OwnedUserId, HashMap<ReceiptType, (OwnedEventId, Receipt)>>,
+    item_metadata: &'a HashMap<OwnedEventId, ItemMetadata>,
+    event_ordering: EventOrdering,
+    divider_policy: DividerPolicy,
+    security_notices: SecurityNoticeSettings,
+    language_detector: Option<Arc<dyn LanguageDetector>>,
     result: HandleEventResult,
 }
 
@@ -243,10 +268,16 @@ impl<'a> TimelineEventHandler<'a> {
             items: &mut state.items,
             reaction_map: &mut state.reaction_map,
             pending_reactions: &mut state.pending_reactions,
+            pending_edits: &mut state.pending_edits,
             fully_read_event: &mut state.fully_read_event,
             event_should_update_fully_read_marker: &mut state.event_should_update_fully_read_marker,
             track_read_receipts,
             users_read_receipts: &mut state.users_read_receipts,
+            item_metadata: &state.item_metadata,
+            event_ordering: state.event_ordering,
+            divider_policy: state.divider_policy.clone(),
+            security_notices: state.security_notices,
+            language_detector: state.language_detector.clone(),
             result: HandleEventResult::default(),
         }
     }
@@ -287,7 +318,12 @@ impl<'a> TimelineEventHandler<'a> {
                     self.handle_room_message_edit(re);
                 }
                 AnyMessageLikeEventContent::RoomMessage(c) => {
-                    self.add(NewEventTimelineItem::message(c, relations, self.items));
+                    self.add(NewEventTimelineItem::message(
+                        c,
+                        relations,
+                        self.items,
+                        self.language_detector.as_deref(),
+                    ));
                 }
                 AnyMessageLikeEventContent::RoomEncrypted(c) => self.handle_room_encrypted(c),
                 AnyMessageLikeEventContent::Sticker(c) => {
@@ -315,7 +351,18 @@ impl<'a> TimelineEventHandler<'a> {
             }
 
             TimelineEventKind::OtherState { state_key, content } => {
+                let enables_encryption = matches!(
+                    content,
+                    AnyOtherFullStateEventContent::RoomEncryption(
+                        FullStateEventContent::Original { prev_content: None, .. }
+                    )
+                );
+
                 self.add(NewEventTimelineItem::other_state(state_key, content));
+
+                if enables_encryption && self.security_notices.encryption_enabled {
+                    self.add_security_notice_after_event(SecurityNotice::EncryptionEnabled);
+                }
             }
 
             TimelineEventKind::FailedToParseMessageLike { event_type, error } => {
@@ -356,6 +403,11 @@ impl<'a> TimelineEventHandler<'a> {
                 return None;
             }
 
+            let edit_json = match &self.flow {
+                Flow::Local { .. } => None,
+                Flow::Remote { raw_event, .. } => Some(raw_event.clone()),
+            };
+
             let msg = match &event_item.content() {
                 TimelineItemContent::Message(msg) => msg,
                 TimelineItemContent::RedactedMessage => {
@@ -367,7 +419,14 @@ impl<'a> TimelineEventHandler<'a> {
                     return None;
                 }
                 TimelineItemContent::UnableToDecrypt(_) => {
-                    info!("Edit event applies to event that couldn't be decrypted, discarding");
+                    // The original event might be decrypted later, keep the
+                    // edit around to apply it then.
+                    info!("Edit event applies to event that couldn't be decrypted, keeping it");
+                    let mut new_content = replacement.new_content.clone();
+                    new_content.sanitize(DEFAULT_SANITIZER_MODE, RemoveReplyFallback::No);
+                    let pending_edit =
+                        PendingEdit { sender: self.meta.sender.clone(), new_content, edit_json };
+                    self.pending_edits.insert(replacement.event_id.clone(), pending_edit);
                     return None;
                 }
                 TimelineItemContent::MembershipChange(_)
@@ -387,17 +446,14 @@ impl<'a> TimelineEventHandler<'a> {
             // Edit's content is never supposed to contain the reply fallback.
             msgtype.sanitize(DEFAULT_SANITIZER_MODE, RemoveReplyFallback::No);
 
+            let language = detect_language(self.language_detector.as_deref(), &msgtype);
             let new_content = TimelineItemContent::Message(Message {
                 msgtype,
                 in_reply_to: msg.in_reply_to.clone(),
                 edited: true,
+                language,
             });
 
-            let edit_json = match &self.flow {
-                Flow::Local { .. } => None,
-                Flow::Remote { raw_event, .. } => Some(raw_event.clone()),
-            };
-
             trace!("Applying edit");
             Some(event_item.with_content(new_content, edit_json))
         });
@@ -407,13 +463,19 @@ impl<'a> TimelineEventHandler<'a> {
     #[instrument(skip_all, fields(relates_to_event_id = ?c.relates_to.event_id))]
     fn handle_reaction(&mut self, c: ReactionEventContent) {
         let event_id: &EventId = &c.relates_to.event_id;
-        let (reaction_id, old_txn_id) = match &self.flow {
-            Flow::Local { txn_id, .. } => ((Some(txn_id.clone()), None), None),
-            Flow::Remote { event_id, txn_id, .. } => {
-                ((None, Some(event_id.clone())), txn_id.as_ref())
-            }
+        let (reaction_id, old_txn_id, is_counted_by_server) = match &self.flow {
+            Flow::Local { txn_id, .. } => ((Some(txn_id.clone()), None), None, false),
+            Flow::Remote { event_id, txn_id, position, .. } => (
+                (None, Some(event_id.clone())),
+                txn_id.as_ref(),
+                // Reactions loaded by back-pagination are older than the summary
+                // of the server.
+                matches!(position, TimelineItemPosition::Start),
+            ),
         };
 
+        let mut updated_item = None;
+
         if let Some((idx, event_item)) = rfind_event_by_id(self.items, event_id) {
             let Some(remote_event_item) = event_item.as_remote() else {
                 error!("inconsistent state: reaction received on a non-remote event item");
@@ -431,23 +493,21 @@ impl<'a> TimelineEventHandler<'a> {
 
                 if let Some(txn_id) = old_txn_id {
                     // Remove the local echo from the related event.
-                    if reaction_group.0.remove(&(Some(txn_id.clone()), None)).is_none() {
+                    if !reaction_group.remove(&(Some(txn_id.clone()), None)) {
                         warn!(
                             "Received reaction with transaction ID, but didn't \
                              find matching reaction in the related event's reactions"
                         );
                     }
                 }
-                reaction_group.0.insert(reaction_id.clone(), self.meta.sender.clone());
-
-                trace!("Adding reaction");
-                self.items.set(
-                    idx,
-                    Arc::new(TimelineItem::Event(
-                        event_item.with_kind(remote_event_item.with_reactions(reactions)),
-                    )),
-                );
-                self.result.items_updated += 1;
+                if is_counted_by_server {
+                    reaction_group.add_counted(reaction_id.clone(), self.meta.sender.clone());
+                } else {
+                    reaction_group.add_new(reaction_id.clone(), self.meta.sender.clone());
+                }
+
+                updated_item =
+                    Some((idx, event_item.with_kind(remote_event_item.with_reactions(reactions))));
             }
         } else {
             trace!("Timeline item not found, adding reaction to the pending list");
@@ -471,6 +531,13 @@ impl<'a> TimelineEventHandler<'a> {
             }
         }
         self.reaction_map.insert(reaction_id, (self.meta.sender.clone(), c.relates_to));
+
+        // Only notify the subscribers once the reaction map is up to date.
+        if let Some((idx, item)) = updated_item {
+            trace!("Adding reaction");
+            self.items.set(idx, Arc::new(TimelineItem::Event(item)));
+            self.result.items_updated += 1;
+        }
     }
 
     #[instrument(skip_all)]
@@ -497,7 +564,7 @@ impl<'a> TimelineEventHandler<'a> {
                     };
                     let group = group_entry.get_mut();
 
-                    if group.0.remove(&(None, Some(redacts.clone()))).is_none() {
+                    if !group.remove(&(None, Some(redacts.clone()))) {
                         error!(
                             "inconsistent state: reaction from reaction_map not in reaction list \
                              of timeline item"
@@ -505,7 +572,7 @@ impl<'a> TimelineEventHandler<'a> {
                         return None;
                     }
 
-                    group.len()
+                    group.count()
                 };
 
                 if count == 0 {
@@ -561,11 +628,12 @@ impl<'a> TimelineEventHandler<'a> {
     fn add(&mut self, item: NewEventTimelineItem) {
         self.result.item_added = true;
 
-        let NewEventTimelineItem { content } = item;
+        let NewEventTimelineItem { mut content } = item;
         let sender = self.meta.sender.to_owned();
         let sender_profile = TimelineDetails::from_initial_value(self.meta.sender_profile.clone());
         let timestamp = self.meta.timestamp;
         let mut reactions = self.pending_reactions().unwrap_or_default();
+        let latest_edit_json = self.apply_pending_edit(&mut content);
 
         let kind: EventTimelineItemKind = match &self.flow {
             Flow::Local { txn_id } => {
@@ -574,19 +642,21 @@ impl<'a> TimelineEventHandler<'a> {
                 LocalEventTimelineItem { send_state, transaction_id }
             }
             .into(),
-            Flow::Remote { event_id, raw_event, position, .. } => {
+            Flow::Remote { event_id, raw_event, txn_id, position } => {
                 // Drop pending reactions if the message is redacted.
                 if let TimelineItemContent::RedactedMessage = content {
                     if !reactions.is_empty() {
                         reactions = BundledReactions::default();
                     }
+                } else {
+                    for (key, count) in bundled_reaction_counts(raw_event) {
+                        reactions.entry(key).or_default().server_count = Some(count);
+                    }
                 }
 
                 let origin = match position {
                     TimelineItemPosition::Start => RemoteEventOrigin::Pagination,
-                    // We only paginate backwards for now, so End only happens for syncs
-                    TimelineItemPosition::End { from_cache: true } => RemoteEventOrigin::Cache,
-                    TimelineItemPosition::End { from_cache: false } => RemoteEventOrigin::Sync,
+                    TimelineItemPosition::End { origin } => *origin,
                     #[cfg(feature = "e2e-encryption")]
                     TimelineItemPosition::Update(idx) => self.items[*idx]
                         .as_event()
@@ -599,14 +669,16 @@ impl<'a> TimelineEventHandler<'a> {
 
                 RemoteEventTimelineItem {
                     event_id: event_id.clone(),
+                    transaction_id: txn_id.clone(),
                     reactions,
                     read_receipts: self.meta.read_receipts.clone(),
                     is_own: self.meta.is_own_event,
                     is_highlighted: self.meta.is_highlighted,
                     encryption_info: self.meta.encryption_info.clone(),
                     original_json: raw_event.clone(),
-                    latest_edit_json: None,
+                    latest_edit_json,
                     origin,
+                    metadata: self.item_metadata.get(event_id).cloned().unwrap_or_default(),
                 }
                 .into()
             }
@@ -618,21 +690,12 @@ impl<'a> TimelineEventHandler<'a> {
             Flow::Local { .. } => {
                 trace!("Adding new local timeline item");
 
-                // Check if the latest event has the same date as this event.
-                if let Some(latest_event) = self.items.iter().rev().find_map(|item| item.as_event())
-                {
-                    let old_ts = latest_event.timestamp();
-
-                    if let Some(day_divider_item) =
-                        maybe_create_day_divider_from_timestamps(old_ts, timestamp)
-                    {
-                        trace!("Adding day divider");
-                        self.items.push_back(Arc::new(day_divider_item));
-                    }
-                } else {
-                    // If there is no event item, there is no day divider yet.
-                    trace!("Adding first day divider");
-                    self.items.push_back(Arc::new(TimelineItem::day_divider(timestamp)));
+                // Check if a divider is needed between the latest event and
+                // this event.
+                let latest_ts = self.latest_event_timestamp();
+                if let Some(day_divider_item) = self.divider_policy.divider(latest_ts, timestamp) {
+                    trace!("Adding day divider");
+                    self.items.push_back(Arc::new(day_divider_item));
                 }
 
                 self.items.push_back(Arc::new(item.into()));
@@ -660,19 +723,31 @@ impl<'a> TimelineEventHandler<'a> {
                     _ => 0,
                 };
 
-                // Check if the earliest day divider has the same date as this event.
-                if let Some(VirtualTimelineItem::DayDivider(divider_ts)) =
-                    self.items.get(offset).and_then(|item| item.as_virtual())
-                {
-                    if let Some(day_divider_item) =
-                        maybe_create_day_divider_from_timestamps(*divider_ts, timestamp)
-                    {
-                        self.items.insert(offset, Arc::new(day_divider_item));
+                // Check if a divider is needed between this event and the
+                // earliest event, which is preceded by a divider unless they
+                // are disabled.
+                let earliest_ts = self
+                    .items
+                    .iter()
+                    .skip(offset)
+                    .find_map(|item| Some(item.as_event()?.timestamp()));
+                let has_leading_divider =
+                    self.items.get(offset).is_some_and(|item| item.is_day_divider());
+
+                let insert_day_divider = match (earliest_ts, has_leading_divider) {
+                    (Some(earliest_ts), true) => {
+                        self.divider_policy.needs_divider(Some(timestamp), earliest_ts)
                     }
-                } else {
-                    // The list must always start with a day divider.
+                    // A divider without events, reuse it.
+                    (None, true) => false,
+                    // The list starts with a divider, unless they are disabled.
+                    (_, false) => self.divider_policy.needs_divider(None, timestamp),
+                };
+                if insert_day_divider {
                     self.items.insert(offset, Arc::new(TimelineItem::day_divider(timestamp)));
                 }
+                let event_idx =
+                    if insert_day_divider || has_leading_divider { offset + 1 } else { offset };
 
                 if self.track_read_receipts {
                     maybe_add_implicit_read_receipt(
@@ -684,7 +759,7 @@ impl<'a> TimelineEventHandler<'a> {
                     );
                 }
 
-                self.items.insert(offset + 1, Arc::new(item.into()));
+                self.items.insert(event_idx, Arc::new(item.into()));
             }
 
             Flow::Remote {
@@ -719,13 +794,21 @@ impl<'a> TimelineEventHandler<'a> {
                         // https://github.com/matrix-org/sliding-sync/issues/3
                         // TODO: Raise log level once that bug is fixed
                         trace!("Received remote echo without transaction ID");
+
+                        // Keep the transaction ID of the local echo, so the
+                        // item keeps the same unique ID.
+                        if let Some(old_txn_id) = old_item.transaction_id() {
+                            item.as_remote_mut()
+                                .expect("Can't have a local item when flow == Remote")
+                                .transaction_id = Some(old_txn_id.to_owned());
+                        }
                     }
 
                     // TODO: Check whether anything is different about the
                     //       old and new item?
 
                     if idx == self.items.len() - 1
-                        && timestamp_to_date(old_item.timestamp()) == timestamp_to_date(timestamp)
+                        && !self.divider_policy.needs_divider(Some(old_item.timestamp()), timestamp)
                     {
                         // If the old item is the last one and no day divider
                         // changes need to happen, replace and return early.
@@ -749,15 +832,10 @@ impl<'a> TimelineEventHandler<'a> {
                         trace!("Removing local echo or duplicate timeline item");
                         self.items.remove(idx);
 
-                        assert_ne!(
-                            idx, 0,
-                            "there is never an event item at index 0 because \
-                             the first event item is preceded by a day divider"
-                        );
-
                         // Pre-requisites for removing the day divider:
                         // 1. there is one preceding the old item at all
-                        if self.items[idx - 1].is_day_divider()
+                        if idx > 0
+                            && self.items[idx - 1].is_day_divider()
                             // 2. the item after the old one that was removed
                             //    is virtual (it should be impossible for this
                             //    to be a read marker)
@@ -780,21 +858,29 @@ impl<'a> TimelineEventHandler<'a> {
                     );
                 }
 
-                // Check if the latest event has the same date as this event.
-                if let Some(latest_event) = self.items.iter().rev().find_map(|item| item.as_event())
-                {
-                    let old_ts = latest_event.timestamp();
-
-                    if let Some(day_divider_item) =
-                        maybe_create_day_divider_from_timestamps(old_ts, timestamp)
-                    {
-                        trace!("Adding day divider");
-                        self.items.push_back(Arc::new(day_divider_item));
+                if let Some(idx) = self.late_event_position(timestamp) {
+                    if self.track_read_receipts {
+                        maybe_add_implicit_read_receipt(
+                            idx,
+                            &mut item,
+                            self.meta.is_own_event,
+                            self.items,
+                            self.users_read_receipts,
+                        );
                     }
-                } else {
-                    // If there is no event item, there is no day divider yet.
-                    trace!("Adding first day divider");
-                    self.items.push_back(Arc::new(TimelineItem::day_divider(timestamp)));
+
+                    trace!(idx, "Inserting late remote timeline item");
+                    self.items.insert(idx, Arc::new(item.into()));
+                    self.maybe_update_read_marker();
+                    return;
+                }
+
+                // Check if a divider is needed between the latest event and
+                // this event.
+                let latest_ts = self.latest_event_timestamp();
+                if let Some(day_divider_item) = self.divider_policy.divider(latest_ts, timestamp) {
+                    trace!("Adding day divider");
+                    self.items.push_back(Arc::new(day_divider_item));
                 }
 
                 if self.track_read_receipts {
@@ -814,11 +900,50 @@ impl<'a> TimelineEventHandler<'a> {
             #[cfg(feature = "e2e-encryption")]
             Flow::Remote { position: TimelineItemPosition::Update(idx), .. } => {
                 trace!("Updating timeline item at position {idx}");
+
+                // Keep the state that was attached to the item while it
+                // couldn't be decrypted, so the update is minimal.
+                if let Some(old_item) = self.items[*idx].as_event().and_then(|ev| ev.as_remote()) {
+                    let is_redacted = item.content.is_redacted();
+                    let remote =
+                        item.as_remote_mut().expect("Can't have a local item when flow == Remote");
+
+                    remote.read_receipts.extend(old_item.read_receipts.clone());
+
+                    if !is_redacted {
+                        for (key, group) in &old_item.reactions {
+                            remote.reactions.entry(key.clone()).or_default().merge(group);
+                        }
+                    }
+                }
+
                 self.items.set(*idx, Arc::new(item.into()));
             }
         }
 
-        // See if we can update the read marker.
+        self.maybe_update_read_marker();
+    }
+
+    /// Add a security notice right after the item of the current event.
+    fn add_security_notice_after_event(&mut self, notice: SecurityNotice) {
+        let Flow::Remote { event_id, .. } = &self.flow else { return };
+        let Some((idx, _)) = rfind_event_by_id(self.items, event_id) else { return };
+
+        let next_notice = match self.items.get(idx + 1).and_then(|item| item.as_virtual()) {
+            Some(VirtualTimelineItem::SecurityNotice { notice, .. }) => Some(notice),
+            _ => None,
+        };
+        if next_notice == Some(&notice) {
+            trace!("Security notice already present");
+            return;
+        }
+
+        trace!(?notice, "Adding security notice");
+        self.items.insert(idx + 1, Arc::new(TimelineItem::security_notice(notice)));
+    }
+
+    /// See if we can update the read marker after an event was added.
+    fn maybe_update_read_marker(&mut self) {
         if *self.event_should_update_fully_read_marker {
             update_read_marker(
                 self.items,
@@ -828,6 +953,86 @@ impl<'a> TimelineEventHandler<'a> {
         }
     }
 
+    /// Find the position of a live event that was received after events that
+    /// were sent later, if the timeline is ordered by timestamp.
+    ///
+    /// Returns `None` if the event should be added at the end.
+    fn late_event_position(&self, timestamp: MilliSecondsSinceUnixEpoch) -> Option<usize> {
+        let EventOrdering::Timestamp { window } = self.event_ordering else {
+            return None;
+        };
+        let window = u64::try_from(window.as_millis()).unwrap_or(u64::MAX);
+        let ts = u64::from(timestamp.0);
+        let mut position = None;
+
+        for (idx, item) in self.items.iter().enumerate().rev() {
+            // Events are not reordered across virtual items, like day dividers
+            // or the read marker, or across local echoes.
+            let Some(event) = item.as_event().filter(|event| event.as_remote().is_some()) else {
+                break;
+            };
+
+            let later_ts = u64::from(event.timestamp().0);
+            if later_ts <= ts || later_ts - ts > window {
+                break;
+            }
+
+            position = Some(idx);
+        }
+
+        // An event that would need a day divider is added at the end instead.
+        position.filter(|idx| {
+            self.items[*idx].as_event().is_some_and(|event| {
+                !self.divider_policy.needs_divider(Some(timestamp), event.timestamp())
+            })
+        })
+    }
+
+    /// The timestamp of the latest event of the timeline, if any.
+    fn latest_event_timestamp(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.items.iter().rev().find_map(|item| Some(item.as_event()?.timestamp()))
+    }
+
+    /// Apply the edit that was received for this event while it couldn't be
+    /// decrypted, if any.
+    ///
+    /// Returns the JSON of the edit event if it was applied.
+    fn apply_pending_edit(
+        &mut self,
+        content: &mut TimelineItemContent,
+    ) -> Option<Arc<Raw<AnySyncTimelineEvent>>> {
+        let Flow::Remote { event_id, .. } = &self.flow else { return None };
+        if let TimelineItemContent::UnableToDecrypt(_) = content {
+            // Keep the edit until the event is decrypted.
+            return None;
+        }
+        let edit = self.pending_edits.remove(event_id)?;
+
+        if edit.sender != self.meta.sender {
+            info!(
+                original_sender = ?self.meta.sender, edit_sender = ?edit.sender,
+                "Pending edit applies to another user's timeline item, discarding"
+            );
+            return None;
+        }
+
+        let TimelineItemContent::Message(msg) = content else {
+            info!("Pending edit applies to an event that is not a message, discarding");
+            return None;
+        };
+
+        trace!("Applying pending edit");
+        let language = detect_language(self.language_detector.as_deref(), &edit.new_content);
+        *content = TimelineItemContent::Message(Message {
+            msgtype: edit.new_content,
+            in_reply_to: msg.in_reply_to.clone(),
+            edited: true,
+            language,
+        });
+
+        edit.edit_json
+    }
+
     fn pending_reactions(&mut self) -> Option<BundledReactions> {
         match &self.flow {
             Flow::Local { .. } => None,
@@ -846,7 +1051,7 @@ impl<'a> TimelineEventHandler<'a> {
 
                     let group: &mut ReactionGroup =
                         bundled.entry(annotation.key.clone()).or_default();
-                    group.0.insert(reaction_id, sender.clone());
+                    group.add_counted(reaction_id, sender.clone());
                 }
 
                 Some(bundled)
@@ -855,6 +1060,64 @@ impl<'a> TimelineEventHandler<'a> {
     }
 }
 
+/// An edit of an event that couldn't be decrypted yet, to apply once it is.
+#[derive(Clone, Debug)]
+pub(super) struct PendingEdit {
+    sender: OwnedUserId,
+    /// The new content, already sanitized.
+    new_content: MessageType,
+    edit_json: Option<Arc<Raw<AnySyncTimelineEvent>>>,
+}
+
+/// Get the number of reactions for each key summarized by the server in the
+/// bundled aggregations of the given event.
+fn bundled_reaction_counts(raw_event: &Raw<AnySyncTimelineEvent>) -> Vec<(String, u64)> {
+    #[derive(Deserialize)]
+    struct UnsignedDeHelper {
+        #[serde(rename = "m.relations", default)]
+        relations: RelationsDeHelper,
+    }
+
+    #[derive(Default, Deserialize)]
+    struct RelationsDeHelper {
+        #[serde(rename = "m.annotation")]
+        annotation: Option<AnnotationsDeHelper>,
+    }
+
+    #[derive(Deserialize)]
+    struct AnnotationsDeHelper {
+        chunk: Vec<AnnotationCountDeHelper>,
+    }
+
+    #[derive(Deserialize)]
+    struct AnnotationCountDeHelper {
+        #[serde(rename = "type")]
+        event_type: String,
+        key: String,
+        count: u64,
+    }
+
+    let unsigned = match raw_event.get_field::<UnsignedDeHelper>("unsigned") {
+        Ok(unsigned) => unsigned,
+        Err(e) => {
+            debug!("Failed to parse the bundled annotations of an event: {e}");
+            None
+        }
+    };
+
+    unsigned
+        .and_then(|unsigned| unsigned.relations.annotation)
+        .map(|annotation| {
+            annotation
+                .chunk
+                .into_iter()
+                .filter(|count| count.event_type == "m.reaction")
+                .map(|count| (count.key, count.count))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 pub(crate) fn update_read_marker(
     items: &mut ObservableVector<Arc<TimelineItem>>,
     fully_read_event: Option<&EventId>,
@@ -925,36 +1188,6 @@ fn _update_timeline_item(
     }
 }
 
-#[derive(PartialEq)]
-struct Date {
-    year: i32,
-    month: u32,
-    day: u32,
-}
-
-/// Converts a timestamp since Unix Epoch to a year, month and day.
-fn timestamp_to_date(ts: MilliSecondsSinceUnixEpoch) -> Date {
-    let datetime = Local
-        .timestamp_millis_opt(ts.0.into())
-        // Only returns `None` if date is after Dec 31, 262143 BCE.
-        .single()
-        // Fallback to the current date to avoid issues with malicious
-        // homeservers.
-        .unwrap_or_else(Local::now);
-
-    Date { year: datetime.year(), month: datetime.month(), day: datetime.day() }
-}
-
-/// Returns a new day divider item for the new timestamp if it is on a different
-/// day than the old timestamp
-fn maybe_create_day_divider_from_timestamps(
-    old_ts: MilliSecondsSinceUnixEpoch,
-    new_ts: MilliSecondsSinceUnixEpoch,
-) -> Option<TimelineItem> {
-    (timestamp_to_date(old_ts) != timestamp_to_date(new_ts))
-        .then(|| TimelineItem::day_divider(new_ts))
-}
-
 struct NewEventTimelineItem {
     content: TimelineItemContent,
 }
@@ -966,9 +1199,14 @@ impl NewEventTimelineItem {
         c: RoomMessageEventContent,
         relations: BundledMessageLikeRelations<AnySyncMessageLikeEvent>,
         timeline_items: &Vector<Arc<TimelineItem>>,
+        language_detector: Option<&dyn LanguageDetector>,
     ) -> Self {
-        let content =
-            TimelineItemContent::Message(Message::from_event(c, relations, timeline_items));
+        let content = TimelineItemContent::Message(Message::from_event(
+            c,
+            relations,
+            timeline_items,
+            language_detector,
+        ));
 
         Self::from_content(content)
     }
@@ -15,7 +15,7 @@
 use std::{fmt, ops::Deref, sync::Arc};
 
 use imbl::{vector, Vector};
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use matrix_sdk::{deserialized_responses::TimelineEvent, Result};
 use ruma::{
     assign,
@@ -56,11 +56,14 @@ use ruma::{
     },
     OwnedDeviceId, OwnedEventId, OwnedMxcUri, OwnedTransactionId, OwnedUserId, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error};
 
 use super::{EventTimelineItem, Profile, TimelineDetails};
 use crate::timeline::{
-    traits::RoomDataProvider, Error as TimelineError, TimelineItem, DEFAULT_SANITIZER_MODE,
+    language::{detect_language, LanguageDetector, TextDirection},
+    traits::RoomDataProvider,
+    Error as TimelineError, TimelineItem, DEFAULT_SANITIZER_MODE,
 };
 
 /// The content of an [`EventTimelineItem`][super::EventTimelineItem].
@@ -139,6 +142,7 @@ pub struct Message {
     pub(in crate::timeline) msgtype: MessageType,
     pub(in crate::timeline) in_reply_to: Option<InReplyToDetails>,
     pub(in crate::timeline) edited: bool,
+    pub(in crate::timeline) language: Option<String>,
 }
 
 impl Message {
@@ -147,6 +151,7 @@ impl Message {
         c: RoomMessageEventContent,
         relations: BundledMessageLikeRelations<AnySyncMessageLikeEvent>,
         timeline_items: &Vector<Arc<TimelineItem>>,
+        language_detector: Option<&dyn LanguageDetector>,
     ) -> Self {
         let edited = relations.has_replacement();
         let edit = relations.replace.and_then(|r| match *r {
@@ -204,7 +209,9 @@ impl Message {
             }
         };
 
-        Self { msgtype, in_reply_to, edited }
+        let language = detect_language(language_detector, &msgtype);
+
+        Self { msgtype, in_reply_to, edited, language }
     }
 
     /// Get the `msgtype`-specific data of this message.
@@ -229,6 +236,24 @@ impl Message {
         self.edited
     }
 
+    /// Get the language of the body of this text message, as a BCP 47
+    /// language tag.
+    ///
+    /// It is only available if a [`LanguageDetector`] was set with
+    /// [`Timeline::set_language_detector()`] and it could detect the
+    /// language.
+    ///
+    /// [`Timeline::set_language_detector()`]: crate::timeline::Timeline::set_language_detector
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    /// Get the direction of the body of this text message, from its
+    /// [language](Self::language).
+    pub fn text_direction(&self) -> Option<TextDirection> {
+        self.language.as_deref().map(TextDirection::of_language)
+    }
+
     pub(in crate::timeline) fn with_in_reply_to(&self, in_reply_to: InReplyToDetails) -> Self {
         Self { in_reply_to: Some(in_reply_to), ..self.clone() }
     }
@@ -246,12 +271,13 @@ impl From<Message> for RoomMessageEventContent {
 #[cfg(not(tarpaulin_include))]
 impl fmt::Debug for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let Self { msgtype: _, in_reply_to, edited } = self;
+        let Self { msgtype: _, in_reply_to, edited, language } = self;
         // since timeline items are logged, don't include all fields here so
         // people don't leak personal data in bug reports
         f.debug_struct("Message")
             .field("in_reply_to", in_reply_to)
             .field("edited", edited)
+            .field("language", language)
             .finish_non_exhaustive()
     }
 }
@@ -327,7 +353,7 @@ impl RepliedToEvent {
             return Err(TimelineError::UnsupportedEvent);
         };
 
-        let message = Message::from_event(c, event.relations(), &vector![]);
+        let message = Message::from_event(c, event.relations(), &vector![], None);
         let sender = event.sender().to_owned();
         let sender_profile =
             TimelineDetails::from_initial_value(room_data_provider.profile(&sender).await);
@@ -388,27 +414,97 @@ pub type BundledReactions = IndexMap<String, ReactionGroup>;
 
 // The long type after a long visibility specified trips up rustfmt currently.
 // This works around. Report: https://github.com/rust-lang/rustfmt/issues/5703
-type ReactionGroupInner = IndexMap<(Option<OwnedTransactionId>, Option<OwnedEventId>), OwnedUserId>;
+type ReactionId = (Option<OwnedTransactionId>, Option<OwnedEventId>);
+type ReactionGroupInner = IndexMap<ReactionId, OwnedUserId>;
 
 /// A group of reaction events on the same event with the same key.
 ///
 /// This is a map of the event ID or transaction ID of the reactions to the ID
 /// of the sender of the reaction.
+///
+/// When the server summarizes the reactions of an event in its bundled
+/// aggregations, only some of the senders are in the map, and
+/// [`ReactionGroup::count()`] should be used to display the number of
+/// reactions. The other senders can be loaded on demand with
+/// [`Timeline::fetch_reaction_senders()`].
+///
+/// [`Timeline::fetch_reaction_senders()`]: crate::timeline::Timeline::fetch_reaction_senders
 #[derive(Clone, Debug, Default)]
-pub struct ReactionGroup(pub(in crate::timeline) ReactionGroupInner);
+pub struct ReactionGroup {
+    pub(in crate::timeline) reactions: ReactionGroupInner,
+    /// The number of reactions according to the bundled aggregations of the
+    /// server, minus the ones that were redacted since.
+    pub(in crate::timeline) server_count: Option<u64>,
+    /// The reactions in the map that were received after the summary of the
+    /// server, so they are not part of `server_count`.
+    pub(in crate::timeline) new_reactions: IndexSet<ReactionId>,
+}
 
 impl ReactionGroup {
-    /// The senders of the reactions in this group.
+    /// The senders of the reactions in this group that are known locally.
     pub fn senders(&self) -> impl Iterator<Item = &UserId> {
         self.values().map(AsRef::as_ref)
     }
+
+    /// The number of reactions in this group.
+    ///
+    /// This uses the count summarized by the server if there is one, so it can
+    /// be bigger than the number of [`senders()`](Self::senders).
+    pub fn count(&self) -> u64 {
+        match self.server_count {
+            Some(count) => count + self.new_reactions.len() as u64,
+            None => self.reactions.len() as u64,
+        }
+    }
+
+    /// Whether the number of reactions comes from the server, in which case
+    /// not all the senders are known locally.
+    pub fn is_summarized(&self) -> bool {
+        self.server_count.is_some()
+    }
+
+    /// Add a reaction that was not counted by the server yet.
+    pub(in crate::timeline) fn add_new(&mut self, id: ReactionId, sender: OwnedUserId) {
+        if self.reactions.insert(id.clone(), sender).is_none() && self.is_summarized() {
+            self.new_reactions.insert(id);
+        }
+    }
+
+    /// Add a reaction that is already part of the count summarized by the
+    /// server, if there is one.
+    pub(in crate::timeline) fn add_counted(&mut self, id: ReactionId, sender: OwnedUserId) {
+        self.reactions.insert(id, sender);
+    }
+
+    /// Remove a reaction, returns `false` if it wasn't in this group.
+    pub(in crate::timeline) fn remove(&mut self, id: &ReactionId) -> bool {
+        if self.reactions.remove(id).is_none() {
+            return false;
+        }
+
+        if !self.new_reactions.remove(id) {
+            if let Some(count) = &mut self.server_count {
+                *count = count.saturating_sub(1);
+            }
+        }
+
+        true
+    }
+
+    /// Merge the reactions of the local echo of an event into the group of its
+    /// remote echo.
+    pub(in crate::timeline) fn merge(&mut self, other: &ReactionGroup) {
+        for (id, sender) in &other.reactions {
+            self.add_new(id.clone(), sender.clone());
+        }
+    }
 }
 
 impl Deref for ReactionGroup {
     type Target = IndexMap<(Option<OwnedTransactionId>, Option<OwnedEventId>), OwnedUserId>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.reactions
     }
 }
 
@@ -457,7 +553,8 @@ impl RoomMembershipChange {
 }
 
 /// An enum over all the possible room membership changes.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MembershipChange {
     /// No change.
     None,
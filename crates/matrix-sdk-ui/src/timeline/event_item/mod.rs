@@ -15,14 +15,20 @@
 use std::sync::Arc;
 
 use indexmap::IndexMap;
-use matrix_sdk::{deserialized_responses::EncryptionInfo, Error};
+use matrix_sdk::{deserialized_responses::EncryptionInfo, room, Error, Result};
 use once_cell::sync::Lazy;
 use ruma::{
-    events::{receipt::Receipt, room::message::MessageType, AnySyncTimelineEvent},
+    events::{
+        receipt::{Receipt, ReceiptType},
+        room::message::MessageType,
+        AnySyncTimelineEvent,
+    },
     serde::Raw,
-    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedMxcUri, OwnedUserId, TransactionId,
-    UserId,
+    EventId, MatrixToUri, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedMxcUri, OwnedUserId,
+    TransactionId, UserId,
 };
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
 
 mod content;
 mod local;
@@ -35,7 +41,7 @@ pub use self::content::{
 };
 pub(super) use self::{
     local::LocalEventTimelineItem,
-    remote::{RemoteEventOrigin, RemoteEventTimelineItem},
+    remote::{ItemMetadata, RemoteEventOrigin, RemoteEventTimelineItem},
 };
 
 /// An item in the timeline that represents at least one event.
@@ -107,16 +113,18 @@ impl EventTimelineItem {
         }
     }
 
-    /// Get a unique identifier to identify the event item, either by using
-    /// transaction ID or event ID in case of a local event, or by event ID in
-    /// case of a remote event.
+    /// Get a unique identifier to identify the event item.
+    ///
+    /// This is the transaction ID for an event sent by this client, which
+    /// doesn't change when the local echo is replaced by the remote echo, or
+    /// the event ID for other events.
     pub fn unique_identifier(&self) -> String {
         match &self.kind {
-            EventTimelineItemKind::Local(item) => match &item.send_state {
-                EventSendState::Sent { event_id } => event_id.to_string(),
-                _ => item.transaction_id.to_string(),
+            EventTimelineItemKind::Local(item) => item.transaction_id.to_string(),
+            EventTimelineItemKind::Remote(item) => match &item.transaction_id {
+                Some(txn_id) => txn_id.to_string(),
+                None => item.event_id.to_string(),
             },
-            EventTimelineItemKind::Remote(item) => item.event_id.to_string(),
         }
     }
 
@@ -130,12 +138,12 @@ impl EventTimelineItem {
 
     /// Get the transaction ID of this item.
     ///
-    /// The transaction ID is currently only kept until the remote echo for a
-    /// local event is received.
+    /// Remote events only have one if they were sent by this client, and the
+    /// server sent back the transaction ID of their local echo.
     pub fn transaction_id(&self) -> Option<&TransactionId> {
         match &self.kind {
             EventTimelineItemKind::Local(local) => Some(&local.transaction_id),
-            EventTimelineItemKind::Remote(_) => None,
+            EventTimelineItemKind::Remote(remote) => remote.transaction_id.as_deref(),
         }
     }
 
@@ -154,6 +162,21 @@ impl EventTimelineItem {
         }
     }
 
+    /// Get a `matrix.to` permalink to the event of this item, in the given
+    /// room.
+    ///
+    /// The servers in the `via` parameter are computed from the current
+    /// members of the room, see [`room::Common::route()`].
+    ///
+    /// Returns `Ok(None)` if this is a local echo that wasn't sent yet.
+    pub async fn permalink(&self, room: &room::Common) -> Result<Option<MatrixToUri>> {
+        let Some(event_id) = self.event_id() else {
+            return Ok(None);
+        };
+
+        room.matrix_to_event_permalink(event_id).await.map(Some)
+    }
+
     /// Get the sender of this item.
     pub fn sender(&self) -> &UserId {
         &self.sender
@@ -164,6 +187,29 @@ impl EventTimelineItem {
         &self.sender_profile
     }
 
+    /// Get the name to show for the sender of this item.
+    ///
+    /// This is the display name of the sender, disambiguated with their user
+    /// ID if another member of the room uses the same name, like
+    /// `Alice (@alice:example.org)`. It is the user ID of the sender if they
+    /// don't have a display name or if their profile is not available.
+    pub fn sender_name(&self) -> String {
+        match &self.sender_profile {
+            TimelineDetails::Ready(Profile {
+                display_name: Some(display_name),
+                display_name_ambiguous,
+                ..
+            }) => {
+                if *display_name_ambiguous {
+                    format!("{display_name} ({})", self.sender)
+                } else {
+                    display_name.clone()
+                }
+            }
+            _ => self.sender.to_string(),
+        }
+    }
+
     /// Get the content of this item.
     pub fn content(&self) -> &TimelineItemContent {
         &self.content
@@ -182,17 +228,40 @@ impl EventTimelineItem {
     /// Get the read receipts of this item.
     ///
     /// The key is the ID of a room member and the value are details about the
-    /// read receipt.
+    /// read receipt. The receipts are in the order they were received, and are
+    /// moved to the next items as the users read them.
+    ///
+    /// The read receipt of our own user is the latest of its public and private
+    /// read receipts, and is not listed on our own events. Use
+    /// [`EventTimelineItem::send_receipt()`] to know whether our own events
+    /// reached the server.
     ///
     /// Note that currently this ignores threads.
-    pub fn read_receipts(&self) -> &IndexMap<OwnedUserId, Receipt> {
-        static EMPTY_RECEIPTS: Lazy<IndexMap<OwnedUserId, Receipt>> = Lazy::new(Default::default);
+    pub fn read_receipts(&self) -> &IndexMap<OwnedUserId, ReadReceipt> {
+        static EMPTY_RECEIPTS: Lazy<IndexMap<OwnedUserId, ReadReceipt>> =
+            Lazy::new(Default::default);
         match &self.kind {
             EventTimelineItemKind::Local(_) => &EMPTY_RECEIPTS,
             EventTimelineItemKind::Remote(remote_event) => &remote_event.read_receipts,
         }
     }
 
+    /// Get how far our own event went on its way to the server.
+    ///
+    /// Returns `None` if the event was not sent by our own user, or if it
+    /// wasn't sent successfully yet, see [`EventTimelineItem::send_state()`].
+    pub fn send_receipt(&self) -> Option<SendReceipt> {
+        match &self.kind {
+            EventTimelineItemKind::Local(local) => match local.send_state {
+                EventSendState::Sent { .. } => Some(SendReceipt::Sent),
+                EventSendState::NotSentYet | EventSendState::SendingFailed { .. } => None,
+            },
+            EventTimelineItemKind::Remote(remote) => {
+                remote.is_own.then_some(SendReceipt::Delivered)
+            }
+        }
+    }
+
     /// Get the timestamp of this item.
     ///
     /// If this event hasn't been echoed back by the server yet, returns the
@@ -245,7 +314,22 @@ impl EventTimelineItem {
     pub fn original_json(&self) -> Option<&Raw<AnySyncTimelineEvent>> {
         match &self.kind {
             EventTimelineItemKind::Local(_local_event) => None,
-            EventTimelineItemKind::Remote(remote_event) => Some(&remote_event.original_json),
+            EventTimelineItemKind::Remote(remote_event) => Some(&*remote_event.original_json),
+        }
+    }
+
+    /// Get a shared reference to the raw JSON representation of the initial
+    /// event.
+    ///
+    /// Unlike [`Self::original_json()`], the result can outlive the item, and
+    /// is cheap to clone since the JSON isn't copied.
+    ///
+    /// Returns `None` if this event hasn't been echoed back by the server
+    /// yet.
+    pub fn shared_original_json(&self) -> Option<Arc<Raw<AnySyncTimelineEvent>>> {
+        match &self.kind {
+            EventTimelineItemKind::Local(_local_event) => None,
+            EventTimelineItemKind::Remote(remote_event) => Some(remote_event.original_json.clone()),
         }
     }
 
@@ -253,10 +337,47 @@ impl EventTimelineItem {
     pub fn latest_edit_json(&self) -> Option<&Raw<AnySyncTimelineEvent>> {
         match &self.kind {
             EventTimelineItemKind::Local(_local_event) => None,
-            EventTimelineItemKind::Remote(remote_event) => remote_event.latest_edit_json.as_ref(),
+            EventTimelineItemKind::Remote(remote_event) => remote_event.latest_edit_json.as_deref(),
+        }
+    }
+
+    /// Get a shared reference to the raw JSON representation of the latest
+    /// edit, if any.
+    ///
+    /// Like [`Self::shared_original_json()`], this doesn't copy the JSON.
+    pub fn shared_latest_edit_json(&self) -> Option<Arc<Raw<AnySyncTimelineEvent>>> {
+        match &self.kind {
+            EventTimelineItemKind::Local(_local_event) => None,
+            EventTimelineItemKind::Remote(remote_event) => remote_event.latest_edit_json.clone(),
+        }
+    }
+
+    /// Get the metadata attached to the event by the application under the
+    /// given key, with [`Timeline::set_item_metadata()`].
+    ///
+    /// Returns `None` if there is no metadata under this key, or if this event
+    /// hasn't been echoed back by the server yet.
+    ///
+    /// [`Timeline::set_item_metadata()`]: super::Timeline::set_item_metadata
+    pub fn metadata(&self, key: &str) -> Option<&JsonValue> {
+        match &self.kind {
+            EventTimelineItemKind::Local(_local_event) => None,
+            EventTimelineItemKind::Remote(remote_event) => remote_event.metadata.get(key),
         }
     }
 
+    /// Deserialize the metadata attached to the event by the application
+    /// under the given key.
+    ///
+    /// Returns `None` if there is no metadata under this key, or if this event
+    /// hasn't been echoed back by the server yet.
+    pub fn deserialize_metadata<T: DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Option<serde_json::Result<T>> {
+        self.metadata(key).map(|value| T::deserialize(value))
+    }
+
     pub(super) fn set_content(&mut self, content: TimelineItemContent) {
         self.content = content;
     }
@@ -273,7 +394,7 @@ impl EventTimelineItem {
     pub(super) fn with_content(
         &self,
         new_content: TimelineItemContent,
-        edit_json: Option<Raw<AnySyncTimelineEvent>>,
+        edit_json: Option<Arc<Raw<AnySyncTimelineEvent>>>,
     ) -> Self {
         let mut new = self.clone();
         new.content = new_content;
@@ -308,6 +429,39 @@ pub enum EventSendState {
     },
 }
 
+/// A read receipt of a user on an [`EventTimelineItem`], see
+/// [`EventTimelineItem::read_receipts()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadReceipt {
+    /// The type of the receipt, [`ReceiptType::Read`] for a public read
+    /// receipt or [`ReceiptType::ReadPrivate`] for a private one, that only
+    /// our own user can have.
+    ///
+    /// The implicit read receipts of the users on the events they sent are
+    /// public.
+    pub receipt_type: ReceiptType,
+    /// When the user read the event, if known.
+    pub ts: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl ReadReceipt {
+    pub(in crate::timeline) fn new(receipt_type: ReceiptType, receipt: &Receipt) -> Self {
+        Self { receipt_type, ts: receipt.ts }
+    }
+}
+
+/// How far an event sent by our own user went, see
+/// [`EventTimelineItem::send_receipt()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendReceipt {
+    /// The server accepted the event, but it wasn't received back from the
+    /// sync yet.
+    Sent,
+    /// The event was received back from the sync, so it was delivered to the
+    /// room.
+    Delivered,
+}
+
 impl From<LocalEventTimelineItem> for EventTimelineItemKind {
     fn from(value: LocalEventTimelineItem) -> Self {
         EventTimelineItemKind::Local(value)
@@ -12,23 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fmt;
+use std::{collections::BTreeMap, fmt, sync::Arc};
 
 use indexmap::IndexMap;
 use matrix_sdk::deserialized_responses::EncryptionInfo;
 use ruma::{
-    events::{receipt::Receipt, AnySyncTimelineEvent},
-    serde::Raw,
-    OwnedEventId, OwnedUserId, UserId,
+    events::AnySyncTimelineEvent, serde::Raw, OwnedEventId, OwnedTransactionId, OwnedUserId, UserId,
 };
+use serde_json::Value as JsonValue;
 
-use super::BundledReactions;
+use super::{BundledReactions, ReadReceipt};
+
+/// Metadata attached to an event by the application, by key.
+pub(in crate::timeline) type ItemMetadata = BTreeMap<String, JsonValue>;
 
 /// An item for an event that was received from the homeserver.
 #[derive(Clone)]
 pub(in crate::timeline) struct RemoteEventTimelineItem {
     /// The event ID.
     pub event_id: OwnedEventId,
+    /// The transaction ID of the local echo of the event, if it was sent by
+    /// this client and the server sent it back.
+    pub transaction_id: Option<OwnedTransactionId>,
     /// All bundled reactions about the event.
     pub reactions: BundledReactions,
     /// All read receipts for the event.
@@ -37,7 +42,7 @@ pub(in crate::timeline) struct RemoteEventTimelineItem {
     /// read receipt.
     ///
     /// Note that currently this ignores threads.
-    pub read_receipts: IndexMap<OwnedUserId, Receipt>,
+    pub read_receipts: IndexMap<OwnedUserId, ReadReceipt>,
     /// Whether the event has been sent by the the logged-in user themselves.
     pub is_own: bool,
     /// Whether the item should be highlighted in the timeline.
@@ -48,15 +53,20 @@ pub(in crate::timeline) struct RemoteEventTimelineItem {
     ///
     /// If the message is edited, this *won't* change, instead
     /// `latest_edit_json` will be updated.
-    pub original_json: Raw<AnySyncTimelineEvent>,
+    ///
+    /// It is shared with the clones of this item, to avoid copying the JSON
+    /// every time the item is updated.
+    pub original_json: Arc<Raw<AnySyncTimelineEvent>>,
     /// JSON of the latest edit to this item.
-    pub latest_edit_json: Option<Raw<AnySyncTimelineEvent>>,
+    pub latest_edit_json: Option<Arc<Raw<AnySyncTimelineEvent>>>,
     /// Where we got this event from: A sync response or pagination.
     pub origin: RemoteEventOrigin,
+    /// Metadata attached to the event by the application.
+    pub metadata: ItemMetadata,
 }
 
 impl RemoteEventTimelineItem {
-    pub fn add_read_receipt(&mut self, user_id: OwnedUserId, receipt: Receipt) {
+    pub fn add_read_receipt(&mut self, user_id: OwnedUserId, receipt: ReadReceipt) {
         self.read_receipts.insert(user_id, receipt);
     }
 
@@ -76,6 +86,11 @@ impl RemoteEventTimelineItem {
     pub fn without_reactions(&self) -> Self {
         Self { reactions: BundledReactions::default(), ..self.clone() }
     }
+
+    /// Clone the current event item, and update its `metadata`.
+    pub fn with_metadata(&self, metadata: ItemMetadata) -> Self {
+        Self { metadata, ..self.clone() }
+    }
 }
 
 /// Where we got an event from.
@@ -98,6 +113,7 @@ impl fmt::Debug for RemoteEventTimelineItem {
         // skip raw JSON, too noisy
         let Self {
             event_id,
+            transaction_id,
             reactions,
             read_receipts,
             is_own,
@@ -106,16 +122,19 @@ impl fmt::Debug for RemoteEventTimelineItem {
             latest_edit_json: _,
             is_highlighted,
             origin,
+            metadata,
         } = self;
 
         f.debug_struct("RemoteEventTimelineItem")
             .field("event_id", event_id)
+            .field("transaction_id", transaction_id)
             .field("reactions", reactions)
             .field("read_receipts", read_receipts)
             .field("is_own", is_own)
             .field("is_highlighted", is_highlighted)
             .field("encryption_info", encryption_info)
             .field("origin", origin)
+            .field("metadata", metadata)
             .finish_non_exhaustive()
     }
 }
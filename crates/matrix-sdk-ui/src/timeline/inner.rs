@@ -14,7 +14,11 @@
 
 #[cfg(feature = "e2e-encryption")]
 use std::collections::BTreeSet;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+    sync::Arc,
+};
 
 use eyeball_im::{ObservableVector, VectorSubscriber};
 #[cfg(any(test, feature = "testing"))]
@@ -25,7 +29,8 @@ use indexmap::IndexSet;
 use matrix_sdk::crypto::OlmMachine;
 use matrix_sdk::{
     deserialized_responses::{SyncTimelineEvent, TimelineEvent},
-    room,
+    instant::Instant,
+    metrics, room,
     sync::{JoinedRoom, Timeline},
     Error, Result,
 };
@@ -34,7 +39,7 @@ use ruma::events::receipt::ReceiptEventContent;
 #[cfg(all(test, feature = "e2e-encryption"))]
 use ruma::RoomId;
 use ruma::{
-    api::client::receipt::create_receipt::v3::ReceiptType as SendReceiptType,
+    api::{client::receipt::create_receipt::v3::ReceiptType as SendReceiptType, Direction},
     events::{
         fully_read::FullyReadEvent,
         receipt::{Receipt, ReceiptThread, ReceiptType},
@@ -45,26 +50,81 @@ use ruma::{
     EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedTransactionId, OwnedUserId,
     TransactionId, UserId,
 };
+use serde_json::Value as JsonValue;
 use tokio::sync::{Mutex, MutexGuard};
 use tracing::{debug, error, field::debug, info, instrument, trace, warn};
 #[cfg(feature = "e2e-encryption")]
 use tracing::{field, info_span, Instrument as _};
 
-#[cfg(feature = "e2e-encryption")]
-use super::traits::Decryptor;
 use super::{
     compare_events_positions,
+    divider::{adjust_dividers, DividerPolicy},
     event_handler::{
-        update_read_marker, Flow, HandleEventResult, TimelineEventHandler, TimelineEventKind,
-        TimelineEventMetadata, TimelineItemPosition,
+        update_read_marker, Flow, HandleEventResult, PendingEdit, TimelineEventHandler,
+        TimelineEventKind, TimelineEventMetadata, TimelineItemPosition,
     },
-    rfind_event_by_id, rfind_event_item,
+    event_item::{ItemMetadata, RemoteEventOrigin},
+    language::{detect_language, LanguageDetector},
+    rfind_event_by_id, rfind_event_item, rfind_local_echo,
     traits::RoomDataProvider,
-    EventSendState, EventTimelineItem, InReplyToDetails, Message, Profile, RelativePosition,
-    RepliedToEvent, TimelineDetails, TimelineItem, TimelineItemContent,
+    EventOrdering, EventSendState, EventTimelineItem, InReplyToDetails, Message, Profile,
+    RelativePosition, RepliedToEvent, SecurityNoticeSettings, TimelineDetails, TimelineItem,
+    TimelineItemContent,
 };
+#[cfg(feature = "e2e-encryption")]
+use super::{traits::Decryptor, SecurityNotice};
 use crate::events::SyncTimelineEventWithoutContent;
 
+/// The number of items decrypted at once above which the subscribers get the
+/// whole list of items again, instead of one update per item.
+#[cfg(feature = "e2e-encryption")]
+const RETRY_DECRYPTION_BATCH_THRESHOLD: usize = 50;
+
+/// The edges of the window of items around the viewport of a timeline.
+#[derive(Debug)]
+pub(super) struct WindowEdges {
+    /// The first remote event to keep, if there are items to drop before it.
+    pub(super) first: Option<WindowEdge>,
+    /// The last remote event to keep, if there are items to drop after it.
+    pub(super) last: Option<WindowEdge>,
+}
+
+impl WindowEdges {
+    /// Whether the token of an edge isn't known locally and must be requested
+    /// from the homeserver.
+    pub(super) fn needs_request(&self) -> bool {
+        [&self.first, &self.last].into_iter().flatten().any(|edge| edge.token.is_none())
+    }
+}
+
+/// An edge of the window of items around the viewport of a timeline.
+#[derive(Debug)]
+pub(super) struct WindowEdge {
+    /// The remote event to keep at this edge.
+    pub(super) event_id: OwnedEventId,
+    /// The pagination token to load the dropped items again, if it was
+    /// received with the event.
+    pub(super) token: Option<String>,
+}
+
+/// The pagination tokens received with an event, to load the events around it.
+#[derive(Clone, Debug, Default)]
+pub(super) struct PaginationTokens {
+    /// The token to load the events before the event.
+    pub(super) before: Option<String>,
+    /// The token to load the events after the event.
+    pub(super) after: Option<String>,
+}
+
+impl PaginationTokens {
+    fn get(&self, direction: Direction) -> Option<&String> {
+        match direction {
+            Direction::Backward => self.before.as_ref(),
+            Direction::Forward => self.after.as_ref(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(super) struct TimelineInner<P: RoomDataProvider = room::Common> {
     state: Mutex<TimelineInnerState>,
@@ -81,6 +141,9 @@ pub(super) struct TimelineInnerState {
     /// ID of event that is not in the timeline yet => List of reaction event
     /// IDs.
     pub(super) pending_reactions: HashMap<OwnedEventId, IndexSet<OwnedEventId>>,
+    /// ID of event that couldn't be decrypted yet => Latest edit of the
+    /// event.
+    pub(super) pending_edits: HashMap<OwnedEventId, PendingEdit>,
     pub(super) fully_read_event: Option<OwnedEventId>,
     /// Whether the fully-read marker item should try to be updated when an
     /// event is added.
@@ -91,6 +154,27 @@ pub(super) struct TimelineInnerState {
     /// User ID => Receipt type => Read receipt of the user of the given type.
     pub(super) users_read_receipts:
         HashMap<OwnedUserId, HashMap<ReceiptType, (OwnedEventId, Receipt)>>,
+    /// Event ID => Metadata attached to the event by the application.
+    ///
+    /// This is kept when the timeline is cleared, so it can be attached again
+    /// to the items when their events are received again.
+    pub(super) item_metadata: HashMap<OwnedEventId, ItemMetadata>,
+    /// How live events are ordered.
+    pub(super) event_ordering: EventOrdering,
+    /// When dividers are inserted between events.
+    pub(super) divider_policy: DividerPolicy,
+    /// Which security notices are added to the timeline.
+    pub(super) security_notices: SecurityNoticeSettings,
+    /// Detects the language of the text messages.
+    pub(super) language_detector: Option<Arc<dyn LanguageDetector>>,
+    /// The maximum number of items kept around the viewport, if the timeline
+    /// is windowed.
+    pub(super) window_size: Option<NonZeroUsize>,
+    /// Event ID => Pagination tokens received with the event.
+    ///
+    /// This is the local cache used to drop the items outside of the window
+    /// without asking the homeserver for the tokens to load them again.
+    pub(super) pagination_tokens: HashMap<OwnedEventId, PaginationTokens>,
 }
 
 impl<P: RoomDataProvider> TimelineInner<P> {
@@ -110,6 +194,194 @@ impl<P: RoomDataProvider> TimelineInner<P> {
         self
     }
 
+    pub(super) async fn set_event_ordering(&self, event_ordering: EventOrdering) {
+        self.state.lock().await.event_ordering = event_ordering;
+    }
+
+    pub(super) async fn set_divider_policy(&self, divider_policy: DividerPolicy) {
+        let mut state = self.state.lock().await;
+        adjust_dividers(&mut state.items, &divider_policy);
+        state.divider_policy = divider_policy;
+    }
+
+    pub(super) async fn set_window_size(&self, window_size: Option<NonZeroUsize>) {
+        self.state.lock().await.window_size = window_size;
+    }
+
+    /// Remember the token to load the events in the given direction from the
+    /// event with the given ID.
+    pub(super) async fn save_pagination_token(
+        &self,
+        event_id: OwnedEventId,
+        direction: Direction,
+        token: String,
+    ) {
+        let mut state = self.state.lock().await;
+        let tokens = state.pagination_tokens.entry(event_id).or_default();
+        match direction {
+            Direction::Backward => tokens.before = Some(token),
+            Direction::Forward => tokens.after = Some(token),
+        }
+    }
+
+    /// Compute the edges of the window of items around the event with the
+    /// given ID.
+    ///
+    /// The edges are moved away from the viewport, by at most the size of the
+    /// window, to an event whose pagination token is known, so the token
+    /// doesn't need to be requested.
+    ///
+    /// Returns `None` if the timeline is not windowed, if it doesn't have more
+    /// items than the window, or if the event is not in the timeline.
+    pub(super) async fn window_edges(&self, viewport: &EventId) -> Option<WindowEdges> {
+        let state = self.state.lock().await;
+        let size = state.window_size?.get();
+        let items = &state.items;
+
+        if items.len() <= size {
+            return None;
+        }
+
+        let (idx, _) = rfind_event_by_id(items, viewport)?;
+        let start = idx.saturating_sub(size / 2).min(items.len() - size);
+        let end = start + size;
+
+        let remote_event_id = |idx: usize| {
+            items[idx].as_event().and_then(|event| event.as_remote()).map(|e| e.event_id.clone())
+        };
+        let is_event = |item: &Arc<TimelineItem>| item.as_event().is_some();
+        let has_event_before = |idx: usize| items.iter().take(idx).any(is_event);
+        let has_event_after = |idx: usize| items.iter().skip(idx + 1).any(is_event);
+        let cached_edge = |idx: usize, direction| {
+            let event_id = remote_event_id(idx)?;
+            let token = state.pagination_tokens.get(&event_id)?.get(direction)?.clone();
+            Some(WindowEdge { event_id, token: Some(token) })
+        };
+
+        // Only drop items if there are events to drop, the virtual items would
+        // be added back anyway.
+        let first = (start..end)
+            .find_map(|idx| Some((idx, remote_event_id(idx)?)))
+            .filter(|(idx, _)| has_event_before(*idx))
+            .map(|(idx, event_id)| {
+                (idx.saturating_sub(size)..=idx)
+                    .rev()
+                    .filter(|idx| has_event_before(*idx))
+                    .find_map(|idx| cached_edge(idx, Direction::Backward))
+                    .unwrap_or(WindowEdge { event_id, token: None })
+            });
+
+        // The local echoes are never dropped, they would be lost otherwise.
+        let has_local_echo_after =
+            items.iter().skip(end).any(|item| item.as_event().is_some_and(|e| e.is_local_echo()));
+        let last = (start..end)
+            .rev()
+            .find_map(|idx| Some((idx, remote_event_id(idx)?)))
+            .filter(|(idx, _)| !has_local_echo_after && has_event_after(*idx))
+            .map(|(idx, event_id)| {
+                (idx..=(idx + size).min(items.len() - 1))
+                    .filter(|idx| has_event_after(*idx))
+                    .find_map(|idx| cached_edge(idx, Direction::Forward))
+                    .unwrap_or(WindowEdge { event_id, token: None })
+            });
+
+        (first.is_some() || last.is_some()).then_some(WindowEdges { first, last })
+    }
+
+    /// Drop the items before the event with ID `first` and after the event
+    /// with ID `last`.
+    pub(super) async fn retain_window(&self, first: Option<&EventId>, last: Option<&EventId>) {
+        let mut state = self.state.lock().await;
+        let state = &mut *state;
+        let mut dropped = Vec::new();
+
+        if let Some((idx, _)) = last.and_then(|last| rfind_event_by_id(&state.items, last)) {
+            while state.items.len() > idx + 1 {
+                dropped.extend(state.items.pop_back());
+            }
+        }
+        if let Some((idx, _)) = first.and_then(|first| rfind_event_by_id(&state.items, first)) {
+            for _ in 0..idx {
+                dropped.extend(state.items.pop_front());
+            }
+        }
+
+        debug!(count = dropped.len(), "Dropped items outside of the window");
+
+        // Only the tokens of the events that are still in the timeline are
+        // useful.
+        let event_ids: HashSet<_> = state
+            .items
+            .iter()
+            .filter_map(|item| item.as_event()?.as_remote().map(|event| &event.event_id))
+            .collect();
+        state.pagination_tokens.retain(|event_id, _| event_ids.contains(event_id));
+
+        // Keep the reactions to the dropped events around, to add them again
+        // when the events are loaded again.
+        for item in &dropped {
+            let Some(remote_event) = item.as_event().and_then(|event| event.as_remote()) else {
+                continue;
+            };
+
+            for group in remote_event.reactions.values() {
+                let reaction_ids = group.reactions.keys().filter_map(|(_, id)| id.clone());
+                state
+                    .pending_reactions
+                    .entry(remote_event.event_id.clone())
+                    .or_default()
+                    .extend(reaction_ids);
+            }
+        }
+
+        adjust_dividers(&mut state.items, &state.divider_policy);
+
+        if let Some(fully_read_event) = &state.fully_read_event {
+            if rfind_event_by_id(&state.items, fully_read_event).is_none() {
+                state.event_should_update_fully_read_marker = true;
+            }
+        }
+    }
+
+    pub(super) async fn set_security_notice_settings(&self, settings: SecurityNoticeSettings) {
+        self.state.lock().await.security_notices = settings;
+    }
+
+    /// Set the language detector, and detect the language of the messages
+    /// already in the timeline again.
+    pub(super) async fn set_language_detector(&self, detector: Option<Arc<dyn LanguageDetector>>) {
+        let mut state = self.state.lock().await;
+
+        for idx in 0..state.items.len() {
+            let Some(event_item) = state.items[idx].as_event() else { continue };
+            let TimelineItemContent::Message(message) = event_item.content() else { continue };
+
+            let language = detect_language(detector.as_deref(), message.msgtype());
+            if language == message.language {
+                continue;
+            }
+
+            let new_message = Message { language, ..message.clone() };
+            let mut new_item = event_item.clone();
+            new_item.content = TimelineItemContent::Message(new_message);
+            state.items.set(idx, Arc::new(new_item.into()));
+        }
+
+        state.language_detector = detector;
+    }
+
+    #[cfg(feature = "e2e-encryption")]
+    pub(super) async fn security_notice_settings(&self) -> SecurityNoticeSettings {
+        self.state.lock().await.security_notices
+    }
+
+    /// Add a security notice at the end of the timeline.
+    #[cfg(feature = "e2e-encryption")]
+    pub(super) async fn add_security_notice(&self, notice: SecurityNotice) {
+        trace!(?notice, "Adding security notice");
+        self.state.lock().await.items.push_back(Arc::new(TimelineItem::security_notice(notice)));
+    }
+
     /// Get a copy of the current items in the list.
     ///
     /// Cheap because `im::Vector` is cheap to clone.
@@ -169,7 +441,7 @@ impl<P: RoomDataProvider> TimelineInner<P> {
             state
                 .handle_remote_event(
                     event,
-                    TimelineItemPosition::End { from_cache: true },
+                    TimelineItemPosition::End { origin: RemoteEventOrigin::Cache },
                     &self.room_data_provider,
                     self.track_read_receipts,
                 )
@@ -177,6 +449,43 @@ impl<P: RoomDataProvider> TimelineInner<P> {
         }
     }
 
+    pub(super) async fn load_item_metadata(&mut self) {
+        let item_metadata = self.room_data_provider.load_item_metadata().await;
+        self.state.get_mut().item_metadata = item_metadata;
+    }
+
+    /// Attach the given metadata to the event with the given ID under the
+    /// given key, or remove it if `value` is `None`, and persist it.
+    ///
+    /// The item of the event is updated if it is in the timeline.
+    pub(super) async fn set_item_metadata(
+        &self,
+        event_id: &EventId,
+        key: &str,
+        value: Option<JsonValue>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().await;
+
+        let item_metadata =
+            self.room_data_provider.update_item_metadata(event_id, key, value).await?;
+        let metadata = item_metadata.get(event_id).cloned().unwrap_or_default();
+        let changed = state.item_metadata.get(event_id) != item_metadata.get(event_id);
+        state.item_metadata = item_metadata;
+
+        if !changed {
+            return Ok(());
+        }
+
+        if let Some((idx, event_item)) = rfind_event_by_id(&state.items, event_id) {
+            if let Some(remote_event_item) = event_item.as_remote() {
+                let new_item = event_item.with_kind(remote_event_item.with_metadata(metadata));
+                state.items.set(idx, Arc::new(new_item.into()));
+            }
+        }
+
+        Ok(())
+    }
+
     pub(super) async fn clear(&self) {
         trace!("Clearing timeline");
         self.state.lock().await.clear();
@@ -298,9 +607,7 @@ impl<P: RoomDataProvider> TimelineInner<P> {
             // Remote echo already received. This is very unlikely.
             trace!("Remote echo received before send-event response");
 
-            let local_echo = rfind_event_item(&state.items, |it| {
-                it.transaction_id() == Some(txn_id)
-            });
+            let local_echo = rfind_local_echo(&state.items, txn_id);
             // If there's both the remote echo and a local echo, that means the
             // remote echo was received before the response *and* contained no
             // transaction ID (and thus duplicated the local echo).
@@ -308,16 +615,17 @@ impl<P: RoomDataProvider> TimelineInner<P> {
                 warn!("Message echo got duplicated, removing the local one");
                 state.items.remove(idx);
 
-                if idx == 0 {
-                    error!("Inconsistent state: Local echo was not preceded by day divider");
-                    return;
-                }
                 if idx == state.items.len() {
                     error!("Inconsistent state: Echo was duplicated but local echo was last");
                     return;
                 }
 
-                if state.items[idx - 1].is_day_divider() && state.items[idx].is_day_divider() {
+                // There is no day divider before the local echo if they are
+                // disabled.
+                if idx > 0
+                    && state.items[idx - 1].is_day_divider()
+                    && state.items[idx].is_day_divider()
+                {
                     // If local echo was the only event from that day, remove day divider.
                     state.items.remove(idx - 1);
                 }
@@ -343,7 +651,7 @@ impl<P: RoomDataProvider> TimelineInner<P> {
     ) -> Option<TimelineItemContent> {
         let mut state = self.state.lock().await;
 
-        let (idx, item) = rfind_event_item(&state.items, |it| it.transaction_id() == Some(txn_id))?;
+        let (idx, item) = rfind_local_echo(&state.items, txn_id)?;
         let local_item = item.as_local()?;
 
         if !matches!(&local_item.send_state, EventSendState::SendingFailed { .. }) {
@@ -363,9 +671,7 @@ impl<P: RoomDataProvider> TimelineInner<P> {
 
     pub(super) async fn discard_local_echo(&self, txn_id: &TransactionId) -> bool {
         let mut state = self.state.lock().await;
-        if let Some((idx, _)) =
-            rfind_event_item(&state.items, |it| it.transaction_id() == Some(txn_id))
-        {
+        if let Some((idx, _)) = rfind_local_echo(&state.items, txn_id) {
             state.items.remove(idx);
             true
         } else {
@@ -373,6 +679,43 @@ impl<P: RoomDataProvider> TimelineInner<P> {
         }
     }
 
+    /// Remove all the events sent by the given user from the timeline, and the
+    /// day dividers that are left without any event after them.
+    ///
+    /// Returns the number of removed events.
+    pub(super) async fn remove_events_of_user(&self, user_id: &UserId) -> usize {
+        let mut state = self.state.lock().await;
+        let mut removed = 0;
+
+        for idx in (0..state.items.len()).rev() {
+            if state.items[idx].as_event().is_some_and(|item| item.sender() == user_id) {
+                state.items.remove(idx);
+                removed += 1;
+            }
+        }
+
+        if removed == 0 {
+            return 0;
+        }
+
+        let mut has_events_after = false;
+        for idx in (0..state.items.len()).rev() {
+            let item = &state.items[idx];
+
+            if item.is_day_divider() {
+                if !has_events_after {
+                    trace!("Removing day divider without events");
+                    state.items.remove(idx);
+                }
+                has_events_after = false;
+            } else if item.as_event().is_some() {
+                has_events_after = true;
+            }
+        }
+
+        removed
+    }
+
     /// Handle a back-paginated event.
     ///
     /// Returns the number of timeline updates that were made.
@@ -381,16 +724,52 @@ impl<P: RoomDataProvider> TimelineInner<P> {
         &self,
         event: TimelineEvent,
     ) -> HandleEventResult {
-        self.state
-            .lock()
-            .await
+        let mut state = self.state.lock().await;
+
+        let start = Instant::now();
+        let result = state
             .handle_remote_event(
                 event.into(),
                 TimelineItemPosition::Start,
                 &self.room_data_provider,
                 self.track_read_receipts,
             )
-            .await
+            .await;
+        metrics::record_duration(
+            metrics::TIMELINE_UPDATE_DURATION,
+            start,
+            &[("source", "back_pagination")],
+        );
+
+        result
+    }
+
+    /// Handle a forward-paginated event.
+    ///
+    /// Returns the number of timeline updates that were made.
+    #[instrument(skip_all)]
+    pub(super) async fn handle_forward_paginated_event(
+        &self,
+        event: TimelineEvent,
+    ) -> HandleEventResult {
+        let mut state = self.state.lock().await;
+
+        let start = Instant::now();
+        let result = state
+            .handle_remote_event(
+                event.into(),
+                TimelineItemPosition::End { origin: RemoteEventOrigin::Pagination },
+                &self.room_data_provider,
+                self.track_read_receipts,
+            )
+            .await;
+        metrics::record_duration(
+            metrics::TIMELINE_UPDATE_DURATION,
+            start,
+            &[("source", "forward_pagination")],
+        );
+
+        result
     }
 
     #[instrument(skip_all)]
@@ -509,16 +888,32 @@ impl<P: RoomDataProvider> TimelineInner<P> {
 
         let mut state = self.state.lock().await;
 
-        // We loop through all the items in the timeline, if we successfully
-        // decrypt a UTD item we either replace it or remove it and update
-        // another one.
-        let mut idx = 0;
-        while let Some(item) = state.items.get(idx) {
-            let Some(mut event) = retry_one(item.clone()).await else {
-                idx += 1;
-                continue;
-            };
+        // Decrypt all the UTD items first, to know how many items will be
+        // updated.
+        let mut decrypted = Vec::new();
+        for (idx, item) in (*state.items).clone().into_iter().enumerate() {
+            if let Some(event) = retry_one(item).await {
+                decrypted.push((idx, event));
+            }
+        }
 
+        if decrypted.is_empty() {
+            return;
+        }
+
+        // When a lot of items are decrypted at once, e.g. after restoring a
+        // backup, the items are updated without notifying the subscribers and
+        // the whole list is replaced at the end, to avoid flooding them.
+        let observed_items = (decrypted.len() > RETRY_DECRYPTION_BATCH_THRESHOLD).then(|| {
+            debug!(count = decrypted.len(), "Updating decrypted items in a batch");
+            let items = ObservableVector::from((*state.items).clone());
+            std::mem::replace(&mut state.items, items)
+        });
+
+        // If we successfully decrypt a UTD item we either replace it or remove
+        // it and update another one, so the following indices are shifted.
+        let mut removed = 0;
+        for (idx, mut event) in decrypted {
             event.push_actions = push_rules_context
                 .as_ref()
                 .map(|(push_rules, push_context)| {
@@ -529,18 +924,22 @@ impl<P: RoomDataProvider> TimelineInner<P> {
             let result = state
                 .handle_remote_event(
                     event.into(),
-                    TimelineItemPosition::Update(idx),
+                    TimelineItemPosition::Update(idx - removed),
                     &self.room_data_provider,
                     self.track_read_receipts,
                 )
                 .await;
 
-            // If the UTD was removed rather than updated, run the loop again
-            // with the same index.
-            if !result.item_removed {
-                idx += 1;
+            if result.item_removed {
+                removed += 1;
             }
         }
+
+        if let Some(observed_items) = observed_items {
+            let items = std::mem::replace(&mut state.items, observed_items);
+            state.items.clear();
+            state.items.append((*items).clone());
+        }
     }
 
     pub(super) async fn set_sender_profiles_pending(&self) {
@@ -783,6 +1182,8 @@ impl TimelineInnerState {
         room_data_provider: &P,
         track_read_receipts: bool,
     ) {
+        let start = Instant::now();
+
         if timeline.limited {
             debug!("Got limited sync response, resetting timeline");
             self.clear();
@@ -791,12 +1192,14 @@ impl TimelineInnerState {
         for event in timeline.events {
             self.handle_live_event(event, room_data_provider, track_read_receipts).await;
         }
+
+        metrics::record_duration(metrics::TIMELINE_UPDATE_DURATION, start, &[("source", "sync")]);
     }
 
     /// Handle a live remote event.
     ///
     /// Shorthand for `handle_remote_event` with a `position` of
-    /// `TimelineItemPosition::End { from_cache: false }`.
+    /// `TimelineItemPosition::End { origin: RemoteEventOrigin::Sync }`.
     async fn handle_live_event<P: RoomDataProvider>(
         &mut self,
         event: SyncTimelineEvent,
@@ -805,7 +1208,7 @@ impl TimelineInnerState {
     ) -> HandleEventResult {
         self.handle_remote_event(
             event,
-            TimelineItemPosition::End { from_cache: false },
+            TimelineItemPosition::End { origin: RemoteEventOrigin::Sync },
             room_data_provider,
             track_read_receipts,
         )
@@ -848,11 +1251,26 @@ impl TimelineInnerState {
             },
         };
 
+        #[cfg(feature = "experimental-encrypted-state-events")]
+        let event_kind = TimelineEventKind::undecrypted_state(&raw).unwrap_or(event_kind);
+
         let is_own_event = sender == room_data_provider.own_user_id();
+
+        // The remote echo of an event sent by another process using the same
+        // store, or received without its transaction ID, can still be matched
+        // with its local echo thanks to the IDs of the sent events in the
+        // store.
+        let txn_id = match txn_id {
+            None if is_own_event && matches!(position, TimelineItemPosition::End { .. }) => {
+                self.find_local_echo_txn_id(&event_id, room_data_provider).await
+            }
+            txn_id => txn_id,
+        };
+
         let encryption_info = event.encryption_info;
         let sender_profile = room_data_provider.profile(&sender).await;
         let read_receipts = if track_read_receipts {
-            self.load_read_receipts_for_event(&event_id, room_data_provider).await
+            self.load_read_receipts_for_event(&event_id, is_own_event, room_data_provider).await
         } else {
             Default::default()
         };
@@ -866,17 +1284,47 @@ impl TimelineInnerState {
             read_receipts,
             is_highlighted,
         };
-        let flow = Flow::Remote { event_id, raw_event: raw, txn_id, position };
+        let flow = Flow::Remote { event_id, raw_event: Arc::new(raw), txn_id, position };
 
         TimelineEventHandler::new(event_meta, flow, self, track_read_receipts)
             .handle_event(event_kind)
     }
 
+    /// Find the transaction ID of the local echo of the given remote event,
+    /// if the local echo is still waiting for its event ID.
+    ///
+    /// The ID of the sent event is removed from the store once it's matched.
+    async fn find_local_echo_txn_id<P: RoomDataProvider>(
+        &self,
+        event_id: &EventId,
+        room_data_provider: &P,
+    ) -> Option<OwnedTransactionId> {
+        let txn_ids: Vec<_> = self
+            .items
+            .iter()
+            .filter_map(|item| {
+                let local = item.as_event()?.as_local()?;
+                local.event_id().is_none().then(|| local.transaction_id.clone())
+            })
+            .collect();
+
+        for txn_id in txn_ids {
+            if room_data_provider.sent_event_id(&txn_id).await.as_deref() == Some(event_id) {
+                room_data_provider.forget_sent_event(&txn_id).await;
+                return Some(txn_id);
+            }
+        }
+
+        None
+    }
+
     pub(super) fn clear(&mut self) {
         self.items.clear();
         self.reaction_map.clear();
+        self.pending_edits.clear();
         self.fully_read_event = None;
         self.event_should_update_fully_read_marker = false;
+        self.pagination_tokens.clear();
     }
 
     #[instrument(skip_all)]
@@ -0,0 +1,274 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use eyeball_im::VectorDiff;
+use futures_core::Stream;
+use imbl::Vector;
+use pin_project_lite::pin_project;
+use ruma::MilliSecondsSinceUnixEpoch;
+
+use super::{SecurityNoticeId, TimelineItem};
+
+/// A stable identifier of a [`TimelineItem`].
+///
+/// The identifier of an item doesn't change when the item is updated, for
+/// example when an event is edited, or when the local echo of an event is
+/// replaced by its remote echo.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TimelineItemId {
+    /// An event, identified by
+    /// [`EventTimelineItem::unique_identifier()`](super::EventTimelineItem::unique_identifier).
+    Event(String),
+    /// A day divider, identified by its timestamp.
+    DayDivider(MilliSecondsSinceUnixEpoch),
+    /// The user's own read marker.
+    ReadMarker,
+    /// The loading indicator.
+    LoadingIndicator,
+    /// The beginning of the visible timeline.
+    TimelineStart,
+    /// A security notice, identified by its unique ID.
+    SecurityNotice(SecurityNoticeId),
+}
+
+/// An update of the items of a timeline, identified by their
+/// [`TimelineItemId`] instead of their index.
+///
+/// This is emitted by [`Timeline::subscribe_keyed`](super::Timeline::subscribe_keyed).
+#[derive(Clone, Debug)]
+pub enum TimelineItemOp {
+    /// An item was inserted.
+    Insert {
+        /// The ID of the item after which the new item was inserted, or `None`
+        /// if it was inserted at the start of the timeline.
+        after: Option<TimelineItemId>,
+        /// The new item.
+        item: Arc<TimelineItem>,
+    },
+    /// An item was updated.
+    Update {
+        /// The ID of the item.
+        id: TimelineItemId,
+        /// The new version of the item.
+        item: Arc<TimelineItem>,
+    },
+    /// An item was removed.
+    Remove {
+        /// The ID of the removed item.
+        id: TimelineItemId,
+    },
+    /// All the items were replaced.
+    Reset {
+        /// The new items.
+        items: Vector<Arc<TimelineItem>>,
+    },
+}
+
+pin_project! {
+    /// A stream converting the index-based updates of the items into
+    /// [`TimelineItemOp`]s.
+    pub(super) struct KeyedStream<S> {
+        #[pin]
+        inner: S,
+        // The IDs of the items with all the received updates applied, used to
+        // find the items that the indices refer to.
+        ids: Vector<TimelineItemId>,
+        // The operations that were computed but not returned yet.
+        pending: VecDeque<TimelineItemOp>,
+    }
+}
+
+impl<S> KeyedStream<S> {
+    pub(super) fn new(items: &Vector<Arc<TimelineItem>>, inner: S) -> Self {
+        Self {
+            inner,
+            ids: items.iter().map(|item| item.unique_id()).collect(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<S> Stream for KeyedStream<S>
+where
+    S: Stream<Item = VectorDiff<Arc<TimelineItem>>>,
+{
+    type Item = TimelineItemOp;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        loop {
+            if let Some(op) = this.pending.pop_front() {
+                return Poll::Ready(Some(op));
+            }
+
+            let Some(diff) = ready!(this.inner.as_mut().poll_next(cx)) else {
+                return Poll::Ready(None);
+            };
+
+            apply_diff(this.ids, this.pending, diff);
+        }
+    }
+}
+
+/// Apply an update to the list of IDs, and push the matching operations.
+fn apply_diff(
+    ids: &mut Vector<TimelineItemId>,
+    ops: &mut VecDeque<TimelineItemOp>,
+    diff: VectorDiff<Arc<TimelineItem>>,
+) {
+    match diff {
+        VectorDiff::Append { values } => {
+            for item in values {
+                let index = ids.len();
+                insert(ids, ops, index, item);
+            }
+        }
+        VectorDiff::Clear => {
+            ids.clear();
+            ops.push_back(TimelineItemOp::Reset { items: Vector::new() });
+        }
+        VectorDiff::PushFront { value } => insert(ids, ops, 0, value),
+        VectorDiff::PushBack { value } => {
+            let index = ids.len();
+            insert(ids, ops, index, value);
+        }
+        VectorDiff::PopFront => {
+            if let Some(id) = ids.pop_front() {
+                ops.push_back(TimelineItemOp::Remove { id });
+            }
+        }
+        VectorDiff::PopBack => {
+            if let Some(id) = ids.pop_back() {
+                ops.push_back(TimelineItemOp::Remove { id });
+            }
+        }
+        VectorDiff::Insert { index, value } => insert(ids, ops, index, value),
+        VectorDiff::Set { index, value } => {
+            let id = value.unique_id();
+
+            if ids[index] == id {
+                ops.push_back(TimelineItemOp::Update { id, item: value });
+            } else {
+                // The item was replaced by another one.
+                let old_id = ids.remove(index);
+                ops.push_back(TimelineItemOp::Remove { id: old_id });
+                insert(ids, ops, index, value);
+            }
+        }
+        VectorDiff::Remove { index } => {
+            let id = ids.remove(index);
+            ops.push_back(TimelineItemOp::Remove { id });
+        }
+        VectorDiff::Reset { values } => {
+            *ids = values.iter().map(|item| item.unique_id()).collect();
+            ops.push_back(TimelineItemOp::Reset { items: values });
+        }
+    }
+}
+
+fn insert(
+    ids: &mut Vector<TimelineItemId>,
+    ops: &mut VecDeque<TimelineItemOp>,
+    index: usize,
+    item: Arc<TimelineItem>,
+) {
+    let after = index.checked_sub(1).map(|previous| ids[previous].clone());
+    ids.insert(index, item.unique_id());
+    ops.push_back(TimelineItemOp::Insert { after, item });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+    use eyeball_im::ObservableVector;
+    use futures_util::StreamExt;
+    use matrix_sdk_test::async_test;
+    use ruma::MilliSecondsSinceUnixEpoch;
+    use stream_assert::assert_pending;
+
+    use super::{KeyedStream, TimelineItemId, TimelineItemOp};
+    use crate::timeline::TimelineItem;
+
+    fn day_divider(ts: u32) -> Arc<TimelineItem> {
+        Arc::new(TimelineItem::day_divider(MilliSecondsSinceUnixEpoch(ts.into())))
+    }
+
+    fn divider_id(ts: u32) -> TimelineItemId {
+        TimelineItemId::DayDivider(MilliSecondsSinceUnixEpoch(ts.into()))
+    }
+
+    #[async_test]
+    async fn updates_are_keyed() {
+        let mut items = ObservableVector::new();
+        items.push_back(day_divider(1));
+        let mut stream = KeyedStream::new(&items, items.subscribe());
+
+        items.push_back(Arc::new(TimelineItem::read_marker()));
+        items.insert(1, day_divider(2));
+        items.push_front(Arc::new(TimelineItem::loading_indicator()));
+        items.set(1, day_divider(1));
+        items.set(1, day_divider(3));
+        items.remove(2);
+
+        assert_matches!(
+            stream.next().await,
+            Some(TimelineItemOp::Insert { after: Some(id), item }) => {
+                assert_eq!(id, divider_id(1));
+                assert!(item.is_read_marker());
+            }
+        );
+        assert_matches!(
+            stream.next().await,
+            Some(TimelineItemOp::Insert { after: Some(id), item }) => {
+                assert_eq!(id, divider_id(1));
+                assert_eq!(item.unique_id(), divider_id(2));
+            }
+        );
+        assert_matches!(
+            stream.next().await,
+            Some(TimelineItemOp::Insert { after: None, item }) => {
+                assert!(item.is_loading_indicator());
+            }
+        );
+        assert_matches!(
+            stream.next().await,
+            Some(TimelineItemOp::Update { id, .. }) => assert_eq!(id, divider_id(1))
+        );
+        assert_matches!(
+            stream.next().await,
+            Some(TimelineItemOp::Remove { id }) => assert_eq!(id, divider_id(1))
+        );
+        assert_matches!(
+            stream.next().await,
+            Some(TimelineItemOp::Insert { after: Some(TimelineItemId::LoadingIndicator), item }) => {
+                assert_eq!(item.unique_id(), divider_id(3));
+            }
+        );
+        assert_matches!(
+            stream.next().await,
+            Some(TimelineItemOp::Remove { id }) => assert_eq!(id, divider_id(2))
+        );
+        assert_pending!(stream);
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use ruma::events::room::message::MessageType;
+
+/// Detects the language of the text messages of a [`Timeline`].
+///
+/// It is called every time a text message is added to the timeline or edited,
+/// while the timeline is locked, so it should be fast.
+///
+/// [`Timeline`]: super::Timeline
+pub trait LanguageDetector: fmt::Debug + Send + Sync {
+    /// Detect the language of the given text.
+    ///
+    /// Returns a BCP 47 language tag, like `en` or `ar-EG`, or `None` if the
+    /// language can't be detected reliably, for example because the text is
+    /// too short.
+    fn detect(&self, text: &str) -> Option<String>;
+}
+
+/// The direction of a text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextDirection {
+    /// The text is written from left to right.
+    LeftToRight,
+    /// The text is written from right to left.
+    RightToLeft,
+}
+
+impl TextDirection {
+    /// Get the direction of the text written in the given language, from its
+    /// BCP 47 language tag.
+    ///
+    /// The script subtag is used if there is one, otherwise the usual script
+    /// of the language.
+    pub fn of_language(language: &str) -> Self {
+        const RTL_SCRIPTS: &[&str] =
+            &["adlm", "arab", "hebr", "mand", "mend", "nkoo", "rohg", "samr", "syrc", "thaa"];
+        const RTL_LANGUAGES: &[&str] = &[
+            "ar", "arc", "ckb", "dv", "fa", "he", "iw", "ks", "ps", "sd", "syr", "ug", "ur", "yi",
+        ];
+
+        let language = language.to_ascii_lowercase();
+        let mut subtags = language.split(['-', '_']);
+        let primary = subtags.next().unwrap_or_default();
+
+        // The script subtag is the only one made of 4 letters.
+        let is_script =
+            |subtag: &&str| subtag.len() == 4 && subtag.chars().all(|c| c.is_ascii_alphabetic());
+        let is_rtl = match subtags.find(is_script) {
+            Some(script) => RTL_SCRIPTS.contains(&script),
+            None => RTL_LANGUAGES.contains(&primary),
+        };
+
+        if is_rtl {
+            Self::RightToLeft
+        } else {
+            Self::LeftToRight
+        }
+    }
+}
+
+/// Detect the language of the body of the given message, if it is a text
+/// message.
+pub(super) fn detect_language(
+    detector: Option<&dyn LanguageDetector>,
+    msgtype: &MessageType,
+) -> Option<String> {
+    let body = match msgtype {
+        MessageType::Text(content) => &content.body,
+        MessageType::Notice(content) => &content.body,
+        MessageType::Emote(content) => &content.body,
+        _ => return None,
+    };
+
+    detector?.detect(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TextDirection;
+
+    #[test]
+    fn text_direction_of_language() {
+        assert_eq!(TextDirection::of_language("en"), TextDirection::LeftToRight);
+        assert_eq!(TextDirection::of_language("fr-CA"), TextDirection::LeftToRight);
+        assert_eq!(TextDirection::of_language("ar"), TextDirection::RightToLeft);
+        assert_eq!(TextDirection::of_language("he-IL"), TextDirection::RightToLeft);
+        assert_eq!(TextDirection::of_language("zh-Hant"), TextDirection::LeftToRight);
+
+        // The script takes precedence over the language.
+        assert_eq!(TextDirection::of_language("az-Arab"), TextDirection::RightToLeft);
+        assert_eq!(TextDirection::of_language("ku-Latn"), TextDirection::LeftToRight);
+    }
+}
@@ -16,43 +16,84 @@
 //!
 //! See [`Timeline`] for details.
 
-use std::{pin::Pin, sync::Arc, task::Poll, time::Duration};
+use std::{
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::Duration,
+};
 
 use async_std::sync::{Condvar, Mutex};
+use eyeball::Subscriber;
 use eyeball_im::VectorDiff;
 use futures_core::Stream;
 use imbl::Vector;
+use indexmap::IndexMap;
 use matrix_sdk::{
     attachment::AttachmentConfig,
+    deserialized_responses::TimelineEvent,
     event_handler::EventHandlerHandle,
     executor::JoinHandle,
-    room::{self, MessagesOptions, Receipts, Room},
-    Client, Result,
+    room::{self, MessagesOptions, Receipts, RelatedEvent, Room},
+    sleep::sleep,
+    Client, Result, SessionVerdict,
 };
 use mime::Mime;
 use pin_project_lite::pin_project;
 use ruma::{
-    api::client::receipt::create_receipt::v3::ReceiptType,
+    api::{
+        client::{
+            receipt::create_receipt::v3::ReceiptType,
+            relations::get_relating_events_with_rel_type_and_event_type,
+        },
+        Direction,
+    },
     assign,
     events::{
+        reaction::OriginalSyncReactionEvent,
         receipt::{Receipt, ReceiptThread},
-        room::message::sanitize::HtmlSanitizerMode,
-        AnyMessageLikeEventContent,
+        relation::RelationType,
+        room::{
+            member::MembershipState,
+            message::{
+                self,
+                sanitize::{HtmlSanitizerMode, RemoveReplyFallback},
+                MessageType,
+            },
+        },
+        AnyMessageLikeEventContent, AnySyncMessageLikeEvent, AnySyncTimelineEvent,
+        MessageLikeEventType, SyncMessageLikeEvent, TimelineEventType,
     },
-    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, TransactionId, UserId,
+    serde::Raw,
+    uint, EventId, Int, MatrixToUri, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId,
+    TransactionId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{debug, error, info, instrument, warn};
 
+mod batched_stream;
 mod builder;
+mod divider;
 mod event_handler;
 mod event_item;
 mod futures;
 mod inner;
+mod keyed_stream;
+mod language;
 mod pagination;
 mod read_receipts;
+#[cfg(feature = "e2e-encryption")]
+mod security_notices;
 #[cfg(feature = "experimental-sliding-sync")]
 mod sliding_sync_ext;
+mod snapshot;
+#[cfg(feature = "testing")]
+pub mod testing;
 #[cfg(test)]
 mod tests;
 #[cfg(feature = "e2e-encryption")]
@@ -61,22 +102,46 @@ mod traits;
 mod virtual_item;
 
 pub(crate) use self::builder::TimelineBuilder;
-use self::inner::{TimelineInner, TimelineInnerState};
 #[cfg(feature = "experimental-sliding-sync")]
 pub use self::sliding_sync_ext::SlidingSyncRoomExt;
+use self::{
+    batched_stream::BatchedStream,
+    event_item::EventTimelineItemKind,
+    inner::{TimelineInner, TimelineInnerState, WindowEdge},
+    keyed_stream::KeyedStream,
+};
 pub use self::{
+    divider::{DividerFn, DividerPolicy},
     event_item::{
         AnyOtherFullStateEventContent, BundledReactions, EncryptedMessage, EventSendState,
         EventTimelineItem, InReplyToDetails, MemberProfileChange, MembershipChange, Message,
-        OtherState, Profile, ReactionGroup, RepliedToEvent, RoomMembershipChange, Sticker,
-        TimelineDetails, TimelineItemContent,
+        OtherState, Profile, ReactionGroup, ReadReceipt, RepliedToEvent, RoomMembershipChange,
+        SendReceipt, Sticker, TimelineDetails, TimelineItemContent,
     },
     futures::SendAttachment,
+    keyed_stream::{TimelineItemId, TimelineItemOp},
+    language::{LanguageDetector, TextDirection},
     pagination::{PaginationOptions, PaginationOutcome},
+    snapshot::{
+        ChangeSnapshot, ContentSnapshot, EventSnapshot, ReactionsSnapshot, ReadReceiptSnapshot,
+        SendStateSnapshot, TimelineItemSnapshot, TimelineSnapshot, VirtualItemSnapshot,
+        TIMELINE_SNAPSHOT_VERSION,
+    },
     traits::RoomExt,
-    virtual_item::VirtualTimelineItem,
+    virtual_item::{SecurityNotice, SecurityNoticeId, VirtualTimelineItem},
 };
 
+/// The number of events to request on each side of the event that
+/// [`Timeline::jump_to_date`] focuses on.
+const JUMP_CONTEXT_SIZE: UInt = uint!(10);
+
+/// How long [`Timeline::set_viewport`] waits for the viewport to stop changing
+/// before asking the homeserver for pagination tokens.
+const VIEWPORT_DEBOUNCE_DELAY: Duration = Duration::from_millis(300);
+
+/// The number of reactions to request per page in [`Timeline::item_context`].
+const REACTIONS_PAGE_SIZE: UInt = uint!(100);
+
 /// The default sanitizer mode used when sanitizing HTML.
 const DEFAULT_SANITIZER_MODE: HtmlSanitizerMode = HtmlSanitizerMode::Compat;
 
@@ -90,7 +155,13 @@ pub struct Timeline {
     inner: Arc<TimelineInner<room::Common>>,
     start_token: Arc<Mutex<Option<String>>>,
     start_token_condvar: Arc<Condvar>,
-    _end_token: Mutex<Option<String>>,
+    end_token: Mutex<Option<String>>,
+    /// Whether the timeline follows the live end of the room, i.e. whether
+    /// events received via sync are added to it.
+    is_live: Arc<AtomicBool>,
+    /// Incremented on every call to [`Timeline::set_viewport`], to skip the
+    /// requests of the viewports that changed in the meantime.
+    viewport_generation: AtomicU64,
     drop_handle: Arc<TimelineDropHandle>,
 }
 
@@ -107,7 +178,7 @@ impl Timeline {
     #[cfg(feature = "experimental-sliding-sync")]
     pub async fn clear(&self) {
         let mut start_lock = self.start_token.lock().await;
-        let mut end_lock = self._end_token.lock().await;
+        let mut end_lock = self.end_token.lock().await;
 
         *start_lock = None;
         *end_lock = None;
@@ -152,6 +223,15 @@ impl Timeline {
                 }))
                 .await?;
 
+            // The events are in reverse chronological order.
+            self.save_chunk_tokens(
+                messages.chunk.last().and_then(event_id_of),
+                messages.end.clone(),
+                messages.chunk.first().and_then(event_id_of),
+                Some(messages.start.clone()),
+            )
+            .await;
+
             let process_events_result = async {
                 outcome.events_received = messages.chunk.len().try_into().ok()?;
                 outcome.total_events_received =
@@ -192,6 +272,315 @@ impl Timeline {
         Ok(())
     }
 
+    /// Add more events to the end of the timeline, while it is focused on a
+    /// point in the past.
+    ///
+    /// This does nothing if the timeline is [live](Self::is_live). Once the
+    /// live end of the room is reached, the timeline goes back to being live
+    /// and events received via sync are added to it again.
+    #[instrument(skip_all, fields(room_id = ?self.room().room_id(), ?options))]
+    pub async fn paginate_forwards(&self, mut options: PaginationOptions<'_>) -> Result<()> {
+        let mut end_lock = self.end_token.lock().await;
+        if self.is_live() {
+            warn!("Timeline is live, ignoring forwards-pagination request");
+            return Ok(());
+        }
+
+        let mut from = end_lock.clone();
+        let mut outcome = PaginationOutcome::new();
+
+        while let Some(limit) = options.next_event_limit(outcome) {
+            let messages = self
+                .room()
+                .messages(assign!(MessagesOptions::forward(), {
+                    from,
+                    limit: limit.into(),
+                }))
+                .await?;
+
+            self.save_chunk_tokens(
+                messages.chunk.first().and_then(event_id_of),
+                Some(messages.start.clone()),
+                messages.chunk.last().and_then(event_id_of),
+                messages.end.clone(),
+            )
+            .await;
+
+            let process_events_result = async {
+                outcome.events_received = messages.chunk.len().try_into().ok()?;
+                outcome.total_events_received =
+                    outcome.total_events_received.checked_add(outcome.events_received)?;
+                outcome.items_added = 0;
+                outcome.items_updated = 0;
+
+                for room_ev in messages.chunk {
+                    let res = self.inner.handle_forward_paginated_event(room_ev).await;
+                    outcome.items_added = outcome.items_added.checked_add(res.item_added as u16)?;
+                    outcome.items_updated = outcome.items_updated.checked_add(res.items_updated)?;
+                }
+
+                outcome.total_items_added =
+                    outcome.total_items_added.checked_add(outcome.items_added)?;
+                outcome.total_items_updated =
+                    outcome.total_items_updated.checked_add(outcome.items_updated)?;
+
+                Some(())
+            }
+            .await;
+
+            from = messages.end;
+
+            if from.is_none() {
+                break;
+            }
+
+            if process_events_result.is_none() {
+                error!("Received an excessive number of events, ending pagination (u16 overflow)");
+                break;
+            }
+        }
+
+        if from.is_none() {
+            info!("Reached the live end of the room, switching back to live mode");
+            self.is_live.store(true, Ordering::SeqCst);
+        }
+
+        *end_lock = from;
+
+        Ok(())
+    }
+
+    /// Whether this timeline follows the live end of the room.
+    ///
+    /// This is `true` unless the timeline has been focused on a point in the
+    /// past with [`Timeline::jump_to_date`] and hasn't returned to the live
+    /// end since.
+    pub fn is_live(&self) -> bool {
+        self.is_live.load(Ordering::SeqCst)
+    }
+
+    /// Focus the timeline on the event that is closest to the given point in
+    /// time.
+    ///
+    /// This asks the homeserver for the event closest to (and not newer than)
+    /// `ts`, then replaces all the timeline items with that event and the
+    /// events surrounding it. While focused, events received via sync are not
+    /// added to the timeline; use [`Timeline::paginate_backwards`] and
+    /// [`Timeline::paginate_forwards`] to load more events around the focused
+    /// one, or [`Timeline::return_to_live`] to go back to the live end of the
+    /// room.
+    ///
+    /// Returns the ID of the event the timeline got focused on.
+    ///
+    /// # Arguments
+    ///
+    /// * `ts` - The point in time to jump to.
+    #[instrument(skip(self), fields(room_id = ?self.room().room_id()))]
+    pub async fn jump_to_date(&self, ts: MilliSecondsSinceUnixEpoch) -> Result<OwnedEventId> {
+        let (event_id, _) = match self.room().event_by_timestamp(ts, Direction::Backward).await {
+            Ok(found) => found,
+            Err(e) => {
+                // There might be no event before the given timestamp, e.g. if
+                // it is older than the room, try to find the first event after
+                // it instead.
+                debug!("Could not find an event before the timestamp: {e}");
+                self.room().event_by_timestamp(ts, Direction::Forward).await?
+            }
+        };
+
+        let context =
+            self.room().event_with_context(&event_id, JUMP_CONTEXT_SIZE, JUMP_CONTEXT_SIZE).await?;
+
+        let mut start_lock = self.start_token.lock().await;
+        let mut end_lock = self.end_token.lock().await;
+
+        self.is_live.store(false, Ordering::SeqCst);
+        self.inner.clear().await;
+
+        // `events_before` and `events_after` are ordered away from the event.
+        self.save_chunk_tokens(
+            context.events_before.last().or(context.event.as_ref()).and_then(event_id_of),
+            context.prev_batch_token.clone(),
+            context.events_after.last().or(context.event.as_ref()).and_then(event_id_of),
+            context.next_batch_token.clone(),
+        )
+        .await;
+
+        if let Some(event) = context.event {
+            self.inner.handle_forward_paginated_event(event).await;
+        }
+        // `events_before` is in reverse chronological order, so each event
+        // goes before the previously added one.
+        for event in context.events_before {
+            self.inner.handle_back_paginated_event(event).await;
+        }
+        for event in context.events_after {
+            self.inner.handle_forward_paginated_event(event).await;
+        }
+
+        *start_lock = context.prev_batch_token;
+        *end_lock = context.next_batch_token;
+
+        Ok(event_id)
+    }
+
+    /// Focus the timeline on the event that is closest to the given number of
+    /// days ago.
+    ///
+    /// This is a shorthand for [`Timeline::jump_to_date`] with a timestamp
+    /// computed from the current time.
+    pub async fn jump_days_back(&self, days: u32) -> Result<OwnedEventId> {
+        let offset = UInt::new_saturating(u64::from(days) * 24 * 60 * 60 * 1000);
+        let now = MilliSecondsSinceUnixEpoch::now();
+        self.jump_to_date(MilliSecondsSinceUnixEpoch(now.0.saturating_sub(offset))).await
+    }
+
+    /// Stop focusing on a point in the past and go back to the live end of the
+    /// room.
+    ///
+    /// This clears all the timeline items and resets pagination. New events
+    /// received via sync are added to the timeline again, and older ones can
+    /// be loaded with [`Timeline::paginate_backwards`].
+    #[instrument(skip(self), fields(room_id = ?self.room().room_id()))]
+    pub async fn return_to_live(&self) {
+        let mut start_lock = self.start_token.lock().await;
+        let mut end_lock = self.end_token.lock().await;
+
+        if self.is_live() {
+            debug!("Timeline is already live");
+            return;
+        }
+
+        *start_lock = None;
+        *end_lock = None;
+
+        self.inner.clear().await;
+        self.is_live.store(true, Ordering::SeqCst);
+    }
+
+    /// Set the maximum number of items to keep in this timeline, for very
+    /// large rooms.
+    ///
+    /// When set, calling [`Timeline::set_viewport`] drops the items that are
+    /// too far from the viewport, so the memory used by the timeline stays
+    /// bounded however far it is paginated. The dropped items are removed with
+    /// the usual diffs, and are loaded again from the homeserver with
+    /// [`Timeline::paginate_backwards`] and [`Timeline::paginate_forwards`].
+    ///
+    /// `None` disables the window, which is the default.
+    pub async fn set_window_size(&self, size: Option<NonZeroUsize>) {
+        self.inner.set_window_size(size).await;
+    }
+
+    /// Tell the timeline which event is shown in the viewport of the app.
+    ///
+    /// If the timeline is windowed with [`Timeline::set_window_size`] and has
+    /// more items than the window, the items are dropped so only the window
+    /// around the given event is kept. If newer items are dropped, the
+    /// timeline stops being [live](Self::is_live) until they are loaded again
+    /// with [`Timeline::paginate_forwards`].
+    ///
+    /// This should be called when the user scrolls, and after a pagination.
+    /// It does nothing if the event is not in the timeline.
+    ///
+    /// The pagination tokens received with the events are used to load the
+    /// dropped items again. If the homeserver must be asked for a token, the
+    /// request is only sent once the viewport stopped changing for a moment.
+    #[instrument(skip(self), fields(room_id = ?self.room().room_id()))]
+    pub async fn set_viewport(&self, event_id: &EventId) -> Result<()> {
+        let generation = self.viewport_generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let Some(mut edges) = self.inner.window_edges(event_id).await else {
+            return Ok(());
+        };
+
+        if edges.needs_request() {
+            sleep(VIEWPORT_DEBOUNCE_DELAY).await;
+
+            if self.viewport_generation.load(Ordering::SeqCst) != generation {
+                debug!("The viewport changed, not requesting the pagination tokens");
+                return Ok(());
+            }
+
+            // The timeline might have changed in the meantime.
+            let Some(new_edges) = self.inner.window_edges(event_id).await else {
+                return Ok(());
+            };
+            edges = new_edges;
+        }
+
+        let mut start_lock = self.start_token.lock().await;
+        let mut end_lock = self.end_token.lock().await;
+
+        // Get the tokens to load the dropped items again. Without a token, the
+        // items are kept, because they couldn't be loaded again.
+        let (first, start_token) = match edges.first {
+            Some(edge) => self.window_edge_token(edge, Direction::Backward).await?.unzip(),
+            None => (None, None),
+        };
+        let (last, end_token) = match edges.last {
+            Some(edge) => self.window_edge_token(edge, Direction::Forward).await?.unzip(),
+            None => (None, None),
+        };
+
+        if last.is_some() {
+            self.is_live.store(false, Ordering::SeqCst);
+        }
+
+        self.inner.retain_window(first.as_deref(), last.as_deref()).await;
+
+        if start_token.is_some() {
+            *start_lock = start_token;
+        }
+        if end_token.is_some() {
+            *end_lock = end_token;
+        }
+
+        Ok(())
+    }
+
+    /// Get the token to load the events in the given direction from the given
+    /// edge of the window, asking the homeserver if it isn't known.
+    async fn window_edge_token(
+        &self,
+        edge: WindowEdge,
+        direction: Direction,
+    ) -> Result<Option<(OwnedEventId, String)>> {
+        if let Some(token) = edge.token {
+            return Ok(Some((edge.event_id, token)));
+        }
+
+        let context = self.room().event_with_context(&edge.event_id, uint!(0), uint!(0)).await?;
+        let token = match direction {
+            Direction::Backward => context.prev_batch_token,
+            Direction::Forward => context.next_batch_token,
+        };
+        let Some(token) = token else {
+            return Ok(None);
+        };
+
+        self.inner.save_pagination_token(edge.event_id.clone(), direction, token.clone()).await;
+        Ok(Some((edge.event_id, token)))
+    }
+
+    /// Remember the pagination tokens received with a chunk of events, to
+    /// drop the items outside of the window without asking the homeserver for
+    /// them.
+    async fn save_chunk_tokens(
+        &self,
+        oldest: Option<OwnedEventId>,
+        before: Option<String>,
+        newest: Option<OwnedEventId>,
+        after: Option<String>,
+    ) {
+        if let (Some(event_id), Some(token)) = (oldest, before) {
+            self.inner.save_pagination_token(event_id, Direction::Backward, token).await;
+        }
+        if let (Some(event_id), Some(token)) = (newest, after) {
+            self.inner.save_pagination_token(event_id, Direction::Forward, token).await;
+        }
+    }
+
     /// Retry decryption of previously un-decryptable events given a list of
     /// session IDs whose keys have been imported.
     ///
@@ -252,6 +641,13 @@ impl Timeline {
         self.inner.items().await
     }
 
+    /// Get a serializable snapshot of the current timeline items.
+    ///
+    /// See [`TimelineSnapshot`] for more details.
+    pub async fn snapshot(&self) -> TimelineSnapshot {
+        TimelineSnapshot::new(self.inner.items().await.iter().map(|item| &**item))
+    }
+
     /// Get the latest of the timeline's event items.
     pub async fn latest_event(&self) -> Option<EventTimelineItem> {
         self.inner.items().await.last()?.as_event().cloned()
@@ -269,6 +665,37 @@ impl Timeline {
         (items, stream)
     }
 
+    /// Get the current timeline items, and a stream of batches of changes.
+    ///
+    /// Unlike [`Timeline::subscribe`], all the changes that are available when
+    /// the stream is polled are returned at once, e.g. after a back-pagination
+    /// or a gappy sync. Consecutive changes are merged when possible, and a
+    /// batch with too many changes is replaced by a single
+    /// [`VectorDiff::Reset`], which is cheaper for the UI to apply.
+    pub async fn subscribe_batched(
+        &self,
+    ) -> (Vector<Arc<TimelineItem>>, impl Stream<Item = Vec<VectorDiff<Arc<TimelineItem>>>>) {
+        let (items, stream) = self.inner.subscribe().await;
+        let stream = BatchedStream::new(items.clone(), stream);
+        let stream = TimelineStream::new(stream, self.drop_handle.clone());
+        (items, stream)
+    }
+
+    /// Get the current timeline items, and a stream of changes identified by
+    /// the [`TimelineItemId`] of the items instead of their index.
+    ///
+    /// This is easier to apply than the [`VectorDiff`]s of
+    /// [`Timeline::subscribe`] for declarative UI frameworks, which identify
+    /// their views by key, and across FFI.
+    pub async fn subscribe_keyed(
+        &self,
+    ) -> (Vector<Arc<TimelineItem>>, impl Stream<Item = TimelineItemOp>) {
+        let (items, stream) = self.inner.subscribe().await;
+        let stream = KeyedStream::new(&items, stream);
+        let stream = TimelineStream::new(stream, self.drop_handle.clone());
+        (items, stream)
+    }
+
     #[cfg(feature = "testing")]
     pub async fn subscribe_filter_map<U: Clone>(
         &self,
@@ -279,6 +706,15 @@ impl Timeline {
         (items, stream)
     }
 
+    /// Get a subscriber to the [`SessionVerdict`] of the client.
+    ///
+    /// When the user needs to log in again, the messages can't be sent
+    /// anymore and their local echoes end up in the
+    /// [`EventSendState::SendingFailed`] state.
+    pub fn session_verdict(&self) -> Subscriber<SessionVerdict> {
+        self.room().client().subscribe_to_session_verdict()
+    }
+
     /// Send a message to the room, and add it to the timeline as a local echo.
     ///
     /// For simplicity, this method doesn't currently allow custom message
@@ -433,6 +869,53 @@ impl Timeline {
         self.inner.discard_local_echo(txn_id).await
     }
 
+    /// Report the event with the given ID to the homeserver administrators,
+    /// and ignore its sender.
+    ///
+    /// Once the sender is ignored, all their events are removed from this
+    /// timeline. The homeserver doesn't send the events of ignored users
+    /// anymore, so they are not added back.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The ID of the event to report. It must be a remote event
+    ///   in this timeline.
+    ///
+    /// * `score` - How offensive the event is, from `-100` for the most
+    ///   offensive to `0` for inoffensive.
+    ///
+    /// * `reason` - The reason why the event is reported.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the event is not in the timeline, if the room is
+    /// not joined, or if either request fails. Nothing is removed from the
+    /// timeline in that case.
+    #[instrument(skip(self, reason), fields(room_id = ?self.room().room_id()))]
+    pub async fn report_and_ignore_user(
+        &self,
+        event_id: &EventId,
+        score: Option<Int>,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let item = self.item_by_event_id(event_id).await.ok_or(Error::RemoteEventNotInTimeline)?;
+        let sender = item.sender().to_owned();
+
+        let Room::Joined(room) = Room::from(self.room().clone()) else {
+            return Err(Error::RoomNotJoined);
+        };
+
+        room.report_event(event_id, score, reason)
+            .await
+            .map_err(|error| Error::FailedReporting(error.into()))?;
+        room.client().account().ignore_user(&sender).await.map_err(Error::FailedReporting)?;
+
+        let removed = self.inner.remove_events_of_user(&sender).await;
+        debug!(%sender, removed, "Removed the events of the ignored user");
+
+        Ok(())
+    }
+
     /// Fetch unavailable details about the event with the given ID.
     ///
     /// This method only works for IDs of remote [`EventTimelineItem`]s,
@@ -457,6 +940,320 @@ impl Timeline {
         self.inner.fetch_in_reply_to_details(event_id).await
     }
 
+    /// Load a page of the senders of the reactions with the given key to the
+    /// given event.
+    ///
+    /// This is meant for events with a lot of reactions, for which the server
+    /// only sends the number of reactions, see
+    /// [`ReactionGroup::is_summarized()`]. The senders are not kept in the
+    /// timeline, so the memory used stays bounded however many reactions there
+    /// are.
+    ///
+    /// The reactions are filtered by key locally, so a page can contain fewer
+    /// senders than `limit`, even if there are more.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The ID of the event that was reacted to.
+    ///
+    /// * `key` - The key of the reactions, usually an emoji.
+    ///
+    /// * `from` - The [`ReactionSenders::next_batch`] token of the previous
+    ///   page, or `None` to load the first page.
+    ///
+    /// * `limit` - The maximum number of reactions to request to the server.
+    #[instrument(skip(self), fields(room_id = ?self.room().room_id()))]
+    pub async fn fetch_reaction_senders(
+        &self,
+        event_id: &EventId,
+        key: &str,
+        from: Option<String>,
+        limit: UInt,
+    ) -> Result<ReactionSenders> {
+        let (reactions, next_batch) = self.load_reactions(event_id, from, limit).await?;
+
+        let senders = reactions
+            .into_iter()
+            .filter(|ev| ev.content.relates_to.key == key)
+            .map(|ev| (ev.sender, ev.event_id))
+            .collect();
+
+        Ok(ReactionSenders { senders, next_batch })
+    }
+
+    /// Load a page of the reactions to the given event from the server.
+    async fn load_reactions(
+        &self,
+        event_id: &EventId,
+        from: Option<String>,
+        limit: UInt,
+    ) -> Result<(Vec<OriginalSyncReactionEvent>, Option<String>)> {
+        let request = assign!(
+            get_relating_events_with_rel_type_and_event_type::v1::Request::new(
+                self.room().room_id().to_owned(),
+                event_id.to_owned(),
+                RelationType::Annotation,
+                TimelineEventType::Reaction,
+            ),
+            { from, limit: Some(limit) }
+        );
+        let response = self.room().client().send(request, None).await?;
+
+        let reactions = response
+            .chunk
+            .into_iter()
+            .filter_map(|raw| match raw.deserialize_as::<OriginalSyncReactionEvent>() {
+                Ok(ev) => Some(ev),
+                Err(e) => {
+                    debug!("Skipping a reaction that couldn't be deserialized: {e}");
+                    None
+                }
+            })
+            .collect();
+
+        Ok((reactions, response.next_batch))
+    }
+
+    /// Load the edit history of the message of the given item, to show the
+    /// previous versions of an edited message.
+    ///
+    /// The versions are sorted from the oldest one, the original message, to
+    /// the newest one. Like in the timeline, the edits that were not sent by
+    /// the sender of the message are ignored.
+    ///
+    /// Returns an empty list if the item is not a message or is a local echo.
+    #[instrument(skip_all, fields(room_id = ?self.room().room_id()))]
+    pub async fn edit_history(&self, item: &EventTimelineItem) -> Result<Vec<MessageVersion>> {
+        let (Some(event_id), Some(original_json)) = (item.event_id(), item.original_json()) else {
+            return Ok(Vec::new());
+        };
+        let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(original),
+        ))) = original_json.deserialize()
+        else {
+            return Ok(Vec::new());
+        };
+
+        let remove_reply_fallback =
+            if matches!(original.content.relates_to, Some(message::Relation::Reply { .. })) {
+                RemoveReplyFallback::Yes
+            } else {
+                RemoveReplyFallback::No
+            };
+        let mut msgtype = original.content.msgtype;
+        msgtype.sanitize(DEFAULT_SANITIZER_MODE, remove_reply_fallback);
+
+        let mut edits = Vec::new();
+        let mut from = None;
+        loop {
+            let relations = self
+                .room()
+                .relations(event_id, Some(RelationType::Replacement), from.as_deref())
+                .await?;
+
+            for related_event in relations.related_events() {
+                let RelatedEvent::Edit { event_id, sender, origin_server_ts, mut new_content } =
+                    related_event
+                else {
+                    continue;
+                };
+
+                if *sender != *item.sender() {
+                    debug!(?event_id, ?sender, "Skipping an edit sent by another user");
+                    continue;
+                }
+
+                // Edit's content is never supposed to contain the reply fallback.
+                new_content.sanitize(DEFAULT_SANITIZER_MODE, RemoveReplyFallback::No);
+                edits.push(MessageVersion {
+                    event_id,
+                    timestamp: origin_server_ts,
+                    msgtype: new_content,
+                });
+            }
+
+            match relations.next_batch_token {
+                Some(token) => from = Some(token),
+                None => break,
+            }
+        }
+
+        edits.sort_by_key(|version| version.timestamp);
+
+        let mut versions = Vec::with_capacity(edits.len() + 1);
+        versions.push(MessageVersion {
+            event_id: original.event_id,
+            timestamp: original.origin_server_ts,
+            msgtype,
+        });
+        versions.extend(edits);
+
+        Ok(versions)
+    }
+
+    /// Get a `matrix.to` permalink to the event of the given item.
+    ///
+    /// See [`EventTimelineItem::permalink()`] for more details.
+    pub async fn permalink(&self, item: &EventTimelineItem) -> Result<Option<MatrixToUri>> {
+        item.permalink(self.room()).await
+    }
+
+    /// Get all the data needed to show a context menu for the event with the
+    /// given ID, for example on a long press, in a single call.
+    ///
+    /// Unlike [`Self::item_by_event_id()`], this loads the senders of all the
+    /// reactions to the event, including the ones summarized by the server,
+    /// and checks which actions the current user is allowed to do on the
+    /// event according to the power levels of the room.
+    ///
+    /// Returns `Ok(None)` if the event is not in the timeline.
+    #[instrument(skip(self), fields(room_id = ?self.room().room_id()))]
+    pub async fn item_context(&self, event_id: &EventId) -> Result<Option<TimelineItemContext>> {
+        let Some(item) = self.item_by_event_id(event_id).await else {
+            return Ok(None);
+        };
+
+        let mut reactions: IndexMap<String, Vec<OwnedUserId>> = item
+            .reactions()
+            .iter()
+            .map(|(key, group)| (key.clone(), group.senders().map(ToOwned::to_owned).collect()))
+            .collect();
+
+        // The server only sent the number of some reactions, so we need to load
+        // all of them to know their senders.
+        if item.reactions().values().any(ReactionGroup::is_summarized) {
+            reactions.values_mut().for_each(Vec::clear);
+
+            let mut from = None;
+            loop {
+                let (page, next_batch) =
+                    self.load_reactions(event_id, from, REACTIONS_PAGE_SIZE).await?;
+
+                for ev in page {
+                    reactions.entry(ev.content.relates_to.key).or_default().push(ev.sender);
+                }
+
+                match next_batch {
+                    Some(token) => from = Some(token),
+                    None => break,
+                }
+            }
+
+            reactions.retain(|_, senders| !senders.is_empty());
+        }
+
+        let room = self.room();
+        let own_member = room
+            .get_member_no_sync(room.own_user_id())
+            .await?
+            .filter(|member| *member.membership() == MembershipState::Join);
+        let can_send = |event_type| {
+            own_member.as_ref().is_some_and(|member| member.can_send_message(event_type))
+        };
+
+        let can_redact = if item.is_own() {
+            can_send(MessageLikeEventType::RoomRedaction)
+        } else {
+            own_member.as_ref().is_some_and(|member| member.can_redact())
+        };
+        let actions = TimelineItemActions {
+            can_reply: can_send(MessageLikeEventType::RoomMessage),
+            can_react: can_send(MessageLikeEventType::Reaction),
+            can_edit: item.is_editable() && can_send(MessageLikeEventType::RoomMessage),
+            can_redact,
+        };
+
+        let (in_reply_to, is_edited) = match item.content() {
+            TimelineItemContent::Message(message) => {
+                (message.in_reply_to().map(|details| details.event_id.clone()), message.is_edited())
+            }
+            _ => (None, false),
+        };
+        let thread_root = item.original_json().and_then(extract_thread_root);
+
+        Ok(Some(TimelineItemContext {
+            read_receipts: item.read_receipts().clone(),
+            item,
+            reactions,
+            actions,
+            in_reply_to,
+            thread_root,
+            is_edited,
+        }))
+    }
+
+    /// Attach metadata to the event with the given ID, under the given key.
+    ///
+    /// This allows to keep application-specific data about an event, like a
+    /// local translation or a "flagged for follow-up" marker, that can be
+    /// retrieved with [`EventTimelineItem::metadata()`]. The metadata is
+    /// persisted in the state store, and attached again to the item of the
+    /// event every time it is rebuilt, including in timelines created later.
+    ///
+    /// Any metadata previously attached under the same key is replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The ID of the event, it doesn't need to be in the
+    ///   timeline.
+    ///
+    /// * `key` - The key of the metadata, to attach several values to the same
+    ///   event.
+    ///
+    /// * `value` - The metadata to attach, it must be serializable to JSON.
+    #[instrument(skip(self, value))]
+    pub async fn set_item_metadata(
+        &self,
+        event_id: &EventId,
+        key: &str,
+        value: impl Serialize,
+    ) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+        self.inner.set_item_metadata(event_id, key, Some(value)).await
+    }
+
+    /// Remove the metadata attached to the event with the given ID under the
+    /// given key with [`Timeline::set_item_metadata()`].
+    #[instrument(skip(self))]
+    pub async fn remove_item_metadata(&self, event_id: &EventId, key: &str) -> Result<()> {
+        self.inner.set_item_metadata(event_id, key, None).await
+    }
+
+    /// Set how the live events are ordered in this timeline.
+    ///
+    /// It only affects the events received after this call. Defaults to
+    /// [`EventOrdering::Received`].
+    pub async fn set_event_ordering(&self, ordering: EventOrdering) {
+        self.inner.set_event_ordering(ordering).await;
+    }
+
+    /// Set when [`VirtualTimelineItem::DayDivider`]s are inserted between the
+    /// events of this timeline.
+    ///
+    /// The dividers already in the timeline are inserted or removed to follow
+    /// the new policy. Defaults to [`DividerPolicy::Daily`].
+    pub async fn set_divider_policy(&self, policy: DividerPolicy) {
+        self.inner.set_divider_policy(policy).await;
+    }
+
+    /// Set which [`SecurityNotice`]s are added to this timeline.
+    ///
+    /// It only affects the changes that happen after this call. Defaults to
+    /// no notices.
+    pub async fn set_security_notices(&self, settings: SecurityNoticeSettings) {
+        self.inner.set_security_notice_settings(settings).await;
+    }
+
+    /// Set the [`LanguageDetector`] used to detect the language of the text
+    /// messages in this timeline, or remove it with `None`.
+    ///
+    /// The language of the messages already in the timeline is detected again,
+    /// and is available with [`Message::language()`]. Defaults to no
+    /// detection.
+    pub async fn set_language_detector(&self, detector: Option<Arc<dyn LanguageDetector>>) {
+        self.inner.set_language_detector(detector).await;
+    }
+
     /// Fetch all member events for the room this timeline is displaying.
     ///
     /// If the full member list is not known, sender profiles are currently
@@ -578,6 +1375,8 @@ struct TimelineDropHandle {
     client: Client,
     event_handler_handles: Vec<EventHandlerHandle>,
     room_update_join_handle: JoinHandle<()>,
+    #[cfg(feature = "e2e-encryption")]
+    security_notices_join_handle: Option<JoinHandle<()>>,
 }
 
 impl Drop for TimelineDropHandle {
@@ -586,6 +1385,10 @@ impl Drop for TimelineDropHandle {
             self.client.remove_event_handler(handle);
         }
         self.room_update_join_handle.abort();
+        #[cfg(feature = "e2e-encryption")]
+        if let Some(handle) = &self.security_notices_join_handle {
+            handle.abort();
+        }
     }
 }
 
@@ -614,6 +1417,50 @@ impl<S: Stream> Stream for TimelineStream<S> {
     }
 }
 
+/// How the live events are ordered in a [`Timeline`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventOrdering {
+    /// Events are added at the end of the timeline, in the order they are
+    /// received.
+    #[default]
+    Received,
+    /// Events are ordered by their `origin_server_ts`, within a window.
+    ///
+    /// An event that is received after events that were sent up to `window`
+    /// later, e.g. because of federation delays, is inserted before them
+    /// instead of being added at the end. Events that are later than that are
+    /// still added at the end, so the timeline doesn't change too far from its
+    /// end.
+    ///
+    /// This is useful for archival or reading use cases, where the order of
+    /// the events matters more than the order they are received.
+    Timestamp {
+        /// The maximum delay for which late events are reordered.
+        window: Duration,
+    },
+}
+
+/// Which [`SecurityNotice`]s are added to a [`Timeline`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SecurityNoticeSettings {
+    /// Add [`SecurityNotice::EncryptionEnabled`] after the event that enabled
+    /// encryption in the room.
+    pub encryption_enabled: bool,
+    /// Add [`SecurityNotice::UnverifiedDevice`] when a member adds an
+    /// unverified device to a room where all the members are verified.
+    pub unverified_devices: bool,
+    /// Add [`SecurityNotice::IdentityChanged`] when the identity of a member
+    /// changes.
+    pub identity_changes: bool,
+}
+
+impl SecurityNoticeSettings {
+    /// Settings that enable all the notices.
+    pub fn all() -> Self {
+        Self { encryption_enabled: true, unverified_devices: true, identity_changes: true }
+    }
+}
+
 /// A single entry in timeline.
 #[derive(Clone, Debug)]
 #[allow(clippy::large_enum_variant)]
@@ -643,6 +1490,22 @@ impl TimelineItem {
         }
     }
 
+    /// Get the stable identifier of this item.
+    pub fn unique_id(&self) -> TimelineItemId {
+        match self {
+            Self::Event(event) => TimelineItemId::Event(event.unique_identifier()),
+            Self::Virtual(VirtualTimelineItem::DayDivider(ts)) => TimelineItemId::DayDivider(*ts),
+            Self::Virtual(VirtualTimelineItem::ReadMarker) => TimelineItemId::ReadMarker,
+            Self::Virtual(VirtualTimelineItem::LoadingIndicator) => {
+                TimelineItemId::LoadingIndicator
+            }
+            Self::Virtual(VirtualTimelineItem::TimelineStart) => TimelineItemId::TimelineStart,
+            Self::Virtual(VirtualTimelineItem::SecurityNotice { id, .. }) => {
+                TimelineItemId::SecurityNotice(*id)
+            }
+        }
+    }
+
     /// Creates a new day divider from the given timestamp.
     fn day_divider(ts: MilliSecondsSinceUnixEpoch) -> Self {
         Self::Virtual(VirtualTimelineItem::DayDivider(ts))
@@ -660,6 +1523,10 @@ impl TimelineItem {
         Self::Virtual(VirtualTimelineItem::TimelineStart)
     }
 
+    fn security_notice(notice: SecurityNotice) -> Self {
+        Self::Virtual(VirtualTimelineItem::SecurityNotice { id: SecurityNoticeId::new(), notice })
+    }
+
     fn is_virtual(&self) -> bool {
         matches!(self, Self::Virtual(_))
     }
@@ -713,10 +1580,111 @@ fn rfind_event_by_id<'a>(
     rfind_event_item(items, |it| it.event_id() == Some(event_id))
 }
 
+fn rfind_local_echo<'a>(
+    items: &'a Vector<Arc<TimelineItem>>,
+    txn_id: &TransactionId,
+) -> Option<(usize, &'a EventTimelineItem)> {
+    rfind_event_item(items, |it| {
+        it.as_local().is_some_and(|local| *local.transaction_id == *txn_id)
+    })
+}
+
 fn find_read_marker(items: &Vector<Arc<TimelineItem>>) -> Option<usize> {
     items.iter().rposition(|item| item.is_read_marker())
 }
 
+/// A page of the senders of reactions, loaded with
+/// [`Timeline::fetch_reaction_senders()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ReactionSenders {
+    /// The senders of the reactions, with the ID of their reaction event.
+    pub senders: Vec<(OwnedUserId, OwnedEventId)>,
+    /// The token to load the next page, or `None` if there are no more
+    /// reactions.
+    pub next_batch: Option<String>,
+}
+
+/// A version of an edited message, loaded with [`Timeline::edit_history()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct MessageVersion {
+    /// The ID of the original event or of the edit.
+    pub event_id: OwnedEventId,
+    /// The timestamp of the original event or of the edit.
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+    /// The content of the message in this version.
+    pub msgtype: MessageType,
+}
+
+/// The data needed to show a context menu for an event, loaded with
+/// [`Timeline::item_context()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TimelineItemContext {
+    /// The timeline item of the event.
+    pub item: EventTimelineItem,
+    /// The senders of the reactions to the event, by reaction key.
+    ///
+    /// Unlike [`EventTimelineItem::reactions()`], this contains all the
+    /// senders, even for the reactions that are summarized by the server.
+    pub reactions: IndexMap<String, Vec<OwnedUserId>>,
+    /// The read receipts on the event, by user ID.
+    pub read_receipts: IndexMap<OwnedUserId, ReadReceipt>,
+    /// The actions that the current user is allowed to do on the event.
+    pub actions: TimelineItemActions,
+    /// The ID of the event that this event replies to, if any.
+    pub in_reply_to: Option<OwnedEventId>,
+    /// The ID of the root event of the thread that this event is part of, if
+    /// any.
+    pub thread_root: Option<OwnedEventId>,
+    /// Whether the event was edited.
+    pub is_edited: bool,
+}
+
+/// The actions that the current user is allowed to do on an event, according
+/// to the power levels of the room.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TimelineItemActions {
+    /// Whether the user can reply to the event.
+    pub can_reply: bool,
+    /// Whether the user can react to the event.
+    pub can_react: bool,
+    /// Whether the user can edit the event.
+    pub can_edit: bool,
+    /// Whether the user can redact the event.
+    pub can_redact: bool,
+}
+
+/// Get the ID of the given event, if it has one.
+fn event_id_of(event: &TimelineEvent) -> Option<OwnedEventId> {
+    event.event.get_field("event_id").ok().flatten()
+}
+
+/// Get the ID of the root event of the thread that the given event is part of,
+/// if any.
+fn extract_thread_root(event: &Raw<AnySyncTimelineEvent>) -> Option<OwnedEventId> {
+    #[derive(Deserialize)]
+    struct Content {
+        #[serde(rename = "m.relates_to")]
+        relates_to: Option<RelatesTo>,
+    }
+
+    #[derive(Deserialize)]
+    struct RelatesTo {
+        rel_type: Option<RelationType>,
+        event_id: Option<OwnedEventId>,
+    }
+
+    let relates_to = event.get_field::<Content>("content").ok()??.relates_to?;
+    if relates_to.rel_type != Some(RelationType::Thread) {
+        return None;
+    }
+
+    relates_to.event_id
+}
+
 /// Errors specific to the timeline.
 #[derive(Error, Debug)]
 #[non_exhaustive]
@@ -748,6 +1716,10 @@ pub enum Error {
     /// The room is not in a joined state.
     #[error("Room is not joined")]
     RoomNotJoined,
+
+    /// Reporting an event or ignoring its sender failed.
+    #[error("Failed reporting the event or ignoring its sender: {0}")]
+    FailedReporting(#[source] matrix_sdk::Error),
 }
 
 /// Result of comparing events position in the timeline.
@@ -15,6 +15,7 @@
 use std::{collections::HashMap, sync::Arc};
 
 use eyeball_im::ObservableVector;
+use imbl::Vector;
 use indexmap::IndexMap;
 use matrix_sdk::room;
 use ruma::{
@@ -25,7 +26,8 @@ use tracing::{error, warn};
 
 use super::{
     compare_events_positions, event_item::EventTimelineItemKind, inner::TimelineInnerState,
-    rfind_event_by_id, traits::RoomDataProvider, EventTimelineItem, RelativePosition, TimelineItem,
+    rfind_event_by_id, traits::RoomDataProvider, EventTimelineItem, ReadReceipt, RelativePosition,
+    TimelineItem,
 };
 
 struct FullReceipt<'a> {
@@ -41,7 +43,7 @@ impl TimelineInnerState {
         &mut self,
         receipt_item_pos: Option<usize>,
         user_id: OwnedUserId,
-        receipt: Receipt,
+        receipt: ReadReceipt,
     ) {
         let Some(pos) = receipt_item_pos else { return };
         let Some(mut event_item) = self.items[pos].as_event().cloned() else { return };
@@ -50,6 +52,36 @@ impl TimelineInnerState {
         self.items.set(pos, Arc::new(event_item.into()));
     }
 
+    /// Show the latest read receipt of our own user, public or private, on the
+    /// item it points to, unless it is one of our own events.
+    fn update_own_read_receipt(&mut self, own_user_id: &UserId) {
+        let new_receipt = self
+            .users_read_receipts
+            .get(own_user_id)
+            .and_then(|receipts| {
+                latest_read_receipt(
+                    receipts.get(&ReceiptType::Read),
+                    receipts.get(&ReceiptType::ReadPrivate),
+                    &self.items,
+                )
+            })
+            .and_then(|(receipt_type, (event_id, receipt))| {
+                let (pos, event_item) = rfind_event_by_id(&self.items, event_id)?;
+                (!event_item.is_own()).then(|| (pos, ReadReceipt::new(receipt_type, receipt)))
+            });
+        let new_pos = new_receipt.as_ref().map(|(pos, _)| *pos);
+
+        if let Some(old_pos) = find_read_receipt_pos(&self.items, own_user_id) {
+            if new_pos != Some(old_pos) {
+                remove_read_receipt(old_pos, own_user_id, &mut self.items);
+            }
+        }
+
+        if let Some((pos, receipt)) = new_receipt {
+            self.add_read_receipt(Some(pos), own_user_id.to_owned(), receipt);
+        }
+    }
+
     pub(super) fn handle_explicit_read_receipts(
         &mut self,
         receipt_event_content: ReceiptEventContent,
@@ -85,7 +117,14 @@ impl TimelineInnerState {
                         &mut self.users_read_receipts,
                     );
 
-                    if read_receipt_updated && !is_own_user_id {
+                    if !read_receipt_updated {
+                        continue;
+                    }
+
+                    if is_own_user_id {
+                        self.update_own_read_receipt(own_user_id);
+                    } else {
+                        let receipt = ReadReceipt::new(receipt_type.clone(), &receipt);
                         self.add_read_receipt(receipt_item_pos, user_id, receipt);
                     }
                 }
@@ -94,11 +133,15 @@ impl TimelineInnerState {
     }
 
     /// Load the read receipts from the store for the given event ID.
+    ///
+    /// The read receipt of our own user is only added if it is the latest one,
+    /// public or private, and the event was sent by another user.
     pub(super) async fn load_read_receipts_for_event<P: RoomDataProvider>(
         &mut self,
         event_id: &EventId,
+        is_own_event: bool,
         room_data_provider: &P,
-    ) -> IndexMap<OwnedUserId, Receipt> {
+    ) -> IndexMap<OwnedUserId, ReadReceipt> {
         let read_receipts = room_data_provider.read_receipts_for_event(event_id).await;
 
         // Filter out receipts for our own user.
@@ -118,6 +161,31 @@ impl TimelineInnerState {
             }
         }
 
+        let mut read_receipts: IndexMap<_, _> = read_receipts
+            .into_iter()
+            .map(|(user_id, receipt)| (user_id, ReadReceipt::new(ReceiptType::Read, &receipt)))
+            .collect();
+
+        if !is_own_event {
+            let own_receipt = self.users_read_receipts.get(own_user_id).and_then(|receipts| {
+                latest_read_receipt(
+                    receipts.get(&ReceiptType::Read),
+                    receipts.get(&ReceiptType::ReadPrivate),
+                    &self.items,
+                )
+            });
+            if let Some((receipt_type, (_, receipt))) =
+                own_receipt.filter(|(_, (receipt_event_id, _))| receipt_event_id == event_id)
+            {
+                // The receipt moves from the item that shows it, if any.
+                if let Some(old_pos) = find_read_receipt_pos(&self.items, own_user_id) {
+                    remove_read_receipt(old_pos, own_user_id, &mut self.items);
+                }
+                read_receipts
+                    .insert(own_user_id.to_owned(), ReadReceipt::new(receipt_type, receipt));
+            }
+        }
+
         read_receipts
     }
 
@@ -157,38 +225,77 @@ impl TimelineInnerState {
         let public_read_receipt = self.user_receipt(user_id, ReceiptType::Read, room).await;
         let private_read_receipt = self.user_receipt(user_id, ReceiptType::ReadPrivate, room).await;
 
-        // If we only have one, return it.
-        let Some((pub_event_id, pub_receipt)) = &public_read_receipt else {
-    return private_read_receipt;
-};
-        let Some((priv_event_id, priv_receipt)) = &private_read_receipt else {
-    return public_read_receipt;
-};
-
-        // Compare by position in the timeline.
-        if let Some(relative_pos) =
-            compare_events_positions(pub_event_id, priv_event_id, &self.items)
-        {
-            if relative_pos == RelativePosition::After {
-                return private_read_receipt;
-            }
+        latest_read_receipt(
+            public_read_receipt.as_ref(),
+            private_read_receipt.as_ref(),
+            &self.items,
+        )
+        .map(|(_, receipt)| receipt.clone())
+    }
+}
 
-            return public_read_receipt;
+/// Get the latest of the given public and private read receipts of a user,
+/// with its type.
+fn latest_read_receipt<'a>(
+    public_read_receipt: Option<&'a (OwnedEventId, Receipt)>,
+    private_read_receipt: Option<&'a (OwnedEventId, Receipt)>,
+    timeline_items: &Vector<Arc<TimelineItem>>,
+) -> Option<(ReceiptType, &'a (OwnedEventId, Receipt))> {
+    let public = public_read_receipt.map(|receipt| (ReceiptType::Read, receipt));
+    let private = private_read_receipt.map(|receipt| (ReceiptType::ReadPrivate, receipt));
+
+    // If we only have one, return it.
+    let Some((_, (pub_event_id, pub_receipt))) = public else { return private };
+    let Some((_, (priv_event_id, priv_receipt))) = private else { return public };
+
+    // Compare by position in the timeline.
+    if let Some(relative_pos) =
+        compare_events_positions(pub_event_id, priv_event_id, timeline_items)
+    {
+        if relative_pos == RelativePosition::After {
+            return private;
         }
 
-        // Compare by timestamp.
-        if let Some((pub_ts, priv_ts)) = pub_receipt.ts.zip(priv_receipt.ts) {
-            if priv_ts > pub_ts {
-                return private_read_receipt;
-            }
+        return public;
+    }
 
-            return public_read_receipt;
+    // Compare by timestamp.
+    if let Some((pub_ts, priv_ts)) = pub_receipt.ts.zip(priv_receipt.ts) {
+        if priv_ts > pub_ts {
+            return private;
         }
 
-        // As a fallback, let's assume that a private read receipt should be more recent
-        // than a public read receipt, otherwise there's no point in the private read
-        // receipt.
-        private_read_receipt
+        return public;
+    }
+
+    // As a fallback, let's assume that a private read receipt should be more recent
+    // than a public read receipt, otherwise there's no point in the private read
+    // receipt.
+    private
+}
+
+/// Find the position of the item that shows the read receipt of the given
+/// user.
+fn find_read_receipt_pos(
+    timeline_items: &Vector<Arc<TimelineItem>>,
+    user_id: &UserId,
+) -> Option<usize> {
+    timeline_items.iter().rposition(|item| {
+        item.as_event().is_some_and(|event_item| event_item.read_receipts().contains_key(user_id))
+    })
+}
+
+/// Remove the read receipt of the given user from the item at the given
+/// position.
+fn remove_read_receipt(
+    pos: usize,
+    user_id: &UserId,
+    timeline_items: &mut ObservableVector<Arc<TimelineItem>>,
+) {
+    let Some(mut event_item) = timeline_items[pos].as_event().cloned() else { return };
+    if let Some(remote_event_item) = event_item.as_remote_mut() {
+        remote_event_item.remove_read_receipt(user_id);
+        timeline_items.set(pos, Arc::new(event_item.into()));
     }
 }
 
@@ -225,7 +332,20 @@ pub(super) fn maybe_add_implicit_read_receipt(
         timeline_items,
         users_read_receipts,
     );
-    if read_receipt_updated && !is_own_event {
+    if !read_receipt_updated {
+        return;
+    }
+
+    if is_own_event {
+        // Our own read receipt isn't shown on our own events, so it is hidden
+        // once it is older than this one.
+        if let Some(old_pos) = find_read_receipt_pos(timeline_items, &event_item.sender) {
+            if old_pos < item_pos {
+                remove_read_receipt(old_pos, &event_item.sender, timeline_items);
+            }
+        }
+    } else {
+        let receipt = ReadReceipt::new(ReceiptType::Read, &receipt);
         remote_event_item.add_read_receipt(event_item.sender.clone(), receipt);
     }
 }
@@ -0,0 +1,162 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::Arc,
+};
+
+use futures_util::{pin_mut, stream, StreamExt};
+use matrix_sdk::{room, Result, RoomMemberships};
+use ruma::{events::room::member::MembershipState, OwnedDeviceId, OwnedUserId, UserId};
+use tracing::trace;
+
+use super::{inner::TimelineInner, SecurityNotice};
+
+enum KeysUpdate {
+    Identities(Vec<OwnedUserId>),
+    Devices(BTreeMap<OwnedUserId, Vec<OwnedDeviceId>>),
+}
+
+/// Get a task that adds security notices to the timeline when the identities
+/// or the devices of the members of the room change.
+pub(super) async fn security_notices_task(
+    inner: Arc<TimelineInner>,
+) -> Result<impl Future<Output = ()>> {
+    let room = inner.room().clone();
+    let encryption = room.client().encryption();
+    let identity_updates = encryption.user_identity_updates().await?;
+    let new_devices = encryption.new_devices().await?;
+
+    Ok(async move {
+        let updates = stream::select(
+            identity_updates.map(KeysUpdate::Identities),
+            new_devices.map(KeysUpdate::Devices),
+        );
+        pin_mut!(updates);
+
+        // The identities can be reported as changed without any change to their
+        // keys, so we need to remember the keys to detect an actual change.
+        let mut master_keys = HashMap::new();
+        if let Ok(members) = room.members_no_sync(RoomMemberships::JOIN).await {
+            for member in members {
+                let master_key = master_key(&room, member.user_id()).await;
+                master_keys.insert(member.user_id().to_owned(), master_key);
+            }
+        }
+
+        while let Some(update) = updates.next().await {
+            let settings = inner.security_notice_settings().await;
+
+            match update {
+                KeysUpdate::Identities(user_ids) => {
+                    for user_id in user_ids {
+                        if !is_joined(&room, &user_id).await {
+                            continue;
+                        }
+
+                        let master_key = master_key(&room, &user_id).await;
+                        let previous = master_keys.insert(user_id.clone(), master_key.clone());
+
+                        // A user that had no identity before just set up
+                        // cross-signing, it isn't a change.
+                        let changed = matches!(
+                            (previous, master_key),
+                            (Some(Some(previous)), Some(new)) if previous != new
+                        );
+
+                        if changed && settings.identity_changes {
+                            inner
+                                .add_security_notice(SecurityNotice::IdentityChanged { user_id })
+                                .await;
+                        }
+                    }
+                }
+
+                KeysUpdate::Devices(devices) => {
+                    if !settings.unverified_devices || !is_verified(&room).await {
+                        continue;
+                    }
+
+                    let client = room.client();
+                    let own_device_id = client.device_id();
+
+                    for (user_id, device_ids) in devices {
+                        if !is_joined(&room, &user_id).await {
+                            continue;
+                        }
+
+                        for device_id in device_ids {
+                            let is_own_device = own_device_id == Some(&*device_id)
+                                && room.own_user_id() == &*user_id;
+                            if is_own_device {
+                                continue;
+                            }
+
+                            let encryption = client.encryption();
+                            let Ok(Some(device)) =
+                                encryption.get_device(&user_id, &device_id).await
+                            else {
+                                continue;
+                            };
+
+                            if !device.is_verified() {
+                                inner
+                                    .add_security_notice(SecurityNotice::UnverifiedDevice {
+                                        user_id: user_id.clone(),
+                                        device_id,
+                                    })
+                                    .await;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        trace!("The keys update streams were closed");
+    })
+}
+
+/// Get the base64-encoded master key of the given user, if they have a
+/// cross-signing identity.
+async fn master_key(room: &room::Common, user_id: &UserId) -> Option<String> {
+    let identity = room.client().encryption().get_user_identity(user_id).await.ok()??;
+    identity.master_key().get_first_key().map(|key| key.to_base64())
+}
+
+async fn is_joined(room: &room::Common, user_id: &UserId) -> bool {
+    matches!(
+        room.get_member_no_sync(user_id).await,
+        Ok(Some(member)) if *member.membership() == MembershipState::Join
+    )
+}
+
+/// Whether all the joined members of the room have a verified identity.
+async fn is_verified(room: &room::Common) -> bool {
+    let Ok(members) = room.members_no_sync(RoomMemberships::JOIN).await else {
+        return false;
+    };
+    let encryption = room.client().encryption();
+
+    for member in members {
+        match encryption.get_user_identity(member.user_id()).await {
+            Ok(Some(identity)) if identity.is_verified() => {}
+            _ => return false,
+        }
+    }
+
+    true
+}
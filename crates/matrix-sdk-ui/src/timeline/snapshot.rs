@@ -0,0 +1,411 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serializable snapshots of the timeline items.
+//!
+//! The snapshots have a schema that doesn't depend on the internal
+//! representation of the items, so they can be used to debug the state of a
+//! timeline, in golden tests, or to send the items to a UI layer running in
+//! another process. The schema is versioned with [`TIMELINE_SNAPSHOT_VERSION`],
+//! which is bumped on breaking changes.
+
+use std::collections::BTreeMap;
+
+use ruma::{
+    events::room::{member::Change, message::MessageType},
+    MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedEventId, OwnedMxcUri, OwnedTransactionId,
+    OwnedUserId,
+};
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::{
+    EncryptedMessage, EventSendState, EventTimelineItem, MembershipChange, SecurityNotice,
+    TimelineDetails, TimelineItem, TimelineItemContent, VirtualTimelineItem,
+};
+
+/// The version of the schema of the snapshots.
+pub const TIMELINE_SNAPSHOT_VERSION: u32 = 1;
+
+/// A snapshot of the items of a timeline.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimelineSnapshot {
+    /// The version of the schema of the snapshot, see
+    /// [`TIMELINE_SNAPSHOT_VERSION`].
+    pub version: u32,
+    /// The items, in the order of the timeline.
+    pub items: Vec<TimelineItemSnapshot>,
+}
+
+impl TimelineSnapshot {
+    /// Create a snapshot of the given items.
+    pub fn new<'a>(items: impl IntoIterator<Item = &'a TimelineItem>) -> Self {
+        Self {
+            version: TIMELINE_SNAPSHOT_VERSION,
+            items: items.into_iter().map(TimelineItemSnapshot::from).collect(),
+        }
+    }
+}
+
+/// A snapshot of a [`TimelineItem`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineItemSnapshot {
+    /// An event.
+    Event(EventSnapshot),
+    /// An item that doesn't correspond to an event.
+    Virtual(VirtualItemSnapshot),
+}
+
+impl From<&TimelineItem> for TimelineItemSnapshot {
+    fn from(item: &TimelineItem) -> Self {
+        match item {
+            TimelineItem::Event(event) => Self::Event(event.into()),
+            TimelineItem::Virtual(virtual_item) => Self::Virtual(virtual_item.into()),
+        }
+    }
+}
+
+/// A snapshot of an [`EventTimelineItem`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EventSnapshot {
+    /// The ID of the event, if it is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<OwnedEventId>,
+    /// The transaction ID, for the events sent by this client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<OwnedTransactionId>,
+    /// The sender of the event.
+    pub sender: OwnedUserId,
+    /// The display name of the sender, if it is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_display_name: Option<String>,
+    /// The timestamp of the event.
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+    /// Whether the event was sent by our own user.
+    pub is_own: bool,
+    /// The send state, for local echoes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_state: Option<SendStateSnapshot>,
+    /// The content of the event.
+    pub content: ContentSnapshot,
+    /// The reactions to the event, by key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, ReactionsSnapshot>,
+    /// The read receipts on the event, by user.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub read_receipts: BTreeMap<OwnedUserId, ReadReceiptSnapshot>,
+}
+
+impl From<&EventTimelineItem> for EventSnapshot {
+    fn from(item: &EventTimelineItem) -> Self {
+        let sender_display_name = match item.sender_profile() {
+            TimelineDetails::Ready(profile) => profile.display_name.clone(),
+            _ => None,
+        };
+
+        Self {
+            event_id: item.event_id().map(ToOwned::to_owned),
+            transaction_id: item.transaction_id().map(ToOwned::to_owned),
+            sender: item.sender().to_owned(),
+            sender_display_name,
+            timestamp: item.timestamp(),
+            is_own: item.is_own(),
+            send_state: item.send_state().map(Into::into),
+            content: item.content().into(),
+            reactions: item
+                .reactions()
+                .iter()
+                .map(|(key, group)| {
+                    let snapshot = ReactionsSnapshot {
+                        count: group.count(),
+                        senders: group.senders().map(ToOwned::to_owned).collect(),
+                    };
+                    (key.clone(), snapshot)
+                })
+                .collect(),
+            read_receipts: item
+                .read_receipts()
+                .iter()
+                .map(|(user_id, receipt)| {
+                    let snapshot = ReadReceiptSnapshot {
+                        receipt_type: receipt.receipt_type.to_string(),
+                        ts: receipt.ts,
+                    };
+                    (user_id.clone(), snapshot)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A snapshot of an [`EventSendState`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SendStateSnapshot {
+    /// The event was not sent yet.
+    NotSentYet,
+    /// The event couldn't be sent.
+    SendingFailed {
+        /// The description of the error.
+        error: String,
+    },
+    /// The event was sent.
+    Sent {
+        /// The ID of the event.
+        event_id: OwnedEventId,
+    },
+}
+
+impl From<&EventSendState> for SendStateSnapshot {
+    fn from(state: &EventSendState) -> Self {
+        match state {
+            EventSendState::NotSentYet => Self::NotSentYet,
+            EventSendState::SendingFailed { error } => {
+                Self::SendingFailed { error: error.to_string() }
+            }
+            EventSendState::Sent { event_id } => Self::Sent { event_id: event_id.clone() },
+        }
+    }
+}
+
+/// A snapshot of the reactions with the same key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReactionsSnapshot {
+    /// The number of reactions.
+    pub count: u64,
+    /// The senders of the reactions that are known locally.
+    pub senders: Vec<OwnedUserId>,
+}
+
+/// A snapshot of a read receipt.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReadReceiptSnapshot {
+    /// The type of the receipt, e.g. `m.read`.
+    pub receipt_type: String,
+    /// When the user read the event, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+/// A snapshot of a [`TimelineItemContent`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentSnapshot {
+    /// A message.
+    Message {
+        /// The `msgtype` of the message, e.g. `m.text`.
+        msgtype: String,
+        /// The plain text body.
+        body: String,
+        /// The HTML body, if any.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        formatted_body: Option<String>,
+        /// The ID of the event the message replies to, if any.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        in_reply_to: Option<OwnedEventId>,
+        /// Whether the message was edited.
+        edited: bool,
+    },
+    /// A redacted message.
+    RedactedMessage,
+    /// A sticker.
+    Sticker {
+        /// The description of the sticker.
+        body: String,
+    },
+    /// An event that couldn't be decrypted.
+    UnableToDecrypt {
+        /// The ID of the Megolm session of the event, if known.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+    },
+    /// A membership change.
+    MembershipChange {
+        /// The user whose membership changed.
+        user_id: OwnedUserId,
+        /// The change, if it could be computed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        change: Option<MembershipChange>,
+    },
+    /// A profile change.
+    ProfileChange {
+        /// The user whose profile changed.
+        user_id: OwnedUserId,
+        /// The change of the display name, if it changed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        display_name: Option<ChangeSnapshot<String>>,
+        /// The change of the avatar URL, if it changed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        avatar_url: Option<ChangeSnapshot<OwnedMxcUri>>,
+    },
+    /// Another state event.
+    OtherState {
+        /// The type of the event.
+        event_type: String,
+        /// The state key of the event.
+        state_key: String,
+    },
+    /// An event that couldn't be deserialized.
+    FailedToParse {
+        /// The type of the event.
+        event_type: String,
+        /// The state key, for state events.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state_key: Option<String>,
+        /// The deserialization error.
+        error: String,
+    },
+}
+
+impl From<&TimelineItemContent> for ContentSnapshot {
+    fn from(content: &TimelineItemContent) -> Self {
+        match content {
+            TimelineItemContent::Message(message) => {
+                let formatted_body = match message.msgtype() {
+                    MessageType::Text(c) => c.formatted.as_ref(),
+                    MessageType::Notice(c) => c.formatted.as_ref(),
+                    MessageType::Emote(c) => c.formatted.as_ref(),
+                    _ => None,
+                };
+
+                Self::Message {
+                    msgtype: message.msgtype().msgtype().to_owned(),
+                    body: message.body().to_owned(),
+                    formatted_body: formatted_body.map(|f| f.body.clone()),
+                    in_reply_to: message.in_reply_to().map(|r| r.event_id.clone()),
+                    edited: message.is_edited(),
+                }
+            }
+            TimelineItemContent::RedactedMessage => Self::RedactedMessage,
+            TimelineItemContent::Sticker(sticker) => {
+                Self::Sticker { body: sticker.content().body.clone() }
+            }
+            TimelineItemContent::UnableToDecrypt(encrypted) => {
+                let session_id = match encrypted {
+                    EncryptedMessage::MegolmV1AesSha2 { session_id, .. } => {
+                        Some(session_id.clone())
+                    }
+                    _ => None,
+                };
+                Self::UnableToDecrypt { session_id }
+            }
+            TimelineItemContent::MembershipChange(change) => Self::MembershipChange {
+                user_id: change.user_id().to_owned(),
+                change: change.change(),
+            },
+            TimelineItemContent::ProfileChange(change) => Self::ProfileChange {
+                user_id: change.user_id().to_owned(),
+                display_name: change.displayname_change().map(ChangeSnapshot::new),
+                avatar_url: change.avatar_url_change().map(ChangeSnapshot::new),
+            },
+            TimelineItemContent::OtherState(state) => Self::OtherState {
+                event_type: state.content().event_type().to_string(),
+                state_key: state.state_key().to_owned(),
+            },
+            TimelineItemContent::FailedToParseMessageLike { event_type, error } => {
+                Self::FailedToParse {
+                    event_type: event_type.to_string(),
+                    state_key: None,
+                    error: error.to_string(),
+                }
+            }
+            TimelineItemContent::FailedToParseState { event_type, state_key, error } => {
+                Self::FailedToParse {
+                    event_type: event_type.to_string(),
+                    state_key: Some(state_key.clone()),
+                    error: error.to_string(),
+                }
+            }
+        }
+    }
+}
+
+/// A snapshot of the change of a field of a profile.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChangeSnapshot<T> {
+    /// The previous value, if it was set.
+    pub old: Option<T>,
+    /// The new value, if it is set.
+    pub new: Option<T>,
+}
+
+impl<T: Clone> ChangeSnapshot<T> {
+    fn new(change: &Change<Option<T>>) -> Self {
+        Self { old: change.old.clone(), new: change.new.clone() }
+    }
+}
+
+/// A snapshot of a [`VirtualTimelineItem`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VirtualItemSnapshot {
+    /// A divider between messages of two days.
+    DayDivider {
+        /// A timestamp on the day.
+        ts: MilliSecondsSinceUnixEpoch,
+    },
+    /// The user's own read marker.
+    ReadMarker,
+    /// A loading indicator for a pagination request.
+    LoadingIndicator,
+    /// The beginning of the visible timeline.
+    TimelineStart,
+    /// End-to-end encryption was enabled in the room.
+    EncryptionEnabled,
+    /// A member added a device that isn't verified.
+    UnverifiedDevice {
+        /// The owner of the device.
+        user_id: OwnedUserId,
+        /// The ID of the device.
+        device_id: OwnedDeviceId,
+    },
+    /// The cross-signing identity of a member changed.
+    IdentityChanged {
+        /// The member whose identity changed.
+        user_id: OwnedUserId,
+    },
+}
+
+impl From<&VirtualTimelineItem> for VirtualItemSnapshot {
+    fn from(item: &VirtualTimelineItem) -> Self {
+        match item {
+            VirtualTimelineItem::DayDivider(ts) => Self::DayDivider { ts: *ts },
+            VirtualTimelineItem::ReadMarker => Self::ReadMarker,
+            VirtualTimelineItem::LoadingIndicator => Self::LoadingIndicator,
+            VirtualTimelineItem::TimelineStart => Self::TimelineStart,
+            VirtualTimelineItem::SecurityNotice { notice, .. } => match notice {
+                SecurityNotice::EncryptionEnabled => Self::EncryptionEnabled,
+                SecurityNotice::UnverifiedDevice { user_id, device_id } => Self::UnverifiedDevice {
+                    user_id: user_id.clone(),
+                    device_id: device_id.clone(),
+                },
+                SecurityNotice::IdentityChanged { user_id } => {
+                    Self::IdentityChanged { user_id: user_id.clone() }
+                }
+            },
+        }
+    }
+}
+
+impl Serialize for TimelineItem {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TimelineItemSnapshot::from(self).serialize(serializer)
+    }
+}
+
+impl Serialize for TimelineItemContent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ContentSnapshot::from(self).serialize(serializer)
+    }
+}
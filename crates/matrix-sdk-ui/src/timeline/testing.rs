@@ -0,0 +1,493 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A harness to test the timeline of an application without a homeserver.
+//!
+//! The [`TimelineHarness`] processes events that are scripted by the test, the
+//! same way a [`Timeline`](super::Timeline) processes the events received from
+//! the homeserver. The resulting items and their updates can be inspected to
+//! cover edits, reactions, redactions, read receipts or local echoes in the
+//! UI tests of an application.
+//!
+//! ```
+//! # async {
+//! use eyeball_im::VectorDiff;
+//! use matrix_sdk_ui::timeline::testing::TimelineHarness;
+//! use ruma::{events::room::message::RoomMessageEventContent, user_id};
+//!
+//! let bob = user_id!("@bob:example.org");
+//! let harness = TimelineHarness::builder().build();
+//! let mut diffs = harness.subscribe().await;
+//!
+//! let event_id =
+//!     harness.push_live_message(bob, RoomMessageEventContent::text_plain("Hello")).await;
+//! harness.push_reaction(bob, &event_id, "👍").await;
+//!
+//! // A day divider, the message, then the update of the message for the reaction.
+//! let diffs = diffs.ready_diffs();
+//! assert_eq!(diffs.len(), 3);
+//! assert!(matches!(diffs[2], VectorDiff::Set { index: 1, .. }));
+//! # };
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use eyeball_im::{VectorDiff, VectorSubscriber};
+use futures_util::{FutureExt, StreamExt};
+use imbl::Vector;
+use indexmap::IndexMap;
+use matrix_sdk::deserialized_responses::{SyncTimelineEvent, TimelineEvent};
+use ruma::{
+    assign,
+    events::{
+        reaction::ReactionEventContent,
+        receipt::{Receipt, ReceiptEventContent, ReceiptThread, ReceiptType},
+        relation::{Annotation, Replacement},
+        room::message::{self, MessageType, RoomMessageEventContent},
+        AnyMessageLikeEventContent, MessageLikeEventContent,
+    },
+    int,
+    power_levels::NotificationPowerLevels,
+    push::{PushConditionRoomCtx, Ruleset},
+    room_id,
+    serde::Raw,
+    uint, user_id, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId,
+    OwnedTransactionId, OwnedUserId, TransactionId, UserId,
+};
+use serde_json::{json, Value as JsonValue};
+
+use super::{
+    event_item::ItemMetadata,
+    traits::{apply_item_metadata_update, RoomDataProvider},
+    EventSendState, EventTimelineItem, Profile, TimelineInner, TimelineItem,
+};
+
+/// The virtual time at which a [`TimelineHarness`] starts by default,
+/// 2023-01-01T00:00:00Z.
+const DEFAULT_START_TIME: u64 = 1_672_531_200_000;
+
+/// A builder for a [`TimelineHarness`].
+#[derive(Debug)]
+pub struct TimelineHarnessBuilder {
+    own_user_id: OwnedUserId,
+    room_id: OwnedRoomId,
+    profiles: HashMap<OwnedUserId, Profile>,
+    track_read_receipts: bool,
+    start_time: MilliSecondsSinceUnixEpoch,
+}
+
+impl TimelineHarnessBuilder {
+    fn new() -> Self {
+        Self {
+            own_user_id: user_id!("@alice:example.org").to_owned(),
+            room_id: room_id!("!room:example.org").to_owned(),
+            profiles: HashMap::new(),
+            track_read_receipts: false,
+            start_time: MilliSecondsSinceUnixEpoch(
+                DEFAULT_START_TIME.try_into().expect("default start time should fit in a UInt"),
+            ),
+        }
+    }
+
+    /// Set the ID of the user of the timeline, defaults to
+    /// `@alice:example.org`.
+    pub fn own_user_id(mut self, user_id: &UserId) -> Self {
+        self.own_user_id = user_id.to_owned();
+        self
+    }
+
+    /// Set the ID of the room of the timeline, defaults to
+    /// `!room:example.org`.
+    pub fn room_id(mut self, room_id: OwnedRoomId) -> Self {
+        self.room_id = room_id;
+        self
+    }
+
+    /// Set the profile of a member of the room.
+    ///
+    /// The senders without a profile are considered to have no display name
+    /// and no avatar.
+    pub fn profile(mut self, user_id: &UserId, profile: Profile) -> Self {
+        self.profiles.insert(user_id.to_owned(), profile);
+        self
+    }
+
+    /// Add read receipts to the timeline items, like the timelines created
+    /// with [`RoomExt::timeline()`](super::RoomExt::timeline) do.
+    pub fn track_read_receipts(mut self) -> Self {
+        self.track_read_receipts = true;
+        self
+    }
+
+    /// Set the virtual time at which the harness starts, defaults to
+    /// 2023-01-01T00:00:00Z.
+    pub fn start_time(mut self, time: MilliSecondsSinceUnixEpoch) -> Self {
+        self.start_time = time;
+        self
+    }
+
+    /// Create the [`TimelineHarness`].
+    pub fn build(self) -> TimelineHarness {
+        let provider = ScriptedRoomDataProvider {
+            own_user_id: self.own_user_id.clone(),
+            room_id: self.room_id,
+            profiles: self.profiles,
+            item_metadata: Default::default(),
+        };
+
+        TimelineHarness {
+            own_user_id: self.own_user_id,
+            inner: TimelineInner::new(provider)
+                .with_read_receipt_tracking(self.track_read_receipts),
+            now: AtomicU64::new(self.start_time.get().into()),
+            next_event_number: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A timeline fed with scripted events, see the [module-level
+/// documentation](self).
+///
+/// Every scripted event gets the current virtual time as its
+/// `origin_server_ts`, the virtual time only changes with
+/// [`advance_time()`](Self::advance_time). Local echoes use the real time, like
+/// in a real timeline.
+#[derive(Debug)]
+pub struct TimelineHarness {
+    own_user_id: OwnedUserId,
+    inner: TimelineInner<ScriptedRoomDataProvider>,
+    now: AtomicU64,
+    next_event_number: AtomicU64,
+}
+
+impl TimelineHarness {
+    /// Create a new [`TimelineHarnessBuilder`].
+    pub fn builder() -> TimelineHarnessBuilder {
+        TimelineHarnessBuilder::new()
+    }
+
+    /// The ID of the user of the timeline.
+    pub fn own_user_id(&self) -> &UserId {
+        &self.own_user_id
+    }
+
+    /// Get the current virtual time.
+    pub fn now(&self) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch(
+            self.now.load(Ordering::SeqCst).try_into().expect("virtual time should fit in a UInt"),
+        )
+    }
+
+    /// Move the virtual time forward by the given duration.
+    pub fn advance_time(&self, duration: Duration) {
+        let millis = duration.as_millis().try_into().expect("duration should fit in a u64");
+        self.now.fetch_add(millis, Ordering::SeqCst);
+    }
+
+    /// Get the current items of the timeline.
+    pub async fn items(&self) -> Vector<Arc<TimelineItem>> {
+        self.inner.items().await
+    }
+
+    /// Get the current event items of the timeline.
+    pub async fn event_items(&self) -> Vec<EventTimelineItem> {
+        self.inner.items().await.iter().filter_map(|item| item.as_event().cloned()).collect()
+    }
+
+    /// Subscribe to the updates of the timeline items.
+    ///
+    /// Only the updates that happen after this call are recorded, the current
+    /// items can be retrieved with [`items()`](Self::items).
+    pub async fn subscribe(&self) -> TimelineDiffs {
+        let (_, subscriber) = self.inner.subscribe().await;
+        TimelineDiffs { subscriber }
+    }
+
+    /// Generate a new, unique event ID.
+    fn next_event_id(&self) -> OwnedEventId {
+        let number = self.next_event_number.fetch_add(1, Ordering::SeqCst);
+        EventId::parse(format!("$event{number}:example.org")).expect("event ID should be valid")
+    }
+
+    /// Process an event received from the sync, given as JSON.
+    ///
+    /// The `event_id` and `origin_server_ts` fields are added if they are
+    /// missing.
+    ///
+    /// Returns the ID of the event.
+    pub async fn push_live_json(&self, mut event: JsonValue) -> OwnedEventId {
+        let event_id = self.complete_event_json(&mut event);
+        let event = SyncTimelineEvent {
+            event: Raw::new(&event).expect("event should serialize").cast(),
+            encryption_info: None,
+            push_actions: Vec::new(),
+        };
+
+        self.inner.handle_live_event(event).await;
+        event_id
+    }
+
+    /// Process an event received from back-pagination, given as JSON.
+    ///
+    /// The `event_id` and `origin_server_ts` fields are added if they are
+    /// missing.
+    ///
+    /// Returns the ID of the event.
+    pub async fn push_back_paginated_json(&self, mut event: JsonValue) -> OwnedEventId {
+        let event_id = self.complete_event_json(&mut event);
+        let event = TimelineEvent::new(Raw::new(&event).expect("event should serialize").cast());
+
+        self.inner.handle_back_paginated_event(event).await;
+        event_id
+    }
+
+    /// Process a message-like event received from the sync.
+    ///
+    /// Returns the ID of the event.
+    pub async fn push_live_message<C>(&self, sender: &UserId, content: C) -> OwnedEventId
+    where
+        C: MessageLikeEventContent,
+    {
+        self.push_live_json(json!({
+            "type": content.event_type(),
+            "content": content,
+            "sender": sender,
+        }))
+        .await
+    }
+
+    /// Process an edit of the text message with the given ID, received from the
+    /// sync.
+    ///
+    /// Returns the ID of the edit event.
+    pub async fn push_edit(
+        &self,
+        sender: &UserId,
+        original_event_id: &EventId,
+        new_body: &str,
+    ) -> OwnedEventId {
+        let content = assign!(RoomMessageEventContent::text_plain(format!(" * {new_body}")), {
+            relates_to: Some(message::Relation::Replacement(Replacement::new(
+                original_event_id.to_owned(),
+                MessageType::text_plain(new_body),
+            ))),
+        });
+
+        self.push_live_message(sender, content).await
+    }
+
+    /// Process a reaction to the event with the given ID, received from the
+    /// sync.
+    ///
+    /// Returns the ID of the reaction event.
+    pub async fn push_reaction(
+        &self,
+        sender: &UserId,
+        event_id: &EventId,
+        key: &str,
+    ) -> OwnedEventId {
+        let content =
+            ReactionEventContent::new(Annotation::new(event_id.to_owned(), key.to_owned()));
+        self.push_live_message(sender, content).await
+    }
+
+    /// Process a redaction of the event with the given ID, received from the
+    /// sync.
+    ///
+    /// Returns the ID of the redaction event.
+    pub async fn push_redaction(&self, sender: &UserId, redacts: &EventId) -> OwnedEventId {
+        self.push_live_json(json!({
+            "type": "m.room.redaction",
+            "content": {},
+            "redacts": redacts,
+            "sender": sender,
+        }))
+        .await
+    }
+
+    /// Process a public read receipt of the given user on the event with the
+    /// given ID, received from the sync.
+    pub async fn push_read_receipt(&self, user_id: &UserId, event_id: &EventId) {
+        let mut receipt = Receipt::new(self.now());
+        receipt.thread = ReceiptThread::Unthreaded;
+
+        let content = ReceiptEventContent(BTreeMap::from([(
+            event_id.to_owned(),
+            BTreeMap::from([(ReceiptType::Read, BTreeMap::from([(user_id.to_owned(), receipt)]))]),
+        )]));
+
+        self.inner.handle_read_receipts(content).await;
+    }
+
+    /// Add a local echo for an event that is being sent by the user of the
+    /// timeline.
+    ///
+    /// Returns the transaction ID of the local echo, to be used with
+    /// [`set_local_echo_send_state()`](Self::set_local_echo_send_state).
+    pub async fn push_local_echo(
+        &self,
+        content: impl Into<AnyMessageLikeEventContent>,
+    ) -> OwnedTransactionId {
+        let txn_id = TransactionId::new();
+        self.inner.handle_local_event(txn_id.clone(), content.into()).await;
+        txn_id
+    }
+
+    /// Update the send state of the local echo with the given transaction ID.
+    ///
+    /// Use [`mark_local_echo_sent()`](Self::mark_local_echo_sent) to simulate a
+    /// successful sending.
+    pub async fn set_local_echo_send_state(&self, txn_id: &TransactionId, state: EventSendState) {
+        self.inner.update_event_send_state(txn_id, state).await;
+    }
+
+    /// Mark the local echo with the given transaction ID as sent.
+    ///
+    /// Returns the event ID given to the event by the simulated server. Note
+    /// that the remote echo of the event still needs to be pushed to replace
+    /// the local echo, like in a real timeline.
+    pub async fn mark_local_echo_sent(&self, txn_id: &TransactionId) -> OwnedEventId {
+        let event_id = self.next_event_id();
+        self.set_local_echo_send_state(txn_id, EventSendState::Sent { event_id: event_id.clone() })
+            .await;
+        event_id
+    }
+
+    /// Add the `event_id` and `origin_server_ts` fields to the given event if
+    /// they are missing, and return its event ID.
+    fn complete_event_json(&self, event: &mut JsonValue) -> OwnedEventId {
+        let object = event.as_object_mut().expect("event should be a JSON object");
+
+        object.entry("origin_server_ts").or_insert_with(|| json!(self.now()));
+
+        let event_id = object
+            .entry("event_id")
+            .or_insert_with(|| json!(self.next_event_id()))
+            .as_str()
+            .expect("event_id should be a string");
+
+        EventId::parse(event_id).expect("event_id should be valid")
+    }
+}
+
+/// The updates of the items of a [`TimelineHarness`], see
+/// [`TimelineHarness::subscribe()`].
+#[derive(Debug)]
+pub struct TimelineDiffs {
+    subscriber: VectorSubscriber<Arc<TimelineItem>>,
+}
+
+impl TimelineDiffs {
+    /// Get the next update, if one is ready.
+    ///
+    /// Since the harness processes the scripted events immediately, all the
+    /// updates caused by an event are ready as soon as the method that pushed
+    /// it returns.
+    pub fn next_diff(&mut self) -> Option<VectorDiff<Arc<TimelineItem>>> {
+        self.subscriber.next().now_or_never().flatten()
+    }
+
+    /// Get all the updates that are ready.
+    pub fn ready_diffs(&mut self) -> Vec<VectorDiff<Arc<TimelineItem>>> {
+        std::iter::from_fn(|| self.next_diff()).collect()
+    }
+
+    /// Assert that there are no pending updates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an update is ready.
+    #[track_caller]
+    pub fn assert_empty(&mut self) {
+        if let Some(diff) = self.next_diff() {
+            panic!("expected no timeline update, got {diff:?}");
+        }
+    }
+}
+
+/// The [`RoomDataProvider`] of a [`TimelineHarness`].
+#[derive(Debug)]
+struct ScriptedRoomDataProvider {
+    own_user_id: OwnedUserId,
+    room_id: OwnedRoomId,
+    profiles: HashMap<OwnedUserId, Profile>,
+    item_metadata: StdMutex<HashMap<OwnedEventId, ItemMetadata>>,
+}
+
+#[async_trait]
+impl RoomDataProvider for ScriptedRoomDataProvider {
+    fn own_user_id(&self) -> &UserId {
+        &self.own_user_id
+    }
+
+    async fn profile(&self, user_id: &UserId) -> Option<Profile> {
+        Some(self.profiles.get(user_id).cloned().unwrap_or(Profile {
+            display_name: None,
+            display_name_ambiguous: false,
+            avatar_url: None,
+        }))
+    }
+
+    async fn read_receipts_for_event(&self, _event_id: &EventId) -> IndexMap<OwnedUserId, Receipt> {
+        IndexMap::new()
+    }
+
+    async fn push_rules_and_context(&self) -> Option<(Ruleset, PushConditionRoomCtx)> {
+        let push_rules = Ruleset::server_default(&self.own_user_id);
+        let push_context = PushConditionRoomCtx {
+            room_id: self.room_id.clone(),
+            member_count: uint!(2),
+            user_id: self.own_user_id.clone(),
+            user_display_name: self
+                .profiles
+                .get(&self.own_user_id)
+                .and_then(|p| p.display_name.clone())
+                .unwrap_or_else(|| self.own_user_id.localpart().to_owned()),
+            users_power_levels: BTreeMap::new(),
+            default_power_level: int!(0),
+            notification_power_levels: NotificationPowerLevels::new(),
+        };
+
+        Some((push_rules, push_context))
+    }
+
+    async fn load_item_metadata(&self) -> HashMap<OwnedEventId, ItemMetadata> {
+        self.item_metadata.lock().unwrap().clone()
+    }
+
+    async fn update_item_metadata(
+        &self,
+        event_id: &EventId,
+        key: &str,
+        value: Option<JsonValue>,
+    ) -> matrix_sdk::Result<HashMap<OwnedEventId, ItemMetadata>> {
+        let mut item_metadata = self.item_metadata.lock().unwrap();
+        apply_item_metadata_update(&mut item_metadata, event_id, key, value);
+        Ok(item_metadata.clone())
+    }
+
+    async fn sent_event_id(&self, _txn_id: &TransactionId) -> Option<OwnedEventId> {
+        None
+    }
+
+    async fn forget_sent_event(&self, _txn_id: &TransactionId) {}
+}
@@ -327,3 +327,34 @@ async fn reply() {
     let replied_to_event = assert_matches!(&in_reply_to.event, TimelineDetails::Ready(msg) => msg);
     assert_eq!(replied_to_event.sender(), *ALICE);
 }
+
+#[async_test]
+async fn remove_events_of_user() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("hi")).await;
+    timeline.handle_live_message_event(&BOB, RoomMessageEventContent::text_plain("spam")).await;
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("bye")).await;
+
+    let day_divider = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert!(day_divider.is_day_divider());
+    for _ in 0..3 {
+        assert_next_matches!(stream, VectorDiff::PushBack { .. });
+    }
+
+    let removed = timeline.inner.remove_events_of_user(&BOB).await;
+    assert_eq!(removed, 1);
+    assert_next_matches!(stream, VectorDiff::Remove { index: 2 });
+
+    // The day divider is removed with the last events.
+    let removed = timeline.inner.remove_events_of_user(&ALICE).await;
+    assert_eq!(removed, 2);
+    assert_next_matches!(stream, VectorDiff::Remove { index: 2 });
+    assert_next_matches!(stream, VectorDiff::Remove { index: 1 });
+    assert_next_matches!(stream, VectorDiff::Remove { index: 0 });
+    assert!(timeline.inner.items().await.is_empty());
+
+    let removed = timeline.inner.remove_events_of_user(&BOB).await;
+    assert_eq!(removed, 0);
+}
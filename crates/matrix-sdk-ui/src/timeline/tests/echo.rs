@@ -26,7 +26,7 @@ use serde_json::json;
 use stream_assert::assert_next_matches;
 
 use super::{TestTimeline, ALICE, BOB};
-use crate::timeline::event_item::EventSendState;
+use crate::timeline::{event_item::EventSendState, SendReceipt, TimelineItemId};
 
 #[async_test]
 async fn remote_echo_full_trip() {
@@ -47,6 +47,7 @@ async fn remote_echo_full_trip() {
         let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
         let event = item.as_event().unwrap();
         assert_matches!(event.send_state(), Some(EventSendState::NotSentYet));
+        assert_eq!(event.send_receipt(), None);
     }
 
     // Scenario 2: The local event has not been sent to the server successfully, it
@@ -64,6 +65,7 @@ async fn remote_echo_full_trip() {
         let item = assert_next_matches!(stream, VectorDiff::Set { value, index: 1 } => value);
         let event = item.as_event().unwrap();
         assert_matches!(event.send_state(), Some(EventSendState::SendingFailed { .. }));
+        assert_eq!(event.send_receipt(), None);
     }
 
     // Scenario 3: The local event has been sent successfully to the server and an
@@ -81,6 +83,7 @@ async fn remote_echo_full_trip() {
         let item = assert_next_matches!(stream, VectorDiff::Set { value, index: 1 } => value);
         let event_item = item.as_event().unwrap();
         assert_matches!(event_item.send_state(), Some(EventSendState::Sent { .. }));
+        assert_eq!(event_item.send_receipt(), Some(SendReceipt::Sent));
 
         event_item.timestamp()
     };
@@ -103,6 +106,8 @@ async fn remote_echo_full_trip() {
     // The local echo is replaced with the remote echo
     let item = assert_next_matches!(stream, VectorDiff::Set { index: 1, value } => value);
     assert!(!item.as_event().unwrap().is_local_echo());
+    assert_eq!(item.as_event().unwrap().send_receipt(), Some(SendReceipt::Delivered));
+    assert_eq!(item.unique_id(), TimelineItemId::Event(txn_id.to_string()));
 }
 
 #[async_test]
@@ -153,5 +158,56 @@ async fn remote_echo_new_position() {
     // … and the remote echo added (no new day divider because both bob's and
     // alice's message are from the same day according to server timestamps)
     let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
-    assert!(!item.as_event().unwrap().is_local_echo());
+    let event_item = item.as_event().unwrap();
+    assert!(!event_item.is_local_echo());
+    // The transaction ID sent by the server is kept.
+    assert_eq!(event_item.transaction_id(), Some(&*txn_id));
+}
+
+#[async_test]
+async fn remote_echo_of_event_sent_by_other_process() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+
+    // Given a local event…
+    let txn_id = timeline
+        .handle_local_event(AnyMessageLikeEventContent::RoomMessage(
+            RoomMessageEventContent::text_plain("echo"),
+        ))
+        .await;
+
+    let _day_divider = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let timestamp = item.as_event().unwrap().timestamp();
+
+    // … that was sent by another process, which saved its event ID in the
+    // store…
+    let event_id = event_id!("$W6mZSLWMmfuQQ9jhZWeTxFIM");
+    timeline.sent_events.lock().unwrap().insert(txn_id.clone(), event_id.to_owned());
+
+    // … when the remote echo comes in without a transaction ID…
+    timeline
+        .handle_live_custom_event(json!({
+            "content": {
+                "body": "echo",
+                "msgtype": "m.text",
+            },
+            "sender": &*ALICE,
+            "event_id": event_id,
+            "origin_server_ts": timestamp,
+            "type": "m.room.message",
+        }))
+        .await;
+
+    // … the local echo is replaced with the remote echo, instead of being
+    // duplicated.
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 1, value } => value);
+    let event_item = item.as_event().unwrap();
+    assert!(!event_item.is_local_echo());
+    assert_eq!(event_item.event_id(), Some(event_id));
+    assert_eq!(event_item.transaction_id(), Some(&*txn_id));
+    assert_eq!(timeline.inner.items().await.len(), 2);
+
+    // The ID of the sent event isn't needed anymore.
+    assert!(timeline.sent_events.lock().unwrap().is_empty());
 }
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::Arc;
+
 use assert_matches::assert_matches;
 use eyeball_im::VectorDiff;
 use matrix_sdk_test::async_test;
@@ -26,7 +28,7 @@ use ruma::{
     serde::Raw,
     server_name, EventId,
 };
-use serde_json::json;
+use serde_json::{json, Value as JsonValue};
 use stream_assert::assert_next_matches;
 
 use super::{TestTimeline, ALICE};
@@ -160,3 +162,39 @@ async fn aggregated_sanitized() {
     assert_eq!(text.body, "!!edited!! **better** message");
     assert_eq!(text.formatted.as_ref().unwrap().body, " <strong>better</strong> message");
 }
+
+#[async_test]
+async fn edit_shares_original_json() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe_events().await;
+
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("hi")).await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let original_json = item.shared_original_json().unwrap();
+    assert!(item.shared_latest_edit_json().is_none());
+
+    let edit = assign!(RoomMessageEventContent::text_plain(" * hello"), {
+        relates_to: Some(message::Relation::Replacement(Replacement::new(
+            item.event_id().unwrap().to_owned(),
+            MessageType::text_plain("hello"),
+        ))),
+    });
+    timeline.handle_live_message_event(&ALICE, edit).await;
+
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    // The JSON of the original event isn't copied when the item is updated.
+    assert!(Arc::ptr_eq(&item.shared_original_json().unwrap(), &original_json));
+    let edit_json = item.shared_latest_edit_json().unwrap();
+    drop(item);
+
+    // The shared JSON outlives the item, and still holds the original event
+    // and the edit.
+    let original_content = original_json.get_field::<JsonValue>("content").unwrap().unwrap();
+    assert_eq!(original_content["body"], "hi");
+    let edit_content = edit_json.get_field::<JsonValue>("content").unwrap().unwrap();
+    assert_eq!(edit_content["m.new_content"]["body"], "hello");
+    assert_ne!(
+        edit_json.get_field::<String>("event_id").unwrap().unwrap(),
+        original_json.get_field::<String>("event_id").unwrap().unwrap()
+    );
+}
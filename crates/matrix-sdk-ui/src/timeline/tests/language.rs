@@ -0,0 +1,93 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use eyeball_im::VectorDiff;
+use matrix_sdk_test::async_test;
+use ruma::{
+    assign,
+    events::{
+        relation::Replacement,
+        room::message::{self, MessageType, RoomMessageEventContent},
+    },
+};
+use stream_assert::{assert_next_matches, assert_pending};
+
+use super::{TestTimeline, ALICE};
+use crate::timeline::{LanguageDetector, TextDirection};
+
+/// Detects Arabic when the text contains Arabic letters, and English
+/// otherwise.
+#[derive(Debug)]
+struct TestDetector;
+
+impl LanguageDetector for TestDetector {
+    fn detect(&self, text: &str) -> Option<String> {
+        let is_arabic = text.chars().any(|c| ('\u{0600}'..='\u{06FF}').contains(&c));
+        Some(if is_arabic { "ar" } else { "en" }.to_owned())
+    }
+}
+
+#[async_test]
+async fn language_detection() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe_events().await;
+
+    // Without a detector, the language is unknown.
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("hi!")).await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let event_id = item.event_id().unwrap().to_owned();
+    assert_eq!(item.content().as_message().unwrap().language(), None);
+
+    // The language of the existing messages is detected when the detector is
+    // set.
+    timeline.inner.set_language_detector(Some(Arc::new(TestDetector))).await;
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    let message = item.content().as_message().unwrap();
+    assert_eq!(message.language(), Some("en"));
+    assert_eq!(message.text_direction(), Some(TextDirection::LeftToRight));
+
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("مرحبا")).await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let message = item.content().as_message().unwrap();
+    assert_eq!(message.language(), Some("ar"));
+    assert_eq!(message.text_direction(), Some(TextDirection::RightToLeft));
+
+    // The language is detected again when the message is edited.
+    let edit = assign!(RoomMessageEventContent::text_plain(" * مرحبا"), {
+        relates_to: Some(message::Relation::Replacement(Replacement::new(
+            event_id,
+            MessageType::text_plain("مرحبا"),
+        ))),
+    });
+    timeline.handle_live_message_event(&ALICE, edit).await;
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    assert_eq!(item.content().as_message().unwrap().language(), Some("ar"));
+
+    // Only the text messages have a language.
+    timeline
+        .handle_live_message_event(
+            &ALICE,
+            RoomMessageEventContent::new(
+                MessageType::new("org.example.custom", "hi".to_owned(), Default::default())
+                    .unwrap(),
+            ),
+        )
+        .await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert_eq!(item.content().as_message().unwrap().language(), None);
+
+    assert_pending!(stream);
+}
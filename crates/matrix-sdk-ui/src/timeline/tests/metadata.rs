@@ -0,0 +1,93 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use assert_matches::assert_matches;
+use eyeball_im::VectorDiff;
+use matrix_sdk_test::async_test;
+use ruma::{events::room::message::RoomMessageEventContent, EventId};
+use serde_json::json;
+use stream_assert::{assert_next_matches, assert_pending};
+
+use super::{TestTimeline, ALICE};
+
+#[async_test]
+async fn set_and_remove_metadata() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe_events().await;
+
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("hi!")).await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert_eq!(item.metadata("flagged"), None);
+    let event_id = item.event_id().unwrap();
+
+    timeline.inner.set_item_metadata(event_id, "flagged", Some(json!(true))).await.unwrap();
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    assert_eq!(item.metadata("flagged"), Some(&json!(true)));
+    assert_matches!(item.deserialize_metadata::<bool>("flagged"), Some(Ok(true)));
+
+    timeline.inner.set_item_metadata(event_id, "flagged", None).await.unwrap();
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    assert_eq!(item.metadata("flagged"), None);
+
+    // Removing metadata that isn't there doesn't update the item.
+    timeline.inner.set_item_metadata(event_id, "flagged", None).await.unwrap();
+    assert_pending!(stream);
+}
+
+#[async_test]
+async fn metadata_is_attached_to_rebuilt_items() {
+    let timeline = TestTimeline::new();
+    let event = timeline.make_message_event(&ALICE, RoomMessageEventContent::text_plain("hi!"));
+    let event_id = EventId::parse(event["event_id"].as_str().unwrap()).unwrap();
+
+    // Metadata can be set before the event is in the timeline.
+    timeline.inner.set_item_metadata(&event_id, "note", Some(json!("spam?"))).await.unwrap();
+
+    timeline.handle_live_custom_event(event.clone()).await;
+    let items = timeline.inner.items().await;
+    assert_eq!(items[1].as_event().unwrap().metadata("note"), Some(&json!("spam?")));
+
+    timeline.inner.clear().await;
+    timeline.handle_live_custom_event(event).await;
+    let items = timeline.inner.items().await;
+    assert_eq!(items[1].as_event().unwrap().metadata("note"), Some(&json!("spam?")));
+}
+
+#[async_test]
+async fn metadata_set_by_other_timelines_is_kept() {
+    let timeline = TestTimeline::new();
+    let other_timeline = timeline.sharing_store();
+    let first_event_id = EventId::parse("$first:server.name").unwrap();
+    let second_event_id = EventId::parse("$second:server.name").unwrap();
+
+    // Both timelines start without metadata, and set some on different events
+    // and under different keys of the same event.
+    timeline.inner.set_item_metadata(&first_event_id, "flagged", Some(json!(true))).await.unwrap();
+    other_timeline
+        .inner
+        .set_item_metadata(&second_event_id, "note", Some(json!("spam?")))
+        .await
+        .unwrap();
+    other_timeline
+        .inner
+        .set_item_metadata(&first_event_id, "note", Some(json!("read later")))
+        .await
+        .unwrap();
+
+    let stored = timeline.item_metadata.lock().unwrap().clone();
+    assert_eq!(stored.len(), 2);
+    assert_eq!(stored[&first_event_id].get("flagged"), Some(&json!(true)));
+    assert_eq!(stored[&first_event_id].get("note"), Some(&json!("read later")));
+    assert_eq!(stored[&second_event_id].get("note"), Some(&json!("spam?")));
+}
@@ -15,10 +15,10 @@
 //! Unit tests (based on private methods) for the timeline API.
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
     sync::{
         atomic::{AtomicU64, Ordering::SeqCst},
-        Arc,
+        Arc, Mutex as StdMutex,
     },
 };
 
@@ -45,7 +45,11 @@ use ruma::{
 };
 use serde_json::{json, Value as JsonValue};
 
-use super::{traits::RoomDataProvider, EventTimelineItem, Profile, TimelineInner, TimelineItem};
+use super::{
+    event_item::ItemMetadata,
+    traits::{apply_item_metadata_update, RoomDataProvider},
+    EventTimelineItem, Profile, TimelineInner, TimelineItem,
+};
 
 mod basic;
 mod echo;
@@ -53,9 +57,15 @@ mod edit;
 #[cfg(feature = "e2e-encryption")]
 mod encryption;
 mod invalid;
+mod language;
+mod metadata;
+mod ordering;
+mod reactions;
 mod read_receipts;
 mod redaction;
+mod snapshot;
 mod virt;
+mod window;
 
 static ALICE: Lazy<&UserId> = Lazy::new(|| user_id!("@alice:server.name"));
 static BOB: Lazy<&UserId> = Lazy::new(|| user_id!("@bob:other.server"));
@@ -63,11 +73,34 @@ static BOB: Lazy<&UserId> = Lazy::new(|| user_id!("@bob:other.server"));
 struct TestTimeline {
     inner: TimelineInner<TestRoomDataProvider>,
     next_ts: AtomicU64,
+    /// The events sent by other processes, shared with the room data provider.
+    sent_events: SentEvents,
+    /// The stored timeline item metadata, shared with the room data provider.
+    item_metadata: StoredItemMetadata,
 }
 
 impl TestTimeline {
     fn new() -> Self {
-        Self { inner: TimelineInner::new(TestRoomDataProvider), next_ts: AtomicU64::new(0) }
+        Self::with_store(SentEvents::default(), StoredItemMetadata::default())
+    }
+
+    fn with_store(sent_events: SentEvents, item_metadata: StoredItemMetadata) -> Self {
+        let provider = TestRoomDataProvider {
+            sent_events: sent_events.clone(),
+            item_metadata: item_metadata.clone(),
+        };
+
+        Self {
+            inner: TimelineInner::new(provider),
+            next_ts: AtomicU64::new(0),
+            sent_events,
+            item_metadata,
+        }
+    }
+
+    /// Create another timeline for the same room, using the same store.
+    fn sharing_store(&self) -> Self {
+        Self::with_store(self.sent_events.clone(), self.item_metadata.clone())
     }
 
     fn with_read_receipt_tracking(mut self) -> Self {
@@ -304,7 +337,16 @@ impl TestTimeline {
     }
 }
 
-struct TestRoomDataProvider;
+/// The IDs of the events sent by other processes, by transaction ID.
+type SentEvents = Arc<StdMutex<HashMap<OwnedTransactionId, OwnedEventId>>>;
+
+/// The metadata attached to the timeline items, as persisted in the store.
+type StoredItemMetadata = Arc<StdMutex<HashMap<OwnedEventId, ItemMetadata>>>;
+
+struct TestRoomDataProvider {
+    sent_events: SentEvents,
+    item_metadata: StoredItemMetadata,
+}
 
 #[async_trait]
 impl RoomDataProvider for TestRoomDataProvider {
@@ -334,4 +376,27 @@ impl RoomDataProvider for TestRoomDataProvider {
 
         Some((push_rules, push_context))
     }
+
+    async fn load_item_metadata(&self) -> HashMap<OwnedEventId, ItemMetadata> {
+        self.item_metadata.lock().unwrap().clone()
+    }
+
+    async fn update_item_metadata(
+        &self,
+        event_id: &EventId,
+        key: &str,
+        value: Option<JsonValue>,
+    ) -> matrix_sdk::Result<HashMap<OwnedEventId, ItemMetadata>> {
+        let mut item_metadata = self.item_metadata.lock().unwrap();
+        apply_item_metadata_update(&mut item_metadata, event_id, key, value);
+        Ok(item_metadata.clone())
+    }
+
+    async fn sent_event_id(&self, txn_id: &TransactionId) -> Option<OwnedEventId> {
+        self.sent_events.lock().unwrap().get(txn_id).cloned()
+    }
+
+    async fn forget_sent_event(&self, txn_id: &TransactionId) {
+        self.sent_events.lock().unwrap().remove(txn_id);
+    }
 }
@@ -0,0 +1,72 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use eyeball_im::VectorDiff;
+use matrix_sdk_test::async_test;
+use ruma::events::room::message::RoomMessageEventContent;
+use stream_assert::assert_next_matches;
+
+use super::{TestTimeline, ALICE};
+use crate::timeline::{EventOrdering, EventTimelineItem};
+
+fn body(item: &EventTimelineItem) -> &str {
+    item.content().as_message().unwrap().body()
+}
+
+#[async_test]
+async fn events_are_added_in_received_order_by_default() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe_events().await;
+
+    timeline.set_next_ts(20_000);
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("second")).await;
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    timeline.set_next_ts(15_000);
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("first")).await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert_eq!(body(&item), "first");
+}
+
+#[async_test]
+async fn late_events_are_inserted_by_timestamp() {
+    let timeline = TestTimeline::new();
+    timeline
+        .inner
+        .set_event_ordering(EventOrdering::Timestamp { window: Duration::from_secs(10) })
+        .await;
+    let mut stream = timeline.subscribe_events().await;
+
+    timeline.set_next_ts(10_000);
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("first")).await;
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    timeline.set_next_ts(20_000);
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("third")).await;
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    // Received late, but within the window.
+    timeline.set_next_ts(15_000);
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("second")).await;
+    let item = assert_next_matches!(stream, VectorDiff::Insert { index: 1, value } => value);
+    assert_eq!(body(&item), "second");
+
+    // Received too late, it's added at the end.
+    timeline.set_next_ts(5_000);
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("zeroth")).await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert_eq!(body(&item), "zeroth");
+}
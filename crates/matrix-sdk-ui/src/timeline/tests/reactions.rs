@@ -0,0 +1,165 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use eyeball_im::VectorDiff;
+use matrix_sdk_test::async_test;
+use ruma::{
+    event_id,
+    events::{reaction::ReactionEventContent, relation::Annotation},
+};
+use serde_json::json;
+use stream_assert::assert_next_matches;
+
+use super::{TestTimeline, ALICE, BOB};
+
+#[async_test]
+async fn server_summarized_reactions() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe_events().await;
+
+    let event_id = event_id!("$popular");
+    timeline
+        .handle_live_custom_event(json!({
+            "content": {
+                "msgtype": "m.text",
+                "body": "Something popular",
+            },
+            "event_id": event_id,
+            "origin_server_ts": 152037280,
+            "sender": *ALICE,
+            "type": "m.room.message",
+            "unsigned": {
+                "m.relations": {
+                    "m.annotation": {
+                        "chunk": [
+                            { "type": "m.reaction", "key": "👍", "count": 12000 },
+                            { "type": "m.reaction", "key": "🎉", "count": 3 },
+                        ],
+                    },
+                },
+            },
+        }))
+        .await;
+
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let reactions = item.reactions();
+    assert_eq!(reactions.len(), 2);
+    let thumbs_up = &reactions["👍"];
+    assert!(thumbs_up.is_summarized());
+    assert_eq!(thumbs_up.count(), 12000);
+    assert_eq!(thumbs_up.senders().count(), 0);
+    assert_eq!(reactions["🎉"].count(), 3);
+
+    // A new reaction is added to the summarized count.
+    let rel = Annotation::new(event_id.to_owned(), "👍".to_owned());
+    timeline.handle_live_message_event(&BOB, ReactionEventContent::new(rel)).await;
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    let thumbs_up = &item.reactions()["👍"];
+    assert_eq!(thumbs_up.count(), 12001);
+    assert_eq!(thumbs_up.senders().collect::<Vec<_>>(), vec![*BOB]);
+
+    // Redacting it removes it from the count, but the group stays.
+    let reaction_event_id = thumbs_up.keys().next().unwrap().1.clone().unwrap();
+    timeline.handle_live_redaction(&BOB, &reaction_event_id).await;
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    let thumbs_up = &item.reactions()["👍"];
+    assert_eq!(thumbs_up.count(), 12000);
+    assert_eq!(thumbs_up.senders().count(), 0);
+}
+
+#[async_test]
+async fn reactions_without_summary() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe_events().await;
+
+    let event_id = event_id!("$not_popular");
+    timeline
+        .handle_live_custom_event(json!({
+            "content": {
+                "msgtype": "m.text",
+                "body": "Something not popular",
+            },
+            "event_id": event_id,
+            "origin_server_ts": 152037280,
+            "sender": *ALICE,
+            "type": "m.room.message",
+        }))
+        .await;
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    let rel = Annotation::new(event_id.to_owned(), "👍".to_owned());
+    timeline.handle_live_message_event(&BOB, ReactionEventContent::new(rel)).await;
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    let thumbs_up = &item.reactions()["👍"];
+    assert!(!thumbs_up.is_summarized());
+    assert_eq!(thumbs_up.count(), 1);
+}
+
+#[async_test]
+async fn server_summarized_reactions_are_not_counted_twice() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe_events().await;
+
+    let event_id = event_id!("$popular");
+    timeline
+        .handle_live_custom_event(json!({
+            "content": {
+                "msgtype": "m.text",
+                "body": "Something popular",
+            },
+            "event_id": event_id,
+            "origin_server_ts": 152037280,
+            "sender": *ALICE,
+            "type": "m.room.message",
+            "unsigned": {
+                "m.relations": {
+                    "m.annotation": {
+                        "chunk": [
+                            { "type": "m.reaction", "key": "👍", "count": 2 },
+                        ],
+                    },
+                },
+            },
+        }))
+        .await;
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    // A reaction loaded by back-pagination is already part of the summary.
+    timeline
+        .handle_back_paginated_custom_event(json!({
+            "content": {
+                "m.relates_to": {
+                    "rel_type": "m.annotation",
+                    "event_id": event_id,
+                    "key": "👍",
+                },
+            },
+            "event_id": "$old_reaction",
+            "origin_server_ts": 152037281,
+            "sender": *BOB,
+            "type": "m.reaction",
+        }))
+        .await;
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    let thumbs_up = &item.reactions()["👍"];
+    assert_eq!(thumbs_up.count(), 2);
+    assert_eq!(thumbs_up.senders().collect::<Vec<_>>(), vec![*BOB]);
+
+    // Redacting it removes it from the summary.
+    timeline.handle_live_redaction(&BOB, event_id!("$old_reaction")).await;
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    let thumbs_up = &item.reactions()["👍"];
+    assert_eq!(thumbs_up.count(), 1);
+    assert_eq!(thumbs_up.senders().count(), 0);
+}
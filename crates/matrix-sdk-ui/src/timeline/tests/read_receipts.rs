@@ -14,13 +14,17 @@
 
 use eyeball_im::VectorDiff;
 use matrix_sdk_test::async_test;
-use ruma::events::{
-    receipt::{ReceiptThread, ReceiptType},
-    room::message::RoomMessageEventContent,
+use ruma::{
+    events::{
+        receipt::{ReceiptThread, ReceiptType},
+        room::message::RoomMessageEventContent,
+    },
+    uint, MilliSecondsSinceUnixEpoch,
 };
 use stream_assert::assert_next_matches;
 
 use super::{TestTimeline, ALICE, BOB};
+use crate::timeline::ReadReceipt;
 
 #[async_test]
 async fn read_receipts_updates() {
@@ -80,3 +84,104 @@ async fn read_receipts_updates() {
     assert_eq!(event_d.read_receipts().len(), 1);
     assert!(event_d.read_receipts().get(*BOB).is_some());
 }
+
+#[async_test]
+async fn read_receipts_types_and_timestamps() {
+    let timeline = TestTimeline::new().with_read_receipt_tracking();
+    let mut stream = timeline.subscribe().await;
+
+    timeline.set_next_ts(1_000);
+    timeline.handle_live_message_event(*BOB, RoomMessageEventContent::text_plain("A")).await;
+
+    let _day_divider = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    // The implicit read receipt is public and has the timestamp of the event.
+    let item_a = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let event_a = item_a.as_event().unwrap();
+    assert_eq!(
+        event_a.read_receipts().get(*BOB),
+        Some(&ReadReceipt { receipt_type: ReceiptType::Read, ts: Some(event_a.timestamp()) })
+    );
+
+    timeline.handle_live_message_event(*ALICE, RoomMessageEventContent::text_plain("B")).await;
+    let item_b = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let event_b = item_b.as_event().unwrap();
+
+    // Explicit read receipt, with the timestamp of the receipt.
+    timeline.set_next_ts(5_000);
+    timeline
+        .handle_read_receipts([(
+            event_b.event_id().unwrap().to_owned(),
+            ReceiptType::Read,
+            BOB.to_owned(),
+            ReceiptThread::Unthreaded,
+        )])
+        .await;
+
+    let item_a = assert_next_matches!(stream, VectorDiff::Set { index: 1, value } => value);
+    assert!(item_a.as_event().unwrap().read_receipts().is_empty());
+
+    let item_b = assert_next_matches!(stream, VectorDiff::Set { index: 2, value } => value);
+    let receipt = item_b.as_event().unwrap().read_receipts().get(*BOB).unwrap();
+    assert_eq!(receipt.receipt_type, ReceiptType::Read);
+    assert_eq!(receipt.ts, Some(MilliSecondsSinceUnixEpoch(uint!(5_000))));
+}
+
+#[async_test]
+async fn own_read_receipts() {
+    let timeline = TestTimeline::new().with_read_receipt_tracking();
+    let mut stream = timeline.subscribe().await;
+
+    timeline.handle_live_message_event(*BOB, RoomMessageEventContent::text_plain("A")).await;
+    timeline.handle_live_message_event(*BOB, RoomMessageEventContent::text_plain("B")).await;
+
+    let _day_divider = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let item_a = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let event_a = item_a.as_event().unwrap();
+    let _item_a = assert_next_matches!(stream, VectorDiff::Set { index: 1, value } => value);
+    let item_b = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let event_b = item_b.as_event().unwrap();
+
+    // Our own private read receipt is shown.
+    timeline
+        .handle_read_receipts([(
+            event_a.event_id().unwrap().to_owned(),
+            ReceiptType::ReadPrivate,
+            ALICE.to_owned(),
+            ReceiptThread::Unthreaded,
+        )])
+        .await;
+
+    let item_a = assert_next_matches!(stream, VectorDiff::Set { index: 1, value } => value);
+    let receipt = item_a.as_event().unwrap().read_receipts().get(*ALICE).unwrap();
+    assert_eq!(receipt.receipt_type, ReceiptType::ReadPrivate);
+
+    // A more recent public read receipt replaces it.
+    timeline
+        .handle_read_receipts([(
+            event_b.event_id().unwrap().to_owned(),
+            ReceiptType::Read,
+            ALICE.to_owned(),
+            ReceiptThread::Unthreaded,
+        )])
+        .await;
+
+    let item_a = assert_next_matches!(stream, VectorDiff::Set { index: 1, value } => value);
+    assert!(item_a.as_event().unwrap().read_receipts().is_empty());
+
+    let item_b = assert_next_matches!(stream, VectorDiff::Set { index: 2, value } => value);
+    let event_b = item_b.as_event().unwrap();
+    assert_eq!(event_b.read_receipts().len(), 2);
+    assert_eq!(event_b.read_receipts().get(*ALICE).unwrap().receipt_type, ReceiptType::Read);
+
+    // Our own read receipt isn't shown on our own events.
+    timeline.handle_live_message_event(*ALICE, RoomMessageEventContent::text_plain("C")).await;
+
+    let item_b = assert_next_matches!(stream, VectorDiff::Set { index: 2, value } => value);
+    let event_b = item_b.as_event().unwrap();
+    assert_eq!(event_b.read_receipts().len(), 1);
+    assert!(event_b.read_receipts().get(*ALICE).is_none());
+
+    let item_c = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert!(item_c.as_event().unwrap().read_receipts().is_empty());
+}
@@ -0,0 +1,70 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use assert_matches::assert_matches;
+use matrix_sdk_test::async_test;
+use ruma::events::room::message::RoomMessageEventContent;
+use serde_json::{from_value as from_json_value, to_value as to_json_value};
+
+use super::{TestTimeline, ALICE};
+use crate::timeline::{
+    ContentSnapshot, TimelineItemSnapshot, TimelineSnapshot, VirtualItemSnapshot,
+    TIMELINE_SNAPSHOT_VERSION,
+};
+
+#[async_test]
+async fn snapshot_round_trip() {
+    let timeline = TestTimeline::new();
+    timeline
+        .handle_live_message_event(
+            &ALICE,
+            RoomMessageEventContent::text_html("hello", "<b>hello</b>"),
+        )
+        .await;
+
+    let items = timeline.inner.items().await;
+    let snapshot = TimelineSnapshot::new(items.iter().map(|item| &**item));
+    assert_eq!(snapshot.version, TIMELINE_SNAPSHOT_VERSION);
+    assert_eq!(snapshot.items.len(), 2);
+    assert_matches!(
+        &snapshot.items[0],
+        TimelineItemSnapshot::Virtual(VirtualItemSnapshot::DayDivider { .. })
+    );
+    assert_matches!(&snapshot.items[1], TimelineItemSnapshot::Event(event) => {
+        assert_eq!(*event.sender, **ALICE);
+        assert!(event.send_state.is_none());
+        assert_matches!(
+            &event.content,
+            ContentSnapshot::Message { msgtype, body, formatted_body, edited, .. } => {
+                assert_eq!(msgtype, "m.text");
+                assert_eq!(body, "hello");
+                assert_eq!(formatted_body.as_deref(), Some("<b>hello</b>"));
+                assert!(!edited);
+            }
+        );
+    });
+
+    let json = to_json_value(&snapshot).unwrap();
+    assert_eq!(json["version"], TIMELINE_SNAPSHOT_VERSION);
+    assert_eq!(json["items"][0]["kind"], "virtual");
+    assert_eq!(json["items"][0]["type"], "day_divider");
+    assert_eq!(json["items"][1]["kind"], "event");
+    assert_eq!(json["items"][1]["content"]["type"], "message");
+
+    // The items serialize to the same schema.
+    assert_eq!(to_json_value(&*items[1]).unwrap(), json["items"][1]);
+
+    let deserialized: TimelineSnapshot = from_json_value(json).unwrap();
+    assert_eq!(deserialized, snapshot);
+}
@@ -12,18 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::Arc;
+
 use assert_matches::assert_matches;
 use chrono::{Datelike, Local, TimeZone};
 use eyeball_im::VectorDiff;
 use matrix_sdk_test::async_test;
 use ruma::{
     event_id,
-    events::{room::message::RoomMessageEventContent, AnyMessageLikeEventContent},
+    events::{
+        room::{encryption::RoomEncryptionEventContent, message::RoomMessageEventContent},
+        AnyMessageLikeEventContent,
+    },
 };
-use stream_assert::assert_next_matches;
+use stream_assert::{assert_next_matches, assert_pending};
 
 use super::{TestTimeline, ALICE, BOB};
-use crate::timeline::{TimelineItem, VirtualTimelineItem};
+use crate::timeline::{
+    DividerPolicy, SecurityNotice, SecurityNoticeSettings, TimelineItem, VirtualTimelineItem,
+};
 
 #[async_test]
 async fn day_divider() {
@@ -98,6 +105,89 @@ async fn day_divider() {
     item.as_event().unwrap();
 }
 
+#[async_test]
+async fn day_divider_back_pagination() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+
+    timeline.set_next_ts(24 * 60 * 60 * 1000);
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("B")).await;
+    let _day_divider = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    // An event of the previous day gets its own day divider.
+    timeline.set_next_ts(1);
+    let event = timeline.make_message_event(*ALICE, RoomMessageEventContent::text_plain("A2"));
+    timeline.handle_back_paginated_custom_event(event).await;
+
+    let day_divider = assert_next_matches!(stream, VectorDiff::Insert { index: 0, value } => value);
+    assert!(day_divider.is_day_divider());
+    let item = assert_next_matches!(stream, VectorDiff::Insert { index: 1, value } => value);
+    item.as_event().unwrap();
+
+    // An event of the same day is added after the existing day divider.
+    timeline.set_next_ts(0);
+    let event = timeline.make_message_event(*ALICE, RoomMessageEventContent::text_plain("A1"));
+    timeline.handle_back_paginated_custom_event(event).await;
+
+    let item = assert_next_matches!(stream, VectorDiff::Insert { index: 1, value } => value);
+    item.as_event().unwrap();
+    assert_pending!(stream);
+}
+
+#[async_test]
+async fn no_dividers() {
+    let timeline = TestTimeline::new();
+    timeline.inner.set_divider_policy(DividerPolicy::None).await;
+    let mut stream = timeline.subscribe().await;
+
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("A")).await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    item.as_event().unwrap();
+
+    timeline.set_next_ts(24 * 60 * 60 * 1000);
+    timeline.handle_live_message_event(&BOB, RoomMessageEventContent::text_plain("B")).await;
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    item.as_event().unwrap();
+
+    timeline.set_next_ts(0);
+    let event = timeline.make_message_event(*ALICE, RoomMessageEventContent::text_plain("C"));
+    timeline.handle_back_paginated_custom_event(event).await;
+    let item = assert_next_matches!(stream, VectorDiff::Insert { index: 0, value } => value);
+    item.as_event().unwrap();
+    assert_pending!(stream);
+}
+
+#[async_test]
+async fn change_divider_policy() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+
+    timeline.handle_live_message_event(&ALICE, RoomMessageEventContent::text_plain("A")).await;
+    timeline.set_next_ts(2 * 60 * 60 * 1000);
+    timeline.handle_live_message_event(&BOB, RoomMessageEventContent::text_plain("B")).await;
+    let _day_divider = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    // The events are in different hours.
+    timeline.inner.set_divider_policy(DividerPolicy::Hourly).await;
+    let divider = assert_next_matches!(stream, VectorDiff::Insert { index: 2, value } => value);
+    assert!(divider.is_day_divider());
+    assert_pending!(stream);
+
+    timeline.inner.set_divider_policy(DividerPolicy::None).await;
+    assert_next_matches!(stream, VectorDiff::Remove { index: 0 });
+    assert_next_matches!(stream, VectorDiff::Remove { index: 1 });
+    assert_pending!(stream);
+
+    // Only the first event gets a divider.
+    timeline.inner.set_divider_policy(DividerPolicy::Custom(Arc::new(|_, _| false))).await;
+    let divider = assert_next_matches!(stream, VectorDiff::Insert { index: 0, value } => value);
+    assert!(divider.is_day_divider());
+    assert_pending!(stream);
+}
+
 #[async_test]
 async fn update_read_marker() {
     let timeline = TestTimeline::new();
@@ -155,3 +245,66 @@ async fn update_read_marker() {
     let marker = assert_next_matches!(stream, VectorDiff::Insert { index: 4, value } => value);
     assert_matches!(*marker, TimelineItem::Virtual(VirtualTimelineItem::ReadMarker));
 }
+
+#[async_test]
+async fn encryption_enabled_security_notice() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+
+    // The notices are disabled by default.
+    timeline
+        .handle_live_state_event(
+            &ALICE,
+            RoomEncryptionEventContent::with_recommended_defaults(),
+            None,
+        )
+        .await;
+    let _day_divider = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    timeline.inner.set_security_notice_settings(SecurityNoticeSettings::all()).await;
+
+    timeline
+        .handle_live_state_event(
+            &BOB,
+            RoomEncryptionEventContent::with_recommended_defaults(),
+            None,
+        )
+        .await;
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let notice = assert_next_matches!(stream, VectorDiff::Insert { index: 3, value } => value);
+    assert_matches!(
+        notice.as_virtual(),
+        Some(VirtualTimelineItem::SecurityNotice { notice: SecurityNotice::EncryptionEnabled, .. })
+    );
+
+    // Only the first encryption event enables encryption.
+    timeline
+        .handle_live_state_event(
+            &ALICE,
+            RoomEncryptionEventContent::with_recommended_defaults(),
+            Some(RoomEncryptionEventContent::with_recommended_defaults()),
+        )
+        .await;
+    let _item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert_pending!(stream);
+}
+
+#[cfg(feature = "e2e-encryption")]
+#[async_test]
+async fn repeated_security_notices() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+
+    let notice = SecurityNotice::IdentityChanged { user_id: (*BOB).to_owned() };
+    timeline.inner.add_security_notice(notice.clone()).await;
+    let first = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+
+    // The same change notified again gets a new item, with its own ID.
+    timeline.inner.add_security_notice(notice).await;
+    let second = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert_pending!(stream);
+
+    assert_ne!(first.unique_id(), second.unique_id());
+    assert_eq!(timeline.inner.items().await.len(), 2);
+}
@@ -0,0 +1,148 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::num::NonZeroUsize;
+
+use eyeball_im::VectorDiff;
+use matrix_sdk_test::async_test;
+use ruma::{
+    api::Direction,
+    event_id,
+    events::{
+        reaction::ReactionEventContent, relation::Annotation,
+        room::message::RoomMessageEventContent,
+    },
+    EventId,
+};
+use serde_json::{json, Value as JsonValue};
+use stream_assert::assert_next_matches;
+
+use super::{TestTimeline, ALICE, BOB};
+use crate::timeline::{inner::WindowEdge, rfind_event_by_id};
+
+fn message(event_id: &str, ts: u64) -> JsonValue {
+    json!({
+        "content": {
+            "body": "hello",
+            "msgtype": "m.text",
+        },
+        "event_id": event_id,
+        "origin_server_ts": ts,
+        "sender": *ALICE,
+        "type": "m.room.message",
+    })
+}
+
+fn edge_event_id(edge: &Option<WindowEdge>) -> Option<&EventId> {
+    edge.as_ref().map(|edge| &*edge.event_id)
+}
+
+async fn event_ids(timeline: &TestTimeline) -> Vec<String> {
+    timeline
+        .inner
+        .items()
+        .await
+        .iter()
+        .filter_map(|item| item.as_event()?.event_id().map(ToString::to_string))
+        .collect()
+}
+
+#[async_test]
+async fn window_drops_old_items() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+    timeline.inner.set_window_size(NonZeroUsize::new(4)).await;
+
+    for (ts, event_id) in ["$1", "$2", "$3", "$4", "$5", "$6"].into_iter().enumerate() {
+        timeline.handle_live_custom_event(message(event_id, ts as u64)).await;
+    }
+    let rel = Annotation::new(event_id!("$1").to_owned(), "👍".to_owned());
+    timeline.handle_live_message_event(&BOB, ReactionEventContent::new(rel)).await;
+
+    // The day divider and the 6 events.
+    for _ in 0..7 {
+        assert_next_matches!(stream, VectorDiff::PushBack { .. });
+    }
+    assert_next_matches!(stream, VectorDiff::Set { index: 1, .. });
+
+    // The viewport is at the live end, the oldest items are dropped.
+    let edges = timeline.inner.window_edges(event_id!("$6")).await.unwrap();
+    assert_eq!(edge_event_id(&edges.first), Some(event_id!("$3")));
+    assert!(edges.last.is_none());
+    assert!(edges.needs_request());
+
+    timeline.inner.retain_window(edge_event_id(&edges.first), edge_event_id(&edges.last)).await;
+    for _ in 0..3 {
+        assert_next_matches!(stream, VectorDiff::PopFront);
+    }
+    // The day divider is added again before the first event.
+    assert_next_matches!(stream, VectorDiff::Insert { index: 0, .. });
+    assert_eq!(event_ids(&timeline).await, ["$3", "$4", "$5", "$6"]);
+
+    // The window is full, nothing more to drop.
+    assert!(timeline.inner.window_edges(event_id!("$6")).await.is_none());
+
+    // The reactions are added again when the events are loaded again.
+    timeline.handle_back_paginated_custom_event(message("$2", 1)).await;
+    timeline.handle_back_paginated_custom_event(message("$1", 0)).await;
+    assert_eq!(event_ids(&timeline).await, ["$1", "$2", "$3", "$4", "$5", "$6"]);
+
+    let items = timeline.inner.items().await;
+    let (_, item) = rfind_event_by_id(&items, event_id!("$1")).unwrap();
+    assert_eq!(item.reactions()["👍"].senders().collect::<Vec<_>>(), vec![*BOB]);
+}
+
+#[async_test]
+async fn window_keeps_local_echoes() {
+    let timeline = TestTimeline::new();
+    timeline.inner.set_window_size(NonZeroUsize::new(3)).await;
+
+    for (ts, event_id) in ["$1", "$2", "$3", "$4", "$5", "$6"].into_iter().enumerate() {
+        timeline.handle_live_custom_event(message(event_id, ts as u64)).await;
+    }
+
+    // The viewport is on old events, the newest items are dropped.
+    let edges = timeline.inner.window_edges(event_id!("$2")).await.unwrap();
+    assert!(edges.first.is_none());
+    assert_eq!(edge_event_id(&edges.last), Some(event_id!("$3")));
+
+    // But not if they contain a local echo.
+    timeline.handle_local_event(RoomMessageEventContent::text_plain("unsent").into()).await;
+    assert!(timeline.inner.window_edges(event_id!("$2")).await.is_none());
+}
+
+#[async_test]
+async fn window_uses_cached_pagination_tokens() {
+    let timeline = TestTimeline::new();
+    timeline.inner.set_window_size(NonZeroUsize::new(4)).await;
+
+    for (ts, event_id) in ["$1", "$2", "$3", "$4", "$5", "$6"].into_iter().enumerate() {
+        timeline.handle_live_custom_event(message(event_id, ts as u64)).await;
+    }
+    timeline
+        .inner
+        .save_pagination_token(event_id!("$2").to_owned(), Direction::Backward, "t2".to_owned())
+        .await;
+
+    // The event with a known token is kept, so there is no need to ask the
+    // homeserver for one.
+    let edges = timeline.inner.window_edges(event_id!("$6")).await.unwrap();
+    let first = edges.first.as_ref().unwrap();
+    assert_eq!(first.event_id, event_id!("$2"));
+    assert_eq!(first.token.as_deref(), Some("t2"));
+    assert!(!edges.needs_request());
+
+    timeline.inner.retain_window(Some(&first.event_id), None).await;
+    assert_eq!(event_ids(&timeline).await, ["$2", "$3", "$4", "$5", "$6"]);
+}
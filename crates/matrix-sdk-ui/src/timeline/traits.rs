@@ -12,21 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use indexmap::IndexMap;
-use matrix_sdk::room;
 #[cfg(feature = "e2e-encryption")]
-use matrix_sdk::{deserialized_responses::TimelineEvent, Result};
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::{room, Result};
+use matrix_sdk_base::{StateStoreDataKey, StateStoreDataValue};
+use once_cell::sync::Lazy;
 use ruma::{
     events::receipt::{Receipt, ReceiptThread, ReceiptType},
     push::{PushConditionRoomCtx, Ruleset},
-    EventId, OwnedUserId, UserId,
+    EventId, OwnedEventId, OwnedUserId, RoomId, TransactionId, UserId,
 };
 #[cfg(feature = "e2e-encryption")]
 use ruma::{events::AnySyncTimelineEvent, serde::Raw};
+use serde_json::Value as JsonValue;
+use tokio::sync::Mutex;
 use tracing::{debug, error};
 
-use super::Profile;
+use super::{event_item::ItemMetadata, Profile};
 use crate::timeline::Timeline;
 
 #[async_trait]
@@ -52,6 +58,78 @@ pub(super) trait RoomDataProvider {
     async fn profile(&self, user_id: &UserId) -> Option<Profile>;
     async fn read_receipts_for_event(&self, event_id: &EventId) -> IndexMap<OwnedUserId, Receipt>;
     async fn push_rules_and_context(&self) -> Option<(Ruleset, PushConditionRoomCtx)>;
+    async fn load_item_metadata(&self) -> HashMap<OwnedEventId, ItemMetadata>;
+    /// Attach the given value to the event with the given ID under the given
+    /// key, or remove it if `value` is `None`, and persist it.
+    ///
+    /// Returns all the metadata of the room after the update, including the
+    /// updates made by other timelines.
+    async fn update_item_metadata(
+        &self,
+        event_id: &EventId,
+        key: &str,
+        value: Option<JsonValue>,
+    ) -> Result<HashMap<OwnedEventId, ItemMetadata>>;
+    /// The ID of the event sent with the given transaction ID, possibly by
+    /// another process using the same store.
+    async fn sent_event_id(&self, txn_id: &TransactionId) -> Option<OwnedEventId>;
+    /// Forget the ID of the event sent with the given transaction ID, once its
+    /// remote echo was matched with its local echo.
+    async fn forget_sent_event(&self, txn_id: &TransactionId);
+}
+
+/// The key of the metadata attached to the timeline items of the given room in
+/// the custom values of the state store.
+fn item_metadata_key(room_id: &RoomId) -> String {
+    format!("timeline_item_metadata:{room_id}")
+}
+
+/// Serializes the updates of the timeline item metadata, which are stored as
+/// a whole per room, between all the timelines.
+static ITEM_METADATA_LOCK: Lazy<Mutex<()>> = Lazy::new(Mutex::default);
+
+/// Attach the given value to the event with the given ID under the given key,
+/// or remove it if `value` is `None`.
+///
+/// Returns `false` if the metadata didn't change.
+pub(super) fn apply_item_metadata_update(
+    item_metadata: &mut HashMap<OwnedEventId, ItemMetadata>,
+    event_id: &EventId,
+    key: &str,
+    value: Option<JsonValue>,
+) -> bool {
+    match value {
+        Some(value) => {
+            let metadata = item_metadata.entry(event_id.to_owned()).or_default();
+            if metadata.get(key) == Some(&value) {
+                return false;
+            }
+            metadata.insert(key.to_owned(), value);
+        }
+        None => {
+            let Some(metadata) = item_metadata.get_mut(event_id) else {
+                return false;
+            };
+            if metadata.remove(key).is_none() {
+                return false;
+            }
+            if metadata.is_empty() {
+                item_metadata.remove(event_id);
+            }
+        }
+    }
+
+    true
+}
+
+/// Load the metadata attached to the timeline items of the given room from the
+/// state store.
+async fn stored_item_metadata(room: &room::Common) -> Result<HashMap<OwnedEventId, ItemMetadata>> {
+    let key = item_metadata_key(room.room_id());
+    Ok(match room.client().store().get_custom_value(key.as_bytes()).await? {
+        Some(value) => serde_json::from_slice(&value)?,
+        None => HashMap::new(),
+    })
 }
 
 #[async_trait]
@@ -109,6 +187,63 @@ impl RoomDataProvider for room::Common {
             }
         }
     }
+
+    async fn load_item_metadata(&self) -> HashMap<OwnedEventId, ItemMetadata> {
+        match stored_item_metadata(self).await {
+            Ok(item_metadata) => item_metadata,
+            Err(e) => {
+                error!("Failed to load timeline item metadata: {e}");
+                HashMap::new()
+            }
+        }
+    }
+
+    async fn update_item_metadata(
+        &self,
+        event_id: &EventId,
+        key: &str,
+        value: Option<JsonValue>,
+    ) -> Result<HashMap<OwnedEventId, ItemMetadata>> {
+        let _guard = ITEM_METADATA_LOCK.lock().await;
+
+        // Start from the stored metadata rather than the one of the timeline,
+        // so the updates made by other timelines aren't lost.
+        let mut item_metadata = stored_item_metadata(self).await?;
+        if !apply_item_metadata_update(&mut item_metadata, event_id, key, value) {
+            return Ok(item_metadata);
+        }
+
+        let store_key = item_metadata_key(self.room_id());
+        let client = self.client();
+        let store = client.store();
+
+        if item_metadata.is_empty() {
+            store.remove_custom_value(store_key.as_bytes()).await?;
+        } else {
+            let value = serde_json::to_vec(&item_metadata)?;
+            store.set_custom_value(store_key.as_bytes(), value).await?;
+        }
+
+        Ok(item_metadata)
+    }
+
+    async fn sent_event_id(&self, txn_id: &TransactionId) -> Option<OwnedEventId> {
+        match self.client().store().get_kv_data(StateStoreDataKey::SentEvent(txn_id)).await {
+            Ok(value) => value.and_then(StateStoreDataValue::into_sent_event),
+            Err(e) => {
+                error!(?txn_id, "Failed to load the ID of a sent event: {e}");
+                None
+            }
+        }
+    }
+
+    async fn forget_sent_event(&self, txn_id: &TransactionId) {
+        if let Err(e) =
+            self.client().store().remove_kv_data(StateStoreDataKey::SentEvent(txn_id)).await
+        {
+            error!(?txn_id, "Failed to remove the ID of a sent event: {e}");
+        }
+    }
 }
 
 // Internal helper to make most of retry_event_decryption independent of a room
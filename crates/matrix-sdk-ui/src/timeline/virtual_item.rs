@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use ruma::MilliSecondsSinceUnixEpoch;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ruma::{MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId};
 
 /// A [`TimelineItem`](super::TimelineItem) that doesn't correspond to an event.
 #[derive(Clone, Debug)]
@@ -34,4 +36,54 @@ pub enum VirtualTimelineItem {
     /// There might be earlier events the user is not allowed to see due to
     /// history visibility.
     TimelineStart,
+
+    /// A security-relevant change in the room.
+    ///
+    /// These are only added if they are enabled with
+    /// [`Timeline::set_security_notices()`](super::Timeline::set_security_notices).
+    SecurityNotice {
+        /// The unique ID of this notice.
+        ///
+        /// The same change can be notified several times, each notice has its
+        /// own ID.
+        id: SecurityNoticeId,
+        /// The change.
+        notice: SecurityNotice,
+    },
+}
+
+/// The unique ID of a security notice in the timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SecurityNoticeId(u64);
+
+impl SecurityNoticeId {
+    /// Get a new ID, different from all the other IDs of this process.
+    pub(super) fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A security-relevant change in a room, shown inline in the timeline.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SecurityNotice {
+    /// End-to-end encryption was enabled in the room.
+    ///
+    /// This notice follows the `m.room.encryption` event.
+    EncryptionEnabled,
+
+    /// A member added a device that isn't verified, while all the members of
+    /// the room were verified.
+    UnverifiedDevice {
+        /// The owner of the device.
+        user_id: OwnedUserId,
+        /// The ID of the device.
+        device_id: OwnedDeviceId,
+    },
+
+    /// The cross-signing identity of a member of the room changed.
+    IdentityChanged {
+        /// The member whose identity changed.
+        user_id: OwnedUserId,
+    },
 }
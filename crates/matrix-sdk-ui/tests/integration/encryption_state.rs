@@ -0,0 +1,71 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk_test::{async_test, test_json, EventBuilder, GlobalAccountDataTestEvent};
+use matrix_sdk_ui::encryption_state::{
+    EncryptionBanner, EncryptionStateObserver, KeyBackupState, RecoveryState,
+};
+use serde_json::json;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::{logged_in_client, mock_sync};
+
+#[async_test]
+async fn encryption_state_is_updated_in_the_background() {
+    let (client, server) = logged_in_client().await;
+
+    // The key backup is requested when the observer is created and when it's
+    // refreshed explicitly, but not when the secret storage changes.
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/room_keys/version"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(&*test_json::NOT_FOUND))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let observer = EncryptionStateObserver::new(client.clone()).await;
+    let state = observer.get();
+    assert_eq!(state.recovery, RecoveryState::Disabled);
+    assert_eq!(state.key_backup, KeyBackupState::Disabled);
+    assert_eq!(state.banner(), Some(EncryptionBanner::SetUpRecovery));
+
+    let mut subscriber = observer.subscribe();
+
+    // The secret storage is set up by another session.
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_global_account_data_event(GlobalAccountDataTestEvent::Custom(json!({
+        "type": "m.secret_storage.default_key",
+        "content": { "key": "bmur2d9ypPUH1msSwCxQOJkuKRmJI55e" },
+    })));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    client.sync_once(SyncSettings::default()).await.unwrap();
+
+    // This session doesn't have the private cross-signing keys yet.
+    let state = async_std::future::timeout(Duration::from_secs(5), subscriber.next())
+        .await
+        .expect("the encryption state wasn't updated")
+        .unwrap();
+    assert_eq!(state.recovery, RecoveryState::Incomplete);
+    assert_eq!(state.key_backup, KeyBackupState::Disabled);
+    assert_eq!(state.banner(), Some(EncryptionBanner::KeyBackupOff));
+
+    observer.refresh().await;
+}
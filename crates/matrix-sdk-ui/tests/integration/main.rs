@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use matrix_sdk::{config::RequestConfig, Client, ClientBuilder, Session};
-use matrix_sdk_test::test_json;
+use matrix_sdk_test::{test_json, MatrixMockServer};
 use ruma::{api::MatrixVersion, device_id, user_id};
 use serde::Serialize;
 use wiremock::{
@@ -21,6 +21,8 @@ use wiremock::{
     Mock, MockServer, ResponseTemplate,
 };
 
+#[cfg(feature = "e2e-encryption")]
+mod encryption_state;
 #[cfg(feature = "experimental-notification")]
 mod notification;
 #[cfg(feature = "experimental-room-list")]
@@ -52,15 +54,34 @@ async fn no_retry_test_client() -> (Client, MockServer) {
     (client, server)
 }
 
-async fn logged_in_client() -> (Client, MockServer) {
-    let session = Session {
+fn test_session() -> Session {
+    Session {
         access_token: "1234".to_owned(),
         refresh_token: None,
         user_id: user_id!("@example:localhost").to_owned(),
         device_id: device_id!("DEVICEID").to_owned(),
-    };
+    }
+}
+
+async fn logged_in_client() -> (Client, MockServer) {
     let (client, server) = no_retry_test_client().await;
-    client.restore_session(session).await.unwrap();
+    client.restore_session(test_session()).await.unwrap();
+
+    (client, server)
+}
+
+/// Like [`logged_in_client()`], but with a [`MatrixMockServer`] to mock the
+/// common endpoints.
+async fn logged_in_client_with_mock_server() -> (Client, MatrixMockServer) {
+    let server = MatrixMockServer::new().await;
+    let client = Client::builder()
+        .homeserver_url(server.uri())
+        .server_versions([MatrixVersion::V1_0])
+        .request_config(RequestConfig::new().disable_retry())
+        .build()
+        .await
+        .unwrap();
+    client.restore_session(test_session()).await.unwrap();
 
     (client, server)
 }
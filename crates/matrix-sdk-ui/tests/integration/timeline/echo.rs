@@ -25,6 +25,7 @@ use matrix_sdk_ui::timeline::{
 use ruma::{
     event_id,
     events::room::message::{MessageType, RoomMessageEventContent},
+    matrix_uri::MatrixId,
     room_id, uint, TransactionId,
 };
 use serde_json::json;
@@ -34,18 +35,20 @@ use wiremock::{
     Mock, ResponseTemplate,
 };
 
-use crate::{logged_in_client, mock_encryption_state, mock_sync};
+use crate::{
+    logged_in_client, logged_in_client_with_mock_server, mock_encryption_state, mock_sync,
+};
 
 #[async_test]
 async fn echo() {
     let room_id = room_id!("!a98sd12bjh:example.org");
-    let (client, server) = logged_in_client().await;
+    let (client, server) = logged_in_client_with_mock_server().await;
     let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
 
     let mut ev_builder = EventBuilder::new();
     ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id));
 
-    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    server.mock_sync().ok(ev_builder.build_json_sync_response()).mount().await;
     let _response = client.sync_once(sync_settings.clone()).await.unwrap();
     server.reset().await;
 
@@ -53,17 +56,10 @@ async fn echo() {
     let timeline = Arc::new(room.timeline().await);
     let (_, mut timeline_stream) = timeline.subscribe().await;
 
-    let event_id = event_id!("$wWgymRfo7ri1uQx0NXO40vLJ");
     let txn_id: &TransactionId = "my-txn-id".into();
 
-    mock_encryption_state(&server, false).await;
-
-    Mock::given(method("PUT"))
-        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/send/.*"))
-        .and(header("authorization", "Bearer 1234"))
-        .respond_with(ResponseTemplate::new(200).set_body_json(&json!({ "event_id": event_id })))
-        .mount(&server)
-        .await;
+    server.mock_room_encryption_state().mount().await;
+    server.mock_room_send().with_access_token("1234").mount().await;
 
     // Don't move the original timeline, it must live until the end of the test
     let timeline = timeline.clone();
@@ -107,7 +103,7 @@ async fn echo() {
         })),
     ));
 
-    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    server.mock_sync().ok(ev_builder.build_json_sync_response()).mount().await;
     let _response = client.sync_once(sync_settings.clone()).await.unwrap();
     server.reset().await;
 
@@ -133,6 +129,62 @@ async fn echo() {
     assert_eq!(item.timestamp(), MilliSecondsSinceUnixEpoch(uint!(152038280)));
 }
 
+#[async_test]
+async fn permalink_of_local_echo() {
+    let room_id = room_id!("!a98sd12bjh:example.org");
+    let (client, server) = logged_in_client_with_mock_server().await;
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id));
+
+    server.mock_sync().ok(ev_builder.build_json_sync_response()).mount().await;
+    let _response = client.sync_once(SyncSettings::new()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_room(room_id).unwrap();
+    let timeline = Arc::new(room.timeline().await);
+    let (_, mut timeline_stream) = timeline.subscribe().await;
+
+    server.mock_room_encryption_state().mount().await;
+    server.mock_room_send().mount().await;
+
+    let txn_id: &TransactionId = "my-txn-id".into();
+    let send_hdl = spawn({
+        let timeline = timeline.clone();
+        async move {
+            timeline
+                .send(RoomMessageEventContent::text_plain("Hello, World!").into(), Some(txn_id))
+                .await
+        }
+    });
+
+    let _day_divider = assert_matches!(timeline_stream.next().await, Some(VectorDiff::PushBack { value }) => value);
+    let local_echo = assert_matches!(timeline_stream.next().await, Some(VectorDiff::PushBack { value }) => value);
+    let item = local_echo.as_event().unwrap();
+    assert_matches!(item.send_state(), Some(EventSendState::NotSentYet));
+
+    // The local echo doesn't have an event ID yet.
+    assert_eq!(item.event_id(), None);
+    assert!(item.permalink(&room).await.unwrap().is_none());
+    assert!(timeline.permalink(item).await.unwrap().is_none());
+
+    send_hdl.await.unwrap();
+
+    // The event ID is known once the event is sent, even before the remote echo.
+    let sent_confirmation = assert_matches!(
+        timeline_stream.next().await,
+        Some(VectorDiff::Set { index: 1, value }) => value
+    );
+    let item = sent_confirmation.as_event().unwrap();
+    assert_matches!(item.send_state(), Some(EventSendState::Sent { .. }));
+
+    let permalink = item.permalink(&room).await.unwrap().unwrap();
+    assert_matches!(permalink.id(), MatrixId::Event(permalink_room_id, event_id) => {
+        assert_eq!(permalink_room_id.as_str(), room_id.as_str());
+        assert_eq!(event_id.as_str(), "$my-txn-id");
+    });
+}
+
 #[async_test]
 async fn retry_failed() {
     let room_id = room_id!("!a98sd12bjh:example.org");
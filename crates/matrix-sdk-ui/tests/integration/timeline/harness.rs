@@ -0,0 +1,80 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use assert_matches::assert_matches;
+use eyeball_im::VectorDiff;
+use matrix_sdk_test::async_test;
+use matrix_sdk_ui::timeline::{testing::TimelineHarness, EventSendState, VirtualTimelineItem};
+use ruma::{events::room::message::RoomMessageEventContent, user_id};
+
+#[async_test]
+async fn edit_and_reaction() {
+    let bob = user_id!("@bob:example.org");
+    let harness = TimelineHarness::builder().build();
+    let mut diffs = harness.subscribe().await;
+
+    let event_id =
+        harness.push_live_message(bob, RoomMessageEventContent::text_plain("Helo")).await;
+    let day_divider =
+        assert_matches!(diffs.next_diff(), Some(VectorDiff::PushBack { value }) => value);
+    assert_matches!(day_divider.as_virtual(), Some(VirtualTimelineItem::DayDivider(_)));
+    let item = assert_matches!(diffs.next_diff(), Some(VectorDiff::PushBack { value }) => value);
+    assert_eq!(item.as_event().unwrap().timestamp(), harness.now());
+    diffs.assert_empty();
+
+    harness.advance_time(Duration::from_secs(10));
+    harness.push_edit(bob, &event_id, "Hello").await;
+    let item =
+        assert_matches!(diffs.next_diff(), Some(VectorDiff::Set { index: 1, value }) => value);
+    let message = item.as_event().unwrap().content().as_message().unwrap();
+    assert_eq!(message.body(), "Hello");
+    assert!(message.is_edited());
+
+    harness.push_reaction(harness.own_user_id(), &event_id, "👍").await;
+    let item =
+        assert_matches!(diffs.next_diff(), Some(VectorDiff::Set { index: 1, value }) => value);
+    let senders: Vec<_> = item.as_event().unwrap().reactions()["👍"].senders().collect();
+    assert_eq!(senders, [harness.own_user_id()]);
+    diffs.assert_empty();
+}
+
+#[async_test]
+async fn read_receipts_and_local_echo() {
+    let bob = user_id!("@bob:example.org");
+    let harness = TimelineHarness::builder().track_read_receipts().build();
+
+    let event_id = harness.push_live_message(bob, RoomMessageEventContent::text_plain("Hi")).await;
+    let mut diffs = harness.subscribe().await;
+
+    let carol = user_id!("@carol:example.org");
+    harness.push_read_receipt(carol, &event_id).await;
+    let item =
+        assert_matches!(diffs.next_diff(), Some(VectorDiff::Set { index: 1, value }) => value);
+    assert!(item.as_event().unwrap().read_receipts().contains_key(carol));
+
+    // Local echoes use the real time, so they might come with a new day divider.
+    let txn_id = harness.push_local_echo(RoomMessageEventContent::text_plain("Hey")).await;
+    let echo_diff = diffs.ready_diffs().pop();
+    assert_matches!(echo_diff, Some(VectorDiff::PushBack { .. }));
+    let echo_index = harness.items().await.len() - 1;
+
+    harness.mark_local_echo_sent(&txn_id).await;
+    let diff = diffs.next_diff();
+    let (index, item) =
+        assert_matches!(diff, Some(VectorDiff::Set { index, value }) => (index, value));
+    assert_eq!(index, echo_index);
+    assert_matches!(item.as_event().unwrap().send_state(), Some(EventSendState::Sent { .. }));
+}
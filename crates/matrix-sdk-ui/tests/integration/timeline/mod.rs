@@ -25,14 +25,18 @@ use matrix_sdk_test::{
 use matrix_sdk_ui::timeline::{
     Error as TimelineError, RoomExt, TimelineDetails, TimelineItemContent, VirtualTimelineItem,
 };
-use ruma::{event_id, events::room::message::MessageType, room_id, uint, user_id};
+use ruma::{
+    event_id, events::room::message::MessageType, matrix_uri::MatrixId, owned_server_name, room_id,
+    uint, user_id,
+};
 use serde_json::json;
 use wiremock::{
-    matchers::{header, method, path_regex},
+    matchers::{header, method, path_regex, query_param},
     Mock, ResponseTemplate,
 };
 
 mod echo;
+mod harness;
 mod pagination;
 mod read_receipts;
 #[cfg(feature = "experimental-sliding-sync")]
@@ -40,6 +44,73 @@ pub(crate) mod sliding_sync;
 
 use crate::{logged_in_client, mock_sync};
 
+#[cfg(feature = "metrics")]
+#[async_test]
+async fn timeline_metrics_are_recorded() {
+    use matrix_sdk::metrics::TIMELINE_UPDATE_DURATION;
+    use matrix_sdk_test::{
+        metrics::{install_recorder, take_recorded_histograms},
+        test_json,
+    };
+    use matrix_sdk_ui::timeline::PaginationOptions;
+
+    let room_id = room_id!("!a98sd12bjh:example.org");
+    let (client, server) = logged_in_client().await;
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id));
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_room(room_id).unwrap();
+    let timeline = room.timeline().await;
+    let (_, mut timeline_stream) = timeline.subscribe().await;
+    install_recorder();
+
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_timeline_event(
+        TimelineTestEvent::Custom(json!({
+            "content": {
+                "body": "hello",
+                "msgtype": "m.text",
+            },
+            "event_id": "$msda7m:localhost",
+            "origin_server_ts": 152037280,
+            "sender": "@alice:example.org",
+            "type": "m.room.message",
+        })),
+    ));
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    // Wait for the timeline to be updated with the sync response.
+    let _day_divider = assert_matches!(timeline_stream.next().await, Some(VectorDiff::PushBack { value }) => value);
+    let _message = assert_matches!(timeline_stream.next().await, Some(VectorDiff::PushBack { value }) => value);
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/messages$"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::ROOM_MESSAGES_BATCH_1))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    timeline.paginate_backwards(PaginationOptions::single_request(10)).await.unwrap();
+
+    let recorded = take_recorded_histograms();
+    let is_recorded = |source| {
+        recorded
+            .iter()
+            .any(|histogram| histogram.matches(TIMELINE_UPDATE_DURATION, &[("source", source)]))
+    };
+    assert!(is_recorded("sync"), "{recorded:?}");
+    assert!(is_recorded("back_pagination"), "{recorded:?}");
+}
+
 #[async_test]
 async fn edit() {
     let room_id = room_id!("!a98sd12bjh:example.org");
@@ -545,6 +616,167 @@ async fn in_reply_to_details() {
     assert_matches!(message.in_reply_to().unwrap().event, TimelineDetails::Ready(_));
 }
 
+#[async_test]
+async fn item_context() {
+    let room_id = room_id!("!a98sd12bjh:example.org");
+    let (client, server) = logged_in_client().await;
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(
+        JoinedRoomBuilder::new(room_id)
+            .add_state_event(StateTestEvent::Member)
+            .add_state_event(StateTestEvent::PowerLevels),
+    );
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_room(room_id).unwrap();
+    let timeline = room.timeline().await;
+
+    ev_builder.add_joined_room(
+        JoinedRoomBuilder::new(room_id)
+            .add_timeline_event(TimelineTestEvent::Custom(json!({
+                "content": {
+                    "body": "in a thread",
+                    "msgtype": "m.text",
+                    "m.relates_to": {
+                        "rel_type": "m.thread",
+                        "event_id": "$threadroot",
+                    },
+                },
+                "event_id": "$inthread",
+                "origin_server_ts": 152037280,
+                "sender": "@bob:localhost",
+                "type": "m.room.message",
+            })))
+            .add_timeline_event(TimelineTestEvent::Custom(json!({
+                "content": {
+                    "m.relates_to": {
+                        "event_id": "$inthread",
+                        "key": "👍",
+                        "rel_type": "m.annotation",
+                    },
+                },
+                "event_id": "$reaction",
+                "origin_server_ts": 152038300,
+                "sender": "@example:localhost",
+                "type": "m.reaction",
+            }))),
+    );
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    assert!(timeline.item_context(event_id!("$unknown")).await.unwrap().is_none());
+
+    let context = timeline.item_context(event_id!("$inthread")).await.unwrap().unwrap();
+    assert_eq!(context.item.event_id(), Some(event_id!("$inthread")));
+    assert_eq!(context.reactions.len(), 1);
+    assert_eq!(context.reactions["👍"], [user_id!("@example:localhost").to_owned()]);
+    assert_eq!(context.thread_root.as_deref(), Some(event_id!("$threadroot")));
+    assert_eq!(context.in_reply_to, None);
+    assert!(!context.is_edited);
+
+    // The event was sent by someone else, but we are allowed to redact it.
+    assert!(context.actions.can_reply);
+    assert!(context.actions.can_react);
+    assert!(!context.actions.can_edit);
+    assert!(context.actions.can_redact);
+
+    // We are the only member, and an admin.
+    let permalink = timeline.permalink(&context.item).await.unwrap().unwrap();
+    assert_matches!(permalink.id(), MatrixId::Event(room, event_id) => {
+        assert_eq!(room.as_str(), room_id.as_str());
+        assert_eq!(event_id.as_str(), "$inthread");
+    });
+    assert_eq!(permalink.via(), [owned_server_name!("localhost")]);
+}
+
+#[async_test]
+async fn edit_history() {
+    let room_id = room_id!("!a98sd12bjh:example.org");
+    let (client, server) = logged_in_client().await;
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_timeline_event(
+        TimelineTestEvent::Custom(json!({
+            "content": {
+                "body": "helo",
+                "msgtype": "m.text",
+            },
+            "event_id": "$original",
+            "origin_server_ts": 152037280,
+            "sender": "@alice:example.org",
+            "type": "m.room.message",
+        })),
+    ));
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_room(room_id).unwrap();
+    let timeline = room.timeline().await;
+    let item = timeline.item_by_event_id(event_id!("$original")).await.unwrap();
+
+    let edit = |event_id: &str, sender: &str, body: &str, ts: u64| {
+        json!({
+            "content": {
+                "body": format!(" * {body}"),
+                "m.new_content": {
+                    "body": body,
+                    "msgtype": "m.text",
+                },
+                "m.relates_to": {
+                    "event_id": "$original",
+                    "rel_type": "m.replace",
+                },
+                "msgtype": "m.text",
+            },
+            "event_id": event_id,
+            "origin_server_ts": ts,
+            "room_id": room_id,
+            "sender": sender,
+            "type": "m.room.message",
+        })
+    };
+
+    // The edits are returned in reverse chronological order, over two pages.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v1/rooms/.*/relations/.*/m\.replace$"))
+        .and(query_param("from", "page2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "chunk": [edit("$edit1", "@alice:example.org", "hello", 152037290)],
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v1/rooms/.*/relations/.*/m\.replace$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "chunk": [
+                edit("$edit3", "@alice:example.org", "hello!", 152037310),
+                edit("$edit2", "@bob:example.org", "goodbye", 152037300),
+            ],
+            "next_batch": "page2",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let versions = timeline.edit_history(&item).await.unwrap();
+    let versions: Vec<_> = versions
+        .iter()
+        .map(|version| (version.event_id.as_str(), version.msgtype.body()))
+        .collect();
+    assert_eq!(versions, [("$original", "helo"), ("$edit1", "hello"), ("$edit3", "hello!")]);
+}
+
 #[async_test]
 async fn sync_highlighted() {
     let room_id = room_id!("!a98sd12bjh:example.org");
@@ -25,7 +25,7 @@ use matrix_sdk_ui::timeline::{
 };
 use ruma::{
     events::{room::message::MessageType, FullStateEventContent},
-    room_id,
+    room_id, uint, MilliSecondsSinceUnixEpoch,
 };
 use serde_json::json;
 use wiremock::{
@@ -243,3 +243,108 @@ async fn back_pagination_highlighted() {
     // Removal of the loading indicator
     assert_matches!(timeline_stream.next().await, Some(VectorDiff::PopFront));
 }
+
+#[async_test]
+async fn jump_to_date() {
+    let room_id = room_id!("!a98sd12bjh:example.org");
+    let (client, server) = logged_in_client().await;
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id));
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_room(room_id).unwrap();
+    let timeline = Arc::new(room.timeline().await);
+    assert!(timeline.is_live());
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/.*/rooms/.*/timestamp_to_event$"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$focused",
+            "origin_server_ts": 152037280,
+        })))
+        .expect(1)
+        .named("timestamp_to_event")
+        .mount(&server)
+        .await;
+
+    let text_event = |event_id: &str, body: &str, ts: u64| {
+        json!({
+            "content": {
+                "body": body,
+                "msgtype": "m.text",
+            },
+            "event_id": event_id,
+            "origin_server_ts": ts,
+            "sender": "@example:localhost",
+            "type": "m.room.message",
+            "room_id": room_id,
+        })
+    };
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/context/.*$"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "events_before": [
+                text_event("$before2", "before 2", 152037270),
+                text_event("$before1", "before 1", 152037260),
+            ],
+            "event": text_event("$focused", "focused", 152037280),
+            "events_after": [
+                text_event("$after1", "after 1", 152037290),
+            ],
+            "start": "t392-516_47314_0_7_1_1_1_11444_1",
+            "end": "t47409-4357353_219380_26003_2269",
+            "state": [],
+        })))
+        .expect(1)
+        .named("context")
+        .mount(&server)
+        .await;
+
+    let event_id =
+        timeline.jump_to_date(MilliSecondsSinceUnixEpoch(uint!(152037285))).await.unwrap();
+    server.reset().await;
+
+    assert_eq!(event_id, "$focused");
+    assert!(!timeline.is_live());
+
+    let bodies: Vec<_> = timeline
+        .items()
+        .await
+        .iter()
+        .filter_map(|item| {
+            let TimelineItemContent::Message(msg) = item.as_event()?.content() else {
+                return None;
+            };
+            Some(msg.body().to_owned())
+        })
+        .collect();
+    assert_eq!(bodies, ["before 1", "before 2", "focused", "after 1"]);
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/messages$"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "chunk": [text_event("$after2", "after 2", 152037300)],
+            "start": "t47409-4357353_219380_26003_2269",
+        })))
+        .expect(1)
+        .named("messages_forward")
+        .mount(&server)
+        .await;
+
+    timeline.paginate_forwards(PaginationOptions::single_request(10)).await.unwrap();
+    server.reset().await;
+
+    let last = timeline.latest_event().await.unwrap();
+    assert_eq!(last.event_id().unwrap(), "$after2");
+    // There is no end token anymore, so the live end of the room was reached.
+    assert!(timeline.is_live());
+}
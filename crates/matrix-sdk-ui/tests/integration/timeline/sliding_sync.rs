@@ -21,7 +21,7 @@ use futures_util::{pin_mut, FutureExt, Stream, StreamExt};
 use matrix_sdk::{
     SlidingSync, SlidingSyncList, SlidingSyncListBuilder, SlidingSyncMode, UpdateSummary,
 };
-use matrix_sdk_test::async_test;
+use matrix_sdk_test::{async_test, SlidingSyncResponseBuilder, SlidingSyncRoomResponseBuilder};
 use matrix_sdk_ui::timeline::{SlidingSyncRoomExt, TimelineItem, VirtualTimelineItem};
 use ruma::{room_id, RoomId};
 use serde_json::json;
@@ -192,21 +192,10 @@ async fn create_one_room(
     room_id: &RoomId,
     room_name: String,
 ) -> Result<()> {
-    let update = receive_response!(
-        [server, stream]
-        {
-            "pos": "foo",
-            "lists": {},
-            "rooms": {
-                room_id: {
-                    "name": room_name,
-                    "initial": true,
-                    "timeline": [],
-                }
-            },
-            "extensions": {},
-        }
-    );
+    let response = SlidingSyncResponseBuilder::new()
+        .add_room(SlidingSyncRoomResponseBuilder::new(room_id).initial().name(&room_name))
+        .build_json_response();
+    let update = receive_response!([server, stream] response);
 
     assert!(update.rooms.contains(&room_id.to_owned()));
 
@@ -258,21 +247,13 @@ async fn test_timeline_basic() -> Result<()> {
 
     // Receiving a bunch of events.
     {
-        receive_response! {
-            [server, stream]
-            {
-                "pos": "1",
-                "lists": {},
-                "rooms": {
-                    room_id: {
-                        "timeline": [
-                            timeline_event!("$x1:bar.org" at 1 sec),
-                            timeline_event!("$x2:bar.org" at 2 sec),
-                        ]
-                    }
-                }
-            }
-        };
+        let response = SlidingSyncResponseBuilder::new()
+            .add_room(SlidingSyncRoomResponseBuilder::new(room_id).add_timeline_bulk([
+                timeline_event!("$x1:bar.org" at 1 sec),
+                timeline_event!("$x2:bar.org" at 2 sec),
+            ]))
+            .build_json_response();
+        receive_response!([server, stream] response);
 
         assert_timeline_stream! {
             [timeline_stream]
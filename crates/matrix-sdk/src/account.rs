@@ -14,6 +14,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::future::Future;
+
+use bytes::BufMut;
 use matrix_sdk_base::{
     media::{MediaFormat, MediaRequest},
     store::StateStoreExt,
@@ -21,19 +24,25 @@ use matrix_sdk_base::{
 };
 use mime::Mime;
 use ruma::{
-    api::client::{
-        account::{
-            add_3pid, change_password, deactivate, delete_3pid, get_3pids,
-            request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
-        },
-        config::set_global_account_data,
-        profile::{
-            get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
+    api::{
+        client::{
+            account::{
+                add_3pid, change_password, deactivate, delete_3pid, get_3pids,
+                request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
+            },
+            config::set_global_account_data,
+            profile::{
+                get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
+            },
+            uiaa::{AuthData, AuthType},
         },
-        uiaa::AuthData,
+        error::{FromHttpResponseError, IntoHttpError},
+        metadata, EndpointError, IncomingResponse, MatrixVersion, Metadata, OutgoingRequest,
+        SendAccessToken,
     },
     assign,
     events::{
+        direct::DirectEventContent,
         ignored_user_list::{IgnoredUser, IgnoredUserListEventContent},
         push_rules::PushRulesEventContent,
         room::MediaSource,
@@ -41,14 +50,17 @@ use ruma::{
         GlobalAccountDataEventType, StaticEventContent,
     },
     push::Ruleset,
-    serde::Raw,
+    serde::{json_to_buf, Raw},
     thirdparty::Medium,
-    ClientSecret, MxcUri, OwnedMxcUri, OwnedUserId, RoomId, SessionId, UInt, UserId,
+    ClientSecret, MxcUri, OwnedClientSecret, OwnedMxcUri, OwnedSessionId, OwnedUserId, RoomId,
+    SessionId, UInt, UserId,
 };
 use serde::Deserialize;
-use tracing::error;
+use serde_json::json;
+use tracing::{debug, error};
+use url::Url;
 
-use crate::{config::RequestConfig, Client, Error, HttpError, Result};
+use crate::{config::RequestConfig, uiaa::UiaaFlow, Client, Error, HttpError, Result};
 
 /// A high-level API to manage the client owner's account.
 ///
@@ -369,13 +381,104 @@ impl Account {
         id_server: Option<&str>,
         auth_data: Option<AuthData>,
     ) -> Result<deactivate::v3::Response> {
-        let request = assign!(deactivate::v3::Request::new(), {
+        let options = assign!(DeactivateOptions::new(), {
             id_server: id_server.map(ToOwned::to_owned),
+        });
+        self.deactivate_with_options(auth_data, options).await
+    }
+
+    /// Deactivate this account definitively, with the given options.
+    ///
+    /// This is like [`Account::deactivate()`], but it can also ask the
+    /// homeserver to erase the data of the account.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, DeactivateOptions};
+    /// # use matrix_sdk::ruma::assign;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// # let account = client.account();
+    /// let options = assign!(DeactivateOptions::new(), { erase: true });
+    /// let response = account.deactivate_with_options(None, options).await;
+    ///
+    /// // Proceed with UIAA.
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub async fn deactivate_with_options(
+        &self,
+        auth_data: Option<AuthData>,
+        options: DeactivateOptions,
+    ) -> Result<deactivate::v3::Response> {
+        let request = assign!(deactivate::v3::Request::new(), {
+            id_server: options.id_server,
             auth: auth_data,
+            erase: options.erase,
         });
         Ok(self.client.send(request, None).await?)
     }
 
+    /// Send a request that uses the [User-Interactive Authentication
+    /// API][uiaa], authenticating with the password of the account.
+    ///
+    /// The request is first sent without authentication data. If the
+    /// homeserver requires authentication and accepts the password alone, it
+    /// is sent again with the password. Otherwise the error of the first
+    /// request is returned, and the application needs to go through the other
+    /// stages itself, with a [`UiaaFlow`].
+    ///
+    /// # Arguments
+    ///
+    /// * `password` - The current password of the account.
+    ///
+    /// * `send` - A function sending the request with the given
+    /// authentication data, like [`Account::change_password()`],
+    /// [`Account::deactivate()`] or [`Account::add_3pid()`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let account = client.account();
+    ///
+    /// account
+    ///     .with_password_auth("current password", |auth_data| {
+    ///         account.change_password("new password", auth_data)
+    ///     })
+    ///     .await?;
+    /// # anyhow::Ok(()) };
+    /// ```
+    /// [uiaa]: https://spec.matrix.org/v1.2/client-server-api/#user-interactive-authentication-api
+    pub async fn with_password_auth<T, F, Fut>(&self, password: &str, send: F) -> Result<T>
+    where
+        F: Fn(Option<AuthData>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut flow = UiaaFlow::new(&self.client, send);
+        if let Some(response) = flow.send().await? {
+            return Ok(response);
+        }
+
+        // Only the flows that can be completed with the password alone are
+        // supported.
+        if !flow.remaining_stages().iter().any(|stages| stages == &[AuthType::Password]) {
+            debug!("The homeserver requires other authentication stages than the password");
+            return Err(flow.take_pending_error().unwrap_or(Error::InsufficientData));
+        }
+
+        match flow.complete_password(password).await? {
+            Some(response) => Ok(response),
+            None => Err(flow.take_pending_error().unwrap_or(Error::InsufficientData)),
+        }
+    }
+
     /// Get the registered [Third Party Identifiers][3pid] on the homeserver of
     /// the account.
     ///
@@ -555,6 +658,75 @@ impl Account {
         Ok(self.client.send(request, None).await?)
     }
 
+    /// Submit the token received by the user to validate a [Third Party
+    /// Identifier][3pid].
+    ///
+    /// This is only necessary when [`Account::request_3pid_email_token()`] or
+    /// [`Account::request_3pid_msisdn_token()`] returned a `submit_url`.
+    /// Otherwise the user submits the token directly to the server that sent
+    /// it. In both cases, call [`Account::add_3pid()`] next.
+    ///
+    /// # Arguments
+    ///
+    /// * `submit_url` - The `submit_url` returned with the session ID.
+    ///
+    /// * `client_secret` - The same client secret used to request the token.
+    ///
+    /// * `sid` - The session ID returned with the `submit_url`.
+    ///
+    /// * `token` - The token received by the user.
+    ///
+    /// # Returns
+    ///
+    /// Whether the token was valid.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use matrix_sdk::ruma::{ClientSecret, uint};
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// # let account = client.account();
+    /// # let secret = ClientSecret::parse("secret")?;
+    /// let token_response = account
+    ///     .request_3pid_msisdn_token(&secret, "FR", "0123456789", uint!(0))
+    ///     .await?;
+    ///
+    /// if let Some(submit_url) = &token_response.submit_url {
+    ///     // Prompt the user for the token they received by SMS.
+    ///     let token = "123456";
+    ///
+    ///     if !account
+    ///         .submit_3pid_token(submit_url, &secret, &token_response.sid, token)
+    ///         .await?
+    ///     {
+    ///         // Ask the user to try again.
+    ///     }
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    /// [3pid]: https://spec.matrix.org/v1.2/appendices/#3pid-types
+    pub async fn submit_3pid_token(
+        &self,
+        submit_url: &str,
+        client_secret: &ClientSecret,
+        sid: &SessionId,
+        token: &str,
+    ) -> Result<bool> {
+        let url = Url::parse(submit_url).map_err(|error| Error::UnknownError(error.into()))?;
+        let request = SubmitTokenRequest {
+            url,
+            client_secret: client_secret.to_owned(),
+            sid: sid.to_owned(),
+            token: token.to_owned(),
+        };
+
+        Ok(self.client.send(request, None).await?.success)
+    }
+
     /// Add a [Third Party Identifier][3pid] on the homeserver for this
     /// account.
     ///
@@ -765,11 +937,30 @@ impl Account {
         room_id: &RoomId,
         user_ids: &[OwnedUserId],
     ) -> Result<()> {
-        use ruma::events::direct::DirectEventContent;
+        // Now we need to mark the room as a DM for ourselves, we append the
+        // room to the list of DMs we have with this user.
+        self.update_direct_rooms(|content| {
+            for user_id in user_ids {
+                let rooms = content.entry(user_id.to_owned()).or_default();
+                if !rooms.iter().any(|id| id == room_id) {
+                    rooms.push(room_id.to_owned());
+                }
+            }
+        })
+        .await
+    }
+
+    /// Update the `m.direct` account data event with the given function.
+    ///
+    /// The updates are serialized, so concurrent updates don't overwrite each
+    /// other, and the new content is saved locally without waiting for the
+    /// next sync.
+    pub(crate) async fn update_direct_rooms(
+        &self,
+        update: impl FnOnce(&mut DirectEventContent),
+    ) -> Result<()> {
+        let _guard = self.client.inner.direct_rooms_lock.lock().await;
 
-        // Now we need to mark the room as a DM for ourselves, we fetch the
-        // existing `m.direct` event and append the room to the list of DMs we
-        // have with this user.
         let mut content = self
             .account_data::<DirectEventContent>()
             .await?
@@ -777,14 +968,15 @@ impl Account {
             .transpose()?
             .unwrap_or_default();
 
-        for user_id in user_ids {
-            content.entry(user_id.to_owned()).or_default().push(room_id.to_owned());
-        }
+        update(&mut content);
 
         // TODO We should probably save the fact that we need to send this out
         // because otherwise we might end up in a state where we have a DM that
         // isn't marked as one.
-        self.set_account_data(content).await?;
+        self.set_account_data(content.clone()).await?;
+
+        let event = Raw::new(&json!({ "type": DirectEventContent::TYPE, "content": content }))?;
+        self.client.base_client().receive_account_data(&[event.cast()]).await?;
 
         Ok(())
     }
@@ -848,6 +1040,105 @@ impl Account {
     }
 }
 
+/// Options for [`Account::deactivate_with_options()`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct DeactivateOptions {
+    /// The identity server from which to unbind the user’s [Third Party
+    /// Identifiers][3pid].
+    ///
+    /// [3pid]: https://spec.matrix.org/v1.2/appendices/#3pid-types
+    pub id_server: Option<String>,
+
+    /// Whether the homeserver should also erase the data of the account, like
+    /// the messages it sent, as far as possible.
+    ///
+    /// Default: `false`.
+    pub erase: bool,
+}
+
+impl DeactivateOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Request to submit a token to validate a 3PID at the `submit_url` returned
+/// by the homeserver.
+///
+/// The URL is not known in advance and doesn't have to be on the homeserver,
+/// so the metadata path is only a placeholder and the URL is used as is.
+#[derive(Clone, Debug)]
+struct SubmitTokenRequest {
+    url: Url,
+    client_secret: OwnedClientSecret,
+    sid: OwnedSessionId,
+    token: String,
+}
+
+impl OutgoingRequest for SubmitTokenRequest {
+    type EndpointError = ruma::api::client::Error;
+    type IncomingResponse = SubmitTokenResponse;
+
+    const METADATA: Metadata = metadata! {
+        method: POST,
+        rate_limited: false,
+        authentication: None,
+        history: {
+            unstable => "/_matrix/client/unstable/submit_token",
+        }
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        _base_url: &str,
+        _access_token: SendAccessToken<'_>,
+        _considering_versions: &[MatrixVersion],
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let body = json!({
+            "client_secret": self.client_secret,
+            "sid": self.sid,
+            "token": self.token,
+        });
+
+        Ok(http::Request::builder()
+            .method(Self::METADATA.method)
+            .uri(self.url.as_str())
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(json_to_buf(&body)?)?)
+    }
+}
+
+/// Response to a [`SubmitTokenRequest`].
+#[derive(Clone, Debug)]
+struct SubmitTokenResponse {
+    /// Whether the validation was successful.
+    success: bool,
+}
+
+impl IncomingResponse for SubmitTokenResponse {
+    type EndpointError = ruma::api::client::Error;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<Self::EndpointError>> {
+        #[derive(Deserialize)]
+        struct ResponseBody {
+            success: bool,
+        }
+
+        if response.status().as_u16() >= 400 {
+            return Err(FromHttpResponseError::Server(
+                ruma::api::client::Error::from_http_response(response),
+            ));
+        }
+
+        let body: ResponseBody = serde_json::from_slice(response.body().as_ref())?;
+        Ok(Self { success: body.success })
+    }
+}
+
 fn get_raw_content<Ev, C>(raw: Option<Raw<Ev>>) -> Result<Option<Raw<C>>> {
     #[derive(Deserialize)]
     #[serde(bound = "C: Sized")] // Replace default Deserialize bound
@@ -19,7 +19,7 @@ use std::io::{BufRead, Cursor, Seek};
 use std::time::Duration;
 
 #[cfg(feature = "image-proc")]
-use image::GenericImageView;
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
 use ruma::{
     assign,
     events::room::{
@@ -183,6 +183,8 @@ pub struct AttachmentConfig {
     pub(crate) generate_thumbnail: bool,
     #[cfg(feature = "image-proc")]
     pub(crate) thumbnail_size: Option<(u32, u32)>,
+    #[cfg(feature = "image-proc")]
+    pub(crate) send_policy: Option<AttachmentSendPolicy>,
 }
 
 impl AttachmentConfig {
@@ -198,6 +200,8 @@ impl AttachmentConfig {
             generate_thumbnail: Default::default(),
             #[cfg(feature = "image-proc")]
             thumbnail_size: Default::default(),
+            #[cfg(feature = "image-proc")]
+            send_policy: Default::default(),
         }
     }
 
@@ -240,9 +244,24 @@ impl AttachmentConfig {
             generate_thumbnail: Default::default(),
             #[cfg(feature = "image-proc")]
             thumbnail_size: Default::default(),
+            #[cfg(feature = "image-proc")]
+            send_policy: Default::default(),
         }
     }
 
+    /// Resize, compress or convert the image before uploading it, according to
+    /// the given policy.
+    ///
+    /// The policy is only applied to the images in a supported format, the
+    /// other attachments are sent unchanged. The thumbnail is generated from
+    /// the processed image.
+    #[cfg(feature = "image-proc")]
+    #[must_use]
+    pub fn send_policy(mut self, policy: AttachmentSendPolicy) -> Self {
+        self.send_policy = Some(policy);
+        self
+    }
+
     /// Set the transaction ID to send.
     ///
     /// # Arguments
@@ -275,6 +294,256 @@ impl Default for AttachmentConfig {
     }
 }
 
+/// The JPEG quality used when an image is re-encoded.
+#[cfg(feature = "image-proc")]
+const JPEG_QUALITY: u8 = 85;
+
+/// The lowest JPEG quality used to reach the maximum size of an image.
+#[cfg(feature = "image-proc")]
+const MIN_JPEG_QUALITY: u8 = 45;
+
+/// The size under which an image is not downscaled anymore to reach its
+/// maximum size.
+#[cfg(feature = "image-proc")]
+const MIN_DOWNSCALED_DIMENSION: u32 = 64;
+
+/// A policy to resize, compress or convert the images before uploading them.
+///
+/// Images taken by the camera of a phone are usually much bigger than needed
+/// to be displayed in a chat, so it's common to resize them before sending
+/// them to save bandwidth and storage.
+///
+/// An image that already respects the policy is sent unchanged. When an image
+/// is re-encoded, its EXIF data is dropped, so its orientation is applied
+/// first.
+#[cfg(feature = "image-proc")]
+#[derive(Debug, Clone, Default)]
+pub struct AttachmentSendPolicy {
+    max_dimensions: Option<(u32, u32)>,
+    max_size: Option<usize>,
+    convert_to: Option<mime::Mime>,
+}
+
+#[cfg(feature = "image-proc")]
+impl AttachmentSendPolicy {
+    /// Create a policy that doesn't change the images.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy suitable for mobile clients: the images are downscaled to fit
+    /// in 2048×2048 pixels, and compressed to at most 1 MiB if possible.
+    pub fn recommended() -> Self {
+        Self::new().max_dimensions(2048, 2048).max_size(1024 * 1024)
+    }
+
+    /// Downscale the images that are bigger than the given dimensions, in
+    /// pixels, keeping their aspect ratio.
+    #[must_use]
+    pub fn max_dimensions(mut self, width: u32, height: u32) -> Self {
+        self.max_dimensions = Some((width, height));
+        self
+    }
+
+    /// Compress the images that are bigger than the given size, in bytes.
+    ///
+    /// The quality of JPEG images is lowered first, then the images are
+    /// downscaled until they are small enough. This is a best effort: a tiny
+    /// image might still be bigger than the maximum size.
+    #[must_use]
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Convert the images to the format of the given content type, e.g.
+    /// [`mime::IMAGE_JPEG`].
+    ///
+    /// The extension of the file name used as the body of the message is
+    /// replaced with the one of the new format.
+    #[must_use]
+    pub fn convert_to(mut self, content_type: mime::Mime) -> Self {
+        self.convert_to = Some(content_type);
+        self
+    }
+
+    /// Whether this policy can be applied to images of the given content type.
+    pub(crate) fn supports(&self, content_type: &mime::Mime) -> bool {
+        ImageFormat::from_mime_type(content_type).is_some()
+            && self
+                .convert_to
+                .as_ref()
+                .map_or(true, |target| ImageFormat::from_mime_type(target).is_some())
+    }
+
+    /// Apply this policy to the given image.
+    ///
+    /// Returns [`ImageError::FormatNotSupported`] if the format of the image,
+    /// or the format to convert it to, is not supported.
+    pub fn apply(
+        &self,
+        content_type: &mime::Mime,
+        data: Vec<u8>,
+    ) -> Result<ProcessedImage, ImageError> {
+        let format =
+            ImageFormat::from_mime_type(content_type).ok_or(ImageError::FormatNotSupported)?;
+        let (target_content_type, target_format) = match &self.convert_to {
+            Some(target) => (
+                target.clone(),
+                ImageFormat::from_mime_type(target).ok_or(ImageError::FormatNotSupported)?,
+            ),
+            None => (content_type.clone(), format),
+        };
+
+        // The EXIF data is lost when the image is re-encoded, so its
+        // orientation must be applied to the pixels.
+        let mut image =
+            apply_exif_orientation(image::load_from_memory_with_format(&data, format)?, &data);
+        let original_size = data.len();
+        let original_dimensions = image.dimensions();
+        let unchanged = |data| ProcessedImage {
+            data,
+            content_type: content_type.clone(),
+            report: ImageProcessingReport {
+                original_size,
+                sent_size: original_size,
+                original_dimensions,
+                sent_dimensions: original_dimensions,
+            },
+        };
+
+        let resize = self.max_dimensions.filter(|(max_width, max_height)| {
+            original_dimensions.0 > *max_width || original_dimensions.1 > *max_height
+        });
+        let compress = self.max_size.is_some_and(|max_size| original_size > max_size);
+
+        if resize.is_none() && !compress && target_format == format {
+            return Ok(unchanged(data));
+        }
+
+        if let Some((max_width, max_height)) = resize {
+            image = image.resize(max_width, max_height, FilterType::Lanczos3);
+        }
+        if target_format == ImageFormat::Jpeg {
+            // JPEG doesn't support transparency.
+            image = DynamicImage::ImageRgb8(image.to_rgb8());
+        }
+
+        let mut quality = JPEG_QUALITY;
+        let mut encoded = encode_image(&image, target_format, quality)?;
+
+        if let Some(max_size) = self.max_size {
+            while encoded.len() > max_size {
+                let (width, height) = image.dimensions();
+
+                if target_format == ImageFormat::Jpeg && quality > MIN_JPEG_QUALITY {
+                    quality -= 10;
+                } else if width > MIN_DOWNSCALED_DIMENSION || height > MIN_DOWNSCALED_DIMENSION {
+                    image = image.resize(width * 3 / 4, height * 3 / 4, FilterType::Triangle);
+                } else {
+                    break;
+                }
+
+                encoded = encode_image(&image, target_format, quality)?;
+            }
+        }
+
+        // Re-encoding the image didn't make it any smaller.
+        if resize.is_none() && target_format == format && encoded.len() >= original_size {
+            return Ok(unchanged(data));
+        }
+
+        let sent_size = encoded.len();
+        Ok(ProcessedImage {
+            data: encoded,
+            content_type: target_content_type,
+            report: ImageProcessingReport {
+                original_size,
+                sent_size,
+                original_dimensions,
+                sent_dimensions: image.dimensions(),
+            },
+        })
+    }
+}
+
+/// Rotate and flip the given image according to the EXIF orientation tag of
+/// its raw bytes, if any.
+#[cfg(feature = "image-proc")]
+fn apply_exif_orientation(image: DynamicImage, data: &[u8]) -> DynamicImage {
+    let orientation =
+        exif::Reader::new().read_from_container(&mut Cursor::new(data)).ok().and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)
+        });
+
+    match orientation {
+        Some(2) => image.fliph(),
+        Some(3) => image.rotate180(),
+        Some(4) => image.flipv(),
+        Some(5) => image.rotate90().fliph(),
+        Some(6) => image.rotate90(),
+        Some(7) => image.rotate270().fliph(),
+        Some(8) => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Replace the extension of the given file name with the one of the given
+/// content type.
+///
+/// The file name is returned unchanged if it doesn't have an extension, or if
+/// the extension of the content type is unknown.
+#[cfg(feature = "image-proc")]
+pub(crate) fn replace_extension(file_name: &str, content_type: &mime::Mime) -> String {
+    match (file_name.rsplit_once('.'), mime2ext::mime2ext(content_type)) {
+        (Some((stem, _)), Some(extension)) if !stem.is_empty() => format!("{stem}.{extension}"),
+        _ => file_name.to_owned(),
+    }
+}
+
+#[cfg(feature = "image-proc")]
+fn encode_image(
+    image: &DynamicImage,
+    format: ImageFormat,
+    jpeg_quality: u8,
+) -> Result<Vec<u8>, ImageError> {
+    let output_format = match format {
+        ImageFormat::Jpeg => ImageOutputFormat::Jpeg(jpeg_quality),
+        format => format.into(),
+    };
+
+    let mut data = vec![];
+    image.write_to(&mut Cursor::new(&mut data), output_format)?;
+    Ok(data)
+}
+
+/// An image processed with an [`AttachmentSendPolicy`].
+#[cfg(feature = "image-proc")]
+#[derive(Debug)]
+pub struct ProcessedImage {
+    /// The raw bytes of the image to send.
+    pub data: Vec<u8>,
+    /// The type of the image to send.
+    pub content_type: mime::Mime,
+    /// The changes made to the image.
+    pub report: ImageProcessingReport,
+}
+
+/// The changes made to an image by an [`AttachmentSendPolicy`].
+#[cfg(feature = "image-proc")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageProcessingReport {
+    /// The size of the original image, in bytes.
+    pub original_size: usize,
+    /// The size of the sent image, in bytes.
+    pub sent_size: usize,
+    /// The `(width, height)` of the original image, in pixels, once its EXIF
+    /// orientation is applied.
+    pub original_dimensions: (u32, u32),
+    /// The `(width, height)` of the sent image, in pixels.
+    pub sent_dimensions: (u32, u32),
+}
+
 /// Generate a thumbnail for an image.
 ///
 /// This is a convenience method that uses the
@@ -369,3 +638,82 @@ pub fn generate_image_thumbnail<R: BufRead + Seek>(
         },
     ))
 }
+
+#[cfg(all(test, feature = "image-proc"))]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+
+    use super::{replace_extension, AttachmentSendPolicy};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = vec![];
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn send_policy_downscales_and_converts() {
+        let data = png(400, 300);
+        let policy =
+            AttachmentSendPolicy::new().max_dimensions(200, 200).convert_to(mime::IMAGE_JPEG);
+
+        let processed = policy.apply(&mime::IMAGE_PNG, data.clone()).unwrap();
+        assert_eq!(processed.content_type, mime::IMAGE_JPEG);
+        assert_eq!(processed.report.original_size, data.len());
+        assert_eq!(processed.report.sent_size, processed.data.len());
+        assert_eq!(processed.report.original_dimensions, (400, 300));
+        assert_eq!(processed.report.sent_dimensions, (200, 150));
+    }
+
+    #[test]
+    fn send_policy_keeps_small_images() {
+        let data = png(100, 50);
+        let policy = AttachmentSendPolicy::recommended();
+
+        let processed = policy.apply(&mime::IMAGE_PNG, data.clone()).unwrap();
+        assert_eq!(processed.content_type, mime::IMAGE_PNG);
+        assert_eq!(processed.data, data);
+        assert_eq!(processed.report.sent_dimensions, (100, 50));
+    }
+
+    #[test]
+    fn send_policy_applies_exif_orientation() {
+        let mut jpeg = vec![];
+        DynamicImage::ImageRgb8(RgbImage::new(400, 300))
+            .write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(85))
+            .unwrap();
+
+        // Insert an EXIF segment with the orientation "rotate 90° clockwise"
+        // after the start of image marker.
+        let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+        // A single entry in the IFD: the orientation tag, a short with the
+        // value 6.
+        tiff.extend_from_slice(&[0x00, 0x01]);
+        tiff.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1, 0x00, 0x06, 0, 0]);
+        // No next IFD.
+        tiff.extend_from_slice(&[0; 4]);
+        let len = u16::try_from(2 + 6 + tiff.len()).unwrap();
+        let mut data = jpeg[..2].to_vec();
+        data.extend_from_slice(&[0xff, 0xe1]);
+        data.extend_from_slice(&len.to_be_bytes());
+        data.extend_from_slice(b"Exif\0\0");
+        data.extend_from_slice(&tiff);
+        data.extend_from_slice(&jpeg[2..]);
+
+        let policy = AttachmentSendPolicy::new().max_dimensions(200, 200);
+        let processed = policy.apply(&mime::IMAGE_JPEG, data).unwrap();
+        assert_eq!(processed.report.original_dimensions, (300, 400));
+        assert_eq!(processed.report.sent_dimensions, (150, 200));
+    }
+
+    #[test]
+    fn replace_file_name_extension() {
+        assert_eq!(replace_extension("photo.jpg", &mime::IMAGE_PNG), "photo.png");
+        assert_eq!(replace_extension("My photo", &mime::IMAGE_PNG), "My photo");
+        assert_eq!(replace_extension(".hidden", &mime::IMAGE_PNG), ".hidden");
+    }
+}
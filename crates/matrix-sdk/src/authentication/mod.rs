@@ -0,0 +1,165 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ways to authenticate a device with the help of another one.
+//!
+//! The login methods of [`Client`] authenticate a device with the credentials
+//! of the user. The methods of [`Auth`] let a device that is already logged in
+//! authenticate a new one instead.
+
+use std::time::Duration;
+
+use bytes::BufMut;
+use ruma::{
+    api::{
+        client::uiaa::{AuthData, UiaaResponse},
+        error::{FromHttpResponseError, IntoHttpError},
+        metadata, EndpointError, IncomingResponse, MatrixVersion, Metadata, OutgoingRequest,
+        SendAccessToken,
+    },
+    serde::json_to_buf,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{Client, HttpResult};
+
+#[cfg(feature = "experimental-qr-login")]
+pub mod qrcode;
+
+/// A high-level API to authenticate new devices of the user.
+///
+/// To get this, use [`Client::auth()`].
+#[derive(Debug, Clone)]
+pub struct Auth {
+    client: Client,
+}
+
+impl Auth {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Get a single-use token that another device can use to log in as the
+    /// current user, with [`Client::login_token()`].
+    ///
+    /// Homeservers usually require User-Interactive Authentication for this
+    /// request, see [`UiaaFlow`](crate::uiaa::UiaaFlow) to go through it.
+    ///
+    /// # Arguments
+    ///
+    /// * `auth_data` - The authentication data of the current stage of the
+    ///   User-Interactive Authentication, if any.
+    pub async fn get_login_token(&self, auth_data: Option<AuthData>) -> HttpResult<LoginToken> {
+        let response = self.client.send(GetLoginTokenRequest { auth: auth_data }, None).await?;
+
+        Ok(LoginToken {
+            token: response.login_token,
+            expires_in: Duration::from_millis(response.expires_in_ms),
+        })
+    }
+}
+
+/// A single-use token to log in as the current user, returned by
+/// [`Auth::get_login_token()`].
+#[derive(Clone)]
+pub struct LoginToken {
+    /// The token to pass to [`Client::login_token()`].
+    pub token: String,
+    /// The time after which the token can't be used anymore.
+    pub expires_in: Duration,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl std::fmt::Debug for LoginToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoginToken").field("expires_in", &self.expires_in).finish_non_exhaustive()
+    }
+}
+
+/// Request to get a login token, defined in [MSC3882] and released in
+/// Matrix 1.7.
+///
+/// The path of the stable endpoint is used as is, because the Matrix version
+/// that released it isn't known to the metadata.
+///
+/// [MSC3882]: https://github.com/matrix-org/matrix-spec-proposals/pull/3882
+#[derive(Clone, Debug)]
+struct GetLoginTokenRequest {
+    auth: Option<AuthData>,
+}
+
+impl OutgoingRequest for GetLoginTokenRequest {
+    type EndpointError = UiaaResponse;
+    type IncomingResponse = GetLoginTokenResponse;
+
+    const METADATA: Metadata = metadata! {
+        method: POST,
+        rate_limited: true,
+        authentication: AccessToken,
+        history: {
+            unstable => "/_matrix/client/unstable/org.matrix.msc3882/login/get_token",
+        }
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+        _considering_versions: &[MatrixVersion],
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let access_token =
+            access_token.get_required_for_endpoint().ok_or(IntoHttpError::NeedsAuthentication)?;
+        let url = format!("{}/_matrix/client/v1/login/get_token", base_url.trim_end_matches('/'));
+        let body = match self.auth {
+            Some(auth) => json!({ "auth": auth }),
+            None => json!({}),
+        };
+
+        Ok(http::Request::builder()
+            .method(Self::METADATA.method)
+            .uri(url)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, format!("Bearer {access_token}"))
+            .body(json_to_buf(&body)?)?)
+    }
+}
+
+/// Response to a [`GetLoginTokenRequest`].
+#[derive(Clone, Debug)]
+struct GetLoginTokenResponse {
+    login_token: String,
+    expires_in_ms: u64,
+}
+
+impl IncomingResponse for GetLoginTokenResponse {
+    type EndpointError = UiaaResponse;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<Self::EndpointError>> {
+        #[derive(Deserialize)]
+        struct ResponseBody {
+            login_token: String,
+            expires_in_ms: u64,
+        }
+
+        if response.status().as_u16() >= 400 {
+            return Err(FromHttpResponseError::Server(UiaaResponse::from_http_response(response)));
+        }
+
+        let body: ResponseBody = serde_json::from_slice(response.body().as_ref())?;
+        Ok(Self { login_token: body.login_token, expires_in_ms: body.expires_in_ms })
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    future::{Future, IntoFuture},
+    pin::Pin,
+};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use ruma::{api::client::uiaa::AuthData, OwnedDeviceId};
+use tracing::warn;
+
+use super::{
+    login::send_failure,
+    messages::{
+        BackupSecrets, CrossSigningSecrets, LoginFailureReason, LoginProtocolType, QrAuthMessage,
+        SecretsBundle, BACKUP_ALGORITHM,
+    },
+    secure_channel::{EstablishedSecureChannel, EstablishmentProgress},
+    CheckCode, CheckCodeSender, QrCodeData, QrCodeGrantLoginError, QrCodeMode,
+};
+use crate::{Client, Error};
+
+/// The progress of the login of a new device, granted with a QR code by a
+/// device that is already logged in.
+#[derive(Clone, Debug, Default)]
+pub enum GrantLoginProgress {
+    /// The grant is starting.
+    #[default]
+    Starting,
+    /// The QR code was generated, and must be scanned by the new device.
+    QrCodeGenerated(QrCodeData),
+    /// The QR code of the new device was scanned, and the check code must be
+    /// entered on the new device.
+    EstablishingSecureChannel {
+        /// The check code to display.
+        check_code: CheckCode,
+    },
+    /// The check code displayed by the new device must be entered on this
+    /// one, and sent with the [`CheckCodeSender`].
+    WaitingForCheckCode(CheckCodeSender),
+    /// The new device is logging in with the token that was sent to it.
+    WaitingForLogin {
+        /// The ID of the new device.
+        device_id: OwnedDeviceId,
+    },
+    /// The secrets were sent to the new device, which is now logged in.
+    Done,
+}
+
+/// Future returned by [`Auth::grant_login_with_qr()`] and
+/// [`Auth::grant_login_with_generated_qr()`].
+///
+/// [`Auth::grant_login_with_qr()`]: crate::authentication::Auth::grant_login_with_qr
+/// [`Auth::grant_login_with_generated_qr()`]: crate::authentication::Auth::grant_login_with_generated_qr
+#[derive(Debug)]
+pub struct GrantLoginWithQrCode {
+    client: Client,
+    scanned: Option<QrCodeData>,
+    auth_data: Option<AuthData>,
+    progress: SharedObservable<GrantLoginProgress>,
+}
+
+impl GrantLoginWithQrCode {
+    pub(crate) fn new(client: Client, scanned: Option<QrCodeData>) -> Self {
+        Self { client, scanned, auth_data: None, progress: Default::default() }
+    }
+
+    /// Set the authentication data of the User-Interactive Authentication
+    /// that the homeserver requires to get a login token.
+    ///
+    /// See [`Auth::get_login_token()`](crate::authentication::Auth::get_login_token).
+    pub fn auth_data(mut self, auth_data: AuthData) -> Self {
+        self.auth_data = Some(auth_data);
+        self
+    }
+
+    /// Subscribe to the progress of the grant.
+    ///
+    /// The QR code to display and the check code to enter are given by the
+    /// progress, so this must be called before the future is awaited.
+    pub fn subscribe_to_progress(&self) -> Subscriber<GrantLoginProgress> {
+        self.progress.subscribe()
+    }
+}
+
+impl IntoFuture for GrantLoginWithQrCode {
+    type Output = Result<(), QrCodeGrantLoginError>;
+    #[cfg(target_arch = "wasm32")]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output>>>;
+    #[cfg(not(target_arch = "wasm32"))]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let Self { client, scanned, auth_data, progress } = self;
+        Box::pin(async move {
+            let user_id = client.user_id().ok_or(Error::AuthenticationRequired)?.to_owned();
+
+            // Make sure that the new device can be verified before starting.
+            let cross_signing = {
+                let olm = client.olm_machine().await;
+                let olm = olm.as_ref().ok_or(Error::AuthenticationRequired)?;
+                olm.export_cross_signing_keys()
+                    .await
+                    .as_ref()
+                    .and_then(CrossSigningSecrets::from_export)
+                    .ok_or(QrCodeGrantLoginError::MissingSecrets)?
+            };
+
+            let mode = match &scanned {
+                Some(qr_code_data) => {
+                    if qr_code_data.mode != QrCodeMode::Login {
+                        return Err(QrCodeGrantLoginError::UnexpectedQrCodeMode);
+                    }
+
+                    QrCodeMode::Login
+                }
+                None => QrCodeMode::Reciprocate { server_name: user_id.server_name().to_string() },
+            };
+
+            let mut channel =
+                EstablishedSecureChannel::establish(&client, scanned.as_ref(), mode, |p| {
+                    progress.set(match p {
+                        EstablishmentProgress::QrCodeGenerated(data) => {
+                            GrantLoginProgress::QrCodeGenerated(data)
+                        }
+                        EstablishmentProgress::CheckCodeGenerated(check_code) => {
+                            GrantLoginProgress::EstablishingSecureChannel { check_code }
+                        }
+                        EstablishmentProgress::WaitingForCheckCode(sender) => {
+                            GrantLoginProgress::WaitingForCheckCode(sender)
+                        }
+                    });
+                })
+                .await?;
+
+            channel
+                .send_json(&QrAuthMessage::LoginProtocols {
+                    protocols: vec![LoginProtocolType::LoginToken],
+                    homeserver: client.homeserver().await,
+                })
+                .await?;
+
+            let device_id = match channel.receive_json().await? {
+                QrAuthMessage::LoginProtocol {
+                    protocol: LoginProtocolType::LoginToken,
+                    device_id,
+                } => device_id,
+                QrAuthMessage::LoginProtocol { .. } => {
+                    send_failure(&mut channel, LoginFailureReason::UnsupportedProtocol).await;
+                    return Err(QrCodeGrantLoginError::UnsupportedProtocol);
+                }
+                QrAuthMessage::LoginFailure { reason } => {
+                    return Err(QrCodeGrantLoginError::LoginFailure { reason })
+                }
+                message => return Err(unexpected_message(&mut channel, message).await),
+            };
+
+            // A new device with the ID of an existing one would take over its keys.
+            if device_exists(&client, &device_id).await? {
+                send_failure(&mut channel, LoginFailureReason::DeviceAlreadyExists).await;
+                return Err(QrCodeGrantLoginError::DeviceIdAlreadyInUse(device_id));
+            }
+
+            let login_token = match client.auth().get_login_token(auth_data).await {
+                Ok(login_token) => login_token,
+                Err(error) => {
+                    if let Err(error) = channel.send_json(&QrAuthMessage::LoginDeclined).await {
+                        warn!(?error, "Couldn't decline the login of the other device");
+                    }
+                    return Err(error.into());
+                }
+            };
+
+            channel
+                .send_json(&QrAuthMessage::LoginProtocolAccepted { login_token: login_token.token })
+                .await?;
+
+            progress.set(GrantLoginProgress::WaitingForLogin { device_id: device_id.clone() });
+
+            match channel.receive_json().await? {
+                QrAuthMessage::LoginSuccess => {}
+                QrAuthMessage::LoginFailure { reason } => {
+                    return Err(QrCodeGrantLoginError::LoginFailure { reason })
+                }
+                message => return Err(unexpected_message(&mut channel, message).await),
+            }
+
+            if !device_exists(&client, &device_id).await? {
+                send_failure(&mut channel, LoginFailureReason::DeviceNotFound).await;
+                return Err(QrCodeGrantLoginError::DeviceNotFound(device_id));
+            }
+
+            let backup = {
+                let olm = client.olm_machine().await;
+                let olm = olm.as_ref().ok_or(Error::AuthenticationRequired)?;
+                let backup_keys = olm.backup_machine().get_backup_keys().await?;
+
+                backup_keys.recovery_key.zip(backup_keys.backup_version).map(
+                    |(recovery_key, backup_version)| BackupSecrets {
+                        algorithm: BACKUP_ALGORITHM.to_owned(),
+                        key: recovery_key.to_base64(),
+                        backup_version,
+                    },
+                )
+            };
+
+            channel
+                .send_json(&QrAuthMessage::LoginSecrets(SecretsBundle { cross_signing, backup }))
+                .await?;
+
+            progress.set(GrantLoginProgress::Done);
+
+            Ok(())
+        })
+    }
+}
+
+async fn device_exists(
+    client: &Client,
+    device_id: &OwnedDeviceId,
+) -> Result<bool, QrCodeGrantLoginError> {
+    let response = client.devices().await?;
+    Ok(response.devices.iter().any(|device| device.device_id == *device_id))
+}
+
+async fn unexpected_message(
+    channel: &mut EstablishedSecureChannel,
+    message: QrAuthMessage,
+) -> QrCodeGrantLoginError {
+    send_failure(channel, LoginFailureReason::UnexpectedMessageReceived).await;
+    QrCodeGrantLoginError::UnexpectedMessage(message.message_type())
+}
@@ -0,0 +1,250 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    future::{Future, IntoFuture},
+    pin::Pin,
+};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use matrix_sdk_base::crypto::store::RecoveryKey;
+use ruma::DeviceId;
+use tracing::{info, warn};
+
+use super::{
+    messages::{LoginFailureReason, LoginProtocolType, QrAuthMessage, SecretsBundle},
+    secure_channel::{EstablishedSecureChannel, EstablishmentProgress},
+    CheckCode, CheckCodeSender, QrCodeData, QrCodeLoginError, QrCodeMode,
+};
+use crate::{Client, Error};
+
+/// The progress of the login of a new device with a QR code.
+#[derive(Clone, Debug, Default)]
+pub enum LoginProgress {
+    /// The login is starting.
+    #[default]
+    Starting,
+    /// The QR code was generated, and must be scanned by a device that is
+    /// already logged in.
+    QrCodeGenerated(QrCodeData),
+    /// The QR code of the other device was scanned, and the check code must be
+    /// entered on the other device.
+    EstablishingSecureChannel {
+        /// The check code to display.
+        check_code: CheckCode,
+    },
+    /// The check code displayed by the other device must be entered on this
+    /// one, and sent with the [`CheckCodeSender`].
+    WaitingForCheckCode(CheckCodeSender),
+    /// The secure channel is established, the other device must authorize the
+    /// login.
+    WaitingForToken,
+    /// The device is logged in, and is importing the secrets of the other
+    /// device to verify itself.
+    SyncingSecrets,
+    /// The device is logged in and verified.
+    Done,
+}
+
+/// Future returned by [`Auth::login_with_qr()`] and
+/// [`Auth::login_with_generated_qr()`].
+///
+/// [`Auth::login_with_qr()`]: crate::authentication::Auth::login_with_qr
+/// [`Auth::login_with_generated_qr()`]: crate::authentication::Auth::login_with_generated_qr
+#[derive(Debug)]
+pub struct LoginWithQrCode {
+    client: Client,
+    scanned: Option<QrCodeData>,
+    progress: SharedObservable<LoginProgress>,
+}
+
+impl LoginWithQrCode {
+    pub(crate) fn new(client: Client, scanned: Option<QrCodeData>) -> Self {
+        Self { client, scanned, progress: Default::default() }
+    }
+
+    /// Subscribe to the progress of the login.
+    ///
+    /// The QR code to display and the check code to enter are given by the
+    /// progress, so this must be called before the future is awaited.
+    pub fn subscribe_to_progress(&self) -> Subscriber<LoginProgress> {
+        self.progress.subscribe()
+    }
+}
+
+impl IntoFuture for LoginWithQrCode {
+    type Output = Result<(), QrCodeLoginError>;
+    #[cfg(target_arch = "wasm32")]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output>>>;
+    #[cfg(not(target_arch = "wasm32"))]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let Self { client, scanned, progress } = self;
+        Box::pin(async move {
+            if client.logged_in() {
+                return Err(QrCodeLoginError::AlreadyLoggedIn);
+            }
+
+            if let Some(qr_code_data) = &scanned {
+                if !matches!(qr_code_data.mode, QrCodeMode::Reciprocate { .. }) {
+                    return Err(QrCodeLoginError::UnexpectedQrCodeMode);
+                }
+            }
+
+            let mut channel = EstablishedSecureChannel::establish(
+                &client,
+                scanned.as_ref(),
+                QrCodeMode::Login,
+                |p| {
+                    progress.set(match p {
+                        EstablishmentProgress::QrCodeGenerated(data) => {
+                            LoginProgress::QrCodeGenerated(data)
+                        }
+                        EstablishmentProgress::CheckCodeGenerated(check_code) => {
+                            LoginProgress::EstablishingSecureChannel { check_code }
+                        }
+                        EstablishmentProgress::WaitingForCheckCode(sender) => {
+                            LoginProgress::WaitingForCheckCode(sender)
+                        }
+                    });
+                },
+            )
+            .await?;
+
+            let homeserver = match channel.receive_json().await? {
+                QrAuthMessage::LoginProtocols { protocols, homeserver } => {
+                    if !protocols.contains(&LoginProtocolType::LoginToken) {
+                        send_failure(&mut channel, LoginFailureReason::UnsupportedProtocol).await;
+                        return Err(QrCodeLoginError::UnsupportedProtocol);
+                    }
+
+                    homeserver
+                }
+                message => return Err(unexpected_message(&mut channel, message).await),
+            };
+
+            if homeserver != client.homeserver().await {
+                info!(%homeserver, "Using the homeserver of the other device");
+                client.set_homeserver(homeserver).await;
+            }
+
+            let device_id = DeviceId::new();
+            channel
+                .send_json(&QrAuthMessage::LoginProtocol {
+                    protocol: LoginProtocolType::LoginToken,
+                    device_id: device_id.clone(),
+                })
+                .await?;
+
+            progress.set(LoginProgress::WaitingForToken);
+
+            let login_token = match channel.receive_json().await? {
+                QrAuthMessage::LoginProtocolAccepted { login_token } => login_token,
+                QrAuthMessage::LoginDeclined => return Err(QrCodeLoginError::LoginDeclined),
+                QrAuthMessage::LoginFailure { reason } => {
+                    return Err(QrCodeLoginError::LoginFailure { reason })
+                }
+                message => return Err(unexpected_message(&mut channel, message).await),
+            };
+
+            client.login_token(&login_token).device_id(device_id.as_str()).send().await?;
+            channel.send_json(&QrAuthMessage::LoginSuccess).await?;
+
+            progress.set(LoginProgress::SyncingSecrets);
+
+            let secrets = match channel.receive_json().await? {
+                QrAuthMessage::LoginSecrets(secrets) => secrets,
+                QrAuthMessage::LoginFailure { reason } => {
+                    return Err(QrCodeLoginError::LoginFailure { reason })
+                }
+                message => return Err(unexpected_message(&mut channel, message).await),
+            };
+
+            import_secrets(&client, secrets).await?;
+
+            progress.set(LoginProgress::Done);
+
+            Ok(())
+        })
+    }
+}
+
+/// Import the secrets of the other device, and sign the new device with the
+/// self-signing key.
+async fn import_secrets(client: &Client, secrets: SecretsBundle) -> Result<(), QrCodeLoginError> {
+    let user_id = client.user_id().ok_or(Error::AuthenticationRequired)?.to_owned();
+    let device_id = client.device_id().ok_or(Error::AuthenticationRequired)?.to_owned();
+
+    {
+        let olm = client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::AuthenticationRequired)?;
+        olm.update_tracked_users([user_id.as_ref()]).await?;
+    }
+
+    // Upload the keys of the new device, and get the public cross-signing keys
+    // of the user to check the private ones against them.
+    client.send_outgoing_requests().await?;
+
+    {
+        let olm = client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::AuthenticationRequired)?;
+
+        let status = olm.import_cross_signing_keys(secrets.cross_signing.to_export()).await?;
+        if !(status.has_master && status.has_self_signing && status.has_user_signing) {
+            return Err(QrCodeLoginError::InvalidSecrets);
+        }
+
+        if let Some(backup) = &secrets.backup {
+            if backup.algorithm == super::messages::BACKUP_ALGORITHM {
+                let recovery_key = RecoveryKey::from_base64(&backup.key)
+                    .map_err(|_| QrCodeLoginError::InvalidSecrets)?;
+                olm.backup_machine()
+                    .save_recovery_key(Some(recovery_key), Some(backup.backup_version.clone()))
+                    .await?;
+            } else {
+                warn!(
+                    algorithm = backup.algorithm.as_str(),
+                    "Ignoring the key of an unsupported backup"
+                );
+            }
+        }
+    }
+
+    let device = client
+        .encryption()
+        .get_device(&user_id, &device_id)
+        .await?
+        .ok_or(QrCodeLoginError::InvalidSecrets)?;
+    device.verify().await?;
+
+    Ok(())
+}
+
+pub(super) async fn send_failure(
+    channel: &mut EstablishedSecureChannel,
+    reason: LoginFailureReason,
+) {
+    if let Err(error) = channel.send_json(&QrAuthMessage::LoginFailure { reason }).await {
+        warn!(?error, "Couldn't report the failure of the login to the other device");
+    }
+}
+
+async fn unexpected_message(
+    channel: &mut EstablishedSecureChannel,
+    message: QrAuthMessage,
+) -> QrCodeLoginError {
+    send_failure(channel, LoginFailureReason::UnexpectedMessageReceived).await;
+    QrCodeLoginError::UnexpectedMessage(message.message_type())
+}
@@ -0,0 +1,239 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use matrix_sdk_base::crypto::store::CrossSigningKeyExport;
+use ruma::OwnedDeviceId;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use zeroize::Zeroize;
+
+/// The algorithm of the backups whose key can be sent to the new device.
+pub(super) const BACKUP_ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+
+/// The messages exchanged over the secure channel once it is established.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub(super) enum QrAuthMessage {
+    /// Sent by the existing device, with the login protocols it supports.
+    #[serde(rename = "m.login.protocols")]
+    LoginProtocols { protocols: Vec<LoginProtocolType>, homeserver: Url },
+
+    /// Sent by the new device, with the protocol it chose and the ID it will
+    /// use for its device.
+    #[serde(rename = "m.login.protocol")]
+    LoginProtocol { protocol: LoginProtocolType, device_id: OwnedDeviceId },
+
+    /// Sent by the existing device, with the token the new device logs in
+    /// with.
+    #[serde(rename = "m.login.protocol_accepted")]
+    LoginProtocolAccepted { login_token: String },
+
+    /// Sent by the new device once it is logged in.
+    #[serde(rename = "m.login.success")]
+    LoginSuccess,
+
+    /// Sent by the existing device if it doesn't allow the login.
+    #[serde(rename = "m.login.declined")]
+    LoginDeclined,
+
+    /// Sent by either device when the login fails.
+    #[serde(rename = "m.login.failure")]
+    LoginFailure { reason: LoginFailureReason },
+
+    /// Sent by the existing device, with the secrets the new device needs to
+    /// verify itself.
+    #[serde(rename = "m.login.secrets")]
+    LoginSecrets(SecretsBundle),
+}
+
+impl QrAuthMessage {
+    /// The type of the message, to report unexpected messages without
+    /// logging their content.
+    pub(super) fn message_type(&self) -> &'static str {
+        match self {
+            Self::LoginProtocols { .. } => "m.login.protocols",
+            Self::LoginProtocol { .. } => "m.login.protocol",
+            Self::LoginProtocolAccepted { .. } => "m.login.protocol_accepted",
+            Self::LoginSuccess => "m.login.success",
+            Self::LoginDeclined => "m.login.declined",
+            Self::LoginFailure { .. } => "m.login.failure",
+            Self::LoginSecrets(_) => "m.login.secrets",
+        }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for QrAuthMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QrAuthMessage").field("type", &self.message_type()).finish_non_exhaustive()
+    }
+}
+
+/// The protocols the new device can log in with.
+///
+/// [MSC4108] logs in with the OAuth 2.0 device authorization grant, which
+/// isn't supported by the client. The new device logs in with a single-use
+/// login token minted by the existing device instead, as defined in
+/// [MSC3882].
+///
+/// [MSC4108]: https://github.com/matrix-org/matrix-spec-proposals/pull/4108
+/// [MSC3882]: https://github.com/matrix-org/matrix-spec-proposals/pull/3882
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(super) enum LoginProtocolType {
+    #[serde(rename = "login_token")]
+    LoginToken,
+    #[serde(other)]
+    Unsupported,
+}
+
+/// The reason of a failure of the login, reported by one of the devices.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum LoginFailureReason {
+    /// The ID chosen by the new device is already used by another device of
+    /// the user.
+    DeviceAlreadyExists,
+    /// The new device wasn't found after it logged in.
+    DeviceNotFound,
+    /// A message was received at a point of the login where it wasn't
+    /// expected.
+    UnexpectedMessageReceived,
+    /// None of the login protocols supported by the existing device is
+    /// supported by the new device.
+    UnsupportedProtocol,
+    /// The user cancelled the login.
+    UserCancelled,
+    /// A reason that isn't known to the client.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The secrets that the existing device sends to the new one.
+#[derive(Deserialize, Serialize)]
+pub(super) struct SecretsBundle {
+    pub(super) cross_signing: CrossSigningSecrets,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) backup: Option<BackupSecrets>,
+}
+
+/// The private cross-signing keys, as unpadded base64.
+#[derive(Deserialize, Serialize)]
+pub(super) struct CrossSigningSecrets {
+    pub(super) master_key: String,
+    pub(super) self_signing_key: String,
+    pub(super) user_signing_key: String,
+}
+
+impl CrossSigningSecrets {
+    /// Get the keys if all of them are in the export.
+    pub(super) fn from_export(export: &CrossSigningKeyExport) -> Option<Self> {
+        Some(Self {
+            master_key: export.master_key.clone()?,
+            self_signing_key: export.self_signing_key.clone()?,
+            user_signing_key: export.user_signing_key.clone()?,
+        })
+    }
+
+    pub(super) fn to_export(&self) -> CrossSigningKeyExport {
+        CrossSigningKeyExport {
+            master_key: Some(self.master_key.clone()),
+            self_signing_key: Some(self.self_signing_key.clone()),
+            user_signing_key: Some(self.user_signing_key.clone()),
+        }
+    }
+}
+
+impl Drop for CrossSigningSecrets {
+    fn drop(&mut self) {
+        self.master_key.zeroize();
+        self.self_signing_key.zeroize();
+        self.user_signing_key.zeroize();
+    }
+}
+
+/// The key of the current server-side key backup, as unpadded base64.
+#[derive(Deserialize, Serialize)]
+pub(super) struct BackupSecrets {
+    pub(super) algorithm: String,
+    pub(super) key: String,
+    pub(super) backup_version: String,
+}
+
+impl Drop for BackupSecrets {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use ruma::device_id;
+    use serde_json::json;
+
+    use super::{LoginFailureReason, LoginProtocolType, QrAuthMessage};
+
+    #[test]
+    fn serialization() {
+        let message = QrAuthMessage::LoginProtocol {
+            protocol: LoginProtocolType::LoginToken,
+            device_id: device_id!("NEWDEVICE").to_owned(),
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({
+                "type": "m.login.protocol",
+                "protocol": "login_token",
+                "device_id": "NEWDEVICE",
+            })
+        );
+
+        assert_eq!(
+            serde_json::to_value(&QrAuthMessage::LoginSuccess).unwrap(),
+            json!({ "type": "m.login.success" })
+        );
+    }
+
+    #[test]
+    fn unknown_values_are_deserialized() {
+        let message: QrAuthMessage = serde_json::from_value(json!({
+            "type": "m.login.protocols",
+            "protocols": ["device_authorization_grant", "login_token"],
+            "homeserver": "https://matrix.example.org",
+        }))
+        .unwrap();
+        assert_matches!(
+            message,
+            QrAuthMessage::LoginProtocols { protocols, .. } => {
+                assert_eq!(
+                    protocols,
+                    [LoginProtocolType::Unsupported, LoginProtocolType::LoginToken]
+                );
+            }
+        );
+
+        let message: QrAuthMessage = serde_json::from_value(json!({
+            "type": "m.login.failure",
+            "reason": "authorization_expired",
+        }))
+        .unwrap();
+        assert_matches!(
+            message,
+            QrAuthMessage::LoginFailure { reason: LoginFailureReason::Unknown }
+        );
+    }
+}
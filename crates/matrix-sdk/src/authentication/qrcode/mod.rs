@@ -0,0 +1,243 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log in a new device by scanning a QR code, as described in [MSC4108].
+//!
+//! One of the devices displays a QR code with an ephemeral key and the URL of
+//! a rendezvous session, and the other one scans it. The devices establish a
+//! secure channel through the rendezvous session, and the user makes sure that
+//! it wasn't intercepted by entering the check code displayed by the device
+//! that scanned the QR code on the other device.
+//!
+//! The device that is already logged in then sends a login token to the new
+//! device, and once the new device is logged in, the private cross-signing
+//! keys and the key of the server-side key backup, so that the new device can
+//! verify itself.
+//!
+//! Either device can generate the QR code:
+//!
+//! * the new device with [`Auth::login_with_generated_qr()`], and the existing
+//!   device then uses [`Auth::grant_login_with_qr()`],
+//! * the existing device with [`Auth::grant_login_with_generated_qr()`], and
+//!   the new device then uses [`Auth::login_with_qr()`].
+//!
+//! [MSC4108]: https://github.com/matrix-org/matrix-spec-proposals/pull/4108
+
+use http::StatusCode;
+use matrix_sdk_base::crypto::{CryptoStoreError, SecretImportError};
+use ruma::OwnedDeviceId;
+use thiserror::Error;
+
+use super::Auth;
+use crate::{encryption::identities::ManualVerifyError, HttpError};
+
+mod grant;
+mod login;
+mod messages;
+mod qr_code_data;
+mod rendezvous_channel;
+mod secure_channel;
+
+pub use self::{
+    grant::{GrantLoginProgress, GrantLoginWithQrCode},
+    login::{LoginProgress, LoginWithQrCode},
+    messages::LoginFailureReason,
+    qr_code_data::{QrCodeData, QrCodeDecodeError, QrCodeMode},
+    secure_channel::{CheckCode, CheckCodeSender},
+};
+
+impl Auth {
+    /// Log in this device with the QR code displayed by a device of the user
+    /// that is already logged in.
+    ///
+    /// The QR code must have been generated with
+    /// [`Auth::grant_login_with_generated_qr()`].
+    pub fn login_with_qr(&self, qr_code_data: &QrCodeData) -> LoginWithQrCode {
+        LoginWithQrCode::new(self.client.clone(), Some(qr_code_data.clone()))
+    }
+
+    /// Log in this device by generating a QR code, to scan with a device of
+    /// the user that is already logged in.
+    ///
+    /// The QR code is given by the [`LoginProgress::QrCodeGenerated`]
+    /// progress, and must be scanned with [`Auth::grant_login_with_qr()`].
+    pub fn login_with_generated_qr(&self) -> LoginWithQrCode {
+        LoginWithQrCode::new(self.client.clone(), None)
+    }
+
+    /// Log in a new device of the user with the QR code it displays.
+    ///
+    /// The QR code must have been generated with
+    /// [`Auth::login_with_generated_qr()`].
+    pub fn grant_login_with_qr(&self, qr_code_data: &QrCodeData) -> GrantLoginWithQrCode {
+        GrantLoginWithQrCode::new(self.client.clone(), Some(qr_code_data.clone()))
+    }
+
+    /// Log in a new device of the user by generating a QR code for it to scan.
+    ///
+    /// The QR code is given by the [`GrantLoginProgress::QrCodeGenerated`]
+    /// progress, and must be scanned with [`Auth::login_with_qr()`].
+    pub fn grant_login_with_generated_qr(&self) -> GrantLoginWithQrCode {
+        GrantLoginWithQrCode::new(self.client.clone(), None)
+    }
+}
+
+/// An error when establishing or using the secure channel between the
+/// devices.
+#[derive(Debug, Error)]
+pub enum SecureChannelError {
+    /// The request to the rendezvous session failed.
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    /// The rendezvous session responded with an unexpected status code.
+    #[error("the rendezvous session responded with the status code {0}")]
+    RendezvousStatus(StatusCode),
+
+    /// The rendezvous session responded with an invalid response.
+    #[error("the rendezvous session responded with an invalid response")]
+    InvalidRendezvousResponse,
+
+    /// The other device didn't follow the handshake of the secure channel.
+    #[error("the other device didn't follow the handshake of the secure channel")]
+    InvalidHandshake,
+
+    /// A message couldn't be decrypted, it was either tampered with or not
+    /// sent by the other device.
+    #[error("a message of the secure channel couldn't be decrypted")]
+    InvalidMessage,
+
+    /// The check code wasn't sent before the login was aborted.
+    #[error("the check code wasn't sent")]
+    CheckCodeNotSent,
+
+    /// The check code entered by the user doesn't match the one of the other
+    /// device, the secure channel might have been intercepted.
+    #[error("the check code doesn't match the one of the other device")]
+    InvalidCheckCode,
+
+    /// A message couldn't be serialized or deserialized.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// An error when logging in this device with a QR code.
+#[derive(Debug, Error)]
+pub enum QrCodeLoginError {
+    /// The client is already logged in.
+    #[error("the client is already logged in")]
+    AlreadyLoggedIn,
+
+    /// The scanned QR code wasn't generated by a device that is logged in.
+    #[error("the QR code wasn't generated by a device that is logged in")]
+    UnexpectedQrCodeMode,
+
+    /// The other device doesn't support a login protocol supported by this
+    /// device.
+    #[error("the other device doesn't support a login protocol supported by this device")]
+    UnsupportedProtocol,
+
+    /// The other device declined the login.
+    #[error("the other device declined the login")]
+    LoginDeclined,
+
+    /// The other device reported a failure of the login.
+    #[error("the other device reported a failure of the login: {reason:?}")]
+    LoginFailure {
+        /// The reason of the failure.
+        reason: LoginFailureReason,
+    },
+
+    /// The other device sent a message that wasn't expected.
+    #[error("the other device sent an unexpected {0} message")]
+    UnexpectedMessage(&'static str),
+
+    /// The secrets sent by the other device don't match the public keys of
+    /// the user.
+    #[error("the secrets sent by the other device are invalid")]
+    InvalidSecrets,
+
+    /// The secure channel failed.
+    #[error(transparent)]
+    SecureChannel(#[from] SecureChannelError),
+
+    /// The login failed.
+    #[error(transparent)]
+    Sdk(#[from] crate::Error),
+
+    /// The crypto store failed.
+    #[error(transparent)]
+    CryptoStore(#[from] CryptoStoreError),
+
+    /// The cross-signing keys couldn't be imported.
+    #[error(transparent)]
+    SecretImport(#[from] SecretImportError),
+
+    /// The new device couldn't be signed.
+    #[error(transparent)]
+    Verification(#[from] ManualVerifyError),
+}
+
+/// An error when logging in a new device with a QR code.
+#[derive(Debug, Error)]
+pub enum QrCodeGrantLoginError {
+    /// This device doesn't have all the private cross-signing keys, so it
+    /// can't let the new device verify itself.
+    #[error("the private cross-signing keys are missing")]
+    MissingSecrets,
+
+    /// The scanned QR code wasn't generated by a new device.
+    #[error("the QR code wasn't generated by a new device")]
+    UnexpectedQrCodeMode,
+
+    /// The new device chose a login protocol that isn't supported.
+    #[error("the new device chose a login protocol that isn't supported")]
+    UnsupportedProtocol,
+
+    /// The ID chosen by the new device is already used by another device of
+    /// the user.
+    #[error("the device ID {0} is already in use")]
+    DeviceIdAlreadyInUse(OwnedDeviceId),
+
+    /// The new device reported that it logged in, but it wasn't found.
+    #[error("the new device {0} wasn't found after it logged in")]
+    DeviceNotFound(OwnedDeviceId),
+
+    /// The new device reported a failure of the login.
+    #[error("the new device reported a failure of the login: {reason:?}")]
+    LoginFailure {
+        /// The reason of the failure.
+        reason: LoginFailureReason,
+    },
+
+    /// The new device sent a message that wasn't expected.
+    #[error("the new device sent an unexpected {0} message")]
+    UnexpectedMessage(&'static str),
+
+    /// The secure channel failed.
+    #[error(transparent)]
+    SecureChannel(#[from] SecureChannelError),
+
+    /// A request to the homeserver failed.
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    /// The client isn't logged in.
+    #[error(transparent)]
+    Sdk(#[from] crate::Error),
+
+    /// The crypto store failed.
+    #[error(transparent)]
+    CryptoStore(#[from] CryptoStoreError),
+}
@@ -0,0 +1,224 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use thiserror::Error;
+use url::Url;
+use vodozemac::Curve25519PublicKey;
+
+const PREFIX: &[u8] = b"MATRIX";
+const VERSION: u8 = 0x02;
+const LOGIN_MODE: u8 = 0x03;
+const RECIPROCATE_MODE: u8 = 0x04;
+
+/// The device that generated a [`QrCodeData`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QrCodeMode {
+    /// The QR code was generated by the new device, and must be scanned by a
+    /// device that is already logged in.
+    Login,
+
+    /// The QR code was generated by a device that is already logged in, and
+    /// must be scanned by the new device.
+    Reciprocate {
+        /// The name of the homeserver of the user.
+        server_name: String,
+    },
+}
+
+/// The data encoded in the QR codes used to log in a new device.
+///
+/// The binary format is the one defined in [MSC4108]: the `MATRIX` prefix,
+/// the version, the mode, the public key, the rendezvous URL and, in the
+/// [`QrCodeMode::Reciprocate`] mode, the server name. The strings are
+/// prefixed with their length, as a big-endian `u16`.
+///
+/// [MSC4108]: https://github.com/matrix-org/matrix-spec-proposals/pull/4108
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QrCodeData {
+    /// The ephemeral Curve25519 key of the device that generated the QR code,
+    /// used to establish the secure channel.
+    pub public_key: Curve25519PublicKey,
+    /// The URL of the rendezvous session where the devices exchange their
+    /// messages.
+    pub rendezvous_url: Url,
+    /// The device that generated the QR code.
+    pub mode: QrCodeMode,
+}
+
+impl QrCodeData {
+    /// Decode the bytes of a scanned QR code.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, QrCodeDecodeError> {
+        let mut reader = Reader(bytes);
+
+        if reader.take(PREFIX.len())? != PREFIX {
+            return Err(QrCodeDecodeError::InvalidPrefix);
+        }
+
+        let version = reader.take_u8()?;
+        if version != VERSION {
+            return Err(QrCodeDecodeError::UnsupportedVersion(version));
+        }
+
+        let mode = reader.take_u8()?;
+        let public_key = Curve25519PublicKey::from_slice(reader.take(Curve25519PublicKey::LENGTH)?)
+            .map_err(|_| QrCodeDecodeError::Truncated)?;
+        let rendezvous_url = Url::parse(&reader.take_string()?)?;
+
+        let mode = match mode {
+            LOGIN_MODE => QrCodeMode::Login,
+            RECIPROCATE_MODE => QrCodeMode::Reciprocate { server_name: reader.take_string()? },
+            mode => return Err(QrCodeDecodeError::UnsupportedMode(mode)),
+        };
+
+        Ok(Self { public_key, rendezvous_url, mode })
+    }
+
+    /// Encode the data, to generate a QR code with it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn push_string(bytes: &mut Vec<u8>, string: &str) {
+            let length = u16::try_from(string.len()).expect("the string should fit in a QR code");
+            bytes.extend_from_slice(&length.to_be_bytes());
+            bytes.extend_from_slice(string.as_bytes());
+        }
+
+        let mut bytes = PREFIX.to_vec();
+        bytes.push(VERSION);
+        bytes.push(match self.mode {
+            QrCodeMode::Login => LOGIN_MODE,
+            QrCodeMode::Reciprocate { .. } => RECIPROCATE_MODE,
+        });
+        bytes.extend_from_slice(self.public_key.as_bytes());
+        push_string(&mut bytes, self.rendezvous_url.as_str());
+
+        if let QrCodeMode::Reciprocate { server_name } = &self.mode {
+            push_string(&mut bytes, server_name);
+        }
+
+        bytes
+    }
+}
+
+/// An error when decoding the bytes of a QR code into a [`QrCodeData`].
+#[derive(Debug, Error)]
+pub enum QrCodeDecodeError {
+    /// The QR code doesn't start with the `MATRIX` prefix, it wasn't generated
+    /// to log in a device.
+    #[error("the QR code doesn't start with the MATRIX prefix")]
+    InvalidPrefix,
+
+    /// The QR code uses a version of the format that isn't supported.
+    #[error("the version {0} of the QR code format isn't supported")]
+    UnsupportedVersion(u8),
+
+    /// The QR code uses a mode that isn't supported.
+    #[error("the mode {0} of the QR code isn't supported")]
+    UnsupportedMode(u8),
+
+    /// The QR code ends before all its data was read.
+    #[error("the QR code is truncated")]
+    Truncated,
+
+    /// A string of the QR code isn't valid UTF-8.
+    #[error("the QR code contains an invalid string")]
+    InvalidString,
+
+    /// The rendezvous URL of the QR code isn't valid.
+    #[error(transparent)]
+    InvalidUrl(#[from] url::ParseError),
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], QrCodeDecodeError> {
+        if self.0.len() < length {
+            return Err(QrCodeDecodeError::Truncated);
+        }
+
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+
+        Ok(taken)
+    }
+
+    fn take_u8(&mut self) -> Result<u8, QrCodeDecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take_string(&mut self) -> Result<String, QrCodeDecodeError> {
+        let length = self.take(2)?;
+        let length = u16::from_be_bytes([length[0], length[1]]);
+
+        String::from_utf8(self.take(length.into())?.to_vec())
+            .map_err(|_| QrCodeDecodeError::InvalidString)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use url::Url;
+    use vodozemac::{Curve25519PublicKey, Curve25519SecretKey};
+
+    use super::{QrCodeData, QrCodeDecodeError, QrCodeMode};
+
+    fn qr_code_data(mode: QrCodeMode) -> QrCodeData {
+        QrCodeData {
+            public_key: Curve25519PublicKey::from(&Curve25519SecretKey::new()),
+            rendezvous_url: Url::parse("https://rendezvous.lab.example/abcdEFG12345").unwrap(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let login = qr_code_data(QrCodeMode::Login);
+        assert_eq!(QrCodeData::from_bytes(&login.to_bytes()).unwrap(), login);
+
+        let reciprocate =
+            qr_code_data(QrCodeMode::Reciprocate { server_name: "matrix.org".to_owned() });
+        assert_eq!(QrCodeData::from_bytes(&reciprocate.to_bytes()).unwrap(), reciprocate);
+    }
+
+    #[test]
+    fn invalid_data() {
+        let bytes = qr_code_data(QrCodeMode::Login).to_bytes();
+
+        let mut wrong_prefix = bytes.clone();
+        wrong_prefix[0] = b'N';
+        assert_matches!(
+            QrCodeData::from_bytes(&wrong_prefix),
+            Err(QrCodeDecodeError::InvalidPrefix)
+        );
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[6] = 0x01;
+        assert_matches!(
+            QrCodeData::from_bytes(&wrong_version),
+            Err(QrCodeDecodeError::UnsupportedVersion(0x01))
+        );
+
+        let mut wrong_mode = bytes.clone();
+        wrong_mode[7] = 0x00;
+        assert_matches!(
+            QrCodeData::from_bytes(&wrong_mode),
+            Err(QrCodeDecodeError::UnsupportedMode(0x00))
+        );
+
+        assert_matches!(
+            QrCodeData::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(QrCodeDecodeError::Truncated)
+        );
+    }
+}
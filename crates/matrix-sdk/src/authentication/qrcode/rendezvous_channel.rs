@@ -0,0 +1,148 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use http::{
+    header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH},
+    StatusCode,
+};
+use matrix_sdk_common::sleep::sleep;
+use serde::Deserialize;
+use tracing::debug;
+use url::Url;
+
+use super::SecureChannelError;
+
+const TEXT_PLAIN: &str = "text/plain";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A rendezvous session of [MSC4108], where two devices exchange messages.
+///
+/// A message is sent by replacing the content of the session, and received by
+/// polling the session until its `ETag` changes. The protocol relies on
+/// conditional requests and not on the Matrix API, so the requests are sent
+/// with the underlying HTTP client directly.
+///
+/// [MSC4108]: https://github.com/matrix-org/matrix-spec-proposals/pull/4108
+pub(super) struct RendezvousChannel {
+    client: reqwest::Client,
+    rendezvous_url: Url,
+    etag: String,
+}
+
+impl RendezvousChannel {
+    /// Create a new rendezvous session on the given homeserver.
+    pub(super) async fn create_outbound(
+        client: reqwest::Client,
+        homeserver: &Url,
+    ) -> Result<Self, SecureChannelError> {
+        #[derive(Deserialize)]
+        struct CreateSessionResponse {
+            url: Url,
+        }
+
+        let url = homeserver
+            .join("/_matrix/client/unstable/org.matrix.msc4108/rendezvous")
+            .map_err(|_| SecureChannelError::InvalidRendezvousResponse)?;
+        let response = client.post(url).header(CONTENT_TYPE, TEXT_PLAIN).send().await?;
+
+        if !response.status().is_success() {
+            return Err(SecureChannelError::RendezvousStatus(response.status()));
+        }
+
+        let etag = etag(&response)?;
+        let response: CreateSessionResponse = serde_json::from_slice(&response.bytes().await?)?;
+
+        Ok(Self { client, rendezvous_url: response.url, etag })
+    }
+
+    /// Join the rendezvous session at the given URL.
+    pub(super) async fn create_inbound(
+        client: reqwest::Client,
+        rendezvous_url: &Url,
+    ) -> Result<Self, SecureChannelError> {
+        let response = client.get(rendezvous_url.clone()).send().await?;
+
+        if !response.status().is_success() {
+            return Err(SecureChannelError::RendezvousStatus(response.status()));
+        }
+
+        let etag = etag(&response)?;
+
+        Ok(Self { client, rendezvous_url: rendezvous_url.clone(), etag })
+    }
+
+    /// The URL of the rendezvous session.
+    pub(super) fn rendezvous_url(&self) -> &Url {
+        &self.rendezvous_url
+    }
+
+    /// Send a message to the other device.
+    pub(super) async fn send(&mut self, message: String) -> Result<(), SecureChannelError> {
+        let response = self
+            .client
+            .put(self.rendezvous_url.clone())
+            .header(IF_MATCH, &self.etag)
+            .header(CONTENT_TYPE, TEXT_PLAIN)
+            .body(message)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(SecureChannelError::RendezvousStatus(response.status()));
+        }
+
+        self.etag = etag(&response)?;
+
+        Ok(())
+    }
+
+    /// Wait for the next message of the other device.
+    pub(super) async fn receive(&mut self) -> Result<String, SecureChannelError> {
+        loop {
+            let response = self
+                .client
+                .get(self.rendezvous_url.clone())
+                .header(IF_NONE_MATCH, &self.etag)
+                .send()
+                .await?;
+
+            match response.status() {
+                StatusCode::NOT_MODIFIED => {
+                    debug!("No new message in the rendezvous session, polling again");
+                    sleep(POLL_INTERVAL).await;
+                }
+                status if status.is_success() => {
+                    self.etag = etag(&response)?;
+                    let message = response.text().await?;
+
+                    if !message.is_empty() {
+                        return Ok(message);
+                    }
+                }
+                status => return Err(SecureChannelError::RendezvousStatus(status)),
+            }
+        }
+    }
+}
+
+fn etag(response: &reqwest::Response) -> Result<String, SecureChannelError> {
+    response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(ToOwned::to_owned)
+        .ok_or(SecureChannelError::InvalidRendezvousResponse)
+}
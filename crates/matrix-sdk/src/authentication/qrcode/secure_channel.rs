@@ -0,0 +1,335 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex as StdMutex};
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use tokio::sync::oneshot;
+use vodozemac::{Curve25519PublicKey, Curve25519SecretKey};
+use zeroize::Zeroizing;
+
+use super::{rendezvous_channel::RendezvousChannel, QrCodeData, QrCodeMode, SecureChannelError};
+use crate::Client;
+
+const APP_INFO: &str = "MATRIX_QR_CODE_LOGIN";
+const CHECK_CODE_APP_INFO: &str = "MATRIX_QR_CODE_LOGIN_CHECKCODE";
+const LOGIN_INITIATE_MESSAGE: &str = "MATRIX_QR_CODE_LOGIN_INITIATE";
+const LOGIN_OK_MESSAGE: &str = "MATRIX_QR_CODE_LOGIN_OK";
+
+/// A code derived from the keys of the secure channel, that the user compares
+/// on both devices to make sure that the channel wasn't intercepted.
+///
+/// The device that scanned the QR code displays the code, and the user enters
+/// it on the device that generated the QR code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckCode([u8; 2]);
+
+impl CheckCode {
+    /// The code as a number between 0 and 99, to display with two digits.
+    pub fn to_digit(self) -> u8 {
+        (self.0[0] % 10) * 10 + self.0[1] % 10
+    }
+}
+
+/// The sender of the check code that the user entered on the device that
+/// generated the QR code.
+#[derive(Clone, Debug)]
+pub struct CheckCodeSender {
+    inner: Arc<StdMutex<Option<oneshot::Sender<u8>>>>,
+}
+
+impl CheckCodeSender {
+    fn new() -> (Self, oneshot::Receiver<u8>) {
+        let (sender, receiver) = oneshot::channel();
+        (Self { inner: Arc::new(StdMutex::new(Some(sender))) }, receiver)
+    }
+
+    /// Send the check code displayed by the other device, as entered by the
+    /// user.
+    ///
+    /// Returns `false` if a check code was already sent, or if the login was
+    /// aborted.
+    pub fn send(&self, check_code: u8) -> bool {
+        match self.inner.lock().unwrap().take() {
+            Some(sender) => sender.send(check_code).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// The progress of the establishment of the secure channel, reported by the
+/// progress of the login and of the grant.
+pub(super) enum EstablishmentProgress {
+    /// The QR code was generated, and must be scanned by the other device.
+    QrCodeGenerated(QrCodeData),
+    /// The QR code was scanned, the check code must be entered on the other
+    /// device.
+    CheckCodeGenerated(CheckCode),
+    /// The check code displayed by the other device must be entered on this
+    /// one.
+    WaitingForCheckCode(CheckCodeSender),
+}
+
+/// A channel where the messages are encrypted with keys derived from the
+/// ephemeral key in the QR code, and from the one of the device that scanned
+/// it.
+///
+/// The keys are derived with HKDF-SHA256 from the X25519 shared secret, and
+/// each direction of the channel has its own ChaCha20-Poly1305 key, with a
+/// counter as nonce.
+pub(super) struct EstablishedSecureChannel {
+    channel: RendezvousChannel,
+    cipher: Cipher,
+}
+
+impl EstablishedSecureChannel {
+    /// Establish the secure channel, either by scanning the QR code of the
+    /// other device, or by generating a QR code in the given `mode`.
+    pub(super) async fn establish(
+        client: &Client,
+        scanned: Option<&QrCodeData>,
+        mode: QrCodeMode,
+        on_progress: impl Fn(EstablishmentProgress),
+    ) -> Result<Self, SecureChannelError> {
+        let http_client = client.inner.http_client.inner.clone();
+
+        match scanned {
+            Some(qr_code_data) => {
+                let mut channel =
+                    RendezvousChannel::create_inbound(http_client, &qr_code_data.rendezvous_url)
+                        .await?;
+
+                let secret_key = Curve25519SecretKey::new();
+                let public_key = Curve25519PublicKey::from(&secret_key);
+                let (mut cipher, check_code) =
+                    Cipher::new(&secret_key, public_key, qr_code_data.public_key, true);
+
+                let mut initial_message = cipher.encrypt(LOGIN_INITIATE_MESSAGE.as_bytes())?;
+                initial_message.extend_from_slice(public_key.as_bytes());
+                channel.send(STANDARD_NO_PAD.encode(initial_message)).await?;
+
+                let response = cipher.decrypt(&decode(&channel.receive().await?)?)?;
+                if response.as_slice() != LOGIN_OK_MESSAGE.as_bytes() {
+                    return Err(SecureChannelError::InvalidHandshake);
+                }
+
+                on_progress(EstablishmentProgress::CheckCodeGenerated(check_code));
+
+                Ok(Self { channel, cipher })
+            }
+            None => {
+                let mut channel =
+                    RendezvousChannel::create_outbound(http_client, &client.homeserver().await)
+                        .await?;
+
+                let secret_key = Curve25519SecretKey::new();
+                let public_key = Curve25519PublicKey::from(&secret_key);
+                on_progress(EstablishmentProgress::QrCodeGenerated(QrCodeData {
+                    public_key,
+                    rendezvous_url: channel.rendezvous_url().clone(),
+                    mode,
+                }));
+
+                let initial_message = decode(&channel.receive().await?)?;
+                let Some(split) = initial_message.len().checked_sub(Curve25519PublicKey::LENGTH)
+                else {
+                    return Err(SecureChannelError::InvalidHandshake);
+                };
+                let (ciphertext, their_public_key) = initial_message.split_at(split);
+                let their_public_key = Curve25519PublicKey::from_slice(their_public_key)
+                    .map_err(|_| SecureChannelError::InvalidHandshake)?;
+
+                let (mut cipher, check_code) =
+                    Cipher::new(&secret_key, public_key, their_public_key, false);
+
+                if cipher.decrypt(ciphertext)?.as_slice() != LOGIN_INITIATE_MESSAGE.as_bytes() {
+                    return Err(SecureChannelError::InvalidHandshake);
+                }
+
+                let response = cipher.encrypt(LOGIN_OK_MESSAGE.as_bytes())?;
+                channel.send(STANDARD_NO_PAD.encode(response)).await?;
+
+                let (sender, receiver) = CheckCodeSender::new();
+                on_progress(EstablishmentProgress::WaitingForCheckCode(sender));
+
+                let entered = receiver.await.map_err(|_| SecureChannelError::CheckCodeNotSent)?;
+                if entered != check_code.to_digit() {
+                    return Err(SecureChannelError::InvalidCheckCode);
+                }
+
+                Ok(Self { channel, cipher })
+            }
+        }
+    }
+
+    /// Encrypt and send a message to the other device.
+    pub(super) async fn send_json(
+        &mut self,
+        message: &impl Serialize,
+    ) -> Result<(), SecureChannelError> {
+        let message = Zeroizing::new(serde_json::to_vec(message)?);
+        let ciphertext = self.cipher.encrypt(&message)?;
+
+        self.channel.send(STANDARD_NO_PAD.encode(ciphertext)).await
+    }
+
+    /// Wait for the next message of the other device and decrypt it.
+    pub(super) async fn receive_json<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<T, SecureChannelError> {
+        let ciphertext = decode(&self.channel.receive().await?)?;
+        let message = Zeroizing::new(self.cipher.decrypt(&ciphertext)?);
+
+        Ok(serde_json::from_slice(&message)?)
+    }
+}
+
+fn decode(message: &str) -> Result<Vec<u8>, SecureChannelError> {
+    STANDARD_NO_PAD.decode(message).map_err(|_| SecureChannelError::InvalidMessage)
+}
+
+struct Cipher {
+    encryption: ChaCha20Poly1305,
+    decryption: ChaCha20Poly1305,
+    sent_messages: u64,
+    received_messages: u64,
+}
+
+impl Cipher {
+    /// Derive the keys of both directions of the channel, and the check code.
+    ///
+    /// The device that scanned the QR code is the initiator: the first key
+    /// encrypts its messages, the second one the messages of the device that
+    /// generated the QR code.
+    fn new(
+        secret_key: &Curve25519SecretKey,
+        our_public_key: Curve25519PublicKey,
+        their_public_key: Curve25519PublicKey,
+        initiator: bool,
+    ) -> (Self, CheckCode) {
+        let shared_secret = secret_key.diffie_hellman(&their_public_key);
+        let (initiator_key, recipient_key) = if initiator {
+            (our_public_key, their_public_key)
+        } else {
+            (their_public_key, our_public_key)
+        };
+        let keys_info = format!("{}|{}", initiator_key.to_base64(), recipient_key.to_base64());
+
+        let hkdf: Hkdf<Sha256> = Hkdf::new(None, shared_secret.as_bytes());
+
+        let mut keys = Zeroizing::new([0u8; 64]);
+        hkdf.expand(format!("{APP_INFO}|{keys_info}").as_bytes(), keys.as_mut_slice())
+            .expect("We should be able to expand the shared secret into 64 bytes");
+
+        let mut check_code = [0u8; 2];
+        hkdf.expand(format!("{CHECK_CODE_APP_INFO}|{keys_info}").as_bytes(), &mut check_code)
+            .expect("We should be able to expand the shared secret into 2 bytes");
+
+        let initiator_cipher = ChaCha20Poly1305::new(Key::from_slice(&keys[..32]));
+        let recipient_cipher = ChaCha20Poly1305::new(Key::from_slice(&keys[32..]));
+        let (encryption, decryption) = if initiator {
+            (initiator_cipher, recipient_cipher)
+        } else {
+            (recipient_cipher, initiator_cipher)
+        };
+
+        (
+            Self { encryption, decryption, sent_messages: 0, received_messages: 0 },
+            CheckCode(check_code),
+        )
+    }
+
+    fn nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, SecureChannelError> {
+        let nonce = Self::nonce(self.sent_messages);
+        self.sent_messages += 1;
+
+        self.encryption
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| SecureChannelError::InvalidMessage)
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, SecureChannelError> {
+        let nonce = Self::nonce(self.received_messages);
+        self.received_messages += 1;
+
+        self.decryption
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .map_err(|_| SecureChannelError::InvalidMessage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vodozemac::{Curve25519PublicKey, Curve25519SecretKey};
+
+    use super::Cipher;
+
+    #[test]
+    fn both_ends_derive_the_same_keys() {
+        let scanner_key = Curve25519SecretKey::new();
+        let scanner_public_key = Curve25519PublicKey::from(&scanner_key);
+        let generator_key = Curve25519SecretKey::new();
+        let generator_public_key = Curve25519PublicKey::from(&generator_key);
+
+        let (mut scanner, scanner_check_code) =
+            Cipher::new(&scanner_key, scanner_public_key, generator_public_key, true);
+        let (mut generator, generator_check_code) =
+            Cipher::new(&generator_key, generator_public_key, scanner_public_key, false);
+
+        assert_eq!(scanner_check_code, generator_check_code);
+        assert!(scanner_check_code.to_digit() < 100);
+
+        for message in ["first", "second"] {
+            let ciphertext = scanner.encrypt(message.as_bytes()).unwrap();
+            assert_eq!(generator.decrypt(&ciphertext).unwrap(), message.as_bytes());
+
+            let ciphertext = generator.encrypt(message.as_bytes()).unwrap();
+            assert_eq!(scanner.decrypt(&ciphertext).unwrap(), message.as_bytes());
+        }
+    }
+
+    #[test]
+    fn messages_cant_be_replayed() {
+        let scanner_key = Curve25519SecretKey::new();
+        let scanner_public_key = Curve25519PublicKey::from(&scanner_key);
+        let generator_key = Curve25519SecretKey::new();
+        let generator_public_key = Curve25519PublicKey::from(&generator_key);
+
+        let (mut scanner, _) =
+            Cipher::new(&scanner_key, scanner_public_key, generator_public_key, true);
+        let (mut generator, _) =
+            Cipher::new(&generator_key, generator_public_key, scanner_public_key, false);
+
+        let ciphertext = scanner.encrypt(b"message").unwrap();
+        generator.decrypt(&ciphertext).unwrap();
+        generator.decrypt(&ciphertext).unwrap_err();
+
+        // A device can't decrypt its own messages either.
+        let ciphertext = scanner.encrypt(b"message").unwrap();
+        scanner.decrypt(&ciphertext).unwrap_err();
+    }
+}
@@ -0,0 +1,1083 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small framework to write bots, built on top of the event handlers.
+//!
+//! A [`Bot`] routes the text messages that look like commands to the handlers
+//! registered with [`Bot::command()`]. A message is a command if it starts
+//! with the command prefix, `!` by default, or with a mention of the bot,
+//! followed by the name of a command. The rest of the message contains the
+//! arguments of the command.
+//!
+//! The arguments can be parsed into typed values with
+//! [`CommandContext::parse_args()`], and the responses can be sent as replies
+//! or in threads according to the [`ResponseMode`] of the bot.
+//!
+//! The bot can also keep some state for each room, limit the rate of the
+//! commands sent by each user, and join the rooms it is invited to.
+//!
+//! For simpler needs, commands can also be registered directly on the client
+//! with [`Client::commands()`], see [`Commands`].
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use matrix_sdk::{
+//!     bot::{AutoJoin, Bot, ResponseMode},
+//!     config::SyncSettings,
+//!     Client,
+//! };
+//! # use url::Url;
+//! # async {
+//! # let homeserver = Url::parse("http://example.com")?;
+//! let client = Client::new(homeserver).await?;
+//! client.login_username("bot", "password").send().await?;
+//!
+//! // The state of each room is the number of times the counter was bumped.
+//! Bot::<u64>::new(client.clone())
+//!     .auto_join(AutoJoin::Always)
+//!     .rate_limit(5, Duration::from_secs(60))
+//!     .response_mode(ResponseMode::Reply)
+//!     .command("ping", |ctx| async move { ctx.respond("pong").await })
+//!     .command("add", |ctx| async move {
+//!         match ctx.parse_args::<(i64, i64)>() {
+//!             Ok((a, b)) => ctx.respond(&(a + b).to_string()).await,
+//!             Err(error) => {
+//!                 ctx.respond(&format!("Usage: !add <a> <b> ({error})")).await
+//!             }
+//!         }
+//!     })
+//!     .command("bump", |ctx| async move {
+//!         let count = {
+//!             let mut count = ctx.state().await;
+//!             *count += 1;
+//!             *count
+//!         };
+//!         ctx.respond(&format!("The counter is at {count}")).await
+//!     })
+//!     .register();
+//!
+//! client.sync(SyncSettings::default()).await?;
+//! # anyhow::Ok(()) };
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::{FromStr, SplitWhitespace},
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use matrix_sdk_common::{executor::spawn, instant::Instant, sleep::sleep};
+use ruma::{
+    events::{
+        relation::Thread,
+        room::{
+            member::{MembershipState, StrippedRoomMemberEvent},
+            message::{
+                ForwardThread, MessageType, OriginalSyncRoomMessageEvent, Relation,
+                RoomMessageEventContent,
+            },
+        },
+    },
+    OwnedRoomId, OwnedServerName, OwnedUserId, UserId,
+};
+use thiserror::Error;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{debug, info, warn};
+
+use crate::{
+    event_handler::EventHandlerHandle,
+    room::{self, Room},
+    Client, Result,
+};
+
+/// The default prefix of the commands.
+const DEFAULT_PREFIX: &str = "!";
+
+/// The maximum delay between two attempts to join a room.
+const MAX_JOIN_DELAY: Duration = Duration::from_secs(3600);
+
+type CommandFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type CommandHandlerFn<S> = dyn Fn(CommandContext<S>) -> CommandFuture + Send + Sync;
+
+/// The policy to accept the invites received by a [`Bot`].
+#[derive(Clone, Debug, Default)]
+pub enum AutoJoin {
+    /// Never join the rooms automatically.
+    #[default]
+    Never,
+    /// Join all the rooms the bot is invited to.
+    Always,
+    /// Only join the rooms when the invite was sent by one of these users.
+    FromUsers(BTreeSet<OwnedUserId>),
+    /// Only join the rooms when the invite was sent by a user of one of these
+    /// servers.
+    FromServers(BTreeSet<OwnedServerName>),
+}
+
+impl AutoJoin {
+    fn accepts(&self, inviter: &UserId) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Always => true,
+            Self::FromUsers(user_ids) => user_ids.contains(inviter),
+            Self::FromServers(server_names) => server_names.contains(inviter.server_name()),
+        }
+    }
+}
+
+/// How a [`Bot`] sends the responses to the commands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResponseMode {
+    /// Send the responses as plain messages in the room.
+    #[default]
+    Message,
+    /// Send the responses as replies to the commands.
+    Reply,
+    /// Send the responses in the thread of the commands, starting a new thread
+    /// if the command wasn't sent in a thread.
+    Thread,
+}
+
+/// A bot handling commands sent in the rooms it is in.
+///
+/// `S` is the type of the state kept for each room, which is created with its
+/// [`Default`] implementation the first time a command is received in a room.
+///
+/// See the [module-level documentation](self) for more details.
+pub struct Bot<S = ()> {
+    client: Client,
+    config: BotConfig<S>,
+    rate_limiter: Option<RateLimiter>,
+    auto_join: AutoJoin,
+}
+
+impl<S> Bot<S>
+where
+    S: Default + Send + 'static,
+{
+    /// Create a new `Bot` for the given client.
+    ///
+    /// By default, the bot responds to the commands starting with `!` and to
+    /// mentions with plain messages, doesn't limit the rate of the commands,
+    /// and doesn't join rooms automatically.
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            config: BotConfig::new(Some(DEFAULT_PREFIX)),
+            rate_limiter: None,
+            auto_join: AutoJoin::Never,
+        }
+    }
+
+    /// Set the prefix of the commands, or `None` to only respond to mentions.
+    pub fn prefix(mut self, prefix: Option<&str>) -> Self {
+        self.config.prefix = prefix.map(ToOwned::to_owned);
+        self
+    }
+
+    /// Set whether the messages starting with a mention of the bot are
+    /// commands, e.g. `bot: ping`.
+    pub fn respond_to_mentions(mut self, value: bool) -> Self {
+        self.config.respond_to_mentions = value;
+        self
+    }
+
+    /// Register the handler of the command with the given name.
+    ///
+    /// The names of the commands are case-insensitive, and can contain
+    /// spaces, e.g. `room list`. When several names match a message, the
+    /// longest one is used. If a handler fails, the error is logged.
+    pub fn command<F, Fut>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(CommandContext<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.config.insert(name, handler);
+        self
+    }
+
+    /// Only handle up to `max_commands` commands from the same user in the
+    /// same room during `period`.
+    ///
+    /// The commands above the limit are ignored.
+    pub fn rate_limit(mut self, max_commands: usize, period: Duration) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(max_commands, period));
+        self
+    }
+
+    /// Set the policy to accept the invites received by the bot.
+    pub fn auto_join(mut self, policy: AutoJoin) -> Self {
+        self.auto_join = policy;
+        self
+    }
+
+    /// Set how the responses sent with [`CommandContext::respond()`] relate to
+    /// the commands.
+    pub fn response_mode(mut self, mode: ResponseMode) -> Self {
+        self.config.response_mode = mode;
+        self
+    }
+
+    /// Register the event handlers of the bot on the client.
+    ///
+    /// The bot handles the events received by the sync from then on, until
+    /// [`BotHandle::stop()`] is called.
+    pub fn register(self) -> BotHandle {
+        let client = self.client.clone();
+        let auto_join = self.auto_join.clone();
+        let inner = Arc::new(BotInner::new(self.config, self.rate_limiter));
+
+        let message_handle = inner.add_message_handler(&client);
+
+        let invite_handle = client.add_event_handler(
+            move |event: StrippedRoomMemberEvent, room: Room, client: Client| {
+                let auto_join = auto_join.clone();
+                async move {
+                    let Room::Invited(room) = room else { return };
+                    if event.content.membership != MembershipState::Invite
+                        || client.user_id() != Some(&*event.state_key)
+                    {
+                        return;
+                    }
+
+                    if auto_join.accepts(&event.sender) {
+                        spawn(join_room(room));
+                    } else {
+                        debug!(room_id = ?room.room_id(), inviter = ?event.sender, "Ignoring invite");
+                    }
+                }
+            },
+        );
+
+        BotHandle { client, handles: vec![message_handle, invite_handle] }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<S> fmt::Debug for Bot<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bot")
+            .field("prefix", &self.config.prefix)
+            .field("respond_to_mentions", &self.config.respond_to_mentions)
+            .field("commands", &self.config.commands.keys())
+            .field("auto_join", &self.auto_join)
+            .field("response_mode", &self.config.response_mode)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A handle to a [`Bot`] that has been registered.
+#[derive(Debug)]
+pub struct BotHandle {
+    client: Client,
+    handles: Vec<EventHandlerHandle>,
+}
+
+impl BotHandle {
+    /// Stop the bot, by removing its event handlers from the client.
+    pub fn stop(self) {
+        for handle in self.handles {
+            self.client.remove_event_handler(handle);
+        }
+    }
+}
+
+/// The commands registered on a [`Client`], obtained with
+/// [`Client::commands()`].
+///
+/// The commands are dispatched by a [`Bot`] shared by the client, without a
+/// prefix: the commands are registered with their full trigger, including
+/// their prefix, e.g. `!weather`, and a text message is dispatched to the
+/// handler of the longest trigger it starts with, case-insensitively. If
+/// mentions are enabled, the trigger can also follow a mention of the own
+/// user, with or without its prefix, e.g. `bot: weather London`.
+///
+/// The event handler dispatching the commands is added to the client when the
+/// first command is registered, and removed when the last one is unregistered.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use matrix_sdk::{bot::ResponseMode, Client};
+/// # async fn example(client: Client) {
+/// let commands = client.commands();
+/// commands.set_response_mode(ResponseMode::Thread);
+/// commands.register("!weather", |ctx| async move {
+///     match ctx.parse_args::<(String,)>() {
+///         Ok((city,)) => ctx.respond(&format!("It is always sunny in {city}")).await,
+///         Err(error) => ctx.respond(&format!("Usage: !weather <city> ({error})")).await,
+///     }
+/// });
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Commands {
+    client: Client,
+}
+
+impl Commands {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    fn bot(&self) -> &ClientBot {
+        &self.client.inner.bot
+    }
+
+    /// Register the handler of the command with the given trigger, replacing
+    /// the previous handler of this trigger, if any.
+    ///
+    /// The trigger is usually a prefix followed by the name of the command,
+    /// e.g. `!weather`. If a handler fails, the error is logged.
+    pub fn register<F, Fut>(&self, trigger: &str, handler: F)
+    where
+        F: Fn(CommandContext<()>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let bot = self.bot();
+        bot.inner.config.lock().unwrap().insert(trigger, handler);
+
+        let mut handle = bot.handle.lock().unwrap();
+        if handle.is_none() {
+            *handle = Some(bot.inner.add_message_handler(&self.client));
+        }
+    }
+
+    /// Unregister the command with the given trigger.
+    ///
+    /// Returns `true` if the command was registered.
+    pub fn unregister(&self, trigger: &str) -> bool {
+        let bot = self.bot();
+        let (removed, is_empty) = {
+            let mut config = bot.inner.config.lock().unwrap();
+            let removed = config.commands.remove(&trigger.to_lowercase()).is_some();
+            (removed, config.commands.is_empty())
+        };
+
+        if is_empty {
+            if let Some(handle) = bot.handle.lock().unwrap().take() {
+                self.client.remove_event_handler(handle);
+            }
+        }
+
+        removed
+    }
+
+    /// Set how the responses sent with [`CommandContext::respond()`] relate to
+    /// the commands.
+    ///
+    /// Defaults to [`ResponseMode::Message`].
+    pub fn set_response_mode(&self, mode: ResponseMode) {
+        self.bot().inner.config.lock().unwrap().response_mode = mode;
+    }
+
+    /// Set whether the triggers can follow a mention of the own user, e.g.
+    /// `bot: weather London`.
+    ///
+    /// Defaults to `true`.
+    pub fn set_respond_to_mentions(&self, value: bool) {
+        self.bot().inner.config.lock().unwrap().respond_to_mentions = value;
+    }
+}
+
+/// The [`Bot`] dispatching the [`Commands`] of a client.
+pub(crate) struct ClientBot {
+    inner: Arc<BotInner<()>>,
+    handle: StdMutex<Option<EventHandlerHandle>>,
+}
+
+impl Default for ClientBot {
+    fn default() -> Self {
+        // The commands are registered with their prefix.
+        let inner = BotInner::new(BotConfig::new(Some("")), None);
+        Self { inner: Arc::new(inner), handle: Default::default() }
+    }
+}
+
+/// The context of a command received by a [`Bot`].
+pub struct CommandContext<S> {
+    /// The room where the command was sent.
+    pub room: room::Joined,
+    /// The message containing the command.
+    pub event: OriginalSyncRoomMessageEvent,
+    /// The name of the command, in lowercase.
+    ///
+    /// For the commands registered with [`Commands::register()`], this is the
+    /// trigger of the command, including its prefix.
+    pub command: String,
+    /// The text following the name of the command, trimmed.
+    pub args: String,
+    state: Arc<Mutex<S>>,
+    response_mode: ResponseMode,
+}
+
+impl<S> CommandContext<S> {
+    /// The user who sent the command.
+    pub fn sender(&self) -> &UserId {
+        &self.event.sender
+    }
+
+    /// The arguments of the command, split on whitespace.
+    pub fn split_args(&self) -> impl Iterator<Item = &str> {
+        self.args.split_whitespace()
+    }
+
+    /// Parse the arguments of the command, split on whitespace, into typed
+    /// values.
+    ///
+    /// `T` is usually a tuple of types implementing [`FromStr`], each of them
+    /// parsed from one argument, or a `Vec` of such a type to parse all the
+    /// arguments.
+    pub fn parse_args<T: CommandArgs>(&self) -> Result<T, ArgsError> {
+        parse_args(&self.args)
+    }
+
+    /// Lock the state of the room where the command was sent.
+    ///
+    /// The state is shared by all the commands received in the room, so the
+    /// lock shouldn't be held longer than necessary.
+    pub async fn state(&self) -> MutexGuard<'_, S> {
+        self.state.lock().await
+    }
+
+    /// Send a plain text message in the room where the command was sent.
+    ///
+    /// The message is related to the command according to the
+    /// [`ResponseMode`] of the bot, or of the [`Commands`] registry.
+    pub async fn respond(&self, body: &str) -> Result<()> {
+        let content = RoomMessageEventContent::text_plain(body);
+        let content = match self.response_mode {
+            ResponseMode::Message => content,
+            ResponseMode::Reply => {
+                let event = self.event.clone().into_full_event(self.room.room_id().to_owned());
+                content.make_reply_to(&event, ForwardThread::Yes)
+            }
+            ResponseMode::Thread => {
+                let root = match &self.event.content.relates_to {
+                    Some(Relation::Thread(thread)) => thread.event_id.clone(),
+                    _ => self.event.event_id.clone(),
+                };
+                let mut content = content;
+                content.relates_to =
+                    Some(Relation::Thread(Thread::plain(root, self.event.event_id.clone())));
+                content
+            }
+        };
+
+        self.room.send(content, None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<S> fmt::Debug for CommandContext<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandContext")
+            .field("room_id", &self.room.room_id())
+            .field("event_id", &self.event.event_id)
+            .field("command", &self.command)
+            .field("args", &self.args)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Types that can be parsed from the arguments of a command, with
+/// [`CommandContext::parse_args()`].
+///
+/// It is implemented for tuples of up to 6 types implementing [`FromStr`],
+/// and for a `Vec` of such a type.
+pub trait CommandArgs: Sized {
+    /// Parse the value from the given arguments, consuming the ones it uses.
+    fn parse(args: &mut SplitWhitespace<'_>) -> Result<Self, ArgsError>;
+}
+
+impl<T: FromStr> CommandArgs for Vec<T> {
+    fn parse(args: &mut SplitWhitespace<'_>) -> Result<Self, ArgsError> {
+        args.enumerate().map(|(index, arg)| parse_arg(index, arg)).collect()
+    }
+}
+
+macro_rules! impl_command_args {
+    ($($ty:ident),+) => {
+        impl<$($ty: FromStr),+> CommandArgs for ($($ty,)+) {
+            fn parse(args: &mut SplitWhitespace<'_>) -> Result<Self, ArgsError> {
+                let mut index = 0;
+                Ok(($(next_arg::<$ty>(args, &mut index)?,)+))
+            }
+        }
+    };
+}
+
+impl_command_args!(A);
+impl_command_args!(A, B);
+impl_command_args!(A, B, C);
+impl_command_args!(A, B, C, D);
+impl_command_args!(A, B, C, D, E);
+impl_command_args!(A, B, C, D, E, F);
+
+fn parse_args<T: CommandArgs>(args: &str) -> Result<T, ArgsError> {
+    let mut args = args.split_whitespace();
+    let parsed = T::parse(&mut args)?;
+
+    if args.next().is_some() {
+        return Err(ArgsError::TooMany);
+    }
+
+    Ok(parsed)
+}
+
+fn next_arg<T: FromStr>(args: &mut SplitWhitespace<'_>, index: &mut usize) -> Result<T, ArgsError> {
+    let arg = args.next().ok_or(ArgsError::Missing { index: *index })?;
+    let value = parse_arg(*index, arg)?;
+    *index += 1;
+    Ok(value)
+}
+
+fn parse_arg<T: FromStr>(index: usize, arg: &str) -> Result<T, ArgsError> {
+    arg.parse().map_err(|_| ArgsError::Invalid { index, value: arg.to_owned() })
+}
+
+/// An error when parsing the arguments of a command.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ArgsError {
+    /// An argument is missing.
+    #[error("missing argument {}", index + 1)]
+    Missing {
+        /// The position of the missing argument, starting at 0.
+        index: usize,
+    },
+    /// An argument couldn't be parsed.
+    #[error("invalid argument {}: `{value}`", index + 1)]
+    Invalid {
+        /// The position of the invalid argument, starting at 0.
+        index: usize,
+        /// The value of the invalid argument.
+        value: String,
+    },
+    /// There are more arguments than expected.
+    #[error("too many arguments")]
+    TooMany,
+}
+
+/// The settings of a [`Bot`] that can change once it is registered.
+struct BotConfig<S> {
+    prefix: Option<String>,
+    respond_to_mentions: bool,
+    commands: BTreeMap<String, Arc<CommandHandlerFn<S>>>,
+    response_mode: ResponseMode,
+}
+
+impl<S> BotConfig<S> {
+    fn new(prefix: Option<&str>) -> Self {
+        Self {
+            prefix: prefix.map(ToOwned::to_owned),
+            respond_to_mentions: true,
+            commands: BTreeMap::new(),
+            response_mode: ResponseMode::Message,
+        }
+    }
+
+    fn insert<F, Fut>(&mut self, name: &str, handler: F)
+    where
+        F: Fn(CommandContext<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.commands.insert(name.to_lowercase(), Arc::new(move |ctx| Box::pin(handler(ctx))));
+    }
+}
+
+struct BotInner<S> {
+    config: StdMutex<BotConfig<S>>,
+    rate_limiter: Option<RateLimiter>,
+    states: StdMutex<HashMap<OwnedRoomId, Arc<Mutex<S>>>>,
+}
+
+impl<S: Default + Send + 'static> BotInner<S> {
+    fn new(config: BotConfig<S>, rate_limiter: Option<RateLimiter>) -> Self {
+        Self { config: StdMutex::new(config), rate_limiter, states: Default::default() }
+    }
+
+    /// Add the event handler dispatching the commands to the client.
+    fn add_message_handler(self: &Arc<Self>, client: &Client) -> EventHandlerHandle {
+        let inner = self.clone();
+        client.add_event_handler(move |event: OriginalSyncRoomMessageEvent, room: Room| {
+            let inner = inner.clone();
+            async move {
+                let Room::Joined(room) = room else { return };
+                inner.handle_message(event, room).await;
+            }
+        })
+    }
+
+    async fn handle_message(&self, event: OriginalSyncRoomMessageEvent, room: room::Joined) {
+        if room.own_user_id() == event.sender {
+            return;
+        }
+
+        let MessageType::Text(text) = &event.content.msgtype else { return };
+
+        let respond_to_mentions = self.config.lock().unwrap().respond_to_mentions;
+        let mentions = if respond_to_mentions { own_mentions(&room).await } else { Vec::new() };
+
+        let (command, args, handler, response_mode) = {
+            let config = self.config.lock().unwrap();
+            let Some((command, args)) = find_command(
+                config.commands.keys().map(String::as_str),
+                &text.body,
+                config.prefix.as_deref(),
+                mentions.iter().map(String::as_str),
+            ) else {
+                return;
+            };
+            let handler = config.commands[command].clone();
+            (command.to_owned(), args, handler, config.response_mode)
+        };
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if !rate_limiter.check(room.room_id().to_owned(), event.sender.clone()) {
+                info!(command, sender = ?event.sender, "Rate limit reached, ignoring command");
+                return;
+            }
+        }
+
+        let state =
+            self.states.lock().unwrap().entry(room.room_id().to_owned()).or_default().clone();
+        let ctx =
+            CommandContext { room, event, command: command.clone(), args, state, response_mode };
+
+        if let Err(error) = handler(ctx).await {
+            warn!(command, ?error, "Command failed");
+        }
+    }
+}
+
+/// Join a room, retrying with an increasing delay.
+///
+/// Some servers send the invite before the invited user can join, see
+/// <https://github.com/matrix-org/synapse/issues/4345>.
+async fn join_room(room: room::Invited) {
+    let mut delay = Duration::from_secs(2);
+
+    while let Err(error) = room.accept_invitation().await {
+        if delay > MAX_JOIN_DELAY {
+            warn!(room_id = ?room.room_id(), ?error, "Couldn't join room");
+            return;
+        }
+
+        debug!(room_id = ?room.room_id(), ?error, ?delay, "Couldn't join room, retrying");
+        sleep(delay).await;
+        delay *= 2;
+    }
+
+    info!(room_id = ?room.room_id(), "Joined room");
+}
+
+/// The ways the own user can be mentioned at the start of a command in the
+/// given room: its user ID, its localpart and its display name, if any.
+async fn own_mentions(room: &room::Joined) -> Vec<String> {
+    let user_id = room.own_user_id();
+    let mut mentions = vec![user_id.to_string(), user_id.localpart().to_owned()];
+
+    match room.get_member_no_sync(user_id).await {
+        Ok(member) => mentions.extend(member.and_then(|m| m.display_name().map(ToOwned::to_owned))),
+        Err(error) => warn!(?error, "Couldn't get the display name of the bot"),
+    }
+
+    mentions
+}
+
+/// Find the command a message starts with, among the given names, and its
+/// arguments.
+///
+/// A command starts with the prefix followed by the name of the command, or
+/// with a mention of the own user followed by the name of the command, with
+/// or without the prefix. After a mention, the name can also be used without
+/// the punctuation it starts with, e.g. `bot: weather` for `!weather`.
+///
+/// When several names match, e.g. `room` and `room list`, the longest one is
+/// used, so the result doesn't depend on the order of the names.
+fn find_command<'a, 'm>(
+    names: impl IntoIterator<Item = &'a str>,
+    body: &str,
+    prefix: Option<&str>,
+    mentions: impl IntoIterator<Item = &'m str>,
+) -> Option<(&'a str, String)> {
+    let body = body.trim_start();
+
+    let after_mention = mentions.into_iter().find_map(|mention| {
+        let rest = body.strip_prefix(mention)?;
+        // The mention must be a whole word, optionally followed by a
+        // colon or a comma as added by clients.
+        let rest = rest.strip_prefix([':', ',']).unwrap_or(rest);
+        rest.starts_with(char::is_whitespace).then(|| rest.trim_start())
+    });
+    let mentioned = after_mention.is_some();
+
+    let rest = match after_mention {
+        Some(rest) => prefix.and_then(|prefix| rest.strip_prefix(prefix)).unwrap_or(rest),
+        None => body.strip_prefix(prefix?)?,
+    };
+
+    // The length of the given name at the start of the message, if it is a
+    // whole word.
+    let match_len = |name: &str| {
+        let tail = rest.get(name.len()..)?;
+        let matches = !name.is_empty()
+            && rest[..name.len()].to_lowercase() == name
+            && (tail.is_empty() || tail.starts_with(char::is_whitespace));
+        matches.then_some(name.len())
+    };
+
+    let (name, len, _) = names
+        .into_iter()
+        .filter_map(|name| {
+            if let Some(len) = match_len(name) {
+                return Some((name, len, true));
+            }
+
+            let bare_name = name.trim_start_matches(|c: char| !c.is_alphanumeric());
+            let len = mentioned.then(|| match_len(bare_name)).flatten()?;
+            Some((name, len, false))
+        })
+        // Prefer the exact matches to the names used without their punctuation,
+        // and the first name in lexicographic order.
+        .max_by_key(|(name, len, exact)| (*len, *exact, Reverse(*name)))?;
+
+    Some((name, rest[len..].trim().to_owned()))
+}
+
+/// A sliding window rate limiter for the commands of each user in each room.
+struct RateLimiter {
+    max_commands: usize,
+    period: Duration,
+    history: StdMutex<HashMap<(OwnedRoomId, OwnedUserId), VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    fn new(max_commands: usize, period: Duration) -> Self {
+        Self { max_commands, period, history: StdMutex::new(HashMap::new()) }
+    }
+
+    /// Record a command, if it is allowed.
+    fn check(&self, room_id: OwnedRoomId, user_id: OwnedUserId) -> bool {
+        self.check_at(room_id, user_id, Instant::now())
+    }
+
+    fn check_at(&self, room_id: OwnedRoomId, user_id: OwnedUserId, now: Instant) -> bool {
+        let mut history = self.history.lock().unwrap();
+
+        // Forget the expired commands, and the users without recent commands,
+        // so the history only grows with the number of active users.
+        history.retain(|_, timestamps| {
+            while timestamps.front().is_some_and(|ts| now.duration_since(*ts) >= self.period) {
+                timestamps.pop_front();
+            }
+            !timestamps.is_empty()
+        });
+
+        let timestamps = history.entry((room_id, user_id)).or_default();
+
+        if timestamps.len() >= self.max_commands {
+            return false;
+        }
+
+        timestamps.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use matrix_sdk_common::instant::Instant;
+    use matrix_sdk_test::{
+        async_test, EventBuilder, JoinedRoomBuilder, MatrixMockServer, TimelineTestEvent,
+    };
+    use ruma::{room_id, user_id};
+    use serde_json::{json, Value as JsonValue};
+    use wiremock::{matchers::body_partial_json, Request};
+
+    use super::{
+        find_command, parse_args, ArgsError, AutoJoin, CommandContext, RateLimiter, ResponseMode,
+    };
+    use crate::{test_utils::logged_in_client, Client};
+
+    /// Receive a text message from Alice in a joined room through the sync.
+    async fn receive_message(
+        client: &Client,
+        event_id: &str,
+        body: &str,
+        thread_root: Option<&str>,
+    ) {
+        let mut content = json!({ "body": body, "msgtype": "m.text" });
+        if let Some(root) = thread_root {
+            content["m.relates_to"] = json!({ "rel_type": "m.thread", "event_id": root });
+        }
+
+        let response = EventBuilder::new()
+            .add_joined_room(
+                JoinedRoomBuilder::new(room_id!("!room:localhost")).add_timeline_event(
+                    TimelineTestEvent::Custom(json!({
+                        "content": content,
+                        "event_id": event_id,
+                        "origin_server_ts": 152037280,
+                        "sender": "@alice:localhost",
+                        "type": "m.room.message",
+                    })),
+                ),
+            )
+            .build_sync_response();
+        client.process_sync(response).await.unwrap();
+    }
+
+    /// Register a `!weather` command responding with the city in its argument.
+    fn register_weather(client: &Client) {
+        client.commands().register("!weather", |ctx: CommandContext<()>| async move {
+            let (city,) = ctx.parse_args::<(String,)>().unwrap();
+            ctx.respond(&format!("Sunny in {city}")).await
+        });
+    }
+
+    #[async_test]
+    async fn commands_are_dispatched() {
+        let client = logged_in_client(None).await;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        let commands = client.commands();
+        commands.register("!weather", {
+            let calls = calls.clone();
+            move |ctx: CommandContext<()>| {
+                let calls = calls.clone();
+                async move {
+                    let (city, days) = ctx.parse_args::<(String, u8)>().unwrap();
+                    calls.lock().unwrap().push((ctx.command.clone(), city, days));
+                    Ok(())
+                }
+            }
+        });
+
+        receive_message(&client, "$1", "!Weather London 3", None).await;
+        // The trigger can follow a mention, with or without its prefix.
+        receive_message(&client, "$2", "example: weather Paris 1", None).await;
+        receive_message(&client, "$3", "@example:localhost !weather Rome 2", None).await;
+        // Not commands.
+        receive_message(&client, "$4", "weather Berlin 1", None).await;
+        receive_message(&client, "$5", "!forecast Madrid 1", None).await;
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                ("!weather".to_owned(), "London".to_owned(), 3),
+                ("!weather".to_owned(), "Paris".to_owned(), 1),
+                ("!weather".to_owned(), "Rome".to_owned(), 2),
+            ]
+        );
+
+        commands.set_respond_to_mentions(false);
+        receive_message(&client, "$6", "example: weather Paris 1", None).await;
+        assert_eq!(calls.lock().unwrap().len(), 3);
+
+        assert!(commands.unregister("!WEATHER"));
+        assert!(!commands.unregister("!weather"));
+        receive_message(&client, "$7", "!weather London 3", None).await;
+        assert_eq!(calls.lock().unwrap().len(), 3);
+    }
+
+    #[async_test]
+    async fn respond_with_message() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        server.mock_room_encryption_state().mount().await;
+        server
+            .mock_room_send()
+            .and(body_partial_json(json!({ "body": "Sunny in London" })))
+            .and(|request: &Request| {
+                request.body_json::<JsonValue>().unwrap().get("m.relates_to").is_none()
+            })
+            .expect(1)
+            .mount()
+            .await;
+
+        register_weather(&client);
+        receive_message(&client, "$command", "!weather London", None).await;
+    }
+
+    #[async_test]
+    async fn respond_with_reply() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        server.mock_room_encryption_state().mount().await;
+        server
+            .mock_room_send()
+            .and(body_partial_json(json!({
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$command" } },
+            })))
+            .expect(1)
+            .mount()
+            .await;
+
+        register_weather(&client);
+        client.commands().set_response_mode(ResponseMode::Reply);
+        receive_message(&client, "$command", "!weather London", None).await;
+    }
+
+    #[async_test]
+    async fn respond_in_thread() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        server.mock_room_encryption_state().mount().await;
+        // A new thread is started from the command.
+        server
+            .mock_room_send()
+            .and(body_partial_json(json!({
+                "body": "Sunny in London",
+                "m.relates_to": { "rel_type": "m.thread", "event_id": "$command" },
+            })))
+            .expect(1)
+            .mount()
+            .await;
+        // The response to a command sent in a thread stays in this thread.
+        server
+            .mock_room_send()
+            .and(body_partial_json(json!({
+                "body": "Sunny in Paris",
+                "m.relates_to": {
+                    "rel_type": "m.thread",
+                    "event_id": "$root",
+                    "m.in_reply_to": { "event_id": "$threaded" },
+                },
+            })))
+            .expect(1)
+            .mount()
+            .await;
+
+        register_weather(&client);
+        client.commands().set_response_mode(ResponseMode::Thread);
+        receive_message(&client, "$command", "!weather London", None).await;
+        receive_message(&client, "$threaded", "!weather Paris", Some("$root")).await;
+    }
+
+    fn parse(body: &str) -> Option<(&'static str, String)> {
+        find_command(
+            ["echo", "help", "ping", "roll", "room", "room list"],
+            body,
+            Some("!"),
+            ["@bot:localhost", "bot", "Friendly Bot"],
+        )
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse("!ping"), Some(("ping", String::new())));
+        assert_eq!(parse("  !Echo  hello  world "), Some(("echo", "hello  world".to_owned())));
+        assert_eq!(parse("bot: ping"), Some(("ping", String::new())));
+        assert_eq!(parse("bot: !ping"), Some(("ping", String::new())));
+        assert_eq!(parse("Friendly Bot, roll 2d6"), Some(("roll", "2d6".to_owned())));
+        assert_eq!(parse("@bot:localhost help"), Some(("help", String::new())));
+
+        assert_eq!(parse("ping"), None);
+        assert_eq!(parse("!"), None);
+        assert_eq!(parse("!pong"), None);
+        assert_eq!(parse("!pinged"), None);
+        assert_eq!(parse("botany is great"), None);
+        assert_eq!(parse("hello bot: ping"), None);
+        assert_eq!(find_command(["ping"], "!ping", None, []), None);
+    }
+
+    #[test]
+    fn longest_command_is_found() {
+        assert_eq!(parse("!room list all"), Some(("room list", "all".to_owned())));
+        assert_eq!(parse("!Room List"), Some(("room list", String::new())));
+        assert_eq!(parse("!room lister"), Some(("room", "lister".to_owned())));
+        assert_eq!(parse("bot: room list"), Some(("room list", String::new())));
+
+        // The commands of the client are registered with their prefix, which
+        // is optional after a mention.
+        let triggers = ["!weather", "?weather", "weather"];
+        let find = |body: &str| find_command(triggers, body, Some(""), ["bot"]);
+        assert_eq!(find("?weather Paris"), Some(("?weather", "Paris".to_owned())));
+        assert_eq!(find("bot: weather Paris"), Some(("weather", "Paris".to_owned())));
+        assert_eq!(find("bot: !weather Paris"), Some(("!weather", "Paris".to_owned())));
+        // The result doesn't depend on the order of the names.
+        for triggers in [["!weather", "?weather"], ["?weather", "!weather"]] {
+            assert_eq!(
+                find_command(triggers, "bot: weather", Some(""), ["bot"]),
+                Some(("!weather", String::new()))
+            );
+        }
+    }
+
+    #[test]
+    fn args_are_parsed() {
+        assert_eq!(parse_args::<(String, u32)>("London  3"), Ok(("London".to_owned(), 3)));
+        assert_eq!(parse_args::<Vec<i64>>("1 -2 3"), Ok(vec![1, -2, 3]));
+        assert_eq!(parse_args::<Vec<i64>>(""), Ok(vec![]));
+
+        assert_eq!(parse_args::<(String, u32)>("London"), Err(ArgsError::Missing { index: 1 }));
+        assert_eq!(
+            parse_args::<(String, u32)>("London three"),
+            Err(ArgsError::Invalid { index: 1, value: "three".to_owned() })
+        );
+        assert_eq!(parse_args::<(u32,)>("3 4"), Err(ArgsError::TooMany));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let room_id = room_id!("!room:localhost");
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:localhost");
+        let start = Instant::now();
+
+        assert!(limiter.check_at(room_id.to_owned(), alice.to_owned(), start));
+        assert!(limiter.check_at(room_id.to_owned(), alice.to_owned(), start));
+        assert!(!limiter.check_at(room_id.to_owned(), alice.to_owned(), start));
+        // Other users have their own limit.
+        assert!(limiter.check_at(room_id.to_owned(), bob.to_owned(), start));
+
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.check_at(room_id.to_owned(), alice.to_owned(), later));
+        // Bob's expired commands are forgotten.
+        assert_eq!(limiter.history.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn auto_join_policy() {
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:example.org");
+
+        assert!(!AutoJoin::Never.accepts(alice));
+        assert!(AutoJoin::Always.accepts(alice));
+
+        let from_users = AutoJoin::FromUsers([alice.to_owned()].into());
+        assert!(from_users.accepts(alice));
+        assert!(!from_users.accepts(bob));
+
+        let from_servers = AutoJoin::FromServers(["example.org".try_into().unwrap()].into());
+        assert!(!from_servers.accepts(alice));
+        assert!(from_servers.accepts(bob));
+    }
+}
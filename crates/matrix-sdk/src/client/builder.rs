@@ -13,24 +13,36 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "experimental-sliding-sync")]
-use std::sync::RwLock as StdRwLock;
-use std::{fmt, sync::Arc};
+use std::{
+    fmt,
+    sync::{Arc, RwLock as StdRwLock},
+    time::Duration,
+};
 
 use matrix_sdk_base::{store::StoreConfig, BaseClient};
+use matrix_sdk_common::executor::spawn;
+#[cfg(feature = "experimental-encrypted-state-events")]
+use ruma::events::StateEventType;
 use ruma::{
-    api::{client::discovery::discover_homeserver, error::FromHttpResponseError, MatrixVersion},
-    OwnedServerName, ServerName,
+    api::{client::discovery::get_supported_versions, MatrixVersion},
+    OwnedRoomId, OwnedServerName, ServerName,
 };
 use thiserror::Error;
 use tokio::sync::{broadcast, Mutex, OnceCell, RwLock};
 use tracing::{debug, field::debug, instrument, Span};
 use url::Url;
 
-use super::{Client, ClientInner};
+use super::{
+    well_known::{self, ClientWellKnown, WellKnownError},
+    Client, ClientInner,
+};
 #[cfg(not(target_arch = "wasm32"))]
 use crate::http_client::HttpSettings;
-use crate::{config::RequestConfig, error::RumaApiError, http_client::HttpClient, HttpError};
+use crate::{
+    config::RequestConfig,
+    http_client::{HttpClient, HttpMiddleware, RequestLimits},
+    HttpError,
+};
 
 /// Builder that allows creating and configuring various parts of a [`Client`].
 ///
@@ -81,6 +93,12 @@ pub struct ClientBuilder {
     appservice_mode: bool,
     server_versions: Option<Box<[MatrixVersion]>>,
     handle_refresh_tokens: bool,
+    well_known_refresh_interval: Option<Duration>,
+    minimal_rooms: Option<Box<[OwnedRoomId]>>,
+    http_middlewares: Vec<Arc<dyn HttpMiddleware>>,
+    request_limits: RequestLimits,
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    encrypted_state_event_types: Box<[StateEventType]>,
 }
 
 impl ClientBuilder {
@@ -94,6 +112,12 @@ impl ClientBuilder {
             appservice_mode: false,
             server_versions: None,
             handle_refresh_tokens: false,
+            well_known_refresh_interval: None,
+            minimal_rooms: None,
+            http_middlewares: Vec::new(),
+            request_limits: Default::default(),
+            #[cfg(feature = "experimental-encrypted-state-events")]
+            encrypted_state_event_types: Box::new([]),
         }
     }
 
@@ -117,6 +141,16 @@ impl ClientBuilder {
         self
     }
 
+    /// Fetch the `.well-known/matrix/client` file of the server again every
+    /// `interval`, to keep [`Client::well_known()`] up to date.
+    ///
+    /// This only has an effect if the homeserver is discovered with
+    /// [`server_name()`][Self::server_name].
+    pub fn well_known_refresh_interval(mut self, interval: Duration) -> Self {
+        self.well_known_refresh_interval = Some(interval);
+        self
+    }
+
     /// Set up the store configuration for a SQLite store.
     ///
     /// This is the same as
@@ -124,14 +158,74 @@ impl ClientBuilder {
     /// except it delegates the actual store config creation to when
     /// `.build().await` is called.
     #[cfg(feature = "sqlite")]
-    pub fn sqlite_store(
+    pub fn sqlite_store(self, path: impl AsRef<std::path::Path>, passphrase: Option<&str>) -> Self {
+        self.sqlite_store_with_config(path, passphrase, Default::default())
+    }
+
+    /// Set up the store configuration for a SQLite store, with the given
+    /// settings for the SQLite databases.
+    ///
+    /// This allows to tune the databases for the workload, e.g. to trade
+    /// durability for speed on mobile devices. See [`sqlite_store()`] for the
+    /// details.
+    ///
+    /// [`sqlite_store()`]: Self::sqlite_store
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_store_with_config(
         mut self,
         path: impl AsRef<std::path::Path>,
         passphrase: Option<&str>,
+        config: matrix_sdk_sqlite::SqliteStoreConfig,
     ) -> Self {
         self.store_config = BuilderStoreConfig::Sqlite {
             path: path.as_ref().to_owned(),
             passphrase: passphrase.map(ToOwned::to_owned),
+            config,
+        };
+        self
+    }
+
+    /// Set up the store configuration for a SQLite store, with a passphrase
+    /// kept in the given [`PassphraseStorage`].
+    ///
+    /// The passphrase of the `account` is generated and saved in the storage
+    /// the first time, and reused afterwards. It is retrieved when
+    /// `.build().await` is called.
+    ///
+    /// [`PassphraseStorage`]: crate::keychain::PassphraseStorage
+    #[cfg(all(feature = "sqlite", feature = "keychain"))]
+    pub fn sqlite_store_with_keychain(
+        self,
+        path: impl AsRef<std::path::Path>,
+        keychain: impl crate::keychain::PassphraseStorage + 'static,
+        account: &str,
+    ) -> Self {
+        self.sqlite_store_with_keychain_and_config(path, keychain, account, Default::default())
+    }
+
+    /// Set up the store configuration for a SQLite store, with a passphrase
+    /// kept in the given [`PassphraseStorage`] and the given settings for the
+    /// SQLite databases.
+    ///
+    /// See [`sqlite_store_with_keychain()`] and [`sqlite_store_with_config()`]
+    /// for the details.
+    ///
+    /// [`PassphraseStorage`]: crate::keychain::PassphraseStorage
+    /// [`sqlite_store_with_keychain()`]: Self::sqlite_store_with_keychain
+    /// [`sqlite_store_with_config()`]: Self::sqlite_store_with_config
+    #[cfg(all(feature = "sqlite", feature = "keychain"))]
+    pub fn sqlite_store_with_keychain_and_config(
+        mut self,
+        path: impl AsRef<std::path::Path>,
+        keychain: impl crate::keychain::PassphraseStorage + 'static,
+        account: &str,
+        config: matrix_sdk_sqlite::SqliteStoreConfig,
+    ) -> Self {
+        self.store_config = BuilderStoreConfig::SqliteWithKeychain {
+            path: path.as_ref().to_owned(),
+            keychain: Arc::new(keychain),
+            account: account.to_owned(),
+            config,
         };
         self
     }
@@ -261,6 +355,42 @@ impl ClientBuilder {
         self
     }
 
+    /// Add a middleware on the HTTP requests sent to the homeserver.
+    ///
+    /// The middlewares are called in the order they were added before a
+    /// request is sent, and in the reverse order after its response is
+    /// received. See [`HttpMiddleware`] for more details.
+    pub fn add_http_middleware(mut self, middleware: impl HttpMiddleware + 'static) -> Self {
+        self.http_middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Limit the number of concurrent requests to the client-server API.
+    ///
+    /// When the limit is reached, the interactive requests, like sending a
+    /// message or syncing, are sent before the background ones, like loading
+    /// the history of a room. See [`RequestPriority`] for more details.
+    ///
+    /// Media downloads are not included in this limit, see
+    /// [`ClientBuilder::max_concurrent_media_downloads()`]. There is no limit
+    /// by default.
+    ///
+    /// [`RequestPriority`]: crate::config::RequestPriority
+    pub fn max_concurrent_requests(mut self, max: usize) -> Self {
+        self.request_limits.api = Some(max);
+        self
+    }
+
+    /// Limit the number of concurrent media downloads.
+    ///
+    /// This is separate from the limit of
+    /// [`ClientBuilder::max_concurrent_requests()`], so a burst of media
+    /// downloads can't delay the other requests. There is no limit by default.
+    pub fn max_concurrent_media_downloads(mut self, max: usize) -> Self {
+        self.request_limits.media = Some(max);
+        self
+    }
+
     /// Specify the Matrix versions supported by the homeserver manually, rather
     /// than `build()` doing it using a `get_supported_versions` request.
     ///
@@ -301,6 +431,48 @@ impl ClientBuilder {
         self
     }
 
+    /// Build a slim client, for resource-constrained environments like IoT
+    /// devices or command-line tools, that only cares about the given rooms.
+    ///
+    /// A minimal client keeps syncing, sending messages and end-to-end
+    /// encryption working, but:
+    ///
+    /// * Unless [`SyncSettings::filter()`] is used, it syncs with a filter that
+    ///   only includes the given rooms, lazy-loads room members and skips
+    ///   presence, ephemeral events and room account data.
+    ///
+    /// * It never stores media in the media cache, regardless of the
+    ///   `use_cache` argument of the [`Media`] methods.
+    ///
+    /// Higher-level features like the timeline of `matrix-sdk-ui` are not meant
+    /// to be used with such a client.
+    ///
+    /// [`SyncSettings::filter()`]: crate::config::SyncSettings::filter
+    /// [`Media`]: crate::Media
+    pub fn minimal(mut self, rooms: impl IntoIterator<Item = OwnedRoomId>) -> Self {
+        self.minimal_rooms = Some(rooms.into_iter().collect());
+        self
+    }
+
+    /// Encrypt the state events of the given types, in the rooms where the
+    /// participants agreed to encrypt state events.
+    ///
+    /// The participants agree by enabling it in the `m.room.encryption` event
+    /// of the room, with [`Joined::enable_state_event_encryption()`]. The
+    /// state events that the homeserver needs to authorize events, like the
+    /// membership or the power levels, are never encrypted.
+    ///
+    /// This is an experimental implementation of [MSC3414], the encrypted
+    /// state events can only be read by clients that implement it too.
+    ///
+    /// [`Joined::enable_state_event_encryption()`]: crate::room::Joined::enable_state_event_encryption
+    /// [MSC3414]: https://github.com/matrix-org/matrix-spec-proposals/pull/3414
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    pub fn encrypt_state_events(mut self, types: impl IntoIterator<Item = StateEventType>) -> Self {
+        self.encrypted_state_event_types = types.into_iter().collect();
+        self
+    }
+
     /// Create a [`Client`] with the options set on this builder.
     ///
     /// # Errors
@@ -330,11 +502,32 @@ impl ClientBuilder {
             HttpConfig::Custom(c) => c,
         };
 
+        // The stores whose passphrase can be rotated.
+        #[cfg(feature = "sqlite")]
+        let sqlite_store = match &self.store_config {
+            BuilderStoreConfig::Sqlite { path, passphrase: Some(_), config } => {
+                Some((path.clone(), config.clone()))
+            }
+            _ => None,
+        };
+
         #[allow(clippy::infallible_destructuring_match)]
         let store_config = match self.store_config {
             #[cfg(feature = "sqlite")]
-            BuilderStoreConfig::Sqlite { path, passphrase } => {
-                matrix_sdk_sqlite::make_store_config(&path, passphrase.as_deref()).await?
+            BuilderStoreConfig::Sqlite { path, passphrase, config } => {
+                matrix_sdk_sqlite::make_store_config_with(&path, passphrase.as_deref(), config)
+                    .await?
+            }
+            #[cfg(all(feature = "sqlite", feature = "keychain"))]
+            BuilderStoreConfig::SqliteWithKeychain { path, keychain, account, config } => {
+                // Accessing the keychain can block, e.g. while the user unlocks it.
+                let passphrase = tokio::task::spawn_blocking(move || {
+                    crate::keychain::get_or_create_passphrase(&*keychain, &account)
+                })
+                .await
+                .expect("Task join error")?;
+                matrix_sdk_sqlite::make_store_config_with(&path, Some(passphrase.as_str()), config)
+                    .await?
             }
             #[cfg(feature = "indexeddb")]
             BuilderStoreConfig::IndexedDb { name, passphrase } => {
@@ -344,45 +537,53 @@ impl ClientBuilder {
         };
 
         let base_client = BaseClient::with_store_config(store_config);
-        let http_client = HttpClient::new(inner_http_client.clone(), self.request_config);
-
-        let mut authentication_server_info = None;
-        #[cfg(feature = "experimental-sliding-sync")]
-        let mut sliding_sync_proxy: Option<Url> = None;
+        let http_client = HttpClient::new(
+            inner_http_client.clone(),
+            self.request_config,
+            self.http_middlewares,
+            self.request_limits,
+        );
+
+        let mut server_versions = self.server_versions;
+        let mut server_name = None;
+        let mut well_known = None;
         let homeserver = match homeserver_cfg {
             HomeserverConfig::Url(url) => url,
-            HomeserverConfig::ServerName(server_name) => {
+            HomeserverConfig::ServerName(name) => {
                 debug!("Trying to discover the homeserver");
 
-                let homeserver = homeserver_from_name(&server_name);
-                let well_known = http_client
+                let discovered = ClientWellKnown::fetch(&http_client, &name).await?;
+                let homeserver = discovered.homeserver.to_string();
+
+                // Make sure that the discovered server is a Matrix homeserver.
+                let versions = http_client
                     .send(
-                        discover_homeserver::Request::new(),
+                        get_supported_versions::Request::new(),
                         Some(RequestConfig::short_retry()),
-                        homeserver,
+                        homeserver.clone(),
                         None,
                         None,
                         &[MatrixVersion::V1_0],
                         Default::default(),
                     )
                     .await
-                    .map_err(|e| match e {
-                        HttpError::Api(err) => ClientBuildError::AutoDiscovery(err),
-                        err => ClientBuildError::Http(err),
+                    .map_err(|source| WellKnownError::NotAHomeserver {
+                        url: discovered.homeserver.clone(),
+                        source,
                     })?;
 
-                authentication_server_info = well_known.authentication;
-
-                #[cfg(feature = "experimental-sliding-sync")]
-                if let Some(proxy) = well_known.sliding_sync_proxy.map(|p| p.url) {
-                    sliding_sync_proxy = Url::parse(&proxy).ok();
+                if server_versions.is_none() {
+                    let known_versions: Box<[MatrixVersion]> = versions.known_versions().collect();
+                    if !known_versions.is_empty() {
+                        server_versions = Some(known_versions);
+                    }
                 }
-                debug!(
-                    homeserver_url = well_known.homeserver.base_url,
-                    "Discovered the homeserver"
-                );
 
-                well_known.homeserver.base_url
+                debug!(homeserver_url = homeserver, "Discovered the homeserver");
+
+                server_name = Some(name);
+                well_known = Some(discovered);
+                homeserver
             }
         };
 
@@ -390,31 +591,67 @@ impl ClientBuilder {
 
         let (unknown_token_error_sender, _) = broadcast::channel(1);
 
-        let inner = Arc::new(ClientInner {
+        let authentication_server_info =
+            well_known.as_ref().and_then(|well_known| well_known.authentication.clone());
+        #[cfg(feature = "experimental-sliding-sync")]
+        let sliding_sync_proxy =
+            well_known.as_ref().and_then(|well_known| well_known.sliding_sync_proxy.clone());
+        let well_known_refresh_interval =
+            self.well_known_refresh_interval.filter(|_| server_name.is_some());
+
+        let inner = Arc::new_cyclic(|weak_inner| ClientInner {
             homeserver,
             authentication_server_info,
+            server_name,
+            well_known: StdRwLock::new(well_known),
+            _well_known_refresh_task: well_known_refresh_interval.map(|interval| {
+                spawn(well_known::refresh_periodically(weak_inner.clone(), interval))
+            }),
             #[cfg(feature = "experimental-sliding-sync")]
             sliding_sync_proxy: StdRwLock::new(sliding_sync_proxy),
             http_client,
             base_client,
-            server_versions: OnceCell::new_with(self.server_versions),
+            server_versions: OnceCell::new_with(server_versions),
+            server_capabilities: Default::default(),
             #[cfg(feature = "e2e-encryption")]
             group_session_locks: Default::default(),
             #[cfg(feature = "e2e-encryption")]
             key_claim_lock: Default::default(),
+            #[cfg(feature = "e2e-encryption")]
+            untrusted_devices_handler: Default::default(),
+            #[cfg(feature = "e2e-encryption")]
+            cross_process_crypto_store_lock: OnceCell::new(),
+            #[cfg(feature = "e2e-encryption")]
+            crypto_store_generation: Default::default(),
             members_request_locks: Default::default(),
             encryption_state_request_locks: Default::default(),
             typing_notice_times: Default::default(),
+            #[cfg(feature = "matrixrtc")]
+            call_member_locks: Default::default(),
+            direct_rooms_lock: Default::default(),
+            client_settings_locks: Default::default(),
+            dm_lock: Default::default(),
+            room_alias_cache: Default::default(),
             event_handlers: Default::default(),
             notification_handlers: Default::default(),
             room_update_channels: Default::default(),
             sync_gap_broadcast_txs: Default::default(),
+            presence_channels: Default::default(),
+            presence_setter: Default::default(),
             appservice_mode: self.appservice_mode,
+            minimal_rooms: self.minimal_rooms,
+            #[cfg(feature = "experimental-encrypted-state-events")]
+            encrypted_state_event_types: self.encrypted_state_event_types,
             respect_login_well_known: self.respect_login_well_known,
             sync_beat: event_listener::Event::new(),
             handle_refresh_tokens: self.handle_refresh_tokens,
             refresh_token_lock: Mutex::new(Ok(())),
             unknown_token_error_sender,
+            session_verdict: Default::default(),
+            #[cfg(feature = "sqlite")]
+            sqlite_store,
+            #[cfg(all(feature = "bot", not(target_arch = "wasm32")))]
+            bot: Default::default(),
         });
 
         debug!("Done building the Client");
@@ -423,7 +660,7 @@ impl ClientBuilder {
     }
 }
 
-fn homeserver_from_name(server_name: &ServerName) -> String {
+pub(super) fn homeserver_from_name(server_name: &ServerName) -> String {
     #[cfg(not(test))]
     return format!("https://{server_name}");
 
@@ -478,6 +715,14 @@ enum BuilderStoreConfig {
     Sqlite {
         path: std::path::PathBuf,
         passphrase: Option<String>,
+        config: matrix_sdk_sqlite::SqliteStoreConfig,
+    },
+    #[cfg(all(feature = "sqlite", feature = "keychain"))]
+    SqliteWithKeychain {
+        path: std::path::PathBuf,
+        keychain: Arc<dyn crate::keychain::PassphraseStorage>,
+        account: String,
+        config: matrix_sdk_sqlite::SqliteStoreConfig,
     },
     #[cfg(feature = "indexeddb")]
     IndexedDb {
@@ -493,9 +738,19 @@ impl fmt::Debug for BuilderStoreConfig {
         #[allow(clippy::infallible_destructuring_match)]
         match self {
             #[cfg(feature = "sqlite")]
-            Self::Sqlite { path, .. } => {
-                f.debug_struct("Sqlite").field("path", path).finish_non_exhaustive()
-            }
+            Self::Sqlite { path, config, .. } => f
+                .debug_struct("Sqlite")
+                .field("path", path)
+                .field("config", config)
+                .finish_non_exhaustive(),
+            #[cfg(all(feature = "sqlite", feature = "keychain"))]
+            Self::SqliteWithKeychain { path, keychain, account, config } => f
+                .debug_struct("SqliteWithKeychain")
+                .field("path", path)
+                .field("keychain", keychain)
+                .field("account", account)
+                .field("config", config)
+                .finish(),
             #[cfg(feature = "indexeddb")]
             Self::IndexedDb { name, .. } => {
                 f.debug_struct("IndexedDb").field("name", name).finish_non_exhaustive()
@@ -513,8 +768,8 @@ pub enum ClientBuildError {
     MissingHomeserver,
 
     /// Error looking up the .well-known endpoint on auto-discovery
-    #[error("Error looking up the .well-known endpoint on auto-discovery")]
-    AutoDiscovery(FromHttpResponseError<RumaApiError>),
+    #[error("Error looking up the .well-known endpoint on auto-discovery: {0}")]
+    AutoDiscovery(#[from] WellKnownError),
 
     /// An error encountered when trying to parse the homeserver url.
     #[error(transparent)]
@@ -533,6 +788,11 @@ pub enum ClientBuildError {
     #[cfg(feature = "sqlite")]
     #[error(transparent)]
     SqliteStore(#[from] matrix_sdk_sqlite::OpenStoreError),
+
+    /// Error getting the passphrase of the store from the keychain.
+    #[cfg(feature = "keychain")]
+    #[error(transparent)]
+    Keychain(#[from] crate::keychain::KeychainError),
 }
 
 impl ClientBuildError {
@@ -0,0 +1,79 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use ruma::{
+    api::{
+        client::discovery::{get_capabilities::Capabilities, get_supported_versions},
+        MatrixVersion,
+    },
+    RoomVersionId,
+};
+
+/// What a homeserver supports, as returned by
+/// [`Client::server_capabilities()`].
+///
+/// [`Client::server_capabilities()`]: crate::Client::server_capabilities
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ServerCapabilities {
+    /// The versions of the Matrix specification supported by the homeserver.
+    ///
+    /// Only the versions known by the SDK are listed.
+    pub versions: Vec<MatrixVersion>,
+
+    /// The unstable features advertised by the homeserver, usually named
+    /// after the MSC introducing them, and whether they are enabled.
+    pub unstable_features: BTreeMap<String, bool>,
+
+    /// The capabilities advertised by the homeserver on the `/capabilities`
+    /// endpoint.
+    pub capabilities: Capabilities,
+}
+
+impl ServerCapabilities {
+    pub(crate) fn new(
+        versions: get_supported_versions::Response,
+        capabilities: Capabilities,
+    ) -> Self {
+        Self {
+            versions: versions.known_versions().collect(),
+            unstable_features: versions.unstable_features,
+            capabilities,
+        }
+    }
+
+    /// Whether the homeserver supports the given version of the Matrix
+    /// specification.
+    pub fn supports_version(&self, version: MatrixVersion) -> bool {
+        self.versions.contains(&version)
+    }
+
+    /// Whether the homeserver advertises the given unstable feature as
+    /// enabled, e.g. `org.matrix.msc3575` for sliding sync.
+    pub fn supports_unstable_feature(&self, feature: &str) -> bool {
+        self.unstable_features.get(feature).copied().unwrap_or(false)
+    }
+
+    /// The version of new rooms, if it isn't specified at their creation.
+    pub fn default_room_version(&self) -> &RoomVersionId {
+        &self.capabilities.room_versions.default
+    }
+
+    /// Whether users can change their password.
+    pub fn can_change_password(&self) -> bool {
+        self.capabilities.change_password.enabled
+    }
+}
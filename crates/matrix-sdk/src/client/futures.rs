@@ -8,6 +8,8 @@ use cfg_vis::cfg_vis;
 use eyeball::shared::Observable as SharedObservable;
 #[cfg(not(target_arch = "wasm32"))]
 use eyeball::Subscriber;
+#[cfg(feature = "sqlite")]
+use matrix_sdk_sqlite::PassphraseRotationProgress;
 use ruma::api::{client::error::ErrorKind, error::FromHttpResponseError, OutgoingRequest};
 
 use super::super::Client;
@@ -83,17 +85,72 @@ where
                                 // this `Session`, ignore.
                             }
                             _ => {
-                                return Err(refresh_error);
+                                let res = Err(refresh_error);
+                                client.update_session_verdict(&res);
+                                return res;
                             }
                         }
                     } else {
-                        return Box::pin(client.send_inner(request, config, None, send_progress))
-                            .await;
+                        let res =
+                            Box::pin(client.send_inner(request, config, None, send_progress)).await;
+                        client.update_session_verdict(&res);
+                        return res;
                     }
                 }
             }
 
+            client.update_session_verdict(&res);
             res
         })
     }
 }
+
+/// `IntoFuture` returned by [`Client::rotate_store_passphrase`].
+#[cfg(feature = "sqlite")]
+#[allow(missing_debug_implementations)]
+pub struct RotateStorePassphrase<'a> {
+    pub(crate) client: &'a Client,
+    pub(crate) old_passphrase: &'a str,
+    pub(crate) new_passphrase: &'a str,
+    pub(crate) progress: SharedObservable<Option<PassphraseRotationProgress>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl<'a> RotateStorePassphrase<'a> {
+    /// Subscribe to the progress of the rotation.
+    ///
+    /// It is `None` until the first step was applied to a database.
+    pub fn subscribe_to_progress(&self) -> eyeball::Subscriber<Option<PassphraseRotationProgress>> {
+        self.progress.subscribe()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl<'a> IntoFuture for RotateStorePassphrase<'a> {
+    type Output = crate::Result<()>;
+    #[cfg(target_arch = "wasm32")]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + 'a>>;
+    #[cfg(not(target_arch = "wasm32"))]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send + 'a>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let Self { client, old_passphrase, new_passphrase, progress } = self;
+        Box::pin(async move {
+            let (path, config) =
+                client.inner.sqlite_store.clone().ok_or(crate::Error::NoStorePassphrase)?;
+
+            matrix_sdk_sqlite::rotate_passphrase_with_config(
+                path,
+                old_passphrase,
+                new_passphrase,
+                config,
+                |p| {
+                    progress.set(Some(p));
+                },
+            )
+            .await?;
+
+            Ok(())
+        })
+    }
+}
@@ -14,31 +14,39 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "experimental-sliding-sync")]
-use std::sync::RwLock as StdRwLock;
 use std::{
     collections::{btree_map, BTreeMap},
     fmt::{self, Debug},
     future::Future,
     pin::Pin,
-    sync::{Arc, Mutex as StdMutex},
+    sync::{Arc, Mutex as StdMutex, RwLock as StdRwLock},
+    time::Duration,
 };
 
 use dashmap::DashMap;
 use eyeball::{shared::Observable as SharedObservable, unique::Observable, Subscriber};
 use futures_core::Stream;
 use futures_util::StreamExt;
+#[cfg(feature = "e2e-encryption")]
+use matrix_sdk_base::crypto::store::locks::CryptoStoreLock;
 use matrix_sdk_base::{
     store::DynStateStore, BaseClient, RoomState, RoomStateFilter, SendOutsideWasm, Session,
-    SessionMeta, SessionTokens, SyncOutsideWasm,
+    SessionMeta, SessionTokens, StateChanges, SyncOutsideWasm,
 };
-use matrix_sdk_common::instant::Instant;
+use matrix_sdk_common::{executor::JoinHandle, instant::Instant, metrics};
+#[cfg(feature = "experimental-encrypted-state-events")]
+use ruma::events::StateEventType;
+#[cfg(feature = "e2e-encryption")]
+use ruma::events::{room::encryption::RoomEncryptionEventContent, InitialStateEvent};
 #[cfg(feature = "appservice")]
 use ruma::TransactionId;
 use ruma::{
     api::{
         client::{
-            account::{register, whoami},
+            account::{
+                register::{self, RegistrationKind},
+                whoami,
+            },
             alias::get_alias,
             device::{delete_devices, get_devices, update_device},
             directory::{get_public_rooms, get_public_rooms_filtered},
@@ -48,7 +56,10 @@ use ruma::{
                 get_supported_versions,
             },
             error::ErrorKind,
-            filter::{create_filter::v3::Request as FilterUploadRequest, FilterDefinition},
+            filter::{
+                create_filter::v3::Request as FilterUploadRequest, Filter, FilterDefinition,
+                RoomEventFilter,
+            },
             membership::{join_room_by_id, join_room_by_id_or_alias},
             profile::get_profile,
             push::{get_notifications::v3::Notification, set_pusher, Pusher},
@@ -56,6 +67,7 @@ use ruma::{
             session::{
                 get_login_types, login, logout, refresh_token, sso_login, sso_login_with_provider,
             },
+            state::get_state_events,
             sync::sync_events,
             uiaa::{AuthData, UserIdentifier},
             user_directory::search_users,
@@ -64,41 +76,71 @@ use ruma::{
         MatrixVersion, OutgoingRequest, SendAccessToken,
     },
     assign,
+    events::{presence::PresenceEventContent, room::member::MembershipState},
     serde::JsonObject,
-    DeviceId, OwnedDeviceId, OwnedRoomId, OwnedServerName, RoomAliasId, RoomId, RoomOrAliasId,
-    ServerName, UInt, UserId,
+    DeviceId, OwnedDeviceId, OwnedRoomAliasId, OwnedRoomId, OwnedServerName, OwnedUserId,
+    RoomAliasId, RoomId, RoomOrAliasId, ServerName, UInt, UserId,
 };
 use serde::de::DeserializeOwned;
 use tokio::sync::{broadcast, Mutex, OnceCell, RwLock, RwLockReadGuard};
 use tracing::{debug, error, info, instrument, trace, Instrument, Span};
 use url::Url;
 
+#[cfg(all(feature = "bot", not(target_arch = "wasm32")))]
+use crate::bot::{ClientBot, Commands};
+#[cfg(feature = "diagnostics")]
+use crate::diagnostics::Diagnostics;
 #[cfg(feature = "e2e-encryption")]
 use crate::encryption::Encryption;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::http_client::StreamedResponse;
 use crate::{
+    authentication::Auth,
     config::RequestConfig,
+    contacts::Contacts,
+    devices::DeviceList,
     error::{HttpError, HttpResult},
     event_handler::{
         EventHandler, EventHandlerDropGuard, EventHandlerHandle, EventHandlerStore, SyncEvent,
     },
-    http_client::HttpClient,
+    http_client::{EndpointClass, HttpClient, NetworkStatus, RateLimited},
+    invites::{self, RoomInvite},
+    notification_settings::NotificationSettings,
+    presence::{Presence, PresenceSetter},
     room,
-    sync::{RoomUpdate, SyncResponse},
+    room_preview::RoomPreview,
+    spaces::SpaceNotificationCounts,
+    sync::{RoomUpdate, SyncProcessingPolicy, SyncProgress, SyncResponse, SyncResponseProcessor},
+    uri::{ResolvedUri, UriTarget},
     Account, Error, Media, RefreshTokenError, Result, RumaApiError, TransmissionProgress,
 };
 
 mod builder;
+mod capabilities;
 mod futures;
 mod login_builder;
+mod well_known;
 
+#[cfg(feature = "sqlite")]
+pub use self::futures::RotateStorePassphrase;
 #[cfg(feature = "sso-login")]
 pub use self::login_builder::SsoLoginBuilder;
 pub use self::{
     builder::{ClientBuildError, ClientBuilder},
+    capabilities::ServerCapabilities,
     futures::SendRequest,
     login_builder::LoginBuilder,
+    well_known::{ClientWellKnown, WellKnownError},
 };
 
+/// How long a resolved room alias is cached by
+/// [`Client::resolve_room_alias()`].
+const ROOM_ALIAS_CACHE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// How many resolved room aliases are cached by
+/// [`Client::resolve_room_alias()`] at most.
+const ROOM_ALIAS_CACHE_CAPACITY: usize = 100;
+
 #[cfg(not(target_arch = "wasm32"))]
 type NotificationHandlerFut = Pin<Box<dyn Future<Output = ()> + Send>>;
 #[cfg(target_arch = "wasm32")]
@@ -132,6 +174,37 @@ pub struct UnknownToken {
     pub soft_logout: bool,
 }
 
+/// Whether the session of the [`Client`] is still usable.
+///
+/// This is updated every time a request fails because the access token is
+/// unknown to the homeserver, after trying to refresh it if the client handles
+/// refresh tokens. It gives apps a single signal to decide when to show a
+/// login screen, instead of having to inspect the errors of every request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionVerdict {
+    /// The access token is accepted by the homeserver, or no request was
+    /// refused yet.
+    #[default]
+    Valid,
+    /// The homeserver soft logged out the session.
+    ///
+    /// The user needs to log in again, but the device ID can be reused to keep
+    /// the encryption keys and the local data.
+    SoftLogout,
+    /// The session was revoked, for example because the device was logged out
+    /// from another client.
+    ///
+    /// The local data of the session should be discarded.
+    Revoked,
+}
+
+impl SessionVerdict {
+    /// Whether the user needs to log in again.
+    pub fn needs_login(&self) -> bool {
+        !matches!(self, Self::Valid)
+    }
+}
+
 /// An async/await enabled Matrix client.
 ///
 /// All of the state is held in an `Arc` so the `Client` can be cloned freely.
@@ -145,15 +218,26 @@ pub(crate) struct ClientInner {
     homeserver: RwLock<Url>,
     /// The authentication server info discovered from the homeserver.
     authentication_server_info: Option<AuthenticationServerInfo>,
+    /// The server name used to discover the homeserver, if any.
+    server_name: Option<OwnedServerName>,
+    /// The configuration from the `.well-known` file of the server, if the
+    /// homeserver was discovered with it.
+    well_known: StdRwLock<Option<ClientWellKnown>>,
+    /// The task refreshing `well_known` periodically, if any. It is aborted
+    /// when its handle is dropped on WASM, so it must be kept here.
+    _well_known_refresh_task: Option<JoinHandle<()>>,
     /// The sliding sync proxy that is trusted by the homeserver.
     #[cfg(feature = "experimental-sliding-sync")]
     sliding_sync_proxy: StdRwLock<Option<Url>>,
     /// The underlying HTTP client.
-    http_client: HttpClient,
+    pub(crate) http_client: HttpClient,
     /// User session data.
     base_client: BaseClient,
     /// The Matrix versions the server supports (well-known ones only)
     server_versions: OnceCell<Box<[MatrixVersion]>>,
+    /// The capabilities of the server, fetched on the first call to
+    /// `server_capabilities()`.
+    server_capabilities: Mutex<Option<ServerCapabilities>>,
     /// Locks making sure we only have one group session sharing request in
     /// flight per room.
     #[cfg(feature = "e2e-encryption")]
@@ -161,20 +245,57 @@ pub(crate) struct ClientInner {
     /// Lock making sure we're only doing one key claim request at a time.
     #[cfg(feature = "e2e-encryption")]
     pub(crate) key_claim_lock: Mutex<()>,
+    /// See [`Encryption::set_untrusted_devices_handler()`].
+    #[cfg(feature = "e2e-encryption")]
+    pub(crate) untrusted_devices_handler:
+        StdRwLock<Option<crate::encryption::UntrustedDevicesHandlerFn>>,
+    /// See [`Encryption::enable_cross_process_store_lock()`].
+    #[cfg(feature = "e2e-encryption")]
+    pub(crate) cross_process_crypto_store_lock: OnceCell<Mutex<CryptoStoreLock>>,
+    /// The generation of the crypto store last seen by this process, to detect
+    /// that another process modified it.
+    #[cfg(feature = "e2e-encryption")]
+    pub(crate) crypto_store_generation: Mutex<Option<u64>>,
     pub(crate) members_request_locks: Mutex<BTreeMap<OwnedRoomId, Arc<Mutex<()>>>>,
     /// Locks for requests on the encryption state of rooms.
     pub(crate) encryption_state_request_locks: DashMap<OwnedRoomId, Arc<Mutex<()>>>,
     pub(crate) typing_notice_times: DashMap<OwnedRoomId, Instant>,
+    /// Locks for the updates of the call member state event of the user, see
+    /// [`matrixrtc`](crate::matrixrtc).
+    #[cfg(feature = "matrixrtc")]
+    pub(crate) call_member_locks: DashMap<OwnedRoomId, Arc<Mutex<()>>>,
+    /// Lock for the updates of the `m.direct` account data event.
+    pub(crate) direct_rooms_lock: Mutex<()>,
+    /// Locks for the updates of the client settings of the rooms, see
+    /// [`Common::update_client_settings()`](crate::room::Common::update_client_settings).
+    pub(crate) client_settings_locks: DashMap<OwnedRoomId, Arc<Mutex<()>>>,
+    /// Lock to find or create a DM, see [`Client::dm_with()`].
+    dm_lock: Mutex<()>,
+    /// The resolved room aliases, with the time when they were resolved.
+    pub(crate) room_alias_cache: DashMap<OwnedRoomAliasId, (Instant, get_alias::v3::Response)>,
     /// Event handlers. See `add_event_handler`.
     pub(crate) event_handlers: EventHandlerStore,
     /// Notification handlers. See `register_notification_handler`.
     notification_handlers: RwLock<Vec<NotificationHandlerFn>>,
     pub(crate) room_update_channels: StdMutex<BTreeMap<OwnedRoomId, broadcast::Sender<RoomUpdate>>>,
     pub(crate) sync_gap_broadcast_txs: StdMutex<BTreeMap<OwnedRoomId, Observable<()>>>,
+    /// The senders of the presence updates of the observed users. See
+    /// [`Presence::observe()`].
+    pub(crate) presence_channels:
+        StdMutex<BTreeMap<OwnedUserId, broadcast::Sender<PresenceEventContent>>>,
+    /// The state of the requests to set the presence of the user.
+    pub(crate) presence_setter: PresenceSetter,
     /// Whether the client should operate in application service style mode.
     /// This is low-level functionality. For an high-level API check the
     /// `matrix_sdk_appservice` crate.
     appservice_mode: bool,
+    /// The rooms the client is restricted to, if it was built in minimal mode.
+    /// See [`ClientBuilder::minimal()`].
+    minimal_rooms: Option<Box<[OwnedRoomId]>>,
+    /// The types of the state events to encrypt. See
+    /// [`ClientBuilder::encrypt_state_events()`].
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    pub(crate) encrypted_state_event_types: Box<[StateEventType]>,
     /// Whether the client should update its homeserver URL with the discovery
     /// information present in the login response.
     respect_login_well_known: bool,
@@ -192,6 +313,15 @@ pub(crate) struct ClientInner {
     /// Client API UnknownToken error publisher. Allows the subscriber logout
     /// the user when any request fails because of an invalid access token
     pub(crate) unknown_token_error_sender: broadcast::Sender<UnknownToken>,
+    /// Whether the session is still accepted by the homeserver.
+    pub(crate) session_verdict: SharedObservable<SessionVerdict>,
+    /// The directory and the settings of the SQLite stores, if they are
+    /// encrypted with a passphrase.
+    #[cfg(feature = "sqlite")]
+    pub(crate) sqlite_store: Option<(std::path::PathBuf, matrix_sdk_sqlite::SqliteStoreConfig)>,
+    /// The bot dispatching the commands registered with [`Client::commands()`].
+    #[cfg(all(feature = "bot", not(target_arch = "wasm32")))]
+    pub(crate) bot: ClientBot,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -221,6 +351,151 @@ impl Client {
         self.inner.base_client.subscribe_to_ignore_user_list_changes()
     }
 
+    /// Returns a subscriber that publishes the progress of the processing of
+    /// the rooms of the sync responses.
+    ///
+    /// This can be used to show how far the processing of a large response,
+    /// like the one of an initial sync, is.
+    pub fn subscribe_to_sync_progress(&self) -> Subscriber<SyncProgress> {
+        self.inner.base_client.subscribe_to_sync_progress()
+    }
+
+    /// Returns a receiver of the changes to the state of the rooms and of the
+    /// account, after they are persisted in the state store and applied to
+    /// the rooms.
+    ///
+    /// This allows to react incrementally to the changes, like new account
+    /// data or room state events, instead of querying the store again after
+    /// every sync response.
+    pub fn subscribe_to_state_changes(&self) -> broadcast::Receiver<Arc<StateChanges>> {
+        self.inner.base_client.subscribe_to_state_changes()
+    }
+
+    /// Add a processor that is called with the changes of every sync response,
+    /// before they are saved in the state store.
+    ///
+    /// This allows to maintain custom data, like indexes of account data,
+    /// in the same transaction as the state of the SDK. See
+    /// [`SyncResponseProcessor`] for more details.
+    pub fn add_sync_response_processor(&self, processor: Arc<dyn SyncResponseProcessor>) {
+        self.inner.base_client.add_sync_response_processor(processor);
+    }
+
+    /// Set the policy deciding which data of the sync responses is saved in
+    /// the state store.
+    ///
+    /// This allows bridges and bots in huge rooms to skip the presence, the
+    /// receipts or the member events they don't need, to reduce the writes to
+    /// the store. See [`SyncProcessingPolicy`] for more details.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, sync::SyncProcessingPolicy};
+    /// # async {
+    /// # let client: Client = unimplemented!();
+    /// client.set_sync_processing_policy(
+    ///     SyncProcessingPolicy::new().skip_presence().skip_receipts_above(1000),
+    /// );
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub fn set_sync_processing_policy(&self, policy: SyncProcessingPolicy) {
+        self.inner.base_client.set_sync_processing_policy(policy);
+    }
+
+    /// Set whether the device is connected to the network.
+    ///
+    /// This should be called by the network monitor of the platform every
+    /// time the connectivity changes. While the device is offline, the
+    /// requests wait until it is back online before being sent, which pauses
+    /// the sync loops and the media downloads. When it is back online, the
+    /// waiting requests are sent right away, and the pending `GET` requests,
+    /// like a long-polling sync, are sent again instead of waiting for a
+    /// response on a lost connection.
+    pub fn set_network_status(&self, status: NetworkStatus) {
+        self.inner.http_client.set_network_status(status);
+    }
+
+    /// Get whether the device is connected to the network, as set with
+    /// [`Client::set_network_status()`].
+    pub fn network_status(&self) -> NetworkStatus {
+        self.inner.http_client.network_status()
+    }
+
+    /// Returns a subscriber that publishes the network status every time it
+    /// changes, to show the connection state.
+    pub fn subscribe_to_network_status(&self) -> Subscriber<NetworkStatus> {
+        self.inner.http_client.subscribe_to_network_status()
+    }
+
+    /// Get whether the session is still accepted by the homeserver.
+    ///
+    /// It is [`SessionVerdict::Valid`] again after logging in or restoring a
+    /// session.
+    pub fn session_verdict(&self) -> SessionVerdict {
+        self.inner.session_verdict.get()
+    }
+
+    /// Returns a subscriber that publishes the [`SessionVerdict`] every time it
+    /// changes, to show a login screen when the session can't be used anymore.
+    pub fn subscribe_to_session_verdict(&self) -> Subscriber<SessionVerdict> {
+        self.inner.session_verdict.subscribe()
+    }
+
+    /// Update the [`SessionVerdict`] with the final result of a request.
+    pub(crate) fn update_session_verdict<T>(&self, result: &HttpResult<T>) {
+        if let Err(Some(ErrorKind::UnknownToken { soft_logout })) =
+            result.as_ref().map_err(HttpError::client_api_error_kind)
+        {
+            let verdict =
+                if *soft_logout { SessionVerdict::SoftLogout } else { SessionVerdict::Revoked };
+            self.inner.session_verdict.set_if_not_eq(verdict);
+        }
+    }
+
+    /// Encrypt the stores of the client with a new passphrase.
+    ///
+    /// This only works if the client was built with
+    /// [`ClientBuilder::sqlite_store()`] and a passphrase. The state store,
+    /// which includes the media cache, and the crypto store are updated in
+    /// place while the client keeps using them, and the new passphrase must
+    /// be used the next time the client is built.
+    ///
+    /// The keys that encrypt the data are themselves encrypted with the
+    /// passphrase, so rotating it is fast whatever the size of the stores. If
+    /// the rotation is interrupted, both passphrases can open the stores until
+    /// it is run again. See [`matrix_sdk_sqlite::rotate_passphrase()`] for the
+    /// details.
+    ///
+    /// The data is not encrypted again, so a copy of the stores taken before
+    /// the rotation can still be decrypted with the old passphrase.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # async {
+    /// # let client: Client = unimplemented!();
+    /// let rotation = client.rotate_store_passphrase("old-secret", "new-secret");
+    /// let mut progress = rotation.subscribe_to_progress();
+    ///
+    /// rotation.await?;
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[cfg(feature = "sqlite")]
+    pub fn rotate_store_passphrase<'a>(
+        &'a self,
+        old_passphrase: &'a str,
+        new_passphrase: &'a str,
+    ) -> RotateStorePassphrase<'a> {
+        RotateStorePassphrase {
+            client: self,
+            old_passphrase,
+            new_passphrase,
+            progress: Default::default(),
+        }
+    }
+
     /// Create a new [`ClientBuilder`].
     pub fn builder() -> ClientBuilder {
         ClientBuilder::new()
@@ -235,9 +510,12 @@ impl Client {
     /// # Arguments
     ///
     /// * `homeserver_url` - The new URL to use.
-    async fn set_homeserver(&self, homeserver_url: Url) {
+    pub(crate) async fn set_homeserver(&self, homeserver_url: Url) {
         let mut homeserver = self.inner.homeserver.write().await;
         *homeserver = homeserver_url;
+
+        // The capabilities of the previous homeserver are irrelevant.
+        self.inner.server_capabilities.lock().await.take();
     }
 
     /// Get the capabilities of the homeserver.
@@ -266,6 +544,64 @@ impl Client {
         Ok(res.capabilities)
     }
 
+    /// Get what the homeserver supports: the versions of the specification,
+    /// the unstable features and the capabilities.
+    ///
+    /// They are only fetched on the first call and cached afterwards, use
+    /// [`refresh_server_capabilities()`] to fetch them again.
+    ///
+    /// The client must be logged in, the capabilities require authentication.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let server = client.server_capabilities().await?;
+    ///
+    /// if server.supports_unstable_feature("org.matrix.msc3575") {
+    ///     // Use the native sliding sync implementation.
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    ///
+    /// [`refresh_server_capabilities()`]: Self::refresh_server_capabilities
+    pub async fn server_capabilities(&self) -> HttpResult<ServerCapabilities> {
+        let mut cached = self.inner.server_capabilities.lock().await;
+
+        if let Some(capabilities) = &*cached {
+            return Ok(capabilities.clone());
+        }
+
+        let capabilities = self.request_server_capabilities().await?;
+        *cached = Some(capabilities.clone());
+
+        Ok(capabilities)
+    }
+
+    /// Fetch what the homeserver supports again, and update the cache used by
+    /// [`server_capabilities()`].
+    ///
+    /// [`server_capabilities()`]: Self::server_capabilities
+    pub async fn refresh_server_capabilities(&self) -> HttpResult<ServerCapabilities> {
+        let mut cached = self.inner.server_capabilities.lock().await;
+
+        let capabilities = self.request_server_capabilities().await?;
+        *cached = Some(capabilities.clone());
+
+        Ok(capabilities)
+    }
+
+    async fn request_server_capabilities(&self) -> HttpResult<ServerCapabilities> {
+        let versions = self.request_supported_versions().await?;
+        let capabilities = self.get_capabilities().await?;
+
+        Ok(ServerCapabilities::new(versions, capabilities))
+    }
+
     /// Process a [transaction] received from the homeserver which has been
     /// converted into a sync response.
     ///
@@ -328,6 +664,12 @@ impl Client {
         self.inner.base_client.logged_in()
     }
 
+    /// The rooms this client is restricted to, if it was built with
+    /// [`ClientBuilder::minimal()`].
+    pub fn minimal_rooms(&self) -> Option<&[OwnedRoomId]> {
+        self.inner.minimal_rooms.as_deref()
+    }
+
     /// The Homeserver of the client.
     pub async fn homeserver(&self) -> Url {
         self.inner.homeserver.read().await.clone()
@@ -345,6 +687,39 @@ impl Client {
         self.inner.authentication_server_info.as_ref()
     }
 
+    /// The configuration from the `.well-known/matrix/client` file of the
+    /// server.
+    ///
+    /// This will only be set if this `Client` was constructed using
+    /// auto-discovery by setting the homeserver with
+    /// [`ClientBuilder::server_name()`]. It is kept up to date in the
+    /// background if [`ClientBuilder::well_known_refresh_interval()`] was
+    /// used, and can be refreshed manually with
+    /// [`refresh_well_known()`](Self::refresh_well_known).
+    pub fn well_known(&self) -> Option<ClientWellKnown> {
+        self.inner.well_known.read().unwrap().clone()
+    }
+
+    /// Fetch the `.well-known/matrix/client` file of the server again, and
+    /// update the configuration returned by
+    /// [`well_known()`](Self::well_known).
+    ///
+    /// The homeserver used by the client doesn't change, even if the file now
+    /// points to another one.
+    ///
+    /// Returns `None` if this `Client` wasn't constructed using
+    /// auto-discovery.
+    pub async fn refresh_well_known(&self) -> Result<Option<ClientWellKnown>, WellKnownError> {
+        let Some(server_name) = &self.inner.server_name else {
+            return Ok(None);
+        };
+
+        let well_known = ClientWellKnown::fetch(&self.inner.http_client, server_name).await?;
+        *self.inner.well_known.write().unwrap() = Some(well_known.clone());
+
+        Ok(Some(well_known))
+    }
+
     /// The sliding sync proxy that is trusted by the homeserver.
     #[cfg(feature = "experimental-sliding-sync")]
     pub fn sliding_sync_proxy(&self) -> Option<Url> {
@@ -558,6 +933,12 @@ impl Client {
         Account::new(self.clone())
     }
 
+    /// Get the authentication manager of the client, to authenticate the
+    /// other devices of the user.
+    pub fn auth(&self) -> Auth {
+        Auth::new(self.clone())
+    }
+
     /// Get the encryption manager of the client.
     #[cfg(feature = "e2e-encryption")]
     pub fn encryption(&self) -> Encryption {
@@ -569,6 +950,43 @@ impl Client {
         Media::new(self.clone())
     }
 
+    /// Get the notification settings of the account.
+    pub fn notification_settings(&self) -> NotificationSettings {
+        NotificationSettings::new(self.clone())
+    }
+
+    /// Get the presence API of the client.
+    pub fn presence(&self) -> Presence {
+        Presence::new(self.clone())
+    }
+
+    /// Get the contact book of the client.
+    pub fn contacts(&self) -> Contacts {
+        Contacts::new(self.clone())
+    }
+
+    /// Get an observable rollup of the unread notification counts of the
+    /// joined rooms in every joined space of the user.
+    ///
+    /// See [`SpaceNotificationCounts`] for more details.
+    pub async fn space_notification_counts(&self) -> Result<SpaceNotificationCounts> {
+        SpaceNotificationCounts::new(self.clone()).await
+    }
+
+    /// Get the commands registered on the client.
+    ///
+    /// See [`Commands`] for more details.
+    #[cfg(all(feature = "bot", not(target_arch = "wasm32")))]
+    pub fn commands(&self) -> Commands {
+        Commands::new(self.clone())
+    }
+
+    /// Get the diagnostics manager of the client.
+    #[cfg(feature = "diagnostics")]
+    pub fn diagnostics(&self) -> Diagnostics {
+        Diagnostics::new(self.clone())
+    }
+
     /// Register a handler for a specific event type.
     ///
     /// The handler is a function or closure with one or more arguments. The
@@ -703,7 +1121,69 @@ impl Client {
         Ev: SyncEvent + DeserializeOwned + Send + 'static,
         H: EventHandler<Ev, Ctx>,
     {
-        self.add_event_handler_impl(handler, None)
+        self.add_event_handler_impl(handler, None, 0)
+    }
+
+    /// Register a handler for a specific event type, with the given priority.
+    ///
+    /// This method works the same way as
+    /// [`add_event_handler`][Self::add_event_handler], except that the
+    /// handlers for an event are called by decreasing priority. The handlers
+    /// registered with the other methods have a priority of `0`, and the
+    /// handlers with the same priority are called concurrently.
+    ///
+    /// A handler can return [`EventPropagation::Stop`] to prevent the handlers
+    /// with a lower priority from being called for the same event. This allows
+    /// to build processing chains, where for example a handler for commands
+    /// runs before a handler that logs all the messages, and consumes the
+    /// commands.
+    ///
+    /// [`EventPropagation::Stop`]: crate::event_handler::EventPropagation::Stop
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// use matrix_sdk::{
+    ///     event_handler::EventPropagation,
+    ///     ruma::events::room::message::OriginalSyncRoomMessageEvent, Client,
+    /// };
+    ///
+    /// # futures_executor::block_on(async {
+    /// # let client = matrix_sdk::Client::builder()
+    /// #     .homeserver_url(homeserver)
+    /// #     .server_versions([ruma::api::MatrixVersion::V1_0])
+    /// #     .build()
+    /// #     .await
+    /// #     .unwrap();
+    /// #
+    /// client.add_event_handler_with_priority(
+    ///     10,
+    ///     |ev: OriginalSyncRoomMessageEvent| async move {
+    ///         if ev.content.body().starts_with('!') {
+    ///             // Handle the command, and don't log it.
+    ///             EventPropagation::Stop
+    ///         } else {
+    ///             EventPropagation::Continue
+    ///         }
+    ///     },
+    /// );
+    /// client.add_event_handler(|ev: OriginalSyncRoomMessageEvent| async move {
+    ///     println!("{}", ev.content.body());
+    /// });
+    /// # });
+    /// ```
+    pub fn add_event_handler_with_priority<Ev, Ctx, H>(
+        &self,
+        priority: i32,
+        handler: H,
+    ) -> EventHandlerHandle
+    where
+        Ev: SyncEvent + DeserializeOwned + Send + 'static,
+        H: EventHandler<Ev, Ctx>,
+    {
+        self.add_event_handler_impl(handler, None, priority)
     }
 
     /// Register a handler for a specific room, and event type.
@@ -713,6 +1193,10 @@ impl Client {
     /// will only be called for events in the room with the specified ID. See
     /// that method for more details on event handler functions.
     ///
+    /// The handler is registered for the room ID, so it keeps being called if
+    /// the room objects are dropped and recreated, for example when the room is
+    /// left and joined again.
+    ///
     /// `client.add_room_event_handler(room_id, hdl)` is equivalent to
     /// `room.add_event_handler(hdl)`. Use whichever one is more convenient in
     /// your use case.
@@ -725,7 +1209,27 @@ impl Client {
         Ev: SyncEvent + DeserializeOwned + Send + 'static,
         H: EventHandler<Ev, Ctx>,
     {
-        self.add_event_handler_impl(handler, Some(room_id.to_owned()))
+        self.add_event_handler_impl(handler, Some(room_id.to_owned()), 0)
+    }
+
+    /// Register a handler for a specific room, and event type, with the given
+    /// priority.
+    ///
+    /// This method works the same way as
+    /// [`add_event_handler_with_priority`][Self::add_event_handler_with_priority],
+    /// except that the handler will only be called for events in the room with
+    /// the specified ID.
+    pub fn add_room_event_handler_with_priority<Ev, Ctx, H>(
+        &self,
+        room_id: &RoomId,
+        priority: i32,
+        handler: H,
+    ) -> EventHandlerHandle
+    where
+        Ev: SyncEvent + DeserializeOwned + Send + 'static,
+        H: EventHandler<Ev, Ctx>,
+    {
+        self.add_event_handler_impl(handler, Some(room_id.to_owned()), priority)
     }
 
     /// Remove the event handler associated with the handle.
@@ -864,6 +1368,16 @@ impl Client {
         self
     }
 
+    /// Automatically join the successors of the rooms that are tombstoned,
+    /// i.e. when a room is upgraded.
+    ///
+    /// The tags and the notification mode of the old rooms are copied to the
+    /// new rooms. The rooms are followed until the returned
+    /// [`RoomUpgradeFollower`](room::RoomUpgradeFollower) is dropped.
+    pub fn follow_room_upgrades(&self) -> room::RoomUpgradeFollower {
+        room::RoomUpgradeFollower::new(self)
+    }
+
     /// Subscribe to all updates for the room with the given ID.
     ///
     /// The returned receiver will receive a new message for each sync response
@@ -923,6 +1437,36 @@ impl Client {
             .collect()
     }
 
+    /// Get a stream of the invites of the user.
+    ///
+    /// The current invites are yielded first, then the whole list is yielded
+    /// again every time an invite is received, updated, accepted or rejected,
+    /// with a sync or a sliding sync.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures_util::{pin_mut, StreamExt};
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let invites = client.invited_rooms_stream();
+    /// pin_mut!(invites);
+    ///
+    /// while let Some(invites) = invites.next().await {
+    ///     for invite in invites {
+    ///         let inviter = invite.inviter.map(|inviter| inviter.user_id);
+    ///         println!("Invited to {} by {inviter:?}", invite.display_name);
+    ///     }
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub fn invited_rooms_stream(&self) -> impl Stream<Item = Vec<RoomInvite>> {
+        invites::invites_stream(self.clone())
+    }
+
     /// Returns the left rooms this client knows about.
     pub fn left_rooms(&self) -> Vec<room::Left> {
         self.base_client()
@@ -973,6 +1517,10 @@ impl Client {
     /// Resolve a room alias to a room id and a list of servers which know
     /// about it.
     ///
+    /// The result is cached for a few minutes, the cache is invalidated when
+    /// the alias is published or removed with this client. Only the most
+    /// recently resolved aliases are kept.
+    ///
     /// # Arguments
     ///
     /// `room_alias` - The room alias to be resolved.
@@ -980,8 +1528,30 @@ impl Client {
         &self,
         room_alias: &RoomAliasId,
     ) -> HttpResult<get_alias::v3::Response> {
+        if let Some(entry) = self.inner.room_alias_cache.get(room_alias) {
+            let (resolved_at, response) = &*entry;
+            if resolved_at.elapsed() < ROOM_ALIAS_CACHE_DURATION {
+                return Ok(response.clone());
+            }
+        }
+
         let request = get_alias::v3::Request::new(room_alias.to_owned());
-        self.send(request, None).await
+        let response = self.send(request, None).await?;
+
+        // Drop the expired aliases, and the oldest one if the cache is still
+        // full.
+        let cache = &self.inner.room_alias_cache;
+        cache.retain(|_, (resolved_at, _)| resolved_at.elapsed() < ROOM_ALIAS_CACHE_DURATION);
+        if cache.len() >= ROOM_ALIAS_CACHE_CAPACITY {
+            let oldest =
+                cache.iter().min_by_key(|entry| entry.value().0).map(|entry| entry.key().clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+
+        cache.insert(room_alias.to_owned(), (Instant::now(), response.clone()));
+        Ok(response)
     }
 
     /// Gets the homeserver’s supported login types.
@@ -1273,6 +1843,7 @@ impl Client {
         }
 
         self.inner.base_client.receive_login_response(response).await?;
+        self.inner.session_verdict.set_if_not_eq(SessionVerdict::Valid);
 
         Ok(())
     }
@@ -1342,6 +1913,7 @@ impl Client {
 
         self.base_client().set_session_tokens(tokens);
         self.base_client().set_session_meta(meta).await?;
+        self.inner.session_verdict.set_if_not_eq(SessionVerdict::Valid);
 
         debug!("Done restoring session");
 
@@ -1465,6 +2037,7 @@ impl Client {
                     session_tokens.update_with_refresh_response(&res);
 
                     self.base_client().set_session_tokens(session_tokens);
+                    self.inner.session_verdict.set_if_not_eq(SessionVerdict::Valid);
 
                     // TODO: Let ffi client to know that tokens have changed
 
@@ -1539,6 +2112,34 @@ impl Client {
         self.send(request, config).await
     }
 
+    /// Register a guest account on the homeserver and log in with it.
+    ///
+    /// Guests can only use a limited set of endpoints, which is enough to
+    /// [peek](Self::peek_room) into world-readable rooms, for example to
+    /// preview public rooms before the user creates an account.
+    ///
+    /// The session can be retrieved with [`Client::session()`] to be persisted,
+    /// like any other session.
+    #[instrument(skip_all)]
+    pub async fn register_guest(&self) -> Result<()> {
+        let request = assign!(register::v3::Request::new(), { kind: RegistrationKind::Guest });
+        let response = self.register(request).await?;
+
+        let (Some(access_token), Some(device_id)) = (response.access_token, response.device_id)
+        else {
+            error!("The homeserver didn't log the guest in");
+            return Err(Error::InconsistentState);
+        };
+
+        self.restore_session(Session {
+            access_token,
+            refresh_token: response.refresh_token,
+            user_id: response.user_id,
+            device_id,
+        })
+        .await
+    }
+
     /// Get or upload a sync filter.
     ///
     /// This method will either get a filter ID from the store or upload the
@@ -1605,6 +2206,102 @@ impl Client {
         }
     }
 
+    /// Peek into a world-readable room, without joining it.
+    ///
+    /// This fetches the current state of the room, so its details and members
+    /// are available. Its history can then be loaded with
+    /// [`Common::messages()`](room::Common::messages), or with a timeline.
+    ///
+    /// The room is read-only: unless the user is a member of the room, it is
+    /// returned as a [`room::Room::Left`] whose state is only kept in memory.
+    /// It is not added to the rooms of the client and nothing is written to
+    /// the store, and it doesn't receive updates from the sync. Peek into it
+    /// again to refresh its state.
+    ///
+    /// If the user is already a member of the room or invited to it, the known
+    /// room is returned directly.
+    ///
+    /// The client must be logged in, a [guest](Self::register_guest) account
+    /// can be used.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room to peek into.
+    #[instrument(skip(self))]
+    pub async fn peek_room(&self, room_id: &RoomId) -> Result<room::Room> {
+        if !self.logged_in() {
+            return Err(Error::AuthenticationRequired);
+        }
+
+        if let Some(room) = self.get_room(room_id) {
+            if room.state() != RoomState::Left {
+                return Ok(room);
+            }
+        }
+
+        let request = get_state_events::v3::Request::new(room_id.to_owned());
+        let response = self.send(request, None).await?;
+        let base_room =
+            self.base_client().receive_peeked_state(room_id, &response.room_state).await?;
+
+        Ok(room::Common::new(self.clone(), base_room).into())
+    }
+
+    /// Get the details of a room, to show them before joining it, like on an
+    /// invite or a link preview.
+    ///
+    /// The room summary of [MSC3266] is used if the homeserver supports it.
+    /// Otherwise, the room is looked up in the space hierarchy API, then
+    /// [peeked](Self::peek_room) into, which only work for rooms that are
+    /// public, world-readable or already known.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_or_alias_id` - The ID or alias of the room.
+    ///
+    /// * `via` - The servers that can be used to look up the room, like the
+    ///   ones of a permalink.
+    ///
+    /// [MSC3266]: https://github.com/matrix-org/matrix-spec-proposals/pull/3266
+    pub async fn room_preview(
+        &self,
+        room_or_alias_id: &RoomOrAliasId,
+        via: &[OwnedServerName],
+    ) -> Result<RoomPreview> {
+        RoomPreview::load(self, room_or_alias_id, via).await
+    }
+
+    /// Parse a `matrix.to` or `matrix:` URI and load the details of its
+    /// target, to show them before navigating to it.
+    ///
+    /// See [`UriTarget::parse()`] and [`UriTarget::resolve()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The URI, like a link in a message.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use matrix_sdk::{uri::ResolvedUri, Client};
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    ///
+    /// match client.resolve_uri("https://matrix.to/#/#room:example.org").await? {
+    ///     ResolvedUri::Room { preview, .. } => {
+    ///         println!("Room with {} members", preview.num_joined_members);
+    ///     }
+    ///     ResolvedUri::User(user) => println!("User {}", user.user_id),
+    ///     _ => {}
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub async fn resolve_uri(&self, uri: &str) -> Result<ResolvedUri> {
+        UriTarget::parse(uri)?.resolve(self).await
+    }
+
     /// Join a room by `RoomId`.
     ///
     /// Returns a `join_room_by_id::Response` consisting of the
@@ -1754,6 +2451,101 @@ impl Client {
         .await
     }
 
+    /// Get the DM room with the given user, creating it if there is none.
+    ///
+    /// The joined rooms marked as DMs with only this user in the `m.direct`
+    /// account data are preferred, the ones where the user is still joined
+    /// first, then the ones where they are invited. The DMs that the user left
+    /// are ignored. Otherwise, an unnamed room with only the user and us is
+    /// used, and marked as a DM.
+    ///
+    /// If no room is found, a new one is created like with
+    /// [`create_dm()`][Self::create_dm], and encryption is enabled in it when
+    /// the `e2e-encryption` feature is enabled.
+    ///
+    /// Concurrent calls are serialized, so they return the same room.
+    pub async fn dm_with(&self, user_id: &UserId) -> Result<room::Joined> {
+        let _guard = self.inner.dm_lock.lock().await;
+
+        if let Some(room) = self.find_dm(user_id).await? {
+            return Ok(room);
+        }
+
+        debug!(%user_id, "Creating a new DM");
+        let request = assign!(create_room::v3::Request::new(), {
+            invite: vec![user_id.to_owned()],
+            is_direct: true,
+            preset: Some(create_room::v3::RoomPreset::TrustedPrivateChat),
+        });
+        #[cfg(feature = "e2e-encryption")]
+        let request = assign!(request, {
+            initial_state: vec![InitialStateEvent::new(
+                RoomEncryptionEventContent::with_recommended_defaults(),
+            )
+            .to_raw_any()],
+        });
+
+        self.create_room(request).await
+    }
+
+    /// Find the best existing DM room with the given user, see
+    /// [`Client::dm_with()`].
+    async fn find_dm(&self, user_id: &UserId) -> Result<Option<room::Joined>> {
+        let mut best_dm = None;
+
+        for room in self.joined_rooms() {
+            let targets = room.direct_targets();
+            if targets.len() != 1 || !targets.contains(user_id) {
+                continue;
+            }
+
+            // Prefer the rooms where the user is joined, then the ones where
+            // they are invited.
+            let rank = match room.get_member_no_sync(user_id).await? {
+                Some(member) => match member.membership() {
+                    MembershipState::Join => 2,
+                    MembershipState::Invite => 1,
+                    // The user can't come back to the room on their own.
+                    MembershipState::Leave | MembershipState::Ban => continue,
+                    _ => 0,
+                },
+                // The members of the room might not be loaded.
+                None => 0,
+            };
+
+            if best_dm.as_ref().map_or(true, |(best_rank, _)| rank > *best_rank) {
+                best_dm = Some((rank, room));
+            }
+        }
+
+        if let Some((_, room)) = best_dm {
+            return Ok(Some(room));
+        }
+
+        // Look for a room that looks like a DM but that was not marked as one.
+        for room in self.joined_rooms() {
+            if !room.direct_targets().is_empty()
+                || room.is_space()
+                || room.name().is_some()
+                || room.canonical_alias().is_some()
+                || room.active_members_count() != 2
+            {
+                continue;
+            }
+
+            let is_member = room.get_member_no_sync(user_id).await?.is_some_and(|member| {
+                matches!(member.membership(), MembershipState::Join | MembershipState::Invite)
+            });
+            if is_member {
+                debug!(room_id = ?room.room_id(), %user_id, "Marking room as DM");
+                self.account().mark_as_dm(room.room_id(), &[user_id.to_owned()]).await?;
+                return Ok(Some(room));
+            }
+        }
+
+        Ok(None)
+    }
+
     /// Search the homeserver's directory for public rooms with a filter.
     ///
     /// # Arguments
@@ -1876,21 +2668,26 @@ impl Client {
                             // this `Session`, ignore.
                         }
                         _ => {
-                            return Err(refresh_error);
+                            let res = Err(refresh_error);
+                            self.update_session_verdict(&res);
+                            return res;
                         }
                     }
                 } else {
-                    return Box::pin(self.send_inner(
+                    let res = Box::pin(self.send_inner(
                         request,
                         config,
                         sliding_sync_proxy,
                         Default::default(),
                     ))
                     .await;
+                    self.update_session_verdict(&res);
+                    return res;
                 }
             }
         }
 
+        self.update_session_verdict(&res);
         res
     }
 
@@ -1938,9 +2735,38 @@ impl Client {
         response
     }
 
-    async fn request_server_versions(&self) -> HttpResult<Box<[MatrixVersion]>> {
-        let server_versions: Box<[MatrixVersion]> = self
-            .inner
+    /// Send a request and stream the body of its response, starting at the
+    /// given byte offset if it isn't `0`.
+    ///
+    /// This is used to download media that can be resumed, see
+    /// [`HttpClient::send_streamed()`].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) async fn send_streamed<Request>(
+        &self,
+        request: Request,
+        offset: u64,
+        config: Option<RequestConfig>,
+    ) -> HttpResult<StreamedResponse>
+    where
+        Request: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        self.inner
+            .http_client
+            .send_streamed(
+                request,
+                offset,
+                config,
+                self.homeserver().await.to_string(),
+                self.access_token().as_deref(),
+                self.user_id(),
+                self.server_versions().await?,
+            )
+            .await
+    }
+
+    async fn request_supported_versions(&self) -> HttpResult<get_supported_versions::Response> {
+        self.inner
             .http_client
             .send(
                 get_supported_versions::Request::new(),
@@ -1951,9 +2777,12 @@ impl Client {
                 &[MatrixVersion::V1_0],
                 Default::default(),
             )
-            .await?
-            .known_versions()
-            .collect();
+            .await
+    }
+
+    async fn request_server_versions(&self) -> HttpResult<Box<[MatrixVersion]>> {
+        let server_versions: Box<[MatrixVersion]> =
+            self.request_supported_versions().await?.known_versions().collect();
 
         if server_versions.is_empty() {
             Ok(vec![MatrixVersion::V1_0].into())
@@ -2067,6 +2896,14 @@ impl Client {
         self.send(request, None).await
     }
 
+    /// Get an observable list of our own devices, with their verification
+    /// status.
+    ///
+    /// See [`DeviceList`] for more details.
+    pub async fn device_list(&self) -> Result<DeviceList> {
+        DeviceList::new(self.clone()).await
+    }
+
     /// Synchronize the client's state with the latest state on the server.
     ///
     /// ## Syncing Events
@@ -2177,7 +3014,7 @@ impl Client {
         }
 
         let request = assign!(sync_events::v3::Request::new(), {
-            filter: sync_settings.filter.map(|f| *f),
+            filter: sync_settings.filter.map(|f| *f).or_else(|| self.minimal_sync_filter()),
             since: sync_settings.token,
             full_state: sync_settings.full_state,
             set_presence: sync_settings.set_presence,
@@ -2188,9 +3025,18 @@ impl Client {
             request_config.timeout += timeout;
         }
 
+        let start = Instant::now();
         let response = self.send(request, Some(request_config)).await?;
         let next_batch = response.next_batch.clone();
+
+        let processing_start = Instant::now();
         let response = self.process_sync(response).await?;
+        metrics::record_duration(
+            metrics::SYNC_PROCESSING_DURATION,
+            processing_start,
+            &[("kind", "v3")],
+        );
+        metrics::record_duration(metrics::SYNC_DURATION, start, &[("kind", "v3")]);
 
         #[cfg(feature = "e2e-encryption")]
         if let Err(e) = self.send_outgoing_requests().await {
@@ -2202,6 +3048,21 @@ impl Client {
         Ok(SyncResponse::new(next_batch, response))
     }
 
+    /// The sync filter used by a client built with
+    /// [`ClientBuilder::minimal()`], when no filter was set in the
+    /// `SyncSettings`.
+    fn minimal_sync_filter(&self) -> Option<sync_events::v3::Filter> {
+        let rooms = self.minimal_rooms()?;
+
+        let mut definition = FilterDefinition::with_lazy_loading();
+        definition.presence = Filter::ignore_all();
+        definition.room.rooms = Some(rooms.to_vec());
+        definition.room.ephemeral = RoomEventFilter::ignore_all();
+        definition.room.account_data = RoomEventFilter::ignore_all();
+
+        Some(sync_events::v3::Filter::FilterDefinition(definition))
+    }
+
     /// Repeatedly synchronize the client state with the server.
     ///
     /// This method will only return on error, if cancellation is needed
@@ -2514,6 +3375,22 @@ impl Client {
         self.send(request, None).await
     }
 
+    /// Subscribe to the rate limits applied by the homeserver.
+    ///
+    /// A [`RateLimited`] notification is received every time a request is
+    /// rate-limited. The following requests of the same [`EndpointClass`] wait
+    /// until the limit is lifted before being sent, so this can be used to
+    /// reschedule the requests that are less urgent.
+    pub fn subscribe_to_rate_limits(&self) -> broadcast::Receiver<RateLimited> {
+        self.inner.http_client.subscribe_to_rate_limits()
+    }
+
+    /// Get how long the requests of the given class have to wait before being
+    /// sent, if the homeserver currently rate limits them.
+    pub fn rate_limit_delay(&self, class: EndpointClass) -> Option<Duration> {
+        self.inner.http_client.rate_limit_delay(class)
+    }
+
     /// Subscribes a new receiver to client UnknownToken errors
     pub fn subscribe_to_unknown_token_errors(&self) -> broadcast::Receiver<UnknownToken> {
         let broadcast = &self.inner.unknown_token_error_sender;
@@ -2552,18 +3429,20 @@ impl Client {
 pub(crate) mod tests {
     use std::time::Duration;
 
+    use assert_matches::assert_matches;
     use matrix_sdk_test::{async_test, test_json, EventBuilder, JoinedRoomBuilder, StateTestEvent};
     #[cfg(target_arch = "wasm32")]
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
     use ruma::{events::ignored_user_list::IgnoredUserListEventContent, UserId};
+    use serde_json::json;
     use url::Url;
     use wiremock::{
         matchers::{body_json, header, method, path},
         Mock, MockServer, ResponseTemplate,
     };
 
-    use super::Client;
+    use super::{Client, ClientBuildError, WellKnownError};
     use crate::{
         config::{RequestConfig, SyncSettings},
         test_utils::{logged_in_client, no_retry_test_client, test_client_builder},
@@ -2620,6 +3499,88 @@ pub(crate) mod tests {
         let client = Client::builder().server_name(alice.server_name()).build().await.unwrap();
 
         assert_eq!(client.homeserver().await, Url::parse(server_url.as_ref()).unwrap());
+        let well_known = client.well_known().unwrap();
+        assert_eq!(well_known.homeserver, Url::parse(server_url.as_ref()).unwrap());
+        assert_eq!(well_known.e2ee_default, None);
+    }
+
+    #[async_test]
+    async fn discovery_not_a_homeserver() {
+        let server = MockServer::start().await;
+        let server_url = server.uri();
+        let domain = server_url.strip_prefix("http://").unwrap();
+        let alice = UserId::parse("@alice:".to_owned() + domain).unwrap();
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/matrix/client"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                test_json::WELL_KNOWN.to_string().replace("HOMESERVER_URL", server_url.as_ref()),
+                "application/json",
+            ))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let result = Client::builder().server_name(alice.server_name()).build().await;
+        assert_matches!(
+            result,
+            Err(ClientBuildError::AutoDiscovery(WellKnownError::NotAHomeserver { .. }))
+        );
+    }
+
+    #[async_test]
+    async fn refresh_well_known() {
+        let server = MockServer::start().await;
+        let server_url = server.uri();
+        let domain = server_url.strip_prefix("http://").unwrap();
+        let alice = UserId::parse("@alice:".to_owned() + domain).unwrap();
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/matrix/client"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                test_json::WELL_KNOWN.to_string().replace("HOMESERVER_URL", server_url.as_ref()),
+                "application/json",
+            ))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::VERSIONS))
+            .mount(&server)
+            .await;
+
+        let client = Client::builder().server_name(alice.server_name()).build().await.unwrap();
+        assert_eq!(client.well_known().unwrap().sliding_sync_proxy, None);
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/matrix/client"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "m.homeserver": { "base_url": server_url },
+                "org.matrix.msc3575.proxy": { "url": "https://slidingsync.example.org" },
+                "io.element.e2ee": { "default": false },
+            })))
+            .mount(&server)
+            .await;
+
+        let well_known = client.refresh_well_known().await.unwrap().unwrap();
+        assert_eq!(
+            well_known.sliding_sync_proxy.unwrap().as_str(),
+            "https://slidingsync.example.org/"
+        );
+        assert_eq!(well_known.e2ee_default, Some(false));
+        assert_eq!(client.well_known().unwrap().e2ee_default, Some(false));
+
+        // Clients that didn't use discovery have nothing to refresh.
+        let client = logged_in_client(Some(server.uri())).await;
+        assert!(client.refresh_well_known().await.unwrap().is_none());
+        assert!(client.well_known().is_none());
     }
 
     #[async_test]
@@ -2770,4 +3731,47 @@ pub(crate) mod tests {
         assert_eq!(response.results.len(), 1);
         assert!(!response.limited);
     }
+
+    #[cfg(feature = "metrics")]
+    #[async_test]
+    async fn sync_and_http_metrics_are_recorded() {
+        use matrix_sdk_common::metrics::{
+            HTTP_REQUEST_DURATION, SYNC_DURATION, SYNC_PROCESSING_DURATION,
+        };
+        use matrix_sdk_test::{
+            metrics::{install_recorder, take_recorded_histograms},
+            MatrixMockServer,
+        };
+
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        server.mock_sync().mount().await;
+        install_recorder();
+
+        client.sync_once(SyncSettings::new()).await.unwrap();
+        // The profile endpoint isn't mocked, so the request fails.
+        client.account().get_display_name().await.unwrap_err();
+
+        let recorded = take_recorded_histograms();
+        let is_recorded = |name, labels: &[(&str, &str)]| {
+            recorded.iter().any(|histogram| histogram.matches(name, labels))
+        };
+
+        assert!(is_recorded(SYNC_DURATION, &[("kind", "v3")]), "{recorded:?}");
+        assert!(is_recorded(SYNC_PROCESSING_DURATION, &[("kind", "v3")]), "{recorded:?}");
+        assert!(
+            is_recorded(
+                HTTP_REQUEST_DURATION,
+                &[("endpoint", "sync::sync_events::v3"), ("outcome", "success")]
+            ),
+            "{recorded:?}"
+        );
+        assert!(
+            is_recorded(
+                HTTP_REQUEST_DURATION,
+                &[("endpoint", "profile::get_display_name::v3"), ("outcome", "error")]
+            ),
+            "{recorded:?}"
+        );
+    }
 }
@@ -0,0 +1,362 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{sync::Weak, time::Duration};
+
+use bytes::BufMut;
+use matrix_sdk_common::sleep::sleep;
+use ruma::{
+    api::{
+        client::discovery::discover_homeserver::AuthenticationServerInfo,
+        error::{FromHttpResponseError, IntoHttpError},
+        metadata, EndpointError, IncomingResponse, MatrixVersion, Metadata, OutgoingRequest,
+        SendAccessToken,
+    },
+    serde::JsonObject,
+    ServerName,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use thiserror::Error;
+use tracing::warn;
+use url::Url;
+
+use super::{Client, ClientInner};
+use crate::{config::RequestConfig, http_client::HttpClient, HttpError};
+
+/// The configuration published by a server in its `.well-known/matrix/client`
+/// file, as returned by [`Client::well_known()`].
+///
+/// [`Client::well_known()`]: crate::Client::well_known
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ClientWellKnown {
+    /// The URL of the homeserver.
+    pub homeserver: Url,
+
+    /// The URL of the identity server, if any.
+    pub identity_server: Option<Url>,
+
+    /// The URL of the style of the maps used to show locations ([MSC3488]),
+    /// if any.
+    ///
+    /// [MSC3488]: https://github.com/matrix-org/matrix-spec-proposals/pull/3488
+    pub tile_server: Option<Url>,
+
+    /// The OpenID Connect provider used to authenticate ([MSC2965]), if any.
+    ///
+    /// [MSC2965]: https://github.com/matrix-org/matrix-spec-proposals/pull/2965
+    pub authentication: Option<AuthenticationServerInfo>,
+
+    /// The sliding sync proxy trusted by the homeserver ([MSC3575]), if any.
+    ///
+    /// [MSC3575]: https://github.com/matrix-org/matrix-spec-proposals/pull/3575
+    pub sliding_sync_proxy: Option<Url>,
+
+    /// Whether new private rooms should be encrypted by default, if the
+    /// server has a preference.
+    pub e2ee_default: Option<bool>,
+}
+
+/// An error when discovering the homeserver from its `.well-known` file.
+#[derive(Debug, Error)]
+pub enum WellKnownError {
+    /// The request of the `.well-known` file failed, or the server responded
+    /// with an error status.
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    /// The `.well-known` file isn't a valid JSON object.
+    #[error("the .well-known file is not valid: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+
+    /// The `.well-known` file doesn't contain the URL of the homeserver.
+    #[error("the .well-known file doesn't contain the homeserver")]
+    MissingHomeserver,
+
+    /// The URL of the homeserver isn't a valid HTTP URL.
+    #[error("the URL of the homeserver is not valid: {0}")]
+    InvalidHomeserver(String),
+
+    /// The URL of the identity server isn't a valid HTTP URL.
+    #[error("the URL of the identity server is not valid: {0}")]
+    InvalidIdentityServer(String),
+
+    /// The homeserver doesn't respond to the `/versions` endpoint, so it
+    /// probably isn't a Matrix homeserver.
+    #[error("{url} doesn't look like a Matrix homeserver")]
+    NotAHomeserver {
+        /// The URL of the homeserver.
+        url: Url,
+        /// The error of the `/versions` request.
+        source: HttpError,
+    },
+}
+
+#[derive(Deserialize)]
+struct BaseUrl {
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+struct TileServer {
+    map_style_url: String,
+}
+
+#[derive(Deserialize)]
+struct SlidingSyncProxy {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct E2ee {
+    default: Option<bool>,
+}
+
+impl ClientWellKnown {
+    /// Parse and validate the content of a `.well-known/matrix/client` file.
+    ///
+    /// The homeserver and the identity server, if any, must have valid HTTP
+    /// URLs. The other fields are ignored if they are invalid.
+    pub fn parse(json: &[u8]) -> Result<Self, WellKnownError> {
+        let object: JsonObject = serde_json::from_slice(json)?;
+
+        let homeserver = object.get("m.homeserver").ok_or(WellKnownError::MissingHomeserver)?;
+        let homeserver = BaseUrl::deserialize(homeserver)?.base_url;
+        let homeserver =
+            parse_http_url(&homeserver).ok_or(WellKnownError::InvalidHomeserver(homeserver))?;
+
+        let identity_server = match object.get("m.identity_server") {
+            Some(value) => {
+                let url = BaseUrl::deserialize(value)?.base_url;
+                Some(parse_http_url(&url).ok_or(WellKnownError::InvalidIdentityServer(url))?)
+            }
+            None => None,
+        };
+
+        let tile_server = lenient_field::<TileServer>(&object, "m.tile_server")
+            .or_else(|| lenient_field(&object, "org.matrix.msc3488.tile_server"))
+            .and_then(|tile_server| lenient_url("tile server", &tile_server.map_style_url));
+
+        let authentication = lenient_field(&object, "org.matrix.msc2965.authentication");
+
+        let sliding_sync_proxy =
+            lenient_field::<SlidingSyncProxy>(&object, "org.matrix.msc3575.proxy")
+                .and_then(|proxy| lenient_url("sliding sync proxy", &proxy.url));
+
+        let e2ee_default =
+            lenient_field::<E2ee>(&object, "io.element.e2ee").and_then(|e2ee| e2ee.default);
+
+        Ok(Self {
+            homeserver,
+            identity_server,
+            tile_server,
+            authentication,
+            sliding_sync_proxy,
+            e2ee_default,
+        })
+    }
+
+    /// Fetch and parse the `.well-known/matrix/client` file of the given
+    /// server.
+    pub(crate) async fn fetch(
+        http_client: &HttpClient,
+        server_name: &ServerName,
+    ) -> Result<Self, WellKnownError> {
+        let response = http_client
+            .send(
+                WellKnownRequest,
+                Some(RequestConfig::short_retry()),
+                super::builder::homeserver_from_name(server_name),
+                None,
+                None,
+                &[MatrixVersion::V1_0],
+                Default::default(),
+            )
+            .await?;
+
+        Self::parse(&response.body)
+    }
+}
+
+/// Request of the `.well-known/matrix/client` file.
+///
+/// Ruma's `discover_homeserver` endpoint drops the fields it doesn't know and
+/// fails on the invalid ones, so the file is parsed by
+/// [`ClientWellKnown::parse()`] instead.
+#[derive(Clone, Debug)]
+struct WellKnownRequest;
+
+impl OutgoingRequest for WellKnownRequest {
+    type EndpointError = ruma::api::client::Error;
+    type IncomingResponse = WellKnownResponse;
+
+    const METADATA: Metadata = metadata! {
+        method: GET,
+        rate_limited: false,
+        authentication: None,
+        history: {
+            1.0 => "/.well-known/matrix/client",
+        }
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        _access_token: SendAccessToken<'_>,
+        considering_versions: &[MatrixVersion],
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let url = Self::METADATA.make_endpoint_url(considering_versions, base_url, &[], "")?;
+        Ok(http::Request::builder().method(Self::METADATA.method).uri(url).body(T::default())?)
+    }
+}
+
+/// Response to a [`WellKnownRequest`], with the raw content of the file.
+#[derive(Clone, Debug)]
+struct WellKnownResponse {
+    body: Vec<u8>,
+}
+
+impl IncomingResponse for WellKnownResponse {
+    type EndpointError = ruma::api::client::Error;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<Self::EndpointError>> {
+        if response.status().as_u16() >= 400 {
+            return Err(FromHttpResponseError::Server(
+                ruma::api::client::Error::from_http_response(response),
+            ));
+        }
+
+        Ok(Self { body: response.body().as_ref().to_owned() })
+    }
+}
+
+/// Refresh the `.well-known` configuration of the client every `interval`,
+/// until the client is dropped.
+pub(super) async fn refresh_periodically(client: Weak<ClientInner>, interval: Duration) {
+    loop {
+        sleep(interval).await;
+
+        let Some(inner) = client.upgrade() else { break };
+        if let Err(error) = (Client { inner }).refresh_well_known().await {
+            warn!(?error, "Couldn't refresh the .well-known configuration");
+        }
+    }
+}
+
+/// Parse a URL, only accepting the HTTP and HTTPS schemes.
+fn parse_http_url(url: &str) -> Option<Url> {
+    Url::parse(url).ok().filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Parse an optional URL, logging it if it is invalid.
+fn lenient_url(name: &str, url: &str) -> Option<Url> {
+    let parsed = parse_http_url(url);
+    if parsed.is_none() {
+        warn!(url, "Ignoring invalid {name} URL in the .well-known file");
+    }
+    parsed
+}
+
+/// Deserialize an optional field, logging it if it is invalid.
+fn lenient_field<T: DeserializeOwned>(object: &JsonObject, key: &str) -> Option<T> {
+    let value = object.get(key)?;
+    match T::deserialize(value) {
+        Ok(value) => Some(value),
+        Err(error) => {
+            warn!(key, %error, "Ignoring invalid field in the .well-known file");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use serde_json::json;
+
+    use super::{ClientWellKnown, WellKnownError};
+
+    fn parse(json: serde_json::Value) -> Result<ClientWellKnown, WellKnownError> {
+        ClientWellKnown::parse(json.to_string().as_bytes())
+    }
+
+    #[test]
+    fn parse_full_well_known() {
+        let well_known = parse(json!({
+            "m.homeserver": { "base_url": "https://matrix.example.org" },
+            "m.identity_server": { "base_url": "https://identity.example.org" },
+            "m.tile_server": { "map_style_url": "https://tiles.example.org/style.json" },
+            "org.matrix.msc2965.authentication": {
+                "issuer": "https://auth.example.org/",
+                "account": "https://auth.example.org/account",
+            },
+            "org.matrix.msc3575.proxy": { "url": "https://slidingsync.example.org" },
+            "io.element.e2ee": { "default": false },
+        }))
+        .unwrap();
+
+        assert_eq!(well_known.homeserver.as_str(), "https://matrix.example.org/");
+        assert_eq!(well_known.identity_server.unwrap().as_str(), "https://identity.example.org/");
+        assert_eq!(
+            well_known.tile_server.unwrap().as_str(),
+            "https://tiles.example.org/style.json"
+        );
+        assert_eq!(well_known.authentication.unwrap().issuer, "https://auth.example.org/");
+        assert_eq!(
+            well_known.sliding_sync_proxy.unwrap().as_str(),
+            "https://slidingsync.example.org/"
+        );
+        assert_eq!(well_known.e2ee_default, Some(false));
+    }
+
+    #[test]
+    fn parse_minimal_well_known() {
+        let well_known = parse(json!({
+            "m.homeserver": { "base_url": "https://matrix.example.org" },
+            "org.matrix.msc3488.tile_server": { "map_style_url": "https://tiles.example.org" },
+            // Invalid optional fields are ignored.
+            "org.matrix.msc3575.proxy": { "url": "not a url" },
+            "io.element.e2ee": "yes",
+        }))
+        .unwrap();
+
+        assert_eq!(well_known.identity_server, None);
+        assert_eq!(well_known.tile_server.unwrap().as_str(), "https://tiles.example.org/");
+        assert!(well_known.authentication.is_none());
+        assert_eq!(well_known.sliding_sync_proxy, None);
+        assert_eq!(well_known.e2ee_default, None);
+    }
+
+    #[test]
+    fn parse_invalid_well_known() {
+        assert_matches!(
+            ClientWellKnown::parse(b"<html></html>"),
+            Err(WellKnownError::InvalidJson(_))
+        );
+        assert_matches!(parse(json!({})), Err(WellKnownError::MissingHomeserver));
+        assert_matches!(
+            parse(json!({ "m.homeserver": { "base_url": "ftp://matrix.example.org" } })),
+            Err(WellKnownError::InvalidHomeserver(_))
+        );
+        assert_matches!(
+            parse(json!({
+                "m.homeserver": { "base_url": "https://matrix.example.org" },
+                "m.identity_server": { "base_url": "" },
+            })),
+            Err(WellKnownError::InvalidIdentityServer(_))
+        );
+    }
+}
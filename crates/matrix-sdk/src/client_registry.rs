@@ -0,0 +1,319 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for several accounts in the same process.
+//!
+//! A [`ClientRegistry`] keeps one [`Client`] per account. The clients share
+//! the same HTTP connection pool, but each one has its own stores. The
+//! notifications of all the accounts can be received in a single stream, and
+//! the pushes received by the app can be routed to the accounts they are for.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use matrix_sdk::{client_registry::ClientRegistry, config::SyncSettings};
+//! # async {
+//! let registry = ClientRegistry::new();
+//!
+//! for (user, password) in [("alice", "secret"), ("bob", "hunter2")] {
+//!     let client = registry
+//!         .client_builder()
+//!         .server_name(matrix_sdk::ruma::server_name!("example.org"))
+//!         .build()
+//!         .await?;
+//!     client.login_username(user, password).send().await?;
+//!     registry.add(client.clone()).await?;
+//!
+//!     tokio::spawn(async move { client.sync(SyncSettings::default()).await });
+//! }
+//!
+//! let mut notifications = registry.subscribe_to_notifications();
+//! while let Ok(notification) = notifications.recv().await {
+//!     println!("New notification for {}", notification.user_id);
+//! }
+//! # anyhow::Ok(()) };
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock as StdRwLock,
+    },
+};
+
+use ruma::{api::client::push::get_notifications::v3::Notification, OwnedUserId, RoomId, UserId};
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::{room, Client, ClientBuilder, RoomState};
+
+/// An error that occurred when adding a client to a [`ClientRegistry`].
+#[derive(Debug, Error)]
+pub enum ClientRegistryError {
+    /// The client is not logged in, so it doesn't belong to an account yet.
+    #[error("the client is not logged in")]
+    NotLoggedIn,
+
+    /// A client for the same account is already in the registry.
+    #[error("a client for {0} is already in the registry")]
+    AlreadyRegistered(OwnedUserId),
+}
+
+/// A notification received by one of the accounts of a [`ClientRegistry`].
+#[derive(Clone, Debug)]
+pub struct AccountNotification {
+    /// The user ID of the account that received the notification.
+    pub user_id: OwnedUserId,
+    /// The notification.
+    pub notification: Notification,
+    /// The room of the notification.
+    pub room: room::Room,
+}
+
+/// The clients of several accounts in the same process.
+///
+/// See the [module documentation](self) for more details.
+#[derive(Clone, Debug)]
+pub struct ClientRegistry {
+    inner: Arc<ClientRegistryInner>,
+}
+
+#[derive(Debug)]
+struct ClientRegistryInner {
+    http_client: reqwest::Client,
+    clients: StdRwLock<BTreeMap<OwnedUserId, RegisteredClient>>,
+    notifications: broadcast::Sender<AccountNotification>,
+}
+
+#[derive(Debug)]
+struct RegisteredClient {
+    client: Client,
+    /// Whether the client is still in the registry, the notification handlers
+    /// can't be removed from the client.
+    registered: Arc<AtomicBool>,
+}
+
+impl ClientRegistry {
+    /// Create a new empty `ClientRegistry`, with a default HTTP client.
+    pub fn new() -> Self {
+        Self::with_http_client(reqwest::Client::new())
+    }
+
+    /// Create a new empty `ClientRegistry`, that will share the given HTTP
+    /// client between the accounts.
+    pub fn with_http_client(http_client: reqwest::Client) -> Self {
+        let (notifications, _) = broadcast::channel(64);
+        Self {
+            inner: Arc::new(ClientRegistryInner {
+                http_client,
+                clients: Default::default(),
+                notifications,
+            }),
+        }
+    }
+
+    /// Get a [`ClientBuilder`] for a new account, using the shared HTTP
+    /// client.
+    ///
+    /// The store must be different for every account, it uses an in-memory
+    /// store by default.
+    pub fn client_builder(&self) -> ClientBuilder {
+        Client::builder().http_client(self.inner.http_client.clone())
+    }
+
+    /// Get a [`ClientBuilder`] for a new account, using the shared HTTP
+    /// client and SQLite stores in the `account` subdirectory of `base_path`.
+    ///
+    /// `account` must be a valid file name, e.g. a local identifier of the
+    /// account, since the user ID is not known before logging in.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_client_builder(
+        &self,
+        base_path: impl AsRef<std::path::Path>,
+        account: &str,
+        passphrase: Option<&str>,
+    ) -> ClientBuilder {
+        self.client_builder().sqlite_store(base_path.as_ref().join(account), passphrase)
+    }
+
+    /// Add the given logged-in client to the registry.
+    ///
+    /// Its notifications are forwarded to the subscribers of
+    /// [`subscribe_to_notifications()`](Self::subscribe_to_notifications).
+    pub async fn add(&self, client: Client) -> Result<(), ClientRegistryError> {
+        let user_id = client.user_id().ok_or(ClientRegistryError::NotLoggedIn)?.to_owned();
+        let registered = Arc::new(AtomicBool::new(true));
+
+        {
+            let mut clients = self.inner.clients.write().unwrap();
+            if clients.contains_key(&user_id) {
+                return Err(ClientRegistryError::AlreadyRegistered(user_id));
+            }
+            clients.insert(
+                user_id.clone(),
+                RegisteredClient { client: client.clone(), registered: registered.clone() },
+            );
+        }
+
+        let notifications = self.inner.notifications.clone();
+        client
+            .register_notification_handler(move |notification, room, _| {
+                if registered.load(Ordering::SeqCst) {
+                    // There might be no subscribers, that's fine.
+                    let _ = notifications.send(AccountNotification {
+                        user_id: user_id.clone(),
+                        notification,
+                        room,
+                    });
+                }
+                async {}
+            })
+            .await;
+
+        Ok(())
+    }
+
+    /// Remove the client of the given account from the registry.
+    ///
+    /// Returns the client if it was in the registry. Its notifications are
+    /// not forwarded anymore.
+    pub fn remove(&self, user_id: &UserId) -> Option<Client> {
+        let registered = self.inner.clients.write().unwrap().remove(user_id)?;
+        registered.registered.store(false, Ordering::SeqCst);
+        Some(registered.client)
+    }
+
+    /// Get the client of the given account.
+    pub fn get(&self, user_id: &UserId) -> Option<Client> {
+        self.inner.clients.read().unwrap().get(user_id).map(|r| r.client.clone())
+    }
+
+    /// Get the clients of all the accounts, ordered by user ID.
+    pub fn clients(&self) -> Vec<Client> {
+        self.inner.clients.read().unwrap().values().map(|r| r.client.clone()).collect()
+    }
+
+    /// Subscribe to the notifications of all the accounts.
+    pub fn subscribe_to_notifications(&self) -> broadcast::Receiver<AccountNotification> {
+        self.inner.notifications.subscribe()
+    }
+
+    /// Get the clients that a push about the given room should be handled
+    /// with.
+    ///
+    /// The push gateways don't tell which account a push is for, so the push
+    /// is routed to the accounts that are in the room, or invited to it. If
+    /// `user_id` is set, e.g. because the app adds it to the data of its
+    /// pushers, only the client of this account is returned.
+    ///
+    /// Several accounts can be in the same room, in which case the push should
+    /// be handled with all of them.
+    pub fn route_push(&self, room_id: &RoomId, user_id: Option<&UserId>) -> Vec<Client> {
+        if let Some(user_id) = user_id {
+            return self.get(user_id).into_iter().collect();
+        }
+
+        let clients: Vec<_> = self
+            .inner
+            .clients
+            .read()
+            .unwrap()
+            .values()
+            .filter(|r| {
+                r.client.get_room(room_id).is_some_and(|room| room.state() != RoomState::Left)
+            })
+            .map(|r| r.client.clone())
+            .collect();
+
+        debug!(?room_id, accounts = clients.len(), "Routed a push");
+        clients
+    }
+}
+
+impl Default for ClientRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use matrix_sdk_base::{RoomState, Session};
+    use matrix_sdk_test::async_test;
+    use ruma::{device_id, room_id, user_id};
+
+    use super::{ClientRegistry, ClientRegistryError};
+    use crate::test_utils::no_retry_test_client;
+
+    async fn client_for(user_id: &str) -> crate::Client {
+        let client = no_retry_test_client(None).await;
+        let session = Session {
+            access_token: "1234".to_owned(),
+            refresh_token: None,
+            user_id: user_id.try_into().unwrap(),
+            device_id: device_id!("DEVICEID").to_owned(),
+        };
+        client.restore_session(session).await.unwrap();
+        client
+    }
+
+    #[async_test]
+    async fn add_and_remove_clients() {
+        let registry = ClientRegistry::new();
+
+        let result = registry.add(no_retry_test_client(None).await).await;
+        assert_matches!(result, Err(ClientRegistryError::NotLoggedIn));
+
+        registry.add(client_for("@alice:localhost").await).await.unwrap();
+        registry.add(client_for("@bob:localhost").await).await.unwrap();
+        let result = registry.add(client_for("@alice:localhost").await).await;
+        assert_matches!(result, Err(ClientRegistryError::AlreadyRegistered(_)));
+
+        assert_eq!(registry.clients().len(), 2);
+        assert!(registry.get(user_id!("@bob:localhost")).is_some());
+
+        assert!(registry.remove(user_id!("@bob:localhost")).is_some());
+        assert!(registry.remove(user_id!("@bob:localhost")).is_none());
+        assert!(registry.get(user_id!("@bob:localhost")).is_none());
+        assert_eq!(registry.clients().len(), 1);
+    }
+
+    #[async_test]
+    async fn route_push_to_accounts_in_room() {
+        let registry = ClientRegistry::new();
+        let room_id = room_id!("!room:localhost");
+
+        let alice = client_for("@alice:localhost").await;
+        alice.base_client().get_or_create_room(room_id, RoomState::Joined).await;
+        let bob = client_for("@bob:localhost").await;
+        bob.base_client().get_or_create_room(room_id, RoomState::Left).await;
+        let carol = client_for("@carol:localhost").await;
+        carol.base_client().get_or_create_room(room_id, RoomState::Invited).await;
+
+        registry.add(alice).await.unwrap();
+        registry.add(bob).await.unwrap();
+        registry.add(carol).await.unwrap();
+
+        let routed = registry.route_push(room_id, None);
+        let user_ids: Vec<_> = routed.iter().map(|c| c.user_id().unwrap().to_string()).collect();
+        assert_eq!(user_ids, ["@alice:localhost", "@carol:localhost"]);
+
+        let routed = registry.route_push(room_id, Some(user_id!("@bob:localhost")));
+        assert_eq!(routed.len(), 1);
+        assert!(registry.route_push(room_id!("!other:localhost"), None).is_empty());
+    }
+}
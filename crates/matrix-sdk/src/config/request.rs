@@ -46,17 +46,20 @@ pub struct RequestConfig {
     pub(crate) retry_timeout: Option<Duration>,
     pub(crate) force_auth: bool,
     pub(crate) assert_identity: bool,
+    pub(crate) priority: Option<RequestPriority>,
 }
 
 #[cfg(not(tarpaulin_include))]
 impl Debug for RequestConfig {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let Self { timeout, retry_limit, retry_timeout, force_auth, assert_identity } = self;
+        let Self { timeout, retry_limit, retry_timeout, force_auth, assert_identity, priority } =
+            self;
 
         let mut res = fmt.debug_struct("RequestConfig");
         res.field("timeout", timeout)
             .maybe_field("retry_limit", retry_limit)
-            .maybe_field("retry_timeout", retry_timeout);
+            .maybe_field("retry_timeout", retry_timeout)
+            .maybe_field("priority", priority);
 
         if *force_auth {
             res.field("force_auth", &true);
@@ -77,6 +80,7 @@ impl Default for RequestConfig {
             retry_timeout: Default::default(),
             force_auth: false,
             assert_identity: false,
+            priority: None,
         }
     }
 }
@@ -132,6 +136,37 @@ impl RequestConfig {
         self.force_auth = true;
         self
     }
+
+    /// Set the priority of the request, when the number of concurrent requests
+    /// is limited.
+    ///
+    /// By default, media downloads and requests to load the history of a room
+    /// are [`RequestPriority::Background`], and the other requests are
+    /// [`RequestPriority::Interactive`].
+    #[must_use]
+    pub fn priority(mut self, priority: RequestPriority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+}
+
+/// The priority of a request, when the number of concurrent requests is
+/// limited with [`ClientBuilder::max_concurrent_requests()`] or
+/// [`ClientBuilder::max_concurrent_media_downloads()`].
+///
+/// [`ClientBuilder::max_concurrent_requests()`]: crate::ClientBuilder::max_concurrent_requests
+/// [`ClientBuilder::max_concurrent_media_downloads()`]: crate::ClientBuilder::max_concurrent_media_downloads
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestPriority {
+    /// A request needed to show what the user is looking at, or to answer
+    /// their action, like sending a message or syncing.
+    ///
+    /// These requests are sent before the background requests that are
+    /// waiting.
+    Interactive,
+    /// A request that can wait, like loading the history of a room or
+    /// prefetching media.
+    Background,
 }
 
 #[cfg(test)]
@@ -0,0 +1,593 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An offline-first contact book, built from the rooms of the user.
+//!
+//! The contacts are the users sharing a joined room with the user. They are
+//! collected from the local stores, and cached with their last interaction, so
+//! pickers like the one to start a chat can be shown without making any
+//! request to the homeserver, e.g. to the user directory.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Mutex as StdMutex,
+    },
+};
+
+use eyeball_im::{ObservableVector, VectorDiff};
+use futures_core::Stream;
+use imbl::Vector;
+use matrix_sdk_base::RoomMemberships;
+use matrix_sdk_common::executor::{spawn, JoinHandle};
+use ruma::{
+    events::{
+        direct::DirectEvent,
+        presence::PresenceEvent,
+        room::member::{MembershipState, SyncRoomMemberEvent},
+        AnySyncTimelineEvent,
+    },
+    presence::PresenceState,
+    MilliSecondsSinceUnixEpoch, OwnedMxcUri, OwnedRoomId, OwnedUserId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{event_handler::EventHandlerHandle, room::Room, Client, Result};
+
+/// The key of the cached contacts in the custom values of the state store.
+const CONTACTS_KEY: &[u8] = b"matrix-sdk.contacts";
+
+/// A high-level API to get the contacts of the user.
+///
+/// The contacts are the users sharing a joined room with the user, including
+/// the users that were invited to a direct chat. They can be obtained once with
+/// [`Contacts::get()`], or observed with [`Contacts::list()`].
+#[derive(Debug, Clone)]
+pub struct Contacts {
+    client: Client,
+}
+
+impl Contacts {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Get the contacts of the user, ordered by last interaction then by name.
+    ///
+    /// This doesn't make any request to the homeserver, the contacts are
+    /// collected from the rooms known locally.
+    pub async fn get(&self) -> Result<Vec<Contact>> {
+        let cached = load_cache(&self.client).await?;
+        let contacts = collect(&self.client, &cached).await?;
+
+        Ok(sorted(contacts.into_values(), "").into_iter().collect())
+    }
+
+    /// Get an observable list of the contacts of the user.
+    ///
+    /// See [`ContactList`] for more details.
+    pub async fn list(&self) -> Result<ContactList> {
+        ContactList::new(self.client.clone()).await
+    }
+}
+
+/// A user sharing a room with the user of the client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Contact {
+    /// The ID of the user.
+    pub user_id: OwnedUserId,
+
+    /// The display name of the user.
+    ///
+    /// The one from a direct chat is preferred, since it can be different in
+    /// every room.
+    pub display_name: Option<String>,
+
+    /// The avatar of the user.
+    pub avatar_url: Option<OwnedMxcUri>,
+
+    /// The direct chats with the user.
+    pub direct_rooms: BTreeSet<OwnedRoomId>,
+
+    /// The rooms shared with the user, including the direct chats.
+    pub shared_rooms: BTreeSet<OwnedRoomId>,
+
+    /// When the user last sent a message in a shared room, or was last sent a
+    /// message in a direct chat.
+    ///
+    /// Only the messages received while the contacts are observed are taken
+    /// into account.
+    pub last_interaction: Option<MilliSecondsSinceUnixEpoch>,
+
+    /// The presence of the user, if the homeserver supports presence.
+    #[serde(skip)]
+    pub presence: Option<PresenceState>,
+
+    /// Whether the identity of the user was verified.
+    #[cfg(feature = "e2e-encryption")]
+    #[serde(skip)]
+    pub verified: bool,
+}
+
+impl Contact {
+    fn new(user_id: OwnedUserId) -> Self {
+        Self {
+            user_id,
+            display_name: None,
+            avatar_url: None,
+            direct_rooms: BTreeSet::new(),
+            shared_rooms: BTreeSet::new(),
+            last_interaction: None,
+            presence: None,
+            #[cfg(feature = "e2e-encryption")]
+            verified: false,
+        }
+    }
+
+    /// The display name of the user, or the localpart of their user ID if
+    /// they don't have one.
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or_else(|| self.user_id.localpart())
+    }
+
+    /// Whether the user has a direct chat with the user of the client.
+    pub fn is_direct(&self) -> bool {
+        !self.direct_rooms.is_empty()
+    }
+
+    fn has_profile(&self) -> bool {
+        self.display_name.is_some() || self.avatar_url.is_some()
+    }
+
+    fn interacted_at(&mut self, ts: MilliSecondsSinceUnixEpoch) {
+        self.last_interaction = self.last_interaction.max(Some(ts));
+    }
+}
+
+/// An observable list of the contacts of the user, ordered by last
+/// interaction then by name.
+///
+/// It can be created with [`Contacts::list()`]. The list starts with the
+/// contacts that were cached the last time, and is then refreshed with the
+/// rooms known locally. After that, it is updated with the memberships,
+/// messages and presence received in the sync, and the cache is saved after
+/// every sync. Changes of our own memberships or of the direct chats make the
+/// list collect the contacts from the stores again, at most once per sync.
+///
+/// The list can be narrowed down with [`ContactList::set_search()`].
+///
+/// The event handlers and the background task updating the list are removed
+/// when the list is dropped.
+pub struct ContactList {
+    inner: Arc<ContactListInner>,
+    handles: Vec<EventHandlerHandle>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    task: JoinHandle<()>,
+}
+
+struct ContactListInner {
+    client: Client,
+    /// Whether the contacts changed since they were last cached.
+    dirty: AtomicBool,
+    /// Whether the contacts should be collected again from the stores after
+    /// the current sync.
+    needs_reload: AtomicBool,
+    state: StdMutex<ContactListState>,
+}
+
+struct ContactListState {
+    /// The current search query, in lowercase.
+    search: String,
+    contacts: BTreeMap<OwnedUserId, Contact>,
+    items: ObservableVector<Contact>,
+}
+
+impl ContactList {
+    async fn new(client: Client) -> Result<Self> {
+        let contacts = load_cache(&client).await?;
+
+        let mut items = ObservableVector::new();
+        items.append(sorted(contacts.values().cloned(), ""));
+
+        let inner = Arc::new(ContactListInner {
+            client,
+            dirty: AtomicBool::new(false),
+            needs_reload: AtomicBool::new(false),
+            state: StdMutex::new(ContactListState { search: String::new(), contacts, items }),
+        });
+
+        let handles = Arc::clone(&inner).add_event_handlers();
+        let task = spawn(Arc::clone(&inner).run());
+
+        Ok(Self { inner, handles, task })
+    }
+
+    /// Get the current contacts and a stream of updates of the list.
+    pub fn subscribe(&self) -> (Vector<Contact>, impl Stream<Item = VectorDiff<Contact>>) {
+        let state = self.inner.state.lock().unwrap();
+        ((*state.items).clone(), state.items.subscribe())
+    }
+
+    /// Get the contact with the given user ID, even if it doesn't match the
+    /// current search.
+    pub fn get(&self, user_id: &UserId) -> Option<Contact> {
+        self.inner.state.lock().unwrap().contacts.get(user_id).cloned()
+    }
+
+    /// Only keep the contacts whose display name or user ID contains the given
+    /// query, ignoring case.
+    ///
+    /// An empty query shows all the contacts again.
+    pub fn set_search(&self, query: &str) {
+        let query = query.to_lowercase();
+
+        let mut state = self.inner.state.lock().unwrap();
+        if query == state.search {
+            return;
+        }
+
+        let items = sorted(state.contacts.values().cloned(), &query);
+        state.items.clear();
+        state.items.append(items);
+        state.search = query;
+    }
+
+    /// Get the current search query, in lowercase.
+    pub fn search(&self) -> String {
+        self.inner.state.lock().unwrap().search.clone()
+    }
+
+    /// Collect the contacts from the rooms known locally again.
+    ///
+    /// This can be used to update the verification status of the contacts.
+    pub async fn refresh(&self) -> Result<()> {
+        self.inner.reload().await
+    }
+}
+
+impl Drop for ContactList {
+    fn drop(&mut self) {
+        for handle in self.handles.drain(..) {
+            self.inner.client.remove_event_handler(handle);
+        }
+
+        // The task is aborted when its handle is dropped on WASM.
+        #[cfg(not(target_arch = "wasm32"))]
+        self.task.abort();
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for ContactList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.inner.state.lock().unwrap();
+        f.debug_struct("ContactList")
+            .field("search", &state.search)
+            .field("len", &state.items.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ContactListInner {
+    fn add_event_handlers(self: Arc<Self>) -> Vec<EventHandlerHandle> {
+        let client = self.client.clone();
+
+        let inner = Arc::clone(&self);
+        let message_handle = client.add_event_handler(
+            move |event: AnySyncTimelineEvent, room: Room, client: Client| {
+                let inner = inner.clone();
+                async move {
+                    let AnySyncTimelineEvent::MessageLike(event) = event else { return };
+                    let ts = event.origin_server_ts();
+
+                    if client.user_id() == Some(event.sender()) {
+                        // A message sent in a direct chat is an interaction with
+                        // the other users.
+                        for user_id in room.direct_targets() {
+                            inner.update_contact(&user_id, |contact| contact.interacted_at(ts));
+                        }
+                    } else {
+                        inner.update_contact(event.sender(), |contact| contact.interacted_at(ts));
+                    }
+                }
+            },
+        );
+
+        let inner = Arc::clone(&self);
+        let member_handle = client.add_event_handler(
+            move |event: SyncRoomMemberEvent, room: Room, client: Client| {
+                let inner = inner.clone();
+                async move {
+                    let Room::Joined(room) = room else { return };
+                    let user_id: &UserId = event.state_key();
+
+                    if client.user_id() == Some(user_id) {
+                        // The shared rooms of every contact might have changed.
+                        inner.needs_reload.store(true, atomic::Ordering::SeqCst);
+                        return;
+                    }
+
+                    let room_id = room.room_id();
+                    let is_direct = room.direct_targets().contains(user_id);
+                    let membership = event.membership();
+
+                    inner.update_contact(user_id, |contact| {
+                        match membership {
+                            MembershipState::Join => {
+                                contact.shared_rooms.insert(room_id.to_owned());
+                            }
+                            // The other user might not have joined the direct chat yet.
+                            MembershipState::Invite if is_direct => {
+                                contact.shared_rooms.insert(room_id.to_owned());
+                            }
+                            MembershipState::Invite => return,
+                            _ => {
+                                contact.shared_rooms.remove(room_id);
+                                contact.direct_rooms.remove(room_id);
+                                return;
+                            }
+                        }
+
+                        if is_direct {
+                            contact.direct_rooms.insert(room_id.to_owned());
+                        }
+
+                        if let SyncRoomMemberEvent::Original(event) = &event {
+                            if is_direct || !contact.is_direct() {
+                                contact.display_name = event.content.displayname.clone();
+                                contact.avatar_url = event.content.avatar_url.clone();
+                            }
+                        }
+                    });
+                }
+            },
+        );
+
+        let inner = Arc::clone(&self);
+        let direct_handle = client.add_event_handler(move |_: DirectEvent| {
+            let inner = inner.clone();
+            async move {
+                // The direct chats of every contact might have changed.
+                inner.needs_reload.store(true, atomic::Ordering::SeqCst);
+            }
+        });
+
+        let inner = self;
+        let presence_handle = client.add_event_handler(move |event: PresenceEvent| {
+            let inner = inner.clone();
+            async move {
+                let presence = event.content.presence;
+                inner.update_contact(&event.sender, |contact| contact.presence = Some(presence));
+            }
+        });
+
+        vec![message_handle, member_handle, direct_handle, presence_handle]
+    }
+
+    async fn run(self: Arc<Self>) {
+        self.reload_or_warn().await;
+
+        loop {
+            self.client.inner.sync_beat.listen().await;
+
+            // Reload at most once per sync, however many events asked for it.
+            if self.needs_reload.swap(false, atomic::Ordering::SeqCst) {
+                self.reload_or_warn().await;
+            } else if self.dirty.swap(false, atomic::Ordering::SeqCst) {
+                if let Err(error) = self.save().await {
+                    warn!(?error, "Couldn't cache the contacts");
+                }
+            }
+        }
+    }
+
+    async fn reload_or_warn(&self) {
+        if let Err(error) = self.reload().await {
+            warn!(?error, "Couldn't load the contacts");
+        }
+    }
+
+    /// Replace the contacts with the ones collected from the stores.
+    async fn reload(&self) -> Result<()> {
+        let cached = self.state.lock().unwrap().contacts.clone();
+        let mut contacts = collect(&self.client, &cached).await?;
+
+        {
+            let mut state = self.state.lock().unwrap();
+
+            // Keep the interactions received while the contacts were collected.
+            for contact in contacts.values_mut() {
+                if let Some(current) = state.contacts.get(&contact.user_id) {
+                    contact.last_interaction =
+                        contact.last_interaction.max(current.last_interaction);
+                }
+            }
+
+            let items = sorted(contacts.values().cloned(), &state.search);
+            state.items.clear();
+            state.items.append(items);
+            state.contacts = contacts;
+        }
+
+        self.dirty.store(false, atomic::Ordering::SeqCst);
+        self.save().await
+    }
+
+    /// Save the contacts in the state store.
+    async fn save(&self) -> Result<()> {
+        let contacts: Vec<_> = self.state.lock().unwrap().contacts.values().cloned().collect();
+        let value = serde_json::to_vec(&contacts)?;
+        self.client.store().set_custom_value(CONTACTS_KEY, value).await?;
+
+        Ok(())
+    }
+
+    /// Apply the given change to the contact of the user and update the list.
+    ///
+    /// The contact is created if it doesn't exist, and removed if it doesn't
+    /// share any room with the user anymore.
+    fn update_contact(&self, user_id: &UserId, f: impl FnOnce(&mut Contact)) {
+        let mut state = self.state.lock().unwrap();
+        let ContactListState { search, contacts, items } = &mut *state;
+
+        let contact =
+            contacts.entry(user_id.to_owned()).or_insert_with(|| Contact::new(user_id.to_owned()));
+        f(contact);
+
+        let contact = (!contact.shared_rooms.is_empty()).then(|| contact.clone());
+        if contact.is_none() {
+            contacts.remove(user_id);
+        }
+
+        if let Some(index) = items.iter().position(|c| c.user_id == user_id) {
+            items.remove(index);
+        }
+
+        if let Some(contact) = contact.filter(|contact| matches_search(contact, search)) {
+            let index = items.iter().position(|c| compare(&contact, c) == Ordering::Less);
+            match index {
+                Some(index) => items.insert(index, contact),
+                None => items.push_back(contact),
+            }
+        }
+
+        self.dirty.store(true, atomic::Ordering::SeqCst);
+    }
+}
+
+/// Load the contacts cached in the state store.
+async fn load_cache(client: &Client) -> Result<BTreeMap<OwnedUserId, Contact>> {
+    let Some(value) = client.store().get_custom_value(CONTACTS_KEY).await? else {
+        return Ok(BTreeMap::new());
+    };
+
+    match serde_json::from_slice::<Vec<Contact>>(&value) {
+        Ok(contacts) => Ok(contacts.into_iter().map(|c| (c.user_id.clone(), c)).collect()),
+        Err(error) => {
+            warn!(?error, "Couldn't deserialize the cached contacts, ignoring them");
+            Ok(BTreeMap::new())
+        }
+    }
+}
+
+/// Collect the contacts from the joined rooms known locally.
+///
+/// The last interactions, and the profiles that can't be found in the rooms,
+/// are taken from the cached contacts.
+async fn collect(
+    client: &Client,
+    cached: &BTreeMap<OwnedUserId, Contact>,
+) -> Result<BTreeMap<OwnedUserId, Contact>> {
+    let own_user_id = client.user_id();
+
+    let mut rooms = client.joined_rooms();
+    // Look at the direct chats first, their profiles are preferred.
+    rooms.sort_by_key(|room| room.direct_targets().is_empty());
+
+    let mut contacts = BTreeMap::new();
+
+    for room in rooms {
+        let direct_targets = room.direct_targets();
+        let mut members = room.members_no_sync(RoomMemberships::JOIN).await?;
+
+        // The other user might not have joined the direct chat yet.
+        for user_id in &direct_targets {
+            if !members.iter().any(|member| member.user_id() == user_id) {
+                if let Some(member) = room.get_member_no_sync(user_id).await? {
+                    if *member.membership() == MembershipState::Invite {
+                        members.push(member);
+                    }
+                }
+            }
+        }
+
+        for member in members {
+            if own_user_id == Some(member.user_id()) || member.is_ignored() {
+                continue;
+            }
+
+            let contact = contacts
+                .entry(member.user_id().to_owned())
+                .or_insert_with(|| Contact::new(member.user_id().to_owned()));
+
+            contact.shared_rooms.insert(room.room_id().to_owned());
+            if direct_targets.contains(member.user_id()) {
+                contact.direct_rooms.insert(room.room_id().to_owned());
+            }
+
+            if !contact.has_profile() {
+                contact.display_name = member.display_name().map(ToOwned::to_owned);
+                contact.avatar_url = member.avatar_url().map(ToOwned::to_owned);
+            }
+        }
+    }
+
+    for contact in contacts.values_mut() {
+        if let Some(cached) = cached.get(&contact.user_id) {
+            contact.last_interaction = cached.last_interaction;
+
+            if !contact.has_profile() {
+                contact.display_name = cached.display_name.clone();
+                contact.avatar_url = cached.avatar_url.clone();
+            }
+        }
+    }
+
+    let user_ids: Vec<_> = contacts.keys().cloned().collect();
+    for event in client.store().get_presence_events(&user_ids).await? {
+        match event.deserialize() {
+            Ok(event) => {
+                if let Some(contact) = contacts.get_mut(&event.sender) {
+                    contact.presence = Some(event.content.presence);
+                }
+            }
+            Err(error) => warn!(?error, "Couldn't deserialize a presence event"),
+        }
+    }
+
+    #[cfg(feature = "e2e-encryption")]
+    for contact in contacts.values_mut() {
+        let identity = client.encryption().get_user_identity(&contact.user_id).await?;
+        contact.verified = identity.is_some_and(|identity| identity.is_verified());
+    }
+
+    Ok(contacts)
+}
+
+/// Sort the contacts that match the search query.
+fn sorted(contacts: impl Iterator<Item = Contact>, search: &str) -> Vector<Contact> {
+    let mut contacts: Vec<_> = contacts.filter(|contact| matches_search(contact, search)).collect();
+    contacts.sort_by(compare);
+    contacts.into_iter().collect()
+}
+
+/// Compare contacts by most recent interaction, then by name, ignoring case.
+fn compare(a: &Contact, b: &Contact) -> Ordering {
+    b.last_interaction
+        .cmp(&a.last_interaction)
+        .then_with(|| a.name().to_lowercase().cmp(&b.name().to_lowercase()))
+        .then_with(|| a.user_id.cmp(&b.user_id))
+}
+
+/// Whether the contact's display name or user ID contains the lowercase query.
+fn matches_search(contact: &Contact, search: &str) -> bool {
+    search.is_empty()
+        || contact.user_id.as_str().to_lowercase().contains(search)
+        || contact.display_name.as_ref().is_some_and(|name| name.to_lowercase().contains(search))
+}
@@ -0,0 +1,259 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An observable list of the devices of the user, for session management.
+//!
+//! The devices are the ones returned by the homeserver, combined with their
+//! verification status from the crypto store.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use eyeball_im::{ObservableVector, VectorDiff};
+use futures_core::Stream;
+use imbl::Vector;
+#[cfg(feature = "e2e-encryption")]
+use matrix_sdk_common::executor::{spawn, JoinHandle};
+use ruma::{
+    api::client::{device::Device as DeviceInfo, uiaa::AuthData},
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+};
+#[cfg(feature = "e2e-encryption")]
+use tracing::warn;
+
+use crate::{Client, Result};
+
+/// A device of the user of the client.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct OwnDevice {
+    /// The ID of the device.
+    pub device_id: OwnedDeviceId,
+
+    /// The display name of the device, if it has one.
+    pub display_name: Option<String>,
+
+    /// The IP address where the device was last seen.
+    pub last_seen_ip: Option<String>,
+
+    /// When the device was last seen.
+    pub last_seen_ts: Option<MilliSecondsSinceUnixEpoch>,
+
+    /// Whether this is the device of the client.
+    pub is_current: bool,
+
+    /// Whether the device was verified.
+    ///
+    /// This is `false` if the device isn't known to the crypto store, for
+    /// example because it doesn't support end-to-end encryption.
+    #[cfg(feature = "e2e-encryption")]
+    pub verified: bool,
+}
+
+impl OwnDevice {
+    fn new(device: DeviceInfo, current_device_id: Option<&DeviceId>) -> Self {
+        Self {
+            is_current: current_device_id == Some(&*device.device_id),
+            device_id: device.device_id,
+            display_name: device.display_name,
+            last_seen_ip: device.last_seen_ip,
+            last_seen_ts: device.last_seen_ts,
+            #[cfg(feature = "e2e-encryption")]
+            verified: false,
+        }
+    }
+
+    /// The display name of the device, or its ID if it doesn't have one.
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or_else(|| self.device_id.as_str())
+    }
+}
+
+/// An observable list of the devices of the user, with the current device
+/// first, then ordered by when they were last seen.
+///
+/// It can be created with [`Client::device_list()`]. The list is loaded from
+/// the homeserver, and loaded again after a device is renamed or deleted with
+/// this list, or when the crypto store receives changes of the devices of the
+/// user, like a new device or a verification.
+///
+/// The background task updating the list is stopped when the list is dropped.
+pub struct DeviceList {
+    inner: Arc<DeviceListInner>,
+    #[cfg(feature = "e2e-encryption")]
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    task: JoinHandle<()>,
+}
+
+struct DeviceListInner {
+    client: Client,
+    items: StdMutex<ObservableVector<OwnDevice>>,
+}
+
+impl DeviceList {
+    pub(crate) async fn new(client: Client) -> Result<Self> {
+        let inner =
+            Arc::new(DeviceListInner { client, items: StdMutex::new(ObservableVector::new()) });
+        inner.reload().await?;
+
+        #[cfg(feature = "e2e-encryption")]
+        let task = spawn(Arc::clone(&inner).run());
+
+        Ok(Self {
+            inner,
+            #[cfg(feature = "e2e-encryption")]
+            task,
+        })
+    }
+
+    /// Get the current devices and a stream of updates of the list.
+    pub fn subscribe(&self) -> (Vector<OwnDevice>, impl Stream<Item = VectorDiff<OwnDevice>>) {
+        let items = self.inner.items.lock().unwrap();
+        ((*items).clone(), items.subscribe())
+    }
+
+    /// Get the device with the given ID, if it is in the list.
+    pub fn get(&self, device_id: &DeviceId) -> Option<OwnDevice> {
+        self.inner.items.lock().unwrap().iter().find(|d| *d.device_id == *device_id).cloned()
+    }
+
+    /// Change the display name of the given device, and reload the list.
+    ///
+    /// See [`Client::rename_device()`].
+    pub async fn rename(&self, device_id: &DeviceId, display_name: &str) -> Result<()> {
+        self.inner.client.rename_device(device_id, display_name).await?;
+        self.inner.reload().await
+    }
+
+    /// Delete the given devices, and reload the list.
+    ///
+    /// This request requires user-interactive authentication, see
+    /// [`Client::delete_devices()`] and [`UiaaFlow`].
+    ///
+    /// [`UiaaFlow`]: crate::uiaa::UiaaFlow
+    pub async fn delete(
+        &self,
+        devices: &[OwnedDeviceId],
+        auth_data: Option<AuthData>,
+    ) -> Result<()> {
+        self.inner.client.delete_devices(devices, auth_data).await?;
+        self.inner.reload().await
+    }
+
+    /// Load the devices from the homeserver again.
+    ///
+    /// This can be used to update when the devices were last seen.
+    pub async fn refresh(&self) -> Result<()> {
+        self.inner.reload().await
+    }
+}
+
+impl Drop for DeviceList {
+    fn drop(&mut self) {
+        // The task is aborted when its handle is dropped on WASM.
+        #[cfg(all(feature = "e2e-encryption", not(target_arch = "wasm32")))]
+        self.task.abort();
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for DeviceList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceList")
+            .field("len", &self.inner.items.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeviceListInner {
+    #[cfg(feature = "e2e-encryption")]
+    async fn run(self: Arc<Self>) {
+        use futures_util::{pin_mut, StreamExt};
+
+        use crate::encryption::identities::DeviceUpdate;
+
+        let stream = match self.client.encryption().devices_stream().await {
+            Ok(stream) => stream,
+            Err(error) => {
+                warn!(?error, "Couldn't observe the changes of the devices");
+                return;
+            }
+        };
+        pin_mut!(stream);
+
+        while let Some(update) = stream.next().await {
+            let user_id = match &update {
+                DeviceUpdate::New(device) | DeviceUpdate::Updated(device) => device.user_id(),
+                DeviceUpdate::Deleted { user_id, .. } => &**user_id,
+            };
+            if self.client.user_id() != Some(user_id) {
+                continue;
+            }
+
+            if let Err(error) = self.reload().await {
+                warn!(?error, "Couldn't reload the devices");
+            }
+        }
+    }
+
+    /// Replace the whole list with the devices from the homeserver.
+    async fn reload(&self) -> Result<()> {
+        let response = self.client.devices().await?;
+        let current_device_id = self.client.device_id();
+
+        #[cfg(feature = "e2e-encryption")]
+        let crypto_devices = match self.client.user_id() {
+            Some(user_id) => self.client.encryption().get_user_devices(user_id).await.ok(),
+            None => None,
+        };
+
+        let mut devices: Vec<_> = response
+            .devices
+            .into_iter()
+            .map(|device| {
+                #[allow(unused_mut)]
+                let mut device = OwnDevice::new(device, current_device_id);
+
+                #[cfg(feature = "e2e-encryption")]
+                {
+                    device.verified = crypto_devices
+                        .as_ref()
+                        .and_then(|devices| devices.get(&device.device_id))
+                        .is_some_and(|d| d.is_verified());
+                }
+
+                device
+            })
+            .collect();
+        devices.sort_by(compare);
+
+        let mut items = self.items.lock().unwrap();
+        items.clear();
+        items.append(devices.into_iter().collect());
+
+        Ok(())
+    }
+}
+
+/// Compare devices with the current device first, then by descending last
+/// seen time.
+fn compare(a: &OwnDevice, b: &OwnDevice) -> Ordering {
+    b.is_current
+        .cmp(&a.is_current)
+        .then_with(|| b.last_seen_ts.cmp(&a.last_seen_ts))
+        .then_with(|| a.device_id.cmp(&b.device_id))
+}
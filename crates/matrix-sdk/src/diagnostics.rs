@@ -0,0 +1,279 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime control over the logs emitted by the SDK.
+//!
+//! The SDK doesn't install a [`tracing`] subscriber by itself. To be able to
+//! change the log filter at runtime and to retrieve the most recent log lines,
+//! the layer returned by [`layer()`] must be added to the subscriber installed
+//! by the application, directly on top of the [`Registry`]:
+//!
+//! ```no_run
+//! use tracing_subscriber::{fmt, prelude::*};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! tracing_subscriber::registry()
+//!     .with(matrix_sdk::diagnostics::layer("info,matrix_sdk=debug", 1000)?)
+//!     .with(fmt::layer())
+//!     .init();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The filter and the log buffer can then be accessed with
+//! [`Client::diagnostics()`].
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write as _},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use ruma::MilliSecondsSinceUnixEpoch;
+use thiserror::Error;
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    filter::ParseError,
+    layer::{Context, Layer},
+    registry::LookupSpan,
+    reload, EnvFilter, Registry,
+};
+
+use crate::Client;
+
+/// The global diagnostics state, set by [`layer()`].
+static DIAGNOSTICS: OnceLock<DiagnosticsState> = OnceLock::new();
+
+#[derive(Debug)]
+struct DiagnosticsState {
+    filter_handle: reload::Handle<EnvFilter, Registry>,
+    buffer: LogBuffer,
+}
+
+/// Errors that can happen when changing the diagnostics configuration.
+#[derive(Debug, Error)]
+pub enum DiagnosticsError {
+    /// The diagnostics layer was not installed, see [`layer()`].
+    #[error("the diagnostics layer was not installed")]
+    NotInstalled,
+
+    /// The diagnostics layer was already installed, it can only be installed
+    /// once per process.
+    #[error("the diagnostics layer was already installed")]
+    AlreadyInstalled,
+
+    /// The log filter could not be parsed.
+    #[error(transparent)]
+    InvalidFilter(#[from] ParseError),
+
+    /// The log filter could not be replaced, because the subscriber it was
+    /// installed on was dropped.
+    #[error(transparent)]
+    Reload(#[from] reload::Error),
+}
+
+/// Create the [`tracing`] layer that allows to control the SDK's logs at
+/// runtime.
+///
+/// This layer filters all the events and spans with a filter that can be
+/// replaced with [`Diagnostics::set_log_filter()`], and keeps the last
+/// `buffer_capacity` log lines in memory, so they can be retrieved with
+/// [`Diagnostics::recent_logs()`], for example to attach them to a bug
+/// report.
+///
+/// This can only be called once per process.
+///
+/// # Arguments
+///
+/// * `filter` - The initial log filter, using the same syntax as the `RUST_LOG`
+///   environment variable, e.g. `"info,matrix_sdk::sliding_sync=trace"`.
+///
+/// * `buffer_capacity` - The maximum number of log lines to keep in memory.
+pub fn layer(
+    filter: &str,
+    buffer_capacity: usize,
+) -> Result<impl Layer<Registry> + Send + Sync, DiagnosticsError> {
+    let (filter_layer, filter_handle) = reload::Layer::new(EnvFilter::try_new(filter)?);
+    let buffer = LogBuffer::new(buffer_capacity);
+
+    DIAGNOSTICS
+        .set(DiagnosticsState { filter_handle, buffer: buffer.clone() })
+        .map_err(|_| DiagnosticsError::AlreadyInstalled)?;
+
+    Ok(filter_layer.and_then(LogBufferLayer { buffer }))
+}
+
+fn state() -> Result<&'static DiagnosticsState, DiagnosticsError> {
+    DIAGNOSTICS.get().ok_or(DiagnosticsError::NotInstalled)
+}
+
+/// A high-level API to control the logs emitted by the SDK at runtime.
+///
+/// Since the [`tracing`] subscriber is global, the settings changed through
+/// this API apply to the whole process, not only to the client it was obtained
+/// from. All the methods return [`DiagnosticsError::NotInstalled`] if the
+/// layer created by [`layer()`] wasn't installed.
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    _client: Client,
+}
+
+impl Diagnostics {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { _client: client }
+    }
+
+    /// Replace the current log filter.
+    ///
+    /// This takes effect immediately, for all the spans and events that are
+    /// created afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The new log filter, using the same syntax as the `RUST_LOG`
+    ///   environment variable, e.g. `"info,matrix_sdk::sliding_sync=trace"`.
+    pub fn set_log_filter(&self, filter: &str) -> Result<(), DiagnosticsError> {
+        let filter = EnvFilter::try_new(filter)?;
+        state()?.filter_handle.reload(filter)?;
+        Ok(())
+    }
+
+    /// Get the current log filter.
+    pub fn log_filter(&self) -> Result<String, DiagnosticsError> {
+        Ok(state()?.filter_handle.with_current(ToString::to_string)?)
+    }
+
+    /// Get the most recent log lines, from the oldest to the newest.
+    pub fn recent_logs(&self) -> Result<Vec<String>, DiagnosticsError> {
+        Ok(state()?.buffer.lines())
+    }
+
+    /// Clear the in-memory log lines.
+    pub fn clear_recent_logs(&self) -> Result<(), DiagnosticsError> {
+        state()?.buffer.clear();
+        Ok(())
+    }
+}
+
+/// A bounded, in-memory buffer of formatted log lines.
+#[derive(Clone, Debug)]
+struct LogBuffer {
+    inner: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    fn new(capacity: usize) -> Self {
+        Self { inner: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))), capacity }
+    }
+
+    fn push(&self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut lines = self.inner.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.inner.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+}
+
+/// A [`Layer`] that writes every event it sees to a [`LogBuffer`].
+struct LogBufferLayer {
+    buffer: LogBuffer,
+}
+
+impl<S> Layer<S> for LogBufferLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut line = format!(
+            "{} {} {}:",
+            MilliSecondsSinceUnixEpoch::now().get(),
+            metadata.level(),
+            metadata.target()
+        );
+        event.record(&mut LineVisitor { line: &mut line });
+
+        self.buffer.push(line);
+    }
+}
+
+/// A field visitor that appends the fields of an event to a log line.
+struct LineVisitor<'a> {
+    line: &'a mut String,
+}
+
+impl Visit for LineVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            _ = write!(self.line, " {value}");
+        } else {
+            _ = write!(self.line, " {}={value:?}", field.name());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            _ = write!(self.line, " {value:?}");
+        } else {
+            _ = write!(self.line, " {}={value:?}", field.name());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{LogBuffer, LogBufferLayer};
+
+    #[test]
+    fn log_buffer_keeps_the_most_recent_lines() {
+        let buffer = LogBuffer::new(2);
+        let subscriber =
+            tracing_subscriber::registry().with(LogBufferLayer { buffer: buffer.clone() });
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("first");
+            tracing::info!(room_id = "!a:b.c", "second");
+            tracing::warn!("third");
+        });
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("INFO matrix_sdk::diagnostics::tests:"));
+        assert!(lines[0].contains(" second"));
+        assert!(lines[0].contains(" room_id=\"!a:b.c\""));
+        assert!(lines[1].ends_with("WARN matrix_sdk::diagnostics::tests: third"));
+
+        buffer.clear();
+        assert!(buffer.lines().is_empty());
+    }
+}
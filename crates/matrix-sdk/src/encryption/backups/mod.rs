@@ -0,0 +1,175 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The trust of the server-side backups of the room keys.
+//!
+//! Anyone with access to the account can create a backup, so its room keys
+//! should only be restored, and new room keys only be sent to it, if the backup
+//! is signed by something we trust. [`Backups`] checks the signatures of a
+//! backup, marks a backup as trusted by signing it, and decides whether the
+//! room keys of a backup can be restored.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use matrix_sdk::{
+//! #     encryption::backups::{RecoveryKey, RestoreDecision, RoomKeyBackupInfo},
+//! #     Client,
+//! # };
+//! # async {
+//! # let client: Client = unimplemented!();
+//! # let backup_info: RoomKeyBackupInfo = unimplemented!();
+//! # let recovery_key: RecoveryKey = unimplemented!();
+//! let backups = client.encryption().backups();
+//!
+//! match backups.restore_decision(backup_info.clone(), &recovery_key).await? {
+//!     RestoreDecision::Trusted => {
+//!         // Restore the room keys.
+//!     }
+//!     RestoreDecision::Untrusted(_) => {
+//!         // Ask the user to confirm, and mark the backup as trusted if they do.
+//!         backups.trust("1", backup_info).await?;
+//!     }
+//!     _ => {
+//!         // The room keys of this backup can't be restored.
+//!     }
+//! }
+//! # anyhow::Ok(()) };
+//! ```
+
+pub use matrix_sdk_base::crypto::{
+    backups::{RestoreDecision, SignatureState, SignatureVerification},
+    store::RecoveryKey,
+    types::RoomKeyBackupInfo,
+};
+use ruma::{api::client::backup::update_backup_version, serde::Raw};
+use tracing::instrument;
+
+use crate::{Client, Error, Result};
+
+/// The trust of the server-side backups of the room keys.
+///
+/// Get one with [`Encryption::backups()`](super::Encryption::backups).
+#[derive(Debug, Clone)]
+pub struct Backups {
+    pub(super) client: Client,
+}
+
+impl Backups {
+    /// Check the signatures of the given backup.
+    ///
+    /// `backup_info` should be fetched from the server with the
+    /// [`/room_keys/version`] endpoint.
+    ///
+    /// [`/room_keys/version`]: https://spec.matrix.org/unstable/client-server-api/#get_matrixclientv3room_keysversion
+    pub async fn signatures(
+        &self,
+        backup_info: RoomKeyBackupInfo,
+    ) -> Result<SignatureVerification> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+        Ok(olm.backup_machine().verify_backup(backup_info, true).await?)
+    }
+
+    /// Mark the given backup as trusted.
+    ///
+    /// The backup is signed with the key of our own device and, if it is
+    /// available, our cross-signing master key, and the signatures are
+    /// uploaded, so our other devices that trust this device or our user
+    /// identity trust the backup too.
+    ///
+    /// This should only be called once the user confirmed that they created
+    /// the backup, for example after they entered its recovery key.
+    #[instrument(skip(self, backup_info))]
+    pub async fn trust(&self, version: &str, backup_info: RoomKeyBackupInfo) -> Result<()> {
+        let signed = {
+            let olm = self.client.olm_machine().await;
+            let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+            olm.backup_machine().sign_backup(backup_info).await?
+        };
+
+        let request =
+            update_backup_version::v3::Request::new(version.to_owned(), Raw::new(&signed)?.cast());
+        self.client.send(request, None).await?;
+
+        Ok(())
+    }
+
+    /// Decide whether the room keys of the given backup should be restored
+    /// with the given recovery key.
+    ///
+    /// The room keys should only be restored without asking the user if the
+    /// decision is [`RestoreDecision::Trusted`].
+    pub async fn restore_decision(
+        &self,
+        backup_info: RoomKeyBackupInfo,
+        recovery_key: &RecoveryKey,
+    ) -> Result<RestoreDecision> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+        Ok(olm.backup_machine().restore_decision(backup_info, recovery_key).await?)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use assert_matches::assert_matches;
+    use matrix_sdk_test::async_test;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{RecoveryKey, RestoreDecision, RoomKeyBackupInfo};
+    use crate::test_utils::logged_in_client;
+
+    #[async_test]
+    async fn trust_backup() {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let backups = client.encryption().backups();
+
+        let recovery_key = RecoveryKey::new().unwrap();
+        let backup_info: RoomKeyBackupInfo = serde_json::from_value(json!({
+            "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+            "auth_data": {
+                "public_key": recovery_key.megolm_v1_public_key().to_base64(),
+            },
+        }))
+        .unwrap();
+
+        let decision = backups.restore_decision(backup_info.clone(), &recovery_key).await.unwrap();
+        assert_matches!(decision, RestoreDecision::Untrusted(_));
+        assert!(!backups.signatures(backup_info.clone()).await.unwrap().trusted());
+
+        Mock::given(method("PUT"))
+            .and(path("/_matrix/client/r0/room_keys/version/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        backups.trust("1", backup_info).await.unwrap();
+
+        // The uploaded backup info is signed by this device.
+        let requests = server.received_requests().await.unwrap();
+        let signed: RoomKeyBackupInfo = requests.last().unwrap().body_json().unwrap();
+        let signatures = backups.signatures(signed.clone()).await.unwrap();
+        assert!(signatures.device_signature.trusted());
+
+        let decision = backups.restore_decision(signed, &recovery_key).await.unwrap();
+        assert_eq!(decision, RestoreDecision::Trusted);
+    }
+}
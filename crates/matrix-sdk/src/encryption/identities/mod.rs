@@ -84,6 +84,8 @@
 //! [cross signing keys]: https://spec.matrix.org/unstable/client-server-api/#cross-signing
 //! [device keys]: https://spec.matrix.org/unstable/client-server-api/#device-keys
 
+use ruma::{OwnedDeviceId, OwnedUserId};
+
 mod devices;
 mod users;
 
@@ -91,6 +93,49 @@ pub use devices::{Device, UserDevices};
 pub use matrix_sdk_base::crypto::types::MasterPubkey;
 pub use users::UserIdentity;
 
+/// An update of a cross-signing identity, emitted by
+/// [`Encryption::identities_stream()`].
+///
+/// [`Encryption::identities_stream()`]: crate::encryption::Encryption::identities_stream
+#[derive(Debug, Clone)]
+pub enum IdentityUpdate {
+    /// The identity of a user was received for the first time.
+    New(UserIdentity),
+    /// The identity of a user was received again, without a change of its
+    /// master key.
+    Updated(UserIdentity),
+    /// The user reset their cross-signing keys, their identity has a new
+    /// master key.
+    Changed {
+        /// The new identity of the user.
+        identity: UserIdentity,
+        /// Whether the previous identity was verified.
+        ///
+        /// The new identity isn't, so the user should probably be warned
+        /// about the change and asked to verify it again.
+        verification_broken: bool,
+    },
+}
+
+/// An update of the devices of a user, emitted by
+/// [`Encryption::devices_stream()`].
+///
+/// [`Encryption::devices_stream()`]: crate::encryption::Encryption::devices_stream
+#[derive(Debug, Clone)]
+pub enum DeviceUpdate {
+    /// The device was seen for the first time.
+    New(Device),
+    /// The keys or the display name of the device were updated.
+    Updated(Device),
+    /// The device was deleted by its owner.
+    Deleted {
+        /// The owner of the device.
+        user_id: OwnedUserId,
+        /// The ID of the device.
+        device_id: OwnedDeviceId,
+    },
+}
+
 /// Error for the manual verification step, when we manually sign users or
 /// devices.
 #[derive(thiserror::Error, Debug)]
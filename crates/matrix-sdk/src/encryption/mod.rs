@@ -17,15 +17,33 @@
 #![cfg_attr(target_arch = "wasm32", allow(unused_imports))]
 
 use std::{
-    collections::{BTreeMap, HashSet},
-    io::{Read, Write},
+    collections::{BTreeMap, BTreeSet, HashSet},
+    future::Future,
+    io::{Cursor, Read},
     iter,
     path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
 };
 
 use eyeball::shared::Observable as SharedObservable;
-use futures_util::stream::{self, StreamExt};
-use matrix_sdk_base::crypto::{OlmMachine, OutgoingRequest, RoomMessageRequest, ToDeviceRequest};
+use futures_core::Stream;
+use futures_util::{
+    io::AllowStdIo,
+    pin_mut,
+    stream::{self, StreamExt, TryStreamExt},
+};
+use matrix_sdk_base::{
+    crypto::{
+        olm::ExportedRoomKey,
+        store::{CryptoStateExport, DeviceChanges, IdentityChanges},
+        types::events::room_key_bundle::RoomKeyBundle,
+        OlmMachine, OutgoingRequest, RoomKeyExportReader, RoomKeyExportWriter, RoomMessageRequest,
+        ToDeviceRequest,
+    },
+    SendOutsideWasm, Session, SyncOutsideWasm,
+};
 use ruma::{
     api::client::{
         backup::add_backup_keys::v3::Response as KeysBackupResponse,
@@ -38,21 +56,31 @@ use ruma::{
         },
         uiaa::AuthData,
     },
-    assign, DeviceId, OwnedDeviceId, OwnedUserId, TransactionId, UserId,
+    assign,
+    events::{room::MediaSource, StaticEventContent, ToDeviceEvent, ToDeviceEventContent},
+    DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, TransactionId, UserId,
 };
-use tokio::sync::RwLockReadGuard;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLockReadGuard};
 use tracing::{debug, instrument, trace, warn};
+use url::Url;
 
 use crate::{
     attachment::{AttachmentInfo, Thumbnail},
+    config::RequestConfig,
     encryption::{
-        identities::{Device, UserDevices},
-        verification::{SasVerification, Verification, VerificationRequest},
+        backups::Backups,
+        identities::{Device, DeviceUpdate, IdentityUpdate, UserDevices},
+        verification::{
+            AutoAcceptHandle, AutoAcceptPolicy, SasVerification, Verification, VerificationRequest,
+        },
     },
     error::HttpResult,
+    media::{MediaFormat, MediaRequest},
     room, Client, Error, Result, TransmissionProgress,
 };
 
+pub mod backups;
 mod futures;
 pub mod identities;
 pub mod verification;
@@ -62,13 +90,18 @@ pub use matrix_sdk_base::crypto::{
         SessionCreationError as MegolmSessionCreationError,
         SessionExportError as OlmSessionExportError,
     },
-    vodozemac, CrossSigningStatus, CryptoStoreError, DecryptorError, EventError, KeyExportError,
-    LocalTrust, MediaEncryptionInfo, MegolmError, OlmError, RoomKeyImportResult, SecretImportError,
-    SessionCreationError, SignatureError, VERSION,
+    types::events::room_key_bundle::{RoomKeyBundleContent, RoomKeyBundleEvent},
+    vodozemac, CrossSigningStatus, CryptoStoreError, DecryptionCounters, DecryptionStatistics,
+    DecryptorError, EncryptionPreflightReport, EncryptionSettings, EventError, KeyExportError,
+    LateDecryptionReport, LocalTrust, MediaEncryptionInfo, MegolmError, OlmError, PreflightWarning,
+    RoomKeyImportResult, SecretImportError, SessionCreationError, SignatureError, TrustRequirement,
+    UserEncryptionPreflight, UtdCause, UtdReport, UtdTelemetryHook, VERSION,
 };
+#[cfg(feature = "decryption-audit")]
+pub use matrix_sdk_base::crypto::{DecryptionAuditRecord, DecryptionAuditSink};
 
 pub use self::futures::PrepareEncryptedFile;
-pub use crate::error::RoomKeyImportError;
+pub use crate::error::{RoomKeyImportError, SessionImportError};
 
 impl Client {
     pub(crate) async fn olm_machine(&self) -> RwLockReadGuard<'_, Option<OlmMachine>> {
@@ -104,7 +137,17 @@ impl Client {
     ) -> Result<get_keys::v3::Response> {
         let request = assign!(get_keys::v3::Request::new(), { device_keys });
 
-        let response = self.send(request, None).await?;
+        let response = match self.send(request, None).await {
+            Ok(response) => response,
+            Err(error) => {
+                // Query the keys of these users again with the next requests.
+                if let Some(machine) = self.olm_machine().await.as_ref() {
+                    machine.mark_keys_query_as_failed(request_id).await;
+                }
+
+                return Err(error.into());
+            }
+        };
         self.mark_request_as_sent(request_id, &response).await?;
 
         Ok(response)
@@ -189,9 +232,7 @@ impl Client {
             .with_send_progress_observable(send_progress)
             .await?;
 
-        use std::io::Cursor;
-
-        use ruma::events::room::{self, message, MediaSource};
+        use ruma::events::room::{self, message};
         Ok(match content_type.type_() {
             mime::IMAGE => {
                 let info = assign!(info.map(room::ImageInfo::from).unwrap_or_default(), {
@@ -356,6 +397,113 @@ impl Client {
         room
     }
 
+    /// Export the session of the client, to restore it on another machine
+    /// with [`Client::import_session()`].
+    ///
+    /// The export contains the homeserver URL, the access and refresh tokens,
+    /// the device ID and the encryption keys of the device. It is encrypted
+    /// with the given passphrase, using the same scheme as
+    /// [`Encryption::export_room_keys()`].
+    ///
+    /// This is meant to migrate a headless client, like a bot or a command
+    /// line tool, to another machine without logging in again. The device is
+    /// moved, not copied: this client must not be used anymore after the
+    /// export, otherwise the encryption state of both clients would diverge.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    /// export.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let export = client.export_session("secret-passphrase").await?;
+    /// std::fs::write("/home/example/session.txt", export)?;
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn export_session(&self, passphrase: &str) -> Result<String> {
+        let session = self.session().ok_or(Error::AuthenticationRequired)?;
+
+        let olm = self.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+        let crypto = olm.export_state().await?;
+
+        let export =
+            SessionExport { homeserver: self.homeserver().await.to_string(), session, crypto };
+        let export = zeroize::Zeroizing::new(serde_json::to_string(&export)?);
+        let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
+
+        let encrypt =
+            move || matrix_sdk_base::crypto::encrypt_with_passphrase(&export, &passphrase, 500_000);
+
+        let task = tokio::task::spawn_blocking(encrypt);
+        Ok(task.await.expect("Task join error"))
+    }
+
+    /// Restore a session exported with [`Client::export_session()`].
+    ///
+    /// This must be called on a new client, that isn't logged in, instead of
+    /// [`Client::restore_session()`]. The homeserver of the client is replaced
+    /// by the one of the export, and the client is logged in with the
+    /// exported device.
+    ///
+    /// # Arguments
+    ///
+    /// * `export` - The encrypted session export.
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt the export.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// let client = Client::new(homeserver).await?;
+    ///
+    /// let export = std::fs::read_to_string("/home/example/session.txt")?;
+    /// client.import_session(&export, "secret-passphrase").await?;
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn import_session(
+        &self,
+        export: &str,
+        passphrase: &str,
+    ) -> Result<(), SessionImportError> {
+        if self.logged_in() {
+            return Err(SessionImportError::AlreadyLoggedIn);
+        }
+
+        let export = export.to_owned();
+        let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
+
+        let decrypt = move || {
+            matrix_sdk_base::crypto::decrypt_with_passphrase(&export, &passphrase)
+                .map(zeroize::Zeroizing::new)
+        };
+
+        let task = tokio::task::spawn_blocking(decrypt);
+        let export = task.await.expect("Task join error")?;
+        let export: SessionExport = serde_json::from_str(&export)?;
+
+        let homeserver = Url::parse(&export.homeserver).map_err(Error::from)?;
+
+        self.base_client().import_crypto_state(export.crypto).await.map_err(Error::from)?;
+        self.set_homeserver(homeserver).await;
+        self.restore_session(export.session).await?;
+
+        Ok(())
+    }
+
     async fn send_outgoing_request(&self, r: OutgoingRequest) -> Result<()> {
         use matrix_sdk_base::crypto::OutgoingRequests;
 
@@ -457,6 +605,12 @@ impl Encryption {
         self.client.olm_machine().await.as_ref().map(|o| o.identity_keys().ed25519.to_base64())
     }
 
+    /// Get the [`Backups`] manager, to check and set the trust of the
+    /// server-side backups of the room keys.
+    pub fn backups(&self) -> Backups {
+        Backups { client: self.client.clone() }
+    }
+
     /// Get the status of the private cross signing keys.
     ///
     /// This can be used to check which private cross signing keys we have
@@ -510,6 +664,38 @@ impl Encryption {
             .map(|r| VerificationRequest { inner: r, client: self.client.clone() })
     }
 
+    /// Accept the verification requests of the users and devices allowed by
+    /// the given policy, and complete the SAS verifications that follow without
+    /// comparing the short authentication strings.
+    ///
+    /// This is meant for bots and test environments, where no human can
+    /// compare the emojis. The requests of the other devices are left
+    /// untouched, and every decision is logged. See [`AutoAcceptPolicy`] for
+    /// the security implications.
+    ///
+    /// The verification requests are accepted as they are received by the
+    /// sync, until [`AutoAcceptHandle::stop()`] is called.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, config::SyncSettings, ruma::owned_user_id};
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// use matrix_sdk::encryption::verification::AutoAcceptPolicy;
+    ///
+    /// let policy = AutoAcceptPolicy::new([owned_user_id!("@operator:example.org")]);
+    /// let handle = client.encryption().auto_accept_verifications(policy);
+    ///
+    /// client.sync(SyncSettings::default()).await?;
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub fn auto_accept_verifications(&self, policy: AutoAcceptPolicy) -> AutoAcceptHandle {
+        verification::auto_accept::register(&self.client, policy)
+    }
+
     /// Get a specific device of a user.
     ///
     /// # Arguments
@@ -592,16 +778,21 @@ impl Encryption {
         Ok(UserDevices { inner: devices, client: self.client.clone() })
     }
 
-    /// Get a E2EE identity of an user.
+    /// Check whether the given users would be able to read the messages of an
+    /// encrypted room, before creating it or enabling encryption in it.
     ///
-    /// # Arguments
+    /// The device lists of the users that aren't tracked yet are downloaded
+    /// first. The report contains the number of devices that would receive
+    /// the room keys, and warnings about the users that have no device
+    /// supporting encryption, that can't be verified or that have a lot of
+    /// devices.
     ///
-    /// * `user_id` - The unique id of the user that the identity belongs to.
+    /// # Arguments
     ///
-    /// Returns a `UserIdentity` if one is found and the crypto store
-    /// didn't throw an error.
+    /// * `user_ids` - The users that would be members of the room.
     ///
-    /// This will always return None if the client hasn't been logged in.
+    /// * `settings` - The encryption settings of the room, e.g. to only share
+    ///   the room keys with verified devices.
     ///
     /// # Examples
     ///
@@ -609,188 +800,1050 @@ impl Encryption {
     /// # use matrix_sdk::{Client, ruma::user_id};
     /// # use url::Url;
     /// # async {
-    /// # let alice = user_id!("@alice:example.org");
     /// # let homeserver = Url::parse("http://example.com")?;
     /// # let client = Client::new(homeserver).await?;
-    /// let user = client.encryption().get_user_identity(alice).await?;
+    /// use matrix_sdk::encryption::{EncryptionSettings, PreflightWarning};
     ///
-    /// if let Some(user) = user {
-    ///     println!("{:?}", user.is_verified());
+    /// let bob = user_id!("@bob:example.org");
+    /// let report = client
+    ///     .encryption()
+    ///     .encryption_preflight([bob], EncryptionSettings::default())
+    ///     .await?;
     ///
-    ///     let verification = user.request_verification().await?;
+    /// for (user_id, warning) in report.warnings() {
+    ///     if *warning == PreflightWarning::NoEncryptionCapableDevices {
+    ///         println!("{user_id} has no device supporting encryption");
+    ///     }
     /// }
     /// # anyhow::Ok(()) };
     /// ```
-    pub async fn get_user_identity(
+    pub async fn encryption_preflight(
         &self,
-        user_id: &UserId,
-    ) -> Result<Option<crate::encryption::identities::UserIdentity>, CryptoStoreError> {
-        use crate::encryption::identities::UserIdentity;
-
-        let olm = self.client.olm_machine().await;
-        let Some(olm) = olm.as_ref() else { return Ok(None) };
-        let identity = olm.get_identity(user_id, None).await?;
+        user_ids: impl IntoIterator<Item = &UserId>,
+        settings: EncryptionSettings,
+    ) -> Result<EncryptionPreflightReport> {
+        const KEYS_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
 
-        Ok(identity.map(|i| match i {
-            matrix_sdk_base::crypto::UserIdentities::Own(i) => {
-                UserIdentity::new_own(self.client.clone(), i)
-            }
-            matrix_sdk_base::crypto::UserIdentities::Other(i) => {
-                UserIdentity::new(self.client.clone(), i, self.client.get_dm_room(user_id))
-            }
-        }))
-    }
+        let user_ids: Vec<_> = user_ids.into_iter().collect();
 
-    /// Create and upload a new cross signing identity.
-    ///
-    /// # Arguments
-    ///
-    /// * `auth_data` - This request requires user interactive auth, the first
-    /// request needs to set this to `None` and will always fail with an
-    /// `UiaaResponse`. The response will contain information for the
-    /// interactive auth and the same request needs to be made but this time
-    /// with some `auth_data` provided.
-    ///
-    /// # Examples
-    ///
-    /// ```no_run
-    /// # use std::collections::BTreeMap;
-    /// # use matrix_sdk::{ruma::api::client::uiaa, Client};
-    /// # use url::Url;
-    /// # use serde_json::json;
-    /// # async {
-    /// # let homeserver = Url::parse("http://example.com")?;
-    /// # let client = Client::new(homeserver).await?;
-    /// if let Err(e) = client.encryption().bootstrap_cross_signing(None).await {
-    ///     if let Some(response) = e.as_uiaa_response() {
-    ///         let mut password = uiaa::Password::new(
-    ///             uiaa::UserIdentifier::UserIdOrLocalpart("example".to_owned()),
-    ///             "wordpass".to_owned(),
-    ///         );
-    ///         password.session = response.session.clone();
-    ///
-    ///         client
-    ///             .encryption()
-    ///             .bootstrap_cross_signing(Some(uiaa::AuthData::Password(password)))
-    ///             .await
-    ///             .expect("Couldn't bootstrap cross signing")
-    ///     } else {
-    ///         panic!("Error durign cross signing bootstrap {:#?}", e);
-    ///     }
-    /// }
-    /// # anyhow::Ok(()) };
-    pub async fn bootstrap_cross_signing(&self, auth_data: Option<AuthData>) -> Result<()> {
-        let olm = self.client.olm_machine().await;
-        let olm = olm.as_ref().ok_or(Error::AuthenticationRequired)?;
+        self.client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .update_tracked_users(user_ids.iter().copied())
+            .await?;
 
-        let (request, signature_request) = olm.bootstrap_cross_signing(false).await?;
+        // Send the keys query for the users that weren't tracked.
+        self.client.send_outgoing_requests().await?;
 
-        let request = assign!(UploadSigningKeysRequest::new(), {
-            auth: auth_data,
-            master_key: request.master_key.map(|c| c.to_raw()),
-            self_signing_key: request.self_signing_key.map(|c| c.to_raw()),
-            user_signing_key: request.user_signing_key.map(|c| c.to_raw()),
-        });
+        let report = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .encryption_preflight(user_ids.into_iter(), settings, Some(KEYS_QUERY_TIMEOUT))
+            .await?;
 
-        self.client.send(request, None).await?;
-        self.client.send(signature_request, None).await?;
+        Ok(report)
+    }
 
-        Ok(())
+    /// Get the global [`TrustRequirement`] for the devices that receive the
+    /// room keys of the messages we send.
+    pub async fn trust_requirement(&self) -> Result<TrustRequirement> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+        Ok(olm.store().get_trust_requirement().await?)
     }
 
-    /// Export E2EE keys that match the given predicate encrypting them with the
-    /// given passphrase.
-    ///
-    /// # Arguments
-    ///
-    /// * `path` - The file path where the exported key file will be saved.
-    ///
-    /// * `passphrase` - The passphrase that will be used to encrypt the
-    ///   exported
-    /// room keys.
+    /// Set the global [`TrustRequirement`] for the devices that receive the
+    /// room keys of the messages we send.
     ///
-    /// * `predicate` - A closure that will be called for every known
-    /// `InboundGroupSession`, which represents a room key. If the closure
-    /// returns `true` the `InboundGroupSessoin` will be included in the export,
-    /// if the closure returns `false` it will not be included.
+    /// A stricter requirement can be set for a single room with
+    /// [`Room::set_trust_requirement()`](crate::room::Room::set_trust_requirement).
     ///
-    /// # Panics
-    ///
-    /// This method will panic if it isn't run on a Tokio runtime.
+    /// When some devices don't satisfy the requirement, sending a message
+    /// fails with [`OlmError::UntrustedDevices`], unless the handler set with
+    /// [`Encryption::set_untrusted_devices_handler()`] resolves the situation.
+    pub async fn set_trust_requirement(&self, trust_requirement: TrustRequirement) -> Result<()> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+        Ok(olm.store().set_trust_requirement(trust_requirement).await?)
+    }
+
+    /// Set the handler that decides what to do with the devices that don't
+    /// satisfy the [`TrustRequirement`] when sending a message.
     ///
-    /// This method will panic if it can't get enough randomness from the OS to
-    /// encrypt the exported keys securely.
+    /// The handler is called with the ID of the room and the IDs of the
+    /// untrusted devices of each user, e.g. to ask the user whether the
+    /// message should be sent to them anyway. Without a handler, sending the
+    /// message fails with [`OlmError::UntrustedDevices`].
     ///
     /// # Examples
     ///
     /// ```no_run
-    /// # use std::{path::PathBuf, time::Duration};
-    /// # use matrix_sdk::{
-    /// #     Client, config::SyncSettings,
-    /// #     ruma::room_id,
-    /// # };
+    /// # use matrix_sdk::Client;
     /// # use url::Url;
     /// # async {
-    /// # let homeserver = Url::parse("http://localhost:8080")?;
-    /// # let mut client = Client::new(homeserver).await?;
-    /// let path = PathBuf::from("/home/example/e2e-keys.txt");
-    /// // Export all room keys.
-    /// client
-    ///     .encryption()
-    ///     .export_room_keys(path, "secret-passphrase", |_| true)
-    ///     .await?;
-    ///
-    /// // Export only the room keys for a certain room.
-    /// let path = PathBuf::from("/home/example/e2e-room-keys.txt");
-    /// let room_id = room_id!("!test:localhost");
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// use matrix_sdk::encryption::{TrustRequirement, UntrustedDevicesDecision};
     ///
     /// client
     ///     .encryption()
-    ///     .export_room_keys(path, "secret-passphrase", |s| s.room_id() == room_id)
+    ///     .set_trust_requirement(TrustRequirement::Verified)
     ///     .await?;
+    /// client.encryption().set_untrusted_devices_handler(
+    ///     |room_id, devices| async move {
+    ///         println!("Unverified devices in {room_id}: {devices:?}");
+    ///         // Ask the user what to do.
+    ///         UntrustedDevicesDecision::Abort
+    ///     },
+    /// );
     /// # anyhow::Ok(()) };
     /// ```
-    #[cfg(not(target_arch = "wasm32"))]
-    pub async fn export_room_keys(
+    pub fn set_untrusted_devices_handler<H, Fut>(&self, handler: H)
+    where
+        H: Fn(OwnedRoomId, BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>) -> Fut
+            + SendOutsideWasm
+            + SyncOutsideWasm
+            + 'static,
+        Fut: Future<Output = UntrustedDevicesDecision> + SendOutsideWasm + 'static,
+    {
+        let handler: UntrustedDevicesHandlerFn =
+            Arc::new(move |room_id, devices| Box::pin(handler(room_id, devices)));
+        *self.client.inner.untrusted_devices_handler.write().unwrap() = Some(handler);
+    }
+
+    /// Ask the untrusted devices handler what to do with the given devices,
+    /// and apply its decision.
+    ///
+    /// Returns whether sharing the room key can be retried.
+    pub(crate) async fn resolve_untrusted_devices(
         &self,
-        path: PathBuf,
-        passphrase: &str,
-        predicate: impl FnMut(&matrix_sdk_base::crypto::olm::InboundGroupSession) -> bool,
-    ) -> Result<()> {
-        let olm = self.client.olm_machine().await;
-        let olm = olm.as_ref().ok_or(Error::AuthenticationRequired)?;
+        room_id: &RoomId,
+        devices: &BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>,
+    ) -> Result<bool> {
+        let Some(handler) = self.client.inner.untrusted_devices_handler.read().unwrap().clone()
+        else {
+            return Ok(false);
+        };
 
-        let keys = olm.export_room_keys(predicate).await?;
-        let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
+        let decision = handler(room_id.to_owned(), devices.clone()).await;
+        debug!(?decision, "Resolving untrusted devices");
 
-        let encrypt = move || -> Result<()> {
-            let export: String =
-                matrix_sdk_base::crypto::encrypt_room_key_export(&keys, &passphrase, 500_000)?;
-            let mut file = std::fs::File::create(path)?;
-            file.write_all(&export.into_bytes())?;
-            Ok(())
+        let trust_state = match decision {
+            UntrustedDevicesDecision::Abort => return Ok(false),
+            UntrustedDevicesDecision::Ignore => LocalTrust::Ignored,
+            UntrustedDevicesDecision::Blacklist => LocalTrust::BlackListed,
         };
 
-        let task = tokio::task::spawn_blocking(encrypt);
-        task.await.expect("Task join error")
+        for (user_id, device_ids) in devices {
+            for device_id in device_ids {
+                if let Some(device) = self.get_device(user_id, device_id).await? {
+                    device.set_local_trust(trust_state).await?;
+                }
+            }
+        }
+
+        Ok(true)
     }
 
-    /// Import E2EE keys from the given file path.
-    ///
-    /// # Arguments
+    /// Enable the cross-process lock of the crypto store, to share the store
+    /// with other processes, like the notification extension of an app.
     ///
-    /// * `path` - The file path where the exported key file will can be found.
+    /// Once it is enabled, every process must call
+    /// [`Encryption::lock_store()`] before using the encryption, and
+    /// [`Encryption::unlock_store()`] when done, so only one process at a time
+    /// uses the store. The lock isn't reentrant, the tasks of a process must
+    /// not take it concurrently.
     ///
-    /// * `passphrase` - The passphrase that should be used to decrypt the
-    /// exported room keys.
+    /// The syncs take the lock themselves while they process their response,
+    /// so it must not be held when syncing.
     ///
-    /// Returns a tuple of numbers that represent the number of sessions that
-    /// were imported and the total number of sessions that were found in the
-    /// key export.
+    /// This must be called after the client is logged in.
     ///
-    /// # Panics
+    /// # Arguments
     ///
-    /// This method will panic if it isn't run on a Tokio runtime.
+    /// * `lock_holder` - The value identifying this process, which must be
+    ///   different for every process sharing the store.
+    pub async fn enable_cross_process_store_lock(&self, lock_holder: String) -> Result<()> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+
+        let mut lock =
+            olm.store().create_store_lock(CROSS_PROCESS_STORE_LOCK_KEY.to_owned(), lock_holder);
+
+        lock.lock().await?;
+        let result = olm
+            .initialize_crypto_store_generation(&self.client.inner.crypto_store_generation)
+            .await;
+        lock.unlock().await?;
+        result?;
+
+        self.client
+            .inner
+            .cross_process_crypto_store_lock
+            .set(Mutex::new(lock))
+            .map_err(|_| Error::BadCryptoStoreState)?;
+
+        Ok(())
+    }
+
+    /// Take the cross-process lock of the crypto store, waiting for another
+    /// process to release it.
+    ///
+    /// If another process modified the store since this process last held the
+    /// lock, the state of the [`OlmMachine`] is outdated, so it is recreated
+    /// from the store. Returns `true` in that case.
+    ///
+    /// Does nothing if the lock wasn't enabled with
+    /// [`Encryption::enable_cross_process_store_lock()`].
+    pub async fn lock_store(&self) -> Result<bool> {
+        let Some(lock) = self.client.inner.cross_process_crypto_store_lock.get() else {
+            return Ok(false);
+        };
+        lock.lock().await.lock().await?;
+
+        let reloaded = self.maintain_store_generation().await;
+        if reloaded.is_err() {
+            // Don't block the other processes if this one can't use the store.
+            lock.lock().await.unlock().await?;
+        }
+
+        reloaded
+    }
+
+    /// Recreate the [`OlmMachine`] if another process modified the crypto
+    /// store.
+    async fn maintain_store_generation(&self) -> Result<bool> {
+        let olm = self.client.olm_machine().await;
+        let Some(machine) = olm.as_ref() else {
+            return Ok(false);
+        };
+        let modified = machine
+            .maintain_crypto_store_generation(&self.client.inner.crypto_store_generation)
+            .await?;
+        // The machine must be released before it is recreated.
+        drop(olm);
+
+        if modified {
+            debug!("The crypto store was modified by another process, reloading the OlmMachine");
+            self.client.base_client().regenerate_olm().await?;
+        }
+
+        Ok(modified)
+    }
+
+    /// Release the cross-process lock of the crypto store, taken with
+    /// [`Encryption::lock_store()`].
+    ///
+    /// Does nothing if the lock wasn't enabled with
+    /// [`Encryption::enable_cross_process_store_lock()`].
+    pub async fn unlock_store(&self) -> Result<()> {
+        if let Some(lock) = self.client.inner.cross_process_crypto_store_lock.get() {
+            lock.lock().await.unlock().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Share the history of a room with a user, by sending them a bundle of
+    /// all the room keys we have for the room.
+    ///
+    /// The bundle is encrypted and uploaded as a file, and only the
+    /// decryption info of the file is sent to the devices of the user, which
+    /// is much cheaper than sending the room keys one by one. This is useful
+    /// for example for bots, to let the users they invite read the messages
+    /// that were sent before.
+    ///
+    /// The devices of the user need to be known already, which is the case if
+    /// they are a member of an encrypted room we're in.
+    ///
+    /// The receiving side should call
+    /// [`Encryption::import_room_key_bundle()`] with the to-device event,
+    /// before joining the room. The bundle should be shared by the user who
+    /// invited the receiver to the room, otherwise it is rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room whose history should be shared.
+    ///
+    /// * `user_id` - The user who should receive the history.
+    pub async fn share_room_history(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+        let bundle = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .build_room_key_bundle(room_id)
+            .await?;
+
+        if bundle.room_keys.is_empty() {
+            debug!(?room_id, "No room keys to share with the new member");
+            return Ok(());
+        }
+
+        let mut cursor = Cursor::new(serde_json::to_vec(&bundle)?);
+        let file = self.client.prepare_encrypted_file(&mime::APPLICATION_JSON, &mut cursor).await?;
+        let content = RoomKeyBundleContent::new(room_id.to_owned(), file);
+
+        self.client.claim_one_time_keys(iter::once(user_id)).await?;
+
+        let requests = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .share_room_key_bundle_data(user_id, &content)
+            .await?;
+
+        if requests.is_empty() {
+            warn!(?user_id, "None of the devices of the user can receive the room key bundle");
+        }
+
+        for request in requests {
+            self.client.send_to_device(&request).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Download a room key bundle we received and import its room keys.
+    ///
+    /// Room key bundle events that weren't encrypted are dropped before
+    /// reaching the event handlers, so the sender of the event is
+    /// authenticated. The imported room keys are still marked as not coming
+    /// directly from the original sender.
+    ///
+    /// As required by MSC4268, the bundle is only accepted while we are
+    /// invited to the room, and if it was sent by the user who invited us, so
+    /// it must be imported before joining the room. Otherwise
+    /// [`Error::RoomKeyBundleNotFromInviter`] is returned and the bundle isn't
+    /// downloaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The room key bundle to-device event.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// use matrix_sdk::{
+    ///     encryption::RoomKeyBundleEvent,
+    ///     ruma::{events::AnyToDeviceEvent, serde::Raw},
+    /// };
+    ///
+    /// client.add_event_handler(
+    ///     |ev: Raw<AnyToDeviceEvent>, client: Client| async move {
+    ///         if let Ok(ev) = ev.deserialize_as::<RoomKeyBundleEvent>() {
+    ///             client.encryption().import_room_key_bundle(&ev).await?;
+    ///         }
+    ///
+    ///         anyhow::Ok(())
+    ///     },
+    /// );
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub async fn import_room_key_bundle(
+        &self,
+        event: &RoomKeyBundleEvent,
+    ) -> Result<RoomKeyImportResult> {
+        let content = &event.content;
+
+        let Some(room) = self.client.get_invited_room(&content.room_id) else {
+            warn!(
+                room_id = ?content.room_id,
+                sender = ?event.sender,
+                "Ignoring a room key bundle for a room we aren't invited to",
+            );
+            return Err(Error::RoomKeyBundleNotFromInviter);
+        };
+
+        let invite = room.invite_details().await?;
+        let inviter_id = invite.invitee.event().sender();
+        if inviter_id != event.sender {
+            warn!(
+                room_id = ?content.room_id,
+                sender = ?event.sender,
+                inviter = ?inviter_id,
+                "Ignoring a room key bundle that wasn't sent by the inviter",
+            );
+            return Err(Error::RoomKeyBundleNotFromInviter);
+        }
+
+        let request = MediaRequest {
+            source: MediaSource::Encrypted(Box::new(content.file.clone())),
+            format: MediaFormat::File,
+        };
+        let data = self.client.media().get_media_content(&request, false).await?;
+        let bundle: RoomKeyBundle = serde_json::from_slice(&data)?;
+
+        let result = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .import_room_key_bundle(bundle, &content.room_id)
+            .await?;
+
+        Ok(result)
+    }
+
+    /// Send an encrypted to-device event to the devices of a user.
+    ///
+    /// The device list of the user is downloaded if it isn't tracked yet, and
+    /// the missing Olm sessions with the devices are established by claiming
+    /// one-time keys. The event is sent in batches, and the requests are
+    /// retried a few times if they fail.
+    ///
+    /// The receiving side can get the event with
+    /// [`Encryption::encrypted_to_device_events()`].
+    ///
+    /// Returns the devices that won't receive the event, because they are
+    /// unknown, blacklisted, or because no Olm session could be established
+    /// with them.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should receive the event.
+    ///
+    /// * `device_ids` - The devices that should receive the event, or `None` to
+    ///   send it to all the devices of the user.
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `content` - The content of the event.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::{device_id, user_id}};
+    /// # use serde_json::json;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let bob = user_id!("@bob:example.org");
+    /// let failed_devices = client
+    ///     .encryption()
+    ///     .send_encrypted_to_device(
+    ///         bob,
+    ///         Some(&[device_id!("BOBDEVICE").to_owned()]),
+    ///         "org.example.call.invite",
+    ///         json!({ "call_id": "1234" }),
+    ///     )
+    ///     .await?;
+    ///
+    /// if !failed_devices.is_empty() {
+    ///     println!("Couldn't reach the devices {failed_devices:?}");
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[instrument(skip(self, content))]
+    pub async fn send_encrypted_to_device(
+        &self,
+        user_id: &UserId,
+        device_ids: Option<&[OwnedDeviceId]>,
+        event_type: &str,
+        content: impl Serialize,
+    ) -> Result<Vec<OwnedDeviceId>> {
+        let content = serde_json::to_value(content)?;
+
+        self.client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .update_tracked_users(iter::once(user_id))
+            .await?;
+
+        // Send the keys query if the user wasn't tracked.
+        self.client.send_outgoing_requests().await?;
+        self.client.claim_one_time_keys(iter::once(user_id)).await?;
+
+        let result = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .encrypt_to_device(user_id, device_ids, event_type, content)
+            .await?;
+
+        for request in result.requests {
+            // The transaction ID is kept between the retries, so the
+            // homeserver doesn't send the event twice.
+            let request =
+                RumaToDeviceRequest::new_raw(request.event_type, request.txn_id, request.messages);
+            self.client.send(request, Some(RequestConfig::short_retry())).await?;
+        }
+
+        Ok(result.failed_devices)
+    }
+
+    /// Get a stream of the to-device events of the given type that were
+    /// received encrypted.
+    ///
+    /// Contrary to the event handlers, only the events that were encrypted are
+    /// in this stream, so it is safe to trust their sender, which makes it
+    /// suitable for custom protocols like call signalling.
+    ///
+    /// If the reader of the stream lags too far behind, events will be
+    /// dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures_util::StreamExt;
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// use matrix_sdk::ruma::events::macros::EventContent;
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// #[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+    /// #[ruma_event(type = "org.example.call.invite", kind = ToDevice)]
+    /// struct CallInviteEventContent {
+    ///     call_id: String,
+    /// }
+    ///
+    /// let mut invites = Box::pin(
+    ///     client
+    ///         .encryption()
+    ///         .encrypted_to_device_events::<CallInviteEventContent>()
+    ///         .await?,
+    /// );
+    ///
+    /// while let Some(invite) = invites.next().await {
+    ///     println!(
+    ///         "{} invited us to the call {}",
+    ///         invite.event.sender, invite.event.content.call_id
+    ///     );
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub async fn encrypted_to_device_events<C>(
+        &self,
+    ) -> Result<impl Stream<Item = ReceivedToDeviceEvent<C>>>
+    where
+        C: StaticEventContent + ToDeviceEventContent + Send + 'static,
+        ToDeviceEvent<C>: DeserializeOwned,
+    {
+        let stream = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .store()
+            .decrypted_to_device_events_stream();
+
+        Ok(stream.filter_map(|decrypted| async move {
+            if decrypted.event_type != C::TYPE {
+                return None;
+            }
+
+            match decrypted.raw_event.deserialize_as::<ToDeviceEvent<C>>() {
+                Ok(event) => Some(ReceivedToDeviceEvent {
+                    event,
+                    sender_device_id: decrypted.sender_device_id,
+                }),
+                Err(e) => {
+                    warn!(
+                        event_type = C::TYPE,
+                        "Failed to deserialize an encrypted to-device event: {e}"
+                    );
+                    None
+                }
+            }
+        }))
+    }
+
+    /// Get a stream of the users whose cross-signing identity was received or
+    /// updated.
+    ///
+    /// An identity can be reported as updated even if its keys stayed the
+    /// same, the keys can be compared with the ones from
+    /// [`Encryption::get_user_identity()`] to detect an actual change.
+    pub async fn user_identity_updates(&self) -> Result<impl Stream<Item = Vec<OwnedUserId>>> {
+        let stream = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .store()
+            .identities_stream();
+
+        Ok(stream.map(|changes| {
+            changes
+                .new
+                .iter()
+                .chain(&changes.changed)
+                .map(|identity| identity.user_id().to_owned())
+                .collect()
+        }))
+    }
+
+    /// Get a stream of the devices that were seen for the first time, grouped
+    /// by user.
+    pub async fn new_devices(
+        &self,
+    ) -> Result<impl Stream<Item = BTreeMap<OwnedUserId, Vec<OwnedDeviceId>>>> {
+        let stream = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .store()
+            .devices_stream();
+
+        Ok(stream.filter_map(|changes| async move {
+            let mut devices = BTreeMap::<_, Vec<_>>::new();
+            for device in changes.new {
+                devices
+                    .entry(device.user_id().to_owned())
+                    .or_default()
+                    .push(device.device_id().to_owned());
+            }
+
+            (!devices.is_empty()).then_some(devices)
+        }))
+    }
+
+    /// Get a stream of the changes of the cross-signing identities we know
+    /// about.
+    ///
+    /// Unlike [`Encryption::user_identity_updates()`], it tells apart new
+    /// identities from changed ones, so apps can warn their users when the
+    /// identity of someone they verified changed.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures_util::{pin_mut, StreamExt};
+    /// # use matrix_sdk::{encryption::identities::IdentityUpdate, Client};
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let updates = client.encryption().identities_stream().await?;
+    /// pin_mut!(updates);
+    ///
+    /// while let Some(update) = updates.next().await {
+    ///     if let IdentityUpdate::Changed { identity, verification_broken: true } =
+    ///         update
+    ///     {
+    ///         println!("The identity of {} changed", identity.user_id());
+    ///     }
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub async fn identities_stream(&self) -> Result<impl Stream<Item = IdentityUpdate>> {
+        let stream = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .store()
+            .identities_stream();
+        let this = self.clone();
+
+        Ok(stream
+            .then(move |changes| {
+                let this = this.clone();
+                async move { this.identity_updates(changes).await }
+            })
+            .flat_map(stream::iter))
+    }
+
+    async fn identity_updates(&self, changes: IdentityChanges) -> Vec<IdentityUpdate> {
+        let new = changes.new.iter().map(|identity| (identity, true));
+        let changed = changes.changed.iter().map(|identity| (identity, false));
+
+        let mut updates = Vec::new();
+        for (identity, is_new) in new.chain(changed) {
+            let user_id = identity.user_id();
+            let identity = match self.get_user_identity(user_id).await {
+                Ok(Some(identity)) => identity,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(%user_id, "Failed to load an updated identity: {e}");
+                    continue;
+                }
+            };
+
+            updates.push(if is_new {
+                IdentityUpdate::New(identity)
+            } else if let Some(&was_verified) = changes.master_key_changed.get(user_id) {
+                IdentityUpdate::Changed { identity, verification_broken: was_verified }
+            } else {
+                IdentityUpdate::Updated(identity)
+            });
+        }
+
+        updates
+    }
+
+    /// Get a stream of the changes of the devices we know about.
+    ///
+    /// Unlike [`Encryption::new_devices()`], it also reports the devices that
+    /// were updated or deleted.
+    pub async fn devices_stream(&self) -> Result<impl Stream<Item = DeviceUpdate>> {
+        let stream = self
+            .client
+            .olm_machine()
+            .await
+            .as_ref()
+            .ok_or(Error::AuthenticationRequired)?
+            .store()
+            .devices_stream();
+        let this = self.clone();
+
+        Ok(stream
+            .then(move |changes| {
+                let this = this.clone();
+                async move { this.device_updates(changes).await }
+            })
+            .flat_map(stream::iter))
+    }
+
+    async fn device_updates(&self, changes: DeviceChanges) -> Vec<DeviceUpdate> {
+        let mut updates = Vec::new();
+        for (device, is_new) in changes
+            .new
+            .iter()
+            .map(|device| (device, true))
+            .chain(changes.changed.iter().map(|device| (device, false)))
+        {
+            let (user_id, device_id) = (device.user_id(), device.device_id());
+            let device = match self.get_device(user_id, device_id).await {
+                Ok(Some(device)) => device,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(%user_id, %device_id, "Failed to load an updated device: {e}");
+                    continue;
+                }
+            };
+
+            updates.push(if is_new {
+                DeviceUpdate::New(device)
+            } else {
+                DeviceUpdate::Updated(device)
+            });
+        }
+
+        updates.extend(changes.deleted.into_iter().map(|device| DeviceUpdate::Deleted {
+            user_id: device.user_id().to_owned(),
+            device_id: device.device_id().to_owned(),
+        }));
+
+        updates
+    }
+
+    /// Set the sink that is notified of every successful decryption of a room
+    /// event, or remove it with `None`.
+    ///
+    /// This is meant for deployments that must audit the decryption activity
+    /// of their clients. The sink only receives metadata about the events, like
+    /// the room, the sender device and the Megolm session, never their
+    /// plaintext. It can be set before logging in.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::sync::Arc;
+    /// # use matrix_sdk::{Client, encryption::DecryptionAuditRecord};
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let (sender, receiver) = std::sync::mpsc::channel();
+    /// let sender = std::sync::Mutex::new(sender);
+    ///
+    /// client
+    ///     .encryption()
+    ///     .set_decryption_audit_sink(Some(Arc::new(
+    ///         move |record: DecryptionAuditRecord| {
+    ///             let _ = sender.lock().unwrap().send(record);
+    ///         },
+    ///     )))
+    ///     .await;
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[cfg(feature = "decryption-audit")]
+    pub async fn set_decryption_audit_sink(
+        &self,
+        sink: Option<std::sync::Arc<dyn DecryptionAuditSink>>,
+    ) {
+        self.client.base_client().set_decryption_audit_sink(sink).await;
+    }
+
+    /// Set the hook that is notified of the room events that couldn't be
+    /// decrypted (UTDs), or remove it with `None`.
+    ///
+    /// The hook receives the cause of every UTD, and the time it took to
+    /// decrypt the events that could be decrypted later, e.g. when the room
+    /// key arrived. This is meant to measure the reliability of the
+    /// encryption. It can be set before logging in.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::sync::Arc;
+    /// # use matrix_sdk::{Client, encryption::{UtdReport, UtdTelemetryHook}};
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// struct Telemetry;
+    ///
+    /// impl UtdTelemetryHook for Telemetry {
+    ///     fn on_utd(&self, report: UtdReport) {
+    ///         println!("Unable to decrypt {}: {:?}", report.event_id, report.cause);
+    ///     }
+    /// }
+    ///
+    /// client.encryption().set_utd_telemetry_hook(Some(Arc::new(Telemetry))).await;
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub async fn set_utd_telemetry_hook(&self, hook: Option<std::sync::Arc<dyn UtdTelemetryHook>>) {
+        self.client.base_client().set_utd_telemetry_hook(hook).await;
+    }
+
+    /// Get the statistics about the decryption of room events since the client
+    /// logged in or was restored, per sender.
+    ///
+    /// Returns empty statistics if the client isn't logged in.
+    pub async fn statistics(&self) -> DecryptionStatistics {
+        self.client
+            .olm_machine()
+            .await
+            .as_ref()
+            .map(|olm| olm.decryption_statistics())
+            .unwrap_or_default()
+    }
+
+    /// Get a E2EE identity of an user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the identity belongs to.
+    ///
+    /// Returns a `UserIdentity` if one is found and the crypto store
+    /// didn't throw an error.
+    ///
+    /// This will always return None if the client hasn't been logged in.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::user_id};
+    /// # use url::Url;
+    /// # async {
+    /// # let alice = user_id!("@alice:example.org");
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let user = client.encryption().get_user_identity(alice).await?;
+    ///
+    /// if let Some(user) = user {
+    ///     println!("{:?}", user.is_verified());
+    ///
+    ///     let verification = user.request_verification().await?;
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub async fn get_user_identity(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<crate::encryption::identities::UserIdentity>, CryptoStoreError> {
+        use crate::encryption::identities::UserIdentity;
+
+        let olm = self.client.olm_machine().await;
+        let Some(olm) = olm.as_ref() else { return Ok(None) };
+        let identity = olm.get_identity(user_id, None).await?;
+
+        Ok(identity.map(|i| match i {
+            matrix_sdk_base::crypto::UserIdentities::Own(i) => {
+                UserIdentity::new_own(self.client.clone(), i)
+            }
+            matrix_sdk_base::crypto::UserIdentities::Other(i) => {
+                UserIdentity::new(self.client.clone(), i, self.client.get_dm_room(user_id))
+            }
+        }))
+    }
+
+    /// Create and upload a new cross signing identity.
+    ///
+    /// # Arguments
+    ///
+    /// * `auth_data` - This request requires user interactive auth, the first
+    /// request needs to set this to `None` and will always fail with an
+    /// `UiaaResponse`. The response will contain information for the
+    /// interactive auth and the same request needs to be made but this time
+    /// with some `auth_data` provided.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::collections::BTreeMap;
+    /// # use matrix_sdk::{ruma::api::client::uiaa, Client};
+    /// # use url::Url;
+    /// # use serde_json::json;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// if let Err(e) = client.encryption().bootstrap_cross_signing(None).await {
+    ///     if let Some(response) = e.as_uiaa_response() {
+    ///         let mut password = uiaa::Password::new(
+    ///             uiaa::UserIdentifier::UserIdOrLocalpart("example".to_owned()),
+    ///             "wordpass".to_owned(),
+    ///         );
+    ///         password.session = response.session.clone();
+    ///
+    ///         client
+    ///             .encryption()
+    ///             .bootstrap_cross_signing(Some(uiaa::AuthData::Password(password)))
+    ///             .await
+    ///             .expect("Couldn't bootstrap cross signing")
+    ///     } else {
+    ///         panic!("Error durign cross signing bootstrap {:#?}", e);
+    ///     }
+    /// }
+    /// # anyhow::Ok(()) };
+    pub async fn bootstrap_cross_signing(&self, auth_data: Option<AuthData>) -> Result<()> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::AuthenticationRequired)?;
+
+        let (request, signature_request) = olm.bootstrap_cross_signing(false).await?;
+
+        let request = assign!(UploadSigningKeysRequest::new(), {
+            auth: auth_data,
+            master_key: request.master_key.map(|c| c.to_raw()),
+            self_signing_key: request.self_signing_key.map(|c| c.to_raw()),
+            user_signing_key: request.user_signing_key.map(|c| c.to_raw()),
+        });
+
+        self.client.send(request, None).await?;
+        self.client.send(signature_request, None).await?;
+
+        Ok(())
+    }
+
+    /// Export E2EE keys that match the given predicate encrypting them with the
+    /// given passphrase.
+    ///
+    /// The room keys are loaded from the store and written to the file in
+    /// batches, so they are never all in memory at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file path where the exported key file will be saved.
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    ///   exported
+    /// room keys.
+    ///
+    /// * `predicate` - A closure that will be called for every known
+    /// `InboundGroupSession`, which represents a room key. If the closure
+    /// returns `true` the `InboundGroupSessoin` will be included in the export,
+    /// if the closure returns `false` it will not be included.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it isn't run on a Tokio runtime.
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// encrypt the exported keys securely.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::{path::PathBuf, time::Duration};
+    /// # use matrix_sdk::{
+    /// #     Client, config::SyncSettings,
+    /// #     ruma::room_id,
+    /// # };
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver).await?;
+    /// let path = PathBuf::from("/home/example/e2e-keys.txt");
+    /// // Export all room keys.
+    /// client
+    ///     .encryption()
+    ///     .export_room_keys(path, "secret-passphrase", |_| true)
+    ///     .await?;
+    ///
+    /// // Export only the room keys for a certain room.
+    /// let path = PathBuf::from("/home/example/e2e-room-keys.txt");
+    /// let room_id = room_id!("!test:localhost");
+    ///
+    /// client
+    ///     .encryption()
+    ///     .export_room_keys(path, "secret-passphrase", |s| s.room_id() == room_id)
+    ///     .await?;
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn export_room_keys(
+        &self,
+        path: PathBuf,
+        passphrase: &str,
+        predicate: impl FnMut(&matrix_sdk_base::crypto::olm::InboundGroupSession) -> bool,
+    ) -> Result<()> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::AuthenticationRequired)?;
+
+        let file = tokio::fs::File::create(path).await?.into_std().await;
+        let writer = AllowStdIo::new(std::io::BufWriter::new(file));
+        let mut writer = RoomKeyExportWriter::new(writer, passphrase, 500_000).await?;
+
+        let keys = olm.export_room_keys_stream(predicate);
+        pin_mut!(keys);
+
+        while let Some(key) = keys.try_next().await? {
+            writer.write_key(&key).await?;
+        }
+
+        writer.finish().await?;
+
+        Ok(())
+    }
+
+    /// Import E2EE keys from the given file path.
+    ///
+    /// The file is decrypted and its room keys are imported in batches, so they
+    /// are never all in memory at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file path where the exported key file will can be found.
+    ///
+    /// * `passphrase` - The passphrase that should be used to decrypt the
+    /// exported room keys.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were found in the
+    /// key export.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it isn't run on a Tokio runtime.
     ///
     /// ```no_run
     /// # use std::{path::PathBuf, time::Duration};
@@ -820,30 +1873,130 @@ impl Encryption {
     ) -> Result<RoomKeyImportResult, RoomKeyImportError> {
         let olm = self.client.olm_machine().await;
         let olm = olm.as_ref().ok_or(RoomKeyImportError::StoreClosed)?;
-        let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
 
-        let decrypt = move || {
-            let file = std::fs::File::open(path)?;
-            matrix_sdk_base::crypto::decrypt_room_key_export(file, &passphrase)
-        };
+        let file = tokio::fs::File::open(path).await?.into_std().await;
+        let reader = AllowStdIo::new(std::io::BufReader::new(file));
+        let mut reader = RoomKeyExportReader::new(reader, passphrase).await?;
 
-        let task = tokio::task::spawn_blocking(decrypt);
-        let import = task.await.expect("Task join error")?;
+        let mut result =
+            RoomKeyImportResult { imported_count: 0, total_count: 0, keys: BTreeMap::new() };
+        let mut batch = Vec::new();
+
+        while let Some(key) = reader.next_key().await? {
+            batch.push(key);
 
-        Ok(olm.import_room_keys(import, false, |_, _| {}).await?)
+            if batch.len() == IMPORT_BATCH_SIZE {
+                import_room_key_batch(olm, std::mem::take(&mut batch), &mut result).await?;
+            }
+        }
+
+        if !batch.is_empty() {
+            import_room_key_batch(olm, batch, &mut result).await?;
+        }
+
+        Ok(result)
+    }
+}
+
+/// The number of room keys of a key export that are imported at once.
+#[cfg(not(target_arch = "wasm32"))]
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Import a batch of the room keys of a key export, and add its result to the
+/// result of the whole import.
+#[cfg(not(target_arch = "wasm32"))]
+async fn import_room_key_batch(
+    olm: &OlmMachine,
+    batch: Vec<ExportedRoomKey>,
+    result: &mut RoomKeyImportResult,
+) -> Result<(), RoomKeyImportError> {
+    let imported = olm.import_room_keys(batch, false, |_, _| {}).await?;
+
+    result.imported_count += imported.imported_count;
+    result.total_count += imported.total_count;
+
+    for (room_id, sessions) in imported.keys {
+        let room_keys = result.keys.entry(room_id).or_default();
+        for (sender_key, session_ids) in sessions {
+            room_keys.entry(sender_key).or_default().extend(session_ids);
+        }
     }
+
+    Ok(())
+}
+
+/// What to do with the devices that don't satisfy the [`TrustRequirement`]
+/// when sending a message, returned by the handler set with
+/// [`Encryption::set_untrusted_devices_handler()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UntrustedDevicesDecision {
+    /// Don't send the message, it fails with [`OlmError::UntrustedDevices`].
+    Abort,
+    /// Ignore the trust state of the devices, by setting their local trust
+    /// state to [`LocalTrust::Ignored`], so they receive the room key.
+    Ignore,
+    /// Blacklist the devices, so they don't receive the room key.
+    Blacklist,
+}
+
+/// The content of an encrypted session export, see
+/// [`Client::export_session()`].
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Serialize, Deserialize)]
+struct SessionExport {
+    homeserver: String,
+    session: Session,
+    crypto: CryptoStateExport,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+type UntrustedDevicesHandlerFut = Pin<Box<dyn Future<Output = UntrustedDevicesDecision> + Send>>;
+#[cfg(target_arch = "wasm32")]
+type UntrustedDevicesHandlerFut = Pin<Box<dyn Future<Output = UntrustedDevicesDecision>>>;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type UntrustedDevicesHandlerFn = Arc<
+    dyn Fn(
+            OwnedRoomId,
+            BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>,
+        ) -> UntrustedDevicesHandlerFut
+        + Send
+        + Sync,
+>;
+#[cfg(target_arch = "wasm32")]
+pub(crate) type UntrustedDevicesHandlerFn = Arc<
+    dyn Fn(
+        OwnedRoomId,
+        BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>,
+    ) -> UntrustedDevicesHandlerFut,
+>;
+
+/// The key of the cross-process lock of the crypto store.
+const CROSS_PROCESS_STORE_LOCK_KEY: &str = "cross_process_lock";
+
+/// A to-device event that was received encrypted, see
+/// [`Encryption::encrypted_to_device_events()`].
+#[derive(Clone, Debug)]
+pub struct ReceivedToDeviceEvent<C: ToDeviceEventContent> {
+    /// The decrypted event.
+    pub event: ToDeviceEvent<C>,
+    /// The ID of the device that sent the event, if the device is known.
+    pub sender_device_id: Option<OwnedDeviceId>,
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
+    use std::{iter, time::Duration};
+
+    use assert_matches::assert_matches;
     use matrix_sdk_test::{
-        async_test, test_json, EventBuilder, GlobalAccountDataTestEvent, JoinedRoomBuilder,
-        StateTestEvent,
+        async_test, test_json, EventBuilder, GlobalAccountDataTestEvent, InvitedRoomBuilder,
+        JoinedRoomBuilder, StateTestEvent, StrippedStateTestEvent,
     };
     use ruma::{
         event_id,
         events::{reaction::ReactionEventContent, relation::Annotation},
-        user_id,
+        room_id, user_id,
     };
     use serde_json::json;
     use wiremock::{
@@ -851,7 +2004,46 @@ mod tests {
         Mock, MockServer, ResponseTemplate,
     };
 
-    use crate::test_utils::logged_in_client;
+    use super::{
+        EncryptionSettings, KeyExportError, RoomKeyBundleEvent, RoomKeyImportError,
+        CROSS_PROCESS_STORE_LOCK_KEY,
+    };
+    use crate::{test_utils::logged_in_client, Error};
+
+    #[async_test]
+    async fn test_sync_takes_the_cross_process_store_lock() {
+        let client = logged_in_client(None).await;
+        client.encryption().enable_cross_process_store_lock("main".to_owned()).await.unwrap();
+
+        // Another process holds the lock.
+        let mut other_lock = client
+            .olm_machine()
+            .await
+            .as_ref()
+            .unwrap()
+            .store()
+            .create_store_lock(CROSS_PROCESS_STORE_LOCK_KEY.to_owned(), "other".to_owned());
+        other_lock.lock().await.unwrap();
+
+        let response = EventBuilder::default()
+            .add_joined_room(JoinedRoomBuilder::default())
+            .build_sync_response();
+        let sync = tokio::spawn({
+            let client = client.clone();
+            async move { client.process_sync(response).await }
+        });
+
+        // The sync waits for the other process to release the lock.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sync.is_finished());
+
+        other_lock.unlock().await.unwrap();
+        sync.await.unwrap().unwrap();
+        assert!(client.get_joined_room(&test_json::DEFAULT_SYNC_ROOM_ID).is_some());
+
+        // The sync released the lock.
+        other_lock.lock().await.unwrap();
+    }
 
     #[async_test]
     async fn test_reaction_sending() {
@@ -971,4 +2163,112 @@ mod tests {
         let found_room = client.get_dm_room(user_id).expect("DM not found!");
         assert!(found_room.get_member_no_sync(user_id).await.unwrap().is_some());
     }
+
+    fn room_key_bundle_event(sender: &str, room_id: &str) -> RoomKeyBundleEvent {
+        serde_json::from_value(json!({
+            "sender": sender,
+            "content": {
+                "room_id": room_id,
+                "file": {
+                    "url": "mxc://example.org/FHyPlCeYUSFFxlgbQYZmoEoe",
+                    "key": {
+                        "kty": "oct",
+                        "key_ops": ["encrypt", "decrypt"],
+                        "alg": "A256CTR",
+                        "k": "aWF6-32KGYaC3A_FEUCk1Bt0JA37zP0wrStgmdCaW-0",
+                        "ext": true
+                    },
+                    "iv": "w+sE15fzSc0AAAAAAAAAAA",
+                    "hashes": {
+                        "sha256": "fdSLu/YkRx3Wyh3KQabP3rd6+SFiKg5lsJZQHtkSAYA"
+                    },
+                    "v": "v2"
+                },
+            },
+            "type": "io.element.msc4268.room_key_bundle",
+        }))
+        .unwrap()
+    }
+
+    #[async_test]
+    async fn test_room_key_bundle_only_accepted_from_inviter() {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        let room_id = room_id!("!invited:example.org");
+
+        // The bundle must never be downloaded.
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/media/.*/download/"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        // The bundle of a room we aren't invited to is rejected.
+        let event = room_key_bundle_event("@alice:example.org", room_id.as_str());
+        assert_matches!(
+            client.encryption().import_room_key_bundle(&event).await,
+            Err(Error::RoomKeyBundleNotFromInviter)
+        );
+
+        let response = EventBuilder::default()
+            .add_invited_room(InvitedRoomBuilder::new(room_id).add_state_event(
+                StrippedStateTestEvent::Custom(json!({
+                    "content": {
+                        "membership": "invite",
+                    },
+                    "event_id": "$invite:example.org",
+                    "origin_server_ts": 1432735824653u64,
+                    "sender": "@alice:example.org",
+                    "state_key": "@example:localhost",
+                    "type": "m.room.member",
+                })),
+            ))
+            .build_sync_response();
+        client.process_sync(response).await.unwrap();
+
+        // The bundle of another member than the inviter is rejected.
+        let event = room_key_bundle_event("@mallory:example.org", room_id.as_str());
+        assert_matches!(
+            client.encryption().import_room_key_bundle(&event).await,
+            Err(Error::RoomKeyBundleNotFromInviter)
+        );
+    }
+
+    #[async_test]
+    async fn test_export_and_import_room_keys() {
+        let client = logged_in_client(None).await;
+        let room_id = room_id!("!a:localhost");
+
+        {
+            let olm = client.olm_machine().await;
+            let olm = olm.as_ref().unwrap();
+            for room_id in [room_id, room_id!("!b:localhost")] {
+                olm.share_room_key(room_id, iter::empty(), EncryptionSettings::default())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("e2e-keys.txt");
+        client
+            .encryption()
+            .export_room_keys(path.clone(), "1234", |s| s.room_id() == room_id)
+            .await
+            .unwrap();
+
+        let other = logged_in_client(None).await;
+        assert_matches!(
+            other.encryption().import_room_keys(path.clone(), "wrong").await,
+            Err(RoomKeyImportError::Export(KeyExportError::InvalidMac))
+        );
+
+        let result = other.encryption().import_room_keys(path, "1234").await.unwrap();
+        assert_eq!(result.imported_count, 1);
+        assert_eq!(result.total_count, 1);
+        assert_eq!(result.keys.len(), 1);
+        assert!(result.keys.contains_key(room_id));
+    }
 }
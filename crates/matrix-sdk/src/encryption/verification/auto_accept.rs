@@ -0,0 +1,357 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Non-interactive acceptance of verification requests.
+//!
+//! Bots and test clients can't compare emojis with a human, so they can use an
+//! [`AutoAcceptPolicy`] to accept the verification requests of a fixed set of
+//! users or devices, and to complete the SAS verification that follows without
+//! comparing the short authentication strings.
+//!
+//! Every decision is logged at the `info` level, with the user, the device and
+//! the flow ID of the verification, so the verifications done by the client
+//! can be audited.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use futures_util::StreamExt;
+use matrix_sdk_common::executor::spawn;
+use ruma::{
+    events::{
+        key::verification::{request::ToDeviceKeyVerificationRequestEvent, VerificationMethod},
+        room::message::{MessageType, OriginalSyncRoomMessageEvent},
+    },
+    DeviceId, OwnedDeviceId, OwnedUserId, UserId,
+};
+use tracing::{info, warn};
+
+use super::{
+    SasState, SasVerification, Verification, VerificationRequest, VerificationRequestState,
+};
+use crate::{event_handler::EventHandlerHandle, Client};
+
+/// The users and devices whose verification requests are accepted
+/// automatically, see [`Encryption::auto_accept_verifications()`].
+///
+/// The SAS verifications are completed without comparing the short
+/// authentication strings. This is a form of trust on first use: an attacker
+/// able to intercept the verification would be trusted as well, so only the
+/// users known to be legitimate should be allowed, e.g. the operators of a
+/// bot.
+///
+/// The devices of our own user are never accepted because our own user is
+/// allowed: once verified, they receive our secrets, like the private
+/// cross-signing keys. They must be allowed one by one with
+/// [`AutoAcceptPolicy::allow_device()`].
+///
+/// [`Encryption::auto_accept_verifications()`]: crate::encryption::Encryption::auto_accept_verifications
+#[derive(Clone, Debug, Default)]
+pub struct AutoAcceptPolicy {
+    allowed_users: BTreeSet<OwnedUserId>,
+    allowed_devices: BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>,
+}
+
+impl AutoAcceptPolicy {
+    /// Create a new `AutoAcceptPolicy` accepting the verification requests of
+    /// all the devices of the given users.
+    pub fn new(allowed_users: impl IntoIterator<Item = OwnedUserId>) -> Self {
+        Self { allowed_users: allowed_users.into_iter().collect(), ..Default::default() }
+    }
+
+    /// Accept the verification requests of all the devices of the given user
+    /// as well.
+    ///
+    /// This has no effect for our own user, see
+    /// [`AutoAcceptPolicy::allow_device()`].
+    pub fn allow_user(mut self, user_id: OwnedUserId) -> Self {
+        self.allowed_users.insert(user_id);
+        self
+    }
+
+    /// Accept the verification requests of the given device as well.
+    ///
+    /// This is the only way to accept the verification requests of our own
+    /// devices.
+    pub fn allow_device(mut self, user_id: OwnedUserId, device_id: OwnedDeviceId) -> Self {
+        self.allowed_devices.entry(user_id).or_default().insert(device_id);
+        self
+    }
+
+    /// Whether the verification requests of the given device are accepted.
+    ///
+    /// `own_user_id` is the ID of the user of the client, whose devices are
+    /// only accepted if they were allowed one by one.
+    pub fn accepts(&self, own_user_id: &UserId, user_id: &UserId, device_id: &DeviceId) -> bool {
+        let device_allowed =
+            self.allowed_devices.get(user_id).is_some_and(|devices| devices.contains(device_id));
+        device_allowed || (user_id != own_user_id && self.allowed_users.contains(user_id))
+    }
+}
+
+/// A handle to an [`AutoAcceptPolicy`] that has been registered on a client.
+#[derive(Debug)]
+pub struct AutoAcceptHandle {
+    client: Client,
+    handles: Vec<EventHandlerHandle>,
+}
+
+impl AutoAcceptHandle {
+    /// Stop accepting the verification requests automatically.
+    ///
+    /// The verifications that were already accepted are still completed.
+    pub fn stop(self) {
+        for handle in self.handles {
+            self.client.remove_event_handler(handle);
+        }
+    }
+}
+
+/// Register the event handlers accepting the verification requests allowed
+/// by the policy.
+pub(crate) fn register(client: &Client, policy: AutoAcceptPolicy) -> AutoAcceptHandle {
+    let to_device_policy = policy.clone();
+    let to_device_handle = client.add_event_handler(
+        move |event: ToDeviceKeyVerificationRequestEvent, client: Client| {
+            let policy = to_device_policy.clone();
+            async move {
+                let flow_id = event.content.transaction_id.to_string();
+                let device_id = &event.content.from_device;
+                handle_request(client, policy, &event.sender, device_id, &flow_id).await;
+            }
+        },
+    );
+
+    let in_room_handle =
+        client.add_event_handler(move |event: OriginalSyncRoomMessageEvent, client: Client| {
+            let policy = policy.clone();
+            async move {
+                let MessageType::VerificationRequest(content) = &event.content.msgtype else {
+                    return;
+                };
+                if client.user_id() != Some(&*content.to) {
+                    return;
+                }
+
+                let flow_id = event.event_id.as_str();
+                handle_request(client, policy, &event.sender, &content.from_device, flow_id).await;
+            }
+        });
+
+    AutoAcceptHandle { client: client.clone(), handles: vec![to_device_handle, in_room_handle] }
+}
+
+async fn handle_request(
+    client: Client,
+    policy: AutoAcceptPolicy,
+    sender: &UserId,
+    device_id: &DeviceId,
+    flow_id: &str,
+) {
+    let Some(own_user_id) = client.user_id() else {
+        return;
+    };
+    if !policy.accepts(own_user_id, sender, device_id) {
+        info!(
+            ?sender,
+            ?device_id,
+            flow_id,
+            "Not accepting a verification request from a device not allowed"
+        );
+        return;
+    }
+
+    let Some(request) = client.encryption().get_verification_request(sender, flow_id).await else {
+        warn!(?sender, flow_id, "Couldn't find the verification request to accept");
+        return;
+    };
+
+    // Our other devices also forward the requests we sent, and the request
+    // might already have been accepted by another device.
+    if request.we_started()
+        || !matches!(request.state(), VerificationRequestState::Requested { .. })
+    {
+        return;
+    }
+
+    spawn(complete_request(request, policy, own_user_id.to_owned()));
+}
+
+async fn complete_request(
+    request: VerificationRequest,
+    policy: AutoAcceptPolicy,
+    own_user_id: OwnedUserId,
+) {
+    let sender = request.other_user_id().to_owned();
+    let flow_id = request.flow_id().to_owned();
+
+    // Subscribe before accepting, so no state is missed.
+    let mut changes = request.changes();
+
+    if let Err(error) = request.accept_with_methods(vec![VerificationMethod::SasV1]).await {
+        warn!(?sender, flow_id, ?error, "Couldn't accept the verification request");
+        return;
+    }
+    info!(?sender, flow_id, "Accepted the verification request");
+
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationRequestState::Transitioned { verification: Verification::SasV1(sas) } => {
+                complete_sas(sas, &policy, &own_user_id, &flow_id).await;
+                break;
+            }
+            #[cfg(feature = "qrcode")]
+            VerificationRequestState::Transitioned { verification: Verification::QrV1(_) } => {
+                warn!(?sender, flow_id, "Cancelling a verification that doesn't use SAS");
+                if let Err(error) = request.cancel().await {
+                    warn!(?sender, flow_id, ?error, "Couldn't cancel the verification");
+                }
+                break;
+            }
+            VerificationRequestState::Done => break,
+            VerificationRequestState::Cancelled(info) => {
+                info!(
+                    ?sender,
+                    flow_id,
+                    reason = info.reason(),
+                    cancelled_by_us = info.cancelled_by_us(),
+                    "The verification request was cancelled"
+                );
+                break;
+            }
+            VerificationRequestState::Created { .. }
+            | VerificationRequestState::Requested { .. }
+            | VerificationRequestState::Ready { .. } => {}
+        }
+    }
+}
+
+async fn complete_sas(
+    sas: SasVerification,
+    policy: &AutoAcceptPolicy,
+    own_user_id: &UserId,
+    flow_id: &str,
+) {
+    let sender = sas.other_user_id().to_owned();
+    let device_id = sas.other_device().device_id().to_owned();
+
+    // In rooms, the device that started the SAS verification isn't
+    // necessarily the one that sent the request.
+    if !policy.accepts(own_user_id, &sender, &device_id) {
+        info!(
+            ?sender,
+            ?device_id,
+            flow_id,
+            "Cancelling a SAS verification with a device not allowed"
+        );
+        if let Err(error) = sas.cancel().await {
+            warn!(?sender, ?device_id, flow_id, ?error, "Couldn't cancel the SAS verification");
+        }
+        return;
+    }
+
+    let mut changes = sas.changes();
+
+    if !sas.we_started() {
+        if let Err(error) = sas.accept().await {
+            warn!(?sender, ?device_id, flow_id, ?error, "Couldn't accept the SAS verification");
+            return;
+        }
+        info!(?sender, ?device_id, flow_id, "Accepted the SAS verification");
+    }
+
+    while let Some(state) = changes.next().await {
+        match state {
+            SasState::KeysExchanged { emojis, decimals } => {
+                let emojis = emojis.map(|e| e.indices);
+                info!(
+                    ?sender,
+                    ?device_id,
+                    flow_id,
+                    ?emojis,
+                    ?decimals,
+                    "Confirming the short authentication string without comparing it"
+                );
+
+                if let Err(error) = sas.confirm().await {
+                    warn!(?sender, ?device_id, flow_id, ?error, "Couldn't confirm the SAS");
+                    break;
+                }
+            }
+            SasState::Done { verified_devices, verified_identities } => {
+                let verified_devices: Vec<_> =
+                    verified_devices.iter().map(|d| d.device_id().to_owned()).collect();
+                let verified_identities: Vec<_> =
+                    verified_identities.iter().map(|i| i.user_id().to_owned()).collect();
+                info!(
+                    ?sender,
+                    ?device_id,
+                    flow_id,
+                    ?verified_devices,
+                    ?verified_identities,
+                    "The verification was completed"
+                );
+                break;
+            }
+            SasState::Cancelled(info) => {
+                info!(
+                    ?sender,
+                    ?device_id,
+                    flow_id,
+                    reason = info.reason(),
+                    cancelled_by_us = info.cancelled_by_us(),
+                    "The SAS verification was cancelled"
+                );
+                break;
+            }
+            SasState::Started { .. } | SasState::Accepted { .. } | SasState::Confirmed => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{device_id, owned_device_id, owned_user_id, user_id};
+
+    use super::AutoAcceptPolicy;
+
+    #[test]
+    fn policy_accepts_allowed_users() {
+        let own_user_id = user_id!("@example:localhost");
+        let device_id = device_id!("DEVICE");
+        let policy = AutoAcceptPolicy::new([owned_user_id!("@alice:localhost")])
+            .allow_user(owned_user_id!("@bob:localhost"));
+
+        assert!(policy.accepts(own_user_id, user_id!("@alice:localhost"), device_id));
+        assert!(policy.accepts(own_user_id, user_id!("@bob:localhost"), device_id));
+        assert!(!policy.accepts(own_user_id, user_id!("@mallory:localhost"), device_id));
+        assert!(!AutoAcceptPolicy::default().accepts(
+            own_user_id,
+            user_id!("@alice:localhost"),
+            device_id
+        ));
+    }
+
+    #[test]
+    fn policy_only_accepts_allowed_own_devices() {
+        let own_user_id = user_id!("@example:localhost");
+        let policy = AutoAcceptPolicy::new([own_user_id.to_owned()])
+            .allow_device(own_user_id.to_owned(), owned_device_id!("PINNED"))
+            .allow_device(owned_user_id!("@alice:localhost"), owned_device_id!("ALICE"));
+
+        assert!(policy.accepts(own_user_id, own_user_id, device_id!("PINNED")));
+        assert!(!policy.accepts(own_user_id, own_user_id, device_id!("OTHER")));
+        assert!(policy.accepts(own_user_id, user_id!("@alice:localhost"), device_id!("ALICE")));
+        assert!(!policy.accepts(own_user_id, user_id!("@alice:localhost"), device_id!("OTHER")));
+    }
+}
@@ -30,11 +30,13 @@
 //! string.
 //! * [`QrVerification`] - Interactive verification using QR codes.
 
+pub(crate) mod auto_accept;
 #[cfg(feature = "qrcode")]
 mod qrcode;
 mod requests;
 mod sas;
 
+pub use auto_accept::{AutoAcceptHandle, AutoAcceptPolicy};
 pub use matrix_sdk_base::crypto::{
     format_emojis, AcceptSettings, AcceptedProtocols, CancelInfo, Emoji, EmojiShortAuthString,
     SasState,
@@ -20,7 +20,7 @@ use std::io::Error as IoError;
 use matrix_sdk_base::crypto::ScanError;
 #[cfg(feature = "e2e-encryption")]
 use matrix_sdk_base::crypto::{
-    CryptoStoreError, DecryptorError, KeyExportError, MegolmError, OlmError,
+    CryptoStoreError, DecryptorError, KeyExportError, MegolmError, OlmError, SignatureError,
 };
 use matrix_sdk_base::{Error as SdkBaseError, StoreError};
 use reqwest::Error as ReqwestError;
@@ -215,6 +215,16 @@ pub enum Error {
     #[error(transparent)]
     DecryptorError(#[from] DecryptorError),
 
+    /// An error occurred while signing or checking a signature.
+    #[cfg(feature = "e2e-encryption")]
+    #[error(transparent)]
+    SignatureError(#[from] SignatureError),
+
+    /// An error occurred while writing a key export.
+    #[cfg(feature = "e2e-encryption")]
+    #[error(transparent)]
+    KeyExport(#[from] KeyExportError),
+
     /// An error occurred in the state store.
     #[error(transparent)]
     StateStore(#[from] StoreError),
@@ -246,6 +256,48 @@ pub enum Error {
     #[error(transparent)]
     SlidingSync(#[from] crate::sliding_sync::Error),
 
+    /// An error occurred while rotating the passphrase of the SQLite stores.
+    #[cfg(feature = "sqlite")]
+    #[error(transparent)]
+    SqliteStore(#[from] matrix_sdk_sqlite::OpenStoreError),
+
+    /// The stores of the client are not SQLite stores encrypted with a
+    /// passphrase.
+    #[cfg(feature = "sqlite")]
+    #[error("the stores of the client are not SQLite stores encrypted with a passphrase")]
+    NoStorePassphrase,
+
+    /// An encrypted state event was decrypted, but it is invalid.
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    #[error(transparent)]
+    EncryptedState(#[from] matrix_sdk_base::encrypted_state::EncryptedStateError),
+
+    /// A room key bundle wasn't sent by the user who invited us to the room,
+    /// or we aren't invited to the room anymore.
+    #[cfg(feature = "e2e-encryption")]
+    #[error("the room key bundle wasn't sent by the user who invited us to the room")]
+    RoomKeyBundleNotFromInviter,
+
+    /// An error occurred while changing the push notification settings.
+    #[error(transparent)]
+    NotificationSettings(#[from] NotificationSettingsError),
+
+    /// A `matrix.to` or `matrix:` URI couldn't be parsed.
+    #[error(transparent)]
+    UriParse(#[from] crate::uri::UriParseError),
+
+    /// A media download was cancelled.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("the media download was cancelled")]
+    DownloadCancelled,
+
+    /// A media download stopped before the whole file was received, because
+    /// the connection was closed early or because the partial file couldn't
+    /// be resumed. The download can be tried again.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("the media download is incomplete")]
+    IncompleteDownload,
+
     /// The client is in inconsistent state. This happens when we set a room to
     /// a specific type, but then cannot get it in this type.
     #[error("The internal client state is inconsistent.")]
@@ -334,6 +386,31 @@ pub enum RoomKeyImportError {
     Export(#[from] KeyExportError),
 }
 
+/// Error for the session importing functionality.
+#[cfg(feature = "e2e-encryption")]
+#[derive(Error, Debug)]
+// This is allowed because session importing isn't enabled under wasm.
+#[allow(dead_code)]
+pub enum SessionImportError {
+    /// The session export couldn't be decrypted, the passphrase is probably
+    /// wrong.
+    #[error(transparent)]
+    Export(#[from] KeyExportError),
+
+    /// The decrypted session export isn't valid.
+    #[error(transparent)]
+    SerdeJson(#[from] JsonError),
+
+    /// The client is already logged in, a session can only be imported into
+    /// a new client.
+    #[error("The client is already logged in, can't import a session")]
+    AlreadyLoggedIn,
+
+    /// An error occurred while restoring the session.
+    #[error(transparent)]
+    Sdk(#[from] Error),
+}
+
 impl From<FromHttpResponseError<ruma::api::client::Error>> for HttpError {
     fn from(err: FromHttpResponseError<ruma::api::client::Error>) -> Self {
         Self::Api(err.map(RumaApiError::ClientApi))
@@ -15,6 +15,7 @@
 use std::{
     borrow::Borrow,
     collections::{btree_map, BTreeMap},
+    sync::Arc,
 };
 
 use ruma::{OwnedRoomId, RoomId};
@@ -30,8 +31,14 @@ pub(super) struct EventHandlerMaps {
 }
 
 impl EventHandlerMaps {
-    pub fn add(&mut self, handle: EventHandlerHandle, handler_fn: Box<EventHandlerFn>) {
-        let wrapper = EventHandlerWrapper { handler_id: handle.handler_id, handler_fn };
+    pub fn add(
+        &mut self,
+        handle: EventHandlerHandle,
+        priority: i32,
+        handler_fn: Box<EventHandlerFn>,
+    ) {
+        let handler_fn = Arc::from(handler_fn);
+        let wrapper = EventHandlerWrapper { handler_id: handle.handler_id, priority, handler_fn };
 
         match Key::new(handle) {
             Key::Kind(key) => {
@@ -54,7 +61,7 @@ impl EventHandlerMaps {
         ev_kind: HandlerKind,
         ev_type: &str,
         room_id: Option<&'a RoomId>,
-    ) -> impl Iterator<Item = (EventHandlerHandle, &'a EventHandlerFn)> + 'a {
+    ) -> impl Iterator<Item = (EventHandlerHandle, i32, Arc<EventHandlerFn>)> + 'a {
         // Use get_key_value instead of just get to be able to access the event_type
         // from the BTreeMap key as &'static str, required for EventHandlerHandle.
         let kind_kv = self.by_kind.get_key_value(&ev_kind).map(|(_, handlers)| (None, handlers));
@@ -91,7 +98,7 @@ impl EventHandlerMaps {
                         handler_id: wrap.handler_id,
                     };
 
-                    (handle, &*wrap.handler_fn)
+                    (handle, wrap.priority, wrap.handler_fn.clone())
                 })
             },
         )
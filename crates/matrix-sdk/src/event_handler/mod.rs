@@ -29,18 +29,32 @@
 //! calling / `.await`ing the event handler if the previous steps succeeded.
 //! It also logs any errors from the above chain of function calls.
 //!
+//! The handlers registered for an event are called by decreasing priority. For
+//! a given priority, the handlers for possibly-redacted events are called
+//! first, then the ones specifically for redacted or unredacted events, then
+//! for timeline events the ones for `AnySyncTimelineEvent`, the handlers of
+//! each of these groups being called concurrently. A handler can return
+//! [`EventPropagation::Stop`] to prevent the handlers with a lower priority
+//! from being called for the same event, the handlers with the same priority
+//! are still called.
+//!
+//! The state events of a room are handled one at a time: all the handlers of
+//! an event are called before the handlers of the next event.
+//!
 //! For more details, see the [`EventHandler`] trait.
 
 #[cfg(any(feature = "anyhow", feature = "eyre"))]
 use std::any::TypeId;
 use std::{
     borrow::Cow,
+    cmp::Reverse,
     fmt,
     future::Future,
+    iter,
     pin::Pin,
     sync::{
         atomic::{AtomicU64, Ordering::SeqCst},
-        RwLock,
+        Arc, RwLock,
     },
 };
 
@@ -65,9 +79,9 @@ mod static_events;
 pub use self::context::{Ctx, EventHandlerContext, RawEvent};
 
 #[cfg(not(target_arch = "wasm32"))]
-type EventHandlerFut = Pin<Box<dyn Future<Output = ()> + Send>>;
+type EventHandlerFut = Pin<Box<dyn Future<Output = EventPropagation> + Send>>;
 #[cfg(target_arch = "wasm32")]
-type EventHandlerFut = Pin<Box<dyn Future<Output = ()>>>;
+type EventHandlerFut = Pin<Box<dyn Future<Output = EventPropagation>>>;
 
 #[cfg(not(target_arch = "wasm32"))]
 type EventHandlerFn = dyn Fn(EventHandlerData<'_>) -> EventHandlerFut + Send + Sync;
@@ -84,8 +98,13 @@ pub(crate) struct EventHandlerStore {
 }
 
 impl EventHandlerStore {
-    pub fn add_handler(&self, handle: EventHandlerHandle, handler_fn: Box<EventHandlerFn>) {
-        self.handlers.write().unwrap().add(handle, handler_fn);
+    pub fn add_handler(
+        &self,
+        handle: EventHandlerHandle,
+        priority: i32,
+        handler_fn: Box<EventHandlerFn>,
+    ) {
+        self.handlers.write().unwrap().add(handle, priority, handler_fn);
     }
 
     pub fn add_context<T>(&self, ctx: T)
@@ -150,8 +169,9 @@ pub trait SyncEvent {
 }
 
 pub(crate) struct EventHandlerWrapper {
-    handler_fn: Box<EventHandlerFn>,
+    handler_fn: Arc<EventHandlerFn>,
     pub handler_id: u64,
+    pub priority: i32,
 }
 
 /// Handle to remove a registered event handler by passing it to
@@ -172,10 +192,11 @@ pub struct EventHandlerHandle {
 /// * They must have at least one argument, which is the event itself, a type
 ///   that implements [`SyncEvent`]. Any additional arguments need to implement
 ///   the [`EventHandlerContext`] trait.
-/// * Their return type has to be one of: `()`, `Result<(), impl Display + Debug
-///   + 'static>` (if you are using `anyhow::Result` or `eyre::Result` you can
-///   additionally enable the `anyhow` / `eyre` feature to get the verbose
-///   `Debug` output printed on error)
+/// * Their return type has to be one of: `()`, [`EventPropagation`],
+///   `Result<(), impl Display + Debug + 'static>` or `Result<EventPropagation,
+///   impl Display + Debug + 'static>` (if you are using `anyhow::Result` or
+///   `eyre::Result` you can additionally enable the `anyhow` / `eyre` feature
+///   to get the verbose `Debug` output printed on error)
 ///
 /// ### How it works
 ///
@@ -238,19 +259,50 @@ pub struct EventHandlerData<'a> {
     handle: EventHandlerHandle,
 }
 
+/// Whether the handlers with a lower priority should be called for an event.
+///
+/// An event handler can return this type, or a `Result` wrapping it, to stop
+/// the propagation of the event. The handlers with the same priority as the
+/// one stopping the propagation are still called.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventPropagation {
+    /// Call the handlers with a lower priority.
+    #[default]
+    Continue,
+    /// Don't call the handlers with a lower priority.
+    Stop,
+}
+
 /// Return types supported for event handlers implement this trait.
 ///
 /// It is not meant to be implemented outside of matrix-sdk.
 pub trait EventHandlerResult: Sized {
     #[doc(hidden)]
     fn print_error(&self, event_type: Option<&str>);
+
+    #[doc(hidden)]
+    fn propagation(&self) -> EventPropagation {
+        EventPropagation::Continue
+    }
 }
 
 impl EventHandlerResult for () {
     fn print_error(&self, _event_type: Option<&str>) {}
 }
 
-impl<E: fmt::Debug + fmt::Display + 'static> EventHandlerResult for Result<(), E> {
+impl EventHandlerResult for EventPropagation {
+    fn print_error(&self, _event_type: Option<&str>) {}
+
+    fn propagation(&self) -> EventPropagation {
+        *self
+    }
+}
+
+impl<T, E> EventHandlerResult for Result<T, E>
+where
+    T: EventHandlerResult,
+    E: fmt::Debug + fmt::Display + 'static,
+{
     fn print_error(&self, event_type: Option<&str>) {
         let msg_fragment = match event_type {
             Some(event_type) => format!(" for `{event_type}`"),
@@ -269,7 +321,14 @@ impl<E: fmt::Debug + fmt::Display + 'static> EventHandlerResult for Result<(), E
             Err(e) => {
                 error!("Event handler{msg_fragment} failed: {e}");
             }
-            Ok(_) => {}
+            Ok(value) => value.print_error(event_type),
+        }
+    }
+
+    fn propagation(&self) -> EventPropagation {
+        match self {
+            Ok(value) => value.propagation(),
+            Err(_) => EventPropagation::Continue,
         }
     }
 }
@@ -285,6 +344,7 @@ impl Client {
         &self,
         handler: H,
         room_id: Option<OwnedRoomId>,
+        priority: i32,
     ) -> EventHandlerHandle
     where
         Ev: SyncEvent + DeserializeOwned + Send + 'static,
@@ -297,13 +357,16 @@ impl Client {
             Box::pin(async move {
                 match maybe_fut {
                     Ok(Some(fut)) => {
-                        fut.await.print_error(Ev::TYPE);
+                        let result = fut.await;
+                        result.print_error(Ev::TYPE);
+                        result.propagation()
                     }
                     Ok(None) => {
                         error!(
                             event_type = Ev::TYPE, event_kind = ?Ev::KIND,
                             "Event handler has an invalid context argument",
                         );
+                        EventPropagation::Continue
                     }
                     Err(e) => {
                         warn!(
@@ -311,6 +374,7 @@ impl Client {
                             "Failed to deserialize event, skipping event handler.\n
                              Deserialization error: {e}",
                         );
+                        EventPropagation::Continue
                     }
                 }
             })
@@ -320,7 +384,7 @@ impl Client {
         let handle =
             EventHandlerHandle { ev_kind: Ev::KIND, ev_type: Ev::TYPE, room_id, handler_id };
 
-        self.inner.event_handlers.add_handler(handle.clone(), handler_fn);
+        self.inner.event_handlers.add_handler(handle.clone(), priority, handler_fn);
 
         handle
     }
@@ -339,7 +403,7 @@ impl Client {
 
         for raw_event in events {
             let event_type = raw_event.deserialize_as::<ExtractType<'_>>()?.event_type;
-            self.call_event_handlers(room, raw_event.json(), kind, &event_type, None, &[]).await;
+            self.call_event_handlers(room, raw_event.json(), &[kind], &event_type, None, &[]).await;
         }
 
         Ok(())
@@ -357,17 +421,23 @@ impl Client {
             unsigned: Option<UnsignedDetails>,
         }
 
-        // Event handlers for possibly-redacted state events
-        self.handle_sync_events(HandlerKind::State, room, state_events).await?;
-
-        // Event handlers specifically for redacted OR unredacted state events
         for raw_event in state_events {
             let StateEventDetails { event_type, unsigned } = raw_event.deserialize_as()?;
             let redacted = unsigned.and_then(|u| u.redacted_because).is_some();
-            let handler_kind = HandlerKind::state_redacted(redacted);
 
-            self.call_event_handlers(room, raw_event.json(), handler_kind, &event_type, None, &[])
-                .await;
+            // Event handlers for possibly-redacted state events, and specifically
+            // for redacted OR unredacted state events
+            let handler_kinds = [HandlerKind::State, HandlerKind::state_redacted(redacted)];
+
+            self.call_event_handlers(
+                room,
+                raw_event.json(),
+                &handler_kinds,
+                &event_type,
+                None,
+                &[],
+            )
+            .await;
         }
 
         Ok(())
@@ -400,34 +470,15 @@ impl Client {
             let encryption_info = item.encryption_info.as_ref();
             let push_actions = &item.push_actions;
 
-            // Event handlers for possibly-redacted timeline events
-            self.call_event_handlers(
-                room,
-                raw_event,
-                handler_kind_g,
-                &event_type,
-                encryption_info,
-                push_actions,
-            )
-            .await;
-
-            // Event handlers specifically for redacted OR unredacted timeline events
-            self.call_event_handlers(
-                room,
-                raw_event,
-                handler_kind_r,
-                &event_type,
-                encryption_info,
-                push_actions,
-            )
-            .await;
+            // Event handlers for possibly-redacted timeline events, specifically
+            // for redacted OR unredacted timeline events, and for
+            // `AnySyncTimelineEvent`
+            let handler_kinds = [handler_kind_g, handler_kind_r, HandlerKind::Timeline];
 
-            // Event handlers for `AnySyncTimelineEvent`
-            let kind = HandlerKind::Timeline;
             self.call_event_handlers(
                 room,
                 raw_event,
-                kind,
+                &handler_kinds,
                 &event_type,
                 encryption_info,
                 push_actions,
@@ -438,12 +489,12 @@ impl Client {
         Ok(())
     }
 
-    #[instrument(skip_all, fields(?event_kind, ?event_type, room_id))]
+    #[instrument(skip_all, fields(?event_kinds, ?event_type, room_id))]
     async fn call_event_handlers(
         &self,
         room: Option<&room::Room>,
         raw: &RawJsonValue,
-        event_kind: HandlerKind,
+        event_kinds: &[HandlerKind],
         event_type: &str,
         encryption_info: Option<&EncryptionInfo>,
         push_actions: &[Action],
@@ -453,34 +504,71 @@ impl Client {
             tracing::Span::current().record("room_id", debug(room_id));
         }
 
-        // Construct event handler futures
-        let mut futures: FuturesUnordered<_> = self
-            .inner
-            .event_handlers
-            .handlers
-            .read()
-            .unwrap()
-            .get_handlers(event_kind, event_type, room_id)
-            .map(|(handle, handler_fn)| {
-                let data = EventHandlerData {
-                    client: self.clone(),
-                    room: room.cloned(),
-                    raw,
-                    encryption_info,
-                    push_actions,
-                    handle,
-                };
-
-                (handler_fn)(data)
-            })
-            .collect();
+        let mut handlers: Vec<_> = {
+            let maps = self.inner.event_handlers.handlers.read().unwrap();
+            event_kinds
+                .iter()
+                .enumerate()
+                .flat_map(|(kind_index, &event_kind)| {
+                    maps.get_handlers(event_kind, event_type, room_id).map(
+                        move |(handle, priority, handler_fn)| {
+                            (handle, priority, kind_index, handler_fn)
+                        },
+                    )
+                })
+                .collect()
+        };
+
+        if handlers.is_empty() {
+            return;
+        }
 
-        if !futures.is_empty() {
-            debug!(amount = futures.len(), "Calling event handlers");
+        debug!(amount = handlers.len(), "Calling event handlers");
+
+        // The sort is stable, so handlers with the same priority and kind keep
+        // their order.
+        handlers.sort_by_key(|(_, priority, kind_index, _)| (Reverse(*priority), *kind_index));
+        let mut handlers = handlers.into_iter().peekable();
+
+        // Run the event handler futures with the `self.event_handlers.handlers`
+        // lock no longer being held, one priority and kind at a time.
+        let mut propagation = EventPropagation::Continue;
+        while let Some((handle, priority, kind_index, handler_fn)) = handlers.next() {
+            let same_group = iter::from_fn(|| {
+                handlers.next_if(|(_, other_priority, other_kind_index, _)| {
+                    *other_priority == priority && *other_kind_index == kind_index
+                })
+            });
+
+            let mut futures: FuturesUnordered<_> =
+                iter::once((handle, priority, kind_index, handler_fn))
+                    .chain(same_group)
+                    .map(|(handle, _, _, handler_fn)| {
+                        let data = EventHandlerData {
+                            client: self.clone(),
+                            room: room.cloned(),
+                            raw,
+                            encryption_info,
+                            push_actions,
+                            handle,
+                        };
+
+                        (handler_fn)(data)
+                    })
+                    .collect();
+
+            while let Some(result) = futures.next().await {
+                if result == EventPropagation::Stop {
+                    propagation = EventPropagation::Stop;
+                }
+            }
 
-            // Run the event handler futures with the `self.event_handlers.handlers`
-            // lock no longer being held.
-            while let Some(()) = futures.next().await {}
+            // The handlers with the same priority are still called.
+            let priority_done = handlers.peek().map_or(true, |(_, other, _, _)| *other != priority);
+            if priority_done && propagation == EventPropagation::Stop {
+                debug!(priority, "An event handler stopped the propagation of the event");
+                break;
+            }
         }
     }
 }
@@ -546,7 +634,7 @@ mod tests {
         future,
         sync::{
             atomic::{AtomicU8, Ordering::SeqCst},
-            Arc,
+            Arc, Mutex,
         },
     };
 
@@ -556,12 +644,14 @@ mod tests {
     use ruma::{
         events::{
             room::{
-                member::{OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent},
+                member::{
+                    OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent, SyncRoomMemberEvent,
+                },
                 name::OriginalSyncRoomNameEvent,
                 power_levels::OriginalSyncRoomPowerLevelsEvent,
             },
             typing::SyncTypingEvent,
-            AnySyncStateEvent,
+            AnySyncStateEvent, AnySyncTimelineEvent,
         },
         room_id,
         serde::Raw,
@@ -569,7 +659,7 @@ mod tests {
     use serde_json::json;
 
     use crate::{
-        event_handler::Ctx,
+        event_handler::{Ctx, EventPropagation},
         room::Room,
         test_utils::{logged_in_client, no_retry_test_client},
         Client,
@@ -779,6 +869,101 @@ mod tests {
         Ok(())
     }
 
+    #[async_test]
+    async fn event_handler_priority() -> crate::Result<()> {
+        let client = logged_in_client(None).await;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        client.add_event_handler({
+            let calls = calls.clone();
+            move |_ev: OriginalSyncRoomMemberEvent| {
+                calls.lock().unwrap().push("default");
+                future::ready(())
+            }
+        });
+        client.add_room_event_handler_with_priority(
+            #[allow(unknown_lints, clippy::explicit_auto_deref)] // lint is buggy
+            *DEFAULT_SYNC_ROOM_ID,
+            10,
+            {
+                let calls = calls.clone();
+                move |_ev: OriginalSyncRoomMemberEvent| {
+                    calls.lock().unwrap().push("high");
+                    future::ready(EventPropagation::Continue)
+                }
+            },
+        );
+        let stop_handle = client.add_event_handler_with_priority(5, {
+            let calls = calls.clone();
+            move |_ev: AnySyncTimelineEvent| {
+                calls.lock().unwrap().push("stop");
+                future::ready(EventPropagation::Stop)
+            }
+        });
+
+        let response = || {
+            EventBuilder::default()
+                .add_joined_room(
+                    JoinedRoomBuilder::default().add_timeline_event(TimelineTestEvent::Member),
+                )
+                .build_sync_response()
+        };
+        client.process_sync(response()).await?;
+
+        assert_eq!(*calls.lock().unwrap(), ["high", "stop"]);
+
+        // Without the handler stopping the propagation, all the handlers are
+        // called.
+        client.remove_event_handler(stop_handle);
+        calls.lock().unwrap().clear();
+        client.process_sync(response()).await?;
+
+        assert_eq!(*calls.lock().unwrap(), ["high", "default"]);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn event_handler_kind_order() -> crate::Result<()> {
+        let client = logged_in_client(None).await;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        client.add_event_handler({
+            let calls = calls.clone();
+            move |_ev: AnySyncTimelineEvent| {
+                calls.lock().unwrap().push("any");
+                future::ready(())
+            }
+        });
+        client.add_event_handler({
+            let calls = calls.clone();
+            move |_ev: OriginalSyncRoomMemberEvent| {
+                calls.lock().unwrap().push("original");
+                future::ready(())
+            }
+        });
+        client.add_event_handler({
+            let calls = calls.clone();
+            move |_ev: SyncRoomMemberEvent| {
+                calls.lock().unwrap().push("possibly redacted");
+                future::ready(())
+            }
+        });
+
+        let response = EventBuilder::default()
+            .add_joined_room(
+                JoinedRoomBuilder::default().add_timeline_event(TimelineTestEvent::Member),
+            )
+            .build_sync_response();
+        client.process_sync(response).await?;
+
+        // The handlers with the same priority are called in the same order as
+        // before priorities existed.
+        assert_eq!(*calls.lock().unwrap(), ["possibly redacted", "original", "any"]);
+
+        Ok(())
+    }
+
     #[async_test]
     async fn event_handler_drop_guard() {
         let client = no_retry_test_client(None).await;
@@ -0,0 +1,89 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use matrix_sdk_common::AsyncTraitDeps;
+
+use super::HttpClient;
+
+/// A middleware on the HTTP requests sent by a [`Client`] to the homeserver.
+///
+/// Middlewares are added with [`ClientBuilder::add_http_middleware()`]. They
+/// can be used to add custom headers to the requests, like authentication
+/// headers for a gateway or tracing headers, to observe the responses, or to
+/// answer the requests without sending them, in tests for example.
+///
+/// The middlewares are called for every attempt to send a request, so several
+/// times if the request is retried.
+///
+/// [`Client`]: crate::Client
+/// [`ClientBuilder::add_http_middleware()`]: crate::ClientBuilder::add_http_middleware
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait HttpMiddleware: AsyncTraitDeps {
+    /// Inspect or modify a request before it is sent.
+    ///
+    /// If this returns a response, the request isn't sent, and the response is
+    /// used instead. The next middlewares are not called for the request, and
+    /// no middleware is called for the response.
+    async fn on_request(
+        &self,
+        _request: &mut http::Request<Bytes>,
+    ) -> Option<http::Response<Bytes>> {
+        None
+    }
+
+    /// Inspect or modify a response after it is received.
+    ///
+    /// `request` is the request that was sent, with the changes made by the
+    /// middlewares.
+    async fn on_response(
+        &self,
+        _request: &http::Request<Bytes>,
+        _response: &mut http::Response<Bytes>,
+    ) {
+    }
+}
+
+impl HttpClient {
+    /// Call the middlewares on a request, in the order they were added.
+    ///
+    /// Returns the response of the first middleware that answered the request,
+    /// if any.
+    pub(super) async fn run_request_middlewares(
+        &self,
+        request: &mut http::Request<Bytes>,
+    ) -> Option<http::Response<Bytes>> {
+        for middleware in &self.middlewares {
+            if let Some(response) = middleware.on_request(request).await {
+                return Some(response);
+            }
+        }
+
+        None
+    }
+
+    /// Call the middlewares on a response, in the reverse order they were
+    /// added.
+    pub(super) async fn run_response_middlewares(
+        &self,
+        request: &http::Request<Bytes>,
+        response: &mut http::Response<Bytes>,
+    ) {
+        for middleware in self.middlewares.iter().rev() {
+            middleware.on_response(request, response).await;
+        }
+    }
+}
@@ -24,7 +24,9 @@ use std::{
 
 use bytes::{Bytes, BytesMut};
 use bytesize::ByteSize;
-use eyeball::shared::Observable as SharedObservable;
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use futures_util::future::{self, Either};
+use matrix_sdk_common::{instant::Instant, metrics};
 use ruma::{
     api::{
         error::{FromHttpResponseError, IntoHttpError},
@@ -32,17 +34,26 @@ use ruma::{
     },
     UserId,
 };
+use tokio::sync::broadcast;
 use tracing::{debug, field::debug, instrument, trace};
 
 use crate::{config::RequestConfig, error::HttpError};
 
+mod middleware;
 #[cfg(not(target_arch = "wasm32"))]
 mod native;
+mod rate_limit;
+mod scheduler;
 #[cfg(target_arch = "wasm32")]
 mod wasm;
 
+pub use middleware::HttpMiddleware;
 #[cfg(not(target_arch = "wasm32"))]
-pub(crate) use native::HttpSettings;
+pub(crate) use native::{HttpSettings, StreamedResponse};
+use rate_limit::RateLimiter;
+pub use rate_limit::{EndpointClass, RateLimited};
+pub(crate) use scheduler::RequestLimits;
+use scheduler::{RequestKind, RequestScheduler};
 
 pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
@@ -50,12 +61,40 @@ pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 pub(crate) struct HttpClient {
     pub(crate) inner: reqwest::Client,
     pub(crate) request_config: RequestConfig,
+    middlewares: Vec<Arc<dyn HttpMiddleware>>,
+    scheduler: RequestScheduler,
+    rate_limiter: RateLimiter,
+    network_status: SharedObservable<NetworkStatus>,
     next_request_id: Arc<AtomicU64>,
 }
 
 impl HttpClient {
-    pub(crate) fn new(inner: reqwest::Client, request_config: RequestConfig) -> Self {
-        HttpClient { inner, request_config, next_request_id: AtomicU64::new(0).into() }
+    pub(crate) fn new(
+        inner: reqwest::Client,
+        request_config: RequestConfig,
+        middlewares: Vec<Arc<dyn HttpMiddleware>>,
+        limits: RequestLimits,
+    ) -> Self {
+        HttpClient {
+            inner,
+            request_config,
+            middlewares,
+            scheduler: RequestScheduler::new(limits),
+            rate_limiter: RateLimiter::new(),
+            network_status: SharedObservable::new(NetworkStatus::Online),
+            next_request_id: AtomicU64::new(0).into(),
+        }
+    }
+
+    /// Subscribe to the rate limits of the homeserver.
+    pub(crate) fn subscribe_to_rate_limits(&self) -> broadcast::Receiver<RateLimited> {
+        self.rate_limiter.subscribe()
+    }
+
+    /// Get how long to wait before sending a request of the given class, if it
+    /// is currently rate-limited.
+    pub(crate) fn rate_limit_delay(&self, class: EndpointClass) -> Option<Duration> {
+        self.rate_limiter.delay(class)
     }
 
     fn get_request_id(&self) -> String {
@@ -195,11 +234,29 @@ impl HttpClient {
             request
         };
 
+        // Don't send the request while the device is offline, or while its
+        // class is rate-limited, it would be rejected too.
+        self.wait_until_online().await;
+        let class = EndpointClass::of(&request);
+        self.rate_limiter.wait(class).await;
+
+        // Wait for a slot if there are too many requests of the same kind.
+        let kind = RequestKind::of(&request);
+        let priority = config.priority.unwrap_or_else(|| kind.default_priority(&request));
+        let _permit = self.scheduler.acquire(kind, priority).await;
+
         debug!("Sending request");
 
-        // There's a bunch of state in send_request, factor out a pinned inner
-        // future to reduce this size of futures that await this function.
-        match Box::pin(self.send_request::<R>(request, config, send_progress)).await {
+        let start = Instant::now();
+        let result = self.send_while_online::<R>(request, class, config, send_progress).await;
+
+        metrics::record_duration(
+            metrics::HTTP_REQUEST_DURATION,
+            start,
+            &[("endpoint", endpoint_label::<R>()), ("outcome", metrics::outcome_label(&result))],
+        );
+
+        match result {
             Ok(response) => {
                 debug!("Got response");
                 Ok(response)
@@ -210,6 +267,109 @@ impl HttpClient {
             }
         }
     }
+
+    /// Send the request, and send it again once the device is back online if
+    /// it went offline while waiting for a response.
+    async fn send_while_online<R>(
+        &self,
+        request: http::Request<Bytes>,
+        class: EndpointClass,
+        config: RequestConfig,
+        send_progress: SharedObservable<TransmissionProgress>,
+    ) -> Result<R::IncomingResponse, HttpError>
+    where
+        R: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<R::EndpointError>>,
+    {
+        // Only the GET requests are sent again, since they don't have side
+        // effects. The others would be sent twice if the homeserver received
+        // them before the connection was lost.
+        if request.method() != http::Method::GET {
+            // There's a bunch of state in send_request, factor out a pinned
+            // inner future to reduce this size of futures that await this
+            // function.
+            return Box::pin(self.send_request::<R>(request, class, config, send_progress)).await;
+        }
+
+        loop {
+            let send = Box::pin(self.send_request::<R>(
+                clone_request(&request),
+                class,
+                config,
+                send_progress.clone(),
+            ));
+
+            // Don't wait for a response on a connection that was probably
+            // lost, like a long-polling sync request.
+            match future::select(send, Box::pin(self.went_offline())).await {
+                Either::Left((result, _)) => return result,
+                Either::Right(_) => {
+                    debug!("The device went offline, sending the request again once it is online");
+                    self.wait_until_online().await;
+                }
+            }
+        }
+    }
+
+    /// Set whether the device is connected to the network.
+    pub(crate) fn set_network_status(&self, status: NetworkStatus) {
+        self.network_status.set_if_not_eq(status);
+    }
+
+    /// Get whether the device is connected to the network.
+    pub(crate) fn network_status(&self) -> NetworkStatus {
+        self.network_status.get()
+    }
+
+    /// Subscribe to the updates of the network status.
+    pub(crate) fn subscribe_to_network_status(&self) -> Subscriber<NetworkStatus> {
+        self.network_status.subscribe()
+    }
+
+    /// Wait until the device is online.
+    async fn wait_until_online(&self) {
+        let mut subscriber = self.network_status.subscribe();
+        let mut status = subscriber.get();
+
+        while status == NetworkStatus::Offline {
+            debug!("Waiting for the device to be online");
+            match subscriber.next().await {
+                Some(new_status) => status = new_status,
+                None => break,
+            }
+        }
+    }
+
+    /// Wait until the device goes offline.
+    async fn went_offline(&self) {
+        let mut subscriber = self.network_status.subscribe();
+
+        while let Some(status) = subscriber.next().await {
+            if status == NetworkStatus::Offline {
+                return;
+            }
+        }
+
+        // The observable is never dropped while the client is alive.
+        future::pending().await
+    }
+}
+
+/// Whether the device is connected to the network.
+///
+/// It is set with [`Client::set_network_status()`], usually from the network
+/// monitor of the platform. While the device is offline, the requests wait
+/// until it is back online before being sent, which pauses the sync loops and
+/// the media downloads.
+///
+/// [`Client::set_network_status()`]: crate::Client::set_network_status
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NetworkStatus {
+    /// The device is connected to the network.
+    #[default]
+    Online,
+    /// The device is not connected to the network.
+    Offline,
 }
 
 /// Progress of sending or receiving a payload.
@@ -221,6 +381,25 @@ pub struct TransmissionProgress {
     pub total: usize,
 }
 
+// Clones all request parts except the extensions which can't be cloned.
+// See also https://github.com/hyperium/http/issues/395
+fn clone_request(request: &http::Request<Bytes>) -> http::Request<Bytes> {
+    let mut builder = http::Request::builder()
+        .version(request.version())
+        .method(request.method())
+        .uri(request.uri());
+    *builder.headers_mut().unwrap() = request.headers().clone();
+    builder.body(request.body().clone()).unwrap()
+}
+
+/// The label of the endpoint of the given request type in the metrics, e.g.
+/// `sync::sync_events::v3` for `ruma_client_api::sync::sync_events::v3::Request`.
+fn endpoint_label<R>() -> &'static str {
+    let name = type_name::<R>();
+    let name = name.strip_prefix("ruma_client_api::").unwrap_or(name);
+    name.strip_suffix("::Request").unwrap_or(name)
+}
+
 async fn response_to_http_response(
     mut response: reqwest::Response,
 ) -> Result<http::Response<Bytes>, reqwest::Error> {
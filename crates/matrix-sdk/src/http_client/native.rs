@@ -23,19 +23,31 @@ use backoff::{future::retry, Error as RetryError, ExponentialBackoff};
 use bytes::Bytes;
 use bytesize::ByteSize;
 use eyeball::shared::Observable as SharedObservable;
-use ruma::api::{
-    client::error::{ErrorBody as ClientApiErrorBody, ErrorKind as ClientApiErrorKind},
-    error::FromHttpResponseError,
-    IncomingResponse, OutgoingRequest,
+use http::{header::RANGE, HeaderValue};
+use matrix_sdk_common::{instant::Instant, metrics};
+use ruma::{
+    api::{
+        error::{FromHttpResponseError, IntoHttpError},
+        EndpointError, IncomingResponse, MatrixVersion, OutgoingRequest,
+    },
+    UserId,
+};
+use tracing::debug;
+
+use super::{
+    clone_request, endpoint_label,
+    rate_limit::retry_after,
+    response_to_http_response,
+    scheduler::{RequestKind, RequestPermit},
+    EndpointClass, HttpClient, TransmissionProgress, DEFAULT_REQUEST_TIMEOUT,
 };
-
-use super::{response_to_http_response, HttpClient, TransmissionProgress, DEFAULT_REQUEST_TIMEOUT};
 use crate::{config::RequestConfig, error::HttpError, RumaApiError};
 
 impl HttpClient {
     pub(super) async fn send_request<R>(
         &self,
         request: http::Request<Bytes>,
+        class: EndpointClass,
         config: RequestConfig,
         send_progress: SharedObservable<TransmissionProgress>,
     ) -> Result<R::IncomingResponse, HttpError>
@@ -63,18 +75,7 @@ impl HttpClient {
                     |err: HttpError| {
                         if let Some(api_error) = err.as_ruma_api_error() {
                             let status_code = match api_error {
-                                RumaApiError::ClientApi(e) => match e.body {
-                                    ClientApiErrorBody::Standard {
-                                        kind: ClientApiErrorKind::LimitExceeded { retry_after_ms },
-                                        ..
-                                    } => {
-                                        return RetryError::Transient {
-                                            err,
-                                            retry_after: retry_after_ms,
-                                        };
-                                    }
-                                    _ => Some(e.status_code),
-                                },
+                                RumaApiError::ClientApi(e) => Some(e.status_code),
                                 RumaApiError::Uiaa(_) => None,
                                 RumaApiError::Other(e) => Some(e.status_code),
                             };
@@ -90,7 +91,14 @@ impl HttpClient {
                     }
                 };
 
-                let response = send_request(&self.inner, &request, config.timeout, send_progress)
+                // The device might have gone offline, or this request or
+                // another one of the same class might have been rate-limited
+                // since the last attempt.
+                self.wait_until_online().await;
+                self.rate_limiter.wait(class).await;
+
+                let response = self
+                    .send_with_middlewares(&request, config.timeout, send_progress)
                     .await
                     .map_err(error_type)?;
 
@@ -100,13 +108,214 @@ impl HttpClient {
                     .record("status", status_code.as_u16())
                     .record("response_size", response_size.to_string_as(true));
 
-                R::IncomingResponse::try_from_http_response(response)
-                    .map_err(|e| error_type(HttpError::from(e)))
+                let is_rate_limited = status_code == http::StatusCode::TOO_MANY_REQUESTS;
+                if is_rate_limited {
+                    self.rate_limiter.record_limited(class, retry_after(&response));
+                }
+
+                match R::IncomingResponse::try_from_http_response(response) {
+                    Ok(response) => {
+                        self.rate_limiter.record_success(class);
+                        Ok(response)
+                    }
+                    // The rate limiter makes the retry wait until the limit is
+                    // lifted.
+                    Err(e) if is_rate_limited && !stop => Err(RetryError::Transient {
+                        err: HttpError::from(e),
+                        retry_after: Some(Duration::ZERO),
+                    }),
+                    Err(e) => Err(error_type(HttpError::from(e))),
+                }
             }
         };
 
         retry::<_, HttpError, _, _, _>(backoff, send_request).await
     }
+
+    async fn send_with_middlewares(
+        &self,
+        request: &http::Request<Bytes>,
+        timeout: Duration,
+        send_progress: SharedObservable<TransmissionProgress>,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        if self.middlewares.is_empty() {
+            return send_request(&self.inner, request, timeout, send_progress).await;
+        }
+
+        // The middlewares might modify the request, so we need our own copy of it.
+        let mut request = clone_request(request);
+        if let Some(response) = self.run_request_middlewares(&mut request).await {
+            return Ok(response);
+        }
+
+        let mut response = send_request(&self.inner, &request, timeout, send_progress).await?;
+        self.run_response_middlewares(&request, &mut response).await;
+
+        Ok(response)
+    }
+}
+
+impl HttpClient {
+    /// Send a request and stream the body of its response, starting at the
+    /// given byte offset with a `Range` header if it isn't `0`.
+    ///
+    /// Like [`HttpClient::send()`], this waits for the device to be online,
+    /// for the rate limit of the endpoint and for a slot in the scheduler, and
+    /// calls the request middlewares. The slot is kept until the response is
+    /// dropped.
+    ///
+    /// The request is not retried and the response middlewares are not
+    /// called, since the body of the response is not buffered. The caller is
+    /// expected to resume the transfer instead.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn send_streamed<R>(
+        &self,
+        request: R,
+        offset: u64,
+        config: Option<RequestConfig>,
+        homeserver: String,
+        access_token: Option<&str>,
+        user_id: Option<&UserId>,
+        server_versions: &[MatrixVersion],
+    ) -> Result<StreamedResponse, HttpError>
+    where
+        R: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<R::EndpointError>>,
+    {
+        let config = config.unwrap_or(self.request_config);
+
+        let mut request = self.serialize_request(
+            request,
+            config,
+            homeserver,
+            access_token,
+            user_id,
+            server_versions,
+        )?;
+        if offset > 0 {
+            let range =
+                HeaderValue::try_from(format!("bytes={offset}-")).map_err(IntoHttpError::from)?;
+            request.headers_mut().insert(RANGE, range);
+        }
+
+        let start = Instant::now();
+        let result = self.execute_streamed::<R>(request, config, offset).await;
+
+        metrics::record_duration(
+            metrics::HTTP_REQUEST_DURATION,
+            start,
+            &[("endpoint", endpoint_label::<R>()), ("outcome", metrics::outcome_label(&result))],
+        );
+
+        result
+    }
+
+    /// Send the serialized request of [`HttpClient::send_streamed()`].
+    async fn execute_streamed<R>(
+        &self,
+        mut request: http::Request<Bytes>,
+        config: RequestConfig,
+        offset: u64,
+    ) -> Result<StreamedResponse, HttpError>
+    where
+        R: OutgoingRequest,
+        HttpError: From<FromHttpResponseError<R::EndpointError>>,
+    {
+        self.wait_until_online().await;
+        let class = EndpointClass::of(&request);
+        self.rate_limiter.wait(class).await;
+
+        let kind = RequestKind::of(&request);
+        let priority = config.priority.unwrap_or_else(|| kind.default_priority(&request));
+        let permit = self.scheduler.acquire(kind, priority).await;
+
+        debug!(offset, "Sending streamed request");
+
+        let body = match self.run_request_middlewares(&mut request).await {
+            Some(response) => StreamedBody::Buffered(response),
+            None => StreamedBody::Remote(self.inner.execute(request.try_into()?).await?),
+        };
+        let status = body.status();
+
+        if status.is_client_error() || status.is_server_error() {
+            let response = match body {
+                StreamedBody::Remote(response) => response_to_http_response(response).await?,
+                StreamedBody::Buffered(response) => response,
+            };
+
+            if status == http::StatusCode::TOO_MANY_REQUESTS {
+                self.rate_limiter.record_limited(class, retry_after(&response));
+            }
+
+            return Err(FromHttpResponseError::Server(R::EndpointError::from_http_response(
+                response,
+            ))
+            .into());
+        }
+
+        self.rate_limiter.record_success(class);
+
+        Ok(StreamedResponse { body, _permit: permit })
+    }
+}
+
+/// A response whose body is received in chunks, returned by
+/// [`HttpClient::send_streamed()`].
+pub(crate) struct StreamedResponse {
+    body: StreamedBody,
+    /// The slot of the request in the scheduler, released when the response
+    /// is dropped.
+    _permit: RequestPermit,
+}
+
+enum StreamedBody {
+    /// The response of the homeserver.
+    Remote(reqwest::Response),
+    /// A response given by a middleware, whose body is taken by the first
+    /// chunk.
+    Buffered(http::Response<Bytes>),
+}
+
+impl StreamedBody {
+    fn status(&self) -> http::StatusCode {
+        match self {
+            Self::Remote(response) => response.status(),
+            Self::Buffered(response) => response.status(),
+        }
+    }
+}
+
+impl StreamedResponse {
+    /// The status code of the response.
+    pub(crate) fn status(&self) -> http::StatusCode {
+        self.body.status()
+    }
+
+    /// The length of the body that remains to be received, if it is known.
+    pub(crate) fn content_length(&self) -> Option<u64> {
+        match &self.body {
+            StreamedBody::Remote(response) => response.content_length(),
+            StreamedBody::Buffered(response) => Some(response.body().len() as u64),
+        }
+    }
+
+    /// Receive the next chunk of the body, or `None` at the end of the body.
+    pub(crate) async fn chunk(&mut self) -> Result<Option<Bytes>, HttpError> {
+        match &mut self.body {
+            StreamedBody::Remote(response) => Ok(response.chunk().await?),
+            StreamedBody::Buffered(response) => {
+                let chunk = mem::take(response.body_mut());
+                Ok((!chunk.is_empty()).then_some(chunk))
+            }
+        }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl Debug for StreamedResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamedResponse").field("status", &self.status()).finish_non_exhaustive()
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -185,17 +394,6 @@ pub(super) async fn send_request(
     Ok(response_to_http_response(response).await?)
 }
 
-// Clones all request parts except the extensions which can't be cloned.
-// See also https://github.com/hyperium/http/issues/395
-fn clone_request(request: &http::Request<Bytes>) -> http::Request<Bytes> {
-    let mut builder = http::Request::builder()
-        .version(request.version())
-        .method(request.method())
-        .uri(request.uri());
-    *builder.headers_mut().unwrap() = request.headers().clone();
-    builder.body(request.body().clone()).unwrap()
-}
-
 struct BytesChunks {
     bytes: Bytes,
     size: usize,
@@ -0,0 +1,275 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, sync::Mutex as StdMutex, time::Duration};
+
+use bytes::Bytes;
+use matrix_sdk_common::{instant::Instant, sleep::sleep};
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// The delay to wait after the first rate-limited response of a class of
+/// endpoints, when the homeserver doesn't say how long to wait.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay to wait after a rate-limited response.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// A class of endpoints sharing the same rate limit budget.
+///
+/// Homeservers usually rate limit the endpoints by class, so when a request is
+/// rate-limited, the following requests of the same class wait until the limit
+/// is lifted instead of being rejected too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum EndpointClass {
+    /// The `/sync` endpoints.
+    Sync,
+    /// The endpoints to send, edit and redact room events.
+    SendEvent,
+    /// The endpoints to upload and download media.
+    Media,
+    /// The endpoints to manage the end-to-end encryption keys and to send
+    /// to-device events.
+    Keys,
+    /// All the other endpoints.
+    Other,
+}
+
+impl EndpointClass {
+    pub(super) fn of(request: &http::Request<Bytes>) -> Self {
+        let path = request.uri().path();
+
+        if path.contains("/_matrix/media/") || path.contains("/v1/media/") {
+            Self::Media
+        } else if path.contains("/keys/") || path.contains("/sendToDevice/") {
+            Self::Keys
+        } else if path.ends_with("/sync") || path.contains("/sync/") {
+            Self::Sync
+        } else if path.contains("/send/") || path.contains("/state/") || path.contains("/redact/") {
+            Self::SendEvent
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// A notification that a class of endpoints was rate-limited by the
+/// homeserver.
+///
+/// It can be used to reschedule the requests of that class, instead of
+/// sending them while the limit is still in effect. It is received with
+/// [`Client::subscribe_to_rate_limits()`].
+///
+/// [`Client::subscribe_to_rate_limits()`]: crate::Client::subscribe_to_rate_limits
+#[derive(Clone, Debug)]
+pub struct RateLimited {
+    /// The class of the endpoint that was rate-limited.
+    pub class: EndpointClass,
+    /// How long to wait before sending another request of this class.
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Default)]
+struct ClassBudget {
+    /// When the requests of this class can be sent again.
+    blocked_until: Option<Instant>,
+    /// The number of rate-limited responses received in a row.
+    consecutive_limits: u32,
+}
+
+/// Tracks the rate limits of the homeserver by class of endpoint.
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    budgets: StdMutex<BTreeMap<EndpointClass, ClassBudget>>,
+    sender: broadcast::Sender<RateLimited>,
+}
+
+impl RateLimiter {
+    pub(super) fn new() -> Self {
+        let (sender, _) = broadcast::channel(16);
+        Self { budgets: Default::default(), sender }
+    }
+
+    pub(super) fn subscribe(&self) -> broadcast::Receiver<RateLimited> {
+        self.sender.subscribe()
+    }
+
+    /// Get how long to wait before sending a request of the given class.
+    pub(super) fn delay(&self, class: EndpointClass) -> Option<Duration> {
+        let blocked_until = self.budgets.lock().unwrap().get(&class)?.blocked_until?;
+        let now = Instant::now();
+        (blocked_until > now).then(|| blocked_until - now)
+    }
+
+    /// Wait until the requests of the given class can be sent.
+    pub(super) async fn wait(&self, class: EndpointClass) {
+        // The limit can be extended while waiting, by a response to another
+        // request of the same class.
+        while let Some(delay) = self.delay(class) {
+            debug!(?class, ?delay, "Waiting for the rate limit to be lifted");
+            sleep(delay).await;
+        }
+    }
+
+    /// Record a successful response for the given class.
+    pub(super) fn record_success(&self, class: EndpointClass) {
+        if let Some(budget) = self.budgets.lock().unwrap().get_mut(&class) {
+            budget.consecutive_limits = 0;
+        }
+    }
+
+    /// Record a rate-limited response for the given class.
+    ///
+    /// `retry_after` is the delay requested by the homeserver, if any.
+    /// Otherwise, the delay increases exponentially with the number of
+    /// rate-limited responses in a row.
+    ///
+    /// Returns how long to wait before retrying.
+    pub(super) fn record_limited(
+        &self,
+        class: EndpointClass,
+        retry_after: Option<Duration>,
+    ) -> Duration {
+        let retry_after = {
+            let mut budgets = self.budgets.lock().unwrap();
+            let budget = budgets.entry(class).or_default();
+
+            let backoff =
+                INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(budget.consecutive_limits));
+            let retry_after = retry_after.unwrap_or(backoff).min(MAX_BACKOFF);
+            budget.consecutive_limits = budget.consecutive_limits.saturating_add(1);
+
+            // Don't shorten the limit set by a concurrent request.
+            let blocked_until = Instant::now() + retry_after;
+            if budget.blocked_until.map_or(true, |until| until < blocked_until) {
+                budget.blocked_until = Some(blocked_until);
+            }
+
+            retry_after
+        };
+
+        warn!(?class, ?retry_after, "The homeserver rate-limited the request");
+
+        // Ignore the error, it only means that nobody is listening.
+        _ = self.sender.send(RateLimited { class, retry_after });
+
+        retry_after
+    }
+}
+
+/// Get the delay to wait before retrying, requested in a rate-limited
+/// response.
+///
+/// The `Retry-After` header takes precedence over the `retry_after_ms` field
+/// of the body.
+pub(super) fn retry_after(response: &http::Response<Bytes>) -> Option<Duration> {
+    #[derive(Deserialize)]
+    struct LimitExceededBody {
+        retry_after_ms: Option<u64>,
+    }
+
+    // Only the number of seconds is supported, not the HTTP date.
+    let header = response
+        .headers()
+        .get(http::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+        .map(Duration::from_secs);
+
+    header.or_else(|| {
+        let body: LimitExceededBody = serde_json::from_slice(response.body()).ok()?;
+        body.retry_after_ms.map(Duration::from_millis)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::{retry_after, EndpointClass, RateLimiter, INITIAL_BACKOFF};
+
+    fn request(path: &str) -> http::Request<Bytes> {
+        http::Request::builder()
+            .uri(format!("https://example.org{path}"))
+            .body(Bytes::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn endpoint_classes() {
+        let class = |path| EndpointClass::of(&request(path));
+
+        assert_eq!(class("/_matrix/client/v3/sync"), EndpointClass::Sync);
+        assert_eq!(class("/_matrix/client/unstable/org.matrix.msc3575/sync"), EndpointClass::Sync);
+        assert_eq!(
+            class("/_matrix/client/v3/rooms/!a:b/send/m.room.message/1"),
+            EndpointClass::SendEvent
+        );
+        assert_eq!(class("/_matrix/client/v3/rooms/!a:b/redact/$c/1"), EndpointClass::SendEvent);
+        assert_eq!(
+            class("/_matrix/client/v3/sendToDevice/m.room.encrypted/1"),
+            EndpointClass::Keys
+        );
+        assert_eq!(class("/_matrix/client/v3/keys/claim"), EndpointClass::Keys);
+        assert_eq!(class("/_matrix/media/v3/upload"), EndpointClass::Media);
+        assert_eq!(class("/_matrix/client/v3/rooms/!a:b/messages"), EndpointClass::Other);
+    }
+
+    #[test]
+    fn retry_after_from_response() {
+        let response = http::Response::builder()
+            .status(429)
+            .header("Retry-After", "3")
+            .body(Bytes::from_static(br#"{"errcode":"M_LIMIT_EXCEEDED","retry_after_ms":500}"#))
+            .unwrap();
+        assert_eq!(retry_after(&response), Some(Duration::from_secs(3)));
+
+        let response = http::Response::builder()
+            .status(429)
+            .body(Bytes::from_static(br#"{"errcode":"M_LIMIT_EXCEEDED","retry_after_ms":500}"#))
+            .unwrap();
+        assert_eq!(retry_after(&response), Some(Duration::from_millis(500)));
+
+        let response = http::Response::builder()
+            .status(429)
+            .body(Bytes::from_static(br#"{"errcode":"M_LIMIT_EXCEEDED"}"#))
+            .unwrap();
+        assert_eq!(retry_after(&response), None);
+    }
+
+    #[test]
+    fn exponential_backoff_by_class() {
+        let limiter = RateLimiter::new();
+        let mut updates = limiter.subscribe();
+
+        assert_eq!(limiter.record_limited(EndpointClass::SendEvent, None), INITIAL_BACKOFF);
+        assert_eq!(limiter.record_limited(EndpointClass::SendEvent, None), INITIAL_BACKOFF * 2);
+        assert!(limiter.delay(EndpointClass::SendEvent).is_some());
+        assert_eq!(updates.try_recv().unwrap().class, EndpointClass::SendEvent);
+
+        // The other classes have their own budget.
+        assert!(limiter.delay(EndpointClass::Sync).is_none());
+
+        // The delay requested by the homeserver is used as is.
+        let retry_after = Duration::from_millis(10);
+        assert_eq!(limiter.record_limited(EndpointClass::Sync, Some(retry_after)), retry_after);
+
+        // The backoff is reset after a successful response.
+        limiter.record_success(EndpointClass::SendEvent);
+        assert_eq!(limiter.record_limited(EndpointClass::SendEvent, None), INITIAL_BACKOFF);
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use crate::config::RequestPriority;
+
+/// The kind of a request, each kind having its own limit of concurrent
+/// requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum RequestKind {
+    /// A request to the client-server API.
+    Api,
+    /// A download of a media or of its thumbnail.
+    Media,
+}
+
+impl RequestKind {
+    pub(super) fn of(request: &http::Request<Bytes>) -> Self {
+        let path = request.uri().path();
+        let is_media_path = path.contains("/_matrix/media/") || path.contains("/v1/media/");
+        let is_download = path.contains("/download/") || path.contains("/thumbnail/");
+
+        if request.method() == http::Method::GET && is_media_path && is_download {
+            Self::Media
+        } else {
+            Self::Api
+        }
+    }
+
+    /// The priority of the requests of this kind, when it isn't set in their
+    /// config.
+    pub(super) fn default_priority(self, request: &http::Request<Bytes>) -> RequestPriority {
+        // Media downloads and backfill requests are usually not needed to show
+        // what the user is looking at right away.
+        let is_backfill = request.uri().path().ends_with("/messages");
+
+        if self == Self::Media || is_backfill {
+            RequestPriority::Background
+        } else {
+            RequestPriority::Interactive
+        }
+    }
+}
+
+/// The maximum number of concurrent requests, by kind of request.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RequestLimits {
+    /// The maximum number of concurrent requests to the client-server API.
+    pub(crate) api: Option<usize>,
+    /// The maximum number of concurrent media downloads.
+    pub(crate) media: Option<usize>,
+}
+
+impl RequestLimits {
+    fn get(&self, kind: RequestKind) -> Option<usize> {
+        let limit = match kind {
+            RequestKind::Api => self.api,
+            RequestKind::Media => self.media,
+        };
+
+        // There must be room for at least one request.
+        limit.map(|limit| limit.max(1))
+    }
+}
+
+/// Limits the number of concurrent requests of every kind, and lets the
+/// interactive requests through before the background ones when the limit is
+/// reached.
+pub(super) struct RequestScheduler {
+    limits: RequestLimits,
+    state: Arc<StdMutex<SchedulerState>>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    running_api: usize,
+    running_media: usize,
+    /// The requests waiting for a slot, in the order they should get one.
+    ///
+    /// The key is `(is_background, sequence number)`, so the interactive
+    /// requests come first, and the requests with the same priority are
+    /// handled in order.
+    waiting: BTreeMap<(bool, u64), (RequestKind, oneshot::Sender<()>)>,
+    next_sequence_number: u64,
+}
+
+impl SchedulerState {
+    fn running(&mut self, kind: RequestKind) -> &mut usize {
+        match kind {
+            RequestKind::Api => &mut self.running_api,
+            RequestKind::Media => &mut self.running_media,
+        }
+    }
+
+    fn has_room(&mut self, limits: &RequestLimits, kind: RequestKind) -> bool {
+        limits.get(kind).map_or(true, |limit| *self.running(kind) < limit)
+    }
+
+    /// Give a slot to the waiting requests, as long as there is room for
+    /// them.
+    fn wake_waiting(&mut self, limits: &RequestLimits) {
+        let keys: Vec<_> = self.waiting.keys().copied().collect();
+
+        for key in keys {
+            let kind = self.waiting[&key].0;
+            if !self.has_room(limits, kind) {
+                continue;
+            }
+
+            let (_, sender) = self.waiting.remove(&key).expect("the key was just found");
+            *self.running(kind) += 1;
+            // The request might have been cancelled while it was waiting, its
+            // permit gives the slot back when it is dropped.
+            _ = sender.send(());
+        }
+    }
+}
+
+impl RequestScheduler {
+    pub(super) fn new(limits: RequestLimits) -> Self {
+        Self { limits, state: Default::default() }
+    }
+
+    /// Wait until there is room for a request of the given kind and priority.
+    ///
+    /// The slot is released when the returned permit is dropped.
+    pub(super) async fn acquire(
+        &self,
+        kind: RequestKind,
+        priority: RequestPriority,
+    ) -> RequestPermit {
+        let (key, receiver) = {
+            let mut state = self.state.lock().unwrap();
+
+            // Don't overtake the requests of the same kind that are already
+            // waiting with the same or a higher priority.
+            let is_background = priority == RequestPriority::Background;
+            let has_precedence = !state
+                .waiting
+                .iter()
+                .any(|((background, _), (k, _))| *k == kind && *background <= is_background);
+
+            if has_precedence && state.has_room(&self.limits, kind) {
+                *state.running(kind) += 1;
+                (None, None)
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                let key = (is_background, state.next_sequence_number);
+                state.next_sequence_number += 1;
+                state.waiting.insert(key, (kind, sender));
+                (Some(key), Some(receiver))
+            }
+        };
+
+        // Create the permit before waiting, so the request is removed from the
+        // queue if this future is dropped.
+        let permit = RequestPermit { kind, key, limits: self.limits, state: self.state.clone() };
+
+        if let Some(receiver) = receiver {
+            // The sender is only dropped after sending, since it is only
+            // removed from the queue when the request gets a slot.
+            _ = receiver.await;
+        }
+
+        permit
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for RequestScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestScheduler").field("limits", &self.limits).finish_non_exhaustive()
+    }
+}
+
+/// A slot for a request given by the [`RequestScheduler`], released when
+/// dropped.
+pub(super) struct RequestPermit {
+    kind: RequestKind,
+    /// The key of the request in the queue, if it had to wait for a slot.
+    key: Option<(bool, u64)>,
+    limits: RequestLimits,
+    state: Arc<StdMutex<SchedulerState>>,
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+
+        // The request is still in the queue if it was cancelled before it got
+        // a slot.
+        if self.key.is_some_and(|key| state.waiting.remove(&key).is_some()) {
+            return;
+        }
+
+        *state.running(self.kind) -= 1;
+        state.wake_waiting(&self.limits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures_util::{pin_mut, FutureExt};
+    use matrix_sdk_test::async_test;
+
+    use super::{RequestKind, RequestLimits, RequestScheduler};
+    use crate::config::RequestPriority;
+
+    fn request(method: http::Method, path: &str) -> http::Request<Bytes> {
+        http::Request::builder()
+            .method(method)
+            .uri(format!("https://example.org{path}"))
+            .body(Bytes::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn request_kinds() {
+        let download = request(http::Method::GET, "/_matrix/media/r0/download/example.org/abc");
+        assert_eq!(RequestKind::of(&download), RequestKind::Media);
+        assert_eq!(RequestKind::Media.default_priority(&download), RequestPriority::Background);
+
+        let upload = request(http::Method::POST, "/_matrix/media/r0/upload");
+        assert_eq!(RequestKind::of(&upload), RequestKind::Api);
+
+        let messages = request(http::Method::GET, "/_matrix/client/r0/rooms/!a:b/messages");
+        assert_eq!(RequestKind::of(&messages), RequestKind::Api);
+        assert_eq!(RequestKind::Api.default_priority(&messages), RequestPriority::Background);
+
+        let sync = request(http::Method::GET, "/_matrix/client/r0/sync");
+        assert_eq!(RequestKind::Api.default_priority(&sync), RequestPriority::Interactive);
+    }
+
+    #[async_test]
+    async fn interactive_requests_go_first() {
+        let scheduler = RequestScheduler::new(RequestLimits { api: Some(1), media: Some(1) });
+
+        let api_permit = scheduler.acquire(RequestKind::Api, RequestPriority::Interactive).await;
+
+        // Media downloads have their own limit.
+        let media_permit =
+            scheduler.acquire(RequestKind::Media, RequestPriority::Background).now_or_never();
+        assert!(media_permit.is_some());
+
+        let background = scheduler.acquire(RequestKind::Api, RequestPriority::Background);
+        let interactive = scheduler.acquire(RequestKind::Api, RequestPriority::Interactive);
+        pin_mut!(background, interactive);
+        assert!((&mut background).now_or_never().is_none());
+        assert!((&mut interactive).now_or_never().is_none());
+
+        // The interactive request gets the slot first, even if it was queued
+        // later.
+        drop(api_permit);
+        let interactive_permit = (&mut interactive).now_or_never().unwrap();
+        assert!((&mut background).now_or_never().is_none());
+
+        drop(interactive_permit);
+        assert!(background.now_or_never().is_some());
+    }
+}
@@ -19,13 +19,17 @@ use bytesize::ByteSize;
 use eyeball::shared::Observable as SharedObservable;
 use ruma::api::{error::FromHttpResponseError, IncomingResponse, OutgoingRequest};
 
-use super::{response_to_http_response, HttpClient, TransmissionProgress};
+use super::{
+    clone_request, rate_limit::retry_after, response_to_http_response, EndpointClass, HttpClient,
+    TransmissionProgress,
+};
 use crate::{config::RequestConfig, error::HttpError};
 
 impl HttpClient {
     pub(super) async fn send_request<R>(
         &self,
-        request: http::Request<Bytes>,
+        mut request: http::Request<Bytes>,
+        class: EndpointClass,
         _config: RequestConfig,
         _send_progress: SharedObservable<TransmissionProgress>,
     ) -> Result<R::IncomingResponse, HttpError>
@@ -33,8 +37,16 @@ impl HttpClient {
         R: OutgoingRequest + Debug,
         HttpError: From<FromHttpResponseError<R::EndpointError>>,
     {
-        let request = reqwest::Request::try_from(request)?;
-        let response = response_to_http_response(self.inner.execute(request).await?).await?;
+        let response = match self.run_request_middlewares(&mut request).await {
+            Some(response) => response,
+            None => {
+                let reqwest_request = reqwest::Request::try_from(clone_request(&request))?;
+                let mut response =
+                    response_to_http_response(self.inner.execute(reqwest_request).await?).await?;
+                self.run_response_middlewares(&request, &mut response).await;
+                response
+            }
+        };
 
         let status_code = response.status();
         let response_size = ByteSize(response.body().len().try_into().unwrap_or(u64::MAX));
@@ -42,6 +54,14 @@ impl HttpClient {
             .record("status", status_code.as_u16())
             .record("response_size", response_size.to_string_as(true));
 
+        // There are no retries, but the following requests of the same class
+        // wait until the limit is lifted.
+        if status_code == http::StatusCode::TOO_MANY_REQUESTS {
+            self.rate_limiter.record_limited(class, retry_after(&response));
+        } else if status_code.is_success() {
+            self.rate_limiter.record_success(class);
+        }
+
         Ok(R::IncomingResponse::try_from_http_response(response)?)
     }
 }
@@ -0,0 +1,219 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for the events of image packs.
+
+use std::collections::BTreeMap;
+
+use ruma::{
+    events::{macros::EventContent, room::ImageInfo},
+    OwnedMxcUri, OwnedRoomId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map as JsonObject, Value};
+
+/// The content of an `im.ponies.room_emotes` state event.
+///
+/// The state key is the ID of the pack, a room can have several packs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "im.ponies.room_emotes", kind = State, state_key_type = String)]
+pub struct RoomImagePackEventContent {
+    /// The pack.
+    #[serde(flatten)]
+    pub pack: ImagePack,
+}
+
+impl RoomImagePackEventContent {
+    /// Create a new `RoomImagePackEventContent` with the given pack.
+    pub fn new(pack: ImagePack) -> Self {
+        Self { pack }
+    }
+}
+
+/// The content of an `im.ponies.user_emotes` global account data event, the
+/// personal pack of the user.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "im.ponies.user_emotes", kind = GlobalAccountData)]
+pub struct UserImagePackEventContent {
+    /// The pack.
+    #[serde(flatten)]
+    pub pack: ImagePack,
+}
+
+impl UserImagePackEventContent {
+    /// Create a new `UserImagePackEventContent` with the given pack.
+    pub fn new(pack: ImagePack) -> Self {
+        Self { pack }
+    }
+}
+
+/// The content of an `im.ponies.emote_rooms` global account data event, the
+/// packs of rooms that the user can use in every room.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "im.ponies.emote_rooms", kind = GlobalAccountData)]
+pub struct EmoteRoomsEventContent {
+    /// The IDs of the packs, by room.
+    ///
+    /// The values of the IDs are empty objects, reserved for future use.
+    #[serde(default)]
+    pub rooms: BTreeMap<OwnedRoomId, BTreeMap<String, JsonObject<String, Value>>>,
+}
+
+/// A pack of images, that can be used as emoticons or stickers.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImagePack {
+    /// The images of the pack, by shortcode.
+    ///
+    /// The shortcodes don't include the surrounding colons.
+    #[serde(default)]
+    pub images: BTreeMap<String, PackImage>,
+
+    /// The details of the pack.
+    #[serde(rename = "pack", default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<PackInfo>,
+}
+
+impl ImagePack {
+    /// Get the images of the pack that can be used as emoticons, by
+    /// shortcode.
+    pub fn emoticons(&self) -> impl Iterator<Item = (&str, &PackImage)> {
+        self.images_with_usage(PackUsage::Emoticon)
+    }
+
+    /// Get the images of the pack that can be used as stickers, by shortcode.
+    pub fn stickers(&self) -> impl Iterator<Item = (&str, &PackImage)> {
+        self.images_with_usage(PackUsage::Sticker)
+    }
+
+    fn images_with_usage(&self, usage: PackUsage) -> impl Iterator<Item = (&str, &PackImage)> {
+        let pack_usage = self.info.as_ref().map(|info| info.usage.as_slice()).unwrap_or_default();
+
+        self.images.iter().filter_map(move |(shortcode, image)| {
+            // The usage of the image falls back to the one of the pack, and
+            // the images can be used for everything if neither is set.
+            let usage_list = if image.usage.is_empty() { pack_usage } else { &image.usage };
+            (usage_list.is_empty() || usage_list.contains(&usage))
+                .then_some((shortcode.as_str(), image))
+        })
+    }
+}
+
+/// An image of a pack.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PackImage {
+    /// The URI of the image.
+    pub url: OwnedMxcUri,
+
+    /// The description of the image, used as the body of stickers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+
+    /// The metadata of the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info: Option<ImageInfo>,
+
+    /// How the image can be used, the usage of the pack is used if this is
+    /// empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub usage: Vec<PackUsage>,
+}
+
+impl PackImage {
+    /// Create a new `PackImage` with the given URI.
+    pub fn new(url: OwnedMxcUri) -> Self {
+        Self { url, body: None, info: None, usage: Vec::new() }
+    }
+}
+
+/// The details of a pack.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct PackInfo {
+    /// The name of the pack.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+
+    /// The avatar of the pack.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<OwnedMxcUri>,
+
+    /// How the images of the pack can be used, they can be used for
+    /// everything if this is empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub usage: Vec<PackUsage>,
+
+    /// The attribution of the pack, like its author.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<String>,
+}
+
+/// How the images of a pack can be used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackUsage {
+    /// As emoticons in messages and reactions.
+    Emoticon,
+
+    /// As stickers.
+    Sticker,
+
+    /// A usage that isn't supported by the SDK.
+    #[serde(other)]
+    Unknown,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{PackUsage, RoomImagePackEventContent};
+
+    #[test]
+    fn room_image_pack_serialization() {
+        let json = json!({
+            "images": {
+                "happy": {
+                    "url": "mxc://example.org/happy",
+                },
+                "wave": {
+                    "url": "mxc://example.org/wave",
+                    "body": "Waving hand",
+                    "usage": ["sticker"],
+                },
+                "dance": {
+                    "url": "mxc://example.org/dance",
+                    "usage": ["emoticon", "gif"],
+                },
+            },
+            "pack": {
+                "display_name": "Party",
+                "usage": ["emoticon"],
+            },
+        });
+
+        let content: RoomImagePackEventContent = serde_json::from_value(json).unwrap();
+        let pack = &content.pack;
+        assert_eq!(pack.info.as_ref().unwrap().display_name.as_deref(), Some("Party"));
+        assert_eq!(pack.images["happy"].url.as_str(), "mxc://example.org/happy");
+        assert_eq!(pack.images["dance"].usage, [PackUsage::Emoticon, PackUsage::Unknown]);
+
+        let emoticons: Vec<_> = pack.emoticons().map(|(shortcode, _)| shortcode).collect();
+        assert_eq!(emoticons, ["dance", "happy"]);
+        let stickers: Vec<_> = pack.stickers().map(|(shortcode, _)| shortcode).collect();
+        assert_eq!(stickers, ["wave"]);
+
+        let json = serde_json::to_value(&content).unwrap();
+        assert_eq!(json["images"]["wave"]["body"], "Waving hand");
+        assert_eq!(json["pack"]["display_name"], "Party");
+    }
+}
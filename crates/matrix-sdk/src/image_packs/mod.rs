@@ -0,0 +1,358 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Custom emoticons and stickers, in image packs as described in [MSC2545].
+//!
+//! The packs can be found in:
+//!
+//! * the `im.ponies.user_emotes` account data, for the personal pack of the
+//!   user,
+//! * the `im.ponies.room_emotes` state events of a room, for the packs of the
+//!   room, the state key being the ID of the pack,
+//! * the `im.ponies.emote_rooms` account data, for the packs of other rooms
+//!   that the user enabled in every room.
+//!
+//! [`Emoticons`] gathers the emoticons that can be used in a room, to replace
+//! their `:shortcode:` with their image when rendering a message, and to react
+//! with them. [`RoomImagePacks`] allows the moderators of a room to manage its
+//! packs.
+//!
+//! [MSC2545]: https://github.com/matrix-org/matrix-spec-proposals/pull/2545
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use matrix_sdk_base::deserialized_responses::SyncOrStrippedState;
+use ruma::{events::SyncStateEvent, MxcUri, OwnedRoomId};
+use tracing::warn;
+
+use crate::{room, Client, Result};
+
+mod event;
+
+pub use self::event::{
+    EmoteRoomsEventContent, ImagePack, PackImage, PackInfo, PackUsage, RoomImagePackEventContent,
+    UserImagePackEventContent,
+};
+
+/// Where an image pack comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PackSource {
+    /// The personal pack of the user.
+    User,
+
+    /// A pack of a room.
+    Room {
+        /// The ID of the room.
+        room_id: OwnedRoomId,
+        /// The ID of the pack in the room.
+        pack_id: String,
+    },
+}
+
+/// The emoticons that can be used in a room.
+///
+/// When several emoticons have the same shortcode, the one of the pack of the
+/// user wins, then the ones of the packs of the room, then the ones of the
+/// packs enabled in every room.
+#[derive(Clone, Debug)]
+pub struct Emoticons {
+    packs: Vec<(PackSource, ImagePack)>,
+    emoticons: BTreeMap<String, PackImage>,
+}
+
+impl Emoticons {
+    /// Load the emoticons that can be used in the given room, or everywhere
+    /// if `room` is `None`.
+    pub async fn load(client: &Client, room: Option<&room::Common>) -> Result<Self> {
+        let account = client.account();
+        let mut packs = Vec::new();
+
+        if let Some(raw) = account.account_data::<UserImagePackEventContent>().await? {
+            match raw.deserialize() {
+                Ok(content) => packs.push((PackSource::User, content.pack)),
+                Err(error) => warn!(?error, "Couldn't deserialize the pack of the user"),
+            }
+        }
+
+        if let Some(room) = room {
+            packs.extend(room_packs(room, None).await?);
+        }
+
+        if let Some(raw) = account.account_data::<EmoteRoomsEventContent>().await? {
+            let rooms = match raw.deserialize() {
+                Ok(content) => content.rooms,
+                Err(error) => {
+                    warn!(?error, "Couldn't deserialize the packs enabled in every room");
+                    BTreeMap::new()
+                }
+            };
+
+            for (room_id, pack_ids) in rooms {
+                if room.is_some_and(|room| *room.room_id() == *room_id) {
+                    // The packs of the room were already added.
+                    continue;
+                }
+                let Some(other_room) = client.get_room(&room_id) else {
+                    continue;
+                };
+
+                let pack_ids = pack_ids.into_keys().collect::<Vec<_>>();
+                packs.extend(room_packs(&other_room, Some(&pack_ids)).await?);
+            }
+        }
+
+        Ok(Self::from_packs(packs))
+    }
+
+    /// Create an `Emoticons` from the given packs, ordered by priority.
+    pub fn from_packs(packs: Vec<(PackSource, ImagePack)>) -> Self {
+        let mut emoticons = BTreeMap::new();
+
+        for (_, pack) in &packs {
+            for (shortcode, image) in pack.emoticons() {
+                if let Entry::Vacant(entry) = emoticons.entry(shortcode.to_owned()) {
+                    entry.insert(image.clone());
+                }
+            }
+        }
+
+        Self { packs, emoticons }
+    }
+
+    /// The packs, ordered by priority, e.g. to show them in a picker.
+    pub fn packs(&self) -> &[(PackSource, ImagePack)] {
+        &self.packs
+    }
+
+    /// Get the emoticon with the given shortcode, without the surrounding
+    /// colons.
+    pub fn get(&self, shortcode: &str) -> Option<&PackImage> {
+        self.emoticons.get(shortcode)
+    }
+
+    /// Get the key to react with the emoticon with the given shortcode.
+    ///
+    /// Like other clients, the key of a reaction with a custom emoticon is
+    /// the `mxc://` URI of its image.
+    pub fn reaction_key(&self, shortcode: &str) -> Option<&MxcUri> {
+        self.get(shortcode).map(|image| &*image.url)
+    }
+
+    /// Split the body of a message into text and the emoticons of the
+    /// `:shortcode:`s it contains, to render them.
+    ///
+    /// The shortcodes that don't match an emoticon are left in the text.
+    pub fn annotate<'a>(&'a self, body: &'a str) -> Vec<BodySegment<'a>> {
+        let mut segments = Vec::new();
+        let mut text_start = 0;
+        let mut search_start = 0;
+
+        while let Some(start) = body[search_start..].find(':').map(|i| search_start + i) {
+            let Some(end) = body[start + 1..].find(':').map(|i| start + 1 + i) else {
+                break;
+            };
+
+            let shortcode = &body[start + 1..end];
+            match self.get(shortcode) {
+                Some(image) if !shortcode.contains(char::is_whitespace) => {
+                    if text_start < start {
+                        segments.push(BodySegment::Text(&body[text_start..start]));
+                    }
+                    segments.push(BodySegment::Emoticon { shortcode, image });
+
+                    text_start = end + 1;
+                    search_start = end + 1;
+                }
+                // The closing colon might open the next shortcode.
+                _ => search_start = end,
+            }
+        }
+
+        if text_start < body.len() {
+            segments.push(BodySegment::Text(&body[text_start..]));
+        }
+
+        segments
+    }
+}
+
+/// A segment of the body of a message, see [`Emoticons::annotate()`].
+#[derive(Clone, Debug)]
+pub enum BodySegment<'a> {
+    /// Some text.
+    Text(&'a str),
+
+    /// An emoticon.
+    Emoticon {
+        /// The shortcode of the emoticon, without the surrounding colons.
+        shortcode: &'a str,
+        /// The image of the emoticon.
+        image: &'a PackImage,
+    },
+}
+
+/// The image packs of a room, to manage them.
+///
+/// Only the users with the power level to send `im.ponies.room_emotes` state
+/// events, usually the moderators, can change the packs.
+#[derive(Clone, Debug)]
+pub struct RoomImagePacks {
+    room: room::Joined,
+}
+
+impl RoomImagePacks {
+    /// Get the image packs of the given room.
+    pub fn new(room: room::Joined) -> Self {
+        Self { room }
+    }
+
+    /// Get the packs of the room, by ID.
+    pub async fn get(&self) -> Result<BTreeMap<String, ImagePack>> {
+        Ok(room_packs(&self.room, None)
+            .await?
+            .into_iter()
+            .filter_map(|(source, pack)| match source {
+                PackSource::Room { pack_id, .. } => Some((pack_id, pack)),
+                PackSource::User => None,
+            })
+            .collect())
+    }
+
+    /// Get the pack of the room with the given ID.
+    pub async fn get_pack(&self, pack_id: &str) -> Result<Option<ImagePack>> {
+        let pack_ids = [pack_id.to_owned()];
+        Ok(room_packs(&self.room, Some(&pack_ids)).await?.pop().map(|(_, pack)| pack))
+    }
+
+    /// Replace the pack of the room with the given ID.
+    pub async fn set_pack(&self, pack_id: &str, pack: ImagePack) -> Result<()> {
+        let content = RoomImagePackEventContent::new(pack);
+        self.room.send_state_event_for_key(pack_id, content).await?;
+        Ok(())
+    }
+
+    /// Add an image to the pack of the room with the given ID, replacing the
+    /// image with the same shortcode.
+    ///
+    /// The pack is created if it doesn't exist.
+    pub async fn add_image(&self, pack_id: &str, shortcode: &str, image: PackImage) -> Result<()> {
+        let mut pack = self.get_pack(pack_id).await?.unwrap_or_default();
+        pack.images.insert(shortcode.to_owned(), image);
+        self.set_pack(pack_id, pack).await
+    }
+
+    /// Remove the image with the given shortcode from the pack of the room
+    /// with the given ID.
+    ///
+    /// Returns `false` if the pack doesn't have this image.
+    pub async fn remove_image(&self, pack_id: &str, shortcode: &str) -> Result<bool> {
+        let Some(mut pack) = self.get_pack(pack_id).await? else {
+            return Ok(false);
+        };
+        if pack.images.remove(shortcode).is_none() {
+            return Ok(false);
+        }
+
+        self.set_pack(pack_id, pack).await?;
+        Ok(true)
+    }
+}
+
+/// Get the packs of the given room, all of them or only the ones with the
+/// given IDs, ordered by ID.
+async fn room_packs(
+    room: &room::Common,
+    pack_ids: Option<&[String]>,
+) -> Result<Vec<(PackSource, ImagePack)>> {
+    let events = room.get_state_events_static::<RoomImagePackEventContent>().await?;
+
+    let mut packs = BTreeMap::new();
+    for event in events {
+        let event = match event.deserialize() {
+            Ok(SyncOrStrippedState::Sync(SyncStateEvent::Original(event))) => event,
+            Ok(_) => continue,
+            Err(error) => {
+                warn!(?error, room_id = ?room.room_id(), "Couldn't deserialize an image pack");
+                continue;
+            }
+        };
+
+        if pack_ids.is_some_and(|pack_ids| !pack_ids.contains(&event.state_key)) {
+            continue;
+        }
+        packs.insert(event.state_key, event.content.pack);
+    }
+
+    Ok(packs
+        .into_iter()
+        .map(|(pack_id, pack)| {
+            (PackSource::Room { room_id: room.room_id().to_owned(), pack_id }, pack)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use ruma::owned_room_id;
+
+    use super::{BodySegment, Emoticons, ImagePack, PackImage, PackSource};
+
+    fn pack(shortcodes: &[(&str, &str)]) -> ImagePack {
+        let mut pack = ImagePack::default();
+        for (shortcode, url) in shortcodes {
+            pack.images.insert((*shortcode).to_owned(), PackImage::new((*url).into()));
+        }
+        pack
+    }
+
+    #[test]
+    fn emoticons_priority() {
+        let emoticons = Emoticons::from_packs(vec![
+            (PackSource::User, pack(&[("happy", "mxc://example.org/mine")])),
+            (
+                PackSource::Room {
+                    room_id: owned_room_id!("!room:example.org"),
+                    pack_id: String::new(),
+                },
+                pack(&[("happy", "mxc://example.org/room"), ("sad", "mxc://example.org/sad")]),
+            ),
+        ]);
+
+        assert_eq!(emoticons.reaction_key("happy").unwrap().as_str(), "mxc://example.org/mine");
+        assert_eq!(emoticons.reaction_key("sad").unwrap().as_str(), "mxc://example.org/sad");
+        assert_eq!(emoticons.reaction_key("angry"), None);
+    }
+
+    #[test]
+    fn annotate_body() {
+        let emoticons = Emoticons::from_packs(vec![(
+            PackSource::User,
+            pack(&[("happy", "mxc://example.org/happy"), ("wave", "mxc://example.org/wave")]),
+        )]);
+
+        let segments = emoticons.annotate("Hi :wave: at 10:30: I'm :happy::happy:");
+        assert_eq!(segments.len(), 5);
+        assert_matches!(segments[0], BodySegment::Text("Hi "));
+        assert_matches!(segments[1], BodySegment::Emoticon { shortcode: "wave", image } => {
+            assert_eq!(image.url.as_str(), "mxc://example.org/wave");
+        });
+        assert_matches!(segments[2], BodySegment::Text(" at 10:30: I'm "));
+        assert_matches!(segments[3], BodySegment::Emoticon { shortcode: "happy", .. });
+        assert_matches!(segments[4], BodySegment::Emoticon { shortcode: "happy", .. });
+
+        let segments = emoticons.annotate(":unknown: text");
+        assert_matches!(segments.as_slice(), [BodySegment::Text(":unknown: text")]);
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The invites of the user, with the details of the rooms they are invited to.
+//!
+//! The details come from the stripped state that the homeserver sends with an
+//! invite, in a sync or a sliding sync response, so they are available before
+//! joining the room. They are observed with [`Client::invited_rooms_stream()`].
+
+use std::collections::BTreeSet;
+
+use futures_core::Stream;
+use ruma::{OwnedMxcUri, OwnedRoomAliasId, OwnedRoomId, OwnedUserId};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::{
+    room::{self, Invite, RoomMember},
+    Client, RoomState, StateChanges,
+};
+
+/// The user who sent an invite.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Inviter {
+    /// The ID of the user.
+    pub user_id: OwnedUserId,
+
+    /// The display name of the user in the room, if it is in the stripped
+    /// state of the invite.
+    pub display_name: Option<String>,
+
+    /// The avatar of the user in the room, if it is in the stripped state of
+    /// the invite.
+    pub avatar_url: Option<OwnedMxcUri>,
+}
+
+impl From<&RoomMember> for Inviter {
+    fn from(member: &RoomMember) -> Self {
+        Self {
+            user_id: member.user_id().to_owned(),
+            display_name: member.display_name().map(ToOwned::to_owned),
+            avatar_url: member.avatar_url().map(ToOwned::to_owned),
+        }
+    }
+}
+
+/// An invite to a room, with the details of the room.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RoomInvite {
+    /// The room, to accept or reject the invite.
+    pub room: room::Invited,
+
+    /// The user who sent the invite, if known.
+    pub inviter: Option<Inviter>,
+
+    /// The name of the room, computed like for joined rooms.
+    pub display_name: String,
+
+    /// The topic of the room.
+    pub topic: Option<String>,
+
+    /// The avatar of the room.
+    pub avatar_url: Option<OwnedMxcUri>,
+
+    /// The canonical alias of the room.
+    pub canonical_alias: Option<OwnedRoomAliasId>,
+
+    /// The number of joined members, if the homeserver sent it.
+    pub joined_members_count: u64,
+
+    /// Whether the invite is to a direct chat.
+    pub is_direct: bool,
+
+    /// Whether the room is encrypted.
+    pub is_encrypted: bool,
+
+    /// Whether the room is a space.
+    pub is_space: bool,
+}
+
+impl RoomInvite {
+    async fn load(room: room::Invited) -> Self {
+        let inviter = match room.invite_details().await {
+            Ok(Invite { inviter: Some(inviter), .. }) => Some(Inviter::from(&inviter)),
+            // The member event of the inviter isn't always in the stripped
+            // state.
+            Ok(Invite { invitee, inviter: None }) => Some(Inviter {
+                user_id: invitee.event().sender().to_owned(),
+                display_name: None,
+                avatar_url: None,
+            }),
+            Err(error) => {
+                warn!(room_id = ?room.room_id(), ?error, "Couldn't load the inviter");
+                None
+            }
+        };
+
+        let display_name = match room.display_name().await {
+            Ok(name) => name.to_string(),
+            Err(error) => {
+                warn!(room_id = ?room.room_id(), ?error, "Couldn't compute the room name");
+                room.room_id().to_string()
+            }
+        };
+        let is_direct = room.is_direct().await.unwrap_or_else(|error| {
+            warn!(room_id = ?room.room_id(), ?error, "Couldn't check if the invite is direct");
+            false
+        });
+
+        Self {
+            inviter,
+            display_name,
+            topic: room.topic(),
+            avatar_url: room.avatar_url(),
+            canonical_alias: room.canonical_alias(),
+            joined_members_count: room.joined_members_count(),
+            is_direct,
+            is_encrypted: room.is_encrypted(),
+            is_space: room.is_space(),
+            room,
+        }
+    }
+}
+
+/// Get a stream of the invites of the user, see
+/// [`Client::invited_rooms_stream()`].
+pub(crate) fn invites_stream(client: Client) -> impl Stream<Item = Vec<RoomInvite>> {
+    let mut state_changes = client.base_client().subscribe_to_state_changes();
+
+    async_stream::stream! {
+        let mut not_invited = BTreeSet::new();
+
+        loop {
+            let invites = load_invites(&client, &not_invited).await;
+            let invited: BTreeSet<_> =
+                invites.iter().map(|invite| invite.room.room_id().to_owned()).collect();
+            yield invites;
+
+            not_invited = loop {
+                match state_changes.recv().await {
+                    Ok(changes) => {
+                        if let Some(not_invited) = changed_invites(&changes, &invited) {
+                            break not_invited;
+                        }
+                    }
+                    // Some changes were missed, reload everything.
+                    Err(RecvError::Lagged(_)) => break BTreeSet::new(),
+                    Err(RecvError::Closed) => return,
+                }
+            };
+        }
+    }
+}
+
+/// Load the invites of the user, except for the given rooms.
+async fn load_invites(client: &Client, not_invited: &BTreeSet<OwnedRoomId>) -> Vec<RoomInvite> {
+    // Wait for the changes of the current sync to be applied to the rooms.
+    let _sync_lock = client.base_client().sync_lock().read().await;
+
+    let mut invites = Vec::new();
+    for room in client.invited_rooms() {
+        if !not_invited.contains(room.room_id()) {
+            invites.push(RoomInvite::load(room).await);
+        }
+    }
+
+    invites
+}
+
+/// Check whether the given changes affect the invites.
+///
+/// Returns the rooms that aren't invites anymore if they do, since the changes
+/// might not be applied to the rooms yet.
+fn changed_invites(
+    changes: &StateChanges,
+    invited: &BTreeSet<OwnedRoomId>,
+) -> Option<BTreeSet<OwnedRoomId>> {
+    let mut is_changed = !changes.stripped_state.is_empty();
+    let mut not_invited = BTreeSet::new();
+
+    for (room_id, info) in &changes.room_infos {
+        if info.state() == RoomState::Invited {
+            is_changed = true;
+        } else if invited.contains(room_id) {
+            is_changed = true;
+            not_invited.insert(room_id.clone());
+        }
+    }
+
+    is_changed.then_some(not_invited)
+}
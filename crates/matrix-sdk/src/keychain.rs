@@ -0,0 +1,177 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage of the passphrase of the stores in the keychain of the operating
+//! system.
+//!
+//! Desktop clients usually don't have a safe place to keep the passphrase used
+//! to encrypt the stores, and end up writing it in a plain text configuration
+//! file next to the stores. With a [`PassphraseStorage`], the passphrase is
+//! generated on first use and kept in a secure storage instead, by default the
+//! keychain of the operating system with [`OsKeychain`].
+//!
+//! The passphrase can be used directly with
+//! [`ClientBuilder::sqlite_store_with_keychain()`].
+//!
+//! [`ClientBuilder::sqlite_store_with_keychain()`]: crate::ClientBuilder::sqlite_store_with_keychain
+
+use std::{error::Error, fmt};
+
+use rand::{distributions::Alphanumeric, Rng};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The length of the generated passphrases.
+const PASSPHRASE_LENGTH: usize = 32;
+
+/// An error that occurred when accessing a [`PassphraseStorage`].
+#[derive(Debug, Error)]
+#[error("couldn't access the passphrase storage: {0}")]
+pub struct KeychainError(Box<dyn Error + Send + Sync>);
+
+impl KeychainError {
+    /// Create a new `KeychainError` from the error of the underlying storage.
+    pub fn new(error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self(error.into())
+    }
+}
+
+/// A secure storage for the passphrases of the stores.
+///
+/// Each passphrase is identified by an account name, e.g. the user ID or the
+/// path of the store, so several clients can share the same storage.
+pub trait PassphraseStorage: fmt::Debug + Send + Sync {
+    /// Get the passphrase of the given account, if there is one.
+    fn get_passphrase(&self, account: &str) -> Result<Option<String>, KeychainError>;
+
+    /// Set the passphrase of the given account, replacing the previous one.
+    fn set_passphrase(&self, account: &str, passphrase: &str) -> Result<(), KeychainError>;
+
+    /// Delete the passphrase of the given account, if there is one.
+    fn delete_passphrase(&self, account: &str) -> Result<(), KeychainError>;
+}
+
+/// Get the passphrase of the given account from the storage, or generate a
+/// new random one and save it if there is none.
+pub fn get_or_create_passphrase(
+    storage: &dyn PassphraseStorage,
+    account: &str,
+) -> Result<Zeroizing<String>, KeychainError> {
+    if let Some(passphrase) = storage.get_passphrase(account)? {
+        return Ok(Zeroizing::new(passphrase));
+    }
+
+    let passphrase: Zeroizing<String> = Zeroizing::new(
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(PASSPHRASE_LENGTH)
+            .map(char::from)
+            .collect(),
+    );
+    storage.set_passphrase(account, &passphrase)?;
+
+    Ok(passphrase)
+}
+
+/// A [`PassphraseStorage`] using the keychain of the operating system.
+///
+/// The passphrases are stored in the Keychain on macOS and iOS, in the
+/// Credential Manager on Windows, which protects them with DPAPI, and with the
+/// Secret Service on Linux, e.g. GNOME Keyring or KWallet through libsecret.
+#[derive(Clone, Debug)]
+pub struct OsKeychain {
+    service: String,
+}
+
+impl OsKeychain {
+    /// Create a new `OsKeychain`.
+    ///
+    /// The `service` is the name under which the passphrases are grouped in
+    /// the keychain, usually the name of the application.
+    pub fn new(service: impl Into<String>) -> Self {
+        Self { service: service.into() }
+    }
+
+    fn entry(&self, account: &str) -> Result<keyring::Entry, KeychainError> {
+        keyring::Entry::new(&self.service, account).map_err(KeychainError::new)
+    }
+}
+
+impl PassphraseStorage for OsKeychain {
+    fn get_passphrase(&self, account: &str) -> Result<Option<String>, KeychainError> {
+        match self.entry(account)?.get_password() {
+            Ok(passphrase) => Ok(Some(passphrase)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(error) => Err(KeychainError::new(error)),
+        }
+    }
+
+    fn set_passphrase(&self, account: &str, passphrase: &str) -> Result<(), KeychainError> {
+        self.entry(account)?.set_password(passphrase).map_err(KeychainError::new)
+    }
+
+    fn delete_passphrase(&self, account: &str) -> Result<(), KeychainError> {
+        match self.entry(account)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(error) => Err(KeychainError::new(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use super::{get_or_create_passphrase, KeychainError, PassphraseStorage};
+
+    #[derive(Debug, Default)]
+    struct MemoryStorage(Mutex<BTreeMap<String, String>>);
+
+    impl PassphraseStorage for MemoryStorage {
+        fn get_passphrase(&self, account: &str) -> Result<Option<String>, KeychainError> {
+            Ok(self.0.lock().unwrap().get(account).cloned())
+        }
+
+        fn set_passphrase(&self, account: &str, passphrase: &str) -> Result<(), KeychainError> {
+            self.0.lock().unwrap().insert(account.to_owned(), passphrase.to_owned());
+            Ok(())
+        }
+
+        fn delete_passphrase(&self, account: &str) -> Result<(), KeychainError> {
+            self.0.lock().unwrap().remove(account);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn passphrase_is_created_once() {
+        let storage = MemoryStorage::default();
+
+        let passphrase = get_or_create_passphrase(&storage, "@alice:localhost").unwrap();
+        assert_eq!(passphrase.len(), 32);
+        assert_eq!(
+            storage.get_passphrase("@alice:localhost").unwrap().as_deref(),
+            Some(passphrase.as_str())
+        );
+
+        let same = get_or_create_passphrase(&storage, "@alice:localhost").unwrap();
+        assert_eq!(same, passphrase);
+
+        let other = get_or_create_passphrase(&storage, "@bob:localhost").unwrap();
+        assert_ne!(other, passphrase);
+
+        storage.delete_passphrase("@alice:localhost").unwrap();
+        assert_eq!(storage.get_passphrase("@alice:localhost").unwrap(), None);
+    }
+}
@@ -27,21 +27,49 @@ pub use matrix_sdk_base::{
     RoomState, Session, StateChanges, StateStore, StoreError,
 };
 pub use matrix_sdk_common::*;
+#[cfg(feature = "sqlite")]
+pub use matrix_sdk_sqlite::{
+    JournalMode, PassphraseRotationProgress, PassphraseRotationStep, SqliteStoreConfig, Synchronous,
+};
 pub use reqwest;
 #[doc(no_inline)]
 pub use ruma;
 
 mod account;
 pub mod attachment;
+pub mod authentication;
+#[cfg(all(feature = "bot", not(target_arch = "wasm32")))]
+pub mod bot;
 mod client;
+pub mod client_registry;
 pub mod config;
+pub mod contacts;
+pub mod devices;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
 mod error;
 pub mod event_handler;
 mod http_client;
+#[cfg(feature = "image-packs")]
+pub mod image_packs;
+pub mod invites;
+#[cfg(feature = "keychain")]
+pub mod keychain;
+#[cfg(feature = "matrixrtc")]
+pub mod matrixrtc;
 pub mod media;
 pub mod notification_settings;
+pub mod presence;
 pub mod room;
+pub mod room_preview;
+pub mod spaces;
 pub mod sync;
+pub mod uiaa;
+pub mod uri;
+#[cfg(feature = "voip")]
+pub mod voip;
+#[cfg(feature = "experimental-widgets")]
+pub mod widget;
 
 #[cfg(feature = "experimental-sliding-sync")]
 pub mod sliding_sync;
@@ -49,16 +77,24 @@ pub mod sliding_sync;
 #[cfg(feature = "e2e-encryption")]
 pub mod encryption;
 
-pub use account::Account;
+pub use account::{Account, DeactivateOptions};
+#[cfg(feature = "sqlite")]
+pub use client::RotateStorePassphrase;
 #[cfg(feature = "sso-login")]
 pub use client::SsoLoginBuilder;
 pub use client::{
-    Client, ClientBuildError, ClientBuilder, LoginBuilder, LoopCtrl, SendRequest, UnknownToken,
+    Client, ClientBuildError, ClientBuilder, ClientWellKnown, LoginBuilder, LoopCtrl, SendRequest,
+    ServerCapabilities, SessionVerdict, UnknownToken, WellKnownError,
 };
 #[cfg(feature = "image-proc")]
 pub use error::ImageError;
-pub use error::{Error, HttpError, HttpResult, RefreshTokenError, Result, RumaApiError};
-pub use http_client::TransmissionProgress;
+pub use error::{
+    Error, HttpError, HttpResult, NotificationSettingsError, RefreshTokenError, Result,
+    RumaApiError,
+};
+pub use http_client::{
+    EndpointClass, HttpMiddleware, NetworkStatus, RateLimited, TransmissionProgress,
+};
 pub use media::Media;
 pub use ruma::{IdParseError, OwnedServerName, ServerName};
 #[cfg(feature = "experimental-sliding-sync")]
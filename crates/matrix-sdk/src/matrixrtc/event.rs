@@ -0,0 +1,202 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for the `org.matrix.msc3401.call.member` state event.
+
+use std::collections::BTreeMap;
+
+use ruma::{
+    events::macros::EventContent, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, UInt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The content of an `org.matrix.msc3401.call.member` state event.
+///
+/// The state key is the ID of the user, and the content lists the MatrixRTC
+/// sessions that the devices of the user are participating in.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.matrix.msc3401.call.member", kind = State, state_key_type = OwnedUserId)]
+pub struct CallMemberEventContent {
+    /// The memberships of the devices of the user.
+    #[serde(default)]
+    pub memberships: Vec<CallMembership>,
+}
+
+impl CallMemberEventContent {
+    /// Create a new `CallMemberEventContent` with the given memberships.
+    pub fn new(memberships: Vec<CallMembership>) -> Self {
+        Self { memberships }
+    }
+}
+
+/// The membership of a device in a MatrixRTC session.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CallMembership {
+    /// The application of the session, e.g. `m.call` for a call.
+    pub application: String,
+
+    /// The ID of the session, empty for the session of the whole room.
+    pub call_id: String,
+
+    /// Whether the session is for the whole room or for a user.
+    pub scope: CallScope,
+
+    /// The device participating in the session.
+    pub device_id: OwnedDeviceId,
+
+    /// The duration of the membership in milliseconds, from `created_ts`.
+    pub expires: u64,
+
+    /// When the device joined the session.
+    ///
+    /// The timestamp of the event is used if this is missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_ts: Option<MilliSecondsSinceUnixEpoch>,
+
+    /// The foci, like SFUs, used by the device for the session.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub foci_active: Vec<Focus>,
+
+    /// A random ID for this membership, that changes when the device joins
+    /// the session again.
+    #[serde(rename = "membershipID")]
+    pub membership_id: String,
+}
+
+impl CallMembership {
+    /// When the device joined the session, given the timestamp of the event
+    /// containing the membership.
+    pub fn created_at(
+        &self,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+    ) -> MilliSecondsSinceUnixEpoch {
+        self.created_ts.unwrap_or(origin_server_ts)
+    }
+
+    /// When the membership expires, given the timestamp of the event
+    /// containing the membership.
+    pub fn expires_at(
+        &self,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+    ) -> MilliSecondsSinceUnixEpoch {
+        let created_at = self.created_at(origin_server_ts);
+        MilliSecondsSinceUnixEpoch(created_at.0.saturating_add(UInt::new_saturating(self.expires)))
+    }
+
+    /// Whether the membership is for the given session.
+    pub fn is_for_session(&self, application: &str, call_id: &str) -> bool {
+        self.application == application && self.call_id == call_id
+    }
+}
+
+/// The scope of a MatrixRTC session.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CallScope {
+    /// The session is for the whole room.
+    #[serde(rename = "m.room")]
+    Room,
+
+    /// The session is for some users of the room.
+    #[serde(rename = "m.user")]
+    User,
+}
+
+/// A focus used by a device for a MatrixRTC session, like a LiveKit SFU.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Focus {
+    /// The type of the focus, e.g. `livekit`.
+    #[serde(rename = "type")]
+    pub focus_type: String,
+
+    /// The data of the focus, depending on its type.
+    #[serde(flatten)]
+    pub data: BTreeMap<String, Value>,
+}
+
+impl Focus {
+    /// Create a LiveKit focus using the LiveKit JWT service at the given URL.
+    ///
+    /// The alias of the LiveKit room is the ID of the Matrix room.
+    pub fn livekit(service_url: String, alias: String) -> Self {
+        Self {
+            focus_type: "livekit".to_owned(),
+            data: BTreeMap::from([
+                ("livekit_service_url".to_owned(), service_url.into()),
+                ("livekit_alias".to_owned(), alias.into()),
+            ]),
+        }
+    }
+
+    /// The URL of the LiveKit JWT service, if this is a LiveKit focus.
+    pub fn livekit_service_url(&self) -> Option<&str> {
+        self.livekit_field("livekit_service_url")
+    }
+
+    /// The alias of the LiveKit room, if this is a LiveKit focus.
+    pub fn livekit_alias(&self) -> Option<&str> {
+        self.livekit_field("livekit_alias")
+    }
+
+    fn livekit_field(&self, field: &str) -> Option<&str> {
+        if self.focus_type != "livekit" {
+            return None;
+        }
+
+        self.data.get(field).and_then(Value::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{device_id, uint, MilliSecondsSinceUnixEpoch};
+    use serde_json::json;
+
+    use super::{CallMemberEventContent, CallScope};
+
+    #[test]
+    fn call_member_serialization() {
+        let json = json!({
+            "memberships": [{
+                "application": "m.call",
+                "call_id": "",
+                "scope": "m.room",
+                "device_id": "ABCDEF",
+                "expires": 3_600_000,
+                "created_ts": 1_000,
+                "foci_active": [{
+                    "type": "livekit",
+                    "livekit_service_url": "https://livekit.example.org",
+                    "livekit_alias": "!room:example.org",
+                }],
+                "membershipID": "abcd",
+            }],
+        });
+
+        let content: CallMemberEventContent = serde_json::from_value(json.clone()).unwrap();
+        let membership = &content.memberships[0];
+        assert_eq!(membership.scope, CallScope::Room);
+        assert_eq!(membership.device_id, device_id!("ABCDEF"));
+        assert_eq!(
+            membership.expires_at(MilliSecondsSinceUnixEpoch(uint!(5_000))),
+            MilliSecondsSinceUnixEpoch(uint!(3_601_000))
+        );
+        assert_eq!(
+            membership.foci_active[0].livekit_service_url(),
+            Some("https://livekit.example.org")
+        );
+
+        assert_eq!(serde_json::to_value(&content).unwrap(), json);
+    }
+}
@@ -0,0 +1,597 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MatrixRTC sessions, like group calls, as described in [MSC3401] and
+//! [MSC4143].
+//!
+//! The devices participating in a session publish their membership in an
+//! `org.matrix.msc3401.call.member` state event, with the state key being the
+//! ID of their user. The memberships expire after some time, so they must be
+//! refreshed while the device is in the session.
+//!
+//! The media is handled by an external stack, like a LiveKit SFU, that can be
+//! connected to with the data of [`RtcSession::connection_info()`].
+//!
+//! [MSC3401]: https://github.com/matrix-org/matrix-spec-proposals/pull/3401
+//! [MSC4143]: https://github.com/matrix-org/matrix-spec-proposals/pull/4143
+
+use std::{fmt, time::Duration};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use futures_util::future::{self, Either};
+use matrix_sdk_base::deserialized_responses::SyncOrStrippedState;
+use matrix_sdk_common::{
+    executor::{spawn, JoinHandle},
+    sleep::sleep,
+};
+use ruma::{
+    api::client::{
+        account::request_openid_token, error::ErrorKind, state::get_state_events_for_key,
+    },
+    events::{AnySyncStateEvent, StateEventType, StaticEventContent, SyncStateEvent},
+    MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedRoomId, OwnedServerName, OwnedUserId,
+    TransactionId,
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, warn};
+
+use crate::{room, sync::RoomUpdate, Error, Result};
+
+mod event;
+
+pub use self::event::{
+    CallMemberEvent, CallMemberEventContent, CallMembership, CallScope, Focus,
+    OriginalSyncCallMemberEvent, SyncCallMemberEvent,
+};
+
+/// The application of calls.
+const CALL_APPLICATION: &str = "m.call";
+
+/// The default duration of a membership, before it needs to be refreshed.
+const DEFAULT_MEMBERSHIP_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// A MatrixRTC session in a room, e.g. the call of the room.
+#[derive(Clone)]
+pub struct RtcSession {
+    room: room::Joined,
+    application: String,
+    call_id: String,
+}
+
+impl RtcSession {
+    /// Get the call of the whole room.
+    pub fn room_call(room: room::Joined) -> Self {
+        Self::new(room, CALL_APPLICATION.to_owned(), String::new())
+    }
+
+    /// Get the session with the given application and ID in the room.
+    pub fn new(room: room::Joined, application: String, call_id: String) -> Self {
+        Self { room, application, call_id }
+    }
+
+    /// The room of the session.
+    pub fn room(&self) -> &room::Joined {
+        &self.room
+    }
+
+    /// Get the devices that are currently participating in the session,
+    /// ordered by the time they joined it.
+    ///
+    /// The expired memberships are ignored.
+    pub async fn participants(&self) -> Result<Vec<CallParticipant>> {
+        let events = self.room.get_state_events_static::<CallMemberEventContent>().await?;
+        let now = MilliSecondsSinceUnixEpoch::now();
+
+        let mut participants = Vec::new();
+        for event in events {
+            let event = match event.deserialize() {
+                Ok(SyncOrStrippedState::Sync(SyncStateEvent::Original(event))) => event,
+                Ok(_) => continue,
+                Err(error) => {
+                    warn!(?error, "Couldn't deserialize a call member event");
+                    continue;
+                }
+            };
+
+            for membership in event.content.memberships {
+                if !membership.is_for_session(&self.application, &self.call_id)
+                    || membership.expires_at(event.origin_server_ts) <= now
+                {
+                    continue;
+                }
+
+                participants.push(CallParticipant {
+                    user_id: event.state_key.clone(),
+                    device_id: membership.device_id.clone(),
+                    joined_at: membership.created_at(event.origin_server_ts),
+                    expires_at: membership.expires_at(event.origin_server_ts),
+                    membership,
+                });
+            }
+        }
+
+        participants.sort_by_key(|participant| participant.joined_at);
+        Ok(participants)
+    }
+
+    /// Get an observable list of the devices participating in the session.
+    ///
+    /// See [`CallParticipants`] for more details.
+    pub async fn subscribe_to_participants(&self) -> Result<CallParticipants> {
+        CallParticipants::new(self.clone()).await
+    }
+
+    /// Join the session with the current device, using the given foci.
+    ///
+    /// The membership is refreshed before it expires until
+    /// [`CallMembershipHandle::leave()`] is called.
+    pub async fn join(&self, foci: Vec<Focus>) -> Result<CallMembershipHandle> {
+        self.join_with_expiry(foci, DEFAULT_MEMBERSHIP_EXPIRY).await
+    }
+
+    /// Join the session with the current device, using the given foci, with
+    /// memberships lasting `expiry`.
+    ///
+    /// A short expiry makes the membership disappear sooner if the device
+    /// goes away without leaving, but needs to be refreshed more often.
+    pub async fn join_with_expiry(
+        &self,
+        foci: Vec<Focus>,
+        expiry: Duration,
+    ) -> Result<CallMembershipHandle> {
+        let device_id = self.room.client.device_id().ok_or(Error::AuthenticationRequired)?;
+
+        let membership = CallMembership {
+            application: self.application.clone(),
+            call_id: self.call_id.clone(),
+            scope: CallScope::Room,
+            device_id: device_id.to_owned(),
+            expires: expiry.as_millis().try_into().unwrap_or(u64::MAX),
+            created_ts: Some(MilliSecondsSinceUnixEpoch::now()),
+            foci_active: foci,
+            // Any unique string works.
+            membership_id: TransactionId::new().to_string(),
+        };
+
+        self.update_own_membership(Some(membership.clone())).await?;
+
+        let task = spawn(refresh_membership(self.clone(), membership, expiry));
+        Ok(CallMembershipHandle { session: self.clone(), task })
+    }
+
+    /// Get the data needed to connect to the media stack of the session.
+    ///
+    /// The focus is the first one of the device that joined the session
+    /// first, so all the devices use the same one.
+    pub async fn connection_info(&self) -> Result<CallConnectionInfo> {
+        let client = &self.room.client;
+        let user_id = client.user_id().ok_or(Error::AuthenticationRequired)?;
+        let device_id = client.device_id().ok_or(Error::AuthenticationRequired)?;
+
+        let focus = self
+            .participants()
+            .await?
+            .into_iter()
+            .find_map(|participant| participant.membership.foci_active.into_iter().next());
+
+        let request = request_openid_token::v3::Request::new(user_id.to_owned());
+        let response = client.send(request, None).await?;
+
+        Ok(CallConnectionInfo {
+            room_id: self.room.room_id().to_owned(),
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+            focus,
+            openid_token: OpenIdToken {
+                access_token: response.access_token,
+                matrix_server_name: response.matrix_server_name,
+                expires_in: response.expires_in,
+            },
+        })
+    }
+
+    /// Replace the membership of the current device in this session, keeping
+    /// the other memberships of the user that haven't expired.
+    async fn update_own_membership(&self, membership: Option<CallMembership>) -> Result<()> {
+        let client = &self.room.client;
+        let user_id = client.user_id().ok_or(Error::AuthenticationRequired)?;
+        let device_id = client.device_id().ok_or(Error::AuthenticationRequired)?;
+        let room_id = self.room.room_id();
+
+        // The memberships of all the sessions of the user are in the same event,
+        // so concurrent updates would overwrite each other.
+        let lock = client.inner.call_member_locks.entry(room_id.to_owned()).or_default().clone();
+        let _guard = lock.lock().await;
+
+        // The local state doesn't contain our previous update until it comes
+        // back in a sync, so the current memberships are fetched from the
+        // homeserver.
+        let request = get_state_events_for_key::v3::Request::new(
+            room_id.to_owned(),
+            StateEventType::from(CallMemberEventContent::TYPE),
+            user_id.to_string(),
+        );
+        let content = match client.send(request, None).await {
+            Ok(response) => Some(response.content.deserialize_as::<CallMemberEventContent>()?),
+            Err(error) if error.client_api_error_kind() == Some(&ErrorKind::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
+
+        // The response doesn't contain the timestamp of the event, which is
+        // only needed for the memberships without a creation timestamp. Use the
+        // one of the event we know about.
+        let origin_server_ts = match self
+            .room
+            .get_state_event_static_for_key::<CallMemberEventContent, _>(user_id)
+            .await?
+            .and_then(|event| event.deserialize().ok())
+        {
+            Some(SyncOrStrippedState::Sync(SyncStateEvent::Original(event))) => {
+                event.origin_server_ts
+            }
+            _ => MilliSecondsSinceUnixEpoch::now(),
+        };
+
+        let now = MilliSecondsSinceUnixEpoch::now();
+        let mut memberships: Vec<_> = content
+            .map(|content| content.memberships)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|m| {
+                m.expires_at(origin_server_ts) > now
+                    && !(m.device_id == device_id
+                        && m.is_for_session(&self.application, &self.call_id))
+            })
+            .collect();
+        memberships.extend(membership);
+
+        self.room
+            .send_state_event_for_key(user_id, CallMemberEventContent::new(memberships))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for RtcSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RtcSession")
+            .field("room_id", &self.room.room_id())
+            .field("application", &self.application)
+            .field("call_id", &self.call_id)
+            .finish()
+    }
+}
+
+/// Refresh the membership of the current device before it expires.
+async fn refresh_membership(session: RtcSession, mut membership: CallMembership, expiry: Duration) {
+    let created_at = membership.created_ts.unwrap_or_else(MilliSecondsSinceUnixEpoch::now);
+
+    loop {
+        // Leave some margin for the request to go through.
+        sleep(expiry * 3 / 4).await;
+
+        // The membership lasts `expiry` from now.
+        let elapsed =
+            u64::from(MilliSecondsSinceUnixEpoch::now().0).saturating_sub(u64::from(created_at.0));
+        membership.expires =
+            elapsed.saturating_add(expiry.as_millis().try_into().unwrap_or(u64::MAX));
+
+        if let Err(error) = session.update_own_membership(Some(membership.clone())).await {
+            warn!(?error, "Couldn't refresh the call membership");
+        }
+    }
+}
+
+/// A handle to the membership of the current device in a [`RtcSession`].
+///
+/// Dropping the handle stops refreshing the membership, which will then
+/// expire, but the device should rather leave the session explicitly with
+/// [`CallMembershipHandle::leave()`].
+pub struct CallMembershipHandle {
+    session: RtcSession,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    task: JoinHandle<()>,
+}
+
+impl CallMembershipHandle {
+    /// The session that was joined.
+    pub fn session(&self) -> &RtcSession {
+        &self.session
+    }
+
+    /// Leave the session, by removing the membership of the current device.
+    pub async fn leave(self) -> Result<()> {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.task.abort();
+
+        self.session.update_own_membership(None).await
+    }
+}
+
+impl Drop for CallMembershipHandle {
+    fn drop(&mut self) {
+        // The task is aborted when its handle is dropped on WASM.
+        #[cfg(not(target_arch = "wasm32"))]
+        self.task.abort();
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for CallMembershipHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallMembershipHandle")
+            .field("session", &self.session)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A device participating in a [`RtcSession`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CallParticipant {
+    /// The user of the device.
+    pub user_id: OwnedUserId,
+    /// The device.
+    pub device_id: OwnedDeviceId,
+    /// When the device joined the session.
+    pub joined_at: MilliSecondsSinceUnixEpoch,
+    /// When the membership of the device expires, if it isn't refreshed.
+    pub expires_at: MilliSecondsSinceUnixEpoch,
+    /// The membership of the device.
+    pub membership: CallMembership,
+}
+
+impl CallParticipant {
+    /// How long until the membership expires.
+    fn expires_in(&self) -> Duration {
+        let expires_at = u64::from(self.expires_at.0);
+        let now = u64::from(MilliSecondsSinceUnixEpoch::now().0);
+        Duration::from_millis(expires_at.saturating_sub(now))
+    }
+}
+
+/// An observable list of the devices participating in a [`RtcSession`].
+///
+/// It can be created with [`RtcSession::subscribe_to_participants()`]. The
+/// list is updated with the call member events received in the sync, and when
+/// memberships expire.
+///
+/// The background task updating the list is stopped when the list is dropped.
+pub struct CallParticipants {
+    participants: SharedObservable<Vec<CallParticipant>>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    task: JoinHandle<()>,
+}
+
+impl CallParticipants {
+    async fn new(session: RtcSession) -> Result<Self> {
+        // Subscribe before loading the participants, so no update is missed.
+        let updates = session.room.subscribe_to_updates();
+        let participants = SharedObservable::new(session.participants().await?);
+
+        let task = spawn(update_participants(session, participants.clone(), updates));
+
+        Ok(Self { participants, task })
+    }
+
+    /// Get the current participants.
+    pub fn get(&self) -> Vec<CallParticipant> {
+        self.participants.get()
+    }
+
+    /// Get a subscriber to observe the changes of the participants.
+    pub fn subscribe(&self) -> Subscriber<Vec<CallParticipant>> {
+        self.participants.subscribe()
+    }
+}
+
+impl Drop for CallParticipants {
+    fn drop(&mut self) {
+        // The task is aborted when its handle is dropped on WASM.
+        #[cfg(not(target_arch = "wasm32"))]
+        self.task.abort();
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for CallParticipants {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallParticipants").field("len", &self.participants.get().len()).finish()
+    }
+}
+
+async fn update_participants(
+    session: RtcSession,
+    participants: SharedObservable<Vec<CallParticipant>>,
+    mut updates: Receiver<RoomUpdate>,
+) {
+    loop {
+        let next_expiry =
+            participants.get().iter().map(|participant| participant.expires_in()).min();
+        let expired = match next_expiry {
+            Some(delay) => Either::Left(sleep(delay)),
+            None => Either::Right(future::pending()),
+        };
+
+        let reload = match future::select(Box::pin(updates.recv()), Box::pin(expired)).await {
+            Either::Left((Ok(update), _)) => has_call_member_changes(&update),
+            Either::Left((Err(RecvError::Lagged(count)), _)) => {
+                debug!(count, "Missed room updates, reloading the call participants");
+                true
+            }
+            Either::Left((Err(RecvError::Closed), _)) => break,
+            // A membership expired.
+            Either::Right(_) => true,
+        };
+
+        if reload {
+            match session.participants().await {
+                Ok(list) => participants.set(list),
+                Err(error) => warn!(?error, "Couldn't update the call participants"),
+            }
+        }
+    }
+}
+
+/// Whether the given update contains call member events.
+fn has_call_member_changes(update: &RoomUpdate) -> bool {
+    let (state, timeline) = match update {
+        RoomUpdate::Joined { updates, .. } => (&updates.state, &updates.timeline),
+        RoomUpdate::Left { updates, .. } => (&updates.state, &updates.timeline),
+        RoomUpdate::Invited { .. } => return false,
+    };
+
+    state
+        .iter()
+        .chain(timeline.events.iter().map(|event| event.event.cast_ref::<AnySyncStateEvent>()))
+        .any(|event| {
+            matches!(
+                event.get_field::<StateEventType>("type"),
+                Ok(Some(event_type)) if event_type == StateEventType::from(CallMemberEventContent::TYPE)
+            )
+        })
+}
+
+/// The data needed to connect to the media stack of a [`RtcSession`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CallConnectionInfo {
+    /// The room of the session.
+    pub room_id: OwnedRoomId,
+    /// The user of the current device.
+    pub user_id: OwnedUserId,
+    /// The current device.
+    pub device_id: OwnedDeviceId,
+    /// The focus to connect to, if any participant advertised one.
+    pub focus: Option<Focus>,
+    /// An OpenID token to prove the identity of the user to the focus, e.g.
+    /// to the LiveKit JWT service.
+    pub openid_token: OpenIdToken,
+}
+
+/// An OpenID token, that can be used to prove the identity of the user to a
+/// third party.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct OpenIdToken {
+    /// The token, that the third party can check with the homeserver.
+    pub access_token: String,
+    /// The homeserver that the third party should check the token with.
+    pub matrix_server_name: OwnedServerName,
+    /// How long the token is valid.
+    pub expires_in: Duration,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for OpenIdToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenIdToken")
+            .field("matrix_server_name", &self.matrix_server_name)
+            .field("expires_in", &self.expires_in)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures_util::future::join_all;
+    use matrix_sdk_test::{async_test, EventBuilder, JoinedRoomBuilder};
+    use ruma::room_id;
+    use serde_json::{json, Value as JsonValue};
+    use wiremock::{
+        matchers::{method, path_regex},
+        Mock, MockServer, Request, Respond, ResponseTemplate,
+    };
+
+    use super::{CallMemberEventContent, RtcSession};
+    use crate::test_utils::logged_in_client;
+
+    /// A homeserver keeping the last call member event sent by the user.
+    #[derive(Clone, Default)]
+    struct CallMemberState(Arc<Mutex<Option<JsonValue>>>);
+
+    impl Respond for CallMemberState {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let mut state = self.0.lock().unwrap();
+
+            if request.method == wiremock::http::Method::Put {
+                *state = Some(request.body_json().unwrap());
+                return ResponseTemplate::new(200)
+                    .set_body_json(json!({ "event_id": "$call_member" }));
+            }
+
+            match &*state {
+                Some(content) => ResponseTemplate::new(200).set_body_json(content),
+                None => ResponseTemplate::new(404).set_body_json(json!({
+                    "errcode": "M_NOT_FOUND",
+                    "error": "Event not found.",
+                })),
+            }
+        }
+    }
+
+    #[async_test]
+    async fn concurrent_joins_keep_all_memberships() {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let room_id = room_id!("!call:localhost");
+
+        let response = EventBuilder::default()
+            .add_joined_room(JoinedRoomBuilder::new(room_id))
+            .build_sync_response();
+        client.process_sync(response).await.unwrap();
+        let room = client.get_joined_room(room_id).unwrap();
+
+        let state = CallMemberState::default();
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/client/r0/rooms/.*/state/org.matrix.msc3401.call.member/"))
+            .respond_with(state.clone())
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex(r"^/_matrix/client/r0/rooms/.*/state/org.matrix.msc3401.call.member/"))
+            .respond_with(state.clone())
+            .mount(&server)
+            .await;
+
+        let sessions: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|call_id| RtcSession::new(room.clone(), "m.call".to_owned(), call_id.to_owned()))
+            .collect();
+        let handles: Vec<_> = join_all(sessions.iter().map(|session| session.join(Vec::new())))
+            .await
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        // Every update saw the previous ones.
+        let content: CallMemberEventContent =
+            serde_json::from_value(state.0.lock().unwrap().clone().unwrap()).unwrap();
+        let mut call_ids: Vec<_> = content.memberships.iter().map(|m| m.call_id.as_str()).collect();
+        call_ids.sort_unstable();
+        assert_eq!(call_ids, ["a", "b", "c"]);
+
+        // Leaving only removes the membership of the session.
+        let mut handles = handles.into_iter();
+        handles.next().unwrap().leave().await.unwrap();
+        let content: CallMemberEventContent =
+            serde_json::from_value(state.0.lock().unwrap().clone().unwrap()).unwrap();
+        assert_eq!(content.memberships.len(), 2);
+        assert!(content.memberships.iter().all(|m| m.call_id != "a"));
+    }
+}
@@ -0,0 +1,553 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resumable downloads of big media files.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use http::StatusCode;
+use matrix_sdk_common::{
+    executor::{spawn, JoinHandle},
+    sleep::sleep,
+};
+use ruma::{api::client::media::get_content, events::room::MediaSource, MxcUri};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::{debug, instrument, warn};
+
+use crate::{Client, Error, HttpError, Result, RumaApiError, TransmissionProgress};
+
+/// The extension of the files of the partial downloads.
+const PARTIAL_EXTENSION: &str = "part";
+
+/// The delay before retrying a failed download for the first time. It is
+/// doubled after every failure.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The maximum delay before retrying a failed download.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// A manager of resumable media downloads, obtained with
+/// [`Media::downloads()`](super::Media::downloads).
+///
+/// The files are downloaded in a directory chosen by the app. While a file is
+/// downloaded, the received data is written in a partial file, so an
+/// interrupted download can be resumed later, even after the app was
+/// restarted, with a range request if the homeserver supports them. The
+/// downloads that fail because of the network or of the homeserver are
+/// retried automatically.
+///
+/// The same manager should be used for all the downloads in a directory, so
+/// the same file is not downloaded twice at the same time.
+#[derive(Debug, Clone)]
+pub struct MediaDownloads {
+    client: Client,
+    dir: PathBuf,
+    max_retries: u32,
+    /// The locks of the files being downloaded.
+    locks: Arc<DashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+impl MediaDownloads {
+    pub(crate) fn new(client: Client, dir: PathBuf) -> Self {
+        Self { client, dir, max_retries: 5, locks: Default::default() }
+    }
+
+    /// Set the maximum number of times a failed download is retried.
+    ///
+    /// Defaults to 5.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Start downloading the file of the given media source in the background.
+    ///
+    /// If the file was already downloaded, the download completes immediately.
+    /// If it was partially downloaded, the download is resumed.
+    ///
+    /// If the source is encrypted and encryption is enabled, the file is
+    /// decrypted once it is completely downloaded.
+    pub fn download(&self, source: &MediaSource) -> MediaDownload {
+        let progress = SharedObservable::<TransmissionProgress>::default();
+        let task = spawn({
+            let this = self.clone();
+            let source = source.clone();
+            let progress = progress.clone();
+            async move { this.run(source, progress).await }
+        });
+
+        MediaDownload { progress, task }
+    }
+
+    /// Get the path of the downloaded file of the given media source, if it was
+    /// completely downloaded.
+    pub async fn get(&self, source: &MediaSource) -> Result<Option<PathBuf>> {
+        let path = self.path(source_uri(source))?;
+        Ok(fs::try_exists(&path).await?.then_some(path))
+    }
+
+    /// Remove the downloaded file, or the partial file, of the given media
+    /// source.
+    pub async fn remove(&self, source: &MediaSource) -> Result<()> {
+        let path = self.path(source_uri(source))?;
+        let lock = self.lock(&path);
+        let _guard = lock.lock().await;
+
+        for path in [partial_path(&path), path] {
+            match fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The path of the downloaded file of the given URI.
+    ///
+    /// The files of each server are in their own directory, and the names are
+    /// escaped without ambiguity, so two URIs never share a file.
+    fn path(&self, uri: &MxcUri) -> Result<PathBuf> {
+        let (server_name, media_id) = uri.parts()?;
+        Ok(self.dir.join(escape_file_name(server_name.as_str())).join(escape_file_name(media_id)))
+    }
+
+    fn lock(&self, path: &Path) -> Arc<Mutex<()>> {
+        self.locks.entry(path.to_owned()).or_default().clone()
+    }
+
+    #[instrument(skip(self, progress), fields(uri = %source_uri(&source)))]
+    async fn run(
+        &self,
+        source: MediaSource,
+        progress: SharedObservable<TransmissionProgress>,
+    ) -> Result<PathBuf> {
+        let path = self.path(source_uri(&source))?;
+        let lock = self.lock(&path);
+        let _guard = lock.lock().await;
+
+        if fs::try_exists(&path).await? {
+            debug!("The file was already downloaded");
+            let len = fs::metadata(&path).await?.len().try_into().unwrap_or(usize::MAX);
+            progress.set(TransmissionProgress { current: len, total: len });
+            return Ok(path);
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let partial_path = partial_path(&path);
+
+        let mut retries = 0;
+        let mut delay = INITIAL_RETRY_DELAY;
+
+        loop {
+            match self.download_to(source_uri(&source), &partial_path, &progress).await {
+                Ok(()) => break,
+                Err(error) if retries < self.max_retries && is_transient(&error) => {
+                    retries += 1;
+                    warn!(?error, retries, "The download failed, retrying in {delay:?}");
+                    sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        match &source {
+            #[cfg(feature = "e2e-encryption")]
+            MediaSource::Encrypted(file) => {
+                let file = file.as_ref().clone();
+                let (partial, dest) = (partial_path.clone(), path.clone());
+
+                // The hash of the file is only checked at the end, so the file
+                // is decrypted in a temporary file that is only moved to its
+                // final path if it is valid. The temporary file is removed on
+                // error.
+                let decrypted = tokio::task::spawn_blocking(move || -> Result<()> {
+                    let mut encrypted = std::fs::File::open(&partial)?;
+                    let mut reader = matrix_sdk_base::crypto::AttachmentDecryptor::new(
+                        &mut encrypted,
+                        file.into(),
+                    )?;
+                    let dir = dest.parent().expect("the file is in a directory");
+                    let mut decrypted = tempfile::NamedTempFile::new_in(dir)?;
+                    std::io::copy(&mut reader, &mut decrypted)?;
+                    decrypted.persist(&dest).map_err(|error| error.error)?;
+                    Ok(())
+                })
+                .await
+                .expect("the decryption task panicked");
+
+                // The encrypted file is useless whether it could be decrypted
+                // or not, an invalid file is downloaded again next time.
+                fs::remove_file(&partial_path).await?;
+                decrypted?;
+            }
+            _ => fs::rename(&partial_path, &path).await?,
+        }
+
+        Ok(path)
+    }
+
+    /// Download the file of the given URI in the partial file, from the end
+    /// of the partial file if the homeserver supports range requests.
+    async fn download_to(
+        &self,
+        uri: &MxcUri,
+        partial_path: &Path,
+        progress: &SharedObservable<TransmissionProgress>,
+    ) -> Result<()> {
+        let offset = match fs::metadata(partial_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(error.into()),
+        };
+
+        let request = get_content::v3::Request::from_url(uri)?;
+        if offset > 0 {
+            debug!(offset, "Resuming the download");
+        }
+
+        let mut response = match self.client.send_streamed(request, offset, None).await {
+            Ok(response) => response,
+            // The partial file is bigger than the media, it can't be resumed.
+            Err(error) if error_status(&error) == Some(StatusCode::RANGE_NOT_SATISFIABLE) => {
+                fs::remove_file(partial_path).await?;
+                return Err(Error::IncompleteDownload);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut file = if response.status() == StatusCode::PARTIAL_CONTENT {
+            OpenOptions::new().append(true).open(partial_path).await?
+        } else {
+            // The homeserver doesn't support range requests, start from the
+            // beginning.
+            fs::File::create(partial_path).await?
+        };
+
+        let mut current = if response.status() == StatusCode::PARTIAL_CONTENT { offset } else { 0 };
+        let total = response.content_length().map(|len| current + len);
+
+        let to_usize = |n: u64| usize::try_from(n).unwrap_or(usize::MAX);
+        progress.set(TransmissionProgress {
+            current: to_usize(current),
+            total: total.map_or(0, to_usize),
+        });
+
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            current += chunk.len() as u64;
+            progress.update(|p| p.current = to_usize(current));
+        }
+
+        file.flush().await?;
+
+        if total.is_some_and(|total| current < total) {
+            // The connection was closed before the end of the body.
+            return Err(Error::IncompleteDownload);
+        }
+
+        Ok(())
+    }
+}
+
+/// A download started with [`MediaDownloads::download()`].
+///
+/// Await [`MediaDownload::wait()`] to get the path of the downloaded file.
+/// If this is dropped, the download continues in the background.
+#[derive(Debug)]
+pub struct MediaDownload {
+    progress: SharedObservable<TransmissionProgress>,
+    task: JoinHandle<Result<PathBuf>>,
+}
+
+impl MediaDownload {
+    /// Subscribe to the progress of the download.
+    ///
+    /// The total is `0` until the size of the file is known.
+    pub fn subscribe_to_progress(&self) -> Subscriber<TransmissionProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Cancel the download.
+    ///
+    /// The partial file is kept, so the download can be resumed later.
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
+
+    /// Wait for the end of the download, and get the path of the downloaded
+    /// file.
+    ///
+    /// Returns [`Error::DownloadCancelled`] if the download was cancelled.
+    pub async fn wait(self) -> Result<PathBuf> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(error) if error.is_cancelled() => Err(Error::DownloadCancelled),
+            Err(error) => std::panic::resume_unwind(error.into_panic()),
+        }
+    }
+}
+
+fn source_uri(source: &MediaSource) -> &MxcUri {
+    match source {
+        MediaSource::Plain(uri) => uri,
+        MediaSource::Encrypted(file) => &file.url,
+    }
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    path.with_extension(PARTIAL_EXTENSION)
+}
+
+/// Escape the given string to use it as a file name.
+///
+/// ASCII alphanumeric characters and `-` are kept, and every other byte is
+/// replaced by `_` followed by its hexadecimal value.
+fn escape_file_name(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' {
+            escaped.push(char::from(byte));
+        } else {
+            escaped.push_str(&format!("_{byte:02x}"));
+        }
+    }
+    escaped
+}
+
+/// The status code of the error returned by the homeserver, if any.
+fn error_status(error: &HttpError) -> Option<StatusCode> {
+    match error.as_ruma_api_error()? {
+        RumaApiError::ClientApi(error) => Some(error.status_code),
+        RumaApiError::Other(error) => Some(error.status_code),
+        RumaApiError::Uiaa(_) => None,
+    }
+}
+
+/// Whether the download might succeed if it is retried.
+fn is_transient(error: &Error) -> bool {
+    match error {
+        // The connection failed or was interrupted.
+        Error::Http(HttpError::Reqwest(_)) => true,
+        Error::Http(error) => error_status(error).is_some_and(|status| {
+            status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+        }),
+        // The download can be resumed, or starts again.
+        Error::IncompleteDownload => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk_test::{async_test, MatrixMockServer};
+    use ruma::{events::room::MediaSource, mxc_uri};
+    use wiremock::{
+        matchers::{header, method, path_regex},
+        Mock, ResponseTemplate,
+    };
+
+    use crate::test_utils::logged_in_client;
+
+    #[async_test]
+    async fn resume_download() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/media/.*/download/localhost/abcdef"))
+            .and(header("range", "bytes=6-"))
+            .respond_with(ResponseTemplate::new(206).set_body_string("world"))
+            .expect(1)
+            .mount(server.server())
+            .await;
+
+        // A previous download was interrupted.
+        std::fs::create_dir(dir.path().join("localhost")).unwrap();
+        std::fs::write(dir.path().join("localhost/abcdef.part"), "hello ").unwrap();
+
+        let downloads = client.media().downloads(dir.path());
+        let source = MediaSource::Plain(mxc_uri!("mxc://localhost/abcdef").to_owned());
+        let download = downloads.download(&source);
+        let progress = download.subscribe_to_progress();
+
+        let path = download.wait().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello world");
+        assert_eq!(progress.get().current, 11);
+        assert_eq!(downloads.get(&source).await.unwrap(), Some(path));
+        assert!(!dir.path().join("localhost/abcdef.part").exists());
+    }
+
+    #[async_test]
+    async fn restart_download_without_range_support() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        // The homeserver ignores the range and sends the whole file.
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/media/.*/download/localhost/abcdef"))
+            .and(header("range", "bytes=6-"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello world"))
+            .expect(1)
+            .mount(server.server())
+            .await;
+
+        std::fs::create_dir(dir.path().join("localhost")).unwrap();
+        std::fs::write(dir.path().join("localhost/abcdef.part"), "hello ").unwrap();
+
+        let source = MediaSource::Plain(mxc_uri!("mxc://localhost/abcdef").to_owned());
+        let path = client.media().downloads(dir.path()).download(&source).wait().await.unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "hello world");
+    }
+
+    #[async_test]
+    async fn distinct_paths_for_similar_uris() {
+        let client = logged_in_client(None).await;
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = client.media().downloads(dir.path());
+
+        let first = downloads.path(mxc_uri!("mxc://a.b/c")).unwrap();
+        let second = downloads.path(mxc_uri!("mxc://a/b_c")).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[cfg(feature = "e2e-encryption")]
+    #[async_test]
+    async fn decrypt_downloaded_media() {
+        use std::io::Read;
+
+        use matrix_sdk_base::crypto::AttachmentEncryptor;
+        use ruma::events::room::{EncryptedFile, EncryptedFileInit};
+
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = client.media().downloads(dir.path());
+
+        let encrypt = |data: &[u8]| {
+            let mut reader = data;
+            let mut encryptor = AttachmentEncryptor::new(&mut reader);
+            let mut encrypted = Vec::new();
+            encryptor.read_to_end(&mut encrypted).unwrap();
+            (encrypted, encryptor.finish())
+        };
+        let encrypted_file = |url: &str, keys: matrix_sdk_base::crypto::MediaEncryptionInfo| {
+            let file: EncryptedFile = EncryptedFileInit {
+                url: url.into(),
+                key: keys.key,
+                iv: keys.iv,
+                hashes: keys.hashes,
+                v: keys.version,
+            }
+            .into();
+            MediaSource::Encrypted(Box::new(file))
+        };
+
+        let (encrypted, keys) = encrypt(b"hello world");
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/media/.*/download/localhost/valid"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(encrypted))
+            .mount(server.server())
+            .await;
+
+        let source = encrypted_file("mxc://localhost/valid", keys);
+        let path = downloads.download(&source).wait().await.unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "hello world");
+
+        // The content doesn't match the hash, so the file is rejected.
+        let (_, keys) = encrypt(b"hello world");
+        let (tampered, _) = encrypt(b"hello there");
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/media/.*/download/localhost/tampered"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(tampered))
+            .mount(server.server())
+            .await;
+
+        let source = encrypted_file("mxc://localhost/tampered", keys);
+        downloads.download(&source).wait().await.unwrap_err();
+        assert_eq!(downloads.get(&source).await.unwrap(), None);
+        assert_eq!(std::fs::read_dir(dir.path().join("localhost")).unwrap().count(), 1);
+    }
+
+    #[async_test]
+    async fn retry_failed_download() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/media/.*/download/localhost/abcdef"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(server.server())
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/media/.*/download/localhost/abcdef"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello world"))
+            .expect(1)
+            .mount(server.server())
+            .await;
+
+        let source = MediaSource::Plain(mxc_uri!("mxc://localhost/abcdef").to_owned());
+        let path = client.media().downloads(dir.path()).download(&source).wait().await.unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "hello world");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[async_test]
+    async fn download_metrics_are_recorded() {
+        use matrix_sdk_common::metrics::HTTP_REQUEST_DURATION;
+        use matrix_sdk_test::metrics::{install_recorder, take_recorded_histograms};
+
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/media/.*/download/localhost/abcdef"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello world"))
+            .mount(server.server())
+            .await;
+        install_recorder();
+
+        let source = MediaSource::Plain(mxc_uri!("mxc://localhost/abcdef").to_owned());
+        client.media().downloads(dir.path()).download(&source).wait().await.unwrap();
+
+        let recorded = take_recorded_histograms();
+        assert!(
+            recorded.iter().any(|histogram| histogram.matches(
+                HTTP_REQUEST_DURATION,
+                &[("endpoint", "media::get_content::v3"), ("outcome", "success")]
+            )),
+            "{recorded:?}"
+        );
+    }
+}
@@ -37,11 +37,16 @@ use tempfile::{Builder as TempFileBuilder, NamedTempFile, TempDir};
 #[cfg(not(target_arch = "wasm32"))]
 use tokio::{fs::File as TokioFile, io::AsyncWriteExt};
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::download::{MediaDownload, MediaDownloads};
 use crate::{
     attachment::{AttachmentInfo, Thumbnail},
     Client, Result, SendRequest, TransmissionProgress,
 };
 
+#[cfg(not(target_arch = "wasm32"))]
+mod download;
+
 /// A conservative upload speed of 1Mbps
 const DEFAULT_UPLOAD_SPEED: u64 = 125_000;
 /// 5 min minimal upload request timeout, used to clamp the request timeout.
@@ -124,6 +129,34 @@ impl Media {
         self.client.send(request, Some(request_config))
     }
 
+    /// Get a manager of resumable downloads of media files into the given
+    /// directory.
+    ///
+    /// This is useful for big files, that are not stored in the media cache.
+    /// See [`MediaDownloads`] for more details.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::events::room::MediaSource};
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// # let source: MediaSource = todo!();
+    /// let downloads = client.media().downloads("/home/example/downloads");
+    ///
+    /// let download = downloads.download(&source);
+    /// let progress = download.subscribe_to_progress();
+    /// // Show the progress of the download while waiting…
+    /// let path = download.wait().await?;
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn downloads(&self, dir: impl Into<std::path::PathBuf>) -> MediaDownloads {
+        MediaDownloads::new(self.client.clone(), dir.into())
+    }
+
     /// Gets a media file by copying it to a temporary location on disk.
     ///
     /// The file won't be encrypted even if it is encrypted on the server.
@@ -215,12 +248,16 @@ impl Media {
     ///
     /// * `request` - The `MediaRequest` of the content.
     ///
-    /// * `use_cache` - If we should use the media cache for this request.
+    /// * `use_cache` - If we should use the media cache for this request. The
+    ///   cache is never used by a client built with
+    ///   [`ClientBuilder::minimal()`](crate::ClientBuilder::minimal).
     pub async fn get_media_content(
         &self,
         request: &MediaRequest,
         use_cache: bool,
     ) -> Result<Vec<u8>> {
+        let use_cache = use_cache && self.client.minimal_rooms().is_none();
+
         let content =
             if use_cache { self.client.store().get_media_content(request).await? } else { None };
 
@@ -1,5 +1,14 @@
 //! High-level push notification settings API
 
+use ruma::{
+    api::client::push::{delete_pushrule, set_pushrule, set_pushrule_enabled},
+    push::{Action, RuleKind, Tweak},
+    RoomId, UserId,
+};
+
+use self::rules::{Command, Rules};
+use crate::{error::NotificationSettingsError, Client, Result};
+
 mod rules;
 
 /// Enum representing the push notification modes for a room.
@@ -12,3 +21,248 @@ pub enum RoomNotificationMode {
     /// Do not receive any notifications.
     Mute,
 }
+
+/// The position of a user defined push rule among the rules of the same kind.
+///
+/// The rules of a kind are evaluated in order, so the first rule matching an
+/// event decides whether it notifies. A rule can only be positioned relative
+/// to another user defined rule of the same kind, the server-default rules
+/// have a fixed position.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RulePosition {
+    /// The rule has the highest priority of the user defined rules of its
+    /// kind.
+    #[default]
+    Highest,
+    /// The rule has the lowest priority of the user defined rules of its kind.
+    Lowest,
+    /// The rule is just before the rule with the given ID, so it has a higher
+    /// priority.
+    Before(String),
+    /// The rule is just after the rule with the given ID, so it has a lower
+    /// priority.
+    After(String),
+}
+
+/// What happens when a user defined push rule matches an event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleActions {
+    /// Whether the matching events notify.
+    ///
+    /// A rule that doesn't notify mutes the matching events, e.g. to mute a
+    /// room or a sender.
+    pub notify: bool,
+    /// The sound to play with the notification, e.g. `default`.
+    ///
+    /// The values depend on the client, e.g. the name of a bundled sound.
+    pub sound: Option<String>,
+    /// Whether the notification is highlighted.
+    pub highlight: bool,
+}
+
+impl RuleActions {
+    /// Actions notifying the matching events, without sound or highlight.
+    pub fn notify() -> Self {
+        Self { notify: true, ..Default::default() }
+    }
+
+    /// Actions muting the matching events.
+    pub fn mute() -> Self {
+        Self::default()
+    }
+
+    /// Play the given sound with the notification.
+    pub fn with_sound(mut self, sound: impl Into<String>) -> Self {
+        self.sound = Some(sound.into());
+        self
+    }
+
+    /// Highlight the notification.
+    pub fn highlighted(mut self) -> Self {
+        self.highlight = true;
+        self
+    }
+
+    /// Get the push rule actions.
+    ///
+    /// A sound or a highlight is only valid for a rule that notifies.
+    pub(crate) fn to_actions(&self) -> Result<Vec<Action>, NotificationSettingsError> {
+        if !self.notify {
+            if self.sound.is_some() || self.highlight {
+                return Err(NotificationSettingsError::InvalidParameter(
+                    "a rule that doesn't notify can't have a sound or a highlight".to_owned(),
+                ));
+            }
+            return Ok(vec![]);
+        }
+
+        let mut actions = vec![Action::Notify];
+        if let Some(sound) = &self.sound {
+            actions.push(Action::SetTweak(Tweak::Sound(sound.clone())));
+        }
+        if self.highlight {
+            actions.push(Action::SetTweak(Tweak::Highlight(true)));
+        }
+        Ok(actions)
+    }
+}
+
+/// A high-level API to manage the user defined push rules of the account.
+///
+/// The raw push rules endpoints make it easy to create rules that are not in
+/// the expected order, or that are positioned relative to rules of another
+/// kind. The changes are checked against the current push rules before being
+/// sent to the homeserver.
+///
+/// The push rules of the client are updated with the next sync.
+#[derive(Debug, Clone)]
+pub struct NotificationSettings {
+    client: Client,
+}
+
+impl NotificationSettings {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Get the IDs of the user defined rules of the given kind, from the
+    /// highest priority to the lowest.
+    pub async fn user_defined_rules(&self, kind: RuleKind) -> Result<Vec<String>> {
+        Ok(self.rules().await?.get_user_defined_rules(kind))
+    }
+
+    /// Get the keywords of the keyword rules, from the highest priority to the
+    /// lowest.
+    pub async fn keywords(&self) -> Result<Vec<String>> {
+        self.user_defined_rules(RuleKind::Content).await
+    }
+
+    /// Create or update the keyword rule notifying the messages containing the
+    /// given keyword.
+    ///
+    /// The keyword is the ID of the rule, so it can't contain `/` or `\`.
+    pub async fn set_keyword_rule(
+        &self,
+        keyword: &str,
+        actions: RuleActions,
+        position: RulePosition,
+    ) -> Result<()> {
+        let mut rules = self.rules().await?;
+        let commands = rules.insert_keyword_rule(keyword, &actions, &position)?;
+        run_commands(&self.client, commands).await
+    }
+
+    /// Delete the keyword rule of the given keyword.
+    pub async fn delete_keyword_rule(&self, keyword: &str) -> Result<()> {
+        self.delete_rule(RuleKind::Content, keyword.trim()).await
+    }
+
+    /// Create or update the override rule for the events sent by the given
+    /// user.
+    ///
+    /// The ID of the rule is the user ID.
+    pub async fn set_sender_override_rule(
+        &self,
+        user_id: &UserId,
+        actions: RuleActions,
+        position: RulePosition,
+    ) -> Result<()> {
+        let mut rules = self.rules().await?;
+        let commands = rules.insert_sender_override_rule(user_id, &actions, &position)?;
+        run_commands(&self.client, commands).await
+    }
+
+    /// Create or update the override rule for the events of the given room.
+    ///
+    /// The ID of the rule is the room ID. An override rule that doesn't notify
+    /// mutes the room, like [`RoomNotificationMode::Mute`].
+    pub async fn set_room_override_rule(
+        &self,
+        room_id: &RoomId,
+        actions: RuleActions,
+        position: RulePosition,
+    ) -> Result<()> {
+        let mut rules = self.rules().await?;
+        let commands = rules.insert_room_override_rule(room_id, &actions, &position)?;
+        run_commands(&self.client, commands).await
+    }
+
+    /// Move the user defined rule with the given ID to a new position among
+    /// the rules of the same kind.
+    ///
+    /// Only the order of the `Override`, `Content` and `Underride` rules can be
+    /// changed. The rule keeps its actions and whether it is enabled.
+    pub async fn move_rule(
+        &self,
+        kind: RuleKind,
+        rule_id: &str,
+        position: RulePosition,
+    ) -> Result<()> {
+        let mut rules = self.rules().await?;
+        let commands = rules.move_rule(kind, rule_id, &position)?;
+        run_commands(&self.client, commands).await
+    }
+
+    /// Delete the user defined rule with the given ID.
+    ///
+    /// The server-default rules can't be deleted, only disabled.
+    pub async fn delete_rule(&self, kind: RuleKind, rule_id: &str) -> Result<()> {
+        let mut rules = self.rules().await?;
+        let commands = rules.delete_user_defined_rule(kind, rule_id)?;
+        run_commands(&self.client, commands).await
+    }
+
+    async fn rules(&self) -> Result<Rules> {
+        Ok(Rules::new(self.client.account().push_rules().await?))
+    }
+}
+
+/// Copy the notification mode that the user set for a room to another room,
+/// unless the user already set one for the other room.
+pub(crate) async fn copy_room_notification_mode(
+    client: &Client,
+    from: &RoomId,
+    to: &RoomId,
+) -> Result<()> {
+    let mut rules = Rules::new(client.account().push_rules().await?);
+
+    if rules.get_user_defined_room_notification_mode(to).is_some() {
+        return Ok(());
+    }
+
+    let command = match rules.get_user_defined_room_notification_mode(from) {
+        Some(RoomNotificationMode::Mute) => rules.insert_room_rule(RuleKind::Override, to, false),
+        Some(RoomNotificationMode::MentionsAndKeywordsOnly) => {
+            rules.insert_room_rule(RuleKind::Room, to, false)
+        }
+        Some(RoomNotificationMode::AllMessages) => rules.insert_room_rule(RuleKind::Room, to, true),
+        None => return Ok(()),
+    }?;
+
+    run_commands(client, command.into_iter().collect()).await
+}
+
+/// Send the requests performing the given commands on the user's account data,
+/// in order.
+async fn run_commands(client: &Client, commands: Vec<Command>) -> Result<()> {
+    for command in commands {
+        match command {
+            Command::SetPushRule { scope, rule, before, after } => {
+                let mut request = set_pushrule::v3::Request::new(scope, rule);
+                request.before = before;
+                request.after = after;
+                client.send(request, None).await?;
+            }
+            Command::SetPushRuleEnabled { scope, kind, rule_id, enabled } => {
+                let request = set_pushrule_enabled::v3::Request::new(scope, kind, rule_id, enabled);
+                client.send(request, None).await?;
+            }
+            Command::DeletePushRule { scope, kind, rule_id } => {
+                let request = delete_pushrule::v3::Request::new(scope, kind, rule_id);
+                client.send(request, None).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
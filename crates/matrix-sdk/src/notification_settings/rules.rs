@@ -3,22 +3,27 @@
 use ruma::{
     api::client::push::RuleScope,
     push::{
-        Action, NewConditionalPushRule, NewPushRule, NewSimplePushRule, PredefinedContentRuleId,
-        PredefinedOverrideRuleId, PredefinedUnderrideRuleId, PushCondition, RemovePushRuleError,
-        RuleKind, Ruleset, Tweak,
+        Action, NewConditionalPushRule, NewPatternedPushRule, NewPushRule, NewSimplePushRule,
+        PredefinedContentRuleId, PredefinedOverrideRuleId, PredefinedUnderrideRuleId,
+        PushCondition, RemovePushRuleError, RuleKind, Ruleset, Tweak,
     },
-    RoomId,
+    RoomId, UserId,
 };
 
-use super::RoomNotificationMode;
+use super::{RoomNotificationMode, RuleActions, RulePosition};
 use crate::error::NotificationSettingsError;
 
 /// enum describing the commands required to modify the owner's account data.
 #[derive(Clone, Debug)]
-#[allow(dead_code)]
 pub(crate) enum Command {
-    /// Set a new push rule
-    SetPushRule { scope: RuleScope, rule: NewPushRule },
+    /// Set a new push rule, or update an existing one, optionally positioned
+    /// relative to another rule of the same kind
+    SetPushRule {
+        scope: RuleScope,
+        rule: NewPushRule,
+        before: Option<String>,
+        after: Option<String>,
+    },
     /// Set whether a push rule is enabled
     SetPushRuleEnabled { scope: RuleScope, kind: RuleKind, rule_id: String, enabled: bool },
     /// Delete a push rule
@@ -239,14 +244,24 @@ impl Rules {
                 );
                 let new_rule = NewPushRule::Override(new_rule);
                 self.ruleset.insert(new_rule.clone(), None, None)?;
-                command = Some(Command::SetPushRule { scope: RuleScope::Global, rule: new_rule });
+                command = Some(Command::SetPushRule {
+                    scope: RuleScope::Global,
+                    rule: new_rule,
+                    before: None,
+                    after: None,
+                });
             }
             RuleKind::Room => {
                 // Insert a new `Room` push rule for this `room_id`
                 let new_rule = NewSimplePushRule::new(room_id.to_owned(), actions);
                 let new_rule = NewPushRule::Room(new_rule);
                 self.ruleset.insert(new_rule.clone(), None, None)?;
-                command = Some(Command::SetPushRule { scope: RuleScope::Global, rule: new_rule });
+                command = Some(Command::SetPushRule {
+                    scope: RuleScope::Global,
+                    rule: new_rule,
+                    before: None,
+                    after: None,
+                });
             }
             _ => {
                 return Err(NotificationSettingsError::InvalidParameter(
@@ -288,6 +303,247 @@ impl Rules {
         Ok(commands)
     }
 
+    /// Gets the IDs of the user defined rules of the given kind, from the
+    /// highest priority to the lowest.
+    pub(crate) fn get_user_defined_rules(&self, kind: RuleKind) -> Vec<String> {
+        match kind {
+            RuleKind::Override => user_defined_rule_ids(
+                self.ruleset.override_.iter().map(|r| (r.default, r.rule_id.as_str())),
+            ),
+            RuleKind::Content => user_defined_rule_ids(
+                self.ruleset.content.iter().map(|r| (r.default, r.rule_id.as_str())),
+            ),
+            RuleKind::Room => user_defined_rule_ids(
+                self.ruleset.room.iter().map(|r| (r.default, r.rule_id.as_str())),
+            ),
+            RuleKind::Sender => user_defined_rule_ids(
+                self.ruleset.sender.iter().map(|r| (r.default, r.rule_id.as_str())),
+            ),
+            RuleKind::Underride => user_defined_rule_ids(
+                self.ruleset.underride.iter().map(|r| (r.default, r.rule_id.as_str())),
+            ),
+            _ => vec![],
+        }
+    }
+
+    /// Insert or update a `Content` push rule matching the given `keyword` and
+    /// return a list of `Command` describing the actions to be performed on
+    /// the user's account data.
+    ///
+    /// The keyword is used as the ID of the rule.
+    pub(crate) fn insert_keyword_rule(
+        &mut self,
+        keyword: &str,
+        actions: &RuleActions,
+        position: &RulePosition,
+    ) -> Result<Vec<Command>, NotificationSettingsError> {
+        let keyword = keyword.trim();
+        if keyword.is_empty() {
+            return Err(NotificationSettingsError::InvalidParameter(
+                "the keyword can't be empty".to_owned(),
+            ));
+        }
+        if keyword.starts_with('.') || keyword.contains(['/', '\\']) {
+            return Err(NotificationSettingsError::InvalidParameter(format!(
+                "the keyword `{keyword}` can't be used as a rule ID"
+            )));
+        }
+
+        let new_rule = NewPatternedPushRule::new(
+            keyword.to_owned(),
+            keyword.to_owned(),
+            actions.to_actions()?,
+        );
+        self.insert_rule(RuleKind::Content, NewPushRule::Content(new_rule), keyword, position)
+    }
+
+    /// Insert or update an `Override` push rule matching the events sent by
+    /// the given `user_id` and return a list of `Command` describing the
+    /// actions to be performed on the user's account data.
+    pub(crate) fn insert_sender_override_rule(
+        &mut self,
+        user_id: &UserId,
+        actions: &RuleActions,
+        position: &RulePosition,
+    ) -> Result<Vec<Command>, NotificationSettingsError> {
+        let new_rule = NewConditionalPushRule::new(
+            user_id.to_string(),
+            vec![PushCondition::EventMatch { key: "sender".into(), pattern: user_id.to_string() }],
+            actions.to_actions()?,
+        );
+        self.insert_rule(
+            RuleKind::Override,
+            NewPushRule::Override(new_rule),
+            user_id.as_str(),
+            position,
+        )
+    }
+
+    /// Insert or update an `Override` push rule matching the events of the
+    /// given `room_id` and return a list of `Command` describing the actions
+    /// to be performed on the user's account data.
+    pub(crate) fn insert_room_override_rule(
+        &mut self,
+        room_id: &RoomId,
+        actions: &RuleActions,
+        position: &RulePosition,
+    ) -> Result<Vec<Command>, NotificationSettingsError> {
+        let new_rule = NewConditionalPushRule::new(
+            room_id.to_string(),
+            vec![PushCondition::EventMatch { key: "room_id".into(), pattern: room_id.to_string() }],
+            actions.to_actions()?,
+        );
+        self.insert_rule(
+            RuleKind::Override,
+            NewPushRule::Override(new_rule),
+            room_id.as_str(),
+            position,
+        )
+    }
+
+    /// Move a user defined rule to the given position among the rules of the
+    /// same kind and return a list of `Command` describing the actions to be
+    /// performed on the user's account data.
+    ///
+    /// Only the order of the `Override`, `Content` and `Underride` rules
+    /// matters, since the `Room` and `Sender` rules match distinct events.
+    pub(crate) fn move_rule(
+        &mut self,
+        kind: RuleKind,
+        rule_id: &str,
+        position: &RulePosition,
+    ) -> Result<Vec<Command>, NotificationSettingsError> {
+        let (new_rule, enabled) = match kind {
+            RuleKind::Override | RuleKind::Underride => {
+                let rules = if kind == RuleKind::Override {
+                    &self.ruleset.override_
+                } else {
+                    &self.ruleset.underride
+                };
+                let rule = rules
+                    .iter()
+                    .find(|r| !r.default && r.rule_id == rule_id)
+                    .ok_or(NotificationSettingsError::RuleNotFound)?;
+                let new_rule = NewConditionalPushRule::new(
+                    rule.rule_id.clone(),
+                    rule.conditions.clone(),
+                    rule.actions.clone(),
+                );
+                let new_rule = if kind == RuleKind::Override {
+                    NewPushRule::Override(new_rule)
+                } else {
+                    NewPushRule::Underride(new_rule)
+                };
+                (new_rule, rule.enabled)
+            }
+            RuleKind::Content => {
+                let rule = self
+                    .ruleset
+                    .content
+                    .iter()
+                    .find(|r| !r.default && r.rule_id == rule_id)
+                    .ok_or(NotificationSettingsError::RuleNotFound)?;
+                let new_rule = NewPatternedPushRule::new(
+                    rule.rule_id.clone(),
+                    rule.pattern.clone(),
+                    rule.actions.clone(),
+                );
+                (NewPushRule::Content(new_rule), rule.enabled)
+            }
+            _ => {
+                return Err(NotificationSettingsError::InvalidParameter(
+                    "kind must be either Override, Content or Underride.".to_owned(),
+                ))
+            }
+        };
+
+        let mut commands = self.insert_rule(kind.clone(), new_rule, rule_id, position)?;
+
+        // Setting a rule enables it, so a disabled rule must be disabled again.
+        if !enabled {
+            self.set_rule_enabled(kind, rule_id, false, &mut commands)?;
+        }
+
+        Ok(commands)
+    }
+
+    /// Deletes a user defined rule and returns a list of `Command` describing
+    /// the actions to be performed on the user's account data.
+    pub(crate) fn delete_user_defined_rule(
+        &mut self,
+        kind: RuleKind,
+        rule_id: &str,
+    ) -> Result<Vec<Command>, NotificationSettingsError> {
+        if rule_id.starts_with('.') {
+            return Err(NotificationSettingsError::InvalidParameter(format!(
+                "the server-default rule `{rule_id}` can't be deleted, only disabled"
+            )));
+        }
+
+        self.delete_rules(&[(kind, rule_id.to_owned())], &[])
+            .map_err(|_| NotificationSettingsError::RuleNotFound)
+    }
+
+    /// Helper function to insert a user defined rule at the given position
+    /// and return the command to set it on the user's account data.
+    fn insert_rule(
+        &mut self,
+        kind: RuleKind,
+        rule: NewPushRule,
+        rule_id: &str,
+        position: &RulePosition,
+    ) -> Result<Vec<Command>, NotificationSettingsError> {
+        let (before, after) = self.resolve_position(kind, rule_id, position)?;
+        self.ruleset.insert(rule.clone(), after.as_deref(), before.as_deref())?;
+
+        Ok(vec![Command::SetPushRule { scope: RuleScope::Global, rule, before, after }])
+    }
+
+    /// Get the `before` and `after` parameters to put the rule with the given
+    /// `rule_id` at the given position.
+    ///
+    /// The homeserver only accepts rules positioned relative to another user
+    /// defined rule of the same kind, so this is checked beforehand.
+    fn resolve_position(
+        &self,
+        kind: RuleKind,
+        rule_id: &str,
+        position: &RulePosition,
+    ) -> Result<(Option<String>, Option<String>), NotificationSettingsError> {
+        let relative_rule_id = match position {
+            RulePosition::Highest => return Ok((None, None)),
+            RulePosition::Lowest => {
+                // Put the rule after the last other user defined rule, if any.
+                let last =
+                    self.get_user_defined_rules(kind).into_iter().rev().find(|id| id != rule_id);
+                return Ok((None, last));
+            }
+            RulePosition::Before(id) | RulePosition::After(id) => id,
+        };
+
+        if relative_rule_id == rule_id {
+            return Err(NotificationSettingsError::InvalidParameter(format!(
+                "the rule `{rule_id}` can't be positioned relative to itself"
+            )));
+        }
+        if relative_rule_id.starts_with('.') {
+            return Err(NotificationSettingsError::InvalidParameter(format!(
+                "a rule can't be positioned relative to the server-default rule \
+                 `{relative_rule_id}`"
+            )));
+        }
+        if !self.get_user_defined_rules(kind.clone()).contains(relative_rule_id) {
+            return Err(NotificationSettingsError::InvalidParameter(format!(
+                "`{relative_rule_id}` is not a user defined {kind} rule"
+            )));
+        }
+
+        Ok(match position {
+            RulePosition::Before(_) => (Some(relative_rule_id.clone()), None),
+            _ => (None, Some(relative_rule_id.clone())),
+        })
+    }
+
     /// Sets whether a rule is enabled and returns a list of `Command`
     /// describing the actions to be performed on the user's account data.
     pub(crate) fn set_enabled(
@@ -404,6 +660,12 @@ impl Rules {
     }
 }
 
+/// Gets the IDs of the rules that are not server-default from a list of
+/// `(default, rule_id)`.
+fn user_defined_rule_ids<'a>(rules: impl Iterator<Item = (bool, &'a str)>) -> Vec<String> {
+    rules.filter(|(default, _)| !default).map(|(_, rule_id)| rule_id.to_owned()).collect()
+}
+
 /// Gets the `PredefinedUnderrideRuleId` corresponding to the given
 /// criteria.
 ///
@@ -434,7 +696,7 @@ pub(crate) mod tests {
             PredefinedContentRuleId, PredefinedOverrideRuleId, PredefinedUnderrideRuleId,
             PushCondition, RuleKind, Ruleset,
         },
-        OwnedRoomId, RoomId, UserId,
+        user_id, OwnedRoomId, RoomId, UserId,
     };
 
     use super::Command;
@@ -442,7 +704,7 @@ pub(crate) mod tests {
         error::NotificationSettingsError,
         notification_settings::{
             rules::{self, Rules},
-            RoomNotificationMode,
+            RoomNotificationMode, RuleActions, RulePosition,
         },
     };
 
@@ -762,7 +1024,7 @@ pub(crate) mod tests {
         // The command list should contains only a SetPushRule command
         assert_matches!(
             command,
-            Some(Command::SetPushRule { scope, rule }) => {
+            Some(Command::SetPushRule { scope, rule, .. }) => {
                 assert_eq!(scope, RuleScope::Global);
                 assert_matches!(
                     rule,
@@ -799,7 +1061,7 @@ pub(crate) mod tests {
         // The command list should contains only a SetPushRule command
         assert_matches!(
             command,
-            Some(Command::SetPushRule { scope, rule }) => {
+            Some(Command::SetPushRule { scope, rule, .. }) => {
                 assert_eq!(scope, RuleScope::Global);
                 assert_matches!(
                     rule,
@@ -1086,4 +1348,196 @@ pub(crate) mod tests {
             }
         );
     }
+
+    #[async_test]
+    async fn test_insert_keyword_rule_positions() {
+        let mut rules = Rules::new(get_server_default_ruleset());
+        let actions = RuleActions::notify().with_sound("ring");
+
+        rules.insert_keyword_rule("foo", &actions, &RulePosition::Highest).unwrap();
+        rules.insert_keyword_rule("bar", &actions, &RulePosition::Highest).unwrap();
+        assert_eq!(rules.get_user_defined_rules(RuleKind::Content), ["bar", "foo"]);
+
+        // The lowest position is after the last user defined rule
+        let commands = rules.insert_keyword_rule("baz", &actions, &RulePosition::Lowest).unwrap();
+        assert_matches!(
+            &commands[..],
+            [Command::SetPushRule { scope, rule, before, after }] => {
+                assert_eq!(scope, &RuleScope::Global);
+                assert_matches!(rule, NewPushRule::Content(rule) => assert_eq!(rule.rule_id, "baz"));
+                assert_eq!(before, &None);
+                assert_eq!(after.as_deref(), Some("foo"));
+            }
+        );
+
+        let commands = rules
+            .insert_keyword_rule("qux", &actions, &RulePosition::Before("foo".to_owned()))
+            .unwrap();
+        assert_matches!(
+            &commands[..],
+            [Command::SetPushRule { before, after, .. }] => {
+                assert_eq!(before.as_deref(), Some("foo"));
+                assert_eq!(after, &None);
+            }
+        );
+        assert_eq!(rules.get_user_defined_rules(RuleKind::Content), ["bar", "qux", "foo", "baz"]);
+
+        let rule = rules.ruleset.get(RuleKind::Content, "qux").unwrap();
+        assert_matches!(
+            rule.actions(),
+            [Action::Notify, Action::SetTweak(ruma::push::Tweak::Sound(sound))] => {
+                assert_eq!(sound, "ring");
+            }
+        );
+    }
+
+    #[async_test]
+    async fn test_insert_rules_invalid_parameters() {
+        let room_id = get_test_room_id();
+        let mut rules = Rules::new(get_server_default_ruleset());
+        let actions = RuleActions::notify();
+
+        rules
+            .insert_room_override_rule(&room_id, &RuleActions::mute(), &RulePosition::Highest)
+            .unwrap();
+
+        // Invalid keywords
+        assert_matches!(
+            rules.insert_keyword_rule(" ", &actions, &RulePosition::Highest),
+            Err(NotificationSettingsError::InvalidParameter(_))
+        );
+        assert_matches!(
+            rules.insert_keyword_rule("foo/bar", &actions, &RulePosition::Highest),
+            Err(NotificationSettingsError::InvalidParameter(_))
+        );
+
+        // A rule that doesn't notify can't have a sound
+        assert_matches!(
+            rules.insert_keyword_rule(
+                "foo",
+                &RuleActions::mute().with_sound("ring"),
+                &RulePosition::Highest
+            ),
+            Err(NotificationSettingsError::InvalidParameter(_))
+        );
+
+        // A rule can't be positioned relative to a server-default rule
+        assert_matches!(
+            rules.insert_sender_override_rule(
+                user_id!("@alice:matrix.org"),
+                &actions,
+                &RulePosition::Before(PredefinedOverrideRuleId::Master.to_string())
+            ),
+            Err(NotificationSettingsError::InvalidParameter(_))
+        );
+
+        // A rule can't be positioned relative to a rule of another kind
+        assert_matches!(
+            rules.insert_keyword_rule("foo", &actions, &RulePosition::After(room_id.to_string())),
+            Err(NotificationSettingsError::InvalidParameter(_))
+        );
+
+        // A rule can't be positioned relative to itself
+        assert_matches!(
+            rules.insert_room_override_rule(
+                &room_id,
+                &actions,
+                &RulePosition::After(room_id.to_string())
+            ),
+            Err(NotificationSettingsError::InvalidParameter(_))
+        );
+
+        // Nothing changed
+        assert_eq!(rules.get_user_defined_rules(RuleKind::Content).len(), 0);
+        assert_eq!(rules.get_user_defined_rules(RuleKind::Override), [room_id.to_string()]);
+    }
+
+    #[async_test]
+    async fn test_insert_sender_override_rule() {
+        let user_id = user_id!("@alice:matrix.org");
+        let mut rules = Rules::new(get_server_default_ruleset());
+
+        rules
+            .insert_sender_override_rule(user_id, &RuleActions::mute(), &RulePosition::Highest)
+            .unwrap();
+
+        let rule = rules.ruleset.override_.iter().find(|r| r.rule_id == user_id.as_str()).unwrap();
+        assert!(rule.actions.is_empty());
+        assert_matches!(
+            &rule.conditions[..],
+            [PushCondition::EventMatch { key, pattern }] => {
+                assert_eq!(key, "sender");
+                assert_eq!(pattern, user_id.as_str());
+            }
+        );
+    }
+
+    #[async_test]
+    async fn test_move_rule() {
+        let mut rules = Rules::new(get_server_default_ruleset());
+        let actions = RuleActions::notify();
+
+        rules.insert_keyword_rule("foo", &actions, &RulePosition::Highest).unwrap();
+        rules.insert_keyword_rule("bar", &actions, &RulePosition::Highest).unwrap();
+        rules.set_enabled(RuleKind::Content, "bar", false).unwrap();
+
+        let commands = rules.move_rule(RuleKind::Content, "bar", &RulePosition::Lowest).unwrap();
+        assert_eq!(rules.get_user_defined_rules(RuleKind::Content), ["foo", "bar"]);
+
+        // The rule is disabled again after being moved
+        assert!(!rules.is_enabled(RuleKind::Content, "bar").unwrap());
+        assert_matches!(
+            &commands[..],
+            [
+                Command::SetPushRule { rule, after, .. },
+                Command::SetPushRuleEnabled { rule_id, enabled: false, .. },
+            ] => {
+                assert_matches!(rule, NewPushRule::Content(rule) => assert_eq!(rule.rule_id, "bar"));
+                assert_eq!(after.as_deref(), Some("foo"));
+                assert_eq!(rule_id, "bar");
+            }
+        );
+
+        assert_matches!(
+            rules.move_rule(RuleKind::Content, "unknown", &RulePosition::Highest),
+            Err(NotificationSettingsError::RuleNotFound)
+        );
+        assert_matches!(
+            rules.move_rule(RuleKind::Room, "foo", &RulePosition::Highest),
+            Err(NotificationSettingsError::InvalidParameter(_))
+        );
+        #[allow(deprecated)]
+        let server_default_rule_id = PredefinedContentRuleId::ContainsUserName.to_string();
+        assert_matches!(
+            rules.move_rule(RuleKind::Content, &server_default_rule_id, &RulePosition::Highest),
+            Err(NotificationSettingsError::RuleNotFound)
+        );
+    }
+
+    #[async_test]
+    async fn test_delete_user_defined_rule() {
+        let mut rules = Rules::new(get_server_default_ruleset());
+        rules.insert_keyword_rule("foo", &RuleActions::notify(), &RulePosition::Highest).unwrap();
+
+        assert_matches!(
+            rules.delete_user_defined_rule(
+                RuleKind::Override,
+                PredefinedOverrideRuleId::Master.as_str()
+            ),
+            Err(NotificationSettingsError::InvalidParameter(_))
+        );
+        assert_matches!(
+            rules.delete_user_defined_rule(RuleKind::Override, "foo"),
+            Err(NotificationSettingsError::RuleNotFound)
+        );
+
+        let commands = rules.delete_user_defined_rule(RuleKind::Content, "foo").unwrap();
+        assert_matches!(
+            &commands[..],
+            [Command::DeletePushRule { kind: RuleKind::Content, rule_id, .. }] => {
+                assert_eq!(rule_id, "foo");
+            }
+        );
+        assert_eq!(rules.get_user_defined_rules(RuleKind::Content).len(), 0);
+    }
 }
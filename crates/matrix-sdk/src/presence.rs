@@ -0,0 +1,352 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! High-level API for the presence of the users.
+//!
+//! The presence of the other users is received in the sync responses, if the
+//! homeserver supports presence. It is saved in the state store, and can be
+//! observed with [`Presence::observe()`].
+
+use std::{
+    collections::{btree_map, BTreeMap},
+    sync::Mutex as StdMutex,
+    time::Duration,
+};
+
+use futures_core::Stream;
+use futures_util::{stream, StreamExt};
+use matrix_sdk_common::{instant::Instant, sleep::sleep};
+use ruma::{
+    api::client::presence::{get_presence, set_presence},
+    events::presence::{PresenceEvent, PresenceEventContent},
+    presence::PresenceState,
+    serde::Raw,
+    OwnedUserId, UInt, UserId,
+};
+use tokio::sync::{broadcast, broadcast::error::RecvError, Mutex};
+use tracing::debug;
+
+use crate::{Client, Error, Result};
+
+/// The minimum delay between two requests to set the presence of the user.
+const SET_PRESENCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The maximum number of presence requests sent at the same time by
+/// [`Presence::fetch()`].
+const MAX_CONCURRENT_REQUESTS: usize = 8;
+
+/// The state of the requests to set the presence of the user, shared by all
+/// the [`Presence`] handles of a client.
+#[derive(Debug, Default)]
+pub(crate) struct PresenceSetter {
+    /// The latest presence to set, that wasn't sent yet.
+    pending: StdMutex<Option<(PresenceState, Option<String>)>>,
+    /// The last presence that was sent, with the time of the request. The
+    /// lock is held while a request is sent.
+    last_sent: Mutex<Option<(PresenceState, Option<String>, Instant)>>,
+}
+
+/// A high-level API to get and set the presence of the users.
+///
+/// It can be obtained with [`Client::presence()`].
+#[derive(Debug, Clone)]
+pub struct Presence {
+    client: Client,
+}
+
+impl Presence {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Set the presence of the user, with an optional status message.
+    ///
+    /// The requests are rate-limited: if the presence was set less than a few
+    /// seconds ago, this waits before sending the request. When it is called
+    /// several times in a row, only the last presence is sent, and setting the
+    /// same presence again doesn't send any request.
+    ///
+    /// Note that the sync loop also sets the presence of the user, see
+    /// [`SyncSettings::set_presence()`].
+    ///
+    /// [`SyncSettings::set_presence()`]: crate::config::SyncSettings::set_presence
+    pub async fn set(&self, presence: PresenceState, status_msg: Option<String>) -> Result<()> {
+        let user_id = self.client.user_id().ok_or(Error::AuthenticationRequired)?.to_owned();
+        let setter = &self.client.inner.presence_setter;
+
+        *setter.pending.lock().unwrap() = Some((presence, status_msg));
+
+        let mut last_sent = setter.last_sent.lock().await;
+
+        if let Some((last_presence, last_status_msg, sent_at)) = &*last_sent {
+            {
+                let mut pending = setter.pending.lock().unwrap();
+                if pending.as_ref().is_some_and(|(presence, status_msg)| {
+                    presence == last_presence && status_msg == last_status_msg
+                }) {
+                    debug!("The presence didn't change, not sending it");
+                    *pending = None;
+                    return Ok(());
+                }
+            }
+
+            let elapsed = sent_at.elapsed();
+            if elapsed < SET_PRESENCE_INTERVAL {
+                sleep(SET_PRESENCE_INTERVAL - elapsed).await;
+            }
+        }
+
+        // The presence was already sent by a call that was waiting before this
+        // one.
+        let Some((presence, status_msg)) = setter.pending.lock().unwrap().take() else {
+            return Ok(());
+        };
+
+        let mut request = set_presence::v3::Request::new(user_id, presence.clone());
+        request.status_msg = status_msg.clone();
+        self.client.send(request, None).await?;
+
+        *last_sent = Some((presence, status_msg, Instant::now()));
+
+        Ok(())
+    }
+
+    /// Get the presence of the given user from the state store.
+    ///
+    /// Returns `None` if the presence of the user wasn't received in a sync
+    /// response.
+    pub async fn get(&self, user_id: &UserId) -> Result<Option<PresenceEventContent>> {
+        let event = self.client.store().get_presence_event(user_id).await?;
+        Ok(event.and_then(|event| deserialize_content(&event)))
+    }
+
+    /// Get the presence of the given users from the state store.
+    ///
+    /// The users whose presence wasn't received in a sync response are missing
+    /// from the map.
+    pub async fn get_many(
+        &self,
+        user_ids: &[OwnedUserId],
+    ) -> Result<BTreeMap<OwnedUserId, PresenceEventContent>> {
+        let events = self.client.store().get_presence_events(user_ids).await?;
+
+        Ok(events
+            .iter()
+            .filter_map(|raw| {
+                let event = raw.deserialize().ok()?;
+                Some((event.sender, event.content))
+            })
+            .collect())
+    }
+
+    /// Fetch the presence of the given users from the homeserver.
+    ///
+    /// A request is sent for every user, a few at a time. The users whose
+    /// presence can't be fetched, e.g. because the homeserver doesn't share it
+    /// with the user, are missing from the map.
+    pub async fn fetch(
+        &self,
+        user_ids: &[OwnedUserId],
+    ) -> BTreeMap<OwnedUserId, PresenceEventContent> {
+        stream::iter(user_ids)
+            .map(|user_id| async move {
+                let request = get_presence::v3::Request::new(user_id.clone());
+                match self.client.send(request, None).await {
+                    Ok(response) => Some((user_id.clone(), content_from_response(response))),
+                    Err(error) => {
+                        debug!(?user_id, ?error, "Couldn't fetch the presence");
+                        None
+                    }
+                }
+            })
+            .buffer_unordered(MAX_CONCURRENT_REQUESTS)
+            .filter_map(|presence| async move { presence })
+            .collect()
+            .await
+    }
+
+    /// Observe the presence of the given user.
+    ///
+    /// The stream starts with the presence from the state store, if any, then
+    /// receives the presence updates from the sync responses.
+    pub async fn observe(
+        &self,
+        user_id: &UserId,
+    ) -> Result<impl Stream<Item = PresenceEventContent>> {
+        // Subscribe before loading the stored presence to not miss an update.
+        let mut subscription = self.subscribe(user_id);
+        let current = self.get(user_id).await?;
+
+        Ok(async_stream::stream! {
+            if let Some(current) = current {
+                yield current;
+            }
+
+            loop {
+                match subscription.receiver.recv().await {
+                    Ok(presence) => yield presence,
+                    // Only the latest presence matters.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+
+    fn subscribe(&self, user_id: &UserId) -> PresenceSubscription {
+        let receiver =
+            match self.client.inner.presence_channels.lock().unwrap().entry(user_id.to_owned()) {
+                btree_map::Entry::Vacant(entry) => {
+                    let (tx, rx) = broadcast::channel(4);
+                    entry.insert(tx);
+                    rx
+                }
+                btree_map::Entry::Occupied(entry) => entry.get().subscribe(),
+            };
+
+        PresenceSubscription { client: self.client.clone(), user_id: user_id.to_owned(), receiver }
+    }
+}
+
+/// A receiver of the presence updates of a user, that removes the channel of
+/// the user when the last receiver is dropped.
+struct PresenceSubscription {
+    client: Client,
+    user_id: OwnedUserId,
+    receiver: broadcast::Receiver<PresenceEventContent>,
+}
+
+impl Drop for PresenceSubscription {
+    fn drop(&mut self) {
+        // The lock prevents new subscriptions while the receivers are counted,
+        // and this receiver is only dropped afterwards.
+        let mut channels = self.client.inner.presence_channels.lock().unwrap();
+        if let btree_map::Entry::Occupied(entry) = channels.entry(self.user_id.clone()) {
+            if entry.get().receiver_count() <= 1 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// Send the presence events of a sync response to the observers of the
+/// presence of their senders.
+pub(crate) fn broadcast_presence(client: &Client, events: &[Raw<PresenceEvent>]) {
+    let mut channels = client.inner.presence_channels.lock().unwrap();
+    if channels.is_empty() {
+        return;
+    }
+
+    for raw in events {
+        let Ok(event) = raw.deserialize() else { continue };
+
+        if let btree_map::Entry::Occupied(entry) = channels.entry(event.sender) {
+            let tx = entry.get();
+            if tx.receiver_count() == 0 {
+                entry.remove();
+            } else {
+                _ = tx.send(event.content);
+            }
+        }
+    }
+}
+
+fn deserialize_content(event: &Raw<PresenceEvent>) -> Option<PresenceEventContent> {
+    event.deserialize().ok().map(|event| event.content)
+}
+
+fn content_from_response(response: get_presence::v3::Response) -> PresenceEventContent {
+    let mut content = PresenceEventContent::new(response.presence);
+    content.status_msg = response.status_msg;
+    content.currently_active = response.currently_active;
+    content.last_active_ago = response
+        .last_active_ago
+        .map(|duration| UInt::try_from(duration.as_millis()).unwrap_or(UInt::MAX));
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{pin_mut, StreamExt};
+    use matrix_sdk_test::{
+        async_test, test_json, EventBuilder, MatrixMockServer, PresenceTestEvent,
+    };
+    use ruma::{presence::PresenceState, user_id};
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_partial_json, method, path_regex},
+        Mock, ResponseTemplate,
+    };
+
+    use crate::{config::SyncSettings, test_utils::logged_in_client};
+
+    #[async_test]
+    async fn observe_presence() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let user_id = user_id!("@example:localhost");
+
+        let stream = client.presence().observe(user_id).await.unwrap();
+        pin_mut!(stream);
+
+        let mut ev_builder = EventBuilder::new();
+        ev_builder.add_presence_event(PresenceTestEvent::Presence);
+        server.mock_sync().ok(ev_builder.build_json_sync_response()).mount().await;
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        let presence = stream.next().await.unwrap();
+        assert_eq!(presence.presence, PresenceState::Online);
+        assert_eq!(presence.status_msg.as_deref(), Some("Making cupcakes"));
+
+        let stored = client.presence().get(user_id).await.unwrap().unwrap();
+        assert_eq!(stored.presence, PresenceState::Online);
+    }
+
+    #[async_test]
+    async fn forget_presence_channels_without_observers() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let user_id = user_id!("@example:localhost");
+
+        let first = client.presence().observe(user_id).await.unwrap();
+        let second = client.presence().observe(user_id).await.unwrap();
+        assert_eq!(client.inner.presence_channels.lock().unwrap().len(), 1);
+
+        // The channel is kept while the user is still observed.
+        drop(first);
+        assert_eq!(client.inner.presence_channels.lock().unwrap().len(), 1);
+
+        drop(second);
+        assert!(client.inner.presence_channels.lock().unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn set_same_presence_once() {
+        let server = MatrixMockServer::new().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        Mock::given(method("PUT"))
+            .and(path_regex(r"^/_matrix/client/r0/presence/.*/status"))
+            .and(body_partial_json(json!({ "presence": "unavailable" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EMPTY))
+            .expect(1)
+            .mount(server.server())
+            .await;
+
+        let presence = client.presence();
+        presence.set(PresenceState::Unavailable, None).await.unwrap();
+        // The same presence is not sent again.
+        presence.set(PresenceState::Unavailable, None).await.unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::BTreeSet,
+    future::{Future, IntoFuture},
+    pin::Pin,
+};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use ruma::{events::room::member::MembershipState, OwnedUserId};
+use tracing::{debug, warn, Instrument, Span};
+
+use super::Joined;
+use crate::Error;
+
+/// The progress of a bulk invite, started with [`Joined::invite_users()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BulkInviteProgress {
+    /// The number of users to invite.
+    pub total: usize,
+    /// The number of users that were invited.
+    pub invited: usize,
+    /// The number of users that were skipped, because they are already
+    /// members of the room or invited to it.
+    pub skipped: usize,
+    /// The number of users that couldn't be invited.
+    pub failed: usize,
+}
+
+impl BulkInviteProgress {
+    /// Whether all the users were handled.
+    pub fn is_done(&self) -> bool {
+        self.invited + self.skipped + self.failed == self.total
+    }
+}
+
+/// The result of the invite of a user, in a [`BulkInviteReport`].
+#[derive(Debug)]
+pub enum InviteOutcome {
+    /// The user was invited.
+    Invited,
+    /// The user was already a member of the room or invited to it, so no
+    /// invite was sent.
+    Skipped,
+    /// The invite failed.
+    Failed(Error),
+}
+
+impl InviteOutcome {
+    /// Whether the invite failed.
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::Failed(_))
+    }
+}
+
+/// The report of a bulk invite, returned by [`Joined::invite_users()`].
+#[derive(Debug, Default)]
+pub struct BulkInviteReport {
+    /// The result of the invite of each user, in the order they were given.
+    pub results: Vec<(OwnedUserId, InviteOutcome)>,
+}
+
+impl BulkInviteReport {
+    /// The users that were invited.
+    pub fn invited(&self) -> impl Iterator<Item = &OwnedUserId> + '_ {
+        self.results
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, InviteOutcome::Invited))
+            .map(|(user_id, _)| user_id)
+    }
+
+    /// The users that couldn't be invited, with the error.
+    pub fn failed(&self) -> impl Iterator<Item = (&OwnedUserId, &Error)> + '_ {
+        self.results.iter().filter_map(|(user_id, outcome)| match outcome {
+            InviteOutcome::Failed(error) => Some((user_id, error)),
+            _ => None,
+        })
+    }
+}
+
+/// Future returned by [`Joined::invite_users()`].
+#[allow(missing_debug_implementations)]
+pub struct InviteUsers<'a> {
+    room: &'a Joined,
+    user_ids: Vec<OwnedUserId>,
+    tracing_span: Span,
+    progress: SharedObservable<BulkInviteProgress>,
+}
+
+impl<'a> InviteUsers<'a> {
+    pub(crate) fn new(room: &'a Joined, user_ids: impl IntoIterator<Item = OwnedUserId>) -> Self {
+        // Only invite each user once, but keep the order of the users.
+        let mut seen = BTreeSet::new();
+        let user_ids: Vec<_> =
+            user_ids.into_iter().filter(|user_id| seen.insert(user_id.clone())).collect();
+
+        let progress = SharedObservable::new(BulkInviteProgress {
+            total: user_ids.len(),
+            ..Default::default()
+        });
+
+        Self { room, user_ids, tracing_span: Span::current(), progress }
+    }
+
+    /// Subscribe to the progress of the invites.
+    pub fn subscribe_to_progress(&self) -> Subscriber<BulkInviteProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Invite the given user, unless they are already in the room.
+    async fn invite(&self, user_id: &OwnedUserId) -> InviteOutcome {
+        match self.room.get_member_no_sync(user_id).await {
+            Ok(Some(member))
+                if matches!(
+                    member.membership(),
+                    MembershipState::Join | MembershipState::Invite
+                ) =>
+            {
+                debug!(?user_id, "The user is already in the room, skipping them");
+                return InviteOutcome::Skipped;
+            }
+            Ok(_) => {}
+            // The homeserver knows better anyway.
+            Err(error) => warn!(?user_id, ?error, "Couldn't load the member"),
+        }
+
+        match self.room.invite_user_by_id(user_id).await {
+            Ok(()) => InviteOutcome::Invited,
+            Err(error) => {
+                warn!(?user_id, ?error, "Failed to invite user");
+                InviteOutcome::Failed(error)
+            }
+        }
+    }
+}
+
+impl<'a> IntoFuture for InviteUsers<'a> {
+    type Output = BulkInviteReport;
+    #[cfg(target_arch = "wasm32")]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + 'a>>;
+    #[cfg(not(target_arch = "wasm32"))]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send + 'a>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let tracing_span = self.tracing_span.clone();
+
+        let fut = async move {
+            let mut report = BulkInviteReport::default();
+
+            // The invites are sent one after the other: when the homeserver
+            // rate-limits them, the HTTP client waits until the limit is
+            // lifted before sending the next one.
+            for user_id in &self.user_ids {
+                let outcome = self.invite(user_id).await;
+
+                self.progress.update(|p| match outcome {
+                    InviteOutcome::Invited => p.invited += 1,
+                    InviteOutcome::Skipped => p.skipped += 1,
+                    InviteOutcome::Failed(_) => p.failed += 1,
+                });
+                report.results.push((user_id.clone(), outcome));
+            }
+
+            report
+        };
+
+        Box::pin(fut.instrument(tracing_span))
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-room settings of the clients, saved in the account data of the room.
+//!
+//! The settings are shared by all the sessions of the user, but they are
+//! private to them, unlike the state of the room.
+
+use std::{collections::BTreeMap, fmt};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use matrix_sdk_common::executor::{spawn, JoinHandle};
+use ruma::events::{macros::EventContent, StaticEventContent};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, warn};
+
+use super::Common;
+use crate::{sync::RoomUpdate, Result};
+
+/// The content of an `org.matrix.rust_sdk.room_client_settings` room account
+/// data event.
+///
+/// The fields that are not set use the default of the client, so the settings
+/// of a room only override the global ones.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "org.matrix.rust_sdk.room_client_settings", kind = RoomAccountData)]
+pub struct RoomClientSettingsEventContent {
+    /// Whether the previews of the URLs in the messages are shown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url_previews: Option<bool>,
+
+    /// The sound to play for the notifications of the room.
+    ///
+    /// The values depend on the client, e.g. the name of a bundled sound.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notification_sound: Option<String>,
+
+    /// The layout of the messages, e.g. `bubbles` or `irc`.
+    ///
+    /// The values depend on the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_layout: Option<String>,
+
+    /// The other settings, that are specific to a client.
+    ///
+    /// They are kept when the known settings are updated, so the keys should
+    /// be namespaced to avoid clashes with other clients.
+    #[serde(flatten)]
+    pub custom: BTreeMap<String, JsonValue>,
+}
+
+impl RoomClientSettingsEventContent {
+    /// Whether the previews of the URLs in the messages are shown, falling
+    /// back to the given default of the client.
+    pub fn url_previews_enabled(&self, default: bool) -> bool {
+        self.url_previews.unwrap_or(default)
+    }
+}
+
+/// The observable settings of the clients in a room.
+///
+/// It can be created with [`Common::subscribe_to_client_settings()`]. The
+/// settings are updated when the account data of the room changes during a
+/// sync, until this is dropped.
+pub struct ObservableRoomClientSettings {
+    settings: SharedObservable<RoomClientSettingsEventContent>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    task: JoinHandle<()>,
+}
+
+impl ObservableRoomClientSettings {
+    pub(crate) async fn new(room: Common) -> Result<Self> {
+        // Subscribe before loading the settings, so no update is missed.
+        let updates = room.subscribe_to_updates();
+        let settings = SharedObservable::new(room.client_settings().await?);
+
+        let task = spawn(update_settings(room, settings.clone(), updates));
+
+        Ok(Self { settings, task })
+    }
+
+    /// Get the current settings.
+    pub fn get(&self) -> RoomClientSettingsEventContent {
+        self.settings.get()
+    }
+
+    /// Get a subscriber to observe the changes of the settings.
+    pub fn subscribe(&self) -> Subscriber<RoomClientSettingsEventContent> {
+        self.settings.subscribe()
+    }
+}
+
+impl Drop for ObservableRoomClientSettings {
+    fn drop(&mut self) {
+        // The task is aborted when its handle is dropped on WASM.
+        #[cfg(not(target_arch = "wasm32"))]
+        self.task.abort();
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for ObservableRoomClientSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObservableRoomClientSettings")
+            .field("settings", &self.settings.get())
+            .finish()
+    }
+}
+
+async fn update_settings(
+    room: Common,
+    settings: SharedObservable<RoomClientSettingsEventContent>,
+    mut updates: Receiver<RoomUpdate>,
+) {
+    loop {
+        let reload = match updates.recv().await {
+            Ok(update) => has_settings_changes(&update),
+            Err(RecvError::Lagged(count)) => {
+                debug!(count, "Missed room updates, reloading the client settings");
+                true
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if reload {
+            match room.client_settings().await {
+                Ok(new_settings) => {
+                    // Don't notify the subscribers if nothing changed.
+                    if settings.get() != new_settings {
+                        settings.set(new_settings);
+                    }
+                }
+                Err(error) => warn!(?error, "Couldn't update the client settings of the room"),
+            }
+        }
+    }
+}
+
+/// Whether the given update contains a client settings event.
+fn has_settings_changes(update: &RoomUpdate) -> bool {
+    let account_data = match update {
+        RoomUpdate::Joined { updates, .. } => &updates.account_data,
+        RoomUpdate::Left { updates, .. } => &updates.account_data,
+        RoomUpdate::Invited { .. } => return false,
+    };
+
+    account_data.iter().any(|event| {
+        event
+            .get_field::<String>("type")
+            .ok()
+            .flatten()
+            .is_some_and(|event_type| event_type == RoomClientSettingsEventContent::TYPE)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value as from_json_value, json, to_value as to_json_value};
+
+    use super::RoomClientSettingsEventContent;
+
+    #[test]
+    fn client_settings_keep_custom_fields() {
+        let json = json!({
+            "url_previews": false,
+            "com.example.font_size": 14,
+        });
+
+        let content: RoomClientSettingsEventContent = from_json_value(json.clone()).unwrap();
+        assert!(!content.url_previews_enabled(true));
+        assert_eq!(content.notification_sound, None);
+        assert_eq!(content.custom["com.example.font_size"], 14);
+
+        assert_eq!(to_json_value(&content).unwrap(), json);
+        assert!(RoomClientSettingsEventContent::default().url_previews_enabled(true));
+    }
+}
@@ -1,5 +1,10 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
 use std::{borrow::Borrow, collections::BTreeMap, fmt, ops::Deref, sync::Arc};
 
+use bytes::BufMut;
+#[cfg(feature = "experimental-encrypted-state-events")]
+use matrix_sdk_base::encrypted_state;
 use matrix_sdk_base::{
     deserialized_responses::{
         MembersResponse, RawAnySyncOrStrippedState, RawSyncOrStrippedState, SyncOrStrippedState,
@@ -17,45 +22,61 @@ use ruma::events::{
 use ruma::{
     api::{
         client::{
-            config::set_global_account_data,
+            config::set_room_account_data,
+            context::get_context,
             error::ErrorKind,
             filter::RoomEventFilter,
             membership::{get_member_events, join_room_by_id, leave_room},
             message::get_message_events,
-            room::get_room_event,
+            relations::{get_relating_events, get_relating_events_with_rel_type},
+            room::{aliases, get_event_by_timestamp, get_room_event},
             state::get_state_events_for_key,
             tag::{create_tag, delete_tag},
         },
-        Direction,
+        error::{FromHttpResponseError, IntoHttpError},
+        metadata, Direction, EndpointError, IncomingResponse, MatrixVersion, Metadata,
+        OutgoingRequest, SendAccessToken,
     },
     assign,
     events::{
-        direct::DirectEventContent,
         receipt::{Receipt, ReceiptThread, ReceiptType},
+        relation::RelationType,
         room::{
-            encryption::RoomEncryptionEventContent, history_visibility::HistoryVisibility,
-            power_levels::RoomPowerLevelsEventContent, server_acl::RoomServerAclEventContent,
+            encryption::RoomEncryptionEventContent,
+            history_visibility::HistoryVisibility,
+            message::{MessageType, Relation, RoomMessageEventContent},
+            power_levels::RoomPowerLevelsEventContent,
+            server_acl::RoomServerAclEventContent,
             MediaSource,
         },
         tag::{TagInfo, TagName},
-        AnyRoomAccountDataEvent, AnyStateEvent, EmptyStateKey, RedactContent,
+        AnyMessageLikeEvent, AnyRoomAccountDataEvent, AnyRoomAccountDataEventContent,
+        AnyStateEvent, AnyTimelineEvent, EmptyStateKey, MessageLikeEvent, RedactContent,
         RedactedStateEventContent, RoomAccountDataEvent, RoomAccountDataEventContent,
         RoomAccountDataEventType, StateEventType, StaticEventContent, StaticStateEventContent,
     },
     push::{Action, PushConditionRoomCtx},
-    serde::Raw,
-    uint, EventId, MatrixToUri, MatrixUri, OwnedEventId, OwnedServerName, OwnedUserId, RoomId,
-    UInt, UserId,
+    serde::{json_to_buf, Raw},
+    uint, EventId, MatrixToUri, MatrixUri, MilliSecondsSinceUnixEpoch, OwnedEventId,
+    OwnedRoomAliasId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, UInt, UserId,
 };
 use serde::de::DeserializeOwned;
+use serde_json::json;
 use tokio::sync::{broadcast, Mutex};
 use tracing::{debug, instrument};
 
 use super::Joined;
+#[cfg(not(target_arch = "wasm32"))]
+use super::{ExportMedia, MediaExportOptions, MediaExportRange};
+#[cfg(feature = "e2e-encryption")]
+use crate::encryption::TrustRequirement;
 use crate::{
     event_handler::{EventHandler, EventHandlerHandle, SyncEvent},
     media::{MediaFormat, MediaRequest},
-    room::{Left, RoomMember, RoomState},
+    room::{
+        Left, MembershipSnapshot, ObservableRoomClientSettings, RoomClientSettingsEventContent,
+        RoomMember, RoomMemberList, RoomState, SuccessorRoom,
+    },
     sync::RoomUpdate,
     BaseRoom, Client, Error, HttpError, HttpResult, Result,
 };
@@ -106,11 +127,13 @@ impl Common {
         Self { inner: room, client }
     }
 
-    /// Leave this room.
+    /// Leave this room, with an optional reason.
     ///
     /// Only invited and joined rooms can be left.
-    pub(crate) async fn leave(&self) -> Result<Left> {
-        let request = leave_room::v3::Request::new(self.inner.room_id().to_owned());
+    pub(crate) async fn leave(&self, reason: Option<&str>) -> Result<Left> {
+        let request = assign!(leave_room::v3::Request::new(self.inner.room_id().to_owned()), {
+            reason: reason.map(ToOwned::to_owned),
+        });
         self.client.send(request, None).await?;
 
         let base_room = self.client.base_client().room_left(self.room_id()).await?;
@@ -258,6 +281,61 @@ impl Common {
         Ok(response)
     }
 
+    /// Export the media attachments of this room to a directory.
+    ///
+    /// The timeline of the room is paginated backwards to find the images,
+    /// videos, audio files and generic files sent in the given range, which
+    /// are then downloaded, decrypted if needed, and written to `dest_dir`.
+    ///
+    /// The exported files are named after the date they were sent, their
+    /// original name and their event ID, so running the export again skips
+    /// the attachments that were already exported.
+    ///
+    /// The returned future can be used to subscribe to the progress of the
+    /// export before awaiting it.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The range of events to export the attachments of.
+    ///
+    /// * `dest_dir` - The directory to write the files to. It is created if it
+    ///   doesn't exist.
+    ///
+    /// * `options` - The kinds of attachments to export and the number of
+    ///   concurrent downloads.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::room_id};
+    /// use matrix_sdk::room::{MediaExportOptions, MediaExportRange};
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # async {
+    /// # let client = Client::new(homeserver).await.unwrap();
+    /// let room = client.get_room(room_id!("!roomid:example.com")).unwrap();
+    ///
+    /// let export = room.export_media(
+    ///     MediaExportRange::All,
+    ///     "/tmp/export",
+    ///     MediaExportOptions::new(),
+    /// );
+    /// let mut progress = export.subscribe_to_progress();
+    ///
+    /// let result = export.await?;
+    /// println!("Exported {} attachments", result.exported);
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn export_media(
+        &self,
+        range: MediaExportRange,
+        dest_dir: impl Into<PathBuf>,
+        options: MediaExportOptions,
+    ) -> ExportMedia<'_> {
+        ExportMedia::new(self, range, dest_dir.into(), options)
+    }
+
     /// Register a handler for events of a specific type, within this room.
     ///
     /// This method works the same way as [`Client::add_event_handler`], except
@@ -275,6 +353,22 @@ impl Common {
         self.client.add_room_event_handler(self.room_id(), handler)
     }
 
+    /// Register a handler for events of a specific type, within this room, with
+    /// the given priority.
+    ///
+    /// See [`Client::add_event_handler_with_priority`] for more details.
+    pub fn add_event_handler_with_priority<Ev, Ctx, H>(
+        &self,
+        priority: i32,
+        handler: H,
+    ) -> EventHandlerHandle
+    where
+        Ev: SyncEvent + DeserializeOwned + Send + 'static,
+        H: EventHandler<Ev, Ctx>,
+    {
+        self.client.add_room_event_handler_with_priority(self.room_id(), priority, handler)
+    }
+
     /// Subscribe to all updates for this room.
     ///
     /// The returned receiver will receive a new message for each sync response
@@ -283,12 +377,230 @@ impl Common {
         self.client.subscribe_to_room_updates(self.room_id())
     }
 
+    /// Get the room that replaces this room, if it has been tombstoned.
+    ///
+    /// See [`Client::follow_room_upgrades()`] to join the successors of the
+    /// rooms automatically.
+    pub fn successor(&self) -> Option<SuccessorRoom> {
+        let tombstone = self.inner.tombstone()?;
+        Some(SuccessorRoom {
+            room_id: tombstone.replacement_room,
+            reason: Some(tombstone.body).filter(|body| !body.is_empty()),
+        })
+    }
+
     /// Fetch the event with the given `EventId` in this room.
     pub async fn event(&self, event_id: &EventId) -> Result<TimelineEvent> {
         let request =
             get_room_event::v3::Request::new(self.room_id().to_owned(), event_id.to_owned());
         let event = self.client.send(request, None).await?.event;
 
+        self.decrypt_timeline_event(event).await
+    }
+
+    /// Find the event closest to the given timestamp in this room.
+    ///
+    /// This uses the `/timestamp_to_event` endpoint. `dir` decides whether the
+    /// closest event before ([`Direction::Backward`]) or after
+    /// ([`Direction::Forward`]) the timestamp is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `ts` - The timestamp to look for.
+    ///
+    /// * `dir` - The direction in which to search from `ts`.
+    #[instrument(skip(self), fields(room_id = ?self.inner.room_id()))]
+    pub async fn event_by_timestamp(
+        &self,
+        ts: MilliSecondsSinceUnixEpoch,
+        dir: Direction,
+    ) -> Result<(OwnedEventId, MilliSecondsSinceUnixEpoch)> {
+        let request = get_event_by_timestamp::v1::Request::new(self.room_id().to_owned(), dir, ts);
+        let response = self.client.send(request, None).await?;
+
+        Ok((response.event_id, response.origin_server_ts))
+    }
+
+    /// Fetch the event with the given `EventId` in this room, along with some
+    /// of the events that surround it and the state of the room at this event.
+    ///
+    /// This uses the `/context` endpoint. Encrypted events are decrypted if
+    /// possible; if decryption fails for an individual event, that event is
+    /// returned undecrypted.
+    ///
+    /// The homeserver splits the number of events evenly between the events
+    /// before and after the requested event, so the events of the larger of
+    /// `before` and `after` that don't fit in the `/context` response are
+    /// loaded with the `/messages` endpoint, from the pagination token of that
+    /// side.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The ID of the event to fetch the context of.
+    ///
+    /// * `before` - The maximum number of events to return before the
+    ///   requested event.
+    ///
+    /// * `after` - The maximum number of events to return after the requested
+    ///   event.
+    #[instrument(skip(self), fields(room_id = ?self.inner.room_id()))]
+    pub async fn event_with_context(
+        &self,
+        event_id: &EventId,
+        before: UInt,
+        after: UInt,
+    ) -> Result<EventWithContext> {
+        let request = assign!(
+            get_context::v3::Request::new(self.room_id().to_owned(), event_id.to_owned()),
+            { limit: before.min(after).saturating_mul(uint!(2)) }
+        );
+        let response = self.client.send(request, None).await?;
+
+        let event = match response.event {
+            Some(event) => Some(self.decrypt_timeline_event(event).await?),
+            None => None,
+        };
+
+        let mut events_before = Vec::with_capacity(response.events_before.len());
+        for event in response.events_before {
+            events_before.push(self.decrypt_timeline_event(event).await?);
+        }
+        let prev_batch_token = self
+            .load_missing_context(&mut events_before, response.start, before, Direction::Backward)
+            .await?;
+
+        let mut events_after = Vec::with_capacity(response.events_after.len());
+        for event in response.events_after {
+            events_after.push(self.decrypt_timeline_event(event).await?);
+        }
+        let next_batch_token = self
+            .load_missing_context(&mut events_after, response.end, after, Direction::Forward)
+            .await?;
+
+        Ok(EventWithContext {
+            event,
+            events_before,
+            events_after,
+            state: response.state,
+            prev_batch_token,
+            next_batch_token,
+        })
+    }
+
+    /// Load the events of one side of the context of an event that didn't fit
+    /// in the `/context` response, and return the pagination token of that
+    /// side.
+    async fn load_missing_context(
+        &self,
+        events: &mut Vec<TimelineEvent>,
+        token: Option<String>,
+        limit: UInt,
+        dir: Direction,
+    ) -> Result<Option<String>> {
+        let missing = limit.saturating_sub(UInt::try_from(events.len()).unwrap_or(UInt::MAX));
+        if missing == uint!(0) {
+            return Ok(token);
+        }
+        let Some(token) = token else {
+            return Ok(None);
+        };
+
+        let options = assign!(MessagesOptions::new(dir).from(token.as_str()), { limit: missing });
+        let messages = self.messages(options).await?;
+        events.extend(messages.chunk);
+
+        Ok(messages.end)
+    }
+
+    /// Load a page of the events that relate to the event with the given ID,
+    /// like its edits, the reactions to it or the replies in its thread.
+    ///
+    /// This uses the `/relations` endpoint. Encrypted events are decrypted if
+    /// possible; if decryption fails for an individual event, that event is
+    /// returned undecrypted. Use [`Relations::related_events()`] to get the
+    /// typed relations of the page.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The ID of the event the relations point to.
+    ///
+    /// * `rel_type` - The type of the relations to load, or `None` to load all
+    ///   of them.
+    ///
+    /// * `from` - The [`Relations::next_batch_token`] of the previous page, or
+    ///   `None` to load the first page.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::{event_id, events::relation::RelationType, room_id}};
+    /// use matrix_sdk::room::RelatedEvent;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// # let room = client.get_room(room_id!("!test:localhost")).unwrap();
+    /// let event_id = event_id!("$message:localhost");
+    /// let mut from = None;
+    ///
+    /// loop {
+    ///     let relations =
+    ///         room.relations(event_id, Some(RelationType::Replacement), from.as_deref()).await?;
+    ///
+    ///     for related_event in relations.related_events() {
+    ///         if let RelatedEvent::Edit { sender, new_content, .. } = related_event {
+    ///             println!("{sender} edited the message: {}", new_content.body());
+    ///         }
+    ///     }
+    ///
+    ///     match relations.next_batch_token {
+    ///         Some(token) => from = Some(token),
+    ///         None => break,
+    ///     }
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[instrument(skip(self), fields(room_id = ?self.inner.room_id()))]
+    pub async fn relations(
+        &self,
+        event_id: &EventId,
+        rel_type: Option<RelationType>,
+        from: Option<&str>,
+    ) -> Result<Relations> {
+        let room_id = self.room_id().to_owned();
+        let event_id = event_id.to_owned();
+        let from = from.map(ToOwned::to_owned);
+
+        let (chunk, next_batch_token, prev_batch_token) = match rel_type {
+            Some(rel_type) => {
+                let request = assign!(
+                    get_relating_events_with_rel_type::v1::Request::new(
+                        room_id, event_id, rel_type
+                    ),
+                    { from }
+                );
+                let response = self.client.send(request, None).await?;
+                (response.chunk, response.next_batch, response.prev_batch)
+            }
+            None => {
+                let request =
+                    assign!(get_relating_events::v1::Request::new(room_id, event_id), { from });
+                let response = self.client.send(request, None).await?;
+                (response.chunk, response.next_batch, response.prev_batch)
+            }
+        };
+
+        let mut events = Vec::with_capacity(chunk.len());
+        for event in chunk {
+            events.push(self.decrypt_timeline_event(event.cast()).await?);
+        }
+
+        Ok(Relations { chunk: events, next_batch_token, prev_batch_token })
+    }
+
+    /// Turn a raw event received from the server into a [`TimelineEvent`],
+    /// decrypting it if needed and computing its push actions.
+    async fn decrypt_timeline_event(&self, event: Raw<AnyTimelineEvent>) -> Result<TimelineEvent> {
         #[cfg(feature = "e2e-encryption")]
         if let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(
             SyncMessageLikeEvent::Original(_),
@@ -375,9 +687,8 @@ impl Common {
             room_info.mark_encryption_state_synced();
             room_info.set_encryption_event(response.clone());
             let mut changes = StateChanges::default();
-            changes.add_room(room_info.clone());
-            self.client.store().save_changes(&changes).await?;
-            self.update_summary(room_info);
+            changes.add_room(room_info);
+            self.client.base_client().save_changes(&changes).await?;
             drop(sync_lock);
 
             self.client.inner.encryption_state_request_locks.remove(self.inner.room_id());
@@ -551,6 +862,29 @@ impl Common {
             .collect())
     }
 
+    /// Export a snapshot of the members, power levels and join rule of this
+    /// room, e.g. for moderation audits.
+    ///
+    /// If end-to-end encryption is enabled, the snapshot is signed with the
+    /// keys of this device, and can be verified later with
+    /// [`MembershipSnapshot::verify()`].
+    ///
+    /// *Note*: Like [`members()`](#method.members), this method will fetch the
+    /// members from the homeserver if the member list isn't synchronized.
+    pub async fn export_membership_snapshot(&self) -> Result<MembershipSnapshot> {
+        MembershipSnapshot::from_room(self).await
+    }
+
+    /// Get an observable list of the members of this room with the given
+    /// memberships, ordered by power level then by name.
+    ///
+    /// The list is kept up to date with the changes received in the sync, and
+    /// the members are loaded from the homeserver in the background if the
+    /// member list isn't synchronized. See [`RoomMemberList`] for more details.
+    pub async fn members_stream(&self, memberships: RoomMemberships) -> Result<RoomMemberList> {
+        RoomMemberList::new(self.clone(), memberships).await
+    }
+
     /// Get all state events of a given type in this room.
     pub async fn get_state_events(
         &self,
@@ -739,6 +1073,120 @@ impl Common {
         Ok(self.account_data(C::TYPE.into()).await?.map(Raw::cast))
     }
 
+    /// Set the given account data event in this room.
+    pub async fn set_account_data<C>(
+        &self,
+        content: C,
+    ) -> Result<set_room_account_data::v3::Response>
+    where
+        C: RoomAccountDataEventContent,
+    {
+        let user_id = self.client.user_id().ok_or(Error::AuthenticationRequired)?;
+        let request = set_room_account_data::v3::Request::new(
+            user_id.to_owned(),
+            self.room_id().to_owned(),
+            &content,
+        )?;
+
+        Ok(self.client.send(request, None).await?)
+    }
+
+    /// Set the given raw account data event in this room.
+    pub async fn set_account_data_raw(
+        &self,
+        event_type: RoomAccountDataEventType,
+        content: Raw<AnyRoomAccountDataEventContent>,
+    ) -> Result<set_room_account_data::v3::Response> {
+        let user_id = self.client.user_id().ok_or(Error::AuthenticationRequired)?;
+        let request = set_room_account_data::v3::Request::new_raw(
+            user_id.to_owned(),
+            self.room_id().to_owned(),
+            event_type,
+            content,
+        );
+
+        Ok(self.client.send(request, None).await?)
+    }
+
+    /// Get the settings of the clients in this room.
+    ///
+    /// Returns the default settings if they were never set.
+    pub async fn client_settings(&self) -> Result<RoomClientSettingsEventContent> {
+        Ok(self
+            .account_data_static::<RoomClientSettingsEventContent>()
+            .await?
+            .map(|event| event.deserialize())
+            .transpose()?
+            .map(|event| event.content)
+            .unwrap_or_default())
+    }
+
+    /// Replace the settings of the clients in this room.
+    ///
+    /// The new settings are saved locally once the homeserver accepted them,
+    /// without waiting for the next sync.
+    pub async fn set_client_settings(
+        &self,
+        settings: RoomClientSettingsEventContent,
+    ) -> Result<()> {
+        self.set_account_data(settings.clone()).await?;
+
+        let raw_event: Raw<AnyRoomAccountDataEvent> = Raw::new(&json!({
+            "type": RoomClientSettingsEventContent::TYPE,
+            "content": settings,
+        }))?
+        .cast();
+        let mut changes = StateChanges::default();
+        changes.add_room_account_data(self.room_id(), raw_event.deserialize()?, raw_event);
+
+        let _sync_lock = self.client.base_client().sync_lock().read().await;
+        self.client.base_client().save_changes(&changes).await?;
+
+        Ok(())
+    }
+
+    /// Update the settings of the clients in this room with the given
+    /// function.
+    ///
+    /// The updates made by this client are serialized, so concurrent updates
+    /// don't overwrite each other.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async {
+    /// # let room: matrix_sdk::room::Common = todo!();
+    /// room.update_client_settings(|settings| settings.url_previews = Some(false)).await?;
+    /// # anyhow::Ok(())
+    /// # };
+    /// ```
+    pub async fn update_client_settings(
+        &self,
+        update: impl FnOnce(&mut RoomClientSettingsEventContent),
+    ) -> Result<()> {
+        // The settings are replaced as a whole, so concurrent updates would
+        // overwrite each other.
+        let lock = self
+            .client
+            .inner
+            .client_settings_locks
+            .entry(self.room_id().to_owned())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+
+        let mut settings = self.client_settings().await?;
+        update(&mut settings);
+        self.set_client_settings(settings).await
+    }
+
+    /// Get an observable of the settings of the clients in this room.
+    ///
+    /// See [`ObservableRoomClientSettings`] for more details.
+    pub async fn subscribe_to_client_settings(&self) -> Result<ObservableRoomClientSettings> {
+        ObservableRoomClientSettings::new(self.clone()).await
+    }
+
     /// Check if all members of this room are verified and all their devices are
     /// verified.
     ///
@@ -760,6 +1208,26 @@ impl Common {
         Ok(true)
     }
 
+    /// Set the [`TrustRequirement`] for the devices that receive the room keys
+    /// of the messages we send in this room.
+    ///
+    /// It can only make the global requirement set with
+    /// [`Encryption::set_trust_requirement()`] stricter.
+    ///
+    /// [`Encryption::set_trust_requirement()`]: crate::encryption::Encryption::set_trust_requirement
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn set_trust_requirement(&self, trust_requirement: TrustRequirement) -> Result<()> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+        let store = olm.store();
+
+        let mut settings = store.get_room_settings(self.room_id()).await?.unwrap_or_default();
+        settings.trust_requirement = trust_requirement;
+        store.set_room_settings(self.room_id(), settings).await?;
+
+        Ok(())
+    }
+
     /// Adds a tag to the room, or updates it if it already exists.
     ///
     /// Returns the [`create_tag::v3::Response`] from the server.
@@ -830,43 +1298,28 @@ impl Common {
     /// # Arguments
     /// * `is_direct` - Whether to mark this room as direct.
     pub async fn set_is_direct(&self, is_direct: bool) -> Result<()> {
-        let user_id =
-            self.client.user_id().ok_or_else(|| Error::from(HttpError::AuthenticationRequired))?;
-
-        let mut content = self
-            .client
-            .account()
-            .account_data::<DirectEventContent>()
-            .await?
-            .map(|c| c.deserialize())
-            .transpose()?
-            .unwrap_or_default();
-
         let this_room_id = self.inner.room_id();
 
         if is_direct {
             let mut room_members = self.members(RoomMemberships::ACTIVE).await?;
             room_members.retain(|member| member.user_id() != self.own_user_id());
+            let user_ids: Vec<_> =
+                room_members.iter().map(|member| member.user_id().to_owned()).collect();
 
-            for member in room_members {
-                let entry = content.entry(member.user_id().to_owned()).or_default();
-                if !entry.iter().any(|room_id| room_id == this_room_id) {
-                    entry.push(this_room_id.to_owned());
-                }
-            }
+            self.client.account().mark_as_dm(this_room_id, &user_ids).await
         } else {
-            for (_, list) in content.iter_mut() {
-                list.retain(|room_id| *room_id != this_room_id);
-            }
-
-            // Remove user ids that don't have any room marked as DM
-            content.retain(|_, list| !list.is_empty());
+            self.client
+                .account()
+                .update_direct_rooms(|content| {
+                    for (_, list) in content.iter_mut() {
+                        list.retain(|room_id| *room_id != this_room_id);
+                    }
+
+                    // Remove user ids that don't have any room marked as DM
+                    content.retain(|_, list| !list.is_empty());
+                })
+                .await
         }
-
-        let request = set_global_account_data::v3::Request::new(user_id.to_owned(), &content)?;
-
-        self.client.send(request, None).await?;
-        Ok(())
     }
 
     /// Tries to decrypt a room event.
@@ -882,12 +1335,20 @@ impl Common {
     ) -> Result<TimelineEvent> {
         let machine = self.client.olm_machine().await;
         if let Some(machine) = machine.as_ref() {
-            let mut event =
+            let mut decrypted =
                 machine.decrypt_room_event(event.cast_ref(), self.inner.room_id()).await?;
 
-            event.push_actions = self.event_push_actions(&event.event).await?;
+            // The decrypted payload of a state event misses its state key.
+            #[cfg(feature = "experimental-encrypted-state-events")]
+            if encrypted_state::is_encrypted_state_event(event.cast_ref()) {
+                decrypted.event =
+                    encrypted_state::decrypted_state_event(event.cast_ref(), &decrypted.event)?
+                        .cast();
+            }
+
+            decrypted.push_actions = self.event_push_actions(&decrypted.event).await?;
 
-            Ok(event)
+            Ok(decrypted)
         } else {
             Err(Error::NoOlmMachine)
         }
@@ -952,6 +1413,36 @@ impl Common {
             .collect())
     }
 
+    /// Get the local aliases of this room, that were published on the room
+    /// directory of the homeserver.
+    ///
+    /// Unlike [`canonical_alias()`] and [`alt_aliases()`], which are
+    /// advertised in the state of the room, these aliases might not be
+    /// advertised.
+    ///
+    /// [`canonical_alias()`]: BaseRoom::canonical_alias
+    /// [`alt_aliases()`]: BaseRoom::alt_aliases
+    pub async fn local_aliases(&self) -> HttpResult<Vec<OwnedRoomAliasId>> {
+        let request = aliases::v3::Request::new(self.inner.room_id().to_owned());
+        Ok(self.client.send(request, None).await?.aliases)
+    }
+
+    /// Report this room to the administrators of the homeserver.
+    ///
+    /// This uses the room reporting endpoint added in Matrix 1.13, so it fails
+    /// if the homeserver doesn't support it.
+    ///
+    /// # Arguments
+    /// * `reason` - The reason why the room is reported.
+    pub async fn report_room(&self, reason: Option<&str>) -> HttpResult<()> {
+        let request = ReportRoomRequest {
+            room_id: self.inner.room_id().to_owned(),
+            reason: reason.map(ToOwned::to_owned),
+        };
+        self.client.send(request, None).await?;
+        Ok(())
+    }
+
     /// Get a `matrix.to` permalink to this room.
     ///
     /// If this room has an alias, we use it. Otherwise, we try to use the
@@ -1127,6 +1618,160 @@ impl Common {
     }
 }
 
+/// The result of a [`Common::event_with_context`] call.
+#[derive(Debug)]
+pub struct EventWithContext {
+    /// The requested event, if the homeserver returned it.
+    pub event: Option<TimelineEvent>,
+
+    /// Events that happened before the requested event, in reverse
+    /// chronological order.
+    pub events_before: Vec<TimelineEvent>,
+
+    /// Events that happened after the requested event, in chronological order.
+    pub events_after: Vec<TimelineEvent>,
+
+    /// The state of the room at the last event returned by the `/context`
+    /// endpoint.
+    ///
+    /// The events of `events_after` that were loaded afterwards with the
+    /// `/messages` endpoint are not taken into account.
+    pub state: Vec<Raw<AnyStateEvent>>,
+
+    /// A token that can be used to paginate backwards from the oldest event
+    /// in `events_before`.
+    pub prev_batch_token: Option<String>,
+
+    /// A token that can be used to paginate forwards from the newest event in
+    /// `events_after`.
+    pub next_batch_token: Option<String>,
+}
+
+/// The result of a [`Common::relations`] call.
+#[derive(Debug)]
+pub struct Relations {
+    /// The events that relate to the requested event, decrypted if possible.
+    pub chunk: Vec<TimelineEvent>,
+
+    /// A token that can be used to load the next page of relations, or `None`
+    /// if there are no more relations.
+    pub next_batch_token: Option<String>,
+
+    /// A token that can be used to load the previous page of relations.
+    pub prev_batch_token: Option<String>,
+}
+
+impl Relations {
+    /// Get the typed relations of this page, in the same order as
+    /// [`Self::chunk`].
+    ///
+    /// The events that can't be deserialized are skipped.
+    pub fn related_events(&self) -> Vec<RelatedEvent> {
+        self.chunk
+            .iter()
+            .filter_map(|event| match event.event.deserialize() {
+                Ok(ev) => Some(RelatedEvent::from_event(ev, &event.event)),
+                Err(error) => {
+                    debug!(?error, "Skipping a related event that couldn't be deserialized");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Aggregate the reactions of this page by key, with the senders of each
+    /// key in the order of the page.
+    pub fn reactions(&self) -> BTreeMap<String, Vec<OwnedUserId>> {
+        let mut reactions: BTreeMap<String, Vec<OwnedUserId>> = BTreeMap::new();
+
+        for related_event in self.related_events() {
+            if let RelatedEvent::Reaction { sender, key, .. } = related_event {
+                reactions.entry(key).or_default().push(sender);
+            }
+        }
+
+        reactions
+    }
+}
+
+/// An event that relates to another event, returned by
+/// [`Relations::related_events()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum RelatedEvent {
+    /// An edit of the event, with an `m.replace` relation.
+    Edit {
+        /// The ID of the edit event.
+        event_id: OwnedEventId,
+        /// The sender of the edit.
+        sender: OwnedUserId,
+        /// The timestamp of the edit.
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        /// The new content of the event.
+        new_content: MessageType,
+    },
+
+    /// A reaction to the event, with an `m.annotation` relation.
+    Reaction {
+        /// The ID of the reaction event.
+        event_id: OwnedEventId,
+        /// The sender of the reaction.
+        sender: OwnedUserId,
+        /// The timestamp of the reaction.
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        /// The key of the reaction, usually an emoji.
+        key: String,
+    },
+
+    /// A reply in the thread of the event, with an `m.thread` relation.
+    ThreadReply {
+        /// The ID of the reply.
+        event_id: OwnedEventId,
+        /// The sender of the reply.
+        sender: OwnedUserId,
+        /// The timestamp of the reply.
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        /// The content of the reply.
+        content: RoomMessageEventContent,
+    },
+
+    /// Another kind of relation, or an event that couldn't be decrypted.
+    Other(Raw<AnyTimelineEvent>),
+}
+
+impl RelatedEvent {
+    fn from_event(event: AnyTimelineEvent, raw: &Raw<AnyTimelineEvent>) -> Self {
+        match event {
+            AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                MessageLikeEvent::Original(ev),
+            )) => match ev.content.relates_to {
+                Some(Relation::Replacement(replacement)) => Self::Edit {
+                    event_id: ev.event_id,
+                    sender: ev.sender,
+                    origin_server_ts: ev.origin_server_ts,
+                    new_content: replacement.new_content,
+                },
+                Some(Relation::Thread(_)) => Self::ThreadReply {
+                    event_id: ev.event_id,
+                    sender: ev.sender,
+                    origin_server_ts: ev.origin_server_ts,
+                    content: ev.content,
+                },
+                _ => Self::Other(raw.clone()),
+            },
+            AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::Reaction(
+                MessageLikeEvent::Original(ev),
+            )) => Self::Reaction {
+                event_id: ev.event_id,
+                sender: ev.sender,
+                origin_server_ts: ev.origin_server_ts,
+                key: ev.content.relates_to.key,
+            },
+            _ => Self::Other(raw.clone()),
+        }
+    }
+}
+
 /// Options for [`messages`][Common::messages].
 ///
 /// See that method and
@@ -1220,3 +1865,72 @@ impl fmt::Debug for MessagesOptions {
         s.finish()
     }
 }
+
+/// Request to report a room to the administrators of the homeserver.
+///
+/// Ruma doesn't know about Matrix 1.13 yet, so the endpoint is declared as an
+/// unstable one with its stable path.
+#[derive(Clone, Debug)]
+struct ReportRoomRequest {
+    room_id: OwnedRoomId,
+    reason: Option<String>,
+}
+
+impl OutgoingRequest for ReportRoomRequest {
+    type EndpointError = ruma::api::client::Error;
+    type IncomingResponse = ReportRoomResponse;
+
+    const METADATA: Metadata = metadata! {
+        method: POST,
+        rate_limited: true,
+        authentication: AccessToken,
+        history: {
+            unstable => "/_matrix/client/v3/rooms/:room_id/report",
+        }
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+        considering_versions: &[MatrixVersion],
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let url = Self::METADATA.make_endpoint_url(
+            considering_versions,
+            base_url,
+            &[&self.room_id],
+            "",
+        )?;
+        let body = json!({ "reason": self.reason.unwrap_or_default() });
+
+        let mut request = http::Request::builder()
+            .method(Self::METADATA.method)
+            .uri(url)
+            .header(http::header::CONTENT_TYPE, "application/json");
+        if let Some((name, value)) = Self::METADATA.authorization_header(access_token)? {
+            request = request.header(name, value);
+        }
+
+        Ok(request.body(json_to_buf(&body)?)?)
+    }
+}
+
+/// Response to a [`ReportRoomRequest`], which is empty.
+#[derive(Clone, Debug)]
+struct ReportRoomResponse;
+
+impl IncomingResponse for ReportRoomResponse {
+    type EndpointError = ruma::api::client::Error;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<Self::EndpointError>> {
+        if response.status().as_u16() >= 400 {
+            return Err(FromHttpResponseError::Server(
+                ruma::api::client::Error::from_http_response(response),
+            ));
+        }
+
+        Ok(Self)
+    }
+}
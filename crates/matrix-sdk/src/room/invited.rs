@@ -54,8 +54,26 @@ impl Invited {
     }
 
     /// Reject the invitation.
+    ///
+    /// See [`Invited::reject_invite()`] to give a reason or report the room.
     pub async fn reject_invitation(&self) -> Result<Left> {
-        self.inner.leave().await
+        self.inner.leave(None).await
+    }
+
+    /// Reject the invitation, with an optional reason.
+    ///
+    /// If `report` is `true`, the room is first reported to the administrators
+    /// of the homeserver with [`Common::report_room()`], e.g. because the
+    /// invite is spam or abusive. The invite is rejected even if the room
+    /// couldn't be reported.
+    pub async fn reject_invite(&self, reason: Option<&str>, report: bool) -> Result<Left> {
+        if report {
+            if let Err(error) = self.inner.report_room(reason).await {
+                warn!(room_id = ?self.room_id(), "Couldn't report the room: {error}");
+            }
+        }
+
+        self.inner.leave(reason).await
     }
 
     /// Accept the invitation.
@@ -14,7 +14,10 @@ use super::Joined;
 use crate::{attachment::AttachmentConfig, Result, TransmissionProgress};
 #[cfg(feature = "image-proc")]
 use crate::{
-    attachment::{generate_image_thumbnail, Thumbnail},
+    attachment::{
+        generate_image_thumbnail, replace_extension, AttachmentInfo, BaseImageInfo,
+        ImageProcessingReport, Thumbnail,
+    },
     error::ImageError,
 };
 
@@ -27,6 +30,8 @@ pub struct SendAttachment<'a> {
     config: AttachmentConfig,
     tracing_span: Span,
     send_progress: SharedObservable<TransmissionProgress>,
+    #[cfg(feature = "image-proc")]
+    processing_report: SharedObservable<Option<ImageProcessingReport>>,
 }
 
 impl<'a> SendAttachment<'a> {
@@ -45,6 +50,8 @@ impl<'a> SendAttachment<'a> {
             config,
             tracing_span: Span::current(),
             send_progress: Default::default(),
+            #[cfg(feature = "image-proc")]
+            processing_report: Default::default(),
         }
     }
 
@@ -62,6 +69,22 @@ impl<'a> SendAttachment<'a> {
         self.send_progress = send_progress;
         self
     }
+
+    /// Replace the default `SharedObservable` used for reporting the changes
+    /// made to the image by the [`AttachmentSendPolicy`] of the
+    /// [`AttachmentConfig`].
+    ///
+    /// It is set before the image is uploaded, if the policy was applied.
+    ///
+    /// [`AttachmentSendPolicy`]: crate::attachment::AttachmentSendPolicy
+    #[cfg(feature = "image-proc")]
+    pub fn with_processing_report_observable(
+        mut self,
+        processing_report: SharedObservable<Option<ImageProcessingReport>>,
+    ) -> Self {
+        self.processing_report = processing_report;
+        self
+    }
 }
 
 impl<'a> IntoFuture for SendAttachment<'a> {
@@ -72,8 +95,69 @@ impl<'a> IntoFuture for SendAttachment<'a> {
     type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send + 'a>>;
 
     fn into_future(self) -> Self::IntoFuture {
+        #[cfg(not(feature = "image-proc"))]
         let Self { room, body, content_type, data, config, tracing_span, send_progress } = self;
+        #[cfg(feature = "image-proc")]
+        let Self {
+            room,
+            body,
+            content_type,
+            data,
+            mut config,
+            tracing_span,
+            send_progress,
+            processing_report,
+        } = self;
+
         let fut = async move {
+            #[cfg(feature = "image-proc")]
+            let processed_content_type;
+            #[cfg(feature = "image-proc")]
+            let processed_body;
+            #[cfg(feature = "image-proc")]
+            let (body, content_type, data) = match config.send_policy.take() {
+                Some(policy) if policy.supports(content_type) => {
+                    let original_content_type = content_type.clone();
+                    let process = move || policy.apply(&original_content_type, data);
+
+                    #[cfg(not(target_arch = "wasm32"))]
+                    let res = tokio::task::spawn_blocking(process).await.expect("Task join error");
+
+                    #[cfg(target_arch = "wasm32")]
+                    let res = process();
+
+                    match res {
+                        Ok(processed) => {
+                            let report = processed.report;
+                            if let Some(AttachmentInfo::Image(info)) = &mut config.info {
+                                info.width = Some(report.sent_dimensions.0.into());
+                                info.height = Some(report.sent_dimensions.1.into());
+                                info.size = u32::try_from(report.sent_size).ok().map(Into::into);
+                            } else if config.info.is_none() {
+                                config.info = Some(AttachmentInfo::Image(BaseImageInfo {
+                                    width: Some(report.sent_dimensions.0.into()),
+                                    height: Some(report.sent_dimensions.1.into()),
+                                    size: u32::try_from(report.sent_size).ok().map(Into::into),
+                                    blurhash: None,
+                                }));
+                            }
+                            processing_report.set(Some(report));
+
+                            // The file name must match the new format.
+                            processed_body = if processed.content_type != *content_type {
+                                replace_extension(body, &processed.content_type)
+                            } else {
+                                body.to_owned()
+                            };
+                            processed_content_type = processed.content_type;
+                            (processed_body.as_str(), &processed_content_type, processed.data)
+                        }
+                        Err(error) => return Err(error.into()),
+                    }
+                }
+                _ => (body, content_type, data),
+            };
+
             if config.thumbnail.is_some() {
                 room.prepare_and_send_attachment(body, content_type, data, config, send_progress)
                     .await
@@ -132,6 +216,8 @@ impl<'a> IntoFuture for SendAttachment<'a> {
                     generate_thumbnail: false,
                     #[cfg(feature = "image-proc")]
                     thumbnail_size: None,
+                    #[cfg(feature = "image-proc")]
+                    send_policy: None,
                 };
 
                 room.prepare_and_send_attachment(body, content_type, data, config, send_progress)
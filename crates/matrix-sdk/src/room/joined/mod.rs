@@ -4,11 +4,17 @@ use std::{borrow::Borrow, ops::Deref};
 
 use eyeball::shared::Observable as SharedObservable;
 #[cfg(feature = "e2e-encryption")]
-use matrix_sdk_base::RoomMemberships;
+use matrix_sdk_base::{crypto::OlmError, Error as SdkBaseError, RoomMemberships};
+#[cfg(feature = "experimental-encrypted-state-events")]
+use matrix_sdk_base::{deserialized_responses::RawAnySyncOrStrippedState, encrypted_state};
+use matrix_sdk_base::{StateStoreDataKey, StateStoreDataValue};
 use matrix_sdk_common::instant::{Duration, Instant};
 use mime::{self, Mime};
+#[cfg(feature = "e2e-encryption")]
+use ruma::OwnedDeviceId;
 use ruma::{
     api::client::{
+        alias::{create_alias, delete_alias},
         membership::{
             ban_user,
             invite_user::{self, v3::InvitationRecipient},
@@ -18,6 +24,7 @@ use ruma::{
         read_marker::set_read_marker,
         receipt::create_receipt::{self, v3::ReceiptType},
         redact::redact_event,
+        room::report_content,
         state::send_state_event,
         typing::create_typing_event::v3::{Request as TypingRequest, Typing},
     },
@@ -26,6 +33,7 @@ use ruma::{
         receipt::ReceiptThread,
         room::{
             avatar::{ImageInfo, RoomAvatarEventContent},
+            canonical_alias::RoomCanonicalAliasEventContent,
             message::RoomMessageEventContent,
             name::RoomNameEventContent,
             power_levels::RoomPowerLevelsEventContent,
@@ -34,18 +42,21 @@ use ruma::{
         EmptyStateKey, MessageLikeEventContent, StateEventContent,
     },
     serde::Raw,
-    EventId, Int, MxcUri, OwnedEventId, OwnedTransactionId, TransactionId, UserId,
+    EventId, Int, MxcUri, OwnedEventId, OwnedRoomAliasId, OwnedTransactionId, OwnedUserId,
+    RoomAliasId, TransactionId, UserId,
 };
+#[cfg(feature = "experimental-encrypted-state-events")]
+use ruma::{events::StateEventType, serde::JsonObject};
 use serde_json::Value;
 #[cfg(feature = "e2e-encryption")]
 use tokio::sync::Mutex;
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
 
 use super::Left;
 use crate::{
     attachment::AttachmentConfig,
     error::{Error, HttpResult},
-    room::Common,
+    room::{Common, InviteUsers},
     BaseRoom, Client, Result, RoomState, TransmissionProgress,
 };
 
@@ -93,7 +104,7 @@ impl Joined {
     /// Leave this room.
     #[instrument(skip_all)]
     pub async fn leave(&self) -> Result<Left> {
-        self.inner.leave().await
+        self.inner.leave(None).await
     }
 
     /// Ban the user with `UserId` from this room.
@@ -146,6 +157,41 @@ impl Joined {
         Ok(())
     }
 
+    /// Invite the specified users by `UserId` to this room.
+    ///
+    /// The invites are sent one after the other, pacing them when the
+    /// homeserver rate-limits them. A failure to invite a user doesn't stop the
+    /// other invites, and the users who are already members of the room or
+    /// invited to it are skipped.
+    ///
+    /// Returns a report with the outcome of the invite of each user. The
+    /// progress can be observed with [`InviteUsers::subscribe_to_progress()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `user_ids` - The IDs of the users to invite to the room.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::{owned_user_id, room_id}};
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// # let room = client.get_joined_room(room_id!("!test:localhost")).unwrap();
+    /// let user_ids = vec![owned_user_id!("@alice:localhost"), owned_user_id!("@bob:localhost")];
+    /// let report = room.invite_users(user_ids).await;
+    ///
+    /// for (user_id, error) in report.failed() {
+    ///     println!("Couldn't invite {user_id}: {error}");
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub fn invite_users(&self, user_ids: impl IntoIterator<Item = OwnedUserId>) -> InviteUsers<'_> {
+        InviteUsers::new(self, user_ids)
+    }
+
     /// Invite the specified user by third party id to this room.
     ///
     /// # Arguments
@@ -354,6 +400,99 @@ impl Joined {
         Ok(())
     }
 
+    /// Agree to encrypt the state events of this room, by enabling it in the
+    /// `m.room.encryption` event.
+    ///
+    /// If the room is not encrypted yet, this enables encryption too. Only the
+    /// types set with [`ClientBuilder::encrypt_state_events()`] are encrypted.
+    ///
+    /// This is an experimental implementation of [MSC3414].
+    ///
+    /// [`ClientBuilder::encrypt_state_events()`]: crate::ClientBuilder::encrypt_state_events
+    /// [MSC3414]: https://github.com/matrix-org/matrix-spec-proposals/pull/3414
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    #[instrument(skip_all)]
+    pub async fn enable_state_event_encryption(&self) -> Result<()> {
+        if self.encrypts_state_events().await? {
+            return Ok(());
+        }
+
+        let mut content = self.encryption_event_content().await?.unwrap_or_else(|| {
+            let algorithm = ruma::EventEncryptionAlgorithm::MegolmV1AesSha2;
+            JsonObject::from_iter([("algorithm".to_owned(), algorithm.to_string().into())])
+        });
+        content.insert(encrypted_state::ENCRYPT_STATE_EVENTS_FIELD.to_owned(), true.into());
+
+        self.send_state_event_raw(content.into(), "m.room.encryption", "").await?;
+        Ok(())
+    }
+
+    /// Whether the participants of this room agreed to encrypt state events.
+    ///
+    /// See [`Joined::enable_state_event_encryption()`].
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    pub async fn encrypts_state_events(&self) -> Result<bool> {
+        if !self.is_encrypted().await? {
+            return Ok(false);
+        }
+
+        let content = self.encryption_event_content().await?;
+        Ok(content
+            .and_then(|c| c.get(encrypted_state::ENCRYPT_STATE_EVENTS_FIELD)?.as_bool())
+            .unwrap_or(false))
+    }
+
+    /// Get the raw content of the `m.room.encryption` event of this room, to
+    /// keep the fields unknown to ruma.
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    async fn encryption_event_content(&self) -> Result<Option<JsonObject>> {
+        Ok(match self.get_state_event(StateEventType::RoomEncryption, "").await? {
+            Some(RawAnySyncOrStrippedState::Sync(raw)) => raw.get_field("content")?,
+            _ => None,
+        })
+    }
+
+    /// Whether a state event of the given type should be encrypted.
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    async fn should_encrypt_state_event(&self, event_type: &StateEventType) -> Result<bool> {
+        Ok(self.client.inner.encrypted_state_event_types.contains(event_type)
+            && encrypted_state::can_be_encrypted(event_type)
+            && self.encrypts_state_events().await?)
+    }
+
+    /// Encrypt a state event and send it as an `m.room.encrypted` state event.
+    #[cfg(feature = "experimental-encrypted-state-events")]
+    #[instrument(skip_all, fields(room_id = ?self.room_id()))]
+    async fn send_encrypted_state_event(
+        &self,
+        content: Value,
+        event_type: &str,
+        state_key: &str,
+    ) -> Result<send_state_event::v3::Response> {
+        debug!(event_type, "Sending encrypted state event because the room agreed to it.");
+
+        if !self.are_members_synced() {
+            self.sync_members().await?;
+        }
+
+        self.preshare_room_key().await?;
+
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().expect("Olm machine wasn't started");
+
+        let encrypted_content =
+            olm.encrypt_room_event_raw(self.inner.room_id(), content, event_type).await?;
+
+        let request = send_state_event::v3::Request::new_raw(
+            self.inner.room_id().to_owned(),
+            "m.room.encrypted".into(),
+            encrypted_state::pack_state_key(event_type, state_key),
+            encrypted_content.cast(),
+        );
+
+        Ok(self.client.send(request, None).await?)
+    }
+
     /// Share a room key with users in the given room.
     ///
     /// This will create Olm sessions with all the users/device pairs in the
@@ -388,6 +527,13 @@ impl Joined {
                     .store()
                     .get_user_ids(self.inner.room_id(), RoomMemberships::ACTIVE)
                     .await?;
+
+                // The members of this room should get their keys queried before the
+                // members of the other rooms.
+                if let Some(machine) = self.client.olm_machine().await.as_ref() {
+                    machine.prioritize_key_queries(members.iter().map(Deref::deref));
+                }
+
                 self.client.claim_one_time_keys(members.iter().map(Deref::deref)).await?;
             };
 
@@ -410,6 +556,34 @@ impl Joined {
         Ok(())
     }
 
+    /// Check that our own other devices can decrypt the last message sent in
+    /// this room.
+    ///
+    /// This can be called after sending a message in an encrypted room, to
+    /// warn the user that they won't be able to read it on their other devices.
+    /// If some of them can't, the room key is shared again so they will be able
+    /// to read the next messages, e.g. if one of them appeared after the room
+    /// key was shared.
+    ///
+    /// Returns the IDs of our own other devices that can't decrypt the last
+    /// message. The blacklisted and deleted devices are ignored.
+    #[cfg(feature = "e2e-encryption")]
+    #[instrument(skip_all, fields(room_id = ?self.room_id()))]
+    pub async fn own_devices_without_room_key(&self) -> Result<Vec<OwnedDeviceId>> {
+        let device_ids = {
+            let olm = self.client.olm_machine().await;
+            let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+            olm.own_devices_without_room_key(self.inner.room_id()).await?
+        };
+
+        if !device_ids.is_empty() {
+            debug!(?device_ids, "Some of our own devices can't decrypt the last message");
+            self.preshare_room_key().await?;
+        }
+
+        Ok(device_ids)
+    }
+
     /// Share a group session for a room.
     ///
     /// # Panics
@@ -418,7 +592,19 @@ impl Joined {
     #[cfg(feature = "e2e-encryption")]
     #[instrument(skip_all)]
     async fn share_room_key(&self) -> Result<()> {
-        let requests = self.client.base_client().share_room_key(self.inner.room_id()).await?;
+        let room_id = self.inner.room_id();
+        let requests = match self.client.base_client().share_room_key(room_id).await {
+            Err(SdkBaseError::OlmError(OlmError::UntrustedDevices(devices))) => {
+                // Give the application a chance to decide what to do with the
+                // untrusted devices before giving up.
+                if !self.client.encryption().resolve_untrusted_devices(room_id, &devices).await? {
+                    return Err(OlmError::UntrustedDevices(devices).into());
+                }
+
+                self.client.base_client().share_room_key(room_id).await?
+            }
+            result => result?,
+        };
 
         for request in requests {
             let response = self.client.send_to_device(&request).await?;
@@ -651,12 +837,27 @@ impl Joined {
 
         let request = send_message_event::v3::Request::new_raw(
             self.inner.room_id().to_owned(),
-            txn_id,
+            txn_id.clone(),
             event_type.into(),
             content,
         );
 
         let response = self.client.send(request, None).await?;
+
+        // Remember the ID of the event, so that the other processes using the
+        // same store can match its remote echo with their local echo.
+        if let Err(error) = self
+            .client
+            .store()
+            .set_kv_data(
+                StateStoreDataKey::SentEvent(&txn_id),
+                StateStoreDataValue::SentEvent(response.event_id.clone()),
+            )
+            .await
+        {
+            warn!("Failed to save the ID of the sent event: {error}");
+        }
+
         Ok(response)
     }
 
@@ -834,6 +1035,48 @@ impl Joined {
         self.send_state_event(topic_event).await
     }
 
+    /// Publish the given alias for this room on the room directory of the
+    /// homeserver of the alias.
+    ///
+    /// This doesn't advertise the alias in the state of the room, see
+    /// [`Joined::set_canonical_alias()`] for that.
+    pub async fn publish_alias(&self, alias: &RoomAliasId) -> HttpResult<()> {
+        let request =
+            create_alias::v3::Request::new(alias.to_owned(), self.inner.room_id().to_owned());
+        self.client.send(request, None).await?;
+        self.client.inner.room_alias_cache.remove(alias);
+        Ok(())
+    }
+
+    /// Remove the given alias of this room from the room directory of the
+    /// homeserver of the alias.
+    ///
+    /// This doesn't remove the alias from the state of the room, see
+    /// [`Joined::set_canonical_alias()`] for that.
+    pub async fn remove_alias(&self, alias: &RoomAliasId) -> HttpResult<()> {
+        let request = delete_alias::v3::Request::new(alias.to_owned());
+        self.client.send(request, None).await?;
+        self.client.inner.room_alias_cache.remove(alias);
+        Ok(())
+    }
+
+    /// Sets the canonical alias and the alternative aliases of this room, in a
+    /// single `m.room.canonical_alias` state event.
+    ///
+    /// The homeserver might refuse aliases that don't point to this room.
+    ///
+    /// # Arguments
+    /// * `alias` - The canonical alias of the room, if any.
+    /// * `alt_aliases` - The other aliases advertised for the room.
+    pub async fn set_canonical_alias(
+        &self,
+        alias: Option<OwnedRoomAliasId>,
+        alt_aliases: Vec<OwnedRoomAliasId>,
+    ) -> Result<send_state_event::v3::Response> {
+        let content = assign!(RoomCanonicalAliasEventContent::new(), { alias, alt_aliases });
+        self.send_state_event(content).await
+    }
+
     /// Sets the new avatar url for this room.
     ///
     /// # Arguments
@@ -980,6 +1223,13 @@ impl Joined {
         C::StateKey: Borrow<K>,
         K: AsRef<str> + ?Sized,
     {
+        #[cfg(feature = "experimental-encrypted-state-events")]
+        if self.should_encrypt_state_event(&content.event_type()).await? {
+            let event_type = content.event_type().to_string();
+            let content = serde_json::to_value(&content)?;
+            return self.send_encrypted_state_event(content, &event_type, state_key.as_ref()).await;
+        }
+
         let request = send_state_event::v3::Request::new(
             self.inner.room_id().to_owned(),
             state_key,
@@ -1029,6 +1279,11 @@ impl Joined {
         event_type: &str,
         state_key: &str,
     ) -> Result<send_state_event::v3::Response> {
+        #[cfg(feature = "experimental-encrypted-state-events")]
+        if self.should_encrypt_state_event(&event_type.into()).await? {
+            return self.send_encrypted_state_event(content, event_type, state_key).await;
+        }
+
         let content = Raw::new(&content)?.cast();
         let request = send_state_event::v3::Request::new_raw(
             self.inner.room_id().to_owned(),
@@ -1089,6 +1344,51 @@ impl Joined {
 
         self.client.send(request, None).await
     }
+
+    /// Report an event of this room as inappropriate to the homeserver
+    /// administrators.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The ID of the event to report.
+    ///
+    /// * `score` - How offensive the event is, from `-100` for the most
+    ///   offensive to `0` for inoffensive.
+    ///
+    /// * `reason` - The reason why the event is reported.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use matrix_sdk::ruma::{event_id, int};
+    ///
+    /// # async {
+    /// # let homeserver = url::Url::parse("http://localhost:8080")?;
+    /// # let mut client = matrix_sdk::Client::new(homeserver).await?;
+    /// # let room_id = matrix_sdk::ruma::room_id!("!test:localhost");
+    /// #
+    /// if let Some(room) = client.get_joined_room(&room_id) {
+    ///     let event_id = event_id!("$xxxxxx:example.org");
+    ///     room.report_event(&event_id, Some(int!(-100)), Some("Spam")).await?;
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[instrument(skip_all)]
+    pub async fn report_event(
+        &self,
+        event_id: &EventId,
+        score: Option<Int>,
+        reason: Option<&str>,
+    ) -> HttpResult<report_content::v3::Response> {
+        let request = report_content::v3::Request::new(
+            self.inner.room_id().to_owned(),
+            event_id.to_owned(),
+            score,
+            reason.map(ToOwned::to_owned),
+        );
+
+        self.client.send(request, None).await
+    }
 }
 
 /// Receipts to send all at once.
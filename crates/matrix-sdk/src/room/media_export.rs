@@ -0,0 +1,418 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    future::{Future, IntoFuture},
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use futures_util::{stream, StreamExt};
+use ruma::{
+    events::{
+        room::{
+            message::{MessageType, Relation},
+            MediaSource,
+        },
+        AnyMessageLikeEvent, AnyTimelineEvent, MessageLikeEvent,
+    },
+    MilliSecondsSinceUnixEpoch, OwnedEventId,
+};
+use tracing::{debug, warn, Instrument, Span};
+
+use super::{Common, MessagesOptions};
+use crate::{
+    media::{MediaFormat, MediaRequest},
+    Result,
+};
+
+/// The maximum length of the name of an attachment, in an exported file name.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// The events to export the media of, with [`Common::export_media()`].
+#[derive(Clone, Copy, Debug, Default)]
+pub enum MediaExportRange {
+    /// All the events of the room.
+    #[default]
+    All,
+    /// The events sent between the two given times, inclusive.
+    Between {
+        /// The time of the oldest event to export.
+        start: MilliSecondsSinceUnixEpoch,
+        /// The time of the most recent event to export.
+        end: MilliSecondsSinceUnixEpoch,
+    },
+}
+
+impl MediaExportRange {
+    fn contains(&self, ts: MilliSecondsSinceUnixEpoch) -> bool {
+        match self {
+            Self::All => true,
+            Self::Between { start, end } => *start <= ts && ts <= *end,
+        }
+    }
+
+    fn is_before(&self, ts: MilliSecondsSinceUnixEpoch) -> bool {
+        match self {
+            Self::All => false,
+            Self::Between { start, .. } => ts < *start,
+        }
+    }
+}
+
+/// Options for [`Common::export_media()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct MediaExportOptions {
+    /// The maximum number of attachments downloaded at the same time.
+    ///
+    /// Defaults to 4.
+    pub max_concurrent_downloads: usize,
+
+    /// Whether to export the images.
+    pub images: bool,
+
+    /// Whether to export the videos.
+    pub videos: bool,
+
+    /// Whether to export the audio files.
+    pub audio: bool,
+
+    /// Whether to export the generic files.
+    pub files: bool,
+}
+
+impl MediaExportOptions {
+    /// Creates `MediaExportOptions` exporting all the kinds of media.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for MediaExportOptions {
+    fn default() -> Self {
+        Self { max_concurrent_downloads: 4, images: true, videos: true, audio: true, files: true }
+    }
+}
+
+/// The progress of a media export.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MediaExportProgress {
+    /// The number of attachments found in the range of events so far.
+    pub found: usize,
+    /// The number of attachments that were downloaded and written.
+    pub exported: usize,
+    /// The number of attachments that were already exported.
+    pub skipped: usize,
+    /// The number of attachments that couldn't be downloaded or written.
+    pub failed: usize,
+}
+
+impl MediaExportProgress {
+    /// Whether all the attachments that were found were handled.
+    pub fn is_done(&self) -> bool {
+        self.exported + self.skipped + self.failed == self.found
+    }
+}
+
+/// An attachment to export.
+#[derive(Debug)]
+struct MediaItem {
+    event_id: OwnedEventId,
+    ts: MilliSecondsSinceUnixEpoch,
+    name: String,
+    mimetype: Option<String>,
+    source: MediaSource,
+}
+
+impl MediaItem {
+    fn from_msgtype(
+        msgtype: MessageType,
+        event_id: OwnedEventId,
+        ts: MilliSecondsSinceUnixEpoch,
+        options: &MediaExportOptions,
+    ) -> Option<Self> {
+        let (name, mimetype, source) = match msgtype {
+            MessageType::Image(c) if options.images => {
+                (c.body, c.info.and_then(|i| i.mimetype), c.source)
+            }
+            MessageType::Video(c) if options.videos => {
+                (c.body, c.info.and_then(|i| i.mimetype), c.source)
+            }
+            MessageType::Audio(c) if options.audio => {
+                (c.body, c.info.and_then(|i| i.mimetype), c.source)
+            }
+            MessageType::File(c) if options.files => {
+                (c.body, c.info.and_then(|i| i.mimetype), c.source)
+            }
+            _ => return None,
+        };
+
+        Some(Self { event_id, ts, name, mimetype, source })
+    }
+
+    /// The name of the exported file.
+    ///
+    /// It starts with the date the attachment was sent, so the files are
+    /// sorted chronologically, and ends with a part of the event ID, so two
+    /// attachments with the same name don't overwrite each other.
+    fn file_name(&self) -> String {
+        let path = Path::new(&self.name);
+        let stem = path.file_stem().and_then(|s| s.to_str()).map(sanitize).unwrap_or_default();
+        let stem = if stem.is_empty() { "media".to_owned() } else { stem };
+
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(sanitize)
+            .filter(|e| !e.is_empty())
+            .or_else(|| self.mimetype.as_deref().and_then(mime2ext::mime2ext).map(Into::into));
+
+        let id: String =
+            self.event_id.as_str().chars().filter(char::is_ascii_alphanumeric).take(8).collect();
+
+        let mut file_name = format!("{}_{stem}_{id}", format_date(self.ts));
+        if let Some(extension) = extension {
+            file_name.push('.');
+            file_name.push_str(&extension);
+        }
+
+        file_name
+    }
+}
+
+/// Future returned by [`Common::export_media()`].
+#[allow(missing_debug_implementations)]
+pub struct ExportMedia<'a> {
+    room: &'a Common,
+    range: MediaExportRange,
+    dest_dir: PathBuf,
+    options: MediaExportOptions,
+    tracing_span: Span,
+    progress: SharedObservable<MediaExportProgress>,
+}
+
+impl<'a> ExportMedia<'a> {
+    pub(crate) fn new(
+        room: &'a Common,
+        range: MediaExportRange,
+        dest_dir: PathBuf,
+        options: MediaExportOptions,
+    ) -> Self {
+        Self {
+            room,
+            range,
+            dest_dir,
+            options,
+            tracing_span: Span::current(),
+            progress: Default::default(),
+        }
+    }
+
+    /// Subscribe to the progress of the export.
+    pub fn subscribe_to_progress(&self) -> Subscriber<MediaExportProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Find the attachments to export, from the most recent one.
+    async fn find_media(&self) -> Result<Vec<MediaItem>> {
+        let mut items = Vec::new();
+        let mut from: Option<String> = None;
+
+        loop {
+            let options = MessagesOptions::backward().from(from.as_deref());
+            let messages = self.room.messages(options).await?;
+
+            for event in messages.chunk {
+                let event = match event.event.deserialize() {
+                    Ok(event) => event,
+                    Err(error) => {
+                        debug!(?error, "Failed to deserialize event, skipping it");
+                        continue;
+                    }
+                };
+
+                let ts = event.origin_server_ts();
+                if self.range.is_before(ts) {
+                    // The events are received from the most recent one, so all
+                    // the following events are out of range too.
+                    return Ok(items);
+                }
+                if !self.range.contains(ts) {
+                    continue;
+                }
+
+                let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                    MessageLikeEvent::Original(event),
+                )) = event
+                else {
+                    continue;
+                };
+
+                // Edits replace the content of another event, which is
+                // exported on its own.
+                if matches!(event.content.relates_to, Some(Relation::Replacement(_))) {
+                    continue;
+                }
+
+                if let Some(item) = MediaItem::from_msgtype(
+                    event.content.msgtype,
+                    event.event_id,
+                    event.origin_server_ts,
+                    &self.options,
+                ) {
+                    items.push(item);
+                    self.progress.update(|p| p.found += 1);
+                }
+            }
+
+            match messages.end {
+                Some(end) if from.as_ref() != Some(&end) => from = Some(end),
+                // We reached the start of the room.
+                _ => return Ok(items),
+            }
+        }
+    }
+
+    /// Download the given attachment and write it in the destination
+    /// directory, unless it was already exported.
+    ///
+    /// Returns whether the file was written.
+    async fn export_item(&self, item: &MediaItem) -> Result<bool> {
+        let path = self.dest_dir.join(item.file_name());
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(false);
+        }
+
+        let request = MediaRequest { source: item.source.clone(), format: MediaFormat::File };
+        let data = self.room.client.media().get_media_content(&request, false).await?;
+
+        // Write to a temporary file first, so an interrupted export doesn't
+        // leave a truncated file that would be skipped on the next export.
+        let mut part_path = path.clone().into_os_string();
+        part_path.push(".part");
+        tokio::fs::write(&part_path, data).await?;
+        tokio::fs::rename(&part_path, &path).await?;
+
+        Ok(true)
+    }
+}
+
+impl<'a> IntoFuture for ExportMedia<'a> {
+    type Output = Result<MediaExportProgress>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send + 'a>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let tracing_span = self.tracing_span.clone();
+
+        let fut = async move {
+            let this = &self;
+            tokio::fs::create_dir_all(&this.dest_dir).await?;
+
+            let items = this.find_media().await?;
+            debug!(count = items.len(), "Found the attachments to export");
+
+            stream::iter(&items)
+                .map(|item| async move { (item, this.export_item(item).await) })
+                .buffer_unordered(this.options.max_concurrent_downloads.max(1))
+                .for_each(|(item, result)| {
+                    match result {
+                        Ok(true) => this.progress.update(|p| p.exported += 1),
+                        Ok(false) => this.progress.update(|p| p.skipped += 1),
+                        Err(error) => {
+                            warn!(event_id = ?item.event_id, ?error, "Failed to export attachment");
+                            this.progress.update(|p| p.failed += 1);
+                        }
+                    }
+
+                    async {}
+                })
+                .await;
+
+            Ok(this.progress.get())
+        };
+
+        Box::pin(fut.instrument(tracing_span))
+    }
+}
+
+/// Keep only the characters of the given name that are safe to use in a file
+/// name on all platforms.
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ' '))
+        .take(MAX_NAME_LENGTH)
+        .collect();
+
+    name.trim_matches(|c| c == '.' || c == ' ').to_owned()
+}
+
+/// Format the date of the given time as `YYYY-MM-DD`, in UTC.
+fn format_date(ts: MilliSecondsSinceUnixEpoch) -> String {
+    // Converts a number of days since the Unix epoch to a civil date, see
+    // <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+    let days = (u64::from(ts.get()) / 1000 / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        events::room::MediaSource, mxc_uri, owned_event_id, uint, MilliSecondsSinceUnixEpoch,
+    };
+
+    use super::{format_date, sanitize, MediaItem};
+
+    #[test]
+    fn date_formatting() {
+        assert_eq!(format_date(MilliSecondsSinceUnixEpoch(uint!(0))), "1970-01-01");
+        assert_eq!(format_date(MilliSecondsSinceUnixEpoch(uint!(951_782_400_000))), "2000-02-29");
+        assert_eq!(format_date(MilliSecondsSinceUnixEpoch(uint!(1_697_414_400_000))), "2023-10-16");
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(sanitize("../../etc/passwd"), "etcpasswd");
+        assert_eq!(sanitize(" holiday pic. "), "holiday pic");
+
+        let mut item = MediaItem {
+            event_id: owned_event_id!("$abcdefghijkl:example.org"),
+            ts: MilliSecondsSinceUnixEpoch(uint!(1_697_414_400_000)),
+            name: "beach.jpg".to_owned(),
+            mimetype: Some("image/png".to_owned()),
+            source: MediaSource::Plain(mxc_uri!("mxc://example.org/media").to_owned()),
+        };
+        assert_eq!(item.file_name(), "2023-10-16_beach_abcdefgh.jpg");
+
+        // The extension is inferred from the MIME type.
+        item.name = "Screenshot".to_owned();
+        assert_eq!(item.file_name(), "2023-10-16_Screenshot_abcdefgh.png");
+
+        item.name = "???".to_owned();
+        item.mimetype = None;
+        assert_eq!(item.file_name(), "2023-10-16_media_abcdefgh");
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An observable list of the members of a room.
+
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    fmt,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use eyeball_im::{ObservableVector, VectorDiff};
+use futures_core::Stream;
+use imbl::Vector;
+use matrix_sdk_base::RoomMemberships;
+use matrix_sdk_common::executor::{spawn, JoinHandle};
+use ruma::{
+    events::{AnySyncStateEvent, StateEventType},
+    OwnedUserId,
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, warn};
+
+use super::{Common, RoomMember};
+use crate::{sync::RoomUpdate, Result};
+
+/// An observable list of the members of a room, ordered by power level then
+/// by name.
+///
+/// It can be created with [`Common::members_stream()`]. The list starts with
+/// the members that are known locally, and the full list of members is loaded
+/// from the homeserver in the background if needed. After that, it is updated
+/// with the membership and power levels changes received in the sync.
+///
+/// The list can be narrowed down with [`RoomMemberList::set_search()`].
+///
+/// The background task updating the list is stopped when the list is dropped.
+pub struct RoomMemberList {
+    inner: Arc<MemberListInner>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    task: JoinHandle<()>,
+}
+
+struct MemberListInner {
+    room: Common,
+    memberships: RoomMemberships,
+    state: StdMutex<MemberListState>,
+}
+
+struct MemberListState {
+    /// The current search query, in lowercase.
+    search: String,
+    items: ObservableVector<RoomMember>,
+}
+
+/// The members that need to be updated after a sync.
+enum MemberChanges {
+    Users(BTreeSet<OwnedUserId>),
+    All,
+}
+
+impl RoomMemberList {
+    pub(super) async fn new(room: Common, memberships: RoomMemberships) -> Result<Self> {
+        let members = room.members_no_sync(memberships).await?;
+
+        let mut items = ObservableVector::new();
+        items.append(sorted(members.into_iter(), ""));
+
+        let inner = Arc::new(MemberListInner {
+            room,
+            memberships,
+            state: StdMutex::new(MemberListState { search: String::new(), items }),
+        });
+
+        // Subscribe before spawning the task, so no update is missed.
+        let updates = inner.room.subscribe_to_updates();
+        let task = spawn(Arc::clone(&inner).run(updates));
+
+        Ok(Self { inner, task })
+    }
+
+    /// Get the current members and a stream of updates of the list.
+    pub fn subscribe(&self) -> (Vector<RoomMember>, impl Stream<Item = VectorDiff<RoomMember>>) {
+        let state = self.inner.state.lock().unwrap();
+        ((*state.items).clone(), state.items.subscribe())
+    }
+
+    /// Only keep the members whose display name or user ID contains the given
+    /// query, ignoring case.
+    ///
+    /// An empty query shows all the members again. If the new query contains
+    /// the previous one, the members that don't match anymore are removed from
+    /// the list, otherwise the list is reloaded.
+    pub async fn set_search(&self, query: &str) -> Result<()> {
+        let query = query.to_lowercase();
+
+        {
+            let mut state = self.inner.state.lock().unwrap();
+
+            if query == state.search {
+                return Ok(());
+            }
+
+            if query.contains(&state.search) {
+                let mut index = 0;
+                while index < state.items.len() {
+                    if matches_search(&state.items[index], &query) {
+                        index += 1;
+                    } else {
+                        state.items.remove(index);
+                    }
+                }
+
+                state.search = query;
+                return Ok(());
+            }
+
+            state.search = query;
+        }
+
+        self.inner.reload().await
+    }
+
+    /// Get the current search query, in lowercase.
+    pub fn search(&self) -> String {
+        self.inner.state.lock().unwrap().search.clone()
+    }
+}
+
+impl Drop for RoomMemberList {
+    fn drop(&mut self) {
+        // The task is aborted when its handle is dropped on WASM.
+        #[cfg(not(target_arch = "wasm32"))]
+        self.task.abort();
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for RoomMemberList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.inner.state.lock().unwrap();
+        f.debug_struct("RoomMemberList")
+            .field("room_id", &self.inner.room.room_id())
+            .field("memberships", &self.inner.memberships)
+            .field("search", &state.search)
+            .field("len", &state.items.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemberListInner {
+    async fn run(self: Arc<Self>, mut updates: Receiver<RoomUpdate>) {
+        // Load the members from the homeserver if the list isn't complete,
+        // e.g. because of lazy-loading.
+        match self.room.sync_members().await {
+            Ok(Some(_)) => {
+                if let Err(error) = self.reload().await {
+                    warn!(?error, "Couldn't reload the room members");
+                }
+            }
+            Ok(None) => {}
+            Err(error) => warn!(?error, "Couldn't load the room members"),
+        }
+
+        loop {
+            let changes = match updates.recv().await {
+                Ok(update) => match member_changes(&update) {
+                    Some(changes) => changes,
+                    None => continue,
+                },
+                Err(RecvError::Lagged(count)) => {
+                    debug!(count, "Missed room updates, reloading the room members");
+                    MemberChanges::All
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let result = match changes {
+                MemberChanges::Users(user_ids) => self.update_members(user_ids).await,
+                MemberChanges::All => self.reload().await,
+            };
+
+            if let Err(error) = result {
+                warn!(?error, "Couldn't update the room members");
+            }
+        }
+    }
+
+    /// Replace the whole list with the members from the store.
+    async fn reload(&self) -> Result<()> {
+        let members = self.room.members_no_sync(self.memberships).await?;
+
+        let mut state = self.state.lock().unwrap();
+        let items = sorted(members.into_iter(), &state.search);
+        state.items.clear();
+        state.items.append(items);
+
+        Ok(())
+    }
+
+    /// Update the given members in the list, with their data from the store.
+    async fn update_members(&self, user_ids: BTreeSet<OwnedUserId>) -> Result<()> {
+        let mut members = Vec::with_capacity(user_ids.len());
+        for user_id in user_ids {
+            let member = self.room.get_member_no_sync(&user_id).await?;
+            members.push((user_id, member));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let MemberListState { search, items } = &mut *state;
+
+        for (user_id, member) in members {
+            if let Some(index) = items.iter().position(|m| m.user_id() == &*user_id) {
+                items.remove(index);
+            }
+
+            let Some(member) = member else { continue };
+            if !self.memberships.matches(member.membership()) || !matches_search(&member, search) {
+                continue;
+            }
+
+            let index = items.iter().position(|m| compare(&member, m) == Ordering::Less);
+            match index {
+                Some(index) => items.insert(index, member),
+                None => items.push_back(member),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the members that are affected by the given update, if any.
+fn member_changes(update: &RoomUpdate) -> Option<MemberChanges> {
+    let (state, timeline) = match update {
+        RoomUpdate::Joined { updates, .. } => (&updates.state, &updates.timeline),
+        RoomUpdate::Left { updates, .. } => (&updates.state, &updates.timeline),
+        // The state of invited rooms is stripped, reload everything.
+        RoomUpdate::Invited { .. } => return Some(MemberChanges::All),
+    };
+
+    let events = state
+        .iter()
+        .chain(timeline.events.iter().map(|event| event.event.cast_ref::<AnySyncStateEvent>()));
+
+    let mut user_ids = BTreeSet::new();
+    for event in events {
+        let Ok(Some(event_type)) = event.get_field::<StateEventType>("type") else { continue };
+
+        match event_type {
+            // Power levels changes can change the order of all the members.
+            StateEventType::RoomPowerLevels => return Some(MemberChanges::All),
+            StateEventType::RoomMember => {
+                if let Ok(Some(user_id)) = event.get_field::<OwnedUserId>("state_key") {
+                    user_ids.insert(user_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (!user_ids.is_empty()).then_some(MemberChanges::Users(user_ids))
+}
+
+/// Sort the members that match the search query.
+fn sorted(members: impl Iterator<Item = RoomMember>, search: &str) -> Vector<RoomMember> {
+    let mut members: Vec<_> = members.filter(|member| matches_search(member, search)).collect();
+    members.sort_by(compare);
+    members.into_iter().collect()
+}
+
+/// Compare members by descending power level, then by name, ignoring case.
+fn compare(a: &RoomMember, b: &RoomMember) -> Ordering {
+    b.power_level()
+        .cmp(&a.power_level())
+        .then_with(|| a.name().to_lowercase().cmp(&b.name().to_lowercase()))
+        .then_with(|| a.user_id().cmp(b.user_id()))
+}
+
+/// Whether the member's display name or user ID contains the lowercase query.
+fn matches_search(member: &RoomMember, search: &str) -> bool {
+    search.is_empty()
+        || contains_lowercase(member.user_id().as_str(), search)
+        || member.display_name().is_some_and(|name| contains_lowercase(name, search))
+}
+
+fn contains_lowercase(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(needle)
+}
@@ -0,0 +1,215 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshots of the membership of a room, for audits.
+
+use std::collections::BTreeMap;
+
+use matrix_sdk_base::{deserialized_responses::RawSyncOrStrippedState, RoomMemberships};
+use ruma::{
+    events::room::{
+        join_rules::JoinRule, member::MembershipState, power_levels::RoomPowerLevelsEventContent,
+    },
+    serde::Raw,
+    CanonicalJsonValue, MilliSecondsSinceUnixEpoch, OwnedDeviceKeyId, OwnedRoomId, OwnedUserId,
+};
+#[cfg(feature = "e2e-encryption")]
+use ruma::{DeviceKeyAlgorithm, OwnedDeviceId};
+use serde::{Deserialize, Serialize};
+
+use super::Common;
+#[cfg(feature = "e2e-encryption")]
+use crate::Client;
+use crate::{Error, Result};
+
+/// A snapshot of the members, power levels and join rule of a room at a point
+/// in time.
+///
+/// It can be created with [`Common::export_membership_snapshot()`], and
+/// serialized to JSON to be archived or shared, e.g. for moderation audits.
+///
+/// If end-to-end encryption is enabled, the snapshot is signed with the keys
+/// of the device that exported it, like any signed JSON object in Matrix, and
+/// the signature can be checked with [`MembershipSnapshot::verify()`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MembershipSnapshot {
+    /// The room of the snapshot.
+    pub room_id: OwnedRoomId,
+    /// The user who exported the snapshot.
+    pub exported_by: OwnedUserId,
+    /// When the snapshot was exported.
+    pub exported_at: MilliSecondsSinceUnixEpoch,
+    /// The members of the room, with any membership.
+    pub members: Vec<SnapshotMember>,
+    /// The content of the power levels event of the room, if any.
+    pub power_levels: Option<Raw<RoomPowerLevelsEventContent>>,
+    /// The join rule of the room.
+    pub join_rule: JoinRule,
+    /// The signatures of the snapshot.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub signatures: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceKeyId, String>>,
+}
+
+/// A member of a room in a [`MembershipSnapshot`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SnapshotMember {
+    /// The ID of the member.
+    pub user_id: OwnedUserId,
+    /// The membership of the member.
+    pub membership: MembershipState,
+    /// The display name of the member, if any.
+    pub display_name: Option<String>,
+    /// The power level of the member.
+    pub power_level: i64,
+}
+
+/// The result of the verification of a [`MembershipSnapshot`].
+#[cfg(feature = "e2e-encryption")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotVerification {
+    /// The snapshot was signed by a device of the user who exported it.
+    Signed {
+        /// The ID of the device that signed the snapshot.
+        device_id: OwnedDeviceId,
+        /// Whether this device is verified.
+        is_device_verified: bool,
+    },
+    /// The snapshot isn't signed by the user who exported it.
+    Unsigned,
+    /// The snapshot is signed by devices that aren't known, so the signatures
+    /// can't be checked.
+    UnknownDevice,
+    /// The signature doesn't match the content of the snapshot, which has
+    /// probably been modified.
+    InvalidSignature,
+}
+
+impl MembershipSnapshot {
+    pub(super) async fn from_room(room: &Common) -> Result<Self> {
+        let members = room
+            .members(RoomMemberships::empty())
+            .await?
+            .into_iter()
+            .map(|member| SnapshotMember {
+                user_id: member.user_id().to_owned(),
+                membership: member.membership().clone(),
+                display_name: member.display_name().map(ToOwned::to_owned),
+                power_level: member.power_level(),
+            })
+            .collect();
+
+        let power_levels =
+            match room.get_state_event_static::<RoomPowerLevelsEventContent>().await? {
+                Some(RawSyncOrStrippedState::Sync(event)) => event.get_field("content")?,
+                Some(RawSyncOrStrippedState::Stripped(event)) => event.get_field("content")?,
+                None => None,
+            };
+
+        #[allow(unused_mut)]
+        let mut snapshot = Self {
+            room_id: room.room_id().to_owned(),
+            exported_by: room.own_user_id().to_owned(),
+            exported_at: MilliSecondsSinceUnixEpoch::now(),
+            members,
+            power_levels,
+            join_rule: room.join_rule(),
+            signatures: BTreeMap::new(),
+        };
+
+        #[cfg(feature = "e2e-encryption")]
+        {
+            let client = room.client();
+            if let Some(machine) = client.olm_machine().await.as_ref() {
+                let signatures = machine.sign(&snapshot.canonical_json()?).await;
+                snapshot.signatures = serde_json::from_value(serde_json::to_value(signatures)?)?;
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Check that the snapshot was signed by a device of the user who exported
+    /// it, and that it wasn't modified since.
+    ///
+    /// The device that signed the snapshot must be known by the client, which
+    /// is the case if it shares a room with the user who exported it.
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn verify(&self, client: &Client) -> Result<SnapshotVerification> {
+        use matrix_sdk_base::crypto::types::Signatures;
+
+        let Some(user_signatures) = self.signatures.get(&self.exported_by) else {
+            return Ok(SnapshotVerification::Unsigned);
+        };
+
+        let canonical_json = self.canonical_json()?;
+        let signatures: Signatures =
+            serde_json::from_value(serde_json::to_value(&self.signatures)?)?;
+
+        let mut found_unknown_device = false;
+        let mut found_invalid_signature = false;
+
+        for key_id in user_signatures.keys() {
+            if key_id.algorithm() != DeviceKeyAlgorithm::Ed25519 {
+                continue;
+            }
+
+            // Signatures from cross-signing keys don't match any device, so
+            // they're reported as unknown devices.
+            let Some(device) =
+                client.encryption().get_device(&self.exported_by, key_id.device_id()).await?
+            else {
+                found_unknown_device = true;
+                continue;
+            };
+
+            let is_valid = device
+                .ed25519_key()
+                .zip(signatures.get_signature(&self.exported_by, key_id))
+                .is_some_and(|(key, signature)| {
+                    key.verify(canonical_json.as_bytes(), &signature).is_ok()
+                });
+
+            if is_valid {
+                return Ok(SnapshotVerification::Signed {
+                    device_id: key_id.device_id().to_owned(),
+                    is_device_verified: device.is_verified(),
+                });
+            }
+
+            found_invalid_signature = true;
+        }
+
+        Ok(if found_invalid_signature {
+            SnapshotVerification::InvalidSignature
+        } else if found_unknown_device {
+            SnapshotVerification::UnknownDevice
+        } else {
+            SnapshotVerification::Unsigned
+        })
+    }
+
+    /// The canonical JSON of the snapshot without its signatures, as it is
+    /// signed.
+    fn canonical_json(&self) -> Result<String> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("signatures");
+            object.remove("unsigned");
+        }
+
+        let canonical =
+            CanonicalJsonValue::try_from(value).map_err(|e| Error::UnknownError(e.into()))?;
+        Ok(canonical.to_string())
+    }
+}
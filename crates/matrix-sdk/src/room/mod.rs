@@ -4,18 +4,36 @@ use std::ops::Deref;
 
 use crate::RoomState;
 
+mod bulk_invite;
+mod client_settings;
 mod common;
 mod invited;
 mod joined;
 mod left;
+#[cfg(not(target_arch = "wasm32"))]
+mod media_export;
 mod member;
+mod member_list;
+mod membership_snapshot;
+mod upgrade;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::media_export::{
+    ExportMedia, MediaExportOptions, MediaExportProgress, MediaExportRange,
+};
+#[cfg(feature = "e2e-encryption")]
+pub use self::membership_snapshot::SnapshotVerification;
 pub use self::{
-    common::{Common, Messages, MessagesOptions},
+    bulk_invite::{BulkInviteProgress, BulkInviteReport, InviteOutcome, InviteUsers},
+    client_settings::{ObservableRoomClientSettings, RoomClientSettingsEventContent},
+    common::{Common, EventWithContext, Messages, MessagesOptions, RelatedEvent, Relations},
     invited::{Invite, Invited},
     joined::{Joined, Receipts},
     left::Left,
     member::RoomMember,
+    member_list::RoomMemberList,
+    membership_snapshot::{MembershipSnapshot, SnapshotMember},
+    upgrade::{RoomUpgrade, RoomUpgradeFollower, SuccessorRoom},
 };
 
 /// An enum that abstracts over the different states a room can be in.
@@ -0,0 +1,116 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Following of room upgrades.
+
+use ruma::{events::room::tombstone::OriginalSyncRoomTombstoneEvent, OwnedRoomId, RoomOrAliasId};
+use tokio::sync::broadcast;
+use tracing::{debug, instrument, warn};
+
+use super::{Joined, Room};
+use crate::{event_handler::EventHandlerDropGuard, notification_settings, Client, Result};
+
+/// The room that replaces a room that has been upgraded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SuccessorRoom {
+    /// The ID of the replacement room.
+    pub room_id: OwnedRoomId,
+    /// The message explaining why the room has been replaced, if any.
+    pub reason: Option<String>,
+}
+
+/// A room that has been replaced by its successor, and that was followed by a
+/// [`RoomUpgradeFollower`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomUpgrade {
+    /// The ID of the room that has been tombstoned.
+    pub old_room_id: OwnedRoomId,
+    /// The ID of the replacement room, that has been joined.
+    pub new_room_id: OwnedRoomId,
+}
+
+/// Automatically joins the successors of the rooms that are tombstoned.
+///
+/// It can be created with [`Client::follow_room_upgrades()`]. When a joined
+/// room is tombstoned, the replacement room is joined, and the tags and the
+/// notification mode of the old room are copied to it. A [`RoomUpgrade`] is
+/// then sent to the subscribers, so they can redirect to the new room.
+///
+/// The rooms stop being followed when this is dropped.
+#[derive(Debug)]
+pub struct RoomUpgradeFollower {
+    _handler_guard: EventHandlerDropGuard,
+    upgrades: broadcast::Sender<RoomUpgrade>,
+}
+
+impl RoomUpgradeFollower {
+    pub(crate) fn new(client: &Client) -> Self {
+        let (upgrades, _) = broadcast::channel(16);
+
+        let sender = upgrades.clone();
+        let handle = client.add_event_handler(
+            move |event: OriginalSyncRoomTombstoneEvent, room: Room, client: Client| {
+                let sender = sender.clone();
+                async move {
+                    let Room::Joined(room) = room else { return };
+                    match follow_upgrade(&client, &room, event).await {
+                        Ok(upgrade) => {
+                            // There might be no subscribers, that's fine.
+                            let _ = sender.send(upgrade);
+                        }
+                        Err(error) => warn!(?error, "Couldn't follow the room upgrade"),
+                    }
+                }
+            },
+        );
+
+        Self { _handler_guard: client.event_handler_drop_guard(handle), upgrades }
+    }
+
+    /// Subscribe to the room upgrades that have been followed.
+    pub fn subscribe(&self) -> broadcast::Receiver<RoomUpgrade> {
+        self.upgrades.subscribe()
+    }
+}
+
+#[instrument(skip_all, fields(room_id = ?room.room_id()))]
+async fn follow_upgrade(
+    client: &Client,
+    room: &Joined,
+    event: OriginalSyncRoomTombstoneEvent,
+) -> Result<RoomUpgrade> {
+    let new_room_id = event.content.replacement_room;
+
+    let new_room = match client.get_joined_room(&new_room_id) {
+        Some(new_room) => new_room,
+        None => {
+            debug!(%new_room_id, "Joining the successor of the room");
+            // The sender of the tombstone is in the new room, so their server
+            // can be used to join it.
+            let via = [event.sender.server_name().to_owned()];
+            client.join_room_by_id_or_alias(<&RoomOrAliasId>::from(&*new_room_id), &via).await?
+        }
+    };
+
+    if let Some(tags) = room.tags().await? {
+        for (tag, tag_info) in tags {
+            new_room.set_tag(tag, tag_info).await?;
+        }
+    }
+
+    notification_settings::copy_room_notification_mode(client, room.room_id(), &new_room_id)
+        .await?;
+
+    Ok(RoomUpgrade { old_room_id: room.room_id().to_owned(), new_room_id })
+}
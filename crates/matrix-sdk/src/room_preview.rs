@@ -0,0 +1,281 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Previews of rooms, to show their details before joining them.
+
+use bytes::BufMut;
+use ruma::{
+    api::{
+        client::space::{get_hierarchy, SpaceHierarchyRoomsChunk},
+        error::{FromHttpResponseError, IntoHttpError},
+        metadata, EndpointError, IncomingResponse, MatrixVersion, Metadata, OutgoingRequest,
+        SendAccessToken,
+    },
+    assign,
+    events::room::history_visibility::HistoryVisibility,
+    room::RoomType,
+    space::SpaceRoomJoinRule,
+    uint, EventEncryptionAlgorithm, OwnedMxcUri, OwnedRoomAliasId, OwnedRoomId, OwnedRoomOrAliasId,
+    OwnedServerName, RoomId, RoomOrAliasId,
+};
+use serde::Deserialize;
+use tracing::{debug, instrument};
+use url::form_urlencoded;
+
+use crate::{room, Client, Result, RoomState};
+
+/// The details of a room, that can be shown before joining it.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RoomPreview {
+    /// The ID of the room.
+    pub room_id: OwnedRoomId,
+
+    /// The canonical alias of the room, if any.
+    pub canonical_alias: Option<OwnedRoomAliasId>,
+
+    /// The name of the room, if any.
+    pub name: Option<String>,
+
+    /// The topic of the room, if any.
+    pub topic: Option<String>,
+
+    /// The avatar of the room, if any.
+    pub avatar_url: Option<OwnedMxcUri>,
+
+    /// The number of joined members.
+    pub num_joined_members: u64,
+
+    /// The join rule of the room, if known.
+    pub join_rule: Option<SpaceRoomJoinRule>,
+
+    /// Whether the room is encrypted, if known.
+    pub is_encrypted: Option<bool>,
+
+    /// Whether the history of the room can be read without joining it.
+    pub is_world_readable: bool,
+
+    /// The type of the room, like a space, if any.
+    pub room_type: Option<RoomType>,
+
+    /// The state of the user in the room, if the client knows the room.
+    pub state: Option<RoomState>,
+}
+
+impl RoomPreview {
+    /// Load the preview of the given room.
+    ///
+    /// The room summary endpoint of [MSC3266] is used if the homeserver
+    /// supports it. Otherwise the room is looked up in its own space
+    /// hierarchy, which works for rooms that are public or known by the
+    /// homeserver, and then [peeked](Client::peek_room), which only works if
+    /// it is known or world-readable.
+    ///
+    /// [MSC3266]: https://github.com/matrix-org/matrix-spec-proposals/pull/3266
+    #[instrument(skip(client))]
+    pub(crate) async fn load(
+        client: &Client,
+        room_or_alias_id: &RoomOrAliasId,
+        via: &[OwnedServerName],
+    ) -> Result<Self> {
+        if let Some(summary) = RoomSummary::load(client, room_or_alias_id, via).await? {
+            let state = client.get_room(&summary.room_id).map(|room| room.state());
+            return Ok(summary.into_preview(state));
+        }
+
+        let room_id = match <&RoomId>::try_from(room_or_alias_id) {
+            Ok(room_id) => room_id.to_owned(),
+            Err(alias) => client.resolve_room_alias(alias).await?.room_id,
+        };
+        let state = client.get_room(&room_id).map(|room| room.state());
+
+        debug!("The room summary isn't available, looking up the room hierarchy");
+        let request = assign!(get_hierarchy::v1::Request::new(room_id.clone()), {
+            limit: Some(uint!(1)),
+            max_depth: Some(uint!(0)),
+        });
+        match client.send(request, None).await {
+            Ok(response) => {
+                if let Some(chunk) =
+                    response.rooms.into_iter().find(|chunk| chunk.room_id == room_id)
+                {
+                    return Ok(Self::from_hierarchy_chunk(chunk, state));
+                }
+            }
+            Err(error) => debug!(?error, "Couldn't get the room hierarchy"),
+        }
+
+        debug!("The room hierarchy isn't available, peeking into the room");
+        let room = client.peek_room(&room_id).await?;
+
+        Ok(Self::from_room(&room, state))
+    }
+
+    fn from_room(room: &room::Common, state: Option<RoomState>) -> Self {
+        Self {
+            room_id: room.room_id().to_owned(),
+            canonical_alias: room.canonical_alias(),
+            name: room.name(),
+            topic: room.topic(),
+            avatar_url: room.avatar_url(),
+            num_joined_members: room.joined_members_count(),
+            join_rule: Some(room.join_rule().as_str().into()),
+            is_encrypted: room.is_encryption_state_synced().then(|| room.is_encrypted()),
+            is_world_readable: room.history_visibility() == HistoryVisibility::WorldReadable,
+            room_type: room.create_content().and_then(|content| content.room_type),
+            state,
+        }
+    }
+
+    fn from_hierarchy_chunk(chunk: SpaceHierarchyRoomsChunk, state: Option<RoomState>) -> Self {
+        Self {
+            room_id: chunk.room_id,
+            canonical_alias: chunk.canonical_alias,
+            name: chunk.name,
+            topic: chunk.topic,
+            avatar_url: chunk.avatar_url,
+            num_joined_members: chunk.num_joined_members.into(),
+            join_rule: Some(chunk.join_rule),
+            // The hierarchy doesn't tell whether the room is encrypted.
+            is_encrypted: None,
+            is_world_readable: chunk.world_readable,
+            room_type: chunk.room_type,
+            state,
+        }
+    }
+}
+
+/// Request to the room summary endpoint of MSC3266.
+#[derive(Clone, Debug)]
+struct RoomSummaryRequest {
+    room_or_alias_id: OwnedRoomOrAliasId,
+    via: Vec<OwnedServerName>,
+}
+
+impl OutgoingRequest for RoomSummaryRequest {
+    type EndpointError = ruma::api::client::Error;
+    type IncomingResponse = RoomSummary;
+
+    const METADATA: Metadata = metadata! {
+        method: GET,
+        rate_limited: false,
+        authentication: None,
+        history: {
+            unstable => "/_matrix/client/unstable/im.nheko.summary/rooms/:room_id_or_alias/summary",
+        }
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+        considering_versions: &[MatrixVersion],
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let query_string = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(self.via.iter().map(|server| ("via", server.as_str())))
+            .finish();
+        let url = Self::METADATA.make_endpoint_url(
+            considering_versions,
+            base_url,
+            &[&self.room_or_alias_id],
+            &query_string,
+        )?;
+
+        let mut request = http::Request::builder().method(Self::METADATA.method).uri(url);
+
+        // The endpoint doesn't require authentication, but the homeserver can
+        // only summarize rooms that the user can see if it knows who they are.
+        if let Some(access_token) = access_token.get_required_for_endpoint() {
+            request = request.header(http::header::AUTHORIZATION, format!("Bearer {access_token}"));
+        }
+
+        Ok(request.body(T::default())?)
+    }
+}
+
+/// The response of the room summary endpoint of MSC3266.
+#[derive(Clone, Debug, Deserialize)]
+struct RoomSummary {
+    room_id: OwnedRoomId,
+    canonical_alias: Option<OwnedRoomAliasId>,
+    name: Option<String>,
+    topic: Option<String>,
+    avatar_url: Option<OwnedMxcUri>,
+    num_joined_members: u64,
+    join_rule: Option<SpaceRoomJoinRule>,
+    #[serde(rename = "im.nheko.summary.encryption", alias = "encryption")]
+    encryption: Option<EventEncryptionAlgorithm>,
+    #[serde(default)]
+    world_readable: bool,
+    room_type: Option<RoomType>,
+}
+
+impl RoomSummary {
+    /// Load the summary of the given room.
+    ///
+    /// Returns `None` if the homeserver doesn't support the endpoint, or
+    /// refused to give the summary.
+    async fn load(
+        client: &Client,
+        room_or_alias_id: &RoomOrAliasId,
+        via: &[OwnedServerName],
+    ) -> Result<Option<Self>> {
+        let request = RoomSummaryRequest {
+            room_or_alias_id: room_or_alias_id.to_owned(),
+            via: via.to_owned(),
+        };
+
+        match client.send(request, None).await {
+            Ok(summary) => Ok(Some(summary)),
+            Err(error) if error.as_ruma_api_error().is_some() => {
+                debug!("Couldn't get the room summary: {error}");
+                Ok(None)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn into_preview(self, state: Option<RoomState>) -> RoomPreview {
+        RoomPreview {
+            room_id: self.room_id,
+            canonical_alias: self.canonical_alias,
+            name: self.name,
+            topic: self.topic,
+            avatar_url: self.avatar_url,
+            num_joined_members: self.num_joined_members,
+            join_rule: self.join_rule,
+            // The encryption is only given when it is enabled.
+            is_encrypted: self.encryption.map(|_| true),
+            is_world_readable: self.world_readable,
+            room_type: self.room_type,
+            state,
+        }
+    }
+}
+
+impl IncomingResponse for RoomSummary {
+    type EndpointError = ruma::api::client::Error;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<Self::EndpointError>> {
+        if response.status().as_u16() >= 400 {
+            return Err(FromHttpResponseError::Server(
+                ruma::api::client::Error::from_http_response(response),
+            ));
+        }
+
+        Ok(serde_json::from_slice(response.body().as_ref())?)
+    }
+}
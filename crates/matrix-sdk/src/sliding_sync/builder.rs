@@ -14,7 +14,7 @@ use super::{
     cache::{format_storage_key_prefix, restore_sliding_sync_state},
     sticky_parameters::SlidingSyncStickyManager,
     Error, SlidingSync, SlidingSyncInner, SlidingSyncListBuilder, SlidingSyncPositionMarkers,
-    SlidingSyncRoom,
+    SlidingSyncRoom, SlidingSyncVersion,
 };
 use crate::{sliding_sync::SlidingSyncStickyParameters, Client, Result};
 
@@ -26,7 +26,7 @@ use crate::{sliding_sync::SlidingSyncStickyParameters, Client, Result};
 pub struct SlidingSyncBuilder {
     id: String,
     storage_key: Option<String>,
-    sliding_sync_proxy: Option<Url>,
+    version: Option<SlidingSyncVersion>,
     client: Client,
     lists: Vec<SlidingSyncListBuilder>,
     extensions: Option<ExtensionsConfig>,
@@ -42,7 +42,7 @@ impl SlidingSyncBuilder {
             Ok(Self {
                 id,
                 storage_key: None,
-                sliding_sync_proxy: None,
+                version: None,
                 client,
                 lists: Vec::new(),
                 extensions: None,
@@ -72,7 +72,16 @@ impl SlidingSyncBuilder {
     /// URL. This method should only be called if the proxy is at a
     /// different URL than the one publicized in the `.well-known` endpoint.
     pub fn sliding_sync_proxy(mut self, value: Url) -> Self {
-        self.sliding_sync_proxy = Some(value);
+        self.version = Some(SlidingSyncVersion::Proxy { url: Some(value) });
+        self
+    }
+
+    /// Set the version of the sliding sync protocol to use.
+    ///
+    /// By default, the native version is used if the homeserver supports it,
+    /// otherwise the proxy discovered with the `.well-known` endpoint.
+    pub fn version(mut self, version: SlidingSyncVersion) -> Self {
+        self.version = Some(version);
         self
     }
 
@@ -235,6 +244,9 @@ impl SlidingSyncBuilder {
     ///
     /// If `self.storage_key` is `Some(_)`, load the cached data from cold
     /// storage.
+    ///
+    /// If the version of the protocol wasn't set, it is discovered by asking
+    /// the homeserver for the features it supports.
     pub async fn build(self) -> Result<SlidingSync> {
         let client = self.client;
 
@@ -266,13 +278,16 @@ impl SlidingSyncBuilder {
         let rooms = AsyncRwLock::new(self.rooms);
         let lists = AsyncRwLock::new(lists);
 
-        // Use the configured sliding sync proxy, or if not set, try to use the one
-        // auto-discovered by the client, if any.
-        let sliding_sync_proxy = self.sliding_sync_proxy.or_else(|| client.sliding_sync_proxy());
+        // Use the configured version, or if not set, the native version if the
+        // homeserver supports it, or the proxy auto-discovered by the client.
+        let version = match self.version {
+            Some(version) => version,
+            None => SlidingSyncVersion::discover(&client).await,
+        };
 
         Ok(SlidingSync::new(SlidingSyncInner {
             id: self.id,
-            sliding_sync_proxy,
+            version,
 
             client,
             storage_key: self.storage_key,
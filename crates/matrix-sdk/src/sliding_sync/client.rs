@@ -19,7 +19,15 @@ impl Client {
         &self,
         response: &v4::Response,
     ) -> Result<SyncResponse> {
-        let response = self.base_client().process_sliding_sync(response).await?;
+        #[cfg(feature = "e2e-encryption")]
+        self.encryption().lock_store().await?;
+
+        let response = self.base_client().process_sliding_sync(response).await;
+
+        #[cfg(feature = "e2e-encryption")]
+        self.encryption().unlock_store().await?;
+
+        let response = response?;
         debug!("done processing on base_client");
         self.handle_sync_response(&response).await?;
 
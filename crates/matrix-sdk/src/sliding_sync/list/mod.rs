@@ -29,6 +29,7 @@ use tracing::{instrument, warn};
 use self::sticky::SlidingSyncListStickyParameters;
 use super::{
     sticky_parameters::{LazyTransactionId, SlidingSyncStickyManager},
+    version::NativeListRoom,
     Error, SlidingSyncInternalMessage,
 };
 use crate::Result;
@@ -252,15 +253,39 @@ impl SlidingSyncList {
         Ok(new_changes)
     }
 
+    /// Update the list based on a response of the simplified protocol.
+    ///
+    /// It works like [`Self::update`], except that the simplified protocol has
+    /// no list operations: the `rooms` of the response are placed in the list
+    /// by the client, see [`SlidingSyncListInner::fill_room_list`].
+    #[instrument(skip(self, rooms, rooms_that_have_received_an_update), fields(name = self.name(), rooms_count = rooms.len()))]
+    pub(super) fn update_native(
+        &mut self,
+        maximum_number_of_rooms: u32,
+        rooms: &[NativeListRoom],
+        rooms_that_have_received_an_update: &[OwnedRoomId],
+    ) -> Result<bool, Error> {
+        self.inner.update_request_generator_state(maximum_number_of_rooms)?;
+
+        let filled = self.inner.fill_room_list(maximum_number_of_rooms, rooms);
+        let new_changes = self.inner.update_room_list(
+            maximum_number_of_rooms,
+            &[],
+            rooms_that_have_received_an_update,
+        )?;
+
+        Ok(filled || new_changes)
+    }
+
     /// Commit the set of sticky parameters for this list.
     pub fn maybe_commit_sticky(&mut self, txn_id: &TransactionId) {
         self.inner.sticky.write().unwrap().maybe_commit(txn_id);
     }
 
-    /// Manually invalidate the sticky data, so the sticky parameters are
+    /// Manually invalidate the sticky data, so all the sticky parameters are
     /// re-sent next time.
     pub fn invalidate_sticky_data(&self) {
-        let _ = self.inner.sticky.write().unwrap().data_mut();
+        self.inner.sticky.write().unwrap().reset();
     }
 }
 
@@ -496,6 +521,93 @@ impl SlidingSyncListInner {
         Ok(new_changes)
     }
 
+    /// Place the rooms of a response of the simplified protocol in the
+    /// [`Self::room_list`], and return whether it has changed.
+    ///
+    /// The `rooms` must be sorted like [`NativeListRoom::collect`] does: the
+    /// rooms with new events are moved to the top of the list, so the list
+    /// stays sorted by recency, the other ones take the first free entries.
+    ///
+    /// The server sends the rooms of all the lists together, so the rooms that
+    /// don't match the `is_invite` and `is_dm` filters of this list are removed
+    /// from it. The other filters aren't checked here.
+    fn fill_room_list(&self, maximum_number_of_rooms: u32, rooms: &[NativeListRoom]) -> bool {
+        let filters = self.sticky.read().unwrap().data().filters().cloned();
+        let matches_filters = |room: &NativeListRoom| {
+            filters.as_ref().map_or(true, |filters| {
+                filters.is_invite.map_or(true, |is_invite| is_invite == room.is_invite)
+                    && filters.is_dm.map_or(true, |is_dm| is_dm == room.is_dm.unwrap_or(false))
+            })
+        };
+
+        let mut room_list = self.room_list.write().unwrap();
+        let mut new_changes = false;
+
+        for room in rooms {
+            let position =
+                room_list.iter().position(|entry| entry.as_room_id() == Some(&*room.room_id));
+            let entry = RoomListEntry::Filled(room.room_id.clone());
+
+            if !matches_filters(room) {
+                if let Some(position) = position {
+                    room_list.remove(position);
+                    room_list.push_back(RoomListEntry::Empty);
+                    new_changes = true;
+                }
+
+                continue;
+            }
+
+            if room.latest_timestamp.is_some() {
+                // The room has new events, it goes to the top of the list.
+                match position {
+                    Some(0) if !room_list[0].is_empty_or_invalidated() => continue,
+                    Some(position) => {
+                        room_list.remove(position);
+                    }
+                    None => {
+                        if let Some(free) =
+                            room_list.iter().position(RoomListEntry::is_empty_or_invalidated)
+                        {
+                            room_list.remove(free);
+                        }
+                    }
+                }
+
+                room_list.push_front(entry);
+                new_changes = true;
+            } else {
+                match position {
+                    Some(position) => {
+                        // The room may have been invalidated.
+                        if room_list[position].is_empty_or_invalidated() {
+                            room_list.set(position, entry);
+                            new_changes = true;
+                        }
+                    }
+                    None => {
+                        if let Some(free) =
+                            room_list.iter().position(RoomListEntry::is_empty_or_invalidated)
+                        {
+                            room_list.set(free, entry);
+                            new_changes = true;
+                        } else if room_list.len() < maximum_number_of_rooms as usize {
+                            room_list.push_back(entry);
+                            new_changes = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        while room_list.len() > maximum_number_of_rooms as usize {
+            room_list.pop_back();
+            new_changes = true;
+        }
+
+        new_changes
+    }
+
     /// Update the state of the [`SlidingSyncListRequestGenerator`] after
     /// receiving a response.
     fn update_request_generator_state(&self, maximum_number_of_rooms: u32) -> Result<(), Error> {
@@ -990,6 +1102,46 @@ mod tests {
         assert_eq!(list.inner.sticky.read().unwrap().data().timeline_limit(), None);
     }
 
+    #[test]
+    fn test_sliding_sync_list_only_resends_changed_sticky_parameters() {
+        let (sender, _receiver) = channel(1);
+
+        let list = SlidingSyncList::builder("foo")
+            .sync_mode(SlidingSyncMode::new_selective().add_range(0..=1))
+            .sort(vec!["by_recency".to_owned()])
+            .timeline_limit(7)
+            .build(sender);
+
+        // The first request contains all the sticky parameters.
+        let mut txn_id = LazyTransactionId::new();
+        let request = list.inner.request(vec![0..=1], &mut txn_id);
+        assert_eq!(request.sort, ["by_recency"]);
+        assert!(!request.room_details.required_state.is_empty());
+        assert_eq!(request.room_details.timeline_limit, Some(uint!(7)));
+        list.inner.sticky.write().unwrap().maybe_commit(txn_id.get().unwrap());
+
+        // Once they are acknowledged, only the ones that changed are sent.
+        list.set_timeline_limit(Some(42));
+        let mut txn_id = LazyTransactionId::new();
+        let request = list.inner.request(vec![0..=1], &mut txn_id);
+        assert!(request.sort.is_empty());
+        assert!(request.room_details.required_state.is_empty());
+        assert_eq!(request.room_details.timeline_limit, Some(uint!(42)));
+        list.inner.sticky.write().unwrap().maybe_commit(txn_id.get().unwrap());
+
+        // Nothing is sent when nothing changed.
+        let mut txn_id = LazyTransactionId::new();
+        let request = list.inner.request(vec![0..=1], &mut txn_id);
+        assert_eq!(request.room_details.timeline_limit, None);
+        assert!(txn_id.get().is_none());
+
+        // When the server forgot the parameters, they are all sent again.
+        list.invalidate_sticky_data();
+        let request = list.inner.request(vec![0..=1], &mut LazyTransactionId::new());
+        assert_eq!(request.sort, ["by_recency"]);
+        assert_eq!(request.room_details.timeline_limit, Some(uint!(42)));
+    }
+
     #[test]
     fn test_sliding_sync_get_room_id() {
         let (sender, _receiver) = channel(1);
@@ -4,11 +4,11 @@ use ruma::{
 };
 
 use super::Bound;
-use crate::sliding_sync::sticky_parameters::StickyData;
+use crate::sliding_sync::sticky_parameters::{sticky_param_changed, StickyData};
 
 /// The set of `SlidingSyncList` request parameters that are *sticky*, as
 /// defined by the [Sliding Sync MSC](https://github.com/matrix-org/matrix-spec-proposals/blob/kegan/sync-v3/proposals/3575-sync.md).
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(super) struct SlidingSyncListStickyParameters {
     /// Sort the room list by this.
     sort: Vec<String>,
@@ -49,6 +49,10 @@ impl SlidingSyncListStickyParameters {
     pub(super) fn set_timeline_limit(&mut self, timeline: Option<Bound>) {
         self.timeline_limit = timeline;
     }
+
+    pub(super) fn filters(&self) -> Option<&v4::SyncRequestListFilters> {
+        self.filters.as_ref()
+    }
 }
 
 impl StickyData for SlidingSyncListStickyParameters {
@@ -61,4 +65,22 @@ impl StickyData for SlidingSyncListStickyParameters {
         request.filters = self.filters.clone();
         request.bump_event_types = self.bump_event_types.clone();
     }
+
+    fn apply_changes(&self, committed: &Self, request: &mut v4::SyncRequestList) {
+        if self.sort != committed.sort {
+            request.sort = self.sort.to_vec();
+        }
+        if self.required_state != committed.required_state {
+            request.room_details.required_state = self.required_state.to_vec();
+        }
+        if self.timeline_limit != committed.timeline_limit {
+            request.room_details.timeline_limit = self.timeline_limit.map(Into::into);
+        }
+        if sticky_param_changed(&self.filters, &committed.filters) {
+            request.filters = self.filters.clone();
+        }
+        if self.bump_event_types != committed.bump_event_types {
+            request.bump_event_types = self.bump_event_types.clone();
+        }
+    }
 }
@@ -22,6 +22,7 @@ mod error;
 mod list;
 mod room;
 mod sticky_parameters;
+mod version;
 
 use std::{
     collections::{BTreeMap, BTreeSet},
@@ -36,7 +37,9 @@ pub use builder::*;
 pub use client::*;
 pub use error::*;
 use futures_core::stream::Stream;
+use futures_util::future::Either;
 pub use list::*;
+use matrix_sdk_common::{instant::Instant, metrics};
 pub use room::*;
 use ruma::{
     api::client::{
@@ -51,9 +54,16 @@ use tokio::{
     sync::{broadcast::Sender, Mutex as AsyncMutex, RwLock as AsyncRwLock},
 };
 use tracing::{debug, error, instrument, warn, Instrument, Span};
+#[cfg(any(test, feature = "testing"))]
 use url::Url;
+pub use version::SlidingSyncVersion;
 
-use self::sticky_parameters::{LazyTransactionId, SlidingSyncStickyManager, StickyData};
+use self::{
+    sticky_parameters::{
+        sticky_param_changed, LazyTransactionId, SlidingSyncStickyManager, StickyData,
+    },
+    version::{NativeListRoom, NativeRequest},
+};
 use crate::{config::RequestConfig, Client, Result};
 
 /// The Sliding Sync instance.
@@ -75,8 +85,9 @@ pub(super) struct SlidingSyncInner {
     /// Used to distinguish different connections to the sliding sync proxy.
     id: String,
 
-    /// Customize the sliding sync proxy URL.
-    sliding_sync_proxy: Option<Url>,
+    /// The version of the sliding sync protocol, and the URL of the proxy if
+    /// any.
+    version: SlidingSyncVersion,
 
     /// The HTTP Matrix client.
     client: Client,
@@ -118,6 +129,11 @@ impl SlidingSync {
         SlidingSyncBuilder::new(id, client)
     }
 
+    /// The version of the sliding sync protocol used by this instance.
+    pub fn version(&self) -> &SlidingSyncVersion {
+        &self.inner.version
+    }
+
     /// Subscribe to a given room.
     ///
     /// If the associated `Room` exists, it will be marked as
@@ -293,6 +309,15 @@ impl SlidingSync {
             lists.values_mut().for_each(|list| list.maybe_commit_sticky(txn_id));
         }
 
+        // The simplified protocol has no list operations, the lists are filled from the
+        // rooms of the response.
+        let native_list_rooms = match self.inner.version {
+            SlidingSyncVersion::Proxy { .. } => None,
+            SlidingSyncVersion::Native => {
+                Some(NativeListRoom::collect(&sliding_sync_response.rooms))
+            }
+        };
+
         let update_summary = {
             // Update the rooms.
             let updated_rooms = {
@@ -352,7 +377,16 @@ impl SlidingSync {
                     let maximum_number_of_rooms: u32 =
                         updates.count.try_into().expect("failed to convert `count` to `u32`");
 
-                    if list.update(maximum_number_of_rooms, &updates.ops, &updated_rooms)? {
+                    let new_changes = match &native_list_rooms {
+                        Some(rooms) => {
+                            list.update_native(maximum_number_of_rooms, rooms, &updated_rooms)?
+                        }
+                        None => {
+                            list.update(maximum_number_of_rooms, &updates.ops, &updated_rooms)?
+                        }
+                    };
+
+                    if new_changes {
                         updated_lists.push(name.clone());
                     }
                 }
@@ -394,6 +428,12 @@ impl SlidingSync {
         let room_unsubscriptions = self.inner.room_unsubscriptions.read().unwrap().clone();
         let timeout = Duration::from_secs(30);
 
+        // The delta token is an extension of the proxy.
+        let delta_token = match self.inner.version {
+            SlidingSyncVersion::Proxy { .. } => delta_token,
+            SlidingSyncVersion::Native => None,
+        };
+
         let mut request = assign!(v4::Request::new(), {
             conn_id: Some(self.inner.id.clone()),
             pos,
@@ -432,17 +472,27 @@ impl SlidingSync {
 
     #[instrument(skip_all, fields(pos))]
     async fn sync_once(&self) -> Result<UpdateSummary> {
+        let start = Instant::now();
         let (request, request_config, requested_room_unsubscriptions) =
             self.generate_sync_request(&mut LazyTransactionId::new()).await?;
 
         debug!("Sending the sliding sync request");
 
         // Prepare the request.
-        let request = self.inner.client.send_with_homeserver(
-            request,
-            Some(request_config),
-            self.inner.sliding_sync_proxy.as_ref().map(ToString::to_string),
-        );
+        let request = match &self.inner.version {
+            SlidingSyncVersion::Proxy { url } => {
+                Either::Left(self.inner.client.send_with_homeserver(
+                    request,
+                    Some(request_config),
+                    url.as_ref().map(ToString::to_string),
+                ))
+            }
+            SlidingSyncVersion::Native => Either::Right(self.inner.client.send_with_homeserver(
+                NativeRequest(request),
+                Some(request_config),
+                None,
+            )),
+        };
 
         // Send the request and get a response with end-to-end encryption support.
         //
@@ -514,6 +564,7 @@ impl SlidingSync {
             }
 
             // Handle the response.
+            let processing_start = Instant::now();
             let updates = this.handle_response(response).await?;
 
             this.cache_to_storage().await?;
@@ -521,6 +572,13 @@ impl SlidingSync {
             // Release the lock.
             drop(response_handling_lock);
 
+            metrics::record_duration(
+                metrics::SYNC_PROCESSING_DURATION,
+                processing_start,
+                &[("kind", "sliding")],
+            );
+            metrics::record_duration(metrics::SYNC_DURATION, start, &[("kind", "sliding")]);
+
             debug!("Sliding Sync response has been fully handled");
 
             Ok(updates)
@@ -590,7 +648,7 @@ impl SlidingSync {
                                         }
 
                                         // Force invalidation of all the sticky parameters.
-                                        let _ = self.inner.sticky.write().unwrap().data_mut();
+                                        self.inner.sticky.write().unwrap().reset();
 
                                         self.inner.lists.read().await.values().for_each(|list| list.invalidate_sticky_data());
                                     }).await;
@@ -665,7 +723,7 @@ impl SlidingSync {
 
     /// Get the URL to Sliding Sync.
     pub fn sliding_sync_proxy(&self) -> Option<Url> {
-        self.inner.sliding_sync_proxy.clone()
+        self.inner.version.proxy_url().cloned()
     }
 }
 
@@ -720,7 +778,7 @@ pub struct UpdateSummary {
 
 /// The set of sticky parameters owned by the `SlidingSyncInner` instance, and
 /// sent in the request.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(super) struct SlidingSyncStickyParameters {
     /// Room subscriptions, i.e. rooms that may be out-of-scope of all lists
     /// but one wants to receive updates.
@@ -750,11 +808,44 @@ impl StickyData for SlidingSyncStickyParameters {
             extensions: self.extensions.clone(),
         });
     }
+
+    fn apply_changes(&self, committed: &Self, request: &mut Self::Request) {
+        // The subscriptions are remembered per room, so only the new or
+        // modified ones need to be sent.
+        request.room_subscriptions = self
+            .room_subscriptions
+            .iter()
+            .filter(|(room_id, subscription)| {
+                committed
+                    .room_subscriptions
+                    .get(*room_id)
+                    .map_or(true, |committed| sticky_param_changed(committed, *subscription))
+            })
+            .map(|(room_id, subscription)| (room_id.clone(), subscription.clone()))
+            .collect();
+
+        let (new, old) = (&self.extensions, &committed.extensions);
+        if sticky_param_changed(&old.to_device, &new.to_device) {
+            request.extensions.to_device = new.to_device.clone();
+        }
+        if sticky_param_changed(&old.e2ee, &new.e2ee) {
+            request.extensions.e2ee = new.e2ee.clone();
+        }
+        if sticky_param_changed(&old.account_data, &new.account_data) {
+            request.extensions.account_data = new.account_data.clone();
+        }
+        if sticky_param_changed(&old.receipts, &new.receipts) {
+            request.extensions.receipts = new.receipts.clone();
+        }
+        if sticky_param_changed(&old.typing, &new.typing) {
+            request.extensions.typing = new.typing.clone();
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::ops::Not;
+    use std::{future::ready, ops::Not};
 
     use assert_matches::assert_matches;
     use futures_util::{pin_mut, StreamExt};
@@ -1151,6 +1242,50 @@ mod tests {
         Ok(())
     }
 
+    #[async_test]
+    async fn test_only_changed_room_subscriptions_and_extensions_are_resent() -> Result<()> {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        let sync = client
+            .sliding_sync("test-slidingsync")?
+            .with_to_device_extension(assign!(ToDeviceConfig::default(), { enabled: Some(true) }))
+            .build()
+            .await?;
+
+        let room_id_0 = room_id!("!r0:bar.org");
+        let room_id_1 = room_id!("!r1:bar.org");
+        sync.subscribe_to_room(room_id_0.to_owned(), None);
+
+        let txn_id = TransactionId::new();
+        let (request, _, _) =
+            sync.generate_sync_request(&mut LazyTransactionId::from_owned(txn_id.clone())).await?;
+        assert!(request.room_subscriptions.contains_key(room_id_0));
+        assert_eq!(request.extensions.to_device.enabled, Some(true));
+        sync.inner.sticky.write().unwrap().maybe_commit(&txn_id);
+
+        // Subscribing to another room only sends the new subscription.
+        sync.subscribe_to_room(room_id_1.to_owned(), None);
+
+        let txn_id = TransactionId::new();
+        let (request, _, _) =
+            sync.generate_sync_request(&mut LazyTransactionId::from_owned(txn_id.clone())).await?;
+        assert_eq!(request.room_subscriptions.len(), 1);
+        assert!(request.room_subscriptions.contains_key(room_id_1));
+        assert!(request.extensions.to_device.enabled.is_none());
+        sync.inner.sticky.write().unwrap().maybe_commit(&txn_id);
+
+        // Changing an extension only sends this extension.
+        sync.inner.sticky.write().unwrap().data_mut().extensions.e2ee.enabled = Some(true);
+
+        let (request, _, _) = sync.generate_sync_request(&mut LazyTransactionId::new()).await?;
+        assert!(request.room_subscriptions.is_empty());
+        assert_eq!(request.extensions.e2ee.enabled, Some(true));
+        assert!(request.extensions.to_device.enabled.is_none());
+
+        Ok(())
+    }
+
     #[async_test]
     async fn test_unknown_pos_resets_pos_and_sticky_parameters() -> Result<()> {
         let server = MockServer::start().await;
@@ -1307,4 +1442,151 @@ mod tests {
 
         Ok(())
     }
+
+    #[async_test]
+    async fn test_sliding_sync_version_discovery() -> Result<()> {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        Mock::given(wiremock::matchers::path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "versions": ["r0.6.1"],
+                "unstable_features": { "org.matrix.simplified_msc3575": true },
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(wiremock::matchers::path("/_matrix/client/r0/capabilities"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "capabilities": {
+                    "m.room_versions": { "default": "9", "available": { "9": "stable" } },
+                },
+            })))
+            .mount(&server)
+            .await;
+
+        {
+            // The homeserver supports the native version, it is used even if the client
+            // knows a proxy.
+            client.set_sliding_sync_proxy(Some(Url::parse("https://foo.matrix/").unwrap()));
+            let sync = client.sliding_sync("native")?.build().await?;
+            assert_eq!(sync.version(), &SlidingSyncVersion::Native);
+            assert!(sync.sliding_sync_proxy().is_none());
+        }
+
+        {
+            // …unless the proxy is set explicitly.
+            let url = Url::parse("https://bar.matrix/").unwrap();
+            let sync =
+                client.sliding_sync("own-proxy")?.sliding_sync_proxy(url.clone()).build().await?;
+            assert_eq!(sync.version(), &SlidingSyncVersion::Proxy { url: Some(url) });
+        }
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_native_lists_are_filled_from_the_rooms() -> Result<()> {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        let sliding_sync = client
+            .sliding_sync("native")?
+            .version(SlidingSyncVersion::Native)
+            .add_list(
+                SlidingSyncList::builder("all")
+                    .sync_mode(SlidingSyncMode::new_selective().add_range(0..=10)),
+            )
+            .build()
+            .await?;
+
+        let room_id_0 = room_id!("!r0:bar.org");
+        let room_id_1 = room_id!("!r1:bar.org");
+        let room_id_2 = room_id!("!r2:bar.org");
+
+        let event = |id: &str, ts: u64| {
+            json!({
+                "type": "m.room.message",
+                "event_id": id,
+                "sender": "@alice:bar.org",
+                "origin_server_ts": ts,
+                "content": { "msgtype": "m.text", "body": "hello" },
+            })
+        };
+
+        struct NativeSlidingSyncMatcher;
+
+        impl Match for NativeSlidingSyncMatcher {
+            fn matches(&self, request: &Request) -> bool {
+                request.url.path() == "/_matrix/client/unstable/org.matrix.simplified_msc3575/sync"
+                    && request.method == Method::Post
+            }
+        }
+
+        {
+            // The native responses have no list operations.
+            let _mock_guard = Mock::given(NativeSlidingSyncMatcher)
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "pos": "1",
+                    "lists": {
+                        "all": { "count": 3 },
+                    },
+                    "rooms": {
+                        room_id_0: { "timeline": [event("$e0", 10)] },
+                        room_id_1: { "timeline": [event("$e1", 30)] },
+                        room_id_2: { "name": "Room #2" },
+                    },
+                })))
+                .mount_as_scoped(&server)
+                .await;
+
+            let update_summary = sliding_sync.sync_once().await?;
+            assert_eq!(update_summary.lists, ["all"]);
+        }
+
+        let room_list = sliding_sync
+            .on_list("all", |list| ready(list.room_list::<RoomListEntry>()))
+            .await
+            .unwrap();
+        assert_eq!(
+            room_list,
+            [
+                RoomListEntry::Filled(room_id_1.to_owned()),
+                RoomListEntry::Filled(room_id_0.to_owned()),
+                RoomListEntry::Filled(room_id_2.to_owned()),
+            ]
+        );
+
+        {
+            // A new event moves the room to the top of the list.
+            let _mock_guard = Mock::given(NativeSlidingSyncMatcher)
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "pos": "2",
+                    "lists": {
+                        "all": { "count": 3 },
+                    },
+                    "rooms": {
+                        room_id_2: { "timeline": [event("$e2", 40)] },
+                    },
+                })))
+                .mount_as_scoped(&server)
+                .await;
+
+            sliding_sync.sync_once().await?;
+        }
+
+        let room_list = sliding_sync
+            .on_list("all", |list| ready(list.room_list::<RoomListEntry>()))
+            .await
+            .unwrap();
+        assert_eq!(
+            room_list,
+            [
+                RoomListEntry::Filled(room_id_2.to_owned()),
+                RoomListEntry::Filled(room_id_1.to_owned()),
+                RoomListEntry::Filled(room_id_0.to_owned()),
+            ]
+        );
+
+        Ok(())
+    }
 }
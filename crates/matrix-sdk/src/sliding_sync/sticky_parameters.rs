@@ -6,6 +6,7 @@
 //! [MSC](https://github.com/matrix-org/matrix-spec-proposals/blob/kegan/sync-v3/proposals/3575-sync.md).
 
 use ruma::{OwnedTransactionId, TransactionId};
+use serde::Serialize;
 
 /// An `OwnedTransactionId` that is either initialized at creation, or
 /// lazily-generated once.
@@ -42,13 +43,34 @@ impl LazyTransactionId {
 }
 
 /// A trait to implement for data that can be sticky, given a context.
-pub trait StickyData {
+pub trait StickyData: Clone {
     /// Request type that will be applied to, if the sticky parameters have been
     /// invalidated before.
     type Request;
 
     /// Apply the current data onto the request.
     fn apply(&self, request: &mut Self::Request);
+
+    /// Apply only the parts of the current data that changed since the
+    /// `committed` data, the last one acknowledged by the server, onto the
+    /// request.
+    ///
+    /// The server remembers the sticky parameters that are omitted from a
+    /// request, so this spares bandwidth. By default, all the data is applied.
+    fn apply_changes(&self, committed: &Self, request: &mut Self::Request) {
+        let _ = committed;
+        self.apply(request);
+    }
+}
+
+/// Whether the given sticky parameters differ, comparing their serialized
+/// form since most request types don't implement `PartialEq`.
+pub fn sticky_param_changed<T: Serialize>(old: &T, new: &T) -> bool {
+    match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(old), Ok(new)) => old != new,
+        // Resend the parameter if in doubt.
+        _ => true,
+    }
 }
 
 /// Helper data structure to manage sticky parameters, for any kind of data.
@@ -63,6 +85,10 @@ pub trait StickyData {
 /// the transaction id later, and only consider the data isn't invalidated
 /// anymore (we say it's "committed" in that case) if the response's transaction
 /// id match what we expect.
+///
+/// Once the server acknowledged some data, only the parts of the data that
+/// changed since then are applied onto the next requests, see
+/// [`StickyData::apply_changes`].
 #[derive(Debug)]
 pub struct SlidingSyncStickyManager<D: StickyData> {
     /// The data managed by this sticky manager.
@@ -75,6 +101,12 @@ pub struct SlidingSyncStickyManager<D: StickyData> {
     /// the transaction id generated for that request, that must be matched
     /// upon in the next call to `commit()`.
     txn_id: Option<OwnedTransactionId>,
+
+    /// The data that was applied to the request with `txn_id`.
+    pending: Option<D>,
+
+    /// The data that was last acknowledged by the server, if any.
+    committed: Option<D>,
 }
 
 impl<D: StickyData> SlidingSyncStickyManager<D> {
@@ -82,7 +114,7 @@ impl<D: StickyData> SlidingSyncStickyManager<D> {
     ///
     /// Always assume the initial data invalidates the request, at first.
     pub fn new(data: D) -> Self {
-        Self { data, txn_id: None, invalidated: true }
+        Self { data, txn_id: None, invalidated: true, pending: None, committed: None }
     }
 
     /// Get a mutable reference to the managed data.
@@ -96,6 +128,13 @@ impl<D: StickyData> SlidingSyncStickyManager<D> {
         &mut self.data
     }
 
+    /// Invalidate the whole managed data, e.g. because the server forgot it,
+    /// so it's entirely applied onto the next request.
+    pub fn reset(&mut self) {
+        self.invalidated = true;
+        self.committed = None;
+    }
+
     /// Returns a non-invalidating reference to the managed data.
     pub fn data(&self) -> &D {
         &self.data
@@ -113,7 +152,11 @@ impl<D: StickyData> SlidingSyncStickyManager<D> {
         if self.invalidated {
             let txn_id = txn_id.get_or_create();
             self.txn_id = Some(txn_id.to_owned());
-            self.data.apply(req);
+            match &self.committed {
+                Some(committed) => self.data.apply_changes(committed, req),
+                None => self.data.apply(req),
+            }
+            self.pending = Some(self.data.clone());
         }
     }
 
@@ -122,6 +165,7 @@ impl<D: StickyData> SlidingSyncStickyManager<D> {
     pub fn maybe_commit(&mut self, txn_id: &TransactionId) {
         if self.invalidated && self.txn_id.as_deref() == Some(txn_id) {
             self.invalidated = false;
+            self.committed = self.pending.take();
         }
     }
 
@@ -135,6 +179,7 @@ impl<D: StickyData> SlidingSyncStickyManager<D> {
 mod tests {
     use super::*;
 
+    #[derive(Clone)]
     struct EmptyStickyData;
 
     impl StickyData for EmptyStickyData {
@@ -178,4 +223,59 @@ mod tests {
         assert!(!sticky.is_invalidated());
         assert!(txn_id.get().is_none());
     }
+
+    #[derive(Clone)]
+    struct TwoParams {
+        a: u8,
+        b: u8,
+    }
+
+    impl StickyData for TwoParams {
+        type Request = (Option<u8>, Option<u8>);
+
+        fn apply(&self, req: &mut Self::Request) {
+            *req = (Some(self.a), Some(self.b));
+        }
+
+        fn apply_changes(&self, committed: &Self, req: &mut Self::Request) {
+            if self.a != committed.a {
+                req.0 = Some(self.a);
+            }
+            if self.b != committed.b {
+                req.1 = Some(self.b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sticky_parameters_only_changes_are_applied_after_commit() {
+        let mut sticky = SlidingSyncStickyManager::new(TwoParams { a: 1, b: 2 });
+
+        let mut req = (None, None);
+        let mut txn_id = LazyTransactionId::new();
+        sticky.maybe_apply(&mut req, &mut txn_id);
+        assert_eq!(req, (Some(1), Some(2)));
+        sticky.maybe_commit(txn_id.get().unwrap());
+
+        sticky.data_mut().b = 3;
+        let mut req = (None, None);
+        let mut txn_id = LazyTransactionId::new();
+        sticky.maybe_apply(&mut req, &mut txn_id);
+        assert_eq!(req, (None, Some(3)));
+
+        // Until the server acknowledges the change, it's compared to the data
+        // committed before.
+        sticky.data_mut().a = 4;
+        let mut req = (None, None);
+        let mut txn_id = LazyTransactionId::new();
+        sticky.maybe_apply(&mut req, &mut txn_id);
+        assert_eq!(req, (Some(4), Some(3)));
+        sticky.maybe_commit(txn_id.get().unwrap());
+
+        // Resetting applies everything again.
+        sticky.reset();
+        let mut req = (None, None);
+        sticky.maybe_apply(&mut req, &mut LazyTransactionId::new());
+        assert_eq!(req, (Some(4), Some(3)));
+    }
 }
@@ -0,0 +1,279 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use bytes::BufMut;
+use ruma::{
+    api::{
+        client::sync::sync_events::v4, error::IntoHttpError, MatrixVersion, Metadata,
+        OutgoingRequest, SendAccessToken,
+    },
+    MilliSecondsSinceUnixEpoch, OwnedRoomId,
+};
+use serde_json::Value as JsonValue;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::Client;
+
+/// The unstable feature advertised by the homeservers implementing the
+/// simplified sliding sync natively.
+const NATIVE_UNSTABLE_FEATURE: &str = "org.matrix.simplified_msc3575";
+
+const PROXY_PATH: &str = "/_matrix/client/unstable/org.matrix.msc3575/sync";
+const NATIVE_PATH: &str = "/_matrix/client/unstable/org.matrix.simplified_msc3575/sync";
+
+/// The version of the sliding sync protocol spoken with the server.
+///
+/// It is discovered when the [`SlidingSync`] is built, unless it is set with
+/// [`SlidingSyncBuilder::version()`]. Both versions are used through the same
+/// API, but the simplified protocol has no list operations: the rooms of the
+/// lists are always sorted by recency, and the client fills the lists itself
+/// from the rooms of the responses.
+///
+/// [`SlidingSync`]: super::SlidingSync
+/// [`SlidingSyncBuilder::version()`]: super::SlidingSyncBuilder::version
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SlidingSyncVersion {
+    /// The original protocol, served by a sliding sync proxy.
+    Proxy {
+        /// The URL of the proxy, or `None` to send the requests to the
+        /// homeserver.
+        url: Option<Url>,
+    },
+
+    /// The simplified protocol, implemented natively by the homeserver.
+    Native,
+}
+
+impl SlidingSyncVersion {
+    /// Discover the version supported by the homeserver of the given client.
+    ///
+    /// The native version is used if the homeserver advertises it in its
+    /// `/versions` response, otherwise the proxy discovered with the
+    /// `.well-known` endpoint, if any.
+    pub(super) async fn discover(client: &Client) -> Self {
+        match client.server_capabilities().await {
+            Ok(server) if server.supports_unstable_feature(NATIVE_UNSTABLE_FEATURE) => {
+                debug!("The homeserver supports sliding sync natively");
+                return Self::Native;
+            }
+            Ok(_) => {}
+            Err(error) => {
+                warn!("Couldn't get the capabilities of the homeserver, using the proxy: {error}");
+            }
+        }
+
+        Self::Proxy { url: client.sliding_sync_proxy() }
+    }
+
+    /// The URL of the sliding sync proxy, if any.
+    pub(super) fn proxy_url(&self) -> Option<&Url> {
+        match self {
+            Self::Proxy { url } => url.as_ref(),
+            Self::Native => None,
+        }
+    }
+}
+
+/// The fields of the lists of the original protocol that were removed from the
+/// simplified one.
+const PROXY_ONLY_LIST_FIELDS: &[&str] = &["sort", "bump_event_types", "slow_get_all_rooms"];
+
+/// A sliding sync request sent to the native endpoint of the homeserver.
+///
+/// The simplified protocol is a subset of the original one: the request is
+/// sent to another path, without the fields of the lists that only the proxy
+/// understands.
+#[derive(Clone, Debug)]
+pub(super) struct NativeRequest(pub v4::Request);
+
+impl OutgoingRequest for NativeRequest {
+    type EndpointError = <v4::Request as OutgoingRequest>::EndpointError;
+    type IncomingResponse = v4::Response;
+
+    const METADATA: Metadata = v4::Request::METADATA;
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+        considering_versions: &[MatrixVersion],
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let request = self.0.try_into_http_request::<Vec<u8>>(
+            base_url,
+            access_token,
+            considering_versions,
+        )?;
+        let (mut parts, body) = request.into_parts();
+
+        let uri = parts.uri.to_string().replacen(PROXY_PATH, NATIVE_PATH, 1);
+        parts.uri = uri.parse().map_err(http::Error::from)?;
+
+        let mut json = serde_json::from_slice::<JsonValue>(&body)?;
+        if let Some(lists) = json.get_mut("lists").and_then(JsonValue::as_object_mut) {
+            for list in lists.values_mut().filter_map(JsonValue::as_object_mut) {
+                for field in PROXY_ONLY_LIST_FIELDS {
+                    list.remove(*field);
+                }
+            }
+        }
+
+        let mut native_body = T::default();
+        native_body.put_slice(&serde_json::to_vec(&json)?);
+
+        Ok(http::Request::from_parts(parts, native_body))
+    }
+}
+
+/// A room of a response of the simplified protocol, as seen by the lists.
+#[derive(Clone, Debug)]
+pub(super) struct NativeListRoom {
+    /// The ID of the room.
+    pub room_id: OwnedRoomId,
+
+    /// Whether the user is invited to the room.
+    pub is_invite: bool,
+
+    /// Whether the room is a direct message, if the server said so.
+    pub is_dm: Option<bool>,
+
+    /// The timestamp of the most recent event of the timeline of the room in
+    /// the response, if any.
+    pub latest_timestamp: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl NativeListRoom {
+    /// Collect the rooms of a response of the simplified protocol, in the order
+    /// the lists must handle them.
+    ///
+    /// The rooms without new events come first, then the other rooms from the
+    /// oldest to the most recent activity, so that moving each of them to the
+    /// top of a list leaves the list sorted by recency.
+    pub(super) fn collect(rooms: &BTreeMap<OwnedRoomId, v4::SlidingSyncRoom>) -> Vec<Self> {
+        let mut list_rooms: Vec<_> = rooms
+            .iter()
+            .map(|(room_id, room)| Self {
+                room_id: room_id.clone(),
+                is_invite: !room.invite_state.is_empty(),
+                is_dm: room.is_dm,
+                latest_timestamp: room
+                    .timeline
+                    .iter()
+                    .filter_map(|event| {
+                        event.get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts").ok()?
+                    })
+                    .max(),
+            })
+            .collect();
+
+        // The sort is stable, the rooms with the same timestamp stay in the order of
+        // their IDs.
+        list_rooms.sort_by_key(|room| room.latest_timestamp);
+
+        list_rooms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use assert_matches::assert_matches;
+    use ruma::{
+        api::{client::sync::sync_events::v4, OutgoingRequest, SendAccessToken},
+        assign, room_id,
+        serde::Raw,
+        uint,
+    };
+    use serde_json::{json, Value as JsonValue};
+
+    use super::{NativeListRoom, NativeRequest};
+
+    #[test]
+    fn native_request_path() {
+        let request = NativeRequest(v4::Request::new())
+            .try_into_http_request::<Vec<u8>>(
+                "https://example.org",
+                SendAccessToken::IfRequired("token"),
+                &[],
+            )
+            .unwrap();
+
+        assert_eq!(
+            request.uri().path(),
+            "/_matrix/client/unstable/org.matrix.simplified_msc3575/sync"
+        );
+    }
+
+    #[test]
+    fn native_request_has_no_list_operations() {
+        let list = assign!(v4::SyncRequestList::default(), {
+            ranges: vec![(uint!(0), uint!(9))],
+            sort: vec!["by_recency".to_owned()],
+            bump_event_types: vec!["m.room.message".into()],
+        });
+        let request = assign!(v4::Request::new(), {
+            lists: BTreeMap::from([("all".to_owned(), list)]),
+        });
+
+        let request = NativeRequest(request)
+            .try_into_http_request::<Vec<u8>>(
+                "https://example.org",
+                SendAccessToken::IfRequired("token"),
+                &[],
+            )
+            .unwrap();
+
+        let body: JsonValue = serde_json::from_slice(request.body()).unwrap();
+        let list = assert_matches!(body["lists"]["all"].as_object(), Some(list) => list);
+        assert_eq!(list["ranges"], json!([[0, 9]]));
+        assert!(!list.contains_key("sort"));
+        assert!(!list.contains_key("bump_event_types"));
+    }
+
+    #[test]
+    fn native_list_rooms_are_sorted_by_recency() {
+        let event = |ts: u64| {
+            Raw::new(&json!({
+                "type": "m.room.message",
+                "event_id": format!("$event{ts}"),
+                "sender": "@alice:example.org",
+                "origin_server_ts": ts,
+                "content": { "msgtype": "m.text", "body": "hello" },
+            }))
+            .unwrap()
+            .cast()
+        };
+
+        let rooms = BTreeMap::from([
+            (
+                room_id!("!a:example.org").to_owned(),
+                assign!(v4::SlidingSyncRoom::new(), { timeline: vec![event(30), event(10)] }),
+            ),
+            (room_id!("!b:example.org").to_owned(), v4::SlidingSyncRoom::new()),
+            (
+                room_id!("!c:example.org").to_owned(),
+                assign!(v4::SlidingSyncRoom::new(), { timeline: vec![event(20)] }),
+            ),
+        ]);
+
+        let room_ids: Vec<_> = NativeListRoom::collect(&rooms)
+            .into_iter()
+            .map(|room| room.room_id.to_string())
+            .collect();
+        assert_eq!(room_ids, ["!b:example.org", "!c:example.org", "!a:example.org"]);
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unread notification counts rolled up per space.
+//!
+//! The hierarchy of the spaces is built from their `m.space.child` state
+//! events, so a space includes the rooms of its subspaces. A room that is part
+//! of a space through several paths is only counted once for that space.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, mem,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use matrix_sdk_common::executor::{spawn, JoinHandle};
+use ruma::{
+    events::{AnySyncStateEvent, StateEventType},
+    serde::Raw,
+    OwnedRoomId, RoomId,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    deserialized_responses::RawAnySyncOrStrippedState,
+    event_handler::EventHandlerHandle,
+    room::{self, Room},
+    sync::UnreadNotificationsCount,
+    Client, Result,
+};
+
+/// The unread notification counts of the spaces of the user, by space ID.
+pub type SpaceCounts = BTreeMap<OwnedRoomId, UnreadNotificationsCount>;
+
+/// An observable rollup of the unread notification counts of the joined rooms
+/// in every joined space of the user, to show badges on the spaces.
+///
+/// It can be created with [`Client::space_notification_counts()`]. The counts
+/// are updated after every sync, and only the spaces containing a room whose
+/// counts or children changed are recomputed.
+///
+/// The event handler and the background task updating the counts are removed
+/// when this is dropped.
+pub struct SpaceNotificationCounts {
+    inner: Arc<SpaceCountsInner>,
+    handle: EventHandlerHandle,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    task: JoinHandle<()>,
+}
+
+struct SpaceCountsInner {
+    client: Client,
+    /// The spaces whose `m.space.child` events changed since the last update.
+    dirty_spaces: StdMutex<BTreeSet<OwnedRoomId>>,
+    state: StdMutex<SpaceCountsState>,
+    counts: SharedObservable<SpaceCounts>,
+}
+
+#[derive(Default)]
+struct SpaceCountsState {
+    /// The children of the joined spaces.
+    children: BTreeMap<OwnedRoomId, BTreeSet<OwnedRoomId>>,
+    /// The joined spaces that have the room as a child, by room ID.
+    parents: BTreeMap<OwnedRoomId, BTreeSet<OwnedRoomId>>,
+    /// The counts of the joined rooms that are not spaces.
+    rooms: BTreeMap<OwnedRoomId, UnreadNotificationsCount>,
+}
+
+impl SpaceNotificationCounts {
+    pub(crate) async fn new(client: Client) -> Result<Self> {
+        let inner = Arc::new(SpaceCountsInner {
+            client,
+            dirty_spaces: Default::default(),
+            state: Default::default(),
+            counts: SharedObservable::new(SpaceCounts::new()),
+        });
+        inner.update().await?;
+
+        let handle = Arc::clone(&inner).add_event_handler();
+        let task = spawn(Arc::clone(&inner).run());
+
+        Ok(Self { inner, handle, task })
+    }
+
+    /// Get the counts of the given space.
+    ///
+    /// Returns `None` if the space isn't joined.
+    pub fn get(&self, space_id: &RoomId) -> Option<UnreadNotificationsCount> {
+        self.inner.counts.read().get(space_id).copied()
+    }
+
+    /// Get the current counts of all the joined spaces.
+    pub fn all(&self) -> SpaceCounts {
+        self.inner.counts.get()
+    }
+
+    /// Subscribe to the updates of the counts of all the joined spaces.
+    pub fn subscribe(&self) -> Subscriber<SpaceCounts> {
+        self.inner.counts.subscribe()
+    }
+}
+
+impl Drop for SpaceNotificationCounts {
+    fn drop(&mut self) {
+        self.inner.client.remove_event_handler(self.handle.clone());
+
+        // The task is aborted when its handle is dropped on WASM.
+        #[cfg(not(target_arch = "wasm32"))]
+        self.task.abort();
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for SpaceNotificationCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpaceNotificationCounts")
+            .field("counts", &*self.inner.counts.read())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SpaceCountsInner {
+    fn add_event_handler(self: Arc<Self>) -> EventHandlerHandle {
+        let client = self.client.clone();
+
+        // The `m.space.child` events without `via` remove a child, but they
+        // might not be valid according to ruma.
+        client.add_event_handler(move |event: Raw<AnySyncStateEvent>, room: Room| {
+            let inner = self.clone();
+            async move {
+                if let Ok(Some(StateEventType::SpaceChild)) =
+                    event.get_field::<StateEventType>("type")
+                {
+                    inner.dirty_spaces.lock().unwrap().insert(room.room_id().to_owned());
+                }
+            }
+        })
+    }
+
+    async fn run(self: Arc<Self>) {
+        loop {
+            self.client.inner.sync_beat.listen().await;
+
+            if let Err(error) = self.update().await {
+                warn!(?error, "Couldn't update the counts of the spaces");
+            }
+        }
+    }
+
+    /// Update the hierarchy and the counts of the rooms, and recompute the
+    /// counts of the spaces that changed.
+    async fn update(&self) -> Result<()> {
+        let dirty_spaces = mem::take(&mut *self.dirty_spaces.lock().unwrap());
+        let known_spaces: BTreeSet<_> =
+            self.state.lock().unwrap().children.keys().cloned().collect();
+
+        let mut spaces = BTreeMap::new();
+        let mut rooms = BTreeMap::new();
+
+        for room in self.client.joined_rooms() {
+            let room_id = room.room_id();
+
+            if room.is_space() {
+                // Only load the children of the new and updated spaces.
+                let is_outdated = dirty_spaces.contains(room_id) || !known_spaces.contains(room_id);
+                let children = if is_outdated { Some(load_children(&room).await?) } else { None };
+                spaces.insert(room_id.to_owned(), children);
+            } else {
+                rooms.insert(room_id.to_owned(), room.unread_notification_counts());
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let changed = state.update(spaces, rooms);
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        let mut counts = self.counts.get();
+        for space_id in state.ancestors(changed) {
+            if let Some(space_counts) = state.counts(&space_id) {
+                counts.insert(space_id, space_counts);
+            } else {
+                counts.remove(&space_id);
+            }
+        }
+
+        if counts != *self.counts.read() {
+            self.counts.set(counts);
+        }
+
+        Ok(())
+    }
+}
+
+impl SpaceCountsState {
+    /// Replace the joined spaces and rooms.
+    ///
+    /// The children are `None` for the spaces that didn't change.
+    ///
+    /// Returns the rooms and spaces that changed.
+    fn update(
+        &mut self,
+        spaces: BTreeMap<OwnedRoomId, Option<BTreeSet<OwnedRoomId>>>,
+        rooms: BTreeMap<OwnedRoomId, UnreadNotificationsCount>,
+    ) -> BTreeSet<OwnedRoomId> {
+        let mut changed = BTreeSet::new();
+
+        let left_spaces: Vec<_> =
+            self.children.keys().filter(|id| !spaces.contains_key(*id)).cloned().collect();
+        for space_id in left_spaces {
+            self.set_children(&space_id, BTreeSet::new());
+            self.children.remove(&space_id);
+            changed.insert(space_id);
+        }
+
+        for (space_id, children) in spaces {
+            let Some(children) = children else { continue };
+
+            if self.children.get(&space_id) != Some(&children) {
+                self.set_children(&space_id, children);
+                changed.insert(space_id);
+            }
+        }
+
+        changed.extend(self.rooms.keys().filter(|id| !rooms.contains_key(*id)).cloned());
+        changed.extend(
+            rooms
+                .iter()
+                .filter(|(id, counts)| self.rooms.get(*id) != Some(*counts))
+                .map(|(id, _)| id.clone()),
+        );
+        self.rooms = rooms;
+
+        changed
+    }
+
+    fn set_children(&mut self, space_id: &RoomId, children: BTreeSet<OwnedRoomId>) {
+        let previous = self.children.insert(space_id.to_owned(), children.clone());
+
+        for child_id in previous.iter().flatten().filter(|id| !children.contains(*id)) {
+            if let Some(parents) = self.parents.get_mut(child_id) {
+                parents.remove(space_id);
+                if parents.is_empty() {
+                    self.parents.remove(child_id);
+                }
+            }
+        }
+
+        for child_id in children {
+            self.parents.entry(child_id).or_default().insert(space_id.to_owned());
+        }
+    }
+
+    /// Get the given rooms and the spaces that contain them, directly or
+    /// through subspaces.
+    fn ancestors(&self, room_ids: BTreeSet<OwnedRoomId>) -> BTreeSet<OwnedRoomId> {
+        let mut ancestors = BTreeSet::new();
+        let mut queue: Vec<_> = room_ids.into_iter().collect();
+
+        while let Some(room_id) = queue.pop() {
+            if let Some(parents) = self.parents.get(&room_id) {
+                queue.extend(parents.iter().filter(|id| !ancestors.contains(*id)).cloned());
+            }
+
+            ancestors.insert(room_id);
+        }
+
+        ancestors
+    }
+
+    /// Compute the counts of the given space, by adding the counts of all the
+    /// rooms it contains.
+    ///
+    /// Returns `None` if the space isn't joined.
+    fn counts(&self, space_id: &RoomId) -> Option<UnreadNotificationsCount> {
+        self.children.get(space_id)?;
+
+        let mut visited = BTreeSet::from([space_id.to_owned()]);
+        let mut queue: Vec<&RoomId> = vec![space_id];
+        let mut counts = UnreadNotificationsCount::default();
+
+        while let Some(room_id) = queue.pop() {
+            for child_id in self.children.get(room_id).into_iter().flatten() {
+                // A room can be in several subspaces, and the hierarchy can
+                // contain cycles.
+                if !visited.insert(child_id.clone()) {
+                    continue;
+                }
+
+                if let Some(room_counts) = self.rooms.get(child_id) {
+                    counts.highlight_count += room_counts.highlight_count;
+                    counts.notification_count += room_counts.notification_count;
+                }
+
+                queue.push(child_id);
+            }
+        }
+
+        Some(counts)
+    }
+}
+
+/// Load the children of the given space from its `m.space.child` state
+/// events.
+async fn load_children(space: &room::Common) -> Result<BTreeSet<OwnedRoomId>> {
+    // A child is removed by sending an event without `via`, so we can't rely on
+    // the content being valid.
+    #[derive(Deserialize)]
+    struct SpaceChild {
+        state_key: OwnedRoomId,
+        content: SpaceChildContent,
+    }
+
+    #[derive(Deserialize)]
+    struct SpaceChildContent {
+        via: Option<Vec<String>>,
+    }
+
+    let children = space
+        .get_state_events(StateEventType::SpaceChild)
+        .await?
+        .into_iter()
+        .filter_map(|event| match event {
+            RawAnySyncOrStrippedState::Sync(event) => event.deserialize_as::<SpaceChild>().ok(),
+            RawAnySyncOrStrippedState::Stripped(_) => None,
+        })
+        .filter(|child| child.content.via.as_ref().is_some_and(|via| !via.is_empty()))
+        .map(|child| child.state_key)
+        .collect();
+
+    Ok(children)
+}
@@ -39,7 +39,7 @@ use ruma::{
 };
 use tracing::{debug, error, warn};
 
-use crate::{event_handler::HandlerKind, room, Client, Result};
+use crate::{event_handler::HandlerKind, presence, room, Client, Result};
 
 /// The processed response of a `/sync` request.
 #[derive(Clone, Default)]
@@ -161,7 +161,15 @@ impl Client {
         &self,
         response: sync_events::v3::Response,
     ) -> Result<BaseSyncResponse> {
-        let response = Box::pin(self.base_client().receive_sync_response(response)).await?;
+        #[cfg(feature = "e2e-encryption")]
+        self.encryption().lock_store().await?;
+
+        let response = Box::pin(self.base_client().receive_sync_response(response)).await;
+
+        #[cfg(feature = "e2e-encryption")]
+        self.encryption().unlock_store().await?;
+
+        let response = response?;
         self.handle_sync_response(&response).await?;
         Ok(response)
     }
@@ -182,6 +190,7 @@ impl Client {
         let now = Instant::now();
         self.handle_sync_events(HandlerKind::GlobalAccountData, None, account_data).await?;
         self.handle_sync_events(HandlerKind::Presence, None, presence).await?;
+        presence::broadcast_presence(self, presence);
         self.handle_sync_events(HandlerKind::ToDevice, None, to_device).await?;
 
         for (room_id, room_info) in &rooms.join {
@@ -0,0 +1,265 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for the [User-Interactive Authentication API][uiaa].
+//!
+//! [uiaa]: https://spec.matrix.org/v1.2/client-server-api/#user-interactive-authentication-api
+
+use std::{fmt, future::Future};
+
+use ruma::api::client::uiaa::{
+    AuthData, AuthType, Dummy, FallbackAcknowledgement, Password, RegistrationToken, UiaaInfo,
+    UserIdentifier,
+};
+use tracing::debug;
+use url::Url;
+
+use crate::{Client, Error, Result};
+
+/// A driver for a request protected by the User-Interactive Authentication
+/// API.
+///
+/// The request is first sent without authentication data with
+/// [`UiaaFlow::send()`]. If the homeserver requires authentication, the
+/// stages it accepts are returned by [`UiaaFlow::next_stages()`], and each
+/// stage is completed with one of the `complete_*` methods, which sends the
+/// request again with the authentication data of the stage.
+///
+/// All these methods return the response of the request once the stages of
+/// one of the flows are all completed, and `None` while stages remain. If the
+/// authentication data of a stage is rejected, the error is returned and the
+/// stage can be attempted again.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use matrix_sdk::{
+/// #     ruma::{api::client::uiaa::AuthType, device_id},
+/// #     uiaa::UiaaFlow,
+/// #     Client,
+/// # };
+/// # use url::Url;
+/// # async {
+/// # let homeserver = Url::parse("http://localhost:8080")?;
+/// # let client = Client::new(homeserver).await?;
+/// let devices = &[device_id!("DEVICEID").to_owned()];
+/// let mut flow = UiaaFlow::new(&client, |auth_data| {
+///     client.delete_devices(devices, auth_data)
+/// });
+///
+/// let mut response = flow.send().await?;
+/// while response.is_none() {
+///     let stages = flow.next_stages();
+///
+///     response = if stages.contains(&AuthType::Password) {
+///         flow.complete_password("wordpass").await?
+///     } else if let Some(_url) = flow.fallback_url(&stages[0]).await {
+///         // Open the URL in a browser, and wait for the user to complete the
+///         // stage.
+///         flow.complete_fallback().await?
+///     } else {
+///         anyhow::bail!("Unsupported stages: {stages:?}");
+///     };
+/// }
+/// # anyhow::Ok(()) };
+/// ```
+pub struct UiaaFlow<F> {
+    client: Client,
+    send: F,
+    info: Option<UiaaInfo>,
+    /// The error of the latest response, if it requires stages to be
+    /// completed.
+    pending_error: Option<Error>,
+}
+
+impl<F, Fut, T, E> UiaaFlow<F>
+where
+    F: Fn(Option<AuthData>) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Into<Error>,
+{
+    /// Create a new `UiaaFlow` for the request sent by the given function.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client sending the request.
+    ///
+    /// * `send` - A function sending the request with the given
+    /// authentication data, like [`Client::register()`],
+    /// [`Client::delete_devices()`] or [`Account::add_3pid()`].
+    ///
+    /// [`Account::add_3pid()`]: crate::Account::add_3pid
+    pub fn new(client: &Client, send: F) -> Self {
+        Self { client: client.clone(), send, info: None, pending_error: None }
+    }
+
+    /// Send the request without authentication data.
+    ///
+    /// Returns the response if the homeserver doesn't require authentication
+    /// for it, or `None` if stages need to be completed.
+    pub async fn send(&mut self) -> Result<Option<T>> {
+        self.send_with(None).await
+    }
+
+    /// The latest information about the authentication sent by the
+    /// homeserver, if it required authentication.
+    pub fn info(&self) -> Option<&UiaaInfo> {
+        self.info.as_ref()
+    }
+
+    /// The ID of the authentication session, if the homeserver started one.
+    pub fn session(&self) -> Option<&str> {
+        self.info.as_ref()?.session.as_deref()
+    }
+
+    /// The stages that remain to be completed, in order, for each of the flows
+    /// accepted by the homeserver.
+    pub fn remaining_stages(&self) -> Vec<Vec<AuthType>> {
+        let Some(info) = &self.info else {
+            return Vec::new();
+        };
+
+        info.flows
+            .iter()
+            .filter(|flow| flow.stages.starts_with(&info.completed))
+            .map(|flow| flow.stages.iter().skip(info.completed.len()).cloned().collect())
+            .collect()
+    }
+
+    /// The stages that can be completed next, without duplicates.
+    pub fn next_stages(&self) -> Vec<AuthType> {
+        let mut stages = Vec::new();
+
+        for stage in self.remaining_stages().into_iter().filter_map(|s| s.into_iter().next()) {
+            if !stages.contains(&stage) {
+                stages.push(stage);
+            }
+        }
+
+        stages
+    }
+
+    /// Complete the [`AuthType::Password`] stage with the password of the
+    /// account.
+    ///
+    /// The client must be logged in.
+    pub async fn complete_password(&mut self, password: &str) -> Result<Option<T>> {
+        let user_id = self.client.user_id().ok_or(Error::AuthenticationRequired)?;
+
+        let mut password = Password::new(
+            UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+            password.to_owned(),
+        );
+        password.session = self.session().map(ToOwned::to_owned);
+
+        self.send_with(Some(AuthData::Password(password))).await
+    }
+
+    /// Complete the [`AuthType::RegistrationToken`] stage with the given
+    /// token.
+    pub async fn complete_registration_token(&mut self, token: &str) -> Result<Option<T>> {
+        let mut token = RegistrationToken::new(token.to_owned());
+        token.session = self.session().map(ToOwned::to_owned);
+
+        self.send_with(Some(AuthData::RegistrationToken(token))).await
+    }
+
+    /// Complete the [`AuthType::Dummy`] stage.
+    pub async fn complete_dummy(&mut self) -> Result<Option<T>> {
+        let mut dummy = Dummy::new();
+        dummy.session = self.session().map(ToOwned::to_owned);
+
+        self.send_with(Some(AuthData::Dummy(dummy))).await
+    }
+
+    /// Get the URL of the web page where the user can complete the given
+    /// stage, like [`AuthType::Sso`].
+    ///
+    /// Once the user completed the stage in a browser, call
+    /// [`UiaaFlow::complete_fallback()`].
+    ///
+    /// Returns `None` if the homeserver didn't start an authentication
+    /// session.
+    pub async fn fallback_url(&self, stage: &AuthType) -> Option<Url> {
+        let session = self.session()?;
+        let homeserver = self.client.homeserver().await;
+
+        let mut url = Url::parse(&format!(
+            "{}/_matrix/client/v3/auth/{}/fallback/web",
+            homeserver.as_str().trim_end_matches('/'),
+            stage.as_str(),
+        ))
+        .ok()?;
+        url.query_pairs_mut().append_pair("session", session);
+
+        Some(url)
+    }
+
+    /// Complete the stage that the user completed on the web page returned by
+    /// [`UiaaFlow::fallback_url()`].
+    pub async fn complete_fallback(&mut self) -> Result<Option<T>> {
+        let session = self.session().ok_or(Error::InsufficientData)?.to_owned();
+        let ack = FallbackAcknowledgement::new(session);
+
+        self.send_with(Some(AuthData::FallbackAcknowledgement(ack))).await
+    }
+
+    /// Complete a stage with the given authentication data.
+    ///
+    /// This is useful for the stages that don't have a dedicated method. The
+    /// session ID must be set in the authentication data.
+    pub async fn complete(&mut self, auth_data: AuthData) -> Result<Option<T>> {
+        self.send_with(Some(auth_data)).await
+    }
+
+    /// Take the error returned by the homeserver with the latest stages to
+    /// complete, if any.
+    pub(crate) fn take_pending_error(&mut self) -> Option<Error> {
+        self.pending_error.take()
+    }
+
+    async fn send_with(&mut self, auth_data: Option<AuthData>) -> Result<Option<T>> {
+        self.pending_error = None;
+
+        let error = match (self.send)(auth_data).await {
+            Ok(response) => {
+                self.info = None;
+                return Ok(Some(response));
+            }
+            Err(error) => error.into(),
+        };
+
+        let Some(info) = error.as_uiaa_response() else {
+            return Err(error);
+        };
+
+        let rejected = info.auth_error.is_some();
+        self.info = Some(info.clone());
+
+        if rejected {
+            debug!("The homeserver rejected the authentication data");
+            Err(error)
+        } else {
+            self.pending_error = Some(error);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<F> fmt::Debug for UiaaFlow<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UiaaFlow").field("info", &self.info).finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers to navigate to the targets of `matrix.to` and `matrix:` URIs.
+//!
+//! A URI is parsed into a [`UriTarget`], that can be resolved into a
+//! [`ResolvedUri`] with the details of the room or the user, to be shown
+//! before navigating to it. [`Client::resolve_uri()`] does both at once.
+
+use ruma::{
+    matrix_uri::MatrixId, IdParseError, MatrixToUri, MatrixUri, OwnedEventId, OwnedMxcUri,
+    OwnedRoomOrAliasId, OwnedServerName, OwnedUserId,
+};
+use thiserror::Error;
+
+use crate::{room_preview::RoomPreview, Client, Result};
+
+/// An error that can occur when parsing a `matrix.to` or `matrix:` URI.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum UriParseError {
+    /// The URI isn't a valid `matrix.to` or `matrix:` URI.
+    #[error(transparent)]
+    InvalidUri(#[from] IdParseError),
+
+    /// The URI points to a kind of Matrix ID that isn't supported.
+    #[error("unsupported Matrix ID: {0:?}")]
+    UnsupportedId(MatrixId),
+}
+
+/// The target of a `matrix.to` or `matrix:` URI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UriTarget {
+    /// A room, or an event in a room.
+    Room {
+        /// The ID or alias of the room.
+        room: OwnedRoomOrAliasId,
+        /// The ID of the event, if the URI points to an event.
+        event_id: Option<OwnedEventId>,
+        /// The servers that can be used to join the room.
+        via: Vec<OwnedServerName>,
+    },
+
+    /// A user.
+    User(OwnedUserId),
+}
+
+impl UriTarget {
+    /// Parse a `matrix.to` or `matrix:` URI.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use matrix_sdk::uri::UriTarget;
+    ///
+    /// let target = UriTarget::parse("matrix:u/alice:example.org").unwrap();
+    /// assert!(matches!(
+    ///     target,
+    ///     UriTarget::User(user_id) if user_id.as_str() == "@alice:example.org"
+    /// ));
+    /// ```
+    pub fn parse(uri: &str) -> Result<Self, UriParseError> {
+        let (id, via) = if uri.starts_with("matrix:") {
+            let uri = MatrixUri::parse(uri)?;
+            (uri.id().clone(), uri.via().to_owned())
+        } else {
+            let uri = MatrixToUri::parse(uri)?;
+            (uri.id().clone(), uri.via().to_owned())
+        };
+
+        Ok(match id {
+            MatrixId::Room(room_id) => Self::Room { room: room_id.into(), event_id: None, via },
+            MatrixId::RoomAlias(alias) => Self::Room { room: alias.into(), event_id: None, via },
+            MatrixId::Event(room, event_id) => Self::Room { room, event_id: Some(event_id), via },
+            MatrixId::User(user_id) => Self::User(user_id),
+            id => return Err(UriParseError::UnsupportedId(id)),
+        })
+    }
+
+    /// Load the details of the target.
+    ///
+    /// The preview of a room is loaded with [`Client::room_preview()`]. The
+    /// profile of a user is loaded from the homeserver.
+    pub async fn resolve(self, client: &Client) -> Result<ResolvedUri> {
+        Ok(match self {
+            Self::Room { room, event_id, via } => {
+                let preview = client.room_preview(&room, &via).await?;
+                ResolvedUri::Room { preview, event_id, via }
+            }
+            Self::User(user_id) => {
+                let profile = client.get_profile(&user_id).await?;
+                ResolvedUri::User(UserPreview {
+                    user_id,
+                    display_name: profile.displayname,
+                    avatar_url: profile.avatar_url,
+                })
+            }
+        })
+    }
+}
+
+/// The target of a URI, with its details.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ResolvedUri {
+    /// A room, or an event in a room.
+    Room {
+        /// The preview of the room.
+        preview: RoomPreview,
+        /// The ID of the event, if the URI points to an event.
+        event_id: Option<OwnedEventId>,
+        /// The servers that can be used to join the room.
+        via: Vec<OwnedServerName>,
+    },
+
+    /// A user.
+    User(UserPreview),
+}
+
+/// The public profile of a user.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct UserPreview {
+    /// The ID of the user.
+    pub user_id: OwnedUserId,
+
+    /// The display name of the user, if any.
+    pub display_name: Option<String>,
+
+    /// The avatar of the user, if any.
+    pub avatar_url: Option<OwnedMxcUri>,
+}
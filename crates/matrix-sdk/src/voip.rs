@@ -0,0 +1,637 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Signalling of the legacy 1:1 VoIP calls, with the `m.call.*` events.
+//!
+//! A [`CallManager`] keeps track of the calls in the rooms of a client. It
+//! sends the `m.call.invite`, `m.call.answer`, `m.call.candidates` and
+//! `m.call.hangup` events, and updates the state of the [`Call`]s with the
+//! events received in the sync.
+//!
+//! The media isn't handled here: the SDP offer, answer and ICE candidates are
+//! given to and received from the WebRTC stack of the embedder.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use matrix_sdk::{Client, voip::CallManager};
+//! # use url::Url;
+//! # async {
+//! # let homeserver = Url::parse("http://example.com")?;
+//! # let client = Client::new(homeserver).await?;
+//! # fn create_answer(offer: &str) -> String { todo!() }
+//! let manager = CallManager::new(client.clone());
+//! let mut incoming_calls = manager.subscribe_to_incoming_calls();
+//!
+//! while let Ok(call) = incoming_calls.recv().await {
+//!     let answer = create_answer(&call.offer().sdp);
+//!     call.accept(answer).await?;
+//! }
+//! # anyhow::Ok(()) };
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::Duration,
+};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use matrix_sdk_common::{executor::spawn, sleep::sleep};
+use ruma::{
+    events::call::{
+        answer::{CallAnswerEventContent, OriginalSyncCallAnswerEvent},
+        candidates::{CallCandidatesEventContent, Candidate, OriginalSyncCallCandidatesEvent},
+        hangup::{CallHangupEventContent, OriginalSyncCallHangupEvent},
+        invite::{CallInviteEventContent, OriginalSyncCallInviteEvent},
+        SessionDescription,
+    },
+    MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId, OwnedVoipId, UInt, VoipId,
+};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+use crate::{event_handler::EventHandlerHandle, room, Client, Result};
+
+/// How long an invite is valid, if it isn't answered.
+const INVITE_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The type of the session description of an answer.
+const ANSWER_TYPE: &str = "answer";
+
+/// The type of the session description of an offer.
+const OFFER_TYPE: &str = "offer";
+
+/// Keeps track of the 1:1 calls in the rooms of a client.
+///
+/// The event handlers of the manager are removed when it is dropped.
+pub struct CallManager {
+    inner: Arc<CallManagerInner>,
+    handles: Vec<EventHandlerHandle>,
+}
+
+struct CallManagerInner {
+    client: Client,
+    calls: StdMutex<HashMap<OwnedVoipId, Call>>,
+    incoming_calls: broadcast::Sender<Call>,
+}
+
+impl CallManager {
+    /// Create a new `CallManager`, that handles the call events received by
+    /// the client from then on.
+    pub fn new(client: Client) -> Self {
+        let inner = Arc::new(CallManagerInner {
+            client: client.clone(),
+            calls: Default::default(),
+            incoming_calls: broadcast::channel(16).0,
+        });
+
+        let handles = vec![
+            client.add_event_handler({
+                let inner = inner.clone();
+                move |event: OriginalSyncCallInviteEvent, room: room::Room| {
+                    let inner = inner.clone();
+                    async move {
+                        let room::Room::Joined(room) = room else { return };
+                        inner.handle_invite(event, room).await;
+                    }
+                }
+            }),
+            client.add_event_handler({
+                let inner = inner.clone();
+                move |event: OriginalSyncCallAnswerEvent| {
+                    let inner = inner.clone();
+                    async move { inner.handle_answer(event) }
+                }
+            }),
+            client.add_event_handler({
+                let inner = inner.clone();
+                move |event: OriginalSyncCallCandidatesEvent| {
+                    let inner = inner.clone();
+                    async move { inner.handle_candidates(event) }
+                }
+            }),
+            client.add_event_handler({
+                let inner = inner.clone();
+                move |event: OriginalSyncCallHangupEvent| {
+                    let inner = inner.clone();
+                    async move { inner.handle_hangup(event) }
+                }
+            }),
+        ];
+
+        Self { inner, handles }
+    }
+
+    /// Call the other member of the given room, with the given SDP offer.
+    ///
+    /// The invite expires if it isn't answered within a minute.
+    pub async fn place_call(&self, room: &room::Joined, offer_sdp: String) -> Result<Call> {
+        let call_id = VoipId::new();
+        let offer = SessionDescription::new(OFFER_TYPE.to_owned(), offer_sdp);
+        let lifetime = UInt::try_from(INVITE_LIFETIME.as_millis()).unwrap_or(UInt::MAX);
+
+        let content = CallInviteEventContent::version_0(call_id.clone(), lifetime, offer.clone());
+        room.send(content, None).await?;
+
+        let call = Call::new(
+            Arc::downgrade(&self.inner),
+            room.clone(),
+            call_id,
+            CallDirection::Outgoing,
+            None,
+            offer,
+        );
+        self.inner.insert(call.clone(), INVITE_LIFETIME);
+
+        Ok(call)
+    }
+
+    /// Get a receiver for the calls that are received.
+    pub fn subscribe_to_incoming_calls(&self) -> broadcast::Receiver<Call> {
+        self.inner.incoming_calls.subscribe()
+    }
+
+    /// Get the call with the given ID, if it hasn't ended.
+    pub fn get_call(&self, call_id: &VoipId) -> Option<Call> {
+        self.inner.calls.lock().unwrap().get(call_id).cloned()
+    }
+
+    /// Get the calls that haven't ended.
+    pub fn calls(&self) -> Vec<Call> {
+        self.inner.calls.lock().unwrap().values().cloned().collect()
+    }
+}
+
+impl Drop for CallManager {
+    fn drop(&mut self) {
+        for handle in self.handles.drain(..) {
+            self.inner.client.remove_event_handler(handle);
+        }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for CallManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallManager")
+            .field("calls", &self.inner.calls.lock().unwrap().keys())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CallManagerInner {
+    /// Keep track of the given call until it ends, and end it if it is still
+    /// ringing after `lifetime`.
+    fn insert(self: &Arc<Self>, call: Call, lifetime: Duration) {
+        self.calls.lock().unwrap().insert(call.call_id().to_owned(), call.clone());
+
+        let inner = self.clone();
+        spawn(async move {
+            sleep(lifetime).await;
+
+            if matches!(call.state(), CallState::Inviting | CallState::Ringing) {
+                debug!(call_id = ?call.call_id(), "The call invite expired");
+                if call.is_outgoing() {
+                    if let Err(error) = call.send_hangup().await {
+                        warn!(?error, "Couldn't hang up an expired call");
+                    }
+                }
+                inner.end(&call, CallEndReason::InviteTimeout);
+            }
+        });
+    }
+
+    fn get(&self, call_id: &VoipId) -> Option<Call> {
+        self.calls.lock().unwrap().get(call_id).cloned()
+    }
+
+    fn end(&self, call: &Call, reason: CallEndReason) {
+        self.calls.lock().unwrap().remove(call.call_id());
+        call.set_state(CallState::Ended(reason));
+    }
+
+    fn is_own_user(&self, user_id: &OwnedUserId) -> bool {
+        self.client.user_id() == Some(user_id)
+    }
+
+    async fn handle_invite(
+        self: &Arc<Self>,
+        event: OriginalSyncCallInviteEvent,
+        room: room::Joined,
+    ) {
+        // The invites sent by our other devices are for calls we don't handle.
+        if self.is_own_user(&event.sender) || self.get(&event.content.call_id).is_some() {
+            return;
+        }
+
+        let lifetime = Duration::from_millis(event.content.lifetime.into());
+        let Some(remaining) = remaining_lifetime(event.origin_server_ts, lifetime) else {
+            debug!(call_id = ?event.content.call_id, "Ignoring an expired call invite");
+            return;
+        };
+
+        // Glare: both sides called each other at the same time. Both sides keep
+        // the call with the lowest ID, and hang up the other one.
+        let glare = self
+            .calls
+            .lock()
+            .unwrap()
+            .values()
+            .find(|call| {
+                call.is_outgoing()
+                    && call.room_id() == room.room_id()
+                    && call.state() == CallState::Inviting
+            })
+            .cloned();
+        if let Some(outgoing) = glare {
+            if !incoming_call_wins(outgoing.call_id(), &event.content.call_id) {
+                debug!(call_id = ?event.content.call_id, "Ignoring a call invite that lost the glare");
+                return;
+            }
+
+            debug!(call_id = ?outgoing.call_id(), "The outgoing call lost the glare, replacing it");
+            if let Err(error) = outgoing.send_hangup().await {
+                warn!(?error, "Couldn't hang up a replaced call");
+            }
+            self.end(&outgoing, CallEndReason::Replaced);
+        }
+
+        let call = Call::new(
+            Arc::downgrade(self),
+            room,
+            event.content.call_id,
+            CallDirection::Incoming,
+            Some(event.sender),
+            event.content.offer,
+        );
+        self.insert(call.clone(), remaining);
+
+        // There might be no receiver.
+        _ = self.incoming_calls.send(call);
+    }
+
+    fn handle_answer(&self, event: OriginalSyncCallAnswerEvent) {
+        let Some(call) = self.get(&event.content.call_id) else { return };
+
+        match call.state() {
+            CallState::Inviting if !self.is_own_user(&event.sender) => {
+                *call.inner.answer.lock().unwrap() = Some(event.content.answer);
+                *call.inner.remote_user.lock().unwrap() = Some(event.sender);
+                call.set_state(CallState::Connected);
+            }
+            // Another one of our devices answered the call.
+            CallState::Ringing if self.is_own_user(&event.sender) => {
+                self.end(&call, CallEndReason::AnsweredElsewhere);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_candidates(&self, event: OriginalSyncCallCandidatesEvent) {
+        if self.is_own_user(&event.sender) {
+            return;
+        }
+
+        let Some(call) = self.get(&event.content.call_id) else { return };
+        call.inner.remote_candidates.update(|candidates| {
+            candidates.extend(event.content.candidates);
+        });
+    }
+
+    fn handle_hangup(&self, event: OriginalSyncCallHangupEvent) {
+        let Some(call) = self.get(&event.content.call_id) else { return };
+
+        let reason = if self.is_own_user(&event.sender) {
+            // The calls we hang up are removed when the hangup is sent, so this
+            // must come from another device.
+            CallEndReason::AnsweredElsewhere
+        } else {
+            CallEndReason::RemoteHangup
+        };
+        self.end(&call, reason);
+    }
+}
+
+/// Whether the incoming call wins against the outgoing call, when both sides
+/// called each other at the same time.
+fn incoming_call_wins(outgoing_call_id: &VoipId, incoming_call_id: &VoipId) -> bool {
+    incoming_call_id.as_str() < outgoing_call_id.as_str()
+}
+
+/// The time left before an invite sent at `sent_at` expires, if any.
+fn remaining_lifetime(sent_at: MilliSecondsSinceUnixEpoch, lifetime: Duration) -> Option<Duration> {
+    let elapsed =
+        u64::from(MilliSecondsSinceUnixEpoch::now().0).saturating_sub(u64::from(sent_at.0));
+    lifetime.checked_sub(Duration::from_millis(elapsed)).filter(|remaining| !remaining.is_zero())
+}
+
+/// Whether a call was placed or received by the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallDirection {
+    /// The call was placed by the client.
+    Outgoing,
+    /// The call was received by the client.
+    Incoming,
+}
+
+/// The state of a [`Call`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CallState {
+    /// The invite was sent, and the other side didn't answer yet.
+    Inviting,
+    /// The invite was received, and wasn't answered yet.
+    Ringing,
+    /// The call was answered, the media can flow.
+    Connected,
+    /// The call ended.
+    Ended(CallEndReason),
+}
+
+/// Why a [`Call`] ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallEndReason {
+    /// The call was hung up by the client.
+    LocalHangup,
+    /// The call was hung up by the other side.
+    RemoteHangup,
+    /// The invite wasn't answered in time.
+    InviteTimeout,
+    /// The call was answered or hung up by another device of the user.
+    AnsweredElsewhere,
+    /// Both sides called each other at the same time, and this call was
+    /// replaced by the call of the other side.
+    Replaced,
+}
+
+/// A 1:1 call, tracked by a [`CallManager`].
+#[derive(Clone)]
+pub struct Call {
+    inner: Arc<CallInner>,
+}
+
+struct CallInner {
+    /// The manager tracking the call, to stop tracking it when it ends.
+    manager: Weak<CallManagerInner>,
+    room: room::Joined,
+    call_id: OwnedVoipId,
+    direction: CallDirection,
+    remote_user: StdMutex<Option<OwnedUserId>>,
+    offer: SessionDescription,
+    answer: StdMutex<Option<SessionDescription>>,
+    state: SharedObservable<CallState>,
+    remote_candidates: SharedObservable<Vec<Candidate>>,
+}
+
+impl Call {
+    fn new(
+        manager: Weak<CallManagerInner>,
+        room: room::Joined,
+        call_id: OwnedVoipId,
+        direction: CallDirection,
+        remote_user: Option<OwnedUserId>,
+        offer: SessionDescription,
+    ) -> Self {
+        let state = match direction {
+            CallDirection::Outgoing => CallState::Inviting,
+            CallDirection::Incoming => CallState::Ringing,
+        };
+
+        Self {
+            inner: Arc::new(CallInner {
+                manager,
+                room,
+                call_id,
+                direction,
+                remote_user: StdMutex::new(remote_user),
+                offer,
+                answer: Default::default(),
+                state: SharedObservable::new(state),
+                remote_candidates: SharedObservable::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// The ID of the call.
+    pub fn call_id(&self) -> &VoipId {
+        &self.inner.call_id
+    }
+
+    /// The ID of the room of the call.
+    pub fn room_id(&self) -> OwnedRoomId {
+        self.inner.room.room_id().to_owned()
+    }
+
+    /// Whether the call was placed or received by the client.
+    pub fn direction(&self) -> CallDirection {
+        self.inner.direction
+    }
+
+    fn is_outgoing(&self) -> bool {
+        self.inner.direction == CallDirection::Outgoing
+    }
+
+    /// The user on the other side of the call, once it is known.
+    ///
+    /// For an outgoing call, this is the user that answered it.
+    pub fn remote_user(&self) -> Option<OwnedUserId> {
+        self.inner.remote_user.lock().unwrap().clone()
+    }
+
+    /// The SDP offer of the call.
+    pub fn offer(&self) -> &SessionDescription {
+        &self.inner.offer
+    }
+
+    /// The SDP answer of the call, once it was answered.
+    pub fn answer(&self) -> Option<SessionDescription> {
+        self.inner.answer.lock().unwrap().clone()
+    }
+
+    /// The current state of the call.
+    pub fn state(&self) -> CallState {
+        self.inner.state.get()
+    }
+
+    /// Get a subscriber to observe the state of the call.
+    pub fn subscribe_to_state(&self) -> Subscriber<CallState> {
+        self.inner.state.subscribe()
+    }
+
+    /// Get a subscriber to observe the ICE candidates received from the other
+    /// side.
+    pub fn subscribe_to_remote_candidates(&self) -> Subscriber<Vec<Candidate>> {
+        self.inner.remote_candidates.subscribe()
+    }
+
+    /// Accept an incoming call with the given SDP answer.
+    ///
+    /// Does nothing if the call isn't ringing anymore.
+    pub async fn accept(&self, answer_sdp: String) -> Result<()> {
+        if self.state() != CallState::Ringing {
+            return Ok(());
+        }
+
+        let answer = SessionDescription::new(ANSWER_TYPE.to_owned(), answer_sdp);
+        let content = CallAnswerEventContent::version_0(answer.clone(), self.inner.call_id.clone());
+        self.inner.room.send(content, None).await?;
+
+        *self.inner.answer.lock().unwrap() = Some(answer);
+        self.set_state(CallState::Connected);
+
+        Ok(())
+    }
+
+    /// Send our ICE candidates to the other side.
+    pub async fn send_candidates(&self, candidates: Vec<Candidate>) -> Result<()> {
+        let content = CallCandidatesEventContent::version_0(self.inner.call_id.clone(), candidates);
+        self.inner.room.send(content, None).await?;
+
+        Ok(())
+    }
+
+    /// Hang up the call, or reject it if it is ringing.
+    ///
+    /// Does nothing if the call already ended.
+    pub async fn hangup(&self) -> Result<()> {
+        if matches!(self.state(), CallState::Ended(_)) {
+            return Ok(());
+        }
+
+        self.send_hangup().await?;
+
+        // Stop tracking the call, so the remote echo of the hangup is ignored.
+        match self.inner.manager.upgrade() {
+            Some(manager) => manager.end(self, CallEndReason::LocalHangup),
+            None => self.set_state(CallState::Ended(CallEndReason::LocalHangup)),
+        }
+
+        Ok(())
+    }
+
+    async fn send_hangup(&self) -> Result<()> {
+        let content = CallHangupEventContent::version_0(self.inner.call_id.clone());
+        self.inner.room.send(content, None).await?;
+
+        Ok(())
+    }
+
+    fn set_state(&self, state: CallState) {
+        self.inner.state.set(state);
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Call")
+            .field("room_id", &self.inner.room.room_id())
+            .field("call_id", &self.inner.call_id)
+            .field("direction", &self.inner.direction)
+            .field("state", &self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use matrix_sdk_test::{async_test, EventBuilder, JoinedRoomBuilder, TimelineTestEvent};
+    use ruma::{room_id, MilliSecondsSinceUnixEpoch, UInt, VoipId};
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path_regex},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{incoming_call_wins, remaining_lifetime, CallEndReason, CallManager, CallState};
+    use crate::test_utils::logged_in_client;
+
+    #[test]
+    fn glare_resolution() {
+        let lower = <&VoipId>::from("1234");
+        let higher = <&VoipId>::from("5678");
+
+        assert!(incoming_call_wins(higher, lower));
+        assert!(!incoming_call_wins(lower, higher));
+    }
+
+    #[test]
+    fn invite_lifetime() {
+        let lifetime = Duration::from_secs(60);
+
+        let now = MilliSecondsSinceUnixEpoch::now();
+        let remaining = remaining_lifetime(now, lifetime).unwrap();
+        assert!(remaining > Duration::from_secs(50));
+
+        let long_ago = MilliSecondsSinceUnixEpoch(now.0 - UInt::from(120_000_u32));
+        assert_eq!(remaining_lifetime(long_ago, lifetime), None);
+    }
+
+    #[async_test]
+    async fn local_hangup_stops_tracking_the_call() {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let room_id = room_id!("!call:localhost");
+
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/client/r0/rooms/.*/state/m.*room.*encryption.?"))
+            .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+                "errcode": "M_NOT_FOUND",
+                "error": "Event not found.",
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex(r"^/_matrix/client/r0/rooms/.*/send/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$call" })))
+            .mount(&server)
+            .await;
+
+        let mut ev_builder = EventBuilder::default();
+        client
+            .process_sync(
+                ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id)).build_sync_response(),
+            )
+            .await
+            .unwrap();
+        let room = client.get_joined_room(room_id).unwrap();
+
+        let manager = CallManager::new(client.clone());
+        let call = manager.place_call(&room, "offer".to_owned()).await.unwrap();
+        assert_eq!(manager.calls().len(), 1);
+
+        call.hangup().await.unwrap();
+        assert_eq!(call.state(), CallState::Ended(CallEndReason::LocalHangup));
+        assert!(manager.get_call(call.call_id()).is_none());
+
+        // The remote echo of the hangup doesn't change the reason.
+        ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_timeline_event(
+            TimelineTestEvent::Custom(json!({
+                "content": {
+                    "call_id": call.call_id(),
+                    "version": 0,
+                },
+                "event_id": "$hangup",
+                "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+                "sender": "@example:localhost",
+                "type": "m.call.hangup",
+            })),
+        ));
+        client.process_sync(ev_builder.build_sync_response()).await.unwrap();
+        assert_eq!(call.state(), CallState::Ended(CallEndReason::LocalHangup));
+    }
+}
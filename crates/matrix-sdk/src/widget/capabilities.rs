@@ -0,0 +1,258 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The capabilities that a widget can request, as described in [MSC2762].
+//!
+//! [MSC2762]: https://github.com/matrix-org/matrix-spec-proposals/pull/2762
+
+use serde_json::Value as JsonValue;
+
+const SEND_EVENT: &str = "org.matrix.msc2762.send.event";
+const READ_EVENT: &str = "org.matrix.msc2762.receive.event";
+const SEND_STATE: &str = "org.matrix.msc2762.send.state_event";
+const READ_STATE: &str = "org.matrix.msc2762.receive.state_event";
+const REQUIRES_CLIENT: &str = "io.element.requires_client";
+const TURN_SERVERS: &str = "town.robin.msc3846.turn_servers";
+const OPENID: &str = "org.matrix.msc1960.openid";
+
+/// The capabilities of a widget.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The events that the widget can read.
+    pub read: Vec<EventFilter>,
+    /// The events that the widget can send.
+    pub send: Vec<EventFilter>,
+    /// Whether the widget requires the client to stay open, to not be
+    /// displayed as a standalone page.
+    pub requires_client: bool,
+    /// Whether the widget can get the TURN servers of the homeserver.
+    pub turn_servers: bool,
+    /// Whether the widget can ask for an OpenID token, to prove the identity
+    /// of the user to a third party.
+    ///
+    /// The user is asked again with
+    /// [`CapabilitiesProvider::acquire_openid_consent()`] the first time the
+    /// widget asks for a token.
+    ///
+    /// [`CapabilitiesProvider::acquire_openid_consent()`]: super::CapabilitiesProvider::acquire_openid_consent
+    pub openid: bool,
+    /// The other capabilities, that the SDK doesn't handle, as is.
+    pub other: Vec<String>,
+}
+
+impl Capabilities {
+    /// Parse the capabilities from their string representation in the widget
+    /// API.
+    pub fn from_strings<S: AsRef<str>>(capabilities: &[S]) -> Self {
+        let mut parsed = Self::default();
+
+        for capability in capabilities {
+            let capability = capability.as_ref();
+
+            match capability {
+                REQUIRES_CLIENT => parsed.requires_client = true,
+                TURN_SERVERS => parsed.turn_servers = true,
+                OPENID => parsed.openid = true,
+                _ => match EventFilter::parse_capability(capability) {
+                    Some((EventAccess::Read, filter)) => parsed.read.push(filter),
+                    Some((EventAccess::Send, filter)) => parsed.send.push(filter),
+                    None => parsed.other.push(capability.to_owned()),
+                },
+            }
+        }
+
+        parsed
+    }
+
+    /// Get the string representation of the capabilities in the widget API.
+    pub fn to_strings(&self) -> Vec<String> {
+        let mut strings = Vec::new();
+
+        strings.extend(self.read.iter().map(|filter| filter.to_capability(EventAccess::Read)));
+        strings.extend(self.send.iter().map(|filter| filter.to_capability(EventAccess::Send)));
+        if self.requires_client {
+            strings.push(REQUIRES_CLIENT.to_owned());
+        }
+        if self.turn_servers {
+            strings.push(TURN_SERVERS.to_owned());
+        }
+        if self.openid {
+            strings.push(OPENID.to_owned());
+        }
+        strings.extend(self.other.iter().cloned());
+
+        strings
+    }
+
+    /// Whether the widget can read the given event.
+    pub fn can_read(&self, event_type: &str, state_key: Option<&str>, content: &JsonValue) -> bool {
+        self.read.iter().any(|filter| filter.matches(event_type, state_key, content))
+    }
+
+    /// Whether the widget can send the given event.
+    pub fn can_send(&self, event_type: &str, state_key: Option<&str>, content: &JsonValue) -> bool {
+        self.send.iter().any(|filter| filter.matches(event_type, state_key, content))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum EventAccess {
+    Read,
+    Send,
+}
+
+/// A filter on the events that a widget can read or send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventFilter {
+    /// Message-like events of the given type.
+    MessageLike {
+        /// The type of the events.
+        event_type: String,
+        /// The `msgtype` of the events, for `m.room.message` events.
+        msgtype: Option<String>,
+    },
+    /// State events of the given type.
+    State {
+        /// The type of the events.
+        event_type: String,
+        /// The state key of the events, if it is restricted.
+        state_key: Option<String>,
+    },
+}
+
+impl EventFilter {
+    /// Whether the given event matches this filter.
+    pub fn matches(&self, event_type: &str, state_key: Option<&str>, content: &JsonValue) -> bool {
+        match (self, state_key) {
+            (Self::MessageLike { event_type: filter_type, msgtype }, None) => {
+                filter_type == event_type
+                    && msgtype.as_deref().map_or(true, |msgtype| {
+                        content.get("msgtype").and_then(JsonValue::as_str) == Some(msgtype)
+                    })
+            }
+            (Self::State { event_type: filter_type, state_key: filter_key }, Some(state_key)) => {
+                filter_type == event_type
+                    && filter_key.as_deref().map_or(true, |filter_key| filter_key == state_key)
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_capability(capability: &str) -> Option<(EventAccess, Self)> {
+        let (prefix, filter) = capability.split_once(':')?;
+        let (filter, extra) = match filter.split_once('#') {
+            Some((filter, extra)) => (filter, Some(extra.to_owned())),
+            None => (filter, None),
+        };
+        let event_type = filter.to_owned();
+
+        match prefix {
+            SEND_EVENT => {
+                Some((EventAccess::Send, Self::MessageLike { event_type, msgtype: extra }))
+            }
+            READ_EVENT => {
+                Some((EventAccess::Read, Self::MessageLike { event_type, msgtype: extra }))
+            }
+            SEND_STATE => Some((EventAccess::Send, Self::State { event_type, state_key: extra })),
+            READ_STATE => Some((EventAccess::Read, Self::State { event_type, state_key: extra })),
+            _ => None,
+        }
+    }
+
+    fn to_capability(&self, access: EventAccess) -> String {
+        let (prefix, event_type, extra) = match (self, access) {
+            (Self::MessageLike { event_type, msgtype }, EventAccess::Read) => {
+                (READ_EVENT, event_type, msgtype)
+            }
+            (Self::MessageLike { event_type, msgtype }, EventAccess::Send) => {
+                (SEND_EVENT, event_type, msgtype)
+            }
+            (Self::State { event_type, state_key }, EventAccess::Read) => {
+                (READ_STATE, event_type, state_key)
+            }
+            (Self::State { event_type, state_key }, EventAccess::Send) => {
+                (SEND_STATE, event_type, state_key)
+            }
+        };
+
+        match extra {
+            Some(extra) => format!("{prefix}:{event_type}#{extra}"),
+            None => format!("{prefix}:{event_type}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Capabilities, EventFilter};
+
+    #[test]
+    fn capabilities_round_trip() {
+        let strings = [
+            "org.matrix.msc2762.receive.event:m.room.message#m.text",
+            "org.matrix.msc2762.receive.state_event:org.matrix.msc3401.call.member",
+            "org.matrix.msc2762.send.state_event:m.room.member#@alice:localhost",
+            "io.element.requires_client",
+            "org.matrix.msc1960.openid",
+            "org.matrix.msc2762.timeline:!room:localhost",
+        ];
+        let capabilities = Capabilities::from_strings(&strings);
+
+        assert_eq!(
+            capabilities.read,
+            [
+                EventFilter::MessageLike {
+                    event_type: "m.room.message".to_owned(),
+                    msgtype: Some("m.text".to_owned()),
+                },
+                EventFilter::State {
+                    event_type: "org.matrix.msc3401.call.member".to_owned(),
+                    state_key: None,
+                },
+            ]
+        );
+        assert_eq!(capabilities.send.len(), 1);
+        assert!(capabilities.requires_client);
+        assert!(!capabilities.turn_servers);
+        assert!(capabilities.openid);
+        assert_eq!(capabilities.other, ["org.matrix.msc2762.timeline:!room:localhost"]);
+
+        let mut round_trip = capabilities.to_strings();
+        round_trip.sort();
+        let mut strings = strings.to_vec();
+        strings.sort();
+        assert_eq!(round_trip, strings);
+    }
+
+    #[test]
+    fn event_filters() {
+        let capabilities = Capabilities::from_strings(&[
+            "org.matrix.msc2762.receive.event:m.room.message#m.text",
+            "org.matrix.msc2762.receive.state_event:m.room.member#@alice:localhost",
+        ]);
+
+        assert!(capabilities.can_read("m.room.message", None, &json!({ "msgtype": "m.text" })));
+        assert!(!capabilities.can_read("m.room.message", None, &json!({ "msgtype": "m.image" })));
+        assert!(!capabilities.can_read(
+            "m.room.message",
+            Some(""),
+            &json!({ "msgtype": "m.text" })
+        ));
+        assert!(capabilities.can_read("m.room.member", Some("@alice:localhost"), &json!({})));
+        assert!(!capabilities.can_read("m.room.member", Some("@bob:localhost"), &json!({})));
+        assert!(!capabilities.can_send("m.room.message", None, &json!({ "msgtype": "m.text" })));
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The messages of the widget API, exchanged with `postMessage`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+
+/// The actions of the requests sent by the widget.
+pub(super) mod from_widget {
+    pub(in crate::widget) const SUPPORTED_API_VERSIONS: &str = "supported_api_versions";
+    pub(in crate::widget) const CONTENT_LOADED: &str = "content_loaded";
+    pub(in crate::widget) const GET_OPENID: &str = "get_openid";
+    pub(in crate::widget) const SEND_EVENT: &str = "send_event";
+    pub(in crate::widget) const READ_EVENTS: &str = "org.matrix.msc2876.read_events";
+    pub(in crate::widget) const WATCH_TURN_SERVERS: &str = "watch_turn_servers";
+    pub(in crate::widget) const UNWATCH_TURN_SERVERS: &str = "unwatch_turn_servers";
+}
+
+/// The actions of the requests sent to the widget.
+pub(super) mod to_widget {
+    pub(in crate::widget) const CAPABILITIES: &str = "capabilities";
+    pub(in crate::widget) const NOTIFY_CAPABILITIES: &str = "notify_capabilities";
+    pub(in crate::widget) const SEND_EVENT: &str = "send_event";
+    pub(in crate::widget) const UPDATE_TURN_SERVERS: &str = "update_turn_servers";
+}
+
+/// The versions of the widget API supported by the driver.
+pub(super) const SUPPORTED_API_VERSIONS: &[&str] = &[
+    "0.0.1",
+    "0.0.2",
+    "org.matrix.msc1960",
+    "org.matrix.msc2762",
+    "org.matrix.msc2871",
+    "org.matrix.msc2876",
+    "town.robin.msc3846",
+];
+
+/// Which side of the widget API sent a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub(super) enum Api {
+    /// The request was sent by the widget.
+    #[serde(rename = "fromWidget")]
+    FromWidget,
+    /// The request was sent to the widget.
+    #[serde(rename = "toWidget")]
+    ToWidget,
+}
+
+/// A request of the widget API, with its response once it is answered.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Message {
+    pub(super) api: Api,
+    pub(super) widget_id: String,
+    pub(super) request_id: String,
+    pub(super) action: String,
+    #[serde(default)]
+    pub(super) data: JsonValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) response: Option<JsonValue>,
+}
+
+impl Message {
+    /// The response of a request that failed.
+    pub(super) fn error_response(message: impl Into<String>) -> JsonValue {
+        json!({ "error": { "message": message.into() } })
+    }
+}
+
+/// The data of a `send_event` request from the widget.
+#[derive(Debug, Deserialize)]
+pub(super) struct SendEventRequest {
+    #[serde(rename = "type")]
+    pub(super) event_type: String,
+    pub(super) state_key: Option<String>,
+    #[serde(default)]
+    pub(super) content: JsonValue,
+}
+
+/// The data of a `read_events` request from the widget.
+#[derive(Debug, Deserialize)]
+pub(super) struct ReadEventsRequest {
+    #[serde(rename = "type")]
+    pub(super) event_type: String,
+    pub(super) state_key: Option<ReadStateKey>,
+    pub(super) limit: Option<u32>,
+}
+
+/// The state key of a `read_events` request, `true` meaning any state key.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(super) enum ReadStateKey {
+    Any(bool),
+    Key(String),
+}
+
+/// The data of the response of the widget to a `capabilities` request.
+#[derive(Debug, Deserialize)]
+pub(super) struct CapabilitiesResponse {
+    pub(super) capabilities: Vec<String>,
+}
+
+/// The fields of an event that are needed to filter it.
+#[derive(Debug, Deserialize)]
+pub(super) struct EventFields {
+    #[serde(rename = "type")]
+    pub(super) event_type: String,
+    pub(super) state_key: Option<String>,
+    #[serde(default)]
+    pub(super) content: JsonValue,
+}
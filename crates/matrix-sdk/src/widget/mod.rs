@@ -0,0 +1,769 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The client side of the [widget API], to host widgets like Element Call.
+//!
+//! The widget runs in a webview or an iframe controlled by the embedder, and
+//! exchanges JSON messages with the client through `postMessage`. The
+//! embedder forwards these messages to and from a [`WidgetDriver`], which
+//! performs all the Matrix requests on behalf of the widget, within the
+//! capabilities approved by a [`CapabilitiesProvider`].
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use matrix_sdk::{
+//! #     room,
+//! #     widget::{Capabilities, WidgetDriver, WidgetSettings},
+//! # };
+//! # async {
+//! # let room: room::Joined = todo!();
+//! # fn post_message_to_widget(message: String) {}
+//! let settings = WidgetSettings::new("element-call".to_owned(), true);
+//! let (driver, mut handle) = WidgetDriver::new(settings);
+//!
+//! // Approve all the capabilities requested by the widget.
+//! let provider = |capabilities: Capabilities| async move { capabilities };
+//! tokio::spawn(driver.run(room, provider));
+//!
+//! // Forward the messages of the driver to the widget. The messages received
+//! // from the widget are sent to the driver with `handle.send()`.
+//! while let Some(message) = handle.recv().await {
+//!     post_message_to_widget(message);
+//! }
+//! # anyhow::Ok(()) };
+//! ```
+//!
+//! [widget API]: https://github.com/matrix-org/matrix-spec-proposals/pull/2764
+
+use std::{collections::VecDeque, fmt, future::Future};
+
+use async_trait::async_trait;
+use ruma::{
+    api::client::{account::request_openid_token, voip::get_turn_server_info},
+    assign,
+    events::{AnySyncTimelineEvent, StateEventType},
+    serde::Raw,
+    uint, TransactionId, UInt,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value as JsonValue};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, warn};
+
+use self::messages::{
+    from_widget, to_widget, Api, CapabilitiesResponse, EventFields, Message, ReadEventsRequest,
+    ReadStateKey, SendEventRequest, SUPPORTED_API_VERSIONS,
+};
+use crate::{
+    deserialized_responses::RawAnySyncOrStrippedState,
+    room::{self, MessagesOptions},
+    Error, Result,
+};
+
+mod capabilities;
+mod messages;
+
+pub use self::capabilities::{Capabilities, EventFilter};
+
+/// The default number of events returned to a `read_events` request.
+const DEFAULT_READ_EVENTS_LIMIT: UInt = uint!(50);
+
+/// The maximum number of requests sent to the widget that wait for a
+/// response. The oldest ones are forgotten when there are more.
+const MAX_PENDING_REQUESTS: usize = 64;
+
+/// The settings of a widget.
+#[derive(Clone, Debug)]
+pub struct WidgetSettings {
+    widget_id: String,
+    init_after_content_load: bool,
+}
+
+impl WidgetSettings {
+    /// Create the settings of the widget with the given ID.
+    ///
+    /// If `init_after_content_load` is `true`, the capabilities are negotiated
+    /// after the widget sent the `content_loaded` action, otherwise they are
+    /// negotiated as soon as the driver runs.
+    pub fn new(widget_id: String, init_after_content_load: bool) -> Self {
+        Self { widget_id, init_after_content_load }
+    }
+
+    /// The ID of the widget.
+    pub fn widget_id(&self) -> &str {
+        &self.widget_id
+    }
+}
+
+/// Decides which capabilities are granted to a widget.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait CapabilitiesProvider: Send + Sync + 'static {
+    /// Get the capabilities granted to the widget, among the requested ones.
+    ///
+    /// This is typically implemented by asking the user.
+    async fn acquire_capabilities(&self, requested: Capabilities) -> Capabilities;
+
+    /// Whether the widget can get an OpenID token, to prove the identity of
+    /// the user to a third party.
+    ///
+    /// This is only called the first time the widget asks for a token, if it
+    /// was granted the [`Capabilities::openid`] capability. The default
+    /// implementation refuses, so closures used as providers never give a
+    /// token to the widget.
+    async fn acquire_openid_consent(&self) -> bool {
+        false
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl<F, Fut> CapabilitiesProvider for F
+where
+    F: Fn(Capabilities) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Capabilities> + Send,
+{
+    async fn acquire_capabilities(&self, requested: Capabilities) -> Capabilities {
+        self(requested).await
+    }
+}
+
+enum Incoming {
+    /// A message from the widget.
+    Widget(String),
+    /// An event received in the room of the widget.
+    RoomEvent(Raw<AnySyncTimelineEvent>),
+    /// The handle of the driver was dropped.
+    Closed,
+}
+
+/// The handle used by the embedder to exchange messages between a widget and
+/// its [`WidgetDriver`].
+///
+/// The driver stops when the handle is dropped.
+#[derive(Debug)]
+pub struct WidgetDriverHandle {
+    to_driver: UnboundedSender<Incoming>,
+    from_driver: UnboundedReceiver<String>,
+}
+
+impl WidgetDriverHandle {
+    /// Send a message received from the widget to the driver.
+    ///
+    /// Returns `false` if the driver stopped.
+    pub fn send(&self, message: String) -> bool {
+        self.to_driver.send(Incoming::Widget(message)).is_ok()
+    }
+
+    /// Receive the next message to post to the widget.
+    ///
+    /// Returns `None` when the driver stopped.
+    pub async fn recv(&mut self) -> Option<String> {
+        self.from_driver.recv().await
+    }
+}
+
+impl Drop for WidgetDriverHandle {
+    fn drop(&mut self) {
+        // The driver might have stopped already.
+        _ = self.to_driver.send(Incoming::Closed);
+    }
+}
+
+/// The client side of the widget API for a single widget.
+///
+/// See the [module-level documentation](self) for more details.
+#[derive(Debug)]
+pub struct WidgetDriver {
+    settings: WidgetSettings,
+    incoming_tx: UnboundedSender<Incoming>,
+    incoming_rx: UnboundedReceiver<Incoming>,
+    outgoing_tx: UnboundedSender<String>,
+}
+
+impl WidgetDriver {
+    /// Create a new driver for the widget with the given settings, and the
+    /// handle to exchange messages with it.
+    pub fn new(settings: WidgetSettings) -> (Self, WidgetDriverHandle) {
+        let (incoming_tx, incoming_rx) = unbounded_channel();
+        let (outgoing_tx, outgoing_rx) = unbounded_channel();
+
+        let handle =
+            WidgetDriverHandle { to_driver: incoming_tx.clone(), from_driver: outgoing_rx };
+        let driver = Self { settings, incoming_tx, incoming_rx, outgoing_tx };
+
+        (driver, handle)
+    }
+
+    /// Run the driver for a widget in the given room, until its handle is
+    /// dropped.
+    pub async fn run(self, room: room::Joined, capabilities_provider: impl CapabilitiesProvider) {
+        let Self { settings, incoming_tx, mut incoming_rx, outgoing_tx } = self;
+
+        let event_handler_handle = room.add_event_handler({
+            move |event: Raw<AnySyncTimelineEvent>| {
+                let incoming_tx = incoming_tx.clone();
+                async move {
+                    // The driver might have stopped already.
+                    _ = incoming_tx.send(Incoming::RoomEvent(event));
+                }
+            }
+        });
+
+        let mut state = DriverState {
+            room: room.clone(),
+            settings,
+            capabilities_provider,
+            outgoing_tx,
+            capabilities: None,
+            openid_consent: None,
+            pending_requests: VecDeque::new(),
+        };
+
+        if !state.settings.init_after_content_load {
+            state.request_capabilities();
+        }
+
+        while let Some(incoming) = incoming_rx.recv().await {
+            match incoming {
+                Incoming::Widget(message) => state.handle_widget_message(&message).await,
+                Incoming::RoomEvent(event) => state.forward_room_event(event),
+                Incoming::Closed => break,
+            }
+        }
+
+        room.client.remove_event_handler(event_handler_handle);
+    }
+}
+
+struct DriverState<P> {
+    room: room::Joined,
+    settings: WidgetSettings,
+    capabilities_provider: P,
+    outgoing_tx: UnboundedSender<String>,
+    /// The capabilities approved for the widget, once they were negotiated.
+    capabilities: Option<Capabilities>,
+    /// Whether the user agreed to give OpenID tokens to the widget, once they
+    /// were asked.
+    openid_consent: Option<bool>,
+    /// The request ID and action of the requests sent to the widget, waiting
+    /// for a response, from the oldest to the newest.
+    pending_requests: VecDeque<(String, &'static str)>,
+}
+
+impl<P: CapabilitiesProvider> DriverState<P> {
+    fn send_to_widget(&self, message: &Message) {
+        match serde_json::to_string(message) {
+            Ok(message) => {
+                // The handle might have been dropped already.
+                _ = self.outgoing_tx.send(message);
+            }
+            Err(e) => warn!("Failed to serialize a widget API message: {e}"),
+        }
+    }
+
+    /// Send a request to the widget.
+    fn send_request(&mut self, action: &'static str, data: JsonValue) {
+        let request_id = TransactionId::new().to_string();
+        if self.pending_requests.len() >= MAX_PENDING_REQUESTS {
+            if let Some((request_id, action)) = self.pending_requests.pop_front() {
+                debug!(request_id, action, "Forgetting a request the widget didn't answer");
+            }
+        }
+        self.pending_requests.push_back((request_id.clone(), action));
+
+        self.send_to_widget(&Message {
+            api: Api::ToWidget,
+            widget_id: self.settings.widget_id.clone(),
+            request_id,
+            action: action.to_owned(),
+            data,
+            response: None,
+        });
+    }
+
+    fn request_capabilities(&mut self) {
+        self.send_request(to_widget::CAPABILITIES, json!({}));
+    }
+
+    async fn handle_widget_message(&mut self, message: &str) {
+        let message: Message = match serde_json::from_str(message) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Received an invalid widget API message: {e}");
+                return;
+            }
+        };
+
+        if message.widget_id != self.settings.widget_id {
+            warn!(widget_id = message.widget_id, "Received a message for another widget");
+            return;
+        }
+
+        match message.api {
+            Api::FromWidget => self.handle_request(message).await,
+            Api::ToWidget => self.handle_response(message).await,
+        }
+    }
+
+    /// Handle a response of the widget to one of our requests.
+    async fn handle_response(&mut self, message: Message) {
+        let pending = self
+            .pending_requests
+            .iter()
+            .position(|(request_id, _)| *request_id == message.request_id)
+            .and_then(|index| self.pending_requests.remove(index));
+        let Some((_, action)) = pending else {
+            debug!(request_id = message.request_id, "Received a response to an unknown request");
+            return;
+        };
+        let response = message.response.unwrap_or_default();
+
+        if action == to_widget::CAPABILITIES {
+            let requested = match serde_json::from_value::<CapabilitiesResponse>(response) {
+                Ok(response) => Capabilities::from_strings(&response.capabilities),
+                Err(e) => {
+                    warn!("Received an invalid capabilities response: {e}");
+                    Capabilities::default()
+                }
+            };
+
+            let approved = self.capabilities_provider.acquire_capabilities(requested.clone()).await;
+            self.send_request(
+                to_widget::NOTIFY_CAPABILITIES,
+                json!({
+                    "requested": requested.to_strings(),
+                    "approved": approved.to_strings(),
+                }),
+            );
+            self.capabilities = Some(approved);
+        }
+    }
+
+    /// Handle a request of the widget, and send the response.
+    async fn handle_request(&mut self, mut message: Message) {
+        let response = match message.action.as_str() {
+            from_widget::SUPPORTED_API_VERSIONS => {
+                Ok(json!({ "supported_versions": SUPPORTED_API_VERSIONS }))
+            }
+            from_widget::CONTENT_LOADED => {
+                if self.settings.init_after_content_load && self.capabilities.is_none() {
+                    self.request_capabilities();
+                }
+                Ok(json!({}))
+            }
+            from_widget::GET_OPENID => self.get_openid().await,
+            from_widget::SEND_EVENT => self.send_event(parse_data(&message.data)).await,
+            from_widget::READ_EVENTS => self.read_events(parse_data(&message.data)).await,
+            from_widget::WATCH_TURN_SERVERS => self.watch_turn_servers().await,
+            from_widget::UNWATCH_TURN_SERVERS => Ok(json!({})),
+            action => Err(format!("Unknown action {action}")),
+        };
+
+        message.response = Some(response.unwrap_or_else(Message::error_response));
+        self.send_to_widget(&message);
+    }
+
+    fn capabilities(&self) -> Result<&Capabilities, String> {
+        self.capabilities.as_ref().ok_or_else(|| "The capabilities were not negotiated".to_owned())
+    }
+
+    async fn get_openid(&mut self) -> Result<JsonValue, String> {
+        if !self.capabilities()?.openid {
+            return Ok(json!({ "state": "blocked" }));
+        }
+
+        let consent = match self.openid_consent {
+            Some(consent) => consent,
+            None => {
+                let consent = self.capabilities_provider.acquire_openid_consent().await;
+                self.openid_consent = Some(consent);
+                consent
+            }
+        };
+        if !consent {
+            return Ok(json!({ "state": "blocked" }));
+        }
+
+        let client = &self.room.client;
+        let user_id = client.user_id().ok_or_else(|| Error::AuthenticationRequired.to_string())?;
+
+        let request = request_openid_token::v3::Request::new(user_id.to_owned());
+        let response = client.send(request, None).await.map_err(|e| e.to_string())?;
+
+        Ok(json!({
+            "state": "allowed",
+            "access_token": response.access_token,
+            "token_type": response.token_type,
+            "matrix_server_name": response.matrix_server_name,
+            "expires_in": response.expires_in.as_secs(),
+        }))
+    }
+
+    async fn send_event(
+        &self,
+        request: Result<SendEventRequest, String>,
+    ) -> Result<JsonValue, String> {
+        let request = request?;
+        let capabilities = self.capabilities()?;
+
+        if !capabilities.can_send(
+            &request.event_type,
+            request.state_key.as_deref(),
+            &request.content,
+        ) {
+            return Err(format!("Not allowed to send {} events", request.event_type));
+        }
+
+        let event_id = match &request.state_key {
+            Some(state_key) => {
+                self.room
+                    .send_state_event_raw(request.content, &request.event_type, state_key)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .event_id
+            }
+            None => {
+                self.room
+                    .send_raw(request.content, &request.event_type, None)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .event_id
+            }
+        };
+
+        Ok(json!({ "room_id": self.room.room_id(), "event_id": event_id }))
+    }
+
+    async fn read_events(
+        &self,
+        request: Result<ReadEventsRequest, String>,
+    ) -> Result<JsonValue, String> {
+        let request = request?;
+        let capabilities = self.capabilities()?;
+        let limit = request.limit.map_or(DEFAULT_READ_EVENTS_LIMIT, UInt::from);
+
+        let events: Vec<JsonValue> = match request.state_key {
+            Some(state_key) => {
+                let state_key = match state_key {
+                    ReadStateKey::Key(state_key) => Some(state_key),
+                    ReadStateKey::Any(true) => None,
+                    ReadStateKey::Any(false) => {
+                        return Err("Invalid state_key".to_owned());
+                    }
+                };
+
+                self.room
+                    .get_state_events(StateEventType::from(request.event_type.as_str()))
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .into_iter()
+                    .filter_map(|event| match event {
+                        RawAnySyncOrStrippedState::Sync(event) => event.deserialize_as().ok(),
+                        RawAnySyncOrStrippedState::Stripped(_) => None,
+                    })
+                    .filter(|event: &JsonValue| {
+                        let key = event.get("state_key").and_then(JsonValue::as_str);
+                        state_key.is_none() || key == state_key.as_deref()
+                    })
+                    .collect()
+            }
+            None => {
+                let options = assign!(MessagesOptions::backward(), { limit });
+                self.room
+                    .messages(options)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .chunk
+                    .into_iter()
+                    .filter_map(|event| event.event.deserialize_as().ok())
+                    .filter(|event: &JsonValue| {
+                        event.get("type").and_then(JsonValue::as_str) == Some(&request.event_type)
+                    })
+                    .collect()
+            }
+        };
+
+        // Only return the events the widget is allowed to read.
+        let events: Vec<_> = events
+            .into_iter()
+            .filter(|event| {
+                serde_json::from_value::<EventFields>(event.clone()).is_ok_and(|fields| {
+                    capabilities.can_read(
+                        &fields.event_type,
+                        fields.state_key.as_deref(),
+                        &fields.content,
+                    )
+                })
+            })
+            .map(|mut event| {
+                event["room_id"] = json!(self.room.room_id());
+                event
+            })
+            .collect();
+
+        Ok(json!({ "events": events }))
+    }
+
+    async fn watch_turn_servers(&mut self) -> Result<JsonValue, String> {
+        if !self.capabilities()?.turn_servers {
+            return Err("Not allowed to get the TURN servers".to_owned());
+        }
+
+        let request = get_turn_server_info::v3::Request::new();
+        let response = self.room.client.send(request, None).await.map_err(|e| e.to_string())?;
+
+        self.send_request(
+            to_widget::UPDATE_TURN_SERVERS,
+            json!({
+                "uris": response.uris,
+                "username": response.username,
+                "password": response.password,
+            }),
+        );
+
+        Ok(json!({}))
+    }
+
+    /// Forward an event received in the room to the widget, if it is allowed
+    /// to read it.
+    fn forward_room_event(&mut self, event: Raw<AnySyncTimelineEvent>) {
+        let Some(capabilities) = &self.capabilities else { return };
+        let Ok(fields) = event.deserialize_as::<EventFields>() else { return };
+
+        if !capabilities.can_read(&fields.event_type, fields.state_key.as_deref(), &fields.content)
+        {
+            return;
+        }
+
+        let Ok(mut event) = event.deserialize_as::<JsonValue>() else { return };
+        event["room_id"] = json!(self.room.room_id());
+        self.send_request(to_widget::SEND_EVENT, event);
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<P> fmt::Debug for DriverState<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DriverState")
+            .field("room_id", &self.room.room_id())
+            .field("settings", &self.settings)
+            .field("capabilities", &self.capabilities)
+            .finish_non_exhaustive()
+    }
+}
+
+fn parse_data<T: DeserializeOwned>(data: &JsonValue) -> Result<T, String> {
+    serde_json::from_value(data.clone()).map_err(|e| format!("Invalid request data: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use async_trait::async_trait;
+    use matrix_sdk_common::executor::spawn;
+    use matrix_sdk_test::{async_test, EventBuilder, JoinedRoomBuilder};
+    use ruma::room_id;
+    use serde_json::{json, Value as JsonValue};
+    use tokio::sync::mpsc::unbounded_channel;
+    use wiremock::{
+        matchers::{method, path_regex},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{
+        Capabilities, CapabilitiesProvider, DriverState, WidgetDriver, WidgetDriverHandle,
+        WidgetSettings, MAX_PENDING_REQUESTS,
+    };
+    use crate::{room, test_utils::logged_in_client};
+
+    const WIDGET_ID: &str = "test-widget";
+
+    /// Approves all the capabilities, and answers the OpenID consent with
+    /// `consent`.
+    struct Provider {
+        consent: bool,
+        consent_requests: Arc<AtomicUsize>,
+    }
+
+    #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+    #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+    impl CapabilitiesProvider for Provider {
+        async fn acquire_capabilities(&self, requested: Capabilities) -> Capabilities {
+            requested
+        }
+
+        async fn acquire_openid_consent(&self) -> bool {
+            self.consent_requests.fetch_add(1, Ordering::SeqCst);
+            self.consent
+        }
+    }
+
+    async fn joined_room(server: &MockServer) -> room::Joined {
+        let client = logged_in_client(Some(server.uri())).await;
+        let room_id = room_id!("!widget:localhost");
+
+        let response = EventBuilder::default()
+            .add_joined_room(JoinedRoomBuilder::new(room_id))
+            .build_sync_response();
+        client.process_sync(response).await.unwrap();
+
+        client.get_joined_room(room_id).unwrap()
+    }
+
+    /// Start a driver, and grant the given capabilities to the widget.
+    async fn start_driver(
+        room: room::Joined,
+        capabilities: &[&str],
+        provider: Provider,
+    ) -> WidgetDriverHandle {
+        let (driver, mut handle) =
+            WidgetDriver::new(WidgetSettings::new(WIDGET_ID.to_owned(), false));
+        spawn(driver.run(room, provider));
+
+        let mut request = recv(&mut handle).await;
+        assert_eq!(request["action"], "capabilities");
+        request["response"] = json!({ "capabilities": capabilities });
+        assert!(handle.send(request.to_string()));
+
+        assert_eq!(recv(&mut handle).await["action"], "notify_capabilities");
+
+        handle
+    }
+
+    async fn recv(handle: &mut WidgetDriverHandle) -> JsonValue {
+        serde_json::from_str(&handle.recv().await.unwrap()).unwrap()
+    }
+
+    /// Send a request from the widget, and get the response of the driver.
+    async fn request(handle: &mut WidgetDriverHandle, action: &str) -> JsonValue {
+        let request = json!({
+            "api": "fromWidget",
+            "widgetId": WIDGET_ID,
+            "requestId": "request",
+            "action": action,
+            "data": {},
+        });
+        assert!(handle.send(request.to_string()));
+
+        let mut response = recv(handle).await;
+        assert_eq!(response["action"], action);
+        response["response"].take()
+    }
+
+    async fn mock_openid(server: &MockServer, expected_requests: u64) {
+        Mock::given(method("POST"))
+            .and(path_regex(r"^/_matrix/client/r0/user/.*/openid/request_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "SomeT0kenHere",
+                "token_type": "Bearer",
+                "matrix_server_name": "localhost",
+                "expires_in": 3600,
+            })))
+            .expect(expected_requests)
+            .mount(server)
+            .await;
+    }
+
+    #[async_test]
+    async fn openid_requires_the_capability() {
+        let server = MockServer::start().await;
+        mock_openid(&server, 0).await;
+        let room = joined_room(&server).await;
+
+        let consent_requests = Arc::new(AtomicUsize::new(0));
+        let provider = Provider { consent: true, consent_requests: consent_requests.clone() };
+        let mut handle = start_driver(room, &[], provider).await;
+
+        let response = request(&mut handle, "get_openid").await;
+        assert_eq!(response, json!({ "state": "blocked" }));
+        assert_eq!(consent_requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[async_test]
+    async fn openid_requires_consent() {
+        let server = MockServer::start().await;
+        mock_openid(&server, 0).await;
+        let room = joined_room(&server).await;
+
+        let consent_requests = Arc::new(AtomicUsize::new(0));
+        let provider = Provider { consent: false, consent_requests: consent_requests.clone() };
+        let mut handle = start_driver(room, &["org.matrix.msc1960.openid"], provider).await;
+
+        let response = request(&mut handle, "get_openid").await;
+        assert_eq!(response, json!({ "state": "blocked" }));
+        let response = request(&mut handle, "get_openid").await;
+        assert_eq!(response, json!({ "state": "blocked" }));
+
+        // The user is only asked once.
+        assert_eq!(consent_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_test]
+    async fn openid_with_consent() {
+        let server = MockServer::start().await;
+        mock_openid(&server, 2).await;
+        let room = joined_room(&server).await;
+
+        let consent_requests = Arc::new(AtomicUsize::new(0));
+        let provider = Provider { consent: true, consent_requests: consent_requests.clone() };
+        let mut handle = start_driver(room, &["org.matrix.msc1960.openid"], provider).await;
+
+        let response = request(&mut handle, "get_openid").await;
+        assert_eq!(response["state"], "allowed");
+        assert_eq!(response["access_token"], "SomeT0kenHere");
+        assert_eq!(response["matrix_server_name"], "localhost");
+
+        let response = request(&mut handle, "get_openid").await;
+        assert_eq!(response["state"], "allowed");
+        assert_eq!(consent_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_test]
+    async fn pending_requests_are_bounded() {
+        let server = MockServer::start().await;
+        let room = joined_room(&server).await;
+
+        let (outgoing_tx, _outgoing_rx) = unbounded_channel();
+        let provider = Provider { consent: false, consent_requests: Default::default() };
+        let mut state = DriverState {
+            room,
+            settings: WidgetSettings::new(WIDGET_ID.to_owned(), false),
+            capabilities_provider: provider,
+            outgoing_tx,
+            capabilities: None,
+            openid_consent: None,
+            pending_requests: Default::default(),
+        };
+
+        state.request_capabilities();
+        let (first_request_id, _) = state.pending_requests[0].clone();
+
+        for _ in 0..MAX_PENDING_REQUESTS {
+            state.send_request("update_turn_servers", json!({}));
+        }
+
+        // The oldest request was forgotten.
+        assert_eq!(state.pending_requests.len(), MAX_PENDING_REQUESTS);
+        assert!(state
+            .pending_requests
+            .iter()
+            .all(|(request_id, _)| *request_id != first_request_id));
+    }
+}
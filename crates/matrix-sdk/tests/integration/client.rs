@@ -1,39 +1,67 @@
-use std::{collections::BTreeMap, str::FromStr, time::Duration};
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
 
 use assert_matches::assert_matches;
-use futures_util::FutureExt;
+use async_trait::async_trait;
+use bytes::Bytes;
+use eyeball_im::VectorDiff;
+use futures_util::{pin_mut, FutureExt, StreamExt};
+use http::HeaderValue;
 use matrix_sdk::{
     config::SyncSettings,
+    encryption::SessionImportError,
     media::{MediaFormat, MediaRequest, MediaThumbnailSize},
+    room,
     sync::RoomUpdate,
-    RumaApiError, Session,
+    uiaa::UiaaFlow,
+    uri::{ResolvedUri, UriParseError},
+    DeactivateOptions, EndpointClass, HttpMiddleware, NetworkStatus, RumaApiError, Session,
+};
+use matrix_sdk_test::{
+    async_test, test_json, EventBuilder, GlobalAccountDataTestEvent, InvitedRoomBuilder,
+    JoinedRoomBuilder, StateTestEvent, StrippedStateTestEvent, TimelineTestEvent,
 };
-use matrix_sdk_test::{async_test, test_json};
 use ruma::{
-    api::client::{
-        self as client_api,
-        account::register::{v3::Request as RegistrationRequest, RegistrationKind},
-        directory::{
-            get_public_rooms,
-            get_public_rooms_filtered::{self, v3::Request as PublicRoomsFilterRequest},
+    api::{
+        client::{
+            self as client_api,
+            account::register::{v3::Request as RegistrationRequest, RegistrationKind},
+            directory::{
+                get_public_rooms,
+                get_public_rooms_filtered::{self, v3::Request as PublicRoomsFilterRequest},
+            },
+            media::get_content_thumbnail::v3::Method,
+            session::get_login_types::v3::LoginType,
+            uiaa,
         },
-        media::get_content_thumbnail::v3::Method,
-        session::get_login_types::v3::LoginType,
-        uiaa,
+        MatrixVersion,
     },
     assign, device_id,
     directory::Filter,
-    events::room::{message::ImageMessageEventContent, ImageInfo, MediaSource},
-    mxc_uri, room_id, uint, user_id,
+    events::room::{
+        history_visibility::HistoryVisibility, message::ImageMessageEventContent, ImageInfo,
+        MediaSource,
+    },
+    mxc_uri, room_id, server_name,
+    space::SpaceRoomJoinRule,
+    uint, user_id, ClientSecret, MilliSecondsSinceUnixEpoch, RoomId, RoomVersionId, SessionId,
 };
 use serde_json::{from_value as from_json_value, json, to_value as to_json_value};
+use tokio::{
+    spawn,
+    time::{sleep, timeout},
+};
 use url::Url;
 use wiremock::{
-    matchers::{header, method, path, path_regex},
+    matchers::{body_json, body_partial_json, header, method, path, path_regex, query_param},
     Mock, ResponseTemplate,
 };
 
-use crate::{logged_in_client, mock_sync, no_retry_test_client};
+use crate::{logged_in_client, mock_sync, no_retry_test_client, test_client_builder};
 
 #[async_test]
 async fn login() {
@@ -276,6 +304,326 @@ async fn devices() {
     client.devices().await.unwrap();
 }
 
+#[async_test]
+async fn change_password_with_password_auth() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/account/password"))
+        .and(body_partial_json(json!({
+            "new_password": "new password",
+            "auth": {
+                "type": "m.login.password",
+                "identifier": { "type": "m.id.user", "user": "@example:localhost" },
+                "password": "current password",
+                "session": "vBslorikviAjxzYBASOBGfPp",
+            },
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/account/password"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "flows": [
+                {
+                    "stages": [
+                        "m.login.password"
+                    ]
+                }
+            ],
+            "params": {},
+            "session": "vBslorikviAjxzYBASOBGfPp"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let account = client.account();
+    account
+        .with_password_auth("current password", |auth_data| {
+            account.change_password("new password", auth_data)
+        })
+        .await
+        .unwrap();
+}
+
+#[async_test]
+async fn password_auth_with_other_stages() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/account/deactivate"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "flows": [
+                {
+                    "stages": [
+                        "m.login.password",
+                        "m.login.email.identity"
+                    ]
+                }
+            ],
+            "params": {},
+            "session": "vBslorikviAjxzYBASOBGfPp"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let account = client.account();
+    let error = account
+        .with_password_auth("password", |auth_data| account.deactivate(None, auth_data))
+        .await
+        .unwrap_err();
+    assert_eq!(error.as_uiaa_response().unwrap().flows[0].stages.len(), 2);
+}
+
+#[async_test]
+async fn deactivate_and_erase() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/account/deactivate"))
+        .and(body_partial_json(json!({ "erase": true })))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "id_server_unbind_result": "no-support" })),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let options = assign!(DeactivateOptions::new(), { erase: true });
+    client.account().deactivate_with_options(None, options).await.unwrap();
+}
+
+#[async_test]
+async fn submit_3pid_token() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("POST"))
+        .and(path("/submit_token"))
+        .and(body_partial_json(json!({
+            "client_secret": "secret",
+            "sid": "sid",
+            "token": "123456",
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "success": true })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client_secret = ClientSecret::parse("secret").unwrap();
+    let sid = SessionId::parse("sid").unwrap();
+    let success = client
+        .account()
+        .submit_3pid_token(
+            &format!("{}/submit_token", server.uri()),
+            &client_secret,
+            &sid,
+            "123456",
+        )
+        .await
+        .unwrap();
+    assert!(success);
+}
+
+#[async_test]
+async fn submit_3pid_token_error() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("POST"))
+        .and(path("/submit_token"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "errcode": "M_THREEPID_AUTH_FAILED",
+            "error": "Invalid token",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client_secret = ClientSecret::parse("secret").unwrap();
+    let sid = SessionId::parse("sid").unwrap();
+    let error = client
+        .account()
+        .submit_3pid_token(
+            &format!("{}/submit_token", server.uri()),
+            &client_secret,
+            &sid,
+            "123456",
+        )
+        .await
+        .unwrap_err();
+    assert_matches!(
+        error.client_api_error_kind(),
+        Some(client_api::error::ErrorKind::ThreepidAuthFailed)
+    );
+}
+
+#[async_test]
+async fn device_list() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/devices"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "devices": [
+                {
+                    "device_id": "BNYQQWUMXO",
+                    "display_name": "Client 1",
+                    "last_seen_ts": 1596117733037u64,
+                },
+                {
+                    "device_id": "DEVICEID",
+                    "last_seen_ts": 1596117733000u64,
+                },
+                {
+                    "device_id": "LEBKSEUSNR",
+                    "display_name": "Client 2",
+                    "last_seen_ip": "-",
+                    "last_seen_ts": 1599057006985u64,
+                },
+            ]
+        })))
+        .mount(&server)
+        .await;
+
+    Mock::given(method("PUT"))
+        .and(path("/_matrix/client/r0/devices/LEBKSEUSNR"))
+        .and(body_partial_json(json!({ "display_name": "Phone" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let list = client.device_list().await.unwrap();
+    let (devices, stream) = list.subscribe();
+    pin_mut!(stream);
+
+    // The current device is first, then the most recently seen.
+    let names: Vec<_> = devices.iter().map(|device| device.name()).collect();
+    assert_eq!(names, ["DEVICEID", "Client 2", "Client 1"]);
+    assert!(devices[0].is_current);
+    assert!(!devices[1].is_current);
+    assert_eq!(devices[1].last_seen_ip.as_deref(), Some("-"));
+
+    // Renaming a device reloads the list.
+    list.rename(device_id!("LEBKSEUSNR"), "Phone").await.unwrap();
+    assert_matches!(stream.next().await, Some(VectorDiff::Clear));
+    assert_matches!(stream.next().await, Some(VectorDiff::Append { values }) => {
+        assert_eq!(values.len(), 3);
+    });
+}
+
+#[async_test]
+async fn export_and_import_session() {
+    let (client, _server) = logged_in_client().await;
+    let export = client.export_session("passphrase").await.unwrap();
+
+    let (builder, _new_server) = test_client_builder().await;
+    let new_client = builder.build().await.unwrap();
+
+    assert_matches!(
+        new_client.import_session(&export, "wrong passphrase").await,
+        Err(SessionImportError::Export(_))
+    );
+    assert!(!new_client.logged_in());
+
+    new_client.import_session(&export, "passphrase").await.unwrap();
+    assert_eq!(new_client.session(), client.session());
+    assert_eq!(new_client.homeserver().await, client.homeserver().await);
+    assert_eq!(
+        new_client.encryption().ed25519_key().await,
+        client.encryption().ed25519_key().await
+    );
+
+    assert_matches!(
+        new_client.import_session(&export, "passphrase").await,
+        Err(SessionImportError::AlreadyLoggedIn)
+    );
+}
+
+#[async_test]
+async fn uiaa_flow_delete_devices() {
+    let (client, server) = logged_in_client().await;
+
+    let flows = json!([
+        {
+            "stages": [
+                "m.login.password",
+                "m.login.dummy"
+            ]
+        },
+        {
+            "stages": [
+                "m.login.sso"
+            ]
+        }
+    ]);
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/delete_devices"))
+        .and(body_partial_json(json!({
+            "auth": { "type": "m.login.dummy", "session": "vBslorikviAjxzYBASOBGfPp" },
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/delete_devices"))
+        .and(body_partial_json(json!({
+            "auth": {
+                "type": "m.login.password",
+                "password": "wordpass",
+                "session": "vBslorikviAjxzYBASOBGfPp",
+            },
+        })))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "flows": flows,
+            "completed": ["m.login.password"],
+            "params": {},
+            "session": "vBslorikviAjxzYBASOBGfPp"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/delete_devices"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "flows": flows,
+            "params": {},
+            "session": "vBslorikviAjxzYBASOBGfPp"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let devices = &[device_id!("DEVICEID").to_owned()];
+    let mut flow = UiaaFlow::new(&client, |auth_data| client.delete_devices(devices, auth_data));
+
+    assert!(flow.send().await.unwrap().is_none());
+    assert_eq!(flow.next_stages(), [uiaa::AuthType::Password, uiaa::AuthType::Sso]);
+    let fallback_url = flow.fallback_url(&uiaa::AuthType::Sso).await.unwrap();
+    assert_eq!(
+        fallback_url.as_str(),
+        format!(
+            "{}/_matrix/client/v3/auth/m.login.sso/fallback/web?session=vBslorikviAjxzYBASOBGfPp",
+            server.uri()
+        )
+    );
+
+    assert!(flow.complete_password("wordpass").await.unwrap().is_none());
+    assert_eq!(flow.remaining_stages(), [vec![uiaa::AuthType::Dummy]]);
+
+    assert!(flow.complete_dummy().await.unwrap().is_some());
+    assert!(flow.info().is_none());
+}
+
 #[async_test]
 async fn delete_devices() {
     let (client, server) = no_retry_test_client().await;
@@ -349,11 +697,36 @@ async fn resolve_room_alias() {
     Mock::given(method("GET"))
         .and(path("/_matrix/client/r0/directory/room/%23alias:example.org"))
         .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::GET_ALIAS))
+        .expect(1)
         .mount(&server)
         .await;
 
     let alias = ruma::room_alias_id!("#alias:example.org");
     client.resolve_room_alias(alias).await.unwrap();
+
+    // The alias is cached.
+    client.resolve_room_alias(alias).await.unwrap();
+}
+
+#[async_test]
+async fn room_alias_cache_is_bounded() {
+    let (client, server) = no_retry_test_client().await;
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/directory/room/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::GET_ALIAS))
+        .expect(102)
+        .mount(&server)
+        .await;
+
+    for i in 0..101 {
+        let alias = ruma::RoomAliasId::parse(format!("#alias{i}:example.org")).unwrap();
+        client.resolve_room_alias(&alias).await.unwrap();
+    }
+
+    // The first alias was evicted to make room for the last one.
+    client.resolve_room_alias(ruma::room_alias_id!("#alias100:example.org")).await.unwrap();
+    client.resolve_room_alias(ruma::room_alias_id!("#alias0:example.org")).await.unwrap();
 }
 
 #[async_test]
@@ -406,6 +779,71 @@ async fn join_room_by_id() {
     );
 }
 
+#[async_test]
+async fn peek_room() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!public:example.org");
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/state$"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+            {
+                "content": { "name": "Public archive" },
+                "event_id": "$name:example.org",
+                "origin_server_ts": 1432135524678u64,
+                "room_id": room_id,
+                "sender": "@example:example.org",
+                "state_key": "",
+                "type": "m.room.name",
+            },
+            {
+                "content": { "history_visibility": "world_readable" },
+                "event_id": "$visibility:example.org",
+                "origin_server_ts": 1432135524678u64,
+                "room_id": room_id,
+                "sender": "@example:example.org",
+                "state_key": "",
+                "type": "m.room.history_visibility",
+            },
+        ])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let room = client.peek_room(room_id).await.unwrap();
+    assert_matches!(room, room::Room::Left(_));
+    assert_eq!(room.name().as_deref(), Some("Public archive"));
+    assert_eq!(room.history_visibility(), HistoryVisibility::WorldReadable);
+
+    // The peeked room isn't one of the rooms of the client.
+    assert!(client.get_room(room_id).is_none());
+}
+
+#[async_test]
+async fn register_guest() {
+    let (client, server) = no_retry_test_client().await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/register"))
+        .and(query_param("kind", "guest"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "user_id": "@guest:example.org",
+            "access_token": "guest_token",
+            "device_id": "GUESTDEVICE",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client.register_guest().await.unwrap();
+
+    let session = client.session().unwrap();
+    assert_eq!(session.user_id, "@guest:example.org");
+    assert_eq!(session.access_token, "guest_token");
+    assert_eq!(session.device_id, "GUESTDEVICE");
+}
+
 #[async_test]
 async fn join_room_by_id_or_alias() {
     let (client, server) = logged_in_client().await;
@@ -431,6 +869,84 @@ async fn join_room_by_id_or_alias() {
     );
 }
 
+#[async_test]
+async fn dm_with() {
+    let (client, server) = logged_in_client().await;
+    let user_id = user_id!("@bob:example.org");
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/createRoom"))
+        .and(body_partial_json(json!({
+            "invite": ["@bob:example.org"],
+            "is_direct": true,
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::ROOM_ID))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/user/.*/account_data/m.direct"))
+        .and(body_json(json!({
+            "@bob:example.org": ["!testroom:example.org"],
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EMPTY))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let room = client.dm_with(user_id).await.unwrap();
+    assert_eq!(room.room_id(), room_id!("!testroom:example.org"));
+
+    // The new DM is found without waiting for the next sync.
+    let room = client.dm_with(user_id).await.unwrap();
+    assert_eq!(room.room_id(), room_id!("!testroom:example.org"));
+}
+
+#[async_test]
+async fn dm_with_ignores_left_dms() {
+    let (client, server) = logged_in_client().await;
+    let user_id = user_id!("@bob:example.org");
+    let left_room_id = room_id!("!left:example.org");
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder
+        .add_global_account_data_event(GlobalAccountDataTestEvent::Custom(json!({
+            "content": { "@bob:example.org": [left_room_id] },
+            "type": "m.direct",
+        })))
+        .add_joined_room(JoinedRoomBuilder::new(left_room_id).add_state_event(
+            StateTestEvent::Custom(json!({
+                "content": { "membership": "leave" },
+                "event_id": "$leave:example.org",
+                "origin_server_ts": 1432135524678u64,
+                "sender": user_id,
+                "state_key": user_id,
+                "type": "m.room.member",
+            })),
+        ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    client.sync_once(SyncSettings::new()).await.unwrap();
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/createRoom"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::ROOM_ID))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/user/.*/account_data/m.direct"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EMPTY))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // Bob left the DM, so a new one is created.
+    let room = client.dm_with(user_id).await.unwrap();
+    assert_eq!(room.room_id(), room_id!("!testroom:example.org"));
+}
+
 #[async_test]
 async fn room_search_all() {
     let (client, server) = no_retry_test_client().await;
@@ -575,6 +1091,100 @@ async fn whoami() {
     assert_eq!(client.whoami().await.unwrap().user_id, user_id);
 }
 
+#[async_test]
+async fn rate_limited_requests() {
+    let (client, server) = logged_in_client().await;
+    let mut rate_limits = client.subscribe_to_rate_limits();
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/account/whoami"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1").set_body_json(
+            json!({
+                "errcode": "M_LIMIT_EXCEEDED",
+                "error": "Too many requests",
+                "retry_after_ms": 10,
+            }),
+        ))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/account/whoami"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::WHOAMI))
+        .mount(&server)
+        .await;
+
+    // The retries are disabled, so the error is returned.
+    client.whoami().await.unwrap_err();
+
+    // The `Retry-After` header takes precedence over the body.
+    let rate_limited = rate_limits.try_recv().unwrap();
+    assert_eq!(rate_limited.class, EndpointClass::Other);
+    assert_eq!(rate_limited.retry_after, Duration::from_secs(1));
+    assert!(client.rate_limit_delay(EndpointClass::Other).is_some());
+    assert!(client.rate_limit_delay(EndpointClass::Sync).is_none());
+
+    // The next request of the same class waits for the limit to be lifted.
+    let start = Instant::now();
+    client.whoami().await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert!(client.rate_limit_delay(EndpointClass::Other).is_none());
+}
+
+#[async_test]
+async fn offline_requests() {
+    let (client, server) = logged_in_client().await;
+    let mut network_status = client.subscribe_to_network_status();
+
+    // The first request never gets a response, like a sync request when the
+    // connection is lost.
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/account/whoami"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(&*test_json::WHOAMI)
+                .set_delay(Duration::from_secs(60)),
+        )
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/account/whoami"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::WHOAMI))
+        .mount(&server)
+        .await;
+
+    let request = spawn({
+        let client = client.clone();
+        async move { client.whoami().await }
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    client.set_network_status(NetworkStatus::Offline);
+    assert_eq!(network_status.next().await, Some(NetworkStatus::Offline));
+    assert_eq!(client.network_status(), NetworkStatus::Offline);
+
+    // The requests wait until the device is online.
+    let second_request = spawn({
+        let client = client.clone();
+        async move { client.whoami().await }
+    });
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+
+    // The pending request is sent again once the device is online.
+    client.set_network_status(NetworkStatus::Online);
+    assert_eq!(network_status.next().await, Some(NetworkStatus::Online));
+
+    let user_id = user_id!("@joe:example.org");
+    let response = timeout(Duration::from_secs(5), request).await.unwrap().unwrap();
+    assert_eq!(response.unwrap().user_id, user_id);
+    let response = timeout(Duration::from_secs(5), second_request).await.unwrap().unwrap();
+    assert_eq!(response.unwrap().user_id, user_id);
+}
+
 #[test]
 fn deserialize_session() {
     // First version, or second version without refresh token.
@@ -658,3 +1268,630 @@ async fn room_update_channel() {
     assert_eq!(updates.unread_notifications.highlight_count, 0);
     assert_eq!(updates.unread_notifications.notification_count, 11);
 }
+
+#[async_test]
+async fn server_capabilities() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "versions": ["r0.6.1", "v1.1"],
+            "unstable_features": {
+                "org.matrix.msc3575": true,
+                "org.matrix.msc2716": false,
+            },
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/capabilities"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "capabilities": {
+                "m.change_password": { "enabled": false },
+                "m.room_versions": {
+                    "default": "9",
+                    "available": { "9": "stable", "10": "stable" },
+                },
+            },
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let capabilities = client.server_capabilities().await.unwrap();
+    assert!(capabilities.supports_version(MatrixVersion::V1_1));
+    assert!(!capabilities.supports_version(MatrixVersion::V1_2));
+    assert!(capabilities.supports_unstable_feature("org.matrix.msc3575"));
+    assert!(!capabilities.supports_unstable_feature("org.matrix.msc2716"));
+    assert!(!capabilities.supports_unstable_feature("org.matrix.msc3916"));
+    assert_eq!(capabilities.default_room_version(), &RoomVersionId::V9);
+    assert!(!capabilities.can_change_password());
+
+    // The second call uses the cache.
+    client.server_capabilities().await.unwrap();
+
+    // Refreshing fetches everything again.
+    client.refresh_server_capabilities().await.unwrap();
+}
+
+#[async_test]
+async fn contacts() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!test_room:localhost");
+
+    let member_event = |user_id: &str, name: &str| {
+        json!({
+            "content": {
+                "displayname": name,
+                "membership": "join",
+            },
+            "event_id": format!("${name}_join"),
+            "origin_server_ts": 151800140,
+            "sender": user_id,
+            "state_key": user_id,
+            "type": "m.room.member",
+        })
+    };
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(
+        JoinedRoomBuilder::new(room_id)
+            .add_state_event(StateTestEvent::Custom(member_event("@example:localhost", "example")))
+            .add_state_event(StateTestEvent::Custom(member_event("@bob:localhost", "Bob")))
+            .add_state_event(StateTestEvent::Custom(member_event("@alice:localhost", "Alice"))),
+    );
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let sync_token = client.sync_once(SyncSettings::new()).await.unwrap().next_batch;
+
+    // Our own user isn't a contact.
+    let contacts = client.contacts().get().await.unwrap();
+    let names: Vec<_> = contacts.iter().map(|contact| contact.name()).collect();
+    assert_eq!(names, ["Alice", "Bob"]);
+    assert!(!contacts[0].is_direct());
+    assert_eq!(contacts[0].shared_rooms.len(), 1);
+
+    let list = client.contacts().list().await.unwrap();
+    let (contacts, stream) = list.subscribe();
+    pin_mut!(stream);
+    // Nothing was cached yet.
+    assert!(contacts.is_empty());
+
+    // The contacts are collected in the background.
+    assert_matches!(stream.next().await, Some(VectorDiff::Clear));
+    assert_matches!(stream.next().await, Some(VectorDiff::Append { values }) => {
+        assert_eq!(values.len(), 2);
+    });
+
+    // A message from Bob makes him the most recent contact.
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_timeline_event(
+        TimelineTestEvent::Custom(json!({
+            "content": {
+                "body": "Hello",
+                "msgtype": "m.text",
+            },
+            "event_id": "$hello",
+            "origin_server_ts": 151800150,
+            "sender": "@bob:localhost",
+            "type": "m.room.message",
+        })),
+    ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), Some(sync_token.clone())).await;
+    client.sync_once(SyncSettings::new().token(sync_token)).await.unwrap();
+
+    assert_matches!(stream.next().await, Some(VectorDiff::Remove { index: 1 }));
+    assert_matches!(stream.next().await, Some(VectorDiff::Insert { index: 0, value }) => {
+        assert_eq!(value.user_id, "@bob:localhost");
+        assert_eq!(value.last_interaction, Some(MilliSecondsSinceUnixEpoch(uint!(151800150))));
+    });
+
+    // Searching only keeps the matching contacts.
+    list.set_search("ALI");
+    assert_matches!(stream.next().await, Some(VectorDiff::Clear));
+    assert_matches!(stream.next().await, Some(VectorDiff::Append { values }) => {
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].user_id, "@alice:localhost");
+    });
+    assert!(list.get(user_id!("@bob:localhost")).is_some());
+}
+
+#[async_test]
+async fn contacts_reload_once_per_sync() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!test_room:localhost");
+
+    let member_event = |user_id: &str, name: &str| {
+        json!({
+            "content": {
+                "displayname": name,
+                "membership": "join",
+            },
+            "event_id": format!("${name}_join"),
+            "origin_server_ts": 151800140,
+            "sender": user_id,
+            "state_key": user_id,
+            "type": "m.room.member",
+        })
+    };
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(
+        JoinedRoomBuilder::new(room_id)
+            .add_state_event(StateTestEvent::Custom(member_event("@example:localhost", "example")))
+            .add_state_event(StateTestEvent::Custom(member_event("@bob:localhost", "Bob"))),
+    );
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let sync_token = client.sync_once(SyncSettings::new()).await.unwrap().next_batch;
+
+    let list = client.contacts().list().await.unwrap();
+    let (_, stream) = list.subscribe();
+    pin_mut!(stream);
+    assert_matches!(stream.next().await, Some(VectorDiff::Clear));
+    assert_matches!(stream.next().await, Some(VectorDiff::Append { .. }));
+
+    // Our own membership and the direct chats changing in the same sync only
+    // reload the contacts once.
+    ev_builder
+        .add_joined_room(JoinedRoomBuilder::new(room_id).add_state_event(StateTestEvent::Custom(
+            member_event("@example:localhost", "new name"),
+        )))
+        .add_global_account_data_event(GlobalAccountDataTestEvent::Direct);
+    mock_sync(&server, ev_builder.build_json_sync_response(), Some(sync_token.clone())).await;
+    let sync_token =
+        client.sync_once(SyncSettings::new().token(sync_token)).await.unwrap().next_batch;
+
+    assert_matches!(stream.next().await, Some(VectorDiff::Clear));
+    assert_matches!(stream.next().await, Some(VectorDiff::Append { values }) => {
+        assert_eq!(values.len(), 1);
+    });
+
+    // The next update comes from the following sync, not from another reload.
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_timeline_event(
+        TimelineTestEvent::Custom(json!({
+            "content": {
+                "body": "Hello",
+                "msgtype": "m.text",
+            },
+            "event_id": "$hello",
+            "origin_server_ts": 151800150,
+            "sender": "@bob:localhost",
+            "type": "m.room.message",
+        })),
+    ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), Some(sync_token.clone())).await;
+    client.sync_once(SyncSettings::new().token(sync_token)).await.unwrap();
+
+    assert_matches!(stream.next().await, Some(VectorDiff::Remove { index: 0 }));
+    assert_matches!(stream.next().await, Some(VectorDiff::Insert { index: 0, value }) => {
+        assert_eq!(value.last_interaction, Some(MilliSecondsSinceUnixEpoch(uint!(151800150))));
+    });
+}
+
+#[async_test]
+async fn space_notification_counts() {
+    let (client, server) = logged_in_client().await;
+    let space_id = room_id!("!space:localhost");
+    let subspace_id = room_id!("!subspace:localhost");
+
+    let create_space_event = |room_id: &str| {
+        json!({
+            "content": {
+                "creator": "@example:localhost",
+                "room_version": "9",
+                "type": "m.space",
+            },
+            "event_id": format!("${room_id}_create"),
+            "origin_server_ts": 151800140,
+            "sender": "@example:localhost",
+            "state_key": "",
+            "type": "m.room.create",
+        })
+    };
+    let space_child_event = |child_id: &str| {
+        json!({
+            "content": {
+                "via": ["localhost"],
+            },
+            "event_id": format!("${child_id}_child"),
+            "origin_server_ts": 151800140,
+            "sender": "@example:localhost",
+            "state_key": child_id,
+            "type": "m.space.child",
+        })
+    };
+    let room_with_counts = |room_id: &RoomId, highlight_count: u64, notification_count: u64| {
+        JoinedRoomBuilder::new(room_id).set_unread_notifications_count(json!({
+            "highlight_count": highlight_count,
+            "notification_count": notification_count,
+        }))
+    };
+
+    // The room A is in the space and in its subspace.
+    let mut ev_builder = EventBuilder::new();
+    ev_builder
+        .add_joined_room(
+            JoinedRoomBuilder::new(space_id)
+                .add_state_event(StateTestEvent::Custom(create_space_event(space_id.as_str())))
+                .add_state_event(StateTestEvent::Custom(space_child_event("!subspace:localhost")))
+                .add_state_event(StateTestEvent::Custom(space_child_event("!a:localhost")))
+                .add_state_event(StateTestEvent::Custom(space_child_event("!c:localhost"))),
+        )
+        .add_joined_room(
+            JoinedRoomBuilder::new(subspace_id)
+                .add_state_event(StateTestEvent::Custom(create_space_event(subspace_id.as_str())))
+                .add_state_event(StateTestEvent::Custom(space_child_event("!a:localhost")))
+                .add_state_event(StateTestEvent::Custom(space_child_event("!b:localhost"))),
+        )
+        .add_joined_room(room_with_counts(room_id!("!a:localhost"), 1, 2))
+        .add_joined_room(room_with_counts(room_id!("!b:localhost"), 0, 3))
+        .add_joined_room(room_with_counts(room_id!("!c:localhost"), 0, 1));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let sync_token = client.sync_once(SyncSettings::new()).await.unwrap().next_batch;
+    server.reset().await;
+
+    let counts = client.space_notification_counts().await.unwrap();
+    let mut subscriber = counts.subscribe();
+
+    let space_counts = counts.get(space_id).unwrap();
+    assert_eq!(space_counts.highlight_count, 1);
+    assert_eq!(space_counts.notification_count, 6);
+    let subspace_counts = counts.get(subspace_id).unwrap();
+    assert_eq!(subspace_counts.highlight_count, 1);
+    assert_eq!(subspace_counts.notification_count, 5);
+    assert!(counts.get(room_id!("!a:localhost")).is_none());
+
+    // The room B is read.
+    ev_builder.add_joined_room(room_with_counts(room_id!("!b:localhost"), 0, 0));
+    mock_sync(&server, ev_builder.build_json_sync_response(), Some(sync_token.clone())).await;
+    client.sync_once(SyncSettings::new().token(sync_token)).await.unwrap();
+
+    let all_counts = subscriber.next().await.unwrap();
+    assert_eq!(all_counts[space_id].notification_count, 3);
+    assert_eq!(all_counts[subspace_id].notification_count, 2);
+}
+
+#[async_test]
+async fn minimal_client() {
+    let (builder, server) = test_client_builder().await;
+    let room_id = room_id!("!test_room:localhost");
+    let client = builder.minimal([room_id.to_owned()]).build().await.unwrap();
+    let session = Session {
+        access_token: "1234".to_owned(),
+        refresh_token: None,
+        user_id: user_id!("@example:localhost").to_owned(),
+        device_id: device_id!("DEVICEID").to_owned(),
+    };
+    client.restore_session(session).await.unwrap();
+    assert_eq!(client.minimal_rooms(), Some([room_id.to_owned()].as_slice()));
+
+    // The sync is restricted to the configured rooms, with lazy-loaded members.
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/sync"))
+        .and(|request: &wiremock::Request| {
+            request.url.query_pairs().any(|(key, value)| {
+                key == "filter"
+                    && value.contains(r#""rooms":["!test_room:localhost"]"#)
+                    && value.contains(r#""lazy_load_members":true"#)
+            })
+        })
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::SYNC))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client.sync_once(SyncSettings::new()).await.unwrap();
+}
+
+#[async_test]
+async fn http_middleware() {
+    #[derive(Debug)]
+    struct TestMiddleware {
+        responses: Arc<StdMutex<Vec<u16>>>,
+    }
+
+    #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+    #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+    impl HttpMiddleware for TestMiddleware {
+        async fn on_request(
+            &self,
+            request: &mut http::Request<Bytes>,
+        ) -> Option<http::Response<Bytes>> {
+            if request.uri().path().ends_with("/capabilities") {
+                // Answer the request without sending it.
+                let body = json!({ "capabilities": {} }).to_string();
+                return Some(http::Response::builder().status(200).body(body.into()).unwrap());
+            }
+
+            request.headers_mut().insert("x-gateway-token", HeaderValue::from_static("secret"));
+            None
+        }
+
+        async fn on_response(
+            &self,
+            _request: &http::Request<Bytes>,
+            response: &mut http::Response<Bytes>,
+        ) {
+            self.responses.lock().unwrap().push(response.status().as_u16());
+        }
+    }
+
+    let (builder, server) = test_client_builder().await;
+    let responses = Arc::new(StdMutex::new(Vec::new()));
+    let middleware = TestMiddleware { responses: responses.clone() };
+    let client = builder.add_http_middleware(middleware).build().await.unwrap();
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/login"))
+        .and(header("x-gateway-token", "secret"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::LOGIN_TYPES))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client.get_login_types().await.unwrap();
+    assert_eq!(*responses.lock().unwrap(), [200]);
+
+    // The short-circuited request doesn't reach the server nor the response
+    // hook.
+    client.get_capabilities().await.unwrap();
+    assert_eq!(*responses.lock().unwrap(), [200]);
+}
+
+#[async_test]
+async fn resolve_uri() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/unstable/im.nheko.summary/rooms/.*/summary"))
+        .and(query_param("via", "localhost"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "room_id": "!room:localhost",
+            "canonical_alias": "#room:localhost",
+            "name": "Room",
+            "num_joined_members": 42,
+            "join_rule": "public",
+            "im.nheko.summary.encryption": "m.megolm.v1.aes-sha2",
+            "world_readable": true,
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let resolved = client
+        .resolve_uri("https://matrix.to/#/!room:localhost/$event:localhost?via=localhost")
+        .await
+        .unwrap();
+    assert_matches!(resolved, ResolvedUri::Room { preview, event_id, via } => {
+        assert_eq!(preview.room_id.as_str(), "!room:localhost");
+        assert_eq!(preview.name.as_deref(), Some("Room"));
+        assert_eq!(preview.num_joined_members, 42);
+        assert_eq!(preview.is_encrypted, Some(true));
+        assert!(preview.is_world_readable);
+        assert_eq!(preview.state, None);
+        assert_eq!(event_id.unwrap().as_str(), "$event:localhost");
+        assert_eq!(via, [server_name!("localhost").to_owned()]);
+    });
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/profile/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "displayname": "Alice",
+            "avatar_url": "mxc://localhost/alice",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let resolved = client.resolve_uri("matrix:u/alice:localhost").await.unwrap();
+    assert_matches!(resolved, ResolvedUri::User(user) => {
+        assert_eq!(user.user_id.as_str(), "@alice:localhost");
+        assert_eq!(user.display_name.as_deref(), Some("Alice"));
+        assert_eq!(user.avatar_url.as_deref(), Some(mxc_uri!("mxc://localhost/alice")));
+    });
+
+    // Other URIs are not supported.
+    let error = client.resolve_uri("https://example.org").await.unwrap_err();
+    assert_matches!(error, matrix_sdk::Error::UriParse(UriParseError::InvalidUri(_)));
+}
+
+#[async_test]
+async fn room_preview_from_peek() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!public:localhost");
+
+    // The homeserver supports neither the room summary nor the hierarchy, so the
+    // room is peeked into.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/state$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+            {
+                "content": { "name": "Public archive" },
+                "event_id": "$name:localhost",
+                "origin_server_ts": 1432135524678u64,
+                "room_id": room_id,
+                "sender": "@example:localhost",
+                "state_key": "",
+                "type": "m.room.name",
+            },
+            {
+                "content": { "history_visibility": "world_readable" },
+                "event_id": "$visibility:localhost",
+                "origin_server_ts": 1432135524678u64,
+                "room_id": room_id,
+                "sender": "@example:localhost",
+                "state_key": "",
+                "type": "m.room.history_visibility",
+            },
+        ])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let preview = client.room_preview(room_id.into(), &[]).await.unwrap();
+    assert_eq!(preview.name.as_deref(), Some("Public archive"));
+    assert!(preview.is_world_readable);
+    assert_eq!(preview.state, None);
+
+    // The state of the peeked room isn't persisted.
+    assert!(client.get_room(room_id).is_none());
+    assert!(client.store().get_room_infos().await.unwrap().is_empty());
+}
+
+#[async_test]
+async fn room_preview_from_hierarchy() {
+    let (client, server) = logged_in_client().await;
+
+    // The homeserver doesn't support the room summary, so the room is looked up
+    // in the hierarchy.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v1/rooms/.*/hierarchy"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "rooms": [{
+                "room_id": "!room:localhost",
+                "name": "Room",
+                "topic": "Talking about things",
+                "num_joined_members": 7,
+                "join_rule": "knock",
+                "world_readable": false,
+                "guest_can_join": false,
+                "children_state": [],
+            }],
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let preview = client.room_preview(room_id!("!room:localhost").into(), &[]).await.unwrap();
+    assert_eq!(preview.room_id.as_str(), "!room:localhost");
+    assert_eq!(preview.name.as_deref(), Some("Room"));
+    assert_eq!(preview.topic.as_deref(), Some("Talking about things"));
+    assert_eq!(preview.num_joined_members, 7);
+    assert_eq!(preview.join_rule, Some(SpaceRoomJoinRule::Knock));
+    assert_eq!(preview.is_encrypted, None);
+    assert!(!preview.is_world_readable);
+}
+
+#[async_test]
+async fn invited_rooms_stream() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!invited:localhost");
+
+    let invites = client.invited_rooms_stream();
+    pin_mut!(invites);
+    assert!(invites.next().await.unwrap().is_empty());
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_invited_room(
+        InvitedRoomBuilder::new(room_id)
+            .add_state_event(StrippedStateTestEvent::Custom(json!({
+                "content": { "membership": "invite" },
+                "sender": "@alice:localhost",
+                "state_key": "@example:localhost",
+                "type": "m.room.member",
+            })))
+            .add_state_event(StrippedStateTestEvent::Custom(json!({
+                "content": { "membership": "join", "displayname": "Alice" },
+                "sender": "@alice:localhost",
+                "state_key": "@alice:localhost",
+                "type": "m.room.member",
+            })))
+            .add_state_event(StrippedStateTestEvent::RoomName),
+    );
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    client.sync_once(SyncSettings::new()).await.unwrap();
+
+    let list = invites.next().await.unwrap();
+    assert_eq!(list.len(), 1);
+    let invite = &list[0];
+    assert_eq!(invite.room.room_id(), room_id);
+    assert_eq!(invite.display_name, "room name");
+    let inviter = invite.inviter.as_ref().unwrap();
+    assert_eq!(inviter.user_id, user_id!("@alice:localhost"));
+    assert_eq!(inviter.display_name.as_deref(), Some("Alice"));
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/report$"))
+        .and(body_json(json!({ "reason": "spam" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/leave"))
+        .and(body_json(json!({ "reason": "spam" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    invite.room.reject_invite(Some("spam"), true).await.unwrap();
+    assert!(invites.next().await.unwrap().is_empty());
+}
+
+#[async_test]
+async fn reject_invite_without_report_endpoint() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!invited:localhost");
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_invited_room(InvitedRoomBuilder::new(room_id).add_state_event(
+        StrippedStateTestEvent::Custom(json!({
+            "content": { "membership": "invite" },
+            "sender": "@alice:localhost",
+            "state_key": "@example:localhost",
+            "type": "m.room.member",
+        })),
+    ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    client.sync_once(SyncSettings::new()).await.unwrap();
+
+    // The homeserver doesn't support reporting rooms.
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/report$"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "errcode": "M_UNRECOGNIZED",
+            "error": "Unrecognized request",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/leave"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let room = client.get_invited_room(room_id).unwrap();
+    room.reject_invite(Some("spam"), true).await.unwrap();
+}
+
+#[async_test]
+async fn rotate_store_passphrase() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let (builder, _server) = test_client_builder().await;
+    let client = builder.sqlite_store(dir.path(), Some("old")).build().await.unwrap();
+
+    let rotation = client.rotate_store_passphrase("old", "new");
+    let progress = rotation.subscribe_to_progress();
+    rotation.await.unwrap();
+    assert_matches!(progress.get(), Some(progress) => {
+        assert_eq!(progress.done, progress.total);
+    });
+    drop(client);
+
+    let (builder, _server) = test_client_builder().await;
+    builder.sqlite_store(dir.path(), Some("new")).build().await.unwrap();
+}
+
+#[async_test]
+async fn rotate_store_passphrase_without_sqlite_store() {
+    let (client, _server) = no_retry_test_client().await;
+
+    let result = client.rotate_store_passphrase("old", "new").await;
+    assert_matches!(result, Err(matrix_sdk::Error::NoStorePassphrase));
+}
@@ -2,7 +2,9 @@ use std::time::Duration;
 
 use assert_matches::assert_matches;
 use futures_util::StreamExt;
-use matrix_sdk::{config::RequestConfig, executor::spawn, HttpError, RefreshTokenError, Session};
+use matrix_sdk::{
+    config::RequestConfig, executor::spawn, HttpError, RefreshTokenError, Session, SessionVerdict,
+};
 use matrix_sdk_test::{async_test, test_json};
 use ruma::{
     api::{
@@ -288,6 +290,7 @@ async fn refresh_token_handled_success() {
     client.whoami().await.unwrap();
     tokens_join_handle.await.unwrap();
     changed_join_handle.await.unwrap();
+    assert_eq!(client.session_verdict(), SessionVerdict::Valid);
 }
 
 #[async_test]
@@ -340,7 +343,8 @@ async fn refresh_token_handled_failure() {
         .await;
 
     let res = client.whoami().await.unwrap_err();
-    assert_matches!(res.client_api_error_kind(), Some(ErrorKind::UnknownToken { .. }))
+    assert_matches!(res.client_api_error_kind(), Some(ErrorKind::UnknownToken { .. }));
+    assert_eq!(client.session_verdict(), SessionVerdict::SoftLogout);
 }
 
 #[async_test]
@@ -534,3 +538,64 @@ async fn refresh_token_handled_other_error() {
 
     client.whoami().await.unwrap_err();
 }
+
+#[async_test]
+async fn session_verdict_revoked() {
+    let (client, server) = logged_in_client().await;
+    let mut verdict = client.subscribe_to_session_verdict();
+    assert_eq!(verdict.get(), SessionVerdict::Valid);
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/account/whoami"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "errcode": "M_UNKNOWN_TOKEN",
+            "error": "Invalid access token passed.",
+            "soft_logout": false,
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client.whoami().await.unwrap_err();
+    assert_eq!(verdict.next().await, Some(SessionVerdict::Revoked));
+    assert!(client.session_verdict().needs_login());
+
+    // Restoring a session makes it valid again.
+    let session = Session {
+        access_token: "1234".to_owned(),
+        refresh_token: None,
+        user_id: user_id!("@example:localhost").to_owned(),
+        device_id: device_id!("DEVICEID").to_owned(),
+    };
+    client.restore_session(session).await.unwrap();
+    assert_eq!(verdict.next().await, Some(SessionVerdict::Valid));
+}
+
+#[async_test]
+async fn session_verdict_valid_after_login() {
+    let (client, server) = no_retry_test_client().await;
+    let mut verdict = client.subscribe_to_session_verdict();
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/login"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::LOGIN))
+        .mount(&server)
+        .await;
+    client.login_username("example", "wordpass").send().await.unwrap();
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/account/whoami"))
+        .respond_with(
+            ResponseTemplate::new(401).set_body_json(&*test_json::UNKNOWN_TOKEN_SOFT_LOGOUT),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client.whoami().await.unwrap_err();
+    assert_eq!(verdict.next().await, Some(SessionVerdict::SoftLogout));
+
+    // Logging in again makes the session valid again.
+    client.login_username("example", "wordpass").send().await.unwrap();
+    assert_eq!(verdict.next().await, Some(SessionVerdict::Valid));
+}
@@ -1,10 +1,20 @@
 use std::time::Duration;
 
 use assert_matches::assert_matches;
-use matrix_sdk::{config::SyncSettings, room::RoomMember, DisplayName, RoomMemberships};
+use eyeball_im::VectorDiff;
+use futures_util::{future, pin_mut, StreamExt};
+use matrix_sdk::{
+    config::SyncSettings,
+    deserialized_responses::TimelineEvent,
+    room::{
+        MediaExportOptions, MediaExportProgress, MediaExportRange, RoomClientSettingsEventContent,
+        RoomMember, RoomUpgrade, SuccessorRoom,
+    },
+    DisplayName, RoomMemberships,
+};
 use matrix_sdk_test::{
-    async_test, bulk_room_members, test_json, EventBuilder, JoinedRoomBuilder, StateTestEvent,
-    TimelineTestEvent,
+    async_test, bulk_room_members, test_json, EventBuilder, JoinedRoomBuilder,
+    RoomAccountDataTestEvent, StateTestEvent, TimelineTestEvent,
 };
 use ruma::{
     event_id,
@@ -12,11 +22,11 @@ use ruma::{
         room::member::MembershipState, AnyStateEvent, AnySyncStateEvent, AnyTimelineEvent,
         StateEventType,
     },
-    room_id,
+    room_id, uint,
 };
 use serde_json::json;
 use wiremock::{
-    matchers::{header, method, path_regex},
+    matchers::{body_json, header, method, path, path_regex, query_param},
     Mock, ResponseTemplate,
 };
 
@@ -554,3 +564,432 @@ async fn event() {
     assert!(timeline_event.push_actions.iter().any(|a| a.is_highlight()));
     assert!(timeline_event.push_actions.iter().any(|a| a.should_notify()));
 }
+
+#[async_test]
+async fn event_with_context() {
+    let room_id = room_id!("!a98sd12bjh:example.org");
+
+    let (client, server) = logged_in_client().await;
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    client.sync_once(SyncSettings::new()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_room(room_id).unwrap();
+
+    let text_event = |event_id: &str| {
+        json!({
+            "content": {
+                "body": event_id,
+                "msgtype": "m.text",
+            },
+            "event_id": event_id,
+            "origin_server_ts": 152037280,
+            "sender": "@example:localhost",
+            "type": "m.room.message",
+            "room_id": room_id,
+        })
+    };
+
+    // The homeserver splits the limit evenly, so 2 events are requested to
+    // get 1 event on each side.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/context/.*$"))
+        .and(query_param("limit", "2"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "events_before": [text_event("$before1")],
+            "event": text_event("$focused"),
+            "events_after": [text_event("$after1")],
+            "start": "t392-516_47314_0_7_1_1_1_11444_1",
+            "end": "t47409-4357353_219380_26003_2269",
+            "state": [*test_json::sync_events::TOPIC],
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // The missing event after is loaded from the token of that side.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/messages$"))
+        .and(query_param("from", "t47409-4357353_219380_26003_2269"))
+        .and(query_param("dir", "f"))
+        .and(query_param("limit", "1"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "chunk": [text_event("$after2")],
+            "start": "t47409-4357353_219380_26003_2269",
+            "end": "t47410-4357354_219380_26003_2269",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let context = room.event_with_context(event_id!("$focused"), uint!(1), uint!(2)).await.unwrap();
+
+    let event_id = |event: &TimelineEvent| event.event.get_field::<String>("event_id").unwrap();
+    assert_eq!(event_id(&context.event.unwrap()).as_deref(), Some("$focused"));
+
+    assert_eq!(context.events_before.len(), 1);
+    assert_eq!(event_id(&context.events_before[0]).as_deref(), Some("$before1"));
+    assert_eq!(context.prev_batch_token.as_deref(), Some("t392-516_47314_0_7_1_1_1_11444_1"));
+
+    assert_eq!(context.events_after.len(), 2);
+    assert_eq!(event_id(&context.events_after[1]).as_deref(), Some("$after2"));
+    assert_eq!(context.next_batch_token.as_deref(), Some("t47410-4357354_219380_26003_2269"));
+
+    assert_eq!(context.state.len(), 1);
+}
+
+#[cfg(feature = "e2e-encryption")]
+#[async_test]
+async fn membership_snapshot() {
+    use matrix_sdk::room::SnapshotVerification;
+
+    let (client, server) = logged_in_client().await;
+
+    mock_sync(&server, &*test_json::SYNC, None).await;
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/members"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::MEMBERS))
+        .mount(&server)
+        .await;
+
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+    let _response = client.sync_once(sync_settings).await.unwrap();
+
+    let room = client.get_joined_room(&test_json::DEFAULT_SYNC_ROOM_ID).unwrap();
+    let snapshot = room.export_membership_snapshot().await.unwrap();
+
+    assert_eq!(snapshot.room_id, *test_json::DEFAULT_SYNC_ROOM_ID);
+    assert_eq!(snapshot.exported_by, client.user_id().unwrap());
+    assert!(!snapshot.members.is_empty());
+    assert!(snapshot.power_levels.is_some());
+
+    // The snapshot survives a round-trip through JSON.
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let mut snapshot: matrix_sdk::room::MembershipSnapshot = serde_json::from_str(&json).unwrap();
+
+    assert_matches!(
+        snapshot.verify(&client).await.unwrap(),
+        SnapshotVerification::Signed { device_id, is_device_verified: true } => {
+            assert_eq!(device_id, client.device_id().unwrap());
+        }
+    );
+
+    snapshot.members.pop();
+    assert_eq!(snapshot.verify(&client).await.unwrap(), SnapshotVerification::InvalidSignature);
+
+    snapshot.signatures.clear();
+    assert_eq!(snapshot.verify(&client).await.unwrap(), SnapshotVerification::Unsigned);
+}
+
+#[async_test]
+async fn members_stream() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!test_room:localhost");
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_state_event(
+        StateTestEvent::Custom(json!({
+            "content": {
+                "users": {
+                    "@example:localhost": 100,
+                    "@bob:localhost": 50,
+                },
+            },
+            "event_id": "$power_levels",
+            "origin_server_ts": 151800140,
+            "sender": "@example:localhost",
+            "state_key": "",
+            "type": "m.room.power_levels",
+        })),
+    ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let sync_token = client.sync_once(SyncSettings::new()).await.unwrap().next_batch;
+
+    let member_event = |user_id: &str, name: &str, membership: &str| {
+        json!({
+            "content": {
+                "displayname": name,
+                "membership": membership,
+            },
+            "event_id": format!("${name}_{membership}"),
+            "origin_server_ts": 151800140,
+            "sender": user_id,
+            "state_key": user_id,
+            "type": "m.room.member",
+        })
+    };
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/members"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "chunk": [
+                member_event("@alice:localhost", "Alice", "join"),
+                member_event("@example:localhost", "example", "join"),
+                member_event("@bob:localhost", "Bob", "join"),
+            ],
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let room = client.get_joined_room(room_id).unwrap();
+    let list = room.members_stream(RoomMemberships::ACTIVE).await.unwrap();
+    let (members, stream) = list.subscribe();
+    pin_mut!(stream);
+    assert!(members.is_empty());
+
+    // The members are loaded in the background.
+    assert_matches!(stream.next().await, Some(VectorDiff::Clear));
+    let members =
+        assert_matches!(stream.next().await, Some(VectorDiff::Append { values }) => values);
+    let names: Vec<_> = members.iter().map(|member| member.name().to_owned()).collect();
+    assert_eq!(names, ["example", "Bob", "Alice"]);
+
+    // Narrowing the search removes the members that don't match.
+    list.set_search("B").await.unwrap();
+    assert_matches!(stream.next().await, Some(VectorDiff::Remove { index: 0 }));
+    assert_matches!(stream.next().await, Some(VectorDiff::Remove { index: 1 }));
+    assert_eq!(list.subscribe().0.len(), 1);
+
+    // Clearing the search shows all the members again.
+    list.set_search("").await.unwrap();
+    assert_matches!(stream.next().await, Some(VectorDiff::Clear));
+    assert_matches!(stream.next().await, Some(VectorDiff::Append { values }) => {
+        assert_eq!(values.len(), 3);
+    });
+
+    // Membership changes are received live.
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_timeline_event(
+        TimelineTestEvent::Custom(member_event("@alice:localhost", "Alice", "leave")),
+    ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), Some(sync_token.clone())).await;
+    client.sync_once(SyncSettings::new().token(sync_token)).await.unwrap();
+
+    assert_matches!(stream.next().await, Some(VectorDiff::Remove { index: 2 }));
+}
+
+#[async_test]
+async fn follow_room_upgrades() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!old_room:localhost");
+    // The room ID returned by the mocked join endpoint.
+    let new_room_id = room_id!("!testroom:example.org");
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/join/"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::ROOM_ID))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let follower = client.follow_room_upgrades();
+    let mut upgrades = follower.subscribe();
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_timeline_event(
+        TimelineTestEvent::Custom(json!({
+            "content": {
+                "body": "This room has been replaced",
+                "replacement_room": new_room_id,
+            },
+            "event_id": "$tombstone",
+            "origin_server_ts": 152039280,
+            "sender": "@bob:localhost",
+            "state_key": "",
+            "type": "m.room.tombstone",
+        })),
+    ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    client.sync_once(SyncSettings::new()).await.unwrap();
+
+    let room = client.get_joined_room(room_id).unwrap();
+    assert_eq!(
+        room.successor(),
+        Some(SuccessorRoom {
+            room_id: new_room_id.to_owned(),
+            reason: Some("This room has been replaced".to_owned()),
+        })
+    );
+
+    assert_eq!(
+        upgrades.recv().await.unwrap(),
+        RoomUpgrade { old_room_id: room_id.to_owned(), new_room_id: new_room_id.to_owned() }
+    );
+    assert!(client.get_joined_room(new_room_id).is_some());
+}
+
+#[async_test]
+async fn export_media() {
+    let room_id = room_id!("!a98sd12bjh:example.org");
+
+    let (client, server) = logged_in_client().await;
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(
+        JoinedRoomBuilder::new(room_id)
+            .add_state_event(StateTestEvent::Member)
+            .add_state_event(StateTestEvent::PowerLevels),
+    );
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_room(room_id).unwrap();
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/messages$"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "start": "t392-516_47314_0_7_1_1_1_11444_1",
+            "chunk": [
+                {
+                    "content": {
+                        "body": "holidays.jpg",
+                        "info": { "mimetype": "image/jpeg" },
+                        "msgtype": "m.image",
+                        "url": "mxc://example.org/image",
+                    },
+                    "event_id": "$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg",
+                    "origin_server_ts": 1_697_414_400_000_u64,
+                    "sender": "@bob:example.org",
+                    "type": "m.room.message",
+                    "room_id": room_id,
+                },
+                {
+                    "content": {
+                        "body": "Look at this!",
+                        "msgtype": "m.text",
+                    },
+                    "event_id": "$text:example.org",
+                    "origin_server_ts": 1_697_414_300_000_u64,
+                    "sender": "@bob:example.org",
+                    "type": "m.room.message",
+                    "room_id": room_id,
+                },
+            ],
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/media/r0/download/example.org/image"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw("binaryjpegdata", "image/jpeg"))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dest_dir = tempfile::tempdir().unwrap();
+
+    let export =
+        room.export_media(MediaExportRange::All, dest_dir.path(), MediaExportOptions::new());
+    let progress = export.subscribe_to_progress();
+    let result = export.await.unwrap();
+
+    assert_eq!(result, MediaExportProgress { found: 1, exported: 1, skipped: 0, failed: 0 });
+    assert_eq!(progress.get(), result);
+
+    let data = std::fs::read(dest_dir.path().join("2023-10-16_holidays_RqncFdvn.jpg")).unwrap();
+    assert_eq!(data, b"binaryjpegdata");
+
+    // The attachments that were already exported are not downloaded again.
+    let result = room
+        .export_media(MediaExportRange::All, dest_dir.path(), MediaExportOptions::new())
+        .await
+        .unwrap();
+    assert_eq!(result, MediaExportProgress { found: 1, exported: 0, skipped: 1, failed: 0 });
+}
+
+#[async_test]
+async fn client_settings() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!a98sd12bjh:example.org");
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    client.sync_once(SyncSettings::new()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_joined_room(room_id).unwrap();
+    assert_eq!(room.client_settings().await.unwrap(), RoomClientSettingsEventContent::default());
+
+    let settings = room.subscribe_to_client_settings().await.unwrap();
+    let mut subscriber = settings.subscribe();
+
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id).add_account_data(
+        RoomAccountDataTestEvent::Custom(json!({
+            "content": {
+                "url_previews": false,
+                "notification_sound": "bell",
+                "com.example.font_size": 14,
+            },
+            "type": "org.matrix.rust_sdk.room_client_settings",
+        })),
+    ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    client.sync_once(SyncSettings::new()).await.unwrap();
+    server.reset().await;
+
+    let new_settings = subscriber.next().await.unwrap();
+    assert!(!new_settings.url_previews_enabled(true));
+    assert_eq!(new_settings.notification_sound.as_deref(), Some("bell"));
+    assert_eq!(settings.get(), new_settings);
+    assert_eq!(room.client_settings().await.unwrap(), new_settings);
+
+    // The custom fields are kept when the settings are updated.
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/r0/user/.*/rooms/.*/account_data/org.matrix.rust_sdk.room_client_settings",
+        ))
+        .and(header("authorization", "Bearer 1234"))
+        .and(body_json(json!({
+            "url_previews": true,
+            "notification_sound": "bell",
+            "com.example.font_size": 14,
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    room.update_client_settings(|settings| settings.url_previews = Some(true)).await.unwrap();
+
+    // The new settings are available without waiting for the next sync.
+    assert!(room.client_settings().await.unwrap().url_previews_enabled(false));
+
+    // Concurrent updates don't overwrite each other.
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/r0/user/.*/rooms/.*/account_data/org.matrix.rust_sdk.room_client_settings",
+        ))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let (first, second) = future::join(
+        room.update_client_settings(|settings| settings.message_layout = Some("irc".to_owned())),
+        room.update_client_settings(|settings| settings.notification_sound = None),
+    )
+    .await;
+    first.unwrap();
+    second.unwrap();
+
+    let new_settings = room.client_settings().await.unwrap();
+    assert_eq!(new_settings.message_layout.as_deref(), Some("irc"));
+    assert_eq!(new_settings.notification_sound, None);
+    assert_eq!(new_settings.custom["com.example.font_size"], 14);
+}
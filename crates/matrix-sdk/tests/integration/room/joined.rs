@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use assert_matches::assert_matches;
 use futures_util::future::join_all;
 use matrix_sdk::{
     attachment::{
@@ -7,14 +8,14 @@ use matrix_sdk::{
         Thumbnail,
     },
     config::SyncSettings,
-    room::Receipts,
+    room::{BulkInviteProgress, InviteOutcome, Receipts},
 };
 use matrix_sdk_test::{async_test, test_json};
 use ruma::{
     api::client::{membership::Invite3pidInit, receipt::create_receipt::v3::ReceiptType},
     assign, event_id,
     events::{receipt::ReceiptThread, room::message::RoomMessageEventContent},
-    mxc_uri, thirdparty, uint, user_id, TransactionId,
+    int, mxc_uri, owned_user_id, room_alias_id, thirdparty, uint, user_id, TransactionId,
 };
 use serde_json::json;
 use wiremock::{
@@ -47,6 +48,62 @@ async fn invite_user_by_id() {
     room.invite_user_by_id(user).await.unwrap();
 }
 
+#[async_test]
+async fn invite_users() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/invite$"))
+        .and(body_partial_json(json!({ "user_id": "@bob:localhost" })))
+        .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+            "errcode": "M_FORBIDDEN",
+            "error": "The user is banned from the room",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/invite$"))
+        .and(body_partial_json(json!({ "user_id": "@alice:localhost" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EMPTY))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    mock_sync(&server, &*test_json::SYNC, None).await;
+
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let _response = client.sync_once(sync_settings).await.unwrap();
+
+    let room = client.get_joined_room(&test_json::DEFAULT_SYNC_ROOM_ID).unwrap();
+
+    let invite = room.invite_users([
+        owned_user_id!("@alice:localhost"),
+        owned_user_id!("@example:localhost"),
+        owned_user_id!("@bob:localhost"),
+        owned_user_id!("@alice:localhost"),
+    ]);
+    let progress = invite.subscribe_to_progress();
+    let report = invite.await;
+
+    assert_eq!(report.results.len(), 3);
+    assert_eq!(report.results[0].0, "@alice:localhost");
+    assert_matches!(report.results[0].1, InviteOutcome::Invited);
+    assert_eq!(report.results[1].0, "@example:localhost");
+    assert_matches!(report.results[1].1, InviteOutcome::Skipped);
+    assert_eq!(report.results[2].0, "@bob:localhost");
+    assert_matches!(report.results[2].1, InviteOutcome::Failed(_));
+
+    let failed: Vec<_> = report.failed().map(|(user_id, _)| user_id.as_str()).collect();
+    assert_eq!(failed, ["@bob:localhost"]);
+
+    let progress = progress.get();
+    assert_eq!(progress, BulkInviteProgress { total: 3, invited: 1, skipped: 1, failed: 1 });
+    assert!(progress.is_done());
+}
+
 #[async_test]
 async fn invite_user_by_3pid() {
     let (client, server) = logged_in_client().await;
@@ -375,6 +432,68 @@ async fn room_attachment_send_info() {
     assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
 }
 
+#[cfg(feature = "image-proc")]
+#[async_test]
+async fn room_attachment_send_policy() {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+    use matrix_sdk::attachment::AttachmentSendPolicy;
+
+    let (client, server) = logged_in_client().await;
+
+    // The image is downscaled and converted, and the info and the file name
+    // match the sent image.
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/send/.*"))
+        .and(header("authorization", "Bearer 1234"))
+        .and(body_partial_json(json!({
+            "body": "photo.png",
+            "info": {
+                "mimetype": "image/png",
+                "h": 150,
+                "w": 200,
+            }
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EVENT_ID))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/media/r0/upload"))
+        .and(header("authorization", "Bearer 1234"))
+        .and(header("content-type", "image/png"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+          "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    mock_sync(&server, &*test_json::SYNC, None).await;
+    mock_encryption_state(&server, false).await;
+
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let _response = client.sync_once(sync_settings).await.unwrap();
+
+    let room = client.get_joined_room(&test_json::DEFAULT_SYNC_ROOM_ID).unwrap();
+
+    let mut data = vec![];
+    DynamicImage::ImageRgb8(RgbImage::new(400, 300))
+        .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(85))
+        .unwrap();
+    let config = AttachmentConfig::new().send_policy(
+        AttachmentSendPolicy::new().max_dimensions(200, 200).convert_to(mime::IMAGE_PNG),
+    );
+
+    let response =
+        room.send_attachment("photo.jpg", &mime::IMAGE_JPEG, data, config).await.unwrap();
+
+    assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
+}
+
 #[async_test]
 async fn room_attachment_send_wrong_info() {
     let (client, server) = logged_in_client().await;
@@ -517,6 +636,25 @@ async fn room_redact() {
     assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
 }
 
+#[async_test]
+async fn room_report_event() {
+    let (client, server) = synced_client().await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/report/.*"))
+        .and(header("authorization", "Bearer 1234"))
+        .and(body_json(json!({ "score": -100, "reason": "Spam" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EMPTY))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let room = client.get_joined_room(&test_json::DEFAULT_SYNC_ROOM_ID).unwrap();
+
+    let event_id = event_id!("$xxxxxxxx:example.com");
+    room.report_event(event_id, Some(int!(-100)), Some("Spam")).await.unwrap();
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
 async fn fetch_members_deduplication() {
@@ -585,3 +723,144 @@ async fn set_name() {
 
     room.set_name(Some(name.to_owned())).await.unwrap();
 }
+
+#[async_test]
+async fn set_canonical_alias() {
+    let (client, server) = synced_client().await;
+
+    mock_sync(&server, &*test_json::SYNC, None).await;
+    let sync_settings = SyncSettings::new();
+    client.sync_once(sync_settings).await.unwrap();
+
+    let room = client.get_joined_room(&test_json::DEFAULT_SYNC_ROOM_ID).unwrap();
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/state/m.room.canonical_alias/$"))
+        .and(header("authorization", "Bearer 1234"))
+        .and(body_json(json!({
+            "alias": "#main:example.org",
+            "alt_aliases": ["#other:example.org"],
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EVENT_ID))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    room.set_canonical_alias(
+        Some(room_alias_id!("#main:example.org").to_owned()),
+        vec![room_alias_id!("#other:example.org").to_owned()],
+    )
+    .await
+    .unwrap();
+}
+
+#[cfg(feature = "experimental-encrypted-state-events")]
+#[async_test]
+async fn send_encrypted_state_event() {
+    use matrix_sdk::{config::RequestConfig, Session};
+    use ruma::{
+        device_id,
+        events::{room::topic::RoomTopicEventContent, StateEventType},
+        room_id,
+    };
+
+    let (builder, server) = crate::test_client_builder().await;
+    let client = builder
+        .request_config(RequestConfig::new().disable_retry())
+        .encrypt_state_events([StateEventType::RoomTopic])
+        .build()
+        .await
+        .unwrap();
+    client
+        .restore_session(Session {
+            access_token: "1234".to_owned(),
+            refresh_token: None,
+            user_id: user_id!("@example:localhost").to_owned(),
+            device_id: device_id!("DEVICEID").to_owned(),
+        })
+        .await
+        .unwrap();
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/keys/upload"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "one_time_key_counts": { "signed_curve25519": 50 } })),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/keys/query"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "device_keys": {} })))
+        .mount(&server)
+        .await;
+
+    let room_id = room_id!("!encrypted_state:localhost");
+    let member = json!({
+        "type": "m.room.member",
+        "state_key": "@example:localhost",
+        "sender": "@example:localhost",
+        "content": { "membership": "join" },
+        "event_id": "$member",
+        "origin_server_ts": 0,
+    });
+    mock_sync(
+        &server,
+        json!({
+            "next_batch": "next_batch",
+            "rooms": {
+                "join": {
+                    room_id.as_str(): {
+                        "state": {
+                            "events": [
+                                {
+                                    "type": "m.room.encryption",
+                                    "state_key": "",
+                                    "sender": "@example:localhost",
+                                    "content": {
+                                        "algorithm": "m.megolm.v1.aes-sha2",
+                                        "io.element.msc3414.encrypt_state_events": true,
+                                    },
+                                    "event_id": "$encryption",
+                                    "origin_server_ts": 0,
+                                },
+                                member.clone(),
+                            ],
+                        },
+                        "timeline": { "events": [], "limited": false },
+                    },
+                },
+            },
+        }),
+        None,
+    )
+    .await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/members"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "chunk": [member] })))
+        .mount(&server)
+        .await;
+
+    // Upload the keys of the device, then query the keys of our own user.
+    client.sync_once(SyncSettings::new()).await.unwrap();
+    client.sync_once(SyncSettings::new()).await.unwrap();
+
+    let room = client.get_joined_room(room_id).unwrap();
+    assert!(room.encrypts_state_events().await.unwrap());
+
+    // The topic is sent as an `m.room.encrypted` state event, with the type
+    // and the state key of the topic packed in its state key.
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/r0/rooms/.*/state/m\.room\.encrypted/m\.room\.topic(:|%3A)$",
+        ))
+        .and(body_partial_json(json!({ "algorithm": "m.megolm.v1.aes-sha2" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EVENT_ID))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let response =
+        room.send_state_event(RoomTopicEventContent::new("Secret topic".to_owned())).await.unwrap();
+    assert_eq!(response.event_id, event_id!("$h29iv0s8:example.com"));
+}
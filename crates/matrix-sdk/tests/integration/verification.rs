@@ -0,0 +1,251 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use matrix_sdk::{
+    config::{RequestConfig, SyncSettings},
+    encryption::verification::{AutoAcceptPolicy, SasVerification, VerificationRequestState},
+    Client, Session,
+};
+use matrix_sdk_test::async_test;
+use ruma::{device_id, user_id, DeviceId, UserId};
+use serde_json::{json, Value as JsonValue};
+use wiremock::{
+    matchers::{method, path, path_regex},
+    Mock, MockServer, Request, ResponseTemplate,
+};
+
+use crate::test_client_builder;
+
+/// The device keys and the pending to-device events of the users of a mock
+/// homeserver shared by several clients.
+#[derive(Default)]
+struct SharedHomeserver {
+    device_keys: Mutex<BTreeMap<String, BTreeMap<String, JsonValue>>>,
+    to_device: Mutex<BTreeMap<String, Vec<JsonValue>>>,
+}
+
+/// Create a client for the given user, with a mock homeserver that delivers the
+/// to-device events and the device keys through the shared homeserver.
+///
+/// The users are members of the same encrypted room, so they track the
+/// devices of each other.
+async fn shared_homeserver_client(
+    homeserver: Arc<SharedHomeserver>,
+    user_id: &'static UserId,
+    device_id: &DeviceId,
+    other_user_id: &'static UserId,
+) -> (Client, MockServer) {
+    let (builder, server) = test_client_builder().await;
+    let client =
+        builder.request_config(RequestConfig::new().disable_retry()).build().await.unwrap();
+    client
+        .restore_session(Session {
+            access_token: "1234".to_owned(),
+            refresh_token: None,
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+        })
+        .await
+        .unwrap();
+
+    let hs = homeserver.clone();
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/keys/upload"))
+        .respond_with(move |request: &Request| {
+            let body: JsonValue = request.body_json().unwrap();
+            if let Some(keys) = body.get("device_keys") {
+                let device_id = keys["device_id"].as_str().unwrap().to_owned();
+                let mut device_keys = hs.device_keys.lock().unwrap();
+                device_keys.entry(user_id.to_string()).or_default().insert(device_id, keys.clone());
+            }
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "one_time_key_counts": { "signed_curve25519": 50 } }))
+        })
+        .mount(&server)
+        .await;
+
+    let hs = homeserver.clone();
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/keys/query"))
+        .respond_with(move |request: &Request| {
+            let body: JsonValue = request.body_json().unwrap();
+            let device_keys = hs.device_keys.lock().unwrap();
+            let keys: BTreeMap<_, _> = body["device_keys"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(|user_id| {
+                    (user_id.clone(), device_keys.get(user_id).cloned().unwrap_or_default())
+                })
+                .collect();
+            ResponseTemplate::new(200).set_body_json(json!({ "device_keys": keys }))
+        })
+        .mount(&server)
+        .await;
+
+    let hs = homeserver.clone();
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/sendToDevice/"))
+        .respond_with(move |request: &Request| {
+            let event_type = request.url.path_segments().unwrap().nth(4).unwrap().to_owned();
+            let body: JsonValue = request.body_json().unwrap();
+            let mut to_device = hs.to_device.lock().unwrap();
+            for (recipient, messages) in body["messages"].as_object().unwrap() {
+                for content in messages.as_object().unwrap().values() {
+                    to_device.entry(recipient.clone()).or_default().push(json!({
+                        "type": event_type,
+                        "sender": user_id,
+                        "content": content,
+                    }));
+                }
+            }
+            ResponseTemplate::new(200).set_body_json(json!({}))
+        })
+        .mount(&server)
+        .await;
+
+    let hs = homeserver;
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/sync"))
+        .respond_with(move |_: &Request| {
+            let events = hs.to_device.lock().unwrap().remove(user_id.as_str()).unwrap_or_default();
+            let member = |member: &UserId| {
+                json!({
+                    "type": "m.room.member",
+                    "state_key": member,
+                    "sender": member,
+                    "content": { "membership": "join" },
+                    "event_id": format!("$member_{}", member.localpart()),
+                    "origin_server_ts": 0,
+                })
+            };
+            ResponseTemplate::new(200).set_body_json(json!({
+                "next_batch": "next_batch",
+                "device_lists": { "changed": [other_user_id] },
+                "device_one_time_keys_count": { "signed_curve25519": 50 },
+                "to_device": { "events": events },
+                "rooms": {
+                    "join": {
+                        "!verification:localhost": {
+                            "state": {
+                                "events": [
+                                    {
+                                        "type": "m.room.encryption",
+                                        "state_key": "",
+                                        "sender": user_id,
+                                        "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+                                        "event_id": "$encryption",
+                                        "origin_server_ts": 0,
+                                    },
+                                    member(user_id),
+                                    member(other_user_id),
+                                ],
+                            },
+                            "timeline": { "events": [], "limited": false },
+                        },
+                    },
+                },
+            }))
+        })
+        .mount(&server)
+        .await;
+
+    (client, server)
+}
+
+#[async_test]
+async fn auto_accept_completes_sas_verification() {
+    let alice_id = user_id!("@alice:localhost");
+    let alice_device_id = device_id!("ALICEDEVICE");
+    let bob_id = user_id!("@bob:localhost");
+    let bob_device_id = device_id!("BOBDEVICE");
+
+    let homeserver = Arc::new(SharedHomeserver::default());
+    let (alice, _alice_server) =
+        shared_homeserver_client(homeserver.clone(), alice_id, alice_device_id, bob_id).await;
+    let (bob, _bob_server) =
+        shared_homeserver_client(homeserver, bob_id, bob_device_id, alice_id).await;
+
+    let _handle =
+        bob.encryption().auto_accept_verifications(AutoAcceptPolicy::new([alice_id.to_owned()]));
+
+    // Upload the device keys, then query the keys of the other user.
+    for _ in 0..2 {
+        alice.sync_once(SyncSettings::new()).await.unwrap();
+        bob.sync_once(SyncSettings::new()).await.unwrap();
+    }
+
+    let bob_device = alice.encryption().get_device(bob_id, bob_device_id).await.unwrap().unwrap();
+    let request = bob_device.request_verification().await.unwrap();
+
+    let mut sas: Option<SasVerification> = None;
+    let mut confirmed = false;
+    for _ in 0..100 {
+        alice.sync_once(SyncSettings::new()).await.unwrap();
+        bob.sync_once(SyncSettings::new()).await.unwrap();
+        // Let the auto-accepting tasks of Bob run.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        if sas.is_none() && matches!(request.state(), VerificationRequestState::Ready { .. }) {
+            sas = request.start_sas().await.unwrap();
+        }
+        if let Some(sas) = &sas {
+            assert!(!sas.is_cancelled(), "The verification was cancelled: {:?}", sas.cancel_info());
+            if sas.can_be_presented() && !confirmed {
+                sas.confirm().await.unwrap();
+                confirmed = true;
+            }
+            if sas.is_done() {
+                break;
+            }
+        }
+    }
+
+    assert!(sas.unwrap().is_done());
+
+    let bob_device = alice.encryption().get_device(bob_id, bob_device_id).await.unwrap().unwrap();
+    assert!(bob_device.is_verified());
+
+    // Bob completes the verification once he received the MAC of Alice.
+    bob.sync_once(SyncSettings::new()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let alice_device =
+        bob.encryption().get_device(alice_id, alice_device_id).await.unwrap().unwrap();
+    assert!(alice_device.is_verified());
+}
+
+#[async_test]
+async fn auto_accept_ignores_own_devices() {
+    let user_id = user_id!("@alice:localhost");
+    let device_id = device_id!("DEVICE");
+    let other_device_id = device_id!("OTHERDEVICE");
+
+    let homeserver = Arc::new(SharedHomeserver::default());
+    let (client, _server) =
+        shared_homeserver_client(homeserver.clone(), user_id, device_id, user_id).await;
+    let (other, _other_server) =
+        shared_homeserver_client(homeserver, user_id, other_device_id, user_id).await;
+
+    // Our own user is allowed, but not our other device.
+    let _handle =
+        client.encryption().auto_accept_verifications(AutoAcceptPolicy::new([user_id.to_owned()]));
+
+    for _ in 0..2 {
+        other.sync_once(SyncSettings::new()).await.unwrap();
+        client.sync_once(SyncSettings::new()).await.unwrap();
+    }
+
+    let device = other.encryption().get_device(user_id, device_id).await.unwrap().unwrap();
+    let request = device.request_verification().await.unwrap();
+
+    client.sync_once(SyncSettings::new()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    other.sync_once(SyncSettings::new()).await.unwrap();
+
+    assert!(!request.is_ready());
+    let other_device = client.encryption().get_device(user_id, other_device_id).await.unwrap();
+    assert!(!other_device.unwrap().is_verified());
+}
@@ -0,0 +1,75 @@
+use std::{env, process::exit, time::Duration};
+
+use matrix_sdk::{
+    bot::{AutoJoin, Bot},
+    config::SyncSettings,
+    Client,
+};
+
+/// The state kept for each room: the items of a shopping list.
+type ShoppingList = Vec<String>;
+
+async fn login_and_sync(
+    homeserver_url: String,
+    username: String,
+    password: String,
+) -> anyhow::Result<()> {
+    let client = Client::builder().homeserver_url(homeserver_url).build().await?;
+    client.login_username(&username, &password).initial_device_display_name("bot").await?;
+
+    println!("logged in as {username}");
+
+    // An initial sync so the bot doesn't respond to old messages.
+    let response = client.sync_once(SyncSettings::default()).await?;
+
+    Bot::<ShoppingList>::new(client.clone())
+        .auto_join(AutoJoin::Always)
+        .rate_limit(10, Duration::from_secs(60))
+        .command("ping", |ctx| async move { ctx.respond("pong").await })
+        .command("add", |ctx| async move {
+            if ctx.args.is_empty() {
+                return ctx.respond("Usage: !add <item>").await;
+            }
+
+            ctx.state().await.push(ctx.args.clone());
+            ctx.respond(&format!("Added {}", ctx.args)).await
+        })
+        .command("list", |ctx| async move {
+            let list = ctx.state().await.join(", ");
+            if list.is_empty() {
+                ctx.respond("The list is empty").await
+            } else {
+                ctx.respond(&list).await
+            }
+        })
+        .command("clear", |ctx| async move {
+            ctx.state().await.clear();
+            ctx.respond("The list is now empty").await
+        })
+        .register();
+
+    let settings = SyncSettings::default().token(response.next_batch);
+    client.sync(settings).await?;
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let (homeserver_url, username, password) =
+        match (env::args().nth(1), env::args().nth(2), env::args().nth(3)) {
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+            _ => {
+                eprintln!(
+                    "Usage: {} <homeserver_url> <username> <password>",
+                    env::args().next().unwrap()
+                );
+                exit(1)
+            }
+        };
+
+    login_and_sync(homeserver_url, username, password).await?;
+    Ok(())
+}
@@ -6,6 +6,11 @@ use serde_json::Value as JsonValue;
 #[cfg(feature = "appservice")]
 pub mod appservice;
 mod event_builder;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(not(target_arch = "wasm32"))]
+mod mock_server;
+mod sliding_sync_builder;
 pub mod test_json;
 
 pub use event_builder::{
@@ -13,6 +18,11 @@ pub use event_builder::{
     InvitedRoomBuilder, JoinedRoomBuilder, LeftRoomBuilder, PresenceTestEvent,
     RoomAccountDataTestEvent, StateTestEvent, StrippedStateTestEvent, TimelineTestEvent,
 };
+#[cfg(not(target_arch = "wasm32"))]
+pub use mock_server::{MatrixMockServer, MockEndpoint};
+pub use sliding_sync_builder::{
+    SlidingSyncListResponseBuilder, SlidingSyncResponseBuilder, SlidingSyncRoomResponseBuilder,
+};
 
 /// Embedded sync response files
 pub enum SyncResponseFile {
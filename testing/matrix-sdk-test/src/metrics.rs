@@ -0,0 +1,97 @@
+//! A recorder to check the metrics recorded by the SDK in the tests.
+//!
+//! The recorder is installed globally, but the metrics are recorded per
+//! thread, so the tests running in parallel don't see the metrics of the
+//! others. The async tests must use a single-threaded runtime, which is the
+//! case of [`async_test`](crate::async_test).
+
+use std::{
+    cell::RefCell,
+    sync::{Arc, Once},
+};
+
+use metrics::{Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Recorder, SharedString, Unit};
+
+thread_local! {
+    static RECORDED: RefCell<Vec<RecordedHistogram>> = RefCell::new(Vec::new());
+}
+
+/// A value recorded in a histogram.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedHistogram {
+    /// The name of the histogram.
+    pub name: String,
+    /// The labels of the value, as `(key, value)` pairs.
+    pub labels: Vec<(String, String)>,
+}
+
+impl RecordedHistogram {
+    /// Whether the value has the given name and labels, in any order.
+    pub fn matches(&self, name: &str, labels: &[(&str, &str)]) -> bool {
+        self.name == name
+            && self.labels.len() == labels.len()
+            && labels
+                .iter()
+                .all(|&(key, value)| self.labels.iter().any(|(k, v)| k == key && v == value))
+    }
+}
+
+/// Install the test recorder, if it wasn't installed yet, and forget the
+/// values recorded on the current thread.
+///
+/// # Panics
+///
+/// Panics if another recorder was installed.
+pub fn install_recorder() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        metrics::set_boxed_recorder(Box::new(ThreadLocalRecorder))
+            .expect("another metrics recorder was installed");
+    });
+
+    RECORDED.with(|recorded| recorded.borrow_mut().clear());
+}
+
+/// Take the values recorded in histograms on the current thread since the
+/// last call to this function or to [`install_recorder()`].
+pub fn take_recorded_histograms() -> Vec<RecordedHistogram> {
+    RECORDED.with(|recorded| recorded.take())
+}
+
+struct ThreadLocalRecorder;
+
+impl Recorder for ThreadLocalRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, _key: &Key) -> Counter {
+        Counter::noop()
+    }
+
+    fn register_gauge(&self, _key: &Key) -> Gauge {
+        Gauge::noop()
+    }
+
+    fn register_histogram(&self, key: &Key) -> Histogram {
+        let histogram = RecordedHistogram {
+            name: key.name().to_owned(),
+            labels: key
+                .labels()
+                .map(|label| (label.key().to_owned(), label.value().to_owned()))
+                .collect(),
+        };
+
+        Histogram::from_arc(Arc::new(HistogramHandle(histogram)))
+    }
+}
+
+struct HistogramHandle(RecordedHistogram);
+
+impl HistogramFn for HistogramHandle {
+    fn record(&self, _value: f64) {
+        RECORDED.with(|recorded| recorded.borrow_mut().push(self.0.clone()));
+    }
+}
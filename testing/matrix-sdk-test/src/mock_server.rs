@@ -0,0 +1,235 @@
+//! A mock homeserver with canned endpoints, see [`MatrixMockServer`].
+
+use serde_json::{json, Value as JsonValue};
+use wiremock::{
+    matchers::{header, method, path_regex, query_param, query_param_is_missing},
+    Match, Mock, MockBuilder, MockGuard, MockServer, Request, ResponseTemplate,
+};
+
+use crate::test_json;
+
+type ResponderFn = Box<dyn Fn(&Request) -> ResponseTemplate + Send + Sync>;
+
+/// A wrapper around a [`MockServer`] that knows the common endpoints of the
+/// Client-Server API.
+///
+/// Each `mock_*` method returns a [`MockEndpoint`] matching the requests to an
+/// endpoint, that responds with a successful canned response by default. The
+/// response and the matched requests can be customized before the mock is
+/// mounted.
+///
+/// The endpoints match both the `r0` and `v3` paths, so they work whatever the
+/// server versions of the client.
+///
+/// # Example usage
+///
+/// ```rust
+/// use matrix_sdk_test::{EventBuilder, JoinedRoomBuilder, MatrixMockServer};
+/// use ruma::room_id;
+///
+/// # async {
+/// let server = MatrixMockServer::new().await;
+///
+/// // Create a client with `server.uri()` as its homeserver URL.
+///
+/// let mut builder = EventBuilder::new();
+/// builder.add_joined_room(JoinedRoomBuilder::new(room_id!("!foo:bar.org")));
+/// server.mock_sync().ok(builder.build_json_sync_response()).mount().await;
+///
+/// // The messages sent in the room get an event ID derived from their
+/// // transaction ID.
+/// server.mock_room_send().expect(1).mount().await;
+/// # };
+/// ```
+pub struct MatrixMockServer {
+    server: MockServer,
+}
+
+impl MatrixMockServer {
+    /// Start a new mock server.
+    pub async fn new() -> Self {
+        Self { server: MockServer::start().await }
+    }
+
+    /// The underlying [`MockServer`], to mount custom mocks.
+    pub fn server(&self) -> &MockServer {
+        &self.server
+    }
+
+    /// The base URI of the server, to use as the homeserver URL of a client.
+    pub fn uri(&self) -> String {
+        self.server.uri()
+    }
+
+    /// Remove all the mocks mounted on the server.
+    pub async fn reset(&self) {
+        self.server.reset().await;
+    }
+
+    /// Mock the `GET /_matrix/client/versions` endpoint.
+    ///
+    /// Responds with [`test_json::VERSIONS`] by default.
+    pub fn mock_versions(&self) -> MockEndpoint<'_> {
+        MockEndpoint::new(self, "GET", r"^/_matrix/client/versions$", &test_json::VERSIONS)
+    }
+
+    /// Mock the `POST /login` endpoint.
+    ///
+    /// Responds with [`test_json::LOGIN`] by default.
+    pub fn mock_login(&self) -> MockEndpoint<'_> {
+        MockEndpoint::new(self, "POST", r"^/_matrix/client/(r0|v3)/login$", &test_json::LOGIN)
+    }
+
+    /// Mock the `GET /sync` endpoint.
+    ///
+    /// Responds with [`test_json::SYNC`] by default, use [`MockEndpoint::ok()`]
+    /// with the response of an [`EventBuilder`](crate::EventBuilder) to send
+    /// custom updates.
+    pub fn mock_sync(&self) -> MockEndpoint<'_> {
+        MockEndpoint::new(self, "GET", r"^/_matrix/client/(r0|v3)/sync$", &test_json::SYNC)
+    }
+
+    /// Mock the `PUT /rooms/{roomId}/send/{eventType}/{txnId}` endpoint.
+    ///
+    /// By default, responds with an event ID derived from the transaction ID of
+    /// the request, so the local echoes of several messages can be matched
+    /// with their remote echoes.
+    pub fn mock_room_send(&self) -> MockEndpoint<'_> {
+        MockEndpoint::new(self, "PUT", r"^/_matrix/client/(r0|v3)/rooms/.*/send/.*", &json!({}))
+            .respond_with(|request| {
+                let txn_id = request.url.path_segments().and_then(|s| s.last()).unwrap_or("");
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "event_id": format!("${txn_id}") }))
+            })
+    }
+
+    /// Mock the `GET /rooms/{roomId}/state/m.room.encryption` endpoint.
+    ///
+    /// Responds with a `404` by default, as if the rooms were not encrypted,
+    /// use [`MockEndpoint::ok()`] with
+    /// [`test_json::sync_events::ENCRYPTION_CONTENT`] for encrypted rooms.
+    pub fn mock_room_encryption_state(&self) -> MockEndpoint<'_> {
+        MockEndpoint::new(
+            self,
+            "GET",
+            r"^/_matrix/client/(r0|v3)/rooms/.*/state/m.*room.*encryption.?",
+            &json!({}),
+        )
+        .error(404, test_json::NOT_FOUND.to_owned())
+    }
+
+    /// Mock the `POST /_matrix/media/{version}/upload` endpoint.
+    ///
+    /// Responds with a fixed content URI by default.
+    pub fn mock_upload(&self) -> MockEndpoint<'_> {
+        MockEndpoint::new(
+            self,
+            "POST",
+            r"^/_matrix/media/(r0|v3)/upload$",
+            &json!({ "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw" }),
+        )
+    }
+}
+
+/// A mock of an endpoint of a [`MatrixMockServer`], that isn't mounted yet.
+pub struct MockEndpoint<'a> {
+    server: &'a MockServer,
+    mock: MockBuilder,
+    responder: ResponderFn,
+    expected_calls: Option<u64>,
+    name: Option<String>,
+}
+
+impl<'a> MockEndpoint<'a> {
+    fn new(server: &'a MatrixMockServer, http_method: &str, path: &str, body: &JsonValue) -> Self {
+        let template = ResponseTemplate::new(200).set_body_json(body);
+        Self {
+            server: &server.server,
+            mock: Mock::given(method(http_method)).and(path_regex(path)),
+            responder: Box::new(move |_| template.clone()),
+            expected_calls: None,
+            name: None,
+        }
+    }
+
+    /// Only match the requests that also match the given matcher.
+    pub fn and(mut self, matcher: impl Match + 'static) -> Self {
+        self.mock = self.mock.and(matcher);
+        self
+    }
+
+    /// Only match the requests using the given access token.
+    pub fn with_access_token(self, access_token: &str) -> Self {
+        self.and(header("authorization", format!("Bearer {access_token}").as_str()))
+    }
+
+    /// Only match the sync requests with the given `since` token, or without
+    /// one if it is `None`.
+    pub fn since(self, since: Option<&str>) -> Self {
+        match since {
+            Some(since) => self.and(query_param("since", since)),
+            None => self.and(query_param_is_missing("since")),
+        }
+    }
+
+    /// Respond with a `200` status code and the given body.
+    pub fn ok(self, body: JsonValue) -> Self {
+        self.respond_with_template(ResponseTemplate::new(200).set_body_json(body))
+    }
+
+    /// Respond with the given error status code and body.
+    pub fn error(self, status: u16, body: JsonValue) -> Self {
+        self.respond_with_template(ResponseTemplate::new(status).set_body_json(body))
+    }
+
+    /// Respond with the given response template.
+    pub fn respond_with_template(self, template: ResponseTemplate) -> Self {
+        self.respond_with(move |_| template.clone())
+    }
+
+    /// Compute the response to each request with the given function.
+    pub fn respond_with<F>(mut self, responder: F) -> Self
+    where
+        F: Fn(&Request) -> ResponseTemplate + Send + Sync + 'static,
+    {
+        self.responder = Box::new(responder);
+        self
+    }
+
+    /// Expect the endpoint to be called this number of times, which is
+    /// checked when the server or the [`MockGuard`] is dropped.
+    pub fn expect(mut self, calls: u64) -> Self {
+        self.expected_calls = Some(calls);
+        self
+    }
+
+    /// Name the mock, to identify it in the failed expectations.
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Mount the mock on the server, until the server is reset.
+    pub async fn mount(self) {
+        let server = self.server;
+        self.build().mount(server).await;
+    }
+
+    /// Mount the mock on the server, until the returned guard is dropped.
+    pub async fn mount_as_scoped(self) -> MockGuard {
+        let server = self.server;
+        self.build().mount_as_scoped(server).await
+    }
+
+    fn build(self) -> Mock {
+        let responder = self.responder;
+        let mut mock = self.mock.respond_with(move |request: &Request| responder(request));
+        if let Some(calls) = self.expected_calls {
+            mock = mock.expect(calls);
+        }
+        if let Some(name) = self.name {
+            mock = mock.named(name);
+        }
+        mock
+    }
+}
@@ -0,0 +1,398 @@
+//! Builders of sliding sync responses, see [`SlidingSyncResponseBuilder`].
+
+use std::{collections::BTreeMap, ops::RangeInclusive};
+
+use ruma::{OwnedRoomId, OwnedUserId};
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+
+use crate::{
+    EphemeralTestEvent, GlobalAccountDataTestEvent, RoomAccountDataTestEvent, StateTestEvent,
+    StrippedStateTestEvent, TimelineTestEvent,
+};
+
+/// The `SlidingSyncResponseBuilder` struct can be used to easily generate
+/// valid sliding sync (MSC3575) responses for testing, to be returned by a
+/// mock server.
+///
+/// Like the [`EventBuilder`](crate::EventBuilder), the *same* builder must be
+/// used for all the responses sent to a client, so the `pos` of each response
+/// is different.
+///
+/// # Example usage
+///
+/// ```rust
+/// use matrix_sdk_test::{
+///     SlidingSyncListResponseBuilder, SlidingSyncResponseBuilder,
+///     SlidingSyncRoomResponseBuilder, TimelineTestEvent,
+/// };
+/// use ruma::room_id;
+///
+/// let mut builder = SlidingSyncResponseBuilder::new();
+///
+/// // The first room of the `all_rooms` list, with a name and a message.
+/// let response1 = builder
+///     .add_list(
+///         SlidingSyncListResponseBuilder::new("all_rooms")
+///             .count(1)
+///             .sync_range(0..=0, [room_id!("!foo:bar.org").to_owned()]),
+///     )
+///     .add_room(
+///         SlidingSyncRoomResponseBuilder::new(room_id!("!foo:bar.org"))
+///             .initial()
+///             .name("Foo")
+///             .add_timeline_event(TimelineTestEvent::MessageText),
+///     )
+///     .build_json_response();
+///
+/// // response2 is empty, but has a new `pos`.
+/// let response2 = builder.build_json_response();
+/// ```
+#[derive(Default)]
+pub struct SlidingSyncResponseBuilder {
+    /// Updates to the lists.
+    lists: BTreeMap<String, JsonValue>,
+    /// Updates to the rooms, of the lists or of the room subscriptions.
+    rooms: BTreeMap<OwnedRoomId, JsonValue>,
+    /// The `to_device` extension.
+    to_device: Option<JsonValue>,
+    /// The `e2ee` extension.
+    e2ee: Option<JsonMap<String, JsonValue>>,
+    /// The global events of the `account_data` extension.
+    global_account_data: Vec<JsonValue>,
+    /// The room events of the `account_data` extension.
+    rooms_account_data: BTreeMap<OwnedRoomId, Vec<JsonValue>>,
+    /// The `receipts` extension.
+    receipts: BTreeMap<OwnedRoomId, JsonValue>,
+    /// The `typing` extension.
+    typing: BTreeMap<OwnedRoomId, JsonValue>,
+    /// The transaction ID of the request this is a response to.
+    txn_id: Option<String>,
+    /// Internal counter to enable the `pos` of each response to vary.
+    pos_counter: u64,
+}
+
+impl SlidingSyncResponseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a list update to the next response.
+    ///
+    /// If a list with the same name already exists, it is replaced by this
+    /// one.
+    pub fn add_list(&mut self, list: SlidingSyncListResponseBuilder) -> &mut Self {
+        self.lists.insert(list.name, list.inner);
+        self
+    }
+
+    /// Add a room update to the next response.
+    ///
+    /// If a room with the same room ID already exists, it is replaced by this
+    /// one.
+    pub fn add_room(&mut self, room: SlidingSyncRoomResponseBuilder) -> &mut Self {
+        self.rooms.insert(room.room_id, JsonValue::Object(room.inner));
+        self
+    }
+
+    /// Set the `txn_id` of the next response, to acknowledge the request with
+    /// the same transaction ID.
+    pub fn set_txn_id(&mut self, txn_id: impl Into<String>) -> &mut Self {
+        self.txn_id = Some(txn_id.into());
+        self
+    }
+
+    /// Add to-device events to the `to_device` extension, with the given
+    /// `next_batch` token.
+    pub fn add_to_device_events<I>(&mut self, next_batch: &str, events: I) -> &mut Self
+    where
+        I: IntoIterator<Item = JsonValue>,
+    {
+        let events: Vec<_> = events.into_iter().collect();
+        self.to_device = Some(json!({ "next_batch": next_batch, "events": events }));
+        self
+    }
+
+    /// Set the users whose devices changed, and that we don't share an
+    /// encrypted room with anymore, in the `e2ee` extension.
+    pub fn set_device_lists<C, L>(&mut self, changed: C, left: L) -> &mut Self
+    where
+        C: IntoIterator<Item = OwnedUserId>,
+        L: IntoIterator<Item = OwnedUserId>,
+    {
+        let changed: Vec<_> = changed.into_iter().collect();
+        let left: Vec<_> = left.into_iter().collect();
+        self.e2ee_mut()
+            .insert("device_lists".to_owned(), json!({ "changed": changed, "left": left }));
+        self
+    }
+
+    /// Set the number of one-time keys of each algorithm left on the server in
+    /// the `e2ee` extension.
+    pub fn set_device_one_time_keys_count(&mut self, counts: JsonValue) -> &mut Self {
+        self.e2ee_mut().insert("device_one_time_keys_count".to_owned(), counts);
+        self
+    }
+
+    /// Set the algorithms of the unused fallback keys in the `e2ee` extension.
+    pub fn set_device_unused_fallback_key_types<I>(&mut self, algorithms: I) -> &mut Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let algorithms: Vec<_> = algorithms.into_iter().collect();
+        self.e2ee_mut().insert("device_unused_fallback_key_types".to_owned(), json!(algorithms));
+        self
+    }
+
+    /// Add global account data to the `account_data` extension.
+    pub fn add_global_account_data_event(
+        &mut self,
+        event: GlobalAccountDataTestEvent,
+    ) -> &mut Self {
+        self.global_account_data.push(event.into_json_value());
+        self
+    }
+
+    /// Add room account data to the `account_data` extension.
+    pub fn add_room_account_data_event(
+        &mut self,
+        room_id: impl Into<OwnedRoomId>,
+        event: RoomAccountDataTestEvent,
+    ) -> &mut Self {
+        self.rooms_account_data.entry(room_id.into()).or_default().push(event.into_json_value());
+        self
+    }
+
+    /// Set the receipt event of a room in the `receipts` extension.
+    pub fn set_receipt_event(
+        &mut self,
+        room_id: impl Into<OwnedRoomId>,
+        event: EphemeralTestEvent,
+    ) -> &mut Self {
+        self.receipts.insert(room_id.into(), event.into_json_value());
+        self
+    }
+
+    /// Set the typing event of a room in the `typing` extension.
+    pub fn set_typing_event(
+        &mut self,
+        room_id: impl Into<OwnedRoomId>,
+        event: EphemeralTestEvent,
+    ) -> &mut Self {
+        self.typing.insert(room_id.into(), event.into_json_value());
+        self
+    }
+
+    /// Builds a sliding sync response as a JSON Value containing the updates
+    /// we queued so far.
+    ///
+    /// The next response returned by `build_json_response` will then be empty
+    /// if no further updates were queued.
+    pub fn build_json_response(&mut self) -> JsonValue {
+        self.pos_counter += 1;
+
+        let mut extensions = JsonMap::new();
+        if let Some(to_device) = self.to_device.take() {
+            extensions.insert("to_device".to_owned(), to_device);
+        }
+        if let Some(e2ee) = self.e2ee.take() {
+            extensions.insert("e2ee".to_owned(), JsonValue::Object(e2ee));
+        }
+        if !self.global_account_data.is_empty() || !self.rooms_account_data.is_empty() {
+            extensions.insert(
+                "account_data".to_owned(),
+                json!({
+                    "global": self.global_account_data,
+                    "rooms": self.rooms_account_data,
+                }),
+            );
+        }
+        if !self.receipts.is_empty() {
+            extensions.insert("receipts".to_owned(), json!({ "rooms": self.receipts }));
+        }
+        if !self.typing.is_empty() {
+            extensions.insert("typing".to_owned(), json!({ "rooms": self.typing }));
+        }
+
+        let mut body = json!({
+            "pos": self.pos_counter.to_string(),
+            "lists": self.lists,
+            "rooms": self.rooms,
+            "extensions": extensions,
+        });
+        if let Some(txn_id) = self.txn_id.take() {
+            body["txn_id"] = txn_id.into();
+        }
+
+        // Clear state so that the next response will be empty if nothing was
+        // added.
+        self.clear();
+
+        body
+    }
+
+    pub fn clear(&mut self) {
+        self.lists.clear();
+        self.rooms.clear();
+        self.to_device = None;
+        self.e2ee = None;
+        self.global_account_data.clear();
+        self.rooms_account_data.clear();
+        self.receipts.clear();
+        self.typing.clear();
+        self.txn_id = None;
+    }
+
+    fn e2ee_mut(&mut self) -> &mut JsonMap<String, JsonValue> {
+        self.e2ee.get_or_insert_with(JsonMap::new)
+    }
+}
+
+/// A builder of the update of a list in a sliding sync response, see
+/// [`SlidingSyncResponseBuilder::add_list()`].
+pub struct SlidingSyncListResponseBuilder {
+    name: String,
+    inner: JsonValue,
+}
+
+impl SlidingSyncListResponseBuilder {
+    /// Create a new `SlidingSyncListResponseBuilder` for the list with the
+    /// given name.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into(), inner: json!({ "count": 0, "ops": [] }) }
+    }
+
+    /// Set the total number of rooms of the list.
+    pub fn count(mut self, count: u32) -> Self {
+        self.inner["count"] = count.into();
+        self
+    }
+
+    /// Add a `SYNC` operation, filling the given range with the given rooms.
+    pub fn sync_range<I>(self, range: RangeInclusive<u32>, room_ids: I) -> Self
+    where
+        I: IntoIterator<Item = OwnedRoomId>,
+    {
+        let room_ids: Vec<_> = room_ids.into_iter().collect();
+        self.add_op(json!({
+            "op": "SYNC",
+            "range": [range.start(), range.end()],
+            "room_ids": room_ids,
+        }))
+    }
+
+    /// Add an `INSERT` operation, inserting the given room at the given index.
+    pub fn insert(self, index: u32, room_id: impl Into<OwnedRoomId>) -> Self {
+        self.add_op(json!({ "op": "INSERT", "index": index, "room_id": room_id.into() }))
+    }
+
+    /// Add a `DELETE` operation, removing the room at the given index.
+    pub fn delete(self, index: u32) -> Self {
+        self.add_op(json!({ "op": "DELETE", "index": index }))
+    }
+
+    /// Add an `INVALIDATE` operation, invalidating the rooms of the given
+    /// range.
+    pub fn invalidate(self, range: RangeInclusive<u32>) -> Self {
+        self.add_op(json!({ "op": "INVALIDATE", "range": [range.start(), range.end()] }))
+    }
+
+    /// Add a custom operation.
+    pub fn add_op(mut self, op: JsonValue) -> Self {
+        self.inner["ops"].as_array_mut().unwrap().push(op);
+        self
+    }
+}
+
+/// A builder of the update of a room in a sliding sync response, see
+/// [`SlidingSyncResponseBuilder::add_room()`].
+pub struct SlidingSyncRoomResponseBuilder {
+    room_id: OwnedRoomId,
+    inner: JsonMap<String, JsonValue>,
+}
+
+impl SlidingSyncRoomResponseBuilder {
+    /// Create a new `SlidingSyncRoomResponseBuilder` for the given room ID.
+    pub fn new(room_id: impl Into<OwnedRoomId>) -> Self {
+        Self { room_id: room_id.into(), inner: JsonMap::new() }
+    }
+
+    /// Mark this update as the first one of the room, sent from scratch.
+    pub fn initial(self) -> Self {
+        self.set("initial", true)
+    }
+
+    /// Set the name of the room computed by the server.
+    pub fn name(self, name: &str) -> Self {
+        self.set("name", name)
+    }
+
+    /// Mark the room as a direct message.
+    pub fn is_dm(self) -> Self {
+        self.set("is_dm", true)
+    }
+
+    /// Add an event to the timeline.
+    pub fn add_timeline_event(self, event: TimelineTestEvent) -> Self {
+        self.push("timeline", event.into_json_value())
+    }
+
+    /// Add events in bulk to the timeline.
+    pub fn add_timeline_bulk<I>(self, events: I) -> Self
+    where
+        I: IntoIterator<Item = JsonValue>,
+    {
+        events.into_iter().fold(self, |room, event| room.push("timeline", event))
+    }
+
+    /// Set the `prev_batch` of the timeline.
+    pub fn set_prev_batch(self, prev_batch: &str) -> Self {
+        self.set("prev_batch", prev_batch)
+    }
+
+    /// Set the timeline as limited.
+    pub fn set_limited(self) -> Self {
+        self.set("limited", true)
+    }
+
+    /// Set the number of events of the timeline that were received live.
+    pub fn set_num_live(self, num_live: u32) -> Self {
+        self.set("num_live", num_live)
+    }
+
+    /// Add an event to the required state.
+    pub fn add_required_state_event(self, event: StateTestEvent) -> Self {
+        self.push("required_state", event.into_json_value())
+    }
+
+    /// Add an event to the stripped state of an invite.
+    pub fn add_invite_state_event(self, event: StrippedStateTestEvent) -> Self {
+        self.push("invite_state", event.into_json_value())
+    }
+
+    /// Set the number of joined and invited members of the room.
+    pub fn set_members_count(self, joined: u32, invited: u32) -> Self {
+        self.set("joined_count", joined).set("invited_count", invited)
+    }
+
+    /// Set the number of unread notifications and highlights of the room.
+    pub fn set_unread_notifications_count(self, notifications: u32, highlights: u32) -> Self {
+        self.set("notification_count", notifications).set("highlight_count", highlights)
+    }
+
+    /// Set the timestamp used to sort the room, in milliseconds.
+    pub fn set_timestamp(self, timestamp: u64) -> Self {
+        self.set("timestamp", timestamp)
+    }
+
+    /// Set a custom field of the room.
+    pub fn set(mut self, field: &str, value: impl Into<JsonValue>) -> Self {
+        self.inner.insert(field.to_owned(), value.into());
+        self
+    }
+
+    fn push(mut self, field: &str, value: JsonValue) -> Self {
+        let array = self.inner.entry(field).or_insert_with(|| JsonValue::Array(Vec::new()));
+        array.as_array_mut().unwrap().push(value);
+        self
+    }
+}